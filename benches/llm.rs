@@ -0,0 +1,79 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dadbs_node::llm::{GenerateParams, KvCache, LightLLM, LlmError, ModelBackend};
+use std::hint::black_box;
+use std::sync::Arc;
+
+const VOCAB: usize = 256;
+const WORK_PER_TOKEN: u64 = 2_000;
+
+/// Stands in for a model: every token it is fed costs the same, so with a
+/// working cache the time per generated token stays flat as output grows.
+struct FixedCost;
+
+impl ModelBackend for FixedCost {
+    fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+        Ok(text.bytes().map(u32::from).collect())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+        Ok(tokens.iter().map(|&token| char::from(token as u8)).collect())
+    }
+
+    fn new_cache(&self) -> Result<KvCache, LlmError> {
+        Ok(KvCache::new(()))
+    }
+
+    fn forward(&self, _cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+        let mut state = 0u64;
+        for &token in tokens {
+            for i in 0..WORK_PER_TOKEN {
+                state = black_box(state.wrapping_mul(6364136223846793005).wrapping_add(i ^ token as u64));
+            }
+        }
+        let mut logits = vec![0.0; VOCAB];
+        logits[b'a' as usize + (state % 26) as usize] = 10.0;
+        Ok(logits)
+    }
+
+    fn bos_token(&self) -> Option<u32> {
+        None
+    }
+
+    fn eos_token(&self) -> Option<u32> {
+        None
+    }
+
+    fn context_length(&self) -> usize {
+        4096
+    }
+}
+
+fn bench_generate(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let model = &LightLLM::with_backend(Arc::new(FixedCost));
+    let mut group = c.benchmark_group("generate_per_token");
+    group.sample_size(10);
+
+    for max_tokens in [64, 256, 1024] {
+        group.throughput(Throughput::Elements(max_tokens as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(max_tokens), &max_tokens, |b, &max_tokens| {
+            let params = &GenerateParams::new(max_tokens).with_temperature(0.0);
+            b.to_async(&runtime).iter(|| async move { model.generate("a prompt", params.clone()).await.unwrap() })
+        });
+    }
+
+    group.bench_function("session_turn", |b| {
+        let params = &GenerateParams::new(32).with_temperature(0.0);
+        b.to_async(&runtime).iter(|| async move {
+            let mut session = model.new_session();
+            for _ in 0..8 {
+                session.generate(" and then", params.clone()).await.unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_generate);
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use dadbs_node::node::sig_verify::{self, SigVerifyConfig, SigVerifyPool, SignedMessage};
+use dadbs_node::node::{ConsensusManager, ThresholdPolicy, Transaction, Validator};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+use std::sync::Arc;
+use std::time::Duration;
+
+const BATCH_SIZE: usize = 500;
+const ROUND_TRIP: Duration = Duration::from_micros(200);
+
+/// Stands in for a remote validator: every request costs one round-trip.
+struct RemoteValidator {
+    pubkey: Pubkey,
+}
+
+#[async_trait]
+impl Validator for RemoteValidator {
+    fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    async fn verify_transaction(&self, _transaction: &Transaction) -> bool {
+        tokio::time::sleep(ROUND_TRIP).await;
+        true
+    }
+
+    async fn verify_batch(&self, transactions: &[Transaction]) -> Vec<bool> {
+        tokio::time::sleep(ROUND_TRIP).await;
+        vec![true; transactions.len()]
+    }
+}
+
+fn manager() -> ConsensusManager {
+    let mut manager = ConsensusManager::new(Duration::from_secs(30), 64, Arc::new(ThresholdPolicy::bft()));
+    for _ in 0..4 {
+        manager.add_validator(Arc::new(RemoteValidator { pubkey: Pubkey::new_unique() }));
+    }
+    manager
+}
+
+fn transactions() -> Vec<Transaction> {
+    let now = chrono::Utc::now().timestamp_millis();
+    (0..BATCH_SIZE)
+        .map(|i| Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), 1, 10, i as u64, now))
+        .collect()
+}
+
+fn bench_validation(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let manager = &manager();
+    let mut group = c.benchmark_group("validate_500");
+    group.sample_size(10);
+
+    group.bench_function("individual", |b| {
+        b.to_async(&runtime).iter_batched(
+            transactions,
+            |batch| async move {
+                for transaction in &batch {
+                    manager.validate_transaction(transaction).await;
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("batch", |b| {
+        b.to_async(&runtime).iter_batched(
+            transactions,
+            |batch| async move {
+                manager.validate_batch(&batch).await;
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+/// Only the signatures of a 500-transaction block: one at a time, as one
+/// batch, and split across the verification pool.
+fn bench_signatures(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let pool = &SigVerifyPool::new(&SigVerifyConfig::default());
+    let signed = || transactions().iter().map(SignedMessage::transaction).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("signatures_500");
+
+    group.bench_function("individual", |b| {
+        b.iter_batched(
+            signed,
+            |batch| batch.iter().map(SignedMessage::verify).collect::<Vec<_>>(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("batch", |b| {
+        b.iter_batched(signed, |batch| sig_verify::verify_batch(&batch), BatchSize::LargeInput)
+    });
+
+    group.bench_function("pool", |b| {
+        b.to_async(&runtime).iter_batched(signed, |batch| pool.verify(batch), BatchSize::LargeInput)
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_validation, bench_signatures);
+criterion_main!(benches);
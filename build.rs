@@ -0,0 +1,12 @@
+fn main() {
+    // Only the gRPC server needs generated code; other builds need no protoc.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/dadbs.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("a vendored protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::configure()
+            .compile(&["proto/dadbs.proto"], &["proto"])
+            .expect("proto/dadbs.proto compiles");
+    }
+}
@@ -0,0 +1,113 @@
+use clap::Parser;
+use dadbs_node::client::loadgen::{self, parse_duration, parse_range, FeeDistribution, LoadConfig, LoadDuration};
+use dadbs_node::node::config::DEFAULT_CHAIN_ID;
+use log::error;
+use solana_sdk::signature::read_keypair_file;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// The arguments or the faucet key were unusable.
+const EXIT_CONFIG: u8 = 78;
+/// The run could not be completed.
+const EXIT_RUNTIME: u8 = 1;
+
+/// Funds synthetic accounts from a faucet key, sends transfers between them
+/// at a target rate and prints a JSON report of latency to finality,
+/// errors and the rate achieved.
+#[derive(Parser)]
+#[command(name = "dadbs-loadgen", version, about = "Load and soak tests for DADBS testnets")]
+struct Cli {
+    /// RPC endpoint to send to, e.g. `http://127.0.0.1:8001/`; repeat to
+    /// spread the load over several nodes.
+    #[arg(long = "endpoint", required = true)]
+    endpoints: Vec<String>,
+    /// Keypair file, as `dadbs-node keygen` writes, holding the funds.
+    #[arg(long)]
+    faucet: PathBuf,
+    #[arg(long, default_value = DEFAULT_CHAIN_ID)]
+    chain_id: String,
+    /// Synthetic accounts to fund and send from.
+    #[arg(long, default_value_t = 10)]
+    accounts: usize,
+    /// Sent from the faucet to each account.
+    #[arg(long, default_value_t = 1_000_000)]
+    fund: u64,
+    #[arg(long, default_value_t = 10.0)]
+    tps: f64,
+    /// Transactions to send; ignored with `--duration`.
+    #[arg(long, default_value_t = 100)]
+    count: u64,
+    /// Soak mode: send at the target rate for this long, as `90s`, `30m` or `12h`.
+    #[arg(long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+    /// Payload bytes per transaction, or a range of them such as `0-256`.
+    #[arg(long, default_value = "0", value_parser = parse_range::<usize>)]
+    payload: (usize, usize),
+    /// `fixed:N` or `uniform:MIN-MAX`.
+    #[arg(long, default_value = "fixed:1")]
+    fees: FeeDistribution,
+    /// Seconds a transaction may take to finalize before it counts as timed out.
+    #[arg(long, default_value_t = 30)]
+    finality_timeout: u64,
+    #[arg(long, default_value_t = 256)]
+    max_in_flight: usize,
+    /// Also write the report to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Log filter such as `info` or `dadbs_node=debug`; overrides RUST_LOG.
+    #[arg(long)]
+    log_level: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(filter) = &cli.log_level {
+        logger.parse_filters(filter);
+    }
+    logger.init();
+
+    let faucet = match read_keypair_file(&cli.faucet) {
+        Ok(faucet) => faucet,
+        Err(e) => {
+            error!("Cannot read faucet key {}: {}", cli.faucet.display(), e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    let duration = match cli.duration {
+        Some(duration) => LoadDuration::Soak(duration),
+        None => LoadDuration::Transactions(cli.count),
+    };
+    let config = LoadConfig::new(cli.endpoints, cli.chain_id)
+        .with_accounts(cli.accounts)
+        .with_funding(cli.fund)
+        .with_tps(cli.tps)
+        .with_duration(duration)
+        .with_payload_bytes(cli.payload.0, cli.payload.1)
+        .with_fees(cli.fees)
+        .with_finality_timeout(Duration::from_secs(cli.finality_timeout))
+        .with_max_in_flight(cli.max_in_flight);
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        return ExitCode::from(EXIT_CONFIG);
+    }
+
+    let report = match loadgen::run(&config, &faucet).await {
+        Ok(report) => report,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(EXIT_RUNTIME);
+        }
+    };
+    let json = serde_json::to_string_pretty(&report).expect("the report always serializes");
+    println!("{}", json);
+    if let Some(path) = &cli.report {
+        if let Err(e) = std::fs::write(path, &json) {
+            error!("Cannot write report to {}: {}", path.display(), e);
+            return ExitCode::from(EXIT_RUNTIME);
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,677 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dadbs_node::node::deposit_watcher;
+use dadbs_node::node::network::PEER_STORE_FILE;
+use dadbs_node::node::runtime::{CHAIN_DIR, STATE_FILE};
+use dadbs_node::node::telemetry::write_csv;
+use dadbs_node::node::{
+    BackupManifest, BackupProgress, BackupStage, ConfigError, ConfigOverrides, ConfigProfile, DepositCursor, Genesis,
+    IndexKind, Journal, Node, NodeConfig, PeerStore, ReindexProgress, ReindexReport, Replayer, RpcDepositSource,
+    SnapshotConfig, SnapshotTrust, State, Storage, TelemetryStore, ValidatorInfo,
+};
+use log::error;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{write_keypair_file, Keypair, Signer};
+use std::fmt::Display;
+use std::io::{IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// The configuration could not be loaded or is invalid.
+const EXIT_CONFIG: u8 = 78;
+/// The command failed after its configuration was accepted.
+const EXIT_RUNTIME: u8 = 1;
+/// Written by `init` next to the config file.
+const GENESIS_FILE: &str = "genesis.json";
+
+#[derive(Parser)]
+#[command(name = "dadbs-node", version, about = "DADBS testnet node")]
+struct Cli {
+    /// Log filter such as `info` or `dadbs_node=debug`; overrides RUST_LOG.
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Writes a new config file.
+    Init {
+        #[arg(long, short, default_value = "config/node.toml")]
+        config: PathBuf,
+        #[arg(long, value_enum, default_value_t = Profile::Testnet)]
+        profile: Profile,
+        /// Overwrite an existing file.
+        #[arg(long)]
+        force: bool,
+        /// Also write a genesis template for a new chain next to the config,
+        /// with these validators; repeat for several.
+        #[arg(long = "genesis-validator")]
+        genesis_validators: Vec<Pubkey>,
+        #[command(flatten)]
+        overrides: Overrides,
+    },
+    /// Runs the node until SIGINT or SIGTERM.
+    Run(ConfigArgs),
+    /// Creates a node identity keypair and prints its public key.
+    Keygen {
+        #[arg(long, short, default_value = "node.key")]
+        out: PathBuf,
+        #[arg(long)]
+        force: bool,
+    },
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// Banned peers, kept in the peer store. Edit while the node is stopped;
+    /// a running node rewrites the store when it shuts down.
+    #[command(subcommand)]
+    Peers(PeersCommand),
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Rebuilds secondary indexes from the stored blocks. Refused while the
+    /// node is running unless `--online` is passed; an interrupted reindex
+    /// resumes when run again.
+    Reindex {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Repeat for several; defaults to every index.
+        #[arg(long = "kind", value_enum)]
+        kinds: Vec<Index>,
+        /// Reindex inside the running node through the admin API, which
+        /// keeps indexing new blocks meanwhile.
+        #[arg(long)]
+        online: bool,
+    },
+    /// Copies the store and state into an empty directory, with a manifest
+    /// of checksums. Refused while the node is running unless `--online` is
+    /// passed; block commits wait while the copy is cut.
+    Backup {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Must be empty or missing. With `--online` the path is on the
+        /// node's host.
+        #[arg(long)]
+        dest: PathBuf,
+        /// Back up inside the running node through the admin API.
+        #[arg(long)]
+        online: bool,
+    },
+    /// Checks a backup against its manifest and replaces the store and state
+    /// with it. The node must be stopped.
+    Restore {
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[arg(long)]
+        from: PathBuf,
+    },
+    /// Feeds a consensus journal through consensus started from genesis,
+    /// or the configured snapshot, printing each state transition. Stops
+    /// at the first block finalized otherwise than recorded.
+    Replay {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Defaults to the configured `[journal]` directory.
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// Stop once the recorded finalization of this height is checked.
+        #[arg(long)]
+        until: Option<u64>,
+    },
+    #[command(subcommand)]
+    Telemetry(TelemetryCommand),
+    #[command(subcommand)]
+    Deposits(DepositsCommand),
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// Writes the state at the finalized tip. The node must be stopped.
+    Export {
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[arg(long, short)]
+        out: PathBuf,
+    },
+    /// Verifies a snapshot against the configured trust and installs it.
+    Import {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Defaults to `[snapshot] path`.
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PeersCommand {
+    List(ConfigArgs),
+    Ban {
+        #[command(flatten)]
+        config: ConfigArgs,
+        addr: SocketAddr,
+        /// Defaults to `peer_ban_duration_secs`.
+        #[arg(long)]
+        duration_secs: Option<u64>,
+        #[arg(long, default_value = "banned by operator")]
+        reason: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TelemetryCommand {
+    /// Prints the samples in the telemetry ring taken from `--from` up to
+    /// `--to`. Safe while the node runs.
+    Dump {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// RFC 3339 time or Unix milliseconds; defaults to the oldest sample.
+        #[arg(long, value_parser = parse_time)]
+        from: Option<i64>,
+        /// Exclusive; defaults to now.
+        #[arg(long, value_parser = parse_time)]
+        to: Option<i64>,
+        #[arg(long, value_enum, default_value_t = DumpFormat::Csv)]
+        format: DumpFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum DepositsCommand {
+    /// Compares the credits the deposit watcher made with every finalized
+    /// stake deposit on Solana, and fails if they differ. Safe while the
+    /// node runs.
+    Reconcile(ConfigArgs),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DumpFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Loads the config with any overrides and reports whether it is valid.
+    Validate(ConfigArgs),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Profile {
+    Testnet,
+    Local,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Index {
+    AddressTx,
+    TxHash,
+    ChainStats,
+}
+
+impl From<Index> for IndexKind {
+    fn from(index: Index) -> Self {
+        match index {
+            Index::AddressTx => IndexKind::AddressTx,
+            Index::TxHash => IndexKind::TxHash,
+            Index::ChainStats => IndexKind::ChainStats,
+        }
+    }
+}
+
+impl From<Profile> for ConfigProfile {
+    fn from(profile: Profile) -> Self {
+        match profile {
+            Profile::Testnet => ConfigProfile::Testnet,
+            Profile::Local => ConfigProfile::Local,
+        }
+    }
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    #[arg(long, short, default_value = "config/node.toml")]
+    config: PathBuf,
+    #[command(flatten)]
+    overrides: Overrides,
+}
+
+/// Layered over the config file.
+#[derive(Args)]
+struct Overrides {
+    #[arg(long)]
+    node_id: Option<String>,
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(long)]
+    port: Option<u16>,
+    #[arg(long)]
+    storage_path: Option<String>,
+    /// Replaces the configured bootstrap nodes; repeat for several.
+    #[arg(long = "bootstrap")]
+    bootstrap_nodes: Vec<String>,
+    /// Start without bootstrap nodes.
+    #[arg(long, conflicts_with = "bootstrap_nodes")]
+    no_bootstrap: bool,
+    #[arg(long)]
+    rpc_listen: Option<String>,
+    #[arg(long)]
+    metrics_listen: Option<String>,
+    /// Load the model without the startup cache under
+    /// <storage_path>/llm-cache.
+    #[arg(long)]
+    no_llm_cache: bool,
+}
+
+impl From<Overrides> for ConfigOverrides {
+    fn from(overrides: Overrides) -> Self {
+        let bootstrap_nodes = match (overrides.no_bootstrap, overrides.bootstrap_nodes) {
+            (true, _) => Some(Vec::new()),
+            (false, nodes) if nodes.is_empty() => None,
+            (false, nodes) => Some(nodes),
+        };
+        ConfigOverrides {
+            node_id: overrides.node_id,
+            host: overrides.host,
+            port: overrides.port,
+            storage_path: overrides.storage_path,
+            bootstrap_nodes,
+            rpc_listen: overrides.rpc_listen,
+            metrics_listen: overrides.metrics_listen,
+            no_llm_cache: overrides.no_llm_cache,
+        }
+    }
+}
+
+/// How a command failed, which decides the exit code.
+enum Failure {
+    Config(String),
+    Runtime(String),
+}
+
+impl From<ConfigError> for Failure {
+    fn from(e: ConfigError) -> Self {
+        Failure::Config(e.to_string())
+    }
+}
+
+fn runtime<E: Display>(context: &'static str) -> impl FnOnce(E) -> Failure {
+    move |e| Failure::Runtime(format!("{}: {}", context, e))
+}
+
+fn print_json(value: &impl serde::Serialize) {
+    println!("{}", serde_json::to_string_pretty(value).expect("serializable"));
+}
+
+fn load(args: ConfigArgs) -> Result<NodeConfig, Failure> {
+    let overrides = ConfigOverrides::from(args.overrides);
+    NodeConfig::load_with_overrides(&args.config, &overrides)
+        .map_err(|e| Failure::Config(format!("{}: {}", args.config.display(), e)))
+}
+
+fn open_chain(config: &NodeConfig) -> Result<(Storage, State), Failure> {
+    let root = Path::new(&config.storage_path);
+    let storage = Storage::open(root.join(CHAIN_DIR)).map_err(runtime("cannot open storage"))?;
+    let genesis = Genesis::from_config(config).map_err(runtime("cannot load genesis"))?;
+    let balances = genesis.iter().flat_map(Genesis::balances).collect::<Vec<_>>();
+    let state = State::open(&root.join(STATE_FILE), balances).map_err(runtime("cannot open state"))?;
+    Ok((storage, state))
+}
+
+fn peer_store(config: &NodeConfig) -> Result<PeerStore, Failure> {
+    PeerStore::open(Path::new(&config.storage_path).join(PEER_STORE_FILE)).map_err(runtime("cannot open peer store"))
+}
+
+fn init(
+    config_path: &Path,
+    profile: Profile,
+    force: bool,
+    genesis_validators: Vec<Pubkey>,
+    overrides: Overrides,
+) -> Result<(), Failure> {
+    let genesis_path = config_path.with_file_name(GENESIS_FILE);
+    let mut outputs = vec![config_path];
+    if !genesis_validators.is_empty() {
+        outputs.push(&genesis_path);
+    }
+    for path in outputs {
+        if path.exists() && !force {
+            return Err(Failure::Runtime(format!("{} exists; pass --force to overwrite", path.display())));
+        }
+    }
+    let mut config = NodeConfig::for_profile(profile.into());
+    ConfigOverrides::from(overrides).apply(&mut config);
+    if !genesis_validators.is_empty() {
+        let validators = genesis_validators.into_iter().map(|pubkey| ValidatorInfo::new(pubkey, 1)).collect();
+        let mut genesis = Genesis::template(config.chain_id.clone(), validators);
+        genesis.params.min_fee = config.min_fee;
+        if let Some(parent) = genesis_path.parent() {
+            std::fs::create_dir_all(parent).map_err(runtime("cannot create config directory"))?;
+        }
+        genesis.save(&genesis_path).map_err(runtime("cannot write genesis"))?;
+        println!("Wrote {} with hash {}", genesis_path.display(), genesis.canonical_hash());
+        config.genesis_path = Some(genesis_path.display().to_string());
+    }
+    config.save(config_path)?;
+    println!("Wrote {} for node {}", config_path.display(), config.node_id);
+    Ok(())
+}
+
+fn keygen(out: &Path, force: bool) -> Result<(), Failure> {
+    if out.exists() && !force {
+        return Err(Failure::Runtime(format!("{} exists; pass --force to overwrite", out.display())));
+    }
+    let keypair = Keypair::new();
+    write_keypair_file(&keypair, out).map_err(runtime("cannot write keypair"))?;
+    println!("{}", keypair.pubkey());
+    Ok(())
+}
+
+fn snapshot(command: SnapshotCommand) -> Result<(), Failure> {
+    match command {
+        SnapshotCommand::Export { config, out } => {
+            let config = load(config)?;
+            let (storage, state) = open_chain(&config)?;
+            let manifest = storage.export_snapshot(&out, state.height(), &state, &config.chain_id)
+                .map_err(runtime("export failed"))?;
+            print_json(&manifest);
+        }
+        SnapshotCommand::Import { config, path } => {
+            let mut config = load(config)?;
+            if let Some(path) = path {
+                let trusted_validators = config.snapshot.take().map(|s| s.trusted_validators).unwrap_or_default();
+                config.snapshot = Some(SnapshotConfig { path: path.display().to_string(), trusted_validators });
+            }
+            let (path, trust) = SnapshotTrust::from_config(&config)?
+                .ok_or_else(|| Failure::Config("no snapshot path: pass --path or configure [snapshot]".to_string()))?;
+            let (storage, mut state) = open_chain(&config)?;
+            let snapshot = storage.import_snapshot(&path, &trust).map_err(runtime("import failed"))?;
+            let manifest = snapshot.manifest.clone();
+            state.replace_with(snapshot.state).map_err(runtime("cannot write state"))?;
+            storage.flush().map_err(runtime("cannot flush storage"))?;
+            print_json(&manifest);
+        }
+    }
+    Ok(())
+}
+
+fn peers(command: PeersCommand) -> Result<(), Failure> {
+    let now = chrono::Utc::now().timestamp_millis();
+    match command {
+        PeersCommand::List(config) => {
+            let config = load(config)?;
+            print_json(&peer_store(&config)?.bans(now));
+        }
+        PeersCommand::Ban { config, addr, duration_secs, reason } => {
+            let config = load(config)?;
+            let duration_secs = duration_secs.unwrap_or(config.peer_ban_duration_secs);
+            let until = now.saturating_add((duration_secs as i64).saturating_mul(1000));
+            peer_store(&config)?.ban(addr, until, reason).map_err(runtime("cannot ban peer"))?;
+            println!("Banned {} for {}s", addr, duration_secs);
+        }
+    }
+    Ok(())
+}
+
+/// Redraws a progress bar on stderr, if it is a terminal.
+fn draw_bar(done: u64, total: u64, detail: impl Display) {
+    const WIDTH: u64 = 40;
+    let mut stderr = std::io::stderr();
+    if !stderr.is_terminal() {
+        return;
+    }
+    let total = total.max(1);
+    let done = done.min(total);
+    let filled = (done * WIDTH / total) as usize;
+    let _ = write!(
+        stderr,
+        "\r[{}{}] {:>3}% {}",
+        "#".repeat(filled),
+        "-".repeat(WIDTH as usize - filled),
+        done * 100 / total,
+        detail,
+    );
+    if done == total {
+        let _ = writeln!(stderr);
+    }
+}
+
+fn draw_progress(progress: ReindexProgress) {
+    let total = progress.tip - progress.from_height + 1;
+    let done = (progress.height + 1).saturating_sub(progress.from_height);
+    draw_bar(done, total, format!("height {}/{}, {} entries", progress.height, progress.tip, progress.entries));
+}
+
+fn draw_backup_progress(progress: BackupProgress) {
+    let detail = match progress.stage {
+        BackupStage::Copying => format!("copying column {}/{}", progress.done, progress.total),
+        BackupStage::Checksumming => format!("checksumming {}/{} bytes", progress.done, progress.total),
+    };
+    draw_bar(progress.done, progress.total, detail);
+}
+
+async fn reindex(args: ConfigArgs, kinds: Vec<Index>, online: bool) -> Result<(), Failure> {
+    let config = load(args)?;
+    let mut kinds: Vec<IndexKind> = kinds.into_iter().map(IndexKind::from).collect();
+    if kinds.is_empty() {
+        kinds = IndexKind::ALL.to_vec();
+    }
+    let report = if online {
+        reindex_online(&config, kinds).await?
+    } else {
+        // A running node holds the store's lock, so this fails while it is up.
+        let storage = Storage::open(Path::new(&config.storage_path).join(CHAIN_DIR)).map_err(|e| {
+            Failure::Runtime(format!("cannot open storage: {}; if the node is running, stop it or pass --online", e))
+        })?;
+        let report = storage.reindex(&kinds, draw_progress).map_err(runtime("reindex failed"))?;
+        storage.flush().map_err(runtime("cannot flush storage"))?;
+        report
+    };
+    print_json(&report);
+    Ok(())
+}
+
+/// Calls `method` on the running node's `/admin` endpoint.
+#[cfg(feature = "client")]
+async fn admin_call(
+    config: &NodeConfig,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, Failure> {
+    let token = config.admin.token().map_err(runtime("cannot read admin token"))?
+        .ok_or_else(|| Failure::Config("--online needs the admin token; configure [admin] token_file or token_env".to_string()))?;
+    let request = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let mut response: serde_json::Value = reqwest::Client::new()
+        .post(format!("http://{}/admin", config.rpc.listen))
+        .header("Authorization", token.bearer())
+        .json(&request)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(runtime("admin request failed"))?
+        .json()
+        .await
+        .map_err(runtime("bad admin response"))?;
+    if let Some(error) = response.get("error") {
+        return Err(Failure::Runtime(format!("{} failed: {}", method, error)));
+    }
+    Ok(response["result"].take())
+}
+
+/// Asks the running node to reindex through `/admin`, waiting until it is done.
+#[cfg(feature = "client")]
+async fn reindex_online(config: &NodeConfig, kinds: Vec<IndexKind>) -> Result<ReindexReport, Failure> {
+    let result = admin_call(config, "admin_reindex", serde_json::json!({ "kinds": kinds })).await?;
+    serde_json::from_value(result).map_err(runtime("bad admin response"))
+}
+
+#[cfg(not(feature = "client"))]
+async fn reindex_online(_config: &NodeConfig, _kinds: Vec<IndexKind>) -> Result<ReindexReport, Failure> {
+    Err(Failure::Runtime("--online needs an HTTP client; rebuild with --features client".to_string()))
+}
+
+async fn backup(args: ConfigArgs, dest: PathBuf, online: bool) -> Result<(), Failure> {
+    let config = load(args)?;
+    let manifest = if online {
+        backup_online(&config, &dest).await?
+    } else {
+        // A running node holds the store's lock, so this fails while it is up.
+        let (storage, state) = open_chain(&config).map_err(|failure| match failure {
+            Failure::Runtime(e) => Failure::Runtime(format!("{}; if the node is running, stop it or pass --online", e)),
+            failure => failure,
+        })?;
+        storage.backup(&dest, &state, draw_backup_progress).map_err(runtime("backup failed"))?
+    };
+    print_json(&manifest);
+    Ok(())
+}
+
+/// Starts a backup in the running node and polls it until it is done.
+#[cfg(feature = "client")]
+async fn backup_online(config: &NodeConfig, dest: &Path) -> Result<BackupManifest, Failure> {
+    use dadbs_node::node::BackupStatus;
+
+    admin_call(config, "admin_backup", serde_json::json!({ "dest": dest.display().to_string() })).await?;
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        let result = admin_call(config, "admin_backup_status", serde_json::Value::Null).await?;
+        let status: BackupStatus = serde_json::from_value::<Option<BackupStatus>>(result)
+            .map_err(runtime("bad admin response"))?
+            .ok_or_else(|| Failure::Runtime("the node has no record of the backup".to_string()))?;
+        if let Some(progress) = status.progress {
+            draw_backup_progress(progress);
+        }
+        match status {
+            BackupStatus { error: Some(error), .. } => {
+                return Err(Failure::Runtime(format!("backup failed: {}", error)));
+            }
+            BackupStatus { manifest: Some(manifest), .. } => return Ok(manifest),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(not(feature = "client"))]
+async fn backup_online(_config: &NodeConfig, _dest: &Path) -> Result<BackupManifest, Failure> {
+    Err(Failure::Runtime("--online needs an HTTP client; rebuild with --features client".to_string()))
+}
+
+fn restore(args: ConfigArgs, from: &Path) -> Result<(), Failure> {
+    let config = load(args)?;
+    let manifest = Storage::restore(from, Path::new(&config.storage_path)).map_err(runtime("restore failed"))?;
+    print_json(&manifest);
+    Ok(())
+}
+
+fn replay(args: ConfigArgs, journal: Option<PathBuf>, until: Option<u64>) -> Result<(), Failure> {
+    let config = load(args)?;
+    let dir = journal.unwrap_or_else(|| config.journal.dir(&config.storage_path));
+    let entries = Journal::read(&dir).map_err(runtime("cannot read journal"))?;
+    let mut replayer = Replayer::from_config(&config).map_err(runtime("cannot start the replay"))?;
+    let report = replayer.run(&entries, until, |at_ms, transition| println!("{} {}", at_ms, transition))
+        .map_err(runtime("replay failed"))?;
+    print_json(&report);
+    match &report.divergence {
+        Some(divergence) => Err(Failure::Runtime(format!("replay diverged: {}", divergence))),
+        None => Ok(()),
+    }
+}
+
+fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(ms) = value.parse::<i64>() {
+        return Ok(ms);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|time| time.timestamp_millis())
+        .map_err(|e| format!("expected an RFC 3339 time or Unix milliseconds: {}", e))
+}
+
+fn telemetry(command: TelemetryCommand) -> Result<(), Failure> {
+    match command {
+        TelemetryCommand::Dump { config, from, to, format } => {
+            let config = load(config)?;
+            let store = TelemetryStore::open_read_only(config.telemetry.file(&config.storage_path))
+                .map_err(runtime("cannot open the telemetry ring"))?;
+            let to = to.unwrap_or_else(|| chrono::Utc::now().timestamp_millis() + 1);
+            let samples = store.read(from.unwrap_or(i64::MIN), to).map_err(runtime("cannot read telemetry"))?;
+            match format {
+                DumpFormat::Csv => {
+                    write_csv(&samples, &mut std::io::stdout().lock()).map_err(runtime("cannot write telemetry"))?
+                }
+                DumpFormat::Json => print_json(&samples),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn deposits(command: DepositsCommand) -> Result<(), Failure> {
+    match command {
+        DepositsCommand::Reconcile(args) => {
+            let config = load(args)?;
+            let watcher = &config.deposit_watcher;
+            let program_id = watcher.program_id().map_err(Failure::Config)?;
+            let cursor = DepositCursor::load(&watcher.cursor_file(&config.storage_path))
+                .map_err(runtime("cannot read the deposit cursor"))?;
+            let source = RpcDepositSource::new(watcher.rpc_url.clone(), program_id);
+            let report = deposit_watcher::reconcile(&source, &program_id, &cursor)
+                .await
+                .map_err(runtime("cannot reconcile deposits"))?;
+            print_json(&report);
+            if !report.is_balanced() {
+                return Err(Failure::Runtime(format!(
+                    "{} deposits uncredited, {} credits unbacked",
+                    report.uncredited.len(),
+                    report.unbacked.len()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run(args: ConfigArgs) -> Result<(), Failure> {
+    let config = load(args)?;
+    let node = Node::start(config).await.map_err(runtime("failed to start node"))?;
+    node.run().await.map_err(runtime("unclean shutdown"))?;
+    Ok(())
+}
+
+fn report(message: impl Display, code: u8) -> ExitCode {
+    error!("{}", message);
+    ExitCode::from(code)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(filter) = &cli.log_level {
+        logger.parse_filters(filter);
+    }
+    logger.init();
+
+    let result = match cli.command {
+        Command::Init { config, profile, force, genesis_validators, overrides } => {
+            init(&config, profile, force, genesis_validators, overrides)
+        }
+        Command::Run(args) => run(args).await,
+        Command::Keygen { out, force } => keygen(&out, force),
+        Command::Snapshot(command) => snapshot(command),
+        Command::Peers(command) => peers(command),
+        Command::Config(ConfigCommand::Validate(args)) => load(args).map(|config| {
+            println!("Configuration is valid for node {}", config.node_id);
+        }),
+        Command::Reindex { config, kinds, online } => reindex(config, kinds, online).await,
+        Command::Backup { config, dest, online } => backup(config, dest, online).await,
+        Command::Restore { config, from } => restore(config, &from),
+        Command::Replay { config, journal, until } => replay(config, journal, until),
+        Command::Telemetry(command) => telemetry(command),
+        Command::Deposits(command) => deposits(command).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Config(message)) => report(message, EXIT_CONFIG),
+        Err(Failure::Runtime(message)) => report(message, EXIT_RUNTIME),
+    }
+}
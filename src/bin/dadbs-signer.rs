@@ -0,0 +1,100 @@
+use clap::Parser;
+use dadbs_node::node::signer::{DEFAULT_SIGNER_SECRET_ENV, SIGN_GUARD_FILE};
+use dadbs_node::node::{Keystore, SignGuard, SignerEndpoint, SignerService};
+use log::{error, info};
+use solana_sdk::signature::{Keypair, Signer};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// The arguments, key or secret were unusable.
+const EXIT_CONFIG: u8 = 78;
+/// The signer stopped with an error.
+const EXIT_RUNTIME: u8 = 1;
+
+/// Holds a validator key from a keystore and signs votes for nodes that
+/// connect with the shared secret and `[remote_signer]` configured. It
+/// keeps its own record of what it signed and refuses conflicting votes.
+#[derive(Parser)]
+#[command(name = "dadbs-signer", version, about = "Remote vote signer for DADBS validators")]
+struct Cli {
+    /// `unix:<path>` or `tcp:<host>:<port>`.
+    #[arg(long)]
+    listen: SignerEndpoint,
+    #[arg(long)]
+    keystore: PathBuf,
+    /// Name of the validator key in the keystore.
+    #[arg(long)]
+    key: String,
+    /// Environment variable holding the key's passphrase.
+    #[arg(long, default_value = "DADBS_KEY_PASSPHRASE")]
+    passphrase_env: String,
+    /// File holding the secret shared with the node; read before `--secret-env`.
+    #[arg(long)]
+    secret_file: Option<PathBuf>,
+    #[arg(long, default_value = DEFAULT_SIGNER_SECRET_ENV)]
+    secret_env: String,
+    /// Record of signed votes; defaults to `sign_guard.json` in the keystore.
+    #[arg(long)]
+    guard: Option<PathBuf>,
+    /// Log filter such as `info` or `dadbs_node=debug`; overrides RUST_LOG.
+    #[arg(long)]
+    log_level: Option<String>,
+}
+
+fn load(cli: &Cli) -> Result<SignerService, String> {
+    let passphrase = std::env::var(&cli.passphrase_env)
+        .map_err(|_| format!("set {} to the passphrase of {}", cli.passphrase_env, cli.key))?;
+    let keystore = Keystore::open(&cli.keystore).map_err(|e| format!("cannot open {}: {}", cli.keystore.display(), e))?;
+    // Decrypted once; the signer runs unattended from here on.
+    let bytes = keystore.export(&cli.key, &passphrase).map_err(|e| format!("cannot unlock {}: {}", cli.key, e))?;
+    let keypair = Keypair::from_bytes(&bytes).map_err(|e| format!("{} is not a keypair: {}", cli.key, e))?;
+
+    let secret = match &cli.secret_file {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?,
+        None => std::env::var(&cli.secret_env).unwrap_or_default(),
+    };
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(format!("no secret: pass --secret-file or set {}", cli.secret_env));
+    }
+
+    let guard_path = cli.guard.clone().unwrap_or_else(|| cli.keystore.join(SIGN_GUARD_FILE));
+    let guard = SignGuard::open(&guard_path).map_err(|e| format!("cannot read {}: {}", guard_path.display(), e))?;
+    info!("Loaded validator key {}", keypair.pubkey());
+    Ok(SignerService::new(Arc::new(keypair), guard, secret.as_bytes()))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(filter) = &cli.log_level {
+        logger.parse_filters(filter);
+    }
+    logger.init();
+
+    let service = match load(&cli) {
+        Ok(service) => Arc::new(service),
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            let _ = tokio::signal::ctrl_c().await;
+            cancel.cancel();
+        }
+    });
+    match service.listen(&cli.listen, cancel).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Cannot serve on {}: {}", cli.listen, e);
+            ExitCode::from(EXIT_RUNTIME)
+        }
+    }
+}
@@ -0,0 +1,702 @@
+//! Synthetic load for testnets: funds throwaway accounts from a faucet key,
+//! sends transfers between them at a target rate, and times each to
+//! finality over the nodes' WebSocket subscriptions. Built with the
+//! `loadgen` feature.
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+use tokio::time::{interval, sleep, sleep_until, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use super::{fetch_nonce, ClientError, RpcClient, SignedTransaction, TxBuilder};
+use crate::node::rpc::{BlockResult, MEMPOOL_FULL, RATE_LIMITED, TRANSACTION_REJECTED};
+
+/// Times a transaction is sent before a full or rate-limited node counts
+/// as having failed it.
+const MAX_ATTEMPTS: u32 = 5;
+/// Pause after a refusal that names no `retry_after_ms`.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// How often the pending set is checked while waiting on finality.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("Client error: {0}")]
+    Client(#[from] ClientError),
+    #[error("WebSocket error on {url}: {reason}")]
+    WebSocket { url: String, reason: String },
+    #[error("Invalid load config: {0}")]
+    Config(String),
+    #[error("Funding was not finalized within {0:?}")]
+    Funding(Duration),
+}
+
+/// How each transaction's fee is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeDistribution {
+    Fixed(u64),
+    /// Uniform over `min..=max`.
+    Uniform { min: u64, max: u64 },
+}
+
+impl FeeDistribution {
+    fn sample(&self, rng: &mut impl Rng) -> u64 {
+        match *self {
+            FeeDistribution::Fixed(fee) => fee,
+            FeeDistribution::Uniform { min, max } => rng.gen_range(min..=max),
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        match *self {
+            FeeDistribution::Fixed(fee) => fee,
+            FeeDistribution::Uniform { max, .. } => max,
+        }
+    }
+}
+
+/// `fixed:N` or `uniform:MIN-MAX`.
+impl FromStr for FeeDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("fixed", fee)) => fee.parse().map(FeeDistribution::Fixed).map_err(|e| format!("bad fee {:?}: {}", fee, e)),
+            Some(("uniform", range)) => {
+                let (min, max) = parse_range(range)?;
+                Ok(FeeDistribution::Uniform { min, max })
+            }
+            _ => Err(format!("expected fixed:N or uniform:MIN-MAX, got {:?}", s)),
+        }
+    }
+}
+
+/// `N` or `MIN-MAX`, inclusive.
+pub fn parse_range<T: FromStr + PartialOrd + Copy>(s: &str) -> Result<(T, T), String>
+where
+    T::Err: std::fmt::Display,
+{
+    let parse = |part: &str| part.trim().parse::<T>().map_err(|e| format!("bad bound {:?}: {}", part, e));
+    let (min, max) = match s.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => {
+            let value = parse(s)?;
+            (value, value)
+        }
+    };
+    if min > max {
+        return Err(format!("range {:?} is empty", s));
+    }
+    Ok((min, max))
+}
+
+/// A plain number of seconds, or one suffixed with `s`, `m` or `h`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((at, unit @ ('s' | 'm' | 'h'))) => (&s[..at], unit),
+        _ => (s, 's'),
+    };
+    let value: u64 = number.parse().map_err(|e| format!("bad duration {:?}: {}", s, e))?;
+    let seconds = match unit {
+        'h' => value * 3600,
+        'm' => value * 60,
+        _ => value,
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// When submission stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadDuration {
+    Transactions(u64),
+    /// Soak mode: keep sending at the target rate for this long.
+    Soak(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadConfig {
+    /// RPC URLs, sent to in turn. Each is also subscribed to at `/ws`.
+    pub endpoints: Vec<String>,
+    pub chain_id: String,
+    pub accounts: usize,
+    /// Sent from the faucet to each account before the run.
+    pub funding: u64,
+    pub tps: f64,
+    pub duration: LoadDuration,
+    /// Payload bytes, drawn uniformly from `min..=max`.
+    pub payload_bytes: (usize, usize),
+    pub fees: FeeDistribution,
+    /// Finalized later than this after it was sent, a transaction counts
+    /// as timed out.
+    pub finality_timeout: Duration,
+    /// Submissions awaiting a response at once.
+    pub max_in_flight: usize,
+}
+
+impl LoadConfig {
+    pub fn new(endpoints: Vec<String>, chain_id: impl Into<String>) -> Self {
+        LoadConfig {
+            endpoints,
+            chain_id: chain_id.into(),
+            accounts: 10,
+            funding: 1_000_000,
+            tps: 10.0,
+            duration: LoadDuration::Transactions(100),
+            payload_bytes: (0, 0),
+            fees: FeeDistribution::Fixed(1),
+            finality_timeout: Duration::from_secs(30),
+            max_in_flight: 256,
+        }
+    }
+
+    pub fn with_accounts(mut self, accounts: usize) -> Self {
+        self.accounts = accounts;
+        self
+    }
+
+    pub fn with_funding(mut self, funding: u64) -> Self {
+        self.funding = funding;
+        self
+    }
+
+    pub fn with_tps(mut self, tps: f64) -> Self {
+        self.tps = tps;
+        self
+    }
+
+    pub fn with_duration(mut self, duration: LoadDuration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn with_payload_bytes(mut self, min: usize, max: usize) -> Self {
+        self.payload_bytes = (min, max);
+        self
+    }
+
+    pub fn with_fees(mut self, fees: FeeDistribution) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    pub fn with_finality_timeout(mut self, timeout: Duration) -> Self {
+        self.finality_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), LoadError> {
+        let invalid = |reason: &str| Err(LoadError::Config(reason.to_string()));
+        if self.endpoints.is_empty() {
+            return invalid("at least one endpoint is needed");
+        }
+        if self.accounts == 0 {
+            return invalid("at least one account is needed");
+        }
+        if !(self.tps.is_finite() && self.tps > 0.0) {
+            return invalid("tps must be positive");
+        }
+        if self.payload_bytes.0 > self.payload_bytes.1 {
+            return invalid("the payload range is empty");
+        }
+        if matches!(self.fees, FeeDistribution::Uniform { min, max } if min > max) {
+            return invalid("the fee range is empty");
+        }
+        if self.max_in_flight == 0 {
+            return invalid("max_in_flight must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// The WebSocket endpoint served next to the RPC at `rpc_url`.
+pub fn ws_url(rpc_url: &str) -> String {
+    let base = rpc_url.trim_end_matches('/');
+    let base = match base.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some(("http", rest)) => format!("ws://{}", rest),
+        _ => base.to_string(),
+    };
+    format!("{}/ws", base)
+}
+
+/// Milliseconds from submission to the finalized notification.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencySummary {
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+    pub mean_ms: f64,
+}
+
+impl LatencySummary {
+    /// Nearest-rank percentiles; all zero without samples.
+    pub fn from_samples(samples: &mut [u64]) -> Self {
+        if samples.is_empty() {
+            return LatencySummary::default();
+        }
+        samples.sort_unstable();
+        let rank = |percentile: usize| samples[(samples.len() * percentile).div_ceil(100).max(1) - 1];
+        LatencySummary {
+            p50_ms: rank(50),
+            p95_ms: rank(95),
+            p99_ms: rank(99),
+            max_ms: samples[samples.len() - 1],
+            mean_ms: samples.iter().sum::<u64>() as f64 / samples.len() as f64,
+        }
+    }
+}
+
+/// The outcome of a run. Every transaction attempted was submitted or
+/// failed, and every one submitted was finalized or timed out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoadReport {
+    pub attempted: u64,
+    /// Accepted by a node.
+    pub submitted: u64,
+    pub finalized: u64,
+    /// Refused for good, or still refused after `MAX_ATTEMPTS`.
+    pub failed: u64,
+    pub timed_out: u64,
+    /// Pauses taken because a node was full or rate limiting.
+    pub backoffs: u64,
+    pub target_tps: f64,
+    /// Submissions accepted per second of the submission phase.
+    pub achieved_tps: f64,
+    pub elapsed_ms: u64,
+    /// Over the transactions finalized.
+    pub latency: LatencySummary,
+    /// Failures and timeouts by kind.
+    pub errors: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watched {
+    sent: Instant,
+    /// Funding transactions are waited on but not reported.
+    measured: bool,
+}
+
+/// Transactions sent and not yet finalized, by hash, resolved by whichever
+/// subscription reports them first.
+struct Tracker {
+    timeout: Duration,
+    pending: Mutex<HashMap<String, Watched>>,
+    latencies: Mutex<Vec<u64>>,
+    timed_out: AtomicU64,
+}
+
+impl Tracker {
+    fn new(timeout: Duration) -> Self {
+        Tracker { timeout, pending: Mutex::new(HashMap::new()), latencies: Mutex::new(Vec::new()), timed_out: AtomicU64::new(0) }
+    }
+
+    /// Starts the clock on `hash`, again if it is sent again.
+    fn watch(&self, hash: &str, measured: bool) {
+        self.pending.lock().insert(hash.to_string(), Watched { sent: Instant::now(), measured });
+    }
+
+    fn unwatch(&self, hash: &str) {
+        self.pending.lock().remove(hash);
+    }
+
+    fn is_pending(&self, hash: &str) -> bool {
+        self.pending.lock().contains_key(hash)
+    }
+
+    fn finalized(&self, block: &BlockResult, at: Instant) {
+        let mut pending = self.pending.lock();
+        for hash in &block.transactions {
+            let Some(watched) = pending.remove(hash).filter(|watched| watched.measured) else { continue };
+            let latency = at.duration_since(watched.sent);
+            if latency > self.timeout {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.latencies.lock().push(latency.as_millis() as u64);
+            }
+        }
+    }
+
+    /// Drops what has waited past the timeout. Returns how much is left.
+    fn expire(&self, now: Instant) -> usize {
+        let mut pending = self.pending.lock();
+        pending.retain(|_, watched| {
+            let waiting = now.duration_since(watched.sent) <= self.timeout;
+            if !waiting && watched.measured {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+            }
+            waiting
+        });
+        pending.len()
+    }
+}
+
+/// Holds all submission back while a node asks for a pause.
+#[derive(Default)]
+struct Backoff {
+    until: Mutex<Option<Instant>>,
+    count: AtomicU64,
+}
+
+impl Backoff {
+    fn pause(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut current = self.until.lock();
+        if current.map_or(true, |current| current < until) {
+            *current = Some(until);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn wait(&self) {
+        let until = *self.until.lock();
+        if let Some(until) = until {
+            sleep_until(until).await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    attempted: AtomicU64,
+    submitted: AtomicU64,
+    failed: AtomicU64,
+    errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Counters {
+    fn fail(&self, kind: String) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        *self.errors.lock().entry(kind).or_default() += 1;
+    }
+}
+
+struct Account {
+    keypair: Keypair,
+    nonce: u64,
+}
+
+/// What a submission error is reported as.
+fn error_kind(e: &ClientError) -> String {
+    match e {
+        ClientError::Rpc(error) if error.code == MEMPOOL_FULL => "mempool_full".to_string(),
+        ClientError::Rpc(error) if error.code == RATE_LIMITED => "rate_limited".to_string(),
+        ClientError::Rpc(error) if error.code == TRANSACTION_REJECTED => "rejected".to_string(),
+        ClientError::Rpc(error) => format!("rpc_{}", error.code),
+        ClientError::Http(_) => "http".to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+/// How long to pause before sending again after `e`, if it is worth it.
+fn retry_after(e: &ClientError) -> Option<Duration> {
+    match e {
+        ClientError::Rpc(error) if error.code == MEMPOOL_FULL || error.code == RATE_LIMITED => {
+            let hint = error.data.as_ref().and_then(|data| data["retry_after_ms"].as_u64());
+            Some(hint.map_or(DEFAULT_BACKOFF, Duration::from_millis).min(MAX_BACKOFF))
+        }
+        _ => None,
+    }
+}
+
+/// Sends `signed`, pausing everyone when the node is full or rate limiting
+/// and then trying again, up to `MAX_ATTEMPTS` times.
+async fn submit(
+    signed: &SignedTransaction,
+    rpc: &RpcClient,
+    tracker: &Tracker,
+    backoff: &Backoff,
+    measured: bool,
+) -> Result<(), ClientError> {
+    let hash = signed.hash().to_string();
+    let mut attempt = 1;
+    loop {
+        backoff.wait().await;
+        tracker.watch(&hash, measured);
+        let error = match signed.submit(rpc).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        match retry_after(&error) {
+            Some(wait) if attempt < MAX_ATTEMPTS => {
+                debug!("{} refused {} ({}); pausing {:?}", rpc.url(), hash, error, wait);
+                backoff.pause(wait);
+                attempt += 1;
+            }
+            _ => {
+                tracker.unwatch(&hash);
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// Subscribes to finalized blocks at `url`, waiting for the subscription
+/// to be confirmed so none sent after is missed.
+async fn subscribe_finalized(url: &str) -> Result<Socket, LoadError> {
+    let failed = |reason: String| LoadError::WebSocket { url: url.to_string(), reason };
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.map_err(|e| failed(e.to_string()))?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "subscribe_finalized", "params": Value::Null });
+    socket.send(Message::Text(request.to_string())).await.map_err(|e| failed(e.to_string()))?;
+    while let Some(message) = socket.next().await {
+        let Message::Text(text) = message.map_err(|e| failed(e.to_string()))? else { continue };
+        let response: Value = serde_json::from_str(&text).map_err(|e| failed(e.to_string()))?;
+        if response["id"] == 1 {
+            return match response.get("error") {
+                Some(error) => Err(failed(error.to_string())),
+                None => Ok(socket),
+            };
+        }
+    }
+    Err(failed("closed before subscribing".to_string()))
+}
+
+async fn follow_finalized(url: String, mut socket: Socket, tracker: Arc<Tracker>, stop: CancellationToken) {
+    loop {
+        let message = tokio::select! {
+            _ = stop.cancelled() => return,
+            message = socket.next() => message,
+        };
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+                warn!("Subscription to {} failed: {}", url, e);
+                return;
+            }
+            None => {
+                warn!("Subscription to {} closed", url);
+                return;
+            }
+        };
+        let Ok(notification) = serde_json::from_str::<Value>(&text) else { continue };
+        let params = &notification["params"];
+        if let Some(missed) = params["missed"].as_u64() {
+            warn!("Subscription to {} missed {} blocks; their transactions will time out", url, missed);
+        } else if let Ok(block) = serde_json::from_value::<BlockResult>(params["result"].clone()) {
+            tracker.finalized(&block, Instant::now());
+        }
+    }
+}
+
+/// Sends the faucet's funding to fresh accounts and waits for it to be
+/// finalized.
+async fn fund(
+    config: &LoadConfig,
+    faucet: &Keypair,
+    rpc: &RpcClient,
+    tracker: &Tracker,
+    backoff: &Backoff,
+) -> Result<Vec<Account>, LoadError> {
+    let accounts: Vec<Keypair> = (0..config.accounts).map(|_| Keypair::new()).collect();
+    let mut nonce = fetch_nonce(rpc, &faucet.pubkey()).await?;
+    let mut hashes = Vec::with_capacity(accounts.len());
+    for account in &accounts {
+        let signed = TxBuilder::new(&config.chain_id)
+            .to(account.pubkey())
+            .amount(config.funding)
+            .fee(config.fees.max())
+            .nonce(nonce)
+            .sign(faucet)?;
+        submit(&signed, rpc, tracker, backoff, false).await?;
+        hashes.push(signed.hash().to_string());
+        nonce += 1;
+    }
+    let deadline = Instant::now() + config.finality_timeout;
+    while hashes.iter().any(|hash| tracker.is_pending(hash)) {
+        if Instant::now() >= deadline {
+            return Err(LoadError::Funding(config.finality_timeout));
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    info!("Funded {} accounts with {} each", accounts.len(), config.funding);
+    Ok(accounts.into_iter().map(|keypair| Account { keypair, nonce: 0 }).collect())
+}
+
+struct Transfer {
+    account: Arc<AsyncMutex<Account>>,
+    recipient: Pubkey,
+    fee: u64,
+    payload: Vec<u8>,
+}
+
+/// Sends one transfer. An account's transactions go one at a time, so its
+/// nonces reach the node in order and a refused one is reused.
+async fn send(transfer: Transfer, chain_id: String, rpc: RpcClient, tracker: Arc<Tracker>, backoff: Arc<Backoff>, counters: Arc<Counters>) {
+    let mut account = transfer.account.lock().await;
+    counters.attempted.fetch_add(1, Ordering::Relaxed);
+    let signed = TxBuilder::new(chain_id)
+        .to(transfer.recipient)
+        .amount(1)
+        .fee(transfer.fee)
+        .nonce(account.nonce)
+        .payload(transfer.payload)
+        .sign(&account.keypair);
+    let result = match signed {
+        Ok(signed) => submit(&signed, &rpc, &tracker, &backoff, true).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            account.nonce += 1;
+            counters.submitted.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            debug!("Transaction from {} failed: {}", account.keypair.pubkey(), e);
+            counters.fail(error_kind(&e));
+        }
+    }
+}
+
+/// Funds `config.accounts` accounts from `faucet`, runs the load, and
+/// waits out finality of what was submitted.
+pub async fn run(config: &LoadConfig, faucet: &Keypair) -> Result<LoadReport, LoadError> {
+    config.validate()?;
+    let clients: Vec<RpcClient> = config.endpoints.iter().map(RpcClient::new).collect();
+    let tracker = Arc::new(Tracker::new(config.finality_timeout));
+    let backoff = Arc::new(Backoff::default());
+    let stop = CancellationToken::new();
+    let _stop = stop.clone().drop_guard();
+    for endpoint in &config.endpoints {
+        let url = ws_url(endpoint);
+        let socket = subscribe_finalized(&url).await?;
+        tokio::spawn(follow_finalized(url, socket, Arc::clone(&tracker), stop.clone()));
+    }
+
+    let accounts: Vec<Arc<AsyncMutex<Account>>> = fund(config, faucet, &clients[0], &tracker, &backoff).await?
+        .into_iter()
+        .map(|account| Arc::new(AsyncMutex::new(account)))
+        .collect();
+    let mut recipients = Vec::with_capacity(accounts.len());
+    for account in &accounts {
+        recipients.push(account.lock().await.keypair.pubkey());
+    }
+    let backoffs_funding = backoff.count.load(Ordering::Relaxed);
+    let counters = Arc::new(Counters::default());
+    let in_flight = Arc::new(Semaphore::new(config.max_in_flight));
+    let mut ticker = interval(Duration::from_secs_f64(1.0 / config.tps));
+    // After a pause, carry on at the target rate rather than catching up.
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let started = Instant::now();
+    let done = |sent: u64| match config.duration {
+        LoadDuration::Transactions(count) => sent >= count,
+        LoadDuration::Soak(duration) => started.elapsed() >= duration,
+    };
+    let mut sent = 0u64;
+    while !done(sent) {
+        ticker.tick().await;
+        backoff.wait().await;
+        if done(sent) {
+            break;
+        }
+        let permit = Arc::clone(&in_flight).acquire_owned().await.expect("the semaphore is never closed");
+        let index = sent as usize;
+        let job = {
+            let mut rng = rand::thread_rng();
+            let mut recipient = rng.gen_range(0..recipients.len());
+            if recipient == index % accounts.len() && recipients.len() > 1 {
+                recipient = (recipient + 1) % recipients.len();
+            }
+            let size = rng.gen_range(config.payload_bytes.0..=config.payload_bytes.1);
+            Transfer {
+                account: Arc::clone(&accounts[index % accounts.len()]),
+                recipient: recipients[recipient],
+                fee: config.fees.sample(&mut rng),
+                payload: (0..size).map(|_| rng.gen()).collect(),
+            }
+        };
+        let task = send(
+            job,
+            config.chain_id.clone(),
+            clients[index % clients.len()].clone(),
+            Arc::clone(&tracker),
+            Arc::clone(&backoff),
+            Arc::clone(&counters),
+        );
+        tokio::spawn(async move {
+            task.await;
+            drop(permit);
+        });
+        sent += 1;
+    }
+    // Every permit back means every send has returned.
+    let _all = in_flight.acquire_many(config.max_in_flight as u32).await.expect("the semaphore is never closed");
+    let elapsed = started.elapsed();
+    info!("Sent {} transactions in {:?}; waiting on finality", sent, elapsed);
+    while tracker.expire(Instant::now()) > 0 {
+        sleep(POLL_INTERVAL).await;
+    }
+
+    let submitted = counters.submitted.load(Ordering::Relaxed);
+    let timed_out = tracker.timed_out.load(Ordering::Relaxed);
+    let mut latencies = std::mem::take(&mut *tracker.latencies.lock());
+    let mut errors = std::mem::take(&mut *counters.errors.lock());
+    if timed_out > 0 {
+        errors.insert("timeout".to_string(), timed_out);
+    }
+    Ok(LoadReport {
+        attempted: counters.attempted.load(Ordering::Relaxed),
+        submitted,
+        finalized: latencies.len() as u64,
+        failed: counters.failed.load(Ordering::Relaxed),
+        timed_out,
+        backoffs: backoff.count.load(Ordering::Relaxed) - backoffs_funding,
+        target_tps: config.tps,
+        achieved_tps: submitted as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        elapsed_ms: elapsed.as_millis() as u64,
+        latency: LatencySummary::from_samples(&mut latencies),
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cli_values() {
+        assert_eq!("fixed:3".parse(), Ok(FeeDistribution::Fixed(3)));
+        assert_eq!("uniform:1-10".parse(), Ok(FeeDistribution::Uniform { min: 1, max: 10 }));
+        assert!("uniform:10-1".parse::<FeeDistribution>().is_err());
+        assert_eq!(parse_range::<usize>("64"), Ok((64, 64)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(43_200)));
+        assert!(parse_duration("soon").is_err());
+        assert_eq!(ws_url("http://127.0.0.1:8001/"), "ws://127.0.0.1:8001/ws");
+        assert_eq!(ws_url("https://rpc.example"), "wss://rpc.example/ws");
+    }
+
+    #[test]
+    fn test_latency_percentiles_by_nearest_rank() {
+        let mut samples: Vec<u64> = (1..=200).rev().collect();
+        let summary = LatencySummary::from_samples(&mut samples);
+        assert_eq!((summary.p50_ms, summary.p95_ms, summary.p99_ms, summary.max_ms), (100, 190, 198, 200));
+        assert_eq!(summary.mean_ms, 100.5);
+        assert_eq!(LatencySummary::from_samples(&mut [7]).p99_ms, 7);
+        assert_eq!(LatencySummary::from_samples(&mut []), LatencySummary::default());
+    }
+}
@@ -0,0 +1,68 @@
+//! Building, signing and submitting node transactions from applications.
+//! Built with the `client` feature.
+
+#[cfg(feature = "loadgen")]
+pub mod loadgen;
+pub mod tx;
+
+pub use tx::{fetch_nonce, PendingTransaction, SignedTransaction, TxBuilder, TxSigner};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::node::keystore::KeystoreError;
+use crate::node::rpc::RpcError;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("RPC error: {0}")]
+    Rpc(RpcError),
+    #[error("Unexpected response: {0}")]
+    Response(String),
+    #[error("Keystore error: {0}")]
+    Keystore(#[from] KeystoreError),
+    #[error("Transaction is missing {0}")]
+    Incomplete(&'static str),
+    #[error("Transaction is from {from}, but the signer is {signer}")]
+    SignerMismatch { from: String, signer: String },
+    #[error("Transaction {0} was not finalized in time")]
+    Timeout(String),
+}
+
+/// A JSON-RPC client for one node. Clones share the connection pool.
+#[derive(Clone)]
+pub struct RpcClient {
+    url: String,
+    http: reqwest::Client,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RpcClient {
+    /// `url` is the node's RPC endpoint, e.g. `http://127.0.0.1:8001/`.
+    pub fn new(url: impl Into<String>) -> Self {
+        RpcClient { url: url.into(), http: reqwest::Client::new(), next_id: Arc::new(AtomicU64::new(1)) }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn call<T: DeserializeOwned>(&self, method: &str, params: impl Serialize) -> Result<T, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let mut response: Value = self.http.post(&self.url).json(&request).send().await?.json().await?;
+        if let Some(error) = response.get_mut("error") {
+            let error = serde_json::from_value(error.take()).map_err(|e| ClientError::Response(e.to_string()))?;
+            return Err(ClientError::Rpc(error));
+        }
+        let result = response.get_mut("result").map(Value::take)
+            .ok_or_else(|| ClientError::Response(format!("no result in {}", response)))?;
+        serde_json::from_value(result).map_err(|e| ClientError::Response(e.to_string()))
+    }
+}
@@ -0,0 +1,202 @@
+use borsh::BorshSerialize;
+use serde_json::json;
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::time::{Duration, Instant};
+
+use super::{ClientError, RpcClient};
+use crate::node::config::DEFAULT_CHAIN_ID;
+use crate::node::keystore::Keystore;
+use crate::node::rpc::{SimulationResult, TransactionResult};
+use crate::node::transaction::Transaction;
+use crate::utils::DADBSAddress;
+
+/// How often `wait_for_finality` asks the node.
+const FINALITY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Anything that can sign a transaction.
+pub trait TxSigner {
+    fn signer_pubkey(&self) -> Result<Pubkey, ClientError>;
+    fn sign_bytes(&self, message: &[u8]) -> Result<Signature, ClientError>;
+}
+
+impl TxSigner for Keypair {
+    fn signer_pubkey(&self) -> Result<Pubkey, ClientError> {
+        Ok(self.pubkey())
+    }
+
+    fn sign_bytes(&self, message: &[u8]) -> Result<Signature, ClientError> {
+        Ok(self.sign_message(message))
+    }
+}
+
+/// A named key in a keystore, which must be unlocked.
+impl TxSigner for (&Keystore, &str) {
+    fn signer_pubkey(&self) -> Result<Pubkey, ClientError> {
+        Ok(self.0.pubkey(self.1)?)
+    }
+
+    fn sign_bytes(&self, message: &[u8]) -> Result<Signature, ClientError> {
+        Ok(self.0.sign(self.1, message)?)
+    }
+}
+
+/// The nonce `address` must use next, as the node's state has it.
+/// Transactions still pending in its mempool are not counted.
+pub async fn fetch_nonce(rpc: &RpcClient, address: &Pubkey) -> Result<u64, ClientError> {
+    rpc.call("get_nonce", json!({ "address": DADBSAddress::from_pubkey(address).to_string() })).await
+}
+
+/// Assembles a transaction field by field. The sender defaults to the
+/// signer and the timestamp to the time of signing.
+#[derive(Debug, Clone)]
+pub struct TxBuilder {
+    chain_id: String,
+    from: Option<Pubkey>,
+    to: Option<Pubkey>,
+    amount: u64,
+    fee: u64,
+    nonce: Option<u64>,
+    payload: Vec<u8>,
+    timestamp: Option<i64>,
+}
+
+impl Default for TxBuilder {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHAIN_ID)
+    }
+}
+
+impl TxBuilder {
+    pub fn new(chain_id: impl Into<String>) -> Self {
+        TxBuilder {
+            chain_id: chain_id.into(),
+            from: None,
+            to: None,
+            amount: 0,
+            fee: 0,
+            nonce: None,
+            payload: Vec::new(),
+            timestamp: None,
+        }
+    }
+
+    pub fn from(mut self, from: Pubkey) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    pub fn to(mut self, to: Pubkey) -> Self {
+        self.to = Some(to);
+        self
+    }
+
+    pub fn amount(mut self, amount: u64) -> Self {
+        self.amount = amount;
+        self
+    }
+
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn payload(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets the nonce from the node; needs `from` to be set.
+    pub async fn fetch_nonce(self, rpc: &RpcClient) -> Result<Self, ClientError> {
+        let from = self.from.ok_or(ClientError::Incomplete("from"))?;
+        let nonce = fetch_nonce(rpc, &from).await?;
+        Ok(self.nonce(nonce))
+    }
+
+    /// Signs the canonical encoding with `signer`, which must be `from` if
+    /// that was set.
+    pub fn sign(self, signer: &impl TxSigner) -> Result<SignedTransaction, ClientError> {
+        let signer_key = signer.signer_pubkey()?;
+        let sender = self.from.unwrap_or(signer_key);
+        if sender != signer_key {
+            return Err(ClientError::SignerMismatch { from: sender.to_string(), signer: signer_key.to_string() });
+        }
+        let recipient = self.to.ok_or(ClientError::Incomplete("to"))?;
+        let nonce = self.nonce.ok_or(ClientError::Incomplete("nonce"))?;
+        let timestamp = self.timestamp.unwrap_or_else(|| chrono::Utc::now().timestamp_millis());
+        let mut transaction = Transaction::unsigned(sender, recipient, self.amount, self.fee, nonce, timestamp);
+        transaction.chain_id = self.chain_id;
+        transaction.payload = self.payload;
+        transaction.signature = signer.sign_bytes(&transaction.signing_bytes())?;
+        Ok(SignedTransaction(transaction))
+    }
+}
+
+/// A signed transaction, ready to be simulated or submitted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedTransaction(pub Transaction);
+
+impl SignedTransaction {
+    pub fn transaction(&self) -> &Transaction {
+        &self.0
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.0.hash()
+    }
+
+    /// Hex-encoded Borsh, as `send_transaction` takes it.
+    pub fn to_raw(&self) -> String {
+        hex::encode(self.0.try_to_vec().expect("transaction serialization cannot fail"))
+    }
+
+    /// Asks the node whether it would accept this transaction now.
+    pub async fn simulate(&self, rpc: &RpcClient) -> Result<SimulationResult, ClientError> {
+        rpc.call("simulate_transaction", json!({ "raw": self.to_raw() })).await
+    }
+
+    pub async fn submit(&self, rpc: &RpcClient) -> Result<PendingTransaction, ClientError> {
+        let hash: String = rpc.call("send_transaction", json!({ "raw": self.to_raw() })).await?;
+        Ok(PendingTransaction { hash, rpc: rpc.clone() })
+    }
+}
+
+/// A submitted transaction.
+pub struct PendingTransaction {
+    hash: String,
+    rpc: RpcClient,
+}
+
+impl PendingTransaction {
+    /// Base58 transaction hash.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Waits until the transaction is in a finalized block.
+    pub async fn wait_for_finality(&self, timeout: Duration) -> Result<TransactionResult, ClientError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let found: Option<TransactionResult> =
+                self.rpc.call("get_transaction", json!({ "hash": self.hash })).await?;
+            if let Some(found) = found {
+                return Ok(found);
+            }
+            if Instant::now() >= deadline {
+                return Err(ClientError::Timeout(self.hash.clone()));
+            }
+            tokio::time::sleep(FINALITY_POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,7 @@
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "llm")]
+pub mod llm;
+pub mod node;
+pub mod program;
+pub mod utils;
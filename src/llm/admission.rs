@@ -0,0 +1,370 @@
+use log::debug;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use super::model::LlmError;
+use crate::node::config::LLMConfig;
+use crate::node::control::ConsensusControl;
+
+/// About what a 7B model's f16 KV cache holds per token.
+pub const DEFAULT_BYTES_PER_TOKEN: u64 = 512 * 1024;
+pub const DEFAULT_CONSENSUS_CPU_SHARE: f64 = 0.25;
+pub const DEFAULT_MAX_WAITING: usize = 32;
+/// How often a waiting request looks again when nothing finishes, so it
+/// sees memory freed elsewhere and consensus pressure lifting.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// Recent completions the drain rate is measured over.
+const DRAIN_WINDOW: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdmissionConfig {
+    /// Memory a request holds per prompt or completion token, mostly KV
+    /// cache.
+    pub bytes_per_token: u64,
+    /// Share of the CPUs kept for consensus; LLM requests run one per
+    /// remaining CPU, and at least one at a time.
+    pub consensus_cpu_share: f64,
+    /// Requests waiting for capacity; more are refused with
+    /// `LlmError::BatchFull`.
+    pub max_waiting: usize,
+}
+
+impl AdmissionConfig {
+    pub fn new() -> Self {
+        AdmissionConfig {
+            bytes_per_token: DEFAULT_BYTES_PER_TOKEN,
+            consensus_cpu_share: DEFAULT_CONSENSUS_CPU_SHARE,
+            max_waiting: DEFAULT_MAX_WAITING,
+        }
+    }
+
+    pub fn with_bytes_per_token(mut self, bytes_per_token: u64) -> Self {
+        self.bytes_per_token = bytes_per_token;
+        self
+    }
+
+    pub fn with_consensus_cpu_share(mut self, consensus_cpu_share: f64) -> Self {
+        self.consensus_cpu_share = consensus_cpu_share;
+        self
+    }
+
+    pub fn with_max_waiting(mut self, max_waiting: usize) -> Self {
+        self.max_waiting = max_waiting;
+        self
+    }
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&LLMConfig> for AdmissionConfig {
+    fn from(config: &LLMConfig) -> Self {
+        AdmissionConfig::new()
+            .with_bytes_per_token(config.admission_bytes_per_token)
+            .with_consensus_cpu_share(config.consensus_cpu_share)
+            .with_max_waiting(config.admission_max_waiting)
+    }
+}
+
+/// What the node has to run requests with.
+pub trait ResourceProbe: Send + Sync {
+    /// Bytes free where the model keeps its caches, if known.
+    fn free_memory(&self) -> Option<u64>;
+    fn cpus(&self) -> usize;
+}
+
+/// The host's available memory from `/proc/meminfo`, and the CPUs this
+/// process may use. On a GPU the host memory stands in for the device's.
+pub struct SystemResources;
+
+impl ResourceProbe for SystemResources {
+    fn free_memory(&self) -> Option<u64> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let kib = meminfo.lines().find_map(|line| line.strip_prefix("MemAvailable:"))?;
+        kib.trim().trim_end_matches("kB").trim().parse::<u64>().ok().map(|kib| kib * 1024)
+    }
+
+    fn cpus(&self) -> usize {
+        std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+    }
+}
+
+/// What requests may hold together now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    /// Requests running at once.
+    pub slots: usize,
+    /// Bytes their estimates may add up to; unbounded when the probe
+    /// cannot tell.
+    pub memory: Option<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    /// What the running requests are estimated to hold.
+    reserved: u64,
+    /// Tickets waiting, oldest first; only the oldest is admitted.
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+    /// When recent requests finished.
+    finished: VecDeque<Instant>,
+}
+
+/// Lets a request into the inference queue only while the node has room
+/// for it: a CPU not kept for consensus, and memory for its prompt and
+/// completion. Others wait in order, up to `max_waiting`, and the rest are
+/// refused with a `retry_after_ms` from how fast requests have been
+/// finishing. Consensus can shrink both for a while through its
+/// `ConsensusControl`.
+pub struct AdmissionController {
+    config: AdmissionConfig,
+    probe: Arc<dyn ResourceProbe>,
+    consensus: Option<ConsensusControl>,
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+impl AdmissionController {
+    pub fn new(config: AdmissionConfig, probe: Arc<dyn ResourceProbe>) -> Self {
+        AdmissionController { config, probe, consensus: None, state: Mutex::new(State::default()), changed: Notify::new() }
+    }
+
+    /// Scales capacity by `control.llm_capacity_factor()`.
+    pub fn with_consensus(mut self, control: ConsensusControl) -> Self {
+        self.consensus = Some(control);
+        self
+    }
+
+    fn factor(&self) -> f64 {
+        self.consensus.as_ref().map_or(1.0, ConsensusControl::llm_capacity_factor)
+    }
+
+    pub fn capacity(&self) -> Capacity {
+        let factor = self.factor();
+        let cpus = (self.probe.cpus() as f64 * (1.0 - self.config.consensus_cpu_share)).floor().max(1.0);
+        Capacity {
+            slots: (cpus * factor).floor() as usize,
+            memory: self.probe.free_memory().map(|free| (free as f64 * factor) as u64),
+        }
+    }
+
+    pub fn running(&self) -> usize {
+        self.state.lock().running
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.state.lock().waiting.len()
+    }
+
+    /// Takes a place in line, or refuses with `LlmError::BatchFull` when
+    /// the line is full.
+    pub fn enqueue(self: &Arc<Self>) -> Result<Waiting, LlmError> {
+        let mut state = self.state.lock();
+        if state.waiting.len() >= self.config.max_waiting {
+            let retry_after_ms = retry_after(&state.finished, state.waiting.len());
+            debug!("Refusing LLM request: {} waiting, retry after {:?} ms", state.waiting.len(), retry_after_ms);
+            return Err(LlmError::BatchFull { retry_after_ms });
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        Ok(Waiting { controller: Arc::clone(self), ticket, admitted: false })
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut state = self.state.lock();
+        state.running -= 1;
+        state.reserved -= bytes;
+        if state.finished.len() == DRAIN_WINDOW {
+            state.finished.pop_front();
+        }
+        state.finished.push_back(Instant::now());
+        drop(state);
+        self.changed.notify_waiters();
+    }
+}
+
+/// How long until `waiting` requests have drained at the rate `finished`
+/// shows, if it shows one.
+fn retry_after(finished: &VecDeque<Instant>, waiting: usize) -> Option<u64> {
+    let (first, last) = (finished.front()?, finished.back()?);
+    let span = last.duration_since(*first).as_secs_f64();
+    if finished.len() < 2 || span <= 0.0 {
+        return None;
+    }
+    let per_request = span / (finished.len() - 1) as f64;
+    Some((per_request * (waiting + 1) as f64 * 1000.0).ceil() as u64)
+}
+
+/// A place in line for capacity.
+pub struct Waiting {
+    controller: Arc<AdmissionController>,
+    ticket: u64,
+    admitted: bool,
+}
+
+impl Waiting {
+    /// Waits until the request is first in line and fits. A request that
+    /// could not fit even with nothing else running fails with
+    /// `LlmError::InvalidParams`.
+    pub async fn admit(mut self, prompt_tokens: usize, max_tokens: usize) -> Result<AdmissionPermit, LlmError> {
+        let controller = Arc::clone(&self.controller);
+        let bytes = controller.config.bytes_per_token.saturating_mul((prompt_tokens + max_tokens) as u64);
+        loop {
+            let changed = controller.changed.notified();
+            {
+                let mut state = controller.state.lock();
+                if state.waiting.front() == Some(&self.ticket) {
+                    let capacity = controller.capacity();
+                    let memory_fits = capacity.memory.map_or(true, |memory| state.reserved + bytes <= memory);
+                    if state.running < capacity.slots && memory_fits {
+                        state.waiting.pop_front();
+                        state.running += 1;
+                        state.reserved += bytes;
+                        self.admitted = true;
+                        drop(state);
+                        controller.changed.notify_waiters();
+                        return Ok(AdmissionPermit { controller, bytes });
+                    }
+                    if state.running == 0 && !memory_fits && controller.factor() >= 1.0 {
+                        return Err(LlmError::InvalidParams(format!(
+                            "{} prompt and {} completion tokens need about {} bytes, more than the {} free",
+                            prompt_tokens,
+                            max_tokens,
+                            bytes,
+                            capacity.memory.unwrap_or_default()
+                        )));
+                    }
+                }
+            }
+            let _ = tokio::time::timeout(RECHECK_INTERVAL, changed).await;
+        }
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        self.controller.state.lock().waiting.retain(|&ticket| ticket != self.ticket);
+        self.controller.changed.notify_waiters();
+    }
+}
+
+/// Capacity held by a running request, given back when dropped.
+pub struct AdmissionPermit {
+    controller: Arc<AdmissionController>,
+    bytes: u64,
+}
+
+impl AdmissionPermit {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        self.controller.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    struct Synthetic {
+        free: AtomicU64,
+        cpus: usize,
+    }
+
+    impl ResourceProbe for Synthetic {
+        fn free_memory(&self) -> Option<u64> {
+            Some(self.free.load(Ordering::SeqCst))
+        }
+
+        fn cpus(&self) -> usize {
+            self.cpus
+        }
+    }
+
+    fn controller(cpus: usize, free: u64, config: AdmissionConfig) -> (Arc<AdmissionController>, Arc<Synthetic>) {
+        let probe = Arc::new(Synthetic { free: AtomicU64::new(free), cpus });
+        (Arc::new(AdmissionController::new(config, probe.clone())), probe)
+    }
+
+    #[tokio::test]
+    async fn test_requests_wait_for_memory_and_the_line_is_bounded() {
+        let config = AdmissionConfig::new().with_bytes_per_token(1).with_consensus_cpu_share(0.5).with_max_waiting(1);
+        let (admission, probe) = controller(4, 100, config);
+        assert_eq!(admission.capacity(), Capacity { slots: 2, memory: Some(100) });
+
+        let first = admission.enqueue().unwrap().admit(10, 10).await.unwrap();
+        // 20 + 90 bytes do not fit in 100, so the second waits.
+        let second = tokio::spawn(admission.enqueue().unwrap().admit(50, 40));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!second.is_finished());
+        assert_eq!((admission.running(), admission.waiting()), (1, 1));
+        // No request has finished yet, so there is no drain rate to go by.
+        assert!(matches!(admission.enqueue(), Err(LlmError::BatchFull { retry_after_ms: None })));
+
+        drop(first);
+        let second = second.await.unwrap().unwrap();
+        assert_eq!(second.bytes(), 90);
+        drop(second);
+        // Two requests have finished now, so the refusal says when to retry.
+        let blocked = admission.enqueue().unwrap();
+        assert!(matches!(admission.enqueue(), Err(LlmError::BatchFull { retry_after_ms: Some(_) })));
+        drop(blocked);
+        assert_eq!(admission.waiting(), 0);
+
+        // Memory freed elsewhere is noticed without a request finishing.
+        probe.free.store(10, Ordering::SeqCst);
+        let held = admission.enqueue().unwrap().admit(5, 0).await.unwrap();
+        let larger = tokio::spawn(admission.enqueue().unwrap().admit(8, 0));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!larger.is_finished());
+        probe.free.store(20, Ordering::SeqCst);
+        larger.await.unwrap().unwrap();
+        drop(held);
+
+        // Nothing running could make room for this one.
+        let (small, _) = controller(4, 10, config);
+        assert!(matches!(small.enqueue().unwrap().admit(8, 8).await, Err(LlmError::InvalidParams(_))));
+        assert_eq!(small.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_consensus_pressure_shrinks_capacity() {
+        let control = ConsensusControl::new();
+        let probe = Arc::new(Synthetic { free: AtomicU64::new(1000), cpus: 4 });
+        let admission = Arc::new(AdmissionController::new(AdmissionConfig::new().with_bytes_per_token(1), probe).with_consensus(control.clone()));
+        assert_eq!(admission.capacity(), Capacity { slots: 3, memory: Some(1000) });
+
+        control.shrink_llm_capacity(0.5, Duration::from_millis(150));
+        assert_eq!(admission.capacity(), Capacity { slots: 1, memory: Some(500) });
+        let _running = admission.enqueue().unwrap().admit(100, 100).await.unwrap();
+        let second = tokio::spawn(admission.enqueue().unwrap().admit(100, 100));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+        // Once the pressure lifts the second request runs beside the first.
+        let _second = tokio::time::timeout(Duration::from_secs(1), second).await.unwrap().unwrap().unwrap();
+        assert_eq!(admission.running(), 2);
+
+        // Under full pressure nothing more is admitted, but nothing is refused.
+        control.shrink_llm_capacity(0.0, Duration::from_millis(50));
+        assert_eq!(admission.capacity().slots, 0);
+        let paused = tokio::spawn(admission.enqueue().unwrap().admit(1000, 0));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!paused.is_finished());
+    }
+}
@@ -0,0 +1,203 @@
+use candle_transformers::models::llama::{Config, LlamaConfig};
+use log::warn;
+use serde::Deserialize;
+use std::path::Path;
+
+use super::model::LlmError;
+
+/// The file beside a safetensors model that gives its architecture.
+pub const ARCHITECTURE_FILE: &str = "config.json";
+
+fn default_rope_theta() -> f32 {
+    10_000.0
+}
+
+fn default_rms_norm_eps() -> f64 {
+    1e-5
+}
+
+/// The shape of a Llama checkpoint, read from the `config.json` Hugging
+/// Face ships beside it; other keys in the file are ignored.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Architecture {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    /// Fewer than `num_attention_heads` for grouped-query attention;
+    /// unset means as many.
+    #[serde(default)]
+    pub num_key_value_heads: Option<usize>,
+    pub vocab_size: usize,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    #[serde(default = "default_rms_norm_eps")]
+    pub rms_norm_eps: f64,
+}
+
+/// Llama 2 7B, what models without a `config.json` are taken to be.
+impl Default for Architecture {
+    fn default() -> Self {
+        Architecture {
+            hidden_size: 4096,
+            intermediate_size: 11008,
+            num_hidden_layers: 32,
+            num_attention_heads: 32,
+            num_key_value_heads: None,
+            vocab_size: 32000,
+            rope_theta: default_rope_theta(),
+            rms_norm_eps: default_rms_norm_eps(),
+        }
+    }
+}
+
+impl Architecture {
+    /// Reads and checks a `config.json`.
+    pub fn load(path: &Path) -> Result<Self, LlmError> {
+        let invalid = |reason: String| LlmError::ModelLoad { path: path.to_path_buf(), reason };
+        let bytes = std::fs::read(path).map_err(|e| invalid(e.to_string()))?;
+        let architecture: Architecture =
+            serde_json::from_slice(&bytes).map_err(|e| invalid(format!("not a Llama config: {}", e)))?;
+        architecture.validate().map_err(invalid)?;
+        Ok(architecture)
+    }
+
+    /// The architecture for the model at `model_path`: from `config_path`
+    /// if given, else the `config.json` beside the model, else the default.
+    pub fn for_model(model_path: &Path, config_path: Option<&Path>) -> Result<Self, LlmError> {
+        if let Some(path) = config_path {
+            return Self::load(path);
+        }
+        let beside = model_path.with_file_name(ARCHITECTURE_FILE);
+        if beside.is_file() {
+            return Self::load(&beside);
+        }
+        warn!("No {} beside {}; assuming Llama 2 7B", ARCHITECTURE_FILE, model_path.display());
+        Ok(Self::default())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let sizes = [
+            ("hidden_size", self.hidden_size),
+            ("intermediate_size", self.intermediate_size),
+            ("num_hidden_layers", self.num_hidden_layers),
+            ("num_attention_heads", self.num_attention_heads),
+            ("num_key_value_heads", self.kv_heads()),
+            ("vocab_size", self.vocab_size),
+        ];
+        if let Some((key, _)) = sizes.iter().find(|(_, size)| *size == 0) {
+            return Err(format!("{} must be at least 1", key));
+        }
+        if self.hidden_size % self.num_attention_heads != 0 {
+            return Err(format!(
+                "hidden_size {} is not a multiple of num_attention_heads {}",
+                self.hidden_size, self.num_attention_heads
+            ));
+        }
+        if self.num_attention_heads % self.kv_heads() != 0 {
+            return Err(format!(
+                "num_attention_heads {} is not a multiple of num_key_value_heads {}",
+                self.num_attention_heads,
+                self.kv_heads()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn kv_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    /// The candle config the model is built with.
+    pub(super) fn config(&self) -> Config {
+        LlamaConfig {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: Some(self.kv_heads()),
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+        }
+        .into_config(false)
+    }
+
+    /// Every weight candle's Llama loads with the shape this architecture
+    /// needs, embeddings first and then layer by layer.
+    pub(super) fn tensor_shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let (hidden, kv) = (self.hidden_size, self.kv_heads() * self.head_dim());
+        let mut shapes = vec![("model.embed_tokens.weight".to_string(), vec![self.vocab_size, hidden])];
+        for layer in 0..self.num_hidden_layers {
+            for (weight, shape) in [
+                ("self_attn.q_proj", vec![hidden, hidden]),
+                ("self_attn.k_proj", vec![kv, hidden]),
+                ("self_attn.v_proj", vec![kv, hidden]),
+                ("self_attn.o_proj", vec![hidden, hidden]),
+                ("mlp.gate_proj", vec![self.intermediate_size, hidden]),
+                ("mlp.up_proj", vec![self.intermediate_size, hidden]),
+                ("mlp.down_proj", vec![hidden, self.intermediate_size]),
+                ("input_layernorm", vec![hidden]),
+                ("post_attention_layernorm", vec![hidden]),
+            ] {
+                shapes.push((format!("model.layers.{}.{}.weight", layer, weight), shape));
+            }
+        }
+        shapes.push(("model.norm.weight".to_string(), vec![hidden]));
+        shapes.push(("lm_head.weight".to_string(), vec![self.vocab_size, hidden]));
+        shapes
+    }
+
+    /// Checks each weight `shape_of` finds in the model at `model_path`
+    /// against this architecture, naming the first that is missing or
+    /// shaped otherwise.
+    pub(super) fn check(&self, model_path: &Path, shape_of: impl Fn(&str) -> Option<Vec<usize>>) -> Result<(), LlmError> {
+        let mismatch = |reason: String| LlmError::ModelLoad { path: model_path.to_path_buf(), reason };
+        for (name, expected) in self.tensor_shapes() {
+            match shape_of(&name) {
+                None => return Err(mismatch(format!("missing tensor {}", name))),
+                Some(shape) if shape != expected => {
+                    return Err(mismatch(format!(
+                        "tensor {} is {:?}, but the architecture needs {:?}",
+                        name, shape, expected
+                    )));
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_json_parsed_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(ARCHITECTURE_FILE);
+        // TinyLlama's, trimmed of keys that are not read.
+        let tiny = r#"{"architectures": ["LlamaForCausalLM"], "hidden_size": 2048, "intermediate_size": 5632,
+            "num_attention_heads": 32, "num_hidden_layers": 22, "num_key_value_heads": 4, "vocab_size": 32000}"#;
+        std::fs::write(&path, tiny).unwrap();
+        let architecture = Architecture::load(&path).unwrap();
+        assert_eq!((architecture.kv_heads(), architecture.head_dim()), (4, 64));
+        assert_eq!(architecture.rope_theta, 10_000.0);
+        let shapes = architecture.tensor_shapes();
+        assert_eq!(shapes.len(), 3 + 22 * 9);
+        assert!(shapes.contains(&("model.layers.21.self_attn.k_proj.weight".to_string(), vec![256, 2048])));
+        assert_eq!(Architecture::for_model(&dir.path().join("model.safetensors"), None).unwrap(), architecture);
+        assert_eq!(Architecture::for_model(Path::new("elsewhere/model.safetensors"), None).unwrap(), Architecture::default());
+
+        std::fs::write(&path, tiny.replace("\"num_key_value_heads\": 4", "\"num_key_value_heads\": 5")).unwrap();
+        match Architecture::load(&path) {
+            Err(LlmError::ModelLoad { reason, .. }) => assert!(reason.contains("num_key_value_heads"), "{}", reason),
+            other => panic!("expected ModelLoad, got {:?}", other),
+        }
+    }
+}
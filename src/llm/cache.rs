@@ -0,0 +1,323 @@
+use futures::stream::{self, Stream, StreamExt};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::generate::{GenerateParams, TokenChunk};
+use super::model::LlmError;
+use super::usage::Usage;
+use crate::node::config::LLMConfig;
+
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Completions kept; the least recently used go first beyond it.
+    pub max_entries: usize,
+    /// How long a completion is served after it was generated.
+    pub ttl: Duration,
+}
+
+impl CacheConfig {
+    pub fn new(max_entries: usize) -> Self {
+        CacheConfig { max_entries, ttl: DEFAULT_CACHE_TTL }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+impl From<&LLMConfig> for CacheConfig {
+    fn from(config: &LLMConfig) -> Self {
+        CacheConfig::new(config.cache_entries).with_ttl(Duration::from_secs(config.cache_ttl_secs))
+    }
+}
+
+/// A digest of everything a reproducible completion depends on: the
+/// model, the prompt and the parameters that change what is sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    /// `None` unless `params` always give the same completion: greedy, or
+    /// sampled with a fixed seed.
+    pub fn new(model: &str, prompt: &str, params: &GenerateParams) -> Option<Self> {
+        let greedy = params.temperature <= 0.0;
+        if !greedy && params.seed.is_none() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        field(model.as_bytes());
+        field(prompt.as_bytes());
+        field(&(params.max_tokens as u64).to_le_bytes());
+        field(&params.repetition_penalty.to_bits().to_le_bytes());
+        field(format!("{:?}", params.truncation).as_bytes());
+        field(&params.logprobs.map_or(u64::MAX, |top| top as u64).to_le_bytes());
+        field(&(params.stop.len() as u64).to_le_bytes());
+        for stop in &params.stop {
+            field(stop.as_bytes());
+        }
+        if let Some(schema) = &params.response_format {
+            field(schema.source().to_string().as_bytes());
+        }
+        // Greedy decoding ignores the seed and the sampling filters.
+        if !greedy {
+            field(&params.temperature.to_bits().to_le_bytes());
+            field(&params.top_k.map_or(u64::MAX, |top_k| top_k as u64).to_le_bytes());
+            field(&params.top_p.map_or(u32::MAX, f32::to_bits).to_le_bytes());
+            field(&params.seed.unwrap_or_default().to_le_bytes());
+        }
+        Some(CacheKey(hasher.finalize().into()))
+    }
+}
+
+struct Entry {
+    chunks: Arc<Vec<TokenChunk>>,
+    inserted: Instant,
+    /// When it was last used, as a tick of `Entries.tick`.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<CacheKey, Entry>,
+    /// Keys by when they were last used, least recent first.
+    by_use: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.map.remove(key) {
+            self.by_use.remove(&entry.used);
+        }
+    }
+}
+
+/// Completions of reproducible requests, kept chunk by chunk so a
+/// streamed request replays with the chunking it was generated with.
+/// Consulted by an `InferenceQueue` before a request takes a place in the
+/// batch; `ModelManager` flushes it whenever the serving model changes.
+pub struct InferenceCache {
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+}
+
+impl InferenceCache {
+    pub fn new(config: CacheConfig) -> Self {
+        InferenceCache { config, entries: Mutex::new(Entries::default()) }
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    /// Entries held, expired ones included until they are next looked up
+    /// or evicted.
+    pub fn len(&self) -> usize {
+        self.entries.lock().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The chunks cached under `key`, unless they have expired.
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<Vec<TokenChunk>>> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &CacheKey, now: Instant) -> Option<Arc<Vec<TokenChunk>>> {
+        let mut entries = self.entries.lock();
+        let entries = &mut *entries;
+        let entry = entries.map.get(key)?;
+        if now.saturating_duration_since(entry.inserted) >= self.config.ttl {
+            entries.remove(key);
+            return None;
+        }
+        let chunks = Arc::clone(&entry.chunks);
+        entries.tick += 1;
+        let tick = entries.tick;
+        if let Some(entry) = entries.map.get_mut(key) {
+            entries.by_use.remove(&entry.used);
+            entry.used = tick;
+        }
+        entries.by_use.insert(tick, *key);
+        Some(chunks)
+    }
+
+    /// Caches a finished completion's chunks under `key`.
+    pub fn insert(&self, key: CacheKey, chunks: Vec<TokenChunk>) {
+        self.insert_at(key, chunks, Instant::now())
+    }
+
+    fn insert_at(&self, key: CacheKey, chunks: Vec<TokenChunk>, now: Instant) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        entries.remove(&key);
+        entries.tick += 1;
+        let used = entries.tick;
+        entries.map.insert(key, Entry { chunks: Arc::new(chunks), inserted: now, used });
+        entries.by_use.insert(used, key);
+        while entries.map.len() > self.config.max_entries {
+            match entries.by_use.pop_first() {
+                Some((_, oldest)) => {
+                    entries.map.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drops every entry, returning how many there were.
+    pub fn flush(&self) -> usize {
+        let mut entries = self.entries.lock();
+        let flushed = entries.map.len();
+        *entries = Entries::default();
+        flushed
+    }
+}
+
+/// `chunks` as a completion served from the cache: the same text and
+/// token counts, with `Usage.cached` set and no time spent.
+pub(super) fn replay(chunks: &[TokenChunk]) -> impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static {
+    let chunks: Vec<_> = chunks.iter()
+        .cloned()
+        .map(|mut chunk| {
+            if let Some(usage) = &mut chunk.usage {
+                let (prompt_tokens, completion_tokens) = (usage.prompt_tokens, usage.completion_tokens);
+                *usage = Usage { prompt_tokens, completion_tokens, cached: true, ..Usage::default() };
+            }
+            Ok(chunk)
+        })
+        .collect();
+    stream::iter(chunks)
+}
+
+/// Passes `stream` through, caching its chunks under `key` if it finishes.
+pub(super) fn record(
+    key: Option<(Arc<InferenceCache>, CacheKey)>,
+    stream: impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static,
+) -> impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static {
+    let mut chunks = Vec::new();
+    stream.inspect(move |chunk| {
+        if let (Some((cache, key)), Ok(chunk)) = (&key, chunk) {
+            chunks.push(chunk.clone());
+            if chunk.finish.is_some() {
+                cache.insert(*key, std::mem::take(&mut chunks));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{generate, KvCache, LightLLM, ModelBackend, QueueConfig};
+    use futures::TryStreamExt;
+
+    /// Counts up through the letters a to h, one token each.
+    struct Counting;
+
+    impl ModelBackend for Counting {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().filter(|byte| (b'a'..=b'h').contains(byte)).map(|byte| u32::from(byte - b'a')).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| char::from(b'a' + token as u8)).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(Vec::<u32>::new()))
+        }
+
+        fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            let seen = cache.state_mut::<Vec<u32>>().unwrap();
+            seen.extend_from_slice(tokens);
+            let mut logits = vec![0.0; 8];
+            logits[(*seen.last().unwrap() as usize + 1) % 8] = 10.0;
+            Ok(logits)
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn context_length(&self) -> usize {
+            64
+        }
+    }
+
+    fn key(prompt: &str) -> CacheKey {
+        CacheKey::new("test", prompt, &GenerateParams::new(1).with_temperature(0.0)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_repeated_requests_replay_from_the_cache() {
+        let model = LightLLM::with_backend(Arc::new(Counting));
+        let cache = Arc::new(InferenceCache::new(CacheConfig::new(8)));
+        let queue = model.start_queue(QueueConfig::new(2)).with_cache(Arc::clone(&cache), "test");
+        let greedy = GenerateParams::new(3).with_temperature(0.0);
+
+        let first: Vec<TokenChunk> = queue.generate_stream("ab", greedy.clone()).unwrap().try_collect().await.unwrap();
+        let replayed: Vec<TokenChunk> = queue.generate_stream("ab", greedy.clone()).unwrap().try_collect().await.unwrap();
+        assert_eq!(replayed.iter().map(|chunk| &chunk.text).collect::<Vec<_>>(), first.iter().map(|chunk| &chunk.text).collect::<Vec<_>>());
+        let (first, replayed) = (first.last().unwrap(), replayed.last().unwrap());
+        assert_eq!(replayed.finish, first.finish);
+        let (usage, cached) = (first.usage.unwrap(), replayed.usage.unwrap());
+        assert!(!usage.cached && cached.cached);
+        assert_eq!((cached.prompt_tokens, cached.completion_tokens), (usage.prompt_tokens, usage.completion_tokens));
+        assert_eq!(model.metrics().totals().requests, 1);
+
+        // Seeded sampling is cached per seed; unseeded sampling never is.
+        let seeded = GenerateParams::new(3).with_temperature(1.0).with_seed(5);
+        queue.generate("ab", seeded.clone()).await.unwrap();
+        queue.generate("ab", seeded.with_seed(6)).await.unwrap();
+        let completion = queue.generate("ab", GenerateParams::new(3).with_temperature(1.0)).await.unwrap();
+        assert!(!completion.usage.cached);
+        assert!(queue.generate("ab", GenerateParams::new(2).with_temperature(0.0)).await.is_ok());
+        assert_eq!(model.metrics().cache_totals(), (1, 4));
+        assert_eq!((cache.len(), model.metrics().totals().requests), (4, 5));
+
+        assert_eq!(cache.flush(), 4);
+        assert!(!generate::collect(queue.generate_stream("ab", GenerateParams::new(3).with_temperature(0.0)).unwrap()).await.unwrap().usage.cached);
+    }
+
+    #[test]
+    fn test_entries_expire_and_least_recently_used_are_evicted() {
+        let cache = InferenceCache::new(CacheConfig::new(2).with_ttl(Duration::from_secs(10)));
+        let now = Instant::now();
+        let (a, b, c) = (key("a"), key("b"), key("c"));
+        assert_ne!(a, b);
+        cache.insert_at(a, Vec::new(), now);
+        cache.insert_at(b, Vec::new(), now);
+        // Using `a` leaves `b` least recently used.
+        assert!(cache.get_at(&a, now + Duration::from_secs(1)).is_some());
+        cache.insert_at(c, Vec::new(), now + Duration::from_secs(2));
+        assert!(cache.get_at(&b, now + Duration::from_secs(2)).is_none());
+        assert!(cache.get_at(&c, now + Duration::from_secs(2)).is_some());
+
+        // Use does not extend the entry's life.
+        assert!(cache.get_at(&a, now + Duration::from_secs(9)).is_some());
+        assert!(cache.get_at(&a, now + Duration::from_secs(10)).is_none());
+        assert_eq!(cache.len(), 1);
+
+        assert_eq!(CacheKey::new("test", "a", &GenerateParams::new(1).with_temperature(0.0).with_seed(3)), Some(a));
+        assert_ne!(CacheKey::new("other", "a", &GenerateParams::new(1).with_temperature(0.0)), Some(a));
+    }
+}
@@ -0,0 +1,330 @@
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{timeout, Instant};
+use tokio_util::sync::CancellationToken;
+
+use super::manager::ModelManager;
+use super::model::{LightLLM, LlmError};
+use crate::node::network::{ChallengeResponse, InferenceChallenge, InferenceMessage, NetMessage, Network, PeerId, CAPABILITY_LLM};
+use crate::node::peer_score::Offense;
+
+pub const DEFAULT_CHALLENGE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_CHALLENGE_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_CHALLENGE_POSITIONS: usize = 4;
+/// Logprobs further apart than this disagree; enough for the same model
+/// on other hardware.
+pub const DEFAULT_LOGPROB_TOLERANCE: f32 = 1e-3;
+/// Words in the text a challenge seed stands for.
+const CHALLENGE_WORDS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChallengeConfig {
+    /// How long a challenged peer has to respond.
+    pub timeout: Duration,
+    /// The least time between two challenges to the same peer, whichever
+    /// way; challenges from a peer sooner than this are refused.
+    pub min_interval: Duration,
+    /// Logprobs asked for per challenge.
+    pub positions: usize,
+    pub tolerance: f32,
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        ChallengeConfig {
+            timeout: DEFAULT_CHALLENGE_TIMEOUT,
+            min_interval: DEFAULT_CHALLENGE_INTERVAL,
+            positions: DEFAULT_CHALLENGE_POSITIONS,
+            tolerance: DEFAULT_LOGPROB_TOLERANCE,
+        }
+    }
+}
+
+impl ChallengeConfig {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    pub fn with_positions(mut self, positions: usize) -> Self {
+        self.positions = positions.max(1);
+        self
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f32) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+}
+
+/// The model challenges are scored with, on both sides.
+#[async_trait]
+pub trait LocalScoring: Send + Sync {
+    async fn score(&self, prompt: &str, completion: &str) -> Result<Vec<f32>, LlmError>;
+}
+
+#[async_trait]
+impl LocalScoring for LightLLM {
+    async fn score(&self, prompt: &str, completion: &str) -> Result<Vec<f32>, LlmError> {
+        LightLLM::score(self, prompt, completion).await
+    }
+}
+
+/// Whichever model is serving at the time.
+#[async_trait]
+impl LocalScoring for ModelManager {
+    async fn score(&self, prompt: &str, completion: &str) -> Result<Vec<f32>, LlmError> {
+        let serving = self.serving().ok_or_else(|| LlmError::InferenceFailed("no model is serving".to_string()))?;
+        serving.model().score(prompt, completion).await
+    }
+}
+
+/// The text a challenge with `seed` scores: lowercase words drawn from
+/// sha256 of the seed, so every node derives the same.
+pub fn challenge_text(seed: u64) -> String {
+    let mut bytes = Vec::new();
+    let mut block = 0u64;
+    let mut next = || {
+        if bytes.is_empty() {
+            bytes = Sha256::new().chain_update(seed.to_le_bytes()).chain_update(block.to_le_bytes()).finalize().to_vec();
+            block += 1;
+        }
+        bytes.pop().expect("refilled above")
+    };
+    let words: Vec<String> = (0..CHALLENGE_WORDS)
+        .map(|_| {
+            let len = 2 + next() as usize % 5;
+            (0..len).map(|_| char::from(b'a' + next() % 26)).collect()
+        })
+        .collect();
+    words.join(" ")
+}
+
+/// A challenged peer's logprobs disagreeing with ours, kept as evidence.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChallengeEvidence {
+    pub peer: PeerId,
+    /// Hex sha256 of the prompt.
+    pub prompt_hash: String,
+    pub seed: u64,
+    pub positions: Vec<u32>,
+    /// Our logprobs at `positions`.
+    pub expected: Vec<f32>,
+    /// The peer's; fewer or more than `positions` when it sent such.
+    pub reported: Vec<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChallengeOutcome {
+    /// Every logprob agreed with ours.
+    Passed,
+    /// Recorded as evidence, and the peer penalized and no longer taken as
+    /// serving inference.
+    Mismatched(ChallengeEvidence),
+    Refused(String),
+    /// No response in time; the peer was penalized lightly.
+    TimedOut,
+}
+
+/// Spot-checks that LLM-capable peers run the model they advertise, by
+/// having them score a text only known once challenged and comparing
+/// their logprobs with ours. `run` must be running for responses to
+/// arrive, and answers the peers' own challenges.
+pub struct InferenceChallenger {
+    network: Arc<Network>,
+    local: Arc<dyn LocalScoring>,
+    config: ChallengeConfig,
+    next_id: AtomicU64,
+    /// Challenges awaiting responses, by id, with the peer challenged.
+    pending: Mutex<HashMap<u64, (PeerId, oneshot::Sender<ChallengeResponse>)>>,
+    /// When each peer was last challenged by us, and last challenged us.
+    sent: Mutex<HashMap<PeerId, Instant>>,
+    received: Mutex<HashMap<PeerId, Instant>>,
+    evidence: Mutex<Vec<ChallengeEvidence>>,
+}
+
+/// Takes a turn for `peer` if it has had none within `interval`.
+fn take_turn(turns: &Mutex<HashMap<PeerId, Instant>>, peer: PeerId, interval: Duration) -> bool {
+    let now = Instant::now();
+    let mut turns = turns.lock();
+    turns.retain(|_, last| now.duration_since(*last) < interval);
+    if turns.contains_key(&peer) {
+        return false;
+    }
+    turns.insert(peer, now);
+    true
+}
+
+impl InferenceChallenger {
+    pub fn new(network: Arc<Network>, local: Arc<dyn LocalScoring>, config: ChallengeConfig) -> Self {
+        InferenceChallenger {
+            network,
+            local,
+            config,
+            next_id: AtomicU64::new(rand::random()),
+            pending: Mutex::new(HashMap::new()),
+            sent: Mutex::new(HashMap::new()),
+            received: Mutex::new(HashMap::new()),
+            evidence: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn config(&self) -> ChallengeConfig {
+        self.config
+    }
+
+    /// Every mismatch seen so far, oldest first.
+    pub fn evidence(&self) -> Vec<ChallengeEvidence> {
+        self.evidence.lock().clone()
+    }
+
+    /// Hands responses to the challenges awaiting them and answers peers'
+    /// challenges with the local model, until `cancel` fires.
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let mut inbox = self.network.subscribe_inference();
+        loop {
+            let (peer, message) = tokio::select! {
+                _ = cancel.cancelled() => return,
+                received = inbox.recv() => match received {
+                    Ok(received) => received,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Missed {} inference messages", missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            match message {
+                InferenceMessage::Challenge(challenge) => {
+                    let challenger = Arc::clone(&self);
+                    tokio::spawn(async move { challenger.answer(peer, challenge).await });
+                }
+                InferenceMessage::ChallengeResponse(response) => self.deliver(peer, response),
+                // For the `InferenceRouter`.
+                InferenceMessage::Request(_) | InferenceMessage::Result(_) | InferenceMessage::Refused { .. } => {}
+            }
+        }
+    }
+
+    fn deliver(&self, peer: PeerId, response: ChallengeResponse) {
+        let mut pending = self.pending.lock();
+        match pending.get(&response.id) {
+            Some((challenged, _)) if *challenged == peer => {
+                let (_, responses) = pending.remove(&response.id).expect("looked up above");
+                let _ = responses.send(response);
+            }
+            _ => debug!("Dropping a response from {} to challenge {}, which is not awaited", peer, response.id),
+        }
+    }
+
+    async fn answer(&self, peer: PeerId, challenge: InferenceChallenge) {
+        let id = challenge.id;
+        let refuse = |reason: &str| ChallengeResponse { id, logprobs: Vec::new(), refused: Some(reason.to_string()) };
+        let response = if self.network.capabilities() & CAPABILITY_LLM == 0 {
+            refuse("this node does not serve inference")
+        } else if !take_turn(&self.received, peer, self.config.min_interval) {
+            refuse("challenged too often")
+        } else if Sha256::digest(challenge.prompt.as_bytes())[..] != challenge.prompt_hash[..] {
+            refuse("the prompt does not match its hash")
+        } else {
+            match self.local.score(&challenge.prompt, &challenge_text(challenge.seed)).await {
+                Ok(scores) => match challenge.positions.iter().map(|&i| scores.get(i as usize).map(|score| score.to_bits())).collect::<Option<Vec<u32>>>() {
+                    Some(logprobs) => ChallengeResponse { id, logprobs, refused: None },
+                    None => refuse("a position is past the scored tokens"),
+                },
+                Err(e) => refuse(&e.to_string()),
+            }
+        };
+        if let Err(e) = self.network.send(&peer, NetMessage::Inference(InferenceMessage::ChallengeResponse(response))).await {
+            debug!("Cannot answer challenge {} from {}: {}", id, peer, e);
+        }
+    }
+
+    /// Challenges `peer` to score a fresh text after `prompt`, at random
+    /// positions, and checks its logprobs against our own. A peer may be
+    /// challenged once per `config.min_interval`.
+    pub async fn challenge(&self, peer: PeerId, prompt: &str) -> Result<ChallengeOutcome, LlmError> {
+        if !take_turn(&self.sent, peer, self.config.min_interval) {
+            return Err(LlmError::InvalidParams(format!("{} was challenged under {:?} ago", peer, self.config.min_interval)));
+        }
+        let seed: u64 = rand::random();
+        let scores = self.local.score(prompt, &challenge_text(seed)).await?;
+        if scores.is_empty() {
+            return Err(LlmError::InvalidParams("the challenge text has no tokens to score".to_string()));
+        }
+        let mut positions: Vec<u32> = rand::seq::index::sample(&mut rand::thread_rng(), scores.len(), self.config.positions.min(scores.len()))
+            .into_iter()
+            .map(|i| i as u32)
+            .collect();
+        positions.sort_unstable();
+        let expected: Vec<f32> = positions.iter().map(|&i| scores[i as usize]).collect();
+
+        let prompt_hash: [u8; 32] = Sha256::digest(prompt.as_bytes()).into();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, response) = oneshot::channel();
+        self.pending.lock().insert(id, (peer, sender));
+        let challenge = InferenceChallenge { id, prompt_hash, prompt: prompt.to_string(), seed, positions: positions.clone() };
+        let sent = self.network.send(&peer, NetMessage::Inference(InferenceMessage::Challenge(challenge))).await;
+        let response = match sent {
+            Ok(()) => timeout(self.config.timeout, response).await,
+            Err(e) => {
+                self.pending.lock().remove(&id);
+                return Err(LlmError::InferenceFailed(format!("cannot challenge {}: {}", peer, e)));
+            }
+        };
+        self.pending.lock().remove(&id);
+
+        let response = match response {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) | Err(_) => {
+                info!("Peer {} did not answer challenge {} in time", peer, id);
+                self.network.report(&peer, Offense::UnansweredChallenge);
+                return Ok(ChallengeOutcome::TimedOut);
+            }
+        };
+        if let Some(reason) = response.refused {
+            debug!("{} refused challenge {}: {}", peer, id, reason);
+            return Ok(ChallengeOutcome::Refused(reason));
+        }
+        let reported: Vec<f32> = response.logprobs.into_iter().map(f32::from_bits).collect();
+        let agrees = reported.len() == expected.len()
+            && reported.iter().zip(&expected).all(|(reported, expected)| (reported - expected).abs() <= self.config.tolerance);
+        if agrees {
+            return Ok(ChallengeOutcome::Passed);
+        }
+        info!("Peer {} failed challenge {}", peer, id);
+        self.network.report(&peer, Offense::WrongResult);
+        self.network.revoke_llm(&peer);
+        let evidence = ChallengeEvidence { peer, prompt_hash: hex::encode(prompt_hash), seed, positions, expected, reported };
+        self.evidence.lock().push(evidence.clone());
+        Ok(ChallengeOutcome::Mismatched(evidence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_text_follows_from_the_seed() {
+        let text = challenge_text(7);
+        assert_eq!(text, challenge_text(7));
+        assert_ne!(text, challenge_text(8));
+        let words: Vec<&str> = text.split(' ').collect();
+        assert_eq!(words.len(), CHALLENGE_WORDS);
+        assert!(words.iter().all(|word| (2..=6).contains(&word.len()) && word.bytes().all(|byte| byte.is_ascii_lowercase())));
+    }
+}
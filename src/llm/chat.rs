@@ -0,0 +1,372 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use super::generate::TruncationPolicy;
+use super::model::{LlmError, ModelBackend};
+use crate::node::config::TemplateSpec;
+
+const LLAMA2_INST: (&str, &str) = ("[INST]", "[/INST]");
+const LLAMA2_SYS: (&str, &str) = ("<<SYS>>\n", "\n<</SYS>>\n\n");
+const CHATML_TURN: (&str, &str) = ("<|im_start|>", "<|im_end|>\n");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+impl ChatRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChatRole::System => "system",
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        ChatMessage { role, content: content.into() }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::Assistant, content)
+    }
+}
+
+/// A rendered prompt: text, and the special tokens between it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Piece {
+    /// The model's BOS token, if it has one.
+    Bos,
+    /// The model's EOS token, if it has one.
+    Eos,
+    Text(String),
+}
+
+/// How a conversation is laid out for a chat-tuned model, as
+/// `llm.prompt_template` selects.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PromptTemplate {
+    /// Llama 2 chat: each exchange `[INST]`-wrapped between BOS and EOS,
+    /// with any system message folded into the first.
+    #[default]
+    Llama2,
+    /// `<|im_start|>role` turns, ending on an open assistant turn.
+    ChatMl,
+    /// The messages' text one after another, a line apart.
+    Raw,
+    /// Text in which `{system}` becomes the system message and `{messages}`
+    /// the rest, each on a line of its own as `role: content`.
+    Custom(String),
+}
+
+impl PromptTemplate {
+    /// The template `spec` names, reading a custom one from its file.
+    pub fn from_spec(spec: &TemplateSpec) -> Result<Self, LlmError> {
+        match spec {
+            TemplateSpec::Llama2 => Ok(PromptTemplate::Llama2),
+            TemplateSpec::ChatMl => Ok(PromptTemplate::ChatMl),
+            TemplateSpec::Raw => Ok(PromptTemplate::Raw),
+            TemplateSpec::File(path) => Self::load(path),
+        }
+    }
+
+    /// A custom template from the file at `path`.
+    pub fn load(path: &Path) -> Result<Self, LlmError> {
+        let error = |reason: String| LlmError::TemplateLoad { path: path.to_path_buf(), reason };
+        let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        Self::custom(text).map_err(|e| error(e.to_string()))
+    }
+
+    pub fn custom(text: impl Into<String>) -> Result<Self, LlmError> {
+        let text = text.into();
+        if !text.contains("{messages}") {
+            return Err(LlmError::InvalidParams("a prompt template needs a {messages} placeholder".to_string()));
+        }
+        Ok(PromptTemplate::Custom(text))
+    }
+
+    /// `messages` laid out for the model, ready for its reply.
+    pub(super) fn render(&self, messages: &[ChatMessage]) -> Result<Vec<Piece>, LlmError> {
+        if messages.is_empty() {
+            return Err(LlmError::InvalidParams("a chat needs at least one message".to_string()));
+        }
+        match self {
+            PromptTemplate::Llama2 => llama2(messages),
+            PromptTemplate::ChatMl => {
+                let mut text = String::new();
+                for message in messages {
+                    text.push_str(&format!("{}{}\n{}{}", CHATML_TURN.0, message.role.as_str(), message.content, CHATML_TURN.1));
+                }
+                text.push_str(&format!("{}{}\n", CHATML_TURN.0, ChatRole::Assistant.as_str()));
+                Ok(vec![Piece::Bos, Piece::Text(text)])
+            }
+            PromptTemplate::Raw => {
+                let contents: Vec<&str> = messages.iter().map(|message| message.content.as_str()).collect();
+                Ok(vec![Piece::Bos, Piece::Text(contents.join("\n"))])
+            }
+            PromptTemplate::Custom(template) => {
+                let (system, rest) = split_system(messages)?;
+                let lines: String = rest.iter()
+                    .map(|message| format!("{}: {}\n", message.role.as_str(), message.content))
+                    .collect();
+                let text = template.replace("{system}", system.unwrap_or_default()).replace("{messages}", &lines);
+                Ok(vec![Piece::Bos, Piece::Text(text)])
+            }
+        }
+    }
+}
+
+/// A leading system message, and the messages after it; a system message
+/// anywhere else is refused.
+fn split_system(messages: &[ChatMessage]) -> Result<(Option<&str>, &[ChatMessage]), LlmError> {
+    let (system, rest) = match messages.split_first() {
+        Some((first, rest)) if first.role == ChatRole::System => (Some(first.content.as_str()), rest),
+        _ => (None, messages),
+    };
+    if rest.iter().any(|message| message.role == ChatRole::System) {
+        return Err(LlmError::InvalidParams("only the first message can be a system message".to_string()));
+    }
+    Ok((system, rest))
+}
+
+/// Meta's reference layout: every user message and the reply to it are
+/// one `<s>[INST] user [/INST] reply </s>`, and the last user message is
+/// left open.
+fn llama2(messages: &[ChatMessage]) -> Result<Vec<Piece>, LlmError> {
+    let (system, rest) = split_system(messages)?;
+    let mut turns: Vec<String> = rest.iter().map(|message| message.content.clone()).collect();
+    for (i, message) in rest.iter().enumerate() {
+        let expected = if i % 2 == 0 { ChatRole::User } else { ChatRole::Assistant };
+        if message.role != expected {
+            return Err(LlmError::InvalidParams(
+                "llama2 chats alternate user and assistant messages, starting with the user".to_string(),
+            ));
+        }
+    }
+    if rest.len() % 2 == 0 {
+        return Err(LlmError::InvalidParams("a llama2 chat must end with a user message".to_string()));
+    }
+    if let Some(system) = system {
+        turns[0] = format!("{}{}{}{}", LLAMA2_SYS.0, system, LLAMA2_SYS.1, turns[0]);
+    }
+    let (open, close) = LLAMA2_INST;
+    let mut pieces = Vec::new();
+    for exchange in turns.chunks(2) {
+        pieces.push(Piece::Bos);
+        match exchange {
+            [user, reply] => {
+                pieces.push(Piece::Text(format!("{} {} {} {} ", open, user.trim(), close, reply.trim())));
+                pieces.push(Piece::Eos);
+            }
+            [user] => pieces.push(Piece::Text(format!("{} {} {}", open, user.trim(), close))),
+            _ => unreachable!("chunks of two"),
+        }
+    }
+    Ok(pieces)
+}
+
+/// `pieces` as tokens: each text encoded on its own, as the reference
+/// implementations do, with the model's special tokens in between.
+pub(super) fn encode(backend: &dyn ModelBackend, pieces: &[Piece]) -> Result<Vec<u32>, LlmError> {
+    let bos = backend.bos_token();
+    let mut tokens = Vec::new();
+    for piece in pieces {
+        match piece {
+            Piece::Bos => tokens.extend(bos),
+            Piece::Eos => tokens.extend(backend.eos_token()),
+            Piece::Text(text) => {
+                let encoded = backend.encode(text)?;
+                let start = usize::from(bos.is_some() && encoded.first() == bos.as_ref());
+                tokens.extend_from_slice(&encoded[start..]);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// The conversation's tokens in at most `limit`, with the number dropped.
+/// Whole exchanges go from the front, after any system message, so the
+/// template stays intact; under `TruncationPolicy::Error`, or once only
+/// the last message is left, an overlong chat is refused.
+pub(super) fn fit(
+    backend: &dyn ModelBackend,
+    template: &PromptTemplate,
+    messages: &[ChatMessage],
+    limit: usize,
+    policy: TruncationPolicy,
+) -> Result<(Vec<u32>, usize), LlmError> {
+    let full = encode(backend, &template.render(messages)?)?;
+    let system = usize::from(messages.first().map_or(false, |first| first.role == ChatRole::System));
+    let mut kept: Vec<ChatMessage> = messages.to_vec();
+    let mut tokens = full.clone();
+    while tokens.len() > limit {
+        if policy == TruncationPolicy::Error || kept.len() <= system + 1 {
+            return Err(LlmError::PromptTooLong { tokens: tokens.len(), limit });
+        }
+        // A user message and its reply, so the roles still alternate.
+        let dropped = (kept.len() - system - 1).min(2);
+        kept.drain(system..system + dropped);
+        tokens = encode(backend, &template.render(&kept)?)?;
+    }
+    let truncated = full.len() - tokens.len();
+    Ok((tokens, truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::KvCache;
+
+    const BOS: u32 = 1;
+    const EOS: u32 = 2;
+
+    /// One token per byte, after a BOS token.
+    struct Bytes;
+
+    impl ModelBackend for Bytes {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok([BOS].into_iter().chain(text.bytes().map(|byte| byte as u32 + 3)).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().filter(|&&token| token > 2).map(|&token| (token - 3) as u8 as char).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            Ok(vec![0.0; 259])
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            Some(BOS)
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(EOS)
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    /// The tokens of a prompt written out by hand, spelled out byte by
+    /// byte without going through `encode`.
+    fn expected(fixture: &[Piece]) -> Vec<u32> {
+        fixture.iter()
+            .flat_map(|piece| match piece {
+                Piece::Bos => vec![BOS],
+                Piece::Eos => vec![EOS],
+                Piece::Text(text) => text.bytes().map(|byte| byte as u32 + 3).collect(),
+            })
+            .collect()
+    }
+
+    fn text(text: &str) -> Piece {
+        Piece::Text(text.to_string())
+    }
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::system("Be brief."),
+            ChatMessage::user("Hi there "),
+            ChatMessage::assistant("Hello!"),
+            ChatMessage::user("What is DADBS?"),
+        ]
+    }
+
+    fn rendered(template: &PromptTemplate, messages: &[ChatMessage]) -> Vec<u32> {
+        encode(&Bytes, &template.render(messages).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_built_in_templates_render_known_token_sequences() {
+        assert_eq!(rendered(&PromptTemplate::Llama2, &conversation()), expected(&[
+            Piece::Bos,
+            text("[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi there [/INST] Hello! "),
+            Piece::Eos,
+            Piece::Bos,
+            text("[INST] What is DADBS? [/INST]"),
+        ]));
+        assert_eq!(
+            rendered(&PromptTemplate::Llama2, &[ChatMessage::user("Hi")]),
+            expected(&[Piece::Bos, text("[INST] Hi [/INST]")]),
+        );
+        assert_eq!(rendered(&PromptTemplate::ChatMl, &conversation()), expected(&[
+            Piece::Bos,
+            text(
+                "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi there <|im_end|>\n\
+                 <|im_start|>assistant\nHello!<|im_end|>\n<|im_start|>user\nWhat is DADBS?<|im_end|>\n\
+                 <|im_start|>assistant\n",
+            ),
+        ]));
+        assert_eq!(
+            rendered(&PromptTemplate::Raw, &conversation()),
+            expected(&[Piece::Bos, text("Be brief.\nHi there \nHello!\nWhat is DADBS?")]),
+        );
+        let custom = PromptTemplate::custom("### {system}\n{messages}assistant:").unwrap();
+        assert_eq!(rendered(&custom, &conversation()), expected(&[
+            Piece::Bos,
+            text("### Be brief.\nuser: Hi there \nassistant: Hello!\nuser: What is DADBS?\nassistant:"),
+        ]));
+    }
+
+    #[test]
+    fn test_llama2_refuses_chats_out_of_turn() {
+        let out_of_turn = [
+            vec![],
+            vec![ChatMessage::assistant("Hello!")],
+            vec![ChatMessage::user("Hi"), ChatMessage::assistant("Hello!")],
+            vec![ChatMessage::user("Hi"), ChatMessage::user("Anyone?")],
+            vec![ChatMessage::user("Hi"), ChatMessage::system("Be brief."), ChatMessage::user("Anyone?")],
+        ];
+        for messages in out_of_turn {
+            assert!(matches!(PromptTemplate::Llama2.render(&messages), Err(LlmError::InvalidParams(_))), "{:?}", messages);
+        }
+        assert!(matches!(PromptTemplate::custom("{system} only"), Err(LlmError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn test_budget_counts_template_tokens_and_drops_whole_exchanges() {
+        let messages = conversation();
+        let full = rendered(&PromptTemplate::Llama2, &messages);
+        let (tokens, truncated) = fit(&Bytes, &PromptTemplate::Llama2, &messages, full.len(), TruncationPolicy::Error).unwrap();
+        assert_eq!((tokens, truncated), (full.clone(), 0));
+
+        // The text alone would fit; the markers around it do not.
+        let text: usize = messages.iter().map(|message| message.content.len()).sum();
+        let refused = fit(&Bytes, &PromptTemplate::Llama2, &messages, text + 8, TruncationPolicy::Error);
+        assert_eq!(refused, Err(LlmError::PromptTooLong { tokens: full.len(), limit: text + 8 }));
+
+        let kept = [ChatMessage::system("Be brief."), ChatMessage::user("What is DADBS?")];
+        let shorter = rendered(&PromptTemplate::Llama2, &kept);
+        let (tokens, truncated) =
+            fit(&Bytes, &PromptTemplate::Llama2, &messages, full.len() - 1, TruncationPolicy::TruncateStart).unwrap();
+        assert_eq!((tokens, truncated), (shorter.clone(), full.len() - shorter.len()));
+        let refused = fit(&Bytes, &PromptTemplate::Llama2, &messages, shorter.len() - 1, TruncationPolicy::TruncateStart);
+        assert!(matches!(refused, Err(LlmError::PromptTooLong { .. })));
+    }
+}
@@ -0,0 +1,185 @@
+use candle_core::{Device, Tensor};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::dataset::DatasetCursor;
+use super::model::LlmError;
+use super::train::OptimizerKind;
+
+pub const CHECKPOINT_VERSION: u32 = 1;
+pub const DEFAULT_KEEP_LAST: usize = 3;
+const MANIFEST_FILE: &str = "manifest.json";
+const WEIGHTS_FILE: &str = "model.safetensors";
+const OPTIMIZER_FILE: &str = "optimizer.safetensors";
+/// Periodic checkpoints are `step-` and the zero-padded step, so they
+/// sort by step.
+const STEP_PREFIX: &str = "step-";
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Where and how often a trainer checkpoints itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    pub dir: PathBuf,
+    pub checkpoint_every_n_steps: u64,
+    /// Checkpoints kept; the oldest go once a new one is written.
+    pub keep_last: usize,
+}
+
+impl CheckpointConfig {
+    pub fn new(dir: impl Into<PathBuf>, checkpoint_every_n_steps: u64) -> Self {
+        CheckpointConfig {
+            dir: dir.into(),
+            checkpoint_every_n_steps: checkpoint_every_n_steps.max(1),
+            keep_last: DEFAULT_KEEP_LAST,
+        }
+    }
+
+    pub fn with_keep_last(mut self, keep_last: usize) -> Self {
+        self.keep_last = keep_last.max(1);
+        self
+    }
+
+    /// The checkpoint written after `step` steps.
+    pub fn path_for(&self, step: u64) -> PathBuf {
+        self.dir.join(format!("{}{:08}", STEP_PREFIX, step))
+    }
+
+    /// The newest complete checkpoint in `dir`, to resume a crashed run
+    /// from.
+    pub fn latest(&self) -> Option<PathBuf> {
+        written(&self.dir).ok()?.pop()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    step: u64,
+    seed: u64,
+    optimizer: OptimizerKind,
+    learning_rate: f64,
+    /// Where `DistributedTrainer::train_on` had read its dataset to.
+    #[serde(default)]
+    dataset: Option<DatasetCursor>,
+    /// sha256 of each file beside the manifest, by name.
+    files: BTreeMap<String, String>,
+}
+
+/// Everything a trainer needs to carry on where it left off. The weights
+/// are copies, so writing them out can overlap with training.
+pub(super) struct Snapshot {
+    pub(super) step: u64,
+    pub(super) seed: u64,
+    pub(super) optimizer: OptimizerKind,
+    pub(super) learning_rate: f64,
+    pub(super) dataset: Option<DatasetCursor>,
+    pub(super) weights: HashMap<String, Tensor>,
+    pub(super) optimizer_state: HashMap<String, Tensor>,
+}
+
+fn checkpoint_error(path: &Path, e: impl Display) -> LlmError {
+    LlmError::Checkpoint { path: path.to_path_buf(), reason: e.to_string() }
+}
+
+fn sha256(path: &Path) -> Result<String, LlmError> {
+    let bytes = fs::read(path).map_err(|e| checkpoint_error(path, e))?;
+    Ok(hex::encode(Sha256::digest(bytes)))
+}
+
+fn partial(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}{}", name, PARTIAL_SUFFIX))
+}
+
+/// Writes `snapshot` to a directory beside `path` and renames it into
+/// place, so `path` holds a whole checkpoint or none.
+pub(super) fn write(path: &Path, snapshot: &Snapshot) -> Result<(), LlmError> {
+    let staging = partial(path);
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(|e| checkpoint_error(&staging, e))?;
+    }
+    fs::create_dir_all(&staging).map_err(|e| checkpoint_error(&staging, e))?;
+    let mut files = BTreeMap::new();
+    for (name, tensors) in [(WEIGHTS_FILE, &snapshot.weights), (OPTIMIZER_FILE, &snapshot.optimizer_state)] {
+        let file = staging.join(name);
+        candle_core::safetensors::save(tensors, &file).map_err(|e| checkpoint_error(&file, e))?;
+        files.insert(name.to_string(), sha256(&file)?);
+    }
+    let manifest = Manifest {
+        version: CHECKPOINT_VERSION,
+        step: snapshot.step,
+        seed: snapshot.seed,
+        optimizer: snapshot.optimizer,
+        learning_rate: snapshot.learning_rate,
+        dataset: snapshot.dataset.clone(),
+        files,
+    };
+    let manifest_path = staging.join(MANIFEST_FILE);
+    let json = serde_json::to_vec_pretty(&manifest).map_err(|e| checkpoint_error(&manifest_path, e))?;
+    fs::write(&manifest_path, json).map_err(|e| checkpoint_error(&manifest_path, e))?;
+
+    if path.exists() {
+        fs::remove_dir_all(path).map_err(|e| checkpoint_error(path, e))?;
+    }
+    fs::rename(&staging, path).map_err(|e| checkpoint_error(path, e))
+}
+
+/// Reads the checkpoint at `path` onto `device`, refusing any file whose
+/// hash is not the manifest's.
+pub(super) fn read(path: &Path, device: &Device) -> Result<Snapshot, LlmError> {
+    let manifest_path = path.join(MANIFEST_FILE);
+    let bytes = fs::read(&manifest_path).map_err(|e| checkpoint_error(&manifest_path, e))?;
+    let manifest: Manifest = serde_json::from_slice(&bytes).map_err(|e| checkpoint_error(&manifest_path, e))?;
+    if manifest.version != CHECKPOINT_VERSION {
+        return Err(checkpoint_error(&manifest_path, format!("unsupported version {}", manifest.version)));
+    }
+    let load = |name: &str| {
+        let file = path.join(name);
+        let expected = manifest.files.get(name)
+            .ok_or_else(|| checkpoint_error(&manifest_path, format!("no checksum for {}", name)))?;
+        let actual = sha256(&file)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(LlmError::ChecksumMismatch { path: file, expected: expected.clone(), actual });
+        }
+        candle_core::safetensors::load(&file, device).map_err(|e| checkpoint_error(&file, e))
+    };
+    let weights = load(WEIGHTS_FILE)?;
+    let optimizer_state = load(OPTIMIZER_FILE)?;
+    Ok(Snapshot {
+        step: manifest.step,
+        seed: manifest.seed,
+        optimizer: manifest.optimizer,
+        learning_rate: manifest.learning_rate,
+        dataset: manifest.dataset,
+        weights,
+        optimizer_state,
+    })
+}
+
+/// The complete periodic checkpoints in `dir`, oldest first.
+fn written(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut checkpoints: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.starts_with(STEP_PREFIX) && !name.ends_with(PARTIAL_SUFFIX) && path.is_dir()
+        })
+        .collect();
+    checkpoints.sort();
+    Ok(checkpoints)
+}
+
+/// Deletes all but the newest `config.keep_last` periodic checkpoints.
+pub(super) fn rotate(config: &CheckpointConfig) -> Result<(), LlmError> {
+    let checkpoints = written(&config.dir).map_err(|e| checkpoint_error(&config.dir, e))?;
+    let excess = checkpoints.len().saturating_sub(config.keep_last);
+    for old in &checkpoints[..excess] {
+        fs::remove_dir_all(old).map_err(|e| checkpoint_error(old, e))?;
+    }
+    Ok(())
+}
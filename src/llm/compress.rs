@@ -0,0 +1,118 @@
+use half::{bf16, f16};
+
+use crate::node::network::{GradientCompression, GradientPrecision};
+
+/// Gradients per all-reduce bucket: 4 MiB of them at `f32`.
+pub const DEFAULT_BUCKET_SIZE: usize = 1 << 20;
+
+/// A bucket as it goes on the wire.
+pub(super) struct Packed {
+    pub(super) indices: Vec<u32>,
+    pub(super) values: Vec<u8>,
+    /// The bucket as its receivers will unpack it, so the sender can
+    /// average exactly what they do.
+    pub(super) unpacked: Vec<f32>,
+}
+
+fn encode(precision: GradientPrecision, value: f32, out: &mut Vec<u8>) {
+    match precision {
+        GradientPrecision::F32 => out.extend(value.to_le_bytes()),
+        GradientPrecision::F16 => out.extend(f16::from_f32(value).to_bits().to_le_bytes()),
+        GradientPrecision::Bf16 => out.extend(bf16::from_f32(value).to_bits().to_le_bytes()),
+    }
+}
+
+fn decode(precision: GradientPrecision, bytes: &[u8]) -> f32 {
+    match precision {
+        GradientPrecision::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        GradientPrecision::F16 => f16::from_bits(u16::from_le_bytes([bytes[0], bytes[1]])).to_f32(),
+        GradientPrecision::Bf16 => bf16::from_bits(u16::from_le_bytes([bytes[0], bytes[1]])).to_f32(),
+    }
+}
+
+/// Packs `gradients` plus what earlier steps failed to send of them, and
+/// leaves in `residual` what this one fails to: the values top-k drops,
+/// and the rounding of those it keeps.
+pub(super) fn pack(compression: GradientCompression, gradients: &[f32], residual: &mut [f32]) -> Packed {
+    let corrected: Vec<f32> = gradients.iter().zip(residual.iter()).map(|(gradient, carried)| gradient + carried).collect();
+    let kept: Vec<usize> = match compression.top_k() {
+        Some(fraction) if !corrected.is_empty() => {
+            let k = ((fraction * corrected.len() as f32).ceil() as usize).clamp(1, corrected.len());
+            let mut order: Vec<usize> = (0..corrected.len()).collect();
+            // Ties go to the lower index, so every replica keeps the same.
+            order.sort_by(|&a, &b| corrected[b].abs().total_cmp(&corrected[a].abs()).then(a.cmp(&b)));
+            order.truncate(k);
+            order.sort_unstable();
+            order
+        }
+        _ => (0..corrected.len()).collect(),
+    };
+    let precision = compression.precision;
+    let mut values = Vec::with_capacity(kept.len() * precision.width());
+    let mut unpacked = vec![0.0; corrected.len()];
+    for &i in &kept {
+        let start = values.len();
+        encode(precision, corrected[i], &mut values);
+        unpacked[i] = decode(precision, &values[start..]);
+    }
+    for ((carried, corrected), unpacked) in residual.iter_mut().zip(&corrected).zip(&unpacked) {
+        *carried = corrected - unpacked;
+    }
+    let indices = if compression.top_k().is_some() { kept.into_iter().map(|i| i as u32).collect() } else { Vec::new() };
+    Packed { indices, values, unpacked }
+}
+
+/// A bucket of `len` gradients from its wire form, or `None` if the
+/// payload does not fit it.
+pub(super) fn unpack(compression: GradientCompression, len: usize, indices: &[u32], values: &[u8]) -> Option<Vec<f32>> {
+    let width = compression.precision.width();
+    if values.len() % width != 0 {
+        return None;
+    }
+    let values = values.chunks_exact(width).map(|bytes| decode(compression.precision, bytes));
+    if compression.top_k().is_none() {
+        return (values.len() == len).then(|| values.collect());
+    }
+    if values.len() != indices.len() || indices.iter().any(|&i| i as usize >= len) {
+        return None;
+    }
+    let mut unpacked = vec![0.0; len];
+    for (&i, value) in indices.iter().zip(values) {
+        unpacked[i as usize] = value;
+    }
+    Some(unpacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_roundtrip_and_error_feedback() {
+        let gradients = [0.1, -3.0, 0.25, 2.0, -0.01];
+        let dense = GradientCompression::new(GradientPrecision::F16);
+        let mut residual = vec![0.0; 5];
+        let packed = pack(dense, &gradients, &mut residual);
+        assert_eq!((packed.values.len(), packed.indices.len()), (10, 0));
+        assert_eq!(unpack(dense, 5, &packed.indices, &packed.values), Some(packed.unpacked.clone()));
+        // 0.1 is not a half, and the difference is carried to the next step.
+        assert_ne!(packed.unpacked[0], 0.1);
+        assert_eq!(packed.unpacked[0] + residual[0], 0.1);
+
+        let sparse = GradientCompression::new(GradientPrecision::F32).with_top_k(0.4);
+        let mut residual = vec![0.0; 5];
+        let packed = pack(sparse, &gradients, &mut residual);
+        assert_eq!(packed.indices, [1, 3]);
+        assert_eq!(packed.unpacked, [0.0, -3.0, 0.0, 2.0, 0.0]);
+        assert_eq!(residual, [0.1, 0.0, 0.25, 0.0, -0.01]);
+        assert_eq!(unpack(sparse, 5, &packed.indices, &packed.values), Some(packed.unpacked));
+        // What was dropped builds up until it is sent.
+        let packed = pack(sparse, &[0.2, 0.0, 0.0, 0.0, 0.0], &mut residual);
+        assert_eq!(packed.indices, [0, 2]);
+        assert_eq!(packed.unpacked[0], 0.1 + 0.2);
+
+        assert_eq!(unpack(sparse, 5, &[7, 1], &packed.values), None);
+        assert_eq!(unpack(dense, 5, &[], &[0; 9]), None);
+        assert_eq!(unpack(dense, 4, &[], &[0; 10]), None);
+    }
+}
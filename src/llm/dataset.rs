@@ -0,0 +1,391 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::model::{LlmError, ModelBackend};
+
+/// Where a `Dataset` has read to, as a checkpoint records it. Resuming
+/// from it gives the same sequences as carrying on would have, as long as
+/// the directory holds the same files.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct DatasetCursor {
+    pub epoch: u64,
+    /// Index of the file being read, in name order.
+    pub file: usize,
+    /// Byte offset in it of the next document.
+    pub offset: u64,
+    /// Documents read this epoch on every shard, which decides the shard
+    /// the next one belongs to.
+    pub documents: u64,
+    /// Tokens read but not yet packed into a sequence.
+    pub pending: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatasetConfig {
+    /// Tokens in every packed sequence.
+    pub seq_len: usize,
+    /// This trainer's shard, of `world_size`.
+    pub rank: usize,
+    pub world_size: usize,
+    /// Put after each document before packing; the model's end-of-text
+    /// token when unset.
+    pub boundary_tokens: Option<Vec<u32>>,
+}
+
+impl DatasetConfig {
+    pub fn new(seq_len: usize) -> Self {
+        DatasetConfig { seq_len, rank: 0, world_size: 1, boundary_tokens: None }
+    }
+
+    pub fn with_shard(mut self, rank: usize, world_size: usize) -> Self {
+        self.rank = rank;
+        self.world_size = world_size;
+        self
+    }
+
+    pub fn with_boundary_tokens(mut self, tokens: Vec<u32>) -> Self {
+        self.boundary_tokens = Some(tokens);
+        self
+    }
+
+    fn validate(&self) -> Result<(), LlmError> {
+        if self.seq_len == 0 {
+            return Err(LlmError::InvalidParams("seq_len must be at least 1".to_string()));
+        }
+        if self.rank >= self.world_size {
+            return Err(LlmError::InvalidParams(format!("rank {} is not under world size {}", self.rank, self.world_size)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// A `{"text": ...}` object per line.
+    Jsonl,
+    /// Documents separated by blank lines.
+    Text,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl") => Some(Format::Jsonl),
+            Some("txt") => Some(Format::Text),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonDocument {
+    text: String,
+}
+
+fn dataset_error(path: &Path, e: impl Display) -> LlmError {
+    LlmError::Dataset { path: path.to_path_buf(), reason: e.to_string() }
+}
+
+/// Training text streamed from the `.jsonl` and `.txt` files in a
+/// directory, in name order, tokenized and packed into sequences of
+/// `seq_len` tokens. Documents are dealt out round-robin across the
+/// `world_size` shards, so trainers given different ranks never see the
+/// same document in an epoch. Lines that cannot be read are skipped with
+/// a warning and counted.
+pub struct Dataset {
+    dir: PathBuf,
+    files: Vec<PathBuf>,
+    config: DatasetConfig,
+    encoder: Arc<dyn ModelBackend>,
+    boundary: Vec<u32>,
+    cursor: DatasetCursor,
+    /// The file being read, at `cursor.offset`.
+    reader: Option<BufReader<File>>,
+    skipped: u64,
+}
+
+impl Dataset {
+    /// The files in `dir`, tokenized with `encoder`'s tokenizer.
+    pub fn open(dir: impl AsRef<Path>, config: DatasetConfig, encoder: Arc<dyn ModelBackend>) -> Result<Self, LlmError> {
+        config.validate()?;
+        let dir = dir.as_ref().to_path_buf();
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .map_err(|e| dataset_error(&dir, e))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && Format::of(path).is_some())
+            .collect();
+        files.sort();
+        if files.is_empty() {
+            return Err(dataset_error(&dir, "no .jsonl or .txt files"));
+        }
+        let boundary = match &config.boundary_tokens {
+            Some(tokens) => tokens.clone(),
+            None => encoder.eos_token().into_iter().collect(),
+        };
+        Ok(Dataset { dir, files, config, encoder, boundary, cursor: DatasetCursor::default(), reader: None, skipped: 0 })
+    }
+
+    pub fn config(&self) -> &DatasetConfig {
+        &self.config
+    }
+
+    /// Where the next sequence will come from.
+    pub fn cursor(&self) -> &DatasetCursor {
+        &self.cursor
+    }
+
+    /// Carries on from `cursor`, as saved from a dataset over the same
+    /// files.
+    pub fn seek(&mut self, cursor: DatasetCursor) -> Result<(), LlmError> {
+        if cursor.file > self.files.len() {
+            return Err(dataset_error(&self.dir, format!("cursor is at file {} of {}", cursor.file, self.files.len())));
+        }
+        self.cursor = cursor;
+        self.reader = None;
+        Ok(())
+    }
+
+    /// Lines skipped as unreadable so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The next `seq_len` tokens of this shard, or `None` once the epoch
+    /// is over; the tokens left over are dropped and the next call starts
+    /// the next epoch.
+    pub fn next_sequence(&mut self) -> Result<Option<Vec<u32>>, LlmError> {
+        let seq_len = self.config.seq_len;
+        while self.cursor.pending.len() < seq_len {
+            let Some((path, text)) = self.next_document()? else {
+                self.cursor = DatasetCursor { epoch: self.cursor.epoch + 1, ..DatasetCursor::default() };
+                self.reader = None;
+                return Ok(None);
+            };
+            match self.encoder.encode(&text) {
+                Ok(tokens) => {
+                    self.cursor.pending.extend(tokens);
+                    self.cursor.pending.extend_from_slice(&self.boundary);
+                }
+                Err(e) => self.skip(&path, e),
+            }
+        }
+        let rest = self.cursor.pending.split_off(seq_len);
+        Ok(Some(std::mem::replace(&mut self.cursor.pending, rest)))
+    }
+
+    /// Up to `size` sequences; fewer at the end of the epoch, and none
+    /// once it is over.
+    pub fn next_batch(&mut self, size: usize) -> Result<Vec<Vec<u32>>, LlmError> {
+        let mut batch = Vec::with_capacity(size);
+        while batch.len() < size {
+            match self.next_sequence()? {
+                Some(sequence) => batch.push(sequence),
+                None => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    fn skip(&mut self, path: &Path, reason: impl Display) {
+        self.skipped += 1;
+        warn!("Skipping line before byte {} of {}: {} ({} skipped)", self.cursor.offset, path.display(), reason, self.skipped);
+    }
+
+    /// The next document of this shard and the file it is in.
+    fn next_document(&mut self) -> Result<Option<(PathBuf, String)>, LlmError> {
+        while let Some(path) = self.files.get(self.cursor.file).cloned() {
+            let text = match Format::of(&path) {
+                Some(Format::Jsonl) => self.read_json_line(&path)?,
+                _ => self.read_paragraph(&path)?,
+            };
+            match text {
+                Some(text) => {
+                    let index = self.cursor.documents;
+                    self.cursor.documents += 1;
+                    if index % self.config.world_size as u64 == self.config.rank as u64 {
+                        return Ok(Some((path, text)));
+                    }
+                }
+                None => {
+                    self.cursor.file += 1;
+                    self.cursor.offset = 0;
+                    self.reader = None;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The next line of `path` without its line ending, or `None` at the
+    /// end of the file.
+    fn read_line(&mut self, path: &Path) -> Result<Option<Vec<u8>>, LlmError> {
+        if self.reader.is_none() {
+            let mut file = File::open(path).map_err(|e| dataset_error(path, e))?;
+            file.seek(SeekFrom::Start(self.cursor.offset)).map_err(|e| dataset_error(path, e))?;
+            self.reader = Some(BufReader::new(file));
+        }
+        let reader = self.reader.as_mut().expect("opened above");
+        let mut line = Vec::new();
+        let read = reader.read_until(b'\n', &mut line).map_err(|e| dataset_error(path, e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.cursor.offset += read as u64;
+        while line.last().map_or(false, |byte| *byte == b'\n' || *byte == b'\r') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    fn read_json_line(&mut self, path: &Path) -> Result<Option<String>, LlmError> {
+        while let Some(line) = self.read_line(path)? {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            match serde_json::from_slice::<JsonDocument>(&line) {
+                Ok(document) => return Ok(Some(document.text)),
+                Err(e) => self.skip(path, e),
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_paragraph(&mut self, path: &Path) -> Result<Option<String>, LlmError> {
+        let mut paragraph = String::new();
+        while let Some(line) = self.read_line(path)? {
+            match String::from_utf8(line) {
+                Ok(line) if line.trim().is_empty() => {
+                    if !paragraph.is_empty() {
+                        break;
+                    }
+                }
+                Ok(line) => {
+                    if !paragraph.is_empty() {
+                        paragraph.push('\n');
+                    }
+                    paragraph.push_str(&line);
+                }
+                Err(e) => self.skip(path, e),
+            }
+        }
+        Ok(Some(paragraph).filter(|paragraph| !paragraph.is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::KvCache;
+    use std::collections::BTreeSet;
+
+    /// A token per byte, with 0 as end of text.
+    struct Bytes;
+
+    impl ModelBackend for Bytes {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().map(u32::from).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| char::from(token as u8)).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            unreachable!("datasets only tokenize")
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            64
+        }
+    }
+
+    fn fixture(dir: &Path) {
+        fs::write(dir.join("a.jsonl"), "{\"text\": \"ab\"}\nnot json\n\n{\"text\": \"cde\", \"id\": 2}\n{\"title\": \"no text\"}\n").unwrap();
+        fs::write(dir.join("b.txt"), "fg\nh\n\n\nijkl\n").unwrap();
+        fs::write(dir.join("notes.md"), "ignored").unwrap();
+    }
+
+    fn open(dir: &Path, config: DatasetConfig) -> Dataset {
+        Dataset::open(dir, config, Arc::new(Bytes)).unwrap()
+    }
+
+    fn text(tokens: &[u32]) -> String {
+        Bytes.decode(tokens).unwrap().replace('\0', "|")
+    }
+
+    fn epoch(dataset: &mut Dataset) -> Vec<String> {
+        std::iter::from_fn(|| dataset.next_sequence().unwrap()).map(|sequence| text(&sequence)).collect()
+    }
+
+    #[test]
+    fn test_documents_packed_with_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let mut dataset = open(dir.path(), DatasetConfig::new(4));
+        // ab| cde| fg\nh| ijkl|, the last token left over.
+        assert_eq!(epoch(&mut dataset), ["ab|c", "de|f", "g\nh|", "ijkl"]);
+        assert_eq!(dataset.skipped(), 2);
+        assert_eq!(dataset.cursor().epoch, 1);
+        assert_eq!(epoch(&mut dataset).len(), 4);
+
+        let mut dataset = open(dir.path(), DatasetConfig::new(3).with_boundary_tokens(vec![b'#' as u32, b'#' as u32]));
+        assert_eq!(dataset.next_batch(2).unwrap().iter().map(|sequence| text(sequence)).collect::<Vec<_>>(), ["ab#", "#cd"]);
+
+        assert!(matches!(Dataset::open(dir.path(), DatasetConfig::new(4).with_shard(2, 2), Arc::new(Bytes)), Err(LlmError::InvalidParams(_))));
+        let empty = tempfile::tempdir().unwrap();
+        assert!(matches!(Dataset::open(empty.path(), DatasetConfig::new(4), Arc::new(Bytes)), Err(LlmError::Dataset { .. })));
+    }
+
+    #[test]
+    fn test_shards_are_disjoint_and_cover_every_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines: String = (0..30).map(|i| format!("{{\"text\": \"doc{:02}\"}}\n", i)).collect();
+        fs::write(dir.path().join("docs.jsonl"), lines).unwrap();
+        let mut seen = Vec::new();
+        for rank in 0..3 {
+            let mut dataset = open(dir.path(), DatasetConfig::new(6).with_shard(rank, 3));
+            let documents: Vec<String> = epoch(&mut dataset).into_iter().map(|sequence| sequence.trim_end_matches('|').to_string()).collect();
+            assert_eq!(documents.len(), 10);
+            assert_eq!(documents, epoch(&mut dataset), "every epoch deals the same way");
+            seen.extend(documents);
+        }
+        let distinct: BTreeSet<&String> = seen.iter().collect();
+        assert_eq!((seen.len(), distinct.len()), (30, 30));
+    }
+
+    #[test]
+    fn test_cursor_resumes_where_it_left_off() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let all = epoch(&mut open(dir.path(), DatasetConfig::new(3)));
+
+        let mut first = open(dir.path(), DatasetConfig::new(3));
+        let taken: Vec<String> = first.next_batch(2).unwrap().iter().map(|sequence| text(sequence)).collect();
+        let cursor: DatasetCursor = serde_json::from_str(&serde_json::to_string(first.cursor()).unwrap()).unwrap();
+        assert!(!cursor.pending.is_empty());
+
+        let mut resumed = open(dir.path(), DatasetConfig::new(3));
+        resumed.seek(cursor).unwrap();
+        let rest = epoch(&mut resumed);
+        assert_eq!([taken, rest].concat(), all);
+        assert_eq!(resumed.cursor().epoch, 1);
+    }
+}
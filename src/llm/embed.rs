@@ -0,0 +1,250 @@
+use candle_core::{DType, Tensor};
+
+use super::model::{LlmError, ModelBackend};
+use crate::node::config::Pooling;
+
+/// Texts run through the model together when embedding, unless the config
+/// says otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 8;
+
+/// Cosine of the angle between `a` and `b`: 1.0 for the same direction.
+/// Zero when either is all zeros or their lengths differ.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+    (dot / norms).clamp(-1.0, 1.0)
+}
+
+fn norm(vector: &[f32]) -> f32 {
+    vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+/// `states`, one vector per token, pooled and scaled to unit length.
+fn pool(states: &[Vec<f32>], pooling: Pooling) -> Vec<f32> {
+    let mut pooled = match pooling {
+        Pooling::LastToken => states.last().cloned().unwrap_or_default(),
+        Pooling::Mean => {
+            let mut sum = vec![0.0; states.first().map_or(0, Vec::len)];
+            for state in states {
+                sum.iter_mut().zip(state).for_each(|(sum, x)| *sum += x);
+            }
+            sum.iter_mut().for_each(|x| *x /= states.len() as f32);
+            sum
+        }
+    };
+    let length = norm(&pooled);
+    if length > 0.0 {
+        pooled.iter_mut().for_each(|x| *x /= length);
+    }
+    pooled
+}
+
+/// Encodes every text, then embeds them `batch_size` at a time; model
+/// work, so off the async threads. Any empty text or one longer than the
+/// context window fails the call before the model runs.
+pub(super) fn embed(
+    backend: &dyn ModelBackend,
+    texts: &[String],
+    pooling: Pooling,
+    batch_size: usize,
+) -> Result<Vec<Vec<f32>>, LlmError> {
+    let limit = backend.context_length();
+    let sequences = texts.iter()
+        .enumerate()
+        .map(|(i, text)| {
+            let tokens = if text.is_empty() { Vec::new() } else { backend.encode(text)? };
+            if tokens.is_empty() {
+                return Err(LlmError::InvalidParams(format!("text {} is empty", i)));
+            }
+            if tokens.len() > limit {
+                return Err(LlmError::PromptTooLong { tokens: tokens.len(), limit });
+            }
+            Ok(tokens)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut embeddings = Vec::with_capacity(sequences.len());
+    for batch in sequences.chunks(batch_size.max(1)) {
+        let states = backend.hidden_states(batch)?;
+        if states.len() != batch.len() {
+            return Err(LlmError::InferenceFailed(format!(
+                "hidden states for {} of {} sequences", states.len(), batch.len()
+            )));
+        }
+        for (states, tokens) in states.iter().zip(batch) {
+            // Padding left in would pull short texts towards the pad token.
+            if states.len() != tokens.len() {
+                return Err(LlmError::InferenceFailed(format!(
+                    "hidden states for {} positions of a {} token sequence", states.len(), tokens.len()
+                )));
+            }
+            embeddings.push(pool(states, pooling));
+        }
+    }
+    Ok(embeddings)
+}
+
+/// A model's token embedding matrix, for backends whose layers' outputs
+/// candle keeps to itself: their hidden states are the embedding layer's.
+pub(super) struct TokenEmbeddings {
+    /// `(vocab, dim)`.
+    matrix: Tensor,
+}
+
+impl TokenEmbeddings {
+    pub(super) fn new(matrix: Tensor) -> Self {
+        TokenEmbeddings { matrix }
+    }
+
+    pub(super) fn dim(&self) -> usize {
+        self.matrix.dims().last().copied().unwrap_or(0)
+    }
+
+    /// Looks the whole batch up at once, padded to its longest sequence,
+    /// then cuts each sequence's padding off again.
+    pub(super) fn hidden_states(&self, batch: &[Vec<u32>]) -> Result<Vec<Vec<Vec<f32>>>, LlmError> {
+        let longest = batch.iter().map(Vec::len).max().unwrap_or(0);
+        if longest == 0 {
+            return Ok(vec![Vec::new(); batch.len()]);
+        }
+        let padded: Vec<u32> = batch.iter()
+            .flat_map(|tokens| tokens.iter().copied().chain(std::iter::repeat(0).take(longest - tokens.len())))
+            .collect();
+        let states = Tensor::new(padded.as_slice(), self.matrix.device())
+            .and_then(|ids| self.matrix.index_select(&ids, 0))
+            .and_then(|states| states.to_dtype(DType::F32))
+            .and_then(|states| states.reshape((batch.len(), longest, self.dim())))
+            .and_then(|states| states.to_vec3::<f32>())
+            .map_err(|e| LlmError::InferenceFailed(format!("embedding {} sequences: {}", batch.len(), e)))?;
+        Ok(states.into_iter()
+            .zip(batch)
+            .map(|(mut states, tokens)| {
+                states.truncate(tokens.len());
+                states
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{KvCache, LightLLM};
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// One token per byte, with a hidden state per token drawn from the
+    /// byte and its position. `unmasked` leaves the batch's padding in.
+    #[derive(Default)]
+    struct Positions {
+        batches: Mutex<Vec<usize>>,
+        unmasked: bool,
+    }
+
+    impl ModelBackend for Positions {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().map(u32::from).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| token as u8 as char).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            unreachable!("embedding runs no forward pass")
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn context_length(&self) -> usize {
+            16
+        }
+
+        fn embedding_dim(&self) -> usize {
+            3
+        }
+
+        fn hidden_states(&self, batch: &[Vec<u32>]) -> Result<Vec<Vec<Vec<f32>>>, LlmError> {
+            self.batches.lock().push(batch.len());
+            let longest = batch.iter().map(Vec::len).max().unwrap_or(0);
+            Ok(batch.iter()
+                .map(|tokens| {
+                    let length = if self.unmasked { longest } else { tokens.len() };
+                    (0..length)
+                        .map(|i| {
+                            let token = tokens.get(i).map_or(0.0, |&token| token as f32);
+                            vec![token, (i + 1) as f32, 1.0]
+                        })
+                        .collect()
+                })
+                .collect())
+        }
+    }
+
+    async fn embed(model: &LightLLM, texts: &[&str]) -> Vec<Vec<f32>> {
+        model.embed(texts).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_are_normalized_and_batched() {
+        let backend = Arc::new(Positions::default());
+        let model = LightLLM::with_backend(backend.clone()).with_max_batch_size(2);
+        assert_eq!(model.embedding_dim(), 3);
+
+        let texts = ["ab", "abcdefgh", "ab", "zz", "a"];
+        let embeddings = embed(&model, &texts).await;
+        assert_eq!(*backend.batches.lock(), [2, 2, 1]);
+        assert_eq!(embeddings.len(), texts.len());
+        for embedding in &embeddings {
+            assert_eq!(embedding.len(), 3);
+            assert!((norm(embedding) - 1.0).abs() < 1e-5, "{:?}", embedding);
+        }
+        // Identical texts match whatever they were batched with.
+        assert_eq!(embeddings[0], embeddings[2]);
+        assert_eq!(embed(&model, &["ab"]).await[0], embeddings[0]);
+        assert!(cosine_similarity(&embeddings[0], &embeddings[3]) < 1.0);
+
+        let last = LightLLM::with_backend(backend.clone()).with_pooling(Pooling::LastToken);
+        let pooled = embed(&last, &["ab", "b"]).await;
+        assert_ne!(pooled[0], embeddings[0]);
+        assert_eq!(pooled[0], pool(&[vec![98.0, 2.0, 1.0]], Pooling::Mean));
+    }
+
+    #[tokio::test]
+    async fn test_empty_overlong_and_unmasked_inputs() {
+        let model = LightLLM::with_backend(Arc::new(Positions::default()));
+        assert_eq!(model.embed(&[]).await, Ok(Vec::new()));
+        assert_eq!(model.embed(&["ab", ""]).await, Err(LlmError::InvalidParams("text 1 is empty".to_string())));
+        let long = "a".repeat(17);
+        assert_eq!(model.embed(&["ab", long.as_str()]).await, Err(LlmError::PromptTooLong { tokens: 17, limit: 16 }));
+
+        let unmasked = LightLLM::with_backend(Arc::new(Positions { unmasked: true, ..Positions::default() }));
+        assert_eq!(unmasked.embed(&["abc"]).await.unwrap().len(), 1);
+        assert!(matches!(unmasked.embed(&["abc", "a"]).await, Err(LlmError::InferenceFailed(_))));
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 1.0]), 0.0);
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use super::generate::{prompt_limit, GenerateParams};
+use super::model::{LlmError, ModelBackend};
+use super::usage::LlmMetrics;
+use super::worker::ModelWorker;
+
+/// What generating from a prompt would take, worked out without running
+/// the model.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Estimate {
+    /// Prompt tokens the model would be given, after truncation; what the
+    /// completion's usage will report.
+    pub prompt_tokens: usize,
+    /// Prompt tokens `GenerateParams.truncation` would drop.
+    pub truncated_tokens: usize,
+    /// `prompt_tokens` plus `max_tokens`: the most the completion can use.
+    pub max_total_tokens: usize,
+    /// Whether the whole prompt fits beside `max_tokens` in the context
+    /// window. If not, generation truncates the prompt or refuses it.
+    pub fits_context: bool,
+    /// How long `max_tokens` take at the model's recent generation rate;
+    /// unset until it has generated something.
+    pub est_latency_ms: Option<u64>,
+}
+
+/// `prompt` encoded and fitted just as generation does it, special tokens
+/// and all, so the counts match the completion's.
+fn fit(backend: &dyn ModelBackend, prompt: &str, params: &GenerateParams) -> Result<Estimate, LlmError> {
+    let limit = prompt_limit(params, backend.context_length())?;
+    let tokens = backend.encode(prompt)?;
+    let whole = tokens.len();
+    let (prompt_tokens, truncated_tokens) = match params.truncation.apply(tokens, limit, backend.bos_token()) {
+        Ok((tokens, truncated_tokens)) => (tokens.len(), truncated_tokens),
+        Err(LlmError::PromptTooLong { .. }) => (whole, 0),
+        Err(e) => return Err(e),
+    };
+    Ok(Estimate {
+        prompt_tokens,
+        truncated_tokens,
+        max_total_tokens: prompt_tokens + params.max_tokens,
+        fits_context: whole <= limit,
+        est_latency_ms: None,
+    })
+}
+
+pub(super) async fn count_tokens(worker: &ModelWorker, text: &str) -> Result<usize, LlmError> {
+    let text = text.to_string();
+    worker.run(move |backend| Ok(backend.encode(&text)?.len())).await
+}
+
+pub(super) async fn estimate(
+    worker: &ModelWorker,
+    metrics: &LlmMetrics,
+    prompt: &str,
+    params: GenerateParams,
+) -> Result<Estimate, LlmError> {
+    let prompt = prompt.to_string();
+    let estimate = worker.run(move |backend| fit(backend, &prompt, &params)).await?;
+    let max_tokens = estimate.max_total_tokens - estimate.prompt_tokens;
+    let est_latency_ms = metrics.recent_tokens_per_second()
+        .map(|rate| (max_tokens as f64 / rate * 1000.0).round() as u64);
+    Ok(Estimate { est_latency_ms, ..estimate })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::llm::{GenerateParams, KvCache, LightLLM, LlmError, ModelBackend, TruncationPolicy};
+    use std::sync::Arc;
+
+    /// A BOS token, then one token per byte; never ends the completion.
+    struct Bytes;
+
+    impl ModelBackend for Bytes {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(std::iter::once(1).chain(text.bytes().map(|byte| u32::from(byte) + 2)).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok("x".repeat(tokens.len()))
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            Ok(vec![0.0, 0.0, 1.0])
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            Some(1)
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            64
+        }
+    }
+
+    #[tokio::test]
+    async fn test_estimate_counts_match_generation() {
+        let model = LightLLM::with_backend(Arc::new(Bytes));
+        assert_eq!(model.count_tokens("hello").await.unwrap(), 6);
+
+        let params = GenerateParams::new(8).with_temperature(0.0);
+        let estimate = model.estimate("hello", params.clone()).await.unwrap();
+        assert_eq!(estimate.est_latency_ms, None);
+        let completion = model.generate("hello", params.clone()).await.unwrap();
+        assert_eq!(estimate.prompt_tokens, completion.usage.prompt_tokens);
+        assert_eq!(estimate.max_total_tokens, completion.usage.total_tokens());
+        assert!(estimate.fits_context);
+        assert!(model.estimate("hello", params).await.unwrap().est_latency_ms.is_some());
+
+        // Too long: refused as generation refuses it, or truncated alike.
+        let long = "y".repeat(60);
+        let refused = model.estimate(&long, GenerateParams::new(8)).await.unwrap();
+        assert_eq!((refused.prompt_tokens, refused.fits_context), (61, false));
+        assert!(matches!(model.generate(&long, GenerateParams::new(8)).await, Err(LlmError::PromptTooLong { tokens: 61, .. })));
+        let params = GenerateParams::new(8).with_truncation(TruncationPolicy::TruncateStart);
+        let truncated = model.estimate(&long, params.clone()).await.unwrap();
+        let finish = model.generate(&long, params).await.unwrap().finish;
+        assert_eq!((truncated.prompt_tokens, truncated.truncated_tokens), (finish.prompt_tokens, finish.truncated_tokens));
+        assert_eq!((truncated.prompt_tokens, truncated.truncated_tokens, truncated.fits_context), (56, 5, false));
+
+        assert!(matches!(model.estimate("hello", GenerateParams::new(64)).await, Err(LlmError::InvalidParams(_))));
+    }
+}
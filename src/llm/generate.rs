@@ -0,0 +1,963 @@
+use futures::stream::{self, Stream};
+use futures::{pin_mut, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use super::chat::{self, ChatMessage, PromptTemplate};
+use super::logprobs::{self, TokenLogprob, MAX_LOGPROBS};
+use super::model::{KvCache, LlmError, ModelBackend};
+use super::sampling::Sampler;
+use super::speculative::{self, DraftModel, Speculation};
+use super::structured::{Constraint, JsonSchema};
+use super::usage::{LlmMetrics, Usage};
+use super::worker::ModelWorker;
+
+pub const DEFAULT_TEMPERATURE: f32 = 0.8;
+
+/// How a completion is sampled, and when to give up on it. Checked by
+/// `validate` when generation starts.
+#[derive(Debug, Clone)]
+pub struct GenerateParams {
+    pub max_tokens: usize,
+    /// Zero always picks the likeliest token.
+    pub temperature: f32,
+    /// Sample only from this many of the likeliest tokens.
+    pub top_k: Option<usize>,
+    /// Sample only from the likeliest tokens whose probabilities add up to
+    /// this, in (0, 1].
+    pub top_p: Option<f32>,
+    /// Divides the logits of tokens already in the context; 1.0 leaves
+    /// them as they are.
+    pub repetition_penalty: f32,
+    /// Generation ends where the first of these would appear; the stop
+    /// sequence itself is not emitted.
+    pub stop: Vec<String>,
+    /// Makes sampling reproducible on the same model and device.
+    pub seed: Option<u64>,
+    /// What to do with a prompt longer than the context window leaves room
+    /// for next to `max_tokens`.
+    pub truncation: TruncationPolicy,
+    /// Cancelling it ends generation with `LlmError::Cancelled` before the
+    /// next token.
+    pub cancel: CancellationToken,
+    /// Generation still running at this instant ends with
+    /// `LlmError::Timeout`.
+    pub deadline: Option<Instant>,
+    /// Reports each completion token's log probability, with this many of
+    /// the likeliest alternatives at its step.
+    pub logprobs: Option<usize>,
+    /// Holds the completion to JSON of this schema; generation fails with
+    /// `LlmError::ResponseFormat` rather than end on anything else.
+    pub response_format: Option<JsonSchema>,
+}
+
+impl GenerateParams {
+    pub fn new(max_tokens: usize) -> Self {
+        GenerateParams {
+            max_tokens,
+            temperature: DEFAULT_TEMPERATURE,
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 1.0,
+            stop: Vec::new(),
+            seed: None,
+            truncation: TruncationPolicy::default(),
+            cancel: CancellationToken::new(),
+            deadline: None,
+            logprobs: None,
+            response_format: None,
+        }
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.repetition_penalty = repetition_penalty;
+        self
+    }
+
+    pub fn with_stop(mut self, stop: impl Into<String>) -> Self {
+        self.stop.push(stop.into());
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_truncation(mut self, truncation: TruncationPolicy) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_logprobs(mut self, top: usize) -> Self {
+        self.logprobs = Some(top);
+        self
+    }
+
+    pub fn with_response_format(mut self, schema: JsonSchema) -> Self {
+        self.response_format = Some(schema);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), LlmError> {
+        let invalid = |reason: &str| Err(LlmError::InvalidParams(reason.to_string()));
+        if self.max_tokens == 0 {
+            return invalid("max_tokens must be at least 1");
+        }
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            return invalid("temperature must be a non-negative number");
+        }
+        if self.top_k == Some(0) {
+            return invalid("top_k must be at least 1");
+        }
+        if self.top_p.map_or(false, |top_p| !(top_p > 0.0 && top_p <= 1.0)) {
+            return invalid("top_p must be in (0, 1]");
+        }
+        if !self.repetition_penalty.is_finite() || self.repetition_penalty <= 0.0 {
+            return invalid("repetition_penalty must be a positive number");
+        }
+        if self.stop.iter().any(String::is_empty) {
+            return invalid("stop sequences cannot be empty");
+        }
+        if self.logprobs.map_or(false, |top| top > MAX_LOGPROBS) {
+            return Err(LlmError::InvalidParams(format!("logprobs must be at most {}", MAX_LOGPROBS)));
+        }
+        if self.response_format.is_some() && !self.stop.is_empty() {
+            return invalid("stop sequences cannot be combined with a response_format");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncationPolicy {
+    /// Refuse the prompt with `LlmError::PromptTooLong`.
+    #[default]
+    Error,
+    /// Drop the oldest tokens, keeping a leading BOS token.
+    TruncateStart,
+    /// Drop the newest tokens.
+    TruncateEnd,
+}
+
+impl TruncationPolicy {
+    /// `prompt` cut down to `limit` tokens, with the number dropped.
+    pub(super) fn apply(self, mut prompt: Vec<u32>, limit: usize, bos: Option<u32>) -> Result<(Vec<u32>, usize), LlmError> {
+        let over = match prompt.len().checked_sub(limit) {
+            Some(over) if over > 0 => over,
+            _ => return Ok((prompt, 0)),
+        };
+        match self {
+            TruncationPolicy::Error => return Err(LlmError::PromptTooLong { tokens: prompt.len(), limit }),
+            TruncationPolicy::TruncateStart => {
+                let start = usize::from(bos.is_some() && prompt.first() == bos.as_ref() && limit > 0);
+                prompt.drain(start..start + over);
+            }
+            TruncationPolicy::TruncateEnd => prompt.truncate(limit),
+        }
+        Ok((prompt, over))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// `max_tokens` were generated.
+    Length,
+    /// A stop sequence was generated.
+    Stop,
+    /// The model ended the completion, or the `response_format` value was
+    /// closed.
+    EndOfText,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Finish {
+    pub reason: FinishReason,
+    /// Prompt tokens given to the model, after truncation.
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Prompt tokens dropped under the `TruncationPolicy`, or evicted from
+    /// a `ChatSession`.
+    pub truncated_tokens: usize,
+}
+
+/// What a cancelled or timed-out generation got through: the text already
+/// emitted, and the tokens the model ran over.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Partial {
+    pub text: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+/// A whole completion, as returned by `LightLLM::generate`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Completion {
+    pub text: String,
+    pub finish: Finish,
+    pub usage: Usage,
+    /// One per completion token when `GenerateParams.logprobs` is set.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+/// Completion text decoded since the previous chunk. Text that could still
+/// become a stop sequence or is an incomplete character is held back until
+/// settled, so a chunk may span several tokens.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenChunk {
+    pub text: String,
+    /// Set on the last chunk only.
+    pub finish: Option<Finish>,
+    /// Set on the last chunk only.
+    pub usage: Option<Usage>,
+    /// For the tokens sampled since the previous chunk, when
+    /// `GenerateParams.logprobs` is set.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+/// Bytes at the end of `text` to hold back: an incomplete character, or
+/// the longest suffix that begins a stop sequence.
+fn unsettled_len(text: &str, stop: &[String]) -> usize {
+    if text.ends_with(char::REPLACEMENT_CHARACTER) {
+        return text.len() - text.trim_end_matches(char::REPLACEMENT_CHARACTER).len();
+    }
+    text.char_indices()
+        .map(|(start, _)| &text[start..])
+        .find(|suffix| stop.iter().any(|stop| stop.starts_with(suffix)))
+        .map_or(0, str::len)
+}
+
+/// Collects a stream of chunks into the whole completion.
+pub(super) async fn collect(stream: impl Stream<Item = Result<TokenChunk, LlmError>>) -> Result<Completion, LlmError> {
+    pin_mut!(stream);
+    let (mut text, mut logprobs) = (String::new(), Vec::new());
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        text.push_str(&chunk.text);
+        logprobs.extend(chunk.logprobs);
+        if let Some(finish) = chunk.finish {
+            return Ok(Completion { text, finish, usage: chunk.usage.unwrap_or_default(), logprobs });
+        }
+    }
+    Err(LlmError::InferenceFailed("generation ended without finishing".to_string()))
+}
+
+/// Tokens of context left for the prompt once `max_tokens` is set aside.
+pub(super) fn prompt_limit(params: &GenerateParams, context_length: usize) -> Result<usize, LlmError> {
+    params.validate()?;
+    if params.max_tokens >= context_length {
+        return Err(LlmError::InvalidParams(format!(
+            "max_tokens must leave room for a prompt in the {} token context window", context_length
+        )));
+    }
+    Ok(context_length - params.max_tokens)
+}
+
+/// How an interrupted generation ends.
+pub(super) type Interruption = fn(Partial) -> LlmError;
+
+/// Resolves once `cancel` is cancelled or `deadline` passes.
+async fn interruption(cancel: CancellationToken, deadline: Option<Instant>) -> Interruption {
+    let deadline = async move {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending::<()>().await,
+        }
+    };
+    tokio::select! {
+        _ = cancel.cancelled() => LlmError::Cancelled as Interruption,
+        _ = deadline => LlmError::Timeout as Interruption,
+    }
+}
+
+/// What sampling from a step's logits led to.
+pub(super) enum Sampled {
+    Finished(TokenChunk),
+    /// The completion's tokens, to decode and `settle`.
+    Decode(Vec<u32>),
+}
+
+/// What to complete: text as given, or a conversation the template lays
+/// out first.
+pub(super) enum Prompt {
+    Text(String),
+    Chat(Arc<PromptTemplate>, Vec<ChatMessage>),
+}
+
+/// When a generation was asked for, and when it was admitted and began.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Timing {
+    submitted: Instant,
+    started: Instant,
+}
+
+impl Timing {
+    /// A generation starting now, asked for at `submitted`.
+    pub(super) fn since(submitted: Instant) -> Self {
+        Timing { submitted, started: Instant::now() }
+    }
+}
+
+/// A generation in progress. Its model work runs on `worker`.
+pub(super) struct Generation {
+    worker: ModelWorker,
+    params: GenerateParams,
+    /// The prompt followed by the completion so far.
+    pub(super) tokens: Vec<u32>,
+    /// Holds a prefix of `tokens`; taken while the model runs, and lost if
+    /// it fails.
+    pub(super) cache: Option<KvCache>,
+    prompt_tokens: usize,
+    truncated_tokens: usize,
+    /// The completion so far, decoded.
+    text: String,
+    /// Bytes of `text` already emitted.
+    emitted: usize,
+    sampler: Sampler,
+    metrics: Arc<LlmMetrics>,
+    timing: Timing,
+    /// When the first completion token was sampled.
+    first_token: Option<Instant>,
+    /// Of the tokens sampled since the last chunk.
+    logprobs: Vec<TokenLogprob>,
+    speculation: Option<Speculation>,
+    /// Set with `params.response_format`.
+    pub(super) constraint: Option<Constraint>,
+}
+
+impl Generation {
+    async fn start(
+        worker: ModelWorker,
+        draft: Option<DraftModel>,
+        prompt: Prompt,
+        params: GenerateParams,
+        metrics: Arc<LlmMetrics>,
+    ) -> Result<Self, LlmError> {
+        let submitted = Instant::now();
+        let handle = worker.clone();
+        worker.run(move |backend| {
+            let mut generation = Generation::start_blocking(handle, backend, prompt, params, metrics, submitted)?;
+            generation.speculation = draft.map(Speculation::start).transpose()?;
+            Ok(generation)
+        })
+        .await
+    }
+
+    /// Checks `params`, then encodes and fits `prompt`, asked for at
+    /// `submitted`; model work, so for a job on `worker`. A chat is fitted
+    /// with its template's tokens counted, dropping whole exchanges.
+    pub(super) fn start_blocking(
+        worker: ModelWorker,
+        backend: &dyn ModelBackend,
+        prompt: Prompt,
+        params: GenerateParams,
+        metrics: Arc<LlmMetrics>,
+        submitted: Instant,
+    ) -> Result<Self, LlmError> {
+        let timing = Timing::since(submitted);
+        let limit = prompt_limit(&params, backend.context_length())?;
+        let (tokens, truncated_tokens) = match prompt {
+            Prompt::Text(text) => params.truncation.apply(backend.encode(&text)?, limit, backend.bos_token())?,
+            Prompt::Chat(template, messages) => chat::fit(backend, &template, &messages, limit, params.truncation)?,
+        };
+        let cache = backend.new_cache()?;
+        let constraint = params.response_format.as_ref().map(|schema| Constraint::new(schema, backend)).transpose()?;
+        let mut generation = Generation::resume(worker, tokens, cache, truncated_tokens, params, metrics, timing);
+        generation.constraint = constraint;
+        Ok(generation)
+    }
+
+    /// Completes `tokens`, a prefix of which `cache` already holds.
+    pub(super) fn resume(
+        worker: ModelWorker,
+        tokens: Vec<u32>,
+        cache: KvCache,
+        truncated_tokens: usize,
+        params: GenerateParams,
+        metrics: Arc<LlmMetrics>,
+        timing: Timing,
+    ) -> Self {
+        Generation {
+            worker,
+            sampler: Sampler::new(&params),
+            params,
+            prompt_tokens: tokens.len(),
+            truncated_tokens,
+            tokens,
+            cache: Some(cache),
+            text: String::new(),
+            emitted: 0,
+            metrics,
+            timing,
+            first_token: None,
+            logprobs: Vec::new(),
+            speculation: None,
+            constraint: None,
+        }
+    }
+
+    fn completion_tokens(&self) -> usize {
+        self.tokens.len() - self.prompt_tokens
+    }
+
+    /// Whether the caller has cancelled or the deadline has passed.
+    pub(super) fn interrupted(&self) -> Option<Interruption> {
+        if self.params.cancel.is_cancelled() {
+            return Some(LlmError::Cancelled as Interruption);
+        }
+        match self.params.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(LlmError::Timeout as Interruption),
+            _ => None,
+        }
+    }
+
+    /// What the generation has cost so far.
+    fn usage(&self) -> Usage {
+        let now = Instant::now();
+        let Timing { submitted, started } = self.timing;
+        let running = now.duration_since(started).as_secs_f64();
+        let completion_tokens = self.completion_tokens();
+        let stats = self.speculation.as_ref().map(|speculation| speculation.stats);
+        Usage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens,
+            queue_wait_secs: started.duration_since(submitted).as_secs_f64(),
+            time_to_first_token_secs: self.first_token.unwrap_or(now).duration_since(submitted).as_secs_f64(),
+            tokens_per_second: if running > 0.0 { completion_tokens as f64 / running } else { 0.0 },
+            device_memory_bytes: self.worker.memory_high_water(),
+            cached: false,
+            draft_acceptance_rate: stats.filter(|stats| stats.drafted > 0)
+                .map(|stats| stats.accepted as f64 / stats.drafted as f64),
+            speculative_speedup: stats.filter(|stats| stats.passes > 0)
+                .map(|stats| completion_tokens as f64 / stats.passes as f64),
+        }
+    }
+
+    /// The usage of the generation as it ends, recorded in the metrics.
+    fn record(&self) -> Usage {
+        let usage = self.usage();
+        self.metrics.record(&usage, self.timing.started.elapsed());
+        if let Some(Speculation { stats, .. }) = &self.speculation {
+            self.metrics.record_speculation(stats.drafted, stats.accepted, stats.passes, self.completion_tokens());
+        }
+        usage
+    }
+
+    /// Ends generation with `interruption`, freeing the cache at once.
+    pub(super) fn abort(&mut self, interruption: Interruption) -> LlmError {
+        self.cache = None;
+        self.record();
+        interruption(Partial {
+            text: self.text.get(..self.emitted).unwrap_or_default().to_string(),
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens(),
+        })
+    }
+
+    /// The cache and the tokens it has yet to take in, for a forward pass.
+    pub(super) fn take_input(&mut self) -> Result<(KvCache, Vec<u32>), LlmError> {
+        let cache = self.cache.take()
+            .ok_or_else(|| LlmError::InferenceFailed("KV cache lost to an earlier failure".to_string()))?;
+        let fresh = self.tokens[cache.len()..].to_vec();
+        Ok((cache, fresh))
+    }
+
+    /// Puts the cache back once it has taken in `fed` tokens.
+    pub(super) fn restore(&mut self, mut cache: KvCache, fed: usize) {
+        cache.advance(fed);
+        self.cache = Some(cache);
+    }
+
+    /// Runs the model over the tokens not yet in the cache.
+    async fn forward(&mut self) -> Result<Vec<f32>, LlmError> {
+        let (mut cache, fresh) = self.take_input()?;
+        let (cache, fed, logits) = self.worker.run(move |backend| {
+            let logits = backend.forward(&mut cache, &fresh)?;
+            Ok((cache, fresh.len(), logits))
+        })
+        .await?;
+        self.restore(cache, fed);
+        Ok(logits)
+    }
+
+    /// The last chunk, once `max_tokens` have been sampled; an error if
+    /// the `response_format` value is not yet whole.
+    pub(super) fn length_reached(&mut self) -> Result<Option<TokenChunk>, LlmError> {
+        if self.completion_tokens() < self.params.max_tokens {
+            return Ok(None);
+        }
+        if self.constraint.as_ref().map_or(false, |constraint| !constraint.complete()) {
+            return Err(self.stuck("max_tokens ran out before the value was complete".to_string()));
+        }
+        Ok(Some(self.finish(FinishReason::Length, self.text.len())))
+    }
+
+    /// Samples the next token from `logits`.
+    pub(super) fn accept(&mut self, logits: &[f32]) -> Result<Sampled, LlmError> {
+        let token = self.choose(logits)?;
+        Ok(self.append(token, logits))
+    }
+
+    /// Samples from `logits`, leaving out what the `response_format` does
+    /// not allow next.
+    fn choose(&mut self, logits: &[f32]) -> Result<u32, LlmError> {
+        let masked = match &self.constraint {
+            Some(constraint) => constraint.mask(logits, self.worker.eos_token()),
+            None => return self.sampler.sample(logits, &self.tokens),
+        };
+        if masked.iter().all(|&logit| logit == f32::NEG_INFINITY) {
+            return Err(self.stuck("no token can continue the value".to_string()));
+        }
+        self.sampler.sample(&masked, &self.tokens)
+    }
+
+    /// Ends generation where the completion cannot follow the
+    /// `response_format`, freeing the cache at once.
+    fn stuck(&mut self, reason: String) -> LlmError {
+        let position = self.constraint.as_ref().map_or(0, Constraint::position);
+        self.cache = None;
+        self.record();
+        LlmError::ResponseFormat { position, reason }
+    }
+
+    /// Appends `token`, chosen from `logits`.
+    fn append(&mut self, token: u32, logits: &[f32]) -> Sampled {
+        self.first_token.get_or_insert_with(Instant::now);
+        if Some(token) == self.worker.eos_token() {
+            return Sampled::Finished(self.finish(FinishReason::EndOfText, self.text.len()));
+        }
+        if let Some(top) = self.params.logprobs {
+            self.logprobs.push(logprobs::token_logprob(logits, token, top));
+        }
+        self.tokens.push(token);
+        Sampled::Decode(self.tokens[self.prompt_tokens..].to_vec())
+    }
+
+    /// The next tokens with the logits each was chosen from: one sampled
+    /// after a forward pass, or those a speculative round keeps. A
+    /// `response_format` is followed a token at a time, without rounds.
+    async fn step(&mut self) -> Result<Vec<(u32, Vec<f32>)>, LlmError> {
+        // The last token is the target's own either way.
+        let lookahead = (self.params.max_tokens - self.completion_tokens()).saturating_sub(1);
+        let speculate = lookahead > 0 && self.constraint.is_none();
+        let Some(speculation) = self.speculation.as_mut().filter(|_| speculate) else {
+            if let Some(speculation) = &mut self.speculation {
+                speculation.stats.passes += 1;
+            }
+            let logits = self.forward().await?;
+            let token = self.choose(&logits)?;
+            return Ok(vec![(token, logits)]);
+        };
+        let cache = self.cache.take()
+            .ok_or_else(|| LlmError::InferenceFailed("KV cache lost to an earlier failure".to_string()))?;
+        let draft_cache = speculation.cache.take()
+            .ok_or_else(|| LlmError::InferenceFailed("draft KV cache lost to an earlier failure".to_string()))?;
+        let (draft, mut sampler, context) = (speculation.model.clone(), self.sampler.clone(), self.tokens.clone());
+        let (round, sampler) = self.worker.run(move |backend| {
+            let round = speculative::round(backend, &draft, &mut sampler, &context, cache, draft_cache, lookahead)?;
+            Ok((round, sampler))
+        })
+        .await?;
+        self.sampler = sampler;
+        self.cache = Some(round.cache);
+        speculation.cache = Some(round.draft_cache);
+        speculation.stats.passes += 1;
+        speculation.stats.drafted += round.drafted;
+        speculation.stats.accepted += round.accepted;
+        Ok(round.tokens)
+    }
+
+    /// Takes the completion decoded so far: a chunk if a stop sequence
+    /// appeared, the `response_format` value closed or more text settled.
+    pub(super) fn settle(&mut self, text: String) -> Result<Option<TokenChunk>, LlmError> {
+        self.text = text;
+        if let Some(constraint) = &mut self.constraint {
+            match constraint.advance(&self.text) {
+                Ok(true) => return Ok(Some(self.finish(FinishReason::EndOfText, self.text.len()))),
+                Ok(false) => {}
+                Err(reason) => return Err(self.stuck(reason)),
+            }
+        }
+        let stop_at = self.params.stop.iter().filter_map(|stop| self.text.find(stop.as_str())).min();
+        if let Some(stop_at) = stop_at {
+            return Ok(Some(self.finish(FinishReason::Stop, stop_at)));
+        }
+        let settled = self.text.len() - unsettled_len(&self.text, &self.params.stop);
+        if settled > self.emitted {
+            let text = self.take(settled);
+            return Ok(Some(TokenChunk { text, finish: None, usage: None, logprobs: std::mem::take(&mut self.logprobs) }));
+        }
+        Ok(None)
+    }
+
+    /// Samples until some text settles or generation ends. Between tokens,
+    /// and while the model runs, gives up as soon as it is interrupted; the
+    /// step in progress finishes on the model's worker, unused.
+    pub(super) async fn next_chunk(&mut self) -> Result<TokenChunk, LlmError> {
+        loop {
+            if let Some(last) = self.length_reached()? {
+                return Ok(last);
+            }
+            if let Some(interruption) = self.interrupted() {
+                return Err(self.abort(interruption));
+            }
+            let interrupted = interruption(self.params.cancel.clone(), self.params.deadline);
+            let tokens = tokio::select! {
+                tokens = self.step() => tokens?,
+                interruption = interrupted => return Err(self.abort(interruption)),
+            };
+            // Settled token by token, so a stop sequence ends generation
+            // where plain decoding would; the rest merge into one chunk.
+            let mut settled: Option<TokenChunk> = None;
+            for (token, logits) in tokens {
+                let completion = match self.append(token, &logits) {
+                    Sampled::Finished(last) => return Ok(merge(settled, last)),
+                    Sampled::Decode(completion) => completion,
+                };
+                let text = self.worker.run(move |backend| backend.decode(&completion)).await?;
+                match self.settle(text)? {
+                    Some(last) if last.finish.is_some() => return Ok(merge(settled, last)),
+                    Some(chunk) => settled = Some(merge(settled, chunk)),
+                    None => {}
+                }
+            }
+            if let Some(chunk) = settled {
+                return Ok(chunk);
+            }
+        }
+    }
+
+    /// `text` from the last emitted byte up to `end`.
+    fn take(&mut self, end: usize) -> String {
+        let text = self.text.get(self.emitted..end).unwrap_or_default().to_string();
+        self.emitted = self.emitted.max(end);
+        text
+    }
+
+    fn finish(&mut self, reason: FinishReason, end: usize) -> TokenChunk {
+        let usage = self.record();
+        let finish = Finish {
+            reason,
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens(),
+            truncated_tokens: self.truncated_tokens,
+        };
+        TokenChunk { text: self.take(end), finish: Some(finish), usage: Some(usage), logprobs: std::mem::take(&mut self.logprobs) }
+    }
+}
+
+/// `next` after `earlier`, as one chunk.
+fn merge(earlier: Option<TokenChunk>, next: TokenChunk) -> TokenChunk {
+    match earlier {
+        Some(mut earlier) => {
+            earlier.text.push_str(&next.text);
+            earlier.logprobs.extend(next.logprobs);
+            TokenChunk { finish: next.finish, usage: next.usage, ..earlier }
+        }
+        None => next,
+    }
+}
+
+enum State {
+    Prompt {
+        worker: ModelWorker,
+        draft: Option<DraftModel>,
+        prompt: Prompt,
+        params: GenerateParams,
+        metrics: Arc<LlmMetrics>,
+    },
+    Running(Box<Generation>),
+    Done,
+}
+
+/// Each poll runs the model only until the next chunk, so nothing more is
+/// computed once the stream is dropped. With a `draft`, tokens are decoded
+/// speculatively.
+pub(crate) fn stream(
+    worker: ModelWorker,
+    draft: Option<DraftModel>,
+    prompt: Prompt,
+    params: GenerateParams,
+    metrics: Arc<LlmMetrics>,
+) -> impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static {
+    stream::unfold(State::Prompt { worker, draft, prompt, params, metrics }, |state| async move {
+        let mut generation = match state {
+            State::Done => return None,
+            State::Running(generation) => generation,
+            State::Prompt { worker, draft, prompt, params, metrics } => {
+                match Generation::start(worker, draft, prompt, params, metrics).await {
+                    Ok(generation) => Box::new(generation),
+                    Err(e) => return Some((Err(e), State::Done)),
+                }
+            }
+        };
+        match generation.next_chunk().await {
+            Ok(chunk) if chunk.finish.is_some() => Some((Ok(chunk), State::Done)),
+            Ok(chunk) => Some((Ok(chunk), State::Running(generation))),
+            Err(e) => Some((Err(e), State::Done)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::LightLLM;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const WORDS: [&str; 7] = ["<eos>", "one ", "two ", "three ", "four ", "five ", "six "];
+
+    /// Counts upwards from the last word, then ends the completion.
+    #[derive(Default)]
+    struct Counter {
+        forwards: AtomicUsize,
+    }
+
+    impl ModelBackend for Counter {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            text.split_whitespace()
+                .map(|word| {
+                    WORDS.iter().position(|known| known.trim_end() == word).map(|i| i as u32)
+                        .ok_or_else(|| LlmError::Tokenizer(format!("unknown word {}", word)))
+                })
+                .collect()
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| WORDS[token as usize]).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            self.forwards.fetch_add(1, Ordering::SeqCst);
+            let next = tokens.last().map_or(1, |&last| last as usize + 1) % WORDS.len();
+            let mut logits = vec![0.0; WORDS.len()];
+            logits[next] = 10.0;
+            Ok(logits)
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    /// The chunks of a completion of "one", without the timings in the
+    /// last one's usage.
+    async fn chunks(model: &LightLLM, params: GenerateParams) -> Vec<TokenChunk> {
+        let mut chunks: Vec<TokenChunk> = model.generate_stream("one", params).map(Result::unwrap).collect().await;
+        for chunk in &mut chunks {
+            assert_eq!(chunk.usage.is_some(), chunk.finish.is_some(), "{:?}", chunk);
+            chunk.usage = None;
+        }
+        chunks
+    }
+
+    fn text(chunk: &str) -> TokenChunk {
+        TokenChunk { text: chunk.to_string(), finish: None, usage: None, logprobs: Vec::new() }
+    }
+
+    fn last(text: &str, reason: FinishReason, completion_tokens: usize) -> TokenChunk {
+        let finish = Finish { reason, prompt_tokens: 1, completion_tokens, truncated_tokens: 0 };
+        TokenChunk { text: text.to_string(), finish: Some(finish), usage: None, logprobs: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_per_token_until_end_of_text() {
+        let model = LightLLM::with_backend(Arc::new(Counter::default()));
+        let params = GenerateParams::new(16).with_temperature(0.0);
+        assert_eq!(chunks(&model, params.clone()).await, [
+            text("two "), text("three "), text("four "), text("five "), text("six "),
+            last("", FinishReason::EndOfText, 5),
+        ]);
+        assert_eq!(model.generate("one", GenerateParams::new(2).with_temperature(0.0)).await.unwrap().text, "two three ");
+
+        // Text that may begin a stop sequence waits for the next token.
+        let held = params.with_stop("four five!");
+        assert_eq!(chunks(&model, held).await, [
+            text("two "), text("three "), text("four five "), text("six "),
+            last("", FinishReason::EndOfText, 5),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_stop_sequence_ends_generation_early() {
+        let counter = Arc::new(Counter::default());
+        let model = LightLLM::with_backend(counter.clone());
+        let params = GenerateParams::new(16).with_temperature(0.0).with_stop("ee fo");
+        assert_eq!(chunks(&model, params).await, [text("two "), text("thr"), last("", FinishReason::Stop, 3)]);
+        assert_eq!(counter.forwards.load(Ordering::SeqCst), 3);
+
+        // Dropping the stream stops the model.
+        let counter = Arc::new(Counter::default());
+        let model = LightLLM::with_backend(counter.clone());
+        let mut stream = Box::pin(model.generate_stream("one", GenerateParams::new(16).with_temperature(0.0)));
+        assert_eq!(stream.next().await.unwrap().unwrap(), text("two "));
+        drop(stream);
+        tokio::task::yield_now().await;
+        assert_eq!(counter.forwards.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_and_deadline_stop_between_tokens() {
+        let counter = Arc::new(Counter::default());
+        let model = LightLLM::with_backend(counter.clone());
+        let cancel = CancellationToken::new();
+        let params = GenerateParams::new(16).with_temperature(0.0).with_cancellation(cancel.clone());
+        let mut stream = Box::pin(model.generate_stream("one", params));
+        for expected in ["two ", "three ", "four "] {
+            assert_eq!(stream.next().await.unwrap().unwrap(), text(expected));
+        }
+        cancel.cancel();
+        let partial = Partial { text: "two three four ".to_string(), prompt_tokens: 1, completion_tokens: 3 };
+        assert_eq!(stream.next().await.unwrap(), Err(LlmError::Cancelled(partial)));
+        assert!(stream.next().await.is_none());
+        assert!(counter.forwards.load(Ordering::SeqCst) <= 4, "{:?}", counter.forwards);
+
+        let counter = Arc::new(Counter::default());
+        let model = LightLLM::with_backend(counter.clone());
+        let expired = GenerateParams::new(16).with_deadline(Instant::now());
+        let partial = Partial { text: String::new(), prompt_tokens: 1, completion_tokens: 0 };
+        assert_eq!(model.generate("one", expired).await, Err(LlmError::Timeout(partial)));
+        assert_eq!(counter.forwards.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_params_refused() {
+        let params = GenerateParams::new(16);
+        assert_eq!(params.validate(), Ok(()));
+        for invalid in [
+            GenerateParams::new(0),
+            params.clone().with_temperature(-0.5),
+            params.clone().with_temperature(f32::NAN),
+            params.clone().with_top_k(0),
+            params.clone().with_top_p(0.0),
+            params.clone().with_top_p(1.5),
+            params.clone().with_repetition_penalty(-1.0),
+            params.clone().with_repetition_penalty(0.0),
+            params.clone().with_stop(""),
+            params.clone().with_logprobs(MAX_LOGPROBS + 1),
+        ] {
+            assert!(matches!(invalid.validate(), Err(LlmError::InvalidParams(_))), "{:?}", invalid);
+        }
+        assert_eq!(params.clone().with_top_p(1.0).with_top_k(1).with_repetition_penalty(0.5).validate(), Ok(()));
+
+        let counter = Arc::new(Counter::default());
+        let model = LightLLM::with_backend(counter.clone());
+        let results: Vec<_> = model.generate_stream("one", params.with_top_p(2.0)).collect().await;
+        assert!(matches!(results.as_slice(), [Err(LlmError::InvalidParams(_))]));
+        assert_eq!(counter.forwards.load(Ordering::SeqCst), 0);
+    }
+
+    /// One token per character, behind a BOS token; ends every completion
+    /// at once, recording the prompt it was given.
+    #[derive(Default)]
+    struct Chars {
+        seen: parking_lot::Mutex<Vec<u32>>,
+    }
+
+    impl ModelBackend for Chars {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok([1].into_iter().chain(text.chars().map(u32::from)).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().filter_map(|&token| char::from_u32(token)).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            *self.seen.lock() = tokens.to_vec();
+            Ok(vec![1.0])
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            Some(1)
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[tokio::test]
+    async fn test_context_budget_counts_tokens_not_bytes() {
+        let chars = Arc::new(Chars::default());
+        let model = LightLLM::with_backend(chars.clone());
+        let params = GenerateParams::new(96);
+
+        // 6000 bytes, but 2001 tokens with BOS: well within budget.
+        let cjk = "語".repeat(2000);
+        let finish = model.generate(&cjk, params.clone()).await.unwrap().finish;
+        assert_eq!((finish.prompt_tokens, finish.truncated_tokens), (2001, 0));
+
+        // Under 4096 bytes, yet over the 4000 tokens left beside max_tokens.
+        let ascii = format!("{}{}", "a".repeat(10), "b".repeat(4050));
+        assert_eq!(ascii.len(), 4060);
+        let refused = model.generate(&ascii, params.clone()).await;
+        assert_eq!(refused, Err(LlmError::PromptTooLong { tokens: 4061, limit: 4000 }));
+
+        let start = model.generate(&ascii, params.clone().with_truncation(TruncationPolicy::TruncateStart)).await.unwrap();
+        assert_eq!((start.finish.prompt_tokens, start.finish.truncated_tokens), (4000, 61));
+        let seen = chars.seen.lock().clone();
+        assert_eq!((seen[0], seen[1], seen.len()), (1, u32::from('b'), 4000));
+
+        let end = model.generate(&ascii, params.clone().with_truncation(TruncationPolicy::TruncateEnd)).await.unwrap();
+        assert_eq!((end.finish.prompt_tokens, end.finish.truncated_tokens), (4000, 61));
+        let seen = chars.seen.lock().clone();
+        assert_eq!((seen[1], seen[3999]), (u32::from('a'), u32::from('b')));
+
+        // A CJK prompt over the token budget is refused however it is measured.
+        let long = "語".repeat(4000);
+        assert!(matches!(model.generate(&long, params).await, Err(LlmError::PromptTooLong { tokens: 4001, .. })));
+        assert!(matches!(model.generate("hi", GenerateParams::new(4096)).await, Err(LlmError::InvalidParams(_))));
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use super::chat::{self, Piece};
+use super::model::{LlmError, ModelBackend};
+
+/// Most alternatives `GenerateParams.logprobs` may ask for at each step.
+pub const MAX_LOGPROBS: usize = 20;
+
+/// A token and its natural-log probability under the model.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: u32,
+    pub logprob: f32,
+}
+
+/// A completion token's log probability, with the likeliest tokens at its
+/// step, likeliest first and the lower token first on a tie.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: f32,
+    pub top: Vec<TopLogprob>,
+}
+
+/// The log-softmax of `logits`, the model's own distribution before any
+/// penalty or temperature. Summed in order in `f64`, so the same logits
+/// always give the same values; NaN logits get negative infinity.
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().filter(|logit| !logit.is_nan()).fold(f32::NEG_INFINITY, f32::max) as f64;
+    if max == f64::NEG_INFINITY {
+        return vec![f32::NEG_INFINITY; logits.len()];
+    }
+    let sum: f64 = logits.iter().filter(|logit| !logit.is_nan()).map(|&logit| (logit as f64 - max).exp()).sum();
+    let total = max + sum.ln();
+    logits.iter().map(|&logit| if logit.is_nan() { f32::NEG_INFINITY } else { (logit as f64 - total) as f32 }).collect()
+}
+
+/// `token`'s log probability given `logits`, with the `top` likeliest.
+pub(super) fn token_logprob(logits: &[f32], token: u32, top: usize) -> TokenLogprob {
+    let logprobs = log_softmax(logits);
+    let mut ranked: Vec<TopLogprob> = logprobs.iter()
+        .enumerate()
+        .map(|(token, &logprob)| TopLogprob { token: token as u32, logprob })
+        .collect();
+    ranked.sort_by(|a, b| b.logprob.partial_cmp(&a.logprob).unwrap_or(Ordering::Equal).then(a.token.cmp(&b.token)));
+    ranked.truncate(top);
+    let logprob = logprobs.get(token as usize).copied().unwrap_or(f32::NEG_INFINITY);
+    TokenLogprob { token, logprob, top: ranked }
+}
+
+/// The log probability of each of `completion`'s tokens following
+/// `prompt`, fed to the model token by token as generation feeds it, so
+/// the values match those reported while sampling. The completion is
+/// encoded on its own, without a BOS token.
+pub(super) fn score(backend: &dyn ModelBackend, prompt: &str, completion: &str) -> Result<Vec<f32>, LlmError> {
+    let prompt = backend.encode(prompt)?;
+    let completion = chat::encode(backend, &[Piece::Text(completion.to_string())])?;
+    let tokens = prompt.len() + completion.len();
+    if tokens > backend.context_length() {
+        return Err(LlmError::PromptTooLong { tokens, limit: backend.context_length() });
+    }
+    if completion.is_empty() {
+        return Ok(Vec::new());
+    }
+    if prompt.is_empty() {
+        return Err(LlmError::InvalidParams("scoring needs a prompt to condition on".to_string()));
+    }
+    let mut cache = backend.new_cache()?;
+    let mut input = prompt;
+    let mut scores = Vec::with_capacity(completion.len());
+    for &token in &completion {
+        let logits = backend.forward(&mut cache, &input)?;
+        cache.advance(input.len());
+        scores.push(token_logprob(&logits, token, 0).logprob);
+        input = vec![token];
+    }
+    Ok(scores)
+}
+
+/// Each label's probability as the answer to a prompt asking which of
+/// `labels` fits `text`: the summed log probability of the label's tokens,
+/// normalised over `labels`. Likeliest first; ties keep `labels`' order.
+pub(super) fn classify(backend: &dyn ModelBackend, text: &str, labels: &[String]) -> Result<Vec<(String, f32)>, LlmError> {
+    if labels.is_empty() {
+        return Err(LlmError::InvalidParams("classification needs at least one label".to_string()));
+    }
+    let prompt = format!("Classify the text as one of: {}.\nText: {}\nLabel:", labels.join(", "), text);
+    let mut logprobs = Vec::with_capacity(labels.len());
+    for label in labels {
+        let tokens = score(backend, &prompt, &format!(" {}", label))?;
+        if tokens.is_empty() {
+            return Err(LlmError::InvalidParams(format!("label {:?} encodes to no tokens", label)));
+        }
+        logprobs.push(tokens.iter().map(|&logprob| logprob as f64).sum::<f64>());
+    }
+    let max = logprobs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let weights: Vec<f64> = logprobs.iter().map(|&logprob| (logprob - max).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    let mut scores: Vec<(String, f32)> =
+        labels.iter().zip(weights).map(|(label, weight)| (label.clone(), (weight / total) as f32)).collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{GenerateParams, KvCache, LightLLM};
+    use std::sync::Arc;
+
+    /// A token per letter from a to h. The logits depend on the whole
+    /// sequence, which the cache records, and never end the completion.
+    struct Waves;
+
+    impl ModelBackend for Waves {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().filter(|byte| (b'a'..=b'h').contains(byte)).map(|byte| u32::from(byte - b'a')).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| char::from(b'a' + token as u8)).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(Vec::<u32>::new()))
+        }
+
+        fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            let seen = cache.state_mut::<Vec<u32>>().unwrap();
+            seen.extend_from_slice(tokens);
+            let phase = seen.iter().enumerate().map(|(i, &token)| (i as f32 + 1.0) * token as f32).sum::<f32>();
+            Ok((0..8).map(|i| (phase * 0.37 + i as f32 * 1.3).sin() * 2.0).collect())
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[tokio::test]
+    async fn test_score_matches_logprobs_reported_while_sampling() {
+        let model = LightLLM::with_backend(Arc::new(Waves));
+        let params = GenerateParams::new(12).with_temperature(1.0).with_seed(3).with_logprobs(3);
+        let completion = model.generate("abc", params.clone()).await.unwrap();
+        assert_eq!(completion.logprobs.len(), 12);
+        for (i, step) in completion.logprobs.iter().enumerate() {
+            assert_eq!(step.top.len(), 3, "step {}", i);
+            assert!(step.top.windows(2).all(|pair| pair[0].logprob >= pair[1].logprob), "{:?}", step.top);
+            assert!(step.logprob <= step.top[0].logprob && step.logprob < 0.0, "{:?}", step);
+        }
+        let tokens: Vec<u32> = completion.logprobs.iter().map(|step| step.token).collect();
+        assert_eq!(Waves.decode(&tokens).unwrap(), completion.text);
+
+        // Stable for a fixed seed, and teacher forcing gives the same values.
+        assert_eq!(model.generate("abc", params).await.unwrap().logprobs, completion.logprobs);
+        let reported: Vec<f32> = completion.logprobs.iter().map(|step| step.logprob).collect();
+        assert_eq!(model.score("abc", &completion.text).await.unwrap(), reported);
+        assert!(model.generate("abc", GenerateParams::new(2)).await.unwrap().logprobs.is_empty());
+    }
+
+    #[test]
+    fn test_alternatives_ranked_likeliest_first() {
+        let logprobs = log_softmax(&[1.0, 3.0, f32::NAN, 3.0, 2.0]);
+        let total: f64 = logprobs.iter().map(|&logprob| (logprob as f64).exp()).sum();
+        assert!((total - 1.0).abs() < 1e-6, "{}", total);
+        assert_eq!(logprobs[2], f32::NEG_INFINITY);
+
+        let chosen = token_logprob(&[1.0, 3.0, f32::NAN, 3.0, 2.0], 4, 3);
+        assert_eq!(chosen.logprob, logprobs[4]);
+        let ranked: Vec<u32> = chosen.top.iter().map(|top| top.token).collect();
+        assert_eq!(ranked, [1, 3, 4]);
+        assert!(token_logprob(&[1.0, 2.0], 0, 0).top.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_classify_normalises_label_scores() {
+        let model = LightLLM::with_backend(Arc::new(Waves));
+        let scores = model.classify("a cafe", &["bad", "deed", "fade"]).await.unwrap();
+        assert_eq!(scores.len(), 3);
+        assert!(scores.windows(2).all(|pair| pair[0].1 >= pair[1].1), "{:?}", scores);
+        let total: f32 = scores.iter().map(|(_, score)| score).sum();
+        assert!((total - 1.0).abs() < 1e-5, "{}", total);
+        let prompt = "Classify the text as one of: bad, deed, fade.\nText: a cafe\nLabel:";
+        let best: f32 = model.score(prompt, &format!(" {}", scores[0].0)).await.unwrap().iter().sum();
+        let worst: f32 = model.score(prompt, &format!(" {}", scores[2].0)).await.unwrap().iter().sum();
+        assert!(best >= worst);
+        assert!(matches!(model.classify("a cafe", &["bad", "xyz"]).await, Err(LlmError::InvalidParams(_))));
+    }
+}
@@ -0,0 +1,283 @@
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{init, Dropout, Init, Linear, VarBuilder, VarMap};
+use safetensors::SafeTensors;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use super::model::LlmError;
+
+/// Metadata key the adapter file keeps its `LoraConfig` under.
+const CONFIG_KEY: &str = "lora_config";
+const A_SUFFIX: &str = "lora_a";
+const B_SUFFIX: &str = "lora_b";
+
+/// Low-rank adapters: each targeted projection `W` becomes
+/// `W + alpha / rank * B A`, with only `A` and `B` trained.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraConfig {
+    pub rank: usize,
+    pub alpha: f64,
+    /// Projections adapted, by the last part of their module name:
+    /// `q_proj` in safetensors models, `attn_q` in GGUF ones.
+    pub target_modules: Vec<String>,
+    /// Applied to an adapter's input while training.
+    pub dropout: f32,
+}
+
+impl Default for LoraConfig {
+    fn default() -> Self {
+        LoraConfig {
+            rank: 8,
+            alpha: 16.0,
+            target_modules: vec!["q_proj".to_string(), "v_proj".to_string()],
+            dropout: 0.0,
+        }
+    }
+}
+
+impl LoraConfig {
+    pub fn scale(&self) -> f64 {
+        self.alpha / self.rank.max(1) as f64
+    }
+
+    pub fn targets(&self, module: &str) -> bool {
+        let name = module.rsplit('.').next().unwrap_or(module);
+        self.target_modules.iter().any(|target| target == name)
+    }
+}
+
+fn mismatch(module: &str, reason: impl Into<String>) -> LlmError {
+    LlmError::AdapterMismatch { module: module.to_string(), reason: reason.into() }
+}
+
+fn file_error(path: &Path, e: impl std::fmt::Display) -> LlmError {
+    LlmError::ModelLoad { path: path.to_path_buf(), reason: format!("adapter: {}", e) }
+}
+
+/// A trained set of adapters, apart from the model they adapt.
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    config: LoraConfig,
+    /// `(a, b)` by module: `a` is `(rank, in)` and `b` is `(out, rank)`.
+    modules: BTreeMap<String, (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    pub fn new(config: LoraConfig, modules: BTreeMap<String, (Tensor, Tensor)>) -> Result<Self, LlmError> {
+        for (module, (a, b)) in &modules {
+            let (a_dims, b_dims) = (a.dims(), b.dims());
+            if a_dims.len() != 2 || b_dims.len() != 2 || a_dims[0] != config.rank || b_dims[1] != config.rank {
+                return Err(mismatch(module, format!(
+                    "rank {} adapter has A {:?} and B {:?}", config.rank, a_dims, b_dims
+                )));
+            }
+        }
+        Ok(LoraAdapter { config, modules })
+    }
+
+    /// The adapters a `VarMap` built with `LoraLinear` holds.
+    pub fn from_varmap(config: LoraConfig, varmap: &VarMap) -> Result<Self, LlmError> {
+        let data = varmap.data().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let tensors: HashMap<String, Tensor> = data.iter()
+            .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+            .collect();
+        Self::from_tensors(config, tensors)
+    }
+
+    fn from_tensors(config: LoraConfig, mut tensors: HashMap<String, Tensor>) -> Result<Self, LlmError> {
+        let names: Vec<String> = tensors.keys()
+            .filter_map(|name| name.strip_suffix(A_SUFFIX)?.strip_suffix('.').map(str::to_string))
+            .collect();
+        let mut modules = BTreeMap::new();
+        for module in names {
+            let a = tensors.remove(&format!("{}.{}", module, A_SUFFIX));
+            let b = tensors.remove(&format!("{}.{}", module, B_SUFFIX));
+            match (a, b) {
+                (Some(a), Some(b)) => modules.insert(module, (a, b)),
+                _ => return Err(mismatch(&module, "has an A matrix but no B")),
+            };
+        }
+        if let Some(stray) = tensors.keys().find(|name| name.ends_with(B_SUFFIX)) {
+            return Err(mismatch(stray.trim_end_matches(B_SUFFIX).trim_end_matches('.'), "has a B matrix but no A"));
+        }
+        Self::new(config, modules)
+    }
+
+    pub fn config(&self) -> &LoraConfig {
+        &self.config
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.modules.keys().map(String::as_str)
+    }
+
+    /// A safetensors file of just the adapter matrices, the config in its
+    /// metadata.
+    pub fn save(&self, path: &Path) -> Result<(), LlmError> {
+        let mut tensors = Vec::with_capacity(self.modules.len() * 2);
+        for (module, (a, b)) in &self.modules {
+            for (suffix, tensor) in [(A_SUFFIX, a), (B_SUFFIX, b)] {
+                let tensor = tensor.to_device(&Device::Cpu)
+                    .and_then(|tensor| tensor.to_dtype(DType::F32))
+                    .and_then(|tensor| tensor.contiguous())
+                    .map_err(|e| file_error(path, e))?;
+                tensors.push((format!("{}.{}", module, suffix), tensor));
+            }
+        }
+        let config = serde_json::to_string(&self.config).map_err(|e| file_error(path, e))?;
+        let metadata = Some(HashMap::from([(CONFIG_KEY.to_string(), config)]));
+        safetensors::serialize_to_file(tensors, &metadata, path).map_err(|e| file_error(path, e))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LlmError> {
+        let bytes = fs::read(path).map_err(|e| file_error(path, e))?;
+        let (_, metadata) = SafeTensors::read_metadata(&bytes).map_err(|e| file_error(path, e))?;
+        let config = metadata.metadata()
+            .as_ref()
+            .and_then(|metadata| metadata.get(CONFIG_KEY))
+            .ok_or_else(|| file_error(path, "no LoRA config in the file's metadata"))?;
+        let config: LoraConfig = serde_json::from_str(config).map_err(|e| file_error(path, e))?;
+        let tensors = candle_core::safetensors::load_buffer(&bytes, &Device::Cpu).map_err(|e| file_error(path, e))?;
+        Self::from_tensors(config, tensors)
+    }
+
+    /// Fails naming the first module whose weight the base model lacks or
+    /// whose shape is not the adapter's. `shape_of` gives a base weight's
+    /// shape by name.
+    pub(super) fn check(&self, shape_of: impl Fn(&str) -> Option<Vec<usize>>) -> Result<(), LlmError> {
+        for (module, (a, b)) in &self.modules {
+            let weight = format!("{}.weight", module);
+            let shape = shape_of(&weight).ok_or_else(|| mismatch(module, format!("the base model has no {}", weight)))?;
+            let adapted = [b.dims()[0], a.dims()[1]];
+            if shape != adapted {
+                return Err(mismatch(module, format!("adapter is {:?}, the base weight {:?}", adapted, shape)));
+            }
+        }
+        Ok(())
+    }
+
+    /// `alpha / rank * B A` for every adapted weight, by weight name.
+    pub(super) fn deltas(&self, device: &Device) -> candle_core::Result<HashMap<String, Tensor>> {
+        self.modules.iter()
+            .map(|(module, (a, b))| {
+                let delta = (b.to_device(device)?.to_dtype(DType::F32)?
+                    .matmul(&a.to_device(device)?.to_dtype(DType::F32)?)? * self.config.scale())?;
+                Ok((format!("{}.weight", module), delta))
+            })
+            .collect()
+    }
+}
+
+/// A projection with its weight frozen and, if the config targets it, a
+/// trainable adapter beside it. The adapter's `B` starts at zero, so a new
+/// layer computes just what the base one does.
+pub struct LoraLinear {
+    base: Linear,
+    adapter: Option<(Tensor, Tensor)>,
+    scale: f64,
+    dropout: Dropout,
+}
+
+impl LoraLinear {
+    /// `weight` is `(out, in)`. The adapter's matrices are made under
+    /// `vb.pp(module)`, so only they are in its `VarMap`.
+    pub fn new(
+        module: &str,
+        weight: Tensor,
+        bias: Option<Tensor>,
+        config: &LoraConfig,
+        vb: VarBuilder,
+    ) -> candle_core::Result<Self> {
+        let (out_dim, in_dim) = weight.dims2()?;
+        let adapter = if config.targets(module) {
+            let vb = vb.pp(module);
+            let a = vb.get_with_hints((config.rank, in_dim), A_SUFFIX, init::DEFAULT_KAIMING_UNIFORM)?;
+            let b = vb.get_with_hints((out_dim, config.rank), B_SUFFIX, Init::Const(0.0))?;
+            Some((a, b))
+        } else {
+            None
+        };
+        Ok(LoraLinear {
+            base: Linear::new(weight.detach()?, bias.map(|bias| bias.detach()).transpose()?),
+            adapter,
+            scale: config.scale(),
+            dropout: Dropout::new(config.dropout),
+        })
+    }
+
+    pub fn forward_t(&self, xs: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+        let base = self.base.forward(xs)?;
+        match &self.adapter {
+            Some((a, b)) => {
+                let xs = self.dropout.forward(xs, train)?;
+                let low_rank = xs.broadcast_matmul(&a.t()?)?.broadcast_matmul(&b.t()?)?;
+                base + (low_rank * self.scale)?
+            }
+            None => Ok(base),
+        }
+    }
+}
+
+impl Module for LoraLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        self.forward_t(xs, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(rank: usize, shapes: &[(&str, usize, usize)]) -> LoraAdapter {
+        let config = LoraConfig { rank, alpha: 2.0 * rank as f64, ..LoraConfig::default() };
+        let modules = shapes.iter()
+            .map(|(module, out_dim, in_dim)| {
+                let a = Tensor::randn(0f32, 1.0, (rank, *in_dim), &Device::Cpu).unwrap();
+                let b = Tensor::randn(0f32, 1.0, (*out_dim, rank), &Device::Cpu).unwrap();
+                (module.to_string(), (a, b))
+            })
+            .collect();
+        LoraAdapter::new(config, modules).unwrap()
+    }
+
+    #[test]
+    fn test_adapter_file_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("adapter.safetensors");
+        let saved = adapter(2, &[("layers.0.q_proj", 6, 4), ("layers.0.v_proj", 6, 4)]);
+        saved.save(&path).unwrap();
+        let loaded = LoraAdapter::load(&path).unwrap();
+        assert_eq!(loaded.config(), saved.config());
+        assert_eq!(loaded.modules().collect::<Vec<_>>(), ["layers.0.q_proj", "layers.0.v_proj"]);
+        let (saved, loaded) = (saved.deltas(&Device::Cpu).unwrap(), loaded.deltas(&Device::Cpu).unwrap());
+        let weight = "layers.0.q_proj.weight";
+        assert_eq!(loaded[weight].dims(), [6, 4]);
+        assert_eq!(loaded[weight].to_vec2::<f32>().unwrap(), saved[weight].to_vec2::<f32>().unwrap());
+
+        std::fs::write(&path, b"not safetensors").unwrap();
+        assert!(matches!(LoraAdapter::load(&path), Err(LlmError::ModelLoad { .. })));
+    }
+
+    #[test]
+    fn test_mismatched_adapter_names_the_module() {
+        let adapter = adapter(2, &[("layers.0.q_proj", 6, 4), ("layers.0.v_proj", 6, 4)]);
+        let shapes = |v_in: usize| move |name: &str| match name {
+            "layers.0.q_proj.weight" => Some(vec![6, 4]),
+            "layers.0.v_proj.weight" => Some(vec![6, v_in]),
+            _ => None,
+        };
+        assert_eq!(adapter.check(shapes(4)), Ok(()));
+        match adapter.check(shapes(8)) {
+            Err(LlmError::AdapterMismatch { module, .. }) => assert_eq!(module, "layers.0.v_proj"),
+            other => panic!("expected AdapterMismatch, got {:?}", other),
+        }
+
+        let config = LoraConfig { rank: 3, ..LoraConfig::default() };
+        let a = Tensor::zeros((2, 4), DType::F32, &Device::Cpu).unwrap();
+        let b = Tensor::zeros((6, 2), DType::F32, &Device::Cpu).unwrap();
+        let modules = BTreeMap::from([("layers.1.q_proj".to_string(), (a, b))]);
+        assert!(matches!(LoraAdapter::new(config, modules), Err(LlmError::AdapterMismatch { module, .. }) if module == "layers.1.q_proj"));
+    }
+}
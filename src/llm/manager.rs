@@ -0,0 +1,428 @@
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::admission::{AdmissionConfig, AdmissionController, SystemResources};
+use super::arch::ARCHITECTURE_FILE;
+use super::cache::{CacheConfig, InferenceCache};
+use super::generate::GenerateParams;
+use super::model::{check_model_files, LightLLM, LlmError};
+use super::queue::{InferenceQueue, QueueConfig};
+use super::startup::{StartupCache, STARTUP_CACHE_DIR};
+use super::usage::LlmMetrics;
+use super::verify;
+use crate::node::config::LLMConfig;
+use crate::node::network::{LlmCapability, Network, CAPABILITY_LLM};
+
+/// Model registry directory inside `storage_path`.
+pub const MODELS_DIR: &str = "models";
+/// How long `activate` waits for the old model's requests to finish.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Names the version last activated, inside the registry.
+const ACTIVE_FILE: &str = "active";
+const MANIFEST_FILE: &str = "manifest.json";
+const PARTIAL_SUFFIX: &str = ".partial";
+const WARM_UP_PROMPT: &str = "Hello";
+const WARM_UP_TOKENS: usize = 1;
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// A model version in the registry, as its `manifest.json` records it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledModel {
+    pub version: String,
+    /// File names inside the version's directory.
+    pub model_file: String,
+    pub tokenizer_file: String,
+    /// Checked each time the version is activated.
+    pub model_sha256: String,
+    pub tokenizer_sha256: String,
+    pub size_bytes: u64,
+}
+
+/// What `activate` swapped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Activation {
+    pub version: String,
+    /// `None` when nothing was serving, or the model the config names was.
+    pub previous: Option<String>,
+    /// Whether the previous model's requests all finished within the drain
+    /// timeout; it is freed after the last of them either way.
+    pub drained: bool,
+}
+
+/// A loaded, warmed-up model and the queue serving it. Requests hold it
+/// while they run, so it stays loaded until the last one finishes.
+pub struct ServingModel {
+    version: Option<String>,
+    model: LightLLM,
+    queue: Arc<InferenceQueue>,
+}
+
+impl ServingModel {
+    /// The registry version, or `None` for the model the config names.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn model(&self) -> &LightLLM {
+        &self.model
+    }
+
+    pub fn queue(&self) -> Arc<InferenceQueue> {
+        Arc::clone(&self.queue)
+    }
+}
+
+fn registry_error(path: &Path, e: impl ToString) -> LlmError {
+    LlmError::Registry { path: path.to_path_buf(), reason: e.to_string() }
+}
+
+/// Versions name directories, so they are kept to letters, digits, `.`,
+/// `-` and `_`.
+fn check_version(version: &str) -> Result<(), LlmError> {
+    let valid = !version.is_empty()
+        && !version.starts_with('.')
+        && version != ACTIVE_FILE
+        && !version.ends_with(PARTIAL_SUFFIX)
+        && version.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !valid {
+        return Err(LlmError::InvalidParams(format!("bad model version {:?}", version)));
+    }
+    Ok(())
+}
+
+/// Installed model versions under `storage_path/models/`, one directory
+/// each, and the one serving. A version is loaded, verified and warmed up
+/// beside the serving model, then swapped in; requests already running
+/// finish on the old model, which is freed after them. A version that
+/// fails to load never replaces the model serving. With `cache_entries`
+/// set, every version's queue shares one `InferenceCache`, flushed as each
+/// is swapped in. Loads reuse what the `StartupCache` in
+/// `storage_path/llm-cache/` kept of unchanged files.
+pub struct ModelManager {
+    dir: PathBuf,
+    /// Device, queue and template settings every version runs with.
+    config: LLMConfig,
+    metrics: Arc<LlmMetrics>,
+    cache: Option<Arc<InferenceCache>>,
+    /// Shared by every version's queue, so a draining model's requests
+    /// still count.
+    admission: Arc<AdmissionController>,
+    /// Shared by every version loaded, unless `startup_cache` is off.
+    startup: Option<Arc<StartupCache>>,
+    drain_timeout: Duration,
+    /// Told of each model swapped in, to advertise to peers.
+    network: Option<Arc<Network>>,
+    serving: RwLock<Option<Arc<ServingModel>>>,
+    /// Held through an activation or removal, so they run one at a time.
+    swap: AsyncMutex<()>,
+}
+
+impl ModelManager {
+    /// The registry under `storage_path`, created if missing. Nothing is
+    /// loaded until `start` or `activate`.
+    pub fn open(storage_path: impl AsRef<Path>, config: LLMConfig) -> Result<Self, LlmError> {
+        let dir = storage_path.as_ref().join(MODELS_DIR);
+        fs::create_dir_all(&dir).map_err(|e| registry_error(&dir, e))?;
+        let cache = (config.cache_entries > 0).then(|| Arc::new(InferenceCache::new(CacheConfig::from(&config))));
+        let admission = Arc::new(AdmissionController::new(AdmissionConfig::from(&config), Arc::new(SystemResources)));
+        let startup = if config.startup_cache {
+            let dir = storage_path.as_ref().join(STARTUP_CACHE_DIR);
+            match StartupCache::open(&dir) {
+                Ok(startup) => Some(Arc::new(startup)),
+                Err(e) => {
+                    warn!("Loading models without the startup cache, {} is unusable: {}", dir.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(ModelManager {
+            dir,
+            config,
+            metrics: Arc::new(LlmMetrics::new()),
+            cache,
+            admission,
+            startup,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            network: None,
+            serving: RwLock::new(None),
+            swap: AsyncMutex::new(()),
+        })
+    }
+
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Admits requests through `admission` instead of one sized from the
+    /// config and the host alone.
+    pub fn with_admission(mut self, admission: Arc<AdmissionController>) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Advertises each model swapped in to `network`'s peers.
+    pub fn with_network(mut self, network: Arc<Network>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Usage of every version served, to register with the node's
+    /// `MetricsRegistry`.
+    pub fn metrics(&self) -> Arc<LlmMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The completions cached for the serving model, if caching is on.
+    pub fn cache(&self) -> Option<Arc<InferenceCache>> {
+        self.cache.clone()
+    }
+
+    /// The model serving now; hold it for the length of a request.
+    pub fn serving(&self) -> Option<Arc<ServingModel>> {
+        self.serving.read().clone()
+    }
+
+    pub fn serving_version(&self) -> Option<String> {
+        self.serving.read().as_ref().and_then(|serving| serving.version.clone())
+    }
+
+    /// Serves the version last activated, or else the model the config
+    /// names, as at node start.
+    pub async fn start(&self) -> Result<(), LlmError> {
+        if let Some(version) = self.recorded_version()? {
+            self.activate(&version).await?;
+            return Ok(());
+        }
+        let _swap = self.swap.lock().await;
+        let serving = self.load(None, self.config.clone()).await?;
+        self.swap_in(serving).await;
+        Ok(())
+    }
+
+    /// Copies the files into the registry as `version`, recording their
+    /// hashes, with the `config.json` beside the model if there is one.
+    /// Blocks on the copy, so call it off the async runtime.
+    pub fn install(&self, version: &str, model_path: &Path, tokenizer_path: &Path) -> Result<InstalledModel, LlmError> {
+        check_version(version)?;
+        check_model_files(model_path, tokenizer_path)?;
+        let target = self.dir.join(version);
+        if target.exists() {
+            return Err(registry_error(&target, "version is already installed"));
+        }
+        // Copied beside the registry first, so a failed copy never lists.
+        let partial = self.dir.join(format!("{}{}", version, PARTIAL_SUFFIX));
+        let _ = fs::remove_dir_all(&partial);
+        fs::create_dir_all(&partial).map_err(|e| registry_error(&partial, e))?;
+        let copy = |source: &Path| -> Result<(String, String, u64), LlmError> {
+            let name = source.file_name().map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| registry_error(source, "not a file name"))?;
+            let bytes = fs::copy(source, partial.join(&name)).map_err(|e| registry_error(source, e))?;
+            let sha256 = verify::sha256_file(&partial.join(&name), &mut |_, _, _| {})?;
+            Ok((name, sha256, bytes))
+        };
+        let (model_file, model_sha256, model_bytes) = copy(model_path)?;
+        let (tokenizer_file, tokenizer_sha256, tokenizer_bytes) = copy(tokenizer_path)?;
+        let architecture = model_path.with_file_name(ARCHITECTURE_FILE);
+        if architecture.is_file() {
+            copy(&architecture)?;
+        }
+        let installed = InstalledModel {
+            version: version.to_string(),
+            model_file,
+            tokenizer_file,
+            model_sha256,
+            tokenizer_sha256,
+            size_bytes: model_bytes + tokenizer_bytes,
+        };
+        let manifest = serde_json::to_vec_pretty(&installed).map_err(|e| registry_error(&partial, e))?;
+        fs::write(partial.join(MANIFEST_FILE), manifest).map_err(|e| registry_error(&partial, e))?;
+        fs::rename(&partial, &target).map_err(|e| registry_error(&target, e))?;
+        info!("Installed model {} ({} bytes)", version, installed.size_bytes);
+        Ok(installed)
+    }
+
+    /// Every installed version, by version.
+    pub fn list_models(&self) -> Result<Vec<InstalledModel>, LlmError> {
+        let mut models = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(|e| registry_error(&self.dir, e))? {
+            let entry = entry.map_err(|e| registry_error(&self.dir, e))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_dir() && check_version(&name).is_ok() {
+                models.push(self.installed(&name)?);
+            }
+        }
+        models.sort_by(|a, b| a.version.cmp(&b.version));
+        Ok(models)
+    }
+
+    fn installed(&self, version: &str) -> Result<InstalledModel, LlmError> {
+        check_version(version)?;
+        let path = self.dir.join(version).join(MANIFEST_FILE);
+        let manifest = match fs::read(&path) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(LlmError::UnknownModel(version.to_string())),
+            Err(e) => return Err(registry_error(&path, e)),
+        };
+        serde_json::from_slice(&manifest).map_err(|e| registry_error(&path, e))
+    }
+
+    /// Loads `version`, checking its hashes, runs a short warm-up
+    /// generation on it, then swaps it in and waits up to the drain
+    /// timeout for the old model's requests. On any failure the serving
+    /// model is left as it was. The version serves again after a restart.
+    pub async fn activate(&self, version: &str) -> Result<Activation, LlmError> {
+        let _swap = self.swap.lock().await;
+        let installed = self.installed(version)?;
+        let root = self.dir.join(version);
+        let config = LLMConfig {
+            model_path: root.join(&installed.model_file).display().to_string(),
+            tokenizer_path: root.join(&installed.tokenizer_file).display().to_string(),
+            model_sha256: Some(installed.model_sha256),
+            tokenizer_sha256: Some(installed.tokenizer_sha256),
+            format: None,
+            model_config_path: None,
+            ..self.config.clone()
+        };
+        let serving = self.load(Some(version.to_string()), config).await?;
+        let previous = self.serving_version();
+        self.record_version(version)?;
+        let drained = self.swap_in(serving).await;
+        Ok(Activation { version: version.to_string(), previous, drained })
+    }
+
+    /// Deletes an installed version other than the one serving.
+    pub async fn remove(&self, version: &str) -> Result<InstalledModel, LlmError> {
+        let _swap = self.swap.lock().await;
+        let installed = self.installed(version)?;
+        if self.serving_version().as_deref() == Some(version) {
+            return Err(LlmError::ModelInUse(version.to_string()));
+        }
+        if self.recorded_version()?.as_deref() == Some(version) {
+            let path = self.dir.join(ACTIVE_FILE);
+            fs::remove_file(&path).map_err(|e| registry_error(&path, e))?;
+        }
+        let path = self.dir.join(version);
+        fs::remove_dir_all(&path).map_err(|e| registry_error(&path, e))?;
+        info!("Removed model {}", version);
+        Ok(installed)
+    }
+
+    async fn load(&self, version: Option<String>, config: LLMConfig) -> Result<ServingModel, LlmError> {
+        let started = Instant::now();
+        let startup = self.startup.clone();
+        let load = move || LightLLM::from_config_with_cache(&config, startup.as_deref()).map(|model| (model, config));
+        let model = tokio::task::spawn_blocking(load)
+            .await
+            .map_err(|e| LlmError::InferenceFailed(e.to_string()))?;
+        let (model, config) = model?;
+        // Warmed up before taking the shared metrics, so only requests count.
+        model.generate(WARM_UP_PROMPT, GenerateParams::new(WARM_UP_TOKENS).with_temperature(0.0)).await?;
+        let model = model.with_metrics(Arc::clone(&self.metrics));
+        let name = version.as_deref().unwrap_or(&config.model_path);
+        let mut queue = model.start_queue(QueueConfig::from(&config)).with_admission(Arc::clone(&self.admission));
+        if let Some(cache) = &self.cache {
+            // Named by version too, so a request the old model finishes
+            // after the flush cannot be served to the new one.
+            queue = queue.with_cache(Arc::clone(cache), name);
+        }
+        info!("Loaded model {} in {:?}", name, started.elapsed());
+        let queue = Arc::new(queue);
+        Ok(ServingModel { version, model, queue })
+    }
+
+    /// Serves `serving`, then waits for the old model's requests. Returns
+    /// whether they finished in time.
+    async fn swap_in(&self, serving: ServingModel) -> bool {
+        if let Some(network) = &self.network {
+            network.advertise_llm(Some(self.capability(&serving, network)));
+        }
+        let previous = self.serving.write().replace(Arc::new(serving));
+        if let Some(cache) = &self.cache {
+            cache.flush();
+        }
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return true,
+        };
+        let (old, version) = (Arc::downgrade(&previous), previous.version.clone());
+        drop(previous);
+        let drained = drain(&old, self.drain_timeout).await;
+        let version = version.as_deref().unwrap_or("from config").to_string();
+        if drained {
+            info!("Freed model {}", version);
+        } else {
+            warn!("Model {} still has requests after {:?}; freeing it once they finish", version, self.drain_timeout);
+        }
+        drained
+    }
+
+    /// What peers are told of `serving`: its registry version, or the
+    /// configured model's file name without extension.
+    fn capability(&self, serving: &ServingModel, network: &Network) -> LlmCapability {
+        let model_version = serving.version.clone().unwrap_or_else(|| {
+            let path = Path::new(&self.config.model_path);
+            path.file_stem().unwrap_or(path.as_os_str()).to_string_lossy().into_owned()
+        });
+        LlmCapability {
+            model_version,
+            context_length: serving.model.context_length().try_into().unwrap_or(u32::MAX),
+            quant: serving.model.model_info().quantization,
+            max_batch: self.config.max_batch_size.try_into().unwrap_or(u32::MAX),
+            accepts_remote: network.capabilities() & CAPABILITY_LLM != 0,
+        }
+    }
+
+    fn recorded_version(&self) -> Result<Option<String>, LlmError> {
+        let path = self.dir.join(ACTIVE_FILE);
+        match fs::read_to_string(&path) {
+            Ok(version) => Ok(Some(version.trim().to_string()).filter(|version| !version.is_empty())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(registry_error(&path, e)),
+        }
+    }
+
+    fn record_version(&self, version: &str) -> Result<(), LlmError> {
+        let (path, temp) = (self.dir.join(ACTIVE_FILE), self.dir.join(format!("{}.tmp", ACTIVE_FILE)));
+        fs::write(&temp, version).and_then(|()| fs::rename(&temp, &path)).map_err(|e| registry_error(&path, e))
+    }
+}
+
+/// Waits until nothing holds `old`, or `timeout` passes.
+async fn drain(old: &Weak<ServingModel>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while old.strong_count() > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_must_be_plain_directory_names() {
+        for version in ["v1", "llama-2-7b.Q4_0", "2026_10_01"] {
+            assert!(check_version(version).is_ok(), "{}", version);
+        }
+        for version in ["", "..", ".hidden", "a/b", "a b", ACTIVE_FILE, "v1.partial"] {
+            assert!(matches!(check_version(version), Err(LlmError::InvalidParams(_))), "{}", version);
+        }
+    }
+}
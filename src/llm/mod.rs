@@ -0,0 +1,55 @@
+pub mod admission;
+pub mod arch;
+pub mod cache;
+pub mod challenge;
+pub mod chat;
+pub mod checkpoint;
+pub mod compress;
+pub mod dataset;
+pub mod embed;
+pub mod estimate;
+pub mod generate;
+pub mod logprobs;
+pub mod lora;
+pub mod manager;
+pub mod model;
+mod quantized;
+pub mod queue;
+pub mod router;
+pub mod sampling;
+pub mod session;
+pub mod speculative;
+pub mod startup;
+pub mod structured;
+pub mod train;
+pub mod usage;
+pub mod verify;
+pub mod worker;
+
+pub use admission::{AdmissionConfig, AdmissionController, AdmissionPermit, Capacity, ResourceProbe, SystemResources};
+pub use arch::Architecture;
+pub use cache::{CacheConfig, CacheKey, InferenceCache};
+pub use challenge::{ChallengeConfig, ChallengeEvidence, ChallengeOutcome, InferenceChallenger, LocalScoring};
+pub use chat::{ChatMessage, ChatRole, PromptTemplate};
+pub use checkpoint::CheckpointConfig;
+pub use dataset::{Dataset, DatasetConfig, DatasetCursor};
+pub use embed::cosine_similarity;
+pub use estimate::Estimate;
+pub use generate::{Completion, Finish, FinishReason, GenerateParams, Partial, TokenChunk, TruncationPolicy};
+pub use logprobs::{TokenLogprob, TopLogprob};
+pub use lora::{LoraAdapter, LoraConfig, LoraLinear};
+pub use manager::{Activation, InstalledModel, ModelManager, ServingModel};
+pub use model::{available_devices, check_device, check_gpu, check_model_files, detect_format, DeviceInfo, KvCache, LightLLM, LlmError, ModelBackend, ModelInfo};
+pub use crate::node::config::{DeviceSpec, ModelFormat, Pooling, TemplateSpec};
+pub use crate::node::network::{GradientCompression, GradientPrecision};
+pub use queue::{InferenceQueue, QueueConfig};
+pub use router::{InferenceRouter, LocalInference, RoutedCompletion, RouterConfig};
+pub use sampling::Sampler;
+pub use session::{ChatSession, EvictionStrategy};
+pub use speculative::DEFAULT_DRAFT_TOKENS;
+pub use startup::StartupCache;
+pub use structured::JsonSchema;
+pub use train::{DistributedTrainer, GradientTransport, NetworkGradients, OptimizerKind, StepReport, TrainableModel};
+pub use usage::{LlmMetrics, Usage, UsageTotals};
+pub use verify::ArtifactHashes;
+pub use worker::ModelWorker;
@@ -1,121 +1,943 @@
-use candle_core::{Device, Tensor};
-use candle_transformers::models::llama::{Config, Llama};
-use candle_nn::VarBuilder;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::llama::{Cache, Config, Llama};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{Init, VarBuilder};
+use futures::Stream;
+use log::info;
 use tokenizers::Tokenizer;
-use std::path::Path;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use super::arch::Architecture;
+use super::chat::{ChatMessage, PromptTemplate};
+use super::embed::{self, TokenEmbeddings};
+use super::estimate::{self, Estimate};
+use super::generate::{self, Completion, GenerateParams, Partial, Prompt, TokenChunk};
+use super::logprobs;
+use super::lora::{LoraAdapter, LoraConfig};
+use super::quantized::QuantizedBackend;
+use super::queue::{InferenceQueue, QueueConfig};
+use super::session::ChatSession;
+use super::speculative::DraftModel;
+use super::startup::{StartupCache, TensorIndex};
+use super::structured;
+use super::usage::{self, LlmMetrics};
+use super::verify::{self, ArtifactHashes};
+use super::worker::{ModelWorker, DEFAULT_WORKER_THREADS};
+use crate::node::config::{DeviceSpec, LLMConfig, ModelFormat, Pooling};
+use crate::node::metrics::MetricsSource;
 
 const MODEL_VERSION: &str = "2.0.1";
-const MODEL_RELEASE_DATE: &str = "2023-12";
-const MODEL_CONTEXT_LENGTH: usize = 4096;
+pub(crate) const MODEL_CONTEXT_LENGTH: usize = 4096;
+const BOS_TOKEN: &str = "<s>";
+const EOS_TOKEN: &str = "</s>";
+const GGUF_MAGIC: &[u8] = b"GGUF";
 
-#[derive(Debug)]
-pub struct LightLLM {
-    model: Llama,
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LlmError {
+    #[error("Failed to load model {}: {reason}", path.display())]
+    ModelLoad { path: PathBuf, reason: String },
+    #[error("Failed to load tokenizer {}: {reason}", path.display())]
+    TokenizerLoad { path: PathBuf, reason: String },
+    #[error("Failed to load prompt template {}: {reason}", path.display())]
+    TemplateLoad { path: PathBuf, reason: String },
+    #[error("Checksum mismatch for {}: expected sha256 {expected}, found {actual}", path.display())]
+    ChecksumMismatch { path: PathBuf, expected: String, actual: String },
+    #[error("Device unavailable: {0}")]
+    DeviceUnavailable(String),
+    #[error("Prompt of {tokens} tokens is over the {limit} the context window leaves for it")]
+    PromptTooLong { tokens: usize, limit: usize },
+    #[error("Invalid generation parameters: {0}")]
+    InvalidParams(String),
+    #[error("Tokenizer error: {0}")]
+    Tokenizer(String),
+    #[error("Inference failed: {0}")]
+    InferenceFailed(String),
+    #[error("Generation cancelled after {} tokens", .0.completion_tokens)]
+    Cancelled(Partial),
+    #[error("Generation timed out after {} tokens", .0.completion_tokens)]
+    Timeout(Partial),
+    /// `retry_after_ms` is when the queue should have room, if known.
+    #[error("Inference queue is full")]
+    BatchFull { retry_after_ms: Option<u64> },
+    #[error("Training failed: {0}")]
+    TrainingFailed(String),
+    #[error("Checkpoint {} failed: {reason}", path.display())]
+    Checkpoint { path: PathBuf, reason: String },
+    #[error("Dataset {} unreadable: {reason}", path.display())]
+    Dataset { path: PathBuf, reason: String },
+    #[error("Adapter does not fit {module}: {reason}")]
+    AdapterMismatch { module: String, reason: String },
+    #[error("Draft model does not fit the model: {0}")]
+    DraftMismatch(String),
+    #[error("Model version {0} is not installed")]
+    UnknownModel(String),
+    #[error("Model version {0} is serving; activate another first")]
+    ModelInUse(String),
+    #[error("Model registry error at {}: {reason}", path.display())]
+    Registry { path: PathBuf, reason: String },
+    /// `position` is the byte of the completion where it stopped fitting.
+    #[error("Completion does not fit the response format at byte {position}: {reason}")]
+    ResponseFormat { position: usize, reason: String },
+}
+
+/// Fails with `ModelLoad` or `TokenizerLoad` naming whichever file is
+/// missing, so a bad path is reported before anything is read.
+pub fn check_model_files(model_path: &Path, tokenizer_path: &Path) -> Result<(), LlmError> {
+    if !model_path.is_file() {
+        return Err(LlmError::ModelLoad { path: model_path.to_path_buf(), reason: "file not found".to_string() });
+    }
+    if !tokenizer_path.is_file() {
+        return Err(LlmError::TokenizerLoad { path: tokenizer_path.to_path_buf(), reason: "file not found".to_string() });
+    }
+    Ok(())
+}
+
+/// GGUF files open with their magic; safetensors files with the length of
+/// their JSON header, then the header. Failing both, the extension decides.
+pub fn detect_format(model_path: &Path) -> Result<ModelFormat, LlmError> {
+    let mut head = Vec::with_capacity(9);
+    File::open(model_path)
+        .and_then(|file| file.take(9).read_to_end(&mut head))
+        .map_err(|e| LlmError::ModelLoad { path: model_path.to_path_buf(), reason: e.to_string() })?;
+    if head.starts_with(GGUF_MAGIC) {
+        return Ok(ModelFormat::Gguf);
+    }
+    if head.get(8) == Some(&b'{') {
+        return Ok(ModelFormat::Safetensors);
+    }
+    match model_path.extension().and_then(|extension| extension.to_str()) {
+        Some("gguf") => Ok(ModelFormat::Gguf),
+        Some("safetensors") => Ok(ModelFormat::Safetensors),
+        _ => Err(LlmError::ModelLoad {
+            path: model_path.to_path_buf(),
+            reason: "neither a GGUF nor a safetensors file".to_string(),
+        }),
+    }
+}
+
+/// Fails unless a CUDA device can run a `format` model.
+pub fn check_gpu(format: ModelFormat) -> Result<(), LlmError> {
+    if !candle_core::utils::cuda_is_available() {
+        return Err(LlmError::DeviceUnavailable(format!(
+            "use_gpu is set but this node has no CUDA device to run the {} model on", format
+        )));
+    }
+    Ok(())
+}
+
+/// Fails unless this build can run a `format` model on `spec`; whether the
+/// device is there is only known once it is opened.
+pub fn check_device(spec: DeviceSpec, format: ModelFormat) -> Result<(), LlmError> {
+    match spec {
+        DeviceSpec::Auto | DeviceSpec::Cpu => Ok(()),
+        DeviceSpec::Cuda(_) | DeviceSpec::CudaSplit(..) => check_gpu(format),
+        DeviceSpec::Metal if !candle_core::utils::metal_is_available() => Err(LlmError::DeviceUnavailable(format!(
+            "device is metal but this node was built without Metal support to run the {} model on", format
+        ))),
+        DeviceSpec::Metal => Ok(()),
+    }
+}
+
+/// Devices this node can open, as `llm.device` names them.
+pub fn available_devices() -> Vec<String> {
+    let mut devices = vec![DeviceSpec::Cpu.to_string()];
+    if candle_core::utils::cuda_is_available() {
+        devices.extend((0..).map_while(|ordinal| Device::new_cuda(ordinal).ok().map(|_| DeviceSpec::Cuda(ordinal).to_string())));
+    }
+    if candle_core::utils::metal_is_available() && Device::new_metal(0).is_ok() {
+        devices.push(DeviceSpec::Metal.to_string());
+    }
+    devices
+}
+
+/// Where a model was loaded, for the health endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceInfo {
+    /// "cpu", "cuda:<n>" or "metal".
+    pub device: String,
+    /// The device asked for, which "auto" resolves.
+    pub requested: String,
+}
+
+/// Opens the device `spec` names to run a `format` model on. "auto" takes
+/// the first GPU, or the CPU if `cpu_fallback` allows.
+fn open_device(spec: DeviceSpec, format: ModelFormat, cpu_fallback: bool) -> Result<(Device, DeviceInfo), LlmError> {
+    check_device(spec, format)?;
+    let unavailable = |e: candle_core::Error| {
+        LlmError::DeviceUnavailable(format!(
+            "{} cannot run the {} model ({}); available devices: {}", spec, format, e, available_devices().join(", ")
+        ))
+    };
+    let (device, opened) = match spec {
+        DeviceSpec::Cpu => (Device::Cpu, spec),
+        DeviceSpec::Cuda(ordinal) => (Device::new_cuda(ordinal).map_err(unavailable)?, spec),
+        DeviceSpec::Metal => (Device::new_metal(0).map_err(unavailable)?, spec),
+        // candle's Llama holds every layer on the device its weights
+        // were loaded onto, so a split cannot be placed yet.
+        DeviceSpec::CudaSplit(..) => {
+            return Err(LlmError::DeviceUnavailable(format!(
+                "{} asks to split the {} model's layers over two devices, which is not supported; name one of {}",
+                spec, format, available_devices().join(", ")
+            )));
+        }
+        DeviceSpec::Auto => {
+            let gpu = Device::new_cuda(0).map(|device| (device, DeviceSpec::Cuda(0)))
+                .or_else(|_| Device::new_metal(0).map(|device| (device, DeviceSpec::Metal)));
+            match gpu {
+                Ok(gpu) => gpu,
+                Err(_) if cpu_fallback => (Device::Cpu, DeviceSpec::Cpu),
+                Err(_) => {
+                    return Err(LlmError::DeviceUnavailable(format!(
+                        "use_gpu is set but this node has no GPU to run the {} model on; available devices: {}",
+                        format, available_devices().join(", ")
+                    )));
+                }
+            }
+        }
+    };
+    Ok((device, DeviceInfo { device: opened.to_string(), requested: spec.to_string() }))
+}
+
+/// What a loaded model is and roughly what it costs to hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ModelInfo {
+    /// Unset for a backend not loaded from a file.
+    pub format: Option<ModelFormat>,
+    pub parameters: u64,
+    /// The weights' data type: "f16", or a GGUF quantization such as "Q4_0".
+    pub quantization: String,
+    /// Memory the weights take once loaded, before any KV cache.
+    pub memory_bytes: u64,
+    pub context_length: usize,
+    /// How long loading took; zero for a backend not loaded from a file.
+    pub load_ms: u64,
+    /// Whether everything the startup cache could answer came from it.
+    pub load_cached: bool,
+}
+
+/// A backend's attention state for one sequence, so each step only runs
+/// the model over tokens it has not seen.
+pub struct KvCache {
+    state: Box<dyn Any + Send>,
+    len: usize,
+}
+
+impl KvCache {
+    pub fn new(state: impl Any + Send) -> Self {
+        KvCache { state: Box::new(state), len: 0 }
+    }
+
+    /// Tokens the cache holds; the position of the next token fed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The backend's state, if it is a `T`.
+    pub fn state_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.state.downcast_mut()
+    }
+
+    /// A copy of the cache, if its state is a `T`; the two then take in
+    /// tokens apart.
+    pub fn fork<T: Any + Send + Clone>(&self) -> Option<KvCache> {
+        self.state.downcast_ref::<T>().map(|state| KvCache { state: Box::new(state.clone()), len: self.len })
+    }
+
+    pub(crate) fn advance(&mut self, tokens: usize) {
+        self.len += tokens;
+    }
+}
+
+/// A causal language model with its tokenizer: the candle Llama model, or a
+/// small stand-in in tests.
+pub trait ModelBackend: Send + Sync {
+    fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError>;
+    fn decode(&self, tokens: &[u32]) -> Result<String, LlmError>;
+    /// An empty cache for a new sequence.
+    fn new_cache(&self) -> Result<KvCache, LlmError>;
+    /// Logits over the vocabulary for the token following the sequence in
+    /// `cache` and then `tokens`, which the cache takes in.
+    fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError>;
+    /// `forward` for several sequences at once, one output per cache.
+    /// Backends that can pad and mask sequences into one pass override
+    /// this; by default they run one after another.
+    fn forward_batch(&self, caches: &mut [KvCache], tokens: &[Vec<u32>]) -> Result<Vec<Vec<f32>>, LlmError> {
+        caches.iter_mut().zip(tokens).map(|(cache, tokens)| self.forward(cache, tokens)).collect()
+    }
+    /// Logits following each of `tokens` in turn, after the sequence in
+    /// `cache`, which takes them all in. By default one token is run at a
+    /// time; backends that can score every position in one pass override
+    /// this.
+    fn forward_all(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let start = cache.len;
+        let logits = tokens.iter()
+            .map(|&token| {
+                let logits = self.forward(cache, &[token]);
+                cache.len += 1;
+                logits
+            })
+            .collect();
+        cache.len = start;
+        logits
+    }
+    /// A copy of `cache` that takes in tokens apart from it, so speculative
+    /// decoding can try tokens it may not keep.
+    fn fork_cache(&self, _cache: &KvCache) -> Result<KvCache, LlmError> {
+        Err(LlmError::InferenceFailed("the model's cache cannot be copied".to_string()))
+    }
+    /// Every token the tokenizer knows, by its text; empty for a stand-in
+    /// without a tokenizer file.
+    fn vocab(&self) -> HashMap<String, u32> {
+        HashMap::new()
+    }
+    /// The token `encode` starts a sequence with, if any.
+    fn bos_token(&self) -> Option<u32>;
+    /// The token that ends a completion, if the model has one.
+    fn eos_token(&self) -> Option<u32>;
+    /// Tokens the model attends to: the prompt and the completion together.
+    fn context_length(&self) -> usize;
+
+    fn info(&self) -> ModelInfo {
+        ModelInfo { context_length: self.context_length(), ..ModelInfo::default() }
+    }
+
+    /// Width of the vectors `hidden_states` returns; zero for a backend
+    /// without them.
+    fn embedding_dim(&self) -> usize {
+        0
+    }
+
+    /// The last hidden states of each sequence in `batch`, one vector per
+    /// token. A backend that pads the batch must leave the padding out.
+    fn hidden_states(&self, _batch: &[Vec<u32>]) -> Result<Vec<Vec<Vec<f32>>>, LlmError> {
+        Err(LlmError::InferenceFailed("the model does not produce embeddings".to_string()))
+    }
+
+    /// This model reloaded with `adapter` merged into its weights, or
+    /// without any adapter for `None`.
+    fn with_adapter(&self, _adapter: Option<&LoraAdapter>) -> Result<Arc<dyn ModelBackend>, LlmError> {
+        Err(LlmError::InvalidParams("the model does not take adapters".to_string()))
+    }
+
+    /// Most memory the model's device has held, in bytes, if the device
+    /// reports it.
+    fn memory_high_water(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A tokenizer file with the special tokens generation needs.
+pub(super) struct Vocab {
     tokenizer: Tokenizer,
+    pub(super) bos_token: Option<u32>,
+    pub(super) eos_token: Option<u32>,
+}
+
+impl Vocab {
+    /// Parses `path`, or takes it from `startup` if it was parsed before.
+    pub(super) fn load(path: &Path, startup: Option<&StartupCache>) -> Result<Self, LlmError> {
+        let tokenizer = match startup {
+            Some(startup) => startup.tokenizer(path)?,
+            None => Tokenizer::from_file(path)
+                .map_err(|e| LlmError::TokenizerLoad { path: path.to_path_buf(), reason: e.to_string() })?,
+        };
+        let (bos_token, eos_token) = (tokenizer.token_to_id(BOS_TOKEN), tokenizer.token_to_id(EOS_TOKEN));
+        Ok(Vocab { tokenizer, bos_token, eos_token })
+    }
+
+    pub(super) fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+        let encoding = self.tokenizer.encode(text, true).map_err(|e| LlmError::Tokenizer(e.to_string()))?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    pub(super) fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+        self.tokenizer.decode(tokens, true).map_err(|e| LlmError::Tokenizer(e.to_string()))
+    }
+
+    pub(super) fn tokens(&self) -> HashMap<String, u32> {
+        self.tokenizer.get_vocab(true)
+    }
+}
+
+pub(super) fn model_load_error(path: &Path, what: &str, e: candle_core::Error) -> LlmError {
+    LlmError::ModelLoad { path: path.to_path_buf(), reason: format!("{}: {}", what, e) }
+}
+
+/// `tokens` as a `(1, len)` input on `device`.
+pub(super) fn input_tensor(tokens: &[u32], device: &Device) -> Result<Tensor, LlmError> {
+    Tensor::new(tokens, device)
+        .and_then(|t| t.unsqueeze(0))
+        .map_err(|e| LlmError::InferenceFailed(format!("input of {} tokens: {}", tokens.len(), e)))
+}
+
+/// The `(1, vocab)` logits a forward pass returns, as f32s.
+pub(super) fn logits_vec(logits: Tensor) -> Result<Vec<f32>, LlmError> {
+    let dims = logits.dims().to_vec();
+    logits.squeeze(0)
+        .and_then(|logits| logits.to_dtype(DType::F32))
+        .and_then(|logits| logits.to_vec1())
+        .map_err(|e| LlmError::InferenceFailed(format!("reading logits {:?}: {}", dims, e)))
+}
+
+struct LlamaBackend {
+    model: Llama,
+    embeddings: TokenEmbeddings,
+    architecture: Architecture,
+    config: Config,
+    vocab: Vocab,
     device: Device,
-    version: String,
+    parameters: u64,
+    model_path: PathBuf,
+    tokenizer_path: PathBuf,
 }
 
-impl LightLLM {
-    pub fn new(model_path: &Path, tokenizer_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        
-        let config = Config::config_7b_v2()?;
-        
-
-        let device = Device::cuda_if_available(0)?;
-        
-        let vb = VarBuilder::from_safetensors(model_path, &device)?;
-        let model = Llama::load(vb, &config)?;
-        
-       
-        let tokenizer = Tokenizer::from_file(tokenizer_path)?;
-
-        Ok(Self {
+/// Safetensors weights with adapter deltas added as they are read.
+struct Merged {
+    base: MmapedSafetensors,
+    deltas: HashMap<String, Tensor>,
+}
+
+impl SimpleBackend for Merged {
+    fn get(&self, shape: candle_core::Shape, name: &str, hints: Init, dtype: DType, device: &Device) -> candle_core::Result<Tensor> {
+        let weight = SimpleBackend::get(&self.base, shape, name, hints, dtype, device)?;
+        match self.deltas.get(name) {
+            Some(delta) => weight.add(&delta.to_device(device)?.to_dtype(dtype)?),
+            None => Ok(weight),
+        }
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        SimpleBackend::contains_tensor(&self.base, name)
+    }
+}
+
+impl LlamaBackend {
+    fn load(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        architecture: Architecture,
+        device: Device,
+        adapter: Option<&LoraAdapter>,
+        startup: Option<&StartupCache>,
+    ) -> Result<Self, LlmError> {
+        let config = architecture.config();
+        // Mapped once, and read only as the weights are loaded.
+        let tensors = unsafe { MmapedSafetensors::new(model_path) }
+            .map_err(|e| model_load_error(model_path, "reading safetensors", e))?;
+        let index = match startup {
+            Some(startup) => startup.tensor_index(model_path, &tensors)?,
+            None => TensorIndex::read(&tensors),
+        };
+        architecture.check(model_path, |name| index.shapes.get(name).cloned())?;
+        let vb = match adapter {
+            Some(adapter) => {
+                adapter.check(|name| index.shapes.get(name).cloned())?;
+                let deltas = adapter.deltas(&device).map_err(|e| model_load_error(model_path, "merging adapter", e))?;
+                VarBuilder::from_backend(Box::new(Merged { base: tensors, deltas }), DType::F16, device.clone())
+            }
+            None => VarBuilder::from_backend(Box::new(tensors), DType::F16, device.clone()),
+        };
+        let embeddings = vb.pp("model.embed_tokens")
+            .get((config.vocab_size, config.hidden_size), "weight")
+            .map_err(|e| model_load_error(model_path, "loading token embeddings", e))?;
+        let model = Llama::load(vb, &config).map_err(|e| model_load_error(model_path, "loading weights", e))?;
+        let vocab = Vocab::load(tokenizer_path, startup)?;
+        Ok(LlamaBackend {
             model,
-            tokenizer,
+            embeddings: TokenEmbeddings::new(embeddings),
+            architecture,
+            config,
+            vocab,
             device,
-            version: MODEL_VERSION.to_string(),
+            parameters: index.parameters,
+            model_path: model_path.to_path_buf(),
+            tokenizer_path: tokenizer_path.to_path_buf(),
+        })
+    }
+}
+
+impl ModelBackend for LlamaBackend {
+    fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+        self.vocab.encode(text)
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+        self.vocab.decode(tokens)
+    }
+
+    fn new_cache(&self) -> Result<KvCache, LlmError> {
+        let cache = Cache::new(true, DType::F16, &self.config, &self.device)
+            .map_err(|e| LlmError::InferenceFailed(format!("allocating KV cache: {}", e)))?;
+        Ok(KvCache::new(cache))
+    }
+
+    fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+        let position = cache.len();
+        let cache = cache.state_mut::<Cache>()
+            .ok_or_else(|| LlmError::InferenceFailed("KV cache is not a Llama cache".to_string()))?;
+        let input = input_tensor(tokens, &self.device)?;
+        let logits = self.model.forward(&input, position, cache)
+            .map_err(|e| LlmError::InferenceFailed(format!("forward over input {:?} at {}: {}", input.dims(), position, e)))?;
+        logits_vec(logits)
+    }
+
+    fn fork_cache(&self, cache: &KvCache) -> Result<KvCache, LlmError> {
+        cache.fork::<Cache>().ok_or_else(|| LlmError::InferenceFailed("KV cache is not a Llama cache".to_string()))
+    }
+
+    fn vocab(&self) -> HashMap<String, u32> {
+        self.vocab.tokens()
+    }
+
+    fn bos_token(&self) -> Option<u32> {
+        self.vocab.bos_token
+    }
+
+    fn eos_token(&self) -> Option<u32> {
+        self.vocab.eos_token
+    }
+
+    fn context_length(&self) -> usize {
+        MODEL_CONTEXT_LENGTH
+    }
+
+    fn info(&self) -> ModelInfo {
+        ModelInfo {
+            format: Some(ModelFormat::Safetensors),
+            parameters: self.parameters,
+            quantization: "f16".to_string(),
+            memory_bytes: self.parameters * 2,
+            context_length: MODEL_CONTEXT_LENGTH,
+            ..ModelInfo::default()
+        }
+    }
+
+    fn embedding_dim(&self) -> usize {
+        self.embeddings.dim()
+    }
+
+    fn hidden_states(&self, batch: &[Vec<u32>]) -> Result<Vec<Vec<Vec<f32>>>, LlmError> {
+        self.embeddings.hidden_states(batch)
+    }
+
+    fn with_adapter(&self, adapter: Option<&LoraAdapter>) -> Result<Arc<dyn ModelBackend>, LlmError> {
+        let architecture = self.architecture.clone();
+        Ok(Arc::new(LlamaBackend::load(&self.model_path, &self.tokenizer_path, architecture, self.device.clone(), adapter, None)?))
+    }
+
+    fn memory_high_water(&self) -> Option<u64> {
+        usage::memory_high_water(&self.device)
+    }
+}
+
+pub struct LightLLM {
+    worker: ModelWorker,
+    version: String,
+    metrics: Arc<LlmMetrics>,
+    device: DeviceInfo,
+    pooling: Pooling,
+    /// Texts embedded in one pass.
+    max_batch_size: usize,
+    /// The adapter merged into the model, if any.
+    adapter: Option<LoraConfig>,
+    /// How `chat` lays out conversations.
+    template: Arc<PromptTemplate>,
+    /// Proposes tokens for streamed generation to check.
+    draft: Option<DraftModel>,
+    load_time: Duration,
+    load_cached: bool,
+}
+
+impl LightLLM {
+    /// Loads a safetensors or GGUF model, on the first GPU when there is
+    /// one. A safetensors model's architecture is read from the
+    /// `config.json` beside it.
+    pub fn new(model_path: &Path, tokenizer_path: &Path) -> Result<Self, LlmError> {
+        let started = Instant::now();
+        check_model_files(model_path, tokenizer_path)?;
+        let format = detect_format(model_path)?;
+        let device = open_device(DeviceSpec::Auto, format, true)?;
+        let mut model = Self::load(model_path, tokenizer_path, None, format, device, DEFAULT_WORKER_THREADS, None)?;
+        model.load_time = started.elapsed();
+        Ok(model)
+    }
+
+    /// Loads the model `config` names onto `config.device`, or the CPU
+    /// unless `use_gpu` is set.
+    /// The files are verified first if `config` gives their hashes.
+    pub fn from_config(config: &LLMConfig) -> Result<Self, LlmError> {
+        Self::from_config_with_cache(config, None)
+    }
+
+    /// `from_config`, taking the files' hashes, the parsed tokenizer and
+    /// the tensor index from `startup` when the files are unchanged since
+    /// they went in.
+    pub fn from_config_with_cache(config: &LLMConfig, startup: Option<&StartupCache>) -> Result<Self, LlmError> {
+        let started = Instant::now();
+        let misses = startup.map(StartupCache::misses);
+        let (model_path, tokenizer_path) = (Path::new(&config.model_path), Path::new(&config.tokenizer_path));
+        let architecture = config.model_config_path.as_deref().map(Path::new);
+        check_model_files(model_path, tokenizer_path)?;
+        let expected = ArtifactHashes::from(config);
+        if !expected.is_empty() {
+            let mut logged = 0;
+            verify::verify(model_path, tokenizer_path, architecture, &expected, startup, &mut |path, done, total| {
+                let percent = if total == 0 { 100 } else { done * 100 / total };
+                if percent >= logged + 10 || done == total {
+                    logged = percent;
+                    info!("Verifying {}: {}%", path.display(), percent);
+                }
+            })?;
+        }
+        let format = match config.format {
+            Some(format) => format,
+            None => detect_format(model_path)?,
+        };
+        let spec = config.device_spec().map_err(|e| LlmError::DeviceUnavailable(e.to_string()))?;
+        let template = config.template_spec().map_err(|e| LlmError::InvalidParams(e.to_string()))?;
+        let template = PromptTemplate::from_spec(&template)?;
+        let device = open_device(spec, format, false)?;
+        let draft_device = device.0.clone();
+        let mut model = Self::load(model_path, tokenizer_path, architecture, format, device, config.worker_threads, startup)?
+            .with_pooling(config.pooling)
+            .with_max_batch_size(config.max_batch_size)
+            .with_template(template);
+        if let Some(draft_path) = config.draft_model_path.as_deref().map(Path::new) {
+            let draft_tokenizer = Path::new(config.draft_tokenizer_path.as_deref().unwrap_or(&config.tokenizer_path));
+            check_model_files(draft_path, draft_tokenizer)?;
+            let draft_format = detect_format(draft_path)?;
+            let draft = Self::load_backend(draft_path, draft_tokenizer, None, draft_format, draft_device, startup)?;
+            model = model.with_draft(draft, config.draft_tokens)?;
+            info!("Loaded draft model {} proposing {} tokens a round", draft_path.display(), config.draft_tokens);
+        }
+        model.load_time = started.elapsed();
+        model.load_cached = misses.is_some() && misses == startup.map(StartupCache::misses);
+        Ok(model)
+    }
+
+    /// Checks the files against `expected`, then that the model's header
+    /// has the tensors the architecture needs and the tokenizer parses, so
+    /// a bad download fails here rather than deep inside loading.
+    pub fn verify_artifacts(model_path: &Path, tokenizer_path: &Path, expected: &ArtifactHashes) -> Result<(), LlmError> {
+        Self::verify_artifacts_with_progress(model_path, tokenizer_path, expected, |_, _, _| {})
+    }
+
+    /// `verify_artifacts`, calling `progress` with the file being hashed,
+    /// the bytes hashed so far and its size.
+    pub fn verify_artifacts_with_progress(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        expected: &ArtifactHashes,
+        mut progress: impl FnMut(&Path, u64, u64),
+    ) -> Result<(), LlmError> {
+        verify::verify(model_path, tokenizer_path, None, expected, None, &mut progress)
+    }
+
+    /// `architecture` is the `config.json` to use instead of the one beside
+    /// a safetensors model.
+    fn load(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        architecture: Option<&Path>,
+        format: ModelFormat,
+        (device, info): (Device, DeviceInfo),
+        threads: usize,
+        startup: Option<&StartupCache>,
+    ) -> Result<Self, LlmError> {
+        let backend = Self::load_backend(model_path, tokenizer_path, architecture, format, device, startup)?;
+        Ok(Self { device: info, ..Self::on_worker(ModelWorker::spawn(backend, threads)) })
+    }
+
+    fn load_backend(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        architecture: Option<&Path>,
+        format: ModelFormat,
+        device: Device,
+        startup: Option<&StartupCache>,
+    ) -> Result<Arc<dyn ModelBackend>, LlmError> {
+        Ok(match format {
+            ModelFormat::Safetensors => {
+                let architecture = Architecture::for_model(model_path, architecture)?;
+                Arc::new(LlamaBackend::load(model_path, tokenizer_path, architecture, device, None, startup)?)
+            }
+            ModelFormat::Gguf => Arc::new(QuantizedBackend::load(model_path, tokenizer_path, device, None, startup)?),
         })
     }
 
+    /// Generates with `backend` instead of a model loaded from disk.
+    pub fn with_backend(backend: Arc<dyn ModelBackend>) -> Self {
+        Self::on_worker(ModelWorker::spawn(backend, DEFAULT_WORKER_THREADS))
+    }
+
+    fn on_worker(worker: ModelWorker) -> Self {
+        Self {
+            worker,
+            version: MODEL_VERSION.to_string(),
+            metrics: Arc::new(LlmMetrics::new()),
+            device: DeviceInfo { device: DeviceSpec::Cpu.to_string(), requested: DeviceSpec::Cpu.to_string() },
+            pooling: Pooling::default(),
+            max_batch_size: embed::DEFAULT_BATCH_SIZE,
+            adapter: None,
+            template: Arc::new(PromptTemplate::default()),
+            draft: None,
+            load_time: Duration::ZERO,
+            load_cached: false,
+        }
+    }
+
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    pub fn with_template(mut self, template: PromptTemplate) -> Self {
+        self.template = Arc::new(template);
+        self
+    }
+
+    /// Decodes `generate_stream` and `chat_stream` speculatively: each
+    /// round `draft` proposes up to `tokens` tokens, which the model checks
+    /// in one pass. Output follows the same distribution as without it.
+    /// The batching queue and chat sessions decode plainly. Fails with
+    /// `DraftMismatch` if `draft` does not share the model's tokenizer.
+    /// Blocks until checked, so call it off the async runtime.
+    pub fn with_draft(mut self, draft: Arc<dyn ModelBackend>, tokens: usize) -> Result<Self, LlmError> {
+        let draft = DraftModel::new(draft, tokens)?;
+        let checked = draft.clone();
+        self.worker.run_blocking(move |backend| checked.check(backend))?;
+        self.draft = Some(draft);
+        Ok(self)
+    }
+
+    /// Records usage into `metrics` instead of the model's own, as when
+    /// several models serve one after another.
+    pub fn with_metrics(mut self, metrics: Arc<LlmMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
     pub fn version(&self) -> &str {
         &self.version
     }
 
     pub fn context_length(&self) -> usize {
-        MODEL_CONTEXT_LENGTH
+        self.worker.context_length()
     }
 
-    pub async fn generate(
+    pub fn model_info(&self) -> ModelInfo {
+        ModelInfo { load_ms: self.load_time.as_millis() as u64, load_cached: self.load_cached, ..self.worker.info() }
+    }
+
+    /// Threads running the model apart from the async runtime.
+    pub fn worker_threads(&self) -> usize {
+        self.worker.threads()
+    }
+
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.device
+    }
+
+    /// The config of the adapter `load_adapter` merged in, if any.
+    pub fn adapter(&self) -> Option<&LoraConfig> {
+        self.adapter.as_ref()
+    }
+
+    /// Reloads the model with the LoRA adapter at `path` merged into its
+    /// weights, replacing any adapter already merged. An adapter that does
+    /// not fit the model leaves it as it was. Queues and sessions already
+    /// started keep the model they had. Blocks until the reload is done,
+    /// so call it off the async runtime.
+    pub fn load_adapter(&mut self, path: impl AsRef<Path>) -> Result<(), LlmError> {
+        let adapter = LoraAdapter::load(path.as_ref())?;
+        let (config, modules) = (adapter.config().clone(), adapter.modules().count());
+        let backend = self.worker.run_blocking(move |backend| backend.with_adapter(Some(&adapter)))?;
+        self.worker = ModelWorker::spawn(backend, self.worker.threads());
+        self.adapter = Some(config);
+        info!("Loaded adapter {} for {} modules", path.as_ref().display(), modules);
+        Ok(())
+    }
+
+    /// Reloads the model without its adapter.
+    pub fn unload_adapter(&mut self) -> Result<(), LlmError> {
+        if self.adapter.is_none() {
+            return Ok(());
+        }
+        let backend = self.worker.run_blocking(|backend| backend.with_adapter(None))?;
+        self.worker = ModelWorker::spawn(backend, self.worker.threads());
+        self.adapter = None;
+        Ok(())
+    }
+
+    /// Length of the vectors `embed` returns.
+    pub fn embedding_dim(&self) -> usize {
+        self.worker.embedding_dim()
+    }
+
+    /// A unit-length vector for each text, its hidden states pooled as
+    /// configured, in batches of at most `max_batch_size`. Compare them
+    /// with `cosine_similarity`.
+    pub async fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let texts: Vec<String> = texts.iter().map(|text| text.to_string()).collect();
+        let (pooling, max_batch_size) = (self.pooling, self.max_batch_size);
+        self.worker.run(move |backend| embed::embed(backend, &texts, pooling, max_batch_size)).await
+    }
+
+    /// Text chunks as tokens are sampled; the last one carries why
+    /// generation finished. Dropping the stream, cancelling
+    /// `params.cancel` or passing `params.deadline` stops generation after
+    /// at most the step in progress.
+    pub fn generate_stream(
         &self,
         prompt: &str,
-        max_tokens: usize,
-        temperature: f32,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        
-        if prompt.len() > MODEL_CONTEXT_LENGTH {
-            return Err("Prompt too long for model context window".into());
-        }
+        params: GenerateParams,
+    ) -> impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static {
+        generate::stream(self.worker.clone(), self.draft.clone(), Prompt::Text(prompt.to_string()), params, Arc::clone(&self.metrics))
+    }
 
-       
-        let tokens = self.tokenizer.encode(prompt, true)?;
-        let input_ids = tokens.get_ids();
-        
-       
-        let input_tensor = Tensor::new(input_ids, &self.device)?;
-        
-       
-        let output = self.model.generate(
-            &input_tensor,
-            None,
-            max_tokens,
-            Some(temperature),
-            None,
-        )?;
-        
-      
-        let output_ids: Vec<u32> = output.to_vec1()?;
-        let decoded = self.tokenizer.decode(&output_ids, true)?;
-        
-        Ok(decoded)
-    }
-}
-
-
-pub struct DistributedTrainer {
-    model: LightLLM,
-    peers: Vec<String>,
-    batch_size: usize,
-}
-
-impl DistributedTrainer {
-    pub fn new(
-        model: LightLLM,
-        peers: Vec<String>,
-        batch_size: usize,
-    ) -> Self {
-        Self {
-            model,
-            peers,
-            batch_size,
+    pub fn template(&self) -> &PromptTemplate {
+        &self.template
+    }
+
+    /// `generate_stream` for the assistant's reply to `messages`, laid out
+    /// by the model's `PromptTemplate`. The template's markers count
+    /// against the context window; when the chat is too long,
+    /// `params.truncation` other than `Error` drops its oldest exchanges,
+    /// keeping any system message.
+    pub fn chat_stream(
+        &self,
+        messages: &[ChatMessage],
+        params: GenerateParams,
+    ) -> impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static {
+        let prompt = Prompt::Chat(Arc::clone(&self.template), messages.to_vec());
+        generate::stream(self.worker.clone(), self.draft.clone(), prompt, params, Arc::clone(&self.metrics))
+    }
+
+    /// The whole reply to `messages`.
+    pub async fn chat(&self, messages: &[ChatMessage], params: GenerateParams) -> Result<Completion, LlmError> {
+        generate::collect(self.chat_stream(messages, params)).await
+    }
+
+    /// The log probability of each token of `completion` following
+    /// `prompt`, teacher-forced without sampling. For a completion this
+    /// model generated, it matches the logprobs reported while sampling.
+    pub async fn score(&self, prompt: &str, completion: &str) -> Result<Vec<f32>, LlmError> {
+        let (prompt, completion) = (prompt.to_string(), completion.to_string());
+        self.worker.run(move |backend| logprobs::score(backend, &prompt, &completion)).await
+    }
+
+    /// How likely each of `labels` is as the class of `text`, likeliest
+    /// first, from the log probability of the label's own tokens.
+    pub async fn classify(&self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>, LlmError> {
+        let text = text.to_string();
+        let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        self.worker.run(move |backend| logprobs::classify(backend, &text, &labels)).await
+    }
+
+    /// Tokens `text` encodes to, counted as generation counts a prompt.
+    pub async fn count_tokens(&self, text: &str) -> Result<usize, LlmError> {
+        estimate::count_tokens(&self.worker, text).await
+    }
+
+    /// The tokens generating from `prompt` with `params` would take, and
+    /// roughly how long, without running the model.
+    pub async fn estimate(&self, prompt: &str, params: GenerateParams) -> Result<Estimate, LlmError> {
+        estimate::estimate(&self.worker, &self.metrics, prompt, params).await
+    }
+
+    /// A conversation whose turns extend one token sequence, so each turn
+    /// only runs the model over its own text.
+    pub fn new_session(&self) -> ChatSession {
+        ChatSession::new(self.worker.clone(), Arc::clone(&self.metrics))
+    }
+
+    /// The whole completion of `prompt`.
+    pub async fn generate(&self, prompt: &str, params: GenerateParams) -> Result<Completion, LlmError> {
+        generate::collect(self.generate_stream(prompt, params)).await
+    }
+
+    /// `generate` held to `params.response_format`, which must be set,
+    /// with the completion parsed as `T`.
+    pub async fn generate_json<T: DeserializeOwned>(&self, prompt: &str, params: GenerateParams) -> Result<T, LlmError> {
+        if params.response_format.is_none() {
+            return Err(LlmError::InvalidParams("generate_json needs a response_format".to_string()));
         }
+        let completion = self.generate(prompt, params).await?;
+        serde_json::from_str(&completion.text).map_err(|e| LlmError::ResponseFormat {
+            position: structured::offset(&completion.text, e.line(), e.column()),
+            reason: e.to_string(),
+        })
     }
 
-    pub fn model_info(&self) -> String {
-        format!(
-            "LLaMA-2 7B v{} (Released: {})\nContext Length: {} tokens",
-            MODEL_VERSION,
-            MODEL_RELEASE_DATE,
-            MODEL_CONTEXT_LENGTH
-        )
+    /// A queue that runs concurrent requests against this model in
+    /// batches. Must be called within a Tokio runtime.
+    pub fn start_queue(&self, config: QueueConfig) -> InferenceQueue {
+        InferenceQueue::start(self.worker.clone(), Arc::clone(&self.metrics), config)
     }
 
-    pub async fn train_step(
-        &mut self,
-        batch: Vec<String>,
-    ) -> Result<f32, Box<dyn std::error::Error>> {
-       
-        
-        Ok(0.0) 
+    /// Usage of every generation on this model, its sessions and queues,
+    /// to register with the node's `MetricsRegistry`.
+    pub fn metrics(&self) -> Arc<LlmMetrics> {
+        Arc::clone(&self.metrics)
+    }
+}
+
+impl fmt::Debug for LightLLM {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LightLLM").field("version", &self.version).finish_non_exhaustive()
+    }
+}
+
+impl MetricsSource for LightLLM {
+    fn render_metrics(&self, out: &mut String) {
+        self.metrics.render_metrics(out);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_files_are_load_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (model, tokenizer) = (dir.path().join("model.safetensors"), dir.path().join("tokenizer.json"));
+        assert!(matches!(
+            LightLLM::new(&model, &tokenizer),
+            Err(LlmError::ModelLoad { path, .. }) if path == model
+        ));
+
+        std::fs::write(&model, b"not safetensors").unwrap();
+        assert!(matches!(
+            LightLLM::new(&model, &tokenizer),
+            Err(LlmError::TokenizerLoad { path, .. }) if path == tokenizer
+        ));
+
+        // Both present, but the weights are unreadable.
+        std::fs::write(&tokenizer, b"{}").unwrap();
+        let err = LightLLM::new(&model, &tokenizer).unwrap_err();
+        assert!(matches!(&err, LlmError::ModelLoad { path, .. } if *path == model), "{}", err);
+        assert!(err.to_string().contains("model.safetensors"));
     }
 }
@@ -0,0 +1,202 @@
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::embed::TokenEmbeddings;
+use super::lora::LoraAdapter;
+use super::model::{
+    input_tensor, logits_vec, model_load_error, KvCache, LlmError, ModelBackend, ModelInfo, Vocab,
+    MODEL_CONTEXT_LENGTH,
+};
+use super::startup::StartupCache;
+use super::usage;
+use crate::node::config::ModelFormat;
+
+/// A GGUF model run through candle's quantized Llama. The weights keep
+/// their attention cache inside, so each `KvCache` holds its own copy of
+/// them; the quantized tensors themselves are shared.
+pub(super) struct QuantizedBackend {
+    weights: ModelWeights,
+    embeddings: TokenEmbeddings,
+    vocab: Vocab,
+    device: Device,
+    info: ModelInfo,
+    model_path: PathBuf,
+    tokenizer_path: PathBuf,
+}
+
+impl QuantizedBackend {
+    pub(super) fn load(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        device: Device,
+        adapter: Option<&LoraAdapter>,
+        startup: Option<&StartupCache>,
+    ) -> Result<Self, LlmError> {
+        let mut file = File::open(model_path)
+            .map_err(|e| LlmError::ModelLoad { path: model_path.to_path_buf(), reason: e.to_string() })?;
+        let content = gguf_file::Content::read(&mut file)
+            .map_err(|e| model_load_error(model_path, "reading GGUF header", e))?;
+        let info = gguf_info(&content);
+        let (weights, embeddings) = match adapter {
+            Some(adapter) => {
+                let mut merged = merge_adapter(content, &mut file, adapter, model_path)?;
+                let content = gguf_file::Content::read(&mut merged)
+                    .map_err(|e| model_load_error(model_path, "reading merged GGUF", e))?;
+                load_weights(content, &mut merged, &device, model_path)?
+            }
+            None => load_weights(content, &mut file, &device, model_path)?,
+        };
+        let vocab = Vocab::load(tokenizer_path, startup)?;
+        Ok(QuantizedBackend {
+            weights,
+            embeddings,
+            vocab,
+            device,
+            info,
+            model_path: model_path.to_path_buf(),
+            tokenizer_path: tokenizer_path.to_path_buf(),
+        })
+    }
+}
+
+fn load_weights<R: Read + Seek>(
+    content: gguf_file::Content,
+    reader: &mut R,
+    device: &Device,
+    model_path: &Path,
+) -> Result<(ModelWeights, TokenEmbeddings), LlmError> {
+    // Dequantized once, to f16, since candle cannot index quantized rows.
+    let embeddings = content.tensor(reader, "token_embd.weight", device)
+        .and_then(|embeddings| embeddings.dequantize(device))
+        .and_then(|embeddings| embeddings.to_dtype(DType::F16))
+        .map_err(|e| model_load_error(model_path, "loading token embeddings", e))?;
+    let weights = ModelWeights::from_gguf(content, reader, device)
+        .map_err(|e| model_load_error(model_path, "loading quantized weights", e))?;
+    Ok((weights, TokenEmbeddings::new(embeddings)))
+}
+
+/// The model rewritten in memory with each adapted weight dequantized, its
+/// delta added, and quantized back to its own type; the rest is copied.
+/// An adapter that does not fit is refused before anything is read.
+fn merge_adapter<R: Read + Seek>(
+    content: gguf_file::Content,
+    reader: &mut R,
+    adapter: &LoraAdapter,
+    model_path: &Path,
+) -> Result<Cursor<Vec<u8>>, LlmError> {
+    adapter.check(|name| content.tensor_infos.get(name).map(|info| info.shape.dims().to_vec()))?;
+    let failed = |e| model_load_error(model_path, "merging adapter", e);
+    let deltas = adapter.deltas(&Device::Cpu).map_err(failed)?;
+    let mut tensors = Vec::with_capacity(content.tensor_infos.len());
+    for name in content.tensor_infos.keys() {
+        let tensor = content.tensor(reader, name, &Device::Cpu).map_err(failed)?;
+        let tensor = match deltas.get(name) {
+            Some(delta) => {
+                let merged = tensor.dequantize(&Device::Cpu).and_then(|weight| weight + delta).map_err(failed)?;
+                QTensor::quantize(&merged, tensor.dtype()).map_err(failed)?
+            }
+            None => tensor,
+        };
+        tensors.push((name.as_str(), tensor));
+    }
+    let metadata: Vec<(&str, &gguf_file::Value)> = content.metadata.iter().map(|(key, value)| (key.as_str(), value)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(name, tensor)| (*name, tensor)).collect();
+    let mut merged = Cursor::new(Vec::new());
+    gguf_file::write(&mut merged, &metadata, &tensors).map_err(failed)?;
+    merged.set_position(0);
+    Ok(merged)
+}
+
+/// Sizes from the GGUF tensor table. The quantization named is the one
+/// covering most parameters; norms and embeddings are often kept wider.
+fn gguf_info(content: &gguf_file::Content) -> ModelInfo {
+    let mut by_dtype: Vec<(GgmlDType, u64)> = Vec::new();
+    let (mut parameters, mut memory_bytes) = (0u64, 0u64);
+    for tensor in content.tensor_infos.values() {
+        let (dtype, elements) = (tensor.ggml_dtype, tensor.shape.elem_count() as u64);
+        parameters += elements;
+        memory_bytes += elements / dtype.block_size() as u64 * dtype.type_size() as u64;
+        match by_dtype.iter_mut().find(|(seen, _)| *seen == dtype) {
+            Some((_, count)) => *count += elements,
+            None => by_dtype.push((dtype, elements)),
+        }
+    }
+    let quantization = by_dtype.iter()
+        .max_by_key(|(_, count)| *count)
+        .map_or_else(String::new, |(dtype, _)| format!("{:?}", dtype));
+    let context_length = content.metadata.get("llama.context_length")
+        .and_then(|value| value.to_u32().ok())
+        .map_or(MODEL_CONTEXT_LENGTH, |length| length as usize);
+    ModelInfo { format: Some(ModelFormat::Gguf), parameters, quantization, memory_bytes, context_length, ..ModelInfo::default() }
+}
+
+impl ModelBackend for QuantizedBackend {
+    fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+        self.vocab.encode(text)
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+        self.vocab.decode(tokens)
+    }
+
+    fn new_cache(&self) -> Result<KvCache, LlmError> {
+        Ok(KvCache::new(self.weights.clone()))
+    }
+
+    fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+        let position = cache.len();
+        let weights = cache.state_mut::<ModelWeights>()
+            .ok_or_else(|| LlmError::InferenceFailed("KV cache is not a quantized Llama cache".to_string()))?;
+        let input = input_tensor(tokens, &self.device)?;
+        let logits = weights.forward(&input, position)
+            .map_err(|e| LlmError::InferenceFailed(format!("quantized forward over input {:?} at {}: {}", input.dims(), position, e)))?;
+        logits_vec(logits)
+    }
+
+    fn fork_cache(&self, cache: &KvCache) -> Result<KvCache, LlmError> {
+        cache.fork::<ModelWeights>()
+            .ok_or_else(|| LlmError::InferenceFailed("KV cache is not a quantized Llama cache".to_string()))
+    }
+
+    fn vocab(&self) -> HashMap<String, u32> {
+        self.vocab.tokens()
+    }
+
+    fn bos_token(&self) -> Option<u32> {
+        self.vocab.bos_token
+    }
+
+    fn eos_token(&self) -> Option<u32> {
+        self.vocab.eos_token
+    }
+
+    fn context_length(&self) -> usize {
+        self.info.context_length
+    }
+
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    fn embedding_dim(&self) -> usize {
+        self.embeddings.dim()
+    }
+
+    fn hidden_states(&self, batch: &[Vec<u32>]) -> Result<Vec<Vec<Vec<f32>>>, LlmError> {
+        self.embeddings.hidden_states(batch)
+    }
+
+    fn with_adapter(&self, adapter: Option<&LoraAdapter>) -> Result<Arc<dyn ModelBackend>, LlmError> {
+        Ok(Arc::new(QuantizedBackend::load(&self.model_path, &self.tokenizer_path, self.device.clone(), adapter, None)?))
+    }
+
+    fn memory_high_water(&self) -> Option<u64> {
+        usage::memory_high_water(&self.device)
+    }
+}
@@ -0,0 +1,519 @@
+use futures::stream::{self, Stream, StreamExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use super::admission::{AdmissionController, AdmissionPermit};
+use super::cache::{self, CacheKey, InferenceCache};
+use super::estimate::{self, Estimate};
+use super::generate::{self, Completion, GenerateParams, Generation, Partial, Prompt, Sampled, TokenChunk};
+use super::model::{LlmError, ModelBackend};
+use super::usage::LlmMetrics;
+use super::worker::ModelWorker;
+use crate::node::config::LLMConfig;
+
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 64;
+pub const DEFAULT_BATCH_WINDOW: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Sequences run together in one forward pass.
+    pub max_batch_size: usize,
+    /// Requests waiting for a place in the batch; more are refused with
+    /// `LlmError::BatchFull`.
+    pub max_queue_depth: usize,
+    /// How long an idle queue waits after a request for others to batch
+    /// with it.
+    pub batch_window: Duration,
+}
+
+impl QueueConfig {
+    pub fn new(max_batch_size: usize) -> Self {
+        QueueConfig {
+            max_batch_size,
+            max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+            batch_window: DEFAULT_BATCH_WINDOW,
+        }
+    }
+
+    pub fn with_max_queue_depth(mut self, max_queue_depth: usize) -> Self {
+        self.max_queue_depth = max_queue_depth;
+        self
+    }
+
+    pub fn with_batch_window(mut self, batch_window: Duration) -> Self {
+        self.batch_window = batch_window;
+        self
+    }
+}
+
+impl From<&LLMConfig> for QueueConfig {
+    fn from(config: &LLMConfig) -> Self {
+        QueueConfig::new(config.max_batch_size)
+            .with_max_queue_depth(config.max_queue_depth)
+            .with_batch_window(Duration::from_millis(config.batch_window_ms))
+    }
+}
+
+struct Request {
+    prompt: String,
+    params: GenerateParams,
+    chunks: mpsc::UnboundedSender<Result<TokenChunk, LlmError>>,
+    /// When the request joined the queue.
+    submitted: Instant,
+}
+
+/// A request in the running batch.
+struct Slot {
+    generation: Generation,
+    chunks: mpsc::UnboundedSender<Result<TokenChunk, LlmError>>,
+}
+
+/// Runs generation requests together: every step is one batched forward
+/// pass over all running sequences, and a finished sequence's place goes
+/// to the next waiting request. Sampling stays per request, so any requests
+/// can share a batch.
+pub struct InferenceQueue {
+    requests: mpsc::Sender<Request>,
+    worker: ModelWorker,
+    metrics: Arc<LlmMetrics>,
+    /// Checked before a reproducible request is queued, with the model
+    /// name its keys are made with.
+    cache: Option<(Arc<InferenceCache>, String)>,
+    /// Holds requests back until the node has room for them.
+    admission: Option<Arc<AdmissionController>>,
+}
+
+impl InferenceQueue {
+    pub(crate) fn start(worker: ModelWorker, metrics: Arc<LlmMetrics>, config: QueueConfig) -> Self {
+        let (requests, inbox) = mpsc::channel(config.max_queue_depth.max(1));
+        tokio::spawn(run(worker.clone(), Arc::clone(&metrics), config, inbox));
+        InferenceQueue { requests, worker, metrics, cache: None, admission: None }
+    }
+
+    /// Answers repeated greedy or seeded requests from `cache`, which may
+    /// be shared with other queues; `model` names what this one serves.
+    pub fn with_cache(mut self, cache: Arc<InferenceCache>, model: impl Into<String>) -> Self {
+        self.cache = Some((cache, model.into()));
+        self
+    }
+
+    /// Queues requests only once `admission` lets them in; it may be
+    /// shared with other queues.
+    pub fn with_admission(mut self, admission: Arc<AdmissionController>) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    /// Chunks of the completion as the batch steps through it, as from
+    /// `LightLLM::generate_stream`. Dropping the stream, cancelling
+    /// `params.cancel` or passing `params.deadline` frees its place in the
+    /// batch by the next step; a deadline also covers the wait for a place,
+    /// which the last chunk's usage reports. A request found in the cache
+    /// replays the chunks it was generated in without queueing. Behind
+    /// admission control a request first waits for the node to have room,
+    /// and is refused with `LlmError::BatchFull` if too many already wait.
+    pub fn generate_stream(
+        &self,
+        prompt: &str,
+        params: GenerateParams,
+    ) -> Result<impl Stream<Item = Result<TokenChunk, LlmError>> + Send + 'static, LlmError> {
+        let key = self.cache.as_ref().and_then(|(cache, model)| {
+            CacheKey::new(model, prompt, &params).map(|key| (Arc::clone(cache), key))
+        });
+        if let Some((cache, key)) = &key {
+            let hit = cache.get(key);
+            self.metrics.record_cache_lookup(hit.is_some());
+            if let Some(chunks) = hit {
+                return Ok(cache::replay(&chunks).left_stream());
+            }
+        }
+        let (chunks, receiver) = mpsc::unbounded_channel();
+        let request = Request { prompt: prompt.to_string(), params, chunks, submitted: Instant::now() };
+        let waiting = match &self.admission {
+            Some(admission) => admission.enqueue()?,
+            None => {
+                submit(&self.requests, request)?;
+                return Ok(cache::record(key, stream::unfold((receiver, None), next_chunk).left_stream()).right_stream());
+            }
+        };
+        // Admitted on first poll; the permit goes with the stream.
+        let (requests, worker) = (self.requests.clone(), self.worker.clone());
+        let admitted = async move {
+            let prompt_tokens = estimate::count_tokens(&worker, &request.prompt).await?;
+            let params = request.params.clone();
+            let partial = || Partial { text: String::new(), prompt_tokens, completion_tokens: 0 };
+            let permit = tokio::select! {
+                permit = waiting.admit(prompt_tokens, params.max_tokens) => permit?,
+                _ = params.cancel.cancelled() => return Err(LlmError::Cancelled(partial())),
+                _ = until(params.deadline) => return Err(LlmError::Timeout(partial())),
+            };
+            submit(&requests, request)?;
+            Ok((receiver, Some(permit)))
+        };
+        let stream = stream::once(admitted).flat_map(|admitted| match admitted {
+            Ok(state) => stream::unfold(state, next_chunk).left_stream(),
+            Err(e) => stream::iter([Err(e)]).right_stream(),
+        });
+        Ok(cache::record(key, stream.right_stream()).right_stream())
+    }
+
+    pub async fn generate(&self, prompt: &str, params: GenerateParams) -> Result<Completion, LlmError> {
+        generate::collect(self.generate_stream(prompt, params)?).await
+    }
+
+    /// As `LightLLM::count_tokens`, without taking a place in the batch.
+    pub async fn count_tokens(&self, text: &str) -> Result<usize, LlmError> {
+        estimate::count_tokens(&self.worker, text).await
+    }
+
+    /// As `LightLLM::estimate`, without taking a place in the batch.
+    pub async fn estimate(&self, prompt: &str, params: GenerateParams) -> Result<Estimate, LlmError> {
+        estimate::estimate(&self.worker, &self.metrics, prompt, params).await
+    }
+}
+
+fn submit(requests: &mpsc::Sender<Request>, request: Request) -> Result<(), LlmError> {
+    requests.try_send(request).map_err(|e| match e {
+        TrySendError::Full(_) => LlmError::BatchFull { retry_after_ms: None },
+        TrySendError::Closed(_) => LlmError::InferenceFailed("inference queue has stopped".to_string()),
+    })
+}
+
+type Chunks = mpsc::UnboundedReceiver<Result<TokenChunk, LlmError>>;
+
+async fn next_chunk(
+    (mut receiver, permit): (Chunks, Option<AdmissionPermit>),
+) -> Option<(Result<TokenChunk, LlmError>, (Chunks, Option<AdmissionPermit>))> {
+    receiver.recv().await.map(|chunk| (chunk, (receiver, permit)))
+}
+
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn run(
+    worker: ModelWorker,
+    metrics: Arc<LlmMetrics>,
+    config: QueueConfig,
+    mut inbox: mpsc::Receiver<Request>,
+) {
+    let max_batch_size = config.max_batch_size.max(1);
+    let mut slots: Vec<Slot> = Vec::new();
+    loop {
+        let mut arrived = Vec::new();
+        if slots.is_empty() {
+            match inbox.recv().await {
+                Some(request) => arrived.push(request),
+                None => return,
+            }
+            let window = tokio::time::sleep(config.batch_window);
+            tokio::pin!(window);
+            while arrived.len() < max_batch_size {
+                tokio::select! {
+                    request = inbox.recv() => match request {
+                        Some(request) => arrived.push(request),
+                        None => break,
+                    },
+                    _ = &mut window => break,
+                }
+            }
+        }
+        while slots.len() + arrived.len() < max_batch_size {
+            match inbox.try_recv() {
+                Ok(request) => arrived.push(request),
+                Err(_) => break,
+            }
+        }
+
+        let (handle, metrics) = (worker.clone(), Arc::clone(&metrics));
+        let stepped = worker.run(move |backend| {
+            admit(&handle, backend, &metrics, &mut slots, arrived);
+            step(backend, &mut slots);
+            Ok(slots)
+        });
+        // A panicking backend drops the batch; its streams end unfinished.
+        slots = stepped.await.unwrap_or_default();
+    }
+}
+
+fn admit(
+    worker: &ModelWorker,
+    backend: &dyn ModelBackend,
+    metrics: &Arc<LlmMetrics>,
+    slots: &mut Vec<Slot>,
+    arrived: Vec<Request>,
+) {
+    for Request { prompt, params, chunks, submitted } in arrived {
+        if chunks.is_closed() {
+            continue;
+        }
+        let generation = Generation::start_blocking(worker.clone(), backend, Prompt::Text(prompt), params, Arc::clone(metrics), submitted);
+        match generation {
+            Ok(generation) => slots.push(Slot { generation, chunks }),
+            Err(e) => {
+                let _ = chunks.send(Err(e));
+            }
+        }
+    }
+}
+
+/// One forward pass over the batch, then a token sampled for each request.
+fn step(backend: &dyn ModelBackend, slots: &mut Vec<Slot>) {
+    slots.retain_mut(|slot| match slot.generation.interrupted() {
+        Some(interruption) => {
+            let _ = slot.chunks.send(Err(slot.generation.abort(interruption)));
+            false
+        }
+        None => !slot.chunks.is_closed(),
+    });
+    if slots.is_empty() {
+        return;
+    }
+    let fail = |slots: &mut Vec<Slot>, e: LlmError| {
+        for slot in slots.drain(..) {
+            let _ = slot.chunks.send(Err(e.clone()));
+        }
+    };
+    let taken: Result<Vec<_>, _> = slots.iter_mut().map(|slot| slot.generation.take_input()).collect();
+    let (mut caches, inputs): (Vec<_>, Vec<_>) = match taken {
+        Ok(taken) => taken.into_iter().unzip(),
+        Err(e) => return fail(slots, e),
+    };
+    let logits = match backend.forward_batch(&mut caches, &inputs) {
+        Ok(logits) if logits.len() == slots.len() => logits,
+        Ok(logits) => {
+            let e = format!("batched forward returned {} outputs for {} sequences", logits.len(), slots.len());
+            return fail(slots, LlmError::InferenceFailed(e));
+        }
+        Err(e) => return fail(slots, e),
+    };
+    for ((slot, cache), fed) in slots.iter_mut().zip(caches).zip(&inputs) {
+        slot.generation.restore(cache, fed.len());
+    }
+
+    let mut logits = logits.into_iter();
+    slots.retain_mut(|slot| {
+        let logits = logits.next().unwrap_or_default();
+        let generation = &mut slot.generation;
+        let chunk = generation.accept(&logits).and_then(|sampled| match sampled {
+            Sampled::Finished(last) => Ok(Some(last)),
+            Sampled::Decode(completion) => generation.settle(backend.decode(&completion)?),
+        });
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = slot.chunks.send(Err(e));
+                return false;
+            }
+        };
+        let finished = chunk.as_ref().map_or(false, |chunk| chunk.finish.is_some());
+        // Free the place now rather than a step later.
+        let last = if finished { Ok(None) } else { generation.length_reached() };
+        let running = !finished && matches!(last, Ok(None));
+        for chunk in chunk.into_iter().map(Ok).chain(last.transpose()) {
+            let _ = slot.chunks.send(chunk);
+        }
+        running
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::admission::{AdmissionConfig, ResourceProbe};
+    use crate::llm::{KvCache, LightLLM};
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_util::sync::CancellationToken;
+
+    const WORDS: [&str; 5] = ["<eos>", "one ", "two ", "three ", "four "];
+
+    /// Answers each word with the next in one pass over the whole batch.
+    #[derive(Default)]
+    struct Batched {
+        passes: AtomicUsize,
+        gate: Mutex<()>,
+    }
+
+    impl ModelBackend for Batched {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.split_whitespace()
+                .filter_map(|word| WORDS.iter().position(|known| known.trim_end() == word))
+                .map(|i| i as u32)
+                .collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| WORDS[token as usize]).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            unreachable!("the queue runs whole batches")
+        }
+
+        fn forward_batch(&self, _caches: &mut [KvCache], tokens: &[Vec<u32>]) -> Result<Vec<Vec<f32>>, LlmError> {
+            drop(self.gate.lock());
+            self.passes.fetch_add(1, Ordering::SeqCst);
+            Ok(tokens.iter()
+                .map(|tokens| {
+                    let mut logits = vec![0.0; WORDS.len()];
+                    logits[tokens.last().map_or(1, |&last| last as usize + 1) % WORDS.len()] = 10.0;
+                    logits
+                })
+                .collect())
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            64
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_forward_passes() {
+        let backend = Arc::new(Batched::default());
+        let model = LightLLM::with_backend(backend.clone());
+        let queue = model.start_queue(QueueConfig::new(4).with_batch_window(Duration::from_millis(50)));
+        let params = GenerateParams::new(1).with_temperature(0.0);
+
+        let prompts: Vec<&str> = (0..10).map(|i| WORDS[1 + i % 3].trim_end()).collect();
+        let completions = join_all(prompts.iter().map(|prompt| queue.generate(prompt, params.clone()))).await;
+        for (prompt, completion) in prompts.iter().zip(completions) {
+            let next = WORDS.iter().position(|word| word.trim_end() == *prompt).unwrap() + 1;
+            assert_eq!(completion.unwrap().text, WORDS[next]);
+        }
+        assert!(backend.passes.load(Ordering::SeqCst) <= 3, "{:?}", backend.passes);
+
+        // Longer completions still take one pass per token for the batch.
+        backend.passes.store(0, Ordering::SeqCst);
+        let params = GenerateParams::new(3).with_temperature(0.0);
+        let completions = join_all((0..4).map(|_| queue.generate("one", params.clone()))).await;
+        assert!(completions.into_iter().all(|completion| completion.unwrap().text == "two three four "));
+        assert_eq!(backend.passes.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_leaves_the_batch() {
+        let backend = Arc::new(Batched::default());
+        let model = LightLLM::with_backend(backend.clone());
+        let queue = model.start_queue(QueueConfig::new(2).with_batch_window(Duration::ZERO));
+        let cancel = CancellationToken::new();
+        let params = GenerateParams::new(3).with_temperature(0.0);
+
+        let gate = backend.gate.lock();
+        let cancelled = queue.generate_stream("one", params.clone().with_cancellation(cancel.clone())).unwrap();
+        let running = queue.generate_stream("one", params.clone()).unwrap();
+        cancel.cancel();
+        drop(gate);
+        match generate::collect(cancelled).await {
+            Err(LlmError::Cancelled(partial)) => assert!(partial.completion_tokens <= 1, "{:?}", partial),
+            other => panic!("expected Cancelled, got {:?}", other),
+        }
+        assert_eq!(generate::collect(running).await.unwrap().text, "two three four ");
+        // At most one pass ran for the cancelled request alone.
+        let passes = backend.passes.load(Ordering::SeqCst);
+        assert!(passes <= 4, "{}", passes);
+
+        let expired = params.with_deadline(std::time::Instant::now());
+        assert!(matches!(queue.generate("one", expired).await, Err(LlmError::Timeout(_))));
+        assert_eq!(backend.passes.load(Ordering::SeqCst), passes);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_refuses_requests() {
+        let backend = Arc::new(Batched::default());
+        let model = LightLLM::with_backend(backend.clone());
+        let queue = model.start_queue(QueueConfig::new(1).with_max_queue_depth(2).with_batch_window(Duration::ZERO));
+        let params = GenerateParams::new(1).with_temperature(0.0);
+
+        // The first request holds the batch while two more wait.
+        let gate = backend.gate.lock();
+        let running = queue.generate_stream("one", params.clone()).unwrap();
+        while queue.requests.capacity() < 2 {
+            tokio::task::yield_now().await;
+        }
+        let waiting = [queue.generate_stream("two", params.clone()).unwrap(), queue.generate_stream("three", params.clone()).unwrap()];
+        assert!(matches!(queue.generate("one", params.clone()).await, Err(LlmError::BatchFull { .. })));
+
+        drop(gate);
+        assert_eq!(generate::collect(running).await.unwrap().text, "two ");
+        let [second, third] = waiting;
+        assert_eq!(generate::collect(second).await.unwrap().text, "three ");
+        assert_eq!(generate::collect(third).await.unwrap().text, "four ");
+    }
+
+    struct OneCpu;
+
+    impl ResourceProbe for OneCpu {
+        fn free_memory(&self) -> Option<u64> {
+            None
+        }
+
+        fn cpus(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admission_holds_requests_back() {
+        let backend = Arc::new(Batched::default());
+        let model = LightLLM::with_backend(backend.clone());
+        let admission = Arc::new(AdmissionController::new(AdmissionConfig::new().with_max_waiting(1), Arc::new(OneCpu)));
+        let queue = model.start_queue(QueueConfig::new(4).with_batch_window(Duration::ZERO)).with_admission(Arc::clone(&admission));
+        let params = GenerateParams::new(1).with_temperature(0.0);
+
+        // The batch has room, but the node only for one request at a time.
+        let gate = backend.gate.lock();
+        let first = tokio::spawn(generate::collect(queue.generate_stream("one", params.clone()).unwrap()));
+        while admission.running() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let second = tokio::spawn(generate::collect(queue.generate_stream("two", params.clone()).unwrap()));
+        assert!(matches!(queue.generate_stream("three", params.clone()), Err(LlmError::BatchFull { .. })));
+
+        drop(gate);
+        assert_eq!(first.await.unwrap().unwrap().text, "two ");
+        assert_eq!(second.await.unwrap().unwrap().text, "three ");
+        assert_eq!((admission.running(), admission.waiting()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_usage_counts_queue_wait_and_tokens() {
+        let backend = Arc::new(Batched::default());
+        let model = LightLLM::with_backend(backend.clone());
+        let queue = model.start_queue(QueueConfig::new(1).with_batch_window(Duration::ZERO));
+        let params = GenerateParams::new(2).with_temperature(0.0);
+
+        // The second request waits for the first to finish.
+        let gate = backend.gate.lock();
+        let first = queue.generate_stream("one", params.clone()).unwrap();
+        let second = queue.generate_stream("one two", params).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(gate);
+        let first = generate::collect(first).await.unwrap();
+        let second = generate::collect(second).await.unwrap();
+        assert_eq!((first.usage.prompt_tokens, first.usage.completion_tokens), (1, 2));
+        assert_eq!((second.usage.prompt_tokens, second.usage.completion_tokens), (2, 2));
+        assert!(second.usage.queue_wait_secs >= 0.02, "{:?}", second.usage);
+        assert!(second.usage.time_to_first_token_secs >= second.usage.queue_wait_secs);
+        assert!(first.usage.tokens_per_second > 0.0);
+
+        let totals = model.metrics().totals();
+        assert_eq!((totals.requests, totals.prompt_tokens, totals.completion_tokens), (2, 3, 4));
+    }
+}
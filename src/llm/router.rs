@@ -0,0 +1,408 @@
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+use super::generate::{Completion, Finish, FinishReason, GenerateParams};
+use super::manager::ModelManager;
+use super::model::{LightLLM, LlmError};
+use super::queue::InferenceQueue;
+use super::usage::Usage;
+use crate::node::config::LLMConfig;
+use crate::node::network::{InferenceMessage, InferenceRequest, InferenceResult, NetMessage, Network, PeerId, CAPABILITY_LLM};
+use crate::node::peer_score::Offense;
+
+pub const DEFAULT_ROUTER_PEERS: usize = 3;
+pub const DEFAULT_ROUTER_QUORUM: usize = 2;
+pub const DEFAULT_ROUTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouterConfig {
+    /// Peers each request is sent to.
+    pub peers: usize,
+    /// Peers that must give the same answer for it to be taken. An answer
+    /// must also come from more than half the peers asked.
+    pub quorum: usize,
+    /// How long each peer has to answer.
+    pub timeout: Duration,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig { peers: DEFAULT_ROUTER_PEERS, quorum: DEFAULT_ROUTER_QUORUM, timeout: DEFAULT_ROUTER_TIMEOUT }
+    }
+}
+
+impl RouterConfig {
+    pub fn with_peers(mut self, peers: usize) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        self.quorum = quorum;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl From<&LLMConfig> for RouterConfig {
+    fn from(config: &LLMConfig) -> Self {
+        RouterConfig::default()
+            .with_peers(config.distributed_peers)
+            .with_quorum(config.distributed_quorum)
+            .with_timeout(Duration::from_millis(config.distributed_timeout_ms))
+    }
+}
+
+/// The model a router answers peers with and falls back to.
+#[async_trait]
+pub trait LocalInference: Send + Sync {
+    async fn generate(&self, prompt: &str, params: GenerateParams) -> Result<Completion, LlmError>;
+}
+
+#[async_trait]
+impl LocalInference for LightLLM {
+    async fn generate(&self, prompt: &str, params: GenerateParams) -> Result<Completion, LlmError> {
+        LightLLM::generate(self, prompt, params).await
+    }
+}
+
+#[async_trait]
+impl LocalInference for InferenceQueue {
+    async fn generate(&self, prompt: &str, params: GenerateParams) -> Result<Completion, LlmError> {
+        InferenceQueue::generate(self, prompt, params).await
+    }
+}
+
+/// Whichever model is serving at the time.
+#[async_trait]
+impl LocalInference for ModelManager {
+    async fn generate(&self, prompt: &str, params: GenerateParams) -> Result<Completion, LlmError> {
+        let serving = self.serving().ok_or_else(|| LlmError::InferenceFailed("no model is serving".to_string()))?;
+        serving.queue().generate(prompt, params).await
+    }
+}
+
+/// A distributed completion, with how the peers asked answered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutedCompletion {
+    pub completion: Completion,
+    /// Peers that gave the accepted answer.
+    pub agreeing: Vec<PeerId>,
+    /// Peers that gave another answer; each was penalized.
+    pub disagreeing: Vec<PeerId>,
+    /// Peers asked that refused, timed out or could not be reached.
+    pub unanswered: Vec<PeerId>,
+    /// Whether the answer is our own, because no answer reached a quorum.
+    pub local: bool,
+}
+
+enum Answer {
+    Completed(InferenceResult),
+    Refused(String),
+}
+
+/// Runs requests on several LLM-capable peers at once and takes their
+/// answer only when enough of them agree. Sampling is seeded, so honest
+/// peers running the same model return the same tokens, and are compared
+/// exactly. Without a quorum the request runs locally, and peers that
+/// answered otherwise are penalized either way. `run` must be running for
+/// answers to arrive, and answers the peers' own requests.
+pub struct InferenceRouter {
+    network: Arc<Network>,
+    local: Arc<dyn LocalInference>,
+    config: RouterConfig,
+    next_id: AtomicU64,
+    /// Requests awaiting answers, by id.
+    pending: Mutex<HashMap<u64, mpsc::UnboundedSender<(PeerId, Answer)>>>,
+}
+
+/// Forgets a request once its answers are no longer awaited.
+struct Pending<'a> {
+    router: &'a InferenceRouter,
+    id: u64,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.router.pending.lock().remove(&self.id);
+    }
+}
+
+fn reason_code(reason: FinishReason) -> u8 {
+    match reason {
+        FinishReason::Length => 0,
+        FinishReason::Stop => 1,
+        FinishReason::EndOfText => 2,
+    }
+}
+
+fn reason_from_code(code: u8) -> Option<FinishReason> {
+    match code {
+        0 => Some(FinishReason::Length),
+        1 => Some(FinishReason::Stop),
+        2 => Some(FinishReason::EndOfText),
+        _ => None,
+    }
+}
+
+fn to_request(id: u64, prompt: &str, params: &GenerateParams, seed: u64) -> InferenceRequest {
+    InferenceRequest {
+        id,
+        prompt: prompt.to_string(),
+        max_tokens: params.max_tokens.min(u32::MAX as usize) as u32,
+        temperature: params.temperature.to_bits(),
+        top_k: params.top_k.map(|top_k| top_k.min(u32::MAX as usize) as u32),
+        top_p: params.top_p.map(f32::to_bits),
+        repetition_penalty: params.repetition_penalty.to_bits(),
+        stop: params.stop.clone(),
+        seed,
+    }
+}
+
+fn from_request(request: &InferenceRequest) -> GenerateParams {
+    let mut params = GenerateParams::new(request.max_tokens as usize)
+        .with_temperature(f32::from_bits(request.temperature))
+        .with_repetition_penalty(f32::from_bits(request.repetition_penalty))
+        .with_seed(request.seed);
+    params.top_k = request.top_k.map(|top_k| top_k as usize);
+    params.top_p = request.top_p.map(f32::from_bits);
+    params.stop = request.stop.clone();
+    params
+}
+
+/// `completion`, generated with logprobs so its tokens are known.
+fn to_result(id: u64, completion: &Completion) -> InferenceResult {
+    InferenceResult {
+        id,
+        tokens: completion.logprobs.iter().map(|step| step.token).collect(),
+        text: completion.text.clone(),
+        finish_reason: reason_code(completion.finish.reason),
+        prompt_tokens: completion.finish.prompt_tokens as u32,
+        truncated_tokens: completion.finish.truncated_tokens as u32,
+    }
+}
+
+/// Whether two answers are the same, whichever requests they answer.
+fn same_answer(a: &InferenceResult, b: &InferenceResult) -> bool {
+    InferenceResult { id: b.id, ..a.clone() } == *b
+}
+
+fn to_completion(result: &InferenceResult) -> Option<Completion> {
+    let (prompt_tokens, completion_tokens) = (result.prompt_tokens as usize, result.tokens.len());
+    let finish = Finish {
+        reason: reason_from_code(result.finish_reason)?,
+        prompt_tokens,
+        completion_tokens,
+        truncated_tokens: result.truncated_tokens as usize,
+    };
+    let usage = Usage { prompt_tokens, completion_tokens, ..Usage::default() };
+    Some(Completion { text: result.text.clone(), finish, usage, logprobs: Vec::new() })
+}
+
+impl InferenceRouter {
+    pub fn new(network: Arc<Network>, local: Arc<dyn LocalInference>, config: RouterConfig) -> Self {
+        InferenceRouter {
+            network,
+            local,
+            config,
+            next_id: AtomicU64::new(rand::random()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> RouterConfig {
+        self.config
+    }
+
+    /// Hands answers to the requests awaiting them and answers peers'
+    /// requests with the local model, until `cancel` fires.
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let mut inbox = self.network.subscribe_inference();
+        loop {
+            let (peer, message) = tokio::select! {
+                _ = cancel.cancelled() => return,
+                received = inbox.recv() => match received {
+                    Ok(received) => received,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Missed {} inference messages", missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            match message {
+                InferenceMessage::Request(request) => {
+                    let (router, cancel) = (Arc::clone(&self), cancel.child_token());
+                    tokio::spawn(async move { router.answer(peer, request, cancel).await });
+                }
+                InferenceMessage::Result(result) => self.deliver(peer, result.id, Answer::Completed(result)),
+                InferenceMessage::Refused { id, reason } => self.deliver(peer, id, Answer::Refused(reason)),
+                // For the `InferenceChallenger`.
+                InferenceMessage::Challenge(_) | InferenceMessage::ChallengeResponse(_) => {}
+            }
+        }
+    }
+
+    fn deliver(&self, peer: PeerId, id: u64, answer: Answer) {
+        match self.pending.lock().get(&id) {
+            Some(answers) => {
+                let _ = answers.send((peer, answer));
+            }
+            None => debug!("Dropping an answer from {} to inference {}, which is not awaited", peer, id),
+        }
+    }
+
+    async fn answer(&self, peer: PeerId, request: InferenceRequest, cancel: CancellationToken) {
+        let id = request.id;
+        let reply = if self.network.capabilities() & CAPABILITY_LLM == 0 {
+            InferenceMessage::Refused { id, reason: "this node does not serve inference".to_string() }
+        } else {
+            let params = from_request(&request).with_cancellation(cancel).with_logprobs(0);
+            match self.local.generate(&request.prompt, params).await {
+                Ok(completion) => InferenceMessage::Result(to_result(id, &completion)),
+                Err(e) => InferenceMessage::Refused { id, reason: e.to_string() },
+            }
+        };
+        if let Err(e) = self.network.send(&peer, NetMessage::Inference(reply)).await {
+            debug!("Cannot answer inference {} from {}: {}", id, peer, e);
+        }
+    }
+
+    /// Connected peers advertising LLM capability, in random order, at
+    /// most `config.peers` of them.
+    fn choose_peers(&self) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self.network.peers().into_iter()
+            .filter(|peer| peer.connected && peer.supports(CAPABILITY_LLM))
+            .map(|peer| peer.id)
+            .collect();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(self.config.peers);
+        peers
+    }
+
+    /// Completes `prompt` on the peers, or locally when they do not agree.
+    /// A seed is chosen when `params` has none, and logprobs are not
+    /// reported.
+    pub async fn generate(&self, prompt: &str, mut params: GenerateParams) -> Result<RoutedCompletion, LlmError> {
+        params.validate()?;
+        if params.logprobs.is_some() {
+            return Err(LlmError::InvalidParams("distributed inference does not report logprobs".to_string()));
+        }
+        if params.response_format.is_some() {
+            return Err(LlmError::InvalidParams("distributed inference does not follow a response_format".to_string()));
+        }
+        let seed = *params.seed.get_or_insert_with(rand::random);
+        let peers = self.choose_peers();
+        let (answers, unanswered) = if peers.len() >= self.config.quorum.max(1) {
+            self.ask(&peers, prompt, &params, seed).await
+        } else {
+            debug!("{} LLM peers, {} needed; completing locally", peers.len(), self.config.quorum);
+            (Vec::new(), Vec::new())
+        };
+
+        // Grouped by the whole answer, so agreeing tokens with other text
+        // still disagree.
+        let mut tallies: Vec<(&InferenceResult, Vec<PeerId>)> = Vec::new();
+        for (peer, result) in &answers {
+            match tallies.iter_mut().find(|(answer, _)| same_answer(answer, result)) {
+                Some((_, agreeing)) => agreeing.push(*peer),
+                None => tallies.push((result, vec![*peer])),
+            }
+        }
+        let winner = tallies.into_iter()
+            .max_by_key(|(_, agreeing)| agreeing.len())
+            .filter(|(_, agreeing)| agreeing.len() >= self.config.quorum && agreeing.len() * 2 > peers.len())
+            .and_then(|(result, _)| to_completion(result).map(|completion| (result.clone(), completion)));
+
+        let (reference, completion, local) = match winner {
+            Some((result, completion)) => (result, completion, false),
+            None => {
+                let mut completion = self.local.generate(prompt, params.with_logprobs(0)).await?;
+                let result = to_result(0, &completion);
+                completion.logprobs.clear();
+                (result, completion, true)
+            }
+        };
+        let (agreeing, disagreeing): (Vec<_>, Vec<_>) = answers.iter().partition(|(_, result)| same_answer(result, &reference));
+        let agreeing: Vec<PeerId> = agreeing.into_iter().map(|(peer, _)| *peer).collect();
+        let disagreeing: Vec<PeerId> = disagreeing.into_iter().map(|(peer, _)| *peer).collect();
+        for peer in &disagreeing {
+            info!("Peer {} disagreed on a distributed inference", peer);
+            self.network.report(peer, Offense::WrongResult);
+        }
+        Ok(RoutedCompletion { completion, agreeing, disagreeing, unanswered, local })
+    }
+
+    /// Sends the request to each of `peers`, returning the results that
+    /// arrived within the timeout and the peers that gave none.
+    async fn ask(
+        &self,
+        peers: &[PeerId],
+        prompt: &str,
+        params: &GenerateParams,
+        seed: u64,
+    ) -> (Vec<(PeerId, InferenceResult)>, Vec<PeerId>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, mut inbox) = mpsc::unbounded_channel();
+        self.pending.lock().insert(id, sender);
+        let _pending = Pending { router: self, id };
+
+        let request = to_request(id, prompt, params, seed);
+        let mut waiting = HashSet::new();
+        let mut unanswered = Vec::new();
+        for peer in peers {
+            let message = NetMessage::Inference(InferenceMessage::Request(request.clone()));
+            match self.network.send(peer, message).await {
+                Ok(()) => {
+                    waiting.insert(*peer);
+                }
+                Err(e) => {
+                    debug!("Cannot send inference {} to {}: {}", id, peer, e);
+                    unanswered.push(*peer);
+                }
+            }
+        }
+
+        let deadline = Instant::now() + self.config.timeout;
+        let mut answers = Vec::new();
+        while !waiting.is_empty() {
+            let (peer, answer) = tokio::select! {
+                _ = params.cancel.cancelled() => break,
+                _ = sleep_until(deadline) => break,
+                answer = inbox.recv() => match answer {
+                    Some(answer) => answer,
+                    None => break,
+                },
+            };
+            // Only the first answer of each peer asked counts.
+            if !waiting.remove(&peer) {
+                continue;
+            }
+            match answer {
+                Answer::Completed(result) => answers.push((peer, result)),
+                Answer::Refused(reason) => {
+                    debug!("{} refused inference {}: {}", peer, id, reason);
+                    unanswered.push(peer);
+                }
+            }
+        }
+        for peer in waiting {
+            debug!("{} did not answer inference {} in time", peer, id);
+            unanswered.push(peer);
+        }
+        (answers, unanswered)
+    }
+}
@@ -0,0 +1,194 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use super::generate::GenerateParams;
+use super::model::LlmError;
+
+/// Picks each next token from the model's logits: the repetition penalty,
+/// then temperature, top-k and top-p. A seeded sampler makes the same
+/// choices given the same logits.
+#[derive(Clone)]
+pub struct Sampler {
+    temperature: f32,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+    repetition_penalty: f32,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(params: &GenerateParams) -> Self {
+        let rng = match params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Sampler {
+            temperature: params.temperature,
+            top_k: params.top_k,
+            top_p: params.top_p,
+            repetition_penalty: params.repetition_penalty,
+            rng,
+        }
+    }
+
+    /// The token to follow `context`, given the model's `logits` for it.
+    pub fn sample(&mut self, logits: &[f32], context: &[u32]) -> Result<u32, LlmError> {
+        let distribution = self.distribution(logits, context)?;
+        Ok(self.draw(&distribution))
+    }
+
+    /// The tokens `sample` would choose between, and their weights.
+    pub(super) fn distribution(&self, logits: &[f32], context: &[u32]) -> Result<Distribution, LlmError> {
+        let mut logits = logits.to_vec();
+        self.penalize(&mut logits, context);
+        // Likeliest first; ties go to the lower token so runs reproduce.
+        let mut candidates: Vec<(u32, f32)> = logits.iter()
+            .enumerate()
+            .filter(|(_, logit)| !logit.is_nan())
+            .map(|(token, &logit)| (token as u32, logit))
+            .collect();
+        candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal).then(a.0.cmp(&b.0)));
+        let (best, max) = *candidates.first()
+            .ok_or_else(|| LlmError::InferenceFailed("model returned no usable logits".to_string()))?;
+        if self.temperature <= 0.0 {
+            return Ok(Distribution::new(vec![(best, 1.0)], false));
+        }
+        if let Some(top_k) = self.top_k {
+            candidates.truncate(top_k.max(1));
+        }
+
+        let mut weights: Vec<(u32, f64)> = candidates.iter()
+            .map(|&(token, logit)| (token, (((logit - max) / self.temperature) as f64).exp()))
+            .collect();
+        if let Some(top_p) = self.top_p {
+            let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+            let mut cumulative = 0.0;
+            let kept = weights.iter()
+                .position(|(_, weight)| {
+                    cumulative += weight / total;
+                    cumulative >= top_p as f64
+                })
+                .map_or(weights.len(), |last| last + 1);
+            weights.truncate(kept);
+        }
+        Ok(Distribution::new(weights, true))
+    }
+
+    /// A token from `distribution`; greedy ones take the likeliest without
+    /// using the random number generator.
+    pub(super) fn draw(&mut self, distribution: &Distribution) -> u32 {
+        let candidates = &distribution.candidates;
+        if !distribution.random {
+            return candidates[0].0;
+        }
+        let mut target = self.rng.gen::<f64>() * distribution.total;
+        for &(token, weight) in candidates {
+            if target < weight {
+                return token;
+            }
+            target -= weight;
+        }
+        candidates[candidates.len() - 1].0
+    }
+
+    /// Whether to keep `token`, drawn from `proposal`, where `target` is
+    /// the distribution it should have come from: always if `target` makes
+    /// it at least as likely, else with the ratio of the two.
+    pub(super) fn accept_proposal(&mut self, target: &Distribution, proposal: &Distribution, token: u32) -> bool {
+        let (p, q) = (target.probability(token), proposal.probability(token));
+        p >= q || (p > 0.0 && self.rng.gen::<f64>() * q < p)
+    }
+
+    /// A token in place of a refused proposal: drawn from what `target`
+    /// gives beyond `proposal`, so that proposals kept and replaced
+    /// together follow `target`.
+    pub(super) fn draw_residual(&mut self, target: &Distribution, proposal: &Distribution) -> u32 {
+        let proposed: HashMap<u32, f64> = proposal.candidates.iter()
+            .map(|&(token, weight)| (token, weight / proposal.total))
+            .collect();
+        let residual: Vec<(u32, f64)> = target.candidates.iter()
+            .map(|&(token, weight)| (token, weight / target.total - proposed.get(&token).copied().unwrap_or(0.0)))
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect();
+        if residual.is_empty() {
+            return self.draw(target);
+        }
+        self.draw(&Distribution::new(residual, target.random))
+    }
+
+    /// Makes every token already in `context` less likely, once each.
+    fn penalize(&self, logits: &mut [f32], context: &[u32]) {
+        if self.repetition_penalty == 1.0 {
+            return;
+        }
+        let mut seen = context.to_vec();
+        seen.sort_unstable();
+        seen.dedup();
+        for token in seen {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit = if *logit > 0.0 { *logit / self.repetition_penalty } else { *logit * self.repetition_penalty };
+            }
+        }
+    }
+}
+
+/// The tokens one step may choose, likeliest first, with their weights.
+#[derive(Debug, Clone)]
+pub(super) struct Distribution {
+    candidates: Vec<(u32, f64)>,
+    total: f64,
+    /// Drawn from by weight; otherwise the first is always taken.
+    random: bool,
+}
+
+impl Distribution {
+    fn new(candidates: Vec<(u32, f64)>, random: bool) -> Self {
+        let total = candidates.iter().map(|(_, weight)| weight).sum();
+        Distribution { candidates, total, random }
+    }
+
+    pub(super) fn probability(&self, token: u32) -> f64 {
+        self.candidates.iter().find(|(candidate, _)| *candidate == token).map_or(0.0, |(_, weight)| weight / self.total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGITS: [f32; 6] = [1.0, 2.5, 2.4, -1.0, 0.5, 2.5];
+
+    fn run(params: &GenerateParams, samples: usize) -> Vec<u32> {
+        let mut sampler = Sampler::new(params);
+        (0..samples).map(|_| sampler.sample(&LOGITS, &[]).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_seeded_sampling_reproduces() {
+        let params = GenerateParams::new(1).with_temperature(1.0).with_seed(7);
+        let first = run(&params, 200);
+        assert_eq!(first, run(&params, 200));
+        assert!(first.iter().any(|&token| token != first[0]));
+
+        // Greedy and top-1 both take the likeliest, the lower token on a tie.
+        assert!(run(&GenerateParams::new(1).with_temperature(0.0), 20).iter().all(|&token| token == 1));
+        assert!(run(&params.clone().with_top_k(1), 20).iter().all(|&token| token == 1));
+        // The two tied tokens alone cover half the probability.
+        let nucleus = run(&params.clone().with_top_p(0.5), 200);
+        assert!(nucleus.iter().all(|&token| token == 1 || token == 5));
+        let top_three = run(&params.with_top_k(3), 200);
+        assert!(top_three.iter().all(|&token| [1, 2, 5].contains(&token)));
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let mut sampler = Sampler::new(&GenerateParams::new(1).with_temperature(0.0).with_repetition_penalty(1.5));
+        // Positive logits are divided and negative ones multiplied.
+        assert_eq!(sampler.sample(&[2.0, 1.5, -0.1], &[0, 0]).unwrap(), 1);
+        assert_eq!(sampler.sample(&[-1.0, -1.2], &[0]).unwrap(), 1);
+        assert_eq!(sampler.sample(&[2.0, 1.5], &[]).unwrap(), 0);
+        assert!(sampler.sample(&[f32::NAN], &[]).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::generate::{self, Completion, GenerateParams, Generation, Timing, TruncationPolicy};
+use super::model::{KvCache, LlmError};
+use super::structured::Constraint;
+use super::usage::LlmMetrics;
+use super::worker::ModelWorker;
+
+/// What a `ChatSession` drops once a turn would overflow the context window.
+/// Evicting shifts every position, so the cache is rebuilt over what is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionStrategy {
+    /// Refuse the turn with `LlmError::PromptTooLong`.
+    Error,
+    /// Drop just enough of the oldest tokens for the turn to fit.
+    #[default]
+    DropOldest,
+    /// Drop the oldest tokens down to half the window, so the cache is
+    /// rebuilt once every few turns rather than on each one.
+    DropHalf,
+}
+
+impl EvictionStrategy {
+    /// Tokens of the sequence to keep when it must fit in `limit`.
+    fn keep(self, sequence: usize, turn: usize, limit: usize) -> usize {
+        match self {
+            EvictionStrategy::DropHalf if sequence > limit => (limit / 2).max(turn).min(limit),
+            _ => limit,
+        }
+    }
+
+    fn policy(self) -> TruncationPolicy {
+        match self {
+            EvictionStrategy::Error => TruncationPolicy::Error,
+            EvictionStrategy::DropOldest | EvictionStrategy::DropHalf => TruncationPolicy::TruncateStart,
+        }
+    }
+}
+
+/// A conversation: every turn's text and completion appended to one token
+/// sequence, with the model's cache kept between turns. Each sampled token
+/// stays in the sequence, including any stop sequence.
+pub struct ChatSession {
+    worker: ModelWorker,
+    metrics: Arc<LlmMetrics>,
+    tokens: Vec<u32>,
+    /// Holds a prefix of `tokens`; rebuilt when missing.
+    cache: Option<KvCache>,
+    eviction: EvictionStrategy,
+}
+
+impl ChatSession {
+    pub(crate) fn new(worker: ModelWorker, metrics: Arc<LlmMetrics>) -> Self {
+        ChatSession { worker, metrics, tokens: Vec::new(), cache: None, eviction: EvictionStrategy::default() }
+    }
+
+    pub fn with_eviction(mut self, eviction: EvictionStrategy) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Tokens in the session's context.
+    pub fn token_count(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Appends `append_text` and completes it; the completion is appended
+    /// too. `params.truncation` is not used: the session's
+    /// `EvictionStrategy` makes room instead. A failed turn leaves the
+    /// session as it was.
+    pub async fn generate(&mut self, append_text: &str, params: GenerateParams) -> Result<Completion, LlmError> {
+        let timing = Timing::since(Instant::now());
+        let limit = generate::prompt_limit(&params, self.worker.context_length())?;
+        let text = append_text.to_string();
+        let mut turn = self.worker.run(move |backend| backend.encode(&text)).await?;
+        let bos = self.worker.bos_token();
+        if !self.tokens.is_empty() && bos.is_some() && turn.first() == bos.as_ref() {
+            turn.remove(0);
+        }
+        let fully_cached = self.cache.as_ref().map_or(false, |cache| cache.len() == self.tokens.len());
+        if turn.is_empty() && fully_cached {
+            return Err(LlmError::InvalidParams("nothing appended to continue from".to_string()));
+        }
+
+        let sequence: Vec<u32> = self.tokens.iter().copied().chain(turn.iter().copied()).collect();
+        let keep = self.eviction.keep(sequence.len(), turn.len(), limit);
+        let (tokens, evicted) = self.eviction.policy().apply(sequence, keep, bos)?;
+        let cache = match self.cache.take() {
+            Some(cache) if evicted == 0 => cache,
+            _ => self.worker.run(|backend| backend.new_cache()).await?,
+        };
+
+        let constraint = match params.response_format.clone() {
+            Some(schema) => Some(self.worker.run(move |backend| Constraint::new(&schema, backend)).await?),
+            None => None,
+        };
+        let mut generation =
+            Generation::resume(self.worker.clone(), tokens, cache, evicted, params, Arc::clone(&self.metrics), timing);
+        generation.constraint = constraint;
+        let (mut text, mut logprobs) = (String::new(), Vec::new());
+        let (finish, usage) = loop {
+            let chunk = generation.next_chunk().await?;
+            text.push_str(&chunk.text);
+            logprobs.extend(chunk.logprobs);
+            if let Some(finish) = chunk.finish {
+                break (finish, chunk.usage.unwrap_or_default());
+            }
+        };
+        self.tokens = generation.tokens;
+        self.cache = generation.cache;
+        Ok(Completion { text, finish, usage, logprobs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{LightLLM, ModelBackend};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// One token per byte. Logits over the letters a to h depend on the
+    /// whole sequence, which the cache records, so a cache holding the
+    /// wrong tokens changes the output.
+    struct Bytes {
+        context_length: usize,
+        fed: AtomicUsize,
+    }
+
+    impl Bytes {
+        fn new(context_length: usize) -> Arc<Self> {
+            Arc::new(Bytes { context_length, fed: AtomicUsize::new(0) })
+        }
+    }
+
+    impl ModelBackend for Bytes {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().map(u32::from).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| token as u8 as char).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(Vec::<u32>::new()))
+        }
+
+        fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            self.fed.fetch_add(tokens.len(), Ordering::SeqCst);
+            let sequence = cache.state_mut::<Vec<u32>>().unwrap();
+            sequence.extend_from_slice(tokens);
+            let hash = sequence.iter().fold(17u64, |hash, &token| hash.wrapping_mul(31).wrapping_add(token as u64));
+            let mut logits = vec![f32::NEG_INFINITY; 128];
+            for letter in b'a'..=b'h' {
+                logits[letter as usize] = (hash.wrapping_add(letter as u64 * 7) % 5) as f32;
+            }
+            Ok(logits)
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            self.context_length
+        }
+    }
+
+    fn params() -> GenerateParams {
+        GenerateParams::new(8).with_temperature(1.0).with_seed(42)
+    }
+
+    #[tokio::test]
+    async fn test_session_continuation_matches_concatenated_prompt() {
+        let backend = Bytes::new(4096);
+        let model = LightLLM::with_backend(backend.clone());
+        let mut session = model.new_session();
+        let first = session.generate("hello ", params()).await.unwrap();
+        assert_eq!(session.token_count(), 6 + 8);
+        let second = session.generate(" and then ", params()).await.unwrap();
+        assert_eq!(session.token_count(), 6 + 8 + 10 + 8);
+        // Every token went through the model once, bar the last sampled.
+        assert_eq!(backend.fed.load(Ordering::SeqCst), session.token_count() - 1);
+
+        let prompt = format!("hello {} and then ", first.text);
+        let scratch = model.generate(&prompt, params()).await.unwrap();
+        assert_eq!(second.text, scratch.text);
+        assert_eq!(second.finish, scratch.finish);
+    }
+
+    #[tokio::test]
+    async fn test_session_evicts_from_the_front() {
+        let model = LightLLM::with_backend(Bytes::new(40));
+        let mut session = model.new_session();
+        let first = session.generate("abcdefghijklmnop", params()).await.unwrap();
+        assert_eq!(session.token_count(), 24);
+
+        // 24 + 10, with 8 more to generate, overflow 40: the oldest two go.
+        let turn = session.generate("qrstuvwxyz", params()).await.unwrap();
+        assert_eq!((turn.finish.truncated_tokens, turn.finish.prompt_tokens), (2, 32));
+        assert_eq!(session.token_count(), 40);
+        let scratch = model.generate(&format!("cdefghijklmnop{}qrstuvwxyz", first.text), params()).await.unwrap();
+        assert_eq!(scratch.text, turn.text);
+
+        let mut halving = model.new_session().with_eviction(EvictionStrategy::DropHalf);
+        halving.generate("abcdefghijklmnop", params()).await.unwrap();
+        let turn = halving.generate("qrstuvwxyz", params()).await.unwrap();
+        assert_eq!((turn.finish.truncated_tokens, turn.finish.prompt_tokens), (18, 16));
+
+        let mut strict = model.new_session().with_eviction(EvictionStrategy::Error);
+        strict.generate("abcdefghijklmnop", params()).await.unwrap();
+        let refused = strict.generate("qrstuvwxyz", params()).await;
+        assert_eq!(refused, Err(LlmError::PromptTooLong { tokens: 34, limit: 32 }));
+        assert_eq!(strict.token_count(), 24);
+    }
+}
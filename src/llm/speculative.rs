@@ -0,0 +1,256 @@
+use std::sync::Arc;
+
+use super::model::{KvCache, LlmError, ModelBackend};
+use super::sampling::{Distribution, Sampler};
+
+/// Tokens the draft model proposes per round.
+pub const DEFAULT_DRAFT_TOKENS: usize = 4;
+/// Encoded by both models when they are loaded, to catch tokenizers that
+/// differ without listing their vocabularies.
+const PROBE: &str = "The quick brown fox jumps over the lazy dog. 1234567890";
+
+/// A small model that proposes tokens for a larger one to check. The two
+/// must share a tokenizer.
+#[derive(Clone)]
+pub(crate) struct DraftModel {
+    backend: Arc<dyn ModelBackend>,
+    tokens: usize,
+}
+
+impl DraftModel {
+    pub(super) fn new(backend: Arc<dyn ModelBackend>, tokens: usize) -> Result<Self, LlmError> {
+        if tokens == 0 {
+            return Err(LlmError::InvalidParams("a draft model must propose at least one token".to_string()));
+        }
+        Ok(DraftModel { backend, tokens })
+    }
+
+    /// Fails with `DraftMismatch` unless the draft reads and writes tokens
+    /// as `target` does and covers its context window; and unless both
+    /// caches can be forked, which rounds rely on.
+    pub(super) fn check(&self, target: &dyn ModelBackend) -> Result<(), LlmError> {
+        let draft = self.backend.as_ref();
+        let mismatch = |what: &str| Err(LlmError::DraftMismatch(format!("its tokenizer has different {}", what)));
+        if (draft.bos_token(), draft.eos_token()) != (target.bos_token(), target.eos_token()) {
+            return mismatch("special tokens");
+        }
+        if draft.vocab() != target.vocab() {
+            return mismatch("tokens");
+        }
+        if draft.encode(PROBE)? != target.encode(PROBE)? {
+            return mismatch("encodings");
+        }
+        if draft.context_length() < target.context_length() {
+            return Err(LlmError::DraftMismatch(format!(
+                "its {} token context is shorter than the model's {}", draft.context_length(), target.context_length()
+            )));
+        }
+        for backend in [draft, target] {
+            backend.fork_cache(&backend.new_cache()?).map_err(|e| LlmError::DraftMismatch(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// What speculation has done over one generation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct SpeculationStats {
+    /// Forward passes of the target model, plain steps included.
+    pub(super) passes: usize,
+    pub(super) drafted: usize,
+    pub(super) accepted: usize,
+}
+
+/// A generation's draft model, with the cache it has taken in, which is
+/// lost if a round fails.
+pub(super) struct Speculation {
+    pub(super) model: DraftModel,
+    pub(super) cache: Option<KvCache>,
+    pub(super) stats: SpeculationStats,
+}
+
+impl Speculation {
+    /// Model work, so for a job on the worker.
+    pub(super) fn start(model: DraftModel) -> Result<Self, LlmError> {
+        let cache = model.backend.new_cache()?;
+        Ok(Speculation { model, cache: Some(cache), stats: SpeculationStats::default() })
+    }
+}
+
+/// The outcome of one round.
+pub(super) struct Round {
+    /// Tokens to append, each with the target's logits it was chosen by.
+    pub(super) tokens: Vec<(u32, Vec<f32>)>,
+    pub(super) cache: KvCache,
+    pub(super) draft_cache: KvCache,
+    pub(super) drafted: usize,
+    pub(super) accepted: usize,
+}
+
+/// Has the draft propose up to `lookahead` tokens after `context`, then
+/// checks them in one target pass and keeps the longest prefix the
+/// acceptance rule lets through, plus a token of the target's own: in
+/// place of the first one refused, or after them all. Tokens so chosen
+/// follow the target's distribution as plain sampling would; with a
+/// draft that agrees with the target, they are the very tokens it would
+/// sample. Both models try the proposals on forks of `cache` and
+/// `draft_cache`, kept only if every proposal is, so a refusal costs the
+/// proposals kept being fed again next round.
+pub(super) fn round(
+    target: &dyn ModelBackend,
+    draft: &DraftModel,
+    sampler: &mut Sampler,
+    context: &[u32],
+    cache: KvCache,
+    draft_cache: KvCache,
+    lookahead: usize,
+) -> Result<Round, LlmError> {
+    if context.is_empty() {
+        return Err(LlmError::InvalidParams("speculative decoding needs a prompt to condition on".to_string()));
+    }
+    let eos = target.eos_token();
+    let lookahead = lookahead.min(draft.tokens).max(1);
+    let mut drafting = draft.backend.fork_cache(&draft_cache)?;
+    let mut proposed = context.to_vec();
+    let mut input = context[drafting.len()..].to_vec();
+    let mut proposals: Vec<(u32, Distribution)> = Vec::with_capacity(lookahead);
+    while proposals.len() < lookahead {
+        let logits = draft.backend.forward(&mut drafting, &input)?;
+        drafting.advance(input.len());
+        let distribution = sampler.distribution(&logits, &proposed)?;
+        let token = sampler.draw(&distribution);
+        proposals.push((token, distribution));
+        proposed.push(token);
+        input = vec![token];
+        if Some(token) == eos {
+            break;
+        }
+    }
+
+    // The target takes in what it has not seen as plain decoding would,
+    // all at once, then scores across the proposals. Fed alike, a draft
+    // that is the target gives the very same distributions.
+    let mut checking = target.fork_cache(&cache)?;
+    let fresh = &context[checking.len()..];
+    let mut logits = vec![target.forward(&mut checking, fresh)?];
+    checking.advance(fresh.len());
+    let drafted = proposals.len();
+    let scored: Vec<u32> = proposals.iter().map(|(token, _)| *token).collect();
+    logits.extend(target.forward_all(&mut checking, &scored)?);
+    checking.advance(drafted);
+    if logits.len() != drafted + 1 {
+        return Err(LlmError::InferenceFailed(format!("expected logits at {} positions, got {}", drafted + 1, logits.len())));
+    }
+
+    let mut tokens = Vec::with_capacity(drafted + 1);
+    let mut chosen = context.to_vec();
+    for ((token, proposal), logits) in proposals.iter().zip(&logits) {
+        let distribution = sampler.distribution(logits, &chosen)?;
+        if !sampler.accept_proposal(&distribution, proposal, *token) {
+            tokens.push((sampler.draw_residual(&distribution, proposal), logits.clone()));
+            let accepted = tokens.len() - 1;
+            return Ok(Round { tokens, cache, draft_cache, drafted, accepted });
+        }
+        tokens.push((*token, logits.clone()));
+        chosen.push(*token);
+        if Some(*token) == eos {
+            return Ok(Round { tokens, cache: checking, draft_cache: drafting, drafted, accepted: drafted });
+        }
+    }
+    let last = &logits[drafted];
+    let distribution = sampler.distribution(last, &chosen)?;
+    tokens.push((sampler.draw(&distribution), last.clone()));
+    // The draft has yet to take in its last proposal; with it, both
+    // caches stop short of the same token.
+    let unfed = &proposed[drafting.len()..];
+    draft.backend.forward(&mut drafting, unfed)?;
+    drafting.advance(unfed.len());
+    Ok(Round { tokens, cache: checking, draft_cache: drafting, drafted, accepted: drafted })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{GenerateParams, LightLLM};
+
+    /// A token per letter from a to h, with logits that depend on the
+    /// whole sequence; `skew` makes a model that disagrees with the rest.
+    struct Letters {
+        skew: f32,
+        eos: Option<u32>,
+    }
+
+    impl Letters {
+        fn new(skew: f32) -> Arc<Self> {
+            Arc::new(Letters { skew, eos: None })
+        }
+    }
+
+    impl ModelBackend for Letters {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().filter(|byte| (b'a'..=b'h').contains(byte)).map(|byte| u32::from(byte - b'a')).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().map(|&token| char::from(b'a' + token as u8)).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(Vec::<u32>::new()))
+        }
+
+        fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            let seen = cache.state_mut::<Vec<u32>>().unwrap();
+            seen.extend_from_slice(tokens);
+            let phase = seen.iter().enumerate().map(|(i, &token)| (i as f32 + 1.0) * token as f32).sum::<f32>();
+            Ok((0..8).map(|i| (phase * 0.37 + i as f32 * 1.3).sin() * 2.0 + (i as f32 * self.skew).cos()).collect())
+        }
+
+        fn fork_cache(&self, cache: &KvCache) -> Result<KvCache, LlmError> {
+            cache.fork::<Vec<u32>>().ok_or_else(|| LlmError::InferenceFailed("not a Letters cache".to_string()))
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            self.eos
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[tokio::test]
+    async fn test_draft_that_agrees_changes_nothing_but_passes() {
+        let params = GenerateParams::new(17).with_temperature(1.0).with_seed(11).with_logprobs(2);
+        let plain = LightLLM::with_backend(Letters::new(0.0)).generate("abc", params.clone()).await.unwrap();
+        let model = LightLLM::with_backend(Letters::new(0.0)).with_draft(Letters::new(0.0), 4).unwrap();
+        let speculative = model.generate("abc", params).await.unwrap();
+        assert_eq!((&speculative.text, &speculative.logprobs), (&plain.text, &plain.logprobs));
+        assert_eq!(speculative.usage.draft_acceptance_rate, Some(1.0));
+        // Rounds of five, five, five and then two.
+        assert_eq!(speculative.usage.speculative_speedup, Some(17.0 / 4.0));
+        assert_eq!(plain.usage.speculative_speedup, None);
+    }
+
+    #[tokio::test]
+    async fn test_greedy_output_kept_when_the_draft_disagrees() {
+        let params = GenerateParams::new(24).with_temperature(0.0);
+        let plain = LightLLM::with_backend(Letters::new(0.0)).generate("hab", params.clone()).await.unwrap();
+        let model = LightLLM::with_backend(Letters::new(0.0)).with_draft(Letters::new(0.9), 3).unwrap();
+        let speculative = model.generate("hab", params).await.unwrap();
+        assert_eq!(speculative.text, plain.text);
+        let rate = speculative.usage.draft_acceptance_rate.unwrap();
+        assert!((0.0..1.0).contains(&rate), "{}", rate);
+    }
+
+    #[test]
+    fn test_draft_with_other_special_tokens_refused() {
+        let draft = Arc::new(Letters { skew: 0.0, eos: Some(7) });
+        assert!(matches!(LightLLM::with_backend(Letters::new(0.0)).with_draft(draft, 4), Err(LlmError::DraftMismatch(_))));
+        assert!(matches!(LightLLM::with_backend(Letters::new(0.0)).with_draft(Letters::new(0.0), 0), Err(LlmError::InvalidParams(_))));
+    }
+}
@@ -0,0 +1,220 @@
+use candle_core::safetensors::MmapedSafetensors;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+use tokenizers::Tokenizer;
+
+use super::model::LlmError;
+use super::verify;
+
+/// Startup cache directory inside `storage_path`.
+pub const STARTUP_CACHE_DIR: &str = "llm-cache";
+const HASHES_FILE: &str = "hashes.json";
+
+/// A file's size and modification time when it was hashed; while both
+/// hold, the hash is taken as still right.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Hashed {
+    len: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    sha256: String,
+}
+
+impl Hashed {
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        let modified = metadata.modified().ok().and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        modified.map_or(false, |modified| {
+            (self.len, self.modified_secs, self.modified_nanos) == (metadata.len(), modified.as_secs(), modified.subsec_nanos())
+        })
+    }
+}
+
+/// The tensors a safetensors file holds, with their shapes, once it has
+/// been read and checked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct TensorIndex {
+    pub(super) shapes: BTreeMap<String, Vec<usize>>,
+    pub(super) parameters: u64,
+}
+
+impl TensorIndex {
+    pub(super) fn read(tensors: &MmapedSafetensors) -> Self {
+        let shapes: BTreeMap<String, Vec<usize>> =
+            tensors.tensors().into_iter().map(|(name, view)| (name, view.shape().to_vec())).collect();
+        let parameters = shapes.values().map(|shape| shape.iter().product::<usize>() as u64).sum();
+        TensorIndex { shapes, parameters }
+    }
+}
+
+/// What a restart would otherwise work out again from unchanged model
+/// files: their hashes, the tokenizer in compact form and a safetensors
+/// model's tensor index. Entries are keyed by the source file's SHA-256,
+/// so a changed file misses and its old entries are removed. A file's
+/// hash is itself reused while its size and modification time are
+/// unchanged. Failing to write the cache only costs the next start its
+/// speed.
+pub struct StartupCache {
+    dir: PathBuf,
+    hashes: Mutex<BTreeMap<PathBuf, Hashed>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl StartupCache {
+    /// The cache in `dir`, created if missing.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let hashes = match fs::read(dir.join(HASHES_FILE)) {
+            Ok(hashes) => serde_json::from_slice(&hashes).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable {}: {}", dir.join(HASHES_FILE).display(), e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(StartupCache { dir, hashes: Mutex::new(hashes), hits: AtomicU64::new(0), misses: AtomicU64::new(0) })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Lookups answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that had to read the source file.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `path`'s SHA-256, hashed again only if the file changed since.
+    pub(super) fn sha256(&self, path: &Path, progress: &mut dyn FnMut(&Path, u64, u64)) -> Result<String, LlmError> {
+        let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let metadata = fs::metadata(path).map_err(|e| LlmError::ModelLoad { path: path.to_path_buf(), reason: e.to_string() })?;
+        if let Some(hashed) = self.hashes.lock().get(&key).filter(|hashed| hashed.matches(&metadata)) {
+            self.record(true);
+            return Ok(hashed.sha256.clone());
+        }
+        self.record(false);
+        let sha256 = verify::sha256_file(path, progress)?;
+        // Stamped with the metadata from before hashing, so a write during
+        // it is seen next time.
+        let modified = metadata.modified().ok().and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        let hashed = Hashed {
+            len: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            sha256: sha256.clone(),
+        };
+        let mut hashes = self.hashes.lock();
+        if let Some(stale) = hashes.insert(key, hashed).filter(|stale| stale.sha256 != sha256) {
+            self.remove_entries(&stale.sha256);
+        }
+        self.write(HASHES_FILE, &serde_json::to_vec_pretty(&*hashes).unwrap_or_default());
+        Ok(sha256)
+    }
+
+    /// The tokenizer at `path`, from its compact form if it was parsed
+    /// before.
+    pub(super) fn tokenizer(&self, path: &Path) -> Result<Tokenizer, LlmError> {
+        let load_error = |reason: String| LlmError::TokenizerLoad { path: path.to_path_buf(), reason };
+        let entry = format!("tokenizer-{}.json", self.sha256(path, &mut |_, _, _| {})?);
+        if let Ok(compact) = fs::read_to_string(self.dir.join(&entry)) {
+            match Tokenizer::from_str(&compact) {
+                Ok(tokenizer) => {
+                    self.record(true);
+                    return Ok(tokenizer);
+                }
+                Err(e) => warn!("Ignoring unreadable cached tokenizer {}: {}", entry, e),
+            }
+        }
+        self.record(false);
+        let tokenizer = Tokenizer::from_file(path).map_err(|e| load_error(e.to_string()))?;
+        match tokenizer.to_string(false) {
+            Ok(compact) => self.write(&entry, compact.as_bytes()),
+            Err(e) => debug!("Not caching tokenizer {}: {}", path.display(), e),
+        }
+        Ok(tokenizer)
+    }
+
+    /// The index of the safetensors model at `path`, read from `tensors`
+    /// if it was not cached.
+    pub(super) fn tensor_index(&self, path: &Path, tensors: &MmapedSafetensors) -> Result<TensorIndex, LlmError> {
+        let entry = format!("tensors-{}.json", self.sha256(path, &mut |_, _, _| {})?);
+        if let Some(index) = fs::read(self.dir.join(&entry)).ok().and_then(|index| serde_json::from_slice(&index).ok()) {
+            self.record(true);
+            return Ok(index);
+        }
+        self.record(false);
+        let index = TensorIndex::read(tensors);
+        self.write(&entry, &serde_json::to_vec(&index).unwrap_or_default());
+        Ok(index)
+    }
+
+    fn remove_entries(&self, sha256: &str) {
+        for entry in [format!("tokenizer-{}.json", sha256), format!("tensors-{}.json", sha256)] {
+            let _ = fs::remove_file(self.dir.join(entry));
+        }
+    }
+
+    /// Written beside the entry and renamed over it, so a crash never
+    /// leaves half an entry.
+    fn write(&self, entry: &str, contents: &[u8]) {
+        let (path, partial) = (self.dir.join(entry), self.dir.join(format!("{}.partial", entry)));
+        if let Err(e) = fs::write(&partial, contents).and_then(|_| fs::rename(&partial, &path)) {
+            warn!("Cannot write LLM startup cache entry {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    #[test]
+    fn test_changed_tokenizer_misses_and_drops_its_old_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+        let save = |words: &[&str]| {
+            let vocab: HashMap<String, u32> = words.iter().enumerate().map(|(id, word)| (word.to_string(), id as u32)).collect();
+            let tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("<unk>".to_string()).build().unwrap());
+            tokenizer.save(&path, true).unwrap();
+        };
+        save(&["<unk>", "a"]);
+        let cache = StartupCache::open(dir.path().join(STARTUP_CACHE_DIR)).unwrap();
+        let first = cache.tokenizer(&path).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        let entries = || fs::read_dir(cache.dir()).unwrap().filter(|entry| {
+            entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("tokenizer-")
+        }).count();
+        assert_eq!(entries(), 1);
+
+        // A new process finds both the hash and the compact tokenizer.
+        let reopened = StartupCache::open(cache.dir()).unwrap();
+        assert_eq!(reopened.tokenizer(&path).unwrap().get_vocab(true), first.get_vocab(true));
+        assert_eq!((reopened.hits(), reopened.misses()), (2, 0));
+
+        save(&["<unk>", "a", "b"]);
+        assert_eq!(reopened.tokenizer(&path).unwrap().get_vocab_size(true), 3);
+        assert_eq!((reopened.hits(), reopened.misses()), (2, 2));
+        assert_eq!(entries(), 1);
+    }
+}
@@ -0,0 +1,673 @@
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::model::{LlmError, ModelBackend};
+
+/// Keywords that restrict values further than the grammar can follow.
+const UNSUPPORTED: &[&str] = &[
+    "$ref", "allOf", "anyOf", "oneOf", "not", "if", "pattern", "patternProperties", "minLength", "maxLength",
+    "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum", "multipleOf", "uniqueItems", "contains",
+    "minProperties", "maxProperties", "dependentRequired",
+];
+
+/// The JSON a completion must be, for `GenerateParams.response_format`:
+/// objects with their properties and required fields, arrays of items
+/// with `minItems` and `maxItems`, strings, numbers, integers, booleans,
+/// null, and `enum` or `const` values. Annotations such as `description`
+/// are ignored; keywords output could not be held to are refused. Objects
+/// only ever hold the properties listed, in any order.
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    source: Value,
+    root: Arc<Node>,
+}
+
+impl JsonSchema {
+    /// Fails with `InvalidParams` naming where `source` is malformed or
+    /// uses what is not supported.
+    pub fn new(source: Value) -> Result<Self, LlmError> {
+        let root = Arc::new(compile(&source, "#")?);
+        Ok(JsonSchema { source, root })
+    }
+
+    pub fn source(&self) -> &Value {
+        &self.source
+    }
+}
+
+impl FromStr for JsonSchema {
+    type Err = LlmError;
+
+    fn from_str(source: &str) -> Result<Self, LlmError> {
+        let source = serde_json::from_str(source)
+            .map_err(|e| LlmError::InvalidParams(format!("response_format is not JSON: {}", e)))?;
+        JsonSchema::new(source)
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Object(Arc<[Property]>),
+    Array { items: Arc<Node>, min_items: usize, max_items: Option<usize> },
+    String,
+    Number { integer: bool },
+    /// Exactly one of these JSON texts.
+    Literals(Arc<[String]>),
+}
+
+#[derive(Debug)]
+struct Property {
+    /// The name as it is written between quotes, escapes and all.
+    key: String,
+    value: Arc<Node>,
+    required: bool,
+}
+
+fn compile(schema: &Value, path: &str) -> Result<Node, LlmError> {
+    let invalid = |reason: &str| LlmError::InvalidParams(format!("response_format at {}: {}", path, reason));
+    let schema = schema.as_object().ok_or_else(|| invalid("a schema must be an object"))?;
+    if let Some(keyword) = UNSUPPORTED.iter().find(|keyword| schema.contains_key(**keyword)) {
+        return Err(invalid(&format!("{} is not supported", keyword)));
+    }
+    if let Some(values) = schema.get("enum") {
+        let values = values.as_array().filter(|values| !values.is_empty())
+            .ok_or_else(|| invalid("enum must be a non-empty array"))?;
+        return Ok(Node::Literals(values.iter().map(Value::to_string).collect()));
+    }
+    if let Some(value) = schema.get("const") {
+        return Ok(Node::Literals(vec![value.to_string()].into()));
+    }
+    let kind = match schema.get("type") {
+        Some(Value::String(kind)) => kind.as_str(),
+        Some(_) => return Err(invalid("type must name a single type")),
+        None => return Err(invalid("type, enum or const is required")),
+    };
+    match kind {
+        "object" => {
+            let empty = serde_json::Map::new();
+            let properties = match schema.get("properties") {
+                None => &empty,
+                Some(Value::Object(properties)) => properties,
+                Some(_) => return Err(invalid("properties must be an object")),
+            };
+            let required: Vec<&str> = match schema.get("required") {
+                None => Vec::new(),
+                Some(Value::Array(names)) => names.iter()
+                    .map(|name| name.as_str().ok_or_else(|| invalid("required must list property names")))
+                    .collect::<Result<_, _>>()?,
+                Some(_) => return Err(invalid("required must be an array")),
+            };
+            if let Some(unknown) = required.iter().find(|name| !properties.contains_key(**name)) {
+                return Err(invalid(&format!("required property {} is not in properties", unknown)));
+            }
+            let properties = properties.iter()
+                .map(|(name, schema)| {
+                    let quoted = Value::String(name.clone()).to_string();
+                    Ok(Property {
+                        key: quoted[1..quoted.len() - 1].to_string(),
+                        value: Arc::new(compile(schema, &format!("{}/properties/{}", path, name))?),
+                        required: required.contains(&name.as_str()),
+                    })
+                })
+                .collect::<Result<Vec<_>, LlmError>>()?;
+            Ok(Node::Object(properties.into()))
+        }
+        "array" => {
+            let items = schema.get("items").ok_or_else(|| invalid("an array needs items"))?;
+            let count = |keyword: &str| match schema.get(keyword) {
+                None => Ok(None),
+                Some(value) => value.as_u64().map(|count| Some(count as usize))
+                    .ok_or_else(|| invalid(&format!("{} must be a non-negative integer", keyword))),
+            };
+            let (min_items, max_items) = (count("minItems")?.unwrap_or(0), count("maxItems")?);
+            if max_items.map_or(false, |max_items| max_items < min_items) {
+                return Err(invalid("maxItems is less than minItems"));
+            }
+            let items = Arc::new(compile(items, &format!("{}/items", path))?);
+            Ok(Node::Array { items, min_items, max_items })
+        }
+        "string" => Ok(Node::String),
+        "number" => Ok(Node::Number { integer: false }),
+        "integer" => Ok(Node::Number { integer: true }),
+        "boolean" => Ok(Node::Literals(vec!["true".to_string(), "false".to_string()].into())),
+        "null" => Ok(Node::Literals(vec!["null".to_string()].into())),
+        other => Err(invalid(&format!("unknown type {}", other))),
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+#[derive(Debug, Clone)]
+enum ObjectState {
+    /// After `{`.
+    Open,
+    /// After a `,`, so a key must follow.
+    Comma,
+    /// Inside a key, with what was typed of it.
+    Key(String),
+    /// After the key of this property.
+    Colon(usize),
+    /// While a property's value is matched.
+    Member,
+    /// After a property's value.
+    Next,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArrayState {
+    Open,
+    Comma,
+    Item,
+    Next,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    Backslash,
+    /// Hex digits of a `\u` escape still to come.
+    Unicode(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumberState {
+    Start,
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+impl NumberState {
+    fn next(self, c: char, integer: bool) -> Option<Self> {
+        use NumberState::*;
+        let digit = c.is_ascii_digit();
+        match (self, c) {
+            (Start, '-') => Some(Minus),
+            (Start | Minus, '0') => Some(Zero),
+            (Start | Minus, _) if digit => Some(Int),
+            (Int, _) if digit => Some(Int),
+            (Zero | Int, '.') if !integer => Some(Dot),
+            (Dot | Frac, _) if digit => Some(Frac),
+            (Zero | Int | Frac, 'e' | 'E') if !integer => Some(Exp),
+            (Exp, '+' | '-') => Some(ExpSign),
+            (Exp | ExpSign | ExpDigits, _) if digit => Some(ExpDigits),
+            _ => None,
+        }
+    }
+
+    fn complete(self) -> bool {
+        matches!(self, NumberState::Zero | NumberState::Int | NumberState::Frac | NumberState::ExpDigits)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    /// A value of the node is next, after any whitespace.
+    Value(Arc<Node>),
+    Object { properties: Arc<[Property]>, seen: Vec<bool>, state: ObjectState },
+    Array { items: Arc<Node>, min_items: usize, max_items: Option<usize>, count: usize, state: ArrayState },
+    String(Escape),
+    Literal { options: Arc<[String]>, typed: String },
+    Number { integer: bool, state: NumberState },
+}
+
+/// What a frame made of a character.
+enum Fed {
+    Consumed,
+    Rejected,
+    /// Consumed, closing the frame's value.
+    Closed,
+    /// The frame's value ended before the character, which belongs to what
+    /// follows.
+    Ended,
+    /// The frame is now this one; the character is fed to it unless
+    /// consumed.
+    Replace(Frame, bool),
+    /// This frame goes on top; the character is fed to it unless consumed.
+    Push(Frame, bool),
+}
+
+/// The frame for a value of `node` that `c` begins, and whether `c` is
+/// taken in opening it.
+fn opening(node: &Node, c: char) -> Option<(Frame, bool)> {
+    match node {
+        Node::Object(properties) if c == '{' => {
+            let seen = vec![false; properties.len()];
+            Some((Frame::Object { properties: Arc::clone(properties), seen, state: ObjectState::Open }, true))
+        }
+        Node::Array { items, min_items, max_items } if c == '[' => {
+            let (min_items, max_items) = (*min_items, *max_items);
+            Some((Frame::Array { items: Arc::clone(items), min_items, max_items, count: 0, state: ArrayState::Open }, true))
+        }
+        Node::String if c == '"' => Some((Frame::String(Escape::None), true)),
+        Node::Number { integer } => Some((Frame::Number { integer: *integer, state: NumberState::Start }, false)),
+        Node::Literals(options) => Some((Frame::Literal { options: Arc::clone(options), typed: String::new() }, false)),
+        _ => None,
+    }
+}
+
+impl Frame {
+    fn feed(&mut self, c: char) -> Fed {
+        match self {
+            Frame::Value(_) if is_whitespace(c) => Fed::Consumed,
+            Frame::Value(node) => match opening(node, c) {
+                Some((frame, consumed)) => Fed::Replace(frame, consumed),
+                None => Fed::Rejected,
+            },
+            Frame::Object { properties, seen, state } => {
+                let unseen = || properties.iter().zip(seen.iter()).filter(|(_, seen)| !**seen).map(|(property, _)| property);
+                match state {
+                    ObjectState::Open | ObjectState::Comma | ObjectState::Colon(_) | ObjectState::Next if is_whitespace(c) => Fed::Consumed,
+                    ObjectState::Open | ObjectState::Comma if c == '"' && unseen().next().is_some() => {
+                        *state = ObjectState::Key(String::new());
+                        Fed::Consumed
+                    }
+                    ObjectState::Open | ObjectState::Next if c == '}' && unseen().all(|property| !property.required) => Fed::Closed,
+                    ObjectState::Next if c == ',' && unseen().next().is_some() => {
+                        *state = ObjectState::Comma;
+                        Fed::Consumed
+                    }
+                    ObjectState::Key(typed) => {
+                        if c == '"' {
+                            let matched = (0..properties.len()).find(|&i| !seen[i] && properties[i].key == *typed);
+                            if let Some(i) = matched {
+                                seen[i] = true;
+                                *state = ObjectState::Colon(i);
+                                return Fed::Consumed;
+                            }
+                        }
+                        typed.push(c);
+                        if unseen().any(|property| property.key.starts_with(typed.as_str())) {
+                            Fed::Consumed
+                        } else {
+                            typed.pop();
+                            Fed::Rejected
+                        }
+                    }
+                    ObjectState::Colon(i) if c == ':' => {
+                        let value = Arc::clone(&properties[*i].value);
+                        *state = ObjectState::Member;
+                        Fed::Push(Frame::Value(value), true)
+                    }
+                    _ => Fed::Rejected,
+                }
+            }
+            Frame::Array { items, min_items, max_items, count, state } => {
+                let room = max_items.map_or(true, |max_items| *count < max_items);
+                match state {
+                    ArrayState::Open | ArrayState::Comma | ArrayState::Next if is_whitespace(c) => Fed::Consumed,
+                    ArrayState::Open | ArrayState::Next if c == ']' && *count >= *min_items => Fed::Closed,
+                    ArrayState::Next if c == ',' && room => {
+                        *state = ArrayState::Comma;
+                        Fed::Consumed
+                    }
+                    ArrayState::Open | ArrayState::Comma if room => match opening(items, c) {
+                        Some((frame, consumed)) => {
+                            *state = ArrayState::Item;
+                            Fed::Push(frame, consumed)
+                        }
+                        None => Fed::Rejected,
+                    },
+                    _ => Fed::Rejected,
+                }
+            }
+            Frame::String(escape) => match (*escape, c) {
+                (Escape::None, '"') => Fed::Closed,
+                (Escape::None, '\\') => {
+                    *escape = Escape::Backslash;
+                    Fed::Consumed
+                }
+                (Escape::None, c) if c < ' ' => Fed::Rejected,
+                (Escape::None, _) => Fed::Consumed,
+                (Escape::Backslash, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't') => {
+                    *escape = Escape::None;
+                    Fed::Consumed
+                }
+                (Escape::Backslash, 'u') => {
+                    *escape = Escape::Unicode(4);
+                    Fed::Consumed
+                }
+                (Escape::Unicode(left), c) if c.is_ascii_hexdigit() => {
+                    *escape = if left == 1 { Escape::None } else { Escape::Unicode(left - 1) };
+                    Fed::Consumed
+                }
+                _ => Fed::Rejected,
+            },
+            Frame::Literal { options, typed } => {
+                typed.push(c);
+                if options.iter().any(|option| option.starts_with(typed.as_str())) {
+                    let longer = options.iter().any(|option| option.len() > typed.len() && option.starts_with(typed.as_str()));
+                    return if !longer && options.iter().any(|option| *option == *typed) { Fed::Closed } else { Fed::Consumed };
+                }
+                typed.pop();
+                if options.iter().any(|option| *option == *typed) { Fed::Ended } else { Fed::Rejected }
+            }
+            Frame::Number { integer, state } => match state.next(c, *integer) {
+                Some(next) => {
+                    *state = next;
+                    Fed::Consumed
+                }
+                None if state.complete() => Fed::Ended,
+                None => Fed::Rejected,
+            },
+        }
+    }
+
+    /// Whether the frame holds a whole value, though more could extend it.
+    fn complete(&self) -> bool {
+        match self {
+            Frame::Literal { options, typed } => options.iter().any(|option| option == typed),
+            Frame::Number { state, .. } => state.complete(),
+            _ => false,
+        }
+    }
+}
+
+/// Follows text character by character through the schema's grammar.
+#[derive(Debug, Clone)]
+struct Matcher {
+    stack: Vec<Frame>,
+}
+
+impl Matcher {
+    fn new(root: Arc<Node>) -> Self {
+        Matcher { stack: vec![Frame::Value(root)] }
+    }
+
+    /// Whether the value is closed, so nothing more may follow.
+    fn done(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// Whether the text so far is a whole value.
+    fn complete(&self) -> bool {
+        match self.stack.as_slice() {
+            [] => true,
+            [frame] => frame.complete(),
+            _ => false,
+        }
+    }
+
+    fn feed(&mut self, c: char) -> bool {
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                return false;
+            };
+            match frame.feed(c) {
+                Fed::Consumed => return true,
+                Fed::Rejected => return false,
+                Fed::Closed => {
+                    self.close();
+                    return true;
+                }
+                Fed::Ended => self.close(),
+                Fed::Replace(frame, consumed) => {
+                    *self.stack.last_mut().expect("a frame was fed") = frame;
+                    if consumed {
+                        return true;
+                    }
+                }
+                Fed::Push(frame, consumed) => {
+                    self.stack.push(frame);
+                    if consumed {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pops the frame whose value ended, moving its container on.
+    fn close(&mut self) {
+        self.stack.pop();
+        match self.stack.last_mut() {
+            Some(Frame::Object { state, .. }) => *state = ObjectState::Next,
+            Some(Frame::Array { count, state, .. }) => {
+                *count += 1;
+                *state = ArrayState::Next;
+            }
+            _ => {}
+        }
+    }
+
+    fn accepts(&self, text: &str) -> bool {
+        if text.is_empty() || text.contains(char::REPLACEMENT_CHARACTER) {
+            return false;
+        }
+        let mut matcher = self.clone();
+        text.chars().all(|c| matcher.feed(c))
+    }
+}
+
+/// A generation held to a `JsonSchema`: the grammar state after the
+/// completion so far, and the text each token adds. Each step leaves out
+/// the tokens that cannot continue the value, and the end of text until
+/// it is whole; tokens that add part of a character are always left out.
+pub(super) struct Constraint {
+    matcher: Matcher,
+    /// Bytes of the completion matched.
+    matched: usize,
+    /// By token id.
+    texts: Vec<String>,
+}
+
+impl Constraint {
+    /// Decodes the whole vocabulary, so model work. Each token's text is
+    /// what it adds after another, so leading spaces a tokenizer strips
+    /// from a sequence's start are kept.
+    pub(super) fn new(schema: &JsonSchema, backend: &dyn ModelBackend) -> Result<Self, LlmError> {
+        let size = backend.vocab().into_values().max().map_or(0, |last| last as usize + 1);
+        if size == 0 {
+            return Err(LlmError::InvalidParams("response_format needs a model whose tokenizer lists its vocabulary".to_string()));
+        }
+        let anchor = backend.encode("0")?.last().copied();
+        let anchor_text = anchor.map(|anchor| backend.decode(&[anchor])).transpose()?;
+        let texts = (0..size as u32)
+            .map(|token| {
+                if let (Some(anchor), Some(anchor_text)) = (anchor, &anchor_text) {
+                    if let Some(text) = backend.decode(&[anchor, token])?.strip_prefix(anchor_text.as_str()) {
+                        return Ok(text.to_string());
+                    }
+                }
+                backend.decode(&[token])
+            })
+            .collect::<Result<_, LlmError>>()?;
+        Ok(Constraint { matcher: Matcher::new(Arc::clone(&schema.root)), matched: 0, texts })
+    }
+
+    /// Bytes of the completion that follow the schema.
+    pub(super) fn position(&self) -> usize {
+        self.matched
+    }
+
+    /// Whether the completion so far is a whole value of the schema.
+    pub(super) fn complete(&self) -> bool {
+        self.matcher.complete()
+    }
+
+    /// Matches `completion` beyond what was matched before, leaving an
+    /// incomplete character at its end for later. Returns whether the
+    /// value is closed, or why the text cannot follow the schema.
+    pub(super) fn advance(&mut self, completion: &str) -> Result<bool, String> {
+        let end = completion.trim_end_matches(char::REPLACEMENT_CHARACTER).len();
+        let fresh = completion.get(self.matched..end).ok_or_else(|| "the completion decoded differently".to_string())?;
+        for c in fresh.chars() {
+            if !self.matcher.feed(c) {
+                return Err(format!("{:?} does not fit the schema", c));
+            }
+            self.matched += c.len_utf8();
+        }
+        Ok(self.matcher.done())
+    }
+
+    /// `logits` with every token that cannot come next set to negative
+    /// infinity.
+    pub(super) fn mask(&self, logits: &[f32], eos: Option<u32>) -> Vec<f32> {
+        let complete = self.complete();
+        logits.iter()
+            .enumerate()
+            .map(|(token, &logit)| {
+                let allowed = if Some(token as u32) == eos {
+                    complete
+                } else {
+                    self.texts.get(token).map_or(false, |text| self.matcher.accepts(text))
+                };
+                if allowed { logit } else { f32::NEG_INFINITY }
+            })
+            .collect()
+    }
+}
+
+/// The byte of `text` at `line` and `column`, both counted from one, as
+/// `serde_json` reports them.
+pub(super) fn offset(text: &str, line: usize, column: usize) -> usize {
+    let start: usize = text.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+    (start + column.saturating_sub(1)).min(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{GenerateParams, KvCache, LightLLM};
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    const VOCAB: [&str; 16] = ["<eos>", "x", "{", "}", "\"", "name", "age", "\"name\"", ":", " ", ",", "1", "2", "Bob", "[", "]"];
+    /// Fixed preferences, the invalid `x` first; the last token is
+    /// penalized so nothing repeats forever.
+    const PREFERENCE: [f32; 16] = [0.2, 10.0, 2.0, 1.0, 5.0, 0.1, 1.5, 8.0, 6.0, 0.0, 4.0, 0.5, 3.0, 0.3, 0.4, 0.4];
+
+    struct Tokens;
+
+    impl ModelBackend for Tokens {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.chars().map(|c| VOCAB.iter().position(|token| *token == c.to_string()).unwrap_or(1) as u32).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok(tokens.iter().filter(|&&token| token != 0).map(|&token| VOCAB[token as usize]).collect())
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(Vec::<u32>::new()))
+        }
+
+        fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            let seen = cache.state_mut::<Vec<u32>>().unwrap();
+            seen.extend_from_slice(tokens);
+            let mut logits = PREFERENCE.to_vec();
+            if let Some(&last) = seen.last() {
+                logits[last as usize] -= 20.0;
+            }
+            Ok(logits)
+        }
+
+        fn vocab(&self) -> HashMap<String, u32> {
+            VOCAB.iter().enumerate().map(|(id, token)| (token.to_string(), id as u32)).collect()
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    fn person() -> JsonSchema {
+        JsonSchema::new(json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer", "description": "In years" } },
+            "required": ["name", "age"],
+        }))
+        .unwrap()
+    }
+
+    fn allowed(constraint: &Constraint) -> Vec<&'static str> {
+        let logits = constraint.mask(&[0.0; VOCAB.len()], Some(0));
+        VOCAB.iter().zip(logits).filter(|(_, logit)| logit.is_finite()).map(|(token, _)| *token).collect()
+    }
+
+    #[test]
+    fn test_mask_permits_only_valid_continuations() {
+        let mut constraint = Constraint::new(&person(), &Tokens).unwrap();
+        assert_eq!(allowed(&constraint), ["{", " "]);
+        assert_eq!(constraint.advance("{\"name\":"), Ok(false));
+        assert_eq!(allowed(&constraint), ["\"", "\"name\"", " "]);
+        // Every property is in, so the object may only end, and only then.
+        assert_eq!(constraint.advance("{\"name\":\"Bob\",\"age\":1"), Ok(false));
+        assert_eq!(allowed(&constraint), ["}", " ", "1", "2"]);
+        assert_eq!(constraint.advance("{\"name\":\"Bob\",\"age\":1}"), Ok(true));
+        assert_eq!(allowed(&constraint), ["<eos>"]);
+
+        let mut arrays = Constraint::new(&JsonSchema::from_str(r#"{"type": "array", "items": {"enum": [1, 12]}, "maxItems": 2}"#).unwrap(), &Tokens).unwrap();
+        assert_eq!(arrays.advance("[1"), Ok(false));
+        assert_eq!(allowed(&arrays), [" ", ",", "2", "]"]);
+        assert_eq!(arrays.advance("[1,12"), Ok(false));
+        assert_eq!(allowed(&arrays), [" ", "]"]);
+        assert!(arrays.advance("[1,12,").is_err());
+        assert_eq!(arrays.position(), "[1,12".len());
+    }
+
+    #[tokio::test]
+    async fn test_generation_follows_the_schema() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Person {
+            name: String,
+            age: u32,
+        }
+
+        let model = LightLLM::with_backend(Arc::new(Tokens));
+        let params = GenerateParams::new(20).with_temperature(0.0);
+        let free = model.generate("Bob", params.clone()).await.unwrap();
+        assert!(serde_json::from_str::<Value>(&free.text).is_err(), "{}", free.text);
+
+        let params = params.with_response_format(person());
+        let completion = model.generate("Bob", params.clone()).await.unwrap();
+        assert_eq!(completion.text, r#"{"name":"name","age":2}"#);
+        let person: Person = model.generate_json("Bob", params.clone()).await.unwrap();
+        assert_eq!(person, Person { name: "name".to_string(), age: 2 });
+
+        // Out of tokens before the object closes.
+        let mut short = params.clone();
+        short.max_tokens = 3;
+        assert!(matches!(model.generate("Bob", short).await, Err(LlmError::ResponseFormat { position: 8, .. })));
+        assert!(matches!(model.generate_json::<Person>("Bob", GenerateParams::new(4)).await, Err(LlmError::InvalidParams(_))));
+        assert!(matches!(model.generate("Bob", params.with_stop("}")).await, Err(LlmError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn test_malformed_schemas_refused() {
+        let refused = [
+            json!("string"),
+            json!({ "type": "strin" }),
+            json!({ "type": ["string", "null"] }),
+            json!({ "properties": {} }),
+            json!({ "type": "object", "properties": { "a": { "type": "string" } }, "required": ["b"] }),
+            json!({ "type": "object", "properties": { "a": { "type": "strnig" } } }),
+            json!({ "type": "array" }),
+            json!({ "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 2 }),
+            json!({ "type": "string", "pattern": "^a+$" }),
+            json!({ "anyOf": [{ "type": "string" }] }),
+            json!({ "enum": [] }),
+        ];
+        for schema in refused {
+            assert!(matches!(JsonSchema::new(schema.clone()), Err(LlmError::InvalidParams(_))), "{}", schema);
+        }
+        assert!(matches!("{".parse::<JsonSchema>(), Err(LlmError::InvalidParams(_))));
+    }
+}
@@ -0,0 +1,1264 @@
+use async_trait::async_trait;
+use candle_core::backprop::GradStore;
+use candle_core::{DType, Device, Tensor, Var};
+use candle_nn::{Optimizer as _, ParamsAdamW, VarMap, SGD};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use super::checkpoint::{self, CheckpointConfig, Snapshot};
+use super::compress::{self, Packed, DEFAULT_BUCKET_SIZE};
+use super::dataset::{Dataset, DatasetCursor};
+use super::lora::{LoraAdapter, LoraConfig};
+use super::model::LlmError;
+use crate::node::network::{GradientCompression, GradientMessage, NetMessage, Network, PeerId};
+
+/// How long a step waits for its peers' gradients.
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_LEARNING_RATE: f64 = 1e-3;
+
+/// A model whose parameters are candle `Var`s, so its loss can be
+/// differentiated with respect to them.
+pub trait TrainableModel: Send + Sync {
+    /// Every parameter the optimizer updates.
+    fn vars(&self) -> &VarMap;
+
+    /// The mean loss over `batch`, as a scalar still attached to the graph.
+    fn loss(&self, batch: &[String]) -> candle_core::Result<Tensor>;
+
+    /// The mean loss over sequences already tokenized, as a `Dataset`
+    /// packs them; for models trained with `DistributedTrainer::train_on`.
+    fn token_loss(&self, _batch: &[Vec<u32>]) -> candle_core::Result<Tensor> {
+        candle_core::bail!("this model trains on text, not tokens")
+    }
+
+    /// Seeds whatever randomness the next `loss` uses, such as dropout.
+    /// Called before every step, with a seed drawn from the step.
+    fn reseed(&self, _seed: u64) {}
+
+    /// The config of the `LoraLinear` adapters the vars are, for a model
+    /// that trains adapters alone.
+    fn lora_config(&self) -> Option<&LoraConfig> {
+        None
+    }
+}
+
+/// Carries gradient messages between trainers.
+#[async_trait]
+pub trait GradientTransport: Send + Sync {
+    async fn send(&self, peer: &str, message: GradientMessage) -> Result<(), String>;
+
+    /// The next message from any peer; `None` once no more can arrive.
+    async fn recv(&self) -> Option<GradientMessage>;
+}
+
+/// Gradients over the node's peer connections. Peers are named by the
+/// address they are connected under, so trainers should be named by their
+/// listen addresses.
+pub struct NetworkGradients {
+    network: Arc<Network>,
+    inbox: AsyncMutex<broadcast::Receiver<(PeerId, GradientMessage)>>,
+}
+
+impl NetworkGradients {
+    pub fn new(network: Arc<Network>) -> Self {
+        let inbox = AsyncMutex::new(network.subscribe_gradients());
+        NetworkGradients { network, inbox }
+    }
+}
+
+#[async_trait]
+impl GradientTransport for NetworkGradients {
+    async fn send(&self, peer: &str, message: GradientMessage) -> Result<(), String> {
+        let addr: SocketAddr = peer.parse().map_err(|e| format!("bad peer address {}: {}", peer, e))?;
+        self.network.send(&addr, NetMessage::Gradients(message)).await.map_err(|e| e.to_string())
+    }
+
+    async fn recv(&self) -> Option<GradientMessage> {
+        let mut inbox = self.inbox.lock().await;
+        loop {
+            match inbox.recv().await {
+                Ok((_, message)) => return Some(message),
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Missed {} gradient messages", missed),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizerKind {
+    #[default]
+    Sgd,
+    AdamW,
+}
+
+enum Optimizer {
+    Sgd(SGD),
+    AdamW(AdamW),
+}
+
+impl Optimizer {
+    fn new(kind: OptimizerKind, vars: &[(String, Var)], learning_rate: f64) -> candle_core::Result<Self> {
+        match kind {
+            OptimizerKind::Sgd => SGD::new(vars.iter().map(|(_, var)| var.clone()).collect(), learning_rate).map(Optimizer::Sgd),
+            OptimizerKind::AdamW => AdamW::new(vars, learning_rate).map(Optimizer::AdamW),
+        }
+    }
+
+    fn step(&mut self, grads: &GradStore) -> candle_core::Result<()> {
+        match self {
+            Optimizer::Sgd(optimizer) => optimizer.step(grads),
+            Optimizer::AdamW(optimizer) => optimizer.step(grads),
+        }
+    }
+
+    /// What a checkpoint must keep to carry on; nothing, for SGD.
+    fn state(&self) -> HashMap<String, Tensor> {
+        match self {
+            Optimizer::Sgd(_) => HashMap::new(),
+            Optimizer::AdamW(optimizer) => optimizer.state(),
+        }
+    }
+
+    /// Picks up after `updates` steps from a checkpoint's `state`.
+    fn restore(
+        kind: OptimizerKind,
+        vars: &[(String, Var)],
+        learning_rate: f64,
+        updates: u64,
+        state: &HashMap<String, Tensor>,
+    ) -> candle_core::Result<Self> {
+        let mut optimizer = Optimizer::new(kind, vars, learning_rate)?;
+        if let Optimizer::AdamW(adam) = &mut optimizer {
+            adam.restore(updates, state)?;
+        }
+        Ok(optimizer)
+    }
+}
+
+/// AdamW as candle computes it, with its moments where a checkpoint can
+/// get at them.
+struct AdamW {
+    params: ParamsAdamW,
+    updates: u64,
+    moments: Vec<Moments>,
+}
+
+struct Moments {
+    name: String,
+    var: Var,
+    first: Tensor,
+    second: Tensor,
+}
+
+impl AdamW {
+    fn new(vars: &[(String, Var)], learning_rate: f64) -> candle_core::Result<Self> {
+        let moments = vars.iter()
+            .map(|(name, var)| {
+                Ok(Moments { name: name.clone(), var: var.clone(), first: var.zeros_like()?, second: var.zeros_like()? })
+            })
+            .collect::<candle_core::Result<_>>()?;
+        Ok(AdamW { params: ParamsAdamW { lr: learning_rate, ..ParamsAdamW::default() }, updates: 0, moments })
+    }
+
+    fn step(&mut self, grads: &GradStore) -> candle_core::Result<()> {
+        self.updates += 1;
+        let ParamsAdamW { lr, beta1, beta2, eps, weight_decay } = self.params;
+        let first_scale = 1.0 / (1.0 - beta1.powi(self.updates as i32));
+        let second_scale = 1.0 / (1.0 - beta2.powi(self.updates as i32));
+        for moments in &mut self.moments {
+            let Some(grad) = grads.get(moments.var.as_tensor()) else { continue };
+            moments.first = ((&moments.first * beta1)? + (grad * (1.0 - beta1))?)?;
+            moments.second = ((&moments.second * beta2)? + (grad.sqr()? * (1.0 - beta2))?)?;
+            let first = (&moments.first * first_scale)?;
+            let second = (&moments.second * second_scale)?;
+            let decayed = (moments.var.as_tensor() * (1.0 - lr * weight_decay))?;
+            let adjustment = ((first / (second.sqrt()? + eps)?)? * lr)?;
+            moments.var.set(&(decayed - adjustment)?)?;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> HashMap<String, Tensor> {
+        self.moments.iter()
+            .flat_map(|moments| {
+                [
+                    (format!("first.{}", moments.name), moments.first.clone()),
+                    (format!("second.{}", moments.name), moments.second.clone()),
+                ]
+            })
+            .collect()
+    }
+
+    fn restore(&mut self, updates: u64, state: &HashMap<String, Tensor>) -> candle_core::Result<()> {
+        let get = |key: String, like: &Tensor| match state.get(&key) {
+            Some(tensor) if tensor.shape() == like.shape() => tensor.to_device(like.device())?.to_dtype(like.dtype()),
+            Some(_) => Err(candle_core::Error::Msg(format!("{} has the wrong shape", key))),
+            None => Err(candle_core::Error::Msg(format!("no optimizer state for {}", key))),
+        };
+        for moments in &mut self.moments {
+            moments.first = get(format!("first.{}", moments.name), &moments.first)?;
+            moments.second = get(format!("second.{}", moments.name), &moments.second)?;
+        }
+        self.updates = updates;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepReport {
+    pub step: u64,
+    /// This replica's loss on its own batch.
+    pub loss: f32,
+    /// The mean of every contributor's loss.
+    pub mean_loss: f32,
+    /// The replicas whose gradients were averaged, this one included.
+    pub contributors: Vec<String>,
+    /// Peers not heard from in time, or whose gradients were unusable.
+    pub dropped: Vec<String>,
+    /// Dense `f32` gradients over what was sent for them.
+    pub compression_ratio: f32,
+    /// Gradient bytes sent to each peer, indices included.
+    pub gradient_bytes: usize,
+    /// Time spent sending gradients and waiting for peers'.
+    pub comm_time: Duration,
+}
+
+/// The contributions to one step as they come in.
+struct Gather {
+    step: u64,
+    parameters: usize,
+    compression: GradientCompression,
+    bucket_size: usize,
+    buckets: usize,
+    pending: BTreeSet<String>,
+    /// Peers some of whose buckets have come, with the buckets that have.
+    partial: BTreeMap<String, (f32, Vec<f32>, BTreeSet<u32>)>,
+    /// By sender, so every replica sums them in the same order.
+    contributions: BTreeMap<String, (f32, Vec<f32>)>,
+}
+
+impl Gather {
+    /// Takes `message` if it is this step's from a peer not yet heard from
+    /// in full, handing it back if it is for a later step. A peer packing
+    /// its gradients otherwise than this trainer fails the step.
+    fn offer(&mut self, message: GradientMessage) -> Result<Option<GradientMessage>, LlmError> {
+        if message.step > self.step {
+            return Ok(Some(message));
+        }
+        if message.step < self.step || !self.pending.contains(&message.sender) {
+            debug!("Ignoring step {} gradients from {} during step {}", message.step, message.sender, self.step);
+            return Ok(None);
+        }
+        if message.compression != self.compression || message.buckets as usize != self.buckets {
+            return Err(LlmError::TrainingFailed(format!(
+                "{} packs gradients as {} in {} buckets, but this trainer as {} in {}",
+                message.sender, message.compression, message.buckets, self.compression, self.buckets
+            )));
+        }
+        let bucket = message.bucket as usize;
+        let start = bucket * self.bucket_size;
+        let len = self.bucket_size.min(self.parameters.saturating_sub(start));
+        let unpacked = if bucket < self.buckets {
+            compress::unpack(self.compression, len, &message.indices, &message.values)
+        } else {
+            None
+        };
+        let Some(unpacked) = unpacked else {
+            warn!(
+                "Dropping {} from step {}: sent {} bytes for bucket {} of {} parameters",
+                message.sender, self.step, message.payload_len(), message.bucket, self.parameters
+            );
+            self.pending.remove(&message.sender);
+            self.partial.remove(&message.sender);
+            return Ok(None);
+        };
+        let parameters = self.parameters;
+        let (_, gradients, received) = self.partial
+            .entry(message.sender.clone())
+            .or_insert_with(|| (message.loss(), vec![0.0; parameters], BTreeSet::new()));
+        if received.insert(message.bucket) {
+            gradients[start..start + len].copy_from_slice(&unpacked);
+        }
+        if received.len() == self.buckets {
+            let (loss, gradients, _) = self.partial.remove(&message.sender).expect("inserted above");
+            self.pending.remove(&message.sender);
+            self.contributions.insert(message.sender, (loss, gradients));
+        }
+        Ok(None)
+    }
+}
+
+/// Packs gradients into buckets of `size` as `backward` copies them out,
+/// handing each over to be sent as soon as it is full.
+struct Buckets {
+    compression: GradientCompression,
+    size: usize,
+    /// What earlier steps failed to send, by parameter.
+    residual: Vec<f32>,
+    loss: f32,
+    filled: Vec<f32>,
+    next: usize,
+    ready: mpsc::UnboundedSender<Ready>,
+}
+
+/// A bucket packed for the wire.
+struct Ready {
+    loss: f32,
+    bucket: usize,
+    packed: Packed,
+}
+
+impl Buckets {
+    fn push(&mut self, gradients: impl IntoIterator<Item = f32>) {
+        for gradient in gradients {
+            self.filled.push(gradient);
+            if self.filled.len() == self.size {
+                self.flush();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let start = self.next * self.size;
+        let residual = &mut self.residual[start..start + self.filled.len()];
+        let packed = compress::pack(self.compression, &self.filled, residual);
+        // Nobody listening means the step has already failed.
+        let _ = self.ready.send(Ready { loss: self.loss, bucket: self.next, packed });
+        self.filled.clear();
+        self.next += 1;
+    }
+
+    /// Hands over the last bucket, even an empty one if it is the only
+    /// one, and gives back the residual.
+    fn finish(mut self) -> Vec<f32> {
+        if !self.filled.is_empty() || self.next == 0 {
+            self.flush();
+        }
+        self.residual
+    }
+}
+
+/// Data-parallel training: every replica runs its own batch, then all of
+/// them apply the mean of their gradients. Each sends its gradients to
+/// every peer and averages whatever arrives before the timeout; replicas
+/// agree as long as they hear from the same peers.
+pub struct DistributedTrainer {
+    id: String,
+    model: Arc<dyn TrainableModel>,
+    transport: Arc<dyn GradientTransport>,
+    peers: Vec<String>,
+    batch_size: usize,
+    optimizer_kind: OptimizerKind,
+    learning_rate: f64,
+    peer_timeout: Duration,
+    optimizer: Option<Optimizer>,
+    step: u64,
+    seed: u64,
+    /// Messages from peers already on a later step.
+    early: Vec<GradientMessage>,
+    checkpoints: Option<CheckpointConfig>,
+    /// The periodic checkpoint being written, if any.
+    writing: Option<JoinHandle<Result<PathBuf, LlmError>>>,
+    /// Where `train_on` has read its dataset to.
+    dataset: Option<DatasetCursor>,
+    /// Restored from a checkpoint, for the next `train_on` to seek to.
+    resume_dataset: Option<DatasetCursor>,
+    compression: GradientCompression,
+    bucket_size: usize,
+    /// The error feedback: what compression has not yet sent.
+    residual: Vec<f32>,
+}
+
+/// A step's batch, as text or as packed tokens.
+enum Batch {
+    Texts(Vec<String>),
+    Tokens(Vec<Vec<u32>>),
+}
+
+impl Batch {
+    fn len(&self) -> usize {
+        match self {
+            Batch::Texts(texts) => texts.len(),
+            Batch::Tokens(sequences) => sequences.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl DistributedTrainer {
+    /// `id` is this replica's name in its peers' `peers`.
+    pub fn new(
+        id: impl Into<String>,
+        model: Arc<dyn TrainableModel>,
+        transport: Arc<dyn GradientTransport>,
+        peers: Vec<String>,
+        batch_size: usize,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            model,
+            transport,
+            peers,
+            batch_size: batch_size.max(1),
+            optimizer_kind: OptimizerKind::default(),
+            learning_rate: DEFAULT_LEARNING_RATE,
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            optimizer: None,
+            step: 0,
+            seed: 0,
+            early: Vec::new(),
+            checkpoints: None,
+            writing: None,
+            dataset: None,
+            resume_dataset: None,
+            compression: GradientCompression::default(),
+            bucket_size: DEFAULT_BUCKET_SIZE,
+            residual: Vec::new(),
+        }
+    }
+
+    pub fn with_optimizer(mut self, kind: OptimizerKind) -> Self {
+        self.optimizer_kind = kind;
+        self
+    }
+
+    pub fn with_learning_rate(mut self, learning_rate: f64) -> Self {
+        self.learning_rate = learning_rate;
+        self
+    }
+
+    pub fn with_peer_timeout(mut self, timeout: Duration) -> Self {
+        self.peer_timeout = timeout;
+        self
+    }
+
+    /// Step `n` reseeds the model with `seed + n`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Packs gradients for peers as `compression` says; every replica must
+    /// pack theirs alike.
+    pub fn with_compression(mut self, compression: GradientCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Gradients per all-reduce bucket. Buckets are packed and sent one by
+    /// one, so sending the first overlaps with packing the rest; top-k
+    /// keeps the largest fraction of each.
+    pub fn with_bucket_size(mut self, bucket_size: usize) -> Self {
+        self.bucket_size = bucket_size.max(1);
+        self
+    }
+
+    /// Checkpoints every `config.checkpoint_every_n_steps` steps, written
+    /// in the background while training carries on.
+    pub fn with_checkpoints(mut self, config: CheckpointConfig) -> Self {
+        self.checkpoints = Some(config);
+        self
+    }
+
+    /// Steps taken so far.
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// Where `train_on` has read its dataset to, as checkpoints record it.
+    pub fn dataset_cursor(&self) -> Option<&DatasetCursor> {
+        self.dataset.as_ref()
+    }
+
+    pub async fn train_step(
+        &mut self,
+        batch: Vec<String>,
+    ) -> Result<StepReport, LlmError> {
+        self.step_on(Batch::Texts(batch)).await
+    }
+
+    /// A step on the next `batch_size` sequences of `dataset`, or `None`
+    /// once its epoch is over. After `resume_from_checkpoint` the dataset
+    /// is first sought to where the checkpoint had read it to.
+    pub async fn train_on(&mut self, dataset: &mut Dataset) -> Result<Option<StepReport>, LlmError> {
+        if let Some(cursor) = self.resume_dataset.take() {
+            dataset.seek(cursor)?;
+        }
+        let batch = dataset.next_batch(self.batch_size)?;
+        self.dataset = Some(dataset.cursor().clone());
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        self.step_on(Batch::Tokens(batch)).await.map(Some)
+    }
+
+    async fn step_on(&mut self, batch: Batch) -> Result<StepReport, LlmError> {
+        if batch.is_empty() {
+            return Err(LlmError::InvalidParams("training batch is empty".to_string()));
+        }
+        if batch.len() > self.batch_size {
+            return Err(LlmError::InvalidParams(format!(
+                "batch of {} is over the batch size of {}", batch.len(), self.batch_size
+            )));
+        }
+        let vars = named_vars(self.model.vars());
+        let parameters: usize = vars.iter().map(|(_, var)| var.elem_count()).sum();
+        let buckets = parameters.div_ceil(self.bucket_size).max(1);
+        if self.residual.len() != parameters {
+            self.residual = vec![0.0; parameters];
+        }
+        let model = Arc::clone(&self.model);
+        model.reseed(self.seed.wrapping_add(self.step));
+        let (ready, mut packed) = mpsc::unbounded_channel();
+        let bucketing = Buckets {
+            compression: self.compression,
+            size: self.bucket_size,
+            residual: std::mem::take(&mut self.residual),
+            loss: 0.0,
+            filled: Vec::new(),
+            next: 0,
+            ready,
+        };
+        let backward_vars = vars.clone();
+        let backward = tokio::task::spawn_blocking(move || backward(model.as_ref(), &batch, &backward_vars, bucketing));
+
+        // Each bucket goes out as soon as it is packed, while later ones
+        // still are.
+        let mut own = vec![0.0f32; parameters];
+        let mut loss = 0.0;
+        let mut gradient_bytes = 0;
+        let mut comm_time = Duration::ZERO;
+        while let Some(ready) = packed.recv().await {
+            let started = Instant::now();
+            let start = ready.bucket * self.bucket_size;
+            own[start..start + ready.packed.unpacked.len()].copy_from_slice(&ready.packed.unpacked);
+            loss = ready.loss;
+            let message = GradientMessage {
+                sender: self.id.clone(),
+                step: self.step,
+                loss: ready.loss.to_bits(),
+                compression: self.compression,
+                bucket: ready.bucket as u32,
+                buckets: buckets as u32,
+                indices: ready.packed.indices,
+                values: ready.packed.values,
+            };
+            gradient_bytes += message.payload_len();
+            for peer in &self.peers {
+                if let Err(e) = self.transport.send(peer, message.clone()).await {
+                    debug!("Not sending step {} gradients to {}: {}", self.step, peer, e);
+                }
+            }
+            comm_time += started.elapsed();
+        }
+        let (mut grads, residual) = backward.await.map_err(training_failed)?.map_err(training_failed)?;
+        self.residual = residual;
+
+        let waiting = Instant::now();
+        let mut gather = Gather {
+            step: self.step,
+            parameters,
+            compression: self.compression,
+            bucket_size: self.bucket_size,
+            buckets,
+            pending: self.peers.iter().filter(|peer| **peer != self.id).cloned().collect(),
+            partial: BTreeMap::new(),
+            contributions: BTreeMap::from([(self.id.clone(), (loss, own))]),
+        };
+        let mut early = Vec::new();
+        for message in std::mem::take(&mut self.early) {
+            early.extend(gather.offer(message)?);
+        }
+        let deadline = Instant::now() + self.peer_timeout;
+        while !gather.pending.is_empty() {
+            match timeout_at(deadline, self.transport.recv()).await {
+                Ok(Some(message)) => early.extend(gather.offer(message)?),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        self.early = early;
+        comm_time += waiting.elapsed();
+
+        let dropped: Vec<String> = self.peers.iter()
+            .filter(|peer| **peer != self.id && !gather.contributions.contains_key(*peer))
+            .cloned()
+            .collect();
+        if !dropped.is_empty() {
+            warn!("Step {} averaged without {}", self.step, dropped.join(", "));
+        }
+
+        // Dropped peers are left out of the denominator as well as the sum.
+        let contributors = gather.contributions.len() as f32;
+        let mut mean = vec![0.0f32; gather.parameters];
+        let mut mean_loss = 0.0;
+        for (loss, gradients) in gather.contributions.values() {
+            mean_loss += loss;
+            mean.iter_mut().zip(gradients).for_each(|(mean, gradient)| *mean += gradient);
+        }
+        mean.iter_mut().for_each(|mean| *mean /= contributors);
+        mean_loss /= contributors;
+
+        let mut offset = 0;
+        for (_, var) in &vars {
+            let count = var.elem_count();
+            let averaged = Tensor::from_slice(&mean[offset..offset + count], var.shape(), var.device())
+                .and_then(|gradient| gradient.to_dtype(var.dtype()))
+                .map_err(training_failed)?;
+            grads.insert(var.as_tensor(), averaged);
+            offset += count;
+        }
+        if self.optimizer.is_none() {
+            self.optimizer = Some(Optimizer::new(self.optimizer_kind, &vars, self.learning_rate).map_err(training_failed)?);
+        }
+        if let Some(optimizer) = &mut self.optimizer {
+            optimizer.step(&grads).map_err(training_failed)?;
+        }
+
+        let report = StepReport {
+            step: self.step,
+            loss,
+            mean_loss,
+            contributors: gather.contributions.into_keys().collect(),
+            dropped,
+            compression_ratio: if gradient_bytes == 0 { 1.0 } else { (parameters * 4) as f32 / gradient_bytes as f32 },
+            gradient_bytes,
+            comm_time,
+        };
+        self.step += 1;
+        if let Some(config) = self.checkpoints.clone() {
+            if self.step % config.checkpoint_every_n_steps == 0 {
+                self.checkpoint_in_background(config).await?;
+            }
+        }
+        Ok(report)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, LlmError> {
+        // Vars are updated in place, so the weights are copied out now.
+        let weights: HashMap<String, Tensor> = named_vars(self.model.vars())
+            .into_iter()
+            .map(|(name, var)| var.as_tensor().copy().map(|tensor| (name, tensor)))
+            .collect::<candle_core::Result<_>>()
+            .map_err(training_failed)?;
+        Ok(Snapshot {
+            step: self.step,
+            seed: self.seed,
+            optimizer: self.optimizer_kind,
+            learning_rate: self.learning_rate,
+            weights,
+            optimizer_state: self.optimizer.as_ref().map(Optimizer::state).unwrap_or_default(),
+            dataset: self.dataset.clone(),
+        })
+    }
+
+    /// Writes the weights, optimizer state, step and seed to the directory
+    /// `path`, replacing any checkpoint already there.
+    pub async fn save_checkpoint(&self, path: impl AsRef<Path>) -> Result<(), LlmError> {
+        let snapshot = self.snapshot()?;
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || checkpoint::write(&path, &snapshot))
+            .await
+            .map_err(training_failed)?
+    }
+
+    /// Carries on from the checkpoint at `path`: the next step is the one
+    /// after it, with the optimizer as it was.
+    pub async fn resume_from_checkpoint(&mut self, path: impl AsRef<Path>) -> Result<(), LlmError> {
+        let vars = named_vars(self.model.vars());
+        let device = vars.first().map_or(Device::Cpu, |(_, var)| var.device().clone());
+        let path = path.as_ref().to_path_buf();
+        let snapshot = tokio::task::spawn_blocking(move || checkpoint::read(&path, &device))
+            .await
+            .map_err(training_failed)??;
+
+        if let Some(extra) = snapshot.weights.keys().find(|name| !vars.iter().any(|(var, _)| var == *name)) {
+            return Err(LlmError::TrainingFailed(format!("checkpoint has weights for {}, which the model lacks", extra)));
+        }
+        for (name, var) in &vars {
+            let weights = snapshot.weights.get(name)
+                .ok_or_else(|| LlmError::TrainingFailed(format!("checkpoint has no weights for {}", name)))?;
+            weights.to_dtype(var.dtype())
+                .and_then(|weights| var.set(&weights))
+                .map_err(|e| LlmError::TrainingFailed(format!("restoring {}: {}", name, e)))?;
+        }
+        let optimizer = Optimizer::restore(snapshot.optimizer, &vars, snapshot.learning_rate, snapshot.step, &snapshot.optimizer_state)
+            .map_err(training_failed)?;
+        self.optimizer = Some(optimizer);
+        self.optimizer_kind = snapshot.optimizer;
+        self.learning_rate = snapshot.learning_rate;
+        self.step = snapshot.step;
+        self.seed = snapshot.seed;
+        self.dataset = snapshot.dataset.clone();
+        self.resume_dataset = snapshot.dataset;
+        self.early.clear();
+        info!("Resumed training at step {}", self.step);
+        Ok(())
+    }
+
+    /// Writes just the adapters, for `LightLLM::load_adapter`.
+    pub async fn save_adapter(&self, path: impl AsRef<Path>) -> Result<(), LlmError> {
+        let config = self.model.lora_config()
+            .ok_or_else(|| LlmError::InvalidParams("the model trains no adapters".to_string()))?;
+        let adapter = LoraAdapter::from_varmap(config.clone(), self.model.vars())?;
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || adapter.save(&path))
+            .await
+            .map_err(training_failed)?
+    }
+
+    /// Starts writing a periodic checkpoint, first waiting for the last
+    /// one if it is somehow still going.
+    async fn checkpoint_in_background(&mut self, config: CheckpointConfig) -> Result<(), LlmError> {
+        if let Err(e) = self.flush_checkpoints().await {
+            warn!("Periodic checkpoint failed: {}", e);
+        }
+        let snapshot = self.snapshot()?;
+        self.writing = Some(tokio::task::spawn_blocking(move || {
+            let path = config.path_for(snapshot.step);
+            checkpoint::write(&path, &snapshot)?;
+            checkpoint::rotate(&config)?;
+            Ok(path)
+        }));
+        Ok(())
+    }
+
+    /// Waits for the periodic checkpoint being written, returning where it
+    /// went.
+    pub async fn flush_checkpoints(&mut self) -> Result<Option<PathBuf>, LlmError> {
+        match self.writing.take() {
+            Some(writing) => writing.await.map_err(training_failed)?.map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn training_failed(e: impl std::fmt::Display) -> LlmError {
+    LlmError::TrainingFailed(e.to_string())
+}
+
+/// Sorted by name, the order gradients are flattened in.
+fn named_vars(varmap: &VarMap) -> Vec<(String, Var)> {
+    let data = varmap.data().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let sorted: BTreeMap<&String, &Var> = data.iter().collect();
+    sorted.into_iter().map(|(name, var)| (name.clone(), var.clone())).collect()
+}
+
+/// The loss and its gradients, handed to `buckets` flattened in `vars`
+/// order; a parameter the loss does not depend on gets zeros. Returns the
+/// gradients and what of them compression left for the next step.
+fn backward(
+    model: &dyn TrainableModel,
+    batch: &Batch,
+    vars: &[(String, Var)],
+    mut buckets: Buckets,
+) -> candle_core::Result<(GradStore, Vec<f32>)> {
+    let loss = match batch {
+        Batch::Texts(texts) => model.loss(texts)?,
+        Batch::Tokens(sequences) => model.token_loss(sequences)?,
+    };
+    let grads = loss.backward()?;
+    buckets.loss = loss.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+    for (_, var) in vars {
+        match grads.get(var.as_tensor()) {
+            Some(gradient) => buckets.push(gradient.flatten_all()?.to_dtype(DType::F32)?.to_vec1::<f32>()?),
+            None => buckets.push(std::iter::repeat(0.0).take(var.elem_count())),
+        }
+    }
+    Ok((grads, buckets.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use crate::llm::dataset::DatasetConfig;
+    use crate::llm::lora::LoraLinear;
+    use crate::llm::{KvCache, ModelBackend};
+    use crate::node::network::GradientPrecision;
+    use candle_nn::{Init, VarBuilder};
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+
+    /// Delivers messages between trainers in the same process.
+    #[derive(Default)]
+    struct Hub {
+        inboxes: Mutex<HashMap<String, mpsc::UnboundedSender<GradientMessage>>>,
+    }
+
+    struct Endpoint {
+        hub: Arc<Hub>,
+        inbox: AsyncMutex<mpsc::UnboundedReceiver<GradientMessage>>,
+    }
+
+    impl Hub {
+        fn join(self: &Arc<Self>, name: &str) -> Arc<Endpoint> {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            self.inboxes.lock().insert(name.to_string(), sender);
+            Arc::new(Endpoint { hub: Arc::clone(self), inbox: AsyncMutex::new(receiver) })
+        }
+    }
+
+    #[async_trait]
+    impl GradientTransport for Endpoint {
+        async fn send(&self, peer: &str, message: GradientMessage) -> Result<(), String> {
+            let inboxes = self.hub.inboxes.lock();
+            let inbox = inboxes.get(peer).ok_or_else(|| format!("{} is not connected", peer))?;
+            inbox.send(message).map_err(|e| e.to_string())
+        }
+
+        async fn recv(&self) -> Option<GradientMessage> {
+            self.inbox.lock().await.recv().await
+        }
+    }
+
+    /// Fits `2 * length` from a text's length, or a sequence's token sum
+    /// mod 7, starting from the same
+    /// weights every time.
+    struct Line {
+        varmap: VarMap,
+        weight: Tensor,
+        bias: Tensor,
+    }
+
+    impl Line {
+        fn new() -> Arc<Self> {
+            let varmap = VarMap::new();
+            let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+            let weight = vb.get_with_hints((1, 1), "weight", Init::Const(0.5)).unwrap();
+            let bias = vb.get_with_hints(1, "bias", Init::Const(0.0)).unwrap();
+            Arc::new(Line { varmap, weight, bias })
+        }
+
+        fn fit(&self, lengths: Vec<f32>) -> candle_core::Result<Tensor> {
+            let xs = Tensor::from_slice(&lengths, (lengths.len(), 1), &Device::Cpu)?;
+            let ys = (&xs * 2.0)?;
+            let predictions = xs.matmul(&self.weight.t()?)?.broadcast_add(&self.bias)?;
+            (predictions - ys)?.sqr()?.mean_all()
+        }
+
+        fn parameters(&self) -> Vec<f32> {
+            named_vars(&self.varmap)
+                .iter()
+                .flat_map(|(_, var)| var.flatten_all().unwrap().to_vec1::<f32>().unwrap())
+                .collect()
+        }
+    }
+
+    impl TrainableModel for Line {
+        fn vars(&self) -> &VarMap {
+            &self.varmap
+        }
+
+        fn loss(&self, batch: &[String]) -> candle_core::Result<Tensor> {
+            self.fit(batch.iter().map(|text| text.len() as f32).collect())
+        }
+
+        fn token_loss(&self, batch: &[Vec<u32>]) -> candle_core::Result<Tensor> {
+            self.fit(batch.iter().map(|tokens| (tokens.iter().sum::<u32>() % 7) as f32).collect())
+        }
+    }
+
+    fn batch(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    fn trainer(hub: &Arc<Hub>, name: &str, peers: &[&str]) -> (DistributedTrainer, Arc<Line>) {
+        let model = Line::new();
+        let peers = peers.iter().map(|peer| peer.to_string()).collect();
+        let trainer = DistributedTrainer::new(name, model.clone(), hub.join(name), peers, 4).with_learning_rate(0.01);
+        (trainer, model)
+    }
+
+    #[tokio::test]
+    async fn test_replicas_end_the_step_with_identical_parameters() {
+        let hub = Arc::new(Hub::default());
+        let (mut a, model_a) = trainer(&hub, "a", &["b"]);
+        let (mut b, model_b) = trainer(&hub, "b", &["a"]);
+        let (mut solo, solo_model) = trainer(&hub, "solo", &[]);
+        let initial = model_a.parameters();
+
+        let mut after_first = Vec::new();
+        for _ in 0..2 {
+            let (left, right) = tokio::join!(a.train_step(batch(&["ab", "abc"])), b.train_step(batch(&["abcdefgh"])));
+            let (left, right) = (left.unwrap(), right.unwrap());
+            assert_eq!(left.contributors, ["a", "b"]);
+            assert!(left.dropped.is_empty());
+            assert_eq!(left.mean_loss, right.mean_loss);
+            assert!((left.mean_loss - (left.loss + right.loss) / 2.0).abs() < 1e-4, "{:?} {:?}", left, right);
+            assert_eq!(model_a.parameters(), model_b.parameters());
+            if after_first.is_empty() {
+                after_first = model_a.parameters();
+            }
+        }
+        assert_eq!(a.step(), 2);
+        assert_ne!(after_first, initial);
+
+        // Alone, `a`'s batch would have moved it somewhere else.
+        let alone = solo.train_step(batch(&["ab", "abc"])).await.unwrap();
+        assert_eq!(alone.contributors, ["solo"]);
+        assert_eq!(alone.mean_loss, alone.loss);
+        assert_ne!(solo_model.parameters(), after_first);
+
+        assert!(matches!(a.train_step(Vec::new()).await, Err(LlmError::InvalidParams(_))));
+        assert!(matches!(a.train_step(batch(&["a"; 5])).await, Err(LlmError::InvalidParams(_))));
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_is_dropped_from_the_average() {
+        let hub = Arc::new(Hub::default());
+        let (a, model_a) = trainer(&hub, "a", &["b", "c"]);
+        let (b, model_b) = trainer(&hub, "b", &["a", "c"]);
+        let (mut a, mut b) = (a.with_peer_timeout(Duration::from_millis(50)), b.with_peer_timeout(Duration::from_millis(50)));
+        let (mut pair, model_pair) = trainer(&hub, "pair", &["twin"]);
+        let (mut twin, _) = trainer(&hub, "twin", &["pair"]);
+
+        let (left, right) = tokio::join!(a.train_step(batch(&["ab"])), b.train_step(batch(&["abcd"])));
+        let (left, right) = (left.unwrap(), right.unwrap());
+        assert_eq!((left.contributors.as_slice(), left.dropped.as_slice()), (&["a".to_string(), "b".to_string()][..], &["c".to_string()][..]));
+        assert_eq!(right.dropped, ["c"]);
+        assert_eq!(model_a.parameters(), model_b.parameters());
+
+        // The same step between just two replicas: "c" counted for nothing.
+        let (paired, _) = tokio::join!(pair.train_step(batch(&["ab"])), twin.train_step(batch(&["abcd"])));
+        assert_eq!(paired.unwrap().mean_loss, left.mean_loss);
+        assert_eq!(model_pair.parameters(), model_a.parameters());
+    }
+
+    fn adam(hub: &Arc<Hub>, name: &str) -> (DistributedTrainer, Arc<Line>) {
+        let (trainer, model) = trainer(hub, name, &[]);
+        (trainer.with_optimizer(OptimizerKind::AdamW).with_learning_rate(0.1).with_seed(7), model)
+    }
+
+    fn batches() -> Vec<Vec<String>> {
+        [["a", "abc"], ["abcd", "ab"], ["abcdef", "a"], ["abc", "abc"], ["ab", "abcdefg"], ["abcde", "a"]]
+            .iter()
+            .map(|texts| batch(&texts[..]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_resumed_training_matches_uninterrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let hub = Arc::new(Hub::default());
+        let (mut straight, straight_model) = adam(&hub, "straight");
+        let mut losses = Vec::new();
+        for batch in batches() {
+            losses.push(straight.train_step(batch).await.unwrap().loss);
+        }
+
+        let (mut first, _) = adam(&hub, "first");
+        for batch in batches().into_iter().take(3) {
+            first.train_step(batch).await.unwrap();
+        }
+        let path = dir.path().join("checkpoint");
+        first.save_checkpoint(&path).await.unwrap();
+        drop(first);
+
+        let (second, second_model) = adam(&hub, "second");
+        let mut second = second.with_optimizer(OptimizerKind::Sgd).with_seed(0);
+        second.resume_from_checkpoint(&path).await.unwrap();
+        assert_eq!(second.step(), 3);
+        let mut resumed = Vec::new();
+        for batch in batches().into_iter().skip(3) {
+            resumed.push(second.train_step(batch).await.unwrap().loss);
+        }
+        assert_eq!(resumed, losses[3..]);
+        assert_eq!(second_model.parameters(), straight_model.parameters());
+
+        // A flipped bit in the weights is caught before anything is restored.
+        let weights = path.join("model.safetensors");
+        let mut bytes = std::fs::read(&weights).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&weights, bytes).unwrap();
+        let (mut third, third_model) = adam(&hub, "third");
+        let untouched = third_model.parameters();
+        match third.resume_from_checkpoint(&path).await {
+            Err(LlmError::ChecksumMismatch { path, .. }) => assert_eq!(path, weights),
+            other => panic!("expected ChecksumMismatch, got {:?}", other),
+        }
+        assert_eq!((third.step(), third_model.parameters()), (0, untouched));
+    }
+
+    #[tokio::test]
+    async fn test_periodic_checkpoints_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let hub = Arc::new(Hub::default());
+        let config = CheckpointConfig::new(dir.path(), 2).with_keep_last(2);
+        let (trainer, model) = adam(&hub, "a");
+        let mut trainer = trainer.with_checkpoints(config.clone());
+        for batch in batches() {
+            trainer.train_step(batch).await.unwrap();
+        }
+        assert_eq!(trainer.flush_checkpoints().await.unwrap(), Some(config.path_for(6)));
+        assert_eq!(trainer.flush_checkpoints().await.unwrap(), None);
+
+        let mut written: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        written.sort();
+        assert_eq!(written, ["step-00000004", "step-00000006"]);
+        assert_eq!(config.latest(), Some(config.path_for(6)));
+
+        let (mut resumed, resumed_model) = adam(&hub, "b");
+        resumed.resume_from_checkpoint(config.latest().unwrap()).await.unwrap();
+        assert_eq!(resumed.step(), 6);
+        assert_eq!(resumed_model.parameters(), model.parameters());
+    }
+
+    /// Fits fixed weights over eight features of a text's length, large
+    /// enough to bucket and sparsify.
+    struct Wide {
+        varmap: VarMap,
+        weight: Tensor,
+    }
+
+    impl Wide {
+        fn new() -> Arc<Self> {
+            let varmap = VarMap::new();
+            let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+            let weight = vb.get_with_hints((8, 1), "weight", Init::Const(0.0)).unwrap();
+            Arc::new(Wide { varmap, weight })
+        }
+    }
+
+    impl TrainableModel for Wide {
+        fn vars(&self) -> &VarMap {
+            &self.varmap
+        }
+
+        fn loss(&self, batch: &[String]) -> candle_core::Result<Tensor> {
+            let features: Vec<f32> = batch.iter()
+                .flat_map(|text| (0..8).map(move |j| ((text.len() + j) % 5) as f32 - 2.0))
+                .collect();
+            let xs = Tensor::from_slice(&features, (batch.len(), 8), &Device::Cpu)?;
+            let target: Vec<f32> = (0..8).map(|j| j as f32 * 0.25 - 0.75).collect();
+            let ys = xs.matmul(&Tensor::from_slice(&target, (8, 1), &Device::Cpu)?)?;
+            (xs.matmul(&self.weight)? - ys)?.sqr()?.mean_all()
+        }
+    }
+
+    /// Trains a pair packing gradients as `compression` for 30 steps,
+    /// returning their last reports and final weights.
+    async fn train_pair(hub: &Arc<Hub>, names: [&str; 2], compression: GradientCompression) -> (StepReport, Vec<f32>) {
+        let pair = |name: &str, peer: &str| {
+            let model = Wide::new();
+            let trainer = DistributedTrainer::new(name, model.clone(), hub.join(name), vec![peer.to_string()], 2)
+                .with_learning_rate(0.05)
+                .with_compression(compression)
+                .with_bucket_size(4);
+            (trainer, model)
+        };
+        let ((mut a, model_a), (mut b, model_b)) = (pair(names[0], names[1]), pair(names[1], names[0]));
+        let batches = batches();
+        let others: [&[&str]; 6] = [&["abcdefgh"], &["abcdef", "abc"], &["ab"], &["abcd", "abcdefgh"], &["abc", "a"], &["abcdefg"]];
+        let mut last = None;
+        for step in 0..30 {
+            let (left, right) = tokio::join!(a.train_step(batches[step % 6].clone()), b.train_step(batch(others[step % 6])));
+            let (left, right) = (left.unwrap(), right.unwrap());
+            assert_eq!((left.mean_loss, left.gradient_bytes), (right.mean_loss, right.gradient_bytes));
+            last = Some(left);
+        }
+        let weights = |model: &Wide| model.weight.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(weights(&model_a), weights(&model_b));
+        (last.unwrap(), weights(&model_a))
+    }
+
+    #[tokio::test]
+    async fn test_compressed_gradients_converge_in_fewer_bytes() {
+        let hub = Arc::new(Hub::default());
+        let (dense, dense_weights) = train_pair(&hub, ["a", "b"], GradientCompression::default()).await;
+        assert_eq!((dense.gradient_bytes, dense.compression_ratio), (32, 1.0));
+        assert!(dense.mean_loss < 0.01, "{:?}", dense);
+
+        let halves = GradientCompression::new(GradientPrecision::F16);
+        let (half, half_weights) = train_pair(&hub, ["c", "d"], halves).await;
+        assert_eq!((half.gradient_bytes, half.compression_ratio), (16, 2.0));
+        assert!(half.mean_loss < 0.01, "{:?}", half);
+        assert!(half_weights.iter().zip(&dense_weights).all(|(half, dense)| (half - dense).abs() < 1e-3));
+
+        // One of each bucket's four values, at two bytes and a four-byte
+        // index, with what is held back fed into later steps.
+        let (sparse, sparse_weights) = train_pair(&hub, ["e", "f"], halves.with_top_k(0.25)).await;
+        assert_eq!(sparse.gradient_bytes, 2 * (2 + 4));
+        assert_eq!(sparse.compression_ratio, 32.0 / 12.0);
+        assert!(sparse.mean_loss < 0.01, "{:?}", sparse);
+        assert!(sparse_weights.iter().zip(&dense_weights).all(|(sparse, dense)| (sparse - dense).abs() < 0.05));
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_compression_fails_the_step() {
+        let hub = Arc::new(Hub::default());
+        let (a, _) = trainer(&hub, "a", &["b"]);
+        let (b, _) = trainer(&hub, "b", &["a"]);
+        let mut a = a.with_compression(GradientCompression::new(GradientPrecision::Bf16));
+        let mut b = b.with_bucket_size(1);
+        let (left, right) = tokio::join!(a.train_step(batch(&["ab"])), b.train_step(batch(&["abcd"])));
+        for result in [left, right] {
+            match result {
+                Err(LlmError::TrainingFailed(reason)) => assert!(reason.contains("packs gradients as"), "{}", reason),
+                other => panic!("expected TrainingFailed, got {:?}", other),
+            }
+        }
+    }
+
+    /// A token per letter, from 1, with 0 between documents.
+    struct Letters;
+
+    impl ModelBackend for Letters {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().map(|byte| u32::from(byte - b'a') + 1).collect())
+        }
+
+        fn decode(&self, _tokens: &[u32]) -> Result<String, LlmError> {
+            unreachable!("datasets only encode")
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            unreachable!("datasets only encode")
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            64
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dataset_resumes_with_the_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        std::fs::create_dir(&data).unwrap();
+        let lines: String = (0..20).map(|i| format!("{{\"text\": \"{}\"}}\n", &"abcdefgh"[..1 + i % 7])).collect();
+        std::fs::write(data.join("docs.jsonl"), lines).unwrap();
+        let dataset = || Dataset::open(&data, DatasetConfig::new(3), Arc::new(Letters)).unwrap();
+        let hub = Arc::new(Hub::default());
+
+        let (mut straight, straight_model) = adam(&hub, "straight");
+        let mut epoch = dataset();
+        let mut losses = Vec::new();
+        while let Some(report) = straight.train_on(&mut epoch).await.unwrap() {
+            losses.push(report.loss);
+        }
+        assert!(losses.len() > 4);
+        assert_eq!(straight.dataset_cursor().map(|cursor| cursor.epoch), Some(1));
+
+        let (mut first, _) = adam(&hub, "first");
+        let mut epoch = dataset();
+        for _ in 0..2 {
+            first.train_on(&mut epoch).await.unwrap().unwrap();
+        }
+        let path = dir.path().join("checkpoint");
+        first.save_checkpoint(&path).await.unwrap();
+        drop(first);
+
+        // A fresh dataset, sought to the checkpoint's cursor by the trainer.
+        let (mut second, second_model) = adam(&hub, "second");
+        second.resume_from_checkpoint(&path).await.unwrap();
+        let mut epoch = dataset();
+        let mut resumed = Vec::new();
+        while let Some(report) = second.train_on(&mut epoch).await.unwrap() {
+            resumed.push(report.loss);
+        }
+        assert_eq!(resumed, losses[2..]);
+        assert_eq!(second_model.parameters(), straight_model.parameters());
+    }
+
+    /// Two projections over frozen weights, only the first adapted.
+    struct Adapted {
+        varmap: VarMap,
+        config: LoraConfig,
+        up: LoraLinear,
+        down: LoraLinear,
+        weights: (Tensor, Tensor),
+    }
+
+    impl Adapted {
+        fn new() -> Arc<Self> {
+            let varmap = VarMap::new();
+            let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+            let config = LoraConfig { rank: 2, alpha: 4.0, target_modules: vec!["up_proj".to_string()], dropout: 0.0 };
+            let up_weight = Tensor::randn(0f32, 0.5, (4, 2), &Device::Cpu).unwrap();
+            let down_weight = Tensor::randn(0f32, 0.5, (1, 4), &Device::Cpu).unwrap();
+            let up = LoraLinear::new("mlp.up_proj", up_weight.clone(), None, &config, vb.clone()).unwrap();
+            let down = LoraLinear::new("mlp.down_proj", down_weight.clone(), None, &config, vb).unwrap();
+            Arc::new(Adapted { varmap, config, up, down, weights: (up_weight, down_weight) })
+        }
+    }
+
+    impl TrainableModel for Adapted {
+        fn vars(&self) -> &VarMap {
+            &self.varmap
+        }
+
+        fn loss(&self, batch: &[String]) -> candle_core::Result<Tensor> {
+            let features: Vec<f32> = batch.iter().flat_map(|text| [text.len() as f32, 1.0]).collect();
+            let xs = Tensor::from_slice(&features, (batch.len(), 2), &Device::Cpu)?;
+            let ys = xs.narrow(1, 0, 1)?;
+            let predictions = self.down.forward_t(&self.up.forward_t(&xs, true)?, true)?;
+            (predictions - ys)?.sqr()?.mean_all()
+        }
+
+        fn lora_config(&self) -> Option<&LoraConfig> {
+            Some(&self.config)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_adapters_are_trained() {
+        let hub = Arc::new(Hub::default());
+        let model = Adapted::new();
+        let names: Vec<String> = named_vars(&model.varmap).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["mlp.up_proj.lora_a", "mlp.up_proj.lora_b"]);
+        let values = |tensor: &Tensor| tensor.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        let frozen = (values(&model.weights.0), values(&model.weights.1));
+        let a_before = values(&named_vars(&model.varmap)[0].1);
+
+        let mut adapted = DistributedTrainer::new("a", model.clone(), hub.join("a"), Vec::new(), 4).with_learning_rate(0.01);
+        let first = adapted.train_step(batch(&["ab", "abcd"])).await.unwrap();
+        for _ in 0..3 {
+            adapted.train_step(batch(&["ab", "abcd"])).await.unwrap();
+        }
+        let last = adapted.train_step(batch(&["ab", "abcd"])).await.unwrap();
+        assert!(last.loss < first.loss, "{:?} {:?}", first, last);
+
+        assert_eq!((values(&model.weights.0), values(&model.weights.1)), frozen);
+        let vars = named_vars(&model.varmap);
+        assert_ne!(values(&vars[0].1), a_before);
+        assert!(values(&vars[1].1).iter().any(|&value| value != 0.0));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("adapter.safetensors");
+        adapted.save_adapter(&path).await.unwrap();
+        let adapter = LoraAdapter::load(&path).unwrap();
+        assert_eq!(adapter.config(), &model.config);
+        assert_eq!(adapter.modules().collect::<Vec<_>>(), ["mlp.up_proj"]);
+
+        let (plain, _) = trainer(&hub, "plain", &[]);
+        assert!(matches!(plain.save_adapter(&path).await, Err(LlmError::InvalidParams(_))));
+    }
+}
@@ -0,0 +1,294 @@
+use candle_core::Device;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::node::metrics::{self, Histogram, MetricsSource};
+
+const TOKENS_PER_SECOND_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+/// Generations the recent generation rate is measured over.
+pub const RATE_WINDOW: usize = 32;
+
+/// What one generation cost, for charging by the token and watching the
+/// device.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Usage {
+    /// Prompt tokens given to the model, after truncation.
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Time spent waiting for a place in an `InferenceQueue` batch.
+    pub queue_wait_secs: f64,
+    /// Time from the request to the first sampled token, queue wait
+    /// included.
+    pub time_to_first_token_secs: f64,
+    /// Completion tokens over the time from admission to the last token.
+    pub tokens_per_second: f64,
+    /// Most memory the model's device has held, where it reports it.
+    pub device_memory_bytes: Option<u64>,
+    /// Served from an `InferenceCache` without running the model; the
+    /// token counts are those of the completion cached.
+    #[serde(default)]
+    pub cached: bool,
+    /// Share of draft tokens the model accepted, when decoded
+    /// speculatively.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub draft_acceptance_rate: Option<f64>,
+    /// Completion tokens per forward pass of the model, when decoded
+    /// speculatively; plain decoding makes one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speculative_speedup: Option<f64>,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// `Usage` summed over many requests, as kept per address by the RPC
+/// server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: &Usage) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens as u64;
+        self.completion_tokens += usage.completion_tokens as u64;
+    }
+}
+
+/// The process's peak resident memory on the CPU. Candle reports nothing
+/// for GPU memory, so other devices give `None`.
+pub(super) fn memory_high_water(device: &Device) -> Option<u64> {
+    if !device.is_cpu() {
+        return None;
+    }
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().next()?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+struct MetricsInner {
+    latency: Histogram,
+    queue_wait: Histogram,
+    time_to_first_token: Histogram,
+    tokens_per_second: Histogram,
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    device_memory_bytes: Option<u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    draft_tokens: u64,
+    draft_tokens_accepted: u64,
+    speculative_passes: u64,
+    speculative_tokens: u64,
+    /// Completion tokens and seconds of the last `RATE_WINDOW` generations
+    /// that sampled any.
+    recent: VecDeque<(usize, f64)>,
+}
+
+/// Every generation's `Usage`, for `/metrics`. Interrupted generations are
+/// recorded too, since the model ran over their tokens all the same.
+pub struct LlmMetrics {
+    inner: Mutex<MetricsInner>,
+}
+
+impl Default for LlmMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LlmMetrics {
+    pub fn new() -> Self {
+        LlmMetrics {
+            inner: Mutex::new(MetricsInner {
+                latency: Histogram::latency(),
+                queue_wait: Histogram::latency(),
+                time_to_first_token: Histogram::latency(),
+                tokens_per_second: Histogram::new(TOKENS_PER_SECOND_BUCKETS),
+                requests: 0,
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                device_memory_bytes: None,
+                cache_hits: 0,
+                cache_misses: 0,
+                draft_tokens: 0,
+                draft_tokens_accepted: 0,
+                speculative_passes: 0,
+                speculative_tokens: 0,
+                recent: VecDeque::with_capacity(RATE_WINDOW),
+            }),
+        }
+    }
+
+    /// Records a generation that took `latency` once admitted.
+    pub fn record(&self, usage: &Usage, latency: Duration) {
+        let mut inner = self.inner.lock();
+        inner.latency.observe(latency.as_secs_f64());
+        inner.queue_wait.observe(usage.queue_wait_secs);
+        inner.time_to_first_token.observe(usage.time_to_first_token_secs);
+        inner.tokens_per_second.observe(usage.tokens_per_second);
+        inner.requests += 1;
+        inner.prompt_tokens += usage.prompt_tokens as u64;
+        inner.completion_tokens += usage.completion_tokens as u64;
+        if let Some(bytes) = usage.device_memory_bytes {
+            inner.device_memory_bytes = Some(inner.device_memory_bytes.map_or(bytes, |peak| peak.max(bytes)));
+        }
+        if usage.completion_tokens > 0 {
+            if inner.recent.len() == RATE_WINDOW {
+                inner.recent.pop_front();
+            }
+            inner.recent.push_back((usage.completion_tokens, latency.as_secs_f64()));
+        }
+    }
+
+    /// Records an `InferenceCache` lookup for a reproducible request.
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let mut inner = self.inner.lock();
+        if hit {
+            inner.cache_hits += 1;
+        } else {
+            inner.cache_misses += 1;
+        }
+    }
+
+    /// Records a speculatively decoded generation: the draft tokens it
+    /// proposed and those accepted, and the `tokens` it made in `passes`
+    /// of the model.
+    pub fn record_speculation(&self, drafted: usize, accepted: usize, passes: usize, tokens: usize) {
+        let mut inner = self.inner.lock();
+        inner.draft_tokens += drafted as u64;
+        inner.draft_tokens_accepted += accepted as u64;
+        inner.speculative_passes += passes as u64;
+        inner.speculative_tokens += tokens as u64;
+    }
+
+    /// Cache hits and misses recorded so far.
+    pub fn cache_totals(&self) -> (u64, u64) {
+        let inner = self.inner.lock();
+        (inner.cache_hits, inner.cache_misses)
+    }
+
+    /// Completion tokens per second over the last `RATE_WINDOW`
+    /// generations, prompt processing included; `None` before any.
+    pub fn recent_tokens_per_second(&self) -> Option<f64> {
+        let inner = self.inner.lock();
+        let (tokens, secs) = inner.recent.iter().fold((0, 0.0), |(tokens, secs), &(t, s)| (tokens + t, secs + s));
+        (tokens > 0 && secs > 0.0).then(|| tokens as f64 / secs)
+    }
+
+    /// Tokens recorded so far, summed over every request.
+    pub fn totals(&self) -> UsageTotals {
+        let inner = self.inner.lock();
+        UsageTotals {
+            requests: inner.requests,
+            prompt_tokens: inner.prompt_tokens,
+            completion_tokens: inner.completion_tokens,
+        }
+    }
+}
+
+impl MetricsSource for LlmMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        let inner = self.inner.lock();
+        metrics::write_histogram(out, "dadbs_llm_inference_latency_seconds", "Time to generate a completion", &inner.latency);
+        metrics::write_histogram(out, "dadbs_llm_queue_wait_seconds", "Time waiting for a place in the batch", &inner.queue_wait);
+        metrics::write_histogram(
+            out,
+            "dadbs_llm_time_to_first_token_seconds",
+            "Time from a request to its first token",
+            &inner.time_to_first_token,
+        );
+        metrics::write_histogram(out, "dadbs_llm_tokens_per_second", "Completion tokens generated per second", &inner.tokens_per_second);
+        metrics::write_counter(out, "dadbs_llm_requests_total", "Generation requests run", inner.requests);
+        metrics::write_counter(out, "dadbs_llm_prompt_tokens_total", "Prompt tokens given to the model", inner.prompt_tokens);
+        metrics::write_counter(out, "dadbs_llm_completion_tokens_total", "Completion tokens generated", inner.completion_tokens);
+        metrics::write_counter(out, "dadbs_llm_cache_hits_total", "Requests answered from the inference cache", inner.cache_hits);
+        metrics::write_counter(out, "dadbs_llm_cache_misses_total", "Cacheable requests the model had to run", inner.cache_misses);
+        if inner.speculative_passes > 0 {
+            metrics::write_counter(out, "dadbs_llm_draft_tokens_total", "Tokens proposed by the draft model", inner.draft_tokens);
+            metrics::write_counter(
+                out,
+                "dadbs_llm_draft_tokens_accepted_total",
+                "Draft tokens the model accepted",
+                inner.draft_tokens_accepted,
+            );
+            metrics::write_counter(
+                out,
+                "dadbs_llm_speculative_passes_total",
+                "Forward passes of the model while decoding speculatively",
+                inner.speculative_passes,
+            );
+            metrics::write_counter(
+                out,
+                "dadbs_llm_speculative_tokens_total",
+                "Completion tokens decoded speculatively",
+                inner.speculative_tokens,
+            );
+        }
+        if let Some(bytes) = inner.device_memory_bytes {
+            metrics::write_gauge(out, "dadbs_llm_device_memory_high_water_bytes", "Most memory the model's device has held", bytes as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_render_totals_and_peak_memory() {
+        let metrics = LlmMetrics::new();
+        let usage = |prompt_tokens, completion_tokens, device_memory_bytes| Usage {
+            prompt_tokens,
+            completion_tokens,
+            queue_wait_secs: 0.003,
+            time_to_first_token_secs: 0.02,
+            tokens_per_second: 40.0,
+            device_memory_bytes,
+            cached: false,
+            draft_acceptance_rate: None,
+            speculative_speedup: None,
+        };
+        metrics.record(&usage(5, 7, Some(2048)), Duration::from_millis(200));
+        metrics.record(&usage(3, 1, Some(1024)), Duration::from_millis(30));
+        metrics.record(&usage(2, 0, None), Duration::from_millis(1));
+        metrics.record_cache_lookup(true);
+        metrics.record_cache_lookup(false);
+        metrics.record_cache_lookup(false);
+        metrics.record_speculation(8, 6, 3, 9);
+        assert_eq!(metrics.totals(), UsageTotals { requests: 3, prompt_tokens: 10, completion_tokens: 8 });
+        // The last generation sampled nothing, so is left out of the rate.
+        let rate = metrics.recent_tokens_per_second().unwrap();
+        assert!((rate - 8.0 / 0.23).abs() < 1e-9, "{}", rate);
+        assert_eq!(LlmMetrics::new().recent_tokens_per_second(), None);
+
+        let mut out = String::new();
+        metrics.render_metrics(&mut out);
+        for series in [
+            "dadbs_llm_inference_latency_seconds_count 3",
+            "dadbs_llm_queue_wait_seconds_bucket{le=\"0.005\"} 3",
+            "dadbs_llm_time_to_first_token_seconds_sum 0.06",
+            "dadbs_llm_tokens_per_second_bucket{le=\"50\"} 3",
+            "dadbs_llm_requests_total 3",
+            "dadbs_llm_prompt_tokens_total 10",
+            "dadbs_llm_completion_tokens_total 8",
+            "dadbs_llm_cache_hits_total 1",
+            "dadbs_llm_cache_misses_total 2",
+            "dadbs_llm_draft_tokens_accepted_total 6",
+            "dadbs_llm_speculative_passes_total 3",
+            "dadbs_llm_device_memory_high_water_bytes 2048",
+        ] {
+            assert!(out.lines().any(|line| line == series), "{} missing from\n{}", series, out);
+        }
+    }
+}
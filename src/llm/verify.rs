@@ -0,0 +1,287 @@
+use candle_core::quantized::gguf_file;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+use super::arch::Architecture;
+use super::model::{check_model_files, detect_format, model_load_error, LlmError};
+use super::startup::StartupCache;
+use crate::node::config::{LLMConfig, ModelFormat};
+
+const HASH_CHUNK_BYTES: usize = 1024 * 1024;
+/// Real headers are a few hundred kilobytes; a larger length is corruption.
+const MAX_HEADER_BYTES: u64 = 100 * 1024 * 1024;
+const FLOAT_DTYPES: [&str; 3] = ["F16", "BF16", "F32"];
+
+/// SHA-256 digests, in hex, that model artifacts must match. An unset
+/// digest is not checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArtifactHashes {
+    pub model_sha256: Option<String>,
+    pub tokenizer_sha256: Option<String>,
+}
+
+impl ArtifactHashes {
+    pub fn is_empty(&self) -> bool {
+        self.model_sha256.is_none() && self.tokenizer_sha256.is_none()
+    }
+}
+
+impl From<&LLMConfig> for ArtifactHashes {
+    fn from(config: &LLMConfig) -> Self {
+        ArtifactHashes { model_sha256: config.model_sha256.clone(), tokenizer_sha256: config.tokenizer_sha256.clone() }
+    }
+}
+
+/// Hashes both files against `expected`, then checks the model's header
+/// lists the tensors it should and the tokenizer parses. `progress` gets
+/// the file being hashed with bytes done and its total. A safetensors
+/// model's tensors are those of the `architecture` file, or of the
+/// `config.json` beside it. With `startup`, a file unchanged since it was
+/// last hashed is not hashed again.
+pub(super) fn verify(
+    model_path: &Path,
+    tokenizer_path: &Path,
+    architecture: Option<&Path>,
+    expected: &ArtifactHashes,
+    startup: Option<&StartupCache>,
+    progress: &mut dyn FnMut(&Path, u64, u64),
+) -> Result<(), LlmError> {
+    check_model_files(model_path, tokenizer_path)?;
+    for (path, expected) in [(model_path, &expected.model_sha256), (tokenizer_path, &expected.tokenizer_sha256)] {
+        if let Some(expected) = expected {
+            let actual = match startup {
+                Some(startup) => startup.sha256(path, progress)?,
+                None => sha256_file(path, progress)?,
+            };
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(LlmError::ChecksumMismatch { path: path.to_path_buf(), expected: expected.clone(), actual });
+            }
+        }
+    }
+    match detect_format(model_path)? {
+        ModelFormat::Safetensors => {
+            let architecture = Architecture::for_model(model_path, architecture)?;
+            check_safetensors(model_path, &tensor_names(&architecture))?
+        }
+        ModelFormat::Gguf => check_gguf(model_path)?,
+    }
+    match startup {
+        Some(startup) => startup.tokenizer(tokenizer_path).map(drop)?,
+        None => Tokenizer::from_file(tokenizer_path)
+            .map(drop)
+            .map_err(|e| LlmError::TokenizerLoad { path: tokenizer_path.to_path_buf(), reason: e.to_string() })?,
+    }
+    Ok(())
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> LlmError {
+    LlmError::ModelLoad { path: path.to_path_buf(), reason: e.to_string() }
+}
+
+pub(super) fn sha256_file(path: &Path, progress: &mut dyn FnMut(&Path, u64, u64)) -> Result<String, LlmError> {
+    let mut file = File::open(path).map_err(|e| io_error(path, e))?;
+    let total = file.metadata().map_err(|e| io_error(path, e))?.len();
+    let (mut hasher, mut buffer, mut done) = (Sha256::new(), vec![0u8; HASH_CHUNK_BYTES], 0u64);
+    loop {
+        let read = file.read(&mut buffer).map_err(|e| io_error(path, e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        done += read as u64;
+        progress(path, done, total);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// The weights candle's Llama loads for `architecture`.
+fn tensor_names(architecture: &Architecture) -> Vec<String> {
+    architecture.tensor_shapes().into_iter().map(|(name, _)| name).collect()
+}
+
+#[derive(Deserialize)]
+struct TensorEntry {
+    dtype: String,
+    data_offsets: (u64, u64),
+}
+
+/// Reads just the header: every tensor must lie within the file and each
+/// of `required` must be there as floats.
+fn check_safetensors(path: &Path, required: &[String]) -> Result<(), LlmError> {
+    let corrupt = |reason: String| LlmError::ModelLoad { path: path.to_path_buf(), reason };
+    let mut file = File::open(path).map_err(|e| io_error(path, e))?;
+    let file_len = file.metadata().map_err(|e| io_error(path, e))?.len();
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len).map_err(|_| corrupt("too short for a safetensors header".to_string()))?;
+    let header_len = u64::from_le_bytes(len);
+    if header_len > MAX_HEADER_BYTES || 8 + header_len > file_len {
+        return Err(corrupt(format!("header of {} bytes does not fit in the {} byte file", header_len, file_len)));
+    }
+    let mut header = vec![0u8; header_len as usize];
+    file.read_exact(&mut header).map_err(|e| io_error(path, e))?;
+    let header: BTreeMap<String, Value> =
+        serde_json::from_slice(&header).map_err(|e| corrupt(format!("header is not valid JSON: {}", e)))?;
+
+    let data_len = file_len - 8 - header_len;
+    let mut tensors = BTreeMap::new();
+    for (name, entry) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let entry: TensorEntry =
+            serde_json::from_value(entry).map_err(|e| corrupt(format!("bad header entry for {}: {}", name, e)))?;
+        if entry.data_offsets.1 > data_len {
+            return Err(corrupt(format!(
+                "tensor {} ends at byte {} of {} bytes of data; the file is truncated",
+                name, entry.data_offsets.1, data_len
+            )));
+        }
+        tensors.insert(name, entry.dtype);
+    }
+    for name in required {
+        match tensors.get(name) {
+            None => return Err(corrupt(format!("missing tensor {}", name))),
+            Some(dtype) if !FLOAT_DTYPES.contains(&dtype.as_str()) => {
+                return Err(corrupt(format!("tensor {} is {}, not a float type", name, dtype)));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reads the GGUF header and checks the tensor data it describes is all
+/// in the file.
+fn check_gguf(path: &Path) -> Result<(), LlmError> {
+    let mut file = File::open(path).map_err(|e| io_error(path, e))?;
+    let file_len = file.metadata().map_err(|e| io_error(path, e))?.len();
+    let content = gguf_file::Content::read(&mut file).map_err(|e| model_load_error(path, "reading GGUF header", e))?;
+    for (name, tensor) in &content.tensor_infos {
+        let dtype = tensor.ggml_dtype;
+        let bytes = (tensor.shape.elem_count() / dtype.block_size() * dtype.type_size()) as u64;
+        let end = content.tensor_data_offset + tensor.offset + bytes;
+        if end > file_len {
+            return Err(LlmError::ModelLoad {
+                path: path.to_path_buf(),
+                reason: format!("tensor {} ends at byte {} of the {} byte file; the file is truncated", name, end, file_len),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    /// A safetensors file with one f16 scalar for each of `names`.
+    fn write_safetensors(path: &Path, names: &[String]) {
+        let header: BTreeMap<&str, Value> = names.iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let offsets = [i * 2, i * 2 + 2];
+                (name.as_str(), serde_json::json!({ "dtype": "F16", "shape": [1], "data_offsets": offsets }))
+            })
+            .collect();
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&header);
+        bytes.extend(std::iter::repeat(0x3c).take(names.len() * 2));
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn fixture(dir: &Path) -> (PathBuf, PathBuf) {
+        let model_path = dir.join("model.safetensors");
+        write_safetensors(&model_path, &tensor_names(&Architecture::default()));
+        let vocab: HashMap<String, u32> = [("<unk>".to_string(), 0), ("a".to_string(), 1)].into_iter().collect();
+        let tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("<unk>".to_string()).build().unwrap());
+        let tokenizer_path = dir.join("tokenizer.json");
+        tokenizer.save(&tokenizer_path, false).unwrap();
+        (model_path, tokenizer_path)
+    }
+
+    fn digest(path: &Path) -> String {
+        hex::encode(Sha256::digest(std::fs::read(path).unwrap()))
+    }
+
+    fn check(model_path: &Path, tokenizer_path: &Path, expected: &ArtifactHashes) -> Result<(), LlmError> {
+        verify(model_path, tokenizer_path, None, expected, None, &mut |_, _, _| {})
+    }
+
+    #[test]
+    fn test_good_artifacts_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let (model_path, tokenizer_path) = fixture(dir.path());
+        let expected = ArtifactHashes {
+            model_sha256: Some(digest(&model_path).to_uppercase()),
+            tokenizer_sha256: Some(digest(&tokenizer_path)),
+        };
+        let mut reports: Vec<(PathBuf, u64, u64)> = Vec::new();
+        verify(&model_path, &tokenizer_path, None, &expected, None, &mut |path, done, total| {
+            reports.push((path.to_path_buf(), done, total));
+        })
+        .unwrap();
+        for path in [&model_path, &tokenizer_path] {
+            let (_, done, total) = reports.iter().rev().find(|(reported, _, _)| reported == path).unwrap();
+            assert_eq!(done, total);
+        }
+        assert_eq!(check(&model_path, &tokenizer_path, &ArtifactHashes::default()), Ok(()));
+    }
+
+    #[test]
+    fn test_bit_flip_names_file_and_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (model_path, tokenizer_path) = fixture(dir.path());
+        let expected = ArtifactHashes { model_sha256: Some(digest(&model_path)), tokenizer_sha256: None };
+        let mut bytes = std::fs::read(&model_path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0x01;
+        std::fs::write(&model_path, bytes).unwrap();
+
+        let actual = digest(&model_path);
+        let error = check(&model_path, &tokenizer_path, &expected).unwrap_err();
+        let wanted = expected.model_sha256.clone().unwrap();
+        assert_eq!(error, LlmError::ChecksumMismatch { path: model_path.clone(), expected: wanted.clone(), actual: actual.clone() });
+        let message = error.to_string();
+        assert!(message.contains("model.safetensors") && message.contains(&wanted) && message.contains(&actual), "{}", message);
+    }
+
+    #[test]
+    fn test_truncated_and_incomplete_files_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (model_path, tokenizer_path) = fixture(dir.path());
+        let bytes = std::fs::read(&model_path).unwrap();
+        std::fs::write(&model_path, &bytes[..bytes.len() - 1]).unwrap();
+        match check(&model_path, &tokenizer_path, &ArtifactHashes::default()) {
+            Err(LlmError::ModelLoad { reason, .. }) => assert!(reason.contains("truncated"), "{}", reason),
+            other => panic!("expected ModelLoad, got {:?}", other),
+        }
+        std::fs::write(&model_path, &bytes[..100]).unwrap();
+        assert!(matches!(check(&model_path, &tokenizer_path, &ArtifactHashes::default()), Err(LlmError::ModelLoad { .. })));
+
+        let two_layers = tensor_names(&Architecture { num_hidden_layers: 2, ..Architecture::default() });
+        let mut names = two_layers.clone();
+        names.retain(|name| name != "model.layers.1.mlp.up_proj.weight");
+        write_safetensors(&model_path, &names);
+        match check_safetensors(&model_path, &two_layers) {
+            Err(LlmError::ModelLoad { reason, .. }) => assert!(reason.contains("model.layers.1.mlp.up_proj"), "{}", reason),
+            other => panic!("expected ModelLoad, got {:?}", other),
+        }
+
+        let (model_path, tokenizer_path) = fixture(dir.path());
+        let tokenizer = std::fs::read(&tokenizer_path).unwrap();
+        std::fs::write(&tokenizer_path, &tokenizer[..tokenizer.len() / 2]).unwrap();
+        assert!(matches!(
+            check(&model_path, &tokenizer_path, &ArtifactHashes::default()),
+            Err(LlmError::TokenizerLoad { .. })
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+use log::warn;
+use parking_lot::Mutex;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tokio::sync::oneshot;
+
+use super::model::{LlmError, ModelBackend, ModelInfo};
+
+pub const DEFAULT_WORKER_THREADS: usize = 1;
+
+type Job = Box<dyn FnOnce(&dyn ModelBackend) + Send>;
+
+/// What callers need to know of the model without waiting on a worker.
+#[derive(Debug)]
+struct Facts {
+    info: ModelInfo,
+    context_length: usize,
+    embedding_dim: usize,
+    bos_token: Option<u32>,
+    eos_token: Option<u32>,
+}
+
+/// Threads that own the model and run everything that touches it:
+/// tokenizing, forward passes and decoding. Async callers send jobs over a
+/// channel and await the answer, so model work never holds up the tokio
+/// runtime, and the model's threads are not shared with anything else.
+/// The threads stop once every handle is dropped.
+#[derive(Clone)]
+pub struct ModelWorker {
+    jobs: mpsc::Sender<Job>,
+    threads: usize,
+    facts: Arc<Facts>,
+    /// Most device memory the model has held, as last read after a job;
+    /// zero until the device reports any.
+    memory_high_water: Arc<AtomicU64>,
+}
+
+fn stopped() -> LlmError {
+    LlmError::InferenceFailed("model worker gave no answer".to_string())
+}
+
+impl ModelWorker {
+    /// Hands `backend` to `threads` new threads, at least one.
+    pub fn spawn(backend: Arc<dyn ModelBackend>, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let facts = Arc::new(Facts {
+            info: backend.info(),
+            context_length: backend.context_length(),
+            embedding_dim: backend.embedding_dim(),
+            bos_token: backend.bos_token(),
+            eos_token: backend.eos_token(),
+        });
+        let memory_high_water = Arc::new(AtomicU64::new(backend.memory_high_water().unwrap_or(0)));
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..threads {
+            let (backend, receiver, memory) = (Arc::clone(&backend), Arc::clone(&receiver), Arc::clone(&memory_high_water));
+            thread::Builder::new()
+                .name(format!("dadbs-llm-{}", index))
+                .spawn(move || loop {
+                    let job = match receiver.lock().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    // A panicking job loses its own answer, not the thread.
+                    if panic::catch_unwind(AssertUnwindSafe(|| job(backend.as_ref()))).is_err() {
+                        warn!("Model job panicked");
+                    }
+                    if let Some(bytes) = backend.memory_high_water() {
+                        memory.fetch_max(bytes, Ordering::Relaxed);
+                    }
+                })
+                .expect("failed to spawn a model worker thread");
+        }
+        ModelWorker { jobs, threads, facts, memory_high_water }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Runs `work` on a worker thread and waits for it without blocking
+    /// the runtime.
+    pub(super) async fn run<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&dyn ModelBackend) -> Result<T, LlmError> + Send + 'static,
+    ) -> Result<T, LlmError> {
+        let (answer, receiver) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |backend| {
+                let _ = answer.send(work(backend));
+            }))
+            .map_err(|_| stopped())?;
+        receiver.await.map_err(|_| stopped())?
+    }
+
+    /// `run`, blocking the calling thread; not for use on the runtime, nor
+    /// from inside a job.
+    pub(super) fn run_blocking<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&dyn ModelBackend) -> Result<T, LlmError> + Send + 'static,
+    ) -> Result<T, LlmError> {
+        let (answer, receiver) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |backend| {
+                let _ = answer.send(work(backend));
+            }))
+            .map_err(|_| stopped())?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+
+    pub fn info(&self) -> ModelInfo {
+        self.facts.info.clone()
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.facts.context_length
+    }
+
+    pub fn embedding_dim(&self) -> usize {
+        self.facts.embedding_dim
+    }
+
+    pub fn bos_token(&self) -> Option<u32> {
+        self.facts.bos_token
+    }
+
+    pub fn eos_token(&self) -> Option<u32> {
+        self.facts.eos_token
+    }
+
+    pub fn memory_high_water(&self) -> Option<u64> {
+        match self.memory_high_water.load(Ordering::Relaxed) {
+            0 => None,
+            bytes => Some(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{GenerateParams, KvCache, LightLLM};
+    use futures::StreamExt;
+    use std::time::{Duration, Instant};
+
+    /// Every forward pass takes a few milliseconds of blocking work, and
+    /// the completion never ends by itself.
+    struct Slow;
+
+    impl ModelBackend for Slow {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            if text == "panic" {
+                panic!("bad prompt");
+            }
+            Ok(text.bytes().map(|_| 1).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok("x".repeat(tokens.len()))
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            std::thread::sleep(Duration::from_millis(4));
+            Ok(vec![0.0, 1.0])
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timers_fire_during_long_generation() {
+        let model = LightLLM::with_backend(Arc::new(Slow));
+        let generation = tokio::spawn(model.generate_stream("hello", GenerateParams::new(100).with_temperature(0.0)).count());
+        // All on the test's single runtime thread, which the model must not hold.
+        let mut ticks = 0;
+        while !generation.is_finished() {
+            let started = Instant::now();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert!(started.elapsed() < Duration::from_millis(40), "timer fired {:?} late", started.elapsed());
+            ticks += 1;
+        }
+        assert_eq!(generation.await.unwrap(), 101);
+        assert!(ticks >= 20, "{}", ticks);
+    }
+
+    #[tokio::test]
+    async fn test_worker_threads_run_jobs_side_by_side_and_survive_panics() {
+        let worker = ModelWorker::spawn(Arc::new(Slow), 2);
+        let started = Instant::now();
+        let sleeps = (0..2).map(|_| {
+            worker.run(|_| {
+                std::thread::sleep(Duration::from_millis(100));
+                Ok(())
+            })
+        });
+        futures::future::try_join_all(sleeps).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(190), "{:?}", started.elapsed());
+
+        assert_eq!(worker.run(|backend| backend.encode("panic")).await, Err(stopped()));
+        assert_eq!(worker.run(|backend| backend.encode("ok")).await, Ok(vec![1, 1]));
+        assert_eq!(worker.run_blocking(|backend| backend.decode(&[1])), Ok("x".to_string()));
+    }
+}
@@ -0,0 +1,643 @@
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, State as Shared};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use log::{error, info, warn, LevelFilter};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::api_keys::{ApiKeyLimits, ApiKeyRecord};
+use super::backup::{BackupManifest, BackupProgress};
+use super::control::{ControlError, HaltStatus};
+use super::handover::DEFAULT_HANDOVER_DELAY;
+use super::keystore::{Keystore, KeystoreError};
+use super::rate_limit::BucketConfig;
+use super::rpc::{
+    error_response, parse_params, to_value, Request, RpcError, RpcServer, INTERNAL_ERROR, INVALID_PARAMS,
+    INVALID_REQUEST, METHODS, METHOD_NOT_FOUND, PARSE_ERROR,
+};
+use super::snapshot::SnapshotManifest;
+use super::state::State;
+use super::storage::{AuditRecord, IndexKind};
+use super::webhooks::{DeliveryStats, WebhookEndpoint, WebhookEndpointInfo, WebhookError, Webhooks};
+#[cfg(feature = "llm")]
+use crate::llm::{InstalledModel, ModelManager};
+
+pub const DEFAULT_ADMIN_TOKEN_ENV: &str = "DADBS_ADMIN_TOKEN";
+/// Snapshot directory inside `storage_path` unless `snapshot_dir` is set.
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+/// The admin API answers at `/admin` on the RPC listener. The bearer token
+/// is never kept in the config file itself: it is read from `token_file`,
+/// or else the `token_env` environment variable. Without either the admin
+/// API refuses every request.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub token_file: Option<String>,
+    #[serde(default = "default_token_env")]
+    pub token_env: String,
+    /// Where `admin_trigger_snapshot` writes archives.
+    #[serde(default)]
+    pub snapshot_dir: Option<String>,
+}
+
+fn default_token_env() -> String {
+    DEFAULT_ADMIN_TOKEN_ENV.to_string()
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig { token_file: None, token_env: default_token_env(), snapshot_dir: None }
+    }
+}
+
+impl AdminConfig {
+    /// The configured token, or `None` when the admin API is disabled.
+    pub fn token(&self) -> std::io::Result<Option<AdminToken>> {
+        let token = match &self.token_file {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => std::env::var(&self.token_env).ok(),
+        };
+        Ok(token.map(|token| token.trim().to_string()).filter(|token| !token.is_empty()).map(AdminToken))
+    }
+
+    pub fn snapshot_dir(&self, storage_path: &str) -> PathBuf {
+        match &self.snapshot_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(storage_path).join(DEFAULT_SNAPSHOT_DIR),
+        }
+    }
+}
+
+/// A bearer token; never printed.
+#[derive(Clone)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        AdminToken(token.into())
+    }
+
+    /// The `Authorization` header value presenting this token.
+    pub fn bearer(&self) -> String {
+        format!("Bearer {}", self.0)
+    }
+
+    /// Compares digests so the time taken says nothing about the token.
+    fn matches(&self, presented: &str) -> bool {
+        let (expected, presented) = (Sha256::digest(self.0.as_bytes()), Sha256::digest(presented.as_bytes()));
+        expected.iter().zip(presented.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminToken(..)")
+    }
+}
+
+/// Privileged methods of the RPC server, each recorded in the storage audit
+/// trail whether or not it succeeds.
+pub struct AdminApi {
+    token: AdminToken,
+    snapshot_dir: PathBuf,
+    keystore: Option<Arc<Keystore>>,
+    /// The last backup started, which `admin_backup_status` reports on.
+    backup: Arc<Mutex<Option<BackupStatus>>>,
+}
+
+impl AdminApi {
+    pub fn new(token: AdminToken, snapshot_dir: impl Into<PathBuf>) -> Self {
+        AdminApi { token, snapshot_dir: snapshot_dir.into(), keystore: None, backup: Arc::default() }
+    }
+
+    /// The keystore `admin_rotate_identity` rotates keys in.
+    pub fn with_keystore(mut self, keystore: Arc<Keystore>) -> Self {
+        self.keystore = Some(keystore);
+        self
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map_or(false, |presented| self.token.matches(presented))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BanPeerParams {
+    pub addr: String,
+    /// Defaults to the network's ban duration.
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerParams {
+    pub addr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ResumeParams {
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogLevelParams {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub level: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReindexParams {
+    /// Defaults to every index.
+    #[serde(default)]
+    pub kinds: Vec<IndexKind>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupParams {
+    /// Directory on the node's host to write the backup to; must be empty
+    /// or missing.
+    pub dest: String,
+}
+
+/// The passphrase is left out of the audit trail.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RotateIdentityParams {
+    /// Name of the identity key in the keystore.
+    pub key: String,
+    pub passphrase: String,
+    /// Defaults to `DEFAULT_HANDOVER_DELAY` blocks past the finalized height.
+    #[serde(default)]
+    pub valid_from_height: Option<u64>,
+}
+
+/// Limits left out default to the `[api_keys]` rate, every method and no
+/// token budget.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreateApiKeyParams {
+    pub owner: String,
+    #[serde(default)]
+    pub rate: Option<BucketConfig>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub daily_llm_tokens: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyIdParams {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookIdParams {
+    pub id: String,
+}
+
+/// The newest `limit` dead letters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeadLettersParams {
+    #[serde(default = "default_dead_letters_limit")]
+    pub limit: usize,
+}
+
+fn default_dead_letters_limit() -> usize {
+    100
+}
+
+impl Default for DeadLettersParams {
+    fn default() -> Self {
+        DeadLettersParams { limit: default_dead_letters_limit() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BanResult {
+    pub addr: String,
+    pub duration_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnbanResult {
+    pub addr: String,
+    /// Whether the peer was banned.
+    pub unbanned: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusStatusResult {
+    pub halted: Option<HaltStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogLevelResult {
+    pub level: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RotateIdentityResult {
+    pub old_pub: String,
+    pub new_pub: String,
+    pub valid_from_height: u64,
+    /// Peers the handover was first gossiped to.
+    pub peers: usize,
+}
+
+/// The key is shown only here; the node keeps just its digest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreateApiKeyResult {
+    pub key: String,
+    pub record: ApiKeyRecord,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookResult {
+    #[serde(flatten)]
+    pub endpoint: WebhookEndpointInfo,
+    pub stats: DeliveryStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RemoveWebhookResult {
+    pub id: String,
+    /// Whether there was such an endpoint.
+    pub removed: bool,
+}
+
+/// A backup started by `admin_backup`: running until `manifest` or
+/// `error` is set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupStatus {
+    pub dest: String,
+    pub running: bool,
+    pub progress: Option<BackupProgress>,
+    pub manifest: Option<BackupManifest>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotResult {
+    pub path: String,
+    pub manifest: SnapshotManifest,
+}
+
+#[cfg(feature = "llm")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModelVersionParams {
+    pub version: String,
+}
+
+#[cfg(feature = "llm")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModelListResult {
+    /// `None` when nothing is serving, or the model the config names is.
+    pub serving: Option<String>,
+    pub models: Vec<InstalledModel>,
+}
+
+#[cfg(feature = "llm")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FlushCacheResult {
+    /// Cached completions dropped.
+    pub flushed: usize,
+}
+
+impl From<ControlError> for RpcError {
+    fn from(e: ControlError) -> Self {
+        RpcError::new(INVALID_PARAMS, e.to_string())
+    }
+}
+
+impl From<WebhookError> for RpcError {
+    fn from(e: WebhookError) -> Self {
+        match e {
+            WebhookError::InvalidEndpoint(_) => RpcError::invalid_params(e.to_string()),
+            e => internal(e),
+        }
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, RpcError> {
+    addr.parse().map_err(|e| RpcError::invalid_params(format!("bad address {}: {}", addr, e)))
+}
+
+fn internal(e: impl fmt::Display) -> RpcError {
+    RpcError::new(INTERNAL_ERROR, e.to_string())
+}
+
+fn keystore_error(e: KeystoreError) -> RpcError {
+    match e {
+        KeystoreError::NotFound(_) | KeystoreError::WrongPassphrase(_) | KeystoreError::InvalidName(_)
+        | KeystoreError::DuplicateName(_) => RpcError::invalid_params(e.to_string()),
+        e => internal(e),
+    }
+}
+
+/// `params` as recorded in the audit trail, without passphrases or
+/// webhook secrets.
+fn redacted(mut params: Value) -> Value {
+    for field in ["passphrase", "secret"] {
+        if let Some(value) = params.get_mut(field) {
+            *value = Value::String("..".to_string());
+        }
+    }
+    params
+}
+
+fn webhooks(server: &RpcServer) -> Result<Arc<Webhooks>, RpcError> {
+    server.webhooks().ok_or_else(|| internal("node has no webhooks"))
+}
+
+fn webhook_results(webhooks: &Webhooks) -> Vec<WebhookResult> {
+    let stats = webhooks.metrics().snapshot();
+    webhooks.endpoints()
+        .into_iter()
+        .map(|endpoint| WebhookResult { stats: stats.get(&endpoint.id).copied().unwrap_or_default(), endpoint })
+        .collect()
+}
+
+#[cfg(feature = "llm")]
+fn models(server: &RpcServer) -> Result<Arc<ModelManager>, RpcError> {
+    server.models().ok_or_else(|| internal("node has no model registry"))
+}
+
+impl RpcServer {
+    async fn admin_dispatch(&self, admin: &AdminApi, method: &str, params: Value) -> Result<Value, RpcError> {
+        let context = &self.context;
+        match method {
+            "admin_ban_peer" => {
+                let BanPeerParams { addr, duration_secs, reason } = parse_params(params)?;
+                let network = context.network.as_ref().ok_or_else(|| internal("node has no network"))?;
+                let duration = duration_secs.map_or(network.ban_duration(), Duration::from_secs);
+                let reason = reason.unwrap_or_else(|| "banned by operator".to_string());
+                network.ban_peer(parse_addr(&addr)?, duration, &reason).map_err(internal)?;
+                to_value(BanResult { addr, duration_secs: duration.as_secs() })
+            }
+            "admin_unban_peer" => {
+                let PeerParams { addr } = parse_params(params)?;
+                let network = context.network.as_ref().ok_or_else(|| internal("node has no network"))?;
+                let unbanned = network.unban_peer(&parse_addr(&addr)?).map_err(internal)?;
+                to_value(UnbanResult { addr, unbanned })
+            }
+            "admin_pause_consensus" => {
+                let consensus = context.consensus.lock().await;
+                consensus.control().pause();
+                to_value(ConsensusStatusResult { halted: consensus.halt_status() })
+            }
+            "admin_resume_consensus" => {
+                let ResumeParams { reason } = match params {
+                    Value::Null => ResumeParams::default(),
+                    params => parse_params(params)?,
+                };
+                let consensus = context.consensus.lock().await;
+                consensus.control().resume(&reason)?;
+                to_value(ConsensusStatusResult { halted: consensus.halt_status() })
+            }
+            "admin_set_log_level" => {
+                let LogLevelParams { level } = parse_params(params)?;
+                let filter = LevelFilter::from_str(&level)
+                    .map_err(|_| RpcError::invalid_params(format!("unknown log level {}", level)))?;
+                log::set_max_level(filter);
+                to_value(LogLevelResult { level: filter.to_string().to_lowercase() })
+            }
+            "admin_trigger_snapshot" => {
+                let state = context.state.read();
+                let height = state.height();
+                let path = admin.snapshot_dir.join(format!("snapshot-{}.snap", height));
+                let manifest = context.storage.export_snapshot(&path, height, &state, &context.chain_id)
+                    .map_err(internal)?;
+                to_value(SnapshotResult { path: path.display().to_string(), manifest })
+            }
+            "admin_reindex" => {
+                let ReindexParams { mut kinds } = match params {
+                    Value::Null => ReindexParams::default(),
+                    params => parse_params(params)?,
+                };
+                if kinds.is_empty() {
+                    kinds = IndexKind::ALL.to_vec();
+                }
+                let storage = Arc::clone(&context.storage);
+                let report = tokio::task::spawn_blocking(move || {
+                    storage.reindex(&kinds, |progress| {
+                        info!("Reindexed up to height {} of {}", progress.height, progress.tip);
+                    })
+                })
+                .await
+                .map_err(internal)?
+                .map_err(internal)?;
+                to_value(report)
+            }
+            "admin_backup" => {
+                let BackupParams { dest } = parse_params(params)?;
+                let status = BackupStatus {
+                    dest: dest.clone(),
+                    running: true,
+                    progress: None,
+                    manifest: None,
+                    error: None,
+                };
+                {
+                    let mut current = admin.backup.lock();
+                    if current.as_ref().map_or(false, |status| status.running) {
+                        return Err(RpcError::invalid_params("a backup is already running"));
+                    }
+                    *current = Some(status.clone());
+                }
+                // A copy, so block execution is not held up for the backup.
+                let state = context.state.read().export().and_then(|bytes| State::decode(&bytes));
+                let state = match state {
+                    Ok(state) => state,
+                    Err(e) => {
+                        *admin.backup.lock() = None;
+                        return Err(internal(e));
+                    }
+                };
+                let (storage, tracked) = (Arc::clone(&context.storage), Arc::clone(&admin.backup));
+                tokio::task::spawn_blocking(move || {
+                    let result = storage.backup(Path::new(&dest), &state, |progress| {
+                        if let Some(status) = tracked.lock().as_mut() {
+                            status.progress = Some(progress);
+                        }
+                    });
+                    if let Err(e) = &result {
+                        error!("Backup to {} failed: {}", dest, e);
+                    }
+                    if let Some(status) = tracked.lock().as_mut() {
+                        status.running = false;
+                        match result {
+                            Ok(manifest) => status.manifest = Some(manifest),
+                            Err(e) => status.error = Some(e.to_string()),
+                        }
+                    }
+                });
+                to_value(status)
+            }
+            "admin_backup_status" => to_value(admin.backup.lock().clone()),
+            "admin_rotate_identity" => {
+                let RotateIdentityParams { key, passphrase, valid_from_height } = parse_params(params)?;
+                let keystore = admin.keystore.clone().ok_or_else(|| internal("node has no keystore"))?;
+                let network = context.network.as_ref().ok_or_else(|| internal("node has no network"))?;
+                let finalized = network.handovers().finalized_height();
+                let valid_from_height = valid_from_height.unwrap_or(finalized + DEFAULT_HANDOVER_DELAY);
+                if valid_from_height <= finalized {
+                    return Err(RpcError::invalid_params(format!(
+                        "valid_from_height must be above the finalized height {}", finalized
+                    )));
+                }
+                let chain_id = context.chain_id.clone();
+                let rotate = move || keystore.rotate(&key, &passphrase, &chain_id, valid_from_height);
+                let (handover, keypair) = tokio::task::spawn_blocking(rotate)
+                    .await
+                    .map_err(internal)?
+                    .map_err(keystore_error)?;
+                context.storage.put_handover(&handover).map_err(internal)?;
+                let peers = network.hand_over(handover.clone(), Arc::new(keypair)).map_err(internal)?;
+                to_value(RotateIdentityResult {
+                    old_pub: handover.old_pub.to_string(),
+                    new_pub: handover.new_pub.to_string(),
+                    valid_from_height,
+                    peers,
+                })
+            }
+            "admin_create_api_key" => {
+                let CreateApiKeyParams { owner, rate, methods, daily_llm_tokens } = parse_params(params)?;
+                let keys = self.api_keys().ok_or_else(|| internal("node has no API keys"))?;
+                if owner.is_empty() {
+                    return Err(RpcError::invalid_params("owner must not be empty"));
+                }
+                if let Some(method) = methods.iter().find(|method| !METHODS.contains(&method.as_str())) {
+                    return Err(RpcError::invalid_params(format!("unknown method {}", method)));
+                }
+                let limits = ApiKeyLimits { rate: rate.unwrap_or(keys.default_rate()), methods, daily_llm_tokens };
+                let (key, record) = keys.create(&owner, limits, chrono::Utc::now().timestamp_millis())?;
+                to_value(CreateApiKeyResult { key, record })
+            }
+            "admin_revoke_api_key" => {
+                let ApiKeyIdParams { id } = parse_params(params)?;
+                let keys = self.api_keys().ok_or_else(|| internal("node has no API keys"))?;
+                let record = keys.revoke(&id, chrono::Utc::now().timestamp_millis())?
+                    .ok_or_else(|| RpcError::invalid_params(format!("no API key {}", id)))?;
+                to_value(record)
+            }
+            "admin_list_api_keys" => {
+                let keys = self.api_keys().ok_or_else(|| internal("node has no API keys"))?;
+                to_value(keys.list()?)
+            }
+            "admin_add_webhook" => {
+                let endpoint: WebhookEndpoint = parse_params(params)?;
+                let webhooks = webhooks(self)?;
+                let info = endpoint.info();
+                webhooks.add(endpoint)?;
+                to_value(info)
+            }
+            "admin_remove_webhook" => {
+                let WebhookIdParams { id } = parse_params(params)?;
+                let removed = webhooks(self)?.remove(&id)?;
+                to_value(RemoveWebhookResult { id, removed })
+            }
+            "admin_list_webhooks" => to_value(webhook_results(&webhooks(self)?)),
+            "admin_webhook_dead_letters" => {
+                let DeadLettersParams { limit } = match params {
+                    Value::Null => DeadLettersParams::default(),
+                    params => parse_params(params)?,
+                };
+                to_value(webhooks(self)?.dead_letters(limit)?)
+            }
+            #[cfg(feature = "llm")]
+            "admin_list_models" => {
+                let models = models(self)?;
+                to_value(ModelListResult { serving: models.serving_version(), models: models.list_models()? })
+            }
+            #[cfg(feature = "llm")]
+            "admin_activate_model" => {
+                let ModelVersionParams { version } = parse_params(params)?;
+                to_value(models(self)?.activate(&version).await?)
+            }
+            #[cfg(feature = "llm")]
+            "admin_remove_model" => {
+                let ModelVersionParams { version } = parse_params(params)?;
+                to_value(models(self)?.remove(&version).await?)
+            }
+            #[cfg(feature = "llm")]
+            "admin_flush_llm_cache" => {
+                let cache = models(self)?.cache().ok_or_else(|| internal("inference cache is not enabled"))?;
+                to_value(FlushCacheResult { flushed: cache.flush() })
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        }
+    }
+
+    /// Answers one admin request from `caller` and records it in the audit
+    /// trail. Batches are refused so every call is audited on its own.
+    async fn handle_admin_body(&self, admin: &AdminApi, caller: SocketAddr, body: &[u8]) -> Value {
+        let value: Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => return error_response(Value::Null, RpcError::new(PARSE_ERROR, format!("Parse error: {}", e))),
+        };
+        if value.is_array() {
+            return error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Admin requests cannot be batched"));
+        }
+        let Request { jsonrpc, method, params, id, .. } = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, RpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e))),
+        };
+        let id = id.unwrap_or(Value::Null);
+        if jsonrpc != "2.0" {
+            return error_response(id, RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+        }
+
+        let result = self.admin_dispatch(admin, &method, params.clone()).await;
+        let outcome = match &result {
+            Ok(_) => "ok".to_string(),
+            Err(error) => error.to_string(),
+        };
+        info!("Admin {} from {}: {}", method, caller, outcome);
+        let record = AuditRecord {
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            caller: caller.to_string(),
+            method,
+            params: redacted(params),
+            outcome,
+        };
+        if let Err(e) = self.context.storage.append_audit(&record) {
+            warn!("Failed to record admin call in the audit trail: {}", e);
+        }
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => error_response(id, error),
+        }
+    }
+}
+
+/// Refused requests learn nothing about which methods exist.
+pub(crate) async fn handle_admin(
+    Shared(server): Shared<Arc<RpcServer>>,
+    ConnectInfo(caller): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> HttpResponse {
+    let admin = match server.admin() {
+        Some(admin) if admin.authorized(&headers) => admin,
+        _ => {
+            warn!("Refused unauthenticated admin request from {}", caller);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    let response = server.handle_admin_body(admin, caller, &body).await;
+    ([(header::CONTENT_TYPE, "application/json")], response.to_string()).into_response()
+}
@@ -0,0 +1,438 @@
+//! API keys for the RPC server. Each key has its own request rate, an
+//! optional allowlist of methods and an optional daily budget of LLM
+//! tokens, in place of the per-IP limits keyless clients get.
+//!
+//! Only a SHA-256 digest of a key is stored; the key itself is shown once,
+//! when it is created. Records are cached and read again from storage once
+//! an entry is `cache_refresh_ms` old, so a key revoked by another process
+//! sharing the storage stops working within that interval. Usage is
+//! counted in the cache and written back on each refresh.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::info;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use super::chain_stats::DAY_MS;
+use super::rate_limit::{BucketConfig, RateLimited, TokenBucket};
+use super::storage::{Storage, StorageError};
+
+/// HTTP header, and WebSocket upgrade header, carrying a key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const DEFAULT_CACHE_REFRESH_MS: u64 = 5_000;
+const KEY_PREFIX: &str = "dadbs_";
+const RECORD_PREFIX: u8 = b'k';
+const USAGE_PREFIX: u8 = b'u';
+
+/// The `[api_keys]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiKeysConfig {
+    /// Cached keys are read from storage again once this old, which bounds
+    /// how long a revoked key keeps working.
+    #[serde(default = "default_cache_refresh_ms")]
+    pub cache_refresh_ms: u64,
+    /// Request rate of keys created without one.
+    #[serde(default = "default_rate")]
+    pub default_rate: BucketConfig,
+}
+
+fn default_cache_refresh_ms() -> u64 {
+    DEFAULT_CACHE_REFRESH_MS
+}
+
+fn default_rate() -> BucketConfig {
+    BucketConfig::new(200.0, 400)
+}
+
+impl Default for ApiKeysConfig {
+    fn default() -> Self {
+        ApiKeysConfig { cache_refresh_ms: DEFAULT_CACHE_REFRESH_MS, default_rate: default_rate() }
+    }
+}
+
+impl ApiKeysConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cache_refresh_ms == 0 {
+            return Err("api_keys.cache_refresh_ms must be at least 1".to_string());
+        }
+        check_rate(&self.default_rate).map_err(|e| format!("api_keys.default_rate: {}", e))
+    }
+}
+
+fn check_rate(rate: &BucketConfig) -> Result<(), String> {
+    if !rate.rate_per_sec.is_finite() || rate.rate_per_sec <= 0.0 || rate.burst == 0 {
+        return Err("needs a positive rate_per_sec and burst".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum ApiKeyError {
+    #[error("Unknown or revoked API key")]
+    Invalid,
+    #[error("This API key may not call {0}")]
+    MethodNotAllowed(String),
+    #[error("Rate limited")]
+    RateLimited(RateLimited),
+    #[error("Daily budget of {budget} LLM tokens is spent; it resets at {resets_at_ms}")]
+    BudgetExhausted { budget: u64, resets_at_ms: i64 },
+    #[error("Invalid limits: {0}")]
+    InvalidLimits(String),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// What a key may do.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyLimits {
+    pub rate: BucketConfig,
+    /// Methods the key may call; every method if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Prompt and completion tokens `llm_*` calls may use per UTC day.
+    #[serde(default)]
+    pub daily_llm_tokens: Option<u64>,
+}
+
+impl ApiKeyLimits {
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|allowed| allowed == method)
+    }
+}
+
+/// A key as stored; the key itself is not.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyRecord {
+    /// Hex of the first eight bytes of the key's digest.
+    pub id: String,
+    #[serde(skip)]
+    pub key_hash: [u8; 32],
+    pub owner: String,
+    pub created_ms: i64,
+    #[serde(default)]
+    pub revoked_ms: Option<i64>,
+    pub limits: ApiKeyLimits,
+}
+
+/// A key's use on one UTC day.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApiKeyUsage {
+    pub day: i64,
+    pub requests: u64,
+    pub llm_tokens: u64,
+}
+
+impl ApiKeyUsage {
+    /// Starts counting afresh on a new day.
+    fn roll(&mut self, day: i64) {
+        if self.day != day {
+            *self = ApiKeyUsage { day, ..ApiKeyUsage::default() };
+        }
+    }
+}
+
+fn hash_key(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn key_id(key_hash: &[u8; 32]) -> String {
+    hex::encode(&key_hash[..8])
+}
+
+pub(super) fn record_key(id: &str) -> Vec<u8> {
+    [&[RECORD_PREFIX][..], id.as_bytes()].concat()
+}
+
+pub(super) fn record_range() -> (Vec<u8>, Vec<u8>) {
+    (vec![RECORD_PREFIX], vec![RECORD_PREFIX + 1])
+}
+
+pub(super) fn usage_key(id: &str, day: i64) -> Vec<u8> {
+    [&[USAGE_PREFIX][..], id.as_bytes(), &(day as u64).to_be_bytes()].concat()
+}
+
+fn day_of(ms: i64) -> i64 {
+    ms.div_euclid(DAY_MS)
+}
+
+struct CachedKey {
+    record: ApiKeyRecord,
+    loaded: Instant,
+    bucket: TokenBucket,
+    usage: ApiKeyUsage,
+    /// Whether `usage` has changed since it was written.
+    dirty: bool,
+}
+
+/// The keys in storage, with a cache of those recently presented.
+pub struct ApiKeys {
+    storage: Arc<Storage>,
+    refresh: Duration,
+    default_rate: BucketConfig,
+    cache: Mutex<HashMap<String, CachedKey>>,
+}
+
+impl ApiKeys {
+    pub fn new(config: &ApiKeysConfig, storage: Arc<Storage>) -> Self {
+        ApiKeys {
+            storage,
+            refresh: Duration::from_millis(config.cache_refresh_ms),
+            default_rate: config.default_rate,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The rate of keys created without one.
+    pub fn default_rate(&self) -> BucketConfig {
+        self.default_rate
+    }
+
+    /// Stores a new key for `owner`, returning the key, which is not kept,
+    /// and its record.
+    pub fn create(
+        &self,
+        owner: &str,
+        limits: ApiKeyLimits,
+        now_ms: i64,
+    ) -> Result<(String, ApiKeyRecord), ApiKeyError> {
+        check_rate(&limits.rate).map_err(ApiKeyError::InvalidLimits)?;
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let key = format!("{}{}", KEY_PREFIX, hex::encode(secret));
+        let key_hash = hash_key(&key);
+        let record = ApiKeyRecord {
+            id: key_id(&key_hash),
+            key_hash,
+            owner: owner.to_string(),
+            created_ms: now_ms,
+            revoked_ms: None,
+            limits,
+        };
+        self.storage.put_api_key(&record)?;
+        info!("Created API key {} for {}", record.id, record.owner);
+        Ok((key, record))
+    }
+
+    /// Marks key `id` revoked, or returns `None` if there is no such key.
+    /// This node refuses it at once; others sharing the storage once their
+    /// cache entry is refreshed.
+    pub fn revoke(&self, id: &str, now_ms: i64) -> Result<Option<ApiKeyRecord>, ApiKeyError> {
+        let Some(mut record) = self.storage.get_api_key(id)? else {
+            return Ok(None);
+        };
+        if record.revoked_ms.is_none() {
+            record.revoked_ms = Some(now_ms);
+            self.storage.put_api_key(&record)?;
+            info!("Revoked API key {} of {}", record.id, record.owner);
+        }
+        if let Some(cached) = self.cache.lock().remove(id) {
+            if cached.dirty {
+                self.storage.put_api_key_usage(id, &cached.usage)?;
+            }
+        }
+        Ok(Some(record))
+    }
+
+    /// Every key, revoked ones included, by id.
+    pub fn list(&self) -> Result<Vec<ApiKeyRecord>, ApiKeyError> {
+        Ok(self.storage.api_keys()?)
+    }
+
+    /// Takes a request to `method` presented with `key` out of its limits,
+    /// returning the key's id.
+    pub fn authorize(&self, key: &str, method: &str) -> Result<String, ApiKeyError> {
+        self.authorize_at(key, method, Instant::now(), chrono::Utc::now().timestamp_millis())
+    }
+
+    fn authorize_at(&self, key: &str, method: &str, now: Instant, now_ms: i64) -> Result<String, ApiKeyError> {
+        let key_hash = hash_key(key);
+        let id = key_id(&key_hash);
+        let day = day_of(now_ms);
+        let mut cache = self.cache.lock();
+        let stale = cache.get(&id).map_or(true, |cached| now.saturating_duration_since(cached.loaded) >= self.refresh);
+        if stale {
+            self.reload(&mut cache, &id, now, day)?;
+        }
+        let cached = cache.get_mut(&id)
+            .filter(|cached| cached.record.key_hash == key_hash)
+            .ok_or(ApiKeyError::Invalid)?;
+        if cached.record.revoked_ms.is_some() {
+            return Err(ApiKeyError::Invalid);
+        }
+        let limits = &cached.record.limits;
+        if !limits.allows(method) {
+            return Err(ApiKeyError::MethodNotAllowed(method.to_string()));
+        }
+        cached.usage.roll(day);
+        if let Some(budget) = limits.daily_llm_tokens.filter(|_| method.starts_with("llm_")) {
+            if cached.usage.llm_tokens >= budget {
+                return Err(ApiKeyError::BudgetExhausted { budget, resets_at_ms: (day + 1) * DAY_MS });
+            }
+        }
+        cached.bucket.refill(&limits.rate, now);
+        let retry_after = cached.bucket.wait(&limits.rate);
+        if !retry_after.is_zero() {
+            return Err(ApiKeyError::RateLimited(RateLimited { retry_after }));
+        }
+        cached.bucket.tokens -= 1.0;
+        cached.usage.requests += 1;
+        cached.dirty = true;
+        Ok(id)
+    }
+
+    /// Reads key `id` from storage again, writing back its usage, or drops
+    /// it from the cache if it is gone.
+    fn reload(
+        &self,
+        cache: &mut HashMap<String, CachedKey>,
+        id: &str,
+        now: Instant,
+        day: i64,
+    ) -> Result<(), ApiKeyError> {
+        let Some(record) = self.storage.get_api_key(id)? else {
+            cache.remove(id);
+            return Ok(());
+        };
+        match cache.get_mut(id) {
+            Some(cached) => {
+                if cached.dirty {
+                    self.storage.put_api_key_usage(id, &cached.usage)?;
+                    cached.dirty = false;
+                }
+                cached.record = record;
+                cached.loaded = now;
+            }
+            None => {
+                let usage = self.storage.api_key_usage(id, day)?;
+                let bucket = TokenBucket::full(&record.limits.rate, now);
+                cache.insert(id.to_string(), CachedKey { record, loaded: now, bucket, usage, dirty: false });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts `tokens` used by an `llm_*` call against key `id`'s budget.
+    /// Written at once, as budgets are what keys are billed by.
+    pub fn charge_tokens(&self, id: &str, tokens: u64) -> Result<(), ApiKeyError> {
+        self.charge_tokens_at(id, tokens, chrono::Utc::now().timestamp_millis())
+    }
+
+    fn charge_tokens_at(&self, id: &str, tokens: u64, now_ms: i64) -> Result<(), ApiKeyError> {
+        let mut cache = self.cache.lock();
+        if let Some(cached) = cache.get_mut(id) {
+            cached.usage.roll(day_of(now_ms));
+            cached.usage.llm_tokens = cached.usage.llm_tokens.saturating_add(tokens);
+            self.storage.put_api_key_usage(id, &cached.usage)?;
+            cached.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Key `id`'s record and its use today, as counted by this node.
+    pub fn usage(&self, id: &str) -> Result<Option<(ApiKeyRecord, ApiKeyUsage)>, ApiKeyError> {
+        let day = day_of(chrono::Utc::now().timestamp_millis());
+        if let Some(cached) = self.cache.lock().get_mut(id) {
+            cached.usage.roll(day);
+            return Ok(Some((cached.record.clone(), cached.usage)));
+        }
+        let Some(record) = self.storage.get_api_key(id)? else {
+            return Ok(None);
+        };
+        Ok(Some((record, self.storage.api_key_usage(id, day)?)))
+    }
+
+    /// Writes back the usage counted since each key was last refreshed.
+    pub fn flush(&self) -> Result<(), ApiKeyError> {
+        for (id, cached) in self.cache.lock().iter_mut().filter(|(_, cached)| cached.dirty) {
+            self.storage.put_api_key_usage(id, &cached.usage)?;
+            cached.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rate_per_sec: f64, burst: u32) -> ApiKeyLimits {
+        ApiKeyLimits { rate: BucketConfig::new(rate_per_sec, burst), methods: Vec::new(), daily_llm_tokens: None }
+    }
+
+    #[test]
+    fn test_keys_are_stored_hashed_and_hold_their_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = ApiKeys::new(&ApiKeysConfig::default(), Arc::new(Storage::open(dir.path()).unwrap()));
+        let now_ms = 3 * DAY_MS;
+        let only_balances = ApiKeyLimits { methods: vec!["get_balance".to_string()], ..limits(1.0, 2) };
+        let (key, record) = keys.create("alice", only_balances, now_ms).unwrap();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(record.key_hash, hash_key(&key));
+        assert_eq!(keys.list().unwrap(), vec![record.clone()]);
+
+        let start = Instant::now();
+        assert_eq!(keys.authorize_at(&key, "get_balance", start, now_ms).unwrap(), record.id);
+        assert!(matches!(keys.authorize_at(&key, "get_nonce", start, now_ms), Err(ApiKeyError::MethodNotAllowed(_))));
+        keys.authorize_at(&key, "get_balance", start, now_ms).unwrap();
+        let limited = keys.authorize_at(&key, "get_balance", start, now_ms).unwrap_err();
+        let second = Duration::from_secs(1);
+        assert!(matches!(limited, ApiKeyError::RateLimited(RateLimited { retry_after }) if retry_after == second));
+        assert!(matches!(keys.authorize_at("dadbs_guess", "get_balance", start, now_ms), Err(ApiKeyError::Invalid)));
+        assert_eq!(keys.usage(&record.id).unwrap().unwrap().1.requests, 2);
+    }
+
+    #[test]
+    fn test_token_budget_resets_daily_and_usage_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ApiKeysConfig::default();
+        let now_ms = 7 * DAY_MS + 1_000;
+        let start = Instant::now();
+        let (key, id) = {
+            let keys = ApiKeys::new(&config, Arc::new(Storage::open(dir.path()).unwrap()));
+            let budgeted = ApiKeyLimits { daily_llm_tokens: Some(100), ..limits(100.0, 100) };
+            let (key, record) = keys.create("bob", budgeted, now_ms).unwrap();
+            keys.authorize_at(&key, "llm_generate", start, now_ms).unwrap();
+            keys.charge_tokens_at(&record.id, 120, now_ms).unwrap();
+            let spent = keys.authorize_at(&key, "llm_generate", start, now_ms).unwrap_err();
+            let ApiKeyError::BudgetExhausted { budget, resets_at_ms } = spent else { panic!("{}", spent) };
+            assert_eq!((budget, resets_at_ms), (100, 8 * DAY_MS));
+            // Other methods are not held to the budget.
+            keys.authorize_at(&key, "get_balance", start, now_ms).unwrap();
+            keys.flush().unwrap();
+            (key, record.id)
+        }
+        let keys = ApiKeys::new(&config, Arc::new(Storage::open(dir.path()).unwrap()));
+        let spent = keys.authorize_at(&key, "llm_generate", start, now_ms);
+        assert!(matches!(spent, Err(ApiKeyError::BudgetExhausted { .. })));
+        let usage = keys.storage.api_key_usage(&id, 7).unwrap();
+        assert_eq!((usage.requests, usage.llm_tokens), (2, 120));
+        keys.authorize_at(&key, "llm_generate", start, 8 * DAY_MS).unwrap();
+    }
+
+    #[test]
+    fn test_revocation_elsewhere_is_seen_within_one_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let config = ApiKeysConfig { cache_refresh_ms: 1_000, ..ApiKeysConfig::default() };
+        // Two registries over one storage, as two RPC frontends would be.
+        let (this, other) = (ApiKeys::new(&config, Arc::clone(&storage)), ApiKeys::new(&config, storage));
+        let (key, record) = this.create("carol", limits(100.0, 100), 0).unwrap();
+        let start = Instant::now();
+        this.authorize_at(&key, "get_balance", start, 0).unwrap();
+        other.revoke(&record.id, 0).unwrap().unwrap();
+        assert!(matches!(other.authorize_at(&key, "get_balance", start, 0), Err(ApiKeyError::Invalid)));
+
+        // Cached for up to one refresh interval, then refused.
+        this.authorize_at(&key, "get_balance", start + Duration::from_millis(999), 0).unwrap();
+        let refreshed = start + Duration::from_millis(1_000);
+        assert!(matches!(this.authorize_at(&key, "get_balance", refreshed, 0), Err(ApiKeyError::Invalid)));
+        assert!(this.list().unwrap()[0].revoked_ms.is_some());
+    }
+}
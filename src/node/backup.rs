@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use solana_sdk::hash::Hash;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+use super::runtime::{CHAIN_DIR, STATE_FILE};
+use super::state::{State, StateError};
+use super::storage::StorageError;
+
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+/// Written last, so a backup without one is incomplete.
+pub const BACKUP_MANIFEST_FILE: &str = "manifest.json";
+/// Files are hashed in chunks of this size.
+const CHECKSUM_CHUNK_BYTES: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Invalid backup: {0}")]
+    Invalid(String),
+    #[error("Backup file {0} does not match its manifest checksum")]
+    ChecksumMismatch(String),
+    #[error("{0} is not empty")]
+    DestinationNotEmpty(PathBuf),
+    #[error("Storage at {path} is in use; stop the node before restoring ({reason})")]
+    InUse { path: PathBuf, reason: String },
+    #[error("A backup is already running")]
+    InProgress,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    /// Relative to the backup directory, `/`-separated.
+    pub path: String,
+    pub len: u64,
+    /// Hex-encoded SHA-256 of the file.
+    pub sha256: String,
+}
+
+/// What `Storage::backup` copied. The store holds the finalized blocks up
+/// to `height`; the state was taken at `state_height`, which may trail it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub version: u32,
+    pub created_at_ms: i64,
+    /// `None` when nothing was finalized yet.
+    pub height: Option<u64>,
+    pub block_hash: Option<Hash>,
+    pub state_height: u64,
+    pub state_root: Hash,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupStage {
+    /// Copying the store, `done` of `total` columns.
+    Copying,
+    /// Hashing the copy, `done` of `total` bytes.
+    Checksumming,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub stage: BackupStage,
+    pub done: u64,
+    pub total: u64,
+}
+
+/// Creates `dest`, or checks that it is empty.
+pub(crate) fn prepare(dest: &Path) -> Result<(), BackupError> {
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(BackupError::DestinationNotEmpty(dest.to_path_buf()));
+    }
+    fs::create_dir_all(dest)?;
+    Ok(())
+}
+
+/// Writes `state` next to the store copied into `dest`, then the manifest
+/// over both.
+pub(crate) fn finish(
+    dest: &Path,
+    height: Option<u64>,
+    block_hash: Option<Hash>,
+    state: &State,
+    progress: &dyn Fn(BackupProgress),
+) -> Result<BackupManifest, BackupError> {
+    fs::write(dest.join(STATE_FILE), state.export()?)?;
+    let paths = files_under(dest, Path::new(""))?;
+    let total = paths.iter().map(|(_, len)| len).sum();
+    let mut done = 0;
+    let mut files = Vec::with_capacity(paths.len());
+    for (path, len) in paths {
+        let sha256 = sha256_file(&dest.join(&path))?;
+        done += len;
+        progress(BackupProgress { stage: BackupStage::Checksumming, done, total });
+        files.push(BackupFile { path: manifest_path(&path), len, sha256 });
+    }
+    let manifest = BackupManifest {
+        version: BACKUP_FORMAT_VERSION,
+        created_at_ms: chrono::Utc::now().timestamp_millis(),
+        height,
+        block_hash,
+        state_height: state.height(),
+        state_root: state.root(),
+        files,
+    };
+    fs::write(dest.join(BACKUP_MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+pub fn read_manifest(dir: &Path) -> Result<BackupManifest, BackupError> {
+    let path = dir.join(BACKUP_MANIFEST_FILE);
+    if !path.exists() {
+        return Err(BackupError::Invalid(format!("{} has no {}", dir.display(), BACKUP_MANIFEST_FILE)));
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Reads the manifest in `dir` and checks that every file it lists is
+/// there with its recorded length and checksum.
+pub fn verify(dir: &Path) -> Result<BackupManifest, BackupError> {
+    let manifest = read_manifest(dir)?;
+    if manifest.version != BACKUP_FORMAT_VERSION {
+        return Err(BackupError::Invalid(format!("unsupported backup version {}", manifest.version)));
+    }
+    for file in &manifest.files {
+        let relative = Path::new(&file.path);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(BackupError::Invalid(format!("file path {} leaves the backup", file.path)));
+        }
+        let path = dir.join(relative);
+        let len = fs::metadata(&path).map_err(|_| BackupError::Invalid(format!("{} is missing", file.path)))?.len();
+        if len != file.len || sha256_file(&path)? != file.sha256 {
+            return Err(BackupError::ChecksumMismatch(file.path.clone()));
+        }
+    }
+    for required in [CHAIN_DIR, STATE_FILE] {
+        let prefix = format!("{}/", required);
+        if !manifest.files.iter().any(|file| file.path == required || file.path.starts_with(&prefix)) {
+            return Err(BackupError::Invalid(format!("backup holds no {}", required)));
+        }
+    }
+    Ok(manifest)
+}
+
+/// Copies the directory tree at `from` to `to`, which must not exist.
+pub(crate) fn copy_dir(from: &Path, to: &Path) -> Result<(), BackupError> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Files below `dir.join(prefix)`, relative to `dir`, with their lengths,
+/// in path order.
+fn files_under(dir: &Path, prefix: &Path) -> Result<Vec<(PathBuf, u64)>, BackupError> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir.join(prefix))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let relative = prefix.join(entry.file_name());
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            files.extend(files_under(dir, &relative)?);
+        } else {
+            files.push((relative, meta.len()));
+        }
+    }
+    Ok(files)
+}
+
+fn manifest_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn sha256_file(path: &Path) -> Result<String, BackupError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHECKSUM_CHUNK_BYTES];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tampered_or_escaping_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(CHAIN_DIR).join("sub")).unwrap();
+        fs::write(dir.path().join(CHAIN_DIR).join("sub").join("db"), b"blocks").unwrap();
+        let manifest = finish(dir.path(), Some(3), Some(Hash::new_unique()), &State::in_memory(Vec::new()), &|_| {})
+            .unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, vec!["chain/sub/db", STATE_FILE]);
+        assert_eq!(verify(dir.path()).unwrap(), manifest);
+
+        fs::write(dir.path().join(CHAIN_DIR).join("sub").join("db"), b"blockz").unwrap();
+        assert!(matches!(verify(dir.path()), Err(BackupError::ChecksumMismatch(path)) if path == "chain/sub/db"));
+
+        let mut escaping = manifest;
+        escaping.files[0].path = "../outside".to_string();
+        fs::write(dir.path().join(BACKUP_MANIFEST_FILE), serde_json::to_vec(&escaping).unwrap()).unwrap();
+        assert!(matches!(verify(dir.path()), Err(BackupError::Invalid(_))));
+        assert!(matches!(prepare(dir.path()), Err(BackupError::DestinationNotEmpty(_))));
+    }
+}
@@ -0,0 +1,260 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+use super::network::NetMessage;
+
+/// Share of a bucket that must be full before a bulk frame is sent, so
+/// consensus messages queued behind it find bandwidth left.
+const BULK_RESERVE: f64 = 0.5;
+
+/// The `[bandwidth]` section. Rates are in bytes per second; zero leaves
+/// the direction unlimited. Each bucket holds one second's worth.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BandwidthConfig {
+    /// Upload to any one peer.
+    #[serde(default)]
+    pub peer_upload_bytes_per_sec: u64,
+    /// Upload to all peers together.
+    #[serde(default)]
+    pub total_upload_bytes_per_sec: u64,
+    /// Download from any one peer. Reads from a peer past it are paused,
+    /// and one that keeps sending unrequested traffic past it is penalized.
+    #[serde(default)]
+    pub peer_download_bytes_per_sec: u64,
+}
+
+impl BandwidthConfig {
+    pub fn with_peer_upload(mut self, bytes_per_sec: u64) -> Self {
+        self.peer_upload_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    pub fn with_total_upload(mut self, bytes_per_sec: u64) -> Self {
+        self.total_upload_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    pub fn with_peer_download(mut self, bytes_per_sec: u64) -> Self {
+        self.peer_download_bytes_per_sec = bytes_per_sec;
+        self
+    }
+}
+
+/// What a message is for, as traffic is accounted and paced by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageCategory {
+    /// Blocks, votes and heartbeats.
+    Consensus,
+    /// Handshakes, pings, peer exchange, capability updates and key
+    /// handovers.
+    Control,
+    Transaction,
+    /// Block requests and the batches answering them.
+    Sync,
+    Inference,
+    /// Distributed training gradients.
+    Training,
+}
+
+impl MessageCategory {
+    pub const ALL: [MessageCategory; 6] = [
+        MessageCategory::Consensus,
+        MessageCategory::Control,
+        MessageCategory::Transaction,
+        MessageCategory::Sync,
+        MessageCategory::Inference,
+        MessageCategory::Training,
+    ];
+
+    pub fn of(message: &NetMessage) -> Self {
+        match message {
+            NetMessage::Block(_) | NetMessage::Proposal(_) | NetMessage::Vote(_) | NetMessage::Heartbeat(_) => {
+                MessageCategory::Consensus
+            }
+            NetMessage::Tx(_) => MessageCategory::Transaction,
+            NetMessage::GetBlocks { .. } | NetMessage::Blocks { .. } => MessageCategory::Sync,
+            NetMessage::Inference(_) => MessageCategory::Inference,
+            NetMessage::Gradients(_) => MessageCategory::Training,
+            NetMessage::Handshake { .. }
+            | NetMessage::HandshakeProof(_)
+            | NetMessage::Disconnect(_)
+            | NetMessage::Ping(_)
+            | NetMessage::Pong(_)
+            | NetMessage::GetPeers
+            | NetMessage::Peers(_)
+            | NetMessage::LlmCapability(_)
+            | NetMessage::KeyHandover(_) => MessageCategory::Control,
+        }
+    }
+
+    /// Large transfers that can wait: sent after everything else queued for
+    /// a peer, and only while its buckets hold `BULK_RESERVE`.
+    pub fn is_bulk(self) -> bool {
+        matches!(self, MessageCategory::Sync | MessageCategory::Training)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageCategory::Consensus => "consensus",
+            MessageCategory::Control => "control",
+            MessageCategory::Transaction => "transaction",
+            MessageCategory::Sync => "sync",
+            MessageCategory::Inference => "inference",
+            MessageCategory::Training => "training",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Frame bytes sent and received, by category.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficStats {
+    sent: [u64; 6],
+    received: [u64; 6],
+}
+
+impl TrafficStats {
+    pub fn sent(&self, category: MessageCategory) -> u64 {
+        self.sent[category.index()]
+    }
+
+    pub fn received(&self, category: MessageCategory) -> u64 {
+        self.received[category.index()]
+    }
+
+    pub fn total_sent(&self) -> u64 {
+        self.sent.iter().sum()
+    }
+
+    pub fn total_received(&self) -> u64 {
+        self.received.iter().sum()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    sent: [AtomicU64; 6],
+    received: [AtomicU64; 6],
+}
+
+impl Traffic {
+    pub(crate) fn record_sent(&self, category: MessageCategory, bytes: usize) {
+        self.sent[category.index()].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, category: MessageCategory, bytes: usize) {
+        self.received[category.index()].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            sent: std::array::from_fn(|i| self.sent[i].load(Ordering::Relaxed)),
+            received: std::array::from_fn(|i| self.received[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Bytes refilled at `rate` per second up to a second's worth. A frame is
+/// taken whole even when it overdraws the bucket, and the next waits for
+/// the debt to be repaid.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// `None` for a zero, unlimited, rate.
+    pub(crate) fn new(bytes_per_sec: u64, now: Instant) -> Option<Self> {
+        let rate = bytes_per_sec as f64;
+        (bytes_per_sec > 0).then_some(TokenBucket { rate, tokens: rate, updated: now })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// How long until the bucket holds `share` of its capacity.
+    pub(crate) fn wait(&mut self, share: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = share * self.rate - self.tokens;
+        if missing <= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(missing / self.rate) }
+    }
+
+    pub(crate) fn take(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Paces a peer's writer by its own bucket and the one all peers share.
+/// Any frame may go once neither is overdrawn; bulk frames wait further
+/// for both to hold `BULK_RESERVE`.
+pub(crate) struct OutboundPacer {
+    peer: Option<TokenBucket>,
+    total: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl OutboundPacer {
+    pub(crate) fn new(peer: Option<TokenBucket>, total: Option<Arc<Mutex<TokenBucket>>>) -> Self {
+        OutboundPacer { peer, total }
+    }
+
+    pub(crate) fn wait(&mut self, bulk: bool, now: Instant) -> Duration {
+        let share = if bulk { BULK_RESERVE } else { 0.0 };
+        let peer = self.peer.as_mut().map_or(Duration::ZERO, |bucket| bucket.wait(share, now));
+        let total = self.total.as_ref().map_or(Duration::ZERO, |bucket| bucket.lock().wait(share, now));
+        peer.max(total)
+    }
+
+    /// Waits until a frame that is not bulk may be sent.
+    pub(crate) async fn ready(&mut self) {
+        loop {
+            let wait = self.wait(false, Instant::now());
+            if wait.is_zero() {
+                return;
+            }
+            sleep(wait).await;
+        }
+    }
+
+    pub(crate) fn charge(&mut self, bytes: usize) {
+        let now = Instant::now();
+        if let Some(bucket) = self.peer.as_mut() {
+            bucket.take(bytes, now);
+        }
+        if let Some(bucket) = &self.total {
+            bucket.lock().take(bytes, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_overdraws_then_repays() {
+        let now = Instant::now();
+        assert!(TokenBucket::new(0, now).is_none());
+        let mut bucket = TokenBucket::new(1000, now).unwrap();
+        assert_eq!(bucket.wait(1.0, now), Duration::ZERO);
+        bucket.take(1500, now);
+        assert_eq!(bucket.wait(0.0, now), Duration::from_millis(500));
+        assert_eq!(bucket.wait(BULK_RESERVE, now), Duration::from_secs(1));
+        // Refills stop at a second's worth.
+        assert_eq!(bucket.wait(1.0, now + Duration::from_secs(5)), Duration::ZERO);
+        bucket.take(1000, now + Duration::from_secs(5));
+        assert_eq!(bucket.wait(0.0, now + Duration::from_secs(5)), Duration::ZERO);
+    }
+}
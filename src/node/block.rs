@@ -0,0 +1,369 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+};
+
+use std::collections::BTreeSet;
+
+use super::bloom::{AddressBloom, DEFAULT_ADDRESS_BLOOM_FP_PPM};
+use super::config::DEFAULT_CHAIN_ID;
+use super::merkle::{self, MerkleProof};
+use super::transaction::Transaction;
+use super::validator::ValidatorSet;
+use crate::utils::DADBSAddress;
+
+/// Layout of the headers built now. Version 0 headers, from before headers
+/// carried a version, have no `next_validators_hash` and a flat hash of the
+/// transaction hashes as their root; version 1 headers have no
+/// `address_bloom`, and version 2 headers no `chain_id`. All are kept, and
+/// hash, as they were.
+pub const BLOCK_VERSION: u32 = 3;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    #[serde(default)]
+    pub version: u32,
+    pub height: u64,
+    pub parent_hash: Hash,
+    pub timestamp: i64,
+    pub proposer: Pubkey,
+    /// Merkle root of the transaction hashes, in block order.
+    pub transactions_root: Hash,
+    /// State root after applying the parent block.
+    pub state_root: Hash,
+    /// Sum of the fees of every transaction in the block, owed to the proposer.
+    pub total_fees: u64,
+    /// Hash of the validator set taking over from the next block when it
+    /// changes at this one; the default hash otherwise.
+    #[serde(default)]
+    pub next_validators_hash: Hash,
+    /// Filter over the addresses the block touches: its proposer and the
+    /// senders and recipients of its transactions. Empty before version 2.
+    #[serde(default)]
+    pub address_bloom: AddressBloom,
+    /// The chain the block was built for, so its hash, and the votes
+    /// certifying it, mean nothing on another. Empty before version 3.
+    #[serde(default)]
+    pub chain_id: String,
+}
+
+/// A header as stored before `BlockHeader::version`.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyHeader {
+    height: u64,
+    parent_hash: Hash,
+    timestamp: i64,
+    proposer: Pubkey,
+    transactions_root: Hash,
+    state_root: Hash,
+    total_fees: u64,
+}
+
+impl From<LegacyHeader> for BlockHeader {
+    fn from(legacy: LegacyHeader) -> Self {
+        BlockHeader {
+            version: 0,
+            height: legacy.height,
+            parent_hash: legacy.parent_hash,
+            timestamp: legacy.timestamp,
+            proposer: legacy.proposer,
+            transactions_root: legacy.transactions_root,
+            state_root: legacy.state_root,
+            total_fees: legacy.total_fees,
+            next_validators_hash: Hash::default(),
+            address_bloom: AddressBloom::default(),
+            chain_id: String::new(),
+        }
+    }
+}
+
+/// A version 1 header, as stored before `BlockHeader::address_bloom`.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct V1Header {
+    version: u32,
+    height: u64,
+    parent_hash: Hash,
+    timestamp: i64,
+    proposer: Pubkey,
+    transactions_root: Hash,
+    state_root: Hash,
+    total_fees: u64,
+    next_validators_hash: Hash,
+}
+
+impl From<V1Header> for BlockHeader {
+    fn from(v1: V1Header) -> Self {
+        BlockHeader {
+            version: v1.version,
+            height: v1.height,
+            parent_hash: v1.parent_hash,
+            timestamp: v1.timestamp,
+            proposer: v1.proposer,
+            transactions_root: v1.transactions_root,
+            state_root: v1.state_root,
+            total_fees: v1.total_fees,
+            next_validators_hash: v1.next_validators_hash,
+            address_bloom: AddressBloom::default(),
+            chain_id: String::new(),
+        }
+    }
+}
+
+/// A version 2 header, as stored before `BlockHeader::chain_id`.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct V2Header {
+    version: u32,
+    height: u64,
+    parent_hash: Hash,
+    timestamp: i64,
+    proposer: Pubkey,
+    transactions_root: Hash,
+    state_root: Hash,
+    total_fees: u64,
+    next_validators_hash: Hash,
+    address_bloom: AddressBloom,
+}
+
+impl From<V2Header> for BlockHeader {
+    fn from(v2: V2Header) -> Self {
+        BlockHeader {
+            version: v2.version,
+            height: v2.height,
+            parent_hash: v2.parent_hash,
+            timestamp: v2.timestamp,
+            proposer: v2.proposer,
+            transactions_root: v2.transactions_root,
+            state_root: v2.state_root,
+            total_fees: v2.total_fees,
+            next_validators_hash: v2.next_validators_hash,
+            address_bloom: v2.address_bloom,
+            chain_id: String::new(),
+        }
+    }
+}
+
+impl BlockHeader {
+    /// The block hash; a block's transactions are committed to by its root.
+    pub fn hash(&self) -> Hash {
+        let header = match self.version {
+            0 => LegacyHeader {
+                height: self.height,
+                parent_hash: self.parent_hash,
+                timestamp: self.timestamp,
+                proposer: self.proposer,
+                transactions_root: self.transactions_root,
+                state_root: self.state_root,
+                total_fees: self.total_fees,
+            }
+            .try_to_vec(),
+            1 => V1Header {
+                version: self.version,
+                height: self.height,
+                parent_hash: self.parent_hash,
+                timestamp: self.timestamp,
+                proposer: self.proposer,
+                transactions_root: self.transactions_root,
+                state_root: self.state_root,
+                total_fees: self.total_fees,
+                next_validators_hash: self.next_validators_hash,
+            }
+            .try_to_vec(),
+            2 => V2Header {
+                version: self.version,
+                height: self.height,
+                parent_hash: self.parent_hash,
+                timestamp: self.timestamp,
+                proposer: self.proposer,
+                transactions_root: self.transactions_root,
+                state_root: self.state_root,
+                total_fees: self.total_fees,
+                next_validators_hash: self.next_validators_hash,
+                address_bloom: self.address_bloom.clone(),
+            }
+            .try_to_vec(),
+            _ => self.try_to_vec(),
+        };
+        hashv(&[&header.expect("block header serialization cannot fail")])
+    }
+
+    /// Decodes a header written before headers carried a version.
+    pub fn from_legacy_slice(bytes: &[u8]) -> std::io::Result<Self> {
+        LegacyHeader::try_from_slice(bytes).map(Into::into)
+    }
+
+    /// Decodes a header written before headers carried an address filter.
+    pub fn from_v1_slice(bytes: &[u8]) -> std::io::Result<Self> {
+        V1Header::try_from_slice(bytes).map(Into::into)
+    }
+
+    /// Decodes a header written before headers named their chain.
+    pub fn from_v2_slice(bytes: &[u8]) -> std::io::Result<Self> {
+        V2Header::try_from_slice(bytes).map(Into::into)
+    }
+
+    /// Whether the block may involve `address`. Always true for headers
+    /// from before version 2, which carry no filter.
+    pub fn may_touch(&self, address: &DADBSAddress) -> bool {
+        self.version < 2 || self.address_bloom.contains(address)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn new(height: u64, parent_hash: Hash, timestamp: i64, proposer: Pubkey) -> Self {
+        Self::with_transactions(height, parent_hash, timestamp, proposer, Vec::new())
+    }
+
+    pub fn with_transactions(
+        height: u64,
+        parent_hash: Hash,
+        timestamp: i64,
+        proposer: Pubkey,
+        transactions: Vec<Transaction>,
+    ) -> Self {
+        Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                height,
+                parent_hash,
+                timestamp,
+                proposer,
+                transactions_root: Self::transactions_root(&transactions),
+                state_root: Hash::default(),
+                total_fees: transactions.iter().map(|t| t.fee).fold(0u64, u64::saturating_add),
+                next_validators_hash: Hash::default(),
+                address_bloom: AddressBloom::default(),
+                chain_id: DEFAULT_CHAIN_ID.to_string(),
+            },
+            transactions,
+        }
+        .with_address_bloom(DEFAULT_ADDRESS_BLOOM_FP_PPM)
+    }
+
+    /// Builds the block for `chain_id` rather than the default chain.
+    pub fn with_chain_id(mut self, chain_id: impl Into<String>) -> Self {
+        self.header.chain_id = chain_id.into();
+        self
+    }
+
+    /// Rebuilds the address filter for `fp_ppm` false positives per million.
+    pub fn with_address_bloom(mut self, fp_ppm: u32) -> Self {
+        self.header.address_bloom = AddressBloom::new(&self.touched_addresses(), fp_ppm);
+        self
+    }
+
+    /// The proposer and every sender and recipient, each once, in order.
+    pub fn touched_addresses(&self) -> Vec<DADBSAddress> {
+        let pubkeys = self.transactions.iter().flat_map(|t| [t.sender, t.recipient]);
+        let pubkeys: BTreeSet<Pubkey> = std::iter::once(self.header.proposer).chain(pubkeys).collect();
+        pubkeys.iter().map(DADBSAddress::from_pubkey).collect()
+    }
+
+    /// Announces `validators` as the set certifying the blocks after this one.
+    pub fn with_next_validators(mut self, validators: &ValidatorSet) -> Self {
+        self.header.next_validators_hash = validators.hash();
+        self
+    }
+
+    pub fn transactions_root(transactions: &[Transaction]) -> Hash {
+        merkle::root(&transactions.iter().map(Transaction::hash).collect::<Vec<_>>())
+    }
+
+    /// The root of a version 0 header.
+    fn legacy_transactions_root(transactions: &[Transaction]) -> Hash {
+        if transactions.is_empty() {
+            return Hash::default();
+        }
+        let hashes: Vec<Hash> = transactions.iter().map(Transaction::hash).collect();
+        let refs: Vec<&[u8]> = hashes.iter().map(|h| h.as_ref()).collect();
+        hashv(&refs)
+    }
+
+    /// Proves that the transaction at `index` is under `transactions_root`.
+    /// Version 0 roots are not Merkle roots, so their blocks have no proofs.
+    pub fn inclusion_proof(&self, index: usize) -> Option<MerkleProof> {
+        if self.header.version == 0 {
+            return None;
+        }
+        MerkleProof::new(&self.transactions.iter().map(Transaction::hash).collect::<Vec<_>>(), index)
+    }
+
+    /// Whether the header's root, fee total and address filter match the
+    /// carried transactions. Headers of a version newer than ours never
+    /// match.
+    pub fn verify_body(&self) -> bool {
+        let total_fees = self.transactions.iter().map(|t| t.fee).fold(0u64, u64::saturating_add);
+        let unannounced = self.header.next_validators_hash == Hash::default();
+        // Older hashes leave out the fields added since, so they must be unset.
+        let unfiltered = self.header.address_bloom == AddressBloom::default();
+        let unchained = self.header.chain_id.is_empty();
+        let root = match self.header.version {
+            0 if unannounced && unfiltered && unchained => Self::legacy_transactions_root(&self.transactions),
+            1 if unfiltered && unchained => Self::transactions_root(&self.transactions),
+            2 if unchained && self.header.address_bloom.matches(&self.touched_addresses()) => {
+                Self::transactions_root(&self.transactions)
+            }
+            BLOCK_VERSION if self.header.address_bloom.matches(&self.touched_addresses()) => {
+                Self::transactions_root(&self.transactions)
+            }
+            _ => return false,
+        };
+        self.header.transactions_root == root && self.header.total_fees == total_fees
+    }
+
+    /// Decodes a block written before headers carried a version.
+    pub fn from_legacy_slice(bytes: &[u8]) -> std::io::Result<Self> {
+        let (header, transactions) = <(LegacyHeader, Vec<Transaction>)>::try_from_slice(bytes)?;
+        Ok(Block { header: header.into(), transactions })
+    }
+
+    /// Decodes a block written before headers carried an address filter.
+    pub fn from_v1_slice(bytes: &[u8]) -> std::io::Result<Self> {
+        let (header, transactions) = <(V1Header, Vec<Transaction>)>::try_from_slice(bytes)?;
+        Ok(Block { header: header.into(), transactions })
+    }
+
+    /// Decodes a block written before headers named their chain.
+    pub fn from_v2_slice(bytes: &[u8]) -> std::io::Result<Self> {
+        let (header, transactions) = <(V2Header, Vec<Transaction>)>::try_from_slice(bytes)?;
+        Ok(Block { header: header.into(), transactions })
+    }
+
+    /// Sets the header version, for a block that keeps an older layout.
+    /// Layouts without an address filter or chain drop them.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.header.version = version;
+        if version < 2 {
+            self.header.address_bloom = AddressBloom::default();
+        }
+        if version < 3 {
+            self.header.chain_id.clear();
+        }
+        self
+    }
+
+    /// Block 0 stays at version 0, so chains started before headers were
+    /// versioned keep their genesis hash.
+    pub fn genesis() -> Self {
+        Block::new(0, Hash::default(), 0, Pubkey::default()).with_version(0)
+    }
+
+    pub fn height(&self) -> u64 {
+        self.header.height
+    }
+
+    pub fn parent_hash(&self) -> Hash {
+        self.header.parent_hash
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+}
@@ -0,0 +1,147 @@
+//! Bloom filters over the addresses a block touches. Each version 2 header
+//! carries one, so whether a block may involve an address can be told from
+//! the header alone, after its body was pruned or without fetching it.
+//!
+//! An address sets `hashes` bits, derived by double hashing from SHA-256
+//! of its string form under a domain prefix. Filters are sized for a false
+//! positive rate given in parts per million; they have no false negatives.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::hashv;
+
+use crate::utils::DADBSAddress;
+
+/// One in a hundred.
+pub const DEFAULT_ADDRESS_BLOOM_FP_PPM: u32 = 10_000;
+/// Above this a filter skips too few blocks to be worth its bytes.
+pub const MAX_ADDRESS_BLOOM_FP_PPM: u32 = 500_000;
+const MAX_HASHES: u8 = 16;
+/// Filter bits allowed per address; a rate of one in a million needs 29.
+const MAX_BITS_PER_ADDRESS: usize = 32;
+const BLOOM_DOMAIN: &[u8] = b"dadbs-address-bloom";
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressBloom {
+    /// Bits set per address.
+    pub hashes: u8,
+    pub bits: Vec<u8>,
+}
+
+impl AddressBloom {
+    /// A filter over `addresses` sized for `fp_ppm` false positives per
+    /// million lookups of addresses not in it.
+    pub fn new(addresses: &[DADBSAddress], fp_ppm: u32) -> Self {
+        if addresses.is_empty() {
+            return AddressBloom::default();
+        }
+        let rate = f64::from(fp_ppm.clamp(1, MAX_ADDRESS_BLOOM_FP_PPM)) / 1_000_000.0;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(addresses.len() as f64) * rate.ln() / (ln2 * ln2)).ceil().max(8.0) as usize;
+        let hashes = (bits as f64 / addresses.len() as f64 * ln2).round().clamp(1.0, f64::from(MAX_HASHES)) as u8;
+        Self::with_geometry(addresses, bits.div_ceil(8), hashes)
+    }
+
+    fn with_geometry(addresses: &[DADBSAddress], bytes: usize, hashes: u8) -> Self {
+        let mut bloom = AddressBloom { hashes, bits: vec![0; bytes] };
+        for address in addresses {
+            for bit in bloom.positions(address) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    fn positions(&self, address: &DADBSAddress) -> impl Iterator<Item = usize> {
+        let digest = hashv(&[BLOOM_DOMAIN, address.as_string().as_bytes()]).to_bytes();
+        let first = u64::from_le_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+        let step = u64::from_le_bytes(digest[8..16].try_into().expect("digest is 32 bytes")) | 1;
+        let width = self.bits.len() as u64 * 8;
+        (0..u64::from(self.hashes)).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % width) as usize)
+    }
+
+    /// Whether `address` may be in the filter. Never false for one that is.
+    pub fn contains(&self, address: &DADBSAddress) -> bool {
+        !self.bits.is_empty() && self.positions(address).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Whether this is exactly the filter of its own size over `addresses`,
+    /// and no larger than any rate allows. The rate itself is the
+    /// proposer's to choose, so it is not checked.
+    pub fn matches(&self, addresses: &[DADBSAddress]) -> bool {
+        if addresses.is_empty() {
+            return *self == AddressBloom::default();
+        }
+        let max_bytes = (addresses.len() * MAX_BITS_PER_ADDRESS).div_ceil(8);
+        (1..=MAX_HASHES).contains(&self.hashes)
+            && (1..=max_bytes).contains(&self.bits.len())
+            && *self == Self::with_geometry(addresses, self.bits.len(), self.hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::pubkey::Pubkey;
+
+    fn addresses(count: usize) -> Vec<DADBSAddress> {
+        (0..count).map(|_| DADBSAddress::from_pubkey(&Pubkey::new_unique())).collect()
+    }
+
+    #[test]
+    fn test_skip_rate_on_synthetic_blocks_without_false_negatives() {
+        // A thousand blocks of 20 to 60 addresses; each watched address is
+        // in every tenth block.
+        let watched = addresses(8);
+        let blooms: Vec<(bool, AddressBloom)> = (0..1000)
+            .map(|block| {
+                let mut touched = addresses(20 + block % 41);
+                let active = block % 10 == 0;
+                if active {
+                    touched.extend(watched.iter().cloned());
+                }
+                (active, AddressBloom::new(&touched, DEFAULT_ADDRESS_BLOOM_FP_PPM))
+            })
+            .collect();
+
+        let (mut idle, mut skipped) = (0, 0);
+        for address in &watched {
+            for (active, bloom) in &blooms {
+                if *active {
+                    assert!(bloom.contains(address), "false negative");
+                } else {
+                    idle += 1;
+                    skipped += usize::from(!bloom.contains(address));
+                }
+            }
+        }
+        // Sized for 1%; allow for chance and rounding.
+        let skip_rate = skipped as f64 / idle as f64;
+        assert!(skip_rate > 0.97, "skipped {:.4} of idle blocks", skip_rate);
+
+        // A looser rate gives a smaller filter that skips less.
+        let touched = addresses(1000);
+        let tight = AddressBloom::new(&touched, 1_000);
+        let loose = AddressBloom::new(&touched, 100_000);
+        assert!(loose.bits.len() < tight.bits.len());
+        let strangers = addresses(10_000);
+        let hits = |bloom: &AddressBloom| strangers.iter().filter(|address| bloom.contains(address)).count();
+        assert!(hits(&loose) > hits(&tight));
+        assert!(touched.iter().all(|address| tight.contains(address) && loose.contains(address)));
+    }
+
+    #[test]
+    fn test_only_the_exact_filter_matches() {
+        let touched = addresses(30);
+        let bloom = AddressBloom::new(&touched, DEFAULT_ADDRESS_BLOOM_FP_PPM);
+        assert!(bloom.matches(&touched));
+        assert!(!bloom.matches(&touched[1..]));
+        // Every bit set would hide nothing, but is not the filter of these.
+        let full = AddressBloom { bits: vec![u8::MAX; bloom.bits.len()], ..bloom.clone() };
+        assert!(!full.matches(&touched));
+        let oversized = AddressBloom::with_geometry(&touched, 30 * MAX_BITS_PER_ADDRESS / 8 + 1, 4);
+        assert!(!oversized.matches(&touched));
+        assert!(AddressBloom::default().matches(&[]));
+        assert!(!AddressBloom::default().contains(&touched[0]));
+    }
+}
@@ -0,0 +1,206 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::BTreeMap;
+
+use super::block::Block;
+use super::storage::StorageError;
+use super::validator::ValidatorSet;
+
+/// Length of a stats bucket: aggregates are kept per UTC day.
+pub const DAY_MS: i64 = 86_400_000;
+const ADDRESS_PREFIX: u8 = b'A';
+const ACTIVE_PREFIX: u8 = b'a';
+const DAY_PREFIX: u8 = b'd';
+const TIP_KEY: &[u8] = b"t";
+
+/// Aggregates over the blocks timestamped within one UTC day.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DayStats {
+    /// Unix milliseconds at which the day starts.
+    pub day_start_ms: i64,
+    pub blocks: u64,
+    pub transactions: u64,
+    pub fees: u64,
+    /// Sum of the amounts transferred.
+    pub volume: u64,
+    /// Distinct senders and recipients.
+    pub active_addresses: u64,
+    /// Milliseconds between each block and its parent, summed over the
+    /// blocks whose parent is counted too.
+    pub interval_ms: u64,
+    pub intervals: u64,
+}
+
+impl DayStats {
+    fn new(day: i64) -> Self {
+        DayStats { day_start_ms: day * DAY_MS, ..DayStats::default() }
+    }
+}
+
+/// What an address has done over the counted blocks.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressStats {
+    pub first_seen_height: u64,
+    pub first_seen_ms: i64,
+    /// Transactions sending to or from it, a transfer to itself once.
+    pub tx_count: u64,
+    pub total_in: u64,
+    /// Amounts sent, fees excluded.
+    pub total_out: u64,
+}
+
+/// The last block counted, which the next must follow.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct Tip {
+    height: u64,
+    timestamp: i64,
+}
+
+pub(super) fn day_key(day: i64) -> Vec<u8> {
+    [&[DAY_PREFIX][..], &(day as u64).to_be_bytes()].concat()
+}
+
+pub(super) fn address_key(address: &Pubkey) -> Vec<u8> {
+    [&[ADDRESS_PREFIX][..], address.as_ref()].concat()
+}
+
+fn active_key(day: i64, address: &Pubkey) -> Vec<u8> {
+    [&[ACTIVE_PREFIX][..], &(day as u64).to_be_bytes(), address.as_ref()].concat()
+}
+
+/// Stats entries as `read` returns them, with the blocks applied so far
+/// written over them.
+pub(super) struct StatsUpdate<R> {
+    read: R,
+    written: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Keys written that `read` did not have.
+    added: u64,
+}
+
+impl<R: Fn(&[u8]) -> Result<Option<Vec<u8>>, StorageError>> StatsUpdate<R> {
+    pub(super) fn new(read: R) -> Self {
+        StatsUpdate { read, written: BTreeMap::new(), added: 0 }
+    }
+
+    fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        match self.written.get(key) {
+            Some(bytes) => Ok(Some(bytes.clone())),
+            None => (self.read)(key),
+        }
+    }
+
+    fn get<T: BorshDeserialize>(&self, key: &[u8]) -> Result<Option<T>, StorageError> {
+        self.get_raw(key)?.map(|bytes| T::try_from_slice(&bytes).map_err(StorageError::from)).transpose()
+    }
+
+    fn put_raw(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), StorageError> {
+        if !self.written.contains_key(&key) && (self.read)(&key)?.is_none() {
+            self.added += 1;
+        }
+        self.written.insert(key, value);
+        Ok(())
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: &impl BorshSerialize) -> Result<(), StorageError> {
+        self.put_raw(key, value.try_to_vec()?)
+    }
+
+    /// Counts `block` if it follows the last block counted. A block that
+    /// replaces one already counted is left out until a reindex.
+    pub(super) fn apply(&mut self, block: &Block) -> Result<(), StorageError> {
+        let (height, timestamp) = (block.height(), block.header.timestamp);
+        let tip: Option<Tip> = self.get(TIP_KEY)?;
+        if tip.is_some_and(|tip| height <= tip.height) {
+            return Ok(());
+        }
+        let day = timestamp.div_euclid(DAY_MS);
+        let mut stats = self.get(&day_key(day))?.unwrap_or_else(|| DayStats::new(day));
+        stats.blocks += 1;
+        stats.transactions += block.transactions.len() as u64;
+        stats.fees = stats.fees.saturating_add(block.header.total_fees);
+        if let Some(parent) = tip.filter(|tip| tip.height + 1 == height) {
+            stats.interval_ms = stats.interval_ms.saturating_add(timestamp.saturating_sub(parent.timestamp).max(0) as u64);
+            stats.intervals += 1;
+        }
+        for transaction in &block.transactions {
+            stats.volume = stats.volume.saturating_add(transaction.amount);
+            let self_transfer = transaction.sender == transaction.recipient;
+            self.touch(&transaction.sender, height, timestamp, day, &mut stats, |address| {
+                address.total_out = address.total_out.saturating_add(transaction.amount);
+                if self_transfer {
+                    address.total_in = address.total_in.saturating_add(transaction.amount);
+                }
+            })?;
+            if !self_transfer {
+                self.touch(&transaction.recipient, height, timestamp, day, &mut stats, |address| {
+                    address.total_in = address.total_in.saturating_add(transaction.amount);
+                })?;
+            }
+        }
+        self.put(day_key(day), &stats)?;
+        self.put(TIP_KEY.to_vec(), &Tip { height, timestamp })
+    }
+
+    /// Counts a transaction of `address`'s, marking it active on `day`.
+    fn touch(
+        &mut self,
+        address: &Pubkey,
+        height: u64,
+        timestamp: i64,
+        day: i64,
+        stats: &mut DayStats,
+        update: impl FnOnce(&mut AddressStats),
+    ) -> Result<(), StorageError> {
+        let active = active_key(day, address);
+        if self.get_raw(&active)?.is_none() {
+            self.put_raw(active, Vec::new())?;
+            stats.active_addresses += 1;
+        }
+        let key = address_key(address);
+        let mut entry = self.get(&key)?.unwrap_or(AddressStats {
+            first_seen_height: height,
+            first_seen_ms: timestamp,
+            tx_count: 0,
+            total_in: 0,
+            total_out: 0,
+        });
+        entry.tx_count += 1;
+        update(&mut entry);
+        self.put(key, &entry)
+    }
+
+    /// The entries to write, and how many of them are new keys.
+    pub(super) fn finish(self) -> (Vec<(Vec<u8>, Vec<u8>)>, u64) {
+        (self.written.into_iter().collect(), self.added)
+    }
+}
+
+/// Validators with a weight in `[min, max)`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StakeBucket {
+    pub min: u128,
+    pub max: u128,
+    pub validators: usize,
+    pub weight: u128,
+}
+
+/// Validators grouped by the power of ten of their weight, lightest first;
+/// empty buckets are left out.
+pub fn stake_distribution(validators: &ValidatorSet) -> Vec<StakeBucket> {
+    let mut buckets: BTreeMap<u32, StakeBucket> = BTreeMap::new();
+    for validator in validators.validators() {
+        let exponent = validator.weight.checked_ilog10().unwrap_or(0);
+        let min = if validator.weight == 0 { 0 } else { 10u128.pow(exponent) };
+        let bucket = buckets.entry(exponent).or_insert_with(|| StakeBucket {
+            min,
+            max: 10u128.saturating_pow(exponent + 1),
+            validators: 0,
+            weight: 0,
+        });
+        bucket.min = bucket.min.min(min);
+        bucket.validators += 1;
+        bucket.weight += validator.weight;
+    }
+    buckets.into_values().collect()
+}
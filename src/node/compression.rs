@@ -0,0 +1,126 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use thiserror::Error;
+
+/// Frames smaller than this are sent uncompressed.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Unknown codec flag {0}")]
+    UnknownCodec(u8),
+    #[error("Decompressed frame exceeds the {0} byte limit")]
+    TooLarge(usize),
+    #[error("Corrupt {codec} frame: {error}")]
+    Corrupt { codec: Codec, error: String },
+}
+
+/// Frame compression codecs, in the order of their header flag.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn flag(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_flag(flag: u8) -> Result<Self, CompressionError> {
+        match flag {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Lz4),
+            other => Err(CompressionError::UnknownCodec(other)),
+        }
+    }
+
+    /// Picks the first of `preferred` that `supported` also lists, falling
+    /// back to no compression.
+    pub fn negotiate(preferred: &[Codec], supported: &[Codec]) -> Codec {
+        preferred.iter()
+            .copied()
+            .find(|codec| *codec != Codec::None && supported.contains(codec))
+            .unwrap_or(Codec::None)
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::None => data.to_vec(),
+            Codec::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .expect("in-memory zstd compression cannot fail"),
+            Codec::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                std::io::Write::write_all(&mut encoder, data).expect("writing to a Vec cannot fail");
+                encoder.finish().expect("writing to a Vec cannot fail")
+            }
+        }
+    }
+
+    /// Decompresses `data`, failing as soon as the output would exceed
+    /// `max_bytes` rather than trusting any size the sender declared.
+    pub fn decompress(self, data: &[u8], max_bytes: usize) -> Result<Vec<u8>, CompressionError> {
+        let corrupt = |error: std::io::Error| CompressionError::Corrupt { codec: self, error: error.to_string() };
+        let mut output = Vec::new();
+        match self {
+            Codec::None => output.extend_from_slice(data),
+            Codec::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(data).map_err(corrupt)?;
+                decoder.take(max_bytes as u64 + 1).read_to_end(&mut output).map_err(corrupt)?;
+            }
+            Codec::Lz4 => {
+                let decoder = lz4_flex::frame::FrameDecoder::new(data);
+                decoder.take(max_bytes as u64 + 1).read_to_end(&mut output).map_err(corrupt)?;
+            }
+        }
+        if output.len() > max_bytes {
+            return Err(CompressionError::TooLarge(max_bytes));
+        }
+        Ok(output)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_and_negotiation() {
+        let data = vec![7u8; 64 * 1024];
+        for codec in [Codec::None, Codec::Zstd, Codec::Lz4] {
+            let compressed = codec.compress(&data);
+            assert_eq!(Codec::from_flag(codec.flag()).unwrap(), codec);
+            assert_eq!(codec.decompress(&compressed, data.len()).unwrap(), data);
+        }
+
+        assert_eq!(Codec::negotiate(&[Codec::Zstd, Codec::Lz4], &[Codec::Lz4, Codec::Zstd]), Codec::Zstd);
+        assert_eq!(Codec::negotiate(&[Codec::Zstd], &[Codec::Lz4]), Codec::None);
+        assert_eq!(Codec::negotiate(&[], &[Codec::Lz4]), Codec::None);
+    }
+
+    #[test]
+    fn test_decompression_is_capped() {
+        let bomb = vec![0u8; 4 * 1024 * 1024];
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let compressed = codec.compress(&bomb);
+            assert!(compressed.len() < 64 * 1024);
+            assert!(matches!(codec.decompress(&compressed, 1024 * 1024), Err(CompressionError::TooLarge(_))));
+        }
+        assert!(matches!(Codec::from_flag(9), Err(CompressionError::UnknownCodec(9))));
+    }
+}
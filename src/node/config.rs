@@ -1,50 +1,725 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use solana_sdk::pubkey::Pubkey;
 use log::{warn, error};
 use thiserror::Error;
 
+use super::admin::AdminConfig;
+use super::api_keys::ApiKeysConfig;
+use super::crypto::{self, SchemeKind};
+use super::deposit_watcher::DepositWatcherConfig;
+use super::dns_seed::{self, DnsSeed, DnsSeedConfig};
+use super::evidence::{SlashTarget, DEFAULT_EVIDENCE_MAX_AGE_EPOCHS};
+use super::genesis::{Genesis, GenesisError};
+use super::fork_choice::DEFAULT_MAX_FORK_DEPTH;
+use super::liveness::LivenessConfig;
+use super::mempool::MempoolConfig;
+use super::sig_verify::SigVerifyConfig;
+use super::metrics::MetricsConfig;
+use super::faucet::FaucetConfig;
+use super::grpc::GrpcConfig;
+use super::journal::JournalConfig;
+use super::moderation::ModerationConfig;
+use super::bandwidth::BandwidthConfig;
+use super::nat::NatConfig;
+use super::compression::Codec;
+use super::gossip::DEFAULT_GOSSIP_FANOUT;
+use super::health::HealthConfig;
+use super::heartbeat::DEFAULT_HEARTBEAT_INTERVAL;
+use super::hooks::HooksConfig;
+use super::keystore::KeystoreConfig;
+use super::network::DEFAULT_MAX_FRAME_BYTES;
+use super::peer_score::DEFAULT_BAN_DURATION;
+use super::reconnect::DEFAULT_MAX_BACKOFF;
+use super::quorum::QuorumConfig;
+use super::rate_limit::LimitsConfig;
+use super::rpc::RpcConfig;
+use super::shutdown::DEFAULT_SHUTDOWN_DEADLINE_MS;
+use super::signer::RemoteSignerConfig;
+use super::snapshot::SnapshotConfig;
+use super::storage::StorageConfig;
+use super::telemetry::TelemetryConfig;
+use super::tx_trace::DEFAULT_TX_TRACE_CAPACITY;
+use super::validator::{ValidatorInfo, ValidatorSet};
+use super::validator_oracle::ValidatorOracleConfig;
+use super::webhooks::WebhooksConfig;
+
+pub const DEFAULT_MIN_FEE: u64 = 5_000;
+pub const DEFAULT_CHAIN_ID: &str = "dadbs-testnet";
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("TOML parsing error: {0}")]
     Toml(#[from] toml::de::Error),
+    #[error("TOML serialization error: {0}")]
+    TomlSerialize(#[from] toml::ser::Error),
     #[error("Invalid host or port: {0}")]
     InvalidAddress(String),
     #[error("Invalid bootstrap node address: {0}")]
     InvalidBootstrapNode(String),
     #[error("Storage path error: {0}")]
     StoragePath(String),
+    #[error("Invalid consensus parameter: {0}")]
+    InvalidConsensusParameter(String),
+    #[error("Invalid slashing configuration: {0}")]
+    InvalidSlashingConfig(String),
+    #[error("Genesis error: {0}")]
+    Genesis(#[from] GenesisError),
+    #[cfg(feature = "llm")]
+    #[error("LLM error: {0}")]
+    Llm(#[from] crate::llm::LlmError),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NodeConfig {
     pub node_id: String,
+    /// Network this node belongs to; snapshots from other chains are refused.
+    #[serde(default = "default_chain_id")]
+    pub chain_id: String,
     pub host: String,
     pub port: u16,
     pub storage_path: String,
     pub max_connections: u32,
     pub consensus_timeout: u64,   
     pub bootstrap_nodes: Vec<String>, 
+    /// How `dnsseed:` entries in `bootstrap_nodes` are resolved.
+    #[serde(default)]
+    pub dns_seed: DnsSeedConfig,
+    /// Largest peer message accepted; peers sending more are disconnected.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// Longest wait between attempts to redial a dropped peer.
+    #[serde(default = "default_max_reconnect_backoff_ms")]
+    pub max_reconnect_backoff_ms: u64,
+    /// How long a peer is refused after its misbehavior score crosses the threshold.
+    #[serde(default = "default_peer_ban_duration_secs")]
+    pub peer_ban_duration_secs: u64,
+    /// Frame compression codecs offered to peers, most preferred first.
+    #[serde(default = "default_compression")]
+    pub compression: Vec<Codec>,
+    /// Peers each new transaction, block or vote is forwarded to.
+    #[serde(default = "default_gossip_fanout")]
+    pub gossip_fanout: usize,
+    /// Dial inbound peers back before passing their address on to others.
+    #[serde(default)]
+    pub dial_back: bool,
+    #[serde(default = "default_max_fork_depth")]
+    pub max_fork_depth: u64,
+    /// Smallest fee a transaction must pay to be admitted.
+    #[serde(default = "default_min_fee")]
+    pub min_fee: u64,
+    /// How long shutdown may take before remaining tasks are aborted.
+    #[serde(default = "default_shutdown_deadline_ms")]
+    pub shutdown_deadline_ms: u64,
+    /// How often a signed heartbeat with our finalized height goes to peers.
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64,
+    /// Genesis file defining the chain's initial validators, balances and
+    /// protocol parameters. Without one the node starts from an empty state
+    /// with `validators` as its validator set.
+    #[serde(default)]
+    pub genesis_path: Option<String>,
+    /// Recent transactions whose lifecycle `trace_transaction` can return.
+    #[serde(default = "default_tx_trace_capacity")]
+    pub tx_trace_capacity: usize,
+    /// Scheme this node signs votes with; must match the validator set's.
+    #[serde(default)]
+    pub signature_scheme: SchemeKind,
+    /// Genesis validator set.
+    #[serde(default)]
+    pub validators: Vec<ValidatorInfo>,
+    #[serde(default)]
+    pub quorum: QuorumConfig,
+    #[serde(default)]
+    pub liveness: LivenessConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub mempool: MempoolConfig,
+    #[serde(default)]
+    pub sig_verify: SigVerifyConfig,
+    #[serde(default)]
+    pub rpc: RpcConfig,
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub api_keys: ApiKeysConfig,
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    #[serde(default)]
+    pub validator_oracle: ValidatorOracleConfig,
+    #[serde(default)]
+    pub keystore: KeystoreConfig,
+    #[serde(default)]
+    pub nat: NatConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+    #[serde(default)]
+    pub faucet: FaucetConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub deposit_watcher: DepositWatcherConfig,
+    #[serde(default)]
+    pub snapshot: Option<SnapshotConfig>,
+    #[serde(default)]
+    pub slashing: Option<SlashingConfig>,
+    #[serde(default)]
+    pub remote_signer: Option<RemoteSignerConfig>,
+    /// Keypair file, as `dadbs-node keygen` writes it, this node proposes
+    /// and votes with. Without it, or `[remote_signer]`, the node only
+    /// follows the chain.
+    #[serde(default)]
+    pub validator_key: Option<String>,
     #[serde(default)]
     pub llm: Option<LLMConfig>,
 }
 
+fn default_chain_id() -> String {
+    DEFAULT_CHAIN_ID.to_string()
+}
+
+fn default_max_fork_depth() -> u64 {
+    DEFAULT_MAX_FORK_DEPTH
+}
+
+fn default_max_frame_bytes() -> usize {
+    DEFAULT_MAX_FRAME_BYTES
+}
+
+fn default_max_reconnect_backoff_ms() -> u64 {
+    DEFAULT_MAX_BACKOFF.as_millis() as u64
+}
+
+fn default_peer_ban_duration_secs() -> u64 {
+    DEFAULT_BAN_DURATION.as_secs()
+}
+
+fn default_compression() -> Vec<Codec> {
+    vec![Codec::Zstd, Codec::Lz4]
+}
+
+fn default_gossip_fanout() -> usize {
+    DEFAULT_GOSSIP_FANOUT
+}
+
+fn default_min_fee() -> u64 {
+    DEFAULT_MIN_FEE
+}
+
+fn default_tx_trace_capacity() -> usize {
+    DEFAULT_TX_TRACE_CAPACITY
+}
+
+fn default_shutdown_deadline_ms() -> u64 {
+    DEFAULT_SHUTDOWN_DEADLINE_MS
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    DEFAULT_HEARTBEAT_INTERVAL.as_millis() as u64
+}
+
+/// Starting points for `NodeConfig::for_profile`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigProfile {
+    /// Joins the public testnet through its bootstrap nodes.
+    #[default]
+    Testnet,
+    /// A single node on loopback with no bootstrap nodes, for development.
+    Local,
+}
+
+/// Values layered over a loaded config file, e.g. from command-line flags.
+/// Unset fields keep the file's value.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub node_id: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub storage_path: Option<String>,
+    pub bootstrap_nodes: Option<Vec<String>>,
+    pub rpc_listen: Option<String>,
+    pub metrics_listen: Option<String>,
+    /// Turns off the LLM startup cache.
+    pub no_llm_cache: bool,
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, config: &mut NodeConfig) {
+        if let Some(node_id) = &self.node_id {
+            config.node_id = node_id.clone();
+        }
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(storage_path) = &self.storage_path {
+            config.storage_path = storage_path.clone();
+        }
+        if let Some(bootstrap_nodes) = &self.bootstrap_nodes {
+            config.bootstrap_nodes = bootstrap_nodes.clone();
+        }
+        if let Some(listen) = &self.rpc_listen {
+            config.rpc.listen = listen.clone();
+        }
+        if let Some(listen) = &self.metrics_listen {
+            config.metrics.listen = listen.clone();
+        }
+        if self.no_llm_cache {
+            if let Some(llm) = config.llm.as_mut() {
+                llm.startup_cache = false;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlashingConfig {
+    pub enabled: bool,
+    pub rpc_url: String,
+    pub authority_keypair_path: String,
+    pub program_id: String,
+    pub treasury_account: String,
+    #[serde(default = "default_penalty_bps")]
+    pub penalty_bps: u16,
+    #[serde(default = "default_evidence_max_age_epochs")]
+    pub evidence_max_age_epochs: u64,
+    /// The stake account each validator evidence can be submitted against.
+    #[serde(default)]
+    pub stake_accounts: Vec<SlashableStake>,
+    /// How often pending evidence is submitted.
+    #[serde(default = "default_slash_submit_interval_ms")]
+    pub submit_interval_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SlashableStake {
+    pub validator: String,
+    pub stake_account: String,
+}
+
+fn default_penalty_bps() -> u16 {
+    500
+}
+
+fn default_slash_submit_interval_ms() -> u64 {
+    10_000
+}
+
+impl SlashingConfig {
+    pub fn target(&self) -> Result<SlashTarget, ConfigError> {
+        Ok(SlashTarget {
+            program_id: parse_slashing_key("program_id", &self.program_id)?,
+            treasury_account: parse_slashing_key("treasury_account", &self.treasury_account)?,
+            penalty_bps: self.penalty_bps,
+        })
+    }
+
+    /// Stake accounts by validator. Each validator may be listed once.
+    pub fn stake_accounts(&self) -> Result<HashMap<Pubkey, Pubkey>, ConfigError> {
+        let mut stake_accounts = HashMap::new();
+        for stake in &self.stake_accounts {
+            let validator = parse_slashing_key("stake_accounts.validator", &stake.validator)?;
+            let account = parse_slashing_key("stake_accounts.stake_account", &stake.stake_account)?;
+            if stake_accounts.insert(validator, account).is_some() {
+                return Err(ConfigError::InvalidSlashingConfig(format!("{} is listed twice", stake.validator)));
+            }
+        }
+        Ok(stake_accounts)
+    }
+}
+
+fn parse_slashing_key(name: &str, key: &str) -> Result<Pubkey, ConfigError> {
+    Pubkey::from_str(key)
+        .map_err(|_| ConfigError::InvalidSlashingConfig(format!("{} is not a valid pubkey: {}", name, key)))
+}
+
+fn default_evidence_max_age_epochs() -> u64 {
+    DEFAULT_EVIDENCE_MAX_AGE_EPOCHS
+}
+
+fn default_llm_max_queue_depth() -> usize {
+    64
+}
+
+fn default_llm_batch_window_ms() -> u64 {
+    5
+}
+
+fn default_llm_device() -> String {
+    DeviceSpec::Auto.to_string()
+}
+
+fn default_llm_prompt_template() -> String {
+    TemplateSpec::Llama2.to_string()
+}
+
+fn default_llm_worker_threads() -> usize {
+    1
+}
+
+fn default_llm_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_llm_distributed_peers() -> usize {
+    3
+}
+
+fn default_llm_distributed_quorum() -> usize {
+    2
+}
+
+fn default_llm_distributed_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_llm_admission_bytes_per_token() -> u64 {
+    512 * 1024
+}
+
+fn default_llm_consensus_cpu_share() -> f64 {
+    0.25
+}
+
+fn default_llm_admission_max_waiting() -> usize {
+    32
+}
+
+fn default_llm_startup_cache() -> bool {
+    true
+}
+
+fn default_llm_draft_tokens() -> usize {
+    4
+}
+
+/// How a model file stores its weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelFormat {
+    /// f16 weights, as published.
+    Safetensors,
+    /// Quantized weights in llama.cpp's format.
+    Gguf,
+}
+
+impl std::fmt::Display for ModelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelFormat::Safetensors => f.write_str("safetensors"),
+            ModelFormat::Gguf => f.write_str("gguf"),
+        }
+    }
+}
+
+/// How `LightLLM::embed` pools a text's per-token hidden states into one
+/// vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// The average over every token.
+    #[default]
+    Mean,
+    /// The last token's, which in a causal model has seen the whole text.
+    LastToken,
+}
+
+/// Where the model runs, as `llm.device` spells it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpec {
+    /// "auto": the first GPU found.
+    Auto,
+    /// "cpu"
+    Cpu,
+    /// "cuda:<n>"
+    Cuda(usize),
+    /// "cuda:<n>,cuda:<m>": the model's layers split over two devices.
+    CudaSplit(usize, usize),
+    /// "metal"
+    Metal,
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let cuda = |device: &str| {
+            device.trim()
+                .strip_prefix("cuda:")
+                .and_then(|ordinal| ordinal.parse::<usize>().ok())
+                .ok_or_else(|| format!("expected cuda:<index>, found {:?}", device))
+        };
+        match spec.trim() {
+            "auto" => Ok(DeviceSpec::Auto),
+            "cpu" => Ok(DeviceSpec::Cpu),
+            "metal" => Ok(DeviceSpec::Metal),
+            spec if spec.contains(',') => match spec.split(',').collect::<Vec<_>>().as_slice() {
+                [first, second] => match (cuda(first)?, cuda(second)?) {
+                    (first, second) if first == second => Err(format!("cuda:{} is named twice", first)),
+                    (first, second) => Ok(DeviceSpec::CudaSplit(first, second)),
+                },
+                _ => Err("layers can be split over two CUDA devices at most".to_string()),
+            },
+            spec if spec.starts_with("cuda") => cuda(spec).map(DeviceSpec::Cuda),
+            spec => Err(format!("unknown device {:?}; expected cpu, cuda:<index>, metal or auto", spec)),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSpec::Auto => f.write_str("auto"),
+            DeviceSpec::Cpu => f.write_str("cpu"),
+            DeviceSpec::Cuda(ordinal) => write!(f, "cuda:{}", ordinal),
+            DeviceSpec::CudaSplit(first, second) => write!(f, "cuda:{},cuda:{}", first, second),
+            DeviceSpec::Metal => f.write_str("metal"),
+        }
+    }
+}
+
+/// How `LightLLM::chat` lays out a conversation, as `llm.prompt_template`
+/// spells it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateSpec {
+    /// "llama2"
+    Llama2,
+    /// "chatml"
+    ChatMl,
+    /// "raw"
+    Raw,
+    /// Any other value: a file holding a template with `{system}` and
+    /// `{messages}` placeholders.
+    File(PathBuf),
+}
+
+impl FromStr for TemplateSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.trim() {
+            "llama2" => Ok(TemplateSpec::Llama2),
+            "chatml" => Ok(TemplateSpec::ChatMl),
+            "raw" => Ok(TemplateSpec::Raw),
+            "" => Err("expected llama2, chatml, raw or a template file".to_string()),
+            path => Ok(TemplateSpec::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl std::fmt::Display for TemplateSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateSpec::Llama2 => f.write_str("llama2"),
+            TemplateSpec::ChatMl => f.write_str("chatml"),
+            TemplateSpec::Raw => f.write_str("raw"),
+            TemplateSpec::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LLMConfig {
     pub enabled: bool,
     pub model_path: String,
     pub tokenizer_path: String,
     pub max_batch_size: usize,
+    /// Requests waiting for a place in the batch before more are refused.
+    #[serde(default = "default_llm_max_queue_depth")]
+    pub max_queue_depth: usize,
+    /// How long to wait after a request for others to batch with it.
+    #[serde(default = "default_llm_batch_window_ms")]
+    pub batch_window_ms: u64,
+    /// When unset the model runs on the CPU, whatever `device` says.
     pub use_gpu: bool,
+    /// "auto", "cpu", "cuda:<n>", "cuda:<n>,cuda:<m>" or "metal".
+    #[serde(default = "default_llm_device")]
+    pub device: String,
+    /// Detected from the model file when unset.
+    #[serde(default)]
+    pub format: Option<ModelFormat>,
+    #[serde(default)]
+    pub pooling: Pooling,
+    /// Hex digests the files must match before they are loaded.
+    #[serde(default)]
+    pub model_sha256: Option<String>,
+    #[serde(default)]
+    pub tokenizer_sha256: Option<String>,
+    /// The `config.json` giving a safetensors model's architecture, when
+    /// it is not beside the model.
+    #[serde(default)]
+    pub model_config_path: Option<String>,
+    /// "llama2", "chatml", "raw", or the path of a custom template.
+    #[serde(default = "default_llm_prompt_template")]
+    pub prompt_template: String,
+    /// Threads that own the model and run its work, apart from the async
+    /// runtime.
+    #[serde(default = "default_llm_worker_threads")]
+    pub worker_threads: usize,
+    /// Greedy or seeded completions kept to answer the same request again;
+    /// zero turns the cache off.
+    #[serde(default)]
+    pub cache_entries: usize,
+    /// How long a cached completion is served.
+    #[serde(default = "default_llm_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    /// Peers a `distributed` request is sent to.
+    #[serde(default = "default_llm_distributed_peers")]
+    pub distributed_peers: usize,
+    /// Peers that must return the same tokens for their answer to be
+    /// taken; more than half of `distributed_peers`.
+    #[serde(default = "default_llm_distributed_quorum")]
+    pub distributed_quorum: usize,
+    /// How long each peer has to answer.
+    #[serde(default = "default_llm_distributed_timeout_ms")]
+    pub distributed_timeout_ms: u64,
+    /// Memory a request is taken to hold per prompt or completion token
+    /// when deciding whether it fits.
+    #[serde(default = "default_llm_admission_bytes_per_token")]
+    pub admission_bytes_per_token: u64,
+    /// Share of the CPUs kept for consensus; the rest run LLM requests.
+    #[serde(default = "default_llm_consensus_cpu_share")]
+    pub consensus_cpu_share: f64,
+    /// Requests waiting for capacity before more are refused.
+    #[serde(default = "default_llm_admission_max_waiting")]
+    pub admission_max_waiting: usize,
+    /// Keeps file hashes, the parsed tokenizer and the tensor index under
+    /// `storage_path/llm-cache` so unchanged files load faster next start.
+    #[serde(default = "default_llm_startup_cache")]
+    pub startup_cache: bool,
+    /// A smaller model sharing the tokenizer, which proposes tokens for
+    /// the model to check in one pass.
+    #[serde(default)]
+    pub draft_model_path: Option<String>,
+    /// The draft model's tokenizer, when not `tokenizer_path`.
+    #[serde(default)]
+    pub draft_tokenizer_path: Option<String>,
+    /// Tokens the draft model proposes per round.
+    #[serde(default = "default_llm_draft_tokens")]
+    pub draft_tokens: usize,
+}
+
+impl LLMConfig {
+    /// The device to load onto: `device`, or the CPU without `use_gpu`.
+    pub fn device_spec(&self) -> Result<DeviceSpec, ConfigError> {
+        let spec = DeviceSpec::from_str(&self.device)
+            .map_err(|e| ConfigError::InvalidConsensusParameter(format!("llm.device: {}", e)))?;
+        Ok(if self.use_gpu { spec } else { DeviceSpec::Cpu })
+    }
+
+    pub fn template_spec(&self) -> Result<TemplateSpec, ConfigError> {
+        TemplateSpec::from_str(&self.prompt_template)
+            .map_err(|e| ConfigError::InvalidConsensusParameter(format!("llm.prompt_template: {}", e)))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_batch_size == 0 {
+            return Err(ConfigError::InvalidConsensusParameter("llm.max_batch_size must be at least 1".to_string()));
+        }
+        if self.max_queue_depth == 0 {
+            return Err(ConfigError::InvalidConsensusParameter("llm.max_queue_depth must be at least 1".to_string()));
+        }
+        if self.admission_max_waiting == 0 {
+            return Err(ConfigError::InvalidConsensusParameter("llm.admission_max_waiting must be at least 1".to_string()));
+        }
+        if !(0.0..1.0).contains(&self.consensus_cpu_share) {
+            return Err(ConfigError::InvalidConsensusParameter("llm.consensus_cpu_share must be at least 0 and below 1".to_string()));
+        }
+        if self.worker_threads == 0 {
+            return Err(ConfigError::InvalidConsensusParameter("llm.worker_threads must be at least 1".to_string()));
+        }
+        if self.draft_tokens == 0 {
+            return Err(ConfigError::InvalidConsensusParameter("llm.draft_tokens must be at least 1".to_string()));
+        }
+        if self.distributed_quorum * 2 <= self.distributed_peers || self.distributed_quorum > self.distributed_peers {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "llm.distributed_quorum must be a majority of llm.distributed_peers".to_string(),
+            ));
+        }
+        for (key, digest) in [("model_sha256", &self.model_sha256), ("tokenizer_sha256", &self.tokenizer_sha256)] {
+            let valid = digest.as_ref().map_or(true, |d| d.len() == 64 && d.chars().all(|c| c.is_ascii_hexdigit()));
+            if !valid {
+                return Err(ConfigError::InvalidConsensusParameter(format!("llm.{} must be 64 hex digits", key)));
+            }
+        }
+        self.device_spec()?;
+        if let TemplateSpec::File(path) = self.template_spec()? {
+            if !path.is_file() {
+                return Err(ConfigError::StoragePath(format!("LLM prompt template file not found: {}", path.display())));
+            }
+        }
+        if let Some(path) = &self.model_config_path {
+            if !Path::new(path).is_file() {
+                return Err(ConfigError::StoragePath(format!("LLM model config file not found: {}", path)));
+            }
+        }
+        self.validate_files()
+    }
+
+    /// Checks the model files exist and the device can be had, before
+    /// anything is loaded.
+    #[cfg(feature = "llm")]
+    fn validate_files(&self) -> Result<(), ConfigError> {
+        let model_path = Path::new(&self.model_path);
+        crate::llm::check_model_files(model_path, Path::new(&self.tokenizer_path))?;
+        let format = match self.format {
+            Some(format) => format,
+            None => crate::llm::detect_format(model_path)?,
+        };
+        crate::llm::check_device(self.device_spec()?, format)?;
+        if let Some(draft_path) = &self.draft_model_path {
+            let tokenizer_path = self.draft_tokenizer_path.as_ref().unwrap_or(&self.tokenizer_path);
+            crate::llm::check_model_files(Path::new(draft_path), Path::new(tokenizer_path))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "llm"))]
+    fn validate_files(&self) -> Result<(), ConfigError> {
+        let files = [
+            ("model", Some(&self.model_path)),
+            ("tokenizer", Some(&self.tokenizer_path)),
+            ("draft model", self.draft_model_path.as_ref()),
+            ("draft tokenizer", self.draft_tokenizer_path.as_ref()),
+        ];
+        for (what, path) in files.into_iter().filter_map(|(what, path)| Some((what, path?))) {
+            if !Path::new(path).exists() {
+                return Err(ConfigError::StoragePath(format!("LLM {} file not found: {}", what, path)));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for NodeConfig {
     fn default() -> Self {
         NodeConfig {
             node_id: uuid::Uuid::new_v4().to_string(),
+            chain_id: default_chain_id(),
             host: "127.0.0.1".to_string(),
             port: 8000,
             storage_path: "./data".to_string(),
@@ -54,15 +729,73 @@ impl Default for NodeConfig {
                 "testnet.dadbs.io:8000".to_string(),
                 "testnet2.dadbs.io:8000".to_string(),
             ],
+            dns_seed: DnsSeedConfig::default(),
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_reconnect_backoff_ms: default_max_reconnect_backoff_ms(),
+            peer_ban_duration_secs: default_peer_ban_duration_secs(),
+            compression: default_compression(),
+            gossip_fanout: default_gossip_fanout(),
+            dial_back: false,
+            max_fork_depth: DEFAULT_MAX_FORK_DEPTH,
+            min_fee: DEFAULT_MIN_FEE,
+            shutdown_deadline_ms: DEFAULT_SHUTDOWN_DEADLINE_MS,
+            heartbeat_interval_ms: default_heartbeat_interval_ms(),
+            genesis_path: None,
+            tx_trace_capacity: DEFAULT_TX_TRACE_CAPACITY,
+            signature_scheme: SchemeKind::default(),
+            validators: Vec::new(),
+            quorum: QuorumConfig::default(),
+            liveness: LivenessConfig::default(),
+            storage: StorageConfig::default(),
+            mempool: MempoolConfig::default(),
+            sig_verify: SigVerifyConfig::default(),
+            rpc: RpcConfig::default(),
+            grpc: GrpcConfig::default(),
+            metrics: MetricsConfig::default(),
+            health: HealthConfig::default(),
+            limits: LimitsConfig::default(),
+            admin: AdminConfig::default(),
+            api_keys: ApiKeysConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            validator_oracle: ValidatorOracleConfig::default(),
+            keystore: KeystoreConfig::default(),
+            nat: NatConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            moderation: ModerationConfig::default(),
+            faucet: FaucetConfig::default(),
+            journal: JournalConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            hooks: HooksConfig::default(),
+            deposit_watcher: DepositWatcherConfig::default(),
+            snapshot: None,
+            slashing: None,
+            remote_signer: None,
+            validator_key: None,
             llm: None,
         }
     }
 }
 
 impl NodeConfig {
+    pub fn for_profile(profile: ConfigProfile) -> Self {
+        match profile {
+            ConfigProfile::Testnet => NodeConfig::default(),
+            ConfigProfile::Local => NodeConfig {
+                bootstrap_nodes: Vec::new(),
+                ..NodeConfig::default()
+            },
+        }
+    }
+
     pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::load_with_overrides(path, &ConfigOverrides::default())
+    }
+
+    /// Loads `path`, applies `overrides` over it, then validates the result.
+    pub fn load_with_overrides(path: &Path, overrides: &ConfigOverrides) -> Result<Self, ConfigError> {
         let config_str = fs::read_to_string(path)?;
         let mut config: NodeConfig = toml::from_str(&config_str)?;
+        overrides.apply(&mut config);
         
        
         config.validate()?;
@@ -75,24 +808,10 @@ impl NodeConfig {
             })?;
         }
 
-       
-        if let Some(llm_config) = &config.llm {
-            if llm_config.enabled {
-                let model_path = Path::new(&llm_config.model_path);
-                if !model_path.exists() {
-                    return Err(ConfigError::StoragePath(
-                        format!("LLM model file not found: {}", model_path.display())
-                    ));
-                }
-                
-               
-                let tokenizer_path = Path::new(&llm_config.tokenizer_path);
-                if !tokenizer_path.exists() {
-                    return Err(ConfigError::StoragePath(
-                        format!("LLM tokenizer file not found: {}", tokenizer_path.display())
-                    ));
-                }
-            }
+        Genesis::from_config(&config)?;
+
+        if let Some(llm_config) = config.llm.as_ref().filter(|llm| llm.enabled) {
+            llm_config.validate()?;
         }
 
         Ok(config)
@@ -102,8 +821,7 @@ impl NodeConfig {
        
         self.validate()?;
         
-        let config_str = toml::to_string_pretty(self)
-            .map_err(ConfigError::Toml)?;
+        let config_str = toml::to_string_pretty(self)?;
         
         if let Some(parent) = path.parent() {
             if !parent.exists() {
@@ -123,6 +841,10 @@ impl NodeConfig {
 
        
         for node in &self.bootstrap_nodes {
+            if dns_seed::is_dns_seed(node) {
+                node.parse::<DnsSeed>().map_err(|_| ConfigError::InvalidBootstrapNode(node.clone()))?;
+                continue;
+            }
             node.to_socket_addrs()
                 .map_err(|_| ConfigError::InvalidBootstrapNode(node.clone()))?;
         }
@@ -142,6 +864,138 @@ impl NodeConfig {
             warn!("Very low consensus_timeout ({}ms), this might cause consensus issues", self.consensus_timeout);
         }
 
+        if self.gossip_fanout == 0 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "gossip_fanout must be at least 1".to_string()
+            ));
+        }
+
+        if self.max_reconnect_backoff_ms < 1000 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "max_reconnect_backoff_ms must be at least 1000".to_string()
+            ));
+        }
+
+        if self.max_frame_bytes < 1024 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "max_frame_bytes must be at least 1024".to_string()
+            ));
+        }
+
+        if self.shutdown_deadline_ms == 0 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "shutdown_deadline_ms must be at least 1".to_string()
+            ));
+        }
+
+        if self.heartbeat_interval_ms == 0 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "heartbeat_interval_ms must be at least 1".to_string()
+            ));
+        }
+
+        if self.max_fork_depth == 0 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "max_fork_depth must be at least 1".to_string()
+            ));
+        }
+
+        crypto::scheme(self.signature_scheme)
+            .map_err(|e| ConfigError::InvalidConsensusParameter(e.to_string()))?;
+        let validators = ValidatorSet::try_new(self.validators.clone())
+            .map_err(|e| ConfigError::InvalidConsensusParameter(e.to_string()))?;
+        if !validators.is_empty() && validators.scheme().ok() != Some(self.signature_scheme) {
+            return Err(ConfigError::InvalidConsensusParameter(format!(
+                "signature_scheme {} does not match the validator set", self.signature_scheme
+            )));
+        }
+
+        self.quorum.build()
+            .map_err(|e| ConfigError::InvalidConsensusParameter(e.to_string()))?;
+
+        if self.liveness.quarantine_after == 0 || self.liveness.reinstate_after == 0 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "liveness thresholds must be at least 1".to_string()
+            ));
+        }
+
+        self.limits.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.mempool.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.sig_verify.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.api_keys.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.webhooks.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.validator_oracle.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.moderation.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.faucet.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.journal.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.telemetry.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.hooks.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.deposit_watcher.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        self.dns_seed.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+
+        if self.rpc.listen.parse::<std::net::SocketAddr>().is_err() {
+            return Err(ConfigError::InvalidAddress(format!("rpc.listen {}", self.rpc.listen)));
+        }
+        if self.grpc.enabled && self.grpc.listen.parse::<std::net::SocketAddr>().is_err() {
+            return Err(ConfigError::InvalidAddress(format!("grpc.listen {}", self.grpc.listen)));
+        }
+        if self.metrics.listen.parse::<std::net::SocketAddr>().is_err() {
+            return Err(ConfigError::InvalidAddress(format!("metrics.listen {}", self.metrics.listen)));
+        }
+        if self.health.check_timeout_ms == 0 {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "health.check_timeout_ms must be at least 1".to_string()
+            ));
+        }
+
+        if self.storage.prune_keep_blocks == Some(0) {
+            return Err(ConfigError::InvalidConsensusParameter(
+                "storage.prune_keep_blocks must be at least 1".to_string()
+            ));
+        }
+
+        if let Some(snapshot) = &self.snapshot {
+            if snapshot.trusted_validators.is_empty() && self.validators.is_empty() {
+                return Err(ConfigError::InvalidConsensusParameter(
+                    "snapshot bootstrap needs trusted_validators or a genesis validator set".to_string()
+                ));
+            }
+        }
+
+        if let Some(slashing) = &self.slashing {
+            if slashing.enabled {
+                slashing.target()?;
+                slashing.stake_accounts()?;
+                if slashing.submit_interval_ms == 0 {
+                    return Err(ConfigError::InvalidSlashingConfig("submit_interval_ms must be at least 1".to_string()));
+                }
+                if slashing.penalty_bps == 0 || slashing.penalty_bps > 10_000 {
+                    return Err(ConfigError::InvalidSlashingConfig(
+                        format!("penalty_bps must be between 1 and 10000, got {}", slashing.penalty_bps)
+                    ));
+                }
+                if !Path::new(&slashing.authority_keypair_path).exists() {
+                    return Err(ConfigError::InvalidSlashingConfig(
+                        format!("slashing authority keypair not found: {}", slashing.authority_keypair_path)
+                    ));
+                }
+            }
+        }
+
+        if let Some(remote_signer) = &self.remote_signer {
+            remote_signer.validate().map_err(ConfigError::InvalidConsensusParameter)?;
+        }
+        if let Some(key) = &self.validator_key {
+            if self.remote_signer.is_some() {
+                return Err(ConfigError::InvalidConsensusParameter(
+                    "validator_key and [remote_signer] cannot both sign votes".to_string()
+                ));
+            }
+            if !Path::new(key).exists() {
+                return Err(ConfigError::InvalidConsensusParameter(format!("validator_key not found: {}", key)));
+            }
+        }
+
         
         let storage_path = Path::new(&self.storage_path);
         if storage_path.exists() && !storage_path.is_dir() {
@@ -153,3 +1007,87 @@ impl NodeConfig {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn llm(use_gpu: bool, device: &str) -> LLMConfig {
+        toml::from_str(&format!(
+            "enabled = true\nmodel_path = \"model.gguf\"\ntokenizer_path = \"tokenizer.json\"\n\
+             max_batch_size = 1\nuse_gpu = {}\ndevice = {:?}\n",
+            use_gpu, device
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_device_strings_parse() {
+        for (device, spec) in [
+            ("auto", DeviceSpec::Auto),
+            ("cpu", DeviceSpec::Cpu),
+            ("metal", DeviceSpec::Metal),
+            ("cuda:0", DeviceSpec::Cuda(0)),
+            (" cuda:12 ", DeviceSpec::Cuda(12)),
+            ("cuda:0,cuda:1", DeviceSpec::CudaSplit(0, 1)),
+            ("cuda:1, cuda:0", DeviceSpec::CudaSplit(1, 0)),
+        ] {
+            assert_eq!(DeviceSpec::from_str(device), Ok(spec), "{}", device);
+            assert_eq!(llm(true, device).device_spec().unwrap(), spec);
+            assert_eq!(DeviceSpec::from_str(&spec.to_string()), Ok(spec));
+        }
+        for device in ["", "gpu", "cuda", "cuda:", "cuda:-1", "cuda:x", "cuda:0,cuda:0", "cuda:0,cuda:1,cuda:2", "cuda:0,metal"] {
+            assert!(DeviceSpec::from_str(device).is_err(), "{}", device);
+            assert!(matches!(llm(true, device).validate(), Err(ConfigError::InvalidConsensusParameter(_))), "{}", device);
+        }
+    }
+
+    #[test]
+    fn test_prompt_template_strings_parse() {
+        assert_eq!(llm(false, "cpu").template_spec().unwrap(), TemplateSpec::Llama2);
+        for (template, spec) in [
+            ("llama2", TemplateSpec::Llama2),
+            ("chatml", TemplateSpec::ChatMl),
+            (" raw ", TemplateSpec::Raw),
+            ("templates/alpaca.txt", TemplateSpec::File(PathBuf::from("templates/alpaca.txt"))),
+        ] {
+            assert_eq!(TemplateSpec::from_str(template), Ok(spec.clone()), "{}", template);
+            assert_eq!(TemplateSpec::from_str(&spec.to_string()), Ok(spec));
+        }
+        assert!(TemplateSpec::from_str(" ").is_err());
+        let missing = LLMConfig { prompt_template: "no/such/template.txt".to_string(), ..llm(false, "cpu") };
+        assert!(matches!(missing.validate(), Err(ConfigError::StoragePath(_))));
+    }
+
+    #[test]
+    fn test_device_defaults_and_cpu_without_gpu() {
+        let config: LLMConfig = toml::from_str(
+            "enabled = true\nmodel_path = \"m\"\ntokenizer_path = \"t\"\nmax_batch_size = 1\nuse_gpu = true\n",
+        )
+        .unwrap();
+        assert_eq!(config.device, "auto");
+        assert_eq!(config.device_spec().unwrap(), DeviceSpec::Auto);
+        assert_eq!(llm(false, "cuda:1").device_spec().unwrap(), DeviceSpec::Cpu);
+        // A bad device is refused even when unused.
+        assert!(llm(false, "tpu").device_spec().is_err());
+    }
+
+    #[test]
+    fn test_slashing_stake_accounts_parse_once_each() {
+        let (validator, first, second) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut config: SlashingConfig = toml::from_str(&format!(
+            "enabled = true\nrpc_url = \"r\"\nauthority_keypair_path = \"k\"\nprogram_id = \"{}\"\n\
+             treasury_account = \"{}\"\n\
+             stake_accounts = [{{ validator = \"{}\", stake_account = \"{}\" }}]\n",
+            Pubkey::new_unique(), Pubkey::new_unique(), validator, first,
+        ))
+        .unwrap();
+        assert_eq!(config.submit_interval_ms, 10_000);
+        assert_eq!(config.stake_accounts().unwrap(), HashMap::from([(validator, first)]));
+        let listed_again = SlashableStake { validator: validator.to_string(), stake_account: second.to_string() };
+        config.stake_accounts.push(listed_again);
+        assert!(matches!(config.stake_accounts(), Err(ConfigError::InvalidSlashingConfig(_))));
+        config.treasury_account = "treasury".to_string();
+        assert!(matches!(config.target(), Err(ConfigError::InvalidSlashingConfig(_))));
+    }
+}
@@ -1,62 +1,1448 @@
 use solana_sdk::{
     hash::Hash,
-    signature::Signature,
+    pubkey::Pubkey,
+    signature::Keypair,
 };
+use futures::future::join_all;
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use super::block::{Block, BLOCK_VERSION};
+use super::bloom::DEFAULT_ADDRESS_BLOOM_FP_PPM;
+use super::config::{ConfigError, NodeConfig};
+use super::consensus_metrics::ConsensusMetrics;
+use super::control::{ConsensusControl, ControlError, HaltReason, HaltStatus};
+use super::evidence::EvidencePool;
+use super::fork_choice::{BlockTree, ChainUpdate, ForkChoiceError};
+use super::handover::HandoverRegistry;
+use super::health::DEFAULT_MAX_CLOCK_SKEW_MS;
+use super::heartbeat::{Heartbeat, HeartbeatError, NetworkView, PeerLiveness};
+use super::hooks::{HookEvent, Hooks};
+use super::journal::Journal;
+use super::liveness::{LivenessConfig, LivenessTracker, ValidatorHealth};
+use super::mempool::Mempool;
+use super::network::PeerId;
+use super::params::{ParamChange, ParamsError, ParamsSchedule, ProtocolParams};
+use super::proposal::Proposal;
+use super::quorum::QuorumPolicy;
+use super::sig_verify::{self, SigVerifyPool, SignedMessage};
+use super::signer::{SignerError, VoteSigner};
+use super::snapshot::Snapshot;
+use super::state::{State, StateError};
+use super::storage::Storage;
+use super::subscriptions::ChainEvents;
+use super::transaction::{Transaction, TxKind};
+use super::tx_trace::TxTracer;
+use super::validation::{BatchLedger, ValidationResult, ValidationStage};
+use super::validator::{Validator, ValidatorSet};
+use super::vote::{CertificateError, CommitCertificate, Vote, VoteOutcome, VoteSet};
+
+pub mod driver;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+
+const PARALLEL_VERIFY_THRESHOLD: usize = 64;
+/// Local state roots kept for comparison with peers' heartbeats.
+const RETAINED_STATE_ROOTS: usize = 256;
+/// What LLM capacity shrinks to after a slow batch, and for how many
+/// consensus timeouts.
+const LLM_PRESSURE_FACTOR: f64 = 0.5;
+const LLM_PRESSURE_TIMEOUTS: u32 = 4;
+
 pub struct ConsensusManager {
-    last_block_hash: Hash,
-    validators: Vec<Validator>,
+    block_tree: BlockTree,
+    validators: Vec<Arc<dyn Validator>>,
+    validator_set: ValidatorSet,
+    liveness: Mutex<LivenessTracker>,
+    peer_liveness: Mutex<PeerLiveness>,
+    vote_sets: BTreeMap<(u64, u32), VoteSet>,
+    evidence_pool: Option<Arc<EvidencePool>>,
+    sig_pool: Option<Arc<SigVerifyPool>>,
+    state: Option<Arc<RwLock<State>>>,
+    state_fault: Option<String>,
+    state_roots: BTreeMap<u64, Hash>,
+    events: Option<ChainEvents>,
+    hooks: Option<Hooks>,
+    tracer: Option<TxTracer>,
+    receipts: Option<Arc<Storage>>,
+    handovers: HandoverRegistry,
+    journal: Option<Arc<Journal>>,
+    control: ConsensusControl,
+    highest_finalized: u64,
+    params: ParamsSchedule,
+    /// False positive rate the address filters of built blocks are sized for.
+    address_bloom_fp_ppm: u32,
+    /// How far ahead of our clock a proposal may be timestamped.
+    max_clock_skew_ms: i64,
+    quorum: Arc<dyn QuorumPolicy>,
+    metrics: Arc<ConsensusMetrics>,
     consensus_timeout: Duration,
     last_consensus: Instant,
+    last_finalized: Instant,
 }
 
 impl ConsensusManager {
-    pub fn new(timeout: Duration) -> Self {
+    pub fn new(timeout: Duration, max_fork_depth: u64, quorum: Arc<dyn QuorumPolicy>) -> Self {
         ConsensusManager {
-            last_block_hash: Hash::default(),
+            block_tree: BlockTree::new(Block::genesis(), max_fork_depth),
             validators: Vec::new(),
+            validator_set: ValidatorSet::default(),
+            liveness: Mutex::new(LivenessTracker::default()),
+            peer_liveness: Mutex::new(PeerLiveness::default()),
+            vote_sets: BTreeMap::new(),
+            evidence_pool: None,
+            sig_pool: None,
+            state: None,
+            state_fault: None,
+            state_roots: BTreeMap::new(),
+            events: None,
+            hooks: None,
+            tracer: None,
+            receipts: None,
+            handovers: HandoverRegistry::new(),
+            journal: None,
+            control: ConsensusControl::new(),
+            highest_finalized: 0,
+            params: ParamsSchedule::new(ProtocolParams { min_fee: 0, ..ProtocolParams::default() }),
+            address_bloom_fp_ppm: DEFAULT_ADDRESS_BLOOM_FP_PPM,
+            max_clock_skew_ms: DEFAULT_MAX_CLOCK_SKEW_MS,
+            quorum,
+            metrics: Arc::new(ConsensusMetrics::new()),
             consensus_timeout: timeout,
             last_consensus: Instant::now(),
+            last_finalized: Instant::now(),
+        }
+    }
+
+    pub fn from_config(config: &NodeConfig) -> Result<Self, ConfigError> {
+        let quorum = config.quorum.build()
+            .map_err(|e| ConfigError::InvalidConsensusParameter(e.to_string()))?;
+        Ok(Self::new(
+            Duration::from_millis(config.consensus_timeout),
+            config.max_fork_depth,
+            quorum,
+        ).with_liveness(config.liveness)
+            .with_chain_id(config.chain_id.clone())
+            .with_min_fee(config.min_fee)
+            .with_max_clock_skew_ms(config.health.max_clock_skew_ms)
+            .with_sig_pool(Arc::new(SigVerifyPool::new(&config.sig_verify))))
+    }
+
+    /// Signs, builds and accepts only blocks, votes and transactions for
+    /// `chain_id`.
+    pub fn with_chain_id(mut self, chain_id: impl Into<String>) -> Self {
+        self.params = self.params.with_chain_id(chain_id);
+        self
+    }
+
+    pub fn chain_id(&self) -> &str {
+        self.params.chain_id()
+    }
+
+    /// Sets the genesis minimum fee, keeping the other genesis parameters.
+    pub fn with_min_fee(mut self, min_fee: u64) -> Self {
+        let genesis = ProtocolParams { min_fee, ..self.params.params_for_epoch(0).clone() };
+        self.params = ParamsSchedule::new(genesis).with_chain_id(self.params.chain_id());
+        self
+    }
+
+    /// Starts the parameter schedule from `genesis`.
+    pub fn with_params(mut self, genesis: ProtocolParams) -> Self {
+        self.params = ParamsSchedule::new(genesis).with_chain_id(self.params.chain_id());
+        self
+    }
+
+    /// Sizes the address filters of blocks this node builds, as genesis sets.
+    pub fn with_address_bloom_fp_ppm(mut self, fp_ppm: u32) -> Self {
+        self.address_bloom_fp_ppm = fp_ppm;
+        self
+    }
+
+    /// Rejects proposals timestamped more than `max_skew_ms` ahead of our
+    /// clock, so a proposer cannot hold back the next block by dating its own
+    /// in the future.
+    pub fn with_max_clock_skew_ms(mut self, max_skew_ms: i64) -> Self {
+        self.max_clock_skew_ms = max_skew_ms;
+        self
+    }
+
+    /// The minimum fee of the next block.
+    pub fn min_fee(&self) -> u64 {
+        self.next_params().min_fee
+    }
+
+    pub fn params(&self) -> &ParamsSchedule {
+        &self.params
+    }
+
+    /// Parameters for the block built on the finalized tip.
+    pub fn next_params(&self) -> &ProtocolParams {
+        self.params.params_at(self.finalized_height() + 1)
+    }
+
+    /// Checks a block, e.g. one received while syncing, against our chain
+    /// and the parameters of its own epoch rather than the current one.
+    pub fn check_block_params(&self, block: &Block) -> Result<(), ParamsError> {
+        let parent = self.block_tree.block(&block.parent_hash());
+        self.params.check_block(block, parent, &self.validator_set, self.quorum.as_ref())
+    }
+
+    /// Checks a proposed block like `check_block_params`, and that it has the
+    /// current header version and is not timestamped too far ahead of our
+    /// clock. One that fails is an invalid proposal, counted against its
+    /// proposer.
+    pub fn check_proposal(&self, block: &Block) -> Result<(), ParamsError> {
+        self.check_block_params(block).and_then(|()| self.check_new_block(block)).map_err(|e| {
+            warn!("Invalid proposal {} from {}: {}", block.hash(), block.header.proposer, e);
+            self.metrics.record_invalid_proposal(block.header.proposer);
+            e
+        })
+    }
+
+    fn check_new_block(&self, block: &Block) -> Result<(), ParamsError> {
+        if block.header.version != BLOCK_VERSION {
+            return Err(ParamsError::OldVersion { version: block.header.version, required: BLOCK_VERSION });
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let timestamp = block.header.timestamp;
+        if timestamp > now.saturating_add(self.max_clock_skew_ms) {
+            return Err(ParamsError::AheadOfClock { timestamp, now, max_skew_ms: self.max_clock_skew_ms });
+        }
+        Ok(())
+    }
+
+    /// Checks that a parameter change could be included in the next block.
+    fn check_param_change(&self, transaction: &Transaction) -> Result<(), ParamsError> {
+        match &transaction.kind {
+            TxKind::Transfer => Ok(()),
+            TxKind::ParamChange { change, approvals } => self.params.check_change(
+                self.finalized_height() + 1,
+                change,
+                approvals,
+                &self.validator_set,
+                self.quorum.as_ref(),
+            ),
+        }
+    }
+
+    /// Schedules the parameter changes in newly finalized blocks. They were
+    /// checked before inclusion; one that no longer passes is skipped.
+    fn schedule_param_changes(&mut self, update: &ChainUpdate) {
+        for block in &update.finalized {
+            for transaction in &block.transactions {
+                if let TxKind::ParamChange { change, approvals } = &transaction.kind {
+                    self.schedule_param_change(block.height(), change, approvals);
+                }
+            }
+        }
+    }
+
+    /// Replays the parameter changes finalized before a restart, given
+    /// with their heights in chain order.
+    pub fn restore_param_changes(&mut self, changes: impl IntoIterator<Item = (u64, ParamChange, CommitCertificate)>) {
+        for (height, change, approvals) in changes {
+            if height <= self.finalized_height() {
+                self.schedule_param_change(height, &change, &approvals);
+            }
+        }
+    }
+
+    fn schedule_param_change(&mut self, height: u64, change: &ParamChange, approvals: &CommitCertificate) {
+        match self.params.check_change(height, change, approvals, &self.validator_set, self.quorum.as_ref()) {
+            Ok(()) => {
+                info!("Scheduled protocol parameters for epoch {}: {:?}", change.activation_epoch, change.params);
+                self.params.schedule(change.clone());
+            }
+            Err(e) => warn!("Skipping parameter change finalized at height {}: {}", height, e),
+        }
+    }
+
+    pub fn with_liveness(mut self, config: LivenessConfig) -> Self {
+        self.liveness = Mutex::new(LivenessTracker::new(config));
+        self
+    }
+
+    pub fn add_validator(&mut self, validator: Arc<dyn Validator>) {
+        self.validators.push(validator);
+    }
+
+    /// Validators that currently count toward the quorum denominator.
+    pub fn quorum_denominator(&self) -> usize {
+        let liveness = self.liveness.lock();
+        self.validators.iter()
+            .filter(|validator| !liveness.is_quarantined(&validator.pubkey()))
+            .count()
+    }
+
+    /// Whether `confirmations` from the validators added with
+    /// `add_validator` carry a transaction. With none added there is no one
+    /// to ask, and the local checks decide.
+    fn confirmed(&self, confirmations: usize) -> bool {
+        self.validators.is_empty() || self.quorum.is_met(self.quorum_denominator() as u128, confirmations as u128)
+    }
+
+    pub fn validator_health(&self) -> Vec<ValidatorHealth> {
+        let liveness = self.liveness.lock();
+        self.validators.iter()
+            .map(|validator| liveness.health(&validator.pubkey()))
+            .collect()
+    }
+
+    /// Signs a heartbeat announcing our current finalized height and the
+    /// state root we computed for it.
+    pub fn heartbeat(&self, keypair: &Keypair) -> Heartbeat {
+        let height = self.finalized_height();
+        let state_root = self.state_roots.get(&height).copied().unwrap_or_default();
+        let now = chrono::Utc::now().timestamp_millis();
+        Heartbeat::new_signed_with_root(keypair, self.chain_id(), height, state_root, now)
+    }
+
+    pub fn record_heartbeat(&self, from: PeerId, heartbeat: &Heartbeat) -> Result<(), HeartbeatError> {
+        self.record_heartbeat_at(from, heartbeat, chrono::Utc::now().timestamp_millis())
+    }
+
+    /// `record_heartbeat` as of `now_ms`, as a replay runs on the recorded
+    /// clock.
+    pub fn record_heartbeat_at(&self, from: PeerId, heartbeat: &Heartbeat, now_ms: i64) -> Result<(), HeartbeatError> {
+        self.peer_liveness.lock().record(from, heartbeat, self.chain_id(), now_ms)?;
+        self.check_peer_state_roots(heartbeat.height);
+        Ok(())
+    }
+
+    /// Halts if validators holding a quorum of weight agree on a state root
+    /// at `height` that differs from ours.
+    fn check_peer_state_roots(&self, height: u64) {
+        let local_root = match self.state_roots.get(&height) {
+            Some(root) => *root,
+            None => return,
+        };
+
+        let mut weights: BTreeMap<Hash, u128> = BTreeMap::new();
+        for peer in self.peer_liveness.lock().peers() {
+            if peer.last_height == height && peer.last_state_root != Hash::default() {
+                let identity = self.handovers.identity(&peer.validator);
+                *weights.entry(peer.last_state_root).or_insert(0) += self.validator_set.weight_of(&identity);
+            }
+        }
+
+        let total = self.validator_set.total_weight();
+        for (peer_root, weight) in weights {
+            if peer_root != local_root && self.quorum.is_met(total, weight) {
+                self.control.halt(HaltReason::StateRootMismatch { height, local_root, peer_root });
+                return;
+            }
+        }
+    }
+
+    pub fn expire_peers(&self) -> usize {
+        self.peer_liveness.lock().expire(chrono::Utc::now().timestamp_millis())
+    }
+
+    pub fn clock_offset_ms(&self) -> Option<i64> {
+        self.peer_liveness.lock().clock_offset_ms()
+    }
+
+    /// Peers heard from recently and their announced heights, for the RPC layer.
+    pub fn network_view(&self) -> NetworkView {
+        self.peer_liveness.lock().view()
+    }
+
+    pub fn with_evidence_pool(mut self, pool: Arc<EvidencePool>) -> Self {
+        self.evidence_pool = Some(pool);
+        self
+    }
+
+    /// Checks batch signatures on `pool` rather than the runtime's blocking threads.
+    pub fn with_sig_pool(mut self, pool: Arc<SigVerifyPool>) -> Self {
+        self.sig_pool = Some(pool);
+        self
+    }
+
+    pub fn sig_pool(&self) -> Option<Arc<SigVerifyPool>> {
+        self.sig_pool.clone()
+    }
+
+    /// Applies every finalized block to `state` from now on.
+    pub fn with_state(mut self, state: Arc<RwLock<State>>) -> Self {
+        let (height, root) = {
+            let state = state.read();
+            (state.height(), state.root())
+        };
+        self.state_roots.insert(height, root);
+        self.state = Some(state);
+        self
+    }
+
+    /// Publishes every chain update to `events`.
+    pub fn with_events(mut self, events: ChainEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Queues blocks proposed and finalized, reorgs and validator set
+    /// changes for `hooks`.
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Records validation, inclusion and finality of transactions in `tracer`.
+    pub fn with_tracer(mut self, tracer: TxTracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Stores the receipts of each finalized block's transactions in
+    /// `storage` as the block is applied to state.
+    pub fn with_receipt_store(mut self, storage: Arc<Storage>) -> Self {
+        self.receipts = Some(storage);
+        self
+    }
+
+    /// Records each block finalized, with the state root it left, in
+    /// `journal` for a replay to check against.
+    pub fn with_journal(mut self, journal: Arc<Journal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Counts votes by the keys validators handed their identity over to,
+    /// sharing `handovers` with the network.
+    pub fn with_handovers(mut self, handovers: HandoverRegistry) -> Self {
+        self.handovers = handovers;
+        self
+    }
+
+    pub fn handovers(&self) -> &HandoverRegistry {
+        &self.handovers
+    }
+
+    pub fn state(&self) -> Option<Arc<RwLock<State>>> {
+        self.state.clone()
+    }
+
+    /// The first finalized block that could not be applied to state, if any.
+    pub fn state_fault(&self) -> Option<&str> {
+        self.state_fault.as_deref()
+    }
+
+    /// A handle operators can pause and resume consensus through.
+    pub fn control(&self) -> ConsensusControl {
+        self.control.clone()
+    }
+
+    /// Why this node is refusing to vote or propose, for the RPC/health layer.
+    pub fn halt_status(&self) -> Option<HaltStatus> {
+        self.control.status()
+    }
+
+    /// Signs a vote, unless consensus is paused or halted.
+    pub fn sign_vote(&self, keypair: &Keypair, height: u64, round: u32, block_hash: Hash) -> Result<Vote, ControlError> {
+        self.control.ensure_active()?;
+        Ok(Vote::for_chain(keypair, self.chain_id(), height, round, block_hash))
+    }
+
+    /// Has `signer` sign a vote, unless consensus is paused or halted.
+    pub async fn sign_vote_with(
+        &self,
+        signer: &dyn VoteSigner,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<Vote, SignerError> {
+        self.control.ensure_active()?;
+        signer.sign_vote(self.chain_id(), height, round, block_hash).await
+    }
+
+    /// Has `signer` sign `block` as its proposal for `round`, unless
+    /// consensus is paused or halted.
+    pub async fn sign_proposal_with(
+        &self,
+        signer: &dyn VoteSigner,
+        round: u32,
+        block: Block,
+    ) -> Result<Proposal, SignerError> {
+        self.control.ensure_active()?;
+        let signature = signer.sign_proposal(self.chain_id(), block.height(), round, block.hash()).await?;
+        Ok(Proposal { block, round, signature })
+    }
+
+    /// Replaces the block tree, e.g. with one loaded from storage.
+    pub fn restore_block_tree(&mut self, block_tree: BlockTree) {
+        self.block_tree = block_tree;
+        self.check_finalized_height();
+        self.metrics.record_chain_heights(self.head_height(), self.finalized_height());
+    }
+
+    /// Restarts from a verified snapshot: its block becomes the finalized
+    /// root and its accounts replace the tracked state.
+    pub fn bootstrap_from_snapshot(&mut self, snapshot: Snapshot) -> Result<(), StateError> {
+        let tip = snapshot.tip();
+        let height = snapshot.height();
+        let root = snapshot.state.root();
+        if let Some(state) = &self.state {
+            state.write().replace_with(snapshot.state)?;
+        }
+        self.state_roots.clear();
+        self.state_roots.insert(height, root);
+        self.vote_sets.retain(|(vote_height, _), _| *vote_height > height);
+        self.restore_block_tree(BlockTree::new(tip, self.block_tree.max_depth()));
+        Ok(())
+    }
+
+    fn check_finalized_height(&mut self) {
+        let current = self.block_tree.finalized_height();
+        if current < self.highest_finalized {
+            self.control.halt(HaltReason::FinalizedHeightRegression { previous: self.highest_finalized, current });
+            return;
+        }
+        self.highest_finalized = current;
+        self.handovers.set_finalized_height(current);
+    }
+
+    pub fn quorum_policy(&self) -> Arc<dyn QuorumPolicy> {
+        Arc::clone(&self.quorum)
+    }
+
+    pub fn metrics(&self) -> Arc<ConsensusMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    pub fn report_missed_proposal(&self, leader: Pubkey) {
+        self.metrics.record_missed_proposal(leader);
+    }
+
+    pub fn validator_set(&self) -> &ValidatorSet {
+        &self.validator_set
+    }
+
+    pub fn set_validator_set(&mut self, validator_set: ValidatorSet) {
+        self.validator_set = validator_set;
+    }
+
+    /// Replaces the validator set at an epoch boundary, returning the one
+    /// replaced.
+    pub fn replace_set(&mut self, validator_set: ValidatorSet) -> ValidatorSet {
+        if validator_set.hash() != self.validator_set.hash() {
+            info!(
+                "Validator set {} replaced by {}, with {} validators",
+                self.validator_set.hash(),
+                validator_set.hash(),
+                validator_set.len()
+            );
+            if let Some(hooks) = &self.hooks {
+                let (previous, current) = (self.validator_set.clone(), validator_set.clone());
+                hooks.emit(HookEvent::ValidatorSetChange { previous, current });
+            }
         }
+        std::mem::replace(&mut self.validator_set, validator_set)
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.params.epoch_of(self.head_height())
+    }
+
+    /// Votes are counted by the key each validator signs with at the
+    /// vote's height: one it handed over by then is refused.
+    pub fn add_vote(&mut self, vote: Vote) -> Result<VoteOutcome, CertificateError> {
+        let key = (vote.height, vote.round);
+        self.handovers.check(&vote.validator, vote.height)?;
+        let validators = self.handovers.validators_at(&self.validator_set, vote.height);
+        let vote_set = self.vote_sets.entry(key)
+            .or_insert_with(|| VoteSet::new(key.0, key.1));
+        let outcome = vote_set.add_vote(vote, self.params.chain_id(), &validators)?;
+
+        if let VoteOutcome::Equivocation(evidence) = &outcome {
+            warn!("Validator {} equivocated at height {} round {}", evidence.validator(), key.0, key.1);
+            if let Some(pool) = &self.evidence_pool {
+                let epoch = self.params.epoch_of(key.0);
+                if let Err(e) = pool.add(evidence.clone(), self.params.chain_id(), epoch) {
+                    warn!("Failed to record equivocation evidence: {}", e);
+                }
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    pub fn vote_set(&self, height: u64, round: u32) -> Option<&VoteSet> {
+        self.vote_sets.get(&(height, round))
+    }
+
+    pub fn last_block_hash(&self) -> Hash {
+        self.block_tree.head_hash()
+    }
+
+    pub fn head_height(&self) -> u64 {
+        self.block_tree.head_height()
+    }
+
+    pub fn finalized_height(&self) -> u64 {
+        self.block_tree.finalized_height()
+    }
+
+    pub fn block_tree(&self) -> &BlockTree {
+        &self.block_tree
+    }
+
+    /// The state root computed for finalized `height`, while retained.
+    pub fn state_root(&self, height: u64) -> Option<Hash> {
+        self.state_roots.get(&height).copied()
+    }
+
+    pub fn apply_block(&mut self, block: Block, committed_weight: u128) -> Result<ChainUpdate, ForkChoiceError> {
+        let update = self.block_tree.apply_block(block, committed_weight)?;
+        if !update.is_empty() {
+            self.last_consensus = Instant::now();
+        }
+        self.check_finalized_height();
+        self.schedule_param_changes(&update);
+        self.apply_finalized_state(&update);
+        self.journal_finalized(&update);
+        self.publish(&update);
+        self.metrics.record_chain_heights(self.head_height(), self.finalized_height());
+        Ok(update)
+    }
+
+    pub fn finalize_block(&mut self, hash: &Hash) -> Result<ChainUpdate, ForkChoiceError> {
+        let update = self.block_tree.finalize(hash)?;
+        self.check_finalized_height();
+        self.schedule_param_changes(&update);
+        self.apply_finalized_state(&update);
+        self.journal_finalized(&update);
+        self.publish(&update);
+        let finalized = self.block_tree.finalized_height();
+        if !update.finalized.is_empty() {
+            self.metrics.record_round_latency(self.last_finalized.elapsed());
+            self.last_finalized = Instant::now();
+        }
+        self.metrics.record_chain_heights(self.head_height(), finalized);
+        self.record_height_metrics(finalized);
+        self.vote_sets.retain(|(height, _), _| *height > finalized);
+        Ok(update)
+    }
+
+    fn publish(&self, update: &ChainUpdate) {
+        if let Some(events) = &self.events {
+            events.publish(update);
+        }
+        if let Some(hooks) = &self.hooks {
+            hooks.publish(update);
+        }
+        if let Some(tracer) = &self.tracer {
+            tracer.record_update(update);
+        }
+    }
+
+    fn journal_finalized(&self, update: &ChainUpdate) {
+        if let Some(journal) = &self.journal {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            for block in &update.finalized {
+                let state_root = self.state_roots.get(&block.height()).copied().unwrap_or_default();
+                journal.record_finalized(block.height(), block.hash(), state_root, now_ms);
+            }
+        }
+    }
+
+    fn apply_finalized_state(&mut self, update: &ChainUpdate) {
+        let state = match &self.state {
+            Some(state) => Arc::clone(state),
+            None => return,
+        };
+        if self.state_fault.is_some() {
+            return;
+        }
+
+        let mut state = state.write();
+        for block in &update.finalized {
+            let diff = match state.apply_finalized_block(block) {
+                Ok(diff) => diff,
+                Err(e) => {
+                    self.state_fault = Some(e.to_string());
+                    self.control.halt(match e {
+                        StateError::Io(_) | StateError::Serialization(_) => HaltReason::StorageFailure(e.to_string()),
+                        _ => HaltReason::StateFault(e.to_string()),
+                    });
+                    return;
+                }
+            };
+            self.state_roots.insert(block.height(), state.root());
+            if let Some(storage) = &self.receipts {
+                if let Err(e) = storage.put_receipts(&diff.receipts) {
+                    let reason = format!("cannot store receipts of height {}: {}", block.height(), e);
+                    self.state_fault = Some(reason.clone());
+                    self.control.halt(HaltReason::StorageFailure(reason));
+                    return;
+                }
+            }
+            if let Some(events) = &self.events {
+                events.publish_executed(block, diff.receipts);
+            }
+        }
+        while self.state_roots.len() > RETAINED_STATE_ROOTS {
+            self.state_roots.pop_first();
+        }
+    }
+
+    fn record_height_metrics(&self, height: u64) {
+        let rounds: Vec<&VoteSet> = self.vote_sets.range((height, 0)..=(height, u32::MAX))
+            .map(|(_, set)| set)
+            .collect();
+        if rounds.is_empty() {
+            return;
+        }
+
+        let voted: HashSet<Pubkey> = rounds.iter().flat_map(|set| set.voters()).collect();
+        let expected: Vec<Pubkey> = self.validator_set.validators().iter().map(|v| v.pubkey).collect();
+        let voted: Vec<Pubkey> = voted.into_iter().collect();
+        self.metrics.record_height(rounds.len() as u32, &expected, &voted);
     }
 
     pub async fn validate_transaction(&self, transaction: &Transaction) -> bool {
+        let started = Instant::now();
+        let result = self.check_transaction(transaction).await;
+        self.metrics.record_validation(started.elapsed(), result.confirmations(), result.is_accepted());
+        result.is_accepted()
+    }
+
+    /// Validates many transactions with a single confirmation request per
+    /// validator. Results are returned in the order of `transactions`.
+    pub async fn validate_batch(&self, transactions: &[Transaction]) -> Vec<ValidationResult> {
+        let started = Instant::now();
+        let signatures_ok = self.verify_signatures(transactions).await;
+
+        let mut results: Vec<Option<ValidationResult>> = vec![None; transactions.len()];
+        let mut pending = Vec::new();
+        let min_fee = self.min_fee();
+        {
+            let state = self.state.as_ref().map(|state| state.read());
+            let mut ledger = state.as_deref().map(BatchLedger::new);
+            for (index, transaction) in transactions.iter().enumerate() {
+                if transaction.chain_id != self.chain_id() {
+                    results[index] = Some(ValidationResult::rejected(ValidationStage::Chain));
+                } else if transaction.fee < min_fee {
+                    results[index] = Some(ValidationResult::rejected(ValidationStage::Fee));
+                } else if self.check_param_change(transaction).is_err() {
+                    results[index] = Some(ValidationResult::rejected(ValidationStage::Params));
+                } else if !signatures_ok[index] {
+                    results[index] = Some(ValidationResult::rejected(ValidationStage::Signature));
+                } else if !self.verify_timestamp(transaction) {
+                    results[index] = Some(ValidationResult::rejected(ValidationStage::Timestamp));
+                } else if let Some(Err(e)) = ledger.as_mut().map(|ledger| ledger.admit(transaction)) {
+                    results[index] = Some(ValidationResult::Conflict(e));
+                } else {
+                    pending.push(index);
+                }
+            }
+        }
+
+        let candidates: Vec<Transaction> = pending.iter().map(|&i| transactions[i].clone()).collect();
+        let confirmations = self.get_batch_confirmations(&candidates).await;
+        for (index, confirmations) in pending.into_iter().zip(confirmations) {
+            results[index] = Some(if self.confirmed(confirmations) {
+                ValidationResult::Accepted { confirmations }
+            } else {
+                ValidationResult::Rejected { stage: ValidationStage::Quorum, confirmations }
+            });
+        }
+
+        let elapsed = started.elapsed();
+        // A batch that takes half the timeout crowds the next round out, so
+        // inference gives way for a while.
+        if elapsed > self.consensus_timeout / 2 {
+            self.control.shrink_llm_capacity(LLM_PRESSURE_FACTOR, self.consensus_timeout * LLM_PRESSURE_TIMEOUTS);
+        }
+        results.into_iter()
+            .zip(transactions)
+            .map(|(result, transaction)| {
+                let result = result.expect("every transaction receives a result");
+                self.metrics.record_validation(elapsed, result.confirmations(), result.is_accepted());
+                if let Some(tracer) = &self.tracer {
+                    tracer.record_validation(&transaction.hash(), &result);
+                }
+                result
+            })
+            .collect()
+    }
+
+    /// Drains up to `max_transactions` from `mempool` into a block on top of
+    /// the finalized block, as many as fit the block limits of its epoch.
+    /// The block is timestamped `timestamp`, or just after its parent if that
+    /// is later.
+    /// Account checks here are authoritative: anything that conflicts with
+    /// transactions already included is dropped. Fails without touching the
+    /// mempool while consensus is halted.
+    pub async fn build_block(
+        &self,
+        mempool: &mut Mempool,
+        proposer: Pubkey,
+        timestamp: i64,
+        max_transactions: usize,
+    ) -> Result<Block, ControlError> {
+        self.control.ensure_active()?;
+        mempool.set_min_fee(self.min_fee());
+        let mut budget = self.next_params().block_budget();
+        let height = self.finalized_height() + 1;
+        let candidates = mempool.take_fitting(max_transactions, |transaction| {
+            budget.try_add(height, transaction).is_ok()
+        });
+        let results = self.validate_batch(&candidates).await;
+
+        let state = self.state.as_ref().map(|state| state.read());
+        let mut ledger = state.as_deref().map(BatchLedger::new);
+        let mut included = Vec::with_capacity(candidates.len());
+        for (transaction, result) in candidates.into_iter().zip(results) {
+            if !result.is_accepted() {
+                debug!("Dropping transaction {} from block: {:?}", transaction.hash(), result);
+                continue;
+            }
+            if let Some(Err(e)) = ledger.as_mut().map(|ledger| ledger.admit(&transaction)) {
+                debug!("Dropping conflicting transaction {} from block: {}", transaction.hash(), e);
+                continue;
+            }
+            included.push(transaction);
+        }
+
+        let parent = self.block_tree.finalized();
+        let timestamp = timestamp.max(parent.header.timestamp.saturating_add(1));
+        let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), timestamp, proposer, included)
+            .with_chain_id(self.chain_id())
+            .with_address_bloom(self.address_bloom_fp_ppm);
+        if let Some(state) = &state {
+            block.header.state_root = state.root();
+        }
+        if let Some(hooks) = &self.hooks {
+            hooks.emit(HookEvent::BlockProposed(block.clone()));
+        }
+        Ok(block)
+    }
+
+    async fn check_transaction(&self, transaction: &Transaction) -> ValidationResult {
+        if transaction.chain_id != self.chain_id() {
+            return ValidationResult::rejected(ValidationStage::Chain);
+        }
+
+        if transaction.fee < self.min_fee() {
+            return ValidationResult::rejected(ValidationStage::Fee);
+        }
+
+        if let Err(e) = self.check_param_change(transaction) {
+            debug!("Rejecting parameter change {}: {}", transaction.hash(), e);
+            return ValidationResult::rejected(ValidationStage::Params);
+        }
+
        
         if !self.verify_signature(transaction) {
-            return false;
+            return ValidationResult::rejected(ValidationStage::Signature);
         }
 
         
         if !self.verify_timestamp(transaction) {
-            return false;
+            return ValidationResult::rejected(ValidationStage::Timestamp);
+        }
+
+        if let Some(state) = &self.state {
+            if let Err(e) = BatchLedger::new(&state.read()).admit(transaction) {
+                return ValidationResult::Conflict(e);
+            }
         }
 
        
         let confirmations = self.get_validator_confirmations(transaction).await;
         
         
-        confirmations > (self.validators.len() * 2 / 3)
+        if self.confirmed(confirmations) {
+            ValidationResult::Accepted { confirmations }
+        } else {
+            ValidationResult::Rejected { stage: ValidationStage::Quorum, confirmations }
+        }
     }
 
     fn verify_signature(&self, transaction: &Transaction) -> bool {
         transaction.verify_signature()
     }
 
+    /// Ed25519 verification is CPU-bound, so large batches are checked off
+    /// the runtime's threads.
+    async fn verify_signatures(&self, transactions: &[Transaction]) -> Vec<bool> {
+        if transactions.len() < PARALLEL_VERIFY_THRESHOLD {
+            return transactions.iter().map(Transaction::verify_signature).collect();
+        }
+        let signed = transactions.iter().map(SignedMessage::transaction).collect();
+        sig_verify::verify_with(self.sig_pool.as_deref(), signed).await
+    }
+
     fn verify_timestamp(&self, transaction: &Transaction) -> bool {
        
-        let now = Instant::now();
-        let transaction_age = now.duration_since(transaction.timestamp);
-        transaction_age < self.consensus_timeout
+        let now = chrono::Utc::now().timestamp_millis();
+        let transaction_age = now.saturating_sub(transaction.timestamp).unsigned_abs();
+        transaction_age < self.consensus_timeout.as_millis() as u64
     }
 
     async fn get_validator_confirmations(&self, transaction: &Transaction) -> usize {
         let mut confirmations = 0;
         for validator in &self.validators {
-            if validator.verify_transaction(transaction).await {
-                confirmations += 1;
+            let started = Instant::now();
+            let confirmed = tokio::time::timeout(
+                self.consensus_timeout,
+                validator.verify_transaction(transaction),
+            ).await;
+
+            let mut liveness = self.liveness.lock();
+            match confirmed {
+                Ok(confirmed) => {
+                    liveness.record_success(&validator.pubkey(), started.elapsed());
+                    if confirmed {
+                        confirmations += 1;
+                    }
+                }
+                Err(_) => liveness.record_failure(&validator.pubkey()),
             }
         }
         confirmations
     }
+
+    /// Asks every validator about the whole batch concurrently. A validator
+    /// that times out or answers with the wrong number of results confirms
+    /// nothing.
+    async fn get_batch_confirmations(&self, transactions: &[Transaction]) -> Vec<usize> {
+        let mut confirmations = vec![0; transactions.len()];
+        if transactions.is_empty() {
+            return confirmations;
+        }
+
+        let responses = join_all(self.validators.iter().map(|validator| async move {
+            let started = Instant::now();
+            let response = tokio::time::timeout(self.consensus_timeout, validator.verify_batch(transactions)).await;
+            (validator.pubkey(), started.elapsed(), response)
+        })).await;
+
+        let mut liveness = self.liveness.lock();
+        for (pubkey, latency, response) in responses {
+            match response {
+                Ok(confirmed) if confirmed.len() == transactions.len() => {
+                    liveness.record_success(&pubkey, latency);
+                    for (count, confirmed) in confirmations.iter_mut().zip(confirmed) {
+                        if confirmed {
+                            *count += 1;
+                        }
+                    }
+                }
+                Ok(confirmed) => {
+                    warn!(
+                        "Validator {} answered {} of {} batched transactions",
+                        pubkey,
+                        confirmed.len(),
+                        transactions.len()
+                    );
+                    liveness.record_success(&pubkey, latency);
+                }
+                Err(_) => liveness.record_failure(&pubkey),
+            }
+        }
+        confirmations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::quorum::ThresholdPolicy;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    struct TestValidator {
+        pubkey: Pubkey,
+        responsive: AtomicBool,
+        max_amount: u64,
+        batch_requests: AtomicUsize,
+    }
+
+    impl TestValidator {
+        fn new(max_amount: u64) -> Self {
+            TestValidator {
+                pubkey: Pubkey::new_unique(),
+                responsive: AtomicBool::new(true),
+                max_amount,
+                batch_requests: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Validator for TestValidator {
+        fn pubkey(&self) -> Pubkey {
+            self.pubkey
+        }
+
+        async fn verify_transaction(&self, transaction: &Transaction) -> bool {
+            if !self.responsive.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            transaction.amount <= self.max_amount
+        }
+
+        async fn verify_batch(&self, transactions: &[Transaction]) -> Vec<bool> {
+            self.batch_requests.fetch_add(1, Ordering::SeqCst);
+            if !self.responsive.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            transactions.iter().map(|t| t.amount <= self.max_amount).collect()
+        }
+    }
+
+    fn manager_with_validators(count: usize) -> (ConsensusManager, Vec<Arc<TestValidator>>) {
+        let mut manager = ConsensusManager::new(Duration::from_millis(200), 16, Arc::new(ThresholdPolicy::bft()))
+            .with_liveness(LivenessConfig { quarantine_after: 2, reinstate_after: 2 });
+        let validators: Vec<Arc<TestValidator>> = (0..count)
+            .map(|_| Arc::new(TestValidator::new(u64::MAX)))
+            .collect();
+        for validator in &validators {
+            manager.add_validator(validator.clone());
+        }
+        (manager, validators)
+    }
+
+    fn fresh_transaction() -> Transaction {
+        transaction_with_amount(1)
+    }
+
+    fn transaction_with_amount(amount: u64) -> Transaction {
+        let keypair = Keypair::new();
+        Transaction::new_signed(&keypair, Pubkey::new_unique(), amount, 10, 0, chrono::Utc::now().timestamp_millis())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quarantine_shrinks_quorum_denominator() {
+        let (manager, validators) = manager_with_validators(4);
+        validators[3].responsive.store(false, Ordering::SeqCst);
+        assert_eq!(manager.quorum_denominator(), 4);
+
+        manager.get_validator_confirmations(&fresh_transaction()).await;
+        assert_eq!(manager.quorum_denominator(), 4);
+        manager.get_validator_confirmations(&fresh_transaction()).await;
+        assert_eq!(manager.quorum_denominator(), 3);
+
+        let health = manager.validator_health();
+        assert_eq!(health[3].state, crate::node::liveness::LivenessState::Quarantined);
+        assert_eq!(health[3].consecutive_failures, 2);
+        assert!(health[3].response_rate < health[0].response_rate);
+    }
+
+    #[tokio::test]
+    async fn test_local_checks_decide_without_confirming_validators() {
+        let (manager, _validators) = manager_with_validators(0);
+        let mut unsigned = fresh_transaction();
+        unsigned.amount += 1;
+        let results = manager.validate_batch(&[fresh_transaction(), unsigned]).await;
+        assert_eq!(results[0], ValidationResult::Accepted { confirmations: 0 });
+        assert_eq!(results[1].stage(), Some(ValidationStage::Signature));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quarantined_validator_reinstated_after_probes() {
+        let (manager, validators) = manager_with_validators(4);
+        validators[0].responsive.store(false, Ordering::SeqCst);
+        for _ in 0..2 {
+            manager.get_validator_confirmations(&fresh_transaction()).await;
+        }
+        assert_eq!(manager.quorum_denominator(), 3);
+
+        validators[0].responsive.store(true, Ordering::SeqCst);
+        let confirmations = manager.get_validator_confirmations(&fresh_transaction()).await;
+        assert_eq!(confirmations, 4);
+        assert_eq!(manager.quorum_denominator(), 3);
+
+        manager.get_validator_confirmations(&fresh_transaction()).await;
+        assert_eq!(manager.quorum_denominator(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_failing_stage() {
+        let mut manager = ConsensusManager::new(Duration::from_secs(5), 16, Arc::new(ThresholdPolicy::bft()))
+            .with_min_fee(10);
+        let validators = [
+            Arc::new(TestValidator::new(u64::MAX)),
+            Arc::new(TestValidator::new(u64::MAX)),
+            Arc::new(TestValidator::new(100)),
+            Arc::new(TestValidator::new(100)),
+        ];
+        for validator in &validators {
+            manager.add_validator(validator.clone());
+        }
+
+        let mut forged = transaction_with_amount(5);
+        forged.amount = 6;
+        let stale = Transaction::new_signed(
+            &Keypair::new(),
+            Pubkey::new_unique(),
+            5,
+            10,
+            0,
+            chrono::Utc::now().timestamp_millis() - 60_000,
+        );
+        let underpriced = Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), 5, 9, 0, chrono::Utc::now().timestamp_millis());
+        let batch = vec![transaction_with_amount(5), forged, stale, transaction_with_amount(500), underpriced];
+
+        let results = manager.validate_batch(&batch).await;
+        assert_eq!(results[0], ValidationResult::Accepted { confirmations: 4 });
+        assert_eq!(results[1].stage(), Some(ValidationStage::Signature));
+        assert_eq!(results[2].stage(), Some(ValidationStage::Timestamp));
+        assert_eq!(results[3], ValidationResult::Rejected { stage: ValidationStage::Quorum, confirmations: 2 });
+        assert_eq!(results[4].stage(), Some(ValidationStage::Fee));
+
+        for validator in &validators {
+            assert_eq!(validator.batch_requests.load(Ordering::SeqCst), 1);
+        }
+        assert_eq!(manager.metrics().snapshot().validations_accepted, 1);
+        assert_eq!(manager.metrics().snapshot().validations_rejected, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_matches_individual_validation() {
+        let (manager, validators) = manager_with_validators(4);
+        validators[1].responsive.store(false, Ordering::SeqCst);
+
+        let batch: Vec<Transaction> = (0..PARALLEL_VERIFY_THRESHOLD * 2).map(|i| transaction_with_amount(i as u64)).collect();
+        let results = manager.validate_batch(&batch).await;
+        assert_eq!(results.len(), batch.len());
+        for (transaction, result) in batch.iter().zip(&results) {
+            assert_eq!(result, &ValidationResult::Accepted { confirmations: 3 });
+            assert_eq!(manager.check_transaction(transaction).await.is_accepted(), result.is_accepted());
+        }
+        assert!(manager.validate_batch(&[]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_param_change_applies_at_epoch_boundary() {
+        use crate::node::config::DEFAULT_CHAIN_ID;
+        use crate::node::validator::ValidatorInfo;
+        use solana_sdk::signature::Signer;
+
+        let params = |min_fee| ProtocolParams { min_fee, epoch_length: 10, ..ProtocolParams::default() };
+        let (manager, _validators) = manager_with_validators(4);
+        let mut manager = manager.with_params(params(10));
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        manager.set_validator_set(ValidatorSet::new(keys.iter().map(|k| ValidatorInfo::new(k.pubkey(), 1)).collect()));
+
+        let change = ParamChange::new(1, params(20));
+        let approvals = change.certificate(keys.iter().map(|k| change.approve(k, DEFAULT_CHAIN_ID)).collect());
+        let now = chrono::Utc::now().timestamp_millis();
+        let proposal = Transaction::new_param_change(&keys[0], change.clone(), approvals.clone(), 10, 0, now);
+        assert!(manager.check_transaction(&proposal).await.is_accepted());
+        let unapproved = change.certificate(keys[..2].iter().map(|k| change.approve(k, DEFAULT_CHAIN_ID)).collect());
+        let weak = Transaction::new_param_change(&keys[1], change.clone(), unapproved, 10, 0, now);
+        assert_eq!(manager.check_transaction(&weak).await.stage(), Some(ValidationStage::Params));
+
+        let block = Block::with_transactions(1, manager.block_tree().finalized_hash(), 1, Pubkey::new_unique(), vec![proposal]);
+        manager.apply_block(block.clone(), 1).unwrap();
+        manager.finalize_block(&block.hash()).unwrap();
+        while manager.finalized_height() < 8 {
+            finalize_child(&mut manager, None);
+        }
+        // Height 9 is still epoch 0; height 10 starts epoch 1.
+        assert_eq!(manager.min_fee(), 10);
+        assert!(manager.validate_batch(&[fresh_transaction()]).await[0].is_accepted());
+        let last_of_epoch = finalize_child(&mut manager, None);
+        assert_eq!(manager.min_fee(), 20);
+        assert_eq!(manager.validate_batch(&[fresh_transaction()]).await[0].stage(), Some(ValidationStage::Fee));
+
+        // Synced blocks are judged by the parameters of their own epoch.
+        let historical = Block::with_transactions(5, Hash::default(), 1, Pubkey::new_unique(), vec![fresh_transaction()]);
+        assert_eq!(manager.check_block_params(&historical), Ok(()));
+        let (parent, timestamp) = (last_of_epoch.hash(), last_of_epoch.header.timestamp + 1);
+        let current = Block::with_transactions(10, parent, timestamp, Pubkey::new_unique(), vec![fresh_transaction()]);
+        assert_eq!(
+            manager.check_block_params(&current),
+            Err(ParamsError::Underpriced { height: 10, fee: 10, min_fee: 20 })
+        );
+
+        // After a restart the change is replayed from storage.
+        let mut restarted = ConsensusManager::new(Duration::from_secs(5), 16, Arc::new(ThresholdPolicy::bft()))
+            .with_params(params(10));
+        restarted.set_validator_set(manager.validator_set().clone());
+        restarted.restore_block_tree(BlockTree::new(last_of_epoch, 16));
+        restarted.restore_param_changes(vec![(1, change, approvals)]);
+        assert_eq!(restarted.min_fee(), 20);
+    }
+
+    #[test]
+    fn test_finalized_blocks_update_state() {
+        use crate::node::state::State;
+        use crate::utils::DADBSAddress;
+        use solana_sdk::signature::Signer;
+
+        let sender = Keypair::new();
+        let address = DADBSAddress::from_pubkey(&sender.pubkey());
+        let state = Arc::new(RwLock::new(State::in_memory(vec![(address.clone(), 100)])));
+        let mut manager = ConsensusManager::new(Duration::from_secs(5), 16, Arc::new(ThresholdPolicy::bft()))
+            .with_state(Arc::clone(&state));
+
+        let genesis = Block::genesis();
+        let mut good = Block::with_transactions(1, genesis.hash(), 1, Pubkey::new_unique(), vec![
+            Transaction::new_signed(&sender, Pubkey::new_unique(), 40, 10, 0, 0),
+        ]);
+        good.header.state_root = state.read().root();
+        manager.apply_block(good.clone(), 1).unwrap();
+        manager.finalize_block(&good.hash()).unwrap();
+        assert_eq!(state.read().balance(&address), 50);
+        assert!(manager.state_fault().is_none());
+
+        let bad = Block::with_transactions(2, good.hash(), 2, Pubkey::new_unique(), vec![
+            Transaction::new_signed(&sender, Pubkey::new_unique(), 40, 10, 0, 0),
+        ]);
+        manager.apply_block(bad.clone(), 1).unwrap();
+        manager.finalize_block(&bad.hash()).unwrap();
+        assert!(manager.state_fault().unwrap().contains("height 2"));
+        assert_eq!(state.read().height(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transactions_for_another_chain_rejected() {
+        use crate::node::mempool::MempoolError;
+        use solana_sdk::signature::Signer;
+
+        let (manager, _validators) = manager_with_validators(4);
+        let manager = manager.with_chain_id("chain-b");
+        let keypair = Keypair::new();
+        let signed_for = |chain_id: &str| {
+            let mut transaction = Transaction::unsigned(
+                keypair.pubkey(), Pubkey::new_unique(), 5, 10, 0, chrono::Utc::now().timestamp_millis(),
+            );
+            transaction.chain_id = chain_id.to_string();
+            transaction.signature = keypair.sign_message(&transaction.signing_bytes());
+            transaction
+        };
+        let (foreign, ours) = (signed_for("chain-a"), signed_for("chain-b"));
+        assert!(foreign.verify_signature());
+
+        let mut mempool = Mempool::new(1, 100).with_chain_id("chain-b");
+        assert_eq!(
+            mempool.insert(foreign.clone()),
+            Err(MempoolError::WrongChain { expected: "chain-b".to_string(), actual: "chain-a".to_string() })
+        );
+        mempool.insert(ours.clone()).unwrap();
+
+        let results = manager.validate_batch(&[foreign.clone(), ours.clone()]).await;
+        assert_eq!(results[0].stage(), Some(ValidationStage::Chain));
+        assert!(results[1].is_accepted());
+        assert_eq!(manager.check_transaction(&foreign).await.stage(), Some(ValidationStage::Chain));
+
+        let wrong_chain = Err(ParamsError::WrongChain {
+            expected: "chain-b".to_string(),
+            actual: "chain-a".to_string(),
+        });
+        let carrying = Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![foreign])
+            .with_chain_id("chain-b");
+        assert_eq!(manager.check_block_params(&carrying), wrong_chain);
+        let built_elsewhere = Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![ours.clone()])
+            .with_chain_id("chain-a");
+        assert_eq!(manager.check_block_params(&built_elsewhere), wrong_chain);
+        let block = Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![ours.clone()])
+            .with_chain_id("chain-b");
+        assert_eq!(manager.check_block_params(&block), Ok(()));
+
+        // Headers before version 3 name no chain: fine for a synced block,
+        // not for a new proposal.
+        let unnamed = Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![ours]).with_version(2);
+        assert_eq!(manager.check_block_params(&unnamed), Ok(()));
+        let old_version = Err(ParamsError::OldVersion { version: 2, required: BLOCK_VERSION });
+        assert_eq!(manager.check_proposal(&unnamed), old_version);
+    }
+
+    #[tokio::test]
+    async fn test_batch_and_block_reject_double_spends() {
+        use crate::node::state::State;
+        use crate::node::validation::ValidationError;
+        use crate::utils::DADBSAddress;
+        use solana_sdk::signature::Signer;
+
+        let sender = Keypair::new();
+        let address = DADBSAddress::from_pubkey(&sender.pubkey());
+        let state = Arc::new(RwLock::new(State::in_memory(vec![(address, 100)])));
+        let (manager, _validators) = manager_with_validators(4);
+        let manager = manager.with_state(state);
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let first = Transaction::new_signed(&sender, Pubkey::new_unique(), 90, 10, 0, now);
+        let second = Transaction::new_signed(&sender, Pubkey::new_unique(), 90, 10, 1, now);
+        let results = manager.validate_batch(&[first.clone(), second.clone()]).await;
+        assert!(results[0].is_accepted());
+        assert!(matches!(results[1], ValidationResult::Conflict(ValidationError::InsufficientBalance { .. })));
+
+        let mut mempool = Mempool::new(0, 10);
+        mempool.insert(second).unwrap();
+        mempool.insert(first.clone()).unwrap();
+        let block = manager.build_block(&mut mempool, Pubkey::new_unique(), now, 10).await.unwrap();
+        assert_eq!(block.transactions, vec![first]);
+        assert!(mempool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_built_and_checked_against_their_limits() {
+        let (manager, _validators) = manager_with_validators(4);
+        let transactions: Vec<Transaction> = (0..5).map(|_| fresh_transaction()).collect();
+        let (size, cost) = (transactions[0].size() as u64, manager.next_params().transaction_cost(&transactions[0]));
+        assert_eq!(cost, size + manager.next_params().transfer_cost);
+        let build = |limits: ProtocolParams| {
+            let manager = manager_with_validators(4).0.with_params(ProtocolParams { min_fee: 0, ..limits });
+            let mut mempool = Mempool::new(0, 10);
+            for transaction in &transactions {
+                mempool.insert(transaction.clone()).unwrap();
+            }
+            async move {
+                let block = manager.build_block(&mut mempool, Pubkey::new_unique(), 0, 10).await.unwrap();
+                assert_eq!(manager.check_proposal(&block), Ok(()));
+                (block.transactions.len(), mempool.len())
+            }
+        };
+        let defaults = ProtocolParams::default();
+        assert_eq!(build(ProtocolParams { max_block_txs: 3, ..defaults.clone() }).await, (3, 2));
+        assert_eq!(build(ProtocolParams { max_block_bytes: 2 * size, ..defaults.clone() }).await, (2, 3));
+        assert_eq!(build(ProtocolParams { max_block_cost: 4 * cost + 1, ..defaults.clone() }).await, (4, 1));
+
+        let manager = manager.with_params(ProtocolParams { min_fee: 0, max_block_cost: 4 * cost, ..defaults });
+        let proposer = Pubkey::new_unique();
+        let over = Block::with_transactions(1, Hash::default(), 0, proposer, transactions)
+            .with_chain_id(manager.chain_id());
+        assert_eq!(
+            manager.check_proposal(&over),
+            Err(ParamsError::OverLimit { height: 1, limit: "max_block_cost", used: 5 * cost, max: 4 * cost })
+        );
+        assert_eq!(manager.metrics().snapshot().invalid_proposals.get(&proposer.to_string()), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_proposals_are_dated_after_their_parent_and_not_ahead() {
+        let (manager, _validators) = manager_with_validators(4);
+        let manager = manager.with_max_clock_skew_ms(1_000);
+        let genesis = manager.block_tree().finalized().clone();
+        let proposer = Pubkey::new_unique();
+        let dated = |timestamp: i64| {
+            Block::new(1, genesis.hash(), timestamp, proposer).with_chain_id(manager.chain_id())
+        };
+
+        assert_eq!(
+            manager.check_proposal(&dated(genesis.header.timestamp)),
+            Err(ParamsError::NotAfterParent { timestamp: genesis.header.timestamp, parent: genesis.header.timestamp })
+        );
+        let now = chrono::Utc::now().timestamp_millis();
+        assert!(matches!(
+            manager.check_proposal(&dated(now + 60_000)),
+            Err(ParamsError::AheadOfClock { max_skew_ms: 1_000, .. })
+        ));
+        assert_eq!(manager.check_proposal(&dated(now)), Ok(()));
+        assert_eq!(manager.metrics().snapshot().invalid_proposals.get(&proposer.to_string()), Some(&2));
+
+        // A block built in the same millisecond as its parent is dated after it.
+        let mut mempool = Mempool::new(0, 10);
+        let built = manager.build_block(&mut mempool, proposer, genesis.header.timestamp, 10).await.unwrap();
+        assert_eq!(built.header.timestamp, genesis.header.timestamp + 1);
+        assert_eq!(manager.check_proposal(&built), Ok(()));
+    }
+
+    fn child_block(parent: &Block, state: Option<&Arc<RwLock<State>>>) -> Block {
+        let (height, timestamp) = (parent.height() + 1, parent.header.timestamp + 1);
+        let mut block = Block::with_transactions(height, parent.hash(), timestamp, Pubkey::new_unique(), vec![]);
+        if let Some(state) = state {
+            block.header.state_root = state.read().root();
+        }
+        block
+    }
+
+    fn finalize_child(manager: &mut ConsensusManager, state: Option<&Arc<RwLock<State>>>) -> Block {
+        let block = child_block(manager.block_tree().finalized(), state);
+        manager.apply_block(block.clone(), 1).unwrap();
+        manager.finalize_block(&block.hash()).unwrap();
+        block
+    }
+
+    #[tokio::test]
+    async fn test_pause_stops_voting_and_proposing() {
+        use solana_sdk::signature::Signer;
+
+        let (manager, _validators) = manager_with_validators(4);
+        let keypair = Keypair::new();
+        let control = manager.control();
+        control.pause();
+
+        assert_eq!(manager.sign_vote(&keypair, 1, 0, Hash::default()), Err(ControlError::Halted(HaltReason::Paused)));
+        let mut mempool = Mempool::new(0, 10);
+        mempool.insert(fresh_transaction()).unwrap();
+        assert!(manager.build_block(&mut mempool, keypair.pubkey(), 0, 10).await.is_err());
+        assert_eq!(mempool.len(), 1);
+        assert!(manager.validate_batch(&[fresh_transaction()]).await[0].is_accepted());
+
+        control.resume("maintenance done").unwrap();
+        assert!(manager.sign_vote(&keypair, 1, 0, Hash::default()).is_ok());
+    }
+
+    #[test]
+    fn test_finalized_height_regression_halts() {
+        let mut manager = ConsensusManager::new(Duration::from_secs(5), 16, Arc::new(ThresholdPolicy::bft()));
+        finalize_child(&mut manager, None);
+        finalize_child(&mut manager, None);
+        assert!(manager.halt_status().is_none());
+
+        manager.restore_block_tree(BlockTree::new(Block::genesis(), 16));
+        assert_eq!(
+            manager.halt_status().unwrap().reason,
+            HaltReason::FinalizedHeightRegression { previous: 2, current: 0 }
+        );
+        assert!(manager.sign_vote(&Keypair::new(), 1, 0, Hash::default()).is_err());
+    }
+
+    #[test]
+    fn test_storage_failure_halts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let state = Arc::new(RwLock::new(State::open(&path, vec![]).unwrap()));
+        let mut manager = ConsensusManager::new(Duration::from_secs(5), 16, Arc::new(ThresholdPolicy::bft()))
+            .with_state(Arc::clone(&state));
+
+        // A directory where the temporary snapshot goes makes the write fail.
+        std::fs::create_dir(path.with_extension("tmp")).unwrap();
+        finalize_child(&mut manager, Some(&state));
+
+        assert!(matches!(manager.halt_status().unwrap().reason, HaltReason::StorageFailure(_)));
+        assert!(manager.sign_vote(&Keypair::new(), 2, 0, Hash::default()).is_err());
+        assert_eq!(manager.control().resume(""), Err(ControlError::ReasonRequired));
+    }
+
+    #[test]
+    fn test_state_root_mismatch_with_quorum_halts() {
+        use crate::node::validator::ValidatorInfo;
+        use solana_sdk::signature::Signer;
+
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let state = Arc::new(RwLock::new(State::in_memory(vec![])));
+        let mut manager = ConsensusManager::new(Duration::from_secs(5), 16, Arc::new(ThresholdPolicy::bft()))
+            .with_state(Arc::clone(&state));
+        manager.set_validator_set(ValidatorSet::new(keys.iter().map(|k| ValidatorInfo::new(k.pubkey(), 1)).collect()));
+        finalize_child(&mut manager, Some(&state));
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let local = state.read().root();
+        let diverged = Hash::new_unique();
+        let peer = |i: u16| PeerId::from(([127, 0, 0, 1], 9000 + i));
+        let chain_id = manager.chain_id().to_string();
+        let heartbeat = |key: &Keypair, root: Hash| Heartbeat::new_signed_with_root(key, &chain_id, 1, root, now);
+
+        manager.record_heartbeat(peer(0), &heartbeat(&keys[0], local)).unwrap();
+        for (i, key) in keys[1..3].iter().enumerate() {
+            manager.record_heartbeat(peer(i as u16 + 1), &heartbeat(key, diverged)).unwrap();
+        }
+        assert!(manager.halt_status().is_none());
+        assert!(manager.sign_vote(&keys[0], 2, 0, Hash::default()).is_ok());
+
+        manager.record_heartbeat(peer(3), &heartbeat(&keys[3], diverged)).unwrap();
+        assert_eq!(
+            manager.halt_status().unwrap().reason,
+            HaltReason::StateRootMismatch { height: 1, local_root: local, peer_root: diverged }
+        );
+        assert!(manager.sign_vote(&keys[0], 2, 0, Hash::default()).is_err());
+    }
 }
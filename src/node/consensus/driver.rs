@@ -0,0 +1,542 @@
+//! Drives a running node's consensus: proposes when it leads a round, votes
+//! for valid proposals and finalizes the blocks a quorum voted for.
+//!
+//! There is one round of votes per proposal, and proposals are signed by
+//! the leader of their round. So that two rounds cannot finalize different
+//! blocks at a height, a validator that voted for a block is locked on it:
+//! it votes for no other at that height, and proposes it again whenever it
+//! leads a later round, until a quorum votes for another block in a round
+//! after the one it locked in.
+
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use solana_sdk::{hash::Hash, pubkey::Pubkey};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+
+use super::ConsensusManager;
+use crate::node::block::Block;
+use crate::node::control::HaltReason;
+use crate::node::mempool::{Mempool, MempoolError};
+use crate::node::network::{NetMessage, Network, PeerId};
+use crate::node::proposal::Proposal;
+use crate::node::signer::VoteSigner;
+use crate::node::storage::Storage;
+use crate::node::transaction::Transaction;
+use crate::node::vote::{CommitCertificate, Vote, VoteOutcome};
+
+/// How often the round is checked for a proposal due or a timeout.
+const TICK: Duration = Duration::from_millis(20);
+
+/// Where this node is at the height it is finalizing.
+struct Round {
+    height: u64,
+    round: u32,
+    started: Instant,
+    /// Whether this node proposed in `round`.
+    proposed: bool,
+    /// Valid proposals at `height`, by hash.
+    proposals: HashMap<Hash, Block>,
+    /// The block this node built at `height`, whose transactions go back to
+    /// the mempool if another is finalized.
+    built: Option<Block>,
+    /// The block this node voted for at `height`, and the round it last
+    /// locked on it in; it votes for no other.
+    locked: Option<(Hash, u32)>,
+    voted: HashSet<u32>,
+    /// Rounds votes were counted in at `height`, so a proposal arriving
+    /// after its votes is finalized all the same.
+    counted: BTreeSet<u32>,
+}
+
+impl Round {
+    fn new(height: u64) -> Self {
+        Round {
+            height,
+            round: 0,
+            started: Instant::now(),
+            proposed: false,
+            proposals: HashMap::new(),
+            built: None,
+            locked: None,
+            voted: HashSet::new(),
+            counted: BTreeSet::new(),
+        }
+    }
+}
+
+pub struct ConsensusDriver {
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    storage: Arc<Storage>,
+    mempool: Arc<Mutex<Mempool>>,
+    network: Option<Arc<Network>>,
+    signer: Option<Arc<dyn VoteSigner>>,
+    round_timeout: Duration,
+    round: AsyncMutex<Round>,
+}
+
+impl ConsensusDriver {
+    /// Follows the chain without proposing or voting, until given a signer
+    /// with `with_signer`. A round `round_timeout` long without a block
+    /// finalized moves on to the next, each waiting one timeout longer.
+    pub fn new(
+        consensus: Arc<AsyncMutex<ConsensusManager>>,
+        storage: Arc<Storage>,
+        mempool: Arc<Mutex<Mempool>>,
+        round_timeout: Duration,
+    ) -> Self {
+        ConsensusDriver {
+            consensus,
+            storage,
+            mempool,
+            network: None,
+            signer: None,
+            round_timeout,
+            round: AsyncMutex::new(Round::new(0)),
+        }
+    }
+
+    /// Proposes and votes as `signer`'s key, while it is in the validator set.
+    pub fn with_signer(mut self, signer: Arc<dyn VoteSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Gossips proposals and votes to `network`.
+    pub fn with_network(mut self, network: Arc<Network>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Moves rounds along until `cancel` fires.
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = interval.tick() => self.tick().await,
+            }
+        }
+    }
+
+    /// Times the round out if it ran too long, then proposes if this node
+    /// leads it and the block interval has passed since the last block.
+    pub async fn tick(&self) {
+        let mut round = self.round.lock().await;
+        let mut consensus = self.consensus.lock().await;
+        self.catch_up(&mut round, &consensus);
+        let Some(mut leader) = leader(&consensus, round.height, round.round) else {
+            return;
+        };
+        if round.started.elapsed() >= self.round_timeout * (round.round + 1) {
+            if !round.proposals.values().any(|block| block.header.proposer == leader) {
+                consensus.report_missed_proposal(leader);
+            }
+            debug!("Round {} at height {} timed out", round.round, round.height);
+            round.round += 1;
+            round.started = Instant::now();
+            round.proposed = false;
+            match leader_of(&consensus, &round) {
+                Some(next) => leader = next,
+                None => return,
+            }
+        }
+        let Some(signer) = self.signer.as_ref().filter(|signer| signer.pubkey() == leader) else {
+            return;
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        let parent = consensus.block_tree().finalized().header.timestamp;
+        if round.proposed || now < parent.saturating_add(consensus.next_params().block_interval_ms as i64) {
+            return;
+        }
+        round.proposed = true;
+
+        let locked = round.locked.and_then(|(hash, _)| round.proposals.get(&hash).cloned());
+        let reproposing = locked.is_some();
+        let block = match locked {
+            Some(block) => block,
+            None => match self.build(&consensus, signer.pubkey(), now).await {
+                Some(block) => block,
+                None => return,
+            },
+        };
+        let hash = block.hash();
+        let proposal = match consensus.sign_proposal_with(signer.as_ref(), round.round, block.clone()).await {
+            Ok(proposal) => proposal,
+            Err(e) => {
+                warn!("Not proposing {} at height {} round {}: {}", hash, round.height, round.round, e);
+                if !reproposing {
+                    put_back(&mut self.mempool.lock(), block.transactions);
+                }
+                return;
+            }
+        };
+        debug!("Proposing block {} at height {} round {}", hash, round.height, round.round);
+        if !reproposing {
+            round.built = Some(block.clone());
+        }
+        round.proposals.insert(hash, block);
+        self.gossip(NetMessage::Proposal(proposal));
+        self.vote(&mut round, &mut consensus, hash).await;
+    }
+
+    /// Votes for the proposed block if it is a valid proposal for the current
+    /// round, and finalizes it if its votes are already in.
+    pub async fn on_proposal(&self, from: PeerId, proposal: Proposal) {
+        let mut round = self.round.lock().await;
+        let mut consensus = self.consensus.lock().await;
+        self.catch_up(&mut round, &consensus);
+        let hash = proposal.block.hash();
+        // One seen already may be proposed again, by the leader of a later round.
+        if proposal.block.height() != round.height || round.voted.contains(&proposal.round) {
+            return;
+        }
+        if let Err(reason) = check_proposal(&consensus, &round, &proposal).await {
+            debug!("Ignoring proposal {} from {}: {}", hash, from, reason);
+            return;
+        }
+        round.proposals.insert(hash, proposal.block);
+        self.vote(&mut round, &mut consensus, hash).await;
+        let counted: Vec<u32> = round.counted.iter().copied().collect();
+        for vote_round in counted {
+            self.try_commit(&mut round, &mut consensus, vote_round, hash);
+        }
+    }
+
+    /// Counts a validator's vote, finalizing the block it completes a
+    /// quorum for.
+    pub async fn on_vote(&self, from: PeerId, vote: Vote) {
+        let mut round = self.round.lock().await;
+        let mut consensus = self.consensus.lock().await;
+        self.catch_up(&mut round, &consensus);
+        if vote.height < round.height {
+            return;
+        }
+        debug!("Vote from {} for {} at height {} round {}", from, vote.block_hash, vote.height, vote.round);
+        self.count(&mut round, &mut consensus, vote);
+    }
+
+    /// Starts over at the next height once something else, such as a sync,
+    /// finalized the current one.
+    fn catch_up(&self, round: &mut Round, consensus: &ConsensusManager) {
+        let height = consensus.finalized_height() + 1;
+        if round.height != height {
+            self.enter(round, height);
+        }
+    }
+
+    /// Moves to `height`, putting back what the block this node built at
+    /// the last one held.
+    fn enter(&self, round: &mut Round, height: u64) {
+        if let Some(built) = round.built.take() {
+            put_back(&mut self.mempool.lock(), built.transactions);
+        }
+        *round = Round::new(height);
+    }
+
+    /// Builds a block of pending transactions. Those that do not fit, or
+    /// cannot be built on since consensus paused or halted, stay pending.
+    async fn build(&self, consensus: &ConsensusManager, proposer: Pubkey, now: i64) -> Option<Block> {
+        let max_transactions = consensus.next_params().max_block_txs as usize;
+        let mut batch = Mempool::new(consensus.min_fee(), max_transactions).with_chain_id(consensus.chain_id());
+        let taken = self.mempool.lock().take_batch(max_transactions);
+        put_back(&mut batch, taken);
+        let built = consensus.build_block(&mut batch, proposer, now, max_transactions).await;
+        put_back(&mut self.mempool.lock(), batch.take_batch(max_transactions));
+        match built {
+            Ok(block) => Some(block),
+            Err(e) => {
+                debug!("Not proposing: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Votes for `hash` in the current round, unless this node is no
+    /// validator, already voted in it or is locked on another block.
+    async fn vote(&self, round: &mut Round, consensus: &mut ConsensusManager, hash: Hash) {
+        let Some(signer) = &self.signer else {
+            return;
+        };
+        if round.locked.is_some_and(|(locked, _)| locked != hash)
+            || round.voted.contains(&round.round)
+            || !consensus.handovers().validators_at(consensus.validator_set(), round.height).contains(&signer.pubkey())
+        {
+            return;
+        }
+        match consensus.sign_vote_with(signer.as_ref(), round.height, round.round, hash).await {
+            Ok(vote) => {
+                round.locked = Some((hash, round.round));
+                round.voted.insert(round.round);
+                self.gossip(NetMessage::Vote(vote.clone()));
+                self.count(round, consensus, vote);
+            }
+            Err(e) => warn!("Not voting for {} at height {} round {}: {}", hash, round.height, round.round, e),
+        }
+    }
+
+    fn count(&self, round: &mut Round, consensus: &mut ConsensusManager, vote: Vote) {
+        let (height, vote_round, hash) = (vote.height, vote.round, vote.block_hash);
+        match consensus.add_vote(vote) {
+            Ok(VoteOutcome::Added) if height == round.height => {
+                round.counted.insert(vote_round);
+                if !self.try_commit(round, consensus, vote_round, hash) {
+                    self.relock(round, consensus, vote_round, hash);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Ignoring vote for {} at height {}: {}", hash, height, e),
+        }
+    }
+
+    /// Finalizes `hash` if a quorum voted for it in `vote_round`, returning
+    /// whether it did.
+    fn try_commit(&self, round: &mut Round, consensus: &mut ConsensusManager, vote_round: u32, hash: Hash) -> bool {
+        let Some(block) = round.proposals.get(&hash).cloned() else {
+            return false;
+        };
+        let Some(weight) = quorum_weight(consensus, round.height, vote_round, &hash) else {
+            return false;
+        };
+        let certificate = consensus.vote_set(round.height, vote_round).expect("votes were counted").certificate(&hash);
+        self.commit(round, consensus, block, certificate, weight);
+        true
+    }
+
+    /// Moves this node's lock to `hash` once a quorum voted for it in a round
+    /// after the one the lock was taken in: no block locked on before can
+    /// then be finalized, so holding on to it would only stall the height.
+    fn relock(&self, round: &mut Round, consensus: &ConsensusManager, vote_round: u32, hash: Hash) {
+        let Some((locked, locked_round)) = round.locked else {
+            return;
+        };
+        if locked == hash || vote_round <= locked_round {
+            return;
+        }
+        if quorum_weight(consensus, round.height, vote_round, &hash).is_some() {
+            debug!("Unlocking {} at height {}: round {} has a quorum for {}", locked, round.height, vote_round, hash);
+            round.locked = Some((hash, vote_round));
+        }
+    }
+
+    /// Stores `block` with its certificate before finalizing it, so a
+    /// restart resumes from every block consensus finalized.
+    fn commit(
+        &self,
+        round: &mut Round,
+        consensus: &mut ConsensusManager,
+        block: Block,
+        certificate: CommitCertificate,
+        weight: u128,
+    ) {
+        let (hash, height) = (block.hash(), block.height());
+        if let Err(e) = consensus.apply_block(block.clone(), weight) {
+            warn!("Cannot apply block {} at height {}: {}", hash, height, e);
+            return;
+        }
+        if let Err(e) = self.storage.put_finalized_block(&block, &certificate) {
+            consensus.control().halt(HaltReason::StorageFailure(format!("cannot store block {}: {}", height, e)));
+            return;
+        }
+        if let Err(e) = consensus.finalize_block(&hash) {
+            warn!("Cannot finalize block {} at height {}: {}", hash, height, e);
+            return;
+        }
+        if round.built.as_ref().is_some_and(|built| built.hash() == hash) {
+            round.built = None;
+        }
+        self.enter(round, height + 1);
+        self.mempool.lock().remove_included(&block.transactions);
+        info!("Finalized block {} at height {} with {} transactions", hash, height, block.transactions.len());
+    }
+
+    fn gossip(&self, message: NetMessage) {
+        if let Some(network) = &self.network {
+            network.gossip(message);
+        }
+    }
+}
+
+/// Returns `transactions` to `mempool`. One it no longer takes, say since
+/// it filled up meanwhile, is dropped, and says why.
+fn put_back(mempool: &mut Mempool, transactions: Vec<Transaction>) {
+    for transaction in transactions {
+        let hash = transaction.hash();
+        match mempool.insert(transaction) {
+            Ok(()) | Err(MempoolError::Duplicate(_)) => {}
+            Err(e) => warn!("Dropping pending transaction {}: {}", hash, e),
+        }
+    }
+}
+
+/// The weight of the votes for `hash` in `vote_round` at `height`, if they
+/// make a quorum.
+fn quorum_weight(consensus: &ConsensusManager, height: u64, vote_round: u32, hash: &Hash) -> Option<u128> {
+    let weight = consensus.vote_set(height, vote_round)?.weight_for(hash);
+    let total = consensus.handovers().validators_at(consensus.validator_set(), height).total_weight();
+    consensus.quorum_policy().is_met(total, weight).then_some(weight)
+}
+
+/// The validator leading `round` at `height`, by the key it signs with there.
+fn leader(consensus: &ConsensusManager, height: u64, round: u32) -> Option<Pubkey> {
+    let validators = consensus.handovers().validators_at(consensus.validator_set(), height);
+    let validators = validators.validators();
+    if validators.is_empty() {
+        return None;
+    }
+    Some(validators[((height + round as u64) % validators.len() as u64) as usize].pubkey)
+}
+
+fn leader_of(consensus: &ConsensusManager, round: &Round) -> Option<Pubkey> {
+    leader(consensus, round.height, round.round)
+}
+
+/// Why `proposal` is no proposal to vote for in the current round, if it is
+/// not. Only one its leader signed is checked further, and counted against
+/// it if invalid.
+async fn check_proposal(consensus: &ConsensusManager, round: &Round, proposal: &Proposal) -> Result<(), String> {
+    let block = &proposal.block;
+    if block.parent_hash() != consensus.block_tree().finalized_hash() {
+        return Err("it does not extend the finalized block".to_string());
+    }
+    if proposal.round != round.round {
+        return Err(format!("it is for round {}, not {}", proposal.round, round.round));
+    }
+    if leader_of(consensus, round) != Some(block.header.proposer) {
+        return Err(format!("{} does not lead round {}", block.header.proposer, round.round));
+    }
+    if !proposal.verify(consensus.chain_id()) {
+        return Err(format!("it is not signed by {}", block.header.proposer));
+    }
+    if !block.verify_body() {
+        return Err("its transactions do not match its header".to_string());
+    }
+    consensus.check_proposal(block).map_err(|e| e.to_string())?;
+    if let Some(state) = consensus.state() {
+        if block.header.state_root != state.read().root() {
+            return Err("its state root is not ours".to_string());
+        }
+    }
+    let results = consensus.validate_batch(&block.transactions).await;
+    match results.iter().zip(&block.transactions).find(|(result, _)| !result.is_accepted()) {
+        Some((result, transaction)) => Err(format!("transaction {} is invalid: {:?}", transaction.hash(), result)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::config::DEFAULT_CHAIN_ID;
+    use crate::node::quorum::ThresholdPolicy;
+    use crate::node::validator::{ValidatorInfo, ValidatorSet};
+    use solana_sdk::signature::{Keypair, Signer};
+
+    fn driver(
+        validators: &[&Keypair],
+        mempool: Arc<Mutex<Mempool>>,
+    ) -> (ConsensusDriver, Arc<AsyncMutex<ConsensusManager>>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()));
+        consensus.set_validator_set(ValidatorSet::new(validators.iter()
+            .map(|keypair| ValidatorInfo::new(keypair.pubkey(), 1))
+            .collect()));
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let driver = ConsensusDriver::new(Arc::clone(&consensus), storage, mempool, Duration::from_millis(50));
+        (driver, consensus, dir)
+    }
+
+    #[tokio::test]
+    async fn test_lone_validator_proposes_and_finalizes() {
+        let (validator, alice) = (Keypair::new(), Keypair::new());
+        let mempool = Arc::new(Mutex::new(Mempool::new(0, 100)));
+        let now = chrono::Utc::now().timestamp_millis();
+        let transaction = Transaction::new_signed(&alice, Pubkey::new_unique(), 10, 0, 0, now);
+        mempool.lock().insert(transaction.clone()).unwrap();
+        let (driver, consensus, _dir) = driver(&[&validator], Arc::clone(&mempool));
+        let driver = driver.with_signer(Arc::new(validator));
+
+        driver.tick().await;
+        assert_eq!(consensus.lock().await.finalized_height(), 1);
+        let stored = driver.storage.get_block_by_height(1).unwrap().unwrap();
+        assert_eq!(stored.transactions, vec![transaction]);
+        assert_eq!(driver.storage.get_certificate(1).unwrap().unwrap().block_hash, stored.hash());
+        assert!(mempool.lock().is_empty());
+    }
+
+    /// The keys leading rounds 0 and 1 at height 1, in that order.
+    async fn leaders<'a>(consensus: &AsyncMutex<ConsensusManager>, keys: &'a [Keypair]) -> [&'a Keypair; 2] {
+        let consensus = consensus.lock().await;
+        [0, 1].map(|round| {
+            let leader = leader(&consensus, 1, round).unwrap();
+            keys.iter().find(|keypair| keypair.pubkey() == leader).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_proposal_waits_for_its_votes() {
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let validators: Vec<&Keypair> = keys.iter().collect();
+        let (driver, consensus, _dir) = driver(&validators, Arc::new(Mutex::new(Mempool::new(0, 100))));
+        let peer: PeerId = "127.0.0.1:9000".parse().unwrap();
+        let [leader, _] = leaders(&consensus, &keys).await;
+        let genesis = Block::genesis();
+        let block = Block::new(1, genesis.hash(), 1, leader.pubkey());
+
+        // Neither a stranger's block nor one merely naming the leader is
+        // voted for, or counted against the leader.
+        let stranger = Keypair::new();
+        let strangers = Block::new(1, genesis.hash(), 1, stranger.pubkey());
+        driver.on_proposal(peer, Proposal::new(&stranger, DEFAULT_CHAIN_ID, strangers.clone(), 0)).await;
+        let forged = Block::new(1, genesis.hash(), 2, leader.pubkey());
+        driver.on_proposal(peer, Proposal::new(&stranger, DEFAULT_CHAIN_ID, forged.clone(), 0)).await;
+        let proposals = driver.round.lock().await.proposals.clone();
+        assert!(!proposals.contains_key(&strangers.hash()) && !proposals.contains_key(&forged.hash()));
+        assert!(consensus.lock().await.metrics().snapshot().invalid_proposals.is_empty());
+
+        // Votes before the block count once it arrives.
+        for keypair in &keys[..2] {
+            driver.on_vote(peer, Vote::new(keypair, 1, 0, block.hash())).await;
+        }
+        driver.on_proposal(peer, Proposal::new(leader, DEFAULT_CHAIN_ID, block.clone(), 0)).await;
+        assert_eq!(consensus.lock().await.finalized_height(), 0);
+        driver.on_vote(peer, Vote::new(&keys[2], 1, 0, block.hash())).await;
+        assert_eq!(consensus.lock().await.finalized_height(), 1);
+        assert_eq!(driver.storage.get_block_by_height(1).unwrap(), Some(block));
+    }
+
+    #[tokio::test]
+    async fn test_lock_moves_to_a_later_quorum() {
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let validators: Vec<&Keypair> = keys.iter().collect();
+        let (driver, consensus, _dir) = driver(&validators, Arc::new(Mutex::new(Mempool::new(0, 100))));
+        let peer: PeerId = "127.0.0.1:9000".parse().unwrap();
+        let [first, second] = leaders(&consensus, &keys).await;
+        let us = keys.iter().find(|keypair| [first, second].iter().all(|leader| leader.pubkey() != keypair.pubkey()));
+        let us = us.unwrap();
+        let driver = driver.with_signer(Arc::new(Keypair::from_bytes(&us.to_bytes()).unwrap()));
+        let genesis = Block::genesis();
+        let a = Block::new(1, genesis.hash(), 1, first.pubkey());
+        let b = Block::new(1, genesis.hash(), 2, second.pubkey());
+
+        driver.on_proposal(peer, Proposal::new(first, DEFAULT_CHAIN_ID, a.clone(), 0)).await;
+        assert_eq!(driver.round.lock().await.locked, Some((a.hash(), 0)));
+        tokio::time::sleep(driver.round_timeout).await;
+        driver.tick().await;
+        assert_eq!(driver.round.lock().await.round, 1);
+
+        // Round 1 has a quorum for another block: no quorum can form for `a`
+        // any more, and its proposal is voted for.
+        for keypair in keys.iter().filter(|keypair| keypair.pubkey() != us.pubkey()) {
+            driver.on_vote(peer, Vote::new(keypair, 1, 1, b.hash())).await;
+        }
+        assert_eq!(driver.round.lock().await.locked, Some((b.hash(), 1)));
+        driver.on_proposal(peer, Proposal::new(second, DEFAULT_CHAIN_ID, b.clone(), 1)).await;
+        assert_eq!(consensus.lock().await.finalized_height(), 1);
+        assert_eq!(driver.storage.get_block_by_height(1).unwrap(), Some(b));
+    }
+}
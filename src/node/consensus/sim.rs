@@ -0,0 +1,789 @@
+//! Deterministic multi-node consensus simulation.
+//!
+//! Runs several `ConsensusManager`s over an in-memory bus driven by a virtual
+//! clock. Message delay, loss, duplication and partitions are drawn from a
+//! seeded RNG so a failing scenario can be replayed exactly from its seed.
+
+use solana_sdk::{
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+    signature::{keypair_from_seed, Keypair, Signer},
+};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::ConsensusManager;
+use crate::node::block::Block;
+use crate::node::quorum::{QuorumPolicy, ThresholdPolicy};
+use crate::node::validator::{ValidatorInfo, ValidatorSet};
+use crate::node::vote::{CommitCertificate, Vote, VoteOutcome};
+
+/// Small xorshift generator so the harness does not depend on `rand`.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next_u64() as f64 / u64::MAX as f64) < probability
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    Honest,
+    /// Proposes conflicting blocks as leader and double-signs precommits.
+    Equivocate,
+    /// Never prevotes or precommits.
+    WithholdVotes,
+    /// Proposes blocks that do not extend the finalized chain.
+    InvalidProposals,
+    /// Sends and receives nothing.
+    Offline,
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub groups: Vec<Vec<usize>>,
+}
+
+impl Partition {
+    fn separates(&self, a: usize, b: usize, now: u64) -> bool {
+        if now < self.start_ms || now >= self.end_ms {
+            return false;
+        }
+        let group_of = |node: usize| self.groups.iter().position(|g| g.contains(&node));
+        group_of(a) != group_of(b)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub seed: u64,
+    pub behaviors: HashMap<usize, Behavior>,
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub partitions: Vec<Partition>,
+    pub round_timeout_ms: u64,
+    pub target_height: u64,
+    pub max_time_ms: u64,
+}
+
+impl SimConfig {
+    pub fn new(nodes: usize, seed: u64) -> Self {
+        SimConfig {
+            nodes,
+            seed,
+            behaviors: HashMap::new(),
+            min_delay_ms: 5,
+            max_delay_ms: 50,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            partitions: Vec::new(),
+            round_timeout_ms: 500,
+            target_height: 10,
+            max_time_ms: 120_000,
+        }
+    }
+
+    pub fn with_behavior(mut self, node: usize, behavior: Behavior) -> Self {
+        self.behaviors.insert(node, behavior);
+        self
+    }
+
+    pub fn with_delay(mut self, min_ms: u64, max_ms: u64) -> Self {
+        self.min_delay_ms = min_ms;
+        self.max_delay_ms = max_ms;
+        self
+    }
+
+    pub fn with_drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate;
+        self
+    }
+
+    pub fn with_duplicate_rate(mut self, duplicate_rate: f64) -> Self {
+        self.duplicate_rate = duplicate_rate;
+        self
+    }
+
+    pub fn with_partition(mut self, start_ms: u64, end_ms: u64, groups: Vec<Vec<usize>>) -> Self {
+        self.partitions.push(Partition { start_ms, end_ms, groups });
+        self
+    }
+
+    pub fn with_target_height(mut self, target_height: u64) -> Self {
+        self.target_height = target_height;
+        self
+    }
+
+    pub fn with_max_time(mut self, max_time_ms: u64) -> Self {
+        self.max_time_ms = max_time_ms;
+        self
+    }
+
+    fn behavior(&self, node: usize) -> Behavior {
+        self.behaviors.get(&node).copied().unwrap_or(Behavior::Honest)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SimMessage {
+    Proposal { block: Block, round: u32, pol_round: Option<u32> },
+    Prevote { height: u64, round: u32, block_hash: Option<Hash> },
+    Precommit(Vote),
+    Commit { block: Block, certificate: CommitCertificate },
+    Status { height: u64 },
+}
+
+impl SimMessage {
+    fn height(&self) -> u64 {
+        match self {
+            SimMessage::Proposal { block, .. } | SimMessage::Commit { block, .. } => block.height(),
+            SimMessage::Prevote { height, .. } | SimMessage::Status { height } => *height,
+            SimMessage::Precommit(vote) => vote.height,
+        }
+    }
+}
+
+enum Output {
+    Send { to: Option<usize>, message: SimMessage },
+    Timer { height: u64, round: u32, after_ms: u64 },
+}
+
+enum EventKind {
+    Deliver { from: usize, to: usize, message: SimMessage },
+    Timeout { node: usize, height: u64, round: u32 },
+}
+
+struct Event {
+    at: u64,
+    seq: u64,
+    kind: EventKind,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Event {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+struct SimNode {
+    index: usize,
+    keypair: Keypair,
+    behavior: Behavior,
+    validators: Vec<Pubkey>,
+    quorum: Arc<dyn QuorumPolicy>,
+    round_timeout_ms: u64,
+    manager: ConsensusManager,
+    height: u64,
+    round: u32,
+    locked: Option<(Hash, u32)>,
+    blocks: HashMap<Hash, Block>,
+    proposals: HashMap<(u64, u32), (Hash, Option<u32>)>,
+    prevotes: HashMap<(u64, u32), BTreeMap<usize, Option<Hash>>>,
+    prevoted: HashSet<(u64, u32)>,
+    precommitted: HashSet<(u64, u32)>,
+    commits: BTreeMap<u64, (Block, CommitCertificate)>,
+    commit_times: BTreeMap<u64, u64>,
+    future: Vec<(usize, SimMessage)>,
+    equivocations_detected: usize,
+}
+
+impl SimNode {
+    fn leader(&self, height: u64, round: u32) -> usize {
+        ((height + round as u64) % self.validators.len() as u64) as usize
+    }
+
+    fn votes(&self) -> bool {
+        matches!(self.behavior, Behavior::Honest | Behavior::Equivocate | Behavior::InvalidProposals)
+    }
+
+    fn total_weight(&self) -> u128 {
+        self.manager.validator_set().total_weight()
+    }
+
+    fn start(&mut self, now: u64) -> Vec<Output> {
+        if self.behavior == Behavior::Offline {
+            return Vec::new();
+        }
+        self.enter_round(now)
+    }
+
+    fn enter_round(&mut self, now: u64) -> Vec<Output> {
+        let mut outputs = vec![Output::Timer {
+            height: self.height,
+            round: self.round,
+            after_ms: self.round_timeout_ms * (self.round as u64 + 1),
+        }];
+        if self.leader(self.height, self.round) == self.index {
+            outputs.extend(self.propose(now));
+        }
+        outputs.extend(self.try_prevote(self.height, self.round));
+        outputs.extend(self.check_polka(self.height, self.round));
+        outputs
+    }
+
+    fn propose(&mut self, now: u64) -> Vec<Output> {
+        let parent = self.manager.block_tree().finalized_hash();
+        let pubkey = self.keypair.pubkey();
+        let round = self.round;
+
+        match self.behavior {
+            Behavior::Offline => Vec::new(),
+            Behavior::InvalidProposals => {
+                let block = Block::new(self.height, hashv(&[b"invalid", &now.to_le_bytes()]), now as i64, pubkey);
+                vec![Output::Send { to: None, message: SimMessage::Proposal { block, round, pol_round: None } }]
+            }
+            Behavior::Equivocate => {
+                let first = Block::new(self.height, parent, now as i64, pubkey);
+                let second = Block::new(self.height, parent, now as i64 + 1, pubkey);
+                (0..self.validators.len())
+                    .map(|to| Output::Send {
+                        to: Some(to),
+                        message: SimMessage::Proposal {
+                            block: if to % 2 == 0 { first.clone() } else { second.clone() },
+                            round,
+                            pol_round: None,
+                        },
+                    })
+                    .collect()
+            }
+            Behavior::Honest | Behavior::WithholdVotes => {
+                let (block, pol_round) = match self.locked.and_then(|(hash, r)| self.blocks.get(&hash).map(|b| (b.clone(), r))) {
+                    Some((block, locked_round)) => (block, Some(locked_round)),
+                    None => (Block::new(self.height, parent, now as i64, pubkey), None),
+                };
+                vec![Output::Send { to: None, message: SimMessage::Proposal { block, round, pol_round } }]
+            }
+        }
+    }
+
+    fn is_valid_proposal(&self, block: &Block, round: u32) -> bool {
+        block.height() == self.height
+            && block.parent_hash() == self.manager.block_tree().finalized_hash()
+            && block.header.proposer == self.validators[self.leader(self.height, round)]
+    }
+
+    fn handle(&mut self, from: usize, message: SimMessage, now: u64) -> Vec<Output> {
+        if self.behavior == Behavior::Offline {
+            return Vec::new();
+        }
+
+        let height = message.height();
+        if height < self.height {
+            if matches!(message, SimMessage::Commit { .. }) {
+                return Vec::new();
+            }
+            return match self.commits.get(&height) {
+                Some((block, certificate)) => vec![Output::Send {
+                    to: Some(from),
+                    message: SimMessage::Commit { block: block.clone(), certificate: certificate.clone() },
+                }],
+                None => Vec::new(),
+            };
+        }
+        if height > self.height {
+            self.future.push((from, message));
+            return Vec::new();
+        }
+
+        match message {
+            SimMessage::Proposal { block, round, pol_round } => {
+                if from != self.leader(height, round) || !self.is_valid_proposal(&block, round) {
+                    return Vec::new();
+                }
+                let hash = block.hash();
+                self.blocks.insert(hash, block);
+                self.proposals.entry((height, round)).or_insert((hash, pol_round));
+
+                let mut outputs = self.try_prevote(height, round);
+                outputs.extend(self.check_polka(height, round));
+                outputs.extend(self.check_commit(height, round, hash, now));
+                outputs
+            }
+            SimMessage::Prevote { round, block_hash, .. } => {
+                self.prevotes.entry((height, round)).or_default().entry(from).or_insert(block_hash);
+                self.check_polka(height, round)
+            }
+            SimMessage::Precommit(vote) => {
+                let (round, hash) = (vote.round, vote.block_hash);
+                match self.manager.add_vote(vote) {
+                    Ok(VoteOutcome::Added) => self.check_commit(height, round, hash, now),
+                    Ok(VoteOutcome::Equivocation(_)) => {
+                        self.equivocations_detected += 1;
+                        Vec::new()
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            SimMessage::Commit { block, certificate } => {
+                let hash = block.hash();
+                if block.parent_hash() != self.manager.block_tree().finalized_hash() {
+                    return Vec::new();
+                }
+                let validators = self.manager.validator_set();
+                match certificate.verify(self.manager.chain_id(), &hash, validators, self.quorum.as_ref()) {
+                    Ok(weight) => self.commit(block, certificate, weight, now),
+                    Err(_) => Vec::new(),
+                }
+            }
+            SimMessage::Status { .. } => Vec::new(),
+        }
+    }
+
+    fn on_timeout(&mut self, height: u64, round: u32, now: u64) -> Vec<Output> {
+        if self.behavior == Behavior::Offline || height != self.height || round != self.round {
+            return Vec::new();
+        }
+        self.round += 1;
+        let mut outputs = vec![Output::Send { to: None, message: SimMessage::Status { height } }];
+        outputs.extend(self.enter_round(now));
+        outputs
+    }
+
+    fn try_prevote(&mut self, height: u64, round: u32) -> Vec<Output> {
+        if !self.votes() || height != self.height || round != self.round || self.prevoted.contains(&(height, round)) {
+            return Vec::new();
+        }
+        let (hash, pol_round) = match self.proposals.get(&(height, round)) {
+            Some(proposal) => *proposal,
+            None => return Vec::new(),
+        };
+
+        let acceptable = match self.locked {
+            None => true,
+            Some((locked_hash, locked_round)) => {
+                locked_hash == hash || pol_round.map_or(false, |p| p >= locked_round)
+            }
+        };
+
+        self.prevoted.insert((height, round));
+        let block_hash = if acceptable { Some(hash) } else { None };
+        vec![Output::Send { to: None, message: SimMessage::Prevote { height, round, block_hash } }]
+    }
+
+    fn check_polka(&mut self, height: u64, round: u32) -> Vec<Output> {
+        if height != self.height {
+            return Vec::new();
+        }
+        let prevotes = match self.prevotes.get(&(height, round)) {
+            Some(prevotes) => prevotes,
+            None => return Vec::new(),
+        };
+
+        let mut tally: BTreeMap<Hash, u128> = BTreeMap::new();
+        for (voter, hash) in prevotes {
+            if let Some(hash) = hash {
+                *tally.entry(*hash).or_insert(0) += self.manager.validator_set().weight_of(&self.validators[*voter]);
+            }
+        }
+        let total = self.total_weight();
+        let polka = tally.into_iter()
+            .find(|(hash, weight)| self.quorum.is_met(total, *weight) && self.blocks.contains_key(hash))
+            .map(|(hash, _)| hash);
+        let hash = match polka {
+            Some(hash) => hash,
+            None => return Vec::new(),
+        };
+
+        if self.locked.map_or(true, |(_, locked_round)| round >= locked_round) {
+            self.locked = Some((hash, round));
+        }
+
+        if !self.votes() || round != self.round || !self.precommitted.insert((height, round)) {
+            return Vec::new();
+        }
+
+        let mut outputs = vec![Output::Send {
+            to: None,
+            message: SimMessage::Precommit(Vote::new(&self.keypair, height, round, hash)),
+        }];
+        if self.behavior == Behavior::Equivocate {
+            let forged = hashv(&[hash.as_ref(), b"forged"]);
+            outputs.push(Output::Send {
+                to: None,
+                message: SimMessage::Precommit(Vote::new(&self.keypair, height, round, forged)),
+            });
+        }
+        outputs
+    }
+
+    fn check_commit(&mut self, height: u64, round: u32, hash: Hash, now: u64) -> Vec<Output> {
+        if height != self.height {
+            return Vec::new();
+        }
+        let (weight, certificate) = match self.manager.vote_set(height, round) {
+            Some(votes) => (votes.weight_for(&hash), votes.certificate(&hash)),
+            None => return Vec::new(),
+        };
+        if !self.quorum.is_met(self.total_weight(), weight) {
+            return Vec::new();
+        }
+        match self.blocks.get(&hash).cloned() {
+            Some(block) => self.commit(block, certificate, weight, now),
+            None => Vec::new(),
+        }
+    }
+
+    fn commit(&mut self, block: Block, certificate: CommitCertificate, weight: u128, now: u64) -> Vec<Output> {
+        let hash = block.hash();
+        if self.manager.apply_block(block.clone(), weight).is_err()
+            || self.manager.finalize_block(&hash).is_err()
+        {
+            return Vec::new();
+        }
+
+        self.commits.insert(block.height(), (block, certificate));
+        self.commit_times.insert(self.height, now);
+        self.height += 1;
+        self.round = 0;
+        self.locked = None;
+        self.proposals.retain(|(h, _), _| *h >= self.height);
+        self.prevotes.retain(|(h, _), _| *h >= self.height);
+
+        let mut outputs = self.enter_round(now);
+        for (from, message) in std::mem::take(&mut self.future) {
+            outputs.extend(self.handle(from, message, now));
+        }
+        outputs
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimReport {
+    pub behaviors: Vec<Behavior>,
+    pub finalized: Vec<BTreeMap<u64, Hash>>,
+    pub commit_times: Vec<BTreeMap<u64, u64>>,
+    pub equivocations_detected: usize,
+    pub stats: SimStats,
+    pub elapsed_ms: u64,
+}
+
+impl SimReport {
+    pub fn honest_nodes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.behaviors.iter().enumerate()
+            .filter(|(_, b)| **b == Behavior::Honest)
+            .map(|(i, _)| i)
+    }
+
+    pub fn finalized_height(&self, node: usize) -> u64 {
+        self.finalized[node].keys().next_back().copied().unwrap_or(0)
+    }
+
+    pub fn min_honest_height(&self) -> u64 {
+        self.honest_nodes().map(|i| self.finalized_height(i)).min().unwrap_or(0)
+    }
+
+    /// Heights at which two honest nodes finalized different blocks.
+    pub fn safety_violations(&self) -> Vec<u64> {
+        let mut by_height: BTreeMap<u64, HashSet<Hash>> = BTreeMap::new();
+        for node in self.honest_nodes() {
+            for (height, hash) in &self.finalized[node] {
+                by_height.entry(*height).or_default().insert(*hash);
+            }
+        }
+        by_height.into_iter()
+            .filter(|(_, hashes)| hashes.len() > 1)
+            .map(|(height, _)| height)
+            .collect()
+    }
+
+    pub fn assert_safe(&self) {
+        let violations = self.safety_violations();
+        assert!(violations.is_empty(), "conflicting blocks finalized at heights {:?}", violations);
+    }
+
+    pub fn assert_live(&self, target: u64) {
+        assert!(
+            self.min_honest_height() >= target,
+            "honest nodes only reached height {} of {}",
+            self.min_honest_height(),
+            target
+        );
+    }
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    nodes: Vec<SimNode>,
+    queue: BinaryHeap<Event>,
+    seq: u64,
+    now: u64,
+    rng: SimRng,
+    stats: SimStats,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let mut rng = SimRng::new(config.seed);
+        let keypairs: Vec<Keypair> = (0..config.nodes)
+            .map(|_| {
+                let seed: Vec<u8> = (0..4).flat_map(|_| rng.next_u64().to_le_bytes()).collect();
+                keypair_from_seed(&seed).expect("32-byte seed is valid")
+            })
+            .collect();
+        let validators: Vec<Pubkey> = keypairs.iter().map(|k| k.pubkey()).collect();
+        let set = ValidatorSet::new(validators.iter()
+            .map(|pubkey| ValidatorInfo::new(*pubkey, 1))
+            .collect());
+        let quorum: Arc<dyn QuorumPolicy> = Arc::new(ThresholdPolicy::bft());
+
+        let nodes = keypairs.into_iter().enumerate()
+            .map(|(index, keypair)| {
+                let mut manager = ConsensusManager::new(Duration::from_secs(5), 64, Arc::clone(&quorum));
+                manager.set_validator_set(set.clone());
+                SimNode {
+                    index,
+                    keypair,
+                    behavior: config.behavior(index),
+                    validators: validators.clone(),
+                    quorum: Arc::clone(&quorum),
+                    round_timeout_ms: config.round_timeout_ms,
+                    manager,
+                    height: 1,
+                    round: 0,
+                    locked: None,
+                    blocks: HashMap::new(),
+                    proposals: HashMap::new(),
+                    prevotes: HashMap::new(),
+                    prevoted: HashSet::new(),
+                    precommitted: HashSet::new(),
+                    commits: BTreeMap::new(),
+                    commit_times: BTreeMap::new(),
+                    future: Vec::new(),
+                    equivocations_detected: 0,
+                }
+            })
+            .collect();
+
+        Simulation {
+            config,
+            nodes,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            now: 0,
+            rng,
+            stats: SimStats::default(),
+        }
+    }
+
+    pub fn run(mut self) -> SimReport {
+        for index in 0..self.nodes.len() {
+            let outputs = self.nodes[index].start(0);
+            self.dispatch(index, outputs);
+        }
+
+        while let Some(event) = self.queue.pop() {
+            if event.at > self.config.max_time_ms {
+                break;
+            }
+            self.now = event.at;
+
+            let (node, outputs) = match event.kind {
+                EventKind::Deliver { from, to, message } => (to, self.nodes[to].handle(from, message, self.now)),
+                EventKind::Timeout { node, height, round } => (node, self.nodes[node].on_timeout(height, round, self.now)),
+            };
+            self.dispatch(node, outputs);
+
+            if self.honest_min_height() >= self.config.target_height {
+                break;
+            }
+        }
+
+        SimReport {
+            behaviors: self.nodes.iter().map(|n| n.behavior).collect(),
+            finalized: self.nodes.iter()
+                .map(|n| n.commits.iter().map(|(h, (block, _))| (*h, block.hash())).collect())
+                .collect(),
+            commit_times: self.nodes.iter().map(|n| n.commit_times.clone()).collect(),
+            equivocations_detected: self.nodes.iter().map(|n| n.equivocations_detected).sum(),
+            stats: self.stats,
+            elapsed_ms: self.now,
+        }
+    }
+
+    fn honest_min_height(&self) -> u64 {
+        self.nodes.iter()
+            .filter(|n| n.behavior == Behavior::Honest)
+            .map(|n| n.height - 1)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn push(&mut self, at: u64, kind: EventKind) {
+        self.seq += 1;
+        self.queue.push(Event { at, seq: self.seq, kind });
+    }
+
+    fn dispatch(&mut self, from: usize, outputs: Vec<Output>) {
+        for output in outputs {
+            match output {
+                Output::Send { to: Some(to), message } => self.send(from, to, message),
+                Output::Send { to: None, message } => {
+                    for to in 0..self.nodes.len() {
+                        self.send(from, to, message.clone());
+                    }
+                }
+                Output::Timer { height, round, after_ms } => {
+                    self.push(self.now + after_ms, EventKind::Timeout { node: from, height, round });
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: SimMessage) {
+        if from == to {
+            self.push(self.now, EventKind::Deliver { from, to, message });
+            return;
+        }
+
+        self.stats.sent += 1;
+        let now = self.now;
+        if self.config.partitions.iter().any(|p| p.separates(from, to, now)) || self.rng.chance(self.config.drop_rate) {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let delay = self.rng.range(self.config.min_delay_ms, self.config.max_delay_ms);
+        if self.rng.chance(self.config.duplicate_rate) {
+            self.stats.duplicated += 1;
+            let duplicate_delay = self.rng.range(self.config.min_delay_ms, self.config.max_delay_ms);
+            self.push(now + duplicate_delay, EventKind::Deliver { from, to, message: message.clone() });
+        }
+        self.push(now + delay, EventKind::Deliver { from, to, message });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_honest_network_makes_progress() {
+        let report = Simulation::new(SimConfig::new(4, 1).with_target_height(10)).run();
+        report.assert_safe();
+        report.assert_live(10);
+        assert_eq!(report.equivocations_detected, 0);
+    }
+
+    #[test]
+    fn test_lossy_network_with_duplicates() {
+        let config = SimConfig::new(4, 7)
+            .with_drop_rate(0.05)
+            .with_duplicate_rate(0.2)
+            .with_delay(5, 120)
+            .with_target_height(5);
+        let report = Simulation::new(config).run();
+        report.assert_safe();
+        report.assert_live(5);
+        assert!(report.stats.dropped > 0);
+        assert!(report.stats.duplicated > 0);
+    }
+
+    #[test]
+    fn test_partition_stalls_then_heals() {
+        let config = SimConfig::new(4, 3)
+            .with_partition(0, 3_000, vec![vec![0, 1], vec![2, 3]])
+            .with_target_height(3);
+        let report = Simulation::new(config).run();
+        report.assert_safe();
+        report.assert_live(3);
+        for node in report.honest_nodes() {
+            assert!(report.commit_times[node][&1] >= 3_000);
+        }
+    }
+
+    #[test]
+    fn test_equivocating_validator_is_detected() {
+        let config = SimConfig::new(4, 11)
+            .with_behavior(1, Behavior::Equivocate)
+            .with_target_height(6);
+        let report = Simulation::new(config).run();
+        report.assert_safe();
+        report.assert_live(6);
+        assert!(report.equivocations_detected > 0);
+    }
+
+    #[test]
+    fn test_withheld_votes_and_invalid_proposals() {
+        let config = SimConfig::new(7, 5)
+            .with_behavior(2, Behavior::WithholdVotes)
+            .with_behavior(4, Behavior::InvalidProposals)
+            .with_target_height(8);
+        let report = Simulation::new(config).run();
+        report.assert_safe();
+        report.assert_live(8);
+    }
+
+    #[test]
+    fn test_no_progress_without_quorum() {
+        let config = SimConfig::new(4, 9)
+            .with_behavior(0, Behavior::Offline)
+            .with_behavior(1, Behavior::Offline)
+            .with_target_height(1)
+            .with_max_time(10_000);
+        let report = Simulation::new(config).run();
+        report.assert_safe();
+        assert_eq!(report.min_honest_height(), 0);
+    }
+
+    #[test]
+    fn test_same_seed_replays_identically() {
+        let config = || SimConfig::new(4, 42)
+            .with_drop_rate(0.1)
+            .with_delay(1, 200)
+            .with_behavior(3, Behavior::Equivocate)
+            .with_target_height(4);
+        let first = Simulation::new(config()).run();
+        let second = Simulation::new(config()).run();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,272 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use super::metrics::{self, Histogram, MetricsSource};
+
+pub const PARTICIPATION_WINDOW: usize = 1000;
+const COUNT_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0];
+const ROUND_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 5.0, 8.0, 13.0];
+
+struct HeightRecord {
+    expected: Vec<Pubkey>,
+    voted: HashSet<Pubkey>,
+}
+
+struct MetricsInner {
+    validation_latency: Histogram,
+    confirmations: Histogram,
+    rounds_per_height: Histogram,
+    round_latency: Histogram,
+    last_round_latency: Option<Duration>,
+    head_height: u64,
+    finalized_height: u64,
+    validations_accepted: u64,
+    validations_rejected: u64,
+    heights: VecDeque<HeightRecord>,
+    missed_proposals: BTreeMap<Pubkey, u64>,
+    invalid_proposals: BTreeMap<Pubkey, u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsensusMetricsSnapshot {
+    pub validations_accepted: u64,
+    pub validations_rejected: u64,
+    pub validation_latency_mean_secs: f64,
+    pub confirmations_mean: f64,
+    pub rounds_per_height_mean: f64,
+    pub heights_observed: usize,
+    pub participation: BTreeMap<String, f64>,
+    pub missed_proposals: BTreeMap<String, u64>,
+    pub invalid_proposals: BTreeMap<String, u64>,
+}
+
+pub struct ConsensusMetrics {
+    inner: Mutex<MetricsInner>,
+}
+
+impl Default for ConsensusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConsensusMetrics {
+    pub fn new() -> Self {
+        ConsensusMetrics {
+            inner: Mutex::new(MetricsInner {
+                validation_latency: Histogram::latency(),
+                confirmations: Histogram::new(COUNT_BUCKETS),
+                rounds_per_height: Histogram::new(ROUND_BUCKETS),
+                round_latency: Histogram::latency(),
+                last_round_latency: None,
+                head_height: 0,
+                finalized_height: 0,
+                validations_accepted: 0,
+                validations_rejected: 0,
+                heights: VecDeque::with_capacity(PARTICIPATION_WINDOW),
+                missed_proposals: BTreeMap::new(),
+                invalid_proposals: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn record_validation(&self, latency: Duration, confirmations: usize, accepted: bool) {
+        let mut inner = self.inner.lock();
+        inner.validation_latency.observe(latency.as_secs_f64());
+        inner.confirmations.observe(confirmations as f64);
+        if accepted {
+            inner.validations_accepted += 1;
+        } else {
+            inner.validations_rejected += 1;
+        }
+    }
+
+    /// Records a decided height. Only the last `PARTICIPATION_WINDOW` heights
+    /// count toward participation rates.
+    pub fn record_height(&self, rounds: u32, expected: &[Pubkey], voted: &[Pubkey]) {
+        let mut inner = self.inner.lock();
+        inner.rounds_per_height.observe(rounds as f64);
+        if inner.heights.len() == PARTICIPATION_WINDOW {
+            inner.heights.pop_front();
+        }
+        inner.heights.push_back(HeightRecord {
+            expected: expected.to_vec(),
+            voted: voted.iter().copied().collect(),
+        });
+    }
+
+    pub fn record_chain_heights(&self, head: u64, finalized: u64) {
+        let mut inner = self.inner.lock();
+        inner.head_height = head;
+        inner.finalized_height = finalized;
+    }
+
+    /// Records the time from the previous finalization to this one.
+    pub fn record_round_latency(&self, latency: Duration) {
+        let mut inner = self.inner.lock();
+        inner.round_latency.observe(latency.as_secs_f64());
+        inner.last_round_latency = Some(latency);
+    }
+
+    /// The head and finalized heights last recorded.
+    pub fn chain_heights(&self) -> (u64, u64) {
+        let inner = self.inner.lock();
+        (inner.head_height, inner.finalized_height)
+    }
+
+    pub fn last_round_latency(&self) -> Option<Duration> {
+        self.inner.lock().last_round_latency
+    }
+
+    pub fn record_missed_proposal(&self, leader: Pubkey) {
+        *self.inner.lock().missed_proposals.entry(leader).or_insert(0) += 1;
+    }
+
+    pub fn record_invalid_proposal(&self, proposer: Pubkey) {
+        *self.inner.lock().invalid_proposals.entry(proposer).or_insert(0) += 1;
+    }
+
+    pub fn participation_rate(&self, validator: &Pubkey) -> Option<f64> {
+        Self::participation(&self.inner.lock()).get(validator).copied()
+    }
+
+    fn participation(inner: &MetricsInner) -> BTreeMap<Pubkey, f64> {
+        let mut tallies: BTreeMap<Pubkey, (u64, u64)> = BTreeMap::new();
+        for record in &inner.heights {
+            for validator in &record.expected {
+                let tally = tallies.entry(*validator).or_insert((0, 0));
+                tally.1 += 1;
+                if record.voted.contains(validator) {
+                    tally.0 += 1;
+                }
+            }
+        }
+        tallies.into_iter()
+            .map(|(validator, (voted, expected))| (validator, voted as f64 / expected as f64))
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> ConsensusMetricsSnapshot {
+        let inner = self.inner.lock();
+        ConsensusMetricsSnapshot {
+            validations_accepted: inner.validations_accepted,
+            validations_rejected: inner.validations_rejected,
+            validation_latency_mean_secs: inner.validation_latency.mean(),
+            confirmations_mean: inner.confirmations.mean(),
+            rounds_per_height_mean: inner.rounds_per_height.mean(),
+            heights_observed: inner.heights.len(),
+            participation: Self::participation(&inner).into_iter()
+                .map(|(validator, rate)| (validator.to_string(), rate))
+                .collect(),
+            missed_proposals: inner.missed_proposals.iter()
+                .map(|(validator, count)| (validator.to_string(), *count))
+                .collect(),
+            invalid_proposals: inner.invalid_proposals.iter()
+                .map(|(validator, count)| (validator.to_string(), *count))
+                .collect(),
+        }
+    }
+
+    /// Prometheus text exposition of every consensus series.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock();
+        let mut out = String::new();
+
+        metrics::write_header(&mut out, "dadbs_consensus_validations_total", "Transactions validated by outcome", "counter");
+        metrics::write_sample(&mut out, "dadbs_consensus_validations_total", &[("result", "accepted")], inner.validations_accepted as f64);
+        metrics::write_sample(&mut out, "dadbs_consensus_validations_total", &[("result", "rejected")], inner.validations_rejected as f64);
+
+        metrics::write_histogram(&mut out, "dadbs_consensus_validation_latency_seconds", "Time spent validating a transaction", &inner.validation_latency);
+        metrics::write_histogram(&mut out, "dadbs_consensus_confirmations", "Validator confirmations gathered per transaction", &inner.confirmations);
+        metrics::write_histogram(&mut out, "dadbs_consensus_rounds_per_height", "Rounds needed to decide a height", &inner.rounds_per_height);
+        metrics::write_histogram(&mut out, "dadbs_consensus_round_latency_seconds", "Time between successive finalizations", &inner.round_latency);
+        metrics::write_gauge(&mut out, "dadbs_block_height", "Height of the canonical head", inner.head_height as f64);
+        metrics::write_gauge(&mut out, "dadbs_finalized_height", "Height of the latest finalized block", inner.finalized_height as f64);
+
+        metrics::write_header(&mut out, "dadbs_consensus_vote_participation", "Share of recent heights each validator voted on", "gauge");
+        for (validator, rate) in Self::participation(&inner) {
+            metrics::write_sample(&mut out, "dadbs_consensus_vote_participation", &[("validator", &validator.to_string())], rate);
+        }
+
+        metrics::write_header(&mut out, "dadbs_consensus_missed_proposals_total", "Proposals missed while leader", "counter");
+        for (validator, count) in &inner.missed_proposals {
+            metrics::write_sample(&mut out, "dadbs_consensus_missed_proposals_total", &[("validator", &validator.to_string())], *count as f64);
+        }
+
+        let name = "dadbs_consensus_invalid_proposals_total";
+        metrics::write_header(&mut out, name, "Proposals rejected for breaking protocol parameters", "counter");
+        for (validator, count) in &inner.invalid_proposals {
+            metrics::write_sample(&mut out, name, &[("validator", &validator.to_string())], *count as f64);
+        }
+
+        out
+    }
+}
+
+impl MetricsSource for ConsensusMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        out.push_str(&self.render());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_validation_metrics() {
+        let metrics = ConsensusMetrics::new();
+        metrics.record_validation(Duration::from_millis(4), 3, true);
+        metrics.record_validation(Duration::from_millis(20), 1, false);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("dadbs_consensus_validations_total{result=\"accepted\"} 1"));
+        assert!(rendered.contains("dadbs_consensus_validations_total{result=\"rejected\"} 1"));
+        assert!(rendered.contains("dadbs_consensus_validation_latency_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(rendered.contains("dadbs_consensus_validation_latency_seconds_bucket{le=\"0.025\"} 2"));
+        assert!(rendered.contains("dadbs_consensus_validation_latency_seconds_count 2"));
+        assert!(rendered.contains("dadbs_consensus_confirmations_bucket{le=\"4\"} 2"));
+        assert!(rendered.contains("# TYPE dadbs_consensus_rounds_per_height histogram"));
+    }
+
+    #[test]
+    fn test_participation_and_missed_proposals() {
+        let metrics = ConsensusMetrics::new();
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+
+        for height in 0..4 {
+            let voted = if height % 2 == 0 { vec![a, b] } else { vec![a] };
+            metrics.record_height(1 + height % 2, &[a, b], &voted);
+        }
+        metrics.record_missed_proposal(b);
+
+        assert_eq!(metrics.participation_rate(&a), Some(1.0));
+        assert_eq!(metrics.participation_rate(&b), Some(0.5));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(&format!("dadbs_consensus_vote_participation{{validator=\"{}\"}} 0.5", b)));
+        assert!(rendered.contains(&format!("dadbs_consensus_missed_proposals_total{{validator=\"{}\"}} 1", b)));
+        assert!(rendered.contains("dadbs_consensus_rounds_per_height_sum 6"));
+    }
+
+    #[test]
+    fn test_participation_uses_sliding_window() {
+        let metrics = ConsensusMetrics::new();
+        let validator = Pubkey::new_unique();
+
+        for _ in 0..PARTICIPATION_WINDOW {
+            metrics.record_height(1, &[validator], &[]);
+        }
+        assert_eq!(metrics.participation_rate(&validator), Some(0.0));
+
+        for _ in 0..PARTICIPATION_WINDOW / 2 {
+            metrics.record_height(1, &[validator], &[validator]);
+        }
+        assert_eq!(metrics.participation_rate(&validator), Some(0.5));
+        assert_eq!(metrics.snapshot().heights_observed, PARTICIPATION_WINDOW);
+    }
+}
@@ -0,0 +1,212 @@
+use log::{error, info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::Hash;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    #[error("Consensus is halted: {0}")]
+    Halted(HaltReason),
+    #[error("Resuming after an automatic halt requires a reason")]
+    ReasonRequired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HaltReason {
+    /// Paused by an operator.
+    Paused,
+    FinalizedHeightRegression { previous: u64, current: u64 },
+    /// A quorum of validators reported `peer_root` where we computed `local_root`.
+    StateRootMismatch { height: u64, local_root: Hash, peer_root: Hash },
+    StorageFailure(String),
+    /// A finalized block could not be applied to local state.
+    StateFault(String),
+    /// The remote signer holding the validator key could not sign a vote.
+    SignerUnreachable(String),
+}
+
+impl HaltReason {
+    pub fn is_automatic(&self) -> bool {
+        !matches!(self, HaltReason::Paused)
+    }
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaltReason::Paused => f.write_str("paused by operator"),
+            HaltReason::FinalizedHeightRegression { previous, current } => {
+                write!(f, "finalized height regressed from {} to {}", previous, current)
+            }
+            HaltReason::StateRootMismatch { height, local_root, peer_root } => write!(
+                f,
+                "state root {} at height {} disagrees with quorum root {}",
+                local_root, height, peer_root
+            ),
+            HaltReason::StorageFailure(e) => write!(f, "storage failure: {}", e),
+            HaltReason::StateFault(e) => write!(f, "state fault: {}", e),
+            HaltReason::SignerUnreachable(e) => write!(f, "remote signer unreachable: {}", e),
+        }
+    }
+}
+
+/// Why and since when consensus is halted, as reported over RPC/health.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HaltStatus {
+    pub reason: HaltReason,
+    /// Unix-millisecond time the halt began.
+    pub since: i64,
+}
+
+/// How far LLM work has been asked to shrink, and until when.
+#[derive(Debug, Clone, Copy)]
+struct LlmPressure {
+    factor: f64,
+    until: Instant,
+}
+
+/// A shared switch that stops this node from voting or proposing while it
+/// keeps gossiping and serving reads. Clones share the same switch. It
+/// also carries consensus's requests for inference to give way to it.
+#[derive(Debug, Clone, Default)]
+pub struct ConsensusControl {
+    status: Arc<RwLock<Option<HaltStatus>>>,
+    llm_pressure: Arc<RwLock<Option<LlmPressure>>>,
+}
+
+impl ConsensusControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        let mut status = self.status.write();
+        if status.is_none() {
+            warn!("Consensus paused by operator");
+            *status = Some(HaltStatus { reason: HaltReason::Paused, since: chrono::Utc::now().timestamp_millis() });
+        }
+    }
+
+    /// Halts on an invariant violation. An earlier automatic halt is kept
+    /// since it is the root cause; a manual pause is upgraded.
+    pub fn halt(&self, reason: HaltReason) {
+        let mut status = self.status.write();
+        if status.as_ref().is_some_and(|s| s.reason.is_automatic()) {
+            warn!("Consensus already halted, also observed: {}", reason);
+            return;
+        }
+        error!("HALTING CONSENSUS: {}", reason);
+        *status = Some(HaltStatus { reason, since: chrono::Utc::now().timestamp_millis() });
+    }
+
+    /// Clears a pause or halt. After an automatic halt the operator must say
+    /// why it is safe to continue; the reason is logged.
+    pub fn resume(&self, reason: &str) -> Result<(), ControlError> {
+        let mut status = self.status.write();
+        let halted = match status.as_ref() {
+            Some(halted) => halted,
+            None => return Ok(()),
+        };
+        if halted.reason.is_automatic() && reason.trim().is_empty() {
+            return Err(ControlError::ReasonRequired);
+        }
+        info!("Consensus resumed (was: {}): {}", halted.reason, reason);
+        *status = None;
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.status.read().is_some()
+    }
+
+    pub fn status(&self) -> Option<HaltStatus> {
+        self.status.read().clone()
+    }
+
+    /// Fails if voting and proposing are currently disallowed.
+    pub fn ensure_active(&self) -> Result<(), ControlError> {
+        match self.status.read().as_ref() {
+            Some(status) => Err(ControlError::Halted(status.reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Asks LLM work to run at `factor` (0 to 1) of its capacity for
+    /// `duration`. Overlapping requests keep the smaller factor and the
+    /// later end.
+    pub fn shrink_llm_capacity(&self, factor: f64, duration: Duration) {
+        let now = Instant::now();
+        let (factor, until) = (factor.clamp(0.0, 1.0), now + duration);
+        let mut pressure = self.llm_pressure.write();
+        let shrunk = match *pressure {
+            Some(current) if current.until > now => {
+                LlmPressure { factor: current.factor.min(factor), until: current.until.max(until) }
+            }
+            _ => {
+                info!("Shrinking LLM capacity to {:.0}% for {:?}", factor * 100.0, duration);
+                LlmPressure { factor, until }
+            }
+        };
+        *pressure = Some(shrunk);
+    }
+
+    /// The share of its capacity LLM work may use now: 1 unless shrunk.
+    pub fn llm_capacity_factor(&self) -> f64 {
+        match *self.llm_pressure.read() {
+            Some(pressure) if pressure.until > Instant::now() => pressure.factor,
+            _ => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_automatic_halt_requires_reason() {
+        let control = ConsensusControl::new();
+        let handle = control.clone();
+        control.halt(HaltReason::StorageFailure("disk full".to_string()));
+
+        assert!(handle.is_halted());
+        assert!(matches!(handle.ensure_active(), Err(ControlError::Halted(HaltReason::StorageFailure(_)))));
+        assert_eq!(handle.resume("  "), Err(ControlError::ReasonRequired));
+
+        // A later violation does not mask the first one.
+        control.halt(HaltReason::FinalizedHeightRegression { previous: 5, current: 3 });
+        assert!(matches!(handle.status().unwrap().reason, HaltReason::StorageFailure(_)));
+
+        handle.resume("replaced disk, state verified against snapshot").unwrap();
+        assert!(control.ensure_active().is_ok());
+    }
+
+    #[test]
+    fn test_manual_pause() {
+        let control = ConsensusControl::new();
+        control.pause();
+        assert_eq!(control.status().unwrap().reason, HaltReason::Paused);
+        control.halt(HaltReason::StateFault("bad block".to_string()));
+        assert!(control.status().unwrap().reason.is_automatic());
+
+        let paused = ConsensusControl::new();
+        paused.pause();
+        paused.resume("").unwrap();
+        assert!(!paused.is_halted());
+    }
+
+    #[test]
+    fn test_llm_pressure_expires() {
+        let control = ConsensusControl::new();
+        assert_eq!(control.llm_capacity_factor(), 1.0);
+        control.shrink_llm_capacity(0.5, Duration::from_millis(30));
+        control.clone().shrink_llm_capacity(0.75, Duration::from_millis(10));
+        assert_eq!(control.llm_capacity_factor(), 0.5);
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(control.llm_capacity_factor(), 1.0);
+    }
+}
@@ -0,0 +1,207 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+};
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    #[error("Signature scheme {0} is not compiled into this build")]
+    Unsupported(SchemeKind),
+    #[error("Validator set mixes signature schemes: {0} and {1}")]
+    MixedSchemes(SchemeKind, SchemeKind),
+    #[error("Invalid {0} key material")]
+    InvalidKey(SchemeKind),
+}
+
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize,
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemeKind {
+    #[default]
+    Ed25519,
+    Bls,
+}
+
+impl fmt::Display for SchemeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemeKind::Ed25519 => f.write_str("ed25519"),
+            SchemeKind::Bls => f.write_str("bls"),
+        }
+    }
+}
+
+/// A signature scheme validators can sign votes with. Schemes that support
+/// aggregation let a commit certificate carry one signature for all signers.
+pub trait SignatureScheme: Send + Sync + fmt::Debug {
+    fn kind(&self) -> SchemeKind;
+
+    fn sign(&self, secret_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError>;
+
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool;
+
+    fn supports_aggregation(&self) -> bool {
+        false
+    }
+
+    /// Combines signatures over the same message, or `None` if unsupported.
+    fn aggregate(&self, _signatures: &[&[u8]]) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Verifies an aggregate produced by `aggregate` over one shared message.
+    fn verify_aggregate(&self, _public_keys: &[&[u8]], _message: &[u8], _signature: &[u8]) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ed25519Scheme;
+
+impl SignatureScheme for Ed25519Scheme {
+    fn kind(&self) -> SchemeKind {
+        SchemeKind::Ed25519
+    }
+
+    /// `secret_key` is the 64-byte keypair encoding.
+    fn sign(&self, secret_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let keypair = Keypair::from_bytes(secret_key).map_err(|_| CryptoError::InvalidKey(SchemeKind::Ed25519))?;
+        Ok(keypair.sign_message(message).as_ref().to_vec())
+    }
+
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let (Ok(public_key), Ok(signature)) = (Pubkey::try_from(public_key), Signature::try_from(signature)) else {
+            return false;
+        };
+        signature.verify(public_key.as_ref(), message)
+    }
+}
+
+/// BLS12-381 with public keys in G1. Aggregate verification assumes every
+/// consensus key was registered with a proof of possession.
+#[cfg(feature = "bls")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlsScheme;
+
+#[cfg(feature = "bls")]
+impl BlsScheme {
+    const DST: &'static [u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
+
+    /// Derives a key pair from at least 32 bytes of key material, returning
+    /// `(secret_key, public_key)`.
+    pub fn keygen(ikm: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        let secret = blst::min_pk::SecretKey::key_gen(ikm, &[])
+            .map_err(|_| CryptoError::InvalidKey(SchemeKind::Bls))?;
+        Ok((secret.to_bytes().to_vec(), secret.sk_to_pk().to_bytes().to_vec()))
+    }
+}
+
+#[cfg(feature = "bls")]
+impl SignatureScheme for BlsScheme {
+    fn kind(&self) -> SchemeKind {
+        SchemeKind::Bls
+    }
+
+    fn sign(&self, secret_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let secret = blst::min_pk::SecretKey::from_bytes(secret_key)
+            .map_err(|_| CryptoError::InvalidKey(SchemeKind::Bls))?;
+        Ok(secret.sign(message, Self::DST, &[]).to_bytes().to_vec())
+    }
+
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let (Ok(public_key), Ok(signature)) = (
+            blst::min_pk::PublicKey::from_bytes(public_key),
+            blst::min_pk::Signature::from_bytes(signature),
+        ) else {
+            return false;
+        };
+        signature.verify(true, message, Self::DST, &[], &public_key, true) == blst::BLST_ERROR::BLST_SUCCESS
+    }
+
+    fn supports_aggregation(&self) -> bool {
+        true
+    }
+
+    fn aggregate(&self, signatures: &[&[u8]]) -> Option<Vec<u8>> {
+        let signatures: Vec<blst::min_pk::Signature> = signatures.iter()
+            .map(|s| blst::min_pk::Signature::from_bytes(s))
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let refs: Vec<&blst::min_pk::Signature> = signatures.iter().collect();
+        let aggregate = blst::min_pk::AggregateSignature::aggregate(&refs, true).ok()?;
+        Some(aggregate.to_signature().to_bytes().to_vec())
+    }
+
+    fn verify_aggregate(&self, public_keys: &[&[u8]], message: &[u8], signature: &[u8]) -> bool {
+        let Ok(public_keys) = public_keys.iter()
+            .map(|k| blst::min_pk::PublicKey::from_bytes(k))
+            .collect::<Result<Vec<_>, _>>() else {
+            return false;
+        };
+        let Ok(signature) = blst::min_pk::Signature::from_bytes(signature) else {
+            return false;
+        };
+        if public_keys.is_empty() {
+            return false;
+        }
+        let refs: Vec<&blst::min_pk::PublicKey> = public_keys.iter().collect();
+        signature.fast_aggregate_verify(true, message, Self::DST, &refs) == blst::BLST_ERROR::BLST_SUCCESS
+    }
+}
+
+pub fn scheme(kind: SchemeKind) -> Result<Arc<dyn SignatureScheme>, CryptoError> {
+    match kind {
+        SchemeKind::Ed25519 => Ok(Arc::new(Ed25519Scheme)),
+        #[cfg(feature = "bls")]
+        SchemeKind::Bls => Ok(Arc::new(BlsScheme)),
+        #[cfg(not(feature = "bls"))]
+        SchemeKind::Bls => Err(CryptoError::Unsupported(SchemeKind::Bls)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ed25519_roundtrip() {
+        let keypair = Keypair::new();
+        let scheme = Ed25519Scheme;
+        let signature = scheme.sign(&keypair.to_bytes(), b"vote").unwrap();
+        assert!(scheme.verify(keypair.pubkey().as_ref(), b"vote", &signature));
+        assert!(!scheme.verify(keypair.pubkey().as_ref(), b"other", &signature));
+        assert!(!scheme.supports_aggregation());
+        assert!(scheme.aggregate(&[&signature]).is_none());
+    }
+
+    #[cfg(feature = "bls")]
+    #[test]
+    fn test_bls_aggregate_roundtrip() {
+        let scheme = BlsScheme;
+        let keys: Vec<(Vec<u8>, Vec<u8>)> = (0..4u8).map(|i| BlsScheme::keygen(&[i; 32]).unwrap()).collect();
+        let signatures: Vec<Vec<u8>> = keys.iter().map(|(sk, _)| scheme.sign(sk, b"block").unwrap()).collect();
+        for ((_, pk), signature) in keys.iter().zip(&signatures) {
+            assert!(scheme.verify(pk, b"block", signature));
+        }
+
+        let refs: Vec<&[u8]> = signatures.iter().map(|s| s.as_slice()).collect();
+        let aggregate = scheme.aggregate(&refs).unwrap();
+        let public_keys: Vec<&[u8]> = keys.iter().map(|(_, pk)| pk.as_slice()).collect();
+        assert!(scheme.verify_aggregate(&public_keys, b"block", &aggregate));
+        assert!(!scheme.verify_aggregate(&public_keys[..3], b"block", &aggregate));
+        assert!(!scheme.verify_aggregate(&public_keys, b"other", &aggregate));
+    }
+
+    #[cfg(not(feature = "bls"))]
+    #[test]
+    fn test_bls_unavailable_without_feature() {
+        assert_eq!(scheme(SchemeKind::Bls).unwrap_err(), CryptoError::Unsupported(SchemeKind::Bls));
+    }
+}
@@ -0,0 +1,829 @@
+//! Credits DADBS balances for SOL staked on Solana: the stake program's
+//! `StakeCreated` events are polled over Solana RPC and, once a transaction
+//! holding one is finalized, its amount is sent from the bridge account to
+//! the staker's DADBS address.
+//!
+//! Transactions are discovered at the configured commitment and wait there
+//! until finalized. One that drops out of the listing first was rolled back
+//! by a fork: it is forgotten, counted in `dadbs_deposit_reorged_total`, and
+//! credited only if it lands again. Each credit is written to the cursor
+//! file, signed, before it is submitted, so a restart resubmits the same
+//! transaction rather than crediting a deposit twice.
+
+use async_trait::async_trait;
+use log::{info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_request::RpcRequest;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+
+use super::keystore::{Keystore, KeystoreError};
+use super::mempool::{Mempool, MempoolError};
+use super::metrics::{self, MetricsSource};
+use super::network::{NetMessage, Network};
+use super::state::State;
+use super::transaction::Transaction;
+use crate::program::events::{stake_created_events, StakeCreated};
+use crate::utils::DADBSAddress;
+
+pub const DEFAULT_BRIDGE_KEY: &str = "bridge";
+pub const DEFAULT_BRIDGE_PASSPHRASE_ENV: &str = "DADBS_BRIDGE_PASSPHRASE";
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 5_000;
+pub const DEPOSIT_CURSOR_FILE: &str = "deposits.json";
+/// Signatures asked for per `getSignaturesForAddress` call, the RPC's maximum.
+const SIGNATURE_PAGE: usize = 1_000;
+
+/// How far a Solana transaction has to get before the watcher sees it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Commitment {
+    Processed,
+    #[default]
+    Confirmed,
+    Finalized,
+}
+
+impl Commitment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Commitment::Processed => "processed",
+            Commitment::Confirmed => "confirmed",
+            Commitment::Finalized => "finalized",
+        }
+    }
+}
+
+/// The `[deposit_watcher]` section. Like the faucet's, the bridge key is
+/// read from the keystore with its passphrase kept out of the config file.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DepositWatcherConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Solana JSON-RPC endpoint.
+    #[serde(default)]
+    pub rpc_url: String,
+    /// The stake program, base58.
+    #[serde(default)]
+    pub program_id: String,
+    /// Deposits are always credited at `finalized`; this is where they are
+    /// first seen, and so where rollbacks before finality are noticed.
+    #[serde(default)]
+    pub commitment: Commitment,
+    /// Keystore name of the funded key credits are sent from.
+    #[serde(default = "default_bridge_key")]
+    pub bridge_key: String,
+    #[serde(default)]
+    pub passphrase_file: Option<String>,
+    #[serde(default = "default_passphrase_env")]
+    pub passphrase_env: String,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// Defaults to `deposits.json` in `storage_path`.
+    #[serde(default)]
+    pub cursor_path: Option<String>,
+}
+
+fn default_bridge_key() -> String {
+    DEFAULT_BRIDGE_KEY.to_string()
+}
+
+fn default_passphrase_env() -> String {
+    DEFAULT_BRIDGE_PASSPHRASE_ENV.to_string()
+}
+
+fn default_poll_interval_ms() -> u64 {
+    DEFAULT_POLL_INTERVAL_MS
+}
+
+impl Default for DepositWatcherConfig {
+    fn default() -> Self {
+        DepositWatcherConfig {
+            enabled: false,
+            rpc_url: String::new(),
+            program_id: String::new(),
+            commitment: Commitment::default(),
+            bridge_key: default_bridge_key(),
+            passphrase_file: None,
+            passphrase_env: default_passphrase_env(),
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            cursor_path: None,
+        }
+    }
+}
+
+impl DepositWatcherConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_ms == 0 {
+            return Err("deposit_watcher.poll_interval_ms must be at least 1".to_string());
+        }
+        if self.enabled {
+            if self.rpc_url.is_empty() {
+                return Err("deposit_watcher.rpc_url must be set".to_string());
+            }
+            if self.bridge_key.is_empty() {
+                return Err("deposit_watcher.bridge_key must name a keystore key".to_string());
+            }
+            self.program_id()?;
+        }
+        Ok(())
+    }
+
+    pub fn program_id(&self) -> Result<Pubkey, String> {
+        Pubkey::from_str(&self.program_id).map_err(|e| format!("deposit_watcher.program_id: {}", e))
+    }
+
+    pub fn cursor_file(&self, storage_path: &str) -> PathBuf {
+        match &self.cursor_path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(storage_path).join(DEPOSIT_CURSOR_FILE),
+        }
+    }
+
+    /// The bridge key's passphrase, or `None` if neither source has one.
+    pub fn passphrase(&self) -> std::io::Result<Option<String>> {
+        let passphrase = match &self.passphrase_file {
+            Some(path) => Some(fs::read_to_string(path)?),
+            None => std::env::var(&self.passphrase_env).ok(),
+        };
+        Ok(passphrase.map(|passphrase| passphrase.trim_end_matches(['\r', '\n']).to_string()).filter(|p| !p.is_empty()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DepositError {
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Solana RPC failed: {0}")]
+    Fetch(String),
+    #[error("No passphrase for bridge key {key}: set deposit_watcher.passphrase_file or {env}")]
+    NoPassphrase { key: String, env: String },
+    #[error("Cannot sign credit: {0}")]
+    Signing(String),
+    #[error("Keystore error: {0}")]
+    Keystore(#[from] KeystoreError),
+    #[error("Cursor file error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// A `StakeCreated` event in a finalized transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deposit<'a> {
+    pub signature: &'a str,
+    /// Position among the transaction's events.
+    pub index: usize,
+    pub slot: u64,
+    pub event: StakeCreated,
+}
+
+impl<'a> Deposit<'a> {
+    /// The deposits of a finalized transaction; none if it failed.
+    pub fn in_transaction(program_id: &Pubkey, signature: &'a str, transaction: &SolanaTransaction) -> Vec<Self> {
+        if transaction.failed {
+            return Vec::new();
+        }
+        stake_created_events(program_id, &transaction.logs)
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| Deposit { signature, index, slot: transaction.slot, event })
+            .collect()
+    }
+
+    /// Unique across the program's history: `<signature>:<index>`.
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.signature, self.index)
+    }
+
+    pub fn recipient(&self) -> DADBSAddress {
+        DADBSAddress::from_pubkey(&self.event.staker)
+    }
+}
+
+/// A deposit the watcher credited, with the transaction it signed for it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Credit {
+    pub staker: String,
+    pub recipient: DADBSAddress,
+    pub amount: u64,
+    pub slot: u64,
+    pub tx_hash: String,
+    pub transaction: Transaction,
+}
+
+/// What the watcher has done, kept across restarts.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DepositCursor {
+    /// The newest signature up to which every transaction is settled.
+    /// Settled transactions are finalized, so scans can stop there.
+    #[serde(default)]
+    pub until: Option<String>,
+    /// By deposit id.
+    #[serde(default)]
+    pub credits: BTreeMap<String, Credit>,
+}
+
+impl DepositCursor {
+    /// An empty cursor if `path` does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, DepositError> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DepositCursor::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), DepositError> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn credited_total(&self) -> u64 {
+        self.credits.values().fold(0, |total, credit| total.saturating_add(credit.amount))
+    }
+
+    /// Past the newest nonce a credit was signed with.
+    fn next_nonce(&self) -> u64 {
+        self.credits.values().map(|credit| credit.transaction.nonce + 1).max().unwrap_or(0)
+    }
+}
+
+/// A transaction calling the stake program, as listed by
+/// `getSignaturesForAddress`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureStatus {
+    pub signature: String,
+    pub slot: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolanaTransaction {
+    pub slot: u64,
+    pub failed: bool,
+    pub logs: Vec<String>,
+}
+
+/// The Solana RPC calls the watcher makes; mocked in tests.
+#[async_trait]
+pub trait DepositSource: Send + Sync {
+    /// The program's transactions at `commitment` newer than `until`,
+    /// newest first.
+    async fn signatures(&self, until: Option<&str>, commitment: Commitment) -> Result<Vec<SignatureStatus>, String>;
+
+    /// The transaction, once it is finalized.
+    async fn finalized_transaction(&self, signature: &str) -> Result<Option<SolanaTransaction>, String>;
+}
+
+pub struct RpcDepositSource {
+    rpc_client: RpcClient,
+    program_id: Pubkey,
+}
+
+impl RpcDepositSource {
+    pub fn new(rpc_url: String, program_id: Pubkey) -> Self {
+        RpcDepositSource { rpc_client: RpcClient::new(rpc_url), program_id }
+    }
+
+    async fn call(&self, request: RpcRequest, params: Value) -> Result<Value, String> {
+        self.rpc_client.send(request, params).await.map_err(|e| e.to_string())
+    }
+}
+
+#[async_trait]
+impl DepositSource for RpcDepositSource {
+    async fn signatures(&self, until: Option<&str>, commitment: Commitment) -> Result<Vec<SignatureStatus>, String> {
+        let mut statuses = Vec::new();
+        let mut before: Option<String> = None;
+        loop {
+            let options = json!({
+                "before": before,
+                "until": until,
+                "limit": SIGNATURE_PAGE,
+                "commitment": commitment.as_str(),
+            });
+            let page =
+                self.call(RpcRequest::GetSignaturesForAddress, json!([self.program_id.to_string(), options])).await?;
+            let page = page.as_array().ok_or("getSignaturesForAddress did not return a list")?;
+            for entry in page {
+                let (signature, slot) = entry["signature"]
+                    .as_str()
+                    .zip(entry["slot"].as_u64())
+                    .ok_or_else(|| format!("malformed signature entry {}", entry))?;
+                statuses.push(SignatureStatus { signature: signature.to_string(), slot });
+            }
+            if page.len() < SIGNATURE_PAGE {
+                return Ok(statuses);
+            }
+            before = statuses.last().map(|status| status.signature.clone());
+        }
+    }
+
+    async fn finalized_transaction(&self, signature: &str) -> Result<Option<SolanaTransaction>, String> {
+        let options = json!({ "encoding": "json", "commitment": "finalized", "maxSupportedTransactionVersion": 0 });
+        let transaction = self.call(RpcRequest::GetTransaction, json!([signature, options])).await?;
+        if transaction.is_null() {
+            return Ok(None);
+        }
+        let meta = &transaction["meta"];
+        let logs = meta["logMessages"].as_array().into_iter().flatten();
+        Ok(Some(SolanaTransaction {
+            slot: transaction["slot"].as_u64().ok_or_else(|| format!("no slot for {}", signature))?,
+            failed: !meta["err"].is_null(),
+            logs: logs.filter_map(|line| line.as_str().map(str::to_string)).collect(),
+        }))
+    }
+}
+
+/// Signs credits from the bridge account.
+#[async_trait]
+pub trait BridgeSigner: Send + Sync {
+    fn pubkey(&self) -> Pubkey;
+    async fn sign(&self, message: Vec<u8>) -> Result<Signature, DepositError>;
+}
+
+#[async_trait]
+impl BridgeSigner for Keypair {
+    fn pubkey(&self) -> Pubkey {
+        Signer::pubkey(self)
+    }
+
+    async fn sign(&self, message: Vec<u8>) -> Result<Signature, DepositError> {
+        Ok(self.sign_message(&message))
+    }
+}
+
+/// The bridge key in the keystore, unlocked again when it auto-locks.
+pub struct KeystoreSigner {
+    keystore: Arc<Keystore>,
+    key: String,
+    passphrase: String,
+    pubkey: Pubkey,
+}
+
+impl KeystoreSigner {
+    /// Unlocks the configured key, which checks its passphrase.
+    pub fn open(config: &DepositWatcherConfig, keystore: Arc<Keystore>) -> Result<Self, DepositError> {
+        let passphrase = config.passphrase()?.ok_or_else(|| DepositError::NoPassphrase {
+            key: config.bridge_key.clone(),
+            env: config.passphrase_env.clone(),
+        })?;
+        let pubkey = keystore.pubkey(&config.bridge_key)?;
+        keystore.unlock(&config.bridge_key, &passphrase)?;
+        Ok(KeystoreSigner { keystore, key: config.bridge_key.clone(), passphrase, pubkey })
+    }
+}
+
+#[async_trait]
+impl BridgeSigner for KeystoreSigner {
+    fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    async fn sign(&self, message: Vec<u8>) -> Result<Signature, DepositError> {
+        if !self.keystore.is_unlocked(&self.key) {
+            let (keystore, key, passphrase) = (Arc::clone(&self.keystore), self.key.clone(), self.passphrase.clone());
+            tokio::task::spawn_blocking(move || keystore.unlock(&key, &passphrase))
+                .await
+                .map_err(|e| DepositError::Signing(e.to_string()))??;
+        }
+        Ok(self.keystore.sign(&self.key, &message)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DepositWatcherSnapshot {
+    pub polls: u64,
+    pub failures: u64,
+    pub credited: u64,
+    pub credited_amount: u64,
+    /// Transactions seen at the configured commitment, not yet finalized.
+    pub pending: usize,
+    /// Transactions rolled back by a fork before they were finalized.
+    pub reorged: u64,
+}
+
+#[derive(Default)]
+pub struct DepositWatcherMetrics {
+    inner: Mutex<DepositWatcherSnapshot>,
+}
+
+impl DepositWatcherMetrics {
+    pub fn snapshot(&self) -> DepositWatcherSnapshot {
+        *self.inner.lock()
+    }
+}
+
+impl MetricsSource for DepositWatcherMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        let snapshot = self.snapshot();
+        metrics::write_counter(out, "dadbs_deposit_polls_total", "Polls of the stake program", snapshot.polls);
+        metrics::write_counter(out, "dadbs_deposit_poll_failures_total", "Polls that failed", snapshot.failures);
+        metrics::write_counter(out, "dadbs_deposit_credits_total", "Deposits credited", snapshot.credited);
+        metrics::write_counter(
+            out,
+            "dadbs_deposit_credited_amount_total",
+            "Amount sent from the bridge account",
+            snapshot.credited_amount,
+        );
+        metrics::write_gauge(
+            out,
+            "dadbs_deposit_pending",
+            "Stake transactions waiting for finality",
+            snapshot.pending as f64,
+        );
+        metrics::write_counter(
+            out,
+            "dadbs_deposit_reorged_total",
+            "Stake transactions rolled back before finality",
+            snapshot.reorged,
+        );
+    }
+}
+
+/// Credits finalized `StakeCreated` events from the bridge account.
+pub struct DepositWatcher {
+    source: Arc<dyn DepositSource>,
+    program_id: Pubkey,
+    commitment: Commitment,
+    poll_interval: Duration,
+    signer: Arc<dyn BridgeSigner>,
+    cursor_path: PathBuf,
+    cursor: AsyncMutex<DepositCursor>,
+    /// Seen but not finalized, by signature, with their slot.
+    pending: Mutex<BTreeMap<String, u64>>,
+    state: Arc<RwLock<State>>,
+    mempool: Arc<Mutex<Mempool>>,
+    network: Option<Arc<Network>>,
+    metrics: Arc<DepositWatcherMetrics>,
+}
+
+impl DepositWatcher {
+    /// Picks up where the cursor at `cursor_path` left off.
+    pub fn new(
+        config: &DepositWatcherConfig,
+        source: Arc<dyn DepositSource>,
+        signer: Arc<dyn BridgeSigner>,
+        cursor_path: PathBuf,
+        state: Arc<RwLock<State>>,
+        mempool: Arc<Mutex<Mempool>>,
+    ) -> Result<Self, DepositError> {
+        config.validate().map_err(DepositError::Config)?;
+        let cursor = DepositCursor::load(&cursor_path)?;
+        Ok(DepositWatcher {
+            source,
+            program_id: config.program_id().map_err(DepositError::Config)?,
+            commitment: config.commitment,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            signer,
+            cursor_path,
+            cursor: AsyncMutex::new(cursor),
+            pending: Mutex::new(BTreeMap::new()),
+            state,
+            mempool,
+            network: None,
+            metrics: Arc::new(DepositWatcherMetrics::default()),
+        })
+    }
+
+    /// Gossips credits to peers as well as admitting them locally.
+    pub fn with_network(mut self, network: Arc<Network>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn metrics(&self) -> Arc<DepositWatcherMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    pub async fn cursor(&self) -> DepositCursor {
+        self.cursor.lock().await.clone()
+    }
+
+    /// Resubmits credits not yet applied, then credits every deposit
+    /// finalized since the last poll. Returns how many were credited.
+    pub async fn poll(&self) -> Result<usize, DepositError> {
+        let result = self.poll_once().await;
+        let pending = self.pending.lock().len();
+        let mut metrics = self.metrics.inner.lock();
+        metrics.polls += 1;
+        metrics.pending = pending;
+        if let Err(e) = &result {
+            warn!("Polling stake deposits failed: {}", e);
+            metrics.failures += 1;
+        }
+        result
+    }
+
+    async fn poll_once(&self) -> Result<usize, DepositError> {
+        let mut cursor = self.cursor.lock().await;
+        self.resubmit(&cursor);
+        let mut seen =
+            self.source.signatures(cursor.until.as_deref(), self.commitment).await.map_err(DepositError::Fetch)?;
+        seen.reverse();
+        let (mut credited, mut settled, mut waiting) = (0, None, BTreeMap::new());
+        for status in &seen {
+            match self.source.finalized_transaction(&status.signature).await.map_err(DepositError::Fetch)? {
+                Some(transaction) => {
+                    for deposit in Deposit::in_transaction(&self.program_id, &status.signature, &transaction) {
+                        credited += usize::from(self.credit(&mut cursor, &deposit).await?);
+                    }
+                    if waiting.is_empty() {
+                        settled = Some(status.signature.clone());
+                    }
+                }
+                None => {
+                    waiting.insert(status.signature.clone(), status.slot);
+                }
+            }
+        }
+        let listed: BTreeSet<&str> = seen.iter().map(|status| status.signature.as_str()).collect();
+        let rolled_back = {
+            let mut pending = self.pending.lock();
+            let previous = std::mem::replace(&mut *pending, waiting);
+            previous.into_iter().filter(|(signature, _)| !listed.contains(signature.as_str())).collect::<Vec<_>>()
+        };
+        for (signature, slot) in &rolled_back {
+            warn!("Stake transaction {} at slot {} was rolled back before finality; not crediting it", signature, slot);
+        }
+        self.metrics.inner.lock().reorged += rolled_back.len() as u64;
+        if settled.is_some() && settled != cursor.until {
+            cursor.until = settled;
+            cursor.save(&self.cursor_path)?;
+        }
+        Ok(credited)
+    }
+
+    /// Signs, records and submits the credit for `deposit` unless it has
+    /// one already.
+    async fn credit(&self, cursor: &mut DepositCursor, deposit: &Deposit<'_>) -> Result<bool, DepositError> {
+        let id = deposit.id();
+        if cursor.credits.contains_key(&id) {
+            return Ok(false);
+        }
+        let bridge = self.signer.pubkey();
+        let (nonce, fee, chain_id) = {
+            let state = self.state.read();
+            let mempool = self.mempool.lock();
+            let nonce = mempool.next_nonce(&bridge, &state).max(cursor.next_nonce());
+            (nonce, mempool.min_fee(), mempool.chain_id().to_string())
+        };
+        let (staker, amount) = (deposit.event.staker, deposit.event.amount);
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let mut transaction = Transaction::unsigned(bridge, staker, amount, fee, nonce, timestamp);
+        transaction.chain_id = chain_id;
+        transaction.payload = format!("deposit:{}", id).into_bytes();
+        transaction.signature = self.signer.sign(transaction.signing_bytes()).await?;
+        let credit = Credit {
+            staker: staker.to_string(),
+            recipient: deposit.recipient(),
+            amount,
+            slot: deposit.slot,
+            tx_hash: transaction.hash().to_string(),
+            transaction: transaction.clone(),
+        };
+        info!("Crediting {} to {} for stake deposit {} in {}", credit.amount, credit.recipient, id, credit.tx_hash);
+        cursor.credits.insert(id, credit);
+        cursor.save(&self.cursor_path)?;
+        self.submit(transaction);
+        let mut metrics = self.metrics.inner.lock();
+        metrics.credited += 1;
+        metrics.credited_amount = metrics.credited_amount.saturating_add(amount);
+        Ok(true)
+    }
+
+    /// Submits each recorded credit the bridge account's nonce has not
+    /// passed, in nonce order; ones already pending are left alone.
+    fn resubmit(&self, cursor: &DepositCursor) {
+        let applied = self.state.read().account(&DADBSAddress::from_pubkey(&self.signer.pubkey())).nonce;
+        let mut unapplied: Vec<&Credit> =
+            cursor.credits.values().filter(|credit| credit.transaction.nonce >= applied).collect();
+        unapplied.sort_by_key(|credit| credit.transaction.nonce);
+        for credit in unapplied {
+            self.submit(credit.transaction.clone());
+        }
+    }
+
+    fn submit(&self, transaction: Transaction) {
+        let admitted = {
+            let state = self.state.read();
+            self.mempool.lock().admit(transaction.clone(), &state)
+        };
+        match admitted {
+            Ok(()) => {
+                if let Some(network) = &self.network {
+                    network.gossip(NetMessage::Tx(transaction));
+                }
+            }
+            Err(MempoolError::Duplicate(_)) => {}
+            Err(e) => warn!("Credit {} not admitted, will retry: {}", transaction.hash(), e),
+        }
+    }
+
+    /// Polls every `poll_interval_ms` until `cancel` fires.
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        loop {
+            let _ = self.poll().await;
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(self.poll_interval) => {}
+            }
+        }
+    }
+}
+
+/// Credits checked against the program's finalized history.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Reconciliation {
+    pub deposits: usize,
+    pub deposited_total: u64,
+    pub credits: usize,
+    pub credited_total: u64,
+    /// Finalized deposits without a matching credit. Deposits finalized
+    /// since the watcher last polled show up here until it catches up.
+    pub uncredited: Vec<String>,
+    /// Credits without a finalized deposit of the same recipient and amount.
+    pub unbacked: Vec<String>,
+}
+
+impl Reconciliation {
+    pub fn is_balanced(&self) -> bool {
+        self.uncredited.is_empty() && self.unbacked.is_empty()
+    }
+}
+
+/// Rescans every finalized transaction of `program_id` and compares its
+/// deposits with `cursor`'s credits.
+pub async fn reconcile(
+    source: &dyn DepositSource,
+    program_id: &Pubkey,
+    cursor: &DepositCursor,
+) -> Result<Reconciliation, DepositError> {
+    let mut deposits = BTreeMap::new();
+    for status in source.signatures(None, Commitment::Finalized).await.map_err(DepositError::Fetch)? {
+        let Some(transaction) = source.finalized_transaction(&status.signature).await.map_err(DepositError::Fetch)?
+        else {
+            continue;
+        };
+        for deposit in Deposit::in_transaction(program_id, &status.signature, &transaction) {
+            deposits.insert(deposit.id(), (deposit.recipient(), deposit.event.amount));
+        }
+    }
+    let mut report = Reconciliation {
+        deposits: deposits.len(),
+        deposited_total: deposits.values().fold(0, |total, (_, amount)| total.saturating_add(*amount)),
+        credits: cursor.credits.len(),
+        credited_total: cursor.credited_total(),
+        ..Reconciliation::default()
+    };
+    for (id, credit) in &cursor.credits {
+        match deposits.remove(id) {
+            Some((recipient, amount)) if recipient == credit.recipient && amount == credit.amount => {}
+            _ => report.unbacked.push(id.clone()),
+        }
+    }
+    report.uncredited = deposits.into_keys().collect();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How far a mocked transaction has got.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Stage {
+        Confirmed,
+        Finalized,
+        /// Rolled back by a fork.
+        Dropped,
+    }
+
+    struct MockTransaction {
+        signature: String,
+        stage: Stage,
+        transaction: SolanaTransaction,
+    }
+
+    /// A Solana RPC serving the stake program's transactions, oldest first.
+    #[derive(Default)]
+    struct MockSolana(Mutex<Vec<MockTransaction>>);
+
+    impl MockSolana {
+        fn stake(&self, program_id: &Pubkey, event: StakeCreated, stage: Stage) -> String {
+            let mut transactions = self.0.lock();
+            let signature = Signature::new_unique().to_string();
+            let logs = vec![
+                format!("Program {} invoke [1]", program_id),
+                format!("Program log: {}", event.log_line()),
+                format!("Program {} success", program_id),
+            ];
+            let slot = transactions.len() as u64 + 100;
+            let transaction = SolanaTransaction { slot, failed: false, logs };
+            transactions.push(MockTransaction { signature: signature.clone(), stage, transaction });
+            signature
+        }
+
+        fn advance(&self, signature: &str, stage: Stage) {
+            self.0.lock().iter_mut().find(|mocked| mocked.signature == signature).unwrap().stage = stage;
+        }
+    }
+
+    #[async_trait]
+    impl DepositSource for MockSolana {
+        async fn signatures(
+            &self,
+            until: Option<&str>,
+            commitment: Commitment,
+        ) -> Result<Vec<SignatureStatus>, String> {
+            let listed = |stage: Stage| match commitment {
+                Commitment::Finalized => stage == Stage::Finalized,
+                _ => stage != Stage::Dropped,
+            };
+            let transactions = self.0.lock();
+            let newer = transactions.iter().rev().take_while(|mocked| Some(mocked.signature.as_str()) != until);
+            Ok(newer
+                .filter(|mocked| listed(mocked.stage))
+                .map(|mocked| SignatureStatus { signature: mocked.signature.clone(), slot: mocked.transaction.slot })
+                .collect())
+        }
+
+        async fn finalized_transaction(&self, signature: &str) -> Result<Option<SolanaTransaction>, String> {
+            let transactions = self.0.lock();
+            let mocked = transactions.iter().find(|mocked| mocked.signature == signature);
+            Ok(mocked.filter(|mocked| mocked.stage == Stage::Finalized).map(|mocked| mocked.transaction.clone()))
+        }
+    }
+
+    fn stake(amount: u64) -> StakeCreated {
+        StakeCreated { staker: Pubkey::new_unique(), stake_account: Pubkey::new_unique(), amount }
+    }
+
+    #[tokio::test]
+    async fn test_credits_finalized_deposits_once_and_skips_rolled_back_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let program_id = Pubkey::new_unique();
+        let config = DepositWatcherConfig { program_id: program_id.to_string(), ..DepositWatcherConfig::default() };
+        let solana = Arc::new(MockSolana::default());
+        let bridge = Arc::new(Keypair::new());
+        let state = Arc::new(RwLock::new(State::in_memory(vec![(
+            DADBSAddress::from_pubkey(&Signer::pubkey(bridge.as_ref())),
+            1_000_000,
+        )])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(1, 100)));
+        let cursor_path = dir.path().join(DEPOSIT_CURSOR_FILE);
+        let watcher = |solana: &Arc<MockSolana>| {
+            let source = Arc::clone(solana) as Arc<dyn DepositSource>;
+            let signer = Arc::clone(&bridge) as Arc<dyn BridgeSigner>;
+            DepositWatcher::new(&config, source, signer, cursor_path.clone(), Arc::clone(&state), Arc::clone(&mempool))
+                .unwrap()
+        };
+
+        let (kept, rolled_back) = (stake(5_000), stake(7_000));
+        let kept_signature = solana.stake(&program_id, kept, Stage::Confirmed);
+        let rolled_back_signature = solana.stake(&program_id, rolled_back, Stage::Confirmed);
+        let first = watcher(&solana);
+        assert_eq!(first.poll().await.unwrap(), 0);
+        assert_eq!(first.metrics().snapshot().pending, 2);
+
+        solana.advance(&kept_signature, Stage::Finalized);
+        solana.advance(&rolled_back_signature, Stage::Dropped);
+        assert_eq!(first.poll().await.unwrap(), 1);
+        let snapshot = first.metrics().snapshot();
+        assert_eq!((snapshot.credited, snapshot.credited_amount, snapshot.pending, snapshot.reorged), (1, 5_000, 0, 1));
+        let cursor = first.cursor().await;
+        assert_eq!(cursor.until.as_deref(), Some(kept_signature.as_str()));
+        let credit = &cursor.credits[&format!("{}:0", kept_signature)];
+        assert_eq!(credit.recipient, DADBSAddress::from_pubkey(&kept.staker));
+        assert_eq!((credit.transaction.recipient, credit.transaction.amount), (kept.staker, 5_000));
+        assert_eq!(mempool.lock().len(), 1);
+
+        // After a restart the same deposit, listed again, is not credited twice.
+        let restarted = watcher(&solana);
+        restarted.cursor.lock().await.until = None;
+        assert_eq!(restarted.poll().await.unwrap(), 0);
+        assert_eq!(mempool.lock().len(), 1);
+
+        let report = reconcile(solana.as_ref(), &program_id, &restarted.cursor().await).await.unwrap();
+        assert!(report.is_balanced(), "{:?}", report);
+        assert_eq!((report.deposited_total, report.credited_total), (5_000, 5_000));
+
+        // Finalized deposits the watcher has not seen yet are reported.
+        let late = solana.stake(&program_id, stake(1_000), Stage::Finalized);
+        let report = reconcile(solana.as_ref(), &program_id, &restarted.cursor().await).await.unwrap();
+        assert_eq!(report.uncredited, vec![format!("{}:0", late)]);
+    }
+}
@@ -0,0 +1,376 @@
+//! `dnsseed:<name>[:<port>]` entries in `bootstrap_nodes`, resolved into
+//! dial candidates at startup and every `refresh_secs`.
+//!
+//! A seed's A and AAAA records are taken as nodes listening on the seed's
+//! port. Its TXT records may list nodes too, as whitespace-separated
+//! `<ip>:<port>,<pubkey>` tuples; a node listed with a key must prove
+//! holding it in the handshake. TXT hosts must be IP literals, so a seed
+//! cannot send us resolving further names.
+
+use async_trait::async_trait;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::timeout;
+
+pub const DNS_SEED_PREFIX: &str = "dnsseed:";
+/// Port of the nodes behind a seed's A and AAAA records, unless it names one.
+pub const DEFAULT_DNS_SEED_PORT: u16 = 8000;
+pub const DEFAULT_DNS_SEED_REFRESH_SECS: u64 = 1800;
+pub const DEFAULT_DNS_SEED_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_MAX_ADDRS_PER_SEED: usize = 16;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DnsSeedError {
+    #[error("Invalid DNS seed {0}")]
+    InvalidSeed(String),
+    #[error("Cannot resolve {name}: {reason}")]
+    Resolve { name: String, reason: String },
+    #[error("Resolving {0} timed out")]
+    Timeout(String),
+    #[error("{0} lists no usable addresses")]
+    NoRecords(String),
+    #[error("TXT lookups are not compiled in; rebuild with --features dns-txt")]
+    Unsupported,
+}
+
+/// The `[dns_seed]` section, for the `dnsseed:` entries in `bootstrap_nodes`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct DnsSeedConfig {
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+    /// For each lookup; A/AAAA and TXT are looked up together.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Addresses taken from one seed at most, TXT entries first.
+    #[serde(default = "default_max_addrs_per_seed")]
+    pub max_addrs_per_seed: usize,
+}
+
+fn default_refresh_secs() -> u64 {
+    DEFAULT_DNS_SEED_REFRESH_SECS
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_DNS_SEED_TIMEOUT_MS
+}
+
+fn default_max_addrs_per_seed() -> usize {
+    DEFAULT_MAX_ADDRS_PER_SEED
+}
+
+impl Default for DnsSeedConfig {
+    fn default() -> Self {
+        DnsSeedConfig {
+            refresh_secs: default_refresh_secs(),
+            timeout_ms: default_timeout_ms(),
+            max_addrs_per_seed: default_max_addrs_per_seed(),
+        }
+    }
+}
+
+impl DnsSeedConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.refresh_secs == 0 || self.timeout_ms == 0 {
+            return Err("dns_seed.refresh_secs and dns_seed.timeout_ms must be at least 1".to_string());
+        }
+        if self.max_addrs_per_seed == 0 {
+            return Err("dns_seed.max_addrs_per_seed must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn is_dns_seed(entry: &str) -> bool {
+    entry.starts_with(DNS_SEED_PREFIX)
+}
+
+/// A `dnsseed:` bootstrap entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DnsSeed {
+    pub name: String,
+    pub port: u16,
+}
+
+impl FromStr for DnsSeed {
+    type Err = DnsSeedError;
+
+    fn from_str(entry: &str) -> Result<Self, Self::Err> {
+        let invalid = || DnsSeedError::InvalidSeed(entry.to_string());
+        let seed = entry.strip_prefix(DNS_SEED_PREFIX).ok_or_else(invalid)?;
+        let (name, port) = match seed.rsplit_once(':') {
+            Some((name, port)) => (name, port.parse().map_err(|_| invalid())?),
+            None => (seed, DEFAULT_DNS_SEED_PORT),
+        };
+        let valid_name = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        if !valid_name || port == 0 {
+            return Err(invalid());
+        }
+        Ok(DnsSeed { name: name.to_string(), port })
+    }
+}
+
+impl fmt::Display for DnsSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}:{}", DNS_SEED_PREFIX, self.name, self.port)
+    }
+}
+
+/// An address a seed listed, with the key the node there must prove, if
+/// the seed gave one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeedPeer {
+    pub addr: SocketAddr,
+    pub pubkey: Option<Pubkey>,
+}
+
+impl FromStr for SeedPeer {
+    type Err = String;
+
+    /// `<ip>:<port>,<pubkey>` from a TXT record, the key optional.
+    fn from_str(tuple: &str) -> Result<Self, Self::Err> {
+        let (addr, pubkey) = match tuple.split_once(',') {
+            Some((addr, pubkey)) => (addr, Some(pubkey)),
+            None => (tuple, None),
+        };
+        let addr: SocketAddr = addr.parse().map_err(|_| format!("{} is not an IP address and port", addr))?;
+        if addr.port() == 0 || addr.ip().is_unspecified() {
+            return Err(format!("{} cannot be dialed", addr));
+        }
+        let pubkey = pubkey
+            .map(|pubkey| Pubkey::from_str(pubkey).map_err(|e| format!("bad pubkey {}: {}", pubkey, e)))
+            .transpose()?;
+        Ok(SeedPeer { addr, pubkey })
+    }
+}
+
+/// Looks up a seed's records.
+#[async_trait]
+pub trait SeedResolver: Send + Sync {
+    /// The name's A and AAAA records.
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsSeedError>;
+    /// The name's TXT records, each with its strings joined; none if it has
+    /// no TXT records.
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsSeedError>;
+}
+
+/// The system's resolver for A and AAAA records; TXT records need the
+/// `dns-txt` feature.
+pub struct SystemResolver {
+    #[cfg(feature = "dns-txt")]
+    txt: Option<hickory_resolver::TokioAsyncResolver>,
+}
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        SystemResolver {
+            #[cfg(feature = "dns-txt")]
+            txt: match hickory_resolver::TokioAsyncResolver::tokio_from_system_conf() {
+                Ok(resolver) => Some(resolver),
+                Err(e) => {
+                    log::warn!("Not looking up DNS seed TXT records: {}", e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl SeedResolver for SystemResolver {
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsSeedError> {
+        let addrs = tokio::net::lookup_host((name, 0))
+            .await
+            .map_err(|e| DnsSeedError::Resolve { name: name.to_string(), reason: e.to_string() })?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+
+    #[cfg(feature = "dns-txt")]
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsSeedError> {
+        use hickory_resolver::error::ResolveErrorKind;
+        let resolver = self.txt.as_ref().ok_or(DnsSeedError::Unsupported)?;
+        match resolver.txt_lookup(name).await {
+            Ok(records) => Ok(records.iter()
+                .map(|record| record.txt_data().iter().map(|part| String::from_utf8_lossy(part)).collect())
+                .collect()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+            Err(e) => Err(DnsSeedError::Resolve { name: name.to_string(), reason: e.to_string() }),
+        }
+    }
+
+    #[cfg(not(feature = "dns-txt"))]
+    async fn lookup_txt(&self, _name: &str) -> Result<Vec<String>, DnsSeedError> {
+        Err(DnsSeedError::Unsupported)
+    }
+}
+
+/// What a seed resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedResolution {
+    pub peers: Vec<SeedPeer>,
+    /// TXT tuples that did not parse, and were skipped.
+    pub malformed: usize,
+}
+
+/// Looks up `seed`'s A/AAAA and TXT records together, each within
+/// `timeout_ms`, and takes up to `max_addrs_per_seed` distinct addresses,
+/// those from TXT records first. Fails only if neither lookup gives an
+/// address.
+pub async fn resolve(
+    resolver: &dyn SeedResolver,
+    seed: &DnsSeed,
+    config: &DnsSeedConfig,
+) -> Result<SeedResolution, DnsSeedError> {
+    let wait = Duration::from_millis(config.timeout_ms);
+    let timed_out = |_| DnsSeedError::Timeout(seed.name.clone());
+    let (ips, txt) = tokio::join!(
+        timeout(wait, resolver.lookup_ip(&seed.name)),
+        timeout(wait, resolver.lookup_txt(&seed.name)),
+    );
+    let ips = ips.map_err(timed_out).and_then(|ips| ips);
+    let txt = match txt.map_err(timed_out).and_then(|txt| txt) {
+        Err(DnsSeedError::Unsupported) => Ok(Vec::new()),
+        txt => txt,
+    };
+    if let (Err(e), Err(_)) = (&ips, &txt) {
+        return Err(e.clone());
+    }
+
+    let mut peers: Vec<SeedPeer> = Vec::new();
+    let mut malformed = 0;
+    for tuple in txt.iter().flatten().flat_map(|record| record.split_whitespace()) {
+        match tuple.parse::<SeedPeer>() {
+            Ok(peer) if !peers.iter().any(|known| known.addr == peer.addr) => peers.push(peer),
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Skipping TXT entry {:?} of {}: {}", tuple, seed.name, e);
+                malformed += 1;
+            }
+        }
+    }
+    for ip in ips.iter().flatten() {
+        let addr = SocketAddr::new(*ip, seed.port);
+        if !ip.is_unspecified() && !peers.iter().any(|known| known.addr == addr) {
+            peers.push(SeedPeer { addr, pubkey: None });
+        }
+    }
+    peers.truncate(config.max_addrs_per_seed);
+    if peers.is_empty() {
+        return Err(match ips {
+            Err(e) => e,
+            Ok(_) => DnsSeedError::NoRecords(seed.name.clone()),
+        });
+    }
+    Ok(SeedResolution { peers, malformed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Answers from fixed record sets; names it has no set for fail to
+    /// resolve, and `slow` ones never answer.
+    #[derive(Default)]
+    struct Records {
+        ips: HashMap<&'static str, Vec<IpAddr>>,
+        txt: HashMap<&'static str, Vec<String>>,
+        slow: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl SeedResolver for Records {
+        async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsSeedError> {
+            if self.slow.iter().any(|slow| *slow == name) {
+                std::future::pending::<()>().await;
+            }
+            self.ips.get(name).cloned().ok_or_else(|| DnsSeedError::Resolve {
+                name: name.to_string(),
+                reason: "NXDOMAIN".to_string(),
+            })
+        }
+
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsSeedError> {
+            if self.slow.iter().any(|slow| *slow == name) {
+                std::future::pending::<()>().await;
+            }
+            Ok(self.txt.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    fn seed(entry: &str) -> DnsSeed {
+        entry.parse().unwrap()
+    }
+
+    #[test]
+    fn test_seed_entries_parse() {
+        assert_eq!(seed("dnsseed:seed.dadbs.io"), DnsSeed { name: "seed.dadbs.io".to_string(), port: 8000 });
+        assert_eq!(seed("dnsseed:seed.dadbs.io:9000").port, 9000);
+        assert_eq!(seed("dnsseed:seed.dadbs.io:9000").to_string(), "dnsseed:seed.dadbs.io:9000");
+        for invalid in ["seed.dadbs.io:8000", "dnsseed:", "dnsseed:seed..io", "dnsseed:seed.io:x", "dnsseed:a b"] {
+            assert!(invalid.parse::<DnsSeed>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_txt_entries_come_first_and_malformed_ones_are_skipped() {
+        let key = Pubkey::new_unique();
+        let records = Records {
+            ips: HashMap::from([("seed.test", vec!["10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()])]),
+            txt: HashMap::from([(
+                "seed.test",
+                vec![
+                    format!("10.0.0.2:8001,{} 10.0.0.3:8002", key),
+                    // A hostname, a bad key, no port, an unspecified address, a duplicate.
+                    format!("node.test:8003,{} 10.0.0.4:8004,nokey 10.0.0.5 0.0.0.0:8005 10.0.0.2:8001", key),
+                ],
+            )]),
+            slow: Vec::new(),
+        };
+        let resolution = resolve(&records, &seed("dnsseed:seed.test"), &DnsSeedConfig::default()).await.unwrap();
+        let listed: Vec<(String, Option<Pubkey>)> =
+            resolution.peers.iter().map(|peer| (peer.addr.to_string(), peer.pubkey)).collect();
+        assert_eq!(listed, vec![
+            ("10.0.0.2:8001".to_string(), Some(key)),
+            ("10.0.0.3:8002".to_string(), None),
+            ("10.0.0.1:8000".to_string(), None),
+            ("[2001:db8::1]:8000".to_string(), None),
+        ]);
+        assert_eq!(resolution.malformed, 4);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_is_capped_and_timed_out() {
+        let many: Vec<IpAddr> = (1..=50).map(|last| IpAddr::from([10, 0, 1, last])).collect();
+        let records = Records {
+            ips: HashMap::from([("big.test", many), ("empty.test", Vec::new())]),
+            txt: HashMap::from([("garbage.test", vec!["not-a-peer".to_string()])]),
+            slow: vec!["slow.test"],
+        };
+        let config = DnsSeedConfig { max_addrs_per_seed: 5, timeout_ms: 50, ..DnsSeedConfig::default() };
+        let big = resolve(&records, &seed("dnsseed:big.test"), &config).await.unwrap();
+        assert_eq!(big.peers.len(), 5);
+
+        let slow = resolve(&records, &seed("dnsseed:slow.test"), &config).await;
+        assert_eq!(slow, Err(DnsSeedError::Timeout("slow.test".to_string())));
+        let empty = resolve(&records, &seed("dnsseed:empty.test"), &config).await;
+        assert_eq!(empty, Err(DnsSeedError::NoRecords("empty.test".to_string())));
+        // Only malformed TXT entries, and no A records: nothing to dial.
+        let garbage = resolve(&records, &seed("dnsseed:garbage.test"), &config).await;
+        assert!(matches!(garbage, Err(DnsSeedError::Resolve { .. })), "{:?}", garbage);
+    }
+}
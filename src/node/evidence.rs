@@ -0,0 +1,617 @@
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    hash::{hashv, Hash},
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+use super::subscriptions::ChainEvents;
+use super::vote::Vote;
+use crate::program::client::slash_instruction;
+use crate::program::stake::slash_record_address;
+
+pub const DEFAULT_EVIDENCE_MAX_AGE_EPOCHS: u64 = 4;
+/// Evidence pool file inside `storage_path`.
+pub const EVIDENCE_FILE: &str = "evidence.json";
+const MAX_SUBMIT_ATTEMPTS: u32 = 5;
+const SUBMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum EvidenceError {
+    #[error("Invalid evidence: {0}")]
+    Invalid(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Submission failed: {0}")]
+    Submission(String),
+}
+
+/// Two signed votes from the same validator for different blocks at the same
+/// height and round.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EquivocationEvidence {
+    pub first: Vote,
+    pub second: Vote,
+}
+
+pub type EvidenceKey = (Pubkey, u64, u32);
+
+impl EquivocationEvidence {
+    pub fn new(first: Vote, second: Vote) -> Self {
+        // Order the votes so the same pair always produces the same evidence hash.
+        if first.block_hash <= second.block_hash {
+            EquivocationEvidence { first, second }
+        } else {
+            EquivocationEvidence { first: second, second: first }
+        }
+    }
+
+    pub fn validator(&self) -> Pubkey {
+        self.first.validator
+    }
+
+    pub fn height(&self) -> u64 {
+        self.first.height
+    }
+
+    pub fn round(&self) -> u32 {
+        self.first.round
+    }
+
+    pub fn key(&self) -> EvidenceKey {
+        (self.validator(), self.height(), self.round())
+    }
+
+    pub fn hash(&self) -> Hash {
+        let bytes = self.try_to_vec().expect("evidence serialization cannot fail");
+        hashv(&[&bytes])
+    }
+
+    /// Checks that both votes were signed on `chain_id` for different blocks.
+    pub fn verify(&self, chain_id: &str) -> Result<(), EvidenceError> {
+        if self.first.validator != self.second.validator {
+            return Err(EvidenceError::Invalid("votes from different validators".to_string()));
+        }
+        if self.first.height != self.second.height || self.first.round != self.second.round {
+            return Err(EvidenceError::Invalid("votes for different height or round".to_string()));
+        }
+        if self.first.block_hash == self.second.block_hash {
+            return Err(EvidenceError::Invalid("votes for the same block".to_string()));
+        }
+        if !self.first.verify_signature(chain_id) || !self.second.verify_signature(chain_id) {
+            return Err(EvidenceError::Invalid("invalid vote signature".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EvidenceRecord {
+    pub evidence: EquivocationEvidence,
+    pub epoch: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct SubmittedRecord {
+    hash: Hash,
+    key: EvidenceKey,
+    epoch: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PoolState {
+    pending: Vec<EvidenceRecord>,
+    submitted: Vec<SubmittedRecord>,
+}
+
+#[derive(Default)]
+struct PoolInner {
+    pending: HashMap<EvidenceKey, EvidenceRecord>,
+    submitted: HashMap<Hash, SubmittedRecord>,
+}
+
+impl PoolInner {
+    fn contains_key(&self, key: &EvidenceKey) -> bool {
+        self.pending.contains_key(key) || self.submitted.values().any(|r| r.key == *key)
+    }
+}
+
+/// Deduplicated equivocation evidence awaiting submission, persisted to disk so
+/// evidence and submission records survive restarts.
+pub struct EvidencePool {
+    path: Option<PathBuf>,
+    max_age_epochs: u64,
+    inner: Mutex<PoolInner>,
+}
+
+impl EvidencePool {
+    pub fn in_memory(max_age_epochs: u64) -> Self {
+        EvidencePool {
+            path: None,
+            max_age_epochs,
+            inner: Mutex::new(PoolInner::default()),
+        }
+    }
+
+    pub fn open(path: &Path, max_age_epochs: u64) -> Result<Self, EvidenceError> {
+        let mut inner = PoolInner::default();
+        if path.exists() {
+            let state: PoolState = serde_json::from_str(&fs::read_to_string(path)?)?;
+            for record in state.pending {
+                inner.pending.insert(record.evidence.key(), record);
+            }
+            for record in state.submitted {
+                inner.submitted.insert(record.hash, record);
+            }
+        }
+
+        Ok(EvidencePool {
+            path: Some(path.to_path_buf()),
+            max_age_epochs,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// Adds verified evidence. Returns false if evidence for the same
+    /// (validator, height, round) is already known.
+    pub fn add(&self, evidence: EquivocationEvidence, chain_id: &str, epoch: u64) -> Result<bool, EvidenceError> {
+        evidence.verify(chain_id)?;
+
+        let mut inner = self.inner.lock();
+        let key = evidence.key();
+        if inner.contains_key(&key) {
+            return Ok(false);
+        }
+
+        info!("Recorded equivocation evidence for validator {} at height {}", key.0, key.1);
+        inner.pending.insert(key, EvidenceRecord { evidence, epoch });
+        self.persist(&inner)?;
+        Ok(true)
+    }
+
+    pub fn pending(&self) -> Vec<EvidenceRecord> {
+        let mut pending: Vec<_> = self.inner.lock().pending.values().cloned().collect();
+        pending.sort_by_key(|r| r.evidence.key());
+        pending
+    }
+
+    pub fn is_submitted(&self, hash: &Hash) -> bool {
+        self.inner.lock().submitted.contains_key(hash)
+    }
+
+    pub fn mark_submitted(&self, evidence: &EquivocationEvidence) -> Result<(), EvidenceError> {
+        let mut inner = self.inner.lock();
+        let key = evidence.key();
+        let epoch = inner.pending.remove(&key).map_or(0, |r| r.epoch);
+        let hash = evidence.hash();
+        inner.submitted.insert(hash, SubmittedRecord { hash, key, epoch });
+        self.persist(&inner)
+    }
+
+    /// Drops evidence and submission records older than the configured age.
+    pub fn prune(&self, current_epoch: u64) -> Result<usize, EvidenceError> {
+        let mut inner = self.inner.lock();
+        let cutoff = current_epoch.saturating_sub(self.max_age_epochs);
+        let before = inner.pending.len() + inner.submitted.len();
+        inner.pending.retain(|_, r| r.epoch >= cutoff);
+        inner.submitted.retain(|_, r| r.epoch >= cutoff);
+        let removed = before - inner.pending.len() - inner.submitted.len();
+        if removed > 0 {
+            self.persist(&inner)?;
+        }
+        Ok(removed)
+    }
+
+    fn persist(&self, inner: &PoolInner) -> Result<(), EvidenceError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let state = PoolState {
+            pending: inner.pending.values().cloned().collect(),
+            submitted: inner.submitted.values().cloned().collect(),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&state)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait SlashTransport: Send + Sync {
+    async fn submit(&self, instruction: Instruction, authority: &Keypair) -> Result<Signature, String>;
+    /// Whether the stake program created `record_account`, recording a slash.
+    async fn is_recorded(&self, record_account: &Pubkey) -> Result<bool, String>;
+}
+
+pub struct RpcSlashTransport {
+    rpc_client: solana_client::nonblocking::rpc_client::RpcClient,
+}
+
+impl RpcSlashTransport {
+    pub fn new(rpc_url: String) -> Self {
+        RpcSlashTransport {
+            rpc_client: solana_client::nonblocking::rpc_client::RpcClient::new(rpc_url),
+        }
+    }
+}
+
+#[async_trait]
+impl SlashTransport for RpcSlashTransport {
+    async fn submit(&self, instruction: Instruction, authority: &Keypair) -> Result<Signature, String> {
+        use solana_sdk::{signature::Signer, transaction::Transaction};
+
+        let blockhash = self.rpc_client.get_latest_blockhash().await.map_err(|e| e.to_string())?;
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&authority.pubkey()),
+            &[authority],
+            blockhash,
+        );
+        self.rpc_client
+            .send_and_confirm_transaction(&transaction)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn is_recorded(&self, record_account: &Pubkey) -> Result<bool, String> {
+        let account = self.rpc_client
+            .get_account_with_commitment(record_account, self.rpc_client.commitment())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(account.value.is_some())
+    }
+}
+
+pub trait StakeAccountResolver: Send + Sync {
+    fn stake_account(&self, validator: &Pubkey) -> Option<Pubkey>;
+}
+
+impl StakeAccountResolver for HashMap<Pubkey, Pubkey> {
+    fn stake_account(&self, validator: &Pubkey) -> Option<Pubkey> {
+        self.get(validator).copied()
+    }
+}
+
+/// A slash accepted for equivocation evidence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StakeSlashed {
+    pub validator: Pubkey,
+    pub height: u64,
+    pub round: u32,
+    pub evidence_hash: Hash,
+    pub penalty_bps: u16,
+    /// Of the transaction that submitted the slash.
+    pub signature: Signature,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlashTarget {
+    pub program_id: Pubkey,
+    pub treasury_account: Pubkey,
+    pub penalty_bps: u16,
+}
+
+pub struct EvidenceSubmitter {
+    pool: Arc<EvidencePool>,
+    transport: Arc<dyn SlashTransport>,
+    resolver: Arc<dyn StakeAccountResolver>,
+    authority: Keypair,
+    target: SlashTarget,
+    backoff: Duration,
+    events: Option<ChainEvents>,
+    running: AsyncMutex<()>,
+}
+
+impl EvidenceSubmitter {
+    pub fn new(
+        pool: Arc<EvidencePool>,
+        transport: Arc<dyn SlashTransport>,
+        resolver: Arc<dyn StakeAccountResolver>,
+        authority: Keypair,
+        target: SlashTarget,
+    ) -> Self {
+        EvidenceSubmitter {
+            pool,
+            transport,
+            resolver,
+            authority,
+            target,
+            backoff: SUBMIT_BACKOFF,
+            events: None,
+            running: AsyncMutex::new(()),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Publishes each accepted slash on `events`.
+    pub fn with_events(mut self, events: ChainEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Submits every pending piece of evidence once. Returns how many were accepted.
+    ///
+    /// The stake program records each evidence hash it slashed for and
+    /// refuses it again. Evidence it already recorded, say from a submission
+    /// that landed before a crash kept us from noting it, is only marked
+    /// submitted, so each slash is sent until it lands, and then never again.
+    pub async fn submit_pending(&self) -> usize {
+        let _guard = self.running.lock().await;
+        let mut submitted = 0;
+
+        for record in self.pool.pending() {
+            let evidence = record.evidence;
+            if self.pool.is_submitted(&evidence.hash()) {
+                continue;
+            }
+
+            let stake_account = match self.resolver.stake_account(&evidence.validator()) {
+                Some(account) => account,
+                None => {
+                    warn!("No stake account known for validator {}, keeping evidence", evidence.validator());
+                    continue;
+                }
+            };
+
+            let signature = match self.submit_with_retry(&evidence, &stake_account).await {
+                Ok(signature) => signature,
+                Err(e) => {
+                    warn!("Giving up on slash for {} for now: {}", evidence.validator(), e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.pool.mark_submitted(&evidence) {
+                warn!("Failed to record submitted evidence: {}", e);
+            }
+            let Some(signature) = signature else {
+                let (validator, height) = (evidence.validator(), evidence.height());
+                info!("Evidence against {} at height {} was already slashed for", validator, height);
+                continue;
+            };
+            info!("Submitted slash for {} in transaction {}", evidence.validator(), signature);
+            if let Some(events) = &self.events {
+                events.publish_slashed(StakeSlashed {
+                    validator: evidence.validator(),
+                    height: evidence.height(),
+                    round: evidence.round(),
+                    evidence_hash: evidence.hash(),
+                    penalty_bps: self.target.penalty_bps,
+                    signature,
+                });
+            }
+            submitted += 1;
+        }
+
+        submitted
+    }
+
+    pub async fn run(&self, interval: Duration, cancel: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = sleep(interval) => {
+                    self.submit_pending().await;
+                }
+            }
+        }
+    }
+
+    /// Sends the slash for `evidence`, unless the stake program recorded it
+    /// already, checking again before each retry: an attempt that seemed to
+    /// fail may have landed. Returns the signature of the transaction that
+    /// slashed, if it was this call's.
+    async fn submit_with_retry(
+        &self,
+        evidence: &EquivocationEvidence,
+        stake_account: &Pubkey,
+    ) -> Result<Option<Signature>, EvidenceError> {
+        let evidence_hash = evidence.hash().to_bytes();
+        let (record_account, _) = slash_record_address(&self.target.program_id, &evidence_hash);
+        let instruction = slash_instruction(
+            &self.target.program_id,
+            &solana_sdk::signature::Signer::pubkey(&self.authority),
+            stake_account,
+            &self.target.treasury_account,
+            self.target.penalty_bps,
+            evidence_hash,
+        );
+
+        let mut last_error = String::new();
+        for attempt in 0..MAX_SUBMIT_ATTEMPTS {
+            let submitted = match self.transport.is_recorded(&record_account).await {
+                Ok(true) => return Ok(None),
+                Ok(false) => self.transport.submit(instruction.clone(), &self.authority).await,
+                Err(e) => Err(format!("cannot look up the slash record: {}", e)),
+            };
+            match submitted {
+                Ok(signature) => return Ok(Some(signature)),
+                Err(e) => {
+                    warn!("Slash submission attempt {} failed: {}", attempt + 1, e);
+                    last_error = e;
+                    if attempt + 1 < MAX_SUBMIT_ATTEMPTS {
+                        sleep(self.backoff * 2u32.pow(attempt)).await;
+                    }
+                }
+            }
+        }
+        Err(EvidenceError::Submission(last_error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::config::DEFAULT_CHAIN_ID;
+    use solana_sdk::signature::Signer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PROGRAM_ID: Pubkey = Pubkey::new_from_array([7; 32]);
+
+    /// Accepts each slash after failing the first `failures_before_success`,
+    /// recording it as the stake program would.
+    struct MockTransport {
+        calls: AtomicUsize,
+        failures_before_success: usize,
+        recorded: Mutex<HashSet<Pubkey>>,
+    }
+
+    impl MockTransport {
+        fn new(failures_before_success: usize) -> Self {
+            MockTransport { calls: AtomicUsize::new(0), failures_before_success, recorded: Mutex::new(HashSet::new()) }
+        }
+    }
+
+    #[async_trait]
+    impl SlashTransport for MockTransport {
+        async fn submit(&self, instruction: Instruction, _authority: &Keypair) -> Result<Signature, String> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            if call < self.failures_before_success {
+                return Err("rpc unavailable".to_string());
+            }
+            if !self.recorded.lock().insert(instruction.accounts[4].pubkey) {
+                return Err("evidence already slashed for".to_string());
+            }
+            Ok(Signature::default())
+        }
+
+        async fn is_recorded(&self, record_account: &Pubkey) -> Result<bool, String> {
+            Ok(self.recorded.lock().contains(record_account))
+        }
+    }
+
+    fn equivocation(keypair: &Keypair, height: u64) -> EquivocationEvidence {
+        let a = Vote::new(keypair, height, 0, hashv(&[b"a"]));
+        let b = Vote::new(keypair, height, 0, hashv(&[b"b"]));
+        EquivocationEvidence::new(a, b)
+    }
+
+    fn submitter(pool: Arc<EvidencePool>, transport: Arc<MockTransport>, validator: &Keypair) -> EvidenceSubmitter {
+        let mut stake_accounts = HashMap::new();
+        stake_accounts.insert(validator.pubkey(), Pubkey::new_unique());
+        let target = SlashTarget {
+            program_id: PROGRAM_ID,
+            treasury_account: Pubkey::new_unique(),
+            penalty_bps: 500,
+        };
+        EvidenceSubmitter::new(pool, transport, Arc::new(stake_accounts), Keypair::new(), target)
+            .with_backoff(Duration::from_millis(1))
+    }
+
+    #[test]
+    fn test_evidence_deduplicated_by_key() {
+        let validator = Keypair::new();
+        let pool = EvidencePool::in_memory(DEFAULT_EVIDENCE_MAX_AGE_EPOCHS);
+
+        assert!(pool.add(equivocation(&validator, 5), DEFAULT_CHAIN_ID, 0).unwrap());
+        let c = Vote::new(&validator, 5, 0, hashv(&[b"c"]));
+        let a = Vote::new(&validator, 5, 0, hashv(&[b"a"]));
+        assert!(!pool.add(EquivocationEvidence::new(c, a), DEFAULT_CHAIN_ID, 0).unwrap());
+        assert!(pool.add(equivocation(&validator, 6), DEFAULT_CHAIN_ID, 0).unwrap());
+        assert_eq!(pool.pending().len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_evidence_rejected() {
+        let validator = Keypair::new();
+        let other = Keypair::new();
+        let pool = EvidencePool::in_memory(DEFAULT_EVIDENCE_MAX_AGE_EPOCHS);
+
+        let a = Vote::new(&validator, 5, 0, hashv(&[b"a"]));
+        let b = Vote::new(&other, 5, 0, hashv(&[b"b"]));
+        assert!(pool.add(EquivocationEvidence::new(a.clone(), b), DEFAULT_CHAIN_ID, 0).is_err());
+        assert!(pool.add(EquivocationEvidence::new(a.clone(), a), DEFAULT_CHAIN_ID, 0).is_err());
+    }
+
+    #[test]
+    fn test_expired_evidence_pruned() {
+        let validator = Keypair::new();
+        let pool = EvidencePool::in_memory(2);
+
+        pool.add(equivocation(&validator, 1), DEFAULT_CHAIN_ID, 1).unwrap();
+        pool.add(equivocation(&validator, 2), DEFAULT_CHAIN_ID, 4).unwrap();
+
+        assert_eq!(pool.prune(5).unwrap(), 1);
+        assert_eq!(pool.pending().len(), 1);
+        assert_eq!(pool.pending()[0].epoch, 4);
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_submission_succeeds() {
+        let validator = Keypair::new();
+        let pool = Arc::new(EvidencePool::in_memory(DEFAULT_EVIDENCE_MAX_AGE_EPOCHS));
+        let transport = Arc::new(MockTransport::new(2));
+        pool.add(equivocation(&validator, 3), DEFAULT_CHAIN_ID, 0).unwrap();
+
+        let submitter = submitter(Arc::clone(&pool), Arc::clone(&transport), &validator);
+        assert_eq!(submitter.submit_pending().await, 1);
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 3);
+        assert!(pool.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exactly_once_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("evidence.json");
+        let validator = Keypair::new();
+        let evidence = equivocation(&validator, 9);
+        let transport = Arc::new(MockTransport::new(0));
+
+        {
+            let pool = Arc::new(EvidencePool::open(&path, DEFAULT_EVIDENCE_MAX_AGE_EPOCHS).unwrap());
+            pool.add(evidence.clone(), DEFAULT_CHAIN_ID, 0).unwrap();
+            let submitter = submitter(Arc::clone(&pool), Arc::clone(&transport), &validator);
+            assert_eq!(submitter.submit_pending().await, 1);
+        }
+
+        let pool = Arc::new(EvidencePool::open(&path, DEFAULT_EVIDENCE_MAX_AGE_EPOCHS).unwrap());
+        assert!(pool.is_submitted(&evidence.hash()));
+        assert!(!pool.add(evidence, DEFAULT_CHAIN_ID, 0).unwrap());
+
+        let submitter = submitter(Arc::clone(&pool), Arc::clone(&transport), &validator);
+        assert_eq!(submitter.submit_pending().await, 0);
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slash_landed_before_a_crash_is_not_resent() {
+        let validator = Keypair::new();
+        let pool = Arc::new(EvidencePool::in_memory(DEFAULT_EVIDENCE_MAX_AGE_EPOCHS));
+        let evidence = equivocation(&validator, 4);
+        pool.add(evidence.clone(), DEFAULT_CHAIN_ID, 0).unwrap();
+        // Sent, but the process died before marking it submitted.
+        let transport = Arc::new(MockTransport::new(0));
+        let (record_account, _) = slash_record_address(&PROGRAM_ID, &evidence.hash().to_bytes());
+        transport.recorded.lock().insert(record_account);
+
+        let submitter = submitter(Arc::clone(&pool), Arc::clone(&transport), &validator);
+        assert_eq!(submitter.submit_pending().await, 0);
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 0);
+        assert!(pool.is_submitted(&evidence.hash()));
+        assert!(pool.pending().is_empty());
+    }
+}
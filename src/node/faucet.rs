@@ -0,0 +1,375 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use std::net::IpAddr;
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::chain_stats::DAY_MS;
+use super::keystore::KeystoreError;
+use super::storage::{Storage, StorageError};
+#[cfg(feature = "faucet")]
+use super::keystore::Keystore;
+#[cfg(feature = "faucet")]
+use crate::client::{SignedTransaction, TxBuilder};
+
+pub const DEFAULT_FAUCET_KEY: &str = "faucet";
+pub const DEFAULT_FAUCET_PASSPHRASE_ENV: &str = "DADBS_FAUCET_PASSPHRASE";
+pub const DEFAULT_DRIP_AMOUNT: u64 = 1_000;
+pub const DEFAULT_DAILY_BUDGET: u64 = 1_000_000;
+pub const DEFAULT_ADDRESS_COOLDOWN_SECS: u64 = 86_400;
+pub const DEFAULT_IP_COOLDOWN_SECS: u64 = 3_600;
+const ADDRESS_PREFIX: u8 = b'a';
+const IP_PREFIX: u8 = b'i';
+const DAY_PREFIX: u8 = b'd';
+const GRANT_PREFIX: u8 = b'g';
+
+/// The `[faucet]` section. The key is read from the keystore; like the
+/// admin token, its passphrase is kept out of the config file, in
+/// `passphrase_file` or else the `passphrase_env` environment variable.
+/// Cooldowns of zero are not enforced.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FaucetConfig {
+    /// Serve `faucet_request`; needs the `faucet` feature.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Keystore name of the funded key grants are sent from.
+    #[serde(default = "default_key")]
+    pub key: String,
+    #[serde(default)]
+    pub passphrase_file: Option<String>,
+    #[serde(default = "default_passphrase_env")]
+    pub passphrase_env: String,
+    /// Sent with each grant.
+    #[serde(default = "default_drip_amount")]
+    pub drip_amount: u64,
+    /// Granted at most per UTC day, all addresses together.
+    #[serde(default = "default_daily_budget")]
+    pub daily_budget: u64,
+    /// Before the same address is funded again.
+    #[serde(default = "default_address_cooldown_secs")]
+    pub address_cooldown_secs: u64,
+    /// Before requests from the same IP are granted again.
+    #[serde(default = "default_ip_cooldown_secs")]
+    pub ip_cooldown_secs: u64,
+}
+
+fn default_key() -> String {
+    DEFAULT_FAUCET_KEY.to_string()
+}
+
+fn default_passphrase_env() -> String {
+    DEFAULT_FAUCET_PASSPHRASE_ENV.to_string()
+}
+
+fn default_drip_amount() -> u64 {
+    DEFAULT_DRIP_AMOUNT
+}
+
+fn default_daily_budget() -> u64 {
+    DEFAULT_DAILY_BUDGET
+}
+
+fn default_address_cooldown_secs() -> u64 {
+    DEFAULT_ADDRESS_COOLDOWN_SECS
+}
+
+fn default_ip_cooldown_secs() -> u64 {
+    DEFAULT_IP_COOLDOWN_SECS
+}
+
+impl Default for FaucetConfig {
+    fn default() -> Self {
+        FaucetConfig {
+            enabled: false,
+            key: default_key(),
+            passphrase_file: None,
+            passphrase_env: default_passphrase_env(),
+            drip_amount: DEFAULT_DRIP_AMOUNT,
+            daily_budget: DEFAULT_DAILY_BUDGET,
+            address_cooldown_secs: DEFAULT_ADDRESS_COOLDOWN_SECS,
+            ip_cooldown_secs: DEFAULT_IP_COOLDOWN_SECS,
+        }
+    }
+}
+
+impl FaucetConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.key.is_empty() {
+            return Err("faucet.key must name a keystore key".to_string());
+        }
+        if self.drip_amount == 0 {
+            return Err("faucet.drip_amount must be at least 1".to_string());
+        }
+        if self.daily_budget < self.drip_amount {
+            return Err("faucet.daily_budget must cover at least one drip".to_string());
+        }
+        Ok(())
+    }
+
+    /// The key's passphrase, or `None` if neither source has one.
+    pub fn passphrase(&self) -> std::io::Result<Option<String>> {
+        let passphrase = match &self.passphrase_file {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => std::env::var(&self.passphrase_env).ok(),
+        };
+        Ok(passphrase.map(|passphrase| passphrase.trim_end_matches(['\r', '\n']).to_string()).filter(|p| !p.is_empty()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FaucetError {
+    #[error("{recipient} was funded recently; it can be again from {}", format_ms(*.until_ms))]
+    AddressCooldown { recipient: Pubkey, until_ms: i64 },
+    #[error("This IP was granted funds recently; it can be again from {}", format_ms(*.until_ms))]
+    IpCooldown { until_ms: i64 },
+    #[error("The faucet's daily budget of {budget} is spent; it resets at {}", format_ms(*.resets_at_ms))]
+    BudgetExhausted { budget: u64, resets_at_ms: i64 },
+    #[error("No passphrase for faucet key {key}: set faucet.passphrase_file or {env}")]
+    NoPassphrase { key: String, env: String },
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("Keystore error: {0}")]
+    Keystore(#[from] KeystoreError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Cannot sign grant: {0}")]
+    Signing(String),
+}
+
+impl FaucetError {
+    /// When a request refused by a limit could succeed.
+    pub fn retry_at_ms(&self) -> Option<i64> {
+        match self {
+            FaucetError::AddressCooldown { until_ms, .. } | FaucetError::IpCooldown { until_ms } => Some(*until_ms),
+            FaucetError::BudgetExhausted { resets_at_ms, .. } => Some(*resets_at_ms),
+            _ => None,
+        }
+    }
+}
+
+fn format_ms(ms: i64) -> String {
+    Utc.timestamp_millis_opt(ms).single()
+        .map_or_else(|| ms.to_string(), |time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// A transfer the faucet made, as kept in its audit log.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FaucetGrant {
+    pub recipient: Pubkey,
+    /// The requesting IP, as text.
+    pub ip: String,
+    pub amount: u64,
+    pub tx_hash: Hash,
+    pub granted_ms: i64,
+}
+
+/// What the stored limits say about one request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaucetUsage {
+    pub address_granted_ms: Option<i64>,
+    pub ip_granted_ms: Option<i64>,
+    /// Granted so far on the request's day.
+    pub spent_today: u64,
+}
+
+/// IPv4 is keyed as its IPv6-mapped form, so both share one key space.
+fn ip_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+pub(super) fn address_key(recipient: &Pubkey) -> Vec<u8> {
+    [&[ADDRESS_PREFIX][..], recipient.as_ref()].concat()
+}
+
+pub(super) fn ip_key(ip: IpAddr) -> Vec<u8> {
+    [&[IP_PREFIX][..], &ip_bytes(ip)].concat()
+}
+
+pub(super) fn day_key(day: i64) -> Vec<u8> {
+    [&[DAY_PREFIX][..], &(day as u64).to_be_bytes()].concat()
+}
+
+/// Grants in the order made.
+pub(super) fn grant_key(grant: &FaucetGrant) -> Vec<u8> {
+    [&[GRANT_PREFIX][..], &(grant.granted_ms as u64).to_be_bytes(), grant.tx_hash.as_ref()].concat()
+}
+
+pub(super) fn grant_range() -> (Vec<u8>, Vec<u8>) {
+    (vec![GRANT_PREFIX], vec![GRANT_PREFIX + 1])
+}
+
+pub(super) fn day_of(ms: i64) -> i64 {
+    ms.div_euclid(DAY_MS)
+}
+
+/// The faucet's limits, kept in storage so they hold across restarts.
+/// Requests must be checked and recorded one at a time.
+pub struct FaucetLedger {
+    config: FaucetConfig,
+    storage: Arc<Storage>,
+}
+
+impl FaucetLedger {
+    pub fn new(config: FaucetConfig, storage: Arc<Storage>) -> Self {
+        FaucetLedger { config, storage }
+    }
+
+    pub fn config(&self) -> &FaucetConfig {
+        &self.config
+    }
+
+    /// Whether `recipient`, asking from `ip` at `now_ms`, may be granted
+    /// a drip.
+    pub fn check(&self, recipient: &Pubkey, ip: IpAddr, now_ms: i64) -> Result<(), FaucetError> {
+        let day = day_of(now_ms);
+        let usage = self.storage.faucet_usage(recipient, ip, day)?;
+        let until = |granted: Option<i64>, secs: u64| {
+            granted.filter(|_| secs > 0).map(|granted| granted.saturating_add(secs as i64 * 1000)).filter(|until| *until > now_ms)
+        };
+        if let Some(until_ms) = until(usage.address_granted_ms, self.config.address_cooldown_secs) {
+            return Err(FaucetError::AddressCooldown { recipient: *recipient, until_ms });
+        }
+        if let Some(until_ms) = until(usage.ip_granted_ms, self.config.ip_cooldown_secs) {
+            return Err(FaucetError::IpCooldown { until_ms });
+        }
+        if usage.spent_today.saturating_add(self.config.drip_amount) > self.config.daily_budget {
+            return Err(FaucetError::BudgetExhausted { budget: self.config.daily_budget, resets_at_ms: (day + 1) * DAY_MS });
+        }
+        Ok(())
+    }
+
+    /// Starts the cooldowns, spends from the day's budget and logs `grant`.
+    pub fn record(&self, ip: IpAddr, grant: &FaucetGrant) -> Result<(), FaucetError> {
+        self.storage.put_faucet_grant(ip, grant)?;
+        info!("Faucet granted {} to {} ({}) in {}", grant.amount, grant.recipient, grant.ip, grant.tx_hash);
+        Ok(())
+    }
+
+    /// The most recent `limit` grants, newest first.
+    pub fn grants(&self, limit: usize) -> Result<Vec<FaucetGrant>, FaucetError> {
+        Ok(self.storage.faucet_grants(limit)?)
+    }
+}
+
+/// Grants drips signed by a keystore key, which is unlocked again when it
+/// auto-locks.
+#[cfg(feature = "faucet")]
+pub struct Faucet {
+    ledger: FaucetLedger,
+    keystore: Arc<Keystore>,
+    passphrase: String,
+    pubkey: Pubkey,
+    turn: tokio::sync::Mutex<()>,
+}
+
+#[cfg(feature = "faucet")]
+impl Faucet {
+    /// Unlocks the configured key, which checks its passphrase.
+    pub fn open(config: FaucetConfig, storage: Arc<Storage>, keystore: Arc<Keystore>) -> Result<Self, FaucetError> {
+        let passphrase = config.passphrase()?.ok_or_else(|| FaucetError::NoPassphrase {
+            key: config.key.clone(),
+            env: config.passphrase_env.clone(),
+        })?;
+        let pubkey = keystore.pubkey(&config.key)?;
+        keystore.unlock(&config.key, &passphrase)?;
+        Ok(Faucet { ledger: FaucetLedger::new(config, storage), keystore, passphrase, pubkey, turn: tokio::sync::Mutex::new(()) })
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    pub fn ledger(&self) -> &FaucetLedger {
+        &self.ledger
+    }
+
+    /// Held from the check to the record of a request, so two cannot both
+    /// pass the limits.
+    pub async fn turn(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.turn.lock().await
+    }
+
+    /// Builds and signs a drip to `recipient`.
+    pub async fn sign_drip(&self, chain_id: &str, recipient: Pubkey, nonce: u64, fee: u64) -> Result<SignedTransaction, FaucetError> {
+        let key = &self.ledger.config.key;
+        if !self.keystore.is_unlocked(key) {
+            let (keystore, key, passphrase) = (Arc::clone(&self.keystore), key.clone(), self.passphrase.clone());
+            tokio::task::spawn_blocking(move || keystore.unlock(&key, &passphrase))
+                .await
+                .map_err(|e| FaucetError::Signing(e.to_string()))??;
+        }
+        TxBuilder::new(chain_id)
+            .from(self.pubkey)
+            .to(recipient)
+            .amount(self.ledger.config.drip_amount)
+            .fee(fee)
+            .nonce(nonce)
+            .sign(&(self.keystore.as_ref(), key.as_str()))
+            .map_err(|e| FaucetError::Signing(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(recipient: Pubkey, ip: IpAddr, amount: u64, granted_ms: i64) -> FaucetGrant {
+        FaucetGrant { recipient, ip: ip.to_string(), amount, tx_hash: Hash::new_unique(), granted_ms }
+    }
+
+    #[test]
+    fn test_cooldowns_hold_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FaucetConfig { address_cooldown_secs: 60, ip_cooldown_secs: 10, ..FaucetConfig::default() };
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = 5 * DAY_MS + 1_000;
+        {
+            let ledger = FaucetLedger::new(config.clone(), Arc::new(Storage::open(dir.path()).unwrap()));
+            ledger.check(&alice, ip, now).unwrap();
+            ledger.record(ip, &grant(alice, ip, 1_000, now)).unwrap();
+        }
+        let ledger = FaucetLedger::new(config, Arc::new(Storage::open(dir.path()).unwrap()));
+        let refused = ledger.check(&alice, "10.0.0.2".parse().unwrap(), now + 30_000).unwrap_err();
+        assert!(matches!(refused, FaucetError::AddressCooldown { until_ms, .. } if until_ms == now + 60_000));
+        // The same IPv4 address arriving IPv6-mapped shares the cooldown.
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(matches!(ledger.check(&bob, mapped, now + 5_000), Err(FaucetError::IpCooldown { .. })));
+        ledger.check(&bob, ip, now + 10_000).unwrap();
+        ledger.check(&alice, ip, now + 60_000).unwrap();
+        assert_eq!(ledger.grants(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_budget_resets_at_utc_midnight() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FaucetConfig {
+            drip_amount: 100,
+            daily_budget: 250,
+            address_cooldown_secs: 0,
+            ip_cooldown_secs: 0,
+            ..FaucetConfig::default()
+        };
+        let ledger = FaucetLedger::new(config, Arc::new(Storage::open(dir.path()).unwrap()));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = 9 * DAY_MS + 3_600_000;
+        for offset in 0..2 {
+            let recipient = Pubkey::new_unique();
+            ledger.check(&recipient, ip, now + offset).unwrap();
+            ledger.record(ip, &grant(recipient, ip, 100, now + offset)).unwrap();
+        }
+        let refused = ledger.check(&Pubkey::new_unique(), ip, now + 2).unwrap_err();
+        assert_eq!(refused.retry_at_ms(), Some(10 * DAY_MS));
+        assert!(refused.to_string().ends_with("resets at 1970-01-11T00:00:00Z"), "{}", refused);
+        ledger.check(&Pubkey::new_unique(), ip, 10 * DAY_MS).unwrap();
+        let newest = ledger.grants(1).unwrap();
+        assert_eq!(newest[0].granted_ms, now + 1);
+    }
+}
@@ -0,0 +1,472 @@
+use solana_sdk::hash::Hash;
+use std::collections::HashMap;
+use thiserror::Error;
+
+use super::block::Block;
+
+pub const DEFAULT_MAX_FORK_DEPTH: u64 = 64;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ForkChoiceError {
+    #[error("Block at height {0} is at or below finalized height {1}")]
+    BelowFinalized(u64, u64),
+    #[error("Unknown parent block: {0}")]
+    UnknownParent(Hash),
+    #[error("Unknown block: {0}")]
+    UnknownBlock(Hash),
+    #[error("Invalid block height: expected {expected}, got {actual}")]
+    InvalidHeight { expected: u64, actual: u64 },
+}
+
+/// Blocks the state layer has to undo (newest first) and then apply (oldest first)
+/// after a head change, plus any blocks that became final (oldest first).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainUpdate {
+    pub rolled_back: Vec<Block>,
+    pub applied: Vec<Block>,
+    pub finalized: Vec<Block>,
+}
+
+impl ChainUpdate {
+    pub fn is_reorg(&self) -> bool {
+        !self.rolled_back.is_empty()
+    }
+
+    /// True when the head did not move. Newly finalized blocks don't count.
+    pub fn is_empty(&self) -> bool {
+        self.rolled_back.is_empty() && self.applied.is_empty()
+    }
+}
+
+struct TreeEntry {
+    block: Block,
+    committed_weight: u128,
+    chain_weight: u128,
+}
+
+/// Keeps every known block above the finalized block, up to `max_depth` heights.
+/// The head is the tip with the highest accumulated committed weight, ties broken
+/// by the lowest block hash so every node picks the same head.
+pub struct BlockTree {
+    finalized: Block,
+    finalized_hash: Hash,
+    entries: HashMap<Hash, TreeEntry>,
+    children: HashMap<Hash, Vec<Hash>>,
+    head: Hash,
+    max_depth: u64,
+}
+
+impl BlockTree {
+    pub fn new(finalized: Block, max_depth: u64) -> Self {
+        let finalized_hash = finalized.hash();
+        BlockTree {
+            finalized,
+            finalized_hash,
+            entries: HashMap::new(),
+            children: HashMap::new(),
+            head: finalized_hash,
+            max_depth: max_depth.max(1),
+        }
+    }
+
+    pub fn head_hash(&self) -> Hash {
+        self.head
+    }
+
+    pub fn head(&self) -> &Block {
+        self.block(&self.head).unwrap_or(&self.finalized)
+    }
+
+    pub fn head_height(&self) -> u64 {
+        self.head().height()
+    }
+
+    pub fn finalized(&self) -> &Block {
+        &self.finalized
+    }
+
+    pub fn finalized_hash(&self) -> Hash {
+        self.finalized_hash
+    }
+
+    pub fn finalized_height(&self) -> u64 {
+        self.finalized.height()
+    }
+
+    pub fn max_depth(&self) -> u64 {
+        self.max_depth
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        *hash == self.finalized_hash || self.entries.contains_key(hash)
+    }
+
+    pub fn block(&self, hash: &Hash) -> Option<&Block> {
+        if *hash == self.finalized_hash {
+            return Some(&self.finalized);
+        }
+        self.entries.get(hash).map(|entry| &entry.block)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn apply_block(&mut self, block: Block, committed_weight: u128) -> Result<ChainUpdate, ForkChoiceError> {
+        let finalized_height = self.finalized_height();
+        if block.height() <= finalized_height {
+            return Err(ForkChoiceError::BelowFinalized(block.height(), finalized_height));
+        }
+
+        let hash = block.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(ChainUpdate::default());
+        }
+
+        let parent_hash = block.parent_hash();
+        let (parent_height, parent_weight) = if parent_hash == self.finalized_hash {
+            (finalized_height, 0)
+        } else {
+            let parent = self.entries.get(&parent_hash)
+                .ok_or(ForkChoiceError::UnknownParent(parent_hash))?;
+            (parent.block.height(), parent.chain_weight)
+        };
+
+        if block.height() != parent_height + 1 {
+            return Err(ForkChoiceError::InvalidHeight {
+                expected: parent_height + 1,
+                actual: block.height(),
+            });
+        }
+
+        self.entries.insert(hash, TreeEntry {
+            block,
+            committed_weight,
+            chain_weight: parent_weight + committed_weight,
+        });
+        self.children.entry(parent_hash).or_default().push(hash);
+
+        self.update_head()
+    }
+
+    /// Adds weight from late-arriving commits to an existing block.
+    pub fn add_weight(&mut self, hash: &Hash, weight: u128) -> Result<ChainUpdate, ForkChoiceError> {
+        let entry = self.entries.get_mut(hash).ok_or(ForkChoiceError::UnknownBlock(*hash))?;
+        entry.committed_weight += weight;
+
+        let mut stack = vec![*hash];
+        while let Some(current) = stack.pop() {
+            if let Some(entry) = self.entries.get_mut(&current) {
+                entry.chain_weight += weight;
+            }
+            if let Some(children) = self.children.get(&current) {
+                stack.extend(children.iter().copied());
+            }
+        }
+
+        self.update_head()
+    }
+
+    pub fn committed_weight(&self, hash: &Hash) -> Option<u128> {
+        self.entries.get(hash).map(|entry| entry.committed_weight)
+    }
+
+    /// Marks `hash` as finalized and discards every branch that does not descend from it.
+    pub fn finalize(&mut self, hash: &Hash) -> Result<ChainUpdate, ForkChoiceError> {
+        if *hash == self.finalized_hash {
+            return Ok(ChainUpdate::default());
+        }
+        if !self.entries.contains_key(hash) {
+            return Err(ForkChoiceError::UnknownBlock(*hash));
+        }
+
+        let old_head = self.head;
+        let ancestor = self.common_ancestor(&old_head, hash);
+        if ancestor == *hash {
+            let finalized = self.set_finalized(*hash);
+            return Ok(ChainUpdate { finalized, ..ChainUpdate::default() });
+        }
+
+        let rolled_back = self.blocks_between(&ancestor, &old_head);
+        let mut applied = self.blocks_between(&ancestor, hash);
+        applied.reverse();
+
+        let finalized = self.set_finalized(*hash);
+        self.head = self.best_tip();
+
+        let mut above = self.blocks_between(&self.finalized_hash, &self.head);
+        above.reverse();
+        applied.extend(above);
+
+        Ok(ChainUpdate { rolled_back, applied, finalized })
+    }
+
+    fn update_head(&mut self) -> Result<ChainUpdate, ForkChoiceError> {
+        let new_head = self.best_tip();
+        if new_head == self.head {
+            return Ok(ChainUpdate::default());
+        }
+
+        let old_head = self.head;
+        let ancestor = self.common_ancestor(&old_head, &new_head);
+
+        let rolled_back = self.blocks_between(&ancestor, &old_head);
+        let mut applied = self.blocks_between(&ancestor, &new_head);
+        applied.reverse();
+
+        self.head = new_head;
+        let finalized = self.enforce_depth();
+
+        Ok(ChainUpdate { rolled_back, applied, finalized })
+    }
+
+    fn best_tip(&self) -> Hash {
+        self.entries.iter()
+            .filter(|(hash, _)| self.children.get(*hash).map_or(true, |c| c.is_empty()))
+            .max_by(|(a_hash, a), (b_hash, b)| {
+                a.chain_weight.cmp(&b.chain_weight)
+                    .then_with(|| b_hash.cmp(a_hash))
+            })
+            .map(|(hash, _)| *hash)
+            .unwrap_or(self.finalized_hash)
+    }
+
+    fn common_ancestor(&self, a: &Hash, b: &Hash) -> Hash {
+        let height = |hash: &Hash| self.block(hash).map_or(0, |block| block.height());
+        let parent = |hash: &Hash| self.block(hash).map_or(self.finalized_hash, |block| block.parent_hash());
+
+        let (mut a, mut b) = (*a, *b);
+        while height(&a) > height(&b) {
+            a = parent(&a);
+        }
+        while height(&b) > height(&a) {
+            b = parent(&b);
+        }
+        while a != b {
+            a = parent(&a);
+            b = parent(&b);
+        }
+        a
+    }
+
+    fn path_from_finalized(&self, hash: &Hash) -> Vec<Hash> {
+        let mut path = Vec::new();
+        let mut cursor = *hash;
+        while let Some(entry) = self.entries.get(&cursor) {
+            path.push(cursor);
+            cursor = entry.block.parent_hash();
+        }
+        path.reverse();
+        path
+    }
+
+    fn enforce_depth(&mut self) -> Vec<Block> {
+        let head_height = self.head_height();
+        if head_height <= self.finalized_height() + self.max_depth {
+            return Vec::new();
+        }
+
+        let target_height = head_height - self.max_depth;
+        let target = self.path_from_finalized(&self.head).into_iter()
+            .find(|hash| self.entries[hash].block.height() == target_height);
+        match target {
+            Some(target) => self.set_finalized(target),
+            None => Vec::new(),
+        }
+    }
+
+    /// Moves the finalized block to `hash`, returning the blocks that became
+    /// final on the way (oldest first).
+    fn set_finalized(&mut self, hash: Hash) -> Vec<Block> {
+        let newly_finalized: Vec<Block> = self.path_from_finalized(&hash).iter()
+            .map(|h| self.entries[h].block.clone())
+            .collect();
+
+        let mut keep = HashMap::new();
+        let mut stack = self.children.get(&hash).cloned().unwrap_or_default();
+        while let Some(current) = stack.pop() {
+            if let Some(children) = self.children.get(&current) {
+                stack.extend(children.iter().copied());
+            }
+            if let Some(entry) = self.entries.remove(&current) {
+                keep.insert(current, entry);
+            }
+        }
+
+        let root = self.entries.remove(&hash).expect("finalized block must be in the tree");
+        self.entries = keep;
+        self.children.retain(|parent, _| *parent == hash || self.entries.contains_key(parent));
+        self.finalized = root.block;
+        self.finalized_hash = hash;
+        newly_finalized
+    }
+
+    /// Blocks walking back from `tip` down to, but excluding, `ancestor` (newest first).
+    fn blocks_between(&self, ancestor: &Hash, tip: &Hash) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut cursor = *tip;
+        while cursor != *ancestor {
+            match self.entries.get(&cursor) {
+                Some(entry) => {
+                    blocks.push(entry.block.clone());
+                    cursor = entry.block.parent_hash();
+                }
+                None => break,
+            }
+        }
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::pubkey::Pubkey;
+
+    fn child(parent: &Block, timestamp: i64) -> Block {
+        Block::new(parent.height() + 1, parent.hash(), timestamp, Pubkey::default())
+    }
+
+    #[test]
+    fn test_extends_head_without_reorg() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let b1 = child(&genesis, 1);
+        let update = tree.apply_block(b1.clone(), 1).unwrap();
+
+        assert!(!update.is_reorg());
+        assert_eq!(update.applied, vec![b1.clone()]);
+        assert_eq!(tree.head_hash(), b1.hash());
+    }
+
+    #[test]
+    fn test_heavier_branch_triggers_reorg() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let a1 = child(&genesis, 1);
+        let a2 = child(&a1, 2);
+        tree.apply_block(a1.clone(), 3).unwrap();
+        tree.apply_block(a2.clone(), 3).unwrap();
+        assert_eq!(tree.head_hash(), a2.hash());
+
+        let b1 = child(&genesis, 100);
+        let b2 = child(&b1, 101);
+        let update = tree.apply_block(b1.clone(), 4).unwrap();
+        assert!(update.is_empty());
+
+        let update = tree.apply_block(b2.clone(), 4).unwrap();
+        assert!(update.is_reorg());
+        assert_eq!(update.rolled_back, vec![a2, a1]);
+        assert_eq!(update.applied, vec![b1, b2.clone()]);
+        assert_eq!(tree.head_hash(), b2.hash());
+    }
+
+    #[test]
+    fn test_equal_weight_prefers_lowest_hash() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let a = child(&genesis, 1);
+        let b = child(&genesis, 2);
+        tree.apply_block(a.clone(), 5).unwrap();
+        tree.apply_block(b.clone(), 5).unwrap();
+
+        let expected = if a.hash() < b.hash() { a.hash() } else { b.hash() };
+        assert_eq!(tree.head_hash(), expected);
+    }
+
+    #[test]
+    fn test_late_weight_switches_head() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let a = child(&genesis, 1);
+        let b = child(&genesis, 2);
+        tree.apply_block(a.clone(), 5).unwrap();
+        tree.apply_block(b.clone(), 1).unwrap();
+        assert_eq!(tree.head_hash(), a.hash());
+
+        let update = tree.add_weight(&b.hash(), 10).unwrap();
+        assert!(update.is_reorg());
+        assert_eq!(update.rolled_back, vec![a]);
+        assert_eq!(update.applied, vec![b.clone()]);
+        assert_eq!(tree.committed_weight(&b.hash()), Some(11));
+    }
+
+    #[test]
+    fn test_rejects_blocks_below_finalized() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let a1 = child(&genesis, 1);
+        let a2 = child(&a1, 2);
+        tree.apply_block(a1.clone(), 1).unwrap();
+        tree.apply_block(a2.clone(), 1).unwrap();
+        tree.finalize(&a1.hash()).unwrap();
+
+        let fork = child(&genesis, 50);
+        assert_eq!(
+            tree.apply_block(fork, 100),
+            Err(ForkChoiceError::BelowFinalized(1, 1))
+        );
+        assert_eq!(tree.head_hash(), a2.hash());
+    }
+
+    #[test]
+    fn test_finalizing_side_branch_reorgs() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let a1 = child(&genesis, 1);
+        let b1 = child(&genesis, 2);
+        let b2 = child(&b1, 3);
+        tree.apply_block(a1.clone(), 10).unwrap();
+        tree.apply_block(b1.clone(), 1).unwrap();
+        tree.apply_block(b2.clone(), 1).unwrap();
+        assert_eq!(tree.head_hash(), a1.hash());
+
+        let update = tree.finalize(&b1.hash()).unwrap();
+        assert!(update.is_reorg());
+        assert_eq!(update.rolled_back, vec![a1]);
+        assert_eq!(update.finalized, vec![b1.clone()]);
+        assert_eq!(update.applied, vec![b1, b2.clone()]);
+        assert_eq!(tree.head_hash(), b2.hash());
+        assert_eq!(tree.finalized_height(), 1);
+    }
+
+    #[test]
+    fn test_depth_bound_prunes_old_heights() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 3);
+
+        let mut parent = genesis;
+        for i in 0..6 {
+            let block = child(&parent, i);
+            tree.apply_block(block.clone(), 1).unwrap();
+            parent = block;
+        }
+
+        assert_eq!(tree.head_height(), 6);
+        assert_eq!(tree.finalized_height(), 3);
+        assert_eq!(tree.len(), 3);
+    }
+
+    #[test]
+    fn test_unknown_parent_rejected() {
+        let genesis = Block::genesis();
+        let mut tree = BlockTree::new(genesis.clone(), 10);
+
+        let orphan_parent = child(&genesis, 9);
+        let orphan = child(&orphan_parent, 10);
+        assert_eq!(
+            tree.apply_block(orphan, 1),
+            Err(ForkChoiceError::UnknownParent(orphan_parent.hash()))
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::{hashv, Hash};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+use super::block::Block;
+use super::bloom::{DEFAULT_ADDRESS_BLOOM_FP_PPM, MAX_ADDRESS_BLOOM_FP_PPM};
+use super::config::NodeConfig;
+use super::params::ProtocolParams;
+use super::validator::{ValidatorInfo, ValidatorSet};
+use crate::utils::DADBSAddress;
+
+#[derive(Error, Debug)]
+pub enum GenesisError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid genesis: {0}")]
+    Invalid(String),
+    #[error("Genesis is for chain {genesis}, but the node is configured for {configured}")]
+    ChainMismatch { genesis: String, configured: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub address: DADBSAddress,
+    pub balance: u64,
+}
+
+/// The initial state of a chain, as shared by all of its nodes in a JSON
+/// file. Its canonical hash is block 0's parent and is exchanged in the
+/// p2p handshake, so nodes started from different genesis files never peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Genesis {
+    pub chain_id: String,
+    pub genesis_time: DateTime<Utc>,
+    pub validators: Vec<ValidatorInfo>,
+    #[serde(default)]
+    pub accounts: Vec<GenesisAccount>,
+    #[serde(default)]
+    pub params: ProtocolParams,
+    /// False positives per million lookups that proposers size block
+    /// address filters for. Left out of the file, and so of the hash,
+    /// when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_bloom_fp_ppm: Option<u32>,
+}
+
+impl Genesis {
+    /// A genesis for `chain_id` starting now, with no balances.
+    pub fn template(chain_id: impl Into<String>, validators: Vec<ValidatorInfo>) -> Self {
+        Genesis {
+            chain_id: chain_id.into(),
+            genesis_time: Utc::now().trunc_subsecs(0),
+            validators,
+            accounts: Vec::new(),
+            params: ProtocolParams::default(),
+            address_bloom_fp_ppm: None,
+        }
+    }
+
+    /// Reads and validates `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, GenesisError> {
+        let genesis: Genesis = serde_json::from_slice(&fs::read(path)?)?;
+        genesis.validate()?;
+        Ok(genesis)
+    }
+
+    /// The configured `genesis_path`, loaded, or `None` without one.
+    pub fn from_config(config: &NodeConfig) -> Result<Option<Self>, GenesisError> {
+        let genesis = match &config.genesis_path {
+            Some(path) => Genesis::load(path)?,
+            None => return Ok(None),
+        };
+        if genesis.chain_id != config.chain_id {
+            return Err(GenesisError::ChainMismatch { genesis: genesis.chain_id, configured: config.chain_id.clone() });
+        }
+        Ok(Some(genesis))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), GenesisError> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), GenesisError> {
+        let invalid = |reason: String| Err(GenesisError::Invalid(reason));
+        if self.chain_id.is_empty() {
+            return invalid("chain_id is empty".to_string());
+        }
+        if self.validators.is_empty() {
+            return invalid("at least one validator is required".to_string());
+        }
+        ValidatorSet::try_new(self.validators.clone()).map_err(|e| GenesisError::Invalid(e.to_string()))?;
+        let mut validators = HashSet::new();
+        for validator in &self.validators {
+            if validator.weight == 0 {
+                return invalid(format!("validator {} has no weight", validator.pubkey));
+            }
+            if !validators.insert(validator.pubkey) {
+                return invalid(format!("validator {} is listed twice", validator.pubkey));
+            }
+        }
+        let mut addresses = HashSet::new();
+        let mut total: u64 = 0;
+        for account in &self.accounts {
+            DADBSAddress::from_string(account.address.as_string())
+                .map_err(|e| GenesisError::Invalid(e.to_string()))?;
+            if !addresses.insert(&account.address) {
+                return invalid(format!("account {} is listed twice", account.address));
+            }
+            total = match total.checked_add(account.balance) {
+                Some(total) => total,
+                None => return invalid("balances sum to more than the maximum supply".to_string()),
+            };
+        }
+        if let Some(fp_ppm) = self.address_bloom_fp_ppm {
+            if fp_ppm == 0 || fp_ppm > MAX_ADDRESS_BLOOM_FP_PPM {
+                return invalid(format!(
+                    "address_bloom_fp_ppm must be between 1 and {}, got {}", MAX_ADDRESS_BLOOM_FP_PPM, fp_ppm
+                ));
+            }
+        }
+        self.params.validate().map_err(|e| GenesisError::Invalid(e.to_string()))
+    }
+
+    /// The configured address filter rate, or the default.
+    pub fn address_bloom_fp_ppm(&self) -> u32 {
+        self.address_bloom_fp_ppm.unwrap_or(DEFAULT_ADDRESS_BLOOM_FP_PPM)
+    }
+
+    /// SHA-256 of the compact JSON encoding with accounts sorted by
+    /// address, so the order accounts are listed in does not matter.
+    pub fn canonical_hash(&self) -> Hash {
+        let mut canonical = self.clone();
+        canonical.accounts.sort_by(|a, b| a.address.cmp(&b.address));
+        let encoded = serde_json::to_vec(&canonical).expect("genesis serialization cannot fail");
+        hashv(&[&encoded])
+    }
+
+    /// Block 0, whose parent is the canonical hash. Like `Block::genesis`,
+    /// it keeps version 0 headers.
+    pub fn block(&self) -> Block {
+        Block::new(0, self.canonical_hash(), self.genesis_time.timestamp_millis(), Pubkey::default()).with_version(0)
+    }
+
+    pub fn balances(&self) -> impl Iterator<Item = (DADBSAddress, u64)> + '_ {
+        self.accounts.iter().map(|account| (account.address.clone(), account.balance))
+    }
+
+    pub fn total_supply(&self) -> u64 {
+        self.accounts.iter().map(|account| account.balance).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signature::{Keypair, Signer};
+
+    fn account(balance: u64) -> GenesisAccount {
+        GenesisAccount { address: DADBSAddress::from_pubkey(&Keypair::new().pubkey()), balance }
+    }
+
+    fn genesis() -> Genesis {
+        let mut genesis = Genesis::template("genesis-test", vec![ValidatorInfo::new(Keypair::new().pubkey(), 10)]);
+        genesis.accounts = vec![account(100), account(250)];
+        genesis
+    }
+
+    #[test]
+    fn test_load_round_trip_and_canonical_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("genesis.json");
+        let genesis = genesis();
+        genesis.save(&path).unwrap();
+        let loaded = Genesis::load(&path).unwrap();
+        assert_eq!(loaded, genesis);
+        assert_eq!(loaded.total_supply(), 350);
+
+        let mut reordered = genesis.clone();
+        reordered.accounts.reverse();
+        assert_eq!(reordered.canonical_hash(), genesis.canonical_hash());
+        assert_eq!(genesis.block().parent_hash(), genesis.canonical_hash());
+        let mut changed = genesis.clone();
+        changed.params.min_fee += 1;
+        assert_ne!(changed.canonical_hash(), genesis.canonical_hash());
+        // An unset filter rate leaves existing genesis files hashing as before.
+        assert!(!serde_json::to_string(&genesis).unwrap().contains("address_bloom_fp_ppm"));
+        let tighter = Genesis { address_bloom_fp_ppm: Some(1_000), ..genesis.clone() };
+        assert_ne!(tighter.canonical_hash(), genesis.canonical_hash());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(genesis().validate().is_ok());
+
+        let mut no_validators = genesis();
+        no_validators.validators.clear();
+        let mut duplicate = genesis();
+        duplicate.accounts.push(duplicate.accounts[0].clone());
+        let mut overflow = genesis();
+        overflow.accounts.push(account(u64::MAX));
+        let mut bad_address = genesis();
+        bad_address.accounts[0].address = serde_json::from_str("\"alice\"").unwrap();
+        let mut twice = genesis();
+        twice.validators.push(twice.validators[0].clone());
+        let no_false_positives = Genesis { address_bloom_fp_ppm: Some(0), ..genesis() };
+        for invalid in [no_validators, duplicate, overflow, bad_address, twice, no_false_positives] {
+            assert!(matches!(invalid.validate(), Err(GenesisError::Invalid(_))), "{:?}", invalid);
+        }
+    }
+}
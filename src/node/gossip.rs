@@ -0,0 +1,400 @@
+use borsh::BorshSerialize;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use solana_sdk::hash::{hash, Hash};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+use super::bandwidth::{MessageCategory, OutboundPacer};
+use super::mux::Lane;
+use super::network::{NetMessage, PeerId};
+
+pub const DEFAULT_GOSSIP_FANOUT: usize = 6;
+pub const DEFAULT_SEEN_TTL: Duration = Duration::from_secs(120);
+pub const DEFAULT_SEEN_CAPACITY: usize = 100_000;
+/// Per-peer queue bound for blocks, votes and control messages.
+pub const DEFAULT_PRIORITY_QUEUE: usize = 256;
+/// Per-peer queue bound for transactions; the oldest are dropped when full.
+pub const DEFAULT_TX_QUEUE: usize = 1024;
+/// Per-peer queue bound for block sync batches and gradients.
+pub const DEFAULT_BULK_QUEUE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    /// Peers each new message is forwarded to.
+    pub fanout: usize,
+    pub seen_ttl: Duration,
+    pub seen_capacity: usize,
+    pub priority_queue: usize,
+    pub tx_queue: usize,
+    pub bulk_queue: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            fanout: DEFAULT_GOSSIP_FANOUT,
+            seen_ttl: DEFAULT_SEEN_TTL,
+            seen_capacity: DEFAULT_SEEN_CAPACITY,
+            priority_queue: DEFAULT_PRIORITY_QUEUE,
+            tx_queue: DEFAULT_TX_QUEUE,
+            bulk_queue: DEFAULT_BULK_QUEUE,
+        }
+    }
+}
+
+impl GossipConfig {
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    pub fn with_seen_ttl(mut self, seen_ttl: Duration) -> Self {
+        self.seen_ttl = seen_ttl;
+        self
+    }
+
+    pub fn with_queue_sizes(mut self, priority_queue: usize, tx_queue: usize) -> Self {
+        self.priority_queue = priority_queue.max(1);
+        self.tx_queue = tx_queue.max(1);
+        self
+    }
+
+    pub fn with_bulk_queue(mut self, bulk_queue: usize) -> Self {
+        self.bulk_queue = bulk_queue.max(1);
+        self
+    }
+}
+
+/// Identifies a gossiped message, or `None` for point-to-point messages.
+pub fn message_id(message: &NetMessage) -> Option<Hash> {
+    let (tag, bytes): (&[u8], Vec<u8>) = match message {
+        NetMessage::Tx(tx) => return Some(tx.hash()),
+        NetMessage::Block(block) => return Some(block.hash()),
+        NetMessage::Vote(vote) => (b"vote", vote.try_to_vec().ok()?),
+        NetMessage::KeyHandover(handover) => (b"handover", handover.try_to_vec().ok()?),
+        NetMessage::Proposal(proposal) => (b"proposal", proposal.try_to_vec().ok()?),
+        _ => return None,
+    };
+    let mut data = tag.to_vec();
+    data.extend_from_slice(&bytes);
+    Some(hash(&data))
+}
+
+/// Recently seen message ids. Entries expire after `ttl`, and the least
+/// recently seen are evicted beyond `capacity`.
+#[derive(Debug)]
+pub struct SeenCache {
+    ttl: Duration,
+    capacity: usize,
+    seen: HashMap<Hash, Instant>,
+    /// Insertion order; entries refreshed since are skipped on eviction.
+    order: VecDeque<(Hash, Instant)>,
+}
+
+impl SeenCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        SeenCache { ttl, capacity: capacity.max(1), seen: HashMap::new(), order: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    pub fn contains(&self, id: &Hash, now: Instant) -> bool {
+        self.seen.get(id).is_some_and(|at| now.saturating_duration_since(*at) < self.ttl)
+    }
+
+    /// Marks `id` as seen, returning whether it was not already.
+    pub fn insert(&mut self, id: Hash, now: Instant) -> bool {
+        self.expire(now);
+        let fresh = !self.contains(&id, now);
+        self.seen.insert(id, now);
+        self.order.push_back((id, now));
+        while self.seen.len() > self.capacity {
+            self.evict_front();
+        }
+        fresh
+    }
+
+    fn evict_front(&mut self) {
+        if let Some((id, at)) = self.order.pop_front() {
+            if self.seen.get(&id) == Some(&at) {
+                self.seen.remove(&id);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.order.front().is_some_and(|(_, at)| now.saturating_duration_since(*at) >= self.ttl) {
+            self.evict_front();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct GossipStats {
+    /// Gossip messages we had already seen and did not deliver or forward.
+    pub duplicates_suppressed: u64,
+    /// Transactions pushed out of a full peer queue.
+    pub txs_dropped: u64,
+    /// Blocks, votes and control messages refused by a full peer queue.
+    pub priority_dropped: u64,
+    /// Sync batches and gradients refused by a full peer queue.
+    pub bulk_dropped: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct GossipCounters {
+    duplicates_suppressed: AtomicU64,
+    txs_dropped: AtomicU64,
+    priority_dropped: AtomicU64,
+    bulk_dropped: AtomicU64,
+}
+
+impl GossipCounters {
+    pub(crate) fn duplicate(&self) {
+        self.duplicates_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> GossipStats {
+        GossipStats {
+            duplicates_suppressed: self.duplicates_suppressed.load(Ordering::Relaxed),
+            txs_dropped: self.txs_dropped.load(Ordering::Relaxed),
+            priority_dropped: self.priority_dropped.load(Ordering::Relaxed),
+            bulk_dropped: self.bulk_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Enqueued {
+    Queued,
+    /// Queued after dropping the oldest queued transaction.
+    DroppedOldest,
+    Full,
+}
+
+/// What a writer part way through a chunked bulk frame does next.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum BetweenChunks {
+    /// Sends this consensus lane message first.
+    Consensus(NetMessage),
+    NextChunk,
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    priority: VecDeque<NetMessage>,
+    txs: VecDeque<NetMessage>,
+    bulk: VecDeque<NetMessage>,
+    closed: bool,
+}
+
+/// A peer's outgoing messages. Blocks, votes and control messages are sent
+/// before any transaction, and transactions before bulk messages; under
+/// backpressure transactions are shed oldest first while other messages
+/// are refused.
+#[derive(Debug)]
+pub(crate) struct SendQueue {
+    queues: Mutex<Queues>,
+    ready: Notify,
+    priority_limit: usize,
+    tx_limit: usize,
+    bulk_limit: usize,
+}
+
+impl SendQueue {
+    pub(crate) fn new(config: &GossipConfig) -> Self {
+        SendQueue {
+            queues: Mutex::new(Queues::default()),
+            ready: Notify::new(),
+            priority_limit: config.priority_queue,
+            tx_limit: config.tx_queue,
+            bulk_limit: config.bulk_queue,
+        }
+    }
+
+    pub(crate) fn push(&self, message: NetMessage, counters: &GossipCounters) -> Enqueued {
+        let mut queues = self.queues.lock();
+        if queues.closed {
+            return Enqueued::Full;
+        }
+        let outcome = if matches!(message, NetMessage::Tx(_)) {
+            let outcome = if queues.txs.len() >= self.tx_limit {
+                queues.txs.pop_front();
+                counters.txs_dropped.fetch_add(1, Ordering::Relaxed);
+                Enqueued::DroppedOldest
+            } else {
+                Enqueued::Queued
+            };
+            queues.txs.push_back(message);
+            outcome
+        } else if MessageCategory::of(&message).is_bulk() {
+            if queues.bulk.len() >= self.bulk_limit {
+                counters.bulk_dropped.fetch_add(1, Ordering::Relaxed);
+                return Enqueued::Full;
+            }
+            queues.bulk.push_back(message);
+            Enqueued::Queued
+        } else if queues.priority.len() >= self.priority_limit {
+            counters.priority_dropped.fetch_add(1, Ordering::Relaxed);
+            return Enqueued::Full;
+        } else {
+            queues.priority.push_back(message);
+            Enqueued::Queued
+        };
+        drop(queues);
+        self.ready.notify_one();
+        outcome
+    }
+
+    /// Waits for the next message, or `None` once closed. A bulk message
+    /// is only taken once `pacer` lets one through; until then any other
+    /// message queued meanwhile goes first.
+    pub(crate) async fn pop(&self, pacer: &mut OutboundPacer) -> Option<NetMessage> {
+        loop {
+            let wait = {
+                let mut queues = self.queues.lock();
+                if queues.closed {
+                    return None;
+                }
+                if let Some(message) = queues.priority.pop_front().or_else(|| queues.txs.pop_front()) {
+                    return Some(message);
+                }
+                if queues.bulk.is_empty() {
+                    None
+                } else {
+                    match pacer.wait(true, Instant::now()) {
+                        wait if wait.is_zero() => return queues.bulk.pop_front(),
+                        wait => Some(wait),
+                    }
+                }
+            };
+            match wait {
+                Some(wait) => tokio::select! {
+                    _ = self.ready.notified() => {}
+                    _ = tokio::time::sleep(wait) => {}
+                },
+                None => self.ready.notified().await,
+            }
+        }
+    }
+
+    /// Waits, while a bulk frame is part sent, for a consensus lane
+    /// message to send before its next chunk, or for `pacer` to let that
+    /// chunk through. Other messages wait for the whole frame.
+    pub(crate) async fn between_chunks(&self, pacer: &mut OutboundPacer) -> BetweenChunks {
+        loop {
+            let wait = {
+                let mut queues = self.queues.lock();
+                if queues.closed {
+                    return BetweenChunks::Closed;
+                }
+                if let Some(index) = queues.priority.iter().position(|message| Lane::of(message) == Lane::Consensus) {
+                    return queues.priority.remove(index).map_or(BetweenChunks::NextChunk, BetweenChunks::Consensus);
+                }
+                match pacer.wait(true, Instant::now()) {
+                    wait if wait.is_zero() => return BetweenChunks::NextChunk,
+                    wait => wait,
+                }
+            };
+            tokio::select! {
+                _ = self.ready.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        let queues = self.queues.lock();
+        queues.priority.is_empty() && queues.txs.is_empty() && queues.bulk.is_empty()
+    }
+
+    pub(crate) fn close(&self) {
+        self.queues.lock().closed = true;
+        self.ready.notify_one();
+    }
+}
+
+/// Picks up to `fanout` peers at random, leaving out `exclude`.
+pub fn choose_peers(peers: &[PeerId], exclude: Option<&PeerId>, fanout: usize) -> Vec<PeerId> {
+    let mut candidates: Vec<PeerId> = peers.iter().filter(|peer| Some(*peer) != exclude).copied().collect();
+    candidates.shuffle(&mut rand::thread_rng());
+    candidates.truncate(fanout);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::block::Block;
+    use crate::node::transaction::Transaction;
+    use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_seen_cache_expires_and_evicts() {
+        let now = Instant::now();
+        let mut cache = SeenCache::new(Duration::from_secs(10), 2);
+        let (a, b, c) = (hash(b"a"), hash(b"b"), hash(b"c"));
+
+        assert!(cache.insert(a, now));
+        assert!(!cache.insert(a, now));
+        assert!(cache.insert(b, now + Duration::from_secs(1)));
+        // Seeing `a` again makes `b` the least recently seen.
+        assert!(!cache.insert(a, now + Duration::from_secs(2)));
+        assert!(cache.insert(c, now + Duration::from_secs(3)));
+        assert!(cache.contains(&a, now + Duration::from_secs(3)));
+        assert!(!cache.contains(&b, now + Duration::from_secs(3)));
+
+        assert!(cache.insert(c, now + Duration::from_secs(13)));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_queue_prioritizes_and_sheds_txs() {
+        let counters = GossipCounters::default();
+        let queue = SendQueue::new(&GossipConfig::default().with_queue_sizes(1, 2).with_bulk_queue(1));
+        let tx = |amount| NetMessage::Tx(Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), amount, 1, 0, 0));
+        let pacer = &mut OutboundPacer::new(None, None);
+        let sync = NetMessage::Blocks { blocks: vec![Block::genesis()], certificates: vec![] };
+        assert_eq!(queue.push(sync.clone(), &counters), Enqueued::Queued);
+        assert_eq!(queue.push(sync.clone(), &counters), Enqueued::Full);
+
+        assert_eq!(queue.push(tx(1), &counters), Enqueued::Queued);
+        assert_eq!(queue.push(tx(2), &counters), Enqueued::Queued);
+        assert_eq!(queue.push(tx(3), &counters), Enqueued::DroppedOldest);
+        assert_eq!(queue.push(NetMessage::Block(Block::genesis()), &counters), Enqueued::Queued);
+        assert_eq!(queue.push(NetMessage::Ping(1), &counters), Enqueued::Full);
+
+        assert_eq!(queue.pop(pacer).await, Some(NetMessage::Block(Block::genesis())));
+        assert!(matches!(queue.pop(pacer).await, Some(NetMessage::Tx(tx)) if tx.amount == 2));
+        assert!(matches!(queue.pop(pacer).await, Some(NetMessage::Tx(tx)) if tx.amount == 3));
+        assert_eq!(queue.pop(pacer).await, Some(sync));
+        assert_eq!(
+            counters.snapshot(),
+            GossipStats { duplicates_suppressed: 0, txs_dropped: 1, priority_dropped: 1, bulk_dropped: 1 }
+        );
+
+        queue.close();
+        assert_eq!(queue.pop(pacer).await, None);
+    }
+
+    #[test]
+    fn test_choose_peers() {
+        let peers: Vec<PeerId> = (1..=5).map(|port| SocketAddr::from(([10, 0, 0, 1], port))).collect();
+        let chosen = choose_peers(&peers, Some(&peers[0]), 3);
+        assert_eq!(chosen.len(), 3);
+        assert!(!chosen.contains(&peers[0]));
+        assert_eq!(choose_peers(&peers, None, 10).len(), 5);
+    }
+}
@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "grpc")]
+mod convert;
+
+#[cfg(feature = "grpc")]
+pub use convert::{RETRY_AFTER_KEY, RPC_CODE_KEY};
+
+/// Code generated from `proto/dadbs.proto`, clients included.
+#[cfg(feature = "grpc")]
+pub mod proto {
+    tonic::include_proto!("dadbs.v1");
+}
+
+pub const DEFAULT_GRPC_LISTEN: &str = "127.0.0.1:8002";
+
+/// The `[grpc]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GrpcConfig {
+    /// Serve gRPC; needs the `grpc` feature.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_grpc_listen")]
+    pub listen: String,
+}
+
+fn default_grpc_listen() -> String {
+    DEFAULT_GRPC_LISTEN.to_string()
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig { enabled: false, listen: default_grpc_listen() }
+    }
+}
+
+#[cfg(feature = "grpc")]
+pub use server::GrpcServer;
+
+#[cfg(feature = "grpc")]
+mod server {
+    use log::{debug, info, warn};
+    use parking_lot::Mutex;
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use tokio_util::sync::CancellationToken;
+    use tonic::{Request, Response, Status};
+
+    use super::convert;
+    use super::proto::block_event::Event;
+    use super::proto::node_server::{Node, NodeServer};
+    use super::proto::{
+        self, AddressRequest, BalanceResponse, BlockEvent, BlockResponse, GetBlockByHeightRequest, HashRequest,
+        NonceResponse, SendTransactionRequest, SendTransactionResponse, SubscribeNewBlocksRequest, TransactionResponse,
+    };
+    use super::GrpcConfig;
+    use crate::node::rpc::{BlockResult, RpcError, RpcServer, TransactionResult};
+    use crate::node::subscriptions::TOO_MANY_SUBSCRIPTIONS;
+
+    /// Events queued for a slow stream before it is told it missed some.
+    const STREAM_CAPACITY: usize = 64;
+
+    /// Serves `proto/dadbs.proto` by calling the JSON-RPC methods of the
+    /// same names, so both share one concurrency cap, the per-IP and
+    /// per-method rate limits (and their allowlist), metrics and errors.
+    /// Like `/`, nothing here takes credentials; the admin methods are only
+    /// on `/admin`.
+    pub struct GrpcServer {
+        local_addr: SocketAddr,
+        cancel: CancellationToken,
+    }
+
+    impl GrpcServer {
+        pub async fn bind(config: &GrpcConfig, rpc: Arc<RpcServer>) -> std::io::Result<Self> {
+            let listener = TcpListener::bind(&config.listen).await?;
+            let local_addr = listener.local_addr()?;
+            let cancel = CancellationToken::new();
+            let service = NodeService { rpc, streams: Arc::new(Mutex::new(HashMap::new())), cancel: cancel.clone() };
+            let shutdown = cancel.clone();
+            tokio::spawn(async move {
+                let serve = tonic::transport::Server::builder()
+                    .add_service(NodeServer::new(service))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown.cancelled());
+                if let Err(e) = serve.await {
+                    warn!("gRPC server stopped: {}", e);
+                }
+            });
+            info!("gRPC listening on {}", local_addr);
+            Ok(GrpcServer { local_addr, cancel })
+        }
+
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        /// Stops taking calls and ends every open stream.
+        pub fn shutdown(&self) {
+            self.cancel.cancel();
+        }
+    }
+
+    struct NodeService {
+        rpc: Arc<RpcServer>,
+        /// Open streams by client, held to `max_subscriptions` as a
+        /// WebSocket connection is; one channel multiplexes all its calls.
+        streams: Arc<Mutex<HashMap<IpAddr, usize>>>,
+        cancel: CancellationToken,
+    }
+
+    impl NodeService {
+        async fn call<T: DeserializeOwned>(&self, client: IpAddr, method: &str, params: Value) -> Result<T, Status> {
+            let result = self.rpc.call_limited(client, method, params).await.map_err(convert::status)?;
+            serde_json::from_value(result).map_err(|e| Status::internal(format!("Malformed {} result: {}", method, e)))
+        }
+    }
+
+    fn client_ip<T>(request: &Request<T>) -> IpAddr {
+        request.remote_addr().map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip())
+    }
+
+    /// A client's claim on one of its streams, given back on drop.
+    struct StreamSlot {
+        streams: Arc<Mutex<HashMap<IpAddr, usize>>>,
+        client: IpAddr,
+    }
+
+    impl StreamSlot {
+        fn take(streams: &Arc<Mutex<HashMap<IpAddr, usize>>>, client: IpAddr, max: usize) -> Result<Self, RpcError> {
+            let mut open = streams.lock();
+            let count = open.entry(client).or_insert(0);
+            if *count >= max {
+                return Err(RpcError::new(TOO_MANY_SUBSCRIPTIONS, format!("Client already has {} streams", count)));
+            }
+            *count += 1;
+            Ok(StreamSlot { streams: Arc::clone(streams), client })
+        }
+    }
+
+    impl Drop for StreamSlot {
+        fn drop(&mut self) {
+            let mut open = self.streams.lock();
+            if let Some(count) = open.get_mut(&self.client) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&self.client);
+                }
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Node for NodeService {
+        async fn get_block_by_height(
+            &self,
+            request: Request<GetBlockByHeightRequest>,
+        ) -> Result<Response<BlockResponse>, Status> {
+            let client = client_ip(&request);
+            let params = json!({ "height": request.into_inner().height });
+            let block: Option<BlockResult> = self.call(client, "get_block_by_height", params).await?;
+            Ok(Response::new(BlockResponse { block: block.map(Into::into) }))
+        }
+
+        async fn get_block_by_hash(&self, request: Request<HashRequest>) -> Result<Response<BlockResponse>, Status> {
+            let client = client_ip(&request);
+            let params = json!({ "hash": request.into_inner().hash });
+            let block: Option<BlockResult> = self.call(client, "get_block_by_hash", params).await?;
+            Ok(Response::new(BlockResponse { block: block.map(Into::into) }))
+        }
+
+        async fn get_transaction(&self, request: Request<HashRequest>) -> Result<Response<TransactionResponse>, Status> {
+            let client = client_ip(&request);
+            let params = json!({ "hash": request.into_inner().hash });
+            let transaction: Option<TransactionResult> = self.call(client, "get_transaction", params).await?;
+            Ok(Response::new(TransactionResponse { transaction: transaction.map(Into::into) }))
+        }
+
+        async fn get_balance(&self, request: Request<AddressRequest>) -> Result<Response<BalanceResponse>, Status> {
+            let client = client_ip(&request);
+            let params = json!({ "address": request.into_inner().address });
+            let balance = self.call(client, "get_balance", params).await?;
+            Ok(Response::new(BalanceResponse { balance }))
+        }
+
+        async fn get_nonce(&self, request: Request<AddressRequest>) -> Result<Response<NonceResponse>, Status> {
+            let client = client_ip(&request);
+            let params = json!({ "address": request.into_inner().address });
+            let nonce = self.call(client, "get_nonce", params).await?;
+            Ok(Response::new(NonceResponse { nonce }))
+        }
+
+        async fn send_transaction(
+            &self,
+            request: Request<SendTransactionRequest>,
+        ) -> Result<Response<SendTransactionResponse>, Status> {
+            let client = client_ip(&request);
+            let params = json!({ "raw": hex::encode(request.into_inner().raw) });
+            let hash = self.call(client, "send_transaction", params).await?;
+            Ok(Response::new(SendTransactionResponse { hash }))
+        }
+
+        type SubscribeNewBlocksStream = ReceiverStream<Result<BlockEvent, Status>>;
+
+        async fn subscribe_new_blocks(
+            &self,
+            request: Request<SubscribeNewBlocksRequest>,
+        ) -> Result<Response<Self::SubscribeNewBlocksStream>, Status> {
+            let client = client_ip(&request);
+            self.rpc.check_limits(client, "subscribe_new_blocks").map_err(convert::status)?;
+            let slot = StreamSlot::take(&self.streams, client, self.rpc.max_subscriptions()).map_err(convert::status)?;
+            // Subscribed before answering so no block between the two is lost.
+            let mut blocks = self.rpc.context.events.new_blocks();
+            let (outbox, stream) = mpsc::channel(STREAM_CAPACITY);
+            let cancel = self.cancel.child_token();
+            tokio::spawn(async move {
+                let _slot = slot;
+                loop {
+                    let event = tokio::select! {
+                        _ = cancel.cancelled() => return,
+                        _ = outbox.closed() => return,
+                        event = blocks.recv() => event,
+                    };
+                    let event = match event {
+                        Ok(block) => Event::Block(proto::Block::from(&*block)),
+                        Err(RecvError::Lagged(missed)) => {
+                            debug!("gRPC stream of {} missed {} blocks", client, missed);
+                            Event::Missed(missed)
+                        }
+                        Err(RecvError::Closed) => return,
+                    };
+                    if outbox.send(Ok(BlockEvent { event: Some(event) })).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            Ok(Response::new(ReceiverStream::new(stream)))
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Conversions between the proto messages and the JSON-RPC results they
+//! mirror, so both interfaces describe a block or transaction identically.
+
+use serde_json::Value;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Status};
+
+use super::proto;
+use crate::node::block::Block;
+use crate::node::rpc::{
+    BlockResult, RpcError, TransactionResult, FAUCET_LIMITED, INVALID_PARAMS, INVALID_REQUEST, MEMPOOL_FULL,
+    METHOD_NOT_FOUND, MODEL_BUSY, PARSE_ERROR, PRUNED, RATE_LIMITED, TRANSACTION_REJECTED,
+};
+use crate::node::subscriptions::TOO_MANY_SUBSCRIPTIONS;
+
+/// Trailer holding the JSON-RPC error code.
+pub const RPC_CODE_KEY: &str = "dadbs-rpc-code";
+/// Trailer holding `data.retry_after_ms` of a retriable error.
+pub const RETRY_AFTER_KEY: &str = "dadbs-retry-after-ms";
+
+impl From<BlockResult> for proto::Block {
+    fn from(block: BlockResult) -> Self {
+        proto::Block {
+            hash: block.hash,
+            height: block.height,
+            parent_hash: block.parent_hash,
+            timestamp: block.timestamp,
+            proposer: block.proposer,
+            transactions_root: block.transactions_root,
+            state_root: block.state_root,
+            total_fees: block.total_fees,
+            transactions: block.transactions,
+        }
+    }
+}
+
+impl From<&Block> for proto::Block {
+    fn from(block: &Block) -> Self {
+        BlockResult::from(block).into()
+    }
+}
+
+impl From<TransactionResult> for proto::Transaction {
+    fn from(transaction: TransactionResult) -> Self {
+        proto::Transaction {
+            hash: transaction.hash,
+            height: transaction.height,
+            index: transaction.index,
+            sender: transaction.sender,
+            recipient: transaction.recipient,
+            amount: transaction.amount,
+            fee: transaction.fee,
+            nonce: transaction.nonce,
+            timestamp: transaction.timestamp,
+            chain_id: transaction.chain_id,
+            // Stored payloads are always hex-encoded by `TransactionResult`.
+            payload: hex::decode(&transaction.payload).unwrap_or_default(),
+            signature: transaction.signature,
+        }
+    }
+}
+
+/// The gRPC status closest to `error`. The JSON-RPC code, and any
+/// `retry_after_ms`, ride along as metadata.
+pub fn status(error: RpcError) -> Status {
+    let code = match error.code {
+        PARSE_ERROR | INVALID_REQUEST | INVALID_PARAMS => Code::InvalidArgument,
+        METHOD_NOT_FOUND => Code::Unimplemented,
+        PRUNED => Code::NotFound,
+        TRANSACTION_REJECTED => Code::FailedPrecondition,
+        RATE_LIMITED | MEMPOOL_FULL | MODEL_BUSY | FAUCET_LIMITED | TOO_MANY_SUBSCRIPTIONS => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(code, error.message);
+    let metadata = status.metadata_mut();
+    metadata.insert(RPC_CODE_KEY, MetadataValue::from(error.code));
+    if let Some(retry_after_ms) = error.data.as_ref().and_then(|data| data.get("retry_after_ms")).and_then(Value::as_u64) {
+        metadata.insert(RETRY_AFTER_KEY, MetadataValue::from(retry_after_ms));
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_status_keeps_rpc_code_and_retry_hint() {
+        let limited = status(RpcError::new(RATE_LIMITED, "slow down").with_data(json!({ "retry_after_ms": 250 })));
+        assert_eq!(limited.code(), Code::ResourceExhausted);
+        assert_eq!(limited.message(), "slow down");
+        assert_eq!(limited.metadata().get(RPC_CODE_KEY).unwrap(), "-32005");
+        assert_eq!(limited.metadata().get(RETRY_AFTER_KEY).unwrap(), "250");
+
+        let invalid = status(RpcError::invalid_params("bad hash"));
+        assert_eq!(invalid.code(), Code::InvalidArgument);
+        assert!(invalid.metadata().get(RETRY_AFTER_KEY).is_none());
+    }
+}
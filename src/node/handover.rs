@@ -0,0 +1,296 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::config::DEFAULT_CHAIN_ID;
+use super::validator::{ValidatorInfo, ValidatorSet};
+
+/// Blocks past the finalized height a rotation takes effect at unless
+/// the operator names a height, so the handover reaches peers first.
+pub const DEFAULT_HANDOVER_DELAY: u64 = 10;
+const DOMAIN: &[u8] = b"dadbs-key-handover";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HandoverError {
+    #[error("Handover of {key} has a bad signature by its {signer} key")]
+    InvalidSignature { key: Pubkey, signer: &'static str },
+    #[error("Handover of {0} names it as its own successor")]
+    SameKey(Pubkey),
+    #[error("Key {key} was already handed over to {to}")]
+    AlreadyRotated { key: Pubkey, to: Pubkey },
+    #[error("Key {0} was already used and cannot take over another")]
+    KeyReused(Pubkey),
+    #[error("Handover of {key} at height {height} does not follow the one it took over at {previous}")]
+    Replayed { key: Pubkey, height: u64, previous: u64 },
+    #[error("Key {key} was retired at height {from}")]
+    Retired { key: Pubkey, from: u64 },
+    #[error("Key {key} takes over only from height {from}")]
+    NotYetValid { key: Pubkey, from: u64 },
+}
+
+/// Moves a node's identity from `old_pub` to `new_pub` from block height
+/// `valid_from_height` on. Signed by both keys, it can be checked without
+/// knowing anything but the chain it was signed on.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyHandover {
+    pub old_pub: Pubkey,
+    pub new_pub: Pubkey,
+    pub valid_from_height: u64,
+    pub sig_old: Signature,
+    pub sig_new: Signature,
+}
+
+impl KeyHandover {
+    pub fn new(old: &Keypair, new: &Keypair, valid_from_height: u64) -> Self {
+        Self::for_chain(old, new, DEFAULT_CHAIN_ID, valid_from_height)
+    }
+
+    /// Signs a handover that only verifies on `chain_id`.
+    pub fn for_chain(old: &Keypair, new: &Keypair, chain_id: &str, valid_from_height: u64) -> Self {
+        let message = Self::signing_bytes(chain_id, &old.pubkey(), &new.pubkey(), valid_from_height);
+        KeyHandover {
+            old_pub: old.pubkey(),
+            new_pub: new.pubkey(),
+            valid_from_height,
+            sig_old: old.sign_message(&message),
+            sig_new: new.sign_message(&message),
+        }
+    }
+
+    /// What both keys sign.
+    pub fn signing_bytes(chain_id: &str, old_pub: &Pubkey, new_pub: &Pubkey, valid_from_height: u64) -> Vec<u8> {
+        [
+            DOMAIN,
+            &(chain_id.len() as u32).to_le_bytes(),
+            chain_id.as_bytes(),
+            old_pub.as_ref(),
+            new_pub.as_ref(),
+            &valid_from_height.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Checks both keys signed the handover on `chain_id`.
+    pub fn verify(&self, chain_id: &str) -> Result<(), HandoverError> {
+        if self.old_pub == self.new_pub {
+            return Err(HandoverError::SameKey(self.old_pub));
+        }
+        let message = Self::signing_bytes(chain_id, &self.old_pub, &self.new_pub, self.valid_from_height);
+        if !self.sig_old.verify(self.old_pub.as_ref(), &message) {
+            return Err(HandoverError::InvalidSignature { key: self.old_pub, signer: "old" });
+        }
+        if !self.sig_new.verify(self.new_pub.as_ref(), &message) {
+            return Err(HandoverError::InvalidSignature { key: self.old_pub, signer: "new" });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Handovers {
+    /// By the key handed over.
+    by_old: HashMap<Pubkey, KeyHandover>,
+    /// Each successor to the key it took over from.
+    by_new: HashMap<Pubkey, Pubkey>,
+    finalized_height: u64,
+}
+
+impl Handovers {
+    fn identity(&self, key: &Pubkey) -> Pubkey {
+        let mut key = *key;
+        while let Some(old) = self.by_new.get(&key) {
+            key = *old;
+        }
+        key
+    }
+}
+
+/// The handovers a node has heard of, shared by its consensus and network.
+/// Every key in a chain of handovers stands for the chain's first, its
+/// identity, which stake and peer reputation stay with.
+#[derive(Debug, Clone)]
+pub struct HandoverRegistry {
+    chain_id: Arc<str>,
+    inner: Arc<RwLock<Handovers>>,
+}
+
+impl Default for HandoverRegistry {
+    fn default() -> Self {
+        Self::for_chain(DEFAULT_CHAIN_ID)
+    }
+}
+
+impl HandoverRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts only handovers signed on `chain_id`.
+    pub fn for_chain(chain_id: &str) -> Self {
+        HandoverRegistry { chain_id: Arc::from(chain_id), inner: Arc::default() }
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    /// Verifies and records `handover`. Returns false for one already
+    /// recorded, as when it is gossiped back. A key is handed over once,
+    /// and each handover in a chain must take effect above the last.
+    pub fn record(&self, handover: KeyHandover) -> Result<bool, HandoverError> {
+        handover.verify(&self.chain_id)?;
+        let mut inner = self.inner.write();
+        if let Some(existing) = inner.by_old.get(&handover.old_pub) {
+            if *existing == handover {
+                return Ok(false);
+            }
+            return Err(HandoverError::AlreadyRotated { key: handover.old_pub, to: existing.new_pub });
+        }
+        if inner.by_old.contains_key(&handover.new_pub) || inner.by_new.contains_key(&handover.new_pub) {
+            return Err(HandoverError::KeyReused(handover.new_pub));
+        }
+        let previous = inner.by_new.get(&handover.old_pub).and_then(|from| inner.by_old.get(from));
+        if let Some(previous) = previous.filter(|previous| handover.valid_from_height <= previous.valid_from_height) {
+            return Err(HandoverError::Replayed {
+                key: handover.old_pub,
+                height: handover.valid_from_height,
+                previous: previous.valid_from_height,
+            });
+        }
+        inner.by_new.insert(handover.new_pub, handover.old_pub);
+        inner.by_old.insert(handover.old_pub, handover);
+        Ok(true)
+    }
+
+    /// Records handovers read back from storage, oldest first; any that
+    /// no longer verify are skipped.
+    pub fn restore(&self, mut handovers: Vec<KeyHandover>) -> usize {
+        handovers.sort_by_key(|handover| handover.valid_from_height);
+        handovers.into_iter().filter(|handover| self.record(handover.clone()).unwrap_or(false)).count()
+    }
+
+    /// Every handover recorded, oldest first.
+    pub fn all(&self) -> Vec<KeyHandover> {
+        let mut handovers: Vec<KeyHandover> = self.inner.read().by_old.values().cloned().collect();
+        handovers.sort_by_key(|handover| (handover.valid_from_height, handover.old_pub));
+        handovers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().by_old.is_empty()
+    }
+
+    /// Tells the registry the height consensus has finalized up to, which
+    /// handshakes are checked at.
+    pub fn set_finalized_height(&self, height: u64) {
+        self.inner.write().finalized_height = height;
+    }
+
+    pub fn finalized_height(&self) -> u64 {
+        self.inner.read().finalized_height
+    }
+
+    /// The first key of the chain of handovers `key` is in.
+    pub fn identity(&self, key: &Pubkey) -> Pubkey {
+        self.inner.read().identity(key)
+    }
+
+    /// The identity `key` signs for at `height`. Fails once `key` has been
+    /// handed over, and before it takes over.
+    pub fn check(&self, key: &Pubkey, height: u64) -> Result<Pubkey, HandoverError> {
+        let inner = self.inner.read();
+        if let Some(handover) = inner.by_old.get(key).filter(|handover| height >= handover.valid_from_height) {
+            return Err(HandoverError::Retired { key: *key, from: handover.valid_from_height });
+        }
+        let taken_over = inner.by_new.get(key).and_then(|old| inner.by_old.get(old));
+        if let Some(handover) = taken_over.filter(|handover| height < handover.valid_from_height) {
+            return Err(HandoverError::NotYetValid { key: *key, from: handover.valid_from_height });
+        }
+        Ok(inner.identity(key))
+    }
+
+    /// `validators` as they sign at `height`: each whose key was handed
+    /// over by then under its successor, with the same weight.
+    pub fn validators_at<'a>(&self, validators: &'a ValidatorSet, height: u64) -> Cow<'a, ValidatorSet> {
+        let inner = self.inner.read();
+        let current = |key: &Pubkey| {
+            let mut key = *key;
+            while let Some(handover) = inner.by_old.get(&key).filter(|handover| height >= handover.valid_from_height) {
+                key = handover.new_pub;
+            }
+            key
+        };
+        if validators.validators().iter().all(|validator| current(&validator.pubkey) == validator.pubkey) {
+            return Cow::Borrowed(validators);
+        }
+        let rotated = validators.validators().iter()
+            .map(|validator| ValidatorInfo { pubkey: current(&validator.pubkey), ..validator.clone() })
+            .collect();
+        Cow::Owned(ValidatorSet::new(rotated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handover_verifies_standalone() {
+        let (old, new) = (Keypair::new(), Keypair::new());
+        let handover = KeyHandover::new(&old, &new, 20);
+        assert_eq!(handover.verify(DEFAULT_CHAIN_ID), Ok(()));
+        let elsewhere = KeyHandover::for_chain(&old, &new, "another-chain", 20);
+        let crossed = elsewhere.verify(DEFAULT_CHAIN_ID);
+        assert!(matches!(crossed, Err(HandoverError::InvalidSignature { signer: "old", .. })));
+        assert_eq!(HandoverRegistry::for_chain("another-chain").record(elsewhere.clone()), Ok(true));
+        assert!(HandoverRegistry::new().record(elsewhere).is_err());
+        // Either signature stops covering a record moved to another height.
+        let moved = KeyHandover { valid_from_height: 5, ..handover.clone() };
+        assert!(matches!(moved.verify(DEFAULT_CHAIN_ID), Err(HandoverError::InvalidSignature { signer: "old", .. })));
+        let hijacked = KeyHandover::new(&old, &Keypair::new(), 20);
+        let forged = KeyHandover { new_pub: hijacked.new_pub, sig_old: hijacked.sig_old, ..handover };
+        assert!(matches!(forged.verify(DEFAULT_CHAIN_ID), Err(HandoverError::InvalidSignature { signer: "new", .. })));
+    }
+
+    #[test]
+    fn test_registry_maps_keys_by_height() {
+        let (first, second, third) = (Keypair::new(), Keypair::new(), Keypair::new());
+        let registry = HandoverRegistry::new();
+        let handover = KeyHandover::new(&first, &second, 10);
+        assert_eq!(registry.record(handover.clone()), Ok(true));
+        assert_eq!(registry.record(handover), Ok(false));
+        assert!(matches!(
+            registry.record(KeyHandover::new(&first, &third, 12)),
+            Err(HandoverError::AlreadyRotated { .. })
+        ));
+        assert!(matches!(
+            registry.record(KeyHandover::new(&second, &third, 10)),
+            Err(HandoverError::Replayed { previous: 10, .. })
+        ));
+        assert_eq!(registry.record(KeyHandover::new(&second, &third, 30)), Ok(true));
+
+        assert_eq!(registry.check(&first.pubkey(), 9), Ok(first.pubkey()));
+        assert!(matches!(registry.check(&first.pubkey(), 10), Err(HandoverError::Retired { from: 10, .. })));
+        assert!(matches!(registry.check(&second.pubkey(), 9), Err(HandoverError::NotYetValid { from: 10, .. })));
+        assert_eq!(registry.check(&second.pubkey(), 10), Ok(first.pubkey()));
+        assert_eq!(registry.check(&third.pubkey(), 30), Ok(first.pubkey()));
+
+        let validators = ValidatorSet::new(vec![ValidatorInfo::new(first.pubkey(), 7)]);
+        assert!(matches!(registry.validators_at(&validators, 9), Cow::Borrowed(_)));
+        assert_eq!(registry.validators_at(&validators, 10).weight_of(&second.pubkey()), 7);
+        let at_30 = registry.validators_at(&validators, 30);
+        assert_eq!((at_30.weight_of(&third.pubkey()), at_30.weight_of(&first.pubkey())), (7, 0));
+
+        let restored = HandoverRegistry::new();
+        let mut all = registry.all();
+        all.reverse();
+        assert_eq!(restored.restore(all), 2);
+        assert_eq!(restored.identity(&third.pubkey()), first.pubkey());
+    }
+}
@@ -0,0 +1,263 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::consensus::ConsensusManager;
+use super::network::Network;
+use super::storage::Storage;
+
+pub const DEFAULT_MIN_PEERS: usize = 1;
+pub const DEFAULT_MAX_CLOCK_SKEW_MS: i64 = 5_000;
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT_MS: u64 = 1_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct HealthConfig {
+    /// Connected peers needed before the node reports ready.
+    #[serde(default = "default_min_peers")]
+    pub min_peers: usize,
+    /// Largest tolerated offset between our clock and our peers'.
+    #[serde(default = "default_max_clock_skew_ms")]
+    pub max_clock_skew_ms: i64,
+    /// A component that does not answer within this is reported unhealthy.
+    #[serde(default = "default_check_timeout_ms")]
+    pub check_timeout_ms: u64,
+}
+
+fn default_min_peers() -> usize {
+    DEFAULT_MIN_PEERS
+}
+
+fn default_max_clock_skew_ms() -> i64 {
+    DEFAULT_MAX_CLOCK_SKEW_MS
+}
+
+fn default_check_timeout_ms() -> u64 {
+    DEFAULT_HEALTH_CHECK_TIMEOUT_MS
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            min_peers: DEFAULT_MIN_PEERS,
+            max_clock_skew_ms: DEFAULT_MAX_CLOCK_SKEW_MS,
+            check_timeout_ms: DEFAULT_HEALTH_CHECK_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Ordered from best to worst, so the aggregate is the maximum.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    /// Still serving, but worth a look. Does not fail readiness.
+    Degraded,
+    Unhealthy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+impl ComponentHealth {
+    pub fn healthy(detail: impl Into<String>) -> Self {
+        ComponentHealth { status: HealthStatus::Healthy, detail: detail.into() }
+    }
+
+    pub fn degraded(detail: impl Into<String>) -> Self {
+        ComponentHealth { status: HealthStatus::Degraded, detail: detail.into() }
+    }
+
+    pub fn unhealthy(detail: impl Into<String>) -> Self {
+        ComponentHealth { status: HealthStatus::Unhealthy, detail: detail.into() }
+    }
+}
+
+/// A subsystem that reports on readiness.
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &str;
+    async fn check(&self) -> ComponentHealth;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ComponentReport {
+    pub name: String,
+    #[serde(flatten)]
+    pub health: ComponentHealth,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// The worst component status.
+    pub status: HealthStatus,
+    pub components: Vec<ComponentReport>,
+}
+
+impl HealthReport {
+    pub fn is_ready(&self) -> bool {
+        self.status != HealthStatus::Unhealthy
+    }
+}
+
+/// The checks behind `/health/ready`. Checks run concurrently, each bounded
+/// by the timeout, so a hung subsystem cannot hang the probe.
+pub struct HealthRegistry {
+    checks: RwLock<Vec<Arc<dyn HealthCheck>>>,
+    timeout: Duration,
+}
+
+impl HealthRegistry {
+    pub fn new(timeout: Duration) -> Self {
+        HealthRegistry { checks: RwLock::new(Vec::new()), timeout }
+    }
+
+    pub fn from_config(config: &HealthConfig) -> Self {
+        Self::new(Duration::from_millis(config.check_timeout_ms))
+    }
+
+    pub fn register(&self, check: Arc<dyn HealthCheck>) {
+        self.checks.write().push(check);
+    }
+
+    pub async fn readiness(&self) -> HealthReport {
+        let checks: Vec<Arc<dyn HealthCheck>> = self.checks.read().clone();
+        let components: Vec<ComponentReport> = join_all(checks.iter().map(|check| async move {
+            let health = match tokio::time::timeout(self.timeout, check.check()).await {
+                Ok(health) => health,
+                Err(_) => ComponentHealth::unhealthy(format!("no answer within {}ms", self.timeout.as_millis())),
+            };
+            ComponentReport { name: check.name().to_string(), health }
+        }))
+        .await;
+        let status = components.iter().map(|c| c.health.status).max().unwrap_or(HealthStatus::Healthy);
+        HealthReport { status, components }
+    }
+}
+
+/// Storage answers reads.
+pub struct StorageCheck(pub Arc<Storage>);
+
+#[async_trait]
+impl HealthCheck for StorageCheck {
+    fn name(&self) -> &str {
+        "storage"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        // Off the runtime, so a stuck disk trips the timeout instead of a worker.
+        let storage = Arc::clone(&self.0);
+        match tokio::task::spawn_blocking(move || storage.finalized_height()).await {
+            Ok(Ok(height)) => ComponentHealth::healthy(format!("finalized height {}", height.unwrap_or(0))),
+            Ok(Err(e)) => ComponentHealth::unhealthy(e.to_string()),
+            Err(e) => ComponentHealth::unhealthy(format!("read panicked: {}", e)),
+        }
+    }
+}
+
+/// The p2p listener is up.
+pub struct P2pCheck(pub Arc<Network>);
+
+#[async_trait]
+impl HealthCheck for P2pCheck {
+    fn name(&self) -> &str {
+        "p2p"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        if self.0.is_running() {
+            ComponentHealth::healthy(format!("listening on {}", self.0.local_addr()))
+        } else {
+            ComponentHealth::unhealthy("network shut down")
+        }
+    }
+}
+
+pub struct PeersCheck {
+    pub network: Arc<Network>,
+    pub min_peers: usize,
+}
+
+#[async_trait]
+impl HealthCheck for PeersCheck {
+    fn name(&self) -> &str {
+        "peers"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        let peers = self.network.peer_count();
+        let detail = format!("{} of {} required peers", peers, self.min_peers);
+        if peers >= self.min_peers {
+            ComponentHealth::healthy(detail)
+        } else {
+            ComponentHealth::unhealthy(detail)
+        }
+    }
+}
+
+/// Consensus is neither paused nor halted.
+pub struct ConsensusCheck(pub Arc<AsyncMutex<ConsensusManager>>);
+
+#[async_trait]
+impl HealthCheck for ConsensusCheck {
+    fn name(&self) -> &str {
+        "consensus"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        let consensus = self.0.lock().await;
+        match consensus.halt_status() {
+            Some(halted) => ComponentHealth::unhealthy(format!("halted: {}", halted.reason)),
+            None => ComponentHealth::healthy(format!("finalized height {}", consensus.finalized_height())),
+        }
+    }
+}
+
+/// Our clock agrees with our peers', judged from their heartbeats.
+pub struct ClockCheck {
+    pub consensus: Arc<AsyncMutex<ConsensusManager>>,
+    pub max_skew_ms: i64,
+}
+
+#[async_trait]
+impl HealthCheck for ClockCheck {
+    fn name(&self) -> &str {
+        "clock"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        match self.consensus.lock().await.clock_offset_ms() {
+            None => ComponentHealth::healthy("no peer heartbeats to compare against"),
+            Some(offset) if offset.abs() > self.max_skew_ms => {
+                ComponentHealth::unhealthy(format!("clock is {}ms off peers, limit {}ms", offset, self.max_skew_ms))
+            }
+            Some(offset) => ComponentHealth::healthy(format!("clock is {}ms off peers", offset)),
+        }
+    }
+}
+
+/// The LLM model finished loading. Register only when LLM support is
+/// enabled; whoever loads the model sets the flag.
+pub struct LlmCheck(pub Arc<AtomicBool>);
+
+#[async_trait]
+impl HealthCheck for LlmCheck {
+    fn name(&self) -> &str {
+        "llm"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        if self.0.load(Ordering::Acquire) {
+            ComponentHealth::healthy("model loaded")
+        } else {
+            ComponentHealth::unhealthy("model not loaded")
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use super::config::DEFAULT_CHAIN_ID;
+use super::consensus::ConsensusManager;
+use super::network::PeerId;
+use super::peer_score::Offense;
+use crate::utils::DADBSAddress;
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Heartbeats whose timestamp differs from local time by more than this are dropped.
+pub const DEFAULT_HEARTBEAT_MAX_SKEW_MS: i64 = 30_000;
+/// Peers not heard from within this window are removed from the view.
+pub const DEFAULT_PEER_LIVENESS_TTL_MS: i64 = 60_000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HeartbeatError {
+    #[error("Heartbeat from {0} has an invalid signature")]
+    InvalidSignature(DADBSAddress),
+    #[error("Heartbeat address {0} does not belong to its signer")]
+    AddressMismatch(DADBSAddress),
+    #[error("Stale heartbeat from {node}: timestamp {timestamp}")]
+    Stale { node: DADBSAddress, timestamp: i64 },
+}
+
+impl HeartbeatError {
+    /// What relaying the rejected heartbeat counts as against the peer.
+    pub fn offense(&self) -> Offense {
+        match self {
+            HeartbeatError::Stale { .. } => Offense::StaleMessage,
+            HeartbeatError::InvalidSignature(_) | HeartbeatError::AddressMismatch(_) => Offense::InvalidSignature,
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub node: DADBSAddress,
+    pub validator: Pubkey,
+    pub height: u64,
+    /// State root after applying the block at `height`.
+    pub state_root: Hash,
+    pub timestamp: i64,
+    pub signature: Signature,
+}
+
+impl Heartbeat {
+    pub fn new_signed(keypair: &Keypair, height: u64, timestamp: i64) -> Self {
+        Self::new_signed_with_root(keypair, DEFAULT_CHAIN_ID, height, Hash::default(), timestamp)
+    }
+
+    /// Signs a heartbeat that only verifies on `chain_id`.
+    pub fn new_signed_with_root(
+        keypair: &Keypair,
+        chain_id: &str,
+        height: u64,
+        state_root: Hash,
+        timestamp: i64,
+    ) -> Self {
+        let validator = keypair.pubkey();
+        let node = DADBSAddress::from_pubkey(&validator);
+        let signature = keypair.sign_message(&Self::signing_bytes(chain_id, &node, height, &state_root, timestamp));
+        Heartbeat { node, validator, height, state_root, timestamp, signature }
+    }
+
+    pub fn signing_bytes(
+        chain_id: &str,
+        node: &DADBSAddress,
+        height: u64,
+        state_root: &Hash,
+        timestamp: i64,
+    ) -> Vec<u8> {
+        let mut bytes = b"dadbs-heartbeat".to_vec();
+        bytes.extend_from_slice(&(chain_id.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(chain_id.as_bytes());
+        bytes.extend_from_slice(node.as_string().as_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(state_root.as_ref());
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes
+    }
+
+    /// Checks the heartbeat is its validator's, signed on `chain_id`.
+    pub fn verify(&self, chain_id: &str) -> Result<(), HeartbeatError> {
+        if DADBSAddress::from_pubkey(&self.validator) != self.node {
+            return Err(HeartbeatError::AddressMismatch(self.node.clone()));
+        }
+        let message = Self::signing_bytes(chain_id, &self.node, self.height, &self.state_root, self.timestamp);
+        if !self.signature.verify(self.validator.as_ref(), &message) {
+            return Err(HeartbeatError::InvalidSignature(self.node.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// The network layer's side of heartbeat gossip.
+#[async_trait]
+pub trait HeartbeatTransport: Send + Sync {
+    async fn broadcast_heartbeat(&self, heartbeat: Heartbeat);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerStatus {
+    pub node: DADBSAddress,
+    pub validator: Pubkey,
+    pub last_height: u64,
+    pub last_state_root: Hash,
+    pub last_timestamp: i64,
+    /// Local unix-millisecond time the last heartbeat was accepted.
+    pub last_seen: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NetworkView {
+    pub peers: Vec<PeerStatus>,
+    pub highest_height: u64,
+}
+
+#[derive(Debug)]
+pub struct PeerLiveness {
+    max_skew_ms: i64,
+    ttl_ms: i64,
+    peers: BTreeMap<DADBSAddress, PeerStatus>,
+}
+
+impl Default for PeerLiveness {
+    fn default() -> Self {
+        Self::new(DEFAULT_HEARTBEAT_MAX_SKEW_MS, DEFAULT_PEER_LIVENESS_TTL_MS)
+    }
+}
+
+impl PeerLiveness {
+    pub fn new(max_skew_ms: i64, ttl_ms: i64) -> Self {
+        PeerLiveness {
+            max_skew_ms,
+            ttl_ms,
+            peers: BTreeMap::new(),
+        }
+    }
+
+    /// Verifies and records a heartbeat for `chain_id` relayed by `from`.
+    /// The caller reports a rejected one against `from`, as
+    /// `HeartbeatError::offense` says.
+    pub fn record(
+        &mut self,
+        from: PeerId,
+        heartbeat: &Heartbeat,
+        chain_id: &str,
+        now_ms: i64,
+    ) -> Result<(), HeartbeatError> {
+        let result = self.check(heartbeat, chain_id, now_ms);
+        match &result {
+            Ok(()) => {
+                self.peers.insert(heartbeat.node.clone(), PeerStatus {
+                    node: heartbeat.node.clone(),
+                    validator: heartbeat.validator,
+                    last_height: heartbeat.height,
+                    last_state_root: heartbeat.state_root,
+                    last_timestamp: heartbeat.timestamp,
+                    last_seen: now_ms,
+                });
+            }
+            Err(e) => debug!("Dropping heartbeat relayed by {}: {}", from, e),
+        }
+        result
+    }
+
+    fn check(&self, heartbeat: &Heartbeat, chain_id: &str, now_ms: i64) -> Result<(), HeartbeatError> {
+        heartbeat.verify(chain_id)?;
+
+        let stale = || HeartbeatError::Stale {
+            node: heartbeat.node.clone(),
+            timestamp: heartbeat.timestamp,
+        };
+        if (now_ms - heartbeat.timestamp).abs() > self.max_skew_ms {
+            return Err(stale());
+        }
+        match self.peers.get(&heartbeat.node) {
+            Some(status) if heartbeat.timestamp <= status.last_timestamp => Err(stale()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops peers whose last heartbeat is older than the TTL.
+    pub fn expire(&mut self, now_ms: i64) -> usize {
+        let ttl_ms = self.ttl_ms;
+        let before = self.peers.len();
+        self.peers.retain(|_, status| now_ms - status.last_seen <= ttl_ms);
+        before - self.peers.len()
+    }
+
+    pub fn get(&self, node: &DADBSAddress) -> Option<&PeerStatus> {
+        self.peers.get(node)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerStatus> {
+        self.peers.values()
+    }
+
+    /// Median of how far our clock runs ahead of peers', judged by when
+    /// their heartbeats arrived. `None` without peers.
+    pub fn clock_offset_ms(&self) -> Option<i64> {
+        let mut offsets: Vec<i64> = self.peers.values().map(|p| p.last_seen - p.last_timestamp).collect();
+        if offsets.is_empty() {
+            return None;
+        }
+        offsets.sort_unstable();
+        Some(offsets[offsets.len() / 2])
+    }
+
+    pub fn view(&self) -> NetworkView {
+        let peers: Vec<PeerStatus> = self.peers.values().cloned().collect();
+        NetworkView {
+            highest_height: peers.iter().map(|p| p.last_height).max().unwrap_or(0),
+            peers,
+        }
+    }
+}
+
+/// Broadcasts a signed heartbeat every `interval` and expires silent peers
+/// until `cancel` fires.
+pub async fn run_heartbeats(
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    keypair: Keypair,
+    transport: Arc<dyn HeartbeatTransport>,
+    interval: Duration,
+    cancel: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+
+        let heartbeat = {
+            let consensus = consensus.lock().await;
+            let expired = consensus.expire_peers();
+            if expired > 0 {
+                warn!("Expired {} silent peers from the liveness table", expired);
+            }
+            consensus.heartbeat(&keypair)
+        };
+        transport.broadcast_heartbeat(heartbeat).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn peer(port: u16) -> PeerId {
+        PeerId::from(([127, 0, 0, 1], port))
+    }
+
+    /// Delivers every broadcast heartbeat to all other tables, tagged with
+    /// the sender's peer id.
+    struct FakeTransport {
+        from: PeerId,
+        tables: Arc<Mutex<Vec<(PeerId, PeerLiveness)>>>,
+        now: i64,
+    }
+
+    #[async_trait]
+    impl HeartbeatTransport for FakeTransport {
+        async fn broadcast_heartbeat(&self, heartbeat: Heartbeat) {
+            for (id, table) in self.tables.lock().iter_mut() {
+                if *id != self.from {
+                    table.record(self.from, &heartbeat, DEFAULT_CHAIN_ID, self.now).unwrap();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_liveness_tables_converge() {
+        let keys: Vec<Keypair> = (0..3).map(|_| Keypair::new()).collect();
+        let tables = Arc::new(Mutex::new((0..3).map(|i| (peer(9000 + i), PeerLiveness::default())).collect::<Vec<_>>()));
+
+        for (i, key) in keys.iter().enumerate() {
+            let transport = FakeTransport { from: peer(9000 + i as u16), tables: Arc::clone(&tables), now: NOW };
+            transport.broadcast_heartbeat(Heartbeat::new_signed(key, 10 + i as u64, NOW)).await;
+        }
+
+        let tables = tables.lock();
+        for (i, (_, table)) in tables.iter().enumerate() {
+            assert_eq!(table.len(), 2);
+            assert!(table.get(&DADBSAddress::from_pubkey(&keys[i].pubkey())).is_none());
+        }
+        let view = tables[0].1.view();
+        assert_eq!(view.highest_height, 12);
+        let status = tables[0].1.get(&DADBSAddress::from_pubkey(&keys[1].pubkey())).unwrap();
+        assert_eq!(status.last_height, 11);
+    }
+
+    #[test]
+    fn test_stale_entries_expire() {
+        let mut table = PeerLiveness::new(DEFAULT_HEARTBEAT_MAX_SKEW_MS, 10_000);
+        let a = Keypair::new();
+        let b = Keypair::new();
+        table.record(peer(1), &Heartbeat::new_signed(&a, 1, NOW), DEFAULT_CHAIN_ID, NOW).unwrap();
+        table.record(peer(2), &Heartbeat::new_signed(&b, 1, NOW + 8_000), DEFAULT_CHAIN_ID, NOW + 8_000).unwrap();
+
+        assert_eq!(table.expire(NOW + 12_000), 1);
+        assert_eq!(table.len(), 1);
+        assert!(table.get(&DADBSAddress::from_pubkey(&b.pubkey())).is_some());
+        assert_eq!(table.expire(NOW + 30_000), 1);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_bad_heartbeats_are_offenses() {
+        let mut table = PeerLiveness::default();
+        let key = Keypair::new();
+
+        let old = Heartbeat::new_signed(&key, 5, NOW - DEFAULT_HEARTBEAT_MAX_SKEW_MS - 1);
+        let stale = table.record(peer(1), &old, DEFAULT_CHAIN_ID, NOW).unwrap_err();
+        assert!(matches!(stale, HeartbeatError::Stale { .. }));
+        assert_eq!(stale.offense(), Offense::StaleMessage);
+
+        let mut forged = Heartbeat::new_signed(&key, 5, NOW);
+        forged.height = 500;
+        let invalid = table.record(peer(2), &forged, DEFAULT_CHAIN_ID, NOW).unwrap_err();
+        assert!(matches!(invalid, HeartbeatError::InvalidSignature(_)));
+        assert_eq!(invalid.offense(), Offense::InvalidSignature);
+
+        let mut impersonated = Heartbeat::new_signed(&key, 5, NOW);
+        impersonated.node = DADBSAddress::from_pubkey(&Pubkey::new_unique());
+        let mismatch = table.record(peer(2), &impersonated, DEFAULT_CHAIN_ID, NOW);
+        assert!(matches!(mismatch, Err(HeartbeatError::AddressMismatch(_))));
+
+        table.record(peer(3), &Heartbeat::new_signed(&key, 6, NOW), DEFAULT_CHAIN_ID, NOW).unwrap();
+        let replay = Heartbeat::new_signed(&key, 6, NOW);
+        assert!(matches!(table.record(peer(3), &replay, DEFAULT_CHAIN_ID, NOW), Err(HeartbeatError::Stale { .. })));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_heartbeats_only_verify_on_their_chain() {
+        let key = Keypair::new();
+        let heartbeat = Heartbeat::new_signed_with_root(&key, "chain-a", 5, Hash::new_unique(), NOW);
+        assert_eq!(heartbeat.verify("chain-a"), Ok(()));
+        assert!(matches!(heartbeat.verify("chain-b"), Err(HeartbeatError::InvalidSignature(_))));
+
+        let mut table = PeerLiveness::default();
+        let replayed = table.record(peer(1), &heartbeat, DEFAULT_CHAIN_ID, NOW).unwrap_err();
+        assert_eq!(replayed.offense(), Offense::InvalidSignature);
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,420 @@
+//! Hooks embedders add to a node before starting it, called as
+//! transactions are admitted, blocks proposed and finalized, the chain
+//! reorganizes and the validator set changes, so custom indexing does
+//! not need a fork of the node.
+//!
+//! Events are queued, in the order they happened, and delivered to each
+//! hook in turn on a task of their own. Consensus never waits for a hook:
+//! events that find the queue full are dropped and counted. A hook that
+//! panics or runs past `timeout_ms` is logged and counted, and the next
+//! event is delivered regardless.
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use super::block::Block;
+use super::fork_choice::ChainUpdate;
+use super::metrics::{self, MetricsSource};
+use super::rpc::BlockResult;
+use super::transaction::Transaction;
+use super::validator::ValidatorSet;
+
+pub const DEFAULT_HOOK_TIMEOUT_MS: u64 = 2_000;
+pub const DEFAULT_HOOK_QUEUE_CAPACITY: usize = 1024;
+/// What `BlockJsonLines` appends to, inside its directory.
+pub const FINALIZED_BLOCKS_FILE: &str = "finalized_blocks.jsonl";
+
+/// The `[hooks]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HooksConfig {
+    /// Longest one hook may take over one event.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Events waiting for the hooks before new ones are dropped.
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Appends each finalized block as a JSON line to `finalized_blocks.jsonl`
+    /// here, if set.
+    #[serde(default)]
+    pub finalized_blocks_dir: Option<String>,
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_HOOK_TIMEOUT_MS
+}
+
+fn default_queue_capacity() -> usize {
+    DEFAULT_HOOK_QUEUE_CAPACITY
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        HooksConfig {
+            timeout_ms: default_timeout_ms(),
+            queue_capacity: default_queue_capacity(),
+            finalized_blocks_dir: None,
+        }
+    }
+}
+
+impl HooksConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_ms == 0 || self.queue_capacity == 0 {
+            return Err("hooks.timeout_ms and hooks.queue_capacity must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Called for each lifecycle event; every method does nothing unless a
+/// hook overrides it.
+#[async_trait]
+pub trait NodeHooks: Send + Sync {
+    /// Names the hook in logs.
+    fn name(&self) -> &str;
+
+    /// A transaction from RPC or gossip entered the mempool.
+    async fn on_tx_admitted(&self, _transaction: &Transaction) {}
+
+    /// This node built `block` to propose.
+    async fn on_block_proposed(&self, _block: &Block) {}
+
+    async fn on_block_finalized(&self, _block: &Block) {}
+
+    /// The head moved to another branch: `rolled_back` newest first, then
+    /// `applied` oldest first.
+    async fn on_reorg(&self, _rolled_back: &[Block], _applied: &[Block]) {}
+
+    async fn on_validator_set_change(&self, _previous: &ValidatorSet, _current: &ValidatorSet) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookEvent {
+    TxAdmitted(Transaction),
+    BlockProposed(Block),
+    BlockFinalized(Block),
+    Reorg { rolled_back: Vec<Block>, applied: Vec<Block> },
+    ValidatorSetChange { previous: ValidatorSet, current: ValidatorSet },
+}
+
+impl HookEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HookEvent::TxAdmitted(_) => "tx_admitted",
+            HookEvent::BlockProposed(_) => "block_proposed",
+            HookEvent::BlockFinalized(_) => "block_finalized",
+            HookEvent::Reorg { .. } => "reorg",
+            HookEvent::ValidatorSetChange { .. } => "validator_set_change",
+        }
+    }
+
+    async fn deliver(&self, hook: &dyn NodeHooks) {
+        match self {
+            HookEvent::TxAdmitted(transaction) => hook.on_tx_admitted(transaction).await,
+            HookEvent::BlockProposed(block) => hook.on_block_proposed(block).await,
+            HookEvent::BlockFinalized(block) => hook.on_block_finalized(block).await,
+            HookEvent::Reorg { rolled_back, applied } => hook.on_reorg(rolled_back, applied).await,
+            HookEvent::ValidatorSetChange { previous, current } => {
+                hook.on_validator_set_change(previous, current).await
+            }
+        }
+    }
+}
+
+/// The hooks a node is started with.
+#[derive(Clone, Default)]
+pub struct HookRegistry {
+    hooks: Vec<Arc<dyn NodeHooks>>,
+}
+
+impl HookRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `hook`; hooks are called in the order they were added.
+    pub fn with_hook(mut self, hook: Arc<dyn NodeHooks>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// A handle to emit events through, and the runner delivering them.
+    pub fn start(self, config: &HooksConfig) -> (Hooks, HookRunner) {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let stats = Arc::new(HookStats::default());
+        let hooks = Hooks { sender, stats: Arc::clone(&stats) };
+        let runner = HookRunner {
+            hooks: self.hooks,
+            receiver,
+            timeout: Duration::from_millis(config.timeout_ms),
+            stats,
+        };
+        (hooks, runner)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HookStats {
+    delivered: AtomicU64,
+    panicked: AtomicU64,
+    timed_out: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HookStatsSnapshot {
+    /// Calls that returned in time, over all hooks.
+    pub delivered: u64,
+    pub panicked: u64,
+    pub timed_out: u64,
+    /// Events not queued because the queue was full.
+    pub dropped: u64,
+}
+
+impl HookStats {
+    pub fn snapshot(&self) -> HookStatsSnapshot {
+        HookStatsSnapshot {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsSource for HookStats {
+    fn render_metrics(&self, out: &mut String) {
+        let stats = self.snapshot();
+        metrics::write_counter(out, "dadbs_hook_calls_total", "Hook calls that returned in time", stats.delivered);
+        metrics::write_counter(out, "dadbs_hook_panics_total", "Hook calls that panicked", stats.panicked);
+        metrics::write_counter(out, "dadbs_hook_timeouts_total", "Hook calls abandoned when late", stats.timed_out);
+        metrics::write_counter(out, "dadbs_hook_events_dropped_total", "Hook events dropped", stats.dropped);
+    }
+}
+
+/// Queues events for the hooks without ever waiting.
+#[derive(Clone)]
+pub struct Hooks {
+    sender: mpsc::Sender<Arc<HookEvent>>,
+    stats: Arc<HookStats>,
+}
+
+impl Hooks {
+    pub fn emit(&self, event: HookEvent) {
+        match self.sender.try_send(Arc::new(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                debug!("Hook queue full, dropping {} event", event.kind());
+            }
+            // The runner has stopped, as on shutdown.
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Emits the reorg in `update`, if any, then each block it finalized.
+    pub fn publish(&self, update: &ChainUpdate) {
+        if update.is_reorg() {
+            self.emit(HookEvent::Reorg { rolled_back: update.rolled_back.clone(), applied: update.applied.clone() });
+        }
+        for block in &update.finalized {
+            self.emit(HookEvent::BlockFinalized(block.clone()));
+        }
+    }
+
+    pub fn stats(&self) -> Arc<HookStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+/// Delivers queued events to every hook, one event at a time.
+pub struct HookRunner {
+    hooks: Vec<Arc<dyn NodeHooks>>,
+    receiver: mpsc::Receiver<Arc<HookEvent>>,
+    timeout: Duration,
+    stats: Arc<HookStats>,
+}
+
+impl HookRunner {
+    pub async fn run(mut self, cancel: CancellationToken) {
+        loop {
+            let event = tokio::select! {
+                _ = cancel.cancelled() => return,
+                event = self.receiver.recv() => match event {
+                    Some(event) => event,
+                    None => return,
+                },
+            };
+            for hook in &self.hooks {
+                self.deliver(hook, &event).await;
+            }
+        }
+    }
+
+    /// Calls `hook` on a task of its own, so a panic stays there, and
+    /// abandons it past the timeout.
+    async fn deliver(&self, hook: &Arc<dyn NodeHooks>, event: &Arc<HookEvent>) {
+        let mut call = tokio::spawn({
+            let (hook, event) = (Arc::clone(hook), Arc::clone(event));
+            async move { event.deliver(hook.as_ref()).await }
+        });
+        match timeout(self.timeout, &mut call).await {
+            Ok(Ok(())) => {
+                self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => {
+                self.stats.panicked.fetch_add(1, Ordering::Relaxed);
+                warn!("Hook {} failed on {}: {}", hook.name(), event.kind(), e);
+            }
+            Err(_) => {
+                call.abort();
+                self.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                warn!("Hook {} took over {:?} on {}, abandoned", hook.name(), self.timeout, event.kind());
+            }
+        }
+    }
+}
+
+/// Appends each finalized block, as `get_block` returns it, to
+/// `finalized_blocks.jsonl` in a directory.
+pub struct BlockJsonLines {
+    path: PathBuf,
+}
+
+impl BlockJsonLines {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        BlockJsonLines { path: dir.into().join(FINALIZED_BLOCKS_FILE) }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    async fn append(&self, block: &Block) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(&BlockResult::from(block))?;
+        line.push(b'\n');
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+}
+
+#[async_trait]
+impl NodeHooks for BlockJsonLines {
+    fn name(&self) -> &str {
+        "finalized-blocks"
+    }
+
+    async fn on_block_finalized(&self, block: &Block) {
+        if let Err(e) = self.append(block).await {
+            warn!("Cannot write block {} to {}: {}", block.height(), self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use solana_sdk::hash::Hash;
+    use solana_sdk::pubkey::Pubkey;
+
+    fn block(height: u64) -> Block {
+        Block::with_transactions(height, Hash::new_unique(), 0, Pubkey::new_unique(), Vec::new())
+    }
+
+    /// Panics on even heights and hangs on height 3.
+    struct Unruly;
+
+    #[async_trait]
+    impl NodeHooks for Unruly {
+        fn name(&self) -> &str {
+            "unruly"
+        }
+
+        async fn on_block_finalized(&self, block: &Block) {
+            match block.height() {
+                3 => std::future::pending().await,
+                height if height % 2 == 0 => panic!("height {}", height),
+                _ => {}
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Heights(Mutex<Vec<u64>>);
+
+    #[async_trait]
+    impl NodeHooks for Heights {
+        fn name(&self) -> &str {
+            "heights"
+        }
+
+        async fn on_block_finalized(&self, block: &Block) {
+            self.0.lock().push(block.height());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unruly_hook_is_isolated_and_full_queue_drops() {
+        let heights = Arc::new(Heights::default());
+        let config = HooksConfig { timeout_ms: 50, queue_capacity: 4, ..HooksConfig::default() };
+        let (hooks, runner) = HookRegistry::new()
+            .with_hook(Arc::new(Unruly))
+            .with_hook(Arc::clone(&heights) as Arc<dyn NodeHooks>)
+            .start(&config);
+        for height in 1..=5 {
+            hooks.emit(HookEvent::BlockFinalized(block(height)));
+        }
+        assert_eq!(hooks.stats().snapshot().dropped, 1);
+
+        let cancel = CancellationToken::new();
+        let running = tokio::spawn(runner.run(cancel.clone()));
+        timeout(Duration::from_secs(5), async {
+            while hooks.stats().snapshot().delivered < 5 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*heights.0.lock(), vec![1, 2, 3, 4]);
+        let stats = hooks.stats().snapshot();
+        assert_eq!((stats.delivered, stats.panicked, stats.timed_out), (5, 2, 1));
+        cancel.cancel();
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_finalized_blocks_appended_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = BlockJsonLines::new(dir.path().join("blocks"));
+        hook.on_block_finalized(&block(1)).await;
+        hook.on_block_finalized(&block(2)).await;
+
+        let written = std::fs::read_to_string(hook.path()).unwrap();
+        let heights: Vec<u64> = written.lines()
+            .map(|line| serde_json::from_str::<BlockResult>(line).unwrap().height)
+            .collect();
+        assert_eq!(heights, vec![1, 2]);
+    }
+}
@@ -0,0 +1,301 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+use super::metrics::{self, MetricsSource};
+use super::network::{NetMessage, PeerId};
+
+/// Blocks, votes and control messages queued per peer before its reader
+/// waits for room.
+pub const DEFAULT_PEER_CONSENSUS_QUEUE: usize = 256;
+/// Transactions queued per peer; the excess is shed.
+pub const DEFAULT_PEER_TX_QUEUE: usize = 512;
+/// Queued transactions, over all peers, at which the node reports busy.
+pub const DEFAULT_BUSY_TRANSACTIONS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IngestConfig {
+    pub peer_consensus_queue: usize,
+    pub peer_tx_queue: usize,
+    pub busy_transactions: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            peer_consensus_queue: DEFAULT_PEER_CONSENSUS_QUEUE,
+            peer_tx_queue: DEFAULT_PEER_TX_QUEUE,
+            busy_transactions: DEFAULT_BUSY_TRANSACTIONS,
+        }
+    }
+}
+
+impl IngestConfig {
+    pub fn with_queue_sizes(mut self, peer_consensus_queue: usize, peer_tx_queue: usize) -> Self {
+        self.peer_consensus_queue = peer_consensus_queue.max(1);
+        self.peer_tx_queue = peer_tx_queue.max(1);
+        self
+    }
+
+    pub fn with_busy_transactions(mut self, busy_transactions: usize) -> Self {
+        self.busy_transactions = busy_transactions.max(1);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ingested {
+    Queued,
+    /// A transaction dropped because its peer's queue was full.
+    Shed,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct IngestStats {
+    pub consensus_queued: usize,
+    pub transactions_queued: usize,
+    pub transactions_shed: u64,
+    pub busy: bool,
+}
+
+/// Per-peer FIFO queues of one priority class, served round-robin so a
+/// peer with a long queue cannot starve the others.
+#[derive(Debug, Default)]
+struct Lanes {
+    peers: HashMap<PeerId, VecDeque<NetMessage>>,
+    /// Peers with queued messages, in the order they are next served.
+    turns: VecDeque<PeerId>,
+    len: usize,
+}
+
+impl Lanes {
+    fn queued(&self, peer: &PeerId) -> usize {
+        self.peers.get(peer).map_or(0, VecDeque::len)
+    }
+
+    fn push(&mut self, peer: PeerId, message: NetMessage) {
+        let queue = self.peers.entry(peer).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(peer);
+        }
+        queue.push_back(message);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<(PeerId, NetMessage)> {
+        let peer = self.turns.pop_front()?;
+        let queue = self.peers.get_mut(&peer)?;
+        let message = queue.pop_front()?;
+        if queue.is_empty() {
+            self.peers.remove(&peer);
+        } else {
+            self.turns.push_back(peer);
+        }
+        self.len -= 1;
+        Some((peer, message))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    consensus: Lanes,
+    transactions: Lanes,
+    closed: bool,
+}
+
+/// The bounded path from peer connections to the node. Each peer has its
+/// own queues: blocks, votes and control messages wait for room, stalling
+/// only that peer's connection, while transactions beyond the cap are
+/// shed. Consumers get every queued consensus message before any
+/// transaction. A transaction backlog over `busy_transactions` marks the
+/// node busy, and RPC refuses new transactions until it drains.
+#[derive(Debug)]
+pub struct IngestPipeline {
+    config: IngestConfig,
+    queues: Mutex<Queues>,
+    /// Wakes the consumer when a message is queued.
+    ready: Notify,
+    /// Wakes peers waiting for room when a message is taken.
+    space: Notify,
+    shed: AtomicU64,
+}
+
+impl IngestPipeline {
+    pub fn new(config: IngestConfig) -> Self {
+        IngestPipeline {
+            config,
+            queues: Mutex::new(Queues::default()),
+            ready: Notify::new(),
+            space: Notify::new(),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> &IngestConfig {
+        &self.config
+    }
+
+    /// Queues `message` from `peer`. Transactions never wait: they are
+    /// shed when the peer's queue is full. Anything else waits until the
+    /// peer's queue has room.
+    pub async fn push(&self, peer: PeerId, message: NetMessage) -> Ingested {
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            // Registered before checking, so a pop in between still wakes us.
+            space.as_mut().enable();
+            {
+                let mut queues = self.queues.lock();
+                if queues.closed {
+                    return Ingested::Closed;
+                }
+                if matches!(message, NetMessage::Tx(_)) {
+                    if queues.transactions.queued(&peer) >= self.config.peer_tx_queue {
+                        self.shed.fetch_add(1, Ordering::Relaxed);
+                        return Ingested::Shed;
+                    }
+                    queues.transactions.push(peer, message);
+                    break;
+                }
+                if queues.consensus.queued(&peer) < self.config.peer_consensus_queue {
+                    queues.consensus.push(peer, message);
+                    break;
+                }
+            }
+            space.await;
+        }
+        self.ready.notify_one();
+        Ingested::Queued
+    }
+
+    /// Waits for the next message, consensus before transactions, or
+    /// `None` once closed. Meant for a single consumer.
+    pub async fn recv(&self) -> Option<(PeerId, NetMessage)> {
+        loop {
+            if let Some(next) = self.try_recv() {
+                return Some(next);
+            }
+            if self.queues.lock().closed {
+                return None;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// The next message if one is queued.
+    pub fn try_recv(&self) -> Option<(PeerId, NetMessage)> {
+        let mut queues = self.queues.lock();
+        let next = queues.consensus.pop().or_else(|| queues.transactions.pop())?;
+        drop(queues);
+        self.space.notify_waiters();
+        Some(next)
+    }
+
+    /// Whether the transaction backlog is over `busy_transactions`.
+    pub fn is_busy(&self) -> bool {
+        self.queues.lock().transactions.len >= self.config.busy_transactions
+    }
+
+    pub fn stats(&self) -> IngestStats {
+        let queues = self.queues.lock();
+        IngestStats {
+            consensus_queued: queues.consensus.len,
+            transactions_queued: queues.transactions.len,
+            transactions_shed: self.shed.load(Ordering::Relaxed),
+            busy: queues.transactions.len >= self.config.busy_transactions,
+        }
+    }
+
+    /// Drops everything queued and releases waiting peers.
+    pub fn close(&self) {
+        let mut queues = self.queues.lock();
+        queues.closed = true;
+        queues.consensus = Lanes::default();
+        queues.transactions = Lanes::default();
+        drop(queues);
+        self.ready.notify_one();
+        self.space.notify_waiters();
+    }
+}
+
+impl Default for IngestPipeline {
+    fn default() -> Self {
+        Self::new(IngestConfig::default())
+    }
+}
+
+impl MetricsSource for IngestPipeline {
+    fn render_metrics(&self, out: &mut String) {
+        let stats = self.stats();
+        metrics::write_header(out, "dadbs_ingest_queued", "Peer messages waiting to be handled", "gauge");
+        metrics::write_sample(out, "dadbs_ingest_queued", &[("class", "consensus")], stats.consensus_queued as f64);
+        metrics::write_sample(out, "dadbs_ingest_queued", &[("class", "transaction")], stats.transactions_queued as f64);
+        metrics::write_counter(out, "dadbs_ingest_shed_total", "Peer transactions shed by full queues", stats.transactions_shed);
+        metrics::write_gauge(out, "dadbs_ingest_busy", "Whether new transactions are refused", if stats.busy { 1.0 } else { 0.0 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::block::Block;
+    use crate::node::transaction::Transaction;
+    use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::time::{timeout, Duration};
+
+    fn tx(amount: u64) -> NetMessage {
+        NetMessage::Tx(Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), amount, 1, 0, 0))
+    }
+
+    #[tokio::test]
+    async fn test_consensus_first_and_transactions_shed() {
+        let pipeline = IngestPipeline::new(IngestConfig::default().with_queue_sizes(1, 2).with_busy_transactions(3));
+        let (a, b) = (SocketAddr::from(([10, 0, 0, 1], 1)), SocketAddr::from(([10, 0, 0, 2], 1)));
+
+        assert_eq!(pipeline.push(a, tx(1)).await, Ingested::Queued);
+        assert_eq!(pipeline.push(a, tx(2)).await, Ingested::Queued);
+        assert_eq!(pipeline.push(a, tx(3)).await, Ingested::Shed);
+        assert!(!pipeline.is_busy());
+        assert_eq!(pipeline.push(b, tx(4)).await, Ingested::Queued);
+        assert!(pipeline.is_busy());
+        assert_eq!(pipeline.push(b, NetMessage::Block(Block::genesis())).await, Ingested::Queued);
+
+        assert_eq!(pipeline.recv().await, Some((b, NetMessage::Block(Block::genesis()))));
+        // Round-robin across peers: a, b, then a again.
+        let amounts: Vec<u64> = [pipeline.recv().await, pipeline.recv().await, pipeline.recv().await]
+            .into_iter()
+            .map(|next| match next {
+                Some((_, NetMessage::Tx(tx))) => tx.amount,
+                other => panic!("expected a transaction, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(amounts, [1, 4, 2]);
+        assert_eq!(pipeline.stats(), IngestStats { consensus_queued: 0, transactions_queued: 0, transactions_shed: 1, busy: false });
+    }
+
+    #[tokio::test]
+    async fn test_full_consensus_queue_waits_for_room() {
+        let pipeline = Arc::new(IngestPipeline::new(IngestConfig::default().with_queue_sizes(1, 1)));
+        let peer = SocketAddr::from(([10, 0, 0, 1], 1));
+        assert_eq!(pipeline.push(peer, NetMessage::Ping(1)).await, Ingested::Queued);
+
+        let waiting = tokio::spawn({
+            let pipeline = Arc::clone(&pipeline);
+            async move { pipeline.push(peer, NetMessage::Ping(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        assert_eq!(pipeline.recv().await, Some((peer, NetMessage::Ping(1))));
+        assert_eq!(timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap(), Ingested::Queued);
+        assert_eq!(pipeline.recv().await, Some((peer, NetMessage::Ping(2))));
+
+        pipeline.close();
+        assert_eq!(pipeline.push(peer, NetMessage::Ping(3)).await, Ingested::Closed);
+        assert_eq!(pipeline.recv().await, None);
+    }
+}
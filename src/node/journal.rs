@@ -0,0 +1,392 @@
+//! A recording of the consensus messages a node received, and of the blocks
+//! it finalized, for `dadbs-node replay` to feed back through a fresh
+//! `ConsensusManager`.
+//!
+//! The journal is a directory of numbered segment files. Records are a
+//! big-endian `u32` length followed by the Borsh-encoded entry, as in the
+//! intent log. Once a segment outgrows `segment_bytes` the next is started,
+//! beginning with a `Start` entry, and the oldest segments are deleted to
+//! keep the whole under `max_bytes`.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::Hash;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use super::network::{NetMessage, PeerId};
+
+/// Journal directory inside `storage_path`, unless `[journal] path` is set.
+pub const JOURNAL_DIR: &str = "journal";
+pub const DEFAULT_JOURNAL_MAX_BYTES: u64 = 256 * 1024 * 1024;
+pub const DEFAULT_JOURNAL_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+const SEGMENT_EXTENSION: &str = "journal";
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Corrupt journal segment {path} at byte {offset}")]
+    Corrupt { path: PathBuf, offset: usize },
+    #[error("No journal segments in {0}")]
+    Empty(PathBuf),
+}
+
+/// The kinds of message a journal records; each can be left out.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalKind {
+    Block,
+    Vote,
+    Heartbeat,
+    /// Batches of certified blocks, answering block requests.
+    Blocks,
+}
+
+impl JournalKind {
+    /// `None` for messages consensus does not act on.
+    pub fn of(message: &NetMessage) -> Option<Self> {
+        match message {
+            NetMessage::Block(_) | NetMessage::Proposal(_) => Some(JournalKind::Block),
+            NetMessage::Vote(_) => Some(JournalKind::Vote),
+            NetMessage::Heartbeat(_) => Some(JournalKind::Heartbeat),
+            NetMessage::Blocks { .. } => Some(JournalKind::Blocks),
+            _ => None,
+        }
+    }
+}
+
+/// The `[journal]` section. Recording costs a write per consensus message,
+/// so it is off unless a problem is being chased.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct JournalConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Defaults to <storage_path>/journal.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// All segments together; the oldest are deleted past it.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_segment_bytes")]
+    pub segment_bytes: u64,
+    /// Message kinds not recorded.
+    #[serde(default)]
+    pub exclude: Vec<JournalKind>,
+}
+
+fn default_max_bytes() -> u64 {
+    DEFAULT_JOURNAL_MAX_BYTES
+}
+
+fn default_segment_bytes() -> u64 {
+    DEFAULT_JOURNAL_SEGMENT_BYTES
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            enabled: false,
+            path: None,
+            max_bytes: default_max_bytes(),
+            segment_bytes: default_segment_bytes(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl JournalConfig {
+    pub fn dir(&self, storage_path: &str) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(storage_path).join(JOURNAL_DIR),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.segment_bytes == 0 {
+            return Err("journal.segment_bytes must be at least 1".to_string());
+        }
+        if self.max_bytes < self.segment_bytes {
+            return Err("journal.max_bytes must be at least journal.segment_bytes".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// One journal record. Times are Unix milliseconds on the recording node.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum JournalEntry {
+    /// Recording began, or a new segment did, with this block finalized.
+    /// Messages received before a new segment, for heights above it, went
+    /// with the old one, so a replay from there may miss them.
+    Start { at_ms: i64, height: u64, block_hash: Hash },
+    /// A message from `from`, as it was received.
+    Inbound { at_ms: i64, from: String, message: NetMessage },
+    /// The node finalized `block_hash`, leaving state at `state_root`; the
+    /// default hash if it tracks no state.
+    Finalized { at_ms: i64, height: u64, block_hash: Hash, state_root: Hash },
+}
+
+impl JournalEntry {
+    pub fn at_ms(&self) -> i64 {
+        match self {
+            JournalEntry::Start { at_ms, .. }
+            | JournalEntry::Inbound { at_ms, .. }
+            | JournalEntry::Finalized { at_ms, .. } => *at_ms,
+        }
+    }
+}
+
+struct Segments {
+    file: File,
+    seq: u64,
+    len: u64,
+    /// Earlier segments, oldest first, with their lengths.
+    closed: VecDeque<(u64, u64)>,
+    /// The latest finalized block, for the `Start` of the next segment.
+    finalized: (u64, Hash),
+}
+
+impl Segments {
+    fn total(&self) -> u64 {
+        self.len + self.closed.iter().map(|(_, len)| len).sum::<u64>()
+    }
+}
+
+/// Appends to the journal in `dir`. Writes are not synced: a crash may
+/// lose the last few entries, which `read` tolerates.
+pub struct Journal {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    exclude: Vec<JournalKind>,
+    segments: Mutex<Segments>,
+}
+
+impl Journal {
+    /// Continues the journal in the configured directory in a new segment,
+    /// starting with `finalized` as the finalized block.
+    pub fn open(config: &JournalConfig, storage_path: &str, finalized: (u64, Hash)) -> Result<Self, JournalError> {
+        let dir = config.dir(storage_path);
+        fs::create_dir_all(&dir)?;
+        let closed: VecDeque<(u64, u64)> = segment_paths(&dir)?
+            .into_iter()
+            .map(|(seq, path)| Ok((seq, fs::metadata(path)?.len())))
+            .collect::<Result<_, std::io::Error>>()?;
+        let seq = closed.back().map_or(0, |(seq, _)| seq + 1);
+        let file = create_segment(&dir, seq)?;
+        let journal = Journal {
+            dir,
+            max_bytes: config.max_bytes,
+            segment_bytes: config.segment_bytes,
+            exclude: config.exclude.clone(),
+            segments: Mutex::new(Segments { file, seq, len: 0, closed, finalized }),
+        };
+        let mut segments = journal.segments.lock();
+        journal.start(&mut segments)?;
+        drop(segments);
+        Ok(journal)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Records `message` from `from` if it is of a kind recorded.
+    pub fn record_inbound(&self, from: PeerId, message: &NetMessage, at_ms: i64) {
+        match JournalKind::of(message) {
+            Some(kind) if !self.exclude.contains(&kind) => {}
+            _ => return,
+        }
+        self.record(&JournalEntry::Inbound { at_ms, from: from.to_string(), message: message.clone() });
+    }
+
+    pub fn record_finalized(&self, height: u64, block_hash: Hash, state_root: Hash, at_ms: i64) {
+        self.record(&JournalEntry::Finalized { at_ms, height, block_hash, state_root });
+        self.segments.lock().finalized = (height, block_hash);
+    }
+
+    /// Recording is best effort: a failed write is logged, not returned,
+    /// so it never holds up consensus.
+    fn record(&self, entry: &JournalEntry) {
+        let mut segments = self.segments.lock();
+        if let Err(e) = self.append(&mut segments, entry) {
+            warn!("Cannot write to the journal in {}: {}", self.dir.display(), e);
+        }
+    }
+
+    fn append(&self, segments: &mut Segments, entry: &JournalEntry) -> Result<(), JournalError> {
+        let frame = frame(entry)?;
+        if segments.len > 0 && segments.len + frame.len() as u64 > self.segment_bytes {
+            self.rotate(segments)?;
+        }
+        segments.file.write_all(&frame)?;
+        segments.len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn start(&self, segments: &mut Segments) -> Result<(), JournalError> {
+        let (height, block_hash) = segments.finalized;
+        let at_ms = chrono::Utc::now().timestamp_millis();
+        self.append(segments, &JournalEntry::Start { at_ms, height, block_hash })
+    }
+
+    /// Closes the current segment for a new one and deletes the oldest
+    /// past `max_bytes`, keeping room for a full segment.
+    fn rotate(&self, segments: &mut Segments) -> Result<(), JournalError> {
+        segments.file.flush()?;
+        segments.closed.push_back((segments.seq, segments.len));
+        segments.seq += 1;
+        segments.file = create_segment(&self.dir, segments.seq)?;
+        segments.len = 0;
+        while segments.total() + self.segment_bytes > self.max_bytes {
+            let (seq, _) = match segments.closed.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            fs::remove_file(segment_path(&self.dir, seq))?;
+        }
+        self.start(segments)
+    }
+
+    /// Bytes over all segments.
+    pub fn len(&self) -> u64 {
+        self.segments.lock().total()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn flush(&self) -> Result<(), JournalError> {
+        Ok(self.segments.lock().file.sync_data()?)
+    }
+
+    /// Every entry in `dir`, oldest first. A torn record at the end of a
+    /// segment, where a crash or concurrent write left it, ends that
+    /// segment's entries.
+    pub fn read(dir: &Path) -> Result<Vec<JournalEntry>, JournalError> {
+        let paths = segment_paths(dir)?;
+        if paths.is_empty() {
+            return Err(JournalError::Empty(dir.to_path_buf()));
+        }
+        let mut entries = Vec::new();
+        for (_, path) in paths {
+            let bytes = fs::read(&path)?;
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let header = match bytes.get(offset..offset + 4) {
+                    Some(header) => header,
+                    None => break,
+                };
+                let len = u32::from_be_bytes(header.try_into().expect("four bytes")) as usize;
+                let body = match bytes.get(offset + 4..offset + 4 + len) {
+                    Some(body) => body,
+                    None => break,
+                };
+                let entry = JournalEntry::try_from_slice(body)
+                    .map_err(|_| JournalError::Corrupt { path: path.clone(), offset })?;
+                entries.push(entry);
+                offset += 4 + len;
+            }
+            if offset < bytes.len() {
+                warn!("Ignoring {} bytes of torn record in {}", bytes.len() - offset, path.display());
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Writes `entries` as a journal in `dir`, in one segment; for editing
+    /// a journal, or building one by hand.
+    pub fn write(dir: &Path, entries: &[JournalEntry]) -> Result<(), JournalError> {
+        fs::create_dir_all(dir)?;
+        for (seq, _) in segment_paths(dir)? {
+            fs::remove_file(segment_path(dir, seq))?;
+        }
+        let mut file = create_segment(dir, 0)?;
+        for entry in entries {
+            file.write_all(&frame(entry)?)?;
+        }
+        Ok(file.sync_all()?)
+    }
+}
+
+fn frame(entry: &JournalEntry) -> Result<Vec<u8>, JournalError> {
+    let body = entry.try_to_vec()?;
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", seq, SEGMENT_EXTENSION))
+}
+
+fn create_segment(dir: &Path, seq: u64) -> Result<File, std::io::Error> {
+    OpenOptions::new().write(true).create_new(true).open(segment_path(dir, seq))
+}
+
+/// The segments in `dir` by sequence number.
+fn segment_paths(dir: &Path) -> Result<Vec<(u64, PathBuf)>, std::io::Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(seq) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            segments.push((seq, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::block::Block;
+    use crate::node::heartbeat::Heartbeat;
+    use solana_sdk::signature::Keypair;
+
+    #[test]
+    fn test_segments_rotate_within_bounds_and_skip_excluded_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = JournalConfig {
+            enabled: true,
+            path: Some(dir.path().display().to_string()),
+            max_bytes: 4096,
+            segment_bytes: 1024,
+            exclude: vec![JournalKind::Heartbeat],
+        };
+        let from: PeerId = "127.0.0.1:9000".parse().unwrap();
+        let journal = Journal::open(&config, "unused", (0, Hash::default())).unwrap();
+        let block = NetMessage::Block(Block::genesis());
+        for height in 1..=200 {
+            journal.record_inbound(from, &block, height as i64);
+            journal.record_finalized(height, Hash::new_unique(), Hash::default(), height as i64);
+            assert!(journal.len() <= config.max_bytes);
+        }
+        journal.flush().unwrap();
+
+        // The oldest segments are gone; what is left begins where its first
+        // segment did and runs to the last entry.
+        let entries = Journal::read(dir.path()).unwrap();
+        assert!(matches!(entries[0], JournalEntry::Start { height, .. } if height > 100));
+        assert!(matches!(entries.last(), Some(JournalEntry::Finalized { height: 200, .. })));
+        assert!(segment_paths(dir.path()).unwrap().len() <= 4);
+
+        // Heartbeats are left out, as are messages consensus ignores.
+        let before = journal.len();
+        journal.record_inbound(from, &NetMessage::Heartbeat(Heartbeat::new_signed(&Keypair::new(), 1, 0)), 201);
+        journal.record_inbound(from, &NetMessage::Ping(1), 202);
+        assert_eq!(journal.len(), before);
+    }
+}
@@ -0,0 +1,479 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use log::info;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{keypair_from_seed, Keypair, Signature, Signer};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use super::config::NodeConfig;
+use super::handover::KeyHandover;
+
+/// Keystore directory inside `storage_path` unless `[keystore] dir` is set.
+pub const DEFAULT_KEYSTORE_DIR: &str = "keystore";
+pub const DEFAULT_AUTO_LOCK_SECS: u64 = 300;
+/// scrypt cost, as log2(N); about a quarter second per unlock.
+pub const DEFAULT_SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+const KEY_FILE_VERSION: u32 = 1;
+const CIPHER: &str = "xchacha20poly1305";
+const KDF: &str = "scrypt";
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum KeystoreError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Malformed key file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Key {0} already exists")]
+    DuplicateName(String),
+    #[error("No key named {0}")]
+    NotFound(String),
+    #[error("Wrong passphrase for key {0}")]
+    WrongPassphrase(String),
+    #[error("Key {0} is locked")]
+    Locked(String),
+    #[error("Invalid key name {0:?}: use up to 64 letters, digits, '-' or '_'")]
+    InvalidName(String),
+    #[error("Invalid key material: {0}")]
+    InvalidKey(String),
+    #[error("{path} is accessible by other users (mode {mode:o}); restrict it to the owner")]
+    InsecurePermissions { path: PathBuf, mode: u32 },
+    #[error("Unsupported key file: {0}")]
+    Unsupported(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct KeystoreConfig {
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Unlocked keys are forgotten this long after being unlocked.
+    #[serde(default = "default_auto_lock_secs")]
+    pub auto_lock_secs: u64,
+}
+
+fn default_auto_lock_secs() -> u64 {
+    DEFAULT_AUTO_LOCK_SECS
+}
+
+impl Default for KeystoreConfig {
+    fn default() -> Self {
+        KeystoreConfig { dir: None, auto_lock_secs: DEFAULT_AUTO_LOCK_SECS }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ScryptParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct CryptoSection {
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+    kdf: String,
+    kdfparams: ScryptParams,
+}
+
+/// One key per file, after the layout of common wallet keystores: the
+/// 64-byte keypair is sealed with a key derived from the passphrase, with the
+/// public key as associated data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct KeyFile {
+    version: u32,
+    name: String,
+    pubkey: String,
+    created_at: i64,
+    crypto: CryptoSection,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub name: String,
+    pub pubkey: Pubkey,
+    /// Unix-millisecond creation time.
+    pub created_at: i64,
+}
+
+struct Unlocked {
+    keypair: Keypair,
+    expires: Instant,
+}
+
+/// Passphrase-encrypted Ed25519 keys for the node identity, transaction
+/// signing and stake operations. Key files are kept owner-only; on unix a
+/// file anyone else can read is refused.
+pub struct Keystore {
+    dir: PathBuf,
+    auto_lock: Duration,
+    scrypt_log_n: u8,
+    unlocked: Mutex<HashMap<String, Unlocked>>,
+}
+
+fn validate_name(name: &str) -> Result<(), KeystoreError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KeystoreError::InvalidName(name.to_string()))
+    }
+}
+
+fn derive_key(passphrase: &str, params: &ScryptParams) -> Result<[u8; 32], KeystoreError> {
+    let salt = hex::decode(&params.salt).map_err(|e| KeystoreError::Unsupported(format!("bad salt: {}", e)))?;
+    let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+        .map_err(|e| KeystoreError::Unsupported(format!("bad scrypt parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), &salt, &scrypt_params, &mut key)
+        .map_err(|e| KeystoreError::Unsupported(e.to_string()))?;
+    Ok(key)
+}
+
+fn keypair_from_bytes(bytes: &[u8]) -> Result<Keypair, KeystoreError> {
+    match bytes.len() {
+        64 => Keypair::from_bytes(bytes).map_err(|e| KeystoreError::InvalidKey(e.to_string())),
+        32 => keypair_from_seed(bytes).map_err(|e| KeystoreError::InvalidKey(e.to_string())),
+        len => Err(KeystoreError::InvalidKey(format!("expected a 32-byte seed or 64-byte keypair, got {} bytes", len))),
+    }
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), KeystoreError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(KeystoreError::InsecurePermissions { path: path.to_path_buf(), mode });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), KeystoreError> {
+    Ok(())
+}
+
+impl Keystore {
+    /// Opens the keystore at `dir`, creating it owner-only if missing.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, KeystoreError> {
+        let dir = dir.into();
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+            }
+        }
+        check_permissions(&dir)?;
+        Ok(Keystore {
+            dir,
+            auto_lock: Duration::from_secs(DEFAULT_AUTO_LOCK_SECS),
+            scrypt_log_n: DEFAULT_SCRYPT_LOG_N,
+            unlocked: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn from_config(config: &NodeConfig) -> Result<Self, KeystoreError> {
+        let dir = match &config.keystore.dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&config.storage_path).join(DEFAULT_KEYSTORE_DIR),
+        };
+        Ok(Self::open(dir)?.with_auto_lock(Duration::from_secs(config.keystore.auto_lock_secs)))
+    }
+
+    pub fn with_auto_lock(mut self, auto_lock: Duration) -> Self {
+        self.auto_lock = auto_lock;
+        self
+    }
+
+    /// Applies to keys created or imported from now on.
+    pub fn with_scrypt_log_n(mut self, log_n: u8) -> Self {
+        self.scrypt_log_n = log_n;
+        self
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// Generates a key named `name`, encrypted under `passphrase`.
+    pub fn create_key(&self, name: &str, passphrase: &str) -> Result<Pubkey, KeystoreError> {
+        self.store(name, &Keypair::new(), passphrase)
+    }
+
+    /// Stores an existing key, given as a 32-byte seed or 64-byte keypair.
+    pub fn import_key(&self, name: &str, bytes: &[u8], passphrase: &str) -> Result<Pubkey, KeystoreError> {
+        self.store(name, &keypair_from_bytes(bytes)?, passphrase)
+    }
+
+    fn store(&self, name: &str, keypair: &Keypair, passphrase: &str) -> Result<Pubkey, KeystoreError> {
+        validate_name(name)?;
+        let pubkey = keypair.pubkey();
+        let mut salt = [0u8; 32];
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let kdfparams = ScryptParams { log_n: self.scrypt_log_n, r: SCRYPT_R, p: SCRYPT_P, salt: hex::encode(salt) };
+        let key = derive_key(passphrase, &kdfparams)?;
+        let plaintext = keypair.to_bytes();
+        let ciphertext = XChaCha20Poly1305::new(&key.into())
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: &plaintext, aad: pubkey.as_ref() })
+            .map_err(|_| KeystoreError::InvalidKey("encryption failed".to_string()))?;
+        let file = KeyFile {
+            version: KEY_FILE_VERSION,
+            name: name.to_string(),
+            pubkey: pubkey.to_string(),
+            created_at: chrono::Utc::now().timestamp_millis(),
+            crypto: CryptoSection {
+                cipher: CIPHER.to_string(),
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+                kdf: KDF.to_string(),
+                kdfparams,
+            },
+        };
+
+        // create_new makes a concurrent duplicate lose rather than overwrite.
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut out = match options.open(self.path(name)) {
+            Ok(out) => out,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(KeystoreError::DuplicateName(name.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+        out.write_all(&serde_json::to_vec_pretty(&file)?)?;
+        out.sync_all()?;
+        info!("Stored key {} ({})", name, pubkey);
+        Ok(pubkey)
+    }
+
+    fn read(&self, name: &str) -> Result<KeyFile, KeystoreError> {
+        validate_name(name)?;
+        let path = self.path(name);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(KeystoreError::NotFound(name.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+        check_permissions(&path)?;
+        let file: KeyFile = serde_json::from_slice(&bytes)?;
+        if file.version != KEY_FILE_VERSION || file.crypto.cipher != CIPHER || file.crypto.kdf != KDF {
+            return Err(KeystoreError::Unsupported(format!(
+                "version {} with {} and {}", file.version, file.crypto.cipher, file.crypto.kdf
+            )));
+        }
+        Ok(file)
+    }
+
+    fn decrypt(&self, name: &str, passphrase: &str) -> Result<Keypair, KeystoreError> {
+        let file = self.read(name)?;
+        let pubkey = Pubkey::from_str(&file.pubkey).map_err(|e| KeystoreError::Unsupported(e.to_string()))?;
+        let malformed = |e: hex::FromHexError| KeystoreError::Unsupported(e.to_string());
+        let nonce = hex::decode(&file.crypto.nonce).map_err(malformed)?;
+        let ciphertext = hex::decode(&file.crypto.ciphertext).map_err(malformed)?;
+        if nonce.len() != 24 {
+            return Err(KeystoreError::Unsupported(format!("nonce of {} bytes", nonce.len())));
+        }
+        let key = derive_key(passphrase, &file.crypto.kdfparams)?;
+        let plaintext = XChaCha20Poly1305::new(&key.into())
+            .decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: pubkey.as_ref() })
+            .map_err(|_| KeystoreError::WrongPassphrase(name.to_string()))?;
+        let keypair = keypair_from_bytes(&plaintext)?;
+        if keypair.pubkey() != pubkey {
+            return Err(KeystoreError::InvalidKey(format!("{} does not hold the key for {}", name, pubkey)));
+        }
+        Ok(keypair)
+    }
+
+    /// Every stored key, sorted by name.
+    pub fn list(&self) -> Result<Vec<KeyInfo>, KeystoreError> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = match (path.extension().and_then(|e| e.to_str()), path.file_stem().and_then(|s| s.to_str())) {
+                (Some("json"), Some(name)) if validate_name(name).is_ok() => name.to_string(),
+                _ => continue,
+            };
+            let file = self.read(&name)?;
+            let pubkey = Pubkey::from_str(&file.pubkey).map_err(|e| KeystoreError::Unsupported(e.to_string()))?;
+            keys.push(KeyInfo { name, pubkey, created_at: file.created_at });
+        }
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(keys)
+    }
+
+    pub fn pubkey(&self, name: &str) -> Result<Pubkey, KeystoreError> {
+        let file = self.read(name)?;
+        Pubkey::from_str(&file.pubkey).map_err(|e| KeystoreError::Unsupported(e.to_string()))
+    }
+
+    /// Keeps `name` decrypted in memory for the auto-lock period.
+    pub fn unlock(&self, name: &str, passphrase: &str) -> Result<(), KeystoreError> {
+        let keypair = self.decrypt(name, passphrase)?;
+        let expires = Instant::now() + self.auto_lock;
+        self.unlocked.lock().insert(name.to_string(), Unlocked { keypair, expires });
+        Ok(())
+    }
+
+    pub fn lock(&self, name: &str) {
+        self.unlocked.lock().remove(name);
+    }
+
+    pub fn lock_all(&self) {
+        self.unlocked.lock().clear();
+    }
+
+    pub fn is_unlocked(&self, name: &str) -> bool {
+        self.with_unlocked(name, |_| ()).is_ok()
+    }
+
+    fn with_unlocked<T>(&self, name: &str, f: impl FnOnce(&Keypair) -> T) -> Result<T, KeystoreError> {
+        let now = Instant::now();
+        let mut unlocked = self.unlocked.lock();
+        unlocked.retain(|_, key| key.expires > now);
+        unlocked.get(name).map(|key| f(&key.keypair)).ok_or_else(|| KeystoreError::Locked(name.to_string()))
+    }
+
+    /// Signs with an unlocked key.
+    pub fn sign(&self, name: &str, message: &[u8]) -> Result<Signature, KeystoreError> {
+        self.with_unlocked(name, |keypair| keypair.sign_message(message))
+    }
+
+    /// Replaces key `name` with a fresh one under the same passphrase and
+    /// returns the handover to it on `chain_id`, effective from
+    /// `valid_from_height`. The old key is kept as
+    /// `{name}-retired-{valid_from_height}`; should storing the new key
+    /// fail, it is left there alone.
+    pub fn rotate(
+        &self,
+        name: &str,
+        passphrase: &str,
+        chain_id: &str,
+        valid_from_height: u64,
+    ) -> Result<(KeyHandover, Keypair), KeystoreError> {
+        let old = self.decrypt(name, passphrase)?;
+        let retired = format!("{}-retired-{}", name, valid_from_height);
+        validate_name(&retired)?;
+        if self.path(&retired).exists() {
+            return Err(KeystoreError::DuplicateName(retired));
+        }
+        fs::rename(self.path(name), self.path(&retired))?;
+        let new = Keypair::new();
+        self.store(name, &new, passphrase)?;
+        self.lock(name);
+        info!("Rotated key {} to {}, keeping the old one as {}", name, new.pubkey(), retired);
+        Ok((KeyHandover::for_chain(&old, &new, chain_id, valid_from_height), new))
+    }
+
+    /// The 64-byte keypair, for backup or use in other wallets.
+    pub fn export(&self, name: &str, passphrase: &str) -> Result<Vec<u8>, KeystoreError> {
+        Ok(self.decrypt(name, passphrase)?.to_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap scrypt so the tests run quickly.
+    fn open(dir: &Path) -> Keystore {
+        Keystore::open(dir.join("keys")).unwrap().with_scrypt_log_n(4)
+    }
+
+    #[test]
+    fn test_round_trip_and_typed_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = open(dir.path());
+        let created = keystore.create_key("node", "correct horse").unwrap();
+        let imported = Keypair::new();
+        assert_eq!(keystore.import_key("stake", &imported.to_bytes(), "battery").unwrap(), imported.pubkey());
+
+        let names: Vec<String> = keystore.list().unwrap().into_iter().map(|key| key.name).collect();
+        assert_eq!(names, ["node", "stake"]);
+        assert_eq!(keystore.export("stake", "battery").unwrap(), imported.to_bytes().to_vec());
+        let exported = keystore.export("node", "correct horse").unwrap();
+        assert_eq!(Keypair::from_bytes(&exported).unwrap().pubkey(), created);
+
+        assert!(matches!(keystore.export("node", "wrong"), Err(KeystoreError::WrongPassphrase(_))));
+        assert!(matches!(keystore.unlock("node", "wrong"), Err(KeystoreError::WrongPassphrase(_))));
+        assert!(matches!(keystore.create_key("node", "other"), Err(KeystoreError::DuplicateName(_))));
+        assert!(matches!(keystore.import_key("x", &[1, 2, 3], "p"), Err(KeystoreError::InvalidKey(_))));
+        assert!(matches!(keystore.create_key("../escape", "p"), Err(KeystoreError::InvalidName(_))));
+        assert!(matches!(keystore.sign("node", b"msg"), Err(KeystoreError::Locked(_))));
+        assert!(matches!(keystore.unlock("missing", "p"), Err(KeystoreError::NotFound(_))));
+
+        keystore.unlock("node", "correct horse").unwrap();
+        let signature = keystore.sign("node", b"msg").unwrap();
+        assert!(signature.verify(created.as_ref(), b"msg"));
+    }
+
+    #[test]
+    fn test_rotate_keeps_the_old_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = open(dir.path());
+        let old = keystore.create_key("node", "pass").unwrap();
+        assert!(matches!(keystore.rotate("node", "wrong", "chain", 10), Err(KeystoreError::WrongPassphrase(_))));
+        let (handover, new) = keystore.rotate("node", "pass", "chain", 10).unwrap();
+        assert_eq!((handover.old_pub, handover.new_pub), (old, new.pubkey()));
+        assert_eq!(handover.verify("chain"), Ok(()));
+        assert_eq!(keystore.pubkey("node").unwrap(), new.pubkey());
+        assert_eq!(keystore.pubkey("node-retired-10").unwrap(), old);
+        assert!(matches!(keystore.rotate("node", "pass", "chain", 10), Err(KeystoreError::DuplicateName(_))));
+    }
+
+    #[test]
+    fn test_unlocked_keys_auto_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = open(dir.path()).with_auto_lock(Duration::from_millis(50));
+        keystore.create_key("node", "pass").unwrap();
+        keystore.unlock("node", "pass").unwrap();
+        assert!(keystore.sign("node", b"msg").is_ok());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!keystore.is_unlocked("node"));
+        assert!(matches!(keystore.sign("node", b"msg"), Err(KeystoreError::Locked(_))));
+
+        keystore.unlock("node", "pass").unwrap();
+        keystore.lock("node");
+        assert!(matches!(keystore.sign("node", b"msg"), Err(KeystoreError::Locked(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_enforced() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = open(dir.path());
+        keystore.create_key("node", "pass").unwrap();
+        let path = dir.path().join("keys").join("node.json");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(dir.path().join("keys")).unwrap().permissions().mode() & 0o777, 0o700);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(keystore.unlock("node", "pass"), Err(KeystoreError::InsecurePermissions { mode: 0o644, .. })));
+        assert!(keystore.list().is_err());
+        fs::set_permissions(dir.path().join("keys"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(Keystore::open(dir.path().join("keys")), Err(KeystoreError::InsecurePermissions { .. })));
+    }
+}
@@ -0,0 +1,254 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use thiserror::Error;
+
+use super::block::BlockHeader;
+use super::merkle::MerkleProof;
+use super::quorum::{QuorumPolicy, ThresholdPolicy};
+use super::validator::ValidatorSet;
+use super::vote::{CertificateError, CommitCertificate};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LightClientError {
+    #[error("Expected a header at height {expected}, got {actual}")]
+    NonContiguous { expected: u64, actual: u64 },
+    #[error("Header {height} does not follow {expected}")]
+    ParentMismatch { height: u64, expected: Hash },
+    #[error("Header {height} is for chain {actual}")]
+    WrongChain { height: u64, actual: String },
+    #[error("Certificate for header {height} is for height {actual}")]
+    CertificateHeight { height: u64, actual: u64 },
+    #[error("Invalid certificate for header {height}: {source}")]
+    Certificate { height: u64, source: CertificateError },
+    #[error("Proposer {proposer} of header {height} did not sign it")]
+    ProposerNotSigned { height: u64, proposer: Pubkey },
+    #[error("Header {height} announces validator set {expected}, which was not supplied")]
+    ValidatorSetMismatch { height: u64, expected: Hash },
+    #[error("Transaction {tx_hash} is not in block {height}")]
+    NotIncluded { tx_hash: Hash, height: u64 },
+}
+
+/// A header with what a light client needs to check it without the block:
+/// the certificate that committed it and, if the header announces one, the
+/// next validator set.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignedHeader {
+    pub header: BlockHeader,
+    pub certificate: CommitCertificate,
+    #[serde(default)]
+    pub next_validators: Option<ValidatorSet>,
+}
+
+impl SignedHeader {
+    pub fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+}
+
+/// The last header a light client verified, and the validators that must
+/// certify the one after it.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrustedState {
+    pub height: u64,
+    pub block_hash: Hash,
+    pub validators: ValidatorSet,
+    /// The chain the votes on each header must be signed for.
+    pub chain_id: String,
+}
+
+impl TrustedState {
+    /// Trusts `header`, obtained out of band (the genesis block, or a
+    /// checkpoint), with `validators` of `chain_id` certifying its successors.
+    pub fn new(header: &BlockHeader, chain_id: impl Into<String>, validators: ValidatorSet) -> Self {
+        TrustedState { height: header.height, block_hash: header.hash(), validators, chain_id: chain_id.into() }
+    }
+}
+
+/// Checks that `headers` extend `trusted` one height at a time, each
+/// committed by a quorum of the validators then in force with its proposer
+/// among the signers, and returns the state after the last. Validator set
+/// changes are followed as the headers announce them.
+pub fn verify_header_chain(headers: &[SignedHeader], trusted: &TrustedState) -> Result<TrustedState, LightClientError> {
+    verify_header_chain_with(headers, trusted, &ThresholdPolicy::bft())
+}
+
+/// `verify_header_chain` for a chain configured with another quorum.
+pub fn verify_header_chain_with(
+    headers: &[SignedHeader],
+    trusted: &TrustedState,
+    quorum: &dyn QuorumPolicy,
+) -> Result<TrustedState, LightClientError> {
+    let mut trusted = trusted.clone();
+    for signed in headers {
+        let header = &signed.header;
+        let height = header.height;
+        if height != trusted.height + 1 {
+            return Err(LightClientError::NonContiguous { expected: trusted.height + 1, actual: height });
+        }
+        if header.parent_hash != trusted.block_hash {
+            return Err(LightClientError::ParentMismatch { height, expected: trusted.block_hash });
+        }
+        // Headers before version 3 do not name their chain.
+        if header.version >= 3 && header.chain_id != trusted.chain_id {
+            return Err(LightClientError::WrongChain { height, actual: header.chain_id.clone() });
+        }
+        let certificate = &signed.certificate;
+        if certificate.height != height {
+            return Err(LightClientError::CertificateHeight { height, actual: certificate.height });
+        }
+        let block_hash = header.hash();
+        certificate
+            .verify(&trusted.chain_id, &block_hash, &trusted.validators, quorum)
+            .map_err(|source| LightClientError::Certificate { height, source })?;
+        // Every signature was checked above; the proposer's is its vote.
+        let mut signers = certificate.votes.iter().map(|vote| &vote.validator)
+            .chain(certificate.aggregate.iter().flat_map(|aggregate| &aggregate.signers));
+        if !signers.any(|signer| *signer == header.proposer) {
+            return Err(LightClientError::ProposerNotSigned { height, proposer: header.proposer });
+        }
+
+        // A version 0 hash does not cover `next_validators_hash`; ignore it.
+        let validators = if header.version == 0 || header.next_validators_hash == Hash::default() {
+            trusted.validators
+        } else {
+            match &signed.next_validators {
+                Some(next) if next.hash() == header.next_validators_hash => next.clone(),
+                _ => {
+                    let expected = header.next_validators_hash;
+                    return Err(LightClientError::ValidatorSetMismatch { height, expected });
+                }
+            }
+        };
+        trusted = TrustedState { height, block_hash, validators, chain_id: trusted.chain_id };
+    }
+    Ok(trusted)
+}
+
+/// Checks that `proof` places `tx_hash` under the header's transactions root.
+pub fn verify_tx_inclusion(header: &BlockHeader, proof: &MerkleProof, tx_hash: &Hash) -> Result<(), LightClientError> {
+    if proof.verify(&header.transactions_root, tx_hash) {
+        Ok(())
+    } else {
+        Err(LightClientError::NotIncluded { tx_hash: *tx_hash, height: header.height })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::block::Block;
+    use crate::node::config::DEFAULT_CHAIN_ID;
+    use crate::node::transaction::Transaction;
+    use crate::node::validator::ValidatorInfo;
+    use crate::node::vote::Vote;
+    use solana_sdk::signature::{Keypair, Signer};
+
+    fn set(keys: &[Keypair]) -> ValidatorSet {
+        ValidatorSet::new(keys.iter().map(|key| ValidatorInfo::new(key.pubkey(), 1)).collect())
+    }
+
+    /// A block on `parent` proposed by `signers[0]` and certified by all of
+    /// `signers`.
+    fn signed(parent: &BlockHeader, signers: &[Keypair], transactions: Vec<Transaction>) -> (Block, SignedHeader) {
+        let block = Block::with_transactions(parent.height + 1, parent.hash(), 0, signers[0].pubkey(), transactions);
+        let votes = signers.iter().map(|key| Vote::new(key, block.height(), 0, block.hash())).collect();
+        let certificate = CommitCertificate::new(block.height(), block.hash(), votes);
+        let header = SignedHeader { header: block.header.clone(), certificate, next_validators: None };
+        (block, header)
+    }
+
+    #[test]
+    fn test_valid_chain_follows_a_validator_change() {
+        let first: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let second: Vec<Keypair> = (0..3).map(|_| Keypair::new()).collect();
+        let genesis = Block::genesis();
+        let trusted = TrustedState::new(&genesis.header, DEFAULT_CHAIN_ID, set(&first));
+
+        let (one, h1) = signed(&genesis.header, &first, Vec::new());
+        // Block two hands over to the second set, which certifies block three.
+        let two = Block::new(2, one.hash(), 0, first[1].pubkey()).with_next_validators(&set(&second));
+        let votes = first[1..].iter().map(|key| Vote::new(key, 2, 0, two.hash())).collect();
+        let h2 = SignedHeader {
+            header: two.header.clone(),
+            certificate: CommitCertificate::new(2, two.hash(), votes),
+            next_validators: Some(set(&second)),
+        };
+        let (three, h3) = signed(&two.header, &second, Vec::new());
+
+        let verified = verify_header_chain(&[h1.clone(), h2.clone(), h3.clone()], &trusted).unwrap();
+        assert_eq!((verified.height, verified.block_hash), (3, three.hash()));
+        assert_eq!(verified.validators, set(&second));
+
+        // Without the announced set, what follows cannot be checked.
+        let withheld = SignedHeader { next_validators: None, ..h2 };
+        let error = verify_header_chain(&[h1.clone(), withheld, h3.clone()], &trusted).unwrap_err();
+        assert!(matches!(error, LightClientError::ValidatorSetMismatch { height: 2, .. }));
+        let error = verify_header_chain(&[h1, h3], &trusted).unwrap_err();
+        assert_eq!(error, LightClientError::NonContiguous { expected: 2, actual: 3 });
+    }
+
+    #[test]
+    fn test_forged_certificate_is_rejected() {
+        let validators: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let genesis = Block::genesis();
+        let trusted = TrustedState::new(&genesis.header, DEFAULT_CHAIN_ID, set(&validators));
+
+        // Outsiders sign a header naming a real validator as proposer.
+        let outsiders: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let (_, mut forged) = signed(&genesis.header, &outsiders, Vec::new());
+        forged.header.proposer = validators[0].pubkey();
+        assert!(matches!(
+            verify_header_chain(&[forged], &trusted),
+            Err(LightClientError::Certificate { height: 1, .. })
+        ));
+
+        // Real signatures over a different header do not carry over.
+        let (_, mut moved) = signed(&genesis.header, &validators, Vec::new());
+        moved.header.timestamp += 1;
+        assert!(matches!(
+            verify_header_chain(&[moved], &trusted),
+            Err(LightClientError::Certificate { source: CertificateError::BlockMismatch { .. }, .. })
+        ));
+
+        // A quorum that leaves the proposer out.
+        let (block, mut unsigned) = signed(&genesis.header, &validators, Vec::new());
+        unsigned.certificate.votes.remove(0);
+        assert_eq!(unsigned.certificate.block_hash, block.hash());
+        assert_eq!(
+            verify_header_chain(&[unsigned], &trusted),
+            Err(LightClientError::ProposerNotSigned { height: 1, proposer: validators[0].pubkey() })
+        );
+
+        // Nor do headers and votes from another chain.
+        let foreign = Block::new(1, genesis.hash(), 0, validators[0].pubkey()).with_chain_id("another-chain");
+        let votes = validators.iter().map(|key| Vote::for_chain(key, "another-chain", 1, 0, foreign.hash())).collect();
+        let certificate = CommitCertificate::new(1, foreign.hash(), votes);
+        let foreign = SignedHeader { header: foreign.header.clone(), certificate, next_validators: None };
+        assert_eq!(
+            verify_header_chain(&[foreign], &trusted),
+            Err(LightClientError::WrongChain { height: 1, actual: "another-chain".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        let validators: Vec<Keypair> = (0..1).map(|_| Keypair::new()).collect();
+        let sender = Keypair::new();
+        let transactions: Vec<Transaction> =
+            (0..5).map(|nonce| Transaction::new_signed(&sender, Pubkey::new_unique(), 1, 1, nonce, 0)).collect();
+        let (block, _) = signed(&Block::genesis().header, &validators, transactions.clone());
+
+        for (index, transaction) in transactions.iter().enumerate() {
+            let proof = block.inclusion_proof(index).unwrap();
+            assert_eq!(verify_tx_inclusion(&block.header, &proof, &transaction.hash()), Ok(()));
+        }
+        let outsider = Transaction::new_signed(&sender, Pubkey::new_unique(), 1, 1, 9, 0);
+        let proof = block.inclusion_proof(0).unwrap();
+        assert_eq!(
+            verify_tx_inclusion(&block.header, &proof, &outsider.hash()),
+            Err(LightClientError::NotIncluded { tx_hash: outsider.hash(), height: 1 })
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const DEFAULT_QUARANTINE_AFTER: u32 = 5;
+pub const DEFAULT_REINSTATE_AFTER: u32 = 3;
+const EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessConfig {
+    pub quarantine_after: u32,
+    pub reinstate_after: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        LivenessConfig {
+            quarantine_after: DEFAULT_QUARANTINE_AFTER,
+            reinstate_after: DEFAULT_REINSTATE_AFTER,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LivenessState {
+    Active,
+    Quarantined,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValidatorHealth {
+    pub pubkey: Pubkey,
+    pub state: LivenessState,
+    pub response_rate: f64,
+    pub latency_ms: f64,
+    pub consecutive_failures: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Liveness {
+    state: LivenessState,
+    response_rate: f64,
+    latency_ms: f64,
+    consecutive_failures: u32,
+    probe_successes: u32,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness {
+            state: LivenessState::Active,
+            response_rate: 1.0,
+            latency_ms: 0.0,
+            consecutive_failures: 0,
+            probe_successes: 0,
+        }
+    }
+}
+
+/// Tracks how reliably each validator answers confirmation requests. A validator
+/// failing `quarantine_after` times in a row stops counting toward the quorum
+/// denominator until it answers `reinstate_after` requests in a row again.
+#[derive(Debug, Clone, Default)]
+pub struct LivenessTracker {
+    config: LivenessConfig,
+    validators: HashMap<Pubkey, Liveness>,
+}
+
+impl LivenessTracker {
+    pub fn new(config: LivenessConfig) -> Self {
+        LivenessTracker {
+            config,
+            validators: HashMap::new(),
+        }
+    }
+
+    pub fn record_success(&mut self, validator: &Pubkey, latency: Duration) {
+        let reinstate_after = self.config.reinstate_after.max(1);
+        let entry = self.validators.entry(*validator).or_default();
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+
+        entry.response_rate = ewma(entry.response_rate, 1.0);
+        entry.latency_ms = if entry.latency_ms == 0.0 { latency_ms } else { ewma(entry.latency_ms, latency_ms) };
+        entry.consecutive_failures = 0;
+
+        if entry.state == LivenessState::Quarantined {
+            entry.probe_successes += 1;
+            if entry.probe_successes >= reinstate_after {
+                log::info!("Reinstating validator {} after {} successful probes", validator, entry.probe_successes);
+                entry.state = LivenessState::Active;
+                entry.probe_successes = 0;
+            }
+        }
+    }
+
+    pub fn record_failure(&mut self, validator: &Pubkey) {
+        let quarantine_after = self.config.quarantine_after.max(1);
+        let entry = self.validators.entry(*validator).or_default();
+
+        entry.response_rate = ewma(entry.response_rate, 0.0);
+        entry.consecutive_failures += 1;
+        entry.probe_successes = 0;
+
+        if entry.state == LivenessState::Active && entry.consecutive_failures >= quarantine_after {
+            log::warn!("Quarantining validator {} after {} consecutive failures", validator, entry.consecutive_failures);
+            entry.state = LivenessState::Quarantined;
+        }
+    }
+
+    pub fn is_quarantined(&self, validator: &Pubkey) -> bool {
+        self.validators.get(validator)
+            .map_or(false, |entry| entry.state == LivenessState::Quarantined)
+    }
+
+    pub fn health(&self, validator: &Pubkey) -> ValidatorHealth {
+        let entry = self.validators.get(validator).cloned().unwrap_or_default();
+        ValidatorHealth {
+            pubkey: *validator,
+            state: entry.state,
+            response_rate: entry.response_rate,
+            latency_ms: entry.latency_ms,
+            consecutive_failures: entry.consecutive_failures,
+        }
+    }
+}
+
+fn ewma(previous: f64, sample: f64) -> f64 {
+    EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * previous
+}
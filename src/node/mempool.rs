@@ -0,0 +1,517 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{hash::Hash, pubkey::Pubkey};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use thiserror::Error;
+
+use super::config::DEFAULT_CHAIN_ID;
+use super::metrics::{self, MetricsSource};
+use super::state::State;
+use super::transaction::Transaction;
+use super::validation::{BatchLedger, ValidationError};
+use crate::utils::DADBSAddress;
+
+pub const DEFAULT_MEMPOOL_CAPACITY: usize = 10_000;
+pub const DEFAULT_PERSIST_INTERVAL_MS: u64 = 30_000;
+/// An hour: older transactions are not reloaded after a restart.
+pub const DEFAULT_MAX_RELOAD_AGE_MS: u64 = 3_600_000;
+
+/// The `[mempool]` section: pending transactions kept across restarts.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolConfig {
+    /// Save pending transactions to storage, and reload them at startup.
+    #[serde(default = "default_persist")]
+    pub persist: bool,
+    /// How often they are saved while running; they are also saved at
+    /// shutdown.
+    #[serde(default = "default_persist_interval_ms")]
+    pub persist_interval_ms: u64,
+    /// Most transactions saved, those first in line for a block first.
+    #[serde(default = "default_max_persisted")]
+    pub max_persisted: usize,
+    /// Transactions signed longer ago than this are not reloaded.
+    #[serde(default = "default_max_reload_age_ms")]
+    pub max_reload_age_ms: u64,
+}
+
+fn default_persist() -> bool {
+    true
+}
+
+fn default_persist_interval_ms() -> u64 {
+    DEFAULT_PERSIST_INTERVAL_MS
+}
+
+fn default_max_persisted() -> usize {
+    DEFAULT_MEMPOOL_CAPACITY
+}
+
+fn default_max_reload_age_ms() -> u64 {
+    DEFAULT_MAX_RELOAD_AGE_MS
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            persist: true,
+            persist_interval_ms: DEFAULT_PERSIST_INTERVAL_MS,
+            max_persisted: DEFAULT_MEMPOOL_CAPACITY,
+            max_reload_age_ms: DEFAULT_MAX_RELOAD_AGE_MS,
+        }
+    }
+}
+
+impl MempoolConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.persist_interval_ms < 1000 {
+            return Err("mempool.persist_interval_ms must be at least 1000".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What reloading the transactions saved by a previous run came to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Reloaded {
+    pub restored: usize,
+    /// No longer admissible against the current state, e.g. their nonce
+    /// was used meanwhile.
+    pub invalid: usize,
+    pub expired: usize,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    #[error("Transaction is for chain {actual}, this node is on {expected}")]
+    WrongChain { expected: String, actual: String },
+    #[error("Fee {fee} is below the minimum of {min_fee}")]
+    Underpriced { fee: u64, min_fee: u64 },
+    #[error("Transaction {0} is already pending")]
+    Duplicate(Hash),
+    #[error("Replacement for nonce {nonce} must pay more than {existing_fee}")]
+    ReplacementUnderpriced { nonce: u64, existing_fee: u64 },
+    #[error("Mempool is full ({0} transactions)")]
+    Full(usize),
+    #[error("Transaction conflicts with account state: {0}")]
+    Conflict(#[from] ValidationError),
+    #[error("Transaction rejected by memo moderation: {0}")]
+    Moderated(String),
+}
+
+/// Pending transactions, grouped per sender in nonce order.
+#[derive(Debug)]
+pub struct Mempool {
+    chain_id: String,
+    min_fee: u64,
+    capacity: usize,
+    by_sender: HashMap<Pubkey, BTreeMap<u64, Transaction>>,
+    len: usize,
+}
+
+/// The lowest-nonce transaction of one sender, ordered by fee rate.
+struct Head(Transaction);
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp_fee_rate(&other.0)
+            .then_with(|| other.0.hash().cmp(&self.0.hash()))
+    }
+}
+
+impl Mempool {
+    pub fn new(min_fee: u64, capacity: usize) -> Self {
+        Mempool {
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            min_fee,
+            capacity,
+            by_sender: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Admits only transactions signed for `chain_id`.
+    pub fn with_chain_id(mut self, chain_id: impl Into<String>) -> Self {
+        self.chain_id = chain_id.into();
+        self
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    pub fn min_fee(&self) -> u64 {
+        self.min_fee
+    }
+
+    /// Applies to transactions admitted from now on; pending ones are
+    /// judged again when a block is built.
+    pub fn set_min_fee(&mut self, min_fee: u64) {
+        self.min_fee = min_fee;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Encoded size of every pending transaction. Walks the whole pool.
+    pub fn bytes(&self) -> usize {
+        self.by_sender.values().flat_map(|queue| queue.values()).map(Transaction::size).sum()
+    }
+
+    /// Drops every pending transaction, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        self.by_sender.clear();
+        std::mem::take(&mut self.len)
+    }
+
+    /// Admits a transaction. A pending transaction with the same sender and
+    /// nonce is replaced only by one paying a strictly higher fee.
+    pub fn insert(&mut self, transaction: Transaction) -> Result<(), MempoolError> {
+        self.check_slot(&transaction)?;
+        let queue = self.by_sender.entry(transaction.sender).or_default();
+        if queue.insert(transaction.nonce, transaction).is_none() {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    fn check_slot(&self, transaction: &Transaction) -> Result<(), MempoolError> {
+        if transaction.chain_id != self.chain_id {
+            return Err(MempoolError::WrongChain {
+                expected: self.chain_id.clone(),
+                actual: transaction.chain_id.clone(),
+            });
+        }
+        if transaction.fee < self.min_fee {
+            return Err(MempoolError::Underpriced { fee: transaction.fee, min_fee: self.min_fee });
+        }
+        match self.by_sender.get(&transaction.sender).and_then(|queue| queue.get(&transaction.nonce)) {
+            Some(existing) if existing.hash() == transaction.hash() => {
+                Err(MempoolError::Duplicate(transaction.hash()))
+            }
+            Some(existing) if existing.fee >= transaction.fee => {
+                Err(MempoolError::ReplacementUnderpriced { nonce: transaction.nonce, existing_fee: existing.fee })
+            }
+            Some(_) => Ok(()),
+            None if self.len >= self.capacity => Err(MempoolError::Full(self.capacity)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks `transaction` against `state` together with the sender's
+    /// pending transactions that precede it.
+    fn check_ledger(&self, transaction: &Transaction, state: &State) -> Result<(), MempoolError> {
+        let mut ledger = BatchLedger::new(state);
+        if let Some(queue) = self.by_sender.get(&transaction.sender) {
+            for earlier in queue.range(..transaction.nonce).map(|(_, t)| t) {
+                if ledger.admit(earlier).is_err() {
+                    break;
+                }
+            }
+        }
+        ledger.admit(transaction)?;
+        Ok(())
+    }
+
+    /// Admits a transaction after checking it against `state` together with
+    /// the sender's pending transactions that precede it. This is optimistic:
+    /// block building re-checks against the state at that time.
+    pub fn admit(&mut self, transaction: Transaction, state: &State) -> Result<(), MempoolError> {
+        self.check_ledger(&transaction, state)?;
+        self.insert(transaction)
+    }
+
+    /// Whether `admit` would accept `transaction`, without admitting it.
+    pub fn check(&self, transaction: &Transaction, state: &State) -> Result<(), MempoolError> {
+        self.check_ledger(transaction, state)?;
+        self.check_slot(transaction)
+    }
+
+    /// The nonce `sender`'s next transaction should use: its account's,
+    /// moved past the unbroken run it has pending from there.
+    pub fn next_nonce(&self, sender: &Pubkey, state: &State) -> u64 {
+        let mut nonce = state.account(&DADBSAddress::from_pubkey(sender)).nonce;
+        if let Some(queue) = self.by_sender.get(sender) {
+            while queue.contains_key(&nonce) {
+                nonce += 1;
+            }
+        }
+        nonce
+    }
+
+    /// Removes and returns up to `max` transactions, highest fee-per-byte
+    /// first. A sender's transactions are always returned in nonce order, so
+    /// a high-fee transaction waits behind its sender's earlier nonces.
+    pub fn take_batch(&mut self, max: usize) -> Vec<Transaction> {
+        self.take_fitting(max, |_| true)
+    }
+
+    /// Like `take_batch`, but passes over transactions `fits` refuses, e.g.
+    /// because they would not fit what is left of a block's budget. `fits`
+    /// counts a transaction in when it accepts it. A refused transaction
+    /// stays pending, and so do its sender's later nonces.
+    pub fn take_fitting(&mut self, max: usize, mut fits: impl FnMut(&Transaction) -> bool) -> Vec<Transaction> {
+        let mut heads: BinaryHeap<Head> = self.by_sender.values_mut()
+            .filter_map(|queue| queue.pop_first().map(|(_, transaction)| Head(transaction)))
+            .collect();
+
+        let mut batch = Vec::with_capacity(max.min(self.len));
+        let mut refused = Vec::new();
+        while batch.len() < max {
+            let Head(transaction) = match heads.pop() {
+                Some(head) => head,
+                None => break,
+            };
+            if !fits(&transaction) {
+                refused.push(Head(transaction));
+                continue;
+            }
+            if let Some((_, next)) = self.by_sender.get_mut(&transaction.sender).and_then(|q| q.pop_first()) {
+                heads.push(Head(next));
+            }
+            batch.push(transaction);
+        }
+
+        // Heads that did not make the batch go back to their queues.
+        for Head(transaction) in heads.into_iter().chain(refused) {
+            self.by_sender.entry(transaction.sender).or_default().insert(transaction.nonce, transaction);
+        }
+        self.by_sender.retain(|_, queue| !queue.is_empty());
+        self.len -= batch.len();
+        batch
+    }
+
+    /// Up to `max` pending transactions, in the order `take_batch` would
+    /// return them, leaving the pool as it is.
+    pub fn snapshot(&self, max: usize) -> Vec<Transaction> {
+        let mut copy = Mempool {
+            min_fee: self.min_fee,
+            capacity: self.capacity,
+            by_sender: self.by_sender.clone(),
+            len: self.len,
+        };
+        copy.take_batch(max)
+    }
+
+    /// Admits transactions saved by a previous run, each checked again
+    /// against `state`. Those signed more than `max_age_ms` before `now_ms`
+    /// are skipped.
+    pub fn reload(&mut self, mut saved: Vec<Transaction>, state: &State, now_ms: i64, max_age_ms: u64) -> Reloaded {
+        // Each sender's in nonce order, so every one is checked after those
+        // it spends behind.
+        saved.sort_by_key(|transaction| (transaction.sender, transaction.nonce));
+        let mut reloaded = Reloaded::default();
+        for transaction in saved {
+            if now_ms.saturating_sub(transaction.timestamp) > max_age_ms as i64 {
+                reloaded.expired += 1;
+                continue;
+            }
+            match self.admit(transaction, state) {
+                Ok(()) => reloaded.restored += 1,
+                // Submitted again since we started.
+                Err(MempoolError::Duplicate(_)) => {}
+                Err(_) => reloaded.invalid += 1,
+            }
+        }
+        reloaded
+    }
+
+    /// Drops transactions that were included in a finalized block.
+    pub fn remove_included(&mut self, transactions: &[Transaction]) {
+        for transaction in transactions {
+            if let Some(queue) = self.by_sender.get_mut(&transaction.sender) {
+                let stale: Vec<u64> = queue.range(..=transaction.nonce).map(|(nonce, _)| *nonce).collect();
+                for nonce in stale {
+                    queue.remove(&nonce);
+                    self.len -= 1;
+                }
+                if queue.is_empty() {
+                    self.by_sender.remove(&transaction.sender);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(0, DEFAULT_MEMPOOL_CAPACITY)
+    }
+}
+
+impl MetricsSource for Mutex<Mempool> {
+    fn render_metrics(&self, out: &mut String) {
+        let (len, bytes) = {
+            let pool = self.lock();
+            (pool.len(), pool.bytes())
+        };
+        metrics::write_gauge(out, "dadbs_mempool_transactions", "Pending transactions", len as f64);
+        metrics::write_gauge(out, "dadbs_mempool_bytes", "Encoded size of pending transactions", bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signature::{Keypair, Signer};
+
+    fn tx(keypair: &Keypair, fee: u64, nonce: u64) -> Transaction {
+        Transaction::new_signed(keypair, Pubkey::new_unique(), 10, fee, nonce, 0)
+    }
+
+    #[test]
+    fn test_orders_by_fee_rate() {
+        let mut pool = Mempool::new(1, 100);
+        let (a, b, c) = (Keypair::new(), Keypair::new(), Keypair::new());
+        pool.insert(tx(&a, 5, 0)).unwrap();
+        pool.insert(tx(&b, 50, 0)).unwrap();
+        pool.insert(tx(&c, 20, 0)).unwrap();
+
+        let fees: Vec<u64> = pool.take_batch(10).iter().map(|t| t.fee).collect();
+        assert_eq!(fees, vec![50, 20, 5]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_nonce_dependencies_respected() {
+        let mut pool = Mempool::new(1, 100);
+        let (a, b) = (Keypair::new(), Keypair::new());
+        pool.insert(tx(&a, 100, 1)).unwrap();
+        pool.insert(tx(&a, 2, 0)).unwrap();
+        pool.insert(tx(&b, 10, 0)).unwrap();
+        pool.insert(tx(&b, 1, 1)).unwrap();
+
+        let batch: Vec<(Pubkey, u64)> = pool.take_batch(10).iter().map(|t| (t.sender, t.nonce)).collect();
+        assert_eq!(batch, vec![(b.pubkey(), 0), (a.pubkey(), 0), (a.pubkey(), 1), (b.pubkey(), 1)]);
+    }
+
+    #[test]
+    fn test_partial_batch_keeps_remainder() {
+        let mut pool = Mempool::new(1, 100);
+        let (a, b) = (Keypair::new(), Keypair::new());
+        pool.insert(tx(&a, 30, 0)).unwrap();
+        pool.insert(tx(&a, 30, 1)).unwrap();
+        pool.insert(tx(&b, 10, 0)).unwrap();
+
+        let first = pool.take_batch(2);
+        assert_eq!(first.iter().map(|t| t.nonce).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.take_batch(2)[0].sender, b.pubkey());
+    }
+
+    #[test]
+    fn test_fitting_passes_over_what_does_not_fit() {
+        use crate::node::params::ProtocolParams;
+
+        let mut pool = Mempool::new(1, 100);
+        let (a, b, c) = (Keypair::new(), Keypair::new(), Keypair::new());
+        let mut large = tx(&a, 1_000, 0);
+        large.payload = vec![0; 400];
+        large.signature = a.sign_message(&large.signing_bytes());
+        pool.insert(large).unwrap();
+        pool.insert(tx(&a, 1_000, 1)).unwrap();
+        pool.insert(tx(&b, 50, 0)).unwrap();
+        pool.insert(tx(&c, 20, 0)).unwrap();
+
+        // Room for two small transactions, so the large one and a's next wait.
+        let small = tx(&b, 50, 0).size() as u64;
+        let mut budget = ProtocolParams { max_block_bytes: 2 * small, ..ProtocolParams::default() }.block_budget();
+        let batch = pool.take_fitting(10, |transaction| budget.try_add(1, transaction).is_ok());
+        assert_eq!(batch.iter().map(|t| t.sender).collect::<Vec<_>>(), vec![b.pubkey(), c.pubkey()]);
+        assert_eq!((budget.txs(), budget.bytes()), (2, 2 * small));
+        assert!(!budget.has_room());
+        assert_eq!(pool.take_batch(10).iter().map(|t| t.nonce).collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_admission_rules() {
+        let mut pool = Mempool::new(10, 2);
+        let a = Keypair::new();
+        assert_eq!(pool.insert(tx(&a, 9, 0)), Err(MempoolError::Underpriced { fee: 9, min_fee: 10 }));
+
+        let original = tx(&a, 10, 0);
+        pool.insert(original.clone()).unwrap();
+        assert_eq!(pool.insert(original.clone()), Err(MempoolError::Duplicate(original.hash())));
+        assert_eq!(
+            pool.insert(tx(&a, 10, 0)),
+            Err(MempoolError::ReplacementUnderpriced { nonce: 0, existing_fee: 10 })
+        );
+        pool.insert(tx(&a, 11, 0)).unwrap();
+        assert_eq!(pool.len(), 1);
+
+        pool.insert(tx(&a, 10, 1)).unwrap();
+        assert_eq!(pool.insert(tx(&Keypair::new(), 50, 0)), Err(MempoolError::Full(2)));
+
+        pool.remove_included(&[tx(&a, 11, 0)]);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn test_admission_checks_pending_spends() {
+        let a = Keypair::new();
+        let state = State::in_memory(vec![(DADBSAddress::from_pubkey(&a.pubkey()), 50)]);
+        let mut pool = Mempool::new(1, 100);
+
+        pool.admit(Transaction::new_signed(&a, Pubkey::new_unique(), 30, 5, 0, 0), &state).unwrap();
+        assert!(matches!(
+            pool.admit(Transaction::new_signed(&a, Pubkey::new_unique(), 30, 5, 1, 0), &state),
+            Err(MempoolError::Conflict(ValidationError::InsufficientBalance { available: 15, .. }))
+        ));
+        assert!(matches!(
+            pool.admit(Transaction::new_signed(&a, Pubkey::new_unique(), 1, 5, 3, 0), &state),
+            Err(MempoolError::Conflict(ValidationError::NonceConflict { expected: 1, actual: 3, .. }))
+        ));
+        let next = Transaction::new_signed(&a, Pubkey::new_unique(), 10, 5, 1, 0);
+        pool.check(&next, &state).unwrap();
+        assert_eq!(pool.len(), 1);
+        pool.admit(next.clone(), &state).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.check(&next, &state), Err(MempoolError::Duplicate(next.hash())));
+    }
+
+    #[test]
+    fn test_reload_checks_saved_transactions_again() {
+        let (a, b) = (Keypair::new(), Keypair::new());
+        let now = 10_000_000;
+        let signed = |keypair: &Keypair, nonce: u64, timestamp: i64| {
+            Transaction::new_signed(keypair, Pubkey::new_unique(), 10, 5, nonce, timestamp)
+        };
+        let state = State::in_memory(vec![
+            (DADBSAddress::from_pubkey(&a.pubkey()), 100),
+            (DADBSAddress::from_pubkey(&b.pubkey()), 100),
+        ]);
+        let saved = vec![signed(&a, 1, now), signed(&a, 0, now), signed(&a, 2, now), signed(&b, 0, now - 5_000)];
+
+        let mut pool = Mempool::new(1, 100);
+        let reloaded = pool.reload(saved.clone(), &state, now, 60_000);
+        assert_eq!(reloaded, Reloaded { restored: 4, invalid: 0, expired: 0 });
+        assert_eq!(pool.snapshot(10).len(), 4);
+        assert_eq!(pool.len(), 4);
+
+        // Without a's nonce 0 its later ones cannot apply; b's is past the
+        // age limit.
+        let mut pool = Mempool::new(1, 100);
+        let reloaded = pool.reload(saved[2..].to_vec(), &state, now, 4_000);
+        assert_eq!(reloaded, Reloaded { restored: 0, invalid: 1, expired: 1 });
+        assert!(pool.is_empty());
+    }
+}
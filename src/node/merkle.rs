@@ -0,0 +1,130 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::{hashv, Hash};
+
+// Leaves and inner nodes hash under different prefixes, so no inner node
+// can be passed off as a leaf.
+const LEAF: &[u8] = &[0];
+const NODE: &[u8] = &[1];
+
+fn leaf(hash: &Hash) -> Hash {
+    hashv(&[LEAF, hash.as_ref()])
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    hashv(&[NODE, left.as_ref(), right.as_ref()])
+}
+
+/// The level above `level`. An odd last node is carried up unpaired rather
+/// than paired with itself, so no two leaf lists share a root.
+fn parents(level: &[Hash]) -> Vec<Hash> {
+    level.chunks(2).map(|pair| if let [left, right] = pair { node(left, right) } else { pair[0] }).collect()
+}
+
+/// Root of a binary Merkle tree over `leaves`; the default hash when empty.
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::default();
+    }
+    let mut level: Vec<Hash> = leaves.iter().map(leaf).collect();
+    while level.len() > 1 {
+        level = parents(&level);
+    }
+    level[0]
+}
+
+/// The siblings on the path from one leaf to the root, lowest first.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u32,
+    /// Leaves in the tree; needed to tell where a node went unpaired.
+    pub leaves: u32,
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// Proves `leaves[index]`, or `None` if there is no such leaf.
+    pub fn new(leaves: &[Hash], index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut level: Vec<Hash> = leaves.iter().map(leaf).collect();
+        let mut position = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            level = parents(&level);
+            position /= 2;
+        }
+        Some(MerkleProof { index: index as u32, leaves: leaves.len() as u32, siblings })
+    }
+
+    /// Whether the proof leads from `hash` to `root`.
+    pub fn verify(&self, root: &Hash, hash: &Hash) -> bool {
+        if self.index >= self.leaves {
+            return false;
+        }
+        let (mut position, mut width) = (self.index as usize, self.leaves as usize);
+        let mut siblings = self.siblings.iter();
+        let mut current = leaf(hash);
+        while width > 1 {
+            if (position ^ 1) < width {
+                let sibling = match siblings.next() {
+                    Some(sibling) => sibling,
+                    None => return false,
+                };
+                current = if position % 2 == 0 { node(&current, sibling) } else { node(sibling, &current) };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none() && current == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_leaf_proves_against_the_root() {
+        for count in 1..=9 {
+            let leaves: Vec<Hash> = (0..count).map(|_| Hash::new_unique()).collect();
+            let tree = root(&leaves);
+            for (index, hash) in leaves.iter().enumerate() {
+                let proof = MerkleProof::new(&leaves, index).unwrap();
+                assert!(proof.verify(&tree, hash), "leaf {} of {}", index, count);
+                assert!(!proof.verify(&tree, &Hash::new_unique()));
+            }
+            assert!(MerkleProof::new(&leaves, count).is_none());
+        }
+        assert_eq!(root(&[]), Hash::default());
+    }
+
+    #[test]
+    fn test_empty_single_and_odd_trees() {
+        // An empty block proves nothing.
+        assert!(MerkleProof::new(&[], 0).is_none());
+
+        // A lone leaf is its own root, proved without siblings.
+        let only = Hash::new_unique();
+        assert_eq!(root(&[only]), leaf(&only));
+        assert!(MerkleProof::new(&[only], 0).unwrap().siblings.is_empty());
+
+        // The odd last leaf is carried up, not paired with a copy of itself.
+        let leaves: Vec<Hash> = (0..3).map(|_| Hash::new_unique()).collect();
+        let carried = node(&node(&leaf(&leaves[0]), &leaf(&leaves[1])), &leaf(&leaves[2]));
+        assert_eq!(root(&leaves), carried);
+        let duplicated = [leaves.clone(), vec![leaves[2]]].concat();
+        assert_ne!(root(&duplicated), carried);
+        let proof = MerkleProof::new(&leaves, 2).unwrap();
+        assert_eq!(proof.siblings, vec![node(&leaf(&leaves[0]), &leaf(&leaves[1]))]);
+
+        // The same siblings claimed for a fuller tree do not verify.
+        let padded = MerkleProof { leaves: 4, ..proof.clone() };
+        assert!(proof.verify(&carried, &leaves[2]));
+        assert!(!padded.verify(&carried, &leaves[2]));
+    }
+}
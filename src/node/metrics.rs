@@ -0,0 +1,288 @@
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Json};
+use axum::routing::get;
+use axum::Router;
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use super::health::{HealthRegistry, HealthStatus};
+
+pub const DEFAULT_METRICS_LISTEN: &str = "127.0.0.1:9615";
+
+pub const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn latency() -> Self {
+        Self::new(LATENCY_BUCKETS)
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.sum / self.count as f64 }
+    }
+
+    /// Cumulative counts per upper bound, as Prometheus expects.
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds.iter().zip(&self.counts)
+            .map(|(bound, count)| {
+                total += count;
+                (*bound, total)
+            })
+            .collect()
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let inner: Vec<String> = labels.iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("{{{}}}", inner.join(","))
+}
+
+pub fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn write_sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+}
+
+pub fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    write_header(out, name, help, "counter");
+    write_sample(out, name, &[], value as f64);
+}
+
+pub fn write_gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    write_header(out, name, help, "gauge");
+    write_sample(out, name, &[], value);
+}
+
+pub fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    write_header(out, name, help, "histogram");
+    write_histogram_samples(out, name, &[], histogram);
+}
+
+pub fn write_histogram_samples(out: &mut String, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+    let bucket = format!("{}_bucket", name);
+    for (bound, count) in histogram.cumulative() {
+        let le = bound.to_string();
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", &le));
+        write_sample(out, &bucket, &bucket_labels, count as f64);
+    }
+    let mut inf_labels = labels.to_vec();
+    inf_labels.push(("le", "+Inf"));
+    write_sample(out, &bucket, &inf_labels, histogram.count() as f64);
+    write_sample(out, &format!("{}_sum", name), labels, histogram.sum());
+    write_sample(out, &format!("{}_count", name), labels, histogram.count() as f64);
+}
+
+/// A subsystem that contributes series to a scrape.
+pub trait MetricsSource: Send + Sync {
+    /// Appends this source's series, in Prometheus text format, to `out`.
+    fn render_metrics(&self, out: &mut String);
+}
+
+/// The sources one node exposes on `/metrics`. Each node owns its registry,
+/// so tests can run several side by side.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    sources: RwLock<Vec<Arc<dyn MetricsSource>>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, source: Arc<dyn MetricsSource>) {
+        self.sources.write().push(source);
+    }
+
+    /// Every registered source, in registration order.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for source in self.sources.read().iter() {
+            source.render_metrics(&mut out);
+        }
+        out
+    }
+}
+
+/// Standard `process_*` series. Memory, CPU, thread and file descriptor
+/// figures come from `/proc` and are left out where it is unavailable.
+pub struct ProcessMetrics {
+    start_time: f64,
+}
+
+/// Clock ticks per second in `/proc/self/stat`; fixed at 100 on Linux.
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+impl ProcessMetrics {
+    pub fn new() -> Self {
+        let start_time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64());
+        ProcessMetrics { start_time }
+    }
+
+    /// Resident memory of this process, where `/proc` has it.
+    pub fn resident_memory_bytes() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        Self::status_bytes(&status, "VmRSS:").map(|bytes| bytes as u64)
+    }
+
+    /// A `kB` field of `/proc/self/status`, in bytes.
+    fn status_bytes(status: &str, field: &str) -> Option<f64> {
+        let line = status.lines().find(|line| line.starts_with(field))?;
+        let kb: f64 = line[field.len()..].split_whitespace().next()?.parse().ok()?;
+        Some(kb * 1024.0)
+    }
+
+    fn cpu_seconds() -> Option<f64> {
+        let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+        // Fields after the parenthesised command name; utime and stime are 14th and 15th overall.
+        let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+        let utime: f64 = fields.get(11)?.parse().ok()?;
+        let stime: f64 = fields.get(12)?.parse().ok()?;
+        Some((utime + stime) / CLOCK_TICKS_PER_SEC)
+    }
+}
+
+impl Default for ProcessMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetricsSource for ProcessMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        write_gauge(out, "process_start_time_seconds", "Start time of the process since the Unix epoch", self.start_time);
+        if let Some(cpu) = Self::cpu_seconds() {
+            write_header(out, "process_cpu_seconds_total", "User and system CPU time spent", "counter");
+            write_sample(out, "process_cpu_seconds_total", &[], cpu);
+        }
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            if let Some(rss) = Self::status_bytes(&status, "VmRSS:") {
+                write_gauge(out, "process_resident_memory_bytes", "Resident memory size", rss);
+            }
+            if let Some(vsize) = Self::status_bytes(&status, "VmSize:") {
+                write_gauge(out, "process_virtual_memory_bytes", "Virtual memory size", vsize);
+            }
+            let threads = status.lines()
+                .find_map(|line| line.strip_prefix("Threads:"))
+                .and_then(|count| count.trim().parse::<f64>().ok());
+            if let Some(threads) = threads {
+                write_gauge(out, "process_threads", "OS threads in use", threads);
+            }
+        }
+        if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+            write_gauge(out, "process_open_fds", "Open file descriptors", fds.count() as f64);
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MetricsConfig {
+    /// Address `/metrics` is served on.
+    #[serde(default = "default_metrics_listen")]
+    pub listen: String,
+}
+
+fn default_metrics_listen() -> String {
+    DEFAULT_METRICS_LISTEN.to_string()
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { listen: default_metrics_listen() }
+    }
+}
+
+/// Serves a registry's Prometheus text exposition at `GET /metrics`, plus
+/// `/health/live` and `/health/ready` probes answering 200 or 503.
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    cancel: CancellationToken,
+}
+
+impl MetricsServer {
+    pub async fn bind(
+        config: &MetricsConfig,
+        registry: Arc<MetricsRegistry>,
+        health: Arc<HealthRegistry>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&config.listen).await?;
+        let local_addr = listener.local_addr()?;
+        let app = Router::new()
+            .route("/metrics", get(move || async move {
+                ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], registry.render())
+            }))
+            .route("/health/live", get(|| async { Json(serde_json::json!({ "status": HealthStatus::Healthy })) }))
+            .route("/health/ready", get(move || async move {
+                let report = health.readiness().await;
+                let code = if report.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+                (code, Json(report)).into_response()
+            }));
+        let cancel = CancellationToken::new();
+        let shutdown = cancel.clone();
+        tokio::spawn(async move {
+            let shutdown = async move { shutdown.cancelled().await };
+            if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(shutdown).await {
+                warn!("Metrics server stopped: {}", e);
+            }
+        });
+        info!("Metrics listening on {}", local_addr);
+        Ok(MetricsServer { local_addr, cancel })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown(&self) {
+        self.cancel.cancel();
+    }
+}
@@ -0,0 +1,161 @@
+pub mod admin;
+pub mod api_keys;
+pub mod backup;
+pub mod bandwidth;
+pub mod block;
+pub mod bloom;
+pub mod chain_stats;
+pub mod compression;
+pub mod config;
+pub mod consensus;
+pub mod consensus_metrics;
+pub mod control;
+pub mod crypto;
+pub mod deposit_watcher;
+pub mod dns_seed;
+pub mod evidence;
+pub mod faucet;
+pub mod fork_choice;
+pub mod genesis;
+pub mod gossip;
+pub mod grpc;
+pub mod handover;
+pub mod health;
+pub mod heartbeat;
+pub mod hooks;
+pub mod ingest;
+pub mod journal;
+pub mod keystore;
+pub mod light;
+pub mod liveness;
+pub mod mempool;
+pub mod merkle;
+pub mod metrics;
+pub mod moderation;
+pub mod mux;
+pub mod nat;
+pub mod network;
+pub mod pagination;
+pub mod params;
+pub mod peer_score;
+pub mod peer_store;
+pub mod proposal;
+pub mod quorum;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod replay;
+pub mod rpc;
+pub mod runtime;
+pub mod shutdown;
+pub mod sig_verify;
+pub mod signer;
+pub mod snapshot;
+pub mod state;
+pub mod storage;
+pub mod subscriptions;
+pub mod sync;
+pub mod telemetry;
+pub mod transaction;
+pub mod tx_trace;
+pub mod validation;
+pub mod validator;
+pub mod validator_oracle;
+pub mod vote;
+pub mod wal;
+pub mod webhooks;
+
+pub use admin::{AdminApi, AdminConfig, AdminToken, BackupParams, BackupStatus, ReindexParams};
+pub use api_keys::{ApiKeyError, ApiKeyLimits, ApiKeyRecord, ApiKeyUsage, ApiKeys, ApiKeysConfig};
+pub use backup::{BackupError, BackupFile, BackupManifest, BackupProgress, BackupStage};
+pub use bandwidth::{BandwidthConfig, MessageCategory, TrafficStats};
+pub use block::{Block, BlockHeader, BLOCK_VERSION};
+pub use bloom::{AddressBloom, DEFAULT_ADDRESS_BLOOM_FP_PPM};
+pub use chain_stats::{AddressStats, DayStats, StakeBucket};
+pub use compression::{Codec, CompressionError};
+pub use config::{NodeConfig, ConfigOverrides, ConfigProfile, DeviceSpec, LLMConfig, ModelFormat, Pooling, SlashableStake, SlashingConfig, TemplateSpec, ConfigError};
+pub use consensus::{driver::ConsensusDriver, ConsensusManager};
+pub use consensus_metrics::{ConsensusMetrics, ConsensusMetricsSnapshot};
+pub use control::{ConsensusControl, ControlError, HaltReason, HaltStatus};
+pub use crypto::{CryptoError, Ed25519Scheme, SchemeKind, SignatureScheme};
+pub use deposit_watcher::{
+    BridgeSigner, Commitment, Credit, Deposit, DepositCursor, DepositError, DepositSource, DepositWatcher,
+    DepositWatcherConfig, DepositWatcherMetrics, DepositWatcherSnapshot, KeystoreSigner, Reconciliation, RpcDepositSource,
+    SignatureStatus, SolanaTransaction,
+};
+pub use dns_seed::{DnsSeed, DnsSeedConfig, DnsSeedError, SeedPeer, SeedResolution, SeedResolver, SystemResolver};
+pub use evidence::{EquivocationEvidence, EvidencePool, EvidenceSubmitter, StakeSlashed};
+#[cfg(feature = "faucet")]
+pub use faucet::Faucet;
+pub use faucet::{FaucetConfig, FaucetError, FaucetGrant, FaucetLedger, FaucetUsage};
+pub use fork_choice::{BlockTree, ChainUpdate, ForkChoiceError};
+pub use genesis::{Genesis, GenesisAccount, GenesisError};
+pub use gossip::{GossipConfig, GossipStats, SeenCache};
+pub use grpc::GrpcConfig;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcServer;
+pub use handover::{HandoverError, HandoverRegistry, KeyHandover};
+pub use health::{
+    ClockCheck, ComponentHealth, ConsensusCheck, HealthCheck, HealthConfig, HealthRegistry, HealthReport, HealthStatus,
+    LlmCheck, P2pCheck, PeersCheck, StorageCheck,
+};
+pub use keystore::{KeyInfo, Keystore, KeystoreConfig, KeystoreError};
+pub use light::{LightClientError, SignedHeader, TrustedState};
+pub use heartbeat::{Heartbeat, HeartbeatTransport, NetworkView, PeerLiveness};
+pub use hooks::{
+    BlockJsonLines, HookEvent, HookRegistry, HookRunner, HookStats, HookStatsSnapshot, Hooks, HooksConfig, NodeHooks,
+};
+pub use ingest::{IngestConfig, IngestPipeline, IngestStats, Ingested};
+pub use journal::{Journal, JournalConfig, JournalEntry, JournalError, JournalKind};
+pub use reconnect::{BackoffConfig, BackoffStatus, RetryState};
+pub use replay::{Divergence, ReplayError, ReplayReport, Replayer, Transition};
+pub use rate_limit::{BucketConfig, LimitsConfig, RateLimited, RateLimiter};
+pub use quorum::{QuorumPolicy, QuorumConfig, ThresholdPolicy};
+pub use mempool::{Mempool, MempoolConfig, MempoolError, Reloaded};
+pub use merkle::MerkleProof;
+pub use metrics::{MetricsConfig, MetricsRegistry, MetricsServer, MetricsSource, ProcessMetrics};
+pub use moderation::{FallbackPolicy, MemoClassifier, MemoModerator, ModerationConfig};
+pub use mux::Lane;
+pub use liveness::{LivenessTracker, ValidatorHealth};
+pub use nat::{ExternalAddress, NatConfig, NatError, PortMapper, PortMapping};
+pub use network::{
+    ChallengeResponse, GradientCompression, GradientMessage, GradientPrecision, IdentityProof, InferenceChallenge, LlmCapability, LlmPeerFilter, NetMessage, Network,
+    NetworkConfig, NetworkError, PeerId, PeerInfo,
+};
+pub use pagination::{CursorError, Direction, Page};
+pub use params::{ParamChange, ParamsError, ParamsSchedule, ProtocolParams};
+pub use peer_score::{Offense, PeerScore, ScoreConfig};
+pub use peer_store::{Ban, PeerRecord, PeerStore, PeerStoreError};
+pub use proposal::Proposal;
+pub use rpc::{RpcConfig, RpcContext, RpcError, RpcMetrics, RpcServer};
+pub use runtime::{Node, NodeError};
+pub use shutdown::Shutdown;
+pub use sig_verify::{SigVerifyConfig, SigVerifyPool, SignedMessage};
+pub use signer::{
+    LocalSigner, RemoteSigner, RemoteSignerConfig, SignGuard, SignRecord, SignerConnector, SignerEndpoint,
+    SignerError, SignerService, SignerStream, UnreachablePolicy, VoteSigner,
+};
+pub use snapshot::{Snapshot, SnapshotConfig, SnapshotError, SnapshotManifest, SnapshotTrust};
+pub use state::{Event, Receipt, ReceiptStatus, Simulation, State, StateDiff, StateError};
+pub use storage::{
+    AuditRecord, IndexKind, ReindexProgress, ReindexReport, Storage, StorageConfig, StorageError, StoredTransaction,
+};
+pub use subscriptions::ChainEvents;
+pub use sync::{NetworkSync, SyncManager, SyncMessage, SyncError};
+pub use telemetry::{
+    NodeGauges, Telemetry, TelemetryConfig, TelemetryError, TelemetrySample, TelemetrySource, TelemetryStore,
+};
+pub use transaction::{Transaction, TxKind};
+pub use tx_trace::{TxEvent, TxStage, TxTracer};
+pub use validation::{BatchLedger, ValidationError, ValidationResult, ValidationStage};
+pub use validator::{Validator, ValidatorInfo, ValidatorSet, ValidatorSetHistory};
+pub use validator_oracle::{
+    OracleError, RpcStakeSource, StakeSource, ValidatorOracleConfig, ValidatorOracleMetrics, ValidatorOracleSnapshot,
+    ValidatorRecord, ValidatorRegistration, ValidatorSetOracle,
+};
+pub use vote::{AggregateSignature, Vote, VoteSet, VoteOutcome, CommitCertificate, CertificateError};
+#[cfg(feature = "webhooks")]
+pub use webhooks::HttpTransport;
+pub use webhooks::{
+    DeadLetter, DeliveryStats, WebhookEndpoint, WebhookEndpointInfo, WebhookError, WebhookEvent, WebhookEventKind,
+    WebhookFilter, WebhookMetrics, WebhookTransport, Webhooks, WebhooksConfig,
+};
@@ -0,0 +1,282 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::mempool::MempoolError;
+use super::metrics::{self, MetricsSource};
+use super::transaction::{Transaction, TxKind};
+#[cfg(feature = "llm")]
+use crate::llm::{LightLLM, ModelManager};
+
+pub const DEFAULT_MODERATION_THRESHOLD: f32 = 0.7;
+pub const DEFAULT_MODERATION_TIMEOUT_MS: u64 = 2_000;
+
+/// What happens to a memo no classifier could judge.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackPolicy {
+    #[default]
+    Allow,
+    Reject,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ModerationConfig {
+    /// Classify transfer memos before admitting them to the mempool.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Every label the classifier chooses between.
+    #[serde(default = "default_labels")]
+    pub labels: Vec<String>,
+    /// The labels that keep a memo out.
+    #[serde(default = "default_flagged")]
+    pub flagged: Vec<String>,
+    /// A memo is flagged once a flagged label scores at least this.
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Taken when no model is serving, or it fails or is too slow.
+    #[serde(default)]
+    pub fallback: FallbackPolicy,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_labels() -> Vec<String> {
+    vec!["safe".to_string(), "spam".to_string(), "abuse".to_string()]
+}
+
+fn default_flagged() -> Vec<String> {
+    vec!["spam".to_string(), "abuse".to_string()]
+}
+
+fn default_threshold() -> f32 {
+    DEFAULT_MODERATION_THRESHOLD
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_MODERATION_TIMEOUT_MS
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            enabled: false,
+            labels: default_labels(),
+            flagged: default_flagged(),
+            threshold: DEFAULT_MODERATION_THRESHOLD,
+            fallback: FallbackPolicy::default(),
+            timeout_ms: DEFAULT_MODERATION_TIMEOUT_MS,
+        }
+    }
+}
+
+impl ModerationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.labels.len() < 2 {
+            return Err("moderation.labels needs at least two labels".to_string());
+        }
+        if let Some(label) = self.flagged.iter().find(|label| !self.labels.contains(label)) {
+            return Err(format!("moderation.flagged label {} is not in moderation.labels", label));
+        }
+        if !(self.threshold > 0.0 && self.threshold <= 1.0) {
+            return Err("moderation.threshold must be in (0, 1]".to_string());
+        }
+        if self.timeout_ms == 0 {
+            return Err("moderation.timeout_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Scores a text against labels, likeliest first.
+#[async_trait]
+pub trait MemoClassifier: Send + Sync {
+    async fn classify(&self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>, String>;
+}
+
+#[cfg(feature = "llm")]
+#[async_trait]
+impl MemoClassifier for LightLLM {
+    async fn classify(&self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>, String> {
+        LightLLM::classify(self, text, labels).await.map_err(|e| e.to_string())
+    }
+}
+
+/// Whichever model is serving at the time.
+#[cfg(feature = "llm")]
+#[async_trait]
+impl MemoClassifier for ModelManager {
+    async fn classify(&self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>, String> {
+        let serving = self.serving().ok_or_else(|| "no model is serving".to_string())?;
+        serving.model().classify(text, labels).await.map_err(|e| e.to_string())
+    }
+}
+
+/// The text of a transfer's payload, if it is one worth moderating.
+pub fn memo(transaction: &Transaction) -> Option<&str> {
+    if !matches!(transaction.kind, TxKind::Transfer) {
+        return None;
+    }
+    std::str::from_utf8(&transaction.payload).ok().filter(|memo| !memo.trim().is_empty())
+}
+
+/// Keeps transfers whose memo the classifier flags out of the mempool.
+/// Until a classifier is attached, and whenever it fails, memos are
+/// handled by the configured `FallbackPolicy`.
+pub struct MemoModerator {
+    config: ModerationConfig,
+    classifier: RwLock<Option<Arc<dyn MemoClassifier>>>,
+    allowed: AtomicU64,
+    flagged: AtomicU64,
+    unavailable: AtomicU64,
+}
+
+impl MemoModerator {
+    pub fn new(config: ModerationConfig) -> Self {
+        MemoModerator {
+            config,
+            classifier: RwLock::new(None),
+            allowed: AtomicU64::new(0),
+            flagged: AtomicU64::new(0),
+            unavailable: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_classifier(self, classifier: Arc<dyn MemoClassifier>) -> Self {
+        self.classify_with(classifier);
+        self
+    }
+
+    /// Classifies later memos with `classifier`.
+    pub fn classify_with(&self, classifier: Arc<dyn MemoClassifier>) {
+        *self.classifier.write() = Some(classifier);
+    }
+
+    pub fn config(&self) -> &ModerationConfig {
+        &self.config
+    }
+
+    /// Memos let through on their scores.
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    /// Memos kept out on their scores.
+    pub fn flagged(&self) -> u64 {
+        self.flagged.load(Ordering::Relaxed)
+    }
+
+    /// Memos left to the fallback policy.
+    pub fn unavailable(&self) -> u64 {
+        self.unavailable.load(Ordering::Relaxed)
+    }
+
+    /// Whether `transaction` may enter the mempool as far as its memo goes.
+    /// Transactions without a text memo always may.
+    pub async fn check(&self, transaction: &Transaction) -> Result<(), MempoolError> {
+        let Some(memo) = memo(transaction) else {
+            return Ok(());
+        };
+        let hash = transaction.hash();
+        let scores = match self.classify(memo).await {
+            Ok(scores) => scores,
+            Err(reason) => {
+                self.unavailable.fetch_add(1, Ordering::Relaxed);
+                warn!("Cannot moderate memo of {}, applying {:?}: {}", hash, self.config.fallback, reason);
+                return match self.config.fallback {
+                    FallbackPolicy::Allow => Ok(()),
+                    FallbackPolicy::Reject => Err(MempoolError::Moderated(format!("classifier unavailable: {}", reason))),
+                };
+            }
+        };
+        let flagged = scores.iter()
+            .filter(|(label, score)| self.config.flagged.contains(label) && *score >= self.config.threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match flagged {
+            Some((label, score)) => {
+                self.flagged.fetch_add(1, Ordering::Relaxed);
+                info!("Flagged memo of {} as {} ({:.3}); scores {:?}", hash, label, score, scores);
+                Err(MempoolError::Moderated(format!("memo flagged as {} ({:.2})", label, score)))
+            }
+            None => {
+                self.allowed.fetch_add(1, Ordering::Relaxed);
+                info!("Allowed memo of {}; scores {:?}", hash, scores);
+                Ok(())
+            }
+        }
+    }
+
+    async fn classify(&self, memo: &str) -> Result<Vec<(String, f32)>, String> {
+        let classifier = self.classifier.read().clone().ok_or_else(|| "no classifier attached".to_string())?;
+        let labels: Vec<&str> = self.config.labels.iter().map(String::as_str).collect();
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        match tokio::time::timeout(timeout, classifier.classify(memo, &labels)).await {
+            Ok(scores) => scores,
+            Err(_) => Err(format!("no answer within {}ms", self.config.timeout_ms)),
+        }
+    }
+}
+
+impl MetricsSource for MemoModerator {
+    fn render_metrics(&self, out: &mut String) {
+        let name = "dadbs_moderation_memos_total";
+        metrics::write_header(out, name, "Transfer memos moderated, by outcome", "counter");
+        for (outcome, count) in [("allowed", self.allowed()), ("flagged", self.flagged()), ("unavailable", self.unavailable())] {
+            metrics::write_sample(out, name, &[("outcome", outcome)], count as f64);
+        }
+        let judged = self.allowed() + self.flagged();
+        let rate = if judged == 0 { 0.0 } else { self.flagged() as f64 / judged as f64 };
+        metrics::write_gauge(out, "dadbs_moderation_flag_rate", "Share of classified memos flagged", rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+    struct Canned(Vec<(String, f32)>);
+
+    #[async_trait]
+    impl MemoClassifier for Canned {
+        async fn classify(&self, _text: &str, _labels: &[&str]) -> Result<Vec<(String, f32)>, String> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn transfer(payload: &[u8]) -> Transaction {
+        let mut transaction = Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), 1, 10, 0, 0);
+        transaction.payload = payload.to_vec();
+        transaction
+    }
+
+    #[tokio::test]
+    async fn test_flags_only_above_threshold() {
+        let scores = |spam: f32| Canned(vec![("spam".to_string(), spam), ("safe".to_string(), 1.0 - spam), ("abuse".to_string(), 0.0)]);
+        let moderator = MemoModerator::new(ModerationConfig::default()).with_classifier(Arc::new(scores(0.9)));
+        assert!(matches!(moderator.check(&transfer(b"buy now")).await, Err(MempoolError::Moderated(_))));
+        // Binary payloads and empty memos are not classified.
+        assert!(moderator.check(&transfer(&[0xff, 0xfe])).await.is_ok());
+        assert!(moderator.check(&transfer(b"")).await.is_ok());
+        moderator.classify_with(Arc::new(scores(0.6)));
+        assert!(moderator.check(&transfer(b"thanks for lunch")).await.is_ok());
+        assert_eq!((moderator.allowed(), moderator.flagged(), moderator.unavailable()), (1, 1, 0));
+
+        let mut out = String::new();
+        moderator.render_metrics(&mut out);
+        assert!(out.contains("dadbs_moderation_memos_total{outcome=\"flagged\"} 1"), "{}", out);
+        assert!(out.contains("dadbs_moderation_flag_rate 0.5"), "{}", out);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ModerationConfig::default().validate().is_ok());
+        let unknown = ModerationConfig { flagged: vec!["scam".to_string()], ..ModerationConfig::default() };
+        assert!(unknown.validate().is_err());
+        assert!(ModerationConfig { threshold: 0.0, ..ModerationConfig::default() }.validate().is_err());
+    }
+}
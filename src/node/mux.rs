@@ -0,0 +1,174 @@
+//! Two lanes over one peer connection. Consensus lane messages are always
+//! sent whole; a bulk lane frame larger than `BULK_CHUNK_BYTES` is split
+//! into chunks, and consensus messages queued meanwhile are sent between
+//! them, so a sync batch holds a vote up by at most one chunk.
+//!
+//! A chunk is framed like a message, with `CHUNK` in the flag byte, and
+//! `LAST_CHUNK` too on the final one; concatenated, their payloads are the
+//! bulk message's own frame. Frames without `CHUNK` are whole messages, so
+//! a peer below `MUX_VERSION`, which is never sent chunks, reads the same
+//! single stream of frames as before.
+
+use tokio::io::AsyncRead;
+
+use super::bandwidth::MessageCategory;
+use super::network::{self, NetMessage, NetworkError, FRAME_HEADER_BYTES};
+
+/// Most bytes of a bulk frame sent before queued consensus messages may go.
+pub const BULK_CHUNK_BYTES: usize = 16 * 1024;
+/// Flag bit marking a frame as a chunk of a bulk frame; codecs use the
+/// low bits.
+const CHUNK: u8 = 0x80;
+/// Flag bit marking the final chunk.
+const LAST_CHUNK: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// Proposals, votes and heartbeats, and the control messages that keep
+    /// the connection up.
+    Consensus,
+    /// Everything else: block ranges, peer lists, transactions, inference
+    /// and training traffic.
+    Bulk,
+}
+
+impl Lane {
+    pub fn of(message: &NetMessage) -> Self {
+        match MessageCategory::of(message) {
+            MessageCategory::Consensus => Lane::Consensus,
+            MessageCategory::Control if !matches!(message, NetMessage::GetPeers | NetMessage::Peers(_)) => {
+                Lane::Consensus
+            }
+            _ => Lane::Bulk,
+        }
+    }
+}
+
+/// Whether `message`, encoded as `frame`, goes out in chunks on a
+/// multiplexed connection.
+pub(crate) fn is_chunked(message: &NetMessage, frame: &[u8]) -> bool {
+    frame.len() > BULK_CHUNK_BYTES && Lane::of(message) == Lane::Bulk
+}
+
+/// `frame` split into chunk frames, in order.
+pub(crate) fn chunk_frames(frame: &[u8]) -> Vec<Vec<u8>> {
+    let count = frame.len().div_ceil(BULK_CHUNK_BYTES);
+    frame.chunks(BULK_CHUNK_BYTES)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let flag = if index + 1 == count { CHUNK | LAST_CHUNK } else { CHUNK };
+            let mut framed = Vec::with_capacity(FRAME_HEADER_BYTES + chunk.len());
+            framed.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            framed.push(flag);
+            framed.extend_from_slice(chunk);
+            framed
+        })
+        .collect()
+}
+
+/// A connection's incoming frames, with the bulk frame being received in
+/// chunks so far.
+#[derive(Debug)]
+pub(crate) struct Demux {
+    multiplexed: bool,
+    partial: Vec<u8>,
+    /// Wire bytes of the chunks in `partial`.
+    partial_bytes: usize,
+}
+
+impl Demux {
+    /// Expects chunks only if `multiplexed`; otherwise a chunk is a
+    /// malformed frame.
+    pub(crate) fn new(multiplexed: bool) -> Self {
+        Demux { multiplexed, partial: Vec::new(), partial_bytes: 0 }
+    }
+
+    /// Reads frames until a whole message is in, with the bytes it took on
+    /// the wire. A bulk frame is held to `max_frame_bytes` as it arrives,
+    /// not once complete.
+    pub(crate) async fn read<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        max_frame_bytes: usize,
+    ) -> Result<(NetMessage, usize), NetworkError> {
+        loop {
+            let (flag, payload) = network::read_raw_frame(reader, max_frame_bytes).await?;
+            let bytes = FRAME_HEADER_BYTES + payload.len();
+            if !self.multiplexed || flag & CHUNK == 0 {
+                return Ok((network::decode_frame(flag, payload, max_frame_bytes)?, bytes));
+            }
+            if flag & !(CHUNK | LAST_CHUNK) != 0 {
+                return Err(NetworkError::Decode(format!("unknown chunk flag {:#04x}", flag)));
+            }
+            self.partial.extend_from_slice(&payload);
+            self.partial_bytes += bytes;
+            let size = self.declared_size();
+            if let Some(size) = size {
+                if size > max_frame_bytes {
+                    return Err(NetworkError::FrameTooLarge { size, max: max_frame_bytes });
+                }
+                if self.partial.len() > FRAME_HEADER_BYTES + size {
+                    return Err(NetworkError::Decode("chunks run past their frame".to_string()));
+                }
+            }
+            if flag & LAST_CHUNK == 0 {
+                continue;
+            }
+            let frame = std::mem::take(&mut self.partial);
+            let bytes = std::mem::take(&mut self.partial_bytes);
+            if size.map(|size| FRAME_HEADER_BYTES + size) != Some(frame.len()) {
+                return Err(NetworkError::Decode("chunks end before their frame".to_string()));
+            }
+            let message = network::decode_frame(frame[4], frame[FRAME_HEADER_BYTES..].to_vec(), max_frame_bytes)?;
+            return Ok((message, bytes));
+        }
+    }
+
+    /// The payload length in the header of the frame being reassembled,
+    /// once its first four bytes are in.
+    fn declared_size(&self) -> Option<usize> {
+        let header = <[u8; 4]>::try_from(self.partial.get(..4)?).ok()?;
+        Some(u32::from_be_bytes(header) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::block::Block;
+    use crate::node::compression::Codec;
+    use crate::node::network::{encode_frame, write_frame};
+
+    #[tokio::test]
+    async fn test_chunks_reassemble_around_whole_frames() {
+        let batch = NetMessage::Blocks { blocks: vec![Block::genesis(); 400], certificates: vec![] };
+        let frame = encode_frame(&batch, 1 << 20, Codec::None, usize::MAX).unwrap();
+        assert!(is_chunked(&batch, &frame));
+        assert!(!is_chunked(&NetMessage::Block(Block::genesis()), &frame));
+        let chunks = chunk_frames(&frame);
+        assert_eq!(chunks.len(), frame.len().div_ceil(BULK_CHUNK_BYTES));
+
+        // A ping slipped in between the first two chunks arrives first.
+        let mut wire = chunks[0].clone();
+        write_frame(&mut wire, &NetMessage::Ping(1), 1024).await.unwrap();
+        wire.extend(chunks[1..].concat());
+        let mut demux = Demux::new(true);
+        let mut reader = wire.as_slice();
+        assert_eq!(demux.read(&mut reader, 1 << 20).await.unwrap().0, NetMessage::Ping(1));
+        let (message, bytes) = demux.read(&mut reader, 1 << 20).await.unwrap();
+        assert_eq!((message, bytes), (batch, wire.len() - 14));
+
+        // Held to the frame limit before the last chunk is in.
+        let mut demux = Demux::new(true);
+        assert!(matches!(
+            demux.read(&mut chunks.concat().as_slice(), BULK_CHUNK_BYTES).await,
+            Err(NetworkError::FrameTooLarge { max: BULK_CHUNK_BYTES, .. })
+        ));
+        // And chunks are malformed unless multiplexing was negotiated.
+        let mut demux = Demux::new(false);
+        assert!(matches!(
+            demux.read(&mut chunks.concat().as_slice(), 1 << 20).await,
+            Err(NetworkError::Compression(_))
+        ));
+    }
+}
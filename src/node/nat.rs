@@ -0,0 +1,296 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_MAPPING_LEASE_SECS: u64 = 3600;
+/// Distinct peers that must agree on our address before it is advertised.
+pub const DEFAULT_MIN_OBSERVATIONS: usize = 3;
+/// Peers whose reports are kept; the oldest report is dropped beyond this.
+const MAX_REPORTERS: usize = 64;
+#[cfg(feature = "upnp")]
+const MAPPING_DESCRIPTION: &str = "dadbs-node p2p";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum NatError {
+    #[error("No UPnP gateway found: {0}")]
+    NoGateway(String),
+    #[error("Gateway refused the request: {0}")]
+    Gateway(String),
+    #[error("UPnP support is not compiled in; rebuild with --features upnp")]
+    Unsupported,
+}
+
+/// The `[nat]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NatConfig {
+    /// Map the p2p port on the local gateway with UPnP at startup.
+    #[serde(default)]
+    pub upnp: bool,
+    /// Port to request on the gateway; the p2p port if unset.
+    #[serde(default)]
+    pub external_port: Option<u16>,
+    /// Mappings are renewed halfway through each lease.
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+    #[serde(default = "default_min_observations")]
+    pub min_observations: usize,
+}
+
+fn default_lease_secs() -> u64 {
+    DEFAULT_MAPPING_LEASE_SECS
+}
+
+fn default_min_observations() -> usize {
+    DEFAULT_MIN_OBSERVATIONS
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        NatConfig {
+            upnp: false,
+            external_port: None,
+            lease_secs: DEFAULT_MAPPING_LEASE_SECS,
+            min_observations: DEFAULT_MIN_OBSERVATIONS,
+        }
+    }
+}
+
+/// What peers say our IP is, as reported in their handshakes. Each peer
+/// host has one vote, its latest, so a host opening many connections
+/// counts once. An address is adopted once at least `min_observations`
+/// hosts report it and they outnumber all other reports together.
+#[derive(Debug)]
+pub struct ExternalAddress {
+    min_observations: usize,
+    /// Reporter to the IP it observed, with the report's sequence number.
+    reports: HashMap<IpAddr, (IpAddr, u64)>,
+    next_seq: u64,
+}
+
+impl ExternalAddress {
+    pub fn new(min_observations: usize) -> Self {
+        ExternalAddress { min_observations: min_observations.max(1), reports: HashMap::new(), next_seq: 0 }
+    }
+
+    /// Records that `reporter` sees us at `observed`. Unspecified addresses
+    /// are ignored. Returns whether the adopted address changed.
+    pub fn record(&mut self, reporter: IpAddr, observed: IpAddr) -> bool {
+        if observed.is_unspecified() {
+            return false;
+        }
+        let before = self.majority();
+        self.reports.insert(reporter, (observed, self.next_seq));
+        self.next_seq += 1;
+        if self.reports.len() > MAX_REPORTERS {
+            let oldest = self.reports.iter().min_by_key(|(_, (_, seq))| *seq).map(|(reporter, _)| *reporter);
+            if let Some(oldest) = oldest {
+                self.reports.remove(&oldest);
+            }
+        }
+        self.majority() != before
+    }
+
+    /// Drops `reporter`'s report, e.g. once it is banned. Returns whether
+    /// the adopted address changed.
+    pub fn forget(&mut self, reporter: &IpAddr) -> bool {
+        let before = self.majority();
+        self.reports.remove(reporter);
+        self.majority() != before
+    }
+
+    pub fn majority(&self) -> Option<IpAddr> {
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for (observed, _) in self.reports.values() {
+            *counts.entry(*observed).or_default() += 1;
+        }
+        let (ip, count) = counts.into_iter().max_by_key(|(ip, count)| (*count, *ip))?;
+        (count >= self.min_observations && count * 2 > self.reports.len()).then_some(ip)
+    }
+
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+}
+
+/// A gateway that forwards a public TCP port to us.
+#[async_trait]
+pub trait PortMapper: Send + Sync {
+    async fn external_ip(&self) -> Result<IpAddr, NatError>;
+    async fn add_mapping(&self, external_port: u16, local: SocketAddr, lease: Duration) -> Result<(), NatError>;
+    async fn remove_mapping(&self, external_port: u16) -> Result<(), NatError>;
+}
+
+/// The local network's UPnP gateway.
+#[cfg(feature = "upnp")]
+pub struct UpnpGateway {
+    gateway: igd_next::aio::Gateway<igd_next::aio::tokio::Tokio>,
+}
+
+#[cfg(feature = "upnp")]
+impl UpnpGateway {
+    /// `local` with an unspecified IP replaced by the one we reach the
+    /// gateway from, which is what it forwards to.
+    fn lan_addr(&self, local: SocketAddr) -> Result<SocketAddr, NatError> {
+        if !local.ip().is_unspecified() {
+            return Ok(local);
+        }
+        let gateway_error = |e: std::io::Error| NatError::Gateway(e.to_string());
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(local.ip(), 0)).map_err(gateway_error)?;
+        socket.connect(self.gateway.addr).map_err(gateway_error)?;
+        Ok(SocketAddr::new(socket.local_addr().map_err(gateway_error)?.ip(), local.port()))
+    }
+}
+
+#[cfg(feature = "upnp")]
+#[async_trait]
+impl PortMapper for UpnpGateway {
+    async fn external_ip(&self) -> Result<IpAddr, NatError> {
+        self.gateway.get_external_ip().await.map_err(|e| NatError::Gateway(e.to_string()))
+    }
+
+    async fn add_mapping(&self, external_port: u16, local: SocketAddr, lease: Duration) -> Result<(), NatError> {
+        let lease_secs = lease.as_secs().min(u32::MAX as u64) as u32;
+        let local = self.lan_addr(local)?;
+        self.gateway
+            .add_port(igd_next::PortMappingProtocol::TCP, external_port, local, lease_secs, MAPPING_DESCRIPTION)
+            .await
+            .map_err(|e| NatError::Gateway(e.to_string()))
+    }
+
+    async fn remove_mapping(&self, external_port: u16) -> Result<(), NatError> {
+        self.gateway
+            .remove_port(igd_next::PortMappingProtocol::TCP, external_port)
+            .await
+            .map_err(|e| NatError::Gateway(e.to_string()))
+    }
+}
+
+/// Searches the local network for a UPnP gateway.
+pub async fn upnp_gateway() -> Result<Arc<dyn PortMapper>, NatError> {
+    #[cfg(feature = "upnp")]
+    {
+        let gateway = igd_next::aio::tokio::search_gateway(igd_next::SearchOptions::default())
+            .await
+            .map_err(|e| NatError::NoGateway(e.to_string()))?;
+        Ok(Arc::new(UpnpGateway { gateway }))
+    }
+    #[cfg(not(feature = "upnp"))]
+    {
+        Err(NatError::Unsupported)
+    }
+}
+
+/// A port forwarded by a gateway for as long as this runs: renewed
+/// halfway through each lease and removed when cancelled.
+pub struct PortMapping {
+    mapper: Arc<dyn PortMapper>,
+    local: SocketAddr,
+    external: SocketAddr,
+    lease: Duration,
+}
+
+impl PortMapping {
+    /// Maps `external_port` to `local` and asks the gateway for its address.
+    pub async fn start(
+        mapper: Arc<dyn PortMapper>,
+        local: SocketAddr,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<Self, NatError> {
+        mapper.add_mapping(external_port, local, lease).await?;
+        let ip = match mapper.external_ip().await {
+            Ok(ip) => ip,
+            Err(e) => {
+                let _ = mapper.remove_mapping(external_port).await;
+                return Err(e);
+            }
+        };
+        let external = SocketAddr::new(ip, external_port);
+        info!("Mapped {} on the gateway to {}", external, local);
+        Ok(PortMapping { mapper, local, external, lease })
+    }
+
+    pub fn external(&self) -> SocketAddr {
+        self.external
+    }
+
+    /// Renews the mapping until `cancel` fires, then removes it. A failed
+    /// renewal is retried at the next interval.
+    pub async fn run(self, cancel: CancellationToken) {
+        let interval = (self.lease / 2).max(Duration::from_millis(1));
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = sleep(interval) => {}
+            }
+            if let Err(e) = self.mapper.add_mapping(self.external.port(), self.local, self.lease).await {
+                warn!("Failed to renew port mapping {}: {}", self.external, e);
+            }
+        }
+        match self.mapper.remove_mapping(self.external.port()).await {
+            Ok(()) => info!("Removed port mapping {}", self.external),
+            Err(e) => warn!("Failed to remove port mapping {}: {}", self.external, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, last])
+    }
+
+    #[test]
+    fn test_majority_needs_quorum_of_distinct_reporters() {
+        let mut external = ExternalAddress::new(2);
+        let ours = IpAddr::from([198, 51, 100, 7]);
+        let liar = IpAddr::from([192, 0, 2, 1]);
+
+        assert!(!external.record(ip(1), ours));
+        // The same host reporting again still counts once.
+        assert!(!external.record(ip(1), ours));
+        assert_eq!(external.majority(), None);
+        assert!(external.record(ip(2), ours));
+        assert_eq!(external.majority(), Some(ours));
+
+        // A tie is not a majority.
+        external.record(ip(3), liar);
+        assert!(external.record(ip(4), liar));
+        assert_eq!(external.majority(), None);
+        assert!(external.record(ip(5), ours));
+        assert_eq!(external.majority(), Some(ours));
+
+        // A host changing its report replaces the old one.
+        external.record(ip(1), liar);
+        assert_eq!(external.majority(), Some(liar));
+        assert!(external.forget(&ip(1)));
+        assert_eq!(external.majority(), None);
+        assert!(!external.record(ip(6), IpAddr::from([0, 0, 0, 0])));
+        assert_eq!(external.len(), 4);
+    }
+
+    #[test]
+    fn test_oldest_reports_evicted() {
+        let mut external = ExternalAddress::new(1);
+        let ours = IpAddr::from([198, 51, 100, 7]);
+        external.record(IpAddr::from([10, 0, 0, 1]), IpAddr::from([192, 0, 2, 1]));
+        for i in 0..MAX_REPORTERS as u8 {
+            external.record(ip(i), ours);
+        }
+        assert_eq!(external.len(), MAX_REPORTERS);
+        assert!(!external.forget(&IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(external.majority(), Some(ours));
+    }
+}
@@ -1,213 +1,2207 @@
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{
-    commitment_config::CommitmentConfig,
-    signature::{Keypair, Signer},
-    transaction::Transaction,
-};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc};
-use tokio::time::{sleep, Duration, timeout};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
-use parking_lot::RwLock;
-use log::{info, error, warn, debug};
-
-const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
-const RECONNECT_DELAY: Duration = Duration::from_secs(5);
-const MAX_RECONNECT_ATTEMPTS: u32 = 3;
-
-#[derive(Debug)]
-pub struct Node {
-    config: Arc<NodeConfig>,
-    keypair: Keypair,
-    rpc_client: RpcClient,
-    peers: Arc<RwLock<HashMap<SocketAddr, PeerInfo>>>,
-    tx: broadcast::Sender<Message>,
-    shutdown: mpsc::Sender<()>,
-}
-
-impl Node {
-    pub async fn new(config: NodeConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let keypair = Keypair::new();
-        let rpc_client = RpcClient::new_with_commitment(
-            "https://api.mainnet-beta.solana.com".to_string(),
-            CommitmentConfig::confirmed(),
-        );
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Notify};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use super::bandwidth::{BandwidthConfig, MessageCategory, OutboundPacer, TokenBucket, Traffic, TrafficStats};
+use super::block::Block;
+use super::compression::{Codec, CompressionError, DEFAULT_COMPRESSION_THRESHOLD};
+use super::config::{NodeConfig, DEFAULT_CHAIN_ID};
+use super::dns_seed::{self, DnsSeed, DnsSeedConfig, SeedPeer, SeedResolver};
+use super::handover::{HandoverError, HandoverRegistry, KeyHandover};
+use super::gossip::{self, BetweenChunks, Enqueued, GossipConfig, GossipCounters, GossipStats, SeenCache, SendQueue};
+use super::heartbeat::{Heartbeat, HeartbeatTransport};
+use super::ingest::{IngestConfig, IngestPipeline, Ingested};
+use super::nat::{ExternalAddress, DEFAULT_MIN_OBSERVATIONS};
+use super::metrics::{self, MetricsSource};
+use super::mux::{self, Demux};
+use super::peer_score::{Offense, PeerScore, ScoreConfig};
+use super::peer_store::{Ban, PeerRecord, PeerStore, PeerStoreError};
+use super::proposal::Proposal;
+use super::reconnect::{BackoffConfig, BackoffStatus, Reconnector, RetryState};
+use super::transaction::Transaction;
+use super::vote::{CommitCertificate, Vote};
+use crate::utils::DADBSAddress;
+
+pub type PeerId = SocketAddr;
+
+/// Peer store file inside `storage_path`.
+pub const PEER_STORE_FILE: &str = "peers.json";
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest protocol version we still speak.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// First protocol version that may compress frames.
+pub const COMPRESSION_VERSION: u32 = 2;
+/// First protocol version that sends large bulk frames in chunks, with
+/// consensus messages between them. See `mux`.
+pub const MUX_VERSION: u32 = 3;
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 8 * 1024 * 1024;
+pub const DEFAULT_OUTBOUND_TARGET: usize = 8;
+pub const DEFAULT_MAX_PEERS_PER_RESPONSE: usize = 32;
+/// Gradient messages a slow subscriber may fall behind by.
+pub const GRADIENT_BACKLOG: usize = 64;
+/// Inference messages a slow subscriber may fall behind by.
+pub const INFERENCE_BACKLOG: usize = 64;
+/// Capability bit of a node that runs inference for its peers.
+pub const CAPABILITY_LLM: u32 = 1;
+pub const DEFAULT_PEER_EXCHANGE_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages a peer may send per second before it is penalized for spam.
+pub const DEFAULT_MAX_MESSAGES_PER_SECOND: u32 = 1000;
+/// The length prefix and codec flag before every payload.
+pub(crate) const FRAME_HEADER_BYTES: usize = 5;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Frame of {size} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { size: usize, max: usize },
+    #[error("Malformed message: {0}")]
+    Decode(String),
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+    #[error("Peer speaks protocol version {theirs}, we speak {ours}")]
+    VersionMismatch { ours: u32, theirs: u32 },
+    #[error("Peer has genesis {theirs}, ours is {ours}")]
+    GenesisMismatch { ours: Hash, theirs: Hash },
+    #[error("Peer is on chain {theirs}, we are on {ours}")]
+    ChainMismatch { ours: String, theirs: String },
+    #[error("Connection limit of {0} reached")]
+    TooManyConnections(usize),
+    #[error("Not connected to peer {0}")]
+    NotConnected(PeerId),
+    #[error("Send queue for peer {0} is full")]
+    QueueFull(PeerId),
+    #[error("Address belongs to this node")]
+    SelfConnection,
+    #[error("Already connected to node {0}")]
+    DuplicatePeer(String),
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("Peer refused the connection: {0}")]
+    Refused(String),
+    #[error("Compression error: {0}")]
+    Compression(#[from] CompressionError),
+    #[error("Peer {0} is banned: {1}")]
+    Banned(SocketAddr, String),
+    #[error("Peer was listed with identity {expected}, but proved {actual:?}")]
+    IdentityMismatch { expected: Pubkey, actual: Option<Pubkey> },
+    #[error("Peer store error: {0}")]
+    PeerStore(#[from] PeerStoreError),
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NetMessage {
+    /// `version` is the newest protocol version the sender speaks and
+    /// `min_version` the oldest. `listen_port` is where it accepts
+    /// connections; `codecs` are the compression codecs it supports, most
+    /// preferred first. Peers must share `chain_id` and `genesis_hash`. `observed_addr`
+    /// is the receiver's address as the sender sees it. `capabilities` are
+    /// the `CAPABILITY_*` bits of the services the sender offers, and
+    /// `llm` what it serves inference with, if it has a model up.
+    /// `identity` is the key the sender is known by, if it has one; it
+    /// proves holding it with a `HandshakeProof` over the receiver's
+    /// `nonce` once both handshakes are exchanged.
+    Handshake {
+        version: u32,
+        min_version: u32,
+        node_id: String,
+        listen_port: u16,
+        codecs: Vec<Codec>,
+        chain_id: String,
+        genesis_hash: Hash,
+        observed_addr: Option<String>,
+        capabilities: u32,
+        llm: Option<LlmCapability>,
+        identity: Option<Pubkey>,
+        nonce: [u8; 32],
+    },
+    /// Follows the handshake of a sender claiming an `identity`.
+    HandshakeProof(IdentityProof),
+    /// Sent before closing a connection, saying why.
+    Disconnect(String),
+    Ping(u64),
+    Pong(u64),
+    Tx(Transaction),
+    Block(Block),
+    Vote(Vote),
+    Heartbeat(Heartbeat),
+    GetBlocks { from: u64, to: u64 },
+    Blocks { blocks: Vec<Block>, certificates: Vec<CommitCertificate> },
+    GetPeers,
+    Peers(Vec<PeerRecord>),
+    /// A replica's gradients for one distributed training step; sent to
+    /// each peer directly, never relayed.
+    Gradients(GradientMessage),
+    /// Distributed inference between a node and the peers it asked; sent
+    /// directly, never relayed.
+    Inference(InferenceMessage),
+    /// The sender's `llm` from the handshake changed, as when it swaps
+    /// models; `None` once it serves none.
+    LlmCapability(Option<LlmCapability>),
+    /// A node moving its identity to a new key; gossiped.
+    KeyHandover(KeyHandover),
+    /// A round leader's block, signed by it; gossiped.
+    Proposal(Proposal),
+}
+
+/// A node's identity key, with its signature over the node id and genesis
+/// hash of its handshake and the nonce of the other side's, so it cannot
+/// be replayed on another connection.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityProof {
+    pub pubkey: Pubkey,
+    pub signature: Signature,
+}
+
+impl IdentityProof {
+    pub fn new(keypair: &Keypair, node_id: &str, genesis_hash: &Hash, nonce: &[u8; 32]) -> Self {
+        let signature = keypair.sign_message(&Self::signing_bytes(node_id, genesis_hash, nonce));
+        IdentityProof { pubkey: keypair.pubkey(), signature }
+    }
+
+    fn signing_bytes(node_id: &str, genesis_hash: &Hash, nonce: &[u8; 32]) -> Vec<u8> {
+        [b"dadbs-node-identity".as_slice(), node_id.as_bytes(), genesis_hash.as_ref(), nonce.as_slice()].concat()
+    }
+
+    pub fn verify(&self, node_id: &str, genesis_hash: &Hash, nonce: &[u8; 32]) -> bool {
+        self.signature.verify(self.pubkey.as_ref(), &Self::signing_bytes(node_id, genesis_hash, nonce))
+    }
+}
+
+/// The model a node serves inference with, for clients and peers to pick
+/// nodes by.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LlmCapability {
+    pub model_version: String,
+    pub context_length: u32,
+    /// The weights' data type: "f16", or a GGUF quantization such as "Q4_0".
+    pub quant: String,
+    /// Requests run together in one batch.
+    pub max_batch: u32,
+    /// Whether it completes requests from peers as well as its own clients.
+    pub accepts_remote: bool,
+}
+
+/// Which advertised capabilities `Network::find_llm_peers` returns; unset
+/// fields match any.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct LlmPeerFilter {
+    pub model_version: Option<String>,
+    pub min_context_length: Option<u32>,
+    pub quant: Option<String>,
+    /// Only peers that complete requests from others.
+    pub accepts_remote: bool,
+}
 
-        let (tx, _) = broadcast::channel(100);
-        let (shutdown_tx, _) = mpsc::channel(1);
-        
-        Ok(Node {
-            config: Arc::new(config),
-            keypair,
-            rpc_client,
-            peers: Arc::new(RwLock::new(HashMap::new())),
-            tx,
-            shutdown: shutdown_tx,
+impl LlmPeerFilter {
+    pub fn with_model_version(mut self, model_version: impl Into<String>) -> Self {
+        self.model_version = Some(model_version.into());
+        self
+    }
+
+    pub fn with_min_context_length(mut self, min_context_length: u32) -> Self {
+        self.min_context_length = Some(min_context_length);
+        self
+    }
+
+    pub fn with_quant(mut self, quant: impl Into<String>) -> Self {
+        self.quant = Some(quant.into());
+        self
+    }
+
+    pub fn with_accepts_remote(mut self, accepts_remote: bool) -> Self {
+        self.accepts_remote = accepts_remote;
+        self
+    }
+
+    pub fn matches(&self, capability: &LlmCapability) -> bool {
+        self.model_version.as_ref().map_or(true, |version| *version == capability.model_version)
+            && self.min_context_length.map_or(true, |min| capability.context_length >= min)
+            && self.quant.as_ref().map_or(true, |quant| quant.eq_ignore_ascii_case(&capability.quant))
+            && (!self.accepts_remote || capability.accepts_remote)
+    }
+}
+
+/// How wide each gradient value is on the wire.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GradientPrecision {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl GradientPrecision {
+    /// Bytes per value.
+    pub fn width(self) -> usize {
+        match self {
+            GradientPrecision::F32 => 4,
+            GradientPrecision::F16 | GradientPrecision::Bf16 => 2,
+        }
+    }
+}
+
+/// How a trainer packs its gradients. Every message carries it, and
+/// replicas packing theirs differently refuse to average together.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GradientCompression {
+    pub precision: GradientPrecision,
+    /// The fraction of each bucket's gradients sent, largest first, as
+    /// `f32::to_bits`; 0 sends them all.
+    pub top_k: u32,
+}
+
+impl GradientCompression {
+    pub fn new(precision: GradientPrecision) -> Self {
+        GradientCompression { precision, top_k: 0 }
+    }
+
+    /// Sends only the largest `fraction` of each bucket's gradients.
+    pub fn with_top_k(mut self, fraction: f32) -> Self {
+        self.top_k = if fraction > 0.0 && fraction < 1.0 { fraction.to_bits() } else { 0 };
+        self
+    }
+
+    pub fn top_k(&self) -> Option<f32> {
+        Some(f32::from_bits(self.top_k)).filter(|_| self.top_k != 0)
+    }
+}
+
+impl std::fmt::Display for GradientCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.precision)?;
+        match self.top_k() {
+            Some(fraction) => write!(f, ", top {}%", fraction * 100.0),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One bucket of a replica's gradients for a step.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GradientMessage {
+    /// The sending trainer's name, as its peers list it.
+    pub sender: String,
+    pub step: u64,
+    /// The sender's local loss, as `f32::to_bits`.
+    pub loss: u32,
+    pub compression: GradientCompression,
+    /// Which of the step's `buckets` this is; bucket `i` holds the
+    /// gradients from `i * bucket_size` on, flattened in parameter name
+    /// order.
+    pub bucket: u32,
+    pub buckets: u32,
+    /// Where in the bucket each value goes, when top-k sparsified; empty
+    /// when every value is sent.
+    pub indices: Vec<u32>,
+    /// Little-endian values, `compression.precision` wide.
+    pub values: Vec<u8>,
+}
+
+impl GradientMessage {
+    /// All of `gradients` in one dense `f32` bucket.
+    pub fn new(sender: &str, step: u64, loss: f32, gradients: &[f32]) -> Self {
+        GradientMessage {
+            sender: sender.to_string(),
+            step,
+            loss: loss.to_bits(),
+            compression: GradientCompression::default(),
+            bucket: 0,
+            buckets: 1,
+            indices: Vec::new(),
+            values: gradients.iter().flat_map(|value| value.to_le_bytes()).collect(),
+        }
+    }
+
+    pub fn loss(&self) -> f32 {
+        f32::from_bits(self.loss)
+    }
+
+    /// The values of a dense `f32` message; `None` for any other, or
+    /// unless the payload is a whole number of `f32`s.
+    pub fn gradients(&self) -> Option<Vec<f32>> {
+        if self.compression != GradientCompression::default() || !self.indices.is_empty() || self.values.len() % 4 != 0 {
+            return None;
+        }
+        Some(self.values.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect())
+    }
+
+    /// Bytes of gradients and their indices.
+    pub fn payload_len(&self) -> usize {
+        self.values.len() + self.indices.len() * 4
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InferenceMessage {
+    Request(InferenceRequest),
+    Result(InferenceResult),
+    /// The peer will not complete request `id`, e.g. because it serves no
+    /// model.
+    Refused { id: u64, reason: String },
+    /// A validator's spot check that the peer runs the model it advertises.
+    Challenge(InferenceChallenge),
+    ChallengeResponse(ChallengeResponse),
+}
+
+/// Asks for the logprobs, at `positions`, of the tokens of the text
+/// `seed` stands for following `prompt`, as teacher-forced scoring gives
+/// them. The text is only known once the seed is, so the answer cannot be
+/// worked out ahead of time.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InferenceChallenge {
+    /// Chosen by the validator; the response carries it back.
+    pub id: u64,
+    /// sha256 of `prompt`, as the evidence of a mismatch records it.
+    pub prompt_hash: [u8; 32],
+    pub prompt: String,
+    pub seed: u64,
+    /// Indexes into the scored tokens, ascending.
+    pub positions: Vec<u32>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeResponse {
+    pub id: u64,
+    /// The logprob at each position asked for, as `f32::to_bits`; empty
+    /// when refused.
+    pub logprobs: Vec<u32>,
+    pub refused: Option<String>,
+}
+
+/// A prompt to complete with seeded sampling, so that honest peers
+/// running the same model give the same tokens. Floats are `f32::to_bits`.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InferenceRequest {
+    /// Chosen by the requester; results carry it back.
+    pub id: u64,
+    pub prompt: String,
+    pub max_tokens: u32,
+    pub temperature: u32,
+    pub top_k: Option<u32>,
+    pub top_p: Option<u32>,
+    pub repetition_penalty: u32,
+    pub stop: Vec<String>,
+    pub seed: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InferenceResult {
+    pub id: u64,
+    pub tokens: Vec<u32>,
+    pub text: String,
+    /// Why generation ended: 0 for the token limit, 1 for a stop sequence,
+    /// 2 for the model ending the text.
+    pub finish_reason: u8,
+    pub prompt_tokens: u32,
+    pub truncated_tokens: u32,
+}
+
+/// Writes `message` uncompressed. See `write_compressed_frame`.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &NetMessage,
+    max_frame_bytes: usize,
+) -> Result<(), NetworkError> {
+    write_compressed_frame(writer, message, max_frame_bytes, Codec::None, usize::MAX).await.map(|_| ())
+}
+
+/// Writes `message` as a 4-byte big-endian payload length, a codec flag
+/// byte, and its Borsh encoding, compressed with `codec` if it is larger
+/// than `threshold` bytes and compression helps. Returns the bytes written.
+pub async fn write_compressed_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &NetMessage,
+    max_frame_bytes: usize,
+    codec: Codec,
+    threshold: usize,
+) -> Result<usize, NetworkError> {
+    let frame = encode_frame(message, max_frame_bytes, codec, threshold)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(frame.len())
+}
+
+/// `message` framed as `write_compressed_frame` writes it.
+pub(crate) fn encode_frame(
+    message: &NetMessage,
+    max_frame_bytes: usize,
+    codec: Codec,
+    threshold: usize,
+) -> Result<Vec<u8>, NetworkError> {
+    let encoded = message.try_to_vec()?;
+    if encoded.len() > max_frame_bytes {
+        return Err(NetworkError::FrameTooLarge { size: encoded.len(), max: max_frame_bytes });
+    }
+    let (codec, payload) = match codec {
+        Codec::None => (Codec::None, encoded),
+        _ if encoded.len() <= threshold => (Codec::None, encoded),
+        codec => {
+            let compressed = codec.compress(&encoded);
+            if compressed.len() < encoded.len() { (codec, compressed) } else { (Codec::None, encoded) }
+        }
+    };
+    let mut frame = Vec::with_capacity(FRAME_HEADER_BYTES + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.push(codec.flag());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Reads one frame. An oversized length is rejected before any of the
+/// payload is buffered, and decompression stops at `max_frame_bytes`.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_bytes: usize,
+) -> Result<NetMessage, NetworkError> {
+    read_sized_frame(reader, max_frame_bytes).await.map(|(message, _)| message)
+}
+
+/// Reads one frame as `read_frame` does, with the bytes it took on the wire.
+pub async fn read_sized_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_bytes: usize,
+) -> Result<(NetMessage, usize), NetworkError> {
+    let (flag, payload) = read_raw_frame(reader, max_frame_bytes).await?;
+    let size = payload.len();
+    Ok((decode_frame(flag, payload, max_frame_bytes)?, FRAME_HEADER_BYTES + size))
+}
+
+/// Reads one frame's flag byte and payload, undecoded.
+pub(crate) async fn read_raw_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_bytes: usize,
+) -> Result<(u8, Vec<u8>), NetworkError> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+    let size = u32::from_be_bytes(len) as usize;
+    if size > max_frame_bytes {
+        return Err(NetworkError::FrameTooLarge { size, max: max_frame_bytes });
+    }
+    let flag = reader.read_u8().await?;
+    let mut payload = vec![0u8; size];
+    reader.read_exact(&mut payload).await?;
+    Ok((flag, payload))
+}
+
+/// Decompresses a frame's payload as its flag says and decodes it.
+pub(crate) fn decode_frame(flag: u8, payload: Vec<u8>, max_frame_bytes: usize) -> Result<NetMessage, NetworkError> {
+    let payload = match Codec::from_flag(flag)? {
+        Codec::None => payload,
+        codec => codec.decompress(&payload, max_frame_bytes)?,
+    };
+    NetMessage::try_from_slice(&payload).map_err(|e| NetworkError::Decode(e.to_string()))
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub listen_addr: SocketAddr,
+    pub node_id: String,
+    /// `host:port` addresses, or `dnsseed:` names resolved as `dns_seed` says.
+    pub bootstrap_nodes: Vec<String>,
+    pub dns_seed: DnsSeedConfig,
+    pub max_connections: usize,
+    pub max_frame_bytes: usize,
+    /// Outbound connections discovery dials up to.
+    pub outbound_target: usize,
+    pub max_peers_per_response: usize,
+    pub peer_exchange_interval: Duration,
+    pub backoff: BackoffConfig,
+    pub scoring: ScoreConfig,
+    pub max_messages_per_second: u32,
+    /// Where bans are persisted; bans are kept in memory only if unset.
+    pub peer_store_path: Option<PathBuf>,
+    pub min_protocol_version: u32,
+    pub protocol_version: u32,
+    /// Compression codecs we accept, most preferred first.
+    pub codecs: Vec<Codec>,
+    pub compression_threshold: usize,
+    pub gossip: GossipConfig,
+    pub ingest: IngestConfig,
+    /// Distinct peers that must agree on our external address before we
+    /// advertise it.
+    pub min_observations: usize,
+    /// The chain we are on; peers on another are refused.
+    pub chain_id: String,
+    /// Canonical hash of the chain's genesis; peers with another are refused.
+    pub genesis_hash: Hash,
+    /// `CAPABILITY_*` bits advertised in the handshake.
+    pub capabilities: u32,
+    pub bandwidth: BandwidthConfig,
+    /// The key we prove holding in handshakes, if any.
+    pub identity: Option<Arc<Keypair>>,
+    /// Handovers known, shared with consensus.
+    pub handovers: HandoverRegistry,
+    /// Dial inbound peers back at the address they advertise, and keep it
+    /// for peer lists only if the same node answers there.
+    pub dial_back: bool,
+}
+
+impl NetworkConfig {
+    pub fn new(listen_addr: SocketAddr, node_id: impl Into<String>) -> Self {
+        NetworkConfig {
+            listen_addr,
+            node_id: node_id.into(),
+            bootstrap_nodes: Vec::new(),
+            dns_seed: DnsSeedConfig::default(),
+            max_connections: 50,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            outbound_target: DEFAULT_OUTBOUND_TARGET,
+            max_peers_per_response: DEFAULT_MAX_PEERS_PER_RESPONSE,
+            peer_exchange_interval: DEFAULT_PEER_EXCHANGE_INTERVAL,
+            backoff: BackoffConfig::default(),
+            scoring: ScoreConfig::default(),
+            max_messages_per_second: DEFAULT_MAX_MESSAGES_PER_SECOND,
+            peer_store_path: None,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            protocol_version: PROTOCOL_VERSION,
+            codecs: vec![Codec::Zstd, Codec::Lz4],
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            gossip: GossipConfig::default(),
+            ingest: IngestConfig::default(),
+            min_observations: DEFAULT_MIN_OBSERVATIONS,
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            genesis_hash: Hash::default(),
+            capabilities: 0,
+            bandwidth: BandwidthConfig::default(),
+            identity: None,
+            handovers: HandoverRegistry::new(),
+            dial_back: false,
+        }
+    }
+
+    pub fn from_node_config(config: &NodeConfig) -> Result<Self, NetworkError> {
+        let addr = format!("{}:{}", config.host, config.port);
+        let listen_addr = addr.to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or(NetworkError::InvalidAddress(addr))?;
+        Ok(NetworkConfig {
+            listen_addr,
+            node_id: config.node_id.clone(),
+            bootstrap_nodes: config.bootstrap_nodes.clone(),
+            dns_seed: config.dns_seed,
+            max_connections: config.max_connections as usize,
+            max_frame_bytes: config.max_frame_bytes,
+            outbound_target: DEFAULT_OUTBOUND_TARGET,
+            max_peers_per_response: DEFAULT_MAX_PEERS_PER_RESPONSE,
+            peer_exchange_interval: DEFAULT_PEER_EXCHANGE_INTERVAL,
+            backoff: BackoffConfig {
+                max: Duration::from_millis(config.max_reconnect_backoff_ms),
+                ..BackoffConfig::default()
+            },
+            scoring: ScoreConfig::default()
+                .with_ban_duration(Duration::from_secs(config.peer_ban_duration_secs)),
+            max_messages_per_second: DEFAULT_MAX_MESSAGES_PER_SECOND,
+            peer_store_path: Some(Path::new(&config.storage_path).join(PEER_STORE_FILE)),
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            protocol_version: PROTOCOL_VERSION,
+            codecs: config.compression.clone(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            gossip: GossipConfig::default().with_fanout(config.gossip_fanout),
+            ingest: IngestConfig::default(),
+            min_observations: config.nat.min_observations,
+            chain_id: config.chain_id.clone(),
+            genesis_hash: Hash::default(),
+            capabilities: 0,
+            bandwidth: config.bandwidth,
+            identity: None,
+            handovers: HandoverRegistry::for_chain(&config.chain_id),
+            dial_back: config.dial_back,
         })
     }
 
-    pub async fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-       
-        self.verify_stake().await?;
+    pub fn with_bootstrap_nodes(mut self, bootstrap_nodes: Vec<String>) -> Self {
+        self.bootstrap_nodes = bootstrap_nodes;
+        self
+    }
 
-        let addr = format!("{}:{}", self.config.host, self.config.port);
-        let listener = TcpListener::bind(&addr).await
-            .map_err(|e| {
-                error!("Failed to bind to {}: {}", addr, e);
-                e
-            })?;
-        
-        info!("Node listening on {}", addr);
+    pub fn with_dns_seed(mut self, dns_seed: DnsSeedConfig) -> Self {
+        self.dns_seed = dns_seed;
+        self
+    }
 
-        
-        let peers = Arc::clone(&self.peers);
-        tokio::spawn(async move {
-            loop {
-                sleep(Duration::from_secs(60)).await;
-                Self::cleanup_disconnected_peers(Arc::clone(&peers)).await;
+    /// The `dnsseed:` entries in `bootstrap_nodes`; invalid ones are skipped.
+    pub fn dns_seeds(&self) -> Vec<DnsSeed> {
+        self.bootstrap_nodes.iter()
+            .filter(|node| dns_seed::is_dns_seed(node))
+            .filter_map(|node| match node.parse() {
+                Ok(seed) => Some(seed),
+                Err(e) => {
+                    warn!("Skipping bootstrap node: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = max_frame_bytes;
+        self
+    }
+
+    pub fn with_outbound_target(mut self, outbound_target: usize) -> Self {
+        self.outbound_target = outbound_target;
+        self
+    }
+
+    pub fn with_peer_exchange_interval(mut self, peer_exchange_interval: Duration) -> Self {
+        self.peer_exchange_interval = peer_exchange_interval;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: BackoffConfig) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_scoring(mut self, scoring: ScoreConfig) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    pub fn with_max_messages_per_second(mut self, max_messages_per_second: u32) -> Self {
+        self.max_messages_per_second = max_messages_per_second;
+        self
+    }
+
+    pub fn with_peer_store_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.peer_store_path = Some(path.into());
+        self
+    }
+
+    /// Restricts the protocol versions we speak, e.g. to stay compatible
+    /// during a rolling upgrade. Clamped to versions this build implements.
+    pub fn with_protocol_versions(mut self, min: u32, max: u32) -> Self {
+        self.protocol_version = max.clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+        self.min_protocol_version = min.clamp(MIN_PROTOCOL_VERSION, self.protocol_version);
+        self
+    }
+
+    pub fn with_codecs(mut self, codecs: Vec<Codec>) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    pub fn with_compression_threshold(mut self, compression_threshold: usize) -> Self {
+        self.compression_threshold = compression_threshold;
+        self
+    }
+
+    pub fn with_gossip(mut self, gossip: GossipConfig) -> Self {
+        self.gossip = gossip;
+        self
+    }
+
+    pub fn with_ingest(mut self, ingest: IngestConfig) -> Self {
+        self.ingest = ingest;
+        self
+    }
+
+    pub fn with_min_observations(mut self, min_observations: usize) -> Self {
+        self.min_observations = min_observations;
+        self
+    }
+
+    /// Also starts a registry of handovers for `chain_id`; share another
+    /// with `with_handovers` after this.
+    pub fn with_chain_id(mut self, chain_id: impl Into<String>) -> Self {
+        self.chain_id = chain_id.into();
+        self.handovers = HandoverRegistry::for_chain(&self.chain_id);
+        self
+    }
+
+    pub fn with_genesis_hash(mut self, genesis_hash: Hash) -> Self {
+        self.genesis_hash = genesis_hash;
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: u32) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: BandwidthConfig) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn with_identity(mut self, identity: Arc<Keypair>) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Shares `handovers`, which must be for this network's chain.
+    pub fn with_handovers(mut self, handovers: HandoverRegistry) -> Self {
+        self.handovers = handovers;
+        self
+    }
+
+    pub fn with_dial_back(mut self, dial_back: bool) -> Self {
+        self.dial_back = dial_back;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    /// Empty for a peer we are not connected to.
+    pub node_id: String,
+    /// Whether we dialed this peer.
+    pub outbound: bool,
+    pub connected: bool,
+    /// Negotiated in the handshake; zero for a peer we are not connected to.
+    pub protocol_version: u32,
+    pub codec: Codec,
+    /// `CAPABILITY_*` bits from the handshake; zero for a peer we are not
+    /// connected to.
+    pub capabilities: u32,
+    /// The model the peer last advertised serving.
+    pub llm: Option<LlmCapability>,
+    /// The identity of the key the peer proved holding, mapped back
+    /// through the handovers known when it connected.
+    pub identity: Option<Pubkey>,
+    /// Unix milliseconds of the last message from the peer; zero for a
+    /// peer we are not connected to.
+    pub last_seen: i64,
+    /// Bytes exchanged over the current connection.
+    pub traffic: TrafficStats,
+    /// Reconnection state, for peers we redial when they drop.
+    pub backoff: Option<BackoffStatus>,
+}
+
+impl PeerInfo {
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    /// Whether the connection runs consensus and bulk lanes; see `mux`.
+    pub fn multiplexed(&self) -> bool {
+        self.protocol_version >= MUX_VERSION
+    }
+}
+
+struct Connection {
+    info: PeerInfo,
+    /// Where the peer accepts connections; scores and bans are kept per
+    /// listen address since inbound connections come from ephemeral ports.
+    listen_addr: SocketAddr,
+    outbound: Arc<SendQueue>,
+    cancel: CancellationToken,
+    /// Unix milliseconds, updated by the reader for every message.
+    last_seen: Arc<AtomicI64>,
+    traffic: Arc<Traffic>,
+}
+
+/// TCP transport between nodes. Every connection starts with a handshake
+/// exchanging protocol version, node id and genesis hash, and a signature
+/// over the other side's nonce from nodes with an identity key; afterwards both
+/// sides exchange length-prefixed Borsh frames, large bulk ones in chunks
+/// once both speak `MUX_VERSION`. Peers are discovered by asking connected
+/// nodes for the addresses they have reached themselves. Transactions,
+/// blocks and votes are gossiped: each node delivers and forwards a message
+/// to a random subset of its peers only the first time it sees it.
+pub struct Network {
+    config: NetworkConfig,
+    local_addr: SocketAddr,
+    connections: RwLock<HashMap<PeerId, Connection>>,
+    peer_store: Mutex<PeerStore>,
+    scores: Mutex<PeerScore>,
+    /// Wakes the exchange loop when gossip taught us new addresses.
+    learned_peers: Notify,
+    reconnector: Mutex<Reconnector>,
+    /// Wakes the reconnect loop when a retry was scheduled.
+    retry_scheduled: Notify,
+    seen: Mutex<SeenCache>,
+    gossip_counters: GossipCounters,
+    /// Our address as reported by peers.
+    observed: Mutex<ExternalAddress>,
+    /// The address a gateway forwards to us, if a port is mapped.
+    mapped: RwLock<Option<SocketAddr>>,
+    ingest: Arc<IngestPipeline>,
+    /// Gradient messages, for whichever trainers subscribed.
+    gradients: broadcast::Sender<(PeerId, GradientMessage)>,
+    /// Inference messages, for the router if one subscribed.
+    inference: broadcast::Sender<(PeerId, InferenceMessage)>,
+    /// What we advertise serving inference with.
+    llm: RwLock<Option<LlmCapability>>,
+    /// Listen addresses of peers whose LLM capability we no longer take.
+    revoked_llm: Mutex<HashSet<SocketAddr>>,
+    /// Keys DNS seeds listed nodes with, which we require when dialing them.
+    expected_identities: Mutex<HashMap<SocketAddr, Pubkey>>,
+    /// The key we prove in handshakes, once rotated no longer the
+    /// configured one.
+    identity: RwLock<Option<Arc<Keypair>>>,
+    /// Bytes exchanged with every peer since we started.
+    traffic: Arc<Traffic>,
+    /// Caps upload to all peers together, if limited.
+    upload: Option<Arc<Mutex<TokenBucket>>>,
+    cancel: CancellationToken,
+    /// Stops the accept loop alone; a child of `cancel`.
+    accepting: CancellationToken,
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+impl Network {
+    /// Starts listening on the configured address. Messages from peers arrive
+    /// on the returned pipeline.
+    pub async fn bind(config: NetworkConfig) -> Result<(Arc<Self>, Arc<IngestPipeline>), NetworkError> {
+        let listener = TcpListener::bind(config.listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let ingest = Arc::new(IngestPipeline::new(config.ingest));
+        let mut peer_store = match &config.peer_store_path {
+            Some(path) => PeerStore::open(path)?,
+            None => PeerStore::default(),
+        };
+        peer_store.add_self(local_addr);
+        let mut reconnector = Reconnector::new(config.backoff);
+        for node in config.bootstrap_nodes.iter().filter(|node| !dns_seed::is_dns_seed(node)) {
+            match node.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+                Some(addr) => reconnector.add_bootstrap(addr, Instant::now()),
+                None => warn!("Cannot resolve bootstrap node {}", node),
             }
+        }
+        let scores = PeerScore::new(config.scoring.clone());
+        let seen = SeenCache::new(config.gossip.seen_ttl, config.gossip.seen_capacity);
+        let observed = ExternalAddress::new(config.min_observations);
+        let upload = TokenBucket::new(config.bandwidth.total_upload_bytes_per_sec, Instant::now());
+        let identity = config.identity.clone();
+        let cancel = CancellationToken::new();
+        let network = Arc::new(Network {
+            config,
+            local_addr,
+            connections: RwLock::new(HashMap::new()),
+            peer_store: Mutex::new(peer_store),
+            scores: Mutex::new(scores),
+            seen: Mutex::new(seen),
+            gossip_counters: GossipCounters::default(),
+            observed: Mutex::new(observed),
+            mapped: RwLock::new(None),
+            learned_peers: Notify::new(),
+            reconnector: Mutex::new(reconnector),
+            retry_scheduled: Notify::new(),
+            ingest: Arc::clone(&ingest),
+            gradients: broadcast::channel(GRADIENT_BACKLOG).0,
+            inference: broadcast::channel(INFERENCE_BACKLOG).0,
+            llm: RwLock::new(None),
+            revoked_llm: Mutex::new(HashSet::new()),
+            expected_identities: Mutex::new(HashMap::new()),
+            identity: RwLock::new(identity),
+            traffic: Arc::new(Traffic::default()),
+            upload: upload.map(|bucket| Arc::new(Mutex::new(bucket))),
+            accepting: cancel.child_token(),
+            cancel,
         });
+        info!("Node {} listening on {}", network.config.node_id, local_addr);
 
-       
-        self.connect_to_bootstrap_nodes().await?;
+        tokio::spawn(Arc::clone(&network).accept_loop(listener));
+        tokio::spawn(Arc::clone(&network).exchange_loop());
+        tokio::spawn(Arc::clone(&network).reconnect_loop());
+        Ok((network, ingest))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn ingest(&self) -> &Arc<IngestPipeline> {
+        &self.ingest
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.config.node_id
+    }
 
+    /// Gradient messages from now on, with the connection each came in on.
+    /// A subscriber more than `GRADIENT_BACKLOG` messages behind loses the
+    /// oldest.
+    pub fn subscribe_gradients(&self) -> broadcast::Receiver<(PeerId, GradientMessage)> {
+        self.gradients.subscribe()
+    }
+
+    /// Inference messages from now on, as `subscribe_gradients`.
+    pub fn subscribe_inference(&self) -> broadcast::Receiver<(PeerId, InferenceMessage)> {
+        self.inference.subscribe()
+    }
+
+    /// The `CAPABILITY_*` bits we advertise.
+    pub fn capabilities(&self) -> u32 {
+        self.config.capabilities
+    }
+
+    /// What we advertise serving inference with, if anything.
+    pub fn llm_capability(&self) -> Option<LlmCapability> {
+        self.llm.read().clone()
+    }
+
+    /// Advertises `capability` in handshakes from now on and to every peer
+    /// connected, as when the model serving changes.
+    pub fn advertise_llm(&self, capability: Option<LlmCapability>) {
+        *self.llm.write() = capability.clone();
+        self.broadcast(NetMessage::LlmCapability(capability));
+    }
+
+    /// The key we prove holding in handshakes.
+    pub fn identity(&self) -> Option<Pubkey> {
+        self.identity.read().as_ref().map(|keypair| keypair.pubkey())
+    }
+
+    pub fn handovers(&self) -> &HandoverRegistry {
+        &self.config.handovers
+    }
+
+    /// Records `handover` and gossips it, then proves `keypair`, its new
+    /// key, in handshakes from now on. Peers that have not heard of the
+    /// handover yet take the new key as a node of its own until they do.
+    pub fn hand_over(&self, handover: KeyHandover, keypair: Arc<Keypair>) -> Result<usize, HandoverError> {
+        if keypair.pubkey() != handover.new_pub {
+            return Err(HandoverError::InvalidSignature { key: handover.old_pub, signer: "new" });
+        }
+        self.config.handovers.record(handover.clone())?;
+        *self.identity.write() = Some(keypair);
+        info!("Handed identity {} over to {} from height {}", handover.old_pub, handover.new_pub, handover.valid_from_height);
+        Ok(self.gossip(NetMessage::KeyHandover(handover)))
+    }
+
+    /// Connected peers serving inference with a model `filter` matches,
+    /// the most recently heard from first.
+    pub fn find_llm_peers(&self, filter: &LlmPeerFilter) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.peers().into_iter()
+            .filter(|peer| peer.llm.as_ref().map_or(false, |llm| filter.matches(llm)))
+            .collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        peers
+    }
+
+    /// Stops taking `peer` as serving inference, even once it reconnects
+    /// or advertises again, as after it failed a challenge. Returns whether
+    /// it was connected.
+    pub fn revoke_llm(&self, peer: &PeerId) -> bool {
+        let mut connections = self.connections.write();
+        let Some(connection) = connections.get_mut(peer) else {
+            return false;
+        };
+        self.revoked_llm.lock().insert(connection.listen_addr);
+        connection.info.capabilities &= !CAPABILITY_LLM;
+        connection.info.llm = None;
+        info!("No longer taking {} as serving inference", connection.listen_addr);
+        true
+    }
+
+    fn update_llm(&self, peer: &PeerId, listen_addr: &SocketAddr, capability: Option<LlmCapability>) {
+        let revoked = self.revoked_llm.lock().contains(listen_addr);
+        if let Some(connection) = self.connections.write().get_mut(peer) {
+            connection.info.llm = capability.filter(|_| !revoked);
+        }
+    }
+
+    /// Connected peers.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let reconnector = self.reconnector.lock();
+        let now = Instant::now();
+        self.connections.read().values()
+            .map(|c| PeerInfo {
+                backoff: reconnector.status(&c.info.id, now),
+                last_seen: c.last_seen.load(Ordering::Relaxed),
+                traffic: c.traffic.snapshot(),
+                ..c.info.clone()
+            })
+            .collect()
+    }
+
+    /// A connected peer, or one we are waiting to redial.
+    pub fn peer_info(&self, addr: &SocketAddr) -> Option<PeerInfo> {
+        let backoff = self.reconnector.lock().status(addr, Instant::now());
+        match self.connections.read().get(addr) {
+            Some(c) => Some(PeerInfo {
+                backoff,
+                last_seen: c.last_seen.load(Ordering::Relaxed),
+                traffic: c.traffic.snapshot(),
+                ..c.info.clone()
+            }),
+            None => backoff.map(|backoff| PeerInfo {
+                id: *addr,
+                node_id: String::new(),
+                outbound: true,
+                connected: false,
+                protocol_version: 0,
+                codec: Codec::None,
+                capabilities: 0,
+                llm: None,
+                identity: None,
+                last_seen: 0,
+                traffic: TrafficStats::default(),
+                backoff: Some(backoff),
+            }),
+        }
+    }
+
+    /// False once `shutdown` has been called.
+    pub fn is_running(&self) -> bool {
+        !self.cancel.is_cancelled()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.connections.read().len()
+    }
+
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.connections.read().contains_key(peer)
+    }
+
+    fn outbound_count(&self) -> usize {
+        self.connections.read().values().filter(|c| c.info.outbound).count()
+    }
+
+    /// Periodically refreshes our outbound peers in the store and asks
+    /// everyone for their peers. Outbound connections are topped up on every
+    /// tick and whenever gossip brings new addresses.
+    async fn exchange_loop(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.config.peer_exchange_interval);
         loop {
             tokio::select! {
-                result = listener.accept() => {
-                    match result {
-                        Ok((socket, addr)) => {
-                            let tx = self.tx.clone();
-                            let peers = Arc::clone(&self.peers);
-                            
-                            debug!("New connection from {}", addr);
-                            
-                            tokio::spawn(async move {
-                                match timeout(CONNECTION_TIMEOUT, Self::handle_connection(socket, addr, tx, peers)).await {
-                                    Ok(result) => {
-                                        if let Err(e) = result {
-                                            error!("Error handling connection from {}: {}", addr, e);
-                                        }
-                                    }
-                                    Err(_) => {
-                                        error!("Connection handling timeout for {}", addr);
-                                    }
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            error!("Error accepting connection: {}", e);
-                            sleep(Duration::from_secs(1)).await;
-                        }
+                _ = self.cancel.cancelled() => return,
+                _ = self.learned_peers.notified() => {}
+                _ = ticker.tick() => {
+                    let outbound: Vec<PeerId> = self.connections.read().values()
+                        .filter(|c| c.info.outbound)
+                        .map(|c| c.info.id)
+                        .collect();
+                    let mut store = self.peer_store.lock();
+                    for addr in outbound {
+                        store.mark_reachable(addr, now_ms());
                     }
+                    drop(store);
+                    self.broadcast(NetMessage::GetPeers);
                 }
             }
+            self.discover().await;
         }
     }
 
-    pub async fn shutdown(&self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Shutting down node...");
-        let _ = self.shutdown.send(()).await;
-        Ok(())
+    /// Redials dropped peers and bootstrap nodes as their backoff expires.
+    async fn reconnect_loop(self: Arc<Self>) {
+        loop {
+            let next = self.reconnector.lock().next_due();
+            tokio::select! {
+                _ = self.cancel.cancelled() => return,
+                _ = self.retry_scheduled.notified() => continue,
+                _ = async {
+                    match next {
+                        Some(at) => sleep_until(at).await,
+                        None => sleep(Duration::from_secs(3600)).await,
+                    }
+                } => {}
+            }
+
+            let due = self.reconnector.lock().take_due(Instant::now());
+            for addr in due {
+                let network = Arc::clone(&self);
+                tokio::spawn(async move {
+                    if let Err(e) = network.connect(addr).await {
+                        debug!("Reconnect to {} failed: {}", addr, e);
+                    }
+                });
+            }
+        }
     }
 
-    async fn verify_stake(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let balance = self.rpc_client
-            .get_balance(&self.keypair.pubkey())
-            .await?;
+    /// Dials candidates from the peer store until the outbound target is met.
+    async fn discover(self: &Arc<Self>) {
+        let missing = self.config.outbound_target.saturating_sub(self.outbound_count());
+        if missing == 0 {
+            return;
+        }
+        let connected: HashSet<PeerId> = self.connections.read().keys().copied().collect();
+        let candidates = self.peer_store.lock().dial_candidates(&connected, now_ms(), missing);
+        for addr in candidates {
+            if self.outbound_count() >= self.config.outbound_target {
+                break;
+            }
+            match self.connect(addr).await {
+                Ok(_) => info!("Discovered peer at {}", addr),
+                Err(e) => debug!("Failed to dial discovered peer {}: {}", addr, e),
+            }
+        }
+    }
 
-        if balance < self.config.min_stake {
-            return Err("Insufficient stake amount".into());
+    async fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = self.accepting.cancelled() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Error accepting connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+            if self.peer_count() >= self.config.max_connections {
+                debug!("Refusing connection from {}: connection limit reached", addr);
+                continue;
+            }
+            let network = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = network.establish(stream, addr, false).await {
+                    debug!("Inbound connection from {} failed: {}", addr, e);
+                }
+            });
         }
+    }
 
-        Ok(())
+    /// Dials `addr` and completes the handshake.
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> Result<PeerId, NetworkError> {
+        let result = if self.peer_count() >= self.config.max_connections {
+            Err(NetworkError::TooManyConnections(self.config.max_connections))
+        } else {
+            self.dial(addr).await
+        };
+
+        let mut reconnector = self.reconnector.lock();
+        match &result {
+            Ok(()) => reconnector.connected(addr),
+            Err(
+                NetworkError::SelfConnection
+                | NetworkError::VersionMismatch { .. }
+                | NetworkError::ChainMismatch { .. }
+                | NetworkError::GenesisMismatch { .. }
+                | NetworkError::IdentityMismatch { .. },
+            ) => {
+                reconnector.give_up(addr, Instant::now());
+            }
+            // Reachable under another address we are already connected through.
+            Err(NetworkError::DuplicatePeer(_)) => reconnector.forget(&addr),
+            Err(NetworkError::Banned(..)) => reconnector.give_up(addr, Instant::now()),
+            Err(_) => reconnector.failed(addr, Instant::now()),
+        }
+        drop(reconnector);
+        self.retry_scheduled.notify_one();
+        result.map(|()| addr)
     }
 
-    async fn connect_to_bootstrap_nodes(&self) -> Result<(), Box<dyn std::error::Error>> {
-        for node in &self.config.bootstrap_nodes {
-            let mut attempts = 0;
-            while attempts < MAX_RECONNECT_ATTEMPTS {
-                match TcpStream::connect(node).await {
-                    Ok(stream) => {
-                        info!("Connected to bootstrap node: {}", node);
-                        let peers = Arc::clone(&self.peers);
-                        if let Err(e) = self.handle_outbound_connection(stream, peers).await {
-                            error!("Error handling connection to {}: {}", node, e);
-                            attempts += 1;
-                            sleep(RECONNECT_DELAY).await;
-                            continue;
+    async fn dial(self: &Arc<Self>, addr: SocketAddr) -> Result<(), NetworkError> {
+        self.ensure_not_banned(&addr)?;
+        let result = match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Err(_) => Err(NetworkError::Handshake(format!("connection to {} timed out", addr))),
+            Ok(Err(e)) => Err(e.into()),
+            Ok(Ok(stream)) => Arc::clone(self).establish(stream, addr, true).await,
+        };
+
+        let mut store = self.peer_store.lock();
+        match result {
+            // The address answered as a node we already know, so it is reachable.
+            Ok(()) | Err(NetworkError::DuplicatePeer(_)) => store.mark_reachable(addr, now_ms()),
+            Err(NetworkError::SelfConnection) => store.add_self(addr),
+            Err(NetworkError::Banned(..)) => {}
+            Err(_) => store.mark_failed(addr, now_ms()),
+        }
+        result
+    }
+
+    /// Refuses a peer whose handshake does not prove the identity it
+    /// claims, counting it against the address it listens at.
+    async fn refuse_impostor<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        addr: SocketAddr,
+        listen_addr: SocketAddr,
+        reason: String,
+    ) -> NetworkError {
+        warn!("Refusing {}: {}", addr, reason);
+        self.penalize(listen_addr, Offense::InvalidSignature);
+        let _ = write_frame(writer, &NetMessage::Disconnect(reason.clone()), self.config.max_frame_bytes).await;
+        NetworkError::Handshake(reason)
+    }
+
+    /// Dials an inbound peer back at the address it advertised and reads
+    /// the handshake there. The address is kept for peer lists, as
+    /// verified, only if the node answering is the one that connected.
+    async fn dial_back(self: Arc<Self>, listen_addr: SocketAddr, node_id: String) {
+        let max_frame_bytes = self.config.max_frame_bytes;
+        let answered = async {
+            let mut stream = TcpStream::connect(listen_addr).await?;
+            let hello = read_frame(&mut stream, max_frame_bytes).await?;
+            let goodbye = NetMessage::Disconnect("dial-back check".to_string());
+            let _ = write_frame(&mut stream, &goodbye, max_frame_bytes).await;
+            Ok::<_, NetworkError>(hello)
+        };
+        match timeout(CONNECT_TIMEOUT + HANDSHAKE_TIMEOUT, answered).await {
+            Ok(Ok(NetMessage::Handshake { node_id: answered, .. })) if answered == node_id => {
+                debug!("{} listens at {}", node_id, listen_addr);
+                self.peer_store.lock().mark_reachable(listen_addr, now_ms());
+            }
+            Ok(Ok(NetMessage::Handshake { node_id: answered, .. })) => {
+                warn!("{} advertised {}, where {} answers", node_id, listen_addr, answered);
+            }
+            Ok(Ok(other)) => debug!("{} advertised {}, which answered {:?}", node_id, listen_addr, other),
+            Ok(Err(e)) => debug!("{} does not listen at {} as advertised: {}", node_id, listen_addr, e),
+            Err(_) => debug!("{} does not answer at {} as advertised", node_id, listen_addr),
+        }
+    }
+
+    /// Dials every bootstrap node, returning how many connections succeeded.
+    /// DNS seeds are left to `resolve_dns_seeds`.
+    pub async fn dial_bootstrap(self: &Arc<Self>) -> usize {
+        let mut connected = 0;
+        for node in self.config.bootstrap_nodes.iter().filter(|node| !dns_seed::is_dns_seed(node)) {
+            let addr = match node.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+                Some(addr) => addr,
+                None => {
+                    warn!("Cannot resolve bootstrap node {}", node);
+                    continue;
+                }
+            };
+            match self.connect(addr).await {
+                Ok(_) => connected += 1,
+                Err(e) => warn!("Failed to connect to bootstrap node {}: {}", node, e),
+            }
+        }
+        connected
+    }
+
+    /// Resolves every DNS seed into dial candidates, falling back to what a
+    /// seed listed last time if it cannot be resolved now. Returns how many
+    /// candidates were new.
+    pub async fn resolve_dns_seeds(&self, resolver: &dyn SeedResolver) -> usize {
+        let mut added = 0;
+        for seed in self.config.dns_seeds() {
+            let name = seed.to_string();
+            let peers = match dns_seed::resolve(resolver, &seed, &self.config.dns_seed).await {
+                Ok(resolution) => {
+                    if resolution.malformed > 0 {
+                        warn!("Ignored {} malformed TXT entries from {}", resolution.malformed, name);
+                    }
+                    debug!("{} lists {} peers", name, resolution.peers.len());
+                    if let Err(e) = self.peer_store.lock().cache_seed(&name, &resolution.peers) {
+                        warn!("Cannot save the peers {} lists: {}", name, e);
+                    }
+                    resolution.peers
+                }
+                Err(e) => {
+                    let cached = self.peer_store.lock().cached_seed(&name);
+                    warn!("Cannot resolve {}, using the {} peers it listed before: {}", name, cached.len(), e);
+                    cached
+                }
+            };
+            added += self.add_seed_peers(&peers);
+        }
+        if added > 0 {
+            self.learned_peers.notify_one();
+        }
+        added
+    }
+
+    fn add_seed_peers(&self, peers: &[SeedPeer]) -> usize {
+        let now = now_ms();
+        let mut expected = self.expected_identities.lock();
+        let mut store = self.peer_store.lock();
+        let mut added = 0;
+        for peer in peers {
+            // Kept after a seed stops listing the key, so a later answer
+            // cannot lift the requirement.
+            if let Some(pubkey) = peer.pubkey {
+                expected.insert(peer.addr, pubkey);
+            }
+            if !store.is_banned(&peer.addr, now) && store.add_candidate(peer.addr, now, now) {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Resolves the DNS seeds now and every `dns_seed.refresh_secs` until
+    /// cancelled.
+    pub async fn run_dns_seeds(self: Arc<Self>, resolver: Arc<dyn SeedResolver>, cancel: CancellationToken) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.dns_seed.refresh_secs));
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+            let added = self.resolve_dns_seeds(resolver.as_ref()).await;
+            debug!("DNS seeds gave {} new candidates", added);
+        }
+    }
+
+    async fn establish(self: Arc<Self>, stream: TcpStream, addr: SocketAddr, outbound: bool) -> Result<(), NetworkError> {
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+        let max_frame_bytes = self.config.max_frame_bytes;
+
+        let (min_version, max_version) = (self.config.min_protocol_version, self.config.protocol_version);
+        // Read once, so a rotation mid-handshake cannot split the key we
+        // claim from the one we prove.
+        let keypair = self.identity.read().clone();
+        let nonce: [u8; 32] = rand::random();
+        let hello = NetMessage::Handshake {
+            version: max_version,
+            min_version,
+            node_id: self.config.node_id.clone(),
+            listen_port: self.advertised_port(),
+            codecs: self.config.codecs.clone(),
+            chain_id: self.config.chain_id.clone(),
+            genesis_hash: self.config.genesis_hash,
+            observed_addr: Some(addr.to_string()),
+            capabilities: self.config.capabilities,
+            llm: self.llm_capability(),
+            identity: keypair.as_ref().map(|keypair| keypair.pubkey()),
+            nonce,
+        };
+        write_frame(&mut writer, &hello, max_frame_bytes).await?;
+        let (
+            node_id, listen_port, version, their_codecs, observed_addr, mut capabilities, mut llm, claimed, their_nonce,
+        ) = match timeout(HANDSHAKE_TIMEOUT, read_frame(&mut reader, max_frame_bytes)).await {
+                Err(_) => return Err(NetworkError::Handshake("timed out".to_string())),
+                Ok(Ok(NetMessage::Handshake { version, min_version: their_min, node_id, .. }))
+                    if their_min > max_version || version < min_version =>
+                {
+                    let reason = format!(
+                        "no common protocol version: {} speaks {}-{}, {} speaks {}-{}",
+                        self.config.node_id, min_version, max_version, node_id, their_min, version
+                    );
+                    warn!("Refusing {} at {}: {}", node_id, addr, reason);
+                    let _ = write_frame(&mut writer, &NetMessage::Disconnect(reason), max_frame_bytes).await;
+                    return Err(NetworkError::VersionMismatch { ours: max_version, theirs: version });
+                }
+                Ok(Ok(NetMessage::Handshake { node_id, chain_id, .. })) if chain_id != self.config.chain_id => {
+                    let reason = format!(
+                        "different chain: {} is on {}, {} is on {}",
+                        self.config.node_id, self.config.chain_id, node_id, chain_id
+                    );
+                    warn!("Refusing {} at {}: {}", node_id, addr, reason);
+                    let _ = write_frame(&mut writer, &NetMessage::Disconnect(reason), max_frame_bytes).await;
+                    return Err(NetworkError::ChainMismatch { ours: self.config.chain_id.clone(), theirs: chain_id });
+                }
+                Ok(Ok(NetMessage::Handshake { node_id, genesis_hash, .. }))
+                    if genesis_hash != self.config.genesis_hash =>
+                {
+                    let reason = format!(
+                        "different genesis: {} has {}, {} has {}",
+                        self.config.node_id, self.config.genesis_hash, node_id, genesis_hash
+                    );
+                    warn!("Refusing {} at {}: {}", node_id, addr, reason);
+                    let _ = write_frame(&mut writer, &NetMessage::Disconnect(reason), max_frame_bytes).await;
+                    return Err(NetworkError::GenesisMismatch { ours: self.config.genesis_hash, theirs: genesis_hash });
+                }
+                Ok(Ok(NetMessage::Handshake {
+                    version, node_id, listen_port, codecs, observed_addr, capabilities, llm, identity, nonce, ..
+                })) => {
+                    let version = version.min(max_version);
+                    (node_id, listen_port, version, codecs, observed_addr, capabilities, llm, identity, nonce)
+                }
+                Ok(Ok(NetMessage::Disconnect(reason))) => return Err(NetworkError::Refused(reason)),
+                Ok(Ok(other)) => return Err(NetworkError::Handshake(format!("expected handshake, got {:?}", other))),
+                Ok(Err(e)) => return Err(e),
+            };
+        // Both sides pick the dialer's most preferred codec so they agree
+        // without another round trip.
+        let codec = match version {
+            v if v < COMPRESSION_VERSION => Codec::None,
+            _ if outbound => Codec::negotiate(&self.config.codecs, &their_codecs),
+            _ => Codec::negotiate(&their_codecs, &self.config.codecs),
+        };
+        if node_id == self.config.node_id {
+            return Err(NetworkError::SelfConnection);
+        }
+        let listen_addr = if outbound { addr } else { SocketAddr::new(addr.ip(), listen_port) };
+        if let Some(keypair) = &keypair {
+            let proof = IdentityProof::new(keypair, &self.config.node_id, &self.config.genesis_hash, &their_nonce);
+            write_frame(&mut writer, &NetMessage::HandshakeProof(proof), max_frame_bytes).await?;
+        }
+        let identity = match claimed {
+            Some(claimed) => {
+                let proof = match timeout(HANDSHAKE_TIMEOUT, read_frame(&mut reader, max_frame_bytes)).await {
+                    Err(_) => return Err(NetworkError::Handshake("timed out waiting for identity proof".to_string())),
+                    Ok(Ok(NetMessage::HandshakeProof(proof))) => proof,
+                    Ok(Ok(NetMessage::Disconnect(reason))) => return Err(NetworkError::Refused(reason)),
+                    Ok(Ok(other)) => {
+                        return Err(NetworkError::Handshake(format!("expected identity proof, got {:?}", other)))
+                    }
+                    Ok(Err(e)) => return Err(e),
+                };
+                if proof.pubkey != claimed || !proof.verify(&node_id, &self.config.genesis_hash, &nonce) {
+                    let reason = format!("{} sent an identity proof that does not verify", node_id);
+                    return Err(self.refuse_impostor(&mut writer, addr, listen_addr, reason).await);
+                }
+                // A key handed over is refused from the height the handover
+                // takes effect at; its successor is taken as soon as the
+                // handover is known, so a rotated node reconnects before then.
+                match self.config.handovers.check(&claimed, self.config.handovers.finalized_height() + 1) {
+                    Err(e @ HandoverError::Retired { .. }) => {
+                        warn!("Refusing {} at {}: {}", node_id, addr, e);
+                        let _ = write_frame(&mut writer, &NetMessage::Disconnect(e.to_string()), max_frame_bytes).await;
+                        return Err(NetworkError::Handshake(e.to_string()));
+                    }
+                    _ => Some(self.config.handovers.identity(&claimed)),
+                }
+            }
+            None => None,
+        };
+        // A node id in DADBS address form names the key behind it, so only
+        // the holder of that key may use it.
+        if let Ok(address) = DADBSAddress::from_string(&node_id) {
+            if identity.map(|identity| DADBSAddress::from_pubkey(&identity)) != Some(address) {
+                let reason = format!("{} is not the address of the identity proven", node_id);
+                return Err(self.refuse_impostor(&mut writer, addr, listen_addr, reason).await);
+            }
+        }
+        // Not penalized: the seed may be stale rather than the peer lying.
+        let expected = if outbound { self.expected_identities.lock().get(&addr).copied() } else { None };
+        if let Some(expected) = expected {
+            if claimed != Some(expected) && identity != Some(expected) {
+                warn!("Refusing {} at {}: listed with identity {}", node_id, addr, expected);
+                let reason = format!("expected identity {}", expected);
+                let _ = write_frame(&mut writer, &NetMessage::Disconnect(reason), max_frame_bytes).await;
+                return Err(NetworkError::IdentityMismatch { expected, actual: identity });
+            }
+        }
+        self.ensure_not_banned(&listen_addr)?;
+        if let Some(observed) = observed_addr.and_then(|observed| observed.parse::<SocketAddr>().ok()) {
+            self.observe_external(listen_addr, observed);
+        }
+        if self.revoked_llm.lock().contains(&listen_addr) {
+            capabilities &= !CAPABILITY_LLM;
+            llm = None;
+        }
+
+        let queue = Arc::new(SendQueue::new(&self.config.gossip));
+        let cancel = self.cancel.child_token();
+        let last_seen = Arc::new(AtomicI64::new(now_ms()));
+        let traffic = Arc::new(Traffic::default());
+        {
+            let mut connections = self.connections.write();
+            if connections.len() >= self.config.max_connections {
+                return Err(NetworkError::TooManyConnections(self.config.max_connections));
+            }
+            if connections.values().any(|c| c.info.node_id == node_id) {
+                return Err(NetworkError::DuplicatePeer(node_id));
+            }
+            connections.insert(addr, Connection {
+                info: PeerInfo {
+                    id: addr,
+                    node_id: node_id.clone(),
+                    outbound,
+                    connected: true,
+                    protocol_version: version,
+                    codec,
+                    capabilities,
+                    llm,
+                    identity,
+                    last_seen: 0,
+                    traffic: TrafficStats::default(),
+                    backoff: None,
+                },
+                listen_addr,
+                outbound: Arc::clone(&queue),
+                cancel: cancel.clone(),
+                last_seen: Arc::clone(&last_seen),
+                traffic: Arc::clone(&traffic),
+            });
+        }
+        let multiplexed = version >= MUX_VERSION;
+        info!(
+            "Connected to {} at {} (protocol v{}, compression {}, {})",
+            node_id, addr, version, codec,
+            if multiplexed { "multiplexed" } else { "single stream" }
+        );
+        if !outbound {
+            if self.config.dial_back {
+                tokio::spawn(Arc::clone(&self).dial_back(listen_addr, node_id.clone()));
+            } else {
+                // Unverified until we dial it ourselves.
+                let now = now_ms();
+                self.peer_store.lock().add_candidate(listen_addr, now, now);
+            }
+        }
+        queue.push(NetMessage::GetPeers, &self.gossip_counters);
+        // Peers that were away when a handover was gossiped learn of it
+        // here, whichever side rotated.
+        for handover in self.config.handovers.all() {
+            queue.push(NetMessage::KeyHandover(handover), &self.gossip_counters);
+        }
+
+        let writer_cancel = cancel.clone();
+        let writer_queue = Arc::clone(&queue);
+        let framing = Framing { codec, max_frame_bytes, threshold: self.config.compression_threshold, multiplexed };
+        let pacer = OutboundPacer::new(
+            TokenBucket::new(self.config.bandwidth.peer_upload_bytes_per_sec, Instant::now()),
+            self.upload.clone(),
+        );
+        let (peer_traffic, total_traffic) = (Arc::clone(&traffic), Arc::clone(&self.traffic));
+        tokio::spawn(async move {
+            let counted = [&*peer_traffic, &*total_traffic];
+            tokio::select! {
+                _ = writer_cancel.cancelled() => {}
+                written = write_paced(&mut writer, &writer_queue, pacer, counted, framing) => {
+                    if let Err(e) = written {
+                        debug!("Write to {} failed: {}", addr, e);
+                    }
+                }
+            }
+            writer_queue.close();
+            writer_cancel.cancel();
+        });
+
+        let network = Arc::clone(&self);
+        tokio::spawn(async move {
+            let mut window_start = Instant::now();
+            let mut window_messages = 0u32;
+            let mut download = TokenBucket::new(network.config.bandwidth.peer_download_bytes_per_sec, Instant::now());
+            let mut last_overrun: Option<Instant> = None;
+            let mut demux = Demux::new(multiplexed);
+            loop {
+                let frame = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    frame = demux.read(&mut reader, max_frame_bytes) => frame,
+                };
+                let message = match frame {
+                    Ok((message, bytes)) => {
+                        last_seen.store(now_ms(), Ordering::Relaxed);
+                        let category = MessageCategory::of(&message);
+                        traffic.record_received(category, bytes);
+                        network.traffic.record_received(category, bytes);
+                        if let Some(bucket) = download.as_mut() {
+                            let now = Instant::now();
+                            bucket.take(bytes, now);
+                            let wait = bucket.wait(0.0, now);
+                            if !wait.is_zero() {
+                                // Sync batches answer our own requests; other
+                                // traffic past the cap costs the peer at most
+                                // once a second.
+                                let penalize = category != MessageCategory::Sync
+                                    && last_overrun.map_or(true, |at| now.duration_since(at) >= Duration::from_secs(1));
+                                if penalize {
+                                    last_overrun = Some(now);
+                                    if network.penalize(listen_addr, Offense::ExcessBandwidth) {
+                                        break;
+                                    }
+                                }
+                                tokio::select! {
+                                    _ = cancel.cancelled() => break,
+                                    _ = sleep(wait) => {}
+                                }
+                            }
                         }
+                        message
+                    }
+                    Err(NetworkError::FrameTooLarge { size, max }) => {
+                        warn!("Dropping {}: sent a {} byte frame, limit is {}", addr, size, max);
+                        network.penalize(listen_addr, Offense::OversizedFrame);
+                        break;
+                    }
+                    Err(NetworkError::Compression(CompressionError::TooLarge(max))) => {
+                        warn!("Dropping {}: frame decompresses past the {} byte limit", addr, max);
+                        network.penalize(listen_addr, Offense::OversizedFrame);
+                        break;
+                    }
+                    Err(e @ (NetworkError::Decode(_) | NetworkError::Compression(_))) => {
+                        warn!("Dropping {}: malformed frame: {}", addr, e);
+                        network.penalize(listen_addr, Offense::MalformedFrame);
                         break;
                     }
                     Err(e) => {
-                        warn!("Failed to connect to bootstrap node {}: {}", node, e);
-                        attempts += 1;
-                        if attempts < MAX_RECONNECT_ATTEMPTS {
-                            sleep(RECONNECT_DELAY).await;
+                        debug!("Connection to {} closed: {}", addr, e);
+                        break;
+                    }
+                };
+
+                if window_start.elapsed() >= Duration::from_secs(1) {
+                    window_start = Instant::now();
+                    window_messages = 0;
+                }
+                window_messages += 1;
+                if window_messages > network.config.max_messages_per_second {
+                    // Penalized once per window; the excess is dropped.
+                    if window_messages == network.config.max_messages_per_second + 1
+                        && network.penalize(listen_addr, Offense::Spam)
+                    {
+                        break;
+                    }
+                    continue;
+                }
+
+                let offense = match &message {
+                    NetMessage::Handshake { .. } | NetMessage::HandshakeProof(_) => Some(Offense::ProtocolViolation),
+                    NetMessage::Tx(tx) if !tx.verify_signature() => Some(Offense::InvalidSignature),
+                    NetMessage::Heartbeat(heartbeat) if heartbeat.verify(&network.config.chain_id).is_err() => {
+                        Some(Offense::InvalidSignature)
+                    }
+                    NetMessage::KeyHandover(handover) if handover.verify(&network.config.chain_id).is_err() => {
+                        Some(Offense::InvalidSignature)
+                    }
+                    NetMessage::Proposal(proposal) if !proposal.verify(&network.config.chain_id) => {
+                        Some(Offense::InvalidSignature)
+                    }
+                    _ => None,
+                };
+                if let Some(offense) = offense {
+                    debug!("Dropping message from {}: {}", addr, offense);
+                    if network.penalize(listen_addr, offense) {
+                        break;
+                    }
+                    continue;
+                }
+
+                match &message {
+                    NetMessage::Disconnect(reason) => {
+                        info!("{} closed the connection: {}", addr, reason);
+                        break;
+                    }
+                    NetMessage::Ping(nonce) => {
+                        queue.push(NetMessage::Pong(*nonce), &network.gossip_counters);
+                    }
+                    NetMessage::GetPeers => {
+                        let limit = network.config.max_peers_per_response;
+                        let mut peers = network.peer_store.lock().verified(now_ms(), limit);
+                        if let Some(external) = network.external_addr() {
+                            peers.insert(0, PeerRecord { addr: external.to_string(), last_seen: now_ms() });
+                            peers.truncate(limit);
+                        }
+                        queue.push(NetMessage::Peers(peers), &network.gossip_counters);
+                        continue;
+                    }
+                    NetMessage::Peers(records) => {
+                        let limit = network.config.max_peers_per_response;
+                        if records.len() > limit && network.penalize(listen_addr, Offense::Spam) {
+                            break;
+                        }
+                        let added = network.peer_store.lock().merge(&records[..records.len().min(limit)], now_ms());
+                        if added > 0 {
+                            debug!("Learned {} new peer addresses from {}", added, addr);
+                            network.learned_peers.notify_one();
+                        }
+                        continue;
+                    }
+                    NetMessage::Gradients(gradients) => {
+                        // Nobody training is no reason to drop the peer.
+                        let _ = network.gradients.send((addr, gradients.clone()));
+                        continue;
+                    }
+                    NetMessage::Inference(inference) => {
+                        let _ = network.inference.send((addr, inference.clone()));
+                        continue;
+                    }
+                    NetMessage::LlmCapability(capability) => {
+                        network.update_llm(&addr, &listen_addr, capability.clone());
+                        continue;
+                    }
+                    // Relayed and passed on only the first time; one that
+                    // conflicts with a handover known is not an offense,
+                    // peers may simply have heard of them in another order.
+                    NetMessage::KeyHandover(handover) => match network.config.handovers.record(handover.clone()) {
+                        Ok(true) => info!(
+                            "{} handed identity {} over to {} from height {}",
+                            addr, handover.old_pub, handover.new_pub, handover.valid_from_height
+                        ),
+                        Ok(false) => continue,
+                        Err(e) => {
+                            debug!("Ignoring handover from {}: {}", addr, e);
+                            continue;
                         }
+                    },
+                    _ => {}
+                }
+                if let Some(id) = gossip::message_id(&message) {
+                    if !network.seen.lock().insert(id, Instant::now()) {
+                        network.gossip_counters.duplicate();
+                        continue;
                     }
+                    network.relay(Some(&addr), &message);
+                }
+                let ingested = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    ingested = network.ingest.push(addr, message) => ingested,
+                };
+                match ingested {
+                    Ingested::Closed => break,
+                    Ingested::Shed => debug!("Shed a transaction from {}: its queue is full", addr),
+                    Ingested::Queued => {}
                 }
             }
-        }
+            cancel.cancel();
+            queue.close();
+            let dropped = network.connections.write().remove(&addr).is_some();
+            info!("Disconnected from {}", addr);
+            if dropped && outbound && !network.cancel.is_cancelled() {
+                network.reconnector.lock().failed(addr, Instant::now());
+                network.retry_scheduled.notify_one();
+            }
+        });
         Ok(())
     }
 
-    async fn handle_connection(
-        socket: TcpStream,
-        addr: SocketAddr,
-        tx: broadcast::Sender<Message>,
-        peers: Arc<RwLock<HashMap<SocketAddr, PeerInfo>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        socket.set_nodelay(true)?;
-        
-        
-        let keepalive = socket2::TcpKeepalive::new()
-            .with_time(Duration::from_secs(60))
-            .with_interval(Duration::from_secs(10));
-        
-        let socket2 = socket2::SockRef::from(&socket);
-        socket2.set_tcp_keepalive(&keepalive)?;
-        
-        
-        peers.write().insert(addr, PeerInfo::new(addr));
-        
-        
-        Ok(())
+    pub async fn send(&self, peer: &PeerId, message: NetMessage) -> Result<(), NetworkError> {
+        let queue = self.connections.read()
+            .get(peer)
+            .map(|c| Arc::clone(&c.outbound))
+            .ok_or(NetworkError::NotConnected(*peer))?;
+        match queue.push(message, &self.gossip_counters) {
+            Enqueued::Full => Err(NetworkError::QueueFull(*peer)),
+            Enqueued::Queued | Enqueued::DroppedOldest => Ok(()),
+        }
     }
 
-    async fn handle_outbound_connection(
-        &self,
-        stream: TcpStream,
-        peers: Arc<RwLock<HashMap<SocketAddr, PeerInfo>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let addr = stream.peer_addr()?;
-        stream.set_nodelay(true)?;
-        
-        
-        let keepalive = socket2::TcpKeepalive::new()
-            .with_time(Duration::from_secs(60))
-            .with_interval(Duration::from_secs(10));
-        
-        let socket2 = socket2::SockRef::from(&stream);
-        socket2.set_tcp_keepalive(&keepalive)?;
-        
-        
-        peers.write().insert(addr, PeerInfo::new(addr));
-        
-        
+    /// Queues `message` for every connected peer, returning how many it was
+    /// queued for. Peers whose queue is full miss the message.
+    pub fn broadcast(&self, message: NetMessage) -> usize {
+        let connections = self.connections.read();
+        connections.values()
+            .filter(|c| match c.outbound.push(message.clone(), &self.gossip_counters) {
+                Enqueued::Full => {
+                    debug!("Dropping broadcast to {}: send queue full", c.info.id);
+                    false
+                }
+                Enqueued::Queued | Enqueued::DroppedOldest => true,
+            })
+            .count()
+    }
+
+    /// Gossips a transaction, block or vote originating here to a random
+    /// subset of peers, returning how many it was queued for. Messages
+    /// already seen are not sent again.
+    pub fn gossip(&self, message: NetMessage) -> usize {
+        if let Some(id) = gossip::message_id(&message) {
+            if !self.seen.lock().insert(id, Instant::now()) {
+                return 0;
+            }
+        }
+        self.relay(None, &message)
+    }
+
+    fn relay(&self, from: Option<&PeerId>, message: &NetMessage) -> usize {
+        let connections = self.connections.read();
+        let peers: Vec<PeerId> = connections.keys().copied().collect();
+        gossip::choose_peers(&peers, from, self.config.gossip.fanout).iter()
+            .filter_map(|peer| connections.get(peer))
+            .filter(|c| c.outbound.push(message.clone(), &self.gossip_counters) != Enqueued::Full)
+            .count()
+    }
+
+    pub fn gossip_stats(&self) -> GossipStats {
+        self.gossip_counters.snapshot()
+    }
+
+    /// Bytes exchanged with every peer since we started.
+    pub fn traffic(&self) -> TrafficStats {
+        self.traffic.snapshot()
+    }
+
+    /// Closes the connection to `peer` without redialing it. Bootstrap nodes
+    /// are still retried after a backoff.
+    pub fn disconnect(&self, peer: &PeerId) {
+        let mut reconnector = self.reconnector.lock();
+        reconnector.forget(peer);
+        if reconnector.contains(peer) {
+            reconnector.failed(*peer, Instant::now());
+        }
+        drop(reconnector);
+        self.retry_scheduled.notify_one();
+        if let Some(connection) = self.connections.write().remove(peer) {
+            connection.cancel.cancel();
+        }
+    }
+
+    /// Records the address `reporter` sees us at, unless it has been
+    /// misbehaving: its score is at least half the ban threshold.
+    fn observe_external(&self, reporter: SocketAddr, observed: SocketAddr) {
+        let score = self.peer_score(&reporter);
+        if score >= self.config.scoring.ban_threshold / 2.0 {
+            debug!("Ignoring address report from {} with score {:.0}", reporter, score);
+            return;
+        }
+        if self.observed.lock().record(reporter.ip(), observed.ip()) {
+            self.external_changed();
+        }
+    }
+
+    fn external_changed(&self) {
+        match self.external_addr() {
+            Some(external) => {
+                info!("Advertising external address {}", external);
+                self.peer_store.lock().add_self(external);
+            }
+            None => info!("Peers no longer agree on our external address"),
+        }
+    }
+
+    /// The port peers should dial: the gateway's if a port is mapped.
+    fn advertised_port(&self) -> u16 {
+        self.mapped.read().map_or(self.local_addr.port(), |mapped| mapped.port())
+    }
+
+    /// The address we advertise to peers: the IP most peers see us at, or
+    /// the gateway's while they do not agree, with `advertised_port`.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        let port = self.advertised_port();
+        match self.observed.lock().majority() {
+            Some(ip) => Some(SocketAddr::new(ip, port)),
+            None => *self.mapped.read(),
+        }
+    }
+
+    /// Records that a gateway forwards `external` to us.
+    pub fn set_port_mapping(&self, external: Option<SocketAddr>) {
+        *self.mapped.write() = external;
+        if let Some(external) = external {
+            self.peer_store.lock().add_self(external);
+        }
+    }
+
+    fn ensure_not_banned(&self, addr: &SocketAddr) -> Result<(), NetworkError> {
+        match self.peer_store.lock().ban_for(addr, now_ms()) {
+            Some(ban) => Err(NetworkError::Banned(*addr, ban.reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Penalizes the peer listening at `listen_addr`, banning it once its
+    /// score crosses the threshold. Returns whether it was banned.
+    fn penalize(&self, listen_addr: SocketAddr, offense: Offense) -> bool {
+        let (score, ban) = {
+            let mut scores = self.scores.lock();
+            let score = scores.record(listen_addr, offense, now_ms());
+            (score, scores.should_ban(score))
+        };
+        debug!("Peer {} penalized for {}, score {:.0}", listen_addr, offense, score);
+        if !ban {
+            return false;
+        }
+        let reason = format!("score {:.0} after {}", score, offense);
+        if let Err(e) = self.ban_peer(listen_addr, self.config.scoring.ban_duration, &reason) {
+            warn!("Failed to persist ban of {}: {}", listen_addr, e);
+        }
+        true
+    }
+
+    /// Reports misbehavior noticed above the transport, e.g. an invalid block.
+    pub fn report(&self, peer: &PeerId, offense: Offense) -> bool {
+        self.penalize(self.listen_addr_of(peer), offense)
+    }
+
+    /// Whether the peer is banned, by the address it listens on.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.ensure_not_banned(&self.listen_addr_of(peer)).is_err()
+    }
+
+    fn listen_addr_of(&self, peer: &PeerId) -> SocketAddr {
+        self.connections.read().get(peer).map_or(*peer, |c| c.listen_addr)
+    }
+
+    /// How long misbehaving peers are banned for.
+    pub fn ban_duration(&self) -> Duration {
+        self.config.scoring.ban_duration
+    }
+
+    pub fn peer_score(&self, listen_addr: &SocketAddr) -> f64 {
+        self.scores.lock().score(listen_addr, now_ms())
+    }
+
+    /// Disconnects the peer listening at `addr` and refuses connections to
+    /// and from it for `duration`.
+    pub fn ban_peer(&self, addr: SocketAddr, duration: Duration, reason: &str) -> Result<(), NetworkError> {
+        warn!("Banning peer {} for {:?}: {}", addr, duration, reason);
+        self.scores.lock().clear(&addr);
+        self.reconnector.lock().give_up(addr, Instant::now());
+        let mut connections = self.connections.write();
+        let banned: Vec<PeerId> = connections.iter()
+            .filter(|(id, c)| **id == addr || c.listen_addr == addr)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in banned {
+            if let Some(connection) = connections.remove(&id) {
+                connection.cancel.cancel();
+            }
+        }
+        drop(connections);
+        if self.observed.lock().forget(&addr.ip()) {
+            self.external_changed();
+        }
+        let until = now_ms().saturating_add(duration.as_millis() as i64);
+        self.peer_store.lock().ban(addr, until, reason)?;
         Ok(())
     }
 
-    async fn cleanup_disconnected_peers(peers: Arc<RwLock<HashMap<SocketAddr, PeerInfo>>>) {
-        let mut peers = peers.write();
-        peers.retain(|addr, peer| {
-            if !peer.is_connected() {
-                warn!("Removing disconnected peer: {}", addr);
-                false
-            } else {
-                true
+    /// Lifts a ban, returning whether there was one.
+    pub fn unban_peer(&self, addr: &SocketAddr) -> Result<bool, NetworkError> {
+        let removed = self.peer_store.lock().unban(addr)?;
+        if removed {
+            info!("Unbanned peer {}", addr);
+        }
+        Ok(removed)
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.peer_store.lock().bans(now_ms())
+    }
+
+    /// Refuses new inbound connections; existing ones are kept.
+    pub fn stop_accepting(&self) {
+        self.accepting.cancel();
+    }
+
+    /// Tells every connected peer we are leaving, waiting up to `timeout`
+    /// for the messages to be written.
+    pub async fn say_goodbye(&self, reason: &str, timeout: Duration) {
+        let queues: Vec<Arc<SendQueue>> = self.connections.read().values()
+            .map(|connection| Arc::clone(&connection.outbound))
+            .collect();
+        for queue in &queues {
+            queue.push(NetMessage::Disconnect(reason.to_string()), &self.gossip_counters);
+        }
+        let deadline = Instant::now() + timeout;
+        while queues.iter().any(|queue| !queue.is_empty()) && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    pub fn persist_peers(&self) -> Result<(), NetworkError> {
+        Ok(self.peer_store.lock().persist()?)
+    }
+
+    pub fn shutdown(&self) {
+        self.cancel.cancel();
+        self.connections.write().clear();
+        self.ingest.close();
+    }
+}
+
+impl MetricsSource for Network {
+    fn render_metrics(&self, out: &mut String) {
+        let (inbound, outbound) = {
+            let connections = self.connections.read();
+            let outbound = connections.values().filter(|c| c.info.outbound).count();
+            (connections.len() - outbound, outbound)
+        };
+        let retrying = self.reconnector.lock().count(RetryState::Retrying);
+        let banned = self.bans().len();
+        metrics::write_header(out, "dadbs_peers", "Peers by connection state", "gauge");
+        for (state, count) in [("inbound", inbound), ("outbound", outbound), ("retrying", retrying), ("banned", banned)] {
+            metrics::write_sample(out, "dadbs_peers", &[("state", state)], count as f64);
+        }
+
+        let gossip = self.gossip_stats();
+        metrics::write_counter(out, "dadbs_gossip_duplicates_suppressed_total", "Gossip messages already seen", gossip.duplicates_suppressed);
+        metrics::write_header(out, "dadbs_gossip_dropped_total", "Messages dropped by full peer queues", "counter");
+        metrics::write_sample(out, "dadbs_gossip_dropped_total", &[("kind", "transaction")], gossip.txs_dropped as f64);
+        metrics::write_sample(out, "dadbs_gossip_dropped_total", &[("kind", "priority")], gossip.priority_dropped as f64);
+        metrics::write_sample(out, "dadbs_gossip_dropped_total", &[("kind", "bulk")], gossip.bulk_dropped as f64);
+
+        let traffic = self.traffic();
+        metrics::write_header(out, "dadbs_network_bytes_total", "Frame bytes exchanged with peers, by message category", "counter");
+        for category in MessageCategory::ALL {
+            let labels = |direction| [("direction", direction), ("category", category.as_str())];
+            metrics::write_sample(out, "dadbs_network_bytes_total", &labels("sent"), traffic.sent(category) as f64);
+            metrics::write_sample(out, "dadbs_network_bytes_total", &labels("received"), traffic.received(category) as f64);
+        }
+    }
+}
+
+/// How a connection's writer frames messages.
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    codec: Codec,
+    max_frame_bytes: usize,
+    /// Smallest encoding compressed.
+    threshold: usize,
+    /// Whether bulk frames go in chunks, with consensus messages between.
+    multiplexed: bool,
+}
+
+/// Writes `queue`'s messages until it closes, as fast as `pacer` lets it,
+/// counting each frame in every one of `traffic`.
+async fn write_paced<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    queue: &SendQueue,
+    mut pacer: OutboundPacer,
+    traffic: [&Traffic; 2],
+    framing: Framing,
+) -> Result<(), NetworkError> {
+    let Framing { codec, max_frame_bytes, threshold, multiplexed } = framing;
+    loop {
+        pacer.ready().await;
+        let Some(message) = queue.pop(&mut pacer).await else {
+            return Ok(());
+        };
+        let category = MessageCategory::of(&message);
+        let frame = encode_frame(&message, max_frame_bytes, codec, threshold)?;
+        if !multiplexed || !mux::is_chunked(&message, &frame) {
+            write_counted(writer, &frame, &mut pacer, traffic, category).await?;
+            continue;
+        }
+        let chunks = mux::chunk_frames(&frame);
+        for (index, chunk) in chunks.iter().enumerate() {
+            if index > 0 {
+                // Consensus messages queued meanwhile go before the next chunk.
+                loop {
+                    match queue.between_chunks(&mut pacer).await {
+                        BetweenChunks::Consensus(message) => {
+                            pacer.ready().await;
+                            let frame = encode_frame(&message, max_frame_bytes, codec, threshold)?;
+                            write_counted(writer, &frame, &mut pacer, traffic, MessageCategory::of(&message)).await?;
+                        }
+                        BetweenChunks::NextChunk => break,
+                        BetweenChunks::Closed => return Ok(()),
+                    }
+                }
             }
-        });
+            write_counted(writer, chunk, &mut pacer, traffic, category).await?;
+        }
+    }
+}
+
+async fn write_counted<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &[u8],
+    pacer: &mut OutboundPacer,
+    traffic: [&Traffic; 2],
+    category: MessageCategory,
+) -> Result<(), NetworkError> {
+    writer.write_all(frame).await?;
+    writer.flush().await?;
+    pacer.charge(frame.len());
+    for traffic in traffic {
+        traffic.record_sent(category, frame.len());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl HeartbeatTransport for Network {
+    async fn broadcast_heartbeat(&self, heartbeat: Heartbeat) {
+        self.broadcast(NetMessage::Heartbeat(heartbeat));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip_and_limit() {
+        let message = NetMessage::GetBlocks { from: 3, to: 9 };
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &message, 1024).await.unwrap();
+        assert_eq!(read_frame(&mut buffer.as_slice(), 1024).await.unwrap(), message);
+
+        assert!(matches!(
+            read_frame(&mut buffer.as_slice(), 4).await,
+            Err(NetworkError::FrameTooLarge { max: 4, .. })
+        ));
+        assert!(matches!(write_frame(&mut Vec::new(), &message, 4).await, Err(NetworkError::FrameTooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_gradient_message_roundtrip() {
+        let gradients = GradientMessage::new("a", 7, 0.25, &[1.5, -2.0, f32::MIN_POSITIVE]);
+        assert_eq!((gradients.loss(), gradients.gradients()), (0.25, Some(vec![1.5, -2.0, f32::MIN_POSITIVE])));
+        let message = NetMessage::Gradients(gradients.clone());
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &message, 1024).await.unwrap();
+        assert_eq!(read_frame(&mut buffer.as_slice(), 1024).await.unwrap(), message);
+
+        let ragged = GradientMessage { values: vec![0; 5], ..gradients.clone() };
+        assert_eq!(ragged.gradients(), None);
+        let halves = GradientMessage { compression: GradientCompression::new(GradientPrecision::F16), ..gradients };
+        assert_eq!(halves.gradients(), None);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_frames() {
+        let message = NetMessage::Blocks { blocks: vec![Block::genesis(); 64], certificates: vec![] };
+        let mut plain = Vec::new();
+        write_frame(&mut plain, &message, 1 << 20).await.unwrap();
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let mut buffer = Vec::new();
+            write_compressed_frame(&mut buffer, &message, 1 << 20, codec, 1024).await.unwrap();
+            assert_eq!(buffer[4], codec.flag());
+            assert!(buffer.len() < plain.len() / 4);
+            assert_eq!(read_frame(&mut buffer.as_slice(), 1 << 20).await.unwrap(), message);
+        }
+
+        // Small frames are left alone.
+        let mut buffer = Vec::new();
+        write_compressed_frame(&mut buffer, &NetMessage::Ping(1), 1024, Codec::Zstd, 1024).await.unwrap();
+        assert_eq!(buffer[4], Codec::None.flag());
+
+        // A frame that is small on the wire but huge once decompressed.
+        let bomb = Codec::Zstd.compress(&vec![0u8; 1 << 20]);
+        let mut buffer = (bomb.len() as u32).to_be_bytes().to_vec();
+        buffer.push(Codec::Zstd.flag());
+        buffer.extend_from_slice(&bomb);
+        assert!(matches!(
+            read_frame(&mut buffer.as_slice(), 64 * 1024).await,
+            Err(NetworkError::Compression(CompressionError::TooLarge(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paced_writer_sends_consensus_before_sync() {
+        let sync = NetMessage::Blocks { blocks: vec![Block::genesis(); 16], certificates: vec![] };
+        let frame = FRAME_HEADER_BYTES + sync.try_to_vec().unwrap().len();
+        let counters = GossipCounters::default();
+        let queue = Arc::new(SendQueue::new(&GossipConfig::default()));
+        for _ in 0..2 {
+            queue.push(sync.clone(), &counters);
+        }
+        // A sync frame a second: the first empties the bucket, and the next
+        // waits for it to refill halfway.
+        let pacer = OutboundPacer::new(TokenBucket::new(frame as u64, Instant::now()), None);
+        let (mut ours, mut theirs) = tokio::io::duplex(1 << 20);
+        let (peer, total) = (Arc::new(Traffic::default()), Arc::new(Traffic::default()));
+        let writer = {
+            let (queue, peer, total) = (Arc::clone(&queue), Arc::clone(&peer), Arc::clone(&total));
+            tokio::spawn(async move {
+                let framing =
+                    Framing { codec: Codec::None, max_frame_bytes: 1 << 20, threshold: usize::MAX, multiplexed: false };
+                write_paced(&mut ours, &queue, pacer, [&*peer, &*total], framing).await
+            })
+        };
+
+        let started = Instant::now();
+        assert_eq!(read_frame(&mut theirs, 1 << 20).await.unwrap(), sync);
+        queue.push(NetMessage::Ping(7), &counters);
+        queue.push(NetMessage::Block(Block::genesis()), &counters);
+        assert_eq!(read_frame(&mut theirs, 1 << 20).await.unwrap(), NetMessage::Ping(7));
+        assert_eq!(read_frame(&mut theirs, 1 << 20).await.unwrap(), NetMessage::Block(Block::genesis()));
+        assert_eq!(read_frame(&mut theirs, 1 << 20).await.unwrap(), sync);
+        assert!(started.elapsed() >= Duration::from_millis(500), "{:?}", started.elapsed());
+
+        queue.close();
+        writer.await.unwrap().unwrap();
+        let stats = peer.snapshot();
+        assert_eq!(stats, total.snapshot());
+        assert_eq!(stats.sent(MessageCategory::Sync), 2 * frame as u64);
+        assert_eq!(stats.sent(MessageCategory::Control), 14);
+        assert!(stats.sent(MessageCategory::Consensus) > 0);
+        assert_eq!(stats.total_received(), 0);
     }
 }
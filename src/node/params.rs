@@ -0,0 +1,447 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::{hashv, Hash};
+use solana_sdk::signature::Keypair;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use super::block::Block;
+use super::config::{DEFAULT_CHAIN_ID, DEFAULT_MIN_FEE};
+use super::quorum::QuorumPolicy;
+use super::transaction::{Transaction, TxKind};
+use super::validator::ValidatorSet;
+use super::vote::{CertificateError, CommitCertificate, Vote};
+
+pub const DEFAULT_BLOCK_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_EPOCH_LENGTH: u64 = 1000;
+pub const DEFAULT_ACTIVATION_DELAY_EPOCHS: u64 = 1;
+pub const DEFAULT_MAX_BLOCK_TXS: u64 = 1_000;
+pub const DEFAULT_MAX_BLOCK_BYTES: u64 = 1 << 20;
+pub const DEFAULT_BYTE_COST: u64 = 1;
+pub const DEFAULT_TRANSFER_COST: u64 = 200;
+pub const DEFAULT_MAX_BLOCK_COST: u64 = 1_000_000;
+/// Prefixed to the hash validators vote on, so an approval can never be
+/// mistaken for a block vote.
+const PARAM_CHANGE_DOMAIN: &[u8] = b"dadbs-param-change";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParamsError {
+    #[error("Invalid protocol parameters: {0}")]
+    Invalid(String),
+    #[error("{0} cannot be changed after genesis")]
+    Immutable(&'static str),
+    #[error("Change activates in epoch {activation_epoch}, but the earliest allowed is {earliest}")]
+    TooEarly { activation_epoch: u64, earliest: u64 },
+    #[error("Approvals are for epoch {approved}, the change activates in {activation_epoch}")]
+    EpochMismatch { approved: u64, activation_epoch: u64 },
+    #[error("Approvals do not carry a quorum: {0}")]
+    Approvals(#[from] CertificateError),
+    #[error("Fee {fee} at height {height} is below the minimum of {min_fee}")]
+    Underpriced { height: u64, fee: u64, min_fee: u64 },
+    #[error("Signed for chain {actual}, this chain is {expected}")]
+    WrongChain { expected: String, actual: String },
+    #[error("Block at height {height} exceeds {limit}: {used} > {max}")]
+    OverLimit { height: u64, limit: &'static str, used: u64, max: u64 },
+    #[error("Block timestamp {timestamp} is not after its parent's, {parent}")]
+    NotAfterParent { timestamp: i64, parent: i64 },
+    #[error("Block version {version} is not {required}, the version new blocks must have")]
+    OldVersion { version: u32, required: u32 },
+    #[error("Block timestamp {timestamp} is more than {max_skew_ms} ms ahead of our clock, {now}")]
+    AheadOfClock { timestamp: i64, now: i64, max_skew_ms: i64 },
+}
+
+/// Parameters every node on the chain must agree on. Set in genesis and
+/// changed only by `ParamChange` transactions, at epoch boundaries.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolParams {
+    /// Smallest fee a transaction must pay.
+    #[serde(default = "default_min_fee")]
+    pub min_fee: u64,
+    #[serde(default = "default_block_interval_ms")]
+    pub block_interval_ms: u64,
+    /// Blocks per epoch. Fixed by genesis.
+    #[serde(default = "default_epoch_length")]
+    pub epoch_length: u64,
+    /// Full epochs that must start between a change being finalized and it
+    /// taking effect.
+    #[serde(default = "default_activation_delay_epochs")]
+    pub activation_delay_epochs: u64,
+    /// Most transactions in a block.
+    #[serde(default = "default_max_block_txs")]
+    pub max_block_txs: u64,
+    /// Most bytes of Borsh-encoded transactions in a block.
+    #[serde(default = "default_max_block_bytes")]
+    pub max_block_bytes: u64,
+    /// Execution cost of each encoded transaction byte; see `transaction_cost`.
+    #[serde(default = "default_byte_cost")]
+    pub byte_cost: u64,
+    /// Execution cost of moving funds, on top of the bytes.
+    #[serde(default = "default_transfer_cost")]
+    pub transfer_cost: u64,
+    /// Most execution cost of the transactions in a block.
+    #[serde(default = "default_max_block_cost")]
+    pub max_block_cost: u64,
+}
+
+fn default_min_fee() -> u64 {
+    DEFAULT_MIN_FEE
+}
+
+fn default_block_interval_ms() -> u64 {
+    DEFAULT_BLOCK_INTERVAL_MS
+}
+
+fn default_epoch_length() -> u64 {
+    DEFAULT_EPOCH_LENGTH
+}
+
+fn default_activation_delay_epochs() -> u64 {
+    DEFAULT_ACTIVATION_DELAY_EPOCHS
+}
+
+fn default_max_block_txs() -> u64 {
+    DEFAULT_MAX_BLOCK_TXS
+}
+
+fn default_max_block_bytes() -> u64 {
+    DEFAULT_MAX_BLOCK_BYTES
+}
+
+fn default_byte_cost() -> u64 {
+    DEFAULT_BYTE_COST
+}
+
+fn default_transfer_cost() -> u64 {
+    DEFAULT_TRANSFER_COST
+}
+
+fn default_max_block_cost() -> u64 {
+    DEFAULT_MAX_BLOCK_COST
+}
+
+impl Default for ProtocolParams {
+    fn default() -> Self {
+        ProtocolParams {
+            min_fee: DEFAULT_MIN_FEE,
+            block_interval_ms: DEFAULT_BLOCK_INTERVAL_MS,
+            epoch_length: DEFAULT_EPOCH_LENGTH,
+            activation_delay_epochs: DEFAULT_ACTIVATION_DELAY_EPOCHS,
+            max_block_txs: DEFAULT_MAX_BLOCK_TXS,
+            max_block_bytes: DEFAULT_MAX_BLOCK_BYTES,
+            byte_cost: DEFAULT_BYTE_COST,
+            transfer_cost: DEFAULT_TRANSFER_COST,
+            max_block_cost: DEFAULT_MAX_BLOCK_COST,
+        }
+    }
+}
+
+impl ProtocolParams {
+    pub fn validate(&self) -> Result<(), ParamsError> {
+        for (name, value) in [
+            ("block_interval_ms", self.block_interval_ms),
+            ("epoch_length", self.epoch_length),
+            ("activation_delay_epochs", self.activation_delay_epochs),
+            ("max_block_txs", self.max_block_txs),
+            ("max_block_bytes", self.max_block_bytes),
+            ("max_block_cost", self.max_block_cost),
+        ] {
+            if value == 0 {
+                return Err(ParamsError::Invalid(format!("{} must be at least 1", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The execution cost of `transaction`: `byte_cost` for each byte of its
+    /// Borsh encoding, plus `transfer_cost` if it moves funds. It depends on
+    /// nothing but the transaction, so every validator charges the same.
+    pub fn transaction_cost(&self, transaction: &Transaction) -> u64 {
+        let bytes = (transaction.size() as u64).saturating_mul(self.byte_cost);
+        match transaction.kind {
+            TxKind::Transfer => bytes.saturating_add(self.transfer_cost),
+            TxKind::ParamChange { .. } => bytes,
+        }
+    }
+
+    /// An empty block's budget under these parameters.
+    pub fn block_budget(&self) -> BlockBudget {
+        BlockBudget {
+            params: self.clone(),
+            txs: 0,
+            bytes: 0,
+            cost: 0,
+        }
+    }
+}
+
+/// What the transactions added to a block so far use of its limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockBudget {
+    params: ProtocolParams,
+    txs: u64,
+    bytes: u64,
+    cost: u64,
+}
+
+impl BlockBudget {
+    /// Counts `transaction` in, unless that would exceed a limit, in which
+    /// case nothing is counted and `height` is reported as over it.
+    pub fn try_add(&mut self, height: u64, transaction: &Transaction) -> Result<(), ParamsError> {
+        let txs = self.txs + 1;
+        let bytes = self.bytes.saturating_add(transaction.size() as u64);
+        let cost = self.cost.saturating_add(self.params.transaction_cost(transaction));
+        for (limit, used, max) in [
+            ("max_block_txs", txs, self.params.max_block_txs),
+            ("max_block_bytes", bytes, self.params.max_block_bytes),
+            ("max_block_cost", cost, self.params.max_block_cost),
+        ] {
+            if used > max {
+                return Err(ParamsError::OverLimit { height, limit, used, max });
+            }
+        }
+        (self.txs, self.bytes, self.cost) = (txs, bytes, cost);
+        Ok(())
+    }
+
+    /// Whether another transaction could still fit.
+    pub fn has_room(&self) -> bool {
+        self.txs < self.params.max_block_txs
+            && self.bytes < self.params.max_block_bytes
+            && self.cost < self.params.max_block_cost
+    }
+
+    pub fn txs(&self) -> u64 {
+        self.txs
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn cost(&self) -> u64 {
+        self.cost
+    }
+}
+
+/// A new parameter set proposed to take effect from the first block of
+/// `activation_epoch`.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamChange {
+    pub activation_epoch: u64,
+    pub params: ProtocolParams,
+}
+
+impl ParamChange {
+    pub fn new(activation_epoch: u64, params: ProtocolParams) -> Self {
+        ParamChange { activation_epoch, params }
+    }
+
+    /// What validators approve, in place of a block hash.
+    pub fn hash(&self) -> Hash {
+        let encoded = self.try_to_vec().expect("param change serialization cannot fail");
+        hashv(&[PARAM_CHANGE_DOMAIN, &encoded])
+    }
+
+    /// A validator's approval on `chain_id`: a vote at height
+    /// `activation_epoch`, round 0.
+    pub fn approve(&self, keypair: &Keypair, chain_id: &str) -> Vote {
+        Vote::for_chain(keypair, chain_id, self.activation_epoch, 0, self.hash())
+    }
+
+    /// Collects approvals into the certificate a `ParamChange` transaction carries.
+    pub fn certificate(&self, approvals: Vec<Vote>) -> CommitCertificate {
+        CommitCertificate::new(self.activation_epoch, self.hash(), approvals)
+    }
+}
+
+/// The parameters in force for every epoch: genesis, then each finalized
+/// change from its activation epoch on. Blocks are always judged against
+/// the parameters of the epoch they belong to, and of the chain they are on.
+#[derive(Debug, Clone)]
+pub struct ParamsSchedule {
+    chain_id: String,
+    epoch_length: u64,
+    versions: BTreeMap<u64, ProtocolParams>,
+}
+
+impl ParamsSchedule {
+    pub fn new(genesis: ProtocolParams) -> Self {
+        let mut versions = BTreeMap::new();
+        let epoch_length = genesis.epoch_length.max(1);
+        versions.insert(0, genesis);
+        ParamsSchedule { chain_id: DEFAULT_CHAIN_ID.to_string(), epoch_length, versions }
+    }
+
+    /// Accepts only blocks, transactions and approvals signed for `chain_id`.
+    pub fn with_chain_id(mut self, chain_id: impl Into<String>) -> Self {
+        self.chain_id = chain_id.into();
+        self
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    pub fn epoch_length(&self) -> u64 {
+        self.epoch_length
+    }
+
+    pub fn epoch_of(&self, height: u64) -> u64 {
+        height / self.epoch_length
+    }
+
+    pub fn params_for_epoch(&self, epoch: u64) -> &ProtocolParams {
+        self.versions.range(..=epoch).next_back().map(|(_, params)| params).expect("genesis params are always set")
+    }
+
+    pub fn params_at(&self, height: u64) -> &ProtocolParams {
+        self.params_for_epoch(self.epoch_of(height))
+    }
+
+    /// Every parameter set with the epoch it takes effect in, oldest first.
+    pub fn versions(&self) -> impl Iterator<Item = (u64, &ProtocolParams)> {
+        self.versions.iter().map(|(epoch, params)| (*epoch, params))
+    }
+
+    /// Checks that `change`, included in a block at `height`, may be
+    /// scheduled: it must activate in a later epoch, at least the delay in
+    /// force at `height` away, and carry a quorum of `validators`' approvals.
+    pub fn check_change(
+        &self,
+        height: u64,
+        change: &ParamChange,
+        approvals: &CommitCertificate,
+        validators: &ValidatorSet,
+        quorum: &dyn QuorumPolicy,
+    ) -> Result<(), ParamsError> {
+        change.params.validate()?;
+        if change.params.epoch_length != self.epoch_length {
+            return Err(ParamsError::Immutable("epoch_length"));
+        }
+        let earliest = self.epoch_of(height) + self.params_at(height).activation_delay_epochs.max(1);
+        if change.activation_epoch < earliest {
+            return Err(ParamsError::TooEarly { activation_epoch: change.activation_epoch, earliest });
+        }
+        if approvals.height != change.activation_epoch {
+            return Err(ParamsError::EpochMismatch {
+                approved: approvals.height,
+                activation_epoch: change.activation_epoch,
+            });
+        }
+        approvals.verify(&self.chain_id, &change.hash(), validators, quorum)?;
+        Ok(())
+    }
+
+    /// Checks `block` and every transaction in it against the chain and the
+    /// parameters of its epoch, block limits included. Used for blocks from
+    /// peers, which may be historical; headers from before version 3 name no
+    /// chain. A block must be timestamped after its parent, when that is
+    /// known.
+    pub fn check_block(
+        &self,
+        block: &Block,
+        parent: Option<&Block>,
+        validators: &ValidatorSet,
+        quorum: &dyn QuorumPolicy,
+    ) -> Result<(), ParamsError> {
+        let wrong_chain = |actual: &str| ParamsError::WrongChain {
+            expected: self.chain_id.clone(),
+            actual: actual.to_string(),
+        };
+        if block.header.version >= 3 && block.header.chain_id != self.chain_id {
+            return Err(wrong_chain(&block.header.chain_id));
+        }
+        let timestamp = block.header.timestamp;
+        if let Some(parent) = parent.filter(|parent| timestamp <= parent.header.timestamp) {
+            return Err(ParamsError::NotAfterParent { timestamp, parent: parent.header.timestamp });
+        }
+        let height = block.height();
+        let params = self.params_at(height);
+        let min_fee = params.min_fee;
+        let mut budget = params.block_budget();
+        for transaction in &block.transactions {
+            budget.try_add(height, transaction)?;
+            if transaction.chain_id != self.chain_id {
+                return Err(wrong_chain(&transaction.chain_id));
+            }
+            if transaction.fee < min_fee {
+                return Err(ParamsError::Underpriced { height, fee: transaction.fee, min_fee });
+            }
+            if let TxKind::ParamChange { change, approvals } = &transaction.kind {
+                self.check_change(height, change, approvals, validators, quorum)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedules `change` after `check_change` accepted it. A later change
+    /// for the same epoch replaces an earlier one.
+    pub fn schedule(&mut self, change: ParamChange) {
+        self.versions.insert(change.activation_epoch, change.params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::quorum::ThresholdPolicy;
+    use crate::node::validator::ValidatorInfo;
+    use solana_sdk::signature::Signer;
+
+    fn validators(count: usize) -> (Vec<Keypair>, ValidatorSet) {
+        let keys: Vec<Keypair> = (0..count).map(|_| Keypair::new()).collect();
+        let set = ValidatorSet::new(keys.iter().map(|k| ValidatorInfo::new(k.pubkey(), 1)).collect());
+        (keys, set)
+    }
+
+    fn params(min_fee: u64) -> ProtocolParams {
+        ProtocolParams { min_fee, epoch_length: 10, ..ProtocolParams::default() }
+    }
+
+    #[test]
+    fn test_changes_only_apply_from_a_later_epoch() {
+        let (keys, set) = validators(4);
+        let bft = ThresholdPolicy::bft();
+        let mut schedule = ParamsSchedule::new(params(10));
+        let change = ParamChange::new(1, params(20));
+        let approvals = change.certificate(keys.iter().map(|k| change.approve(k, DEFAULT_CHAIN_ID)).collect());
+
+        // Included at height 9, the last block of epoch 0.
+        assert_eq!(schedule.check_change(9, &change, &approvals, &set, &bft), Ok(()));
+        assert_eq!(
+            schedule.check_change(10, &change, &approvals, &set, &bft),
+            Err(ParamsError::TooEarly { activation_epoch: 1, earliest: 2 })
+        );
+        schedule.schedule(change);
+        assert_eq!(schedule.params_at(9).min_fee, 10);
+        assert_eq!(schedule.params_at(10).min_fee, 20);
+        assert_eq!(schedule.versions().map(|(epoch, _)| epoch).collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn test_change_needs_quorum_and_fixed_epoch_length() {
+        let (keys, set) = validators(4);
+        let bft = ThresholdPolicy::bft();
+        let schedule = ParamsSchedule::new(params(10));
+        let change = ParamChange::new(1, params(20));
+
+        let two = change.certificate(keys[..2].iter().map(|k| change.approve(k, DEFAULT_CHAIN_ID)).collect());
+        assert!(matches!(
+            schedule.check_change(0, &change, &two, &set, &bft),
+            Err(ParamsError::Approvals(CertificateError::InsufficientWeight { .. }))
+        ));
+        let approvals = keys.iter().map(|k| change.approve(k, DEFAULT_CHAIN_ID)).collect();
+        let wrong_epoch = CommitCertificate::new(2, change.hash(), approvals);
+        assert!(matches!(
+            schedule.check_change(0, &change, &wrong_epoch, &set, &bft),
+            Err(ParamsError::EpochMismatch { approved: 2, activation_epoch: 1 })
+        ));
+
+        let longer = ParamChange::new(1, ProtocolParams { epoch_length: 20, ..params(10) });
+        let approvals = longer.certificate(keys.iter().map(|k| longer.approve(k, DEFAULT_CHAIN_ID)).collect());
+        assert_eq!(
+            schedule.check_change(0, &longer, &approvals, &set, &bft),
+            Err(ParamsError::Immutable("epoch_length"))
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+pub const DEFAULT_BAN_THRESHOLD: f64 = 100.0;
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+/// Time for a peer's penalty score to halve.
+pub const DEFAULT_SCORE_HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offense {
+    InvalidSignature,
+    MalformedFrame,
+    OversizedFrame,
+    /// Flooding us with messages or oversized peer lists.
+    Spam,
+    /// Messages that are well-formed but out of place, e.g. a second handshake.
+    ProtocolViolation,
+    /// A result the other peers asked, or we ourselves, disagree with, e.g.
+    /// a distributed inference.
+    WrongResult,
+    /// No answer in time to a spot check of the model it advertises.
+    UnansweredChallenge,
+    /// Unrequested traffic past the download cap.
+    ExcessBandwidth,
+    /// A signed message too old to use, or no newer than one already seen,
+    /// e.g. a heartbeat.
+    StaleMessage,
+    /// Blocks asked for while syncing that do not check out, e.g. without
+    /// a valid commit certificate.
+    InvalidBlocks,
+    /// No answer in time to a request, e.g. for blocks while syncing.
+    UnansweredRequest,
+}
+
+impl Offense {
+    pub fn default_penalty(self) -> f64 {
+        match self {
+            Offense::InvalidSignature => 50.0,
+            Offense::MalformedFrame => 25.0,
+            Offense::OversizedFrame => 50.0,
+            Offense::Spam => 10.0,
+            Offense::ProtocolViolation => 20.0,
+            Offense::WrongResult => 30.0,
+            Offense::UnansweredChallenge => 5.0,
+            Offense::ExcessBandwidth => 5.0,
+            Offense::StaleMessage => 5.0,
+            Offense::InvalidBlocks => 50.0,
+            Offense::UnansweredRequest => 10.0,
+        }
+    }
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Offense::InvalidSignature => "invalid signature",
+            Offense::MalformedFrame => "malformed frame",
+            Offense::OversizedFrame => "oversized frame",
+            Offense::Spam => "spam",
+            Offense::ProtocolViolation => "protocol violation",
+            Offense::WrongResult => "wrong result",
+            Offense::UnansweredChallenge => "unanswered challenge",
+            Offense::ExcessBandwidth => "excess bandwidth",
+            Offense::StaleMessage => "stale message",
+            Offense::InvalidBlocks => "invalid blocks",
+            Offense::UnansweredRequest => "unanswered request",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScoreConfig {
+    penalties: HashMap<Offense, f64>,
+    pub ban_threshold: f64,
+    pub ban_duration: Duration,
+    pub half_life: Duration,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        ScoreConfig {
+            penalties: HashMap::new(),
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            ban_duration: DEFAULT_BAN_DURATION,
+            half_life: DEFAULT_SCORE_HALF_LIFE,
+        }
+    }
+}
+
+impl ScoreConfig {
+    pub fn with_penalty(mut self, offense: Offense, penalty: f64) -> Self {
+        self.penalties.insert(offense, penalty.max(0.0));
+        self
+    }
+
+    pub fn with_ban_threshold(mut self, ban_threshold: f64) -> Self {
+        self.ban_threshold = ban_threshold;
+        self
+    }
+
+    pub fn with_ban_duration(mut self, ban_duration: Duration) -> Self {
+        self.ban_duration = ban_duration;
+        self
+    }
+
+    pub fn with_half_life(mut self, half_life: Duration) -> Self {
+        self.half_life = half_life;
+        self
+    }
+
+    pub fn penalty(&self, offense: Offense) -> f64 {
+        self.penalties.get(&offense).copied().unwrap_or_else(|| offense.default_penalty())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: f64,
+    updated_ms: i64,
+}
+
+/// Penalty points per peer address. Points decay exponentially, so a peer
+/// is only banned for offenses that pile up faster than they are forgiven.
+#[derive(Debug, Default)]
+pub struct PeerScore {
+    config: ScoreConfig,
+    scores: HashMap<SocketAddr, Score>,
+}
+
+impl PeerScore {
+    pub fn new(config: ScoreConfig) -> Self {
+        PeerScore { config, scores: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &ScoreConfig {
+        &self.config
+    }
+
+    fn decayed(&self, score: Score, now_ms: i64) -> f64 {
+        let half_life_ms = self.config.half_life.as_millis() as f64;
+        if half_life_ms == 0.0 {
+            return 0.0;
+        }
+        let elapsed = (now_ms - score.updated_ms).max(0) as f64;
+        score.value * 0.5f64.powf(elapsed / half_life_ms)
+    }
+
+    pub fn score(&self, addr: &SocketAddr, now_ms: i64) -> f64 {
+        self.scores.get(addr).map_or(0.0, |score| self.decayed(*score, now_ms))
+    }
+
+    /// Adds the penalty for `offense` and returns the peer's new score.
+    pub fn record(&mut self, addr: SocketAddr, offense: Offense, now_ms: i64) -> f64 {
+        let value = self.score(&addr, now_ms) + self.config.penalty(offense);
+        self.scores.insert(addr, Score { value, updated_ms: now_ms });
+        value
+    }
+
+    pub fn should_ban(&self, score: f64) -> bool {
+        score >= self.config.ban_threshold
+    }
+
+    pub fn clear(&mut self, addr: &SocketAddr) {
+        self.scores.remove(addr);
+    }
+
+    /// Drops scores that have decayed to nothing.
+    pub fn prune(&mut self, now_ms: i64) {
+        let scores: Vec<(SocketAddr, Score)> = self.scores.iter().map(|(addr, score)| (*addr, *score)).collect();
+        for (addr, score) in scores {
+            if self.decayed(score, now_ms) < 1.0 {
+                self.scores.remove(&addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_offenses_cross_ban_threshold() {
+        let config = ScoreConfig::default().with_penalty(Offense::Spam, 30.0);
+        let mut scores = PeerScore::new(config);
+
+        assert!(!scores.should_ban(scores.record(addr(1), Offense::Spam, NOW)));
+        assert!(!scores.should_ban(scores.record(addr(1), Offense::Spam, NOW)));
+        assert!(!scores.should_ban(scores.record(addr(1), Offense::MalformedFrame, NOW)));
+        assert!(scores.should_ban(scores.record(addr(1), Offense::InvalidSignature, NOW)));
+        assert_eq!(scores.score(&addr(2), NOW), 0.0);
+    }
+
+    #[test]
+    fn test_score_decays() {
+        let mut scores = PeerScore::new(ScoreConfig::default().with_half_life(Duration::from_secs(60)));
+        scores.record(addr(1), Offense::OversizedFrame, NOW);
+        assert_eq!(scores.score(&addr(1), NOW + 60_000), 25.0);
+
+        // Offenses spread out over several half-lives never reach the threshold.
+        let mut now = NOW;
+        for _ in 0..20 {
+            now += 120_000;
+            assert!(!scores.should_ban(scores.record(addr(1), Offense::OversizedFrame, now)));
+        }
+
+        scores.prune(now + 60 * 60_000);
+        assert!(scores.scores.is_empty());
+    }
+}
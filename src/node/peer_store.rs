@@ -0,0 +1,363 @@
+use super::dns_seed::SeedPeer;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Peers we have not reached within this window are no longer relayed.
+pub const DEFAULT_PEER_TTL_MS: i64 = 30 * 60 * 1000;
+/// Addresses that failed to connect are not retried or accepted for this long.
+pub const DEFAULT_FAILURE_BACKOFF_MS: i64 = 10 * 60 * 1000;
+pub const DEFAULT_PEER_STORE_CAPACITY: usize = 1024;
+
+/// An address as exchanged in `Peers` messages.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerRecord {
+    pub addr: String,
+    /// Unix-millisecond time the sender last reached this address.
+    pub last_seen: i64,
+}
+
+#[derive(Error, Debug)]
+pub enum PeerStoreError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub addr: SocketAddr,
+    /// Unix-millisecond time the ban lifts.
+    pub until: i64,
+    pub reason: String,
+}
+
+/// What `persist` writes. Stores from before DNS seeds saved only bans.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Saved {
+    Current {
+        bans: Vec<Ban>,
+        #[serde(default)]
+        seeds: BTreeMap<String, Vec<SeedPeer>>,
+    },
+    Bans(Vec<Ban>),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    /// Last time we ourselves completed a handshake with this address.
+    verified_at: Option<i64>,
+    /// Most recent time any peer claimed to have reached it.
+    announced_at: i64,
+    failed_at: Option<i64>,
+}
+
+/// Known peer addresses. Addresses learned from gossip stay candidates until
+/// we connect to them ourselves; only verified addresses are relayed.
+/// Bans, and the last peers each DNS seed listed, are written to `path`, if
+/// set, so they survive restarts.
+#[derive(Debug)]
+pub struct PeerStore {
+    ttl_ms: i64,
+    failure_backoff_ms: i64,
+    capacity: usize,
+    entries: HashMap<SocketAddr, Entry>,
+    self_addrs: HashSet<SocketAddr>,
+    bans: HashMap<SocketAddr, Ban>,
+    seeds: BTreeMap<String, Vec<SeedPeer>>,
+    path: Option<PathBuf>,
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_PEER_TTL_MS, DEFAULT_FAILURE_BACKOFF_MS, DEFAULT_PEER_STORE_CAPACITY)
+    }
+}
+
+impl PeerStore {
+    pub fn new(ttl_ms: i64, failure_backoff_ms: i64, capacity: usize) -> Self {
+        PeerStore {
+            ttl_ms,
+            failure_backoff_ms,
+            capacity,
+            entries: HashMap::new(),
+            self_addrs: HashSet::new(),
+            bans: HashMap::new(),
+            seeds: BTreeMap::new(),
+            path: None,
+        }
+    }
+
+    /// A default store persisting bans and seed results to `path`, loading
+    /// any saved there.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PeerStoreError> {
+        let path = path.as_ref().to_path_buf();
+        let mut store = PeerStore::default();
+        if path.exists() {
+            let (bans, seeds) = match serde_json::from_str(&fs::read_to_string(&path)?)? {
+                Saved::Current { bans, seeds } => (bans, seeds),
+                Saved::Bans(bans) => (bans, BTreeMap::new()),
+            };
+            store.bans = bans.into_iter().map(|ban| (ban.addr, ban)).collect();
+            store.seeds = seeds;
+        }
+        store.path = Some(path);
+        Ok(store)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Marks `addr` as one of our own, e.g. after dialing it reached us.
+    pub fn add_self(&mut self, addr: SocketAddr) {
+        self.self_addrs.insert(addr);
+        self.entries.remove(&addr);
+    }
+
+    pub fn is_self(&self, addr: &SocketAddr) -> bool {
+        self.self_addrs.contains(addr)
+    }
+
+    fn recently_failed(&self, entry: &Entry, now_ms: i64) -> bool {
+        entry.failed_at.is_some_and(|failed| now_ms - failed < self.failure_backoff_ms)
+    }
+
+    /// Records an address learned from a peer. Returns whether it was new;
+    /// our own and recently failed addresses are ignored.
+    pub fn add_candidate(&mut self, addr: SocketAddr, announced_at: i64, now_ms: i64) -> bool {
+        if self.is_self(&addr) {
+            return false;
+        }
+        match self.entries.get_mut(&addr) {
+            Some(entry) => {
+                entry.announced_at = entry.announced_at.max(announced_at.min(now_ms));
+                false
+            }
+            None if self.entries.len() >= self.capacity => false,
+            None => {
+                self.entries.insert(addr, Entry { announced_at: announced_at.min(now_ms), ..Entry::default() });
+                true
+            }
+        }
+    }
+
+    /// Merges a `Peers` response, returning how many addresses were new.
+    pub fn merge(&mut self, records: &[PeerRecord], now_ms: i64) -> usize {
+        let mut added = 0;
+        for record in records {
+            let addr = match record.addr.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            if self.is_banned(&addr, now_ms)
+                || self.entries.get(&addr).is_some_and(|entry| self.recently_failed(entry, now_ms))
+            {
+                continue;
+            }
+            if self.add_candidate(addr, record.last_seen, now_ms) {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    pub fn mark_reachable(&mut self, addr: SocketAddr, now_ms: i64) {
+        if self.is_self(&addr) {
+            return;
+        }
+        let entry = self.entries.entry(addr).or_default();
+        entry.verified_at = Some(now_ms);
+        entry.failed_at = None;
+    }
+
+    pub fn mark_failed(&mut self, addr: SocketAddr, now_ms: i64) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.failed_at = Some(now_ms);
+        }
+    }
+
+    /// Up to `limit` addresses we reached within the TTL, most recent first.
+    pub fn verified(&self, now_ms: i64, limit: usize) -> Vec<PeerRecord> {
+        let mut verified: Vec<(SocketAddr, i64)> = self.entries.iter()
+            .filter_map(|(addr, entry)| entry.verified_at.map(|at| (*addr, at)))
+            .filter(|(addr, at)| now_ms - at <= self.ttl_ms && !self.is_banned(addr, now_ms))
+            .collect();
+        verified.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        verified.into_iter()
+            .take(limit)
+            .map(|(addr, last_seen)| PeerRecord { addr: addr.to_string(), last_seen })
+            .collect()
+    }
+
+    /// Up to `limit` addresses worth dialing, skipping `connected` ones.
+    pub fn dial_candidates(&self, connected: &HashSet<SocketAddr>, now_ms: i64, limit: usize) -> Vec<SocketAddr> {
+        let mut candidates: Vec<(SocketAddr, i64)> = self.entries.iter()
+            .filter(|(addr, entry)| {
+                !connected.contains(addr) && !self.recently_failed(entry, now_ms) && !self.is_banned(addr, now_ms)
+            })
+            .map(|(addr, entry)| (*addr, entry.verified_at.unwrap_or(entry.announced_at)))
+            .collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        candidates.into_iter().take(limit).map(|(addr, _)| addr).collect()
+    }
+
+    pub fn ban(&mut self, addr: SocketAddr, until: i64, reason: impl Into<String>) -> Result<(), PeerStoreError> {
+        self.bans.insert(addr, Ban { addr, until, reason: reason.into() });
+        self.persist()
+    }
+
+    /// Lifts a ban, returning whether there was one.
+    pub fn unban(&mut self, addr: &SocketAddr) -> Result<bool, PeerStoreError> {
+        let removed = self.bans.remove(addr).is_some();
+        if removed {
+            self.persist()?;
+        }
+        Ok(removed)
+    }
+
+    /// The ban on `addr`, if it has not expired.
+    pub fn ban_for(&self, addr: &SocketAddr, now_ms: i64) -> Option<&Ban> {
+        self.bans.get(addr).filter(|ban| ban.until > now_ms)
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr, now_ms: i64) -> bool {
+        self.ban_for(addr, now_ms).is_some()
+    }
+
+    /// Active bans, soonest to expire first.
+    pub fn bans(&self, now_ms: i64) -> Vec<Ban> {
+        let mut bans: Vec<Ban> = self.bans.values().filter(|ban| ban.until > now_ms).cloned().collect();
+        bans.sort_by(|a, b| a.until.cmp(&b.until).then(a.addr.cmp(&b.addr)));
+        bans
+    }
+
+    /// Remembers what `seed` last listed, for when it cannot be resolved.
+    pub fn cache_seed(&mut self, seed: &str, peers: &[SeedPeer]) -> Result<(), PeerStoreError> {
+        if self.seeds.get(seed).is_some_and(|cached| cached.as_slice() == peers) {
+            return Ok(());
+        }
+        self.seeds.insert(seed.to_string(), peers.to_vec());
+        self.persist()
+    }
+
+    /// What `seed` listed when last resolved, if it ever was.
+    pub fn cached_seed(&self, seed: &str) -> Vec<SeedPeer> {
+        self.seeds.get(seed).cloned().unwrap_or_default()
+    }
+
+    /// Writes active bans and seed results to the store's path, dropping
+    /// expired bans.
+    pub fn persist(&mut self) -> Result<(), PeerStoreError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.bans.retain(|_, ban| ban.until > now);
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let saved = Saved::Current { bans: self.bans(now), seeds: self.seeds.clone() };
+        fs::write(&tmp, serde_json::to_string(&saved)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn record(port: u16, last_seen: i64) -> PeerRecord {
+        PeerRecord { addr: addr(port).to_string(), last_seen }
+    }
+
+    #[test]
+    fn test_only_verified_peers_relayed() {
+        let mut store = PeerStore::default();
+        assert_eq!(store.merge(&[record(1, NOW), record(2, NOW), record(1, NOW)], NOW), 2);
+        assert!(store.verified(NOW, 10).is_empty());
+
+        store.mark_reachable(addr(2), NOW);
+        assert_eq!(store.verified(NOW, 10), vec![record(2, NOW)]);
+        assert!(store.verified(NOW + DEFAULT_PEER_TTL_MS + 1, 10).is_empty());
+    }
+
+    #[test]
+    fn test_self_and_failed_addresses_filtered() {
+        let mut store = PeerStore::default();
+        store.add_self(addr(1));
+        store.merge(&[record(1, NOW), record(2, NOW), record(3, NOW)], NOW);
+        assert_eq!(store.len(), 2);
+
+        store.mark_failed(addr(2), NOW);
+        let connected = HashSet::from([addr(3)]);
+        assert!(store.dial_candidates(&connected, NOW, 10).is_empty());
+        assert_eq!(store.merge(&[record(2, NOW + 1)], NOW + 1), 0);
+
+        let later = NOW + DEFAULT_FAILURE_BACKOFF_MS;
+        assert_eq!(store.dial_candidates(&connected, later, 10), vec![addr(2)]);
+    }
+
+    #[test]
+    fn test_bans_persist_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut store = PeerStore::open(&path).unwrap();
+        store.mark_reachable(addr(1), now);
+        store.ban(addr(1), now + 60_000, "spam").unwrap();
+        store.ban(addr(2), now + 1_000, "malformed frame").unwrap();
+        assert!(store.verified(now, 10).is_empty());
+        assert!(store.dial_candidates(&HashSet::new(), now, 10).is_empty());
+
+        let reopened = PeerStore::open(&path).unwrap();
+        assert_eq!(reopened.bans(now), store.bans(now));
+        assert_eq!(reopened.ban_for(&addr(1), now).unwrap().reason, "spam");
+        assert!(!reopened.is_banned(&addr(2), now + 1_000));
+        assert!(reopened.is_banned(&addr(1), now + 59_999));
+
+        store.unban(&addr(1)).unwrap();
+        assert_eq!(PeerStore::open(&path).unwrap().bans(now).len(), 1);
+    }
+
+    #[test]
+    fn test_seed_results_persist_alongside_bans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let now = chrono::Utc::now().timestamp_millis();
+        let ban = Ban { addr: addr(9), until: now + 60_000, reason: "spam".to_string() };
+        fs::write(&path, serde_json::to_string(&vec![ban.clone()]).unwrap()).unwrap();
+
+        // A store written before seeds were cached still loads.
+        let mut store = PeerStore::open(&path).unwrap();
+        assert_eq!(store.bans(now), vec![ban.clone()]);
+        assert!(store.cached_seed("dnsseed:seed.test:8000").is_empty());
+
+        let peers = vec![SeedPeer { addr: addr(1), pubkey: None }, SeedPeer { addr: addr(2), pubkey: None }];
+        store.cache_seed("dnsseed:seed.test:8000", &peers).unwrap();
+        let reopened = PeerStore::open(&path).unwrap();
+        assert_eq!(reopened.cached_seed("dnsseed:seed.test:8000"), peers);
+        assert_eq!(reopened.bans(now), vec![ban]);
+    }
+}
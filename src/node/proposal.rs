@@ -0,0 +1,77 @@
+//! A block as its proposer put it forward in one round, signed with the
+//! key it leads that round with, so no other peer can propose in the
+//! leader's name or have a forged block counted against it.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_sdk::{
+    hash::Hash,
+    signature::{Keypair, Signer},
+};
+
+use super::block::Block;
+use super::crypto::{self, SignatureScheme};
+
+/// Starts what a proposer signs, so a proposal signature never passes for
+/// a vote on the same block.
+const PROPOSAL_DOMAIN: &[u8] = b"dadbs-proposal-v1";
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Proposal {
+    pub block: Block,
+    pub round: u32,
+    /// By `block.header.proposer`, over `signing_bytes`.
+    pub signature: Vec<u8>,
+}
+
+impl Proposal {
+    /// Signs `block` for `round` on `chain_id` with `keypair`, which should
+    /// be its proposer's.
+    pub fn new(keypair: &Keypair, chain_id: &str, block: Block, round: u32) -> Self {
+        let bytes = Self::signing_bytes(chain_id, block.height(), round, &block.hash());
+        let signature = keypair.sign_message(&bytes).as_ref().to_vec();
+        Proposal { block, round, signature }
+    }
+
+    pub fn signing_bytes(chain_id: &str, height: u64, round: u32, block_hash: &Hash) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PROPOSAL_DOMAIN.len() + 4 + chain_id.len() + 8 + 4 + 32);
+        bytes.extend_from_slice(PROPOSAL_DOMAIN);
+        bytes.extend_from_slice(&(chain_id.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(chain_id.as_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&round.to_le_bytes());
+        bytes.extend_from_slice(block_hash.as_ref());
+        bytes
+    }
+
+    /// Checks the signature is the block's proposer's, made on `chain_id`.
+    pub fn verify(&self, chain_id: &str) -> bool {
+        let bytes = Self::signing_bytes(chain_id, self.block.height(), self.round, &self.block.hash());
+        crypto::Ed25519Scheme.verify(self.block.header.proposer.as_ref(), &bytes, &self.signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::vote::Vote;
+    use solana_sdk::pubkey::Pubkey;
+
+    #[test]
+    fn test_only_the_proposer_signs_on_its_own_chain() {
+        let leader = Keypair::new();
+        let block = Block::new(3, Hash::new_unique(), 0, leader.pubkey());
+        let proposal = Proposal::new(&leader, "chain", block.clone(), 1);
+        assert!(proposal.verify("chain"));
+        assert!(!proposal.verify("other-chain"));
+        assert!(!Proposal { round: 2, ..proposal.clone() }.verify("chain"));
+
+        // Naming the leader is not enough to propose for it.
+        let forged = Proposal::new(&Keypair::new(), "chain", block.clone(), 1);
+        assert!(!forged.verify("chain"));
+        let renamed = Block::new(3, block.parent_hash(), 0, Pubkey::new_unique());
+        assert!(!Proposal { block: renamed, ..proposal.clone() }.verify("chain"));
+
+        let vote = Vote { signature: proposal.signature, ..Vote::for_chain(&leader, "chain", 3, 1, block.hash()) };
+        assert!(!vote.verify_signature("chain"));
+    }
+}
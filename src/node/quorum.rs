@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub const DEFAULT_QUORUM_POLICY: &str = "bft";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum QuorumError {
+    #[error("Unknown quorum policy: {0}")]
+    UnknownPolicy(String),
+    #[error("Quorum policy {0} requires numerator and denominator")]
+    MissingParameters(String),
+    #[error("Invalid quorum threshold {0}/{1}: must be at least 1/2 and below 1")]
+    InvalidThreshold(u64, u64),
+}
+
+/// Decides how much voting weight is needed before the network commits.
+///
+/// Implementations must never require half or less of the total weight: two
+/// disjoint quorums could then commit conflicting blocks. Construction helpers
+/// in this module reject such thresholds.
+pub trait QuorumPolicy: Send + Sync + fmt::Debug {
+    fn name(&self) -> &str;
+
+    /// Smallest weight out of `total` that satisfies the quorum.
+    fn required_weight(&self, total: u128) -> u128;
+
+    fn is_met(&self, total: u128, weight: u128) -> bool {
+        total > 0 && weight >= self.required_weight(total)
+    }
+}
+
+/// Requires strictly more than `numerator / denominator` of the total weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThresholdPolicy {
+    numerator: u64,
+    denominator: u64,
+}
+
+impl ThresholdPolicy {
+    pub fn new(numerator: u64, denominator: u64) -> Result<Self, QuorumError> {
+        if denominator == 0 || numerator >= denominator || numerator * 2 < denominator {
+            return Err(QuorumError::InvalidThreshold(numerator, denominator));
+        }
+        Ok(ThresholdPolicy { numerator, denominator })
+    }
+
+    pub fn bft() -> Self {
+        ThresholdPolicy { numerator: 2, denominator: 3 }
+    }
+
+    fn floor_fraction(&self, total: u128) -> u128 {
+        let numerator = self.numerator as u128;
+        let denominator = self.denominator as u128;
+        (total / denominator) * numerator + (total % denominator) * numerator / denominator
+    }
+}
+
+impl Default for ThresholdPolicy {
+    fn default() -> Self {
+        Self::bft()
+    }
+}
+
+impl QuorumPolicy for ThresholdPolicy {
+    fn name(&self) -> &str {
+        if *self == Self::bft() { "bft" } else { "threshold" }
+    }
+
+    fn required_weight(&self, total: u128) -> u128 {
+        self.floor_fraction(total) + 1
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct QuorumConfig {
+    pub policy: String,
+    #[serde(default)]
+    pub numerator: Option<u64>,
+    #[serde(default)]
+    pub denominator: Option<u64>,
+}
+
+impl Default for QuorumConfig {
+    fn default() -> Self {
+        QuorumConfig {
+            policy: DEFAULT_QUORUM_POLICY.to_string(),
+            numerator: None,
+            denominator: None,
+        }
+    }
+}
+
+impl QuorumConfig {
+    pub fn build(&self) -> Result<Arc<dyn QuorumPolicy>, QuorumError> {
+        let threshold = match (self.numerator, self.denominator) {
+            (Some(numerator), Some(denominator)) => Some(ThresholdPolicy::new(numerator, denominator)?),
+            (None, None) => None,
+            _ => return Err(QuorumError::MissingParameters(self.policy.clone())),
+        };
+
+        match self.policy.as_str() {
+            "bft" => Ok(Arc::new(ThresholdPolicy::bft())),
+            "threshold" => {
+                let threshold = threshold.ok_or_else(|| QuorumError::MissingParameters(self.policy.clone()))?;
+                Ok(Arc::new(threshold))
+            }
+            other => Err(QuorumError::UnknownPolicy(other.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bft_rounding_boundaries() {
+        let policy = ThresholdPolicy::bft();
+        for k in 1..50u128 {
+            assert_eq!(policy.required_weight(3 * k), 2 * k + 1);
+            assert_eq!(policy.required_weight(3 * k + 1), 2 * k + 1);
+            assert_eq!(policy.required_weight(3 * k + 2), 2 * k + 2);
+        }
+        assert!(!policy.is_met(4, 2));
+        assert!(policy.is_met(4, 3));
+        assert!(!policy.is_met(0, 0));
+    }
+
+    #[test]
+    fn test_three_fifths_threshold() {
+        let policy = ThresholdPolicy::new(3, 5).unwrap();
+        assert_eq!(policy.required_weight(5), 4);
+        assert_eq!(policy.required_weight(6), 4);
+        assert_eq!(policy.required_weight(10), 7);
+        assert_eq!(policy.required_weight(11), 7);
+        assert_eq!(policy.name(), "threshold");
+    }
+
+    #[test]
+    fn test_large_totals_do_not_overflow() {
+        let policy = ThresholdPolicy::bft();
+        let total = u128::MAX - 1;
+        assert!(policy.required_weight(total) > total / 3 * 2);
+    }
+
+    #[test]
+    fn test_thresholds_below_half_rejected() {
+        assert_eq!(ThresholdPolicy::new(1, 3), Err(QuorumError::InvalidThreshold(1, 3)));
+        assert_eq!(ThresholdPolicy::new(3, 3), Err(QuorumError::InvalidThreshold(3, 3)));
+        assert_eq!(ThresholdPolicy::new(1, 0), Err(QuorumError::InvalidThreshold(1, 0)));
+        assert!(ThresholdPolicy::new(1, 2).is_ok());
+        assert_eq!(ThresholdPolicy::new(1, 2).unwrap().required_weight(4), 3);
+    }
+
+    #[test]
+    fn test_config_parsing() {
+        let config = QuorumConfig::default();
+        assert_eq!(config.build().unwrap().name(), "bft");
+
+        let config = QuorumConfig {
+            policy: "threshold".to_string(),
+            numerator: Some(3),
+            denominator: Some(5),
+        };
+        assert_eq!(config.build().unwrap().required_weight(10), 7);
+
+        let config = QuorumConfig { policy: "threshold".to_string(), ..QuorumConfig::default() };
+        assert_eq!(config.build().unwrap_err(), QuorumError::MissingParameters("threshold".to_string()));
+
+        let config = QuorumConfig { policy: "raft".to_string(), ..QuorumConfig::default() };
+        assert_eq!(config.build().unwrap_err(), QuorumError::UnknownPolicy("raft".to_string()));
+        let config = QuorumConfig { policy: "leader_fast_path".to_string(), ..QuorumConfig::default() };
+        assert_eq!(config.build().unwrap_err(), QuorumError::UnknownPolicy("leader_fast_path".to_string()));
+    }
+}
@@ -0,0 +1,312 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 256;
+pub const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_BUCKET_IDLE_SECS: u64 = 300;
+
+/// A token bucket holding up to `burst` requests, refilled at
+/// `rate_per_sec`.
+#[derive(Debug, Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq)]
+pub struct BucketConfig {
+    pub rate_per_sec: f64,
+    pub burst: u32,
+}
+
+impl BucketConfig {
+    pub fn new(rate_per_sec: f64, burst: u32) -> Self {
+        BucketConfig { rate_per_sec, burst }
+    }
+
+    /// Seconds an untouched bucket takes to refill completely.
+    fn refill_secs(&self) -> f64 {
+        self.burst as f64 / self.rate_per_sec
+    }
+}
+
+/// RPC request limits, applied per client IP. Every call counts against the
+/// client's `per_ip` bucket and its bucket for that method, which is
+/// `methods[method]` or else `per_method`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LimitsConfig {
+    #[serde(default = "default_per_ip")]
+    pub per_ip: BucketConfig,
+    #[serde(default = "default_per_method")]
+    pub per_method: BucketConfig,
+    #[serde(default)]
+    pub methods: BTreeMap<String, BucketConfig>,
+    /// Requests served at once; later ones wait in a queue.
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// Requests beyond this many waiting are refused at once.
+    #[serde(default = "default_max_queued_requests")]
+    pub max_queued_requests: usize,
+    /// Longest a queued request waits before it is refused.
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    /// Buckets untouched this long are dropped; they would be full anyway.
+    #[serde(default = "default_bucket_idle_secs")]
+    pub bucket_idle_secs: u64,
+    /// Clients exempt from every limit.
+    #[serde(default)]
+    pub allowlist: Vec<IpAddr>,
+}
+
+fn default_per_ip() -> BucketConfig {
+    BucketConfig::new(100.0, 200)
+}
+
+fn default_per_method() -> BucketConfig {
+    BucketConfig::new(50.0, 100)
+}
+
+fn default_max_concurrent_requests() -> usize {
+    DEFAULT_MAX_CONCURRENT_REQUESTS
+}
+
+fn default_max_queued_requests() -> usize {
+    DEFAULT_MAX_QUEUED_REQUESTS
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    DEFAULT_QUEUE_TIMEOUT_MS
+}
+
+fn default_bucket_idle_secs() -> u64 {
+    DEFAULT_BUCKET_IDLE_SECS
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            per_ip: default_per_ip(),
+            per_method: default_per_method(),
+            methods: BTreeMap::new(),
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            max_queued_requests: DEFAULT_MAX_QUEUED_REQUESTS,
+            queue_timeout_ms: DEFAULT_QUEUE_TIMEOUT_MS,
+            bucket_idle_secs: DEFAULT_BUCKET_IDLE_SECS,
+            allowlist: Vec::new(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let buckets = [("per_ip", &self.per_ip), ("per_method", &self.per_method)].into_iter()
+            .chain(self.methods.iter().map(|(method, bucket)| (method.as_str(), bucket)));
+        for (name, bucket) in buckets {
+            if !bucket.rate_per_sec.is_finite() || bucket.rate_per_sec <= 0.0 || bucket.burst == 0 {
+                return Err(format!("limits for {} need a positive rate_per_sec and burst", name));
+            }
+            if bucket.refill_secs() > self.bucket_idle_secs as f64 {
+                return Err(format!("limits.bucket_idle_secs is shorter than the refill time of {}", name));
+            }
+        }
+        if self.max_concurrent_requests == 0 {
+            return Err("limits.max_concurrent_requests must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A request was refused; the client may retry after `retry_after`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenBucket {
+    pub(crate) tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn full(config: &BucketConfig, now: Instant) -> Self {
+        TokenBucket { tokens: config.burst as f64, updated: now }
+    }
+
+    pub(crate) fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate_per_sec).min(config.burst as f64);
+        self.updated = now;
+    }
+
+    /// How long until a token is available; zero if one is now.
+    pub(crate) fn wait(&self, config: &BucketConfig) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / config.rate_per_sec)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    ips: HashMap<IpAddr, TokenBucket>,
+    methods: HashMap<(IpAddr, String), TokenBucket>,
+    swept: Option<Instant>,
+}
+
+/// Token buckets per client and method, plus a cap on concurrent requests.
+pub struct RateLimiter {
+    config: LimitsConfig,
+    buckets: Mutex<Buckets>,
+    concurrent: Semaphore,
+    queued: AtomicUsize,
+}
+
+/// Held while a request is served.
+pub struct RequestPermit<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+/// Leaves the queue however the wait ends, including cancellation.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        let concurrent = Semaphore::new(config.max_concurrent_requests.max(1));
+        RateLimiter { config, buckets: Mutex::new(Buckets::default()), concurrent, queued: AtomicUsize::new(0) }
+    }
+
+    fn exempt(&self, client: IpAddr) -> bool {
+        self.config.allowlist.contains(&client)
+    }
+
+    /// Takes a token from `client`'s bucket and from its bucket for `method`,
+    /// or neither if either is empty. Pass the same name for every unknown
+    /// method so clients cannot create buckets at will.
+    pub fn check(&self, client: IpAddr, method: &str) -> Result<(), RateLimited> {
+        self.check_at(client, method, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, method: &str, now: Instant) -> Result<(), RateLimited> {
+        if self.exempt(client) {
+            return Ok(());
+        }
+        let ip_config = &self.config.per_ip;
+        let method_config = self.config.methods.get(method).unwrap_or(&self.config.per_method);
+        let mut buckets = self.buckets.lock();
+        self.sweep(&mut buckets, now);
+
+        let mut ip = buckets.ips.get(&client).copied().unwrap_or_else(|| TokenBucket::full(ip_config, now));
+        let key = (client, method.to_string());
+        let mut per_method = buckets.methods.get(&key).copied().unwrap_or_else(|| TokenBucket::full(method_config, now));
+        ip.refill(ip_config, now);
+        per_method.refill(method_config, now);
+        let retry_after = ip.wait(ip_config).max(per_method.wait(method_config));
+        if retry_after.is_zero() {
+            ip.tokens -= 1.0;
+            per_method.tokens -= 1.0;
+        }
+        buckets.ips.insert(client, ip);
+        buckets.methods.insert(key, per_method);
+        if retry_after.is_zero() {
+            Ok(())
+        } else {
+            Err(RateLimited { retry_after })
+        }
+    }
+
+    /// Drops idle buckets, at most once per idle period.
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        let idle = Duration::from_secs(self.config.bucket_idle_secs);
+        if buckets.swept.map_or(false, |swept| now.saturating_duration_since(swept) < idle) {
+            return;
+        }
+        buckets.swept = Some(now);
+        buckets.ips.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle);
+        buckets.methods.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle);
+    }
+
+    /// Number of buckets held, per client and per client and method.
+    pub fn bucket_count(&self) -> usize {
+        let buckets = self.buckets.lock();
+        buckets.ips.len() + buckets.methods.len()
+    }
+
+    /// Waits for one of the concurrent request slots. Refused if the queue
+    /// is full or the wait exceeds `queue_timeout_ms`.
+    pub async fn acquire(&self, client: IpAddr) -> Result<RequestPermit<'_>, RateLimited> {
+        if self.exempt(client) {
+            return Ok(RequestPermit { _permit: None });
+        }
+        if let Ok(permit) = self.concurrent.try_acquire() {
+            return Ok(RequestPermit { _permit: Some(permit) });
+        }
+        let timeout = Duration::from_millis(self.config.queue_timeout_ms);
+        if self.queued.fetch_add(1, Ordering::AcqRel) >= self.config.max_queued_requests {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            return Err(RateLimited { retry_after: timeout });
+        }
+        let _slot = QueueSlot(&self.queued);
+        match tokio::time::timeout(timeout, self.concurrent.acquire()).await {
+            Ok(Ok(permit)) => Ok(RequestPermit { _permit: Some(permit) }),
+            _ => Err(RateLimited { retry_after: timeout }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> IpAddr {
+        "10.0.0.1".parse().unwrap()
+    }
+
+    #[test]
+    fn test_bucket_refills_and_idle_buckets_are_evicted() {
+        let config = LimitsConfig { per_method: BucketConfig::new(2.0, 2), bucket_idle_secs: 10, ..LimitsConfig::default() };
+        let limiter = RateLimiter::new(config);
+        let start = Instant::now();
+        assert!(limiter.check_at(client(), "get_nonce", start).is_ok());
+        assert!(limiter.check_at(client(), "get_nonce", start).is_ok());
+        let limited = limiter.check_at(client(), "get_nonce", start).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_millis(500));
+        assert!(limiter.check_at(client(), "get_nonce", start + Duration::from_millis(500)).is_ok());
+        assert_eq!(limiter.bucket_count(), 2);
+
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.check_at(other, "get_nonce", start + Duration::from_secs(11)).is_ok());
+        assert_eq!(limiter.bucket_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_cap_queues_then_refuses() {
+        let config = LimitsConfig {
+            max_concurrent_requests: 1,
+            max_queued_requests: 1,
+            queue_timeout_ms: 50,
+            allowlist: vec!["127.0.0.1".parse().unwrap()],
+            ..LimitsConfig::default()
+        };
+        let limiter = RateLimiter::new(config);
+        let held = limiter.acquire(client()).await.unwrap();
+        let (queued, refused) = tokio::join!(limiter.acquire(client()), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            limiter.acquire(client()).await
+        });
+        assert_eq!(queued.err(), Some(RateLimited { retry_after: Duration::from_millis(50) }));
+        assert!(refused.is_err());
+        assert!(limiter.acquire("127.0.0.1".parse().unwrap()).await.is_ok());
+        drop(held);
+        assert!(limiter.acquire(client()).await.is_ok());
+    }
+}
@@ -0,0 +1,290 @@
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use tokio::time::{Duration, Instant};
+
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+pub const DEFAULT_BACKOFF_JITTER: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackoffConfig {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Each delay is scaled by a random factor in `1 ± jitter`.
+    pub jitter: f64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial: DEFAULT_INITIAL_BACKOFF,
+            max: DEFAULT_MAX_BACKOFF,
+            jitter: DEFAULT_BACKOFF_JITTER,
+        }
+    }
+}
+
+impl BackoffConfig {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        BackoffConfig { initial, max, jitter: DEFAULT_BACKOFF_JITTER }
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before retry number `attempt` (starting at 1), doubling from
+    /// `initial` up to `max`. `seed` picks the jitter.
+    pub fn delay(&self, attempt: u32, seed: u64) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        let base = self.initial.saturating_mul(1 << doublings).min(self.max);
+        let unit = (seed % 10_001) as f64 / 10_000.0;
+        let factor = 1.0 - self.jitter + 2.0 * self.jitter * unit;
+        base.mul_f64(factor).min(self.max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RetryState {
+    Connected,
+    /// Dropped or failed; another attempt is scheduled or in flight.
+    Retrying,
+    /// Never dialed again.
+    Banned,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackoffStatus {
+    pub state: RetryState,
+    /// Consecutive failed attempts since the last successful handshake.
+    pub attempts: u32,
+    pub next_attempt_in: Option<Duration>,
+    pub bootstrap: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    state: RetryState,
+    attempts: u32,
+    next_attempt: Option<Instant>,
+    bootstrap: bool,
+}
+
+/// Reconnection schedule for outbound peers: bootstrap nodes and every
+/// address we have completed a handshake with.
+#[derive(Debug)]
+pub struct Reconnector {
+    config: BackoffConfig,
+    entries: HashMap<SocketAddr, Entry>,
+    seed: u64,
+}
+
+impl Reconnector {
+    pub fn new(config: BackoffConfig) -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Reconnector { config, entries: HashMap::new(), seed }
+    }
+
+    pub fn config(&self) -> &BackoffConfig {
+        &self.config
+    }
+
+    fn jitter_seed(&self, addr: &SocketAddr, attempt: u32) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.seed, addr, attempt).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Adds a bootstrap node, due immediately. Bootstrap nodes stay in the
+    /// retry set whatever their failures.
+    pub fn add_bootstrap(&mut self, addr: SocketAddr, now: Instant) {
+        self.entries.entry(addr).or_insert(Entry {
+            state: RetryState::Retrying,
+            attempts: 0,
+            next_attempt: Some(now),
+            bootstrap: true,
+        }).bootstrap = true;
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.entries.contains_key(addr)
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.entries.get(addr).is_some_and(|entry| entry.state == RetryState::Banned)
+    }
+
+    /// A handshake with `addr` succeeded; its backoff starts over.
+    pub fn connected(&mut self, addr: SocketAddr) {
+        let entry = self.entries.entry(addr).or_insert(Entry {
+            state: RetryState::Connected,
+            attempts: 0,
+            next_attempt: None,
+            bootstrap: false,
+        });
+        if entry.state != RetryState::Banned {
+            entry.state = RetryState::Connected;
+            entry.attempts = 0;
+            entry.next_attempt = None;
+        }
+    }
+
+    /// Schedules the next attempt after a dropped connection or failed dial.
+    /// Addresses we never reached are not tracked.
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let attempt = match self.entries.get_mut(&addr) {
+            Some(entry) if entry.state != RetryState::Banned => {
+                entry.attempts = entry.attempts.saturating_add(1);
+                entry.attempts
+            }
+            _ => return,
+        };
+        let delay = self.config.delay(attempt, self.jitter_seed(&addr, attempt));
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.state = RetryState::Retrying;
+            entry.next_attempt = Some(now + delay);
+        }
+    }
+
+    /// Stops retrying `addr` because retrying would never help, e.g. it runs
+    /// an incompatible protocol. Bootstrap nodes are retried at the maximum
+    /// backoff instead.
+    pub fn give_up(&mut self, addr: SocketAddr, now: Instant) {
+        let max = self.config.max;
+        match self.entries.get_mut(&addr) {
+            Some(entry) if entry.bootstrap => {
+                entry.attempts = entry.attempts.saturating_add(1);
+                entry.state = RetryState::Retrying;
+                entry.next_attempt = Some(now + max);
+            }
+            Some(_) => {
+                self.entries.remove(&addr);
+            }
+            None => {}
+        }
+    }
+
+    pub fn ban(&mut self, addr: SocketAddr) {
+        let entry = self.entries.entry(addr).or_insert(Entry {
+            state: RetryState::Banned,
+            attempts: 0,
+            next_attempt: None,
+            bootstrap: false,
+        });
+        entry.state = RetryState::Banned;
+        entry.next_attempt = None;
+    }
+
+    /// Removes `addr` from the retry set, keeping bootstrap nodes.
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        if self.entries.get(addr).is_some_and(|entry| !entry.bootstrap) {
+            self.entries.remove(addr);
+        }
+    }
+
+    /// Lifts a ban; bootstrap nodes become due immediately.
+    pub fn unban(&mut self, addr: &SocketAddr, now: Instant) {
+        match self.entries.get_mut(addr) {
+            Some(entry) if entry.state == RetryState::Banned && entry.bootstrap => {
+                entry.state = RetryState::Retrying;
+                entry.attempts = 0;
+                entry.next_attempt = Some(now);
+            }
+            Some(entry) if entry.state == RetryState::Banned => {
+                self.entries.remove(addr);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.entries.values().filter_map(|entry| entry.next_attempt).min()
+    }
+
+    /// Addresses whose next attempt is due. They are considered in flight
+    /// until `connected` or `failed` is reported.
+    pub fn take_due(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut due = Vec::new();
+        for (addr, entry) in self.entries.iter_mut() {
+            if entry.next_attempt.is_some_and(|at| at <= now) {
+                entry.next_attempt = None;
+                due.push(*addr);
+            }
+        }
+        due
+    }
+
+    /// Tracked addresses currently in `state`.
+    pub fn count(&self, state: RetryState) -> usize {
+        self.entries.values().filter(|entry| entry.state == state).count()
+    }
+
+    pub fn status(&self, addr: &SocketAddr, now: Instant) -> Option<BackoffStatus> {
+        self.entries.get(addr).map(|entry| BackoffStatus {
+            state: entry.state,
+            attempts: entry.attempts,
+            next_attempt_in: entry.next_attempt.map(|at| at.saturating_duration_since(now)),
+            bootstrap: entry.bootstrap,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_delay_doubles_to_cap_within_jitter() {
+        let config = BackoffConfig::new(Duration::from_secs(1), Duration::from_secs(10)).with_jitter(0.0);
+        let delays: Vec<u64> = (1..=6).map(|attempt| config.delay(attempt, 0).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+
+        let jittered = config.with_jitter(0.5);
+        for seed in [0, 5_000, 10_000, u64::MAX] {
+            let delay = jittered.delay(2, seed);
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(3), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_retry_state_transitions() {
+        let config = BackoffConfig::new(Duration::from_secs(1), Duration::from_secs(60)).with_jitter(0.0);
+        let mut reconnector = Reconnector::new(config);
+        let now = Instant::now();
+
+        reconnector.failed(addr(1), now);
+        assert!(!reconnector.contains(&addr(1)));
+
+        reconnector.connected(addr(1));
+        reconnector.failed(addr(1), now);
+        reconnector.failed(addr(1), now);
+        let status = reconnector.status(&addr(1), now).unwrap();
+        assert_eq!((status.state, status.attempts), (RetryState::Retrying, 2));
+        assert_eq!(status.next_attempt_in, Some(Duration::from_secs(2)));
+        assert!(reconnector.take_due(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(reconnector.take_due(now + Duration::from_secs(2)), vec![addr(1)]);
+
+        reconnector.connected(addr(1));
+        assert_eq!(reconnector.status(&addr(1), now).unwrap().attempts, 0);
+
+        reconnector.ban(addr(1));
+        reconnector.failed(addr(1), now);
+        assert!(reconnector.is_banned(&addr(1)));
+        assert_eq!(reconnector.next_due(), None);
+
+        reconnector.add_bootstrap(addr(2), now);
+        reconnector.give_up(addr(2), now);
+        reconnector.forget(&addr(2));
+        assert_eq!(reconnector.status(&addr(2), now).unwrap().next_attempt_in, Some(Duration::from_secs(60)));
+    }
+}
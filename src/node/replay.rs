@@ -0,0 +1,386 @@
+//! Replays a journal through a fresh `ConsensusManager`, on the journal's
+//! clock rather than the wall clock, checking each block the recording node
+//! finalized against what the replay finalizes by then.
+//!
+//! Messages are applied as consensus treats them: a block joins the tree
+//! with no committed weight, and is finalized once votes from a quorum for
+//! it are in for one round; a batch of certified blocks is checked and
+//! finalized block by block, as sync does; a heartbeat is recorded as of
+//! the time it arrived.
+
+use parking_lot::RwLock;
+use serde::Serialize;
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::block::Block;
+use super::config::{ConfigError, NodeConfig};
+use super::consensus::ConsensusManager;
+use super::fork_choice::{BlockTree, ChainUpdate};
+use super::genesis::{Genesis, GenesisError};
+use super::journal::{JournalEntry, JournalError};
+use super::network::{NetMessage, PeerId};
+use super::params::ProtocolParams;
+use super::snapshot::{Snapshot, SnapshotError, SnapshotTrust};
+use super::state::{State, StateError};
+use super::validator::ValidatorSet;
+use super::vote::{CommitCertificate, Vote, VoteOutcome};
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Configuration error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Genesis error: {0}")]
+    Genesis(#[from] GenesisError),
+    #[error("Snapshot error: {0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+    #[error("Journal error: {0}")]
+    Journal(#[from] JournalError),
+    #[error("Journal starts at height {height} ({block_hash}), but the replay starts at {replay_height}; \
+             replay from a snapshot at that height")]
+    StartMismatch { height: u64, block_hash: Hash, replay_height: u64 },
+}
+
+/// What a journal entry did to the replayed consensus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    Head { height: u64, block_hash: Hash },
+    Reorg { rolled_back: usize },
+    Finalized { height: u64, block_hash: Hash, state_root: Hash },
+    Equivocation { validator: Pubkey, height: u64, round: u32 },
+    /// A message consensus refused, and why.
+    Rejected { kind: &'static str, reason: String },
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transition::Head { height, block_hash } => write!(f, "head {} {}", height, block_hash),
+            Transition::Reorg { rolled_back } => write!(f, "reorg rolling back {} blocks", rolled_back),
+            Transition::Finalized { height, block_hash, state_root } => {
+                write!(f, "finalized {} {} state root {}", height, block_hash, state_root)
+            }
+            Transition::Equivocation { validator, height, round } => {
+                write!(f, "equivocation by {} at height {} round {}", validator, height, round)
+            }
+            Transition::Rejected { kind, reason } => write!(f, "rejected {}: {}", kind, reason),
+        }
+    }
+}
+
+/// The first block the replay disagrees with the recording about.
+/// `actual` is `None` if the replay had not finalized the height by the
+/// time the recording had.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Divergence {
+    pub height: u64,
+    pub at_ms: i64,
+    pub expected: Hash,
+    pub actual: Option<Hash>,
+    pub expected_state_root: Hash,
+    pub actual_state_root: Option<Hash>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.actual, self.actual_state_root) {
+            (None, _) => write!(f, "height {} was finalized as {}, but not in the replay", self.height, self.expected),
+            (Some(actual), _) if actual != self.expected => {
+                write!(f, "height {} was finalized as {}, but as {} in the replay", self.height, self.expected, actual)
+            }
+            (_, root) => write!(
+                f,
+                "height {} left state root {}, but {} in the replay",
+                self.height,
+                self.expected_state_root,
+                root.unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReplayReport {
+    /// Journal entries replayed.
+    pub entries: usize,
+    pub start_height: u64,
+    pub finalized_height: u64,
+    pub finalized_hash: Hash,
+    pub state_root: Option<Hash>,
+    /// Recorded finalizations the replay agreed with.
+    pub checked: usize,
+    pub divergence: Option<Divergence>,
+}
+
+pub struct Replayer {
+    manager: ConsensusManager,
+    start_height: u64,
+    /// Blocks finalized in the replay, with the state root each left.
+    finalized: BTreeMap<u64, (Hash, Option<Hash>)>,
+    /// Rounds with votes, by height above the finalized one.
+    rounds: BTreeMap<u64, BTreeSet<u32>>,
+    started: bool,
+    clock_ms: i64,
+}
+
+impl Replayer {
+    /// Replays into `manager` from its current finalized block.
+    pub fn new(manager: ConsensusManager) -> Self {
+        Replayer {
+            start_height: manager.finalized_height(),
+            manager,
+            finalized: BTreeMap::new(),
+            rounds: BTreeMap::new(),
+            started: false,
+            clock_ms: 0,
+        }
+    }
+
+    /// Consensus as the node starts it, from the configured snapshot or
+    /// else genesis, with state in memory. Nothing in storage is read.
+    pub fn from_config(config: &NodeConfig) -> Result<Self, ReplayError> {
+        let genesis = Genesis::from_config(config)?;
+        let balances = genesis.iter().flat_map(Genesis::balances).collect::<Vec<_>>();
+        let state = Arc::new(RwLock::new(State::in_memory(balances)));
+        let params = genesis.as_ref().map_or_else(
+            || ProtocolParams { min_fee: config.min_fee, ..ProtocolParams::default() },
+            |genesis| genesis.params.clone(),
+        );
+        let mut manager = ConsensusManager::from_config(config)?.with_params(params).with_state(state);
+        let validators = genesis.as_ref().map_or(&config.validators, |genesis| &genesis.validators);
+        if !validators.is_empty() {
+            manager.set_validator_set(ValidatorSet::new(validators.clone()));
+        }
+        if let Some((path, trust)) = SnapshotTrust::from_config(config)? {
+            manager.bootstrap_from_snapshot(Snapshot::read(&path, &trust)?)?;
+        } else if let Some(genesis) = &genesis {
+            manager.restore_block_tree(BlockTree::new(genesis.block(), config.max_fork_depth));
+        }
+        Ok(Replayer::new(manager))
+    }
+
+    pub fn manager(&self) -> &ConsensusManager {
+        &self.manager
+    }
+
+    /// The recorded time of the entry last replayed.
+    pub fn now_ms(&self) -> i64 {
+        self.clock_ms
+    }
+
+    /// Replays `entries` in order, passing each transition to `observe`,
+    /// until the first divergence or the recorded finalization of `until`.
+    pub fn run(
+        &mut self,
+        entries: &[JournalEntry],
+        until: Option<u64>,
+        mut observe: impl FnMut(i64, &Transition),
+    ) -> Result<ReplayReport, ReplayError> {
+        let (mut replayed, mut checked, mut divergence) = (0, 0, None);
+        for entry in entries {
+            if let (JournalEntry::Finalized { height, .. }, Some(until)) = (entry, until) {
+                if *height > until {
+                    break;
+                }
+            }
+            replayed += 1;
+            for transition in self.step(entry)? {
+                observe(entry.at_ms(), &transition);
+            }
+            if let JournalEntry::Finalized { height, .. } = entry {
+                if let Some(found) = self.check(entry) {
+                    divergence = Some(found);
+                    break;
+                }
+                checked += 1;
+                if Some(*height) == until {
+                    break;
+                }
+            }
+        }
+        let finalized_height = self.manager.finalized_height();
+        Ok(ReplayReport {
+            entries: replayed,
+            start_height: self.start_height,
+            finalized_height,
+            finalized_hash: self.manager.block_tree().finalized_hash(),
+            state_root: self.manager.state_root(finalized_height),
+            checked,
+            divergence,
+        })
+    }
+
+    /// Applies one entry. A `Start` must name the block the replay starts
+    /// from; recorded finalizations change nothing, see `check`.
+    pub fn step(&mut self, entry: &JournalEntry) -> Result<Vec<Transition>, ReplayError> {
+        self.clock_ms = entry.at_ms();
+        match entry {
+            &JournalEntry::Start { height, block_hash, .. } => {
+                if !self.started {
+                    let tree = self.manager.block_tree();
+                    if (tree.finalized_height(), tree.finalized_hash()) != (height, block_hash) {
+                        let replay_height = tree.finalized_height();
+                        return Err(ReplayError::StartMismatch { height, block_hash, replay_height });
+                    }
+                }
+                self.started = true;
+                Ok(Vec::new())
+            }
+            JournalEntry::Inbound { from, message, .. } => {
+                self.started = true;
+                let from = from.parse().unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+                Ok(self.deliver(from, message))
+            }
+            JournalEntry::Finalized { .. } => Ok(Vec::new()),
+        }
+    }
+
+    /// The divergence from a recorded `Finalized` entry, if any.
+    pub fn check(&self, entry: &JournalEntry) -> Option<Divergence> {
+        let (at_ms, height, expected, expected_state_root) = match *entry {
+            JournalEntry::Finalized { at_ms, height, block_hash: expected, state_root } => {
+                (at_ms, height, expected, state_root)
+            }
+            _ => return None,
+        };
+        let replayed = self.finalized.get(&height).copied();
+        let (actual, actual_state_root) = (replayed.map(|(hash, _)| hash), replayed.and_then(|(_, root)| root));
+        let roots_differ = expected_state_root != Hash::default() && actual_state_root != Some(expected_state_root);
+        if actual == Some(expected) && !roots_differ {
+            return None;
+        }
+        Some(Divergence { height, at_ms, expected, actual, expected_state_root, actual_state_root })
+    }
+
+    fn deliver(&mut self, from: PeerId, message: &NetMessage) -> Vec<Transition> {
+        match message {
+            NetMessage::Block(block) => self.block(block.clone()),
+            NetMessage::Proposal(proposal) => self.block(proposal.block.clone()),
+            NetMessage::Vote(vote) => self.vote(vote.clone()),
+            NetMessage::Blocks { blocks, certificates } => self.certified(blocks, certificates),
+            NetMessage::Heartbeat(heartbeat) => match self.manager.record_heartbeat_at(from, heartbeat, self.clock_ms) {
+                Ok(()) => Vec::new(),
+                Err(e) => vec![Transition::Rejected { kind: "heartbeat", reason: e.to_string() }],
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn block(&mut self, block: Block) -> Vec<Transition> {
+        let (height, hash) = (block.height(), block.hash());
+        if height <= self.manager.finalized_height() || self.manager.block_tree().contains(&hash) {
+            return Vec::new();
+        }
+        if let Err(e) = self.manager.check_proposal(&block) {
+            return vec![Transition::Rejected { kind: "block", reason: e.to_string() }];
+        }
+        let mut transitions = match self.manager.apply_block(block, 0) {
+            Ok(update) => self.transitions(update),
+            Err(e) => return vec![Transition::Rejected { kind: "block", reason: e.to_string() }],
+        };
+        // Its votes may have come first.
+        transitions.extend(self.finalize_if_certified(height, hash));
+        transitions
+    }
+
+    fn vote(&mut self, vote: Vote) -> Vec<Transition> {
+        let (height, round, hash, validator) = (vote.height, vote.round, vote.block_hash, vote.validator);
+        if height <= self.manager.finalized_height() {
+            return Vec::new();
+        }
+        match self.manager.add_vote(vote) {
+            Ok(VoteOutcome::Added) => {
+                self.rounds.entry(height).or_default().insert(round);
+                self.finalize_if_certified(height, hash)
+            }
+            Ok(VoteOutcome::Duplicate) => Vec::new(),
+            Ok(VoteOutcome::Equivocation(_)) => vec![Transition::Equivocation { validator, height, round }],
+            Err(e) => vec![Transition::Rejected { kind: "vote", reason: e.to_string() }],
+        }
+    }
+
+    /// Finalizes `hash` once it is in the tree and a quorum voted for it in
+    /// one round.
+    fn finalize_if_certified(&mut self, height: u64, hash: Hash) -> Vec<Transition> {
+        if height <= self.manager.finalized_height() || !self.manager.block_tree().contains(&hash) {
+            return Vec::new();
+        }
+        let total = self.manager.validator_set().total_weight();
+        let quorum = self.manager.quorum_policy();
+        let certified = self.rounds.get(&height).into_iter().flatten().any(|round| {
+            let weight = self.manager.vote_set(height, *round).map_or(0, |set| set.weight_for(&hash));
+            quorum.is_met(total, weight)
+        });
+        if !certified {
+            return Vec::new();
+        }
+        match self.manager.finalize_block(&hash) {
+            Ok(update) => self.transitions(update),
+            Err(e) => vec![Transition::Rejected { kind: "vote", reason: e.to_string() }],
+        }
+    }
+
+    fn certified(&mut self, blocks: &[Block], certificates: &[CommitCertificate]) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        for (block, certificate) in blocks.iter().zip(certificates) {
+            let (height, hash) = (block.height(), block.hash());
+            if height <= self.manager.finalized_height() {
+                continue;
+            }
+            let rejected = |reason: String| Transition::Rejected { kind: "blocks", reason };
+            if block.parent_hash() != self.manager.block_tree().finalized_hash() || !block.verify_body() {
+                transitions.push(rejected(format!("block {} does not extend the finalized chain", height)));
+                break;
+            }
+            let validators = self.manager.handovers().validators_at(self.manager.validator_set(), height).into_owned();
+            let quorum = self.manager.quorum_policy();
+            let weight = match certificate.verify(self.manager.chain_id(), &hash, &validators, quorum.as_ref()) {
+                Ok(weight) => weight,
+                Err(e) => {
+                    transitions.push(rejected(format!("bad certificate at height {}: {}", height, e)));
+                    break;
+                }
+            };
+            let updates = self.manager.apply_block(block.clone(), weight)
+                .map(|applied| (applied, self.manager.finalize_block(&hash)));
+            match updates {
+                Ok((applied, Ok(finalized))) => {
+                    transitions.extend(self.transitions(applied));
+                    transitions.extend(self.transitions(finalized));
+                }
+                Ok((_, Err(e))) | Err(e) => {
+                    transitions.push(rejected(e.to_string()));
+                    break;
+                }
+            }
+        }
+        transitions
+    }
+
+    fn transitions(&mut self, update: ChainUpdate) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        if update.is_reorg() {
+            transitions.push(Transition::Reorg { rolled_back: update.rolled_back.len() });
+        }
+        if !update.applied.is_empty() {
+            let tree = self.manager.block_tree();
+            transitions.push(Transition::Head { height: tree.head_height(), block_hash: tree.head_hash() });
+        }
+        for block in &update.finalized {
+            let (height, block_hash) = (block.height(), block.hash());
+            let state_root = self.manager.state_root(height);
+            self.finalized.insert(height, (block_hash, state_root));
+            transitions.push(Transition::Finalized { height, block_hash, state_root: state_root.unwrap_or_default() });
+        }
+        let finalized = self.manager.finalized_height();
+        self.rounds.retain(|height, _| *height > finalized);
+        transitions
+    }
+}
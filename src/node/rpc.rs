@@ -0,0 +1,1452 @@
+use axum::body::Bytes;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, State as Shared};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::routing::{get, post};
+use axum::Router;
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+
+use super::admin::{self, AdminApi};
+use super::api_keys::{ApiKeyError, ApiKeyRecord, ApiKeyUsage, ApiKeys, API_KEY_HEADER};
+use super::block::Block;
+use super::chain_stats::{self, DayStats, StakeBucket};
+use super::consensus::ConsensusManager;
+use super::control::HaltStatus;
+use super::crypto::SchemeKind;
+#[cfg(feature = "faucet")]
+use super::faucet::{Faucet, FaucetGrant};
+use super::faucet::FaucetError;
+use super::hooks::{HookEvent, Hooks};
+use super::mempool::{Mempool, MempoolError};
+use super::metrics::{self, Histogram, MetricsSource};
+use super::moderation::MemoModerator;
+use super::network::{LlmCapability, LlmPeerFilter, NetMessage, Network};
+use super::light::SignedHeader;
+use super::pagination::{Direction, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT};
+use super::rate_limit::{LimitsConfig, RateLimited, RateLimiter};
+use super::state::{Event, Receipt, ReceiptStatus, Simulation, State};
+use super::storage::{Storage, StorageError, StoredTransaction};
+use super::subscriptions::{self, ChainEvents};
+use super::telemetry::Telemetry;
+use super::transaction::{Transaction, MAX_PAYLOAD_BYTES};
+use super::tx_trace::{TxEvent, TxStage, TxTracer};
+use super::webhooks::Webhooks;
+use crate::utils::{AddressError, DADBSAddress};
+#[cfg(feature = "llm")]
+use crate::llm::{Estimate, GenerateParams, InferenceQueue, InferenceRouter, LlmError, ModelManager, UsageTotals};
+
+pub const DEFAULT_RPC_LISTEN: &str = "127.0.0.1:8001";
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 1024 * 1024;
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+pub const DEFAULT_MAX_SUBSCRIPTIONS: usize = 16;
+pub const DEFAULT_SIMULATION_BUDGET_MS: u64 = 100;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The requested block or transaction was pruned from storage.
+pub const PRUNED: i64 = -32001;
+/// The transaction was refused by signature checks or the mempool.
+pub const TRANSACTION_REJECTED: i64 = -32002;
+/// The client exceeded its request limits; `data.retry_after_ms` says when
+/// to try again.
+pub const RATE_LIMITED: i64 = -32005;
+/// The mempool or the node's transaction intake is full; retriable after
+/// `data.retry_after_ms`.
+pub const MEMPOOL_FULL: i64 = -32006;
+const MEMPOOL_FULL_RETRY_AFTER_MS: u64 = 1000;
+/// The inference queue is full; retriable after `data.retry_after_ms`.
+pub const MODEL_BUSY: i64 = -32007;
+const MODEL_BUSY_RETRY_AFTER_MS: u64 = 500;
+/// Generation ran past its deadline or the node shut down; `data` holds the
+/// text emitted so far and the token counts.
+pub const GENERATION_INTERRUPTED: i64 = -32008;
+/// A faucet cooldown or the faucet's daily budget refused the request;
+/// `data.retry_at_ms` says when it could succeed.
+pub const FAUCET_LIMITED: i64 = -32009;
+/// The API key presented is unknown or revoked, or may not call the method.
+pub const API_KEY_REFUSED: i64 = -32010;
+/// The API key's daily LLM token budget is spent; `data.retry_at_ms` says
+/// when it resets.
+pub const QUOTA_EXHAUSTED: i64 = -32011;
+
+/// Widest range one `get_address_activity_hint` call reads the headers of.
+pub const MAX_ACTIVITY_HINT_HEIGHTS: u64 = 10_000;
+
+/// Methods served over HTTP and WebSocket, which get their own rate limit
+/// buckets; any other name shares the `unknown` bucket.
+pub const METHODS: &[&str] = &[
+    "get_block_by_height",
+    "get_block_by_hash",
+    "get_transaction",
+    "get_receipt",
+    "get_light_headers",
+    "get_inclusion_proof",
+    "get_blocks",
+    "get_account_transactions",
+    "get_recent_transactions",
+    "get_balance",
+    "get_nonce",
+    "send_transaction",
+    "simulate_transaction",
+    "trace_transaction",
+    "get_node_info",
+    "get_validator_set",
+    "get_chain_stats",
+    "get_address_stats",
+    "get_telemetry",
+    "get_address_activity_hint",
+    "get_llm_peers",
+    "subscribe_new_blocks",
+    "subscribe_finalized",
+    "subscribe_address",
+    "unsubscribe",
+    "llm_generate",
+    "llm_estimate",
+    "faucet_request",
+    "get_api_key_usage",
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RpcConfig {
+    /// Address the JSON-RPC server listens on.
+    #[serde(default = "default_rpc_listen")]
+    pub listen: String,
+    /// Larger request bodies are refused with HTTP 413.
+    #[serde(default = "default_max_request_bytes")]
+    pub max_request_bytes: usize,
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    /// Open subscriptions allowed on one WebSocket connection.
+    #[serde(default = "default_max_subscriptions")]
+    pub max_subscriptions: usize,
+    /// Longest one `simulate_transaction` may spend executing against the
+    /// state, which it holds a read lock on meanwhile.
+    #[serde(default = "default_simulation_budget_ms")]
+    pub simulation_budget_ms: u64,
+}
+
+fn default_rpc_listen() -> String {
+    DEFAULT_RPC_LISTEN.to_string()
+}
+
+fn default_max_request_bytes() -> usize {
+    DEFAULT_MAX_REQUEST_BYTES
+}
+
+fn default_max_batch_size() -> usize {
+    DEFAULT_MAX_BATCH_SIZE
+}
+
+fn default_max_subscriptions() -> usize {
+    DEFAULT_MAX_SUBSCRIPTIONS
+}
+
+fn default_simulation_budget_ms() -> u64 {
+    DEFAULT_SIMULATION_BUDGET_MS
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            listen: default_rpc_listen(),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            simulation_budget_ms: DEFAULT_SIMULATION_BUDGET_MS,
+        }
+    }
+}
+
+/// A JSON-RPC 2.0 error object.
+#[derive(Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[error("{message} ({code})")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into(), data: None }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub(crate) fn invalid_params(message: impl std::fmt::Display) -> Self {
+        Self::new(INVALID_PARAMS, format!("Invalid params: {}", message))
+    }
+
+    pub(crate) fn mempool_full(message: impl Into<String>) -> Self {
+        Self::new(MEMPOOL_FULL, message).with_data(json!({ "retry_after_ms": MEMPOOL_FULL_RETRY_AFTER_MS }))
+    }
+}
+
+impl From<StorageError> for RpcError {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::Pruned { .. } => RpcError::new(PRUNED, e.to_string()),
+            StorageError::InvalidCursor(_) => RpcError::invalid_params(e),
+            _ => RpcError::new(INTERNAL_ERROR, e.to_string()),
+        }
+    }
+}
+
+impl From<AddressError> for RpcError {
+    fn from(e: AddressError) -> Self {
+        RpcError::invalid_params(e)
+    }
+}
+
+impl From<MempoolError> for RpcError {
+    fn from(e: MempoolError) -> Self {
+        match e {
+            MempoolError::Full(_) => RpcError::mempool_full(e.to_string()),
+            _ => RpcError::new(TRANSACTION_REJECTED, e.to_string()),
+        }
+    }
+}
+
+impl From<FaucetError> for RpcError {
+    fn from(e: FaucetError) -> Self {
+        match e.retry_at_ms() {
+            Some(retry_at_ms) => RpcError::new(FAUCET_LIMITED, e.to_string()).with_data(json!({ "retry_at_ms": retry_at_ms })),
+            None => RpcError::new(INTERNAL_ERROR, e.to_string()),
+        }
+    }
+}
+
+impl From<ApiKeyError> for RpcError {
+    fn from(e: ApiKeyError) -> Self {
+        match e {
+            ApiKeyError::Invalid | ApiKeyError::MethodNotAllowed(_) => RpcError::new(API_KEY_REFUSED, e.to_string()),
+            ApiKeyError::RateLimited(limited) => limited.into(),
+            ApiKeyError::BudgetExhausted { resets_at_ms, .. } => {
+                RpcError::new(QUOTA_EXHAUSTED, e.to_string()).with_data(json!({ "retry_at_ms": resets_at_ms }))
+            }
+            ApiKeyError::InvalidLimits(_) => RpcError::invalid_params(e),
+            ApiKeyError::Storage(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(feature = "llm")]
+impl From<LlmError> for RpcError {
+    fn from(e: LlmError) -> Self {
+        match &e {
+            LlmError::InvalidParams(_)
+            | LlmError::PromptTooLong { .. }
+            | LlmError::UnknownModel(_)
+            | LlmError::ModelInUse(_) => RpcError::invalid_params(&e),
+            LlmError::BatchFull { retry_after_ms } => RpcError::new(MODEL_BUSY, e.to_string())
+                .with_data(json!({ "retry_after_ms": retry_after_ms.unwrap_or(MODEL_BUSY_RETRY_AFTER_MS) })),
+            LlmError::Cancelled(partial) | LlmError::Timeout(partial) => {
+                RpcError::new(GENERATION_INTERRUPTED, e.to_string()).with_data(json!(partial))
+            }
+            _ => RpcError::new(INTERNAL_ERROR, e.to_string()),
+        }
+    }
+}
+
+impl From<RateLimited> for RpcError {
+    fn from(limited: RateLimited) -> Self {
+        let retry_after_ms = limited.retry_after.as_millis().max(1) as u64;
+        RpcError::new(RATE_LIMITED, format!("Rate limited; retry after {}ms", retry_after_ms))
+            .with_data(json!({ "retry_after_ms": retry_after_ms }))
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Request {
+    pub(crate) jsonrpc: String,
+    pub(crate) method: String,
+    #[serde(default)]
+    pub(crate) params: Value,
+    /// Absent for notifications, which get no response.
+    #[serde(default)]
+    pub(crate) id: Option<Value>,
+    /// An API key, in place of the `x-api-key` header.
+    #[serde(default)]
+    pub(crate) auth: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HeightParams {
+    pub height: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HashParams {
+    /// Base58 block or transaction hash.
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressParams {
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FaucetRequestParams {
+    /// Base58 public key to fund.
+    pub address: String,
+}
+
+/// A page of blocks from `from_height`, or after `cursor`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlocksParams {
+    /// Defaults to the lowest or highest stored block.
+    #[serde(default)]
+    pub from_height: Option<u64>,
+    /// `next_cursor` of the previous page; takes precedence over `from_height`.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default = "default_page_limit")]
+    pub limit: usize,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// Consecutive headers from `from_height`, up to `limit`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LightHeadersParams {
+    pub from_height: u64,
+    #[serde(default = "default_page_limit")]
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountTransactionsParams {
+    /// Base58 public key of the sender or recipient.
+    pub pubkey: String,
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default = "default_page_limit")]
+    pub limit: usize,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// Days overlapping `from_ms..to_ms`, in Unix milliseconds; every day by
+/// default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChainStatsParams {
+    pub from_ms: Option<i64>,
+    pub to_ms: Option<i64>,
+}
+
+/// Telemetry samples from the last `hours`, at most the retention.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryParams {
+    pub hours: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressStatsParams {
+    /// Base58 public key.
+    pub pubkey: String,
+}
+
+/// Heights from `from_height` up to, not including, `to_height`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActivityHintParams {
+    pub address: String,
+    pub from_height: u64,
+    pub to_height: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecentTransactionsParams {
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default = "default_page_limit")]
+    pub limit: usize,
+}
+
+fn default_page_limit() -> usize {
+    DEFAULT_PAGE_LIMIT
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LlmGenerateParams {
+    pub prompt: String,
+    pub max_tokens: usize,
+    /// Defaults to the model's sampling temperature.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Generation still running after this long ends with
+    /// `GENERATION_INTERRUPTED`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// The account the completion's usage is charged to.
+    #[serde(default)]
+    pub address: Option<String>,
+    /// Returns each token's log probability with this many alternatives.
+    #[serde(default)]
+    pub logprobs: Option<usize>,
+    /// Runs the prompt on several peers and answers with the tokens most
+    /// of them agree on; see `InferenceRouter`.
+    #[serde(default)]
+    pub distributed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LlmEstimateParams {
+    pub prompt: String,
+    pub max_tokens: usize,
+}
+
+#[cfg(feature = "llm")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LlmEstimateResult {
+    /// The whole prompt's tokens, before any truncation.
+    pub tokens: usize,
+    #[serde(flatten)]
+    pub estimate: Estimate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendTransactionParams {
+    /// Hex-encoded Borsh transaction.
+    pub raw: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockResult {
+    pub hash: String,
+    pub height: u64,
+    pub parent_hash: String,
+    pub timestamp: i64,
+    pub proposer: String,
+    pub transactions_root: String,
+    pub state_root: String,
+    pub total_fees: u64,
+    /// Hashes of the block's transactions, in order.
+    pub transactions: Vec<String>,
+}
+
+impl From<&Block> for BlockResult {
+    fn from(block: &Block) -> Self {
+        BlockResult {
+            hash: block.hash().to_string(),
+            height: block.height(),
+            parent_hash: block.parent_hash().to_string(),
+            timestamp: block.header.timestamp,
+            proposer: block.header.proposer.to_string(),
+            transactions_root: block.header.transactions_root.to_string(),
+            state_root: block.header.state_root.to_string(),
+            total_fees: block.header.total_fees,
+            transactions: block.transactions.iter().map(|tx| tx.hash().to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransactionResult {
+    pub hash: String,
+    pub height: u64,
+    pub index: u32,
+    pub sender: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub fee: u64,
+    pub nonce: u64,
+    pub timestamp: i64,
+    pub chain_id: String,
+    /// Hex-encoded.
+    pub payload: String,
+    pub signature: String,
+}
+
+impl From<&StoredTransaction> for TransactionResult {
+    fn from(stored: &StoredTransaction) -> Self {
+        let tx = &stored.transaction;
+        TransactionResult {
+            hash: tx.hash().to_string(),
+            height: stored.height,
+            index: stored.index,
+            sender: tx.sender.to_string(),
+            recipient: tx.recipient.to_string(),
+            amount: tx.amount,
+            fee: tx.fee,
+            nonce: tx.nonce,
+            timestamp: tx.timestamp,
+            chain_id: tx.chain_id.clone(),
+            payload: hex::encode(&tx.payload),
+            signature: tx.signature.to_string(),
+        }
+    }
+}
+
+/// How an included transaction was executed. A transaction without one
+/// has not been executed yet, or was never included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReceiptResult {
+    pub tx_hash: String,
+    pub block_hash: String,
+    pub height: u64,
+    pub index: u32,
+    pub status: ReceiptStatus,
+    pub error: Option<String>,
+    pub fee_paid: u64,
+    pub events: Vec<Event>,
+}
+
+impl From<&Receipt> for ReceiptResult {
+    fn from(receipt: &Receipt) -> Self {
+        ReceiptResult {
+            tx_hash: receipt.tx_hash.to_string(),
+            block_hash: receipt.block_hash.to_string(),
+            height: receipt.height,
+            index: receipt.index,
+            status: receipt.status,
+            error: receipt.error.clone(),
+            fee_paid: receipt.fee_paid,
+            events: receipt.events.clone(),
+        }
+    }
+}
+
+/// Whether `send_transaction` would accept a transaction right now, and
+/// what it would do if it were the next one applied to the current state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SimulationResult {
+    pub hash: String,
+    pub accepted: bool,
+    /// Why it would be refused.
+    pub error: Option<RpcError>,
+    /// `None` for a transaction no block could carry next, or one refused
+    /// before execution.
+    #[serde(default)]
+    pub execution: Option<Simulation>,
+    /// Why it could not be executed, as a nonce ahead of the state's.
+    #[serde(default)]
+    pub execution_error: Option<String>,
+}
+
+/// A finalized header for light clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignedHeaderResult {
+    pub height: u64,
+    pub hash: String,
+    /// Hex-encoded Borsh `SignedHeader`, as `light::verify_header_chain` takes.
+    pub raw: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProofResult {
+    pub tx_hash: String,
+    pub height: u64,
+    pub block_hash: String,
+    /// Hex-encoded Borsh `MerkleProof` against the block's transactions root.
+    pub proof: String,
+}
+
+/// A faucet grant, submitted but not yet finalized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FaucetResult {
+    pub hash: String,
+    pub amount: u64,
+    /// When the same address may ask again.
+    pub next_request_ms: i64,
+}
+
+/// The calling key and its use today.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyUsageResult {
+    pub key: ApiKeyRecord,
+    pub today: ApiKeyUsage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub node_id: String,
+    pub chain_id: String,
+    pub version: String,
+    pub head_height: u64,
+    pub finalized_height: u64,
+    pub peers: usize,
+    pub halted: Option<HaltStatus>,
+}
+
+/// What `get_chain_stats` returns: totals over the requested days, each
+/// day's aggregates, and how stake is spread over the current validators.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainStatsResult {
+    pub blocks: u64,
+    pub transactions: u64,
+    pub fees: u64,
+    pub volume: u64,
+    /// `None` until two consecutive blocks fall within the days.
+    pub average_block_interval_ms: Option<f64>,
+    pub days: Vec<DayStats>,
+    pub stake_distribution: Vec<StakeBucket>,
+}
+
+impl ChainStatsResult {
+    fn new(days: Vec<DayStats>, stake_distribution: Vec<StakeBucket>) -> Self {
+        let sum = |field: fn(&DayStats) -> u64| days.iter().map(field).fold(0u64, u64::saturating_add);
+        let intervals = sum(|day| day.intervals);
+        ChainStatsResult {
+            blocks: sum(|day| day.blocks),
+            transactions: sum(|day| day.transactions),
+            fees: sum(|day| day.fees),
+            volume: sum(|day| day.volume),
+            average_block_interval_ms: (intervals > 0).then(|| sum(|day| day.interval_ms) as f64 / intervals as f64),
+            days,
+            stake_distribution,
+        }
+    }
+}
+
+/// A peer serving inference, as `get_llm_peers` lists it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LlmPeerResult {
+    pub addr: String,
+    pub node_id: String,
+    #[serde(flatten)]
+    pub capability: LlmCapability,
+    /// Unix milliseconds of the last message from the peer.
+    pub last_seen: i64,
+    /// Milliseconds since then, by this node's clock.
+    pub idle_ms: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValidatorResult {
+    pub pubkey: String,
+    pub weight: u128,
+    pub scheme: SchemeKind,
+}
+
+/// Everything the RPC methods read from or submit to.
+pub struct RpcContext {
+    pub node_id: String,
+    pub chain_id: String,
+    pub storage: Arc<Storage>,
+    pub consensus: Arc<AsyncMutex<ConsensusManager>>,
+    pub state: Arc<RwLock<State>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    /// Accepted transactions are gossiped here, if set.
+    pub network: Option<Arc<Network>>,
+    /// Feeds WebSocket subscriptions; consensus should publish into it.
+    pub events: ChainEvents,
+    /// Lifecycle events returned by `trace_transaction`; consensus should
+    /// record into it too.
+    pub tracer: TxTracer,
+}
+
+pub(crate) fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(RpcError::invalid_params)
+}
+
+fn parse_hash(hash: &str) -> Result<Hash, RpcError> {
+    Hash::from_str(hash).map_err(|e| RpcError::invalid_params(format!("bad hash {}: {}", hash, e)))
+}
+
+pub(crate) fn to_value(result: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+fn decode_transaction(raw: &str) -> Result<Transaction, RpcError> {
+    let bytes = hex::decode(raw).map_err(|e| RpcError::invalid_params(format!("raw is not hex: {}", e)))?;
+    Transaction::try_from_slice(&bytes).map_err(|e| RpcError::invalid_params(format!("malformed transaction: {}", e)))
+}
+
+pub(crate) fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+struct MethodStats {
+    requests: u64,
+    errors: u64,
+    latency: Histogram,
+}
+
+/// Request counts, error counts and latencies per method.
+#[derive(Default)]
+pub struct RpcMetrics {
+    methods: Mutex<BTreeMap<String, MethodStats>>,
+}
+
+impl RpcMetrics {
+    fn record(&self, method: &str, latency: Duration, ok: bool) {
+        let mut methods = self.methods.lock();
+        let stats = methods.entry(method.to_string()).or_insert_with(|| MethodStats {
+            requests: 0,
+            errors: 0,
+            latency: Histogram::latency(),
+        });
+        stats.requests += 1;
+        if !ok {
+            stats.errors += 1;
+        }
+        stats.latency.observe(latency.as_secs_f64());
+    }
+}
+
+impl MetricsSource for RpcMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        let methods = self.methods.lock();
+        metrics::write_header(out, "dadbs_rpc_requests_total", "RPC requests by method", "counter");
+        for (method, stats) in methods.iter() {
+            metrics::write_sample(out, "dadbs_rpc_requests_total", &[("method", method.as_str())], stats.requests as f64);
+        }
+        metrics::write_header(out, "dadbs_rpc_errors_total", "RPC requests answered with an error, by method", "counter");
+        for (method, stats) in methods.iter() {
+            metrics::write_sample(out, "dadbs_rpc_errors_total", &[("method", method.as_str())], stats.errors as f64);
+        }
+        metrics::write_header(out, "dadbs_rpc_request_duration_seconds", "Time to answer an RPC request", "histogram");
+        for (method, stats) in methods.iter() {
+            metrics::write_histogram_samples(out, "dadbs_rpc_request_duration_seconds", &[("method", method.as_str())], &stats.latency);
+        }
+    }
+}
+
+/// HTTP JSON-RPC 2.0 server answering node, chain and mempool queries.
+/// Requests are POSTed to `/`, singly or in batches. `/ws` takes the same
+/// requests over a WebSocket, plus `subscribe_new_blocks`,
+/// `subscribe_finalized`, `subscribe_address` and `unsubscribe`, all subject
+/// to the `[limits]` on each client. With an `AdminApi`, `/admin` takes the
+/// token-gated `admin_*` methods. Once given an `InferenceQueue` or a
+/// `ModelManager`, `llm_generate` completes prompts on it, adding up each
+/// address's usage; with an `InferenceRouter` it also takes `distributed`
+/// requests.
+pub struct RpcServer {
+    pub(crate) context: RpcContext,
+    admin: Option<AdminApi>,
+    limiter: RateLimiter,
+    max_batch_size: usize,
+    max_request_bytes: usize,
+    max_subscriptions: usize,
+    simulation_budget: Duration,
+    metrics: Arc<RpcMetrics>,
+    local_addr: SocketAddr,
+    cancel: CancellationToken,
+    moderator: RwLock<Option<Arc<MemoModerator>>>,
+    api_keys: RwLock<Option<Arc<ApiKeys>>>,
+    webhooks: RwLock<Option<Arc<Webhooks>>>,
+    telemetry: RwLock<Option<Arc<Telemetry>>>,
+    hooks: RwLock<Option<Hooks>>,
+    #[cfg(feature = "faucet")]
+    faucet: RwLock<Option<Arc<Faucet>>>,
+    #[cfg(feature = "llm")]
+    llm: RwLock<Option<Arc<InferenceQueue>>>,
+    #[cfg(feature = "llm")]
+    models: RwLock<Option<Arc<ModelManager>>>,
+    #[cfg(feature = "llm")]
+    router: RwLock<Option<Arc<InferenceRouter>>>,
+    #[cfg(feature = "llm")]
+    llm_usage: Mutex<BTreeMap<DADBSAddress, UsageTotals>>,
+}
+
+impl RpcServer {
+    pub async fn bind(config: RpcConfig, context: RpcContext) -> std::io::Result<Arc<Self>> {
+        Self::bind_with(config, context, LimitsConfig::default(), None).await
+    }
+
+    pub async fn bind_with(
+        config: RpcConfig,
+        context: RpcContext,
+        limits: LimitsConfig,
+        admin: Option<AdminApi>,
+    ) -> std::io::Result<Arc<Self>> {
+        let listener = TcpListener::bind(&config.listen).await?;
+        let local_addr = listener.local_addr()?;
+        let server = Arc::new(RpcServer {
+            context,
+            admin,
+            limiter: RateLimiter::new(limits),
+            max_batch_size: config.max_batch_size.max(1),
+            max_request_bytes: config.max_request_bytes,
+            max_subscriptions: config.max_subscriptions,
+            simulation_budget: Duration::from_millis(config.simulation_budget_ms),
+            metrics: Arc::new(RpcMetrics::default()),
+            local_addr,
+            cancel: CancellationToken::new(),
+            moderator: RwLock::new(None),
+            api_keys: RwLock::new(None),
+            webhooks: RwLock::new(None),
+            telemetry: RwLock::new(None),
+            hooks: RwLock::new(None),
+            #[cfg(feature = "faucet")]
+            faucet: RwLock::new(None),
+            #[cfg(feature = "llm")]
+            llm: RwLock::new(None),
+            #[cfg(feature = "llm")]
+            models: RwLock::new(None),
+            #[cfg(feature = "llm")]
+            router: RwLock::new(None),
+            #[cfg(feature = "llm")]
+            llm_usage: Mutex::new(BTreeMap::new()),
+        });
+
+        let app = Router::new()
+            .route("/", post(handle_http))
+            .route("/ws", get(handle_ws))
+            .route("/admin", post(admin::handle_admin))
+            .layer(DefaultBodyLimit::max(config.max_request_bytes))
+            .with_state(Arc::clone(&server));
+        let cancel = server.cancel.clone();
+        tokio::spawn(async move {
+            let shutdown = async move { cancel.cancelled().await };
+            // Limits and the admin audit trail need the caller's address.
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(shutdown).await {
+                warn!("RPC server stopped: {}", e);
+            }
+        });
+        info!("RPC listening on {}", local_addr);
+        Ok(server)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn metrics(&self) -> Arc<RpcMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    pub(crate) fn admin(&self) -> Option<&AdminApi> {
+        self.admin.as_ref()
+    }
+
+    /// Checks the memo of each `send_transaction` with `moderator` before
+    /// admitting it.
+    pub fn moderate_memos(&self, moderator: Arc<MemoModerator>) {
+        *self.moderator.write() = Some(moderator);
+    }
+
+    /// Takes API keys from `keys`: a request presenting one is held to the
+    /// key's limits instead of its client's.
+    pub fn authenticate_api_keys(&self, keys: Arc<ApiKeys>) {
+        *self.api_keys.write() = Some(keys);
+    }
+
+    pub(crate) fn api_keys(&self) -> Option<Arc<ApiKeys>> {
+        self.api_keys.read().clone()
+    }
+
+    /// Serves the `admin_*_webhook` methods from `webhooks`.
+    pub fn manage_webhooks(&self, webhooks: Arc<Webhooks>) {
+        *self.webhooks.write() = Some(webhooks);
+    }
+
+    pub(crate) fn webhooks(&self) -> Option<Arc<Webhooks>> {
+        self.webhooks.read().clone()
+    }
+
+    /// Serves `get_telemetry` from `telemetry`.
+    pub fn serve_telemetry(&self, telemetry: Arc<Telemetry>) {
+        *self.telemetry.write() = Some(telemetry);
+    }
+
+    /// Queues each transaction `send_transaction` admits for `hooks`.
+    pub fn notify_hooks(&self, hooks: Hooks) {
+        *self.hooks.write() = Some(hooks);
+    }
+
+    /// Serves `faucet_request` from `faucet`.
+    #[cfg(feature = "faucet")]
+    pub fn serve_faucet(&self, faucet: Arc<Faucet>) {
+        *self.faucet.write() = Some(faucet);
+    }
+
+    /// Serves `llm_generate` from `queue`.
+    #[cfg(feature = "llm")]
+    pub fn serve_llm(&self, queue: Arc<InferenceQueue>) {
+        *self.llm.write() = Some(queue);
+    }
+
+    /// Serves `llm_generate` from whichever model `models` has serving,
+    /// ahead of any queue given to `serve_llm`, and the `admin_*_model`
+    /// methods from its registry.
+    #[cfg(feature = "llm")]
+    pub fn serve_models(&self, models: Arc<ModelManager>) {
+        *self.models.write() = Some(models);
+    }
+
+    /// Serves `llm_generate` requests marked `distributed` through `router`.
+    #[cfg(feature = "llm")]
+    pub fn route_inference(&self, router: Arc<InferenceRouter>) {
+        *self.router.write() = Some(router);
+    }
+
+    #[cfg(feature = "llm")]
+    pub(crate) fn models(&self) -> Option<Arc<ModelManager>> {
+        self.models.read().clone()
+    }
+
+    /// Usage of the completed `llm_generate` requests that named an
+    /// address, by address.
+    #[cfg(feature = "llm")]
+    pub fn llm_usage_by_address(&self) -> BTreeMap<DADBSAddress, UsageTotals> {
+        self.llm_usage.lock().clone()
+    }
+
+    /// Answers a request body from `client`, sent with `api_key` if any, or
+    /// `None` if it held only notifications.
+    pub async fn handle_body(&self, client: IpAddr, api_key: Option<&str>, body: &[u8]) -> Option<Value> {
+        let _permit = match self.limiter.acquire(client).await {
+            Ok(permit) => permit,
+            Err(limited) => return Some(error_response(Value::Null, limited.into())),
+        };
+        let value: Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, format!("Parse error: {}", e)))),
+        };
+        let requests = match value {
+            Value::Array(requests) => requests,
+            single => return self.handle_request(client, api_key, single).await,
+        };
+        if requests.is_empty() {
+            return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Empty batch")));
+        }
+        if requests.len() > self.max_batch_size {
+            return Some(error_response(Value::Null, RpcError::new(
+                INVALID_REQUEST,
+                format!("Batch of {} exceeds the limit of {}", requests.len(), self.max_batch_size),
+            )));
+        }
+        let mut responses = Vec::with_capacity(requests.len());
+        for request in requests {
+            responses.extend(self.handle_request(client, api_key, request).await);
+        }
+        if responses.is_empty() {
+            None
+        } else {
+            Some(Value::Array(responses))
+        }
+    }
+
+    async fn handle_request(&self, client: IpAddr, api_key: Option<&str>, value: Value) -> Option<Value> {
+        let request: Request = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e)))),
+        };
+        if request.jsonrpc != "2.0" {
+            let id = request.id.unwrap_or(Value::Null);
+            return Some(error_response(id, RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")));
+        }
+        let api_key = request.auth.as_deref().or(api_key);
+        let result = match self.check_access(client, api_key, &request.method) {
+            Ok(key_id) => self.call(client, key_id.as_deref(), &request.method, request.params).await,
+            Err(error) => Err(error),
+        };
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    /// Takes a token for `method` from `client`'s buckets.
+    pub(crate) fn check_limits(&self, client: IpAddr, method: &str) -> Result<(), RpcError> {
+        let bucket = if METHODS.contains(&method) { method } else { "unknown" };
+        self.limiter.check(client, bucket).map_err(|limited| {
+            debug!("Rate limited {} calling {}: retry after {:?}", client, method, limited.retry_after);
+            limited.into()
+        })
+    }
+
+    /// Takes `method` out of the limits of `api_key` if one was presented,
+    /// or else of `client`'s buckets, returning the key's id.
+    pub(crate) fn check_access(
+        &self,
+        client: IpAddr,
+        api_key: Option<&str>,
+        method: &str,
+    ) -> Result<Option<String>, RpcError> {
+        let Some(key) = api_key else {
+            return self.check_limits(client, method).map(|()| None);
+        };
+        match self.require_api_keys()?.authorize(key, method) {
+            Ok(id) => Ok(Some(id)),
+            // So guessing keys is no faster than calling without one.
+            Err(ApiKeyError::Invalid) => {
+                self.check_limits(client, method)?;
+                Err(ApiKeyError::Invalid.into())
+            }
+            Err(e) => {
+                debug!("Refused API key request from {} calling {}: {}", client, method, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Calls `method` for `client` under the same concurrency cap and rate
+    /// limits as a keyless JSON-RPC request over HTTP, for other transports.
+    pub(crate) async fn call_limited(&self, client: IpAddr, method: &str, params: Value) -> Result<Value, RpcError> {
+        let _permit = self.limiter.acquire(client).await?;
+        self.check_limits(client, method)?;
+        self.call(client, None, method, params).await
+    }
+
+    fn require_api_keys(&self) -> Result<Arc<ApiKeys>, RpcError> {
+        self.api_keys().ok_or_else(|| RpcError::new(API_KEY_REFUSED, "This node does not take API keys"))
+    }
+
+    pub(crate) fn max_subscriptions(&self) -> usize {
+        self.max_subscriptions
+    }
+
+    async fn call(&self, client: IpAddr, key_id: Option<&str>, method: &str, params: Value) -> Result<Value, RpcError> {
+        let started = Instant::now();
+        let result = self.dispatch(client, key_id, method, params).await;
+        // Unknown names share one label so clients cannot grow the series set.
+        let label = match &result {
+            Err(error) if error.code == METHOD_NOT_FOUND => "unknown",
+            _ => method,
+        };
+        self.metrics.record(label, started.elapsed(), result.is_ok());
+        result
+    }
+
+    /// `key_id` is the API key the request was authorized by, if any.
+    async fn dispatch(
+        &self,
+        client: IpAddr,
+        key_id: Option<&str>,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, RpcError> {
+        let storage = &self.context.storage;
+        match method {
+            "get_block_by_height" => {
+                let HeightParams { height } = parse_params(params)?;
+                to_value(storage.get_block_by_height(height)?.as_ref().map(BlockResult::from))
+            }
+            "get_block_by_hash" => {
+                let HashParams { hash } = parse_params(params)?;
+                to_value(storage.get_block(&parse_hash(&hash)?)?.as_ref().map(BlockResult::from))
+            }
+            "get_transaction" => {
+                let HashParams { hash } = parse_params(params)?;
+                to_value(storage.get_transaction(&parse_hash(&hash)?)?.as_ref().map(TransactionResult::from))
+            }
+            "get_receipt" => {
+                let HashParams { hash } = parse_params(params)?;
+                to_value(storage.get_receipt(&parse_hash(&hash)?)?.as_ref().map(ReceiptResult::from))
+            }
+            "get_light_headers" => {
+                let LightHeadersParams { from_height, limit } = parse_params(params)?;
+                to_value(self.light_headers(from_height, limit).await?)
+            }
+            "get_inclusion_proof" => {
+                let HashParams { hash } = parse_params(params)?;
+                let tx_hash = parse_hash(&hash)?;
+                let proof = storage.tx_inclusion_proof(&tx_hash)?.map(|(header, proof)| InclusionProofResult {
+                    tx_hash: hash,
+                    height: header.height,
+                    block_hash: header.hash().to_string(),
+                    proof: hex::encode(proof.try_to_vec().expect("proof serialization cannot fail")),
+                });
+                to_value(proof)
+            }
+            "get_blocks" => {
+                let BlocksParams { from_height, cursor, limit, direction } = parse_params(params)?;
+                let page = match cursor {
+                    Some(cursor) => storage.resume_blocks(&cursor, limit, direction)?,
+                    None => {
+                        let from_height = from_height.unwrap_or(match direction {
+                            Direction::Ascending => 0,
+                            Direction::Descending => u64::MAX,
+                        });
+                        storage.blocks_range(from_height, limit, direction)?
+                    }
+                };
+                to_value(page.map(|block| BlockResult::from(&block)))
+            }
+            "get_account_transactions" => {
+                let AccountTransactionsParams { pubkey, cursor, limit, direction } = parse_params(params)?;
+                let pubkey = Pubkey::from_str(&pubkey)
+                    .map_err(|e| RpcError::invalid_params(format!("bad pubkey {}: {}", pubkey, e)))?;
+                let page = storage.transactions_by_address(&pubkey, cursor.as_deref(), limit, direction)?;
+                to_value(page.map(|stored| TransactionResult::from(&stored)))
+            }
+            "get_recent_transactions" => {
+                let RecentTransactionsParams { cursor, limit } = parse_params(params)?;
+                let page = storage.recent_transactions(cursor.as_deref(), limit)?;
+                to_value(page.map(|stored| TransactionResult::from(&stored)))
+            }
+            "get_balance" => {
+                let AddressParams { address } = parse_params(params)?;
+                to_value(self.context.state.read().balance(&DADBSAddress::from_string(&address)?))
+            }
+            "get_nonce" => {
+                let AddressParams { address } = parse_params(params)?;
+                to_value(self.context.state.read().account(&DADBSAddress::from_string(&address)?).nonce)
+            }
+            "send_transaction" => {
+                let SendTransactionParams { raw } = parse_params(params)?;
+                to_value(self.send_transaction(&raw).await?.to_string())
+            }
+            "simulate_transaction" => {
+                let SendTransactionParams { raw } = parse_params(params)?;
+                to_value(self.simulate_transaction(&raw)?)
+            }
+            "trace_transaction" => {
+                let HashParams { hash } = parse_params(params)?;
+                to_value(self.context.tracer.trace(&parse_hash(&hash)?))
+            }
+            "get_node_info" => to_value(self.node_info().await),
+            "get_validator_set" => {
+                let consensus = self.context.consensus.lock().await;
+                let validators: Vec<ValidatorResult> = consensus.validator_set().validators().iter()
+                    .map(|v| ValidatorResult { pubkey: v.pubkey.to_string(), weight: v.weight, scheme: v.scheme })
+                    .collect();
+                to_value(validators)
+            }
+            "get_chain_stats" => {
+                let ChainStatsParams { from_ms, to_ms } = if params.is_null() { ChainStatsParams::default() } else { parse_params(params)? };
+                let days = storage.chain_stats(from_ms.unwrap_or(0), to_ms.unwrap_or(i64::MAX))?;
+                let stake_distribution = chain_stats::stake_distribution(self.context.consensus.lock().await.validator_set());
+                to_value(ChainStatsResult::new(days, stake_distribution))
+            }
+            "get_address_stats" => {
+                let AddressStatsParams { pubkey } = parse_params(params)?;
+                let pubkey = Pubkey::from_str(&pubkey)
+                    .map_err(|e| RpcError::invalid_params(format!("bad pubkey {}: {}", pubkey, e)))?;
+                to_value(storage.address_stats(&pubkey)?)
+            }
+            "get_telemetry" => {
+                let TelemetryParams { hours } = parse_params(params)?;
+                let telemetry = self.telemetry.read().clone()
+                    .ok_or_else(|| RpcError::new(METHOD_NOT_FOUND, "This node keeps no telemetry"))?;
+                // Reads the whole ring, so off the async runtime.
+                let samples = tokio::task::spawn_blocking(move || telemetry.recent(hours))
+                    .await
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, format!("Cannot read telemetry: {}", e)))?;
+                to_value(samples)
+            }
+            "get_address_activity_hint" => {
+                let ActivityHintParams { address, from_height, to_height } = parse_params(params)?;
+                if to_height.saturating_sub(from_height) > MAX_ACTIVITY_HINT_HEIGHTS {
+                    return Err(RpcError::invalid_params(format!(
+                        "at most {} heights can be hinted at once", MAX_ACTIVITY_HINT_HEIGHTS
+                    )));
+                }
+                let address = DADBSAddress::from_string(&address)?;
+                to_value(storage.address_activity(&address, from_height..to_height)?)
+            }
+            "get_llm_peers" => {
+                let filter = if params.is_null() { LlmPeerFilter::default() } else { parse_params(params)? };
+                to_value(self.llm_peers(&filter))
+            }
+            #[cfg(feature = "llm")]
+            "llm_generate" => to_value(self.llm_generate(parse_params(params)?, key_id).await?),
+            #[cfg(feature = "llm")]
+            "llm_estimate" => to_value(self.llm_estimate(parse_params(params)?).await?),
+            "faucet_request" => to_value(self.faucet_request(client, parse_params(params)?).await?),
+            "get_api_key_usage" => {
+                let key_id = key_id.ok_or_else(|| RpcError::new(API_KEY_REFUSED, "This method needs an API key"))?;
+                let (key, today) = self.require_api_keys()?.usage(key_id)?.ok_or(ApiKeyError::Invalid)?;
+                to_value(ApiKeyUsageResult { key, today })
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        }
+    }
+
+    /// Checks that do not depend on state: signature, chain and payload size.
+    fn check_transaction(&self, transaction: &Transaction) -> Result<(), RpcError> {
+        if !transaction.verify_signature() {
+            return Err(RpcError::new(TRANSACTION_REJECTED, "Invalid signature"));
+        }
+        if transaction.chain_id != self.context.chain_id {
+            return Err(RpcError::new(TRANSACTION_REJECTED, format!(
+                "Transaction is for chain {}, this node is on {}", transaction.chain_id, self.context.chain_id
+            )));
+        }
+        if transaction.payload.len() > MAX_PAYLOAD_BYTES {
+            return Err(RpcError::new(TRANSACTION_REJECTED, format!(
+                "Payload of {} bytes exceeds the limit of {}", transaction.payload.len(), MAX_PAYLOAD_BYTES
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuses new transactions while peers' transactions are backed up,
+    /// rather than queueing more behind them.
+    fn check_busy(&self) -> Result<(), RpcError> {
+        match &self.context.network {
+            Some(network) if network.ingest().is_busy() => {
+                Err(RpcError::mempool_full("Node is busy; transaction intake is full"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_transaction(&self, raw: &str) -> Result<Hash, RpcError> {
+        let transaction = decode_transaction(raw)?;
+        let hash = transaction.hash();
+        let tracer = &self.context.tracer;
+        tracer.record(&hash, TxEvent::new(TxStage::Received).with_detail("rpc"));
+        let moderator = self.moderator.read().clone();
+        let checked = match self.check_transaction(&transaction).and_then(|()| self.check_busy()) {
+            Ok(()) => match moderator {
+                Some(moderator) => moderator.check(&transaction).await.map_err(RpcError::from),
+                None => Ok(()),
+            },
+            Err(e) => Err(e),
+        };
+        checked
+            .and_then(|()| {
+                let state = self.context.state.read();
+                self.context.mempool.lock().admit(transaction.clone(), &state).map_err(RpcError::from)
+            })
+            .inspect_err(|e| tracer.record(&hash, TxEvent::new(TxStage::Rejected).with_detail(e.message.clone())))?;
+        tracer.record(&hash, TxEvent::new(TxStage::Admitted));
+        if let Some(hooks) = self.hooks.read().as_ref() {
+            hooks.emit(HookEvent::TxAdmitted(transaction.clone()));
+        }
+        if let Some(network) = &self.context.network {
+            let peers = network.gossip(NetMessage::Tx(transaction));
+            tracer.record(&hash, TxEvent::new(TxStage::Gossiped).with_detail(format!("sent to {} peers", peers)));
+        }
+        Ok(hash)
+    }
+
+    /// Sends the faucet's drip to `params.address` as any transaction is
+    /// sent, once the faucet's limits allow it.
+    #[cfg(feature = "faucet")]
+    async fn faucet_request(&self, client: IpAddr, params: FaucetRequestParams) -> Result<FaucetResult, RpcError> {
+        let faucet = self.faucet.read().clone().ok_or_else(|| RpcError::new(METHOD_NOT_FOUND, "This node runs no faucet"))?;
+        let recipient = Pubkey::from_str(&params.address)
+            .map_err(|e| RpcError::invalid_params(format!("bad address {}: {}", params.address, e)))?;
+        let ledger = faucet.ledger();
+        let _turn = faucet.turn().await;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        ledger.check(&recipient, client, now_ms)?;
+        let (nonce, fee) = {
+            let state = self.context.state.read();
+            let mempool = self.context.mempool.lock();
+            (mempool.next_nonce(&faucet.pubkey(), &state), mempool.min_fee())
+        };
+        let signed = faucet.sign_drip(&self.context.chain_id, recipient, nonce, fee).await?;
+        let hash = self.send_transaction(&signed.to_raw()).await?;
+        let amount = ledger.config().drip_amount;
+        let grant = FaucetGrant { recipient, ip: client.to_string(), amount, tx_hash: hash, granted_ms: now_ms };
+        ledger.record(client, &grant)?;
+        let cooldown_ms = ledger.config().address_cooldown_secs.saturating_mul(1000) as i64;
+        Ok(FaucetResult { hash: hash.to_string(), amount, next_request_ms: now_ms.saturating_add(cooldown_ms) })
+    }
+
+    #[cfg(not(feature = "faucet"))]
+    async fn faucet_request(&self, _client: IpAddr, _params: FaucetRequestParams) -> Result<FaucetResult, RpcError> {
+        Err(RpcError::new(METHOD_NOT_FOUND, "This node was built without the faucet feature"))
+    }
+
+    /// Finalized headers from `from_height` with their certificates, stopping
+    /// at the first height not stored. A header announcing a validator set
+    /// carries it when that is the set now in force.
+    async fn light_headers(&self, from_height: u64, limit: usize) -> Result<Vec<SignedHeaderResult>, RpcError> {
+        let storage = &self.context.storage;
+        let mut headers = Vec::new();
+        for height in from_height..from_height.saturating_add(limit.min(MAX_PAGE_LIMIT) as u64) {
+            let (block, certificate) = match (storage.get_block_by_height(height)?, storage.get_certificate(height)?) {
+                (Some(block), Some(certificate)) => (block, certificate),
+                _ => break,
+            };
+            let announced = block.header.next_validators_hash;
+            let next_validators = if announced == Hash::default() {
+                None
+            } else {
+                let validators = self.context.consensus.lock().await.validator_set().clone();
+                (validators.hash() == announced).then_some(validators)
+            };
+            let signed = SignedHeader { header: block.header, certificate, next_validators };
+            headers.push(SignedHeaderResult {
+                height,
+                hash: signed.hash().to_string(),
+                raw: hex::encode(signed.try_to_vec().expect("header serialization cannot fail")),
+            });
+        }
+        Ok(headers)
+    }
+
+    /// Dry-runs `send_transaction` against the current state and mempool,
+    /// then executes the transaction against a view of the state that is
+    /// thrown away, within the simulation budget.
+    fn simulate_transaction(&self, raw: &str) -> Result<SimulationResult, RpcError> {
+        let transaction = decode_transaction(raw)?;
+        let checked = self.check_transaction(&transaction);
+        let outcome = checked.clone().and_then(|()| self.check_busy()).and_then(|()| {
+            let state = self.context.state.read();
+            self.context.mempool.lock().check(&transaction, &state).map_err(RpcError::from)
+        });
+        let (execution, execution_error) = match checked {
+            Ok(()) => match self.context.state.read().simulate(&transaction, self.simulation_budget) {
+                Ok(simulation) => (Some(simulation), None),
+                Err(e) => (None, Some(e.to_string())),
+            },
+            Err(_) => (None, None),
+        };
+        Ok(SimulationResult {
+            hash: transaction.hash().to_string(),
+            accepted: outcome.is_ok(),
+            error: outcome.err(),
+            execution,
+            execution_error,
+        })
+    }
+
+    /// Generation is cancelled once the request is dropped, as when the
+    /// client disconnects, or the server shuts down. Tokens used are
+    /// charged to `key_id`'s daily budget.
+    #[cfg(feature = "llm")]
+    async fn llm_generate(
+        &self,
+        params: LlmGenerateParams,
+        key_id: Option<&str>,
+    ) -> Result<crate::llm::Completion, RpcError> {
+        // Held to the end, so a swapped-out model is not freed mid-request.
+        let serving = self.models().and_then(|models| models.serving());
+        let queue = serving.as_ref().map(|serving| serving.queue()).or_else(|| self.llm.read().clone());
+        let router = self.router.read().clone().filter(|_| params.distributed);
+        if params.distributed && router.is_none() {
+            return Err(RpcError::invalid_params("distributed inference is not enabled"));
+        }
+        let cancel = self.cancel.child_token();
+        let _disconnected = cancel.clone().drop_guard();
+        let LlmGenerateParams { prompt, max_tokens, temperature, stop, seed, timeout_ms, address, logprobs, .. } = params;
+        let address = address.as_deref().map(DADBSAddress::from_string).transpose()?;
+        let mut generate = GenerateParams::new(max_tokens).with_cancellation(cancel);
+        generate.stop = stop;
+        generate.seed = seed;
+        generate.logprobs = logprobs;
+        if let Some(temperature) = temperature {
+            generate = generate.with_temperature(temperature);
+        }
+        if let Some(timeout_ms) = timeout_ms {
+            generate = generate.with_deadline(Instant::now() + Duration::from_millis(timeout_ms));
+        }
+        let completion = match (router, queue) {
+            (Some(router), _) => router.generate(&prompt, generate).await?.completion,
+            (None, Some(queue)) => queue.generate(&prompt, generate).await?,
+            (None, None) => {
+                return Err(RpcError::new(METHOD_NOT_FOUND, "Method not found: llm_generate; inference is not enabled"))
+            }
+        };
+        if let Some(address) = address {
+            self.llm_usage.lock().entry(address).or_default().add(&completion.usage);
+        }
+        if let (Some(key_id), Some(keys)) = (key_id, self.api_keys()) {
+            let tokens = completion.usage.prompt_tokens + completion.usage.completion_tokens;
+            keys.charge_tokens(key_id, tokens as u64)?;
+        }
+        Ok(completion)
+    }
+
+    /// Counts as `llm_generate` would on the same model.
+    #[cfg(feature = "llm")]
+    async fn llm_estimate(&self, params: LlmEstimateParams) -> Result<LlmEstimateResult, RpcError> {
+        let serving = self.models().and_then(|models| models.serving());
+        let queue = serving.as_ref().map(|serving| serving.queue()).or_else(|| self.llm.read().clone())
+            .ok_or_else(|| RpcError::new(METHOD_NOT_FOUND, "Method not found: llm_estimate; inference is not enabled"))?;
+        let tokens = queue.count_tokens(&params.prompt).await?;
+        let estimate = queue.estimate(&params.prompt, GenerateParams::new(params.max_tokens)).await?;
+        Ok(LlmEstimateResult { tokens, estimate })
+    }
+
+    /// Connected peers advertising a model `filter` matches; none without
+    /// a network.
+    fn llm_peers(&self, filter: &LlmPeerFilter) -> Vec<LlmPeerResult> {
+        let Some(network) = &self.context.network else {
+            return Vec::new();
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        network.find_llm_peers(filter).into_iter()
+            .filter_map(|peer| {
+                let capability = peer.llm?;
+                Some(LlmPeerResult {
+                    addr: peer.id.to_string(),
+                    node_id: peer.node_id,
+                    capability,
+                    last_seen: peer.last_seen,
+                    idle_ms: (now - peer.last_seen).max(0),
+                })
+            })
+            .collect()
+    }
+
+    async fn node_info(&self) -> NodeInfo {
+        let consensus = self.context.consensus.lock().await;
+        NodeInfo {
+            node_id: self.context.node_id.clone(),
+            chain_id: self.context.chain_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            head_height: consensus.head_height(),
+            finalized_height: consensus.finalized_height(),
+            peers: self.context.network.as_ref().map_or(0, |network| network.peers().len()),
+            halted: consensus.halt_status(),
+        }
+    }
+}
+
+/// The key sent in the `x-api-key` header, if any.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers.get(API_KEY_HEADER).and_then(|value| value.to_str().ok())
+}
+
+async fn handle_http(
+    Shared(server): Shared<Arc<RpcServer>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> HttpResponse {
+    match server.handle_body(client.ip(), api_key(&headers), &body).await {
+        Some(response) => ([(header::CONTENT_TYPE, "application/json")], response.to_string()).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn handle_ws(
+    Shared(server): Shared<Arc<RpcServer>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> HttpResponse {
+    let events = server.context.events.clone();
+    let api_key = api_key(&headers).map(str::to_string);
+    let (max_subscriptions, cancel) = (server.max_subscriptions, server.cancel.child_token());
+    upgrade
+        .max_message_size(server.max_request_bytes)
+        .on_upgrade(move |socket| {
+            subscriptions::serve_socket(server, client.ip(), api_key, socket, events, max_subscriptions, cancel)
+        })
+}
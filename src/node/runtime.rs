@@ -0,0 +1,803 @@
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use solana_sdk::signature::{read_keypair_file, Keypair};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+
+use super::admin::AdminApi;
+use super::api_keys::ApiKeys;
+use super::config::{ConfigError, NodeConfig};
+use super::block::Block;
+use super::bloom::DEFAULT_ADDRESS_BLOOM_FP_PPM;
+use super::consensus::{ConsensusDriver, ConsensusManager};
+use super::deposit_watcher::{BridgeSigner, DepositError, DepositWatcher, KeystoreSigner, RpcDepositSource};
+use super::dns_seed::{self, SystemResolver};
+use super::evidence::{EvidenceError, EvidencePool, EvidenceSubmitter, RpcSlashTransport, EVIDENCE_FILE};
+use super::fork_choice::BlockTree;
+use super::genesis::{Genesis, GenesisError};
+use super::handover::HandoverRegistry;
+use super::health::{ClockCheck, ConsensusCheck, HealthRegistry, P2pCheck, PeersCheck, StorageCheck};
+use super::heartbeat::{self, HeartbeatTransport};
+use super::hooks::{BlockJsonLines, HookEvent, HookRegistry, Hooks};
+use super::ingest::IngestPipeline;
+use super::journal::{Journal, JournalError};
+#[cfg(feature = "faucet")]
+use super::faucet::Faucet;
+use super::faucet::FaucetError;
+#[cfg(feature = "grpc")]
+use super::grpc::GrpcServer;
+use super::keystore::{Keystore, KeystoreError};
+use super::mempool::{Mempool, MempoolConfig, MempoolError, DEFAULT_MEMPOOL_CAPACITY};
+use super::metrics::{MetricsRegistry, MetricsServer, ProcessMetrics};
+use super::moderation::{self, MemoModerator};
+use super::nat::{self, PortMapping};
+use super::network::{NetMessage, Network, NetworkConfig, NetworkError, PeerId};
+use super::params::ProtocolParams;
+use super::rpc::{RpcContext, RpcServer};
+use super::shutdown::Shutdown;
+use super::signer::{LocalSigner, RemoteSigner, SignerError, VoteSigner, SIGN_GUARD_FILE};
+use super::snapshot::{SnapshotError, SnapshotTrust};
+use super::state::{State, StateError};
+use super::storage::{Storage, StorageError};
+use super::subscriptions::ChainEvents;
+use super::sync::{BlockStore, NetworkSync, SyncManager, SyncMessage, DEFAULT_SYNC_BATCH_SIZE};
+use super::telemetry::{NodeGauges, Telemetry, TelemetryError, TelemetryStore};
+use super::transaction::Transaction;
+use super::tx_trace::{TxEvent, TxStage, TxTracer};
+use super::validator::{ValidatorSet, ValidatorSetHistory};
+use super::validator_oracle::{OracleError, RpcStakeSource, ValidatorSetOracle};
+#[cfg(feature = "webhooks")]
+use super::webhooks::{HttpTransport, Webhooks};
+use super::webhooks::WebhookError;
+#[cfg(feature = "llm")]
+use crate::llm::{
+    AdmissionConfig, AdmissionController, ChallengeConfig, InferenceChallenger, InferenceRouter, LlmError, ModelManager,
+    RouterConfig, SystemResources,
+};
+#[cfg(feature = "llm")]
+use super::network::CAPABILITY_LLM;
+
+/// Block storage directory inside `storage_path`.
+pub const CHAIN_DIR: &str = "chain";
+/// Account state file inside `storage_path`.
+pub const STATE_FILE: &str = "state.json";
+/// Longest wait for goodbye messages to reach peers, within the deadline.
+const GOODBYE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum NodeError {
+    #[error("Configuration error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+    #[error("Snapshot error: {0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("Genesis error: {0}")]
+    Genesis(#[from] GenesisError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("Keystore error: {0}")]
+    Keystore(#[from] KeystoreError),
+    #[error("Faucet error: {0}")]
+    Faucet(#[from] FaucetError),
+    #[error("Journal error: {0}")]
+    Journal(#[from] JournalError),
+    #[error("Webhook error: {0}")]
+    Webhooks(#[from] WebhookError),
+    #[error("Validator oracle error: {0}")]
+    Oracle(#[from] OracleError),
+    #[error("Remote signer error: {0}")]
+    Signer(#[from] SignerError),
+    #[error("Telemetry error: {0}")]
+    Telemetry(#[from] TelemetryError),
+    #[error("Deposit watcher error: {0}")]
+    Deposits(#[from] DepositError),
+    #[error("Evidence error: {0}")]
+    Evidence(#[from] EvidenceError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "llm")]
+    #[error("LLM error: {0}")]
+    Llm(#[from] LlmError),
+}
+
+/// A running node: storage, state, consensus, mempool, p2p, RPC and
+/// metrics, wired together from a `NodeConfig`.
+pub struct Node {
+    node_id: String,
+    storage: Arc<Storage>,
+    state: Arc<RwLock<State>>,
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    mempool: Arc<Mutex<Mempool>>,
+    /// Saves and reloads the mempool, unless `mempool.persist` is off.
+    mempool_store: Option<Arc<MempoolStore>>,
+    network: Arc<Network>,
+    rpc: Arc<RpcServer>,
+    /// Signs votes with the `validator_key`, or the `dadbs-signer` holding it.
+    signer: Option<Arc<dyn VoteSigner>>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcServer>,
+    metrics: MetricsServer,
+    shutdown: Arc<Shutdown>,
+}
+
+impl Node {
+    pub async fn start(config: NodeConfig) -> Result<Self, NodeError> {
+        Self::start_with_hooks(config, HookRegistry::new()).await
+    }
+
+    /// Starts a node calling `hooks`, and those `[hooks]` enables, as
+    /// transactions are admitted and blocks proposed and finalized.
+    pub async fn start_with_hooks(config: NodeConfig, hooks: HookRegistry) -> Result<Self, NodeError> {
+        let shutdown = Arc::new(Shutdown::new(Duration::from_millis(config.shutdown_deadline_ms)));
+        let root = Path::new(&config.storage_path);
+        let storage = Arc::new(Storage::open(root.join(CHAIN_DIR))?);
+        let genesis = Genesis::from_config(&config)?;
+        let balances = genesis.iter().flat_map(Genesis::balances).collect::<Vec<_>>();
+        let state = Arc::new(RwLock::new(State::open(&root.join(STATE_FILE), balances)?));
+        let params = genesis.as_ref().map_or_else(
+            || ProtocolParams { min_fee: config.min_fee, ..ProtocolParams::default() },
+            |genesis| genesis.params.clone(),
+        );
+
+        let events = ChainEvents::default();
+        let tracer = TxTracer::new(config.tx_trace_capacity);
+        let hooks = match &config.hooks.finalized_blocks_dir {
+            Some(dir) => hooks.with_hook(Arc::new(BlockJsonLines::new(dir))),
+            None => hooks,
+        };
+        let (hooks, hook_runner) = if hooks.is_empty() {
+            (None, None)
+        } else {
+            info!("Calling {} node hooks", hooks.len());
+            let (hooks, runner) = hooks.start(&config.hooks);
+            (Some(hooks), Some(runner))
+        };
+        let handovers = HandoverRegistry::for_chain(&config.chain_id);
+        let restored = handovers.restore(storage.handovers()?);
+        if restored > 0 {
+            info!("Restored {} key handovers", restored);
+        }
+        let bloom_fp_ppm = genesis.as_ref().map_or(DEFAULT_ADDRESS_BLOOM_FP_PPM, Genesis::address_bloom_fp_ppm);
+        let mut consensus = ConsensusManager::from_config(&config)?
+            .with_params(params)
+            .with_address_bloom_fp_ppm(bloom_fp_ppm)
+            .with_state(Arc::clone(&state))
+            .with_events(events.clone())
+            .with_tracer(tracer.clone())
+            .with_receipt_store(Arc::clone(&storage))
+            .with_handovers(handovers.clone());
+        if let Some(hooks) = &hooks {
+            consensus = consensus.with_hooks(hooks.clone());
+        }
+        let slashing = config.slashing.as_ref().filter(|slashing| slashing.enabled);
+        let evidence = match slashing {
+            Some(slashing) => {
+                let pool = EvidencePool::open(&root.join(EVIDENCE_FILE), slashing.evidence_max_age_epochs)?;
+                let pool = Arc::new(pool);
+                consensus = consensus.with_evidence_pool(Arc::clone(&pool));
+                Some(pool)
+            }
+            None => None,
+        };
+        let validators = genesis.as_ref().map_or(&config.validators, |genesis| &genesis.validators);
+        if !validators.is_empty() {
+            consensus.set_validator_set(ValidatorSet::new(validators.clone()));
+        }
+        match storage.finalized_height()? {
+            // Resume from the stored tip; its body may have been pruned.
+            Some(height) => {
+                if let Some(header) = storage.get_header_by_height(height)? {
+                    let tip = Block { header, transactions: Vec::new() };
+                    consensus.restore_block_tree(BlockTree::new(tip, config.max_fork_depth));
+                }
+            }
+            None => {
+                if let Some((path, trust)) = SnapshotTrust::from_config(&config)? {
+                    let snapshot = storage.import_snapshot(&path, &trust)?;
+                    consensus.bootstrap_from_snapshot(snapshot)?;
+                } else if let Some(genesis) = &genesis {
+                    consensus.restore_block_tree(BlockTree::new(genesis.block(), config.max_fork_depth));
+                }
+            }
+        }
+        consensus.restore_param_changes(storage.param_changes()?);
+        let journal = if config.journal.enabled {
+            let tree = consensus.block_tree();
+            let tip = (tree.finalized_height(), tree.finalized_hash());
+            let journal = Arc::new(Journal::open(&config.journal, &config.storage_path, tip)?);
+            info!("Recording consensus messages in {}", journal.dir().display());
+            consensus = consensus.with_journal(Arc::clone(&journal));
+            Some(journal)
+        } else {
+            None
+        };
+        let min_fee = consensus.min_fee();
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.register(Arc::new(ProcessMetrics::new()));
+        registry.register(consensus.metrics());
+        if let Some(hooks) = &hooks {
+            registry.register(hooks.stats());
+        }
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let mempool = Mempool::new(min_fee, DEFAULT_MEMPOOL_CAPACITY).with_chain_id(config.chain_id.clone());
+        let mempool = Arc::new(Mutex::new(mempool));
+        let moderator = config.moderation.enabled.then(|| Arc::new(MemoModerator::new(config.moderation.clone())));
+        if let Some(moderator) = &moderator {
+            registry.register(Arc::clone(moderator));
+        }
+
+        let genesis_hash = genesis.as_ref().map(Genesis::canonical_hash).unwrap_or_default();
+        let network_config = NetworkConfig::from_node_config(&config)?
+            .with_genesis_hash(genesis_hash)
+            .with_handovers(handovers);
+        #[cfg(feature = "llm")]
+        let network_config = match config.llm.as_ref().filter(|llm| llm.enabled) {
+            Some(_) => network_config.with_capabilities(CAPABILITY_LLM),
+            None => network_config,
+        };
+        let (network, inbound) = Network::bind(network_config).await?;
+        registry.register(Arc::clone(&inbound));
+        if config.nat.upnp {
+            let external_port = config.nat.external_port.unwrap_or(network.local_addr().port());
+            let lease = Duration::from_secs(config.nat.lease_secs);
+            let mapping = async {
+                PortMapping::start(nat::upnp_gateway().await?, network.local_addr(), external_port, lease).await
+            };
+            match mapping.await {
+                Ok(mapping) => {
+                    network.set_port_mapping(Some(mapping.external()));
+                    shutdown.spawn("port-mapping", move |cancel| mapping.run(cancel));
+                }
+                Err(e) => warn!("UPnP port mapping failed, continuing without: {}", e),
+            }
+        }
+        if config.bootstrap_nodes.iter().any(|node| dns_seed::is_dns_seed(node)) {
+            let (network, resolver) = (Arc::clone(&network), Arc::new(SystemResolver::new()));
+            shutdown.spawn("dns-seeds", move |cancel| network.run_dns_seeds(resolver, cancel));
+        }
+        let signer: Option<Arc<dyn VoteSigner>> = match (&config.remote_signer, &config.validator_key) {
+            (Some(signer_config), _) => {
+                let signer = RemoteSigner::from_config(signer_config, &root.join(SIGN_GUARD_FILE))?
+                    .with_control(consensus.lock().await.control());
+                let signer = Arc::new(signer);
+                info!("Signing votes for {} through {}", signer.pubkey(), signer_config.endpoint);
+                shutdown.spawn("remote-signer", {
+                    let signer = Arc::clone(&signer);
+                    move |cancel| signer.run(cancel)
+                });
+                Some(signer)
+            }
+            (None, Some(key)) => {
+                let signer = LocalSigner::open(Path::new(key), &root.join(SIGN_GUARD_FILE))?;
+                info!("Signing votes for {} with {}", signer.pubkey(), key);
+                Some(Arc::new(signer))
+            }
+            (None, None) => None,
+        };
+        // A validator's heartbeats vouch for its state roots; other nodes
+        // sign theirs with a key of their own, carrying no weight.
+        let heartbeat_key = match &config.validator_key {
+            Some(key) => read_keypair_file(key)
+                .map_err(|e| SignerError::Config(format!("cannot read validator key {}: {}", key, e)))?,
+            None => Keypair::new(),
+        };
+        shutdown.spawn("heartbeats", {
+            let (consensus, transport) = (Arc::clone(&consensus), Arc::clone(&network) as Arc<dyn HeartbeatTransport>);
+            let interval = Duration::from_millis(config.heartbeat_interval_ms);
+            move |cancel| heartbeat::run_heartbeats(consensus, heartbeat_key, transport, interval, cancel)
+        });
+        let driver = ConsensusDriver::new(
+            Arc::clone(&consensus),
+            Arc::clone(&storage),
+            Arc::clone(&mempool),
+            Duration::from_millis(config.consensus_timeout),
+        ).with_network(Arc::clone(&network));
+        let driver = Arc::new(match &signer {
+            Some(signer) => driver.with_signer(Arc::clone(signer)),
+            None => driver,
+        });
+        shutdown.spawn("consensus", {
+            let driver = Arc::clone(&driver);
+            move |cancel| driver.run(cancel)
+        });
+        let history = ValidatorSetHistory::new(consensus.lock().await.validator_set().clone());
+        let sync = SyncManager::new(
+            Arc::clone(&consensus),
+            Arc::clone(&storage) as Arc<dyn BlockStore>,
+            Arc::new(RwLock::new(history)),
+            DEFAULT_SYNC_BATCH_SIZE,
+        ).with_network(Arc::clone(&network));
+        let sync = Arc::new(NetworkSync::new(Arc::new(sync), Arc::clone(&network)));
+        let handler = InboundHandler {
+            consensus: Arc::clone(&consensus),
+            network: Arc::clone(&network),
+            driver,
+            sync,
+            storage: Arc::clone(&storage),
+            admission: GossipAdmission {
+                chain_id: config.chain_id.clone(),
+                mempool: Arc::clone(&mempool),
+                state: Arc::clone(&state),
+                tracer: tracer.clone(),
+                moderator: moderator.clone(),
+                hooks: hooks.clone(),
+            },
+            journal,
+        };
+        shutdown.spawn("inbound", move |cancel| handler.run(inbound, cancel));
+        shutdown.spawn("pruner", {
+            let (storage, storage_config) = (Arc::clone(&storage), config.storage);
+            move |cancel| async move { storage.run_pruner(storage_config, cancel).await }
+        });
+
+        let context = RpcContext {
+            node_id: config.node_id.clone(),
+            chain_id: config.chain_id.clone(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::clone(&consensus),
+            state: Arc::clone(&state),
+            mempool: Arc::clone(&mempool),
+            network: Some(Arc::clone(&network)),
+            events,
+            tracer,
+        };
+        let admin = match config.admin.token()? {
+            Some(token) => Some(
+                AdminApi::new(token, config.admin.snapshot_dir(&config.storage_path))
+                    .with_keystore(Arc::new(Keystore::from_config(&config)?)),
+            ),
+            None => None,
+        };
+        let rpc = RpcServer::bind_with(config.rpc.clone(), context, config.limits.clone(), admin).await?;
+        rpc.authenticate_api_keys(Arc::new(ApiKeys::new(&config.api_keys, Arc::clone(&storage))));
+        if let Some(moderator) = &moderator {
+            rpc.moderate_memos(Arc::clone(moderator));
+        }
+        if let Some(hooks) = &hooks {
+            rpc.notify_hooks(hooks.clone());
+        }
+        if let Some(runner) = hook_runner {
+            shutdown.spawn("hooks", move |cancel| runner.run(cancel));
+        }
+        if config.faucet.enabled {
+            #[cfg(feature = "faucet")]
+            {
+                let keystore = Arc::new(Keystore::from_config(&config)?);
+                let faucet = Faucet::open(config.faucet.clone(), Arc::clone(&storage), keystore)?;
+                info!("Faucet serving {} per request from {}", config.faucet.drip_amount, faucet.pubkey());
+                rpc.serve_faucet(Arc::new(faucet));
+            }
+            #[cfg(not(feature = "faucet"))]
+            warn!("Faucet enabled, but this binary was built without the faucet feature");
+        }
+        if config.webhooks.enabled {
+            #[cfg(feature = "webhooks")]
+            {
+                let transport = Arc::new(HttpTransport::new(Duration::from_millis(config.webhooks.timeout_ms)));
+                let webhooks = Arc::new(Webhooks::open(config.webhooks.clone(), Arc::clone(&storage), transport)?);
+                registry.register(webhooks.metrics());
+                rpc.manage_webhooks(Arc::clone(&webhooks));
+                let events = rpc.context.events.clone();
+                shutdown.spawn("webhooks", move |cancel| webhooks.run(&events, cancel));
+            }
+            #[cfg(not(feature = "webhooks"))]
+            warn!("Webhooks enabled, but this binary was built without the webhooks feature");
+        }
+        if config.validator_oracle.enabled {
+            let oracle_config = &config.validator_oracle;
+            let program_id = oracle_config.program_id().map_err(OracleError::Config)?;
+            let source = Arc::new(RpcStakeSource::new(oracle_config.rpc_url.clone(), program_id));
+            let oracle = Arc::new(ValidatorSetOracle::new(oracle_config, source, Arc::clone(&consensus))?);
+            registry.register(oracle.metrics());
+            let events = rpc.context.events.clone();
+            shutdown.spawn("validator-oracle", move |cancel| oracle.run(&events, cancel));
+        }
+        if config.deposit_watcher.enabled {
+            let watcher_config = &config.deposit_watcher;
+            let program_id = watcher_config.program_id().map_err(DepositError::Config)?;
+            let source = Arc::new(RpcDepositSource::new(watcher_config.rpc_url.clone(), program_id));
+            let signer = Arc::new(KeystoreSigner::open(watcher_config, Arc::new(Keystore::from_config(&config)?))?);
+            info!("Crediting stake deposits of {} from {}", program_id, signer.pubkey());
+            let cursor_path = watcher_config.cursor_file(&config.storage_path);
+            let (state, mempool) = (Arc::clone(&state), Arc::clone(&mempool));
+            let watcher = DepositWatcher::new(watcher_config, source, signer, cursor_path, state, mempool)?;
+            let watcher = Arc::new(watcher.with_network(Arc::clone(&network)));
+            registry.register(watcher.metrics());
+            shutdown.spawn("deposit-watcher", move |cancel| watcher.run(cancel));
+        }
+        if let (Some(slashing), Some(pool)) = (slashing, evidence) {
+            let authority = read_keypair_file(&slashing.authority_keypair_path).map_err(|e| {
+                ConfigError::InvalidSlashingConfig(format!("cannot read {}: {}", slashing.authority_keypair_path, e))
+            })?;
+            let target = slashing.target()?;
+            let submitter_key = solana_sdk::signature::Signer::pubkey(&authority);
+            info!("Submitting equivocation evidence to {} as {}", target.program_id, submitter_key);
+            let transport = Arc::new(RpcSlashTransport::new(slashing.rpc_url.clone()));
+            let resolver = Arc::new(slashing.stake_accounts()?);
+            let submitter = EvidenceSubmitter::new(pool, transport, resolver, authority, target)
+                .with_events(rpc.context.events.clone());
+            let interval = Duration::from_millis(slashing.submit_interval_ms);
+            shutdown.spawn("evidence-submitter", move |cancel| async move { submitter.run(interval, cancel).await });
+        }
+        #[cfg(feature = "grpc")]
+        let grpc = if config.grpc.enabled {
+            Some(GrpcServer::bind(&config.grpc, Arc::clone(&rpc)).await?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "grpc"))]
+        if config.grpc.enabled {
+            warn!("gRPC enabled, but this binary was built without the grpc feature");
+        }
+
+        registry.register(Arc::clone(&network));
+        registry.register(Arc::clone(&mempool));
+        registry.register(Arc::clone(&storage));
+        registry.register(rpc.metrics());
+        let gauges = NodeGauges::new(Arc::clone(&network), Arc::clone(&mempool), consensus.lock().await.metrics());
+        #[cfg(feature = "llm")]
+        let mut gauges = gauges;
+        #[cfg(feature = "llm")]
+        if let Some(llm) = config.llm.as_ref().filter(|llm| llm.enabled) {
+            // Inference gives way while consensus reports it is running late.
+            let admission = AdmissionController::new(AdmissionConfig::from(llm), Arc::new(SystemResources))
+                .with_consensus(consensus.lock().await.control());
+            let admission = Arc::new(admission);
+            gauges = gauges.with_admission(Arc::clone(&admission));
+            let models = ModelManager::open(root, llm.clone())?
+                .with_admission(admission)
+                .with_network(Arc::clone(&network));
+            let models = Arc::new(models);
+            registry.register(models.metrics());
+            rpc.serve_models(Arc::clone(&models));
+            if let Some(moderator) = &moderator {
+                moderator.classify_with(Arc::clone(&models));
+            }
+            let router = Arc::new(InferenceRouter::new(Arc::clone(&network), Arc::clone(&models), RouterConfig::from(llm)));
+            rpc.route_inference(Arc::clone(&router));
+            shutdown.spawn("inference-router", move |cancel| router.run(cancel));
+            let challenger = Arc::new(InferenceChallenger::new(Arc::clone(&network), Arc::clone(&models), ChallengeConfig::default()));
+            shutdown.spawn("inference-challenger", move |cancel| challenger.run(cancel));
+            // Loaded in the background so consensus need not wait for it.
+            tokio::spawn(async move {
+                if let Err(e) = models.start().await {
+                    warn!("Not serving a model: {}", e);
+                }
+            });
+        }
+        if config.telemetry.enabled {
+            let store = TelemetryStore::open(config.telemetry.file(&config.storage_path), &config.telemetry)?;
+            info!("Recording telemetry in {}", store.path().display());
+            let telemetry = Arc::new(Telemetry::new(store, Arc::new(gauges), config.telemetry.clone()));
+            rpc.serve_telemetry(Arc::clone(&telemetry));
+            shutdown.spawn("telemetry", move |cancel| telemetry.run(cancel));
+        }
+        let health = Arc::new(HealthRegistry::from_config(&config.health));
+        health.register(Arc::new(StorageCheck(Arc::clone(&storage))));
+        health.register(Arc::new(P2pCheck(Arc::clone(&network))));
+        health.register(Arc::new(PeersCheck { network: Arc::clone(&network), min_peers: config.health.min_peers }));
+        health.register(Arc::new(ConsensusCheck(Arc::clone(&consensus))));
+        health.register(Arc::new(ClockCheck {
+            consensus: Arc::clone(&consensus),
+            max_skew_ms: config.health.max_clock_skew_ms,
+        }));
+        let metrics = MetricsServer::bind(&config.metrics, registry, health).await?;
+        // Reloaded in the background, once consensus is under way.
+        let mempool_store = config.mempool.persist.then(|| {
+            let store = Arc::new(MempoolStore {
+                storage: Arc::clone(&storage),
+                state: Arc::clone(&state),
+                mempool: Arc::clone(&mempool),
+                config: config.mempool,
+                saving: AtomicBool::new(false),
+            });
+            shutdown.spawn("mempool-store", {
+                let store = Arc::clone(&store);
+                move |cancel| store.run(cancel)
+            });
+            store
+        });
+
+        Ok(Node {
+            node_id: config.node_id,
+            storage,
+            state,
+            consensus,
+            mempool,
+            mempool_store,
+            network,
+            rpc,
+            signer,
+            #[cfg(feature = "grpc")]
+            grpc,
+            metrics,
+            shutdown,
+        })
+    }
+
+    pub fn p2p_addr(&self) -> SocketAddr {
+        self.network.local_addr()
+    }
+
+    pub fn rpc_addr(&self) -> SocketAddr {
+        self.rpc.local_addr()
+    }
+
+    #[cfg(feature = "grpc")]
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc.as_ref().map(GrpcServer::local_addr)
+    }
+
+    pub fn metrics_addr(&self) -> SocketAddr {
+        self.metrics.local_addr()
+    }
+
+    /// Penalty points against the peer listening at `listen_addr`.
+    pub fn peer_score(&self, listen_addr: &SocketAddr) -> f64 {
+        self.network.peer_score(listen_addr)
+    }
+
+    /// What signs this node's votes, from `validator_key` or
+    /// `[remote_signer]`, if either is configured.
+    pub fn vote_signer(&self) -> Option<Arc<dyn VoteSigner>> {
+        self.signer.clone()
+    }
+
+    /// Triggering it stops a node blocked in `run`.
+    pub fn shutdown(&self) -> Arc<Shutdown> {
+        Arc::clone(&self.shutdown)
+    }
+
+    /// Runs until SIGINT, SIGTERM or `Shutdown::trigger`, then stops. See
+    /// `stop`.
+    pub async fn run(self) -> Result<Vec<String>, NodeError> {
+        self.shutdown.listen_for_signals()?;
+        info!(
+            "Node {} running: p2p {}, rpc {}, metrics {}",
+            self.node_id, self.p2p_addr(), self.rpc_addr(), self.metrics_addr()
+        );
+        self.shutdown.triggered().await;
+        self.stop().await
+    }
+
+    /// Stops taking RPC requests and inbound connections, pauses consensus
+    /// so we neither propose nor vote, saves and drops the mempool and says
+    /// goodbye to peers. Background tasks are then drained; the peer store, state and
+    /// API key usage are persisted and storage flushed, marking the
+    /// shutdown clean last.
+    /// Returns the tasks aborted at the deadline.
+    pub async fn stop(self) -> Result<Vec<String>, NodeError> {
+        self.shutdown.trigger();
+        self.rpc.shutdown();
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &self.grpc {
+            grpc.shutdown();
+        }
+        self.network.stop_accepting();
+        self.consensus.lock().await.control().pause();
+        let dropped = match &self.mempool_store {
+            Some(store) => store.save_and_clear(),
+            None => self.mempool.lock().clear(),
+        };
+        if dropped > 0 {
+            info!("Dropped {} pending transactions", dropped);
+        }
+        self.network.say_goodbye("shutting down", GOODBYE_TIMEOUT.min(self.shutdown.deadline())).await;
+        self.network.shutdown();
+
+        let aborted = self.shutdown.drain().await;
+        self.network.persist_peers()?;
+        self.state.read().persist()?;
+        if let Some(Err(e)) = self.rpc.api_keys().map(|keys| keys.flush()) {
+            warn!("Failed to write back API key usage: {}", e);
+        }
+        self.storage.mark_clean_shutdown()?;
+        self.metrics.shutdown();
+        info!("Node {} stopped", self.node_id);
+        Ok(aborted)
+    }
+}
+
+/// What handles the messages peers send.
+struct InboundHandler {
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    network: Arc<Network>,
+    driver: Arc<ConsensusDriver>,
+    sync: Arc<NetworkSync>,
+    storage: Arc<Storage>,
+    admission: GossipAdmission,
+    journal: Option<Arc<Journal>>,
+}
+
+impl InboundHandler {
+    /// Admits gossiped transactions, hands proposals and votes to consensus,
+    /// serves and syncs finalized blocks, records heartbeats and stores new
+    /// key handovers until `cancel` fires, journaling consensus messages
+    /// first if a journal is kept.
+    async fn run(self, inbound: Arc<IngestPipeline>, cancel: CancellationToken) {
+        loop {
+            let (from, message) = tokio::select! {
+                _ = cancel.cancelled() => return,
+                received = inbound.recv() => match received {
+                    Some(received) => received,
+                    None => return,
+                },
+            };
+            if let Some(journal) = &self.journal {
+                journal.record_inbound(from, &message, chrono::Utc::now().timestamp_millis());
+            }
+            match message {
+                NetMessage::Tx(transaction) => {
+                    self.admission.submit(from, transaction);
+                }
+                // A proposal or vote at a height shows its sender finalized
+                // the one before.
+                NetMessage::Proposal(proposal) => {
+                    self.sync.on_peer_height(from, proposal.block.height().saturating_sub(1), &cancel).await;
+                    self.driver.on_proposal(from, proposal).await;
+                }
+                NetMessage::Vote(vote) => {
+                    self.sync.on_peer_height(from, vote.height.saturating_sub(1), &cancel).await;
+                    self.driver.on_vote(from, vote).await;
+                }
+                NetMessage::GetBlocks { from: start, to } => {
+                    self.sync.on_message(from, SyncMessage::GetBlocks { from: start, to }).await;
+                }
+                NetMessage::Blocks { blocks, certificates } => {
+                    self.sync.on_message(from, SyncMessage::Blocks { blocks, certificates }).await;
+                }
+                NetMessage::Heartbeat(heartbeat) => {
+                    let recorded = self.consensus.lock().await.record_heartbeat(from, &heartbeat);
+                    if let Err(e) = recorded {
+                        debug!("Ignoring heartbeat from {}: {}", from, e);
+                        self.network.report(&from, e.offense());
+                        continue;
+                    }
+                    self.sync.on_peer_height(from, heartbeat.height, &cancel).await;
+                }
+                // The network recorded it and passes on only those it had not.
+                NetMessage::KeyHandover(handover) => {
+                    if let Err(e) = self.storage.put_handover(&handover) {
+                        warn!("Cannot store the handover of {}: {}", handover.old_pub, e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Saves pending transactions to storage and reloads them at startup.
+struct MempoolStore {
+    storage: Arc<Storage>,
+    state: Arc<RwLock<State>>,
+    mempool: Arc<Mutex<Mempool>>,
+    config: MempoolConfig,
+    /// Whether saves may replace what storage holds: from the reload, so
+    /// none overwrites transactions not yet read back, until the last save
+    /// at shutdown. Changed and read under the mempool lock.
+    saving: AtomicBool,
+}
+
+impl MempoolStore {
+    /// Reloads what was saved, then saves every `persist_interval_ms`
+    /// until `cancel` fires.
+    async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        self.reload();
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.persist_interval_ms));
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            let pool = self.mempool.lock();
+            if let Err(e) = self.save(&pool) {
+                warn!("Failed to save pending transactions: {}", e);
+            }
+        }
+    }
+
+    fn reload(&self) {
+        let saved = match self.storage.saved_mempool() {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Cannot read the pending transactions saved at shutdown: {}", e);
+                return;
+            }
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        let state = self.state.read();
+        let mut pool = self.mempool.lock();
+        let reloaded = pool.reload(saved, &state, now, self.config.max_reload_age_ms);
+        self.saving.store(true, Ordering::Relaxed);
+        if reloaded.restored + reloaded.invalid + reloaded.expired > 0 {
+            info!(
+                "Reloaded {} pending transactions; dropped {} no longer valid and {} expired",
+                reloaded.restored, reloaded.invalid, reloaded.expired
+            );
+        }
+    }
+
+    fn save(&self, pool: &Mempool) -> Result<usize, StorageError> {
+        if !self.saving.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let pending = pool.snapshot(self.config.max_persisted);
+        self.storage.save_mempool(&pending)?;
+        Ok(pending.len())
+    }
+
+    /// Saves a last time and empties the pool, returning how many
+    /// transactions it held.
+    fn save_and_clear(&self) -> usize {
+        let mut pool = self.mempool.lock();
+        match self.save(&pool) {
+            Ok(saved) if saved > 0 => info!("Saved {} pending transactions", saved),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to save pending transactions: {}", e),
+        }
+        self.saving.store(false, Ordering::Relaxed);
+        pool.clear()
+    }
+}
+
+/// What admitting gossiped transactions needs.
+#[derive(Clone)]
+struct GossipAdmission {
+    chain_id: String,
+    mempool: Arc<Mutex<Mempool>>,
+    state: Arc<RwLock<State>>,
+    tracer: TxTracer,
+    moderator: Option<Arc<MemoModerator>>,
+    hooks: Option<Hooks>,
+}
+
+impl GossipAdmission {
+    fn submit(&self, from: PeerId, transaction: Transaction) {
+        if transaction.chain_id != self.chain_id {
+            debug!("Dropping transaction {} from {} for chain {}", transaction.hash(), from, transaction.chain_id);
+            return;
+        }
+        self.tracer.record(&transaction.hash(), TxEvent::new(TxStage::Received).with_peer(from).with_detail("gossip"));
+        match self.moderator.as_ref().filter(|_| moderation::memo(&transaction).is_some()) {
+            // Classified on its own task, so a slow model does not hold up
+            // the messages behind it.
+            Some(moderator) => {
+                let (moderator, admission) = (Arc::clone(moderator), self.clone());
+                tokio::spawn(async move {
+                    let checked = moderator.check(&transaction).await;
+                    admission.admit(from, transaction, checked);
+                });
+            }
+            None => self.admit(from, transaction, Ok(())),
+        }
+    }
+
+    /// Admits `transaction` unless an earlier check failed.
+    fn admit(&self, from: PeerId, transaction: Transaction, checked: Result<(), MempoolError>) {
+        let hash = transaction.hash();
+        let admitted = self.hooks.as_ref().map(|_| transaction.clone());
+        match checked.and_then(|()| self.mempool.lock().admit(transaction, &self.state.read())) {
+            Ok(()) => {
+                self.tracer.record(&hash, TxEvent::new(TxStage::Admitted).with_peer(from));
+                if let (Some(hooks), Some(transaction)) = (&self.hooks, admitted) {
+                    hooks.emit(HookEvent::TxAdmitted(transaction));
+                }
+            }
+            Err(e) => {
+                debug!("Not admitting transaction {} from {}: {}", hash, from, e);
+                self.tracer.record(&hash, TxEvent::new(TxStage::Rejected).with_peer(from).with_detail(e.to_string()));
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_SHUTDOWN_DEADLINE_MS: u64 = 10_000;
+
+/// Coordinates stopping the node. `trigger`, or SIGINT/SIGTERM once
+/// `listen_for_signals` is called, marks shutdown as requested; whoever owns
+/// the node then winds its subsystems down in order and calls `drain`,
+/// which cancels every task spawned here and waits for them until the
+/// deadline, counted from the trigger.
+pub struct Shutdown {
+    requested: CancellationToken,
+    tasks_cancel: CancellationToken,
+    tasks: Mutex<Vec<(String, JoinHandle<()>)>>,
+    deadline: Duration,
+    triggered_at: Mutex<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn new(deadline: Duration) -> Self {
+        Shutdown {
+            requested: CancellationToken::new(),
+            tasks_cancel: CancellationToken::new(),
+            tasks: Mutex::new(Vec::new()),
+            deadline,
+            triggered_at: Mutex::new(None),
+        }
+    }
+
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+
+    /// A token cancelled when `drain` starts.
+    pub fn token(&self) -> CancellationToken {
+        self.tasks_cancel.child_token()
+    }
+
+    /// Spawns a long-running task that `drain` waits for. The task must
+    /// return once `token` fires.
+    pub fn spawn<F>(&self, name: impl Into<String>, task: impl FnOnce(CancellationToken) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(task(self.token()));
+        self.tasks.lock().push((name.into(), handle));
+    }
+
+    /// Requests shutdown. Later calls do nothing.
+    pub fn trigger(&self) {
+        self.triggered_at.lock().get_or_insert_with(Instant::now);
+        self.requested.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.requested.is_cancelled()
+    }
+
+    /// Resolves once shutdown is requested.
+    pub async fn triggered(&self) {
+        self.requested.cancelled().await
+    }
+
+    /// Triggers shutdown on SIGINT or SIGTERM, or Ctrl-C off Unix. The
+    /// handlers are installed before this returns.
+    pub fn listen_for_signals(self: &Arc<Self>) -> std::io::Result<()> {
+        let shutdown = Arc::clone(self);
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut interrupt = signal(SignalKind::interrupt())?;
+            let mut terminate = signal(SignalKind::terminate())?;
+            tokio::spawn(async move {
+                let name = tokio::select! {
+                    _ = shutdown.requested.cancelled() => return,
+                    _ = interrupt.recv() => "SIGINT",
+                    _ = terminate.recv() => "SIGTERM",
+                };
+                info!("Received {}, shutting down", name);
+                shutdown.trigger();
+            });
+        }
+        #[cfg(not(unix))]
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown.requested.cancelled() => {}
+                received = tokio::signal::ctrl_c() => if received.is_ok() {
+                    info!("Received Ctrl-C, shutting down");
+                    shutdown.trigger();
+                },
+            }
+        });
+        Ok(())
+    }
+
+    /// Cancels every spawned task and waits for them until the deadline.
+    /// Tasks still running then are aborted; their names are logged and
+    /// returned.
+    pub async fn drain(&self) -> Vec<String> {
+        self.trigger();
+        self.tasks_cancel.cancel();
+        let deadline = self.triggered_at.lock().unwrap_or_else(Instant::now) + self.deadline;
+        let tasks = std::mem::take(&mut *self.tasks.lock());
+        let mut aborted = Vec::new();
+        for (name, mut handle) in tasks {
+            if tokio::time::timeout_at(deadline, &mut handle).await.is_err() {
+                handle.abort();
+                aborted.push(name);
+            }
+        }
+        if !aborted.is_empty() {
+            warn!("Aborted tasks still running after the {}ms shutdown deadline: {}", self.deadline.as_millis(), aborted.join(", "));
+        }
+        aborted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_drain_waits_for_tasks_then_aborts_stragglers() {
+        let shutdown = Shutdown::new(Duration::from_millis(100));
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&finished);
+        shutdown.spawn("cooperative", |cancel| async move {
+            cancel.cancelled().await;
+            flag.store(true, Ordering::Release);
+        });
+        shutdown.spawn("stuck", |_| async move {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        });
+
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        shutdown.triggered().await;
+        let started = Instant::now();
+        assert_eq!(shutdown.drain().await, vec!["stuck".to_string()]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(finished.load(Ordering::Acquire));
+    }
+}
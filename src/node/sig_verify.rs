@@ -0,0 +1,303 @@
+use curve25519_dalek::{edwards::CompressedEdwardsY, scalar::Scalar};
+use ed25519_dalek::{PublicKey, Signature};
+use futures::future::join_all;
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::oneshot;
+
+use super::transaction::Transaction;
+use super::vote::Vote;
+
+pub const DEFAULT_MAX_BATCH: usize = 256;
+
+/// The `[sig_verify]` section: the threads Ed25519 signatures are checked on.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SigVerifyConfig {
+    /// Worker threads; 0 starts one per core.
+    #[serde(default)]
+    pub threads: usize,
+    /// Most signatures one worker checks as a single batch. A failed batch is
+    /// checked again one signature at a time, so smaller batches cost less
+    /// when a block carries a forgery.
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+}
+
+fn default_max_batch() -> usize {
+    DEFAULT_MAX_BATCH
+}
+
+impl Default for SigVerifyConfig {
+    fn default() -> Self {
+        SigVerifyConfig {
+            threads: 0,
+            max_batch: DEFAULT_MAX_BATCH,
+        }
+    }
+}
+
+impl SigVerifyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_batch == 0 {
+            return Err("sig_verify.max_batch must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// One Ed25519 signature to check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedMessage {
+    pub public_key: Vec<u8>,
+    pub message: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedMessage {
+    pub fn transaction(transaction: &Transaction) -> Self {
+        SignedMessage {
+            public_key: transaction.sender.as_ref().to_vec(),
+            message: transaction.signing_bytes(),
+            signature: transaction.signature.as_ref().to_vec(),
+        }
+    }
+
+    /// A vote on `chain_id` signed with the Ed25519 consensus key `public_key`.
+    pub fn vote(vote: &Vote, chain_id: &str, public_key: &[u8]) -> Self {
+        SignedMessage {
+            public_key: public_key.to_vec(),
+            message: Vote::signing_bytes(chain_id, vote.height, vote.round, &vote.block_hash),
+            signature: vote.signature.clone(),
+        }
+    }
+
+    /// Checks this signature alone, as strictly as `Transaction::verify_signature`.
+    pub fn verify(&self) -> bool {
+        match self.parse() {
+            Some((public_key, signature)) => public_key.verify_strict(&self.message, &signature).is_ok(),
+            None => false,
+        }
+    }
+
+    fn parse(&self) -> Option<(PublicKey, Signature)> {
+        let public_key = PublicKey::from_bytes(&self.public_key).ok()?;
+        let signature = Signature::try_from(self.signature.as_slice()).ok()?;
+        Some((public_key, signature))
+    }
+}
+
+/// Checks `items` with one batch verification, which costs about half as
+/// much per signature as checking them one by one, and accepts exactly what
+/// `SignedMessage::verify` does. If the batch fails, each signature is
+/// checked alone so the forgeries are the only ones rejected.
+pub fn verify_batch(items: &[SignedMessage]) -> Vec<bool> {
+    let mut verified = vec![false; items.len()];
+    let mut batch = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        match item.parse() {
+            Some((public_key, signature)) if batchable(&public_key, &signature) => {
+                batch.push((index, public_key, signature));
+            }
+            Some((public_key, signature)) => {
+                verified[index] = public_key.verify_strict(&item.message, &signature).is_ok();
+            }
+            None => {}
+        }
+    }
+    let messages: Vec<&[u8]> = batch.iter().map(|(index, _, _)| items[*index].message.as_slice()).collect();
+    let signatures: Vec<Signature> = batch.iter().map(|(_, _, signature)| *signature).collect();
+    let public_keys: Vec<PublicKey> = batch.iter().map(|(_, public_key, _)| *public_key).collect();
+    let passed = batch.is_empty() || ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_ok();
+
+    for (index, public_key, signature) in batch {
+        verified[index] = passed || public_key.verify_strict(&items[index].message, &signature).is_ok();
+    }
+    verified
+}
+
+/// Whether a batch gives the answer `verify_strict` would for a signature:
+/// its key and R are canonical points with no small-order component, and its
+/// s is reduced. Honest signers only make such signatures. A batch would
+/// accept some others that `verify_strict` rejects, such as those under a
+/// weak key, so they are checked alone.
+fn batchable(public_key: &PublicKey, signature: &Signature) -> bool {
+    let bytes = signature.to_bytes();
+    let (mut r, mut s) = ([0u8; 32], [0u8; 32]);
+    r.copy_from_slice(&bytes[..32]);
+    s.copy_from_slice(&bytes[32..]);
+    Scalar::from_canonical_bytes(s).is_some() && prime_order(public_key.to_bytes()) && prime_order(r)
+}
+
+fn prime_order(bytes: [u8; 32]) -> bool {
+    match CompressedEdwardsY(bytes).decompress() {
+        Some(point) => point.compress().to_bytes() == bytes && point.is_torsion_free() && !point.is_small_order(),
+        None => false,
+    }
+}
+
+struct Job {
+    items: Vec<SignedMessage>,
+    reply: oneshot::Sender<Vec<bool>>,
+}
+
+/// Dedicated threads for signature checks, so a 500-transaction block does
+/// not occupy the runtime's threads while it is verified. Work is split into
+/// batches of at most `max_batch` across the workers, which exit once the
+/// pool is dropped.
+#[derive(Debug)]
+pub struct SigVerifyPool {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    threads: usize,
+    max_batch: usize,
+}
+
+impl SigVerifyPool {
+    pub fn new(config: &SigVerifyConfig) -> Self {
+        let threads = match config.threads {
+            0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            threads => threads,
+        };
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            let spawned = thread::Builder::new()
+                .name(format!("sig-verify-{}", index))
+                .spawn(move || loop {
+                    let job = receiver.lock().recv();
+                    match job {
+                        Ok(job) => {
+                            let _ = job.reply.send(verify_batch(&job.items));
+                        }
+                        Err(_) => break,
+                    }
+                });
+            if let Err(e) = spawned {
+                warn!("Failed to start signature verification worker: {}", e);
+            }
+        }
+        SigVerifyPool {
+            jobs: Mutex::new(sender),
+            threads,
+            max_batch: config.max_batch.max(1),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Whether each signature in `items` is valid, in order.
+    pub async fn verify(&self, items: Vec<SignedMessage>) -> Vec<bool> {
+        if items.is_empty() {
+            return Vec::new();
+        }
+        let chunk_size = ((items.len() + self.threads - 1) / self.threads).clamp(1, self.max_batch);
+        let replies: Vec<_> = items.chunks(chunk_size)
+            .map(|chunk| {
+                let (reply, result) = oneshot::channel();
+                let job = Job { items: chunk.to_vec(), reply };
+                if self.jobs.lock().send(job).is_err() {
+                    warn!("Signature verification pool has no workers");
+                }
+                result
+            })
+            .collect();
+
+        let mut verified = Vec::with_capacity(items.len());
+        for (result, chunk) in join_all(replies).await.into_iter().zip(items.chunks(chunk_size)) {
+            match result {
+                Ok(results) => verified.extend(results),
+                Err(_) => {
+                    warn!("Signature verification worker failed");
+                    verified.extend(std::iter::repeat(false).take(chunk.len()));
+                }
+            }
+        }
+        verified
+    }
+}
+
+/// Verifies on `pool`, or on a blocking thread of the runtime without one.
+pub async fn verify_with(pool: Option<&SigVerifyPool>, items: Vec<SignedMessage>) -> Vec<bool> {
+    if let Some(pool) = pool {
+        return pool.verify(items).await;
+    }
+    let count = items.len();
+    if count == 0 {
+        return Vec::new();
+    }
+    match tokio::task::spawn_blocking(move || verify_batch(&items)).await {
+        Ok(verified) => verified,
+        Err(e) => {
+            warn!("Signature verification worker failed: {}", e);
+            vec![false; count]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::signature::Keypair;
+
+    fn signed(count: usize) -> Vec<SignedMessage> {
+        (0..count)
+            .map(|nonce| Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), 1, 10, nonce as u64, 0))
+            .map(|transaction| SignedMessage::transaction(&transaction))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_one_bad_signature_in_a_batch_is_pinpointed() {
+        let mut items = signed(100);
+        items[37].message[0] ^= 1;
+        let expected: Vec<bool> = (0..items.len()).map(|i| i != 37).collect();
+
+        assert_eq!(verify_batch(&items), expected);
+        let pool = SigVerifyPool::new(&SigVerifyConfig { threads: 3, max_batch: 16 });
+        assert_eq!(pool.verify(items.clone()).await, expected);
+        assert_eq!(verify_with(None, items).await, expected);
+        assert!(pool.verify(Vec::new()).await.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_keys_and_signatures_fail_alone() {
+        let mut items = signed(4);
+        items[1].public_key.truncate(31);
+        items[2].signature = vec![0; 12];
+        assert_eq!(verify_batch(&items), vec![true, false, false, true]);
+        assert!(items[0].verify());
+        assert!(!items[2].verify());
+    }
+
+    #[tokio::test]
+    async fn test_batches_reject_what_strict_checks_reject() {
+        // The identity is a weak key: with R the identity too and s zero, the
+        // batch equation holds for any message, but `verify_strict` refuses.
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        // p + 1, a non-canonical encoding of the identity.
+        let mut non_canonical = [0xff; 32];
+        non_canonical[0] = 0xee;
+        non_canonical[31] = 0x7f;
+        let mut items = signed(3);
+        for r in [identity, non_canonical] {
+            items.push(SignedMessage {
+                public_key: identity.to_vec(),
+                message: b"anything".to_vec(),
+                signature: [r, [0; 32]].concat(),
+            });
+        }
+        assert!(!items[3].verify() && !items[4].verify());
+
+        let expected = vec![true, true, true, false, false];
+        assert_eq!(verify_batch(&items), expected);
+        assert_eq!(verify_with(None, items).await, expected);
+    }
+}
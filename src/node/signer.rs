@@ -0,0 +1,881 @@
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+
+use super::control::{ConsensusControl, ControlError, HaltReason};
+use super::crypto::{Ed25519Scheme, SignatureScheme};
+use super::proposal::Proposal;
+use super::reconnect::BackoffConfig;
+use super::vote::Vote;
+
+/// Version 2 added proposals.
+pub const SIGNER_PROTOCOL_VERSION: u32 = 2;
+pub const DEFAULT_SIGNER_SECRET_ENV: &str = "DADBS_SIGNER_SECRET";
+pub const DEFAULT_SIGNER_TIMEOUT_MS: u64 = 2_000;
+pub const DEFAULT_SIGNER_PING_INTERVAL_MS: u64 = 5_000;
+/// The node's copy of its voting record, inside `storage_path`.
+pub const SIGN_GUARD_FILE: &str = "sign_guard.json";
+const MAX_SIGNER_FRAME_BYTES: usize = 64 * 1024;
+const AUTH_CONTEXT: &[u8] = b"dadbs-signer-auth-v1";
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Control(#[from] ControlError),
+    #[error("Remote signer unreachable: {0}")]
+    Unreachable(String),
+    #[error("Refusing to sign {requested} at height {height} round {round}: {signed} was signed there")]
+    DoubleSign { height: u64, round: u32, signed: Hash, requested: Hash },
+    #[error("Refusing to sign height {height} round {round}: height {last_height} round {last_round} was signed")]
+    Regression { height: u64, round: u32, last_height: u64, last_round: u32 },
+    #[error("Remote signer refused: {0}")]
+    Refused(String),
+    #[error("Signer protocol error: {0}")]
+    Protocol(String),
+    #[error("Signer authentication failed")]
+    Unauthenticated,
+    #[error("Remote signer holds {actual}, expected {expected}")]
+    WrongKey { expected: Pubkey, actual: Pubkey },
+    #[error("Invalid remote signer configuration: {0}")]
+    Config(String),
+}
+
+/// Signs this node's votes and proposals, with a key held here or elsewhere.
+#[async_trait]
+pub trait VoteSigner: Send + Sync {
+    fn pubkey(&self) -> Pubkey;
+
+    async fn sign_vote(&self, chain_id: &str, height: u64, round: u32, block_hash: Hash) -> Result<Vote, SignerError>;
+
+    /// Signs the block `block_hash` as this key's proposal for `round` at
+    /// `height`, returning the signature. Proposing a block again, in a
+    /// later round, is no conflict, so no guard is consulted.
+    async fn sign_proposal(
+        &self,
+        chain_id: &str,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<Vec<u8>, SignerError>;
+}
+
+#[async_trait]
+impl VoteSigner for Keypair {
+    fn pubkey(&self) -> Pubkey {
+        Signer::pubkey(self)
+    }
+
+    async fn sign_vote(&self, chain_id: &str, height: u64, round: u32, block_hash: Hash) -> Result<Vote, SignerError> {
+        Ok(Vote::for_chain(self, chain_id, height, round, block_hash))
+    }
+
+    async fn sign_proposal(
+        &self,
+        chain_id: &str,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<Vec<u8>, SignerError> {
+        Ok(self.sign_message(&Proposal::signing_bytes(chain_id, height, round, &block_hash)).as_ref().to_vec())
+    }
+}
+
+/// Signs with a `validator_key` held by the node itself, behind the same
+/// kind of record a `dadbs-signer` keeps.
+pub struct LocalSigner {
+    keypair: Keypair,
+    guard: Mutex<SignGuard>,
+}
+
+impl LocalSigner {
+    pub fn new(keypair: Keypair, guard: SignGuard) -> Self {
+        LocalSigner { keypair, guard: Mutex::new(guard) }
+    }
+
+    /// Reads the keypair file `dadbs-node keygen` wrote at `key_path`.
+    pub fn open(key_path: &Path, guard_path: &Path) -> Result<Self, SignerError> {
+        let keypair = solana_sdk::signature::read_keypair_file(key_path)
+            .map_err(|e| SignerError::Config(format!("cannot read validator key {}: {}", key_path.display(), e)))?;
+        Ok(Self::new(keypair, SignGuard::open(guard_path)?))
+    }
+}
+
+#[async_trait]
+impl VoteSigner for LocalSigner {
+    fn pubkey(&self) -> Pubkey {
+        self.keypair.pubkey()
+    }
+
+    async fn sign_vote(&self, chain_id: &str, height: u64, round: u32, block_hash: Hash) -> Result<Vote, SignerError> {
+        self.guard.lock().check_and_record(chain_id, height, round, block_hash)?;
+        Ok(Vote::for_chain(&self.keypair, chain_id, height, round, block_hash))
+    }
+
+    async fn sign_proposal(
+        &self,
+        chain_id: &str,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<Vec<u8>, SignerError> {
+        self.keypair.sign_proposal(chain_id, height, round, block_hash).await
+    }
+}
+
+/// What to do about a vote the remote signer cannot be reached for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnreachablePolicy {
+    /// Halt consensus until an operator resumes it.
+    #[default]
+    Halt,
+    /// Leave the round unsigned and try again on the next one.
+    Skip,
+}
+
+/// The `[remote_signer]` section: votes are signed by a `dadbs-signer`
+/// process holding the validator key, not on this node. The shared secret
+/// authenticating both ends is read from `secret_file`, or else the
+/// `secret_env` environment variable.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RemoteSignerConfig {
+    /// `unix:<path>` or `tcp:<host>:<port>`.
+    pub endpoint: String,
+    /// The validator key the signer must hold.
+    pub pubkey: String,
+    #[serde(default)]
+    pub secret_file: Option<String>,
+    #[serde(default = "default_secret_env")]
+    pub secret_env: String,
+    /// Longest wait for one signature, connecting included.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How often an idle connection is checked, and a lost one redialed.
+    #[serde(default = "default_ping_interval_ms")]
+    pub ping_interval_ms: u64,
+    #[serde(default)]
+    pub on_unreachable: UnreachablePolicy,
+}
+
+fn default_secret_env() -> String {
+    DEFAULT_SIGNER_SECRET_ENV.to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_SIGNER_TIMEOUT_MS
+}
+
+fn default_ping_interval_ms() -> u64 {
+    DEFAULT_SIGNER_PING_INTERVAL_MS
+}
+
+impl RemoteSignerConfig {
+    pub fn new(endpoint: impl Into<String>, pubkey: Pubkey) -> Self {
+        RemoteSignerConfig {
+            endpoint: endpoint.into(),
+            pubkey: pubkey.to_string(),
+            secret_file: None,
+            secret_env: default_secret_env(),
+            timeout_ms: DEFAULT_SIGNER_TIMEOUT_MS,
+            ping_interval_ms: DEFAULT_SIGNER_PING_INTERVAL_MS,
+            on_unreachable: UnreachablePolicy::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        SignerEndpoint::from_str(&self.endpoint)?;
+        Pubkey::from_str(&self.pubkey)
+            .map_err(|_| format!("remote_signer.pubkey is not a valid pubkey: {}", self.pubkey))?;
+        if self.timeout_ms == 0 || self.ping_interval_ms == 0 {
+            return Err("remote_signer.timeout_ms and ping_interval_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// The configured secret, or `None` when neither source has one.
+    pub fn secret(&self) -> io::Result<Option<Vec<u8>>> {
+        let secret = match &self.secret_file {
+            Some(path) => Some(fs::read_to_string(path)?),
+            None => std::env::var(&self.secret_env).ok(),
+        };
+        Ok(secret.map(|secret| secret.trim().as_bytes().to_vec()).filter(|secret| !secret.is_empty()))
+    }
+}
+
+/// Where a signer listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignerEndpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for SignerEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(SignerEndpoint::Unix(PathBuf::from(path))),
+            Some(("tcp", addr)) if addr.contains(':') => Ok(SignerEndpoint::Tcp(addr.to_string())),
+            _ => Err(format!("signer endpoint {} is neither unix:<path> nor tcp:<host>:<port>", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for SignerEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignerEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            SignerEndpoint::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+pub trait SignerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SignerStream for T {}
+
+/// Opens connections to a signer.
+#[async_trait]
+pub trait SignerConnector: Send + Sync {
+    async fn connect(&self) -> io::Result<Box<dyn SignerStream>>;
+}
+
+#[async_trait]
+impl SignerConnector for SignerEndpoint {
+    async fn connect(&self) -> io::Result<Box<dyn SignerStream>> {
+        Ok(match self {
+            SignerEndpoint::Unix(path) => Box::new(UnixStream::connect(path).await?),
+            SignerEndpoint::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+        })
+    }
+}
+
+/// The last vote signed on each chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignRecord {
+    pub height: u64,
+    pub round: u32,
+    pub block_hash: Hash,
+}
+
+/// Refuses to sign anything that could conflict with a vote already
+/// signed: another block at the same height and round, or an earlier
+/// height or round. Signing the same vote again is allowed, so a request
+/// can be retried. The record is persisted before a signature is given out.
+#[derive(Debug, Default)]
+pub struct SignGuard {
+    path: Option<PathBuf>,
+    records: BTreeMap<String, SignRecord>,
+}
+
+impl SignGuard {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, SignerError> {
+        let path = path.into();
+        let records = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SignGuard { path: Some(path), records })
+    }
+
+    pub fn last(&self, chain_id: &str) -> Option<SignRecord> {
+        self.records.get(chain_id).copied()
+    }
+
+    /// Checks a vote against the record and records it.
+    pub fn check_and_record(
+        &mut self,
+        chain_id: &str,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<(), SignerError> {
+        if let Some(last) = self.last(chain_id) {
+            if (height, round) == (last.height, last.round) {
+                if block_hash != last.block_hash {
+                    let signed = last.block_hash;
+                    return Err(SignerError::DoubleSign { height, round, signed, requested: block_hash });
+                }
+                return Ok(());
+            }
+            if (height, round) < (last.height, last.round) {
+                return Err(SignerError::Regression { height, round, last_height: last.height, last_round: last.round });
+            }
+        }
+        self.records.insert(chain_id.to_string(), SignRecord { height, round, block_hash });
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<(), SignerError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.records)?)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SignerMessage {
+    /// Sent by the signer on accepting a connection.
+    Hello { version: u32, pubkey: String, challenge: String },
+    /// The node's answer: its own challenge, and a MAC over both.
+    Auth { challenge: String, mac: String },
+    /// The signer's MAC over both challenges the other way round.
+    Welcome { mac: String },
+    SignVote { id: u64, chain_id: String, height: u64, round: u32, block_hash: Hash },
+    SignProposal { id: u64, chain_id: String, height: u64, round: u32, block_hash: Hash },
+    Signed { id: u64, signature: String },
+    Refused { id: u64, reason: String },
+    Ping { id: u64 },
+    Pong { id: u64 },
+}
+
+/// A frame on the wire: a message, MAC'd with the session key and a
+/// per-direction sequence number once both ends are authenticated.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    body: String,
+    mac: Option<String>,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn verify_mac(key: &[u8], parts: &[&[u8]], expected: &str) -> bool {
+    let Ok(expected) = hex::decode(expected) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.verify_slice(&expected).is_ok()
+}
+
+fn challenge() -> [u8; 32] {
+    let mut challenge = [0; 32];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+fn decode_challenge(challenge: &str) -> Result<Vec<u8>, SignerError> {
+    hex::decode(challenge)
+        .ok()
+        .filter(|c| c.len() == 32)
+        .ok_or_else(|| SignerError::Protocol("malformed challenge".to_string()))
+}
+
+/// One authenticated connection, from either end.
+struct Channel {
+    stream: Box<dyn SignerStream>,
+    session: Option<Vec<u8>>,
+    /// `b"node"` or `b"signer"`: whose frames this end sends.
+    role: &'static [u8],
+    sent: u64,
+    received: u64,
+}
+
+impl Channel {
+    fn new(stream: Box<dyn SignerStream>, role: &'static [u8]) -> Self {
+        Channel { stream, session: None, role, sent: 0, received: 0 }
+    }
+
+    fn peer_role(&self) -> &'static [u8] {
+        if self.role == b"node" {
+            b"signer"
+        } else {
+            b"node"
+        }
+    }
+
+    async fn send(&mut self, message: &SignerMessage) -> Result<(), SignerError> {
+        let body = serde_json::to_string(message)?;
+        let mac = self.session.as_ref()
+            .map(|key| hex::encode(mac(key, &[self.role, &self.sent.to_le_bytes(), body.as_bytes()])));
+        self.sent += 1;
+        let frame = serde_json::to_vec(&Envelope { body, mac })?;
+        self.stream.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<SignerMessage, SignerError> {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_SIGNER_FRAME_BYTES {
+            return Err(SignerError::Protocol(format!("frame of {} bytes", len)));
+        }
+        let mut frame = vec![0; len];
+        self.stream.read_exact(&mut frame).await?;
+        let envelope: Envelope = serde_json::from_slice(&frame)?;
+        if let Some(key) = &self.session {
+            let parts: [&[u8]; 3] = [self.peer_role(), &self.received.to_le_bytes(), envelope.body.as_bytes()];
+            if !envelope.mac.as_deref().is_some_and(|expected| verify_mac(key, &parts, expected)) {
+                return Err(SignerError::Unauthenticated);
+            }
+        }
+        self.received += 1;
+        Ok(serde_json::from_str(&envelope.body)?)
+    }
+
+    fn start_session(&mut self, secret: &[u8], signer_challenge: &[u8], node_challenge: &[u8]) {
+        self.session = Some(mac(secret, &[AUTH_CONTEXT, b"session", signer_challenge, node_challenge]));
+    }
+
+    /// The node's side of the handshake, checking the signer holds `expected`.
+    async fn connect(stream: Box<dyn SignerStream>, secret: &[u8], expected: Pubkey) -> Result<Self, SignerError> {
+        let mut channel = Channel::new(stream, b"node");
+        let (version, pubkey, signer_challenge) = match channel.recv().await? {
+            SignerMessage::Hello { version, pubkey, challenge } => (version, pubkey, decode_challenge(&challenge)?),
+            other => return Err(SignerError::Protocol(format!("expected hello, got {:?}", other))),
+        };
+        if version != SIGNER_PROTOCOL_VERSION {
+            return Err(SignerError::Protocol(format!("signer speaks version {}", version)));
+        }
+        let actual = Pubkey::from_str(&pubkey).map_err(|_| SignerError::Protocol("malformed pubkey".to_string()))?;
+        if actual != expected {
+            return Err(SignerError::WrongKey { expected, actual });
+        }
+        let node_challenge = challenge();
+        let proof = mac(secret, &[AUTH_CONTEXT, b"node", &signer_challenge, &node_challenge]);
+        channel.send(&SignerMessage::Auth { challenge: hex::encode(node_challenge), mac: hex::encode(proof) }).await?;
+        match channel.recv().await? {
+            SignerMessage::Welcome { mac } => {
+                if !verify_mac(secret, &[AUTH_CONTEXT, b"signer", &node_challenge, &signer_challenge], &mac) {
+                    return Err(SignerError::Unauthenticated);
+                }
+            }
+            other => return Err(SignerError::Protocol(format!("expected welcome, got {:?}", other))),
+        }
+        channel.start_session(secret, &signer_challenge, &node_challenge);
+        Ok(channel)
+    }
+}
+
+/// Signs votes and proposals for nodes that connect with the shared
+/// secret, keeping its own `SignGuard` so no node, however confused, gets
+/// conflicting votes out of it.
+pub struct SignerService {
+    signer: Arc<dyn VoteSigner>,
+    guard: Mutex<SignGuard>,
+    secret: Vec<u8>,
+}
+
+impl SignerService {
+    pub fn new(signer: Arc<dyn VoteSigner>, guard: SignGuard, secret: impl Into<Vec<u8>>) -> Self {
+        SignerService { signer, guard: Mutex::new(guard), secret: secret.into() }
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        self.signer.pubkey()
+    }
+
+    /// Serves one connection until the node hangs up.
+    pub async fn serve(&self, stream: Box<dyn SignerStream>) -> Result<(), SignerError> {
+        let mut channel = Channel::new(stream, b"signer");
+        let signer_challenge = challenge();
+        channel.send(&SignerMessage::Hello {
+            version: SIGNER_PROTOCOL_VERSION,
+            pubkey: self.pubkey().to_string(),
+            challenge: hex::encode(signer_challenge),
+        }).await?;
+        let node_challenge = match channel.recv().await? {
+            SignerMessage::Auth { challenge, mac } => {
+                let node_challenge = decode_challenge(&challenge)?;
+                if !verify_mac(&self.secret, &[AUTH_CONTEXT, b"node", &signer_challenge, &node_challenge], &mac) {
+                    return Err(SignerError::Unauthenticated);
+                }
+                node_challenge
+            }
+            other => return Err(SignerError::Protocol(format!("expected auth, got {:?}", other))),
+        };
+        let proof = mac(&self.secret, &[AUTH_CONTEXT, b"signer", &node_challenge, &signer_challenge]);
+        channel.send(&SignerMessage::Welcome { mac: hex::encode(proof) }).await?;
+        channel.start_session(&self.secret, &signer_challenge, &node_challenge);
+
+        loop {
+            let message = match channel.recv().await {
+                Ok(message) => message,
+                Err(SignerError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let reply = match message {
+                SignerMessage::SignVote { id, chain_id, height, round, block_hash } => {
+                    match self.sign(&chain_id, height, round, block_hash).await {
+                        Ok(vote) => SignerMessage::Signed { id, signature: hex::encode(vote.signature) },
+                        Err(e) => {
+                            warn!("Refused to sign height {} round {} for {}: {}", height, round, chain_id, e);
+                            SignerMessage::Refused { id, reason: e.to_string() }
+                        }
+                    }
+                }
+                SignerMessage::SignProposal { id, chain_id, height, round, block_hash } => {
+                    match self.signer.sign_proposal(&chain_id, height, round, block_hash).await {
+                        Ok(signature) => SignerMessage::Signed { id, signature: hex::encode(signature) },
+                        Err(e) => SignerMessage::Refused { id, reason: e.to_string() },
+                    }
+                }
+                SignerMessage::Ping { id } => SignerMessage::Pong { id },
+                other => return Err(SignerError::Protocol(format!("unexpected {:?}", other))),
+            };
+            channel.send(&reply).await?;
+        }
+    }
+
+    async fn sign(&self, chain_id: &str, height: u64, round: u32, block_hash: Hash) -> Result<Vote, SignerError> {
+        self.guard.lock().check_and_record(chain_id, height, round, block_hash)?;
+        self.signer.sign_vote(chain_id, height, round, block_hash).await
+    }
+
+    /// Accepts connections on `endpoint` until `cancel` fires, serving each
+    /// on its own task.
+    pub async fn listen(self: Arc<Self>, endpoint: &SignerEndpoint, cancel: CancellationToken) -> io::Result<()> {
+        enum Listener {
+            Unix(UnixListener),
+            Tcp(TcpListener),
+        }
+        let listener = match endpoint {
+            SignerEndpoint::Unix(path) => {
+                // Left behind by a signer that did not shut down cleanly.
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                Listener::Unix(UnixListener::bind(path)?)
+            }
+            SignerEndpoint::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await?),
+        };
+        info!("Signing for {} on {}", self.pubkey(), endpoint);
+        loop {
+            let accepted: io::Result<Box<dyn SignerStream>> = tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                accepted = async {
+                    io::Result::Ok(match &listener {
+                        Listener::Unix(listener) => Box::new(listener.accept().await?.0) as Box<dyn SignerStream>,
+                        Listener::Tcp(listener) => Box::new(listener.accept().await?.0),
+                    })
+                } => accepted,
+            };
+            match accepted {
+                Ok(stream) => {
+                    let service = Arc::clone(&self);
+                    tokio::spawn(async move {
+                        if let Err(e) = service.serve(stream).await {
+                            warn!("Signer connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept a signer connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Signs votes and proposals through a `SignerService` in another process, typically
+/// `dadbs-signer`. A local `SignGuard` refuses conflicting votes before the
+/// signer is asked, and every signature is checked against the expected
+/// key. A connection lost mid-round is redialed and the request repeated;
+/// should the signer stay unreachable, `on_unreachable` decides whether
+/// consensus halts or the round goes unsigned.
+pub struct RemoteSigner {
+    pubkey: Pubkey,
+    connector: Arc<dyn SignerConnector>,
+    secret: Vec<u8>,
+    timeout: Duration,
+    ping_interval: Duration,
+    on_unreachable: UnreachablePolicy,
+    control: Option<ConsensusControl>,
+    guard: Mutex<SignGuard>,
+    connection: AsyncMutex<Option<Channel>>,
+    next_id: AtomicU64,
+}
+
+impl RemoteSigner {
+    pub fn new(
+        pubkey: Pubkey,
+        connector: Arc<dyn SignerConnector>,
+        secret: impl Into<Vec<u8>>,
+        guard: SignGuard,
+    ) -> Self {
+        RemoteSigner {
+            pubkey,
+            connector,
+            secret: secret.into(),
+            timeout: Duration::from_millis(DEFAULT_SIGNER_TIMEOUT_MS),
+            ping_interval: Duration::from_millis(DEFAULT_SIGNER_PING_INTERVAL_MS),
+            on_unreachable: UnreachablePolicy::default(),
+            control: None,
+            guard: Mutex::new(guard),
+            connection: AsyncMutex::new(None),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// From `[remote_signer]`, guarded by the record at `guard_path`.
+    pub fn from_config(config: &RemoteSignerConfig, guard_path: &Path) -> Result<Self, SignerError> {
+        config.validate().map_err(SignerError::Config)?;
+        let endpoint = SignerEndpoint::from_str(&config.endpoint).map_err(SignerError::Config)?;
+        let pubkey = Pubkey::from_str(&config.pubkey).map_err(|e| SignerError::Config(e.to_string()))?;
+        let secret = config.secret()?.ok_or_else(|| {
+            SignerError::Config(format!("no secret: set remote_signer.secret_file or {}", config.secret_env))
+        })?;
+        Ok(Self::new(pubkey, Arc::new(endpoint), secret, SignGuard::open(guard_path)?)
+            .with_timeout(Duration::from_millis(config.timeout_ms))
+            .with_ping_interval(Duration::from_millis(config.ping_interval_ms))
+            .with_unreachable_policy(config.on_unreachable))
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    pub fn with_unreachable_policy(mut self, on_unreachable: UnreachablePolicy) -> Self {
+        self.on_unreachable = on_unreachable;
+        self
+    }
+
+    /// Halted under `UnreachablePolicy::Halt`.
+    pub fn with_control(mut self, control: ConsensusControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    pub async fn is_connected(&self) -> bool {
+        self.connection.lock().await.is_some()
+    }
+
+    /// Sends `request` and waits for its reply, dialing if there is no
+    /// connection. A connection that fails is dropped and, if it was an
+    /// old one, dialed once more, as when the signer restarted.
+    async fn request(&self, request: SignerMessage) -> Result<SignerMessage, SignerError> {
+        let mut connection = self.connection.lock().await;
+        for fresh in [connection.is_none(), true] {
+            if connection.is_none() {
+                let stream = self.connector.connect().await?;
+                *connection = Some(Channel::connect(stream, &self.secret, self.pubkey).await?);
+            }
+            let channel = connection.as_mut().expect("connected above");
+            let reply = match channel.send(&request).await {
+                Ok(()) => channel.recv().await,
+                Err(e) => Err(e),
+            };
+            match reply {
+                Ok(reply) => return Ok(reply),
+                Err(e @ (SignerError::Io(_) | SignerError::Unauthenticated)) => {
+                    *connection = None;
+                    if fresh {
+                        return Err(e);
+                    }
+                }
+                Err(e) => {
+                    *connection = None;
+                    return Err(e);
+                }
+            }
+        }
+        unreachable!("the second attempt always uses a fresh connection")
+    }
+
+    fn unreachable(&self, reason: String) -> SignerError {
+        match (&self.on_unreachable, &self.control) {
+            (UnreachablePolicy::Halt, Some(control)) => control.halt(HaltReason::SignerUnreachable(reason.clone())),
+            _ => warn!("Not signing, remote signer unreachable: {}", reason),
+        }
+        SignerError::Unreachable(reason)
+    }
+
+    /// Sends a request with `id` for a signature and waits for it.
+    async fn signature(&self, id: u64, request: SignerMessage) -> Result<Vec<u8>, SignerError> {
+        let reply = match tokio::time::timeout(self.timeout, self.request(request)).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(
+                e @ (SignerError::Io(_)
+                | SignerError::Unauthenticated
+                | SignerError::Protocol(_)
+                | SignerError::WrongKey { .. }),
+            )) => return Err(self.unreachable(e.to_string())),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                // The reply may still arrive on this connection; start afresh.
+                *self.connection.lock().await = None;
+                return Err(self.unreachable(format!("no signature within {:?}", self.timeout)));
+            }
+        };
+        match reply {
+            SignerMessage::Signed { id: signed, signature } if signed == id => {
+                hex::decode(signature).map_err(|e| SignerError::Protocol(e.to_string()))
+            }
+            SignerMessage::Refused { id: refused, reason } if refused == id => Err(SignerError::Refused(reason)),
+            other => {
+                *self.connection.lock().await = None;
+                Err(SignerError::Protocol(format!("unexpected reply {:?}", other)))
+            }
+        }
+    }
+
+    /// Keeps the connection up: pings it every `ping_interval` and redials a
+    /// lost one with backoff, until `cancel` fires.
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let backoff = BackoffConfig::new(self.ping_interval, self.ping_interval.saturating_mul(12));
+        let mut failures = 0;
+        loop {
+            let delay = match failures {
+                0 => self.ping_interval,
+                attempt => backoff.delay(attempt, rand::random()),
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(delay) => {}
+            }
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            match tokio::time::timeout(self.timeout, self.request(SignerMessage::Ping { id })).await {
+                Ok(Ok(SignerMessage::Pong { id: pong })) if pong == id => {
+                    if failures > 0 {
+                        info!("Remote signer for {} reachable again", self.pubkey);
+                    }
+                    failures = 0;
+                }
+                Ok(Ok(other)) => {
+                    warn!("Remote signer answered a ping with {:?}", other);
+                    *self.connection.lock().await = None;
+                    failures += 1;
+                }
+                Ok(Err(e)) => {
+                    warn!("Remote signer for {} unreachable: {}", self.pubkey, e);
+                    failures += 1;
+                }
+                Err(_) => {
+                    warn!("Remote signer for {} did not answer a ping within {:?}", self.pubkey, self.timeout);
+                    *self.connection.lock().await = None;
+                    failures += 1;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl VoteSigner for RemoteSigner {
+    fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    async fn sign_vote(&self, chain_id: &str, height: u64, round: u32, block_hash: Hash) -> Result<Vote, SignerError> {
+        self.guard.lock().check_and_record(chain_id, height, round, block_hash)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = SignerMessage::SignVote { id, chain_id: chain_id.to_string(), height, round, block_hash };
+        let signature = self.signature(id, request).await?;
+        let vote = Vote { validator: self.pubkey, height, round, block_hash, signature };
+        if !vote.verify_signature(chain_id) {
+            return Err(SignerError::Protocol("the signer returned an invalid signature".to_string()));
+        }
+        Ok(vote)
+    }
+
+    async fn sign_proposal(
+        &self,
+        chain_id: &str,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<Vec<u8>, SignerError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = SignerMessage::SignProposal { id, chain_id: chain_id.to_string(), height, round, block_hash };
+        let signature = self.signature(id, request).await?;
+        let bytes = Proposal::signing_bytes(chain_id, height, round, &block_hash);
+        if !Ed25519Scheme.verify(self.pubkey.as_ref(), &bytes, &signature) {
+            return Err(SignerError::Protocol("the signer returned an invalid signature".to_string()));
+        }
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_refuses_conflicts_and_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SIGN_GUARD_FILE);
+        let (a, b) = (Hash::new_unique(), Hash::new_unique());
+        let mut guard = SignGuard::open(&path).unwrap();
+        guard.check_and_record("chain", 5, 1, a).unwrap();
+        guard.check_and_record("chain", 5, 1, a).unwrap();
+        guard.check_and_record("other-chain", 1, 0, b).unwrap();
+
+        let mut guard = SignGuard::open(&path).unwrap();
+        let conflict = guard.check_and_record("chain", 5, 1, b);
+        assert!(matches!(conflict, Err(SignerError::DoubleSign { height: 5, round: 1, .. })));
+        let earlier_round = guard.check_and_record("chain", 5, 0, a);
+        assert!(matches!(earlier_round, Err(SignerError::Regression { last_round: 1, .. })));
+        let earlier_height = guard.check_and_record("chain", 4, 3, a);
+        assert!(matches!(earlier_height, Err(SignerError::Regression { last_height: 5, .. })));
+        guard.check_and_record("chain", 5, 2, b).unwrap();
+        assert_eq!(guard.last("chain"), Some(SignRecord { height: 5, round: 2, block_hash: b }));
+    }
+
+    #[test]
+    fn test_endpoints_parse() {
+        assert_eq!("unix:/run/signer.sock".parse(), Ok(SignerEndpoint::Unix(PathBuf::from("/run/signer.sock"))));
+        assert_eq!("tcp:10.0.0.2:7400".parse(), Ok(SignerEndpoint::Tcp("10.0.0.2:7400".to_string())));
+        assert!("10.0.0.2:7400".parse::<SignerEndpoint>().is_err());
+        assert!("tcp:10.0.0.2".parse::<SignerEndpoint>().is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use solana_sdk::hash::Hash;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::block::{Block, BlockHeader};
+use super::compression::{Codec, CompressionError};
+use super::config::{ConfigError, NodeConfig};
+use super::quorum::QuorumPolicy;
+use super::state::{State, StateError};
+use super::storage::StorageError;
+use super::validator::{ValidatorInfo, ValidatorSet};
+use super::vote::{CertificateError, CommitCertificate};
+
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+/// Largest archive we will decompress.
+pub const MAX_SNAPSHOT_BYTES: usize = 1 << 30;
+const SNAPSHOT_MAGIC: [u8; 8] = *b"DADBSNAP";
+
+const STATE_SECTION: &str = "state";
+const HEADER_SECTION: &str = "header";
+const CERTIFICATE_SECTION: &str = "certificate";
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+    #[error("Compression error: {0}")]
+    Compression(#[from] CompressionError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Invalid snapshot: {0}")]
+    Invalid(String),
+    #[error("Snapshot section {0} does not match its manifest checksum")]
+    ChecksumMismatch(String),
+    #[error("Snapshot is for chain {actual}, expected {expected}")]
+    ChainMismatch { expected: String, actual: String },
+    #[error("Snapshot certificate rejected: {0}")]
+    Certificate(#[from] CertificateError),
+    #[error("No finalized block at height {0} to snapshot")]
+    NotFinalized(u64),
+    #[error("Snapshot at height {snapshot} is not ahead of local height {local}")]
+    Stale { snapshot: u64, local: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SectionDigest {
+    pub name: String,
+    /// Hex-encoded SHA-256 of the section.
+    pub sha256: String,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub chain_id: String,
+    pub height: u64,
+    pub block_hash: Hash,
+    pub state_root: Hash,
+    pub sections: Vec<SectionDigest>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct Section {
+    name: String,
+    data: Vec<u8>,
+}
+
+/// On-disk layout, zstd-compressed as a whole. The manifest is JSON so it
+/// can be inspected after decompressing.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct Archive {
+    magic: [u8; 8],
+    manifest: Vec<u8>,
+    sections: Vec<Section>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// What an imported snapshot has to prove: the chain it belongs to, and a
+/// validator set whose quorum must have signed its certificate.
+#[derive(Clone)]
+pub struct SnapshotTrust {
+    pub chain_id: String,
+    pub validators: ValidatorSet,
+    pub quorum: Arc<dyn QuorumPolicy>,
+}
+
+impl SnapshotTrust {
+    pub fn new(chain_id: impl Into<String>, validators: ValidatorSet, quorum: Arc<dyn QuorumPolicy>) -> Self {
+        SnapshotTrust { chain_id: chain_id.into(), validators, quorum }
+    }
+
+    /// Trust for the configured `[snapshot]`, or `None` without one.
+    pub fn from_config(config: &NodeConfig) -> Result<Option<(PathBuf, Self)>, ConfigError> {
+        let snapshot = match &config.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        let validators = if snapshot.trusted_validators.is_empty() {
+            config.validators.clone()
+        } else {
+            snapshot.trusted_validators.clone()
+        };
+        let validators = ValidatorSet::try_new(validators)
+            .map_err(|e| ConfigError::InvalidConsensusParameter(e.to_string()))?;
+        let quorum = config.quorum.build()
+            .map_err(|e| ConfigError::InvalidConsensusParameter(e.to_string()))?;
+        Ok(Some((PathBuf::from(&snapshot.path), Self::new(config.chain_id.clone(), validators, quorum))))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotConfig {
+    /// Archive to bootstrap from instead of syncing from genesis.
+    pub path: String,
+    /// Validators whose certificate the snapshot must carry. Defaults to the
+    /// genesis `validators`.
+    #[serde(default)]
+    pub trusted_validators: Vec<ValidatorInfo>,
+}
+
+/// A snapshot whose manifest, checksums, certificate and state root have all
+/// been verified.
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    pub header: BlockHeader,
+    pub certificate: CommitCertificate,
+    pub state: State,
+}
+
+impl Snapshot {
+    /// Writes a snapshot of `state` at the finalized block `header`.
+    pub(crate) fn write(
+        path: &Path,
+        chain_id: &str,
+        header: BlockHeader,
+        certificate: &CommitCertificate,
+        state: &State,
+    ) -> Result<SnapshotManifest, SnapshotError> {
+        let sections = vec![
+            Section { name: STATE_SECTION.to_string(), data: state.export()? },
+            Section { name: HEADER_SECTION.to_string(), data: header.try_to_vec()? },
+            Section { name: CERTIFICATE_SECTION.to_string(), data: certificate.try_to_vec()? },
+        ];
+        let manifest = SnapshotManifest {
+            version: SNAPSHOT_FORMAT_VERSION,
+            chain_id: chain_id.to_string(),
+            height: header.height,
+            block_hash: Block { header, transactions: Vec::new() }.hash(),
+            state_root: state.root(),
+            sections: sections.iter()
+                .map(|section| SectionDigest {
+                    name: section.name.clone(),
+                    sha256: sha256_hex(&section.data),
+                    len: section.data.len() as u64,
+                })
+                .collect(),
+        };
+        let manifest_bytes = serde_json::to_vec(&manifest)?;
+        let archive = Archive { magic: SNAPSHOT_MAGIC, manifest: manifest_bytes, sections };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, Codec::Zstd.compress(&archive.try_to_vec()?))?;
+        fs::rename(&tmp, path)?;
+        Ok(manifest)
+    }
+
+    /// Reads and verifies the snapshot at `path` without installing it.
+    pub fn read(path: &Path, trust: &SnapshotTrust) -> Result<Self, SnapshotError> {
+        let invalid = |reason: &str| SnapshotError::Invalid(reason.to_string());
+        let bytes = Codec::Zstd.decompress(&fs::read(path)?, MAX_SNAPSHOT_BYTES)?;
+        let archive = Archive::try_from_slice(&bytes).map_err(|_| invalid("malformed archive"))?;
+        if archive.magic != SNAPSHOT_MAGIC {
+            return Err(invalid("not a snapshot archive"));
+        }
+        let manifest: SnapshotManifest = serde_json::from_slice(&archive.manifest)
+            .map_err(|e| SnapshotError::Invalid(format!("malformed manifest: {}", e)))?;
+        if manifest.version != SNAPSHOT_FORMAT_VERSION {
+            return Err(SnapshotError::Invalid(format!("unsupported version {}", manifest.version)));
+        }
+        if manifest.chain_id != trust.chain_id {
+            return Err(SnapshotError::ChainMismatch {
+                expected: trust.chain_id.clone(),
+                actual: manifest.chain_id,
+            });
+        }
+        if archive.sections.len() != manifest.sections.len() {
+            return Err(invalid("sections differ from the manifest"));
+        }
+
+        let section = |name: &str| -> Result<&[u8], SnapshotError> {
+            let digest = manifest.sections.iter().find(|digest| digest.name == name)
+                .ok_or_else(|| SnapshotError::Invalid(format!("manifest lists no {} section", name)))?;
+            let section = archive.sections.iter().find(|section| section.name == name)
+                .ok_or_else(|| SnapshotError::Invalid(format!("missing {} section", name)))?;
+            if section.data.len() as u64 != digest.len || sha256_hex(&section.data) != digest.sha256 {
+                return Err(SnapshotError::ChecksumMismatch(name.to_string()));
+            }
+            Ok(&section.data)
+        };
+        // Snapshots taken before headers were versioned, carried address
+        // filters or named their chain hold the old layouts.
+        let header = section(HEADER_SECTION)?;
+        let header = BlockHeader::try_from_slice(header)
+            .or_else(|_| BlockHeader::from_v2_slice(header))
+            .or_else(|_| BlockHeader::from_v1_slice(header))
+            .or_else(|_| BlockHeader::from_legacy_slice(header))
+            .map_err(|_| invalid("malformed header"))?;
+        let certificate = CommitCertificate::try_from_slice(section(CERTIFICATE_SECTION)?)
+            .map_err(|_| invalid("malformed certificate"))?;
+        let state = State::decode(section(STATE_SECTION)?)?;
+
+        let block_hash = Block { header: header.clone(), transactions: Vec::new() }.hash();
+        if header.height != manifest.height || certificate.height != manifest.height || state.height() != manifest.height {
+            return Err(invalid("sections disagree on the snapshot height"));
+        }
+        if block_hash != manifest.block_hash {
+            return Err(invalid("header does not match the manifest block hash"));
+        }
+        certificate.verify(&trust.chain_id, &block_hash, &trust.validators, trust.quorum.as_ref())?;
+        // The next block's header commits to this root; sync checks it when
+        // applying that block.
+        if state.root() != manifest.state_root {
+            return Err(invalid("state does not match the manifest state root"));
+        }
+
+        Ok(Snapshot { manifest, header, certificate, state })
+    }
+
+    pub fn height(&self) -> u64 {
+        self.manifest.height
+    }
+
+    /// The snapshot block without its body, to root a block tree at.
+    pub fn tip(&self) -> Block {
+        Block { header: self.header.clone(), transactions: Vec::new() }
+    }
+}
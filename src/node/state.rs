@@ -0,0 +1,620 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::error;
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::{hashv, Hash};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use super::block::Block;
+use super::transaction::Transaction;
+use crate::utils::DADBSAddress;
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("Expected block at height {expected}, got {actual}")]
+    HeightMismatch { expected: u64, actual: u64 },
+    #[error("Block state root {actual} does not match local state root {expected}")]
+    StateRootMismatch { expected: Hash, actual: Hash },
+    #[error("Block body does not match its header")]
+    BodyMismatch,
+    #[error("Transaction {0} has an invalid signature")]
+    InvalidSignature(Hash),
+    #[error("Account {address} has nonce {expected}, transaction uses {actual}")]
+    InvalidNonce { address: DADBSAddress, expected: u64, actual: u64 },
+    #[error("Account {address} holds {balance}, needs {required}")]
+    InsufficientBalance { address: DADBSAddress, balance: u64, required: u64 },
+    #[error("Balance overflow for account {0}")]
+    Overflow(DADBSAddress),
+    #[error("Simulation ran past its {0:?} budget")]
+    BudgetExceeded(Duration),
+    #[error("Consensus fault: finalized block at height {height} is invalid: {reason}")]
+    ConsensusFault { height: u64, reason: String },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// Nonce the account's next transaction must carry.
+    pub nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountChange {
+    pub address: DADBSAddress,
+    pub before: Account,
+    pub after: Account,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StateDiff {
+    pub height: u64,
+    pub changes: Vec<AccountChange>,
+    pub state_root: Hash,
+    /// One per transaction, in block order.
+    pub receipts: Vec<Receipt>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptStatus {
+    Success,
+    /// Included and charged its fee, but its transfer was rolled back.
+    Failed,
+}
+
+/// A state change a transaction made.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Transfer { from: DADBSAddress, to: DADBSAddress, amount: u64 },
+}
+
+/// How a transaction included in a finalized block was executed.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub tx_hash: Hash,
+    pub block_hash: Hash,
+    pub height: u64,
+    pub index: u32,
+    pub status: ReceiptStatus,
+    /// Why it failed.
+    pub error: Option<String>,
+    pub fee_paid: u64,
+    /// Empty for a failed transaction.
+    pub events: Vec<Event>,
+}
+
+/// What a transaction would do if it were applied next, as `State::simulate`
+/// finds. The fee's credit to the proposer is not included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Simulation {
+    pub status: ReceiptStatus,
+    /// Why the transfer would fail.
+    pub error: Option<String>,
+    pub fee_paid: u64,
+    pub events: Vec<Event>,
+    pub changes: Vec<AccountChange>,
+    pub state_root_before: Hash,
+    pub state_root_after: Hash,
+}
+
+/// Entries hashed between checks of a simulation's deadline.
+const DEADLINE_CHECK_ENTRIES: usize = 4096;
+
+#[derive(Serialize, Deserialize, Default)]
+struct StateSnapshot {
+    height: u64,
+    accounts: BTreeMap<DADBSAddress, Account>,
+}
+
+/// Account balances and nonces as of the last applied finalized block.
+pub struct State {
+    path: Option<PathBuf>,
+    height: u64,
+    accounts: BTreeMap<DADBSAddress, Account>,
+    root: Hash,
+}
+
+impl State {
+    pub fn in_memory(genesis: impl IntoIterator<Item = (DADBSAddress, u64)>) -> Self {
+        let accounts = genesis.into_iter()
+            .map(|(address, balance)| (address, Account { balance, nonce: 0 }))
+            .collect();
+        Self::from_snapshot(None, StateSnapshot { height: 0, accounts })
+    }
+
+    /// Loads state from `path`, or starts from `genesis` if nothing has been
+    /// persisted yet.
+    pub fn open(path: &Path, genesis: impl IntoIterator<Item = (DADBSAddress, u64)>) -> Result<Self, StateError> {
+        if path.exists() {
+            let snapshot: StateSnapshot = serde_json::from_str(&fs::read_to_string(path)?)?;
+            return Ok(Self::from_snapshot(Some(path.to_path_buf()), snapshot));
+        }
+
+        let mut state = Self::in_memory(genesis);
+        state.path = Some(path.to_path_buf());
+        state.persist()?;
+        Ok(state)
+    }
+
+    fn from_snapshot(path: Option<PathBuf>, snapshot: StateSnapshot) -> Self {
+        let root = Self::compute_root(&snapshot.accounts);
+        State {
+            path,
+            height: snapshot.height,
+            accounts: snapshot.accounts,
+            root,
+        }
+    }
+
+    /// Decodes state exported by `export`, without persisting it.
+    pub fn decode(bytes: &[u8]) -> Result<Self, StateError> {
+        Ok(Self::from_snapshot(None, serde_json::from_slice(bytes)?))
+    }
+
+    /// Every account as of `height`, in the format `decode` reads.
+    pub fn export(&self) -> Result<Vec<u8>, StateError> {
+        let snapshot = StateSnapshot {
+            height: self.height,
+            accounts: self.accounts.clone(),
+        };
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    /// Replaces every account with `other`'s, keeping this state's path.
+    pub fn replace_with(&mut self, other: State) -> Result<(), StateError> {
+        self.height = other.height;
+        self.accounts = other.accounts;
+        self.root = other.root;
+        self.persist()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Root over all accounts sorted by address. The next block header must carry it.
+    pub fn root(&self) -> Hash {
+        self.root
+    }
+
+    pub fn account(&self, address: &DADBSAddress) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn balance(&self, address: &DADBSAddress) -> u64 {
+        self.account(address).balance
+    }
+
+    fn compute_root(accounts: &BTreeMap<DADBSAddress, Account>) -> Hash {
+        Self::hash_accounts(accounts.iter(), None).expect("no deadline")
+    }
+
+    /// The root `accounts` would have with `overlay` written over them, or
+    /// `BudgetExceeded` once `deadline` passes.
+    fn compute_root_with(
+        accounts: &BTreeMap<DADBSAddress, Account>,
+        overlay: &BTreeMap<DADBSAddress, Account>,
+        deadline: (Instant, Duration),
+    ) -> Result<Hash, StateError> {
+        let merged: BTreeMap<_, _> = accounts.iter()
+            .filter(|(address, _)| !overlay.contains_key(*address))
+            .chain(overlay)
+            .collect();
+        Self::hash_accounts(merged.into_iter(), Some(deadline))
+    }
+
+    /// Hashes accounts given in address order.
+    fn hash_accounts<'a>(
+        accounts: impl ExactSizeIterator<Item = (&'a DADBSAddress, &'a Account)>,
+        deadline: Option<(Instant, Duration)>,
+    ) -> Result<Hash, StateError> {
+        let count = accounts.len();
+        if count == 0 {
+            return Ok(Hash::default());
+        }
+        let mut entries: Vec<Vec<u8>> = Vec::with_capacity(count);
+        for (address, account) in accounts {
+            if let Some((deadline, budget)) = deadline {
+                if entries.len() % DEADLINE_CHECK_ENTRIES == 0 && Instant::now() > deadline {
+                    return Err(StateError::BudgetExceeded(budget));
+                }
+            }
+            let mut entry = address.as_string().as_bytes().to_vec();
+            entry.extend_from_slice(&account.balance.to_le_bytes());
+            entry.extend_from_slice(&account.nonce.to_le_bytes());
+            entries.push(entry);
+        }
+        let refs: Vec<&[u8]> = entries.iter().map(|e| e.as_slice()).collect();
+        Ok(hashv(&refs))
+    }
+
+    /// Applies `block`, with a receipt per transaction. A transaction whose
+    /// transfer cannot be made still pays its fee and uses its nonce, and
+    /// its receipt says why it failed. Any other invalid transaction, one
+    /// whose sender cannot pay the fee included, rejects the whole block.
+    pub fn apply_block(&mut self, block: &Block) -> Result<StateDiff, StateError> {
+        let expected = self.height + 1;
+        if block.height() != expected {
+            return Err(StateError::HeightMismatch { expected, actual: block.height() });
+        }
+        if block.header.state_root != self.root {
+            return Err(StateError::StateRootMismatch { expected: self.root, actual: block.header.state_root });
+        }
+        if !block.verify_body() {
+            return Err(StateError::BodyMismatch);
+        }
+
+        let block_hash = block.hash();
+        let mut touched: BTreeMap<DADBSAddress, Account> = BTreeMap::new();
+        let mut receipts = Vec::with_capacity(block.transactions.len());
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            let (status, error, events) = Self::execute(&mut touched, &self.accounts, transaction)?;
+            receipts.push(Receipt {
+                tx_hash: transaction.hash(),
+                block_hash,
+                height: block.height(),
+                index: index as u32,
+                status,
+                error,
+                fee_paid: transaction.fee,
+                events,
+            });
+        }
+
+        if block.header.total_fees > 0 {
+            let proposer = DADBSAddress::from_pubkey(&block.header.proposer);
+            Self::credit(&mut touched, &self.accounts, proposer, block.header.total_fees)?;
+        }
+
+        let changes = self.changes(&touched);
+        self.accounts.extend(touched);
+        self.height = block.height();
+        self.root = Self::compute_root(&self.accounts);
+        self.persist()?;
+
+        Ok(StateDiff { height: self.height, changes, state_root: self.root, receipts })
+    }
+
+    /// Runs `transaction` as if it were the next one applied, through the
+    /// same code as `apply_block`, without changing any account. Errors are
+    /// those that would reject a block carrying it, or `BudgetExceeded` once
+    /// it takes longer than `budget`.
+    pub fn simulate(&self, transaction: &Transaction, budget: Duration) -> Result<Simulation, StateError> {
+        let deadline = Instant::now() + budget;
+        let mut touched = BTreeMap::new();
+        let (status, error, events) = Self::execute(&mut touched, &self.accounts, transaction)?;
+        let state_root_after = Self::compute_root_with(&self.accounts, &touched, (deadline, budget))?;
+        Ok(Simulation {
+            status,
+            error,
+            fee_paid: transaction.fee,
+            events,
+            changes: self.changes(&touched),
+            state_root_before: self.root,
+            state_root_after,
+        })
+    }
+
+    /// Executes one transaction into `touched`, over `accounts`. A transfer
+    /// that cannot be made is a failed receipt; an error rejects the block.
+    fn execute(
+        touched: &mut BTreeMap<DADBSAddress, Account>,
+        accounts: &BTreeMap<DADBSAddress, Account>,
+        transaction: &Transaction,
+    ) -> Result<(ReceiptStatus, Option<String>, Vec<Event>), StateError> {
+        if !transaction.verify_signature() {
+            return Err(StateError::InvalidSignature(transaction.hash()));
+        }
+
+        let sender = DADBSAddress::from_pubkey(&transaction.sender);
+        let mut account = touched.get(&sender).or_else(|| accounts.get(&sender)).copied().unwrap_or_default();
+        if transaction.nonce != account.nonce {
+            return Err(StateError::InvalidNonce {
+                address: sender,
+                expected: account.nonce,
+                actual: transaction.nonce,
+            });
+        }
+        if account.balance < transaction.fee {
+            return Err(StateError::InsufficientBalance {
+                address: sender,
+                balance: account.balance,
+                required: transaction.fee,
+            });
+        }
+        account.balance -= transaction.fee;
+        account.nonce += 1;
+        touched.insert(sender.clone(), account);
+
+        let recipient = DADBSAddress::from_pubkey(&transaction.recipient);
+        Ok(match Self::transfer(touched, accounts, &sender, &recipient, transaction.amount) {
+            Ok(()) => {
+                let event = Event::Transfer { from: sender, to: recipient, amount: transaction.amount };
+                (ReceiptStatus::Success, None, vec![event])
+            }
+            Err(e) => (ReceiptStatus::Failed, Some(e.to_string()), Vec::new()),
+        })
+    }
+
+    fn changes(&self, touched: &BTreeMap<DADBSAddress, Account>) -> Vec<AccountChange> {
+        touched.iter()
+            .map(|(address, after)| AccountChange {
+                address: address.clone(),
+                before: self.account(address),
+                after: *after,
+            })
+            .collect()
+    }
+
+    /// Applies a block that consensus has already finalized. Any rejection is
+    /// a consensus fault: a quorum signed a block this node considers invalid.
+    pub fn apply_finalized_block(&mut self, block: &Block) -> Result<StateDiff, StateError> {
+        match self.apply_block(block) {
+            Err(e @ (StateError::Io(_) | StateError::Serialization(_))) => {
+                error!("Failed to persist state at height {}: {}", block.height(), e);
+                Err(e)
+            }
+            Err(e) => {
+                error!(
+                    "CONSENSUS FAULT: finalized block {} at height {} cannot be applied: {}",
+                    block.hash(),
+                    block.height(),
+                    e
+                );
+                Err(StateError::ConsensusFault { height: block.height(), reason: e.to_string() })
+            }
+            ok => ok,
+        }
+    }
+
+    fn credit(
+        touched: &mut BTreeMap<DADBSAddress, Account>,
+        accounts: &BTreeMap<DADBSAddress, Account>,
+        address: DADBSAddress,
+        amount: u64,
+    ) -> Result<(), StateError> {
+        let mut account = touched.get(&address)
+            .or_else(|| accounts.get(&address))
+            .copied()
+            .unwrap_or_default();
+        account.balance = account.balance.checked_add(amount)
+            .ok_or_else(|| StateError::Overflow(address.clone()))?;
+        touched.insert(address, account);
+        Ok(())
+    }
+
+    /// Moves `amount` from `from` to `to`, or changes nothing.
+    fn transfer(
+        touched: &mut BTreeMap<DADBSAddress, Account>,
+        accounts: &BTreeMap<DADBSAddress, Account>,
+        from: &DADBSAddress,
+        to: &DADBSAddress,
+        amount: u64,
+    ) -> Result<(), StateError> {
+        let account = |address: &DADBSAddress| {
+            touched.get(address).or_else(|| accounts.get(address)).copied().unwrap_or_default()
+        };
+        let (mut sender, mut recipient) = (account(from), account(to));
+        if sender.balance < amount {
+            return Err(StateError::InsufficientBalance { address: from.clone(), balance: sender.balance, required: amount });
+        }
+        if from == to {
+            return Ok(());
+        }
+        recipient.balance = recipient.balance.checked_add(amount)
+            .ok_or_else(|| StateError::Overflow(to.clone()))?;
+        sender.balance -= amount;
+        touched.insert(from.clone(), sender);
+        touched.insert(to.clone(), recipient);
+        Ok(())
+    }
+
+    /// Writes the state to its path; does nothing for in-memory state.
+    pub fn persist(&self) -> Result<(), StateError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let snapshot = StateSnapshot {
+            height: self.height,
+            accounts: self.accounts.clone(),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&snapshot)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{
+        pubkey::Pubkey,
+        signature::{Keypair, Signer},
+    };
+
+    struct Chain {
+        alice: Keypair,
+        bob: Keypair,
+        proposer: Pubkey,
+    }
+
+    impl Chain {
+        fn new() -> Self {
+            Chain { alice: Keypair::new(), bob: Keypair::new(), proposer: Pubkey::new_unique() }
+        }
+
+        fn genesis(&self) -> Vec<(DADBSAddress, u64)> {
+            vec![
+                (DADBSAddress::from_pubkey(&self.alice.pubkey()), 1_000),
+                (DADBSAddress::from_pubkey(&self.bob.pubkey()), 500),
+            ]
+        }
+
+        fn block(&self, state: &State, parent: &Block, transactions: Vec<Transaction>) -> Block {
+            let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, self.proposer, transactions);
+            block.header.state_root = state.root();
+            block
+        }
+    }
+
+    fn transfer(from: &Keypair, to: &Keypair, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        Transaction::new_signed(from, to.pubkey(), amount, fee, nonce, 0)
+    }
+
+    #[test]
+    fn test_replay_produces_identical_roots() {
+        let chain = Chain::new();
+        let mut first = State::in_memory(chain.genesis());
+        let mut second = State::in_memory(chain.genesis());
+
+        let mut parent = Block::genesis();
+        for nonce in 0..5 {
+            let block = chain.block(&first, &parent, vec![
+                transfer(&chain.alice, &chain.bob, 10, 1, nonce),
+                transfer(&chain.bob, &chain.alice, 3, 2, nonce),
+            ]);
+            let a = first.apply_block(&block).unwrap();
+            let b = second.apply_block(&block).unwrap();
+            assert_eq!(a, b);
+            parent = block;
+        }
+
+        assert_eq!(first.root(), second.root());
+        assert_eq!(first.balance(&DADBSAddress::from_pubkey(&chain.alice.pubkey())), 1_000 - 5 * 11 + 5 * 3);
+        assert_eq!(first.balance(&DADBSAddress::from_pubkey(&chain.proposer)), 15);
+        assert_eq!(first.account(&DADBSAddress::from_pubkey(&chain.bob.pubkey())).nonce, 5);
+    }
+
+    #[test]
+    fn test_invalid_transaction_rejects_whole_block() {
+        let chain = Chain::new();
+        let mut state = State::in_memory(chain.genesis());
+        let root = state.root();
+
+        let block = chain.block(&state, &Block::genesis(), vec![
+            transfer(&chain.alice, &chain.bob, 100, 1, 0),
+            transfer(&chain.bob, &chain.alice, 1, 10_000, 0),
+        ]);
+        assert!(matches!(state.apply_block(&block), Err(StateError::InsufficientBalance { required: 10_000, .. })));
+        assert_eq!(state.root(), root);
+        assert_eq!(state.height(), 0);
+
+        let gap = chain.block(&state, &Block::genesis(), vec![transfer(&chain.alice, &chain.bob, 1, 1, 3)]);
+        assert!(matches!(state.apply_block(&gap), Err(StateError::InvalidNonce { expected: 0, actual: 3, .. })));
+    }
+
+    #[test]
+    fn test_failed_transfer_pays_fee_and_gets_receipt() {
+        let chain = Chain::new();
+        let mut state = State::in_memory(chain.genesis());
+        let (alice, bob) = (DADBSAddress::from_pubkey(&chain.alice.pubkey()), DADBSAddress::from_pubkey(&chain.bob.pubkey()));
+        let transactions = vec![transfer(&chain.alice, &chain.bob, 100, 1, 0), transfer(&chain.bob, &chain.alice, 10_000, 2, 0)];
+        let block = chain.block(&state, &Block::genesis(), transactions.clone());
+        let diff = state.apply_block(&block).unwrap();
+
+        let receipt = |index: usize, status, error: Option<&str>, events| Receipt {
+            tx_hash: transactions[index].hash(),
+            block_hash: block.hash(),
+            height: 1,
+            index: index as u32,
+            status,
+            error: error.map(str::to_string),
+            fee_paid: transactions[index].fee,
+            events,
+        };
+        let moved = Event::Transfer { from: alice.clone(), to: bob.clone(), amount: 100 };
+        let failed = format!("Account {} holds 598, needs 10000", bob);
+        assert_eq!(diff.receipts, vec![
+            receipt(0, ReceiptStatus::Success, None, vec![moved]),
+            receipt(1, ReceiptStatus::Failed, Some(&failed), Vec::new()),
+        ]);
+        assert_eq!((state.balance(&alice), state.balance(&bob)), (899, 598));
+        assert_eq!(state.account(&bob).nonce, 1);
+        assert_eq!(state.balance(&DADBSAddress::from_pubkey(&chain.proposer)), 3);
+    }
+
+    #[test]
+    fn test_simulation_matches_inclusion_and_changes_nothing() {
+        let chain = Chain::new();
+        let state = State::in_memory(chain.genesis());
+        let (before, height) = (state.root(), state.height());
+        let transfers = [
+            transfer(&chain.alice, &chain.bob, 100, 1, 0),
+            transfer(&chain.bob, &chain.alice, 10_000, 2, 0),
+        ];
+        let simulations: Vec<Simulation> = transfers.iter()
+            .map(|transaction| state.simulate(transaction, Duration::from_secs(1)).unwrap())
+            .collect();
+        assert_eq!((state.root(), state.height()), (before, height));
+        assert!(matches!(
+            state.simulate(&transfer(&chain.alice, &chain.bob, 1, 1, 4), Duration::from_secs(1)),
+            Err(StateError::InvalidNonce { expected: 0, actual: 4, .. })
+        ));
+        assert!(matches!(
+            state.simulate(&transfers[0], Duration::ZERO),
+            Err(StateError::BudgetExceeded(Duration::ZERO))
+        ));
+
+        // Each alone in a block does the same, besides crediting the proposer.
+        let proposer = DADBSAddress::from_pubkey(&chain.proposer);
+        for (transaction, simulation) in transfers.iter().zip(simulations) {
+            let mut alone = State::in_memory(chain.genesis());
+            let diff = alone.apply_block(&chain.block(&alone, &Block::genesis(), vec![transaction.clone()])).unwrap();
+            let receipt = &diff.receipts[0];
+            assert_eq!(
+                (simulation.status, simulation.error.as_ref(), simulation.fee_paid, &simulation.events),
+                (receipt.status, receipt.error.as_ref(), receipt.fee_paid, &receipt.events)
+            );
+            let changes: Vec<_> = diff.changes.into_iter().filter(|change| change.address != proposer).collect();
+            assert_eq!(simulation.changes, changes);
+
+            let mut accounts = state.accounts.clone();
+            accounts.extend(changes.into_iter().map(|change| (change.address, change.after)));
+            assert_eq!(simulation.state_root_before, before);
+            assert_eq!(simulation.state_root_after, State::compute_root(&accounts));
+        }
+    }
+
+    #[test]
+    fn test_state_root_must_match() {
+        let chain = Chain::new();
+        let mut state = State::in_memory(chain.genesis());
+        let mut block = chain.block(&state, &Block::genesis(), Vec::new());
+        block.header.state_root = Hash::new_unique();
+        assert!(matches!(state.apply_block(&block), Err(StateError::StateRootMismatch { .. })));
+        assert!(matches!(state.apply_finalized_block(&block), Err(StateError::ConsensusFault { height: 1, .. })));
+    }
+
+    #[test]
+    fn test_state_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let chain = Chain::new();
+
+        let root = {
+            let mut state = State::open(&path, chain.genesis()).unwrap();
+            let block = chain.block(&state, &Block::genesis(), vec![transfer(&chain.alice, &chain.bob, 5, 1, 0)]);
+            state.apply_block(&block).unwrap();
+            state.root()
+        };
+
+        let state = State::open(&path, Vec::new()).unwrap();
+        assert_eq!(state.root(), root);
+        assert_eq!(state.height(), 1);
+        assert_eq!(state.account(&DADBSAddress::from_pubkey(&chain.alice.pubkey())).nonce, 1);
+    }
+}
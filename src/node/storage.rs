@@ -0,0 +1,2271 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{hash::Hash, pubkey::Pubkey};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+use super::backup::{self, BackupError, BackupManifest, BackupProgress, BackupStage};
+use super::block::{Block, BlockHeader, BLOCK_VERSION};
+use super::chain_stats::{self, AddressStats, DayStats, StatsUpdate, DAY_MS};
+use super::api_keys::{self, ApiKeyRecord, ApiKeyUsage};
+use super::faucet::{self, FaucetGrant, FaucetUsage};
+use super::handover::KeyHandover;
+use super::merkle::MerkleProof;
+use super::metrics::{self, MetricsSource};
+use super::pagination::{self, CursorCodec, CursorError, Direction, Page};
+use super::params::ParamChange;
+use super::runtime::{CHAIN_DIR, STATE_FILE};
+use super::snapshot::{Snapshot, SnapshotError, SnapshotManifest, SnapshotTrust};
+use super::state::{Receipt, State};
+use super::sync::BlockStore;
+use super::transaction::{Transaction, TxKind};
+use super::vote::CommitCertificate;
+use super::wal::{IntentLog, DEFAULT_WAL_MAX_BYTES};
+use super::webhooks::{self, DeadLetter, WebhookEndpoint};
+use crate::utils::DADBSAddress;
+
+/// Bumped whenever the key layout changes.
+pub const STORAGE_FORMAT_VERSION: u32 = 5;
+/// The last format whose headers carried no version; migrated on open.
+const UNVERSIONED_HEADERS_FORMAT_VERSION: u32 = 2;
+/// The last format whose headers carried no address filter; migrated on
+/// open.
+const UNFILTERED_HEADERS_FORMAT_VERSION: u32 = 3;
+/// The last format whose headers named no chain; migrated on open.
+const UNCHAINED_HEADERS_FORMAT_VERSION: u32 = 4;
+/// Entries rewritten per batch while migrating.
+const MIGRATION_BATCH: usize = 256;
+const FORMAT_VERSION_KEY: &str = "format_version";
+/// Intent log file inside the storage directory.
+const WAL_FILE: &str = "intent.wal";
+/// Highest finalized height, written with its certificate.
+pub const FINALIZED_HEIGHT_KEY: &str = "finalized_height";
+/// Lowest height whose block body is still stored.
+pub const PRUNE_HORIZON_KEY: &str = "prune_horizon";
+/// Written by a clean shutdown, after the final flush; cleared on open.
+pub const CLEAN_SHUTDOWN_KEY: &str = "clean_shutdown";
+/// Secret that pagination cursors are signed with, created on first open.
+const CURSOR_KEY: &str = "cursor_key";
+/// Blocks `recent_transactions` reads for one page before returning it
+/// short, so a run of empty blocks cannot make a call unbounded.
+const MAX_SCANNED_BLOCKS: u64 = 1000;
+const BLOCKS_SCOPE: &[u8] = b"blocks";
+const RECENT_SCOPE: &[u8] = b"recent";
+/// Progress of an unfinished reindex, so it can resume.
+const REINDEX_CHECKPOINT_KEY: &str = "reindex_checkpoint";
+/// Blocks staged per checkpoint during a reindex.
+const REINDEX_BATCH_BLOCKS: u64 = 256;
+
+pub const DEFAULT_PRUNE_BATCH_BLOCKS: u64 = 100;
+pub const DEFAULT_PRUNE_INTERVAL_MS: u64 = 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct StorageConfig {
+    /// Finalized block bodies to keep; older bodies and their transaction
+    /// indexes are pruned. Unset keeps everything.
+    #[serde(default)]
+    pub prune_keep_blocks: Option<u64>,
+    /// Most blocks pruned per tick, so pruning never stalls the node.
+    #[serde(default = "default_prune_batch_blocks")]
+    pub prune_batch_blocks: u64,
+    #[serde(default = "default_prune_interval_ms")]
+    pub prune_interval_ms: u64,
+}
+
+fn default_prune_batch_blocks() -> u64 {
+    DEFAULT_PRUNE_BATCH_BLOCKS
+}
+
+fn default_prune_interval_ms() -> u64 {
+    DEFAULT_PRUNE_INTERVAL_MS
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            prune_keep_blocks: None,
+            prune_batch_blocks: DEFAULT_PRUNE_BATCH_BLOCKS,
+            prune_interval_ms: DEFAULT_PRUNE_INTERVAL_MS,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] std::io::Error),
+    #[error("Storage is corrupted: {0}")]
+    Corrupted(String),
+    #[error("Block {height} was pruned; bodies are kept from height {horizon}")]
+    Pruned { height: u64, horizon: u64 },
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(#[from] CursorError),
+    #[error("A reindex is already running")]
+    ReindexInProgress,
+}
+
+#[cfg(not(feature = "rocksdb-storage"))]
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+#[cfg(feature = "rocksdb-storage")]
+impl From<rocksdb::Error> for StorageError {
+    fn from(e: rocksdb::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+/// A tree in sled, a column family in RocksDB.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// Block hash to block. Pruned below the horizon.
+    Blocks,
+    /// Block hash to header. Never pruned.
+    Headers,
+    /// Big-endian height to block hash.
+    BlockHashes,
+    /// Transaction hash to `StoredTransaction`.
+    Transactions,
+    /// Address, height and position to transaction hash.
+    AddressIndex,
+    /// Big-endian height to the commit certificate finalizing it.
+    Certificates,
+    /// Consensus metadata by name.
+    Metadata,
+    /// Big-endian sequence number to `AuditRecord`.
+    Audit,
+    /// Height and position to the `ParamChange` and approvals finalized
+    /// there. Never pruned.
+    ParamChanges,
+    /// Indexes being rebuilt, keyed by their column's tag and then their key.
+    Reindex,
+    /// Daily chain aggregates and per-address totals, updated as blocks
+    /// are committed.
+    Stats,
+    /// Transaction hash to its `Receipt`, once its block is executed.
+    /// Pruned with the block.
+    Receipts,
+    /// Key handed over to its `KeyHandover`. Never pruned.
+    Handovers,
+    /// Faucet cooldowns, daily spending and the log of its grants.
+    Faucet,
+    /// RPC API key records by id, and each key's daily usage.
+    ApiKeys,
+    /// Webhook endpoints by id, and the deliveries given up on by time.
+    Webhooks,
+    /// Transaction hash to a transaction pending when the mempool was last
+    /// saved.
+    Mempool,
+}
+
+impl Column {
+    pub const ALL: [Column; 17] = [
+        Column::Blocks,
+        Column::Headers,
+        Column::BlockHashes,
+        Column::Transactions,
+        Column::AddressIndex,
+        Column::Certificates,
+        Column::Metadata,
+        Column::Audit,
+        Column::ParamChanges,
+        Column::Reindex,
+        Column::Stats,
+        Column::Receipts,
+        Column::Handovers,
+        Column::Faucet,
+        Column::ApiKeys,
+        Column::Webhooks,
+        Column::Mempool,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Blocks => "blocks",
+            Column::Headers => "headers",
+            Column::BlockHashes => "block_hashes",
+            Column::Transactions => "transactions",
+            Column::AddressIndex => "address_index",
+            Column::Certificates => "certificates",
+            Column::Metadata => "metadata",
+            Column::Audit => "audit",
+            Column::ParamChanges => "param_changes",
+            Column::Reindex => "reindex",
+            Column::Stats => "stats",
+            Column::Receipts => "receipts",
+            Column::Handovers => "handovers",
+            Column::Faucet => "faucet",
+            Column::ApiKeys => "api_keys",
+            Column::Webhooks => "webhooks",
+            Column::Mempool => "mempool",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Writes applied atomically by `Backend::write`.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(Column, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn put(&mut self, column: Column, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push((column, key.into(), Some(value.into())));
+    }
+
+    pub fn delete(&mut self, column: Column, key: impl Into<Vec<u8>>) {
+        self.ops.push((column, key.into(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+type Entry = (Vec<u8>, Vec<u8>);
+
+trait Backend: Send + Sync {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    fn write(&self, batch: WriteBatch) -> Result<(), StorageError>;
+    /// Entries with `from <= key < to`, in key order.
+    fn scan(&self, column: Column, from: &[u8], to: &[u8]) -> Result<Vec<Entry>, StorageError>;
+    /// At most `limit` entries with `from <= key < to`, from the lowest key
+    /// up or, if `reverse`, from the highest down.
+    fn scan_limit(&self, column: Column, from: &[u8], to: &[u8], limit: usize, reverse: bool)
+        -> Result<Vec<Entry>, StorageError>;
+    fn last(&self, column: Column) -> Result<Option<Entry>, StorageError>;
+    fn flush(&self) -> Result<(), StorageError>;
+    /// Writes a copy of every column to `dest`, reporting columns done of
+    /// the total.
+    fn checkpoint(&self, dest: &Path, progress: &dyn Fn(u64, u64)) -> Result<(), StorageError>;
+}
+
+fn open_backend(path: &Path) -> Result<Box<dyn Backend>, StorageError> {
+    #[cfg(not(feature = "rocksdb-storage"))]
+    let backend: Box<dyn Backend> = Box::new(SledBackend::open(path)?);
+    #[cfg(feature = "rocksdb-storage")]
+    let backend: Box<dyn Backend> = Box::new(RocksBackend::open(path)?);
+    Ok(backend)
+}
+
+#[cfg(not(feature = "rocksdb-storage"))]
+struct SledBackend {
+    db: sled::Db,
+    trees: Vec<sled::Tree>,
+}
+
+#[cfg(not(feature = "rocksdb-storage"))]
+impl SledBackend {
+    fn open(path: &Path) -> Result<Self, StorageError> {
+        let db = sled::open(path)?;
+        let trees = Column::ALL.iter()
+            .map(|column| db.open_tree(column.name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SledBackend { db, trees })
+    }
+}
+
+#[cfg(not(feature = "rocksdb-storage"))]
+impl Backend for SledBackend {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.trees[column.index()].get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+
+        self.trees.as_slice()
+            .transaction(|trees| {
+                for (column, key, value) in &batch.ops {
+                    let tree = &trees[column.index()];
+                    match value {
+                        Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                        None => tree.remove(key.as_slice())?,
+                    };
+                }
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e: TransactionError<()>| StorageError::Backend(format!("{:?}", e)))
+    }
+
+    fn scan(&self, column: Column, from: &[u8], to: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        self.trees[column.index()].range(from..to)
+            .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())).map_err(StorageError::from))
+            .collect()
+    }
+
+    fn scan_limit(&self, column: Column, from: &[u8], to: &[u8], limit: usize, reverse: bool)
+        -> Result<Vec<Entry>, StorageError>
+    {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        let range = self.trees[column.index()].range(from..to);
+        let entries: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> =
+            if reverse { Box::new(range.rev()) } else { Box::new(range) };
+        entries.take(limit)
+            .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())).map_err(StorageError::from))
+            .collect()
+    }
+
+    fn last(&self, column: Column) -> Result<Option<Entry>, StorageError> {
+        Ok(self.trees[column.index()].last()?.map(|(key, value)| (key.to_vec(), value.to_vec())))
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Sled has no snapshots, so writes must be held off for the copy.
+    fn checkpoint(&self, dest: &Path, progress: &dyn Fn(u64, u64)) -> Result<(), StorageError> {
+        let copy = sled::open(dest)?;
+        let total = self.trees.len() as u64;
+        for (done, (column, tree)) in Column::ALL.iter().zip(&self.trees).enumerate() {
+            let target = copy.open_tree(column.name())?;
+            let mut batch = sled::Batch::default();
+            for (count, entry) in tree.iter().enumerate() {
+                let (key, value) = entry?;
+                batch.insert(key, value);
+                if (count + 1) % MIGRATION_BATCH == 0 {
+                    target.apply_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            target.apply_batch(batch)?;
+            progress(done as u64 + 1, total);
+        }
+        copy.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rocksdb-storage")]
+struct RocksBackend {
+    db: rocksdb::DB,
+}
+
+#[cfg(feature = "rocksdb-storage")]
+impl RocksBackend {
+    fn open(path: &Path) -> Result<Self, StorageError> {
+        let mut options = rocksdb::Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let names = Column::ALL.iter().map(|column| column.name());
+        Ok(RocksBackend { db: rocksdb::DB::open_cf(&options, path, names)? })
+    }
+
+    fn cf(&self, column: Column) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(column.name()).expect("column families are created on open")
+    }
+}
+
+#[cfg(feature = "rocksdb-storage")]
+impl Backend for RocksBackend {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get_cf(self.cf(column), key)?)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        let mut writes = rocksdb::WriteBatch::default();
+        for (column, key, value) in &batch.ops {
+            match value {
+                Some(value) => writes.put_cf(self.cf(*column), key, value),
+                None => writes.delete_cf(self.cf(*column), key),
+            }
+        }
+        Ok(self.db.write(writes)?)
+    }
+
+    fn scan(&self, column: Column, from: &[u8], to: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        let mode = rocksdb::IteratorMode::From(from, rocksdb::Direction::Forward);
+        let mut entries = Vec::new();
+        for entry in self.db.iterator_cf(self.cf(column), mode) {
+            let (key, value) = entry?;
+            if key.as_ref() >= to {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn scan_limit(&self, column: Column, from: &[u8], to: &[u8], limit: usize, reverse: bool)
+        -> Result<Vec<Entry>, StorageError>
+    {
+        let mode = if reverse {
+            rocksdb::IteratorMode::From(to, rocksdb::Direction::Reverse)
+        } else {
+            rocksdb::IteratorMode::From(from, rocksdb::Direction::Forward)
+        };
+        let mut entries = Vec::new();
+        for entry in self.db.iterator_cf(self.cf(column), mode) {
+            let (key, value) = entry?;
+            if reverse && key.as_ref() >= to {
+                // Seeking backwards lands on `to` itself when it exists.
+                continue;
+            }
+            if key.as_ref() < from || key.as_ref() >= to || entries.len() == limit {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn last(&self, column: Column) -> Result<Option<Entry>, StorageError> {
+        match self.db.iterator_cf(self.cf(column), rocksdb::IteratorMode::End).next() {
+            Some(entry) => {
+                let (key, value) = entry?;
+                Ok(Some((key.to_vec(), value.to_vec())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        for column in Column::ALL {
+            self.db.flush_cf(self.cf(column))?;
+        }
+        Ok(())
+    }
+
+    /// A RocksDB checkpoint hard-links the live files, so it is cut at once.
+    fn checkpoint(&self, dest: &Path, progress: &dyn Fn(u64, u64)) -> Result<(), StorageError> {
+        rocksdb::checkpoint::Checkpoint::new(&self.db)?.create_checkpoint(dest)?;
+        progress(1, 1);
+        Ok(())
+    }
+}
+
+/// A transaction as stored, with where it was included.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredTransaction {
+    pub height: u64,
+    pub index: u32,
+    pub transaction: Transaction,
+}
+
+/// A privileged operation, as kept in the audit trail.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub timestamp_ms: i64,
+    /// Remote address of the caller.
+    pub caller: String,
+    pub method: String,
+    pub params: serde_json::Value,
+    /// `ok`, or the error returned.
+    pub outcome: String,
+}
+
+/// A secondary index, derived entirely from stored blocks.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexKind {
+    /// Address, height and position to transaction hash.
+    AddressTx,
+    /// Transaction hash to the transaction and where it was included.
+    TxHash,
+    /// The aggregates behind `chain_stats` and `address_stats`. Rebuilt
+    /// from the stored bodies alone, so after pruning they cover the
+    /// heights still stored.
+    ChainStats,
+}
+
+impl IndexKind {
+    pub const ALL: [IndexKind; 3] = [IndexKind::AddressTx, IndexKind::TxHash, IndexKind::ChainStats];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IndexKind::AddressTx => "address-tx",
+            IndexKind::TxHash => "tx-hash",
+            IndexKind::ChainStats => "chain-stats",
+        }
+    }
+
+    fn column(self) -> Column {
+        match self {
+            IndexKind::AddressTx => Column::AddressIndex,
+            IndexKind::TxHash => Column::Transactions,
+            IndexKind::ChainStats => Column::Stats,
+        }
+    }
+
+    /// Where this index's entries are staged in `Column::Reindex`.
+    fn staging_prefix(self) -> u8 {
+        self.column() as u8
+    }
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Reported after each batch of blocks a reindex stages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReindexProgress {
+    /// Lowest height with a stored body, where the rebuild started.
+    pub from_height: u64,
+    /// Highest height rebuilt so far.
+    pub height: u64,
+    /// The stored tip, which moves while new blocks arrive.
+    pub tip: u64,
+    /// Entries staged so far over all rebuilt indexes.
+    pub entries: u64,
+}
+
+/// The outcome of `Storage::reindex`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReindexReport {
+    pub kinds: Vec<IndexKind>,
+    pub from_height: u64,
+    /// The highest height rebuilt, or `None` with no stored bodies.
+    pub tip: Option<u64>,
+    /// Entries in each rebuilt index, in the order of `kinds`.
+    pub entries: Vec<u64>,
+    /// Whether an interrupted reindex was picked up from its checkpoint.
+    pub resumed: bool,
+}
+
+/// How far an unfinished reindex got, written with each staged batch.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+struct ReindexCheckpoint {
+    kinds: Vec<IndexKind>,
+    from_height: u64,
+    next_height: u64,
+    /// Entries staged for each of `kinds`.
+    entries: Vec<u64>,
+}
+
+fn staged_key(kind: IndexKind, key: &[u8]) -> Vec<u8> {
+    [&[kind.staging_prefix()][..], key].concat()
+}
+
+/// Entries `block` contributes to the `kind` index. Aggregates depend on
+/// the blocks before, so `StatsUpdate` derives those instead.
+fn index_entries(block: &Block, kind: IndexKind) -> Result<Vec<Entry>, StorageError> {
+    let height = block.height();
+    let mut entries = Vec::new();
+    for (index, transaction) in block.transactions.iter().enumerate() {
+        let index = index as u32;
+        let tx_hash = transaction.hash();
+        match kind {
+            IndexKind::AddressTx => {
+                entries.push((address_key(&transaction.sender, height, index), tx_hash.as_ref().to_vec()));
+                if transaction.recipient != transaction.sender {
+                    entries.push((address_key(&transaction.recipient, height, index), tx_hash.as_ref().to_vec()));
+                }
+            }
+            IndexKind::TxHash => {
+                let stored = StoredTransaction { height, index, transaction: transaction.clone() };
+                entries.push((tx_hash.as_ref().to_vec(), stored.try_to_vec()?));
+            }
+            IndexKind::ChainStats => {}
+        }
+    }
+    Ok(entries)
+}
+
+fn address_key(address: &Pubkey, height: u64, index: u32) -> Vec<u8> {
+    let mut key = address.to_bytes().to_vec();
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+/// Exclusive upper key bound that still includes `height`.
+fn height_bound(height: u64) -> Vec<u8> {
+    match height.checked_add(1) {
+        Some(next) => next.to_be_bytes().to_vec(),
+        None => vec![u8::MAX; 9],
+    }
+}
+
+fn address_scope(address: &Pubkey) -> Vec<u8> {
+    [b"address".as_slice(), address.as_ref()].concat()
+}
+
+/// Height and position from a cursor issued as their big-endian bytes.
+fn decode_position(position: &[u8]) -> Result<(u64, u32), CursorError> {
+    let height = position.get(..8).and_then(|bytes| <[u8; 8]>::try_from(bytes).ok());
+    let index = position.get(8..).and_then(|bytes| <[u8; 4]>::try_from(bytes).ok());
+    match (height, index) {
+        (Some(height), Some(index)) => Ok((u64::from_be_bytes(height), u32::from_be_bytes(index))),
+        _ => Err(CursorError::Malformed),
+    }
+}
+
+fn encode_position(height: u64, index: u32) -> Vec<u8> {
+    let mut position = height.to_be_bytes().to_vec();
+    position.extend_from_slice(&index.to_be_bytes());
+    position
+}
+
+/// A `Blocks` or `Headers` entry in today's layout if it was stored in an
+/// older one, `None` if it already is in today's layout.
+fn upgrade_header_layout(column: Column, hash: &Hash, bytes: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+    let (older, current) = match column {
+        Column::Blocks => (
+            [Block::from_legacy_slice, Block::from_v1_slice, Block::from_v2_slice].iter()
+                .find_map(|decode| decode(bytes).ok().filter(|block| block.hash() == *hash))
+                .map(|block| block.try_to_vec()),
+            Block::try_from_slice(bytes).map(|block| block.hash()),
+        ),
+        _ => (
+            [BlockHeader::from_legacy_slice, BlockHeader::from_v1_slice, BlockHeader::from_v2_slice].iter()
+                .find_map(|decode| decode(bytes).ok().filter(|header| header.hash() == *hash))
+                .map(|header| header.try_to_vec()),
+            BlockHeader::try_from_slice(bytes).map(|header| header.hash()),
+        ),
+    };
+    match (older, current) {
+        (Some(upgraded), _) => Ok(Some(upgraded?)),
+        (None, Ok(stored)) if stored == *hash => Ok(None),
+        _ => Err(StorageError::Corrupted(format!("{} entry {} is in no known header layout", column.name(), hash))),
+    }
+}
+
+fn hash_from(bytes: &[u8]) -> Result<Hash, StorageError> {
+    <[u8; 32]>::try_from(bytes)
+        .map(Hash::new_from_array)
+        .map_err(|_| StorageError::Corrupted(format!("expected a 32 byte hash, found {} bytes", bytes.len())))
+}
+
+/// Durable block and transaction storage under `storage_path`, backed by
+/// sled, or RocksDB with the `rocksdb-storage` feature.
+pub struct Storage {
+    path: PathBuf,
+    backend: Box<dyn Backend>,
+    wal: Mutex<IntentLog>,
+    /// Serializes audit appends so sequence numbers are unique.
+    audit: Mutex<()>,
+    cursors: CursorCodec,
+    /// Whether the previous run shut down cleanly, or this store is new.
+    clean_start: bool,
+    /// Indexes being rebuilt; blocks committed meanwhile are staged for
+    /// them as well.
+    reindexing: RwLock<Option<Vec<IndexKind>>>,
+    /// Held for a whole reindex so only one runs at a time.
+    reindex: Mutex<()>,
+    /// Held for a whole backup so only one runs at a time.
+    backup: Mutex<()>,
+    #[cfg(test)]
+    failpoint: Mutex<Option<Failpoint>>,
+}
+
+/// Where a test block commit stops, as if the process died there.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failpoint {
+    /// Partway through appending the intent.
+    TornIntent,
+    /// After the intent is synced, before the batch is applied.
+    AfterIntent,
+    /// After the batch is applied, before the intent is marked complete.
+    AfterApply,
+    /// After a reindex has staged every block, before it swaps them in.
+    BeforeSwap,
+}
+
+impl Storage {
+    /// Opens or creates the store at `path`, recovers any block commit
+    /// interrupted by a crash, and checks the latest block is intact.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open_with_wal_limit(path, DEFAULT_WAL_MAX_BYTES)
+    }
+
+    fn open_with_wal_limit(path: impl AsRef<Path>, wal_max_bytes: u64) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        let backend = open_backend(path)?;
+
+        let (mut wal, intents) = IntentLog::open(&path.join(WAL_FILE), wal_max_bytes)?;
+        if !intents.is_empty() {
+            for intent in intents {
+                match intent.batch {
+                    Some(batch) => {
+                        info!("Rolling forward interrupted commit of block {} at height {}", intent.block_hash, intent.height);
+                        backend.write(batch)?;
+                    }
+                    None => warn!(
+                        "Discarding interrupted commit of block {} at height {}: logged batch is corrupt",
+                        intent.block_hash, intent.height
+                    ),
+                }
+            }
+            backend.flush()?;
+            wal.checkpoint(true)?;
+        }
+
+        let cursor_key = match backend.get(Column::Metadata, CURSOR_KEY.as_bytes())? {
+            Some(key) => <[u8; 32]>::try_from(key.as_slice())
+                .map_err(|_| StorageError::Corrupted("malformed cursor key".to_string()))?,
+            None => {
+                let key: [u8; 32] = rand::random();
+                let mut batch = WriteBatch::default();
+                batch.put(Column::Metadata, CURSOR_KEY.as_bytes(), key.to_vec());
+                backend.write(batch)?;
+                key
+            }
+        };
+        let marked_clean = backend.get(Column::Metadata, CLEAN_SHUTDOWN_KEY.as_bytes())?.is_some();
+        let clean_start = marked_clean || backend.get(Column::Metadata, FORMAT_VERSION_KEY.as_bytes())?.is_none();
+        let storage = Storage {
+            path: path.to_path_buf(),
+            backend,
+            wal: Mutex::new(wal),
+            audit: Mutex::new(()),
+            cursors: CursorCodec::new(cursor_key),
+            clean_start,
+            reindexing: RwLock::new(None),
+            reindex: Mutex::new(()),
+            backup: Mutex::new(()),
+            #[cfg(test)]
+            failpoint: Mutex::new(None),
+        };
+        storage.check_integrity()?;
+        if marked_clean {
+            // Durably, so a crash in this run is not mistaken for a clean stop.
+            let mut batch = WriteBatch::default();
+            batch.delete(Column::Metadata, CLEAN_SHUTDOWN_KEY.as_bytes());
+            storage.backend.write(batch)?;
+            storage.backend.flush()?;
+        } else if !clean_start {
+            warn!("Storage at {} was not shut down cleanly", path.display());
+        }
+        Ok(storage)
+    }
+
+    fn check_integrity(&self) -> Result<(), StorageError> {
+        match self.get_metadata(FORMAT_VERSION_KEY)? {
+            Some(version)
+                if version == UNVERSIONED_HEADERS_FORMAT_VERSION.to_be_bytes()
+                    || version == UNFILTERED_HEADERS_FORMAT_VERSION.to_be_bytes()
+                    || version == UNCHAINED_HEADERS_FORMAT_VERSION.to_be_bytes() =>
+            {
+                self.migrate_header_layouts()?;
+                self.put_metadata(FORMAT_VERSION_KEY, &STORAGE_FORMAT_VERSION.to_be_bytes())?;
+                self.backend.flush()?;
+            }
+            Some(version) if version != STORAGE_FORMAT_VERSION.to_be_bytes() => {
+                return Err(StorageError::Corrupted(format!("unsupported format version {:?}", version)));
+            }
+            Some(_) => {}
+            None => self.put_metadata(FORMAT_VERSION_KEY, &STORAGE_FORMAT_VERSION.to_be_bytes())?,
+        }
+
+        let height = match self.latest_height()? {
+            Some(height) => height,
+            None => return Ok(()),
+        };
+        if height < self.prune_horizon()? {
+            // Bootstrapped from a snapshot: only the header is stored.
+            if self.get_header_by_height(height)?.is_none() {
+                return Err(StorageError::Corrupted(format!("header at height {} is missing", height)));
+            }
+            info!("Opened storage at snapshot height {}", height);
+            return Ok(());
+        }
+        let block = self.get_block_by_height(height)?
+            .ok_or_else(|| StorageError::Corrupted(format!("block at height {} is missing", height)))?;
+        if block.height() != height {
+            return Err(StorageError::Corrupted(format!(
+                "block indexed at height {} has height {}", height, block.height()
+            )));
+        }
+        for tx in &block.transactions {
+            if self.get_transaction(&tx.hash())?.is_none() {
+                return Err(StorageError::Corrupted(format!("transaction {} of block {} is missing", tx.hash(), height)));
+            }
+        }
+        info!("Opened storage at height {}", height);
+        Ok(())
+    }
+
+    /// Rewrites blocks and headers stored in older header layouts in
+    /// today's. They keep their versions and hashes, so nothing keyed or
+    /// certified by hash changes, and a migration cut short by a crash
+    /// picks up where it stopped.
+    fn migrate_header_layouts(&self) -> Result<(), StorageError> {
+        let mut migrated = 0;
+        for column in [Column::Blocks, Column::Headers] {
+            let mut from = Vec::new();
+            loop {
+                let entries = self.backend.scan_limit(column, &from, &[u8::MAX; 33], MIGRATION_BATCH, false)?;
+                let last = match entries.last() {
+                    Some((key, _)) => key.clone(),
+                    None => break,
+                };
+                let mut batch = WriteBatch::default();
+                for (key, value) in entries {
+                    if let Some(upgraded) = upgrade_header_layout(column, &hash_from(&key)?, &value)? {
+                        batch.put(column, key, upgraded);
+                        migrated += 1;
+                    }
+                }
+                self.backend.write(batch)?;
+                from = [last, vec![0]].concat();
+            }
+        }
+        self.backend.flush()?;
+        info!("Migrated {} stored blocks and headers to the version {} header layout", migrated, BLOCK_VERSION);
+        Ok(())
+    }
+
+    /// Writes `block`, its transactions and every index in one atomic batch.
+    pub fn put_block(&self, block: &Block) -> Result<(), StorageError> {
+        self.commit(block, Self::block_batch(block)?)
+    }
+
+    /// Like `put_block`, also storing the certificate and advancing the
+    /// finalized height in the same batch.
+    pub fn put_finalized_block(&self, block: &Block, certificate: &CommitCertificate) -> Result<(), StorageError> {
+        let height = block.height();
+        let mut batch = Self::block_batch(block)?;
+        batch.put(Column::Certificates, height.to_be_bytes(), certificate.try_to_vec()?);
+        if self.finalized_height()?.map_or(true, |finalized| height > finalized) {
+            batch.put(Column::Metadata, FINALIZED_HEIGHT_KEY.as_bytes(), height.to_be_bytes());
+        }
+        self.commit(block, batch)
+    }
+
+    /// Applies a block's batch behind a synced intent, so a crash at any
+    /// point leaves either the whole batch or none of it after recovery.
+    fn commit(&self, block: &Block, mut batch: WriteBatch) -> Result<(), StorageError> {
+        let mut wal = self.wal.lock();
+        // Also under the log lock, so concurrent commits count on each other.
+        let mut stats = StatsUpdate::new(|key: &[u8]| self.backend.get(Column::Stats, key));
+        stats.apply(block)?;
+        for (key, value) in stats.finish().0 {
+            batch.put(Column::Stats, key, value);
+        }
+        // Under the log lock, so a reindex swapping its indexes in cannot
+        // miss this block or be left with its staged entries.
+        if let Some(kinds) = self.reindexing.read().as_ref() {
+            for kind in kinds {
+                for (key, value) in index_entries(block, *kind)? {
+                    batch.put(Column::Reindex, staged_key(*kind, &key), value);
+                }
+            }
+        }
+        #[cfg(test)]
+        if *self.failpoint.lock() == Some(Failpoint::TornIntent) {
+            wal.begin_torn(&block.hash(), block.height(), &batch)?;
+            return Err(StorageError::Backend("failpoint: torn intent".to_string()));
+        }
+        let seq = wal.begin(&block.hash(), block.height(), &batch)?;
+        #[cfg(test)]
+        self.fail_at(Failpoint::AfterIntent)?;
+        self.backend.write(batch)?;
+        self.backend.flush()?;
+        #[cfg(test)]
+        self.fail_at(Failpoint::AfterApply)?;
+        wal.complete(seq)?;
+        wal.checkpoint(false)
+    }
+
+    #[cfg(test)]
+    fn fail_at(&self, failpoint: Failpoint) -> Result<(), StorageError> {
+        if *self.failpoint.lock() == Some(failpoint) {
+            return Err(StorageError::Backend(format!("failpoint: {:?}", failpoint)));
+        }
+        Ok(())
+    }
+
+    fn block_batch(block: &Block) -> Result<WriteBatch, StorageError> {
+        let hash = block.hash();
+        let height = block.height();
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Blocks, hash.as_ref(), block.try_to_vec()?);
+        batch.put(Column::Headers, hash.as_ref(), block.header.try_to_vec()?);
+        batch.put(Column::BlockHashes, height.to_be_bytes(), hash.as_ref());
+        for kind in IndexKind::ALL {
+            for (key, value) in index_entries(block, kind)? {
+                batch.put(kind.column(), key, value);
+            }
+        }
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if let TxKind::ParamChange { change, approvals } = &transaction.kind {
+                let key = encode_position(height, index as u32);
+                batch.put(Column::ParamChanges, key, (change.clone(), approvals.clone()).try_to_vec()?);
+            }
+        }
+        Ok(batch)
+    }
+
+    pub fn get_block(&self, hash: &Hash) -> Result<Option<Block>, StorageError> {
+        match self.backend.get(Column::Blocks, hash.as_ref())? {
+            Some(bytes) => {
+                let block = Block::try_from_slice(&bytes)?;
+                if block.hash() != *hash {
+                    return Err(StorageError::Corrupted(format!("block stored under {} hashes to {}", hash, block.hash())));
+                }
+                Ok(Some(block))
+            }
+            None => match self.get_header(hash)? {
+                Some(header) => Err(StorageError::Pruned { height: header.height, horizon: self.prune_horizon()? }),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Headers outlive pruning, so this answers for every stored block.
+    pub fn get_header(&self, hash: &Hash) -> Result<Option<BlockHeader>, StorageError> {
+        self.backend.get(Column::Headers, hash.as_ref())?
+            .map(|bytes| BlockHeader::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    pub fn get_header_by_height(&self, height: u64) -> Result<Option<BlockHeader>, StorageError> {
+        match self.block_hash_at(height)? {
+            Some(hash) => self.get_header(&hash),
+            None => Ok(None),
+        }
+    }
+
+    pub fn block_hash_at(&self, height: u64) -> Result<Option<Hash>, StorageError> {
+        self.backend.get(Column::BlockHashes, &height.to_be_bytes())?
+            .map(|bytes| hash_from(&bytes))
+            .transpose()
+    }
+
+    pub fn get_block_by_height(&self, height: u64) -> Result<Option<Block>, StorageError> {
+        let horizon = self.prune_horizon()?;
+        if height < horizon {
+            return Err(StorageError::Pruned { height, horizon });
+        }
+        match self.block_hash_at(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_certificate(&self, height: u64) -> Result<Option<CommitCertificate>, StorageError> {
+        self.backend.get(Column::Certificates, &height.to_be_bytes())?
+            .map(|bytes| CommitCertificate::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    pub fn latest_height(&self) -> Result<Option<u64>, StorageError> {
+        match self.backend.last(Column::BlockHashes)? {
+            Some((key, _)) => {
+                let bytes = <[u8; 8]>::try_from(key.as_slice())
+                    .map_err(|_| StorageError::Corrupted("malformed height key".to_string()))?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_transaction(&self, hash: &Hash) -> Result<Option<StoredTransaction>, StorageError> {
+        self.backend.get(Column::Transactions, hash.as_ref())?
+            .map(|bytes| StoredTransaction::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    /// Proves that the stored transaction `tx_hash` is in its block, with
+    /// the header the proof is against. `None` if the transaction is not
+    /// stored, or its block is from before roots were Merkle roots.
+    pub fn tx_inclusion_proof(&self, tx_hash: &Hash) -> Result<Option<(BlockHeader, MerkleProof)>, StorageError> {
+        let stored = match self.get_transaction(tx_hash)? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let block = self.get_block_by_height(stored.height)?.ok_or_else(|| {
+            StorageError::Corrupted(format!("block {} of transaction {} is missing", stored.height, tx_hash))
+        })?;
+        Ok(block.inclusion_proof(stored.index as usize).map(|proof| (block.header, proof)))
+    }
+
+    /// Stores the receipts of a block's executed transactions.
+    pub fn put_receipts(&self, receipts: &[Receipt]) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        for receipt in receipts {
+            batch.put(Column::Receipts, receipt.tx_hash.as_ref(), receipt.try_to_vec()?);
+        }
+        self.backend.write(batch)
+    }
+
+    /// `None` until the transaction's block is executed, and for one never
+    /// included.
+    pub fn get_receipt(&self, hash: &Hash) -> Result<Option<Receipt>, StorageError> {
+        self.backend.get(Column::Receipts, hash.as_ref())?
+            .map(|bytes| Receipt::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    pub fn put_handover(&self, handover: &KeyHandover) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Handovers, handover.old_pub.as_ref(), handover.try_to_vec()?);
+        self.backend.write(batch)
+    }
+
+    /// Every handover stored, in key order.
+    pub fn handovers(&self) -> Result<Vec<KeyHandover>, StorageError> {
+        self.backend.scan(Column::Handovers, &[], &[u8::MAX; 33])?
+            .into_iter()
+            .map(|(_, value)| KeyHandover::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Transactions sent or received by `address` in blocks at `heights`,
+    /// oldest first. Pruned blocks whose address filters rule `address`
+    /// out are skipped; fails if any other of `heights` was pruned.
+    pub fn txs_for_address(&self, address: &Pubkey, heights: Range<u64>) -> Result<Vec<StoredTransaction>, StorageError> {
+        if heights.is_empty() {
+            return Ok(Vec::new());
+        }
+        let horizon = self.prune_horizon()?;
+        if heights.start < horizon {
+            let pruned = heights.start..heights.end.min(horizon);
+            if let Some(&height) = self.address_activity(&DADBSAddress::from_pubkey(address), pruned)?.first() {
+                return Err(StorageError::Pruned { height, horizon });
+            }
+        }
+        let from = address_key(address, heights.start.max(horizon), 0);
+        let to = address_key(address, heights.end, 0);
+        let mut transactions = Vec::new();
+        for (_, tx_hash) in self.backend.scan(Column::AddressIndex, &from, &to)? {
+            let hash = hash_from(&tx_hash)?;
+            let stored = self.get_transaction(&hash)?
+                .ok_or_else(|| StorageError::Corrupted(format!("indexed transaction {} is missing", hash)))?;
+            transactions.push(stored);
+        }
+        Ok(transactions)
+    }
+
+    /// Heights in `heights` whose blocks may involve `address`, by their
+    /// headers' address filters; these survive pruning. Blocks without a
+    /// filter or a stored header are included, heights past the tip not.
+    pub fn address_activity(&self, address: &DADBSAddress, heights: Range<u64>) -> Result<Vec<u64>, StorageError> {
+        let end = match self.latest_height()? {
+            Some(latest) => heights.end.min(latest + 1),
+            None => return Ok(Vec::new()),
+        };
+        let mut active = Vec::new();
+        for height in heights.start..end {
+            if self.get_header_by_height(height)?.map_or(true, |header| header.may_touch(address)) {
+                active.push(height);
+            }
+        }
+        Ok(active)
+    }
+
+    /// Up to `limit` blocks starting at `from_height` and counting up or
+    /// down. Pruned bodies are skipped; a listing going down ends at the
+    /// prune horizon.
+    pub fn blocks_range(&self, from_height: u64, limit: usize, direction: Direction) -> Result<Page<Block>, StorageError> {
+        let limit = pagination::clamp_limit(limit);
+        let horizon = self.prune_horizon()?;
+        let (from, to) = match direction {
+            Direction::Ascending => (from_height.max(horizon).to_be_bytes().to_vec(), vec![u8::MAX; 9]),
+            Direction::Descending => (horizon.to_be_bytes().to_vec(), height_bound(from_height)),
+        };
+        let reverse = direction == Direction::Descending;
+        let mut entries = self.backend.scan_limit(Column::BlockHashes, &from, &to, limit + 1, reverse)?;
+        let next_cursor = if entries.len() > limit {
+            entries.pop().map(|(key, _)| self.cursors.encode(BLOCKS_SCOPE, direction, &key))
+        } else {
+            None
+        };
+        let items = entries.iter()
+            .map(|(_, hash)| {
+                let hash = hash_from(hash)?;
+                self.get_block(&hash)?
+                    .ok_or_else(|| StorageError::Corrupted(format!("indexed block {} is missing", hash)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Page { items, next_cursor })
+    }
+
+    /// The page of blocks after one returned with `cursor`, listed in the
+    /// same `direction`.
+    pub fn resume_blocks(&self, cursor: &str, limit: usize, direction: Direction) -> Result<Page<Block>, StorageError> {
+        let position = self.cursors.decode(BLOCKS_SCOPE, direction, cursor)?;
+        let height = <[u8; 8]>::try_from(position.as_slice()).map_err(|_| CursorError::Malformed)?;
+        self.blocks_range(u64::from_be_bytes(height), limit, direction)
+    }
+
+    /// Transactions sent or received by `address`, oldest or newest first,
+    /// resuming from `cursor` if given. Ends at the prune horizon.
+    pub fn transactions_by_address(
+        &self,
+        address: &Pubkey,
+        cursor: Option<&str>,
+        limit: usize,
+        direction: Direction,
+    ) -> Result<Page<StoredTransaction>, StorageError> {
+        let limit = pagination::clamp_limit(limit);
+        let scope = address_scope(address);
+        let mut from = address_key(address, self.prune_horizon()?, 0);
+        let mut to = [address_key(address, u64::MAX, u32::MAX), vec![u8::MAX]].concat();
+        if let Some(cursor) = cursor {
+            let (height, index) = decode_position(&self.cursors.decode(&scope, direction, cursor)?)?;
+            let key = address_key(address, height, index);
+            match direction {
+                Direction::Ascending => from = from.max(key),
+                Direction::Descending => to = [key, vec![0]].concat(),
+            }
+        }
+        let reverse = direction == Direction::Descending;
+        let mut entries = self.backend.scan_limit(Column::AddressIndex, &from, &to, limit + 1, reverse)?;
+        let next_cursor = if entries.len() > limit {
+            entries.pop().map(|(key, _)| self.cursors.encode(&scope, direction, &key[32..]))
+        } else {
+            None
+        };
+        let items = entries.iter()
+            .map(|(_, tx_hash)| {
+                let hash = hash_from(tx_hash)?;
+                self.get_transaction(&hash)?
+                    .ok_or_else(|| StorageError::Corrupted(format!("indexed transaction {} is missing", hash)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Page { items, next_cursor })
+    }
+
+    /// Transactions in stored blocks, newest first, resuming from `cursor`
+    /// if given. A page may come back short, with a cursor, after reading
+    /// `MAX_SCANNED_BLOCKS` blocks.
+    pub fn recent_transactions(&self, cursor: Option<&str>, limit: usize) -> Result<Page<StoredTransaction>, StorageError> {
+        let limit = pagination::clamp_limit(limit);
+        let horizon = self.prune_horizon()?;
+        let (mut height, mut last_index) = match cursor {
+            Some(cursor) => decode_position(&self.cursors.decode(RECENT_SCOPE, Direction::Descending, cursor)?)?,
+            None => match self.latest_height()? {
+                Some(height) => (height, u32::MAX),
+                None => return Ok(Page { items: Vec::new(), next_cursor: None }),
+            },
+        };
+        let resume = |height, index| Some(self.cursors.encode(RECENT_SCOPE, Direction::Descending, &encode_position(height, index)));
+        let mut items = Vec::new();
+        for scanned in 0..=MAX_SCANNED_BLOCKS {
+            if height < horizon {
+                break;
+            }
+            if scanned == MAX_SCANNED_BLOCKS {
+                return Ok(Page { items, next_cursor: resume(height, u32::MAX) });
+            }
+            if let Some(block) = self.get_block_by_height(height)? {
+                for (index, transaction) in block.transactions.into_iter().enumerate().rev() {
+                    let index = index as u32;
+                    if index > last_index {
+                        continue;
+                    }
+                    if items.len() == limit {
+                        return Ok(Page { items, next_cursor: resume(height, index) });
+                    }
+                    items.push(StoredTransaction { height, index, transaction });
+                }
+            }
+            match height.checked_sub(1) {
+                Some(lower) => (height, last_index) = (lower, u32::MAX),
+                None => break,
+            }
+        }
+        Ok(Page { items, next_cursor: None })
+    }
+
+    /// Daily aggregates of the days overlapping `from_ms..to_ms`, oldest
+    /// first. Days without a counted block are left out.
+    pub fn chain_stats(&self, from_ms: i64, to_ms: i64) -> Result<Vec<DayStats>, StorageError> {
+        let from_ms = from_ms.max(0);
+        if from_ms >= to_ms {
+            return Ok(Vec::new());
+        }
+        let from = chain_stats::day_key(from_ms.div_euclid(DAY_MS));
+        let to = chain_stats::day_key((to_ms - 1).div_euclid(DAY_MS) + 1);
+        self.backend.scan(Column::Stats, &from, &to)?
+            .into_iter()
+            .map(|(_, value)| DayStats::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Totals over the transactions `address` sent or received, if any were
+    /// counted.
+    pub fn address_stats(&self, address: &Pubkey) -> Result<Option<AddressStats>, StorageError> {
+        self.backend.get(Column::Stats, &chain_stats::address_key(address))?
+            .map(|bytes| AddressStats::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    /// Appends to the audit trail, returning the record's sequence number.
+    pub fn append_audit(&self, record: &AuditRecord) -> Result<u64, StorageError> {
+        let _guard = self.audit.lock();
+        let seq = match self.backend.last(Column::Audit)? {
+            Some((key, _)) => u64::from_be_bytes(
+                <[u8; 8]>::try_from(key.as_slice())
+                    .map_err(|_| StorageError::Corrupted("malformed audit key".to_string()))?,
+            ) + 1,
+            None => 0,
+        };
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Audit, seq.to_be_bytes(), serde_json::to_vec(record).map_err(std::io::Error::from)?);
+        self.backend.write(batch)?;
+        Ok(seq)
+    }
+
+    /// The whole audit trail, oldest first.
+    pub fn audit_log(&self) -> Result<Vec<AuditRecord>, StorageError> {
+        self.backend.scan(Column::Audit, &0u64.to_be_bytes(), &u64::MAX.to_be_bytes())?
+            .into_iter()
+            .map(|(_, value)| serde_json::from_slice(&value).map_err(|e| std::io::Error::from(e).into()))
+            .collect()
+    }
+
+    /// What the faucet has granted `recipient`, `ip` and everyone on `day`.
+    pub fn faucet_usage(&self, recipient: &Pubkey, ip: IpAddr, day: i64) -> Result<FaucetUsage, StorageError> {
+        let granted = |key: Vec<u8>| -> Result<Option<i64>, StorageError> {
+            self.backend.get(Column::Faucet, &key)?.map(|bytes| i64::try_from_slice(&bytes).map_err(StorageError::from)).transpose()
+        };
+        let spent_today = self.backend.get(Column::Faucet, &faucet::day_key(day))?
+            .map(|bytes| u64::try_from_slice(&bytes))
+            .transpose()?
+            .unwrap_or(0);
+        Ok(FaucetUsage {
+            address_granted_ms: granted(faucet::address_key(recipient))?,
+            ip_granted_ms: granted(faucet::ip_key(ip))?,
+            spent_today,
+        })
+    }
+
+    /// Records a grant made to a request from `ip`: its cooldowns, the
+    /// day's spending and the grant log, together.
+    pub fn put_faucet_grant(&self, ip: IpAddr, grant: &FaucetGrant) -> Result<(), StorageError> {
+        let day = faucet::day_of(grant.granted_ms);
+        let spent = self.faucet_usage(&grant.recipient, ip, day)?.spent_today.saturating_add(grant.amount);
+        let granted = grant.granted_ms.try_to_vec()?;
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Faucet, faucet::address_key(&grant.recipient), granted.clone());
+        batch.put(Column::Faucet, faucet::ip_key(ip), granted);
+        batch.put(Column::Faucet, faucet::day_key(day), spent.try_to_vec()?);
+        batch.put(Column::Faucet, faucet::grant_key(grant), grant.try_to_vec()?);
+        self.backend.write(batch)
+    }
+
+    /// The most recent `limit` faucet grants, newest first.
+    pub fn faucet_grants(&self, limit: usize) -> Result<Vec<FaucetGrant>, StorageError> {
+        let (from, to) = faucet::grant_range();
+        self.backend.scan_limit(Column::Faucet, &from, &to, limit, true)?
+            .into_iter()
+            .map(|(_, value)| FaucetGrant::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    pub fn put_api_key(&self, record: &ApiKeyRecord) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.put(Column::ApiKeys, api_keys::record_key(&record.id), record.try_to_vec()?);
+        self.backend.write(batch)
+    }
+
+    pub fn get_api_key(&self, id: &str) -> Result<Option<ApiKeyRecord>, StorageError> {
+        self.backend.get(Column::ApiKeys, &api_keys::record_key(id))?
+            .map(|bytes| ApiKeyRecord::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    /// Every API key, revoked ones included, by id.
+    pub fn api_keys(&self) -> Result<Vec<ApiKeyRecord>, StorageError> {
+        let (from, to) = api_keys::record_range();
+        self.backend.scan(Column::ApiKeys, &from, &to)?
+            .into_iter()
+            .map(|(_, value)| ApiKeyRecord::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Key `id`'s usage on `day`; nothing if it was not used.
+    pub fn api_key_usage(&self, id: &str, day: i64) -> Result<ApiKeyUsage, StorageError> {
+        let usage = self.backend.get(Column::ApiKeys, &api_keys::usage_key(id, day))?
+            .map(|bytes| ApiKeyUsage::try_from_slice(&bytes))
+            .transpose()?;
+        Ok(usage.unwrap_or(ApiKeyUsage { day, ..ApiKeyUsage::default() }))
+    }
+
+    pub fn put_api_key_usage(&self, id: &str, usage: &ApiKeyUsage) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.put(Column::ApiKeys, api_keys::usage_key(id, usage.day), usage.try_to_vec()?);
+        self.backend.write(batch)
+    }
+
+    pub fn put_webhook_endpoint(&self, endpoint: &WebhookEndpoint) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Webhooks, webhooks::endpoint_key(&endpoint.id), endpoint.try_to_vec()?);
+        self.backend.write(batch)
+    }
+
+    pub fn delete_webhook_endpoint(&self, id: &str) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.delete(Column::Webhooks, webhooks::endpoint_key(id));
+        self.backend.write(batch)
+    }
+
+    /// Every stored webhook endpoint, by id.
+    pub fn webhook_endpoints(&self) -> Result<Vec<WebhookEndpoint>, StorageError> {
+        let (from, to) = webhooks::endpoint_range();
+        self.backend.scan(Column::Webhooks, &from, &to)?
+            .into_iter()
+            .map(|(_, value)| WebhookEndpoint::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    pub fn put_dead_letter(&self, letter: &DeadLetter) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Webhooks, webhooks::dead_letter_key(letter), letter.try_to_vec()?);
+        self.backend.write(batch)
+    }
+
+    /// The most recent `limit` webhook deliveries given up on, newest first.
+    pub fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, StorageError> {
+        let (from, to) = webhooks::dead_letter_range();
+        self.backend.scan_limit(Column::Webhooks, &from, &to, limit, true)?
+            .into_iter()
+            .map(|(_, value)| DeadLetter::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Replaces the saved pending transactions with `transactions`.
+    pub fn save_mempool(&self, transactions: &[Transaction]) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        for (key, _) in self.backend.scan(Column::Mempool, &[], &[u8::MAX; 33])? {
+            batch.delete(Column::Mempool, key);
+        }
+        for transaction in transactions {
+            batch.put(Column::Mempool, transaction.hash().as_ref(), transaction.try_to_vec()?);
+        }
+        self.backend.write(batch)
+    }
+
+    /// The pending transactions last saved, by hash.
+    pub fn saved_mempool(&self) -> Result<Vec<Transaction>, StorageError> {
+        self.backend.scan(Column::Mempool, &[], &[u8::MAX; 33])?
+            .into_iter()
+            .map(|(_, value)| Transaction::try_from_slice(&value).map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Every parameter change in a stored block with its height, oldest first.
+    pub fn param_changes(&self) -> Result<Vec<(u64, ParamChange, CommitCertificate)>, StorageError> {
+        self.backend.scan(Column::ParamChanges, &[], &[u8::MAX; 12])?
+            .into_iter()
+            .map(|(key, value)| {
+                let height = key.get(..8)
+                    .and_then(|bytes| <[u8; 8]>::try_from(bytes).ok())
+                    .map(u64::from_be_bytes)
+                    .ok_or_else(|| StorageError::Corrupted("malformed parameter change key".to_string()))?;
+                let (change, approvals) = <(ParamChange, CommitCertificate)>::try_from_slice(&value)?;
+                Ok((height, change, approvals))
+            })
+            .collect()
+    }
+
+    pub fn put_metadata(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Metadata, key.as_bytes(), value);
+        self.backend.write(batch)
+    }
+
+    pub fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.backend.get(Column::Metadata, key.as_bytes())
+    }
+
+    fn height_metadata(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        match self.get_metadata(key)? {
+            Some(bytes) => {
+                let bytes = <[u8; 8]>::try_from(bytes.as_slice())
+                    .map_err(|_| StorageError::Corrupted(format!("malformed {} metadata", key)))?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn finalized_height(&self) -> Result<Option<u64>, StorageError> {
+        self.height_metadata(FINALIZED_HEIGHT_KEY)
+    }
+
+    /// Heights below this have had their bodies pruned.
+    pub fn prune_horizon(&self) -> Result<u64, StorageError> {
+        Ok(self.height_metadata(PRUNE_HORIZON_KEY)?.unwrap_or(0))
+    }
+
+    /// Prunes at most `max_blocks` finalized block bodies, with their
+    /// transactions and address index entries, until only the newest
+    /// `keep_blocks` finalized bodies remain. Headers and certificates are
+    /// kept. Returns the number of heights pruned; once caught up, further
+    /// calls do nothing.
+    pub fn prune_step(&self, keep_blocks: u64, max_blocks: u64) -> Result<u64, StorageError> {
+        // Pruning under a reindex would leave it entries for dropped bodies.
+        if self.reindexing.read().is_some() {
+            return Ok(0);
+        }
+        let finalized = match self.finalized_height()? {
+            Some(finalized) => finalized,
+            None => return Ok(0),
+        };
+        let horizon = self.prune_horizon()?;
+        let target = (finalized + 1).saturating_sub(keep_blocks.max(1));
+        if horizon >= target {
+            return Ok(0);
+        }
+        let end = target.min(horizon.saturating_add(max_blocks.max(1)));
+
+        let mut batch = WriteBatch::default();
+        for height in horizon..end {
+            let hash = match self.block_hash_at(height)? {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let bytes = match self.backend.get(Column::Blocks, hash.as_ref())? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let block = Block::try_from_slice(&bytes)?;
+            for (index, transaction) in block.transactions.iter().enumerate() {
+                let index = index as u32;
+                batch.delete(Column::Transactions, transaction.hash().as_ref());
+                batch.delete(Column::Receipts, transaction.hash().as_ref());
+                batch.delete(Column::AddressIndex, address_key(&transaction.sender, height, index));
+                batch.delete(Column::AddressIndex, address_key(&transaction.recipient, height, index));
+            }
+            batch.delete(Column::Blocks, hash.as_ref());
+        }
+        batch.put(Column::Metadata, PRUNE_HORIZON_KEY.as_bytes(), end.to_be_bytes());
+        self.backend.write(batch)?;
+        debug!("Pruned block bodies below height {}", end);
+        Ok(end - horizon)
+    }
+
+    /// Prunes a batch every `prune_interval_ms` until `cancel` fires. Does
+    /// nothing unless `prune_keep_blocks` is set.
+    pub async fn run_pruner(&self, config: StorageConfig, cancel: CancellationToken) {
+        let keep_blocks = match config.prune_keep_blocks {
+            Some(keep_blocks) => keep_blocks,
+            None => return,
+        };
+        let mut ticker = tokio::time::interval(Duration::from_millis(config.prune_interval_ms.max(1)));
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+            if let Err(e) = self.prune_step(keep_blocks, config.prune_batch_blocks) {
+                error!("Failed to prune storage: {}", e);
+            }
+        }
+    }
+
+    /// Rebuilds the `kinds` indexes from the stored block bodies. Entries
+    /// are staged in a separate column, each batch with a checkpoint so an
+    /// interrupted reindex of the same indexes resumes where it stopped.
+    /// Blocks committed meanwhile are staged too, so the node may keep
+    /// running. Once the staged entry counts match those derived from the
+    /// blocks, the rebuilt indexes replace the old ones in one batch.
+    pub fn reindex(&self, kinds: &[IndexKind], progress: impl Fn(ReindexProgress)) -> Result<ReindexReport, StorageError> {
+        let _running = self.reindex.try_lock().ok_or(StorageError::ReindexInProgress)?;
+        let mut kinds = kinds.to_vec();
+        kinds.sort();
+        kinds.dedup();
+        let horizon = self.prune_horizon()?;
+        let (mut checkpoint, resumed) = match self.reindex_checkpoint()? {
+            Some(checkpoint) if checkpoint.kinds == kinds && checkpoint.from_height == horizon => {
+                info!("Resuming reindex of {:?} at height {}", kinds, checkpoint.next_height);
+                (checkpoint, true)
+            }
+            stale => {
+                if stale.is_some() {
+                    warn!("Discarding the checkpoint of an earlier reindex that no longer applies");
+                }
+                let entries = vec![0; kinds.len()];
+                (ReindexCheckpoint { kinds: kinds.clone(), from_height: horizon, next_height: horizon, entries }, false)
+            }
+        };
+        if !resumed {
+            self.clear_staging()?;
+        }
+
+        *self.reindexing.write() = Some(kinds.clone());
+        let result = self.rebuild(&mut checkpoint, &progress);
+        *self.reindexing.write() = None;
+        let tip = result?;
+        if let Some(tip) = tip {
+            progress(ReindexProgress {
+                from_height: checkpoint.from_height,
+                height: tip,
+                tip,
+                entries: checkpoint.entries.iter().sum(),
+            });
+        }
+        info!("Reindexed {:?} from height {}: {:?} entries", kinds, horizon, checkpoint.entries);
+        Ok(ReindexReport { kinds, from_height: horizon, tip, entries: checkpoint.entries, resumed })
+    }
+
+    /// Stages every block from the checkpoint on, then swaps the staged
+    /// indexes in. Returns the highest height rebuilt.
+    fn rebuild(&self, checkpoint: &mut ReindexCheckpoint, progress: &impl Fn(ReindexProgress)) -> Result<Option<u64>, StorageError> {
+        while let Some(tip) = self.stage_blocks(checkpoint)? {
+            progress(ReindexProgress {
+                from_height: checkpoint.from_height,
+                height: checkpoint.next_height - 1,
+                tip,
+                entries: checkpoint.entries.iter().sum(),
+            });
+        }
+        #[cfg(test)]
+        self.fail_at(Failpoint::BeforeSwap)?;
+
+        // No block commits from here until the swap is written.
+        let _wal = self.wal.lock();
+        while self.stage_blocks(checkpoint)?.is_some() {}
+        let tip = self.latest_height()?.filter(|tip| *tip >= checkpoint.from_height);
+
+        let mut swap = WriteBatch::default();
+        for (kind, expected) in checkpoint.kinds.iter().zip(&checkpoint.entries) {
+            let prefix = kind.staging_prefix();
+            let staged = self.backend.scan(Column::Reindex, &[prefix], &[prefix + 1])?;
+            if staged.len() as u64 != *expected {
+                self.clear_staging()?;
+                return Err(StorageError::Corrupted(format!(
+                    "rebuilt {} index has {} entries, but the stored blocks give {}", kind, staged.len(), expected
+                )));
+            }
+            let column = kind.column();
+            for (key, _) in self.backend.scan(column, &[], &[u8::MAX; 64])? {
+                swap.delete(column, key);
+            }
+            for (key, value) in staged {
+                swap.put(column, key[1..].to_vec(), value);
+                swap.delete(Column::Reindex, key);
+            }
+        }
+        swap.delete(Column::Metadata, REINDEX_CHECKPOINT_KEY.as_bytes());
+        self.backend.write(swap)?;
+        self.backend.flush()?;
+        Ok(tip)
+    }
+
+    /// Stages the next batch of blocks with an updated checkpoint. Returns
+    /// the tip, or `None` once every stored block is staged.
+    fn stage_blocks(&self, checkpoint: &mut ReindexCheckpoint) -> Result<Option<u64>, StorageError> {
+        let tip = match self.latest_height()? {
+            Some(tip) if tip >= checkpoint.next_height => tip,
+            _ => return Ok(None),
+        };
+        let end = tip.min(checkpoint.next_height.saturating_add(REINDEX_BATCH_BLOCKS - 1));
+        let mut batch = WriteBatch::default();
+        // Staged aggregates build on those of the batches before; blocks
+        // committed meanwhile are only counted here.
+        let stats_kind = checkpoint.kinds.iter().position(|kind| *kind == IndexKind::ChainStats);
+        let mut stats = stats_kind.map(|_| {
+            StatsUpdate::new(|key: &[u8]| self.backend.get(Column::Reindex, &staged_key(IndexKind::ChainStats, key)))
+        });
+        for height in checkpoint.next_height..=end {
+            let block = match self.get_block_by_height(height)? {
+                Some(block) => block,
+                None => continue,
+            };
+            for (kind, count) in checkpoint.kinds.iter().zip(checkpoint.entries.iter_mut()) {
+                for (key, value) in index_entries(&block, *kind)? {
+                    batch.put(Column::Reindex, staged_key(*kind, &key), value);
+                    *count += 1;
+                }
+            }
+            if let Some(stats) = stats.as_mut() {
+                stats.apply(&block)?;
+            }
+        }
+        if let (Some(position), Some(stats)) = (stats_kind, stats) {
+            let (entries, added) = stats.finish();
+            for (key, value) in entries {
+                batch.put(Column::Reindex, staged_key(IndexKind::ChainStats, &key), value);
+            }
+            checkpoint.entries[position] += added;
+        }
+        checkpoint.next_height = end + 1;
+        batch.put(Column::Metadata, REINDEX_CHECKPOINT_KEY.as_bytes(), checkpoint.try_to_vec()?);
+        self.backend.write(batch)?;
+        Ok(Some(tip))
+    }
+
+    fn reindex_checkpoint(&self) -> Result<Option<ReindexCheckpoint>, StorageError> {
+        self.get_metadata(REINDEX_CHECKPOINT_KEY)?
+            .map(|bytes| ReindexCheckpoint::try_from_slice(&bytes).map_err(StorageError::from))
+            .transpose()
+    }
+
+    /// Drops staged entries and the checkpoint of any earlier reindex.
+    fn clear_staging(&self) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        for (key, _) in self.backend.scan(Column::Reindex, &[], &[u8::MAX; 64])? {
+            batch.delete(Column::Reindex, key);
+        }
+        batch.delete(Column::Metadata, REINDEX_CHECKPOINT_KEY.as_bytes());
+        self.backend.write(batch)
+    }
+
+    /// Writes a snapshot of `state`, which must be the state after the
+    /// finalized block at `up_to_height`, with that block's header and
+    /// certificate.
+    pub fn export_snapshot(
+        &self,
+        path: &Path,
+        up_to_height: u64,
+        state: &State,
+        chain_id: &str,
+    ) -> Result<SnapshotManifest, SnapshotError> {
+        if state.height() != up_to_height {
+            return Err(SnapshotError::Invalid(format!(
+                "state is at height {}, not {}", state.height(), up_to_height
+            )));
+        }
+        let header = self.get_header_by_height(up_to_height)?;
+        let certificate = self.get_certificate(up_to_height)?;
+        match (header, certificate) {
+            (Some(header), Some(certificate)) => Snapshot::write(path, chain_id, header, &certificate, state),
+            _ => Err(SnapshotError::NotFinalized(up_to_height)),
+        }
+    }
+
+    /// Verifies the snapshot at `path` against `trust` and installs its
+    /// header and certificate as the finalized tip. Nothing is written
+    /// unless the whole archive verifies. The caller installs the returned
+    /// snapshot's state.
+    pub fn import_snapshot(&self, path: &Path, trust: &SnapshotTrust) -> Result<Snapshot, SnapshotError> {
+        let snapshot = Snapshot::read(path, trust)?;
+        let height = snapshot.height();
+        if let Some(local) = self.finalized_height()? {
+            if local >= height {
+                return Err(SnapshotError::Stale { snapshot: height, local });
+            }
+        }
+
+        let hash = snapshot.manifest.block_hash;
+        let mut batch = WriteBatch::default();
+        batch.put(Column::Headers, hash.as_ref(), snapshot.header.try_to_vec().map_err(StorageError::from)?);
+        batch.put(Column::BlockHashes, height.to_be_bytes(), hash.as_ref());
+        batch.put(Column::Certificates, height.to_be_bytes(), snapshot.certificate.try_to_vec().map_err(StorageError::from)?);
+        batch.put(Column::Metadata, FINALIZED_HEIGHT_KEY.as_bytes(), height.to_be_bytes());
+        batch.put(Column::Metadata, PRUNE_HORIZON_KEY.as_bytes(), (height + 1).to_be_bytes());
+        self.backend.write(batch)?;
+        info!("Imported snapshot at height {} ({})", height, hash);
+        Ok(snapshot)
+    }
+
+    /// Copies the store into `dest`, which must be empty or missing, with
+    /// `state` beside it and a manifest of per-file checksums. The copy is
+    /// as of one finalized height: block commits wait while it is cut,
+    /// which with sled is the whole copy and with RocksDB only while the
+    /// checkpoint is taken. Other writes and reads carry on.
+    pub fn backup(
+        &self,
+        dest: &Path,
+        state: &State,
+        progress: impl Fn(BackupProgress),
+    ) -> Result<BackupManifest, BackupError> {
+        let _running = self.backup.try_lock().ok_or(BackupError::InProgress)?;
+        backup::prepare(dest)?;
+        let (height, block_hash) = {
+            let _wal = self.wal.lock();
+            let height = self.finalized_height()?;
+            let block_hash = height.map(|height| self.block_hash_at(height)).transpose()?.flatten();
+            self.backend.checkpoint(&dest.join(CHAIN_DIR), &|done, total| {
+                progress(BackupProgress { stage: BackupStage::Copying, done, total })
+            })?;
+            (height, block_hash)
+        };
+        let manifest = backup::finish(dest, height, block_hash, state, &progress)?;
+        info!("Backed up storage at height {:?} to {}", height, dest.display());
+        Ok(manifest)
+    }
+
+    /// Replaces the store and state under `storage_path` with the backup in
+    /// `src`. Every file is checked against the manifest, and the copied
+    /// store must open at the manifest's height, before anything is
+    /// replaced. Refused while a node has the store open.
+    pub fn restore(src: &Path, storage_path: &Path) -> Result<BackupManifest, BackupError> {
+        let manifest = backup::verify(src)?;
+        let chain = storage_path.join(CHAIN_DIR);
+        if chain.exists() {
+            // A running node holds the store's lock.
+            let probe = open_backend(&chain);
+            drop(probe.map_err(|e| BackupError::InUse { path: chain.clone(), reason: e.to_string() })?);
+        }
+
+        let staged = storage_path.join(format!("{}.restoring", CHAIN_DIR));
+        if staged.exists() {
+            std::fs::remove_dir_all(&staged)?;
+        }
+        backup::copy_dir(&src.join(CHAIN_DIR), &staged)?;
+        {
+            let restored = Storage::open(&staged)?;
+            let height = restored.finalized_height()?;
+            let block_hash = height.map(|height| restored.block_hash_at(height)).transpose()?.flatten();
+            if (height, block_hash) != (manifest.height, manifest.block_hash) {
+                return Err(BackupError::Invalid(format!(
+                    "restored store is at height {:?}, the manifest says {:?}", height, manifest.height
+                )));
+            }
+            restored.mark_clean_shutdown()?;
+        }
+        let state = storage_path.join(format!("{}.restoring", STATE_FILE));
+        std::fs::copy(src.join(STATE_FILE), &state)?;
+
+        let replaced = storage_path.join(format!("{}.replaced", CHAIN_DIR));
+        if chain.exists() {
+            std::fs::rename(&chain, &replaced)?;
+        }
+        std::fs::rename(&staged, &chain)?;
+        std::fs::rename(&state, storage_path.join(STATE_FILE))?;
+        if replaced.exists() {
+            std::fs::remove_dir_all(&replaced)?;
+        }
+        info!("Restored storage at height {:?} from {}", manifest.height, src.display());
+        Ok(manifest)
+    }
+
+    /// Forces buffered writes to disk. Call before shutting down.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.backend.flush()
+    }
+
+    /// Flushes, then records that this run shut down cleanly. Nothing may
+    /// be written afterwards.
+    pub fn mark_clean_shutdown(&self) -> Result<(), StorageError> {
+        self.backend.flush()?;
+        self.put_metadata(CLEAN_SHUTDOWN_KEY, &[1])?;
+        self.backend.flush()
+    }
+
+    /// Whether the previous run ended with `mark_clean_shutdown`. Always
+    /// true for a new store.
+    pub fn previous_shutdown_clean(&self) -> bool {
+        self.clean_start
+    }
+
+    /// Bytes used by the storage directory, including the intent log.
+    pub fn disk_bytes(&self) -> u64 {
+        dir_bytes(&self.path)
+    }
+}
+
+impl BlockStore for Storage {
+    fn get_finalized(&self, height: u64) -> Option<(Block, CommitCertificate)> {
+        let read = || -> Result<Option<(Block, CommitCertificate)>, StorageError> {
+            match (self.get_block_by_height(height)?, self.get_certificate(height)?) {
+                (Some(block), Some(certificate)) => Ok(Some((block, certificate))),
+                _ => Ok(None),
+            }
+        };
+        match read() {
+            Ok(finalized) => finalized,
+            Err(StorageError::Pruned { .. }) => None,
+            Err(e) => {
+                error!("Failed to read finalized block {}: {}", height, e);
+                None
+            }
+        }
+    }
+
+    fn put_finalized(&self, block: Block, certificate: CommitCertificate) {
+        if let Err(e) = self.put_finalized_block(&block, &certificate) {
+            error!("Failed to store finalized block {}: {}", block.height(), e);
+        }
+    }
+}
+
+fn dir_bytes(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_bytes(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+impl MetricsSource for Storage {
+    fn render_metrics(&self, out: &mut String) {
+        metrics::write_gauge(out, "dadbs_storage_disk_bytes", "Size of the storage directory on disk", self.disk_bytes() as f64);
+        metrics::write_gauge(out, "dadbs_storage_wal_bytes", "Size of the commit intent log", self.wal.lock().len() as f64);
+        if let Ok(horizon) = self.prune_horizon() {
+            metrics::write_gauge(out, "dadbs_storage_prune_horizon", "Lowest height whose block body is stored", horizon as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::bloom::AddressBloom;
+    use solana_sdk::signature::{Keypair, Signer};
+
+    #[test]
+    fn test_corrupted_tip_detected_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = Keypair::new();
+        let tx = Transaction::new_signed(&keypair, Pubkey::new_unique(), 5, 1, 0, 0);
+        let block = Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![tx.clone()]);
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            storage.put_block(&block).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.delete(Column::Transactions, tx.hash().as_ref());
+            storage.backend.write(batch).unwrap();
+            storage.flush().unwrap();
+        }
+        assert!(matches!(Storage::open(dir.path()), Err(StorageError::Corrupted(_))));
+    }
+
+    fn crash_during_commit(failpoint: Failpoint) -> (tempfile::TempDir, Block) {
+        let dir = tempfile::tempdir().unwrap();
+        let tx = Transaction::new_signed(&Keypair::new(), Pubkey::new_unique(), 5, 1, 0, 0);
+        let first = Block::new(1, Hash::default(), 0, Pubkey::default());
+        let second = Block::with_transactions(2, first.hash(), 1, Pubkey::default(), vec![tx]);
+        let storage = Storage::open(dir.path()).unwrap();
+        storage.put_block(&first).unwrap();
+        *storage.failpoint.lock() = Some(failpoint);
+        assert!(storage.put_block(&second).is_err());
+        drop(storage);
+        (dir, second)
+    }
+
+    #[test]
+    fn test_interrupted_commits_recover() {
+        // Synced intents are rolled forward whether or not the batch landed.
+        for failpoint in [Failpoint::AfterIntent, Failpoint::AfterApply] {
+            let (dir, block) = crash_during_commit(failpoint);
+            let storage = Storage::open(dir.path()).unwrap();
+            assert_eq!(storage.latest_height().unwrap(), Some(2), "{:?}", failpoint);
+            assert_eq!(storage.get_block_by_height(2).unwrap(), Some(block.clone()));
+            assert!(storage.get_transaction(&block.transactions[0].hash()).unwrap().is_some());
+            assert_eq!(storage.wal.lock().len(), 0);
+        }
+
+        // A torn intent is discarded and the store stays at the last commit.
+        let (dir, block) = crash_during_commit(Failpoint::TornIntent);
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.latest_height().unwrap(), Some(1));
+        assert_eq!(storage.get_block(&block.hash()).unwrap(), None);
+        storage.put_block(&block).unwrap();
+        assert_eq!(storage.latest_height().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_wal_truncated_at_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::open_with_wal_limit(dir.path(), 1024).unwrap();
+        let mut parent = Hash::default();
+        for height in 1..=50 {
+            let block = Block::new(height, parent, height as i64, Pubkey::default());
+            storage.put_block(&block).unwrap();
+            parent = block.hash();
+            assert!(storage.wal.lock().len() <= 1024);
+        }
+        assert_eq!(storage.latest_height().unwrap(), Some(50));
+    }
+
+    #[test]
+    fn test_metadata_and_format_version() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            storage.put_metadata("finalized_height", &7u64.to_be_bytes()).unwrap();
+            storage.flush().unwrap();
+        }
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.get_metadata("finalized_height").unwrap(), Some(7u64.to_be_bytes().to_vec()));
+        assert_eq!(storage.latest_height().unwrap(), None);
+
+        storage.put_metadata(FORMAT_VERSION_KEY, &99u32.to_be_bytes()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        assert!(matches!(Storage::open(dir.path()), Err(StorageError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_unversioned_headers_migrated_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = Keypair::new();
+        let old_tx = Transaction::new_signed(&keypair, Pubkey::new_unique(), 5, 1, 0, 0);
+        let mut old =
+            Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![old_tx.clone()]).with_version(0);
+        old.header.transactions_root = solana_sdk::hash::hashv(&[old_tx.hash().as_ref()]);
+        let certificate = CommitCertificate::new(1, old.hash(), Vec::new());
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            storage.put_finalized_block(&old, &certificate).unwrap();
+            // The old layout: no version in front, no next validators hash,
+            // address filter or chain behind.
+            let header = old.header.try_to_vec().unwrap();
+            let chain = String::new().try_to_vec().unwrap().len();
+            let behind = 32 + AddressBloom::default().try_to_vec().unwrap().len() + chain;
+            let header = &header[4..header.len() - behind];
+            let mut batch = WriteBatch::default();
+            batch.put(Column::Headers, old.hash().as_ref(), header);
+            let transactions = old.transactions.try_to_vec().unwrap();
+            batch.put(Column::Blocks, old.hash().as_ref(), [header, transactions.as_slice()].concat());
+            batch.put(Column::Metadata, FORMAT_VERSION_KEY.as_bytes(), 2u32.to_be_bytes());
+            storage.backend.write(batch).unwrap();
+            storage.flush().unwrap();
+            assert!(storage.get_block(&old.hash()).is_err());
+        }
+
+        let storage = Storage::open(dir.path()).unwrap();
+        let version = storage.get_metadata(FORMAT_VERSION_KEY).unwrap();
+        assert_eq!(version, Some(STORAGE_FORMAT_VERSION.to_be_bytes().to_vec()));
+        let migrated = storage.get_block(&old.hash()).unwrap().unwrap();
+        assert_eq!((migrated.hash(), migrated.header.version), (old.hash(), 0));
+        assert!(migrated.verify_body());
+        assert_eq!(storage.get_header(&old.hash()).unwrap(), Some(old.header.clone()));
+        // Its flat root admits no proofs; blocks built now do.
+        assert_eq!(storage.tx_inclusion_proof(&old_tx.hash()).unwrap(), None);
+        let txs: Vec<Transaction> =
+            (1..4).map(|nonce| Transaction::new_signed(&keypair, Pubkey::new_unique(), 5, 1, nonce, 0)).collect();
+        let new = Block::with_transactions(2, old.hash(), 1, Pubkey::default(), txs.clone());
+        storage.put_block(&new).unwrap();
+        for tx in &txs {
+            let (header, proof) = storage.tx_inclusion_proof(&tx.hash()).unwrap().unwrap();
+            assert_eq!(header, new.header);
+            assert!(proof.verify(&header.transactions_root, &tx.hash()));
+        }
+        assert_eq!(storage.tx_inclusion_proof(&Hash::new_unique()).unwrap(), None);
+        drop(storage);
+        // Reopening a migrated store leaves it as it is.
+        assert_eq!(Storage::open(dir.path()).unwrap().get_block(&old.hash()).unwrap(), Some(migrated));
+    }
+
+    #[test]
+    fn test_unfiltered_headers_migrated_and_filters_outlive_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let (alice, bob, carol) = (Keypair::new(), Pubkey::new_unique(), Pubkey::new_unique());
+        let certify = |block: &Block| CommitCertificate::new(block.height(), block.hash(), Vec::new());
+        let v1 = Block::with_transactions(1, Hash::default(), 1, Pubkey::default(), vec![
+            Transaction::new_signed(&alice, carol, 5, 1, 0, 0),
+        ]).with_version(1);
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            storage.put_finalized_block(&v1, &certify(&v1)).unwrap();
+            // The version 1 layout ends before the address filter and chain.
+            let header = v1.header.try_to_vec().unwrap();
+            let chain = String::new().try_to_vec().unwrap().len();
+            let behind = AddressBloom::default().try_to_vec().unwrap().len() + chain;
+            let header = &header[..header.len() - behind];
+            let mut batch = WriteBatch::default();
+            batch.put(Column::Headers, v1.hash().as_ref(), header);
+            let transactions = v1.transactions.try_to_vec().unwrap();
+            batch.put(Column::Blocks, v1.hash().as_ref(), [header, transactions.as_slice()].concat());
+            batch.put(Column::Metadata, FORMAT_VERSION_KEY.as_bytes(), 3u32.to_be_bytes());
+            storage.backend.write(batch).unwrap();
+            storage.flush().unwrap();
+        }
+
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.get_block(&v1.hash()).unwrap().as_ref(), Some(&v1));
+        // Filters sized for one in a million, so strangers are not let in by chance.
+        let mut parent = v1.hash();
+        for height in 2..=5 {
+            let transfers = if height == 3 { vec![Transaction::new_signed(&alice, bob, 5, 1, 1, 0)] } else { vec![] };
+            let block = Block::with_transactions(height, parent, height as i64, Pubkey::default(), transfers)
+                .with_address_bloom(1);
+            assert!(block.verify_body());
+            storage.put_finalized_block(&block, &certify(&block)).unwrap();
+            parent = block.hash();
+        }
+        while storage.prune_step(1, 64).unwrap() > 0 {}
+        assert_eq!(storage.prune_horizon().unwrap(), 5);
+
+        // Only the unfiltered version 1 block and bob's block may be his.
+        let (bob_address, carol_address) = (DADBSAddress::from_pubkey(&bob), DADBSAddress::from_pubkey(&carol));
+        assert_eq!(storage.address_activity(&bob_address, 1..100).unwrap(), vec![1, 3]);
+        assert_eq!(storage.address_activity(&carol_address, 1..100).unwrap(), vec![1]);
+        assert!(matches!(storage.txs_for_address(&bob, 2..6), Err(StorageError::Pruned { height: 3, horizon: 5 })));
+        assert!(matches!(storage.txs_for_address(&carol, 1..6), Err(StorageError::Pruned { height: 1, horizon: 5 })));
+        // Pruned blocks that never involved carol do not stand in the way.
+        assert_eq!(storage.txs_for_address(&carol, 2..6).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_unchained_headers_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = Keypair::new();
+        let v2 = Block::with_transactions(1, Hash::default(), 1, Pubkey::default(), vec![
+            Transaction::new_signed(&keypair, Pubkey::new_unique(), 5, 1, 0, 0),
+        ]).with_version(2);
+        let certificate = CommitCertificate::new(1, v2.hash(), Vec::new());
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            storage.put_finalized_block(&v2, &certificate).unwrap();
+            // The version 2 layout ends before the chain.
+            let header = v2.header.try_to_vec().unwrap();
+            let header = &header[..header.len() - String::new().try_to_vec().unwrap().len()];
+            let mut batch = WriteBatch::default();
+            batch.put(Column::Headers, v2.hash().as_ref(), header);
+            let transactions = v2.transactions.try_to_vec().unwrap();
+            batch.put(Column::Blocks, v2.hash().as_ref(), [header, transactions.as_slice()].concat());
+            batch.put(Column::Metadata, FORMAT_VERSION_KEY.as_bytes(), 4u32.to_be_bytes());
+            storage.backend.write(batch).unwrap();
+            storage.flush().unwrap();
+        }
+
+        let storage = Storage::open(dir.path()).unwrap();
+        let version = storage.get_metadata(FORMAT_VERSION_KEY).unwrap();
+        assert_eq!(version, Some(STORAGE_FORMAT_VERSION.to_be_bytes().to_vec()));
+        let migrated = storage.get_block(&v2.hash()).unwrap().unwrap();
+        assert_eq!(migrated, v2);
+        assert!(migrated.header.chain_id.is_empty() && migrated.verify_body());
+        // Blocks built now name their chain, and hash it.
+        let v3 = Block::new(2, v2.hash(), 2, Pubkey::default());
+        assert_eq!(v3.header.version, BLOCK_VERSION);
+        assert_ne!(v3.hash(), v3.clone().with_chain_id("another-chain").hash());
+    }
+
+    #[test]
+    fn test_clean_shutdown_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::open(dir.path()).unwrap();
+        assert!(storage.previous_shutdown_clean());
+        storage.flush().unwrap();
+        drop(storage);
+
+        // Dropped without marking: a crash.
+        let storage = Storage::open(dir.path()).unwrap();
+        assert!(!storage.previous_shutdown_clean());
+        storage.mark_clean_shutdown().unwrap();
+        drop(storage);
+
+        let storage = Storage::open(dir.path()).unwrap();
+        assert!(storage.previous_shutdown_clean());
+        assert_eq!(storage.get_metadata(CLEAN_SHUTDOWN_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn test_audit_trail_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = |method: &str| AuditRecord {
+            timestamp_ms: 1,
+            caller: "127.0.0.1:9000".to_string(),
+            method: method.to_string(),
+            params: serde_json::json!({ "addr": "10.0.0.1:8000" }),
+            outcome: "ok".to_string(),
+        };
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            assert_eq!(storage.append_audit(&record("admin_ban_peer")).unwrap(), 0);
+            assert_eq!(storage.append_audit(&record("admin_unban_peer")).unwrap(), 1);
+            storage.flush().unwrap();
+        }
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.append_audit(&record("admin_pause_consensus")).unwrap(), 2);
+        let methods: Vec<String> = storage.audit_log().unwrap().into_iter().map(|r| r.method).collect();
+        assert_eq!(methods, ["admin_ban_peer", "admin_unban_peer", "admin_pause_consensus"]);
+    }
+
+    #[test]
+    fn test_param_changes_survive_reopen() {
+        use crate::node::config::DEFAULT_CHAIN_ID;
+        use crate::node::params::ProtocolParams;
+        use crate::node::vote::Vote;
+
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = Keypair::new();
+        let change = ParamChange::new(3, ProtocolParams { min_fee: 7, ..ProtocolParams::default() });
+        let approvals = change.certificate(vec![change.approve(&keypair, DEFAULT_CHAIN_ID)]);
+        let proposal = Transaction::new_param_change(&keypair, change.clone(), approvals.clone(), 1, 0, 0);
+        let transfer = Transaction::new_signed(&keypair, Pubkey::new_unique(), 5, 1, 1, 0);
+        let block = Block::with_transactions(1, Hash::default(), 0, Pubkey::default(), vec![transfer, proposal]);
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            let vote = Vote::new(&keypair, 1, 0, block.hash());
+            storage.put_finalized_block(&block, &CommitCertificate::new(1, block.hash(), vec![vote])).unwrap();
+            storage.flush().unwrap();
+        }
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.param_changes().unwrap(), vec![(1, change, approvals)]);
+    }
+
+    /// Blocks at `heights` with two transfers each, continuing from `parent`.
+    fn transfers(senders: &[Keypair], recipient: Pubkey, heights: Range<u64>, mut parent: Hash) -> Vec<Block> {
+        heights
+            .map(|height| {
+                let sender = &senders[height as usize % senders.len()];
+                let transactions = vec![
+                    Transaction::new_signed(sender, recipient, height, 1, 2 * height, 0),
+                    Transaction::new_signed(sender, Pubkey::new_unique(), 1, 1, 2 * height + 1, 0),
+                ];
+                let block = Block::with_transactions(height, parent, height as i64, Pubkey::default(), transactions);
+                parent = block.hash();
+                block
+            })
+            .collect()
+    }
+
+    fn index_contents(storage: &Storage) -> Vec<Vec<Entry>> {
+        IndexKind::ALL.iter()
+            .map(|kind| storage.backend.scan(kind.column(), &[], &[u8::MAX; 64]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_reindex_repairs_corrupted_indexes_while_blocks_arrive() {
+        let (control_dir, dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (control, storage) = (Storage::open(control_dir.path()).unwrap(), Storage::open(dir.path()).unwrap());
+        let senders: Vec<Keypair> = (0..3).map(|_| Keypair::new()).collect();
+        let recipient = Pubkey::new_unique();
+        let chain = transfers(&senders, recipient, 1..301, Hash::default());
+        for block in &chain {
+            control.put_block(block).unwrap();
+            storage.put_block(block).unwrap();
+        }
+
+        let mut corrupt = WriteBatch::default();
+        for block in &chain[10..20] {
+            corrupt.delete(Column::AddressIndex, address_key(&recipient, block.height(), 0));
+        }
+        corrupt.put(Column::AddressIndex, address_key(&Pubkey::new_unique(), 5, 0), Hash::new_unique().as_ref());
+        corrupt.delete(Column::Transactions, chain[42].transactions[1].hash().as_ref());
+        storage.backend.write(corrupt).unwrap();
+        assert_ne!(index_contents(&storage), index_contents(&control));
+
+        // A block arrives partway through, as on a running node.
+        let late = transfers(&senders, recipient, 301..302, chain[299].hash()).remove(0);
+        let calls = std::cell::Cell::new(0);
+        let report = storage.reindex(&[IndexKind::TxHash, IndexKind::AddressTx], |progress| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                assert_eq!((progress.height, progress.tip), (REINDEX_BATCH_BLOCKS - 1, 300));
+                control.put_block(&late).unwrap();
+                storage.put_block(&late).unwrap();
+            }
+        }).unwrap();
+        assert_eq!(report.kinds, [IndexKind::AddressTx, IndexKind::TxHash]);
+        assert_eq!((report.tip, report.entries.clone(), report.resumed), (Some(301), vec![4 * 301, 2 * 301], false));
+        assert!(calls.get() >= 2);
+
+        assert_eq!(index_contents(&storage), index_contents(&control));
+        for address in senders.iter().map(Signer::pubkey).chain([recipient]) {
+            assert_eq!(storage.txs_for_address(&address, 0..302).unwrap(), control.txs_for_address(&address, 0..302).unwrap());
+        }
+        assert!(storage.backend.scan(Column::Reindex, &[], &[u8::MAX; 64]).unwrap().is_empty());
+        assert_eq!(storage.get_metadata(REINDEX_CHECKPOINT_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn test_interrupted_reindex_resumes_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let recipient = Pubkey::new_unique();
+        let chain = transfers(&[Keypair::new()], recipient, 1..301, Hash::default());
+        {
+            let storage = Storage::open(dir.path()).unwrap();
+            for block in &chain {
+                storage.put_block(block).unwrap();
+            }
+            let mut corrupt = WriteBatch::default();
+            corrupt.delete(Column::AddressIndex, address_key(&recipient, 7, 0));
+            storage.backend.write(corrupt).unwrap();
+            *storage.failpoint.lock() = Some(Failpoint::BeforeSwap);
+            assert!(storage.reindex(&[IndexKind::AddressTx], |_| {}).is_err());
+            assert!(storage.reindexing.read().is_none());
+            storage.flush().unwrap();
+        }
+
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.txs_for_address(&recipient, 7..8).unwrap(), Vec::new());
+        let checkpoint = storage.reindex_checkpoint().unwrap().unwrap();
+        assert_eq!((checkpoint.next_height, checkpoint.entries.clone()), (301, vec![4 * 300]));
+
+        // Nothing is left to stage, so only the final report is given.
+        let calls = std::cell::Cell::new(0);
+        let report = storage.reindex(&[IndexKind::AddressTx], |_| calls.set(calls.get() + 1)).unwrap();
+        assert!(report.resumed);
+        assert_eq!((calls.get(), report.entries), (1, vec![4 * 300]));
+        assert_eq!(storage.txs_for_address(&recipient, 7..8).unwrap().len(), 1);
+        assert_eq!(storage.reindex_checkpoint().unwrap(), None);
+    }
+
+    #[test]
+    fn test_chain_stats_counted_at_commit_and_rebuilt_by_reindex() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::open(dir.path()).unwrap();
+        let (a, b, c) = (Keypair::new(), Keypair::new(), Pubkey::new_unique());
+        let blocks = [
+            (DAY_MS - 1000, vec![
+                Transaction::new_signed(&a, c, 10, 1, 0, 0),
+                Transaction::new_signed(&b, a.pubkey(), 5, 2, 0, 0),
+            ]),
+            (DAY_MS - 400, vec![Transaction::new_signed(&a, a.pubkey(), 3, 1, 1, 0)]),
+            (DAY_MS + 200, vec![Transaction::new_signed(&b, c, 7, 1, 1, 0)]),
+        ];
+        let mut parent = Hash::default();
+        for (height, (timestamp, transactions)) in (1..).zip(blocks) {
+            let block = Block::with_transactions(height, parent, timestamp, Pubkey::default(), transactions);
+            parent = block.hash();
+            storage.put_block(&block).unwrap();
+        }
+
+        let days = vec![
+            DayStats {
+                day_start_ms: 0,
+                blocks: 2,
+                transactions: 3,
+                fees: 4,
+                volume: 18,
+                active_addresses: 3,
+                interval_ms: 600,
+                intervals: 1,
+            },
+            DayStats {
+                day_start_ms: DAY_MS,
+                blocks: 1,
+                transactions: 1,
+                fees: 1,
+                volume: 7,
+                active_addresses: 2,
+                interval_ms: 600,
+                intervals: 1,
+            },
+        ];
+        assert_eq!(storage.chain_stats(0, i64::MAX).unwrap(), days);
+        assert_eq!(storage.chain_stats(DAY_MS + 1, DAY_MS + 2).unwrap(), days[1..]);
+        assert_eq!(storage.chain_stats(i64::MIN, 0).unwrap(), Vec::new());
+        let address = |first_seen_ms, tx_count, total_in, total_out| {
+            Some(AddressStats { first_seen_height: 1, first_seen_ms, tx_count, total_in, total_out })
+        };
+        assert_eq!(storage.address_stats(&a.pubkey()).unwrap(), address(DAY_MS - 1000, 3, 8, 13));
+        assert_eq!(storage.address_stats(&c).unwrap(), address(DAY_MS - 1000, 2, 17, 0));
+        assert_eq!(storage.address_stats(&Pubkey::new_unique()).unwrap(), None);
+
+        let mut corrupt = WriteBatch::default();
+        corrupt.delete(Column::Stats, chain_stats::day_key(0));
+        let wrong = address(0, 9, 0, 0).unwrap();
+        corrupt.put(Column::Stats, chain_stats::address_key(&c), wrong.try_to_vec().unwrap());
+        storage.backend.write(corrupt).unwrap();
+        let report = storage.reindex(&[IndexKind::ChainStats], |_| {}).unwrap();
+        // The tip, two days, three addresses, and five of them active on a day.
+        assert_eq!(report.entries, vec![11]);
+        assert_eq!(storage.chain_stats(0, i64::MAX).unwrap(), days);
+        assert_eq!(storage.address_stats(&c).unwrap(), address(DAY_MS - 1000, 2, 17, 0));
+    }
+}
@@ -0,0 +1,363 @@
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::block::Block;
+use super::evidence::StakeSlashed;
+use super::fork_choice::ChainUpdate;
+use super::rpc::{
+    error_response, parse_params, AddressParams, BlockResult, ReceiptResult, RpcError, RpcServer, TransactionResult,
+    INVALID_REQUEST, METHOD_NOT_FOUND,
+};
+use super::state::Receipt;
+use super::storage::StoredTransaction;
+use super::transaction::Transaction;
+use crate::utils::DADBSAddress;
+
+/// Events retained per channel for receivers that fall behind.
+pub const DEFAULT_EVENT_CAPACITY: usize = 256;
+/// The per-connection subscription limit was reached.
+pub const TOO_MANY_SUBSCRIPTIONS: i64 = -32003;
+/// Notifications queued for a connection's writer before subscriptions wait.
+const OUTBOX_CAPACITY: usize = 64;
+
+/// Fan-out of chain updates to WebSocket subscribers. A receiver that falls
+/// more than the channel capacity behind loses the oldest events instead of
+/// holding up consensus.
+#[derive(Clone)]
+pub struct ChainEvents {
+    new_blocks: broadcast::Sender<Arc<Block>>,
+    finalized: broadcast::Sender<Arc<Block>>,
+    executed: broadcast::Sender<Arc<ExecutedBlock>>,
+    slashed: broadcast::Sender<Arc<StakeSlashed>>,
+}
+
+/// A finalized block applied to state, with its transactions' receipts.
+#[derive(Debug, Clone)]
+pub struct ExecutedBlock {
+    pub block: Block,
+    pub receipts: Vec<Receipt>,
+}
+
+impl ChainEvents {
+    pub fn new(capacity: usize) -> Self {
+        ChainEvents {
+            new_blocks: broadcast::channel(capacity.max(1)).0,
+            finalized: broadcast::channel(capacity.max(1)).0,
+            executed: broadcast::channel(capacity.max(1)).0,
+            slashed: broadcast::channel(capacity.max(1)).0,
+        }
+    }
+
+    pub fn publish(&self, update: &ChainUpdate) {
+        // Sending only fails without receivers.
+        for block in &update.applied {
+            let _ = self.new_blocks.send(Arc::new(block.clone()));
+        }
+        for block in &update.finalized {
+            let _ = self.finalized.send(Arc::new(block.clone()));
+        }
+    }
+
+    /// Blocks as they join the canonical chain, including after a reorg.
+    pub fn new_blocks(&self) -> broadcast::Receiver<Arc<Block>> {
+        self.new_blocks.subscribe()
+    }
+
+    pub fn finalized(&self) -> broadcast::Receiver<Arc<Block>> {
+        self.finalized.subscribe()
+    }
+
+    pub fn publish_executed(&self, block: &Block, receipts: Vec<Receipt>) {
+        let _ = self.executed.send(Arc::new(ExecutedBlock { block: block.clone(), receipts }));
+    }
+
+    pub fn executed(&self) -> broadcast::Receiver<Arc<ExecutedBlock>> {
+        self.executed.subscribe()
+    }
+
+    pub fn publish_slashed(&self, slashed: StakeSlashed) {
+        let _ = self.slashed.send(Arc::new(slashed));
+    }
+
+    /// Slashes accepted by an `EvidenceSubmitter` given these events.
+    pub fn slashed(&self) -> broadcast::Receiver<Arc<StakeSlashed>> {
+        self.slashed.subscribe()
+    }
+}
+
+impl Default for ChainEvents {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnsubscribeParams {
+    pub subscription: u64,
+}
+
+enum Topic {
+    NewBlocks,
+    Finalized,
+    /// Transactions sent from or to the address, as their blocks are
+    /// included, and then their receipts as the blocks are executed.
+    Address(DADBSAddress),
+}
+
+impl Topic {
+    fn results(&self, block: &Block) -> Vec<Value> {
+        let address = match self {
+            Topic::NewBlocks | Topic::Finalized => return vec![json!({ "result": BlockResult::from(block) })],
+            Topic::Address(address) => address,
+        };
+        Self::involving(address, block)
+            .map(|(index, tx)| {
+                let stored = StoredTransaction { height: block.height(), index: index as u32, transaction: tx.clone() };
+                json!({ "result": TransactionResult::from(&stored) })
+            })
+            .collect()
+    }
+
+    fn receipts(&self, executed: &ExecutedBlock) -> Vec<Value> {
+        let address = match self {
+            Topic::NewBlocks | Topic::Finalized => return Vec::new(),
+            Topic::Address(address) => address,
+        };
+        Self::involving(address, &executed.block)
+            .filter_map(|(index, _)| executed.receipts.get(index))
+            .map(|receipt| json!({ "receipt": ReceiptResult::from(receipt) }))
+            .collect()
+    }
+
+    fn involving<'a>(address: &'a DADBSAddress, block: &'a Block) -> impl Iterator<Item = (usize, &'a Transaction)> {
+        block.transactions.iter().enumerate().filter(move |(_, tx)| {
+            DADBSAddress::from_pubkey(&tx.sender) == *address || DADBSAddress::from_pubkey(&tx.recipient) == *address
+        })
+    }
+}
+
+fn notification(subscription: u64, mut params: Value) -> Value {
+    params["subscription"] = json!(subscription);
+    json!({ "jsonrpc": "2.0", "method": "subscription", "params": params })
+}
+
+/// Forwards one subscription's events to the connection. Lagging by more
+/// than the channel capacity yields a single `missed` notification counting
+/// the blocks dropped.
+async fn forward(
+    subscription: u64,
+    topic: Topic,
+    mut events: broadcast::Receiver<Arc<Block>>,
+    mut executed: Option<broadcast::Receiver<Arc<ExecutedBlock>>>,
+    outbox: mpsc::Sender<Value>,
+    cancel: CancellationToken,
+) {
+    loop {
+        // Biased so nothing is forwarded once unsubscribed.
+        let event = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return,
+            event = events.recv() => event.map(|block| topic.results(&block)),
+            Some(event) = async {
+                match executed.as_mut() {
+                    Some(executed) => Some(executed.recv().await),
+                    None => None,
+                }
+            } => event.map(|executed| topic.receipts(&executed)),
+        };
+        let messages = match event {
+            Ok(results) => results.into_iter().map(|params| notification(subscription, params)).collect(),
+            Err(RecvError::Lagged(missed)) => {
+                debug!("Subscription {} missed {} blocks", subscription, missed);
+                vec![notification(subscription, json!({ "missed": missed }))]
+            }
+            Err(RecvError::Closed) => return,
+        };
+        for message in messages {
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return,
+                sent = outbox.send(message) => if sent.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// One WebSocket client. Subscription requests must be sent singly; anything
+/// else is answered like an HTTP request body.
+struct Connection {
+    server: Arc<RpcServer>,
+    client: IpAddr,
+    /// Sent with the upgrade request; a request's own `auth` takes its place.
+    api_key: Option<String>,
+    events: ChainEvents,
+    max_subscriptions: usize,
+    outbox: mpsc::Sender<Value>,
+    cancel: CancellationToken,
+    subscriptions: HashMap<u64, CancellationToken>,
+    next_subscription: u64,
+}
+
+impl Connection {
+    /// Answers one message. Subscribe responses are queued here, ahead of the
+    /// subscription's first notification.
+    async fn handle(&mut self, body: &[u8]) -> Option<Value> {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(_) => return self.handle_plain(body).await,
+        };
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) if method.starts_with("subscribe_") || method == "unsubscribe" => method.to_string(),
+            _ => return self.handle_plain(body).await,
+        };
+        let id = match request.get("id") {
+            Some(id) => id.clone(),
+            None => return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Subscriptions need an id"))),
+        };
+        let api_key = request.get("auth").and_then(Value::as_str).or(self.api_key.as_deref());
+        if let Err(error) = self.server.check_access(self.client, api_key, &method) {
+            return Some(error_response(id, error));
+        }
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let topic = match method.as_str() {
+            "subscribe_new_blocks" => Topic::NewBlocks,
+            "subscribe_finalized" => Topic::Finalized,
+            "subscribe_address" => match parse_params::<AddressParams>(params) {
+                Ok(AddressParams { address }) => match DADBSAddress::from_string(&address) {
+                    Ok(address) => Topic::Address(address),
+                    Err(e) => return Some(error_response(id, e.into())),
+                },
+                Err(e) => return Some(error_response(id, e)),
+            },
+            "unsubscribe" => {
+                return Some(match parse_params::<UnsubscribeParams>(params) {
+                    Ok(UnsubscribeParams { subscription }) => {
+                        json!({ "jsonrpc": "2.0", "result": self.unsubscribe(subscription), "id": id })
+                    }
+                    Err(e) => error_response(id, e),
+                });
+            }
+            _ => {
+                let error = RpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method));
+                return Some(error_response(id, error));
+            }
+        };
+        if self.subscriptions.len() >= self.max_subscriptions {
+            let error = RpcError::new(
+                TOO_MANY_SUBSCRIPTIONS,
+                format!("Connection already has {} subscriptions", self.subscriptions.len()),
+            );
+            return Some(error_response(id, error));
+        }
+
+        // Subscribe before answering so no event between the two is lost.
+        let (events, executed) = match topic {
+            Topic::Finalized => (self.events.finalized(), None),
+            Topic::NewBlocks => (self.events.new_blocks(), None),
+            Topic::Address(_) => (self.events.new_blocks(), Some(self.events.executed())),
+        };
+        let subscription = self.next_subscription;
+        self.next_subscription += 1;
+        let response = json!({ "jsonrpc": "2.0", "result": subscription, "id": id });
+        if self.outbox.send(response).await.is_err() {
+            return None;
+        }
+        let cancel = self.cancel.child_token();
+        self.subscriptions.insert(subscription, cancel.clone());
+        tokio::spawn(forward(subscription, topic, events, executed, self.outbox.clone(), cancel));
+        None
+    }
+
+    /// Answers `body` as an HTTP request body from this client.
+    async fn handle_plain(&self, body: &[u8]) -> Option<Value> {
+        self.server.handle_body(self.client, self.api_key.as_deref(), body).await
+    }
+
+    /// Returns whether `subscription` was active.
+    fn unsubscribe(&mut self, subscription: u64) -> bool {
+        match self.subscriptions.remove(&subscription) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Serves `socket` until either side closes it or `cancel` fires.
+pub(crate) async fn serve_socket(
+    server: Arc<RpcServer>,
+    client: IpAddr,
+    api_key: Option<String>,
+    socket: WebSocket,
+    events: ChainEvents,
+    max_subscriptions: usize,
+    cancel: CancellationToken,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let (outbox, mut outgoing) = mpsc::channel::<Value>(OUTBOX_CAPACITY);
+
+    let writer_cancel = cancel.clone();
+    let writer = tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                _ = writer_cancel.cancelled() => break,
+                message = outgoing.recv() => message,
+            };
+            match message {
+                Some(message) => {
+                    if sink.send(Message::Text(message.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let mut connection = Connection {
+        server,
+        client,
+        api_key,
+        events,
+        max_subscriptions,
+        outbox,
+        cancel: cancel.clone(),
+        subscriptions: HashMap::new(),
+        next_subscription: 0,
+    };
+    loop {
+        let message = tokio::select! {
+            _ = cancel.cancelled() => break,
+            message = stream.next() => message,
+        };
+        let body = match message {
+            Some(Ok(Message::Text(text))) => text.into_bytes(),
+            Some(Ok(Message::Binary(bytes))) => bytes,
+            Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+        };
+        if let Some(response) = connection.handle(&body).await {
+            if connection.outbox.send(response).await.is_err() {
+                break;
+            }
+        }
+    }
+    // Stops the writer and every subscription on this connection.
+    cancel.cancel();
+    let _ = writer.await;
+}
@@ -0,0 +1,497 @@
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::{oneshot, Mutex as AsyncMutex, Notify};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+use super::block::Block;
+use super::consensus::ConsensusManager;
+use super::fork_choice::ForkChoiceError;
+use super::network::{NetMessage, Network, PeerId};
+use super::peer_score::Offense;
+use super::snapshot::{SnapshotError, SnapshotTrust};
+use super::storage::Storage;
+use super::validator::ValidatorSetHistory;
+use super::sig_verify;
+use super::vote::{CertificateError, CommitCertificate};
+
+pub const DEFAULT_SYNC_BATCH_SIZE: u64 = 32;
+const SYNC_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CLAIM_WAIT: Duration = Duration::from_millis(200);
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("Sync cancelled")]
+    Cancelled,
+    #[error("Peer {0} is banned")]
+    PeerBanned(PeerId),
+    #[error("Request to peer {0} timed out")]
+    Timeout(PeerId),
+    #[error("Transport error: {0}")]
+    Transport(String),
+    #[error("Invalid response from peer {peer}: {reason}")]
+    InvalidResponse { peer: PeerId, reason: String },
+    #[error("No validator set known for height {0}")]
+    UnknownValidatorSet(u64),
+    #[error("Fork choice error: {0}")]
+    ForkChoice(#[from] ForkChoiceError),
+    #[error("Snapshot bootstrap failed: {0}")]
+    Snapshot(#[from] SnapshotError),
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SyncMessage {
+    GetBlocks { from: u64, to: u64 },
+    Blocks { blocks: Vec<Block>, certificates: Vec<CommitCertificate> },
+}
+
+impl From<SyncMessage> for NetMessage {
+    fn from(message: SyncMessage) -> Self {
+        match message {
+            SyncMessage::GetBlocks { from, to } => NetMessage::GetBlocks { from, to },
+            SyncMessage::Blocks { blocks, certificates } => NetMessage::Blocks { blocks, certificates },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    Idle,
+    Syncing { target: u64 },
+}
+
+#[async_trait]
+pub trait SyncPeer: Send + Sync {
+    fn id(&self) -> PeerId;
+    async fn request(&self, message: SyncMessage) -> Result<SyncMessage, SyncError>;
+}
+
+pub trait BlockStore: Send + Sync {
+    fn get_finalized(&self, height: u64) -> Option<(Block, CommitCertificate)>;
+    fn put_finalized(&self, block: Block, certificate: CommitCertificate);
+}
+
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    blocks: RwLock<BTreeMap<u64, (Block, CommitCertificate)>>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.read().is_empty()
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn get_finalized(&self, height: u64) -> Option<(Block, CommitCertificate)> {
+        self.blocks.read().get(&height).cloned()
+    }
+
+    fn put_finalized(&self, block: Block, certificate: CommitCertificate) {
+        self.blocks.write().insert(block.height(), (block, certificate));
+    }
+}
+
+struct SnapshotSource {
+    storage: Arc<Storage>,
+    path: PathBuf,
+    trust: SnapshotTrust,
+}
+
+#[derive(Default)]
+struct SyncState {
+    active: usize,
+    target: u64,
+}
+
+pub struct SyncManager {
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    store: Arc<dyn BlockStore>,
+    validators: Arc<RwLock<ValidatorSetHistory>>,
+    batch_size: u64,
+    request_timeout: Duration,
+    claimed: Mutex<HashSet<u64>>,
+    progress: Notify,
+    /// Scores and bans the peers we sync from, if any.
+    network: Option<Arc<Network>>,
+    state: Mutex<SyncState>,
+    snapshot: Mutex<Option<SnapshotSource>>,
+}
+
+impl SyncManager {
+    pub fn new(
+        consensus: Arc<AsyncMutex<ConsensusManager>>,
+        store: Arc<dyn BlockStore>,
+        validators: Arc<RwLock<ValidatorSetHistory>>,
+        batch_size: u64,
+    ) -> Self {
+        SyncManager {
+            consensus,
+            store,
+            validators,
+            batch_size: batch_size.max(1),
+            request_timeout: SYNC_REQUEST_TIMEOUT,
+            claimed: Mutex::new(HashSet::new()),
+            progress: Notify::new(),
+            network: None,
+            state: Mutex::new(SyncState::default()),
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    /// Bootstraps a fresh node from the snapshot at `path` before syncing
+    /// the remaining blocks.
+    pub fn with_snapshot(self, storage: Arc<Storage>, path: PathBuf, trust: SnapshotTrust) -> Self {
+        *self.snapshot.lock() = Some(SnapshotSource { storage, path, trust });
+        self
+    }
+
+    /// Reports peers that send bad blocks or do not answer to `network`,
+    /// and refuses to sync from those it has banned.
+    pub fn with_network(mut self, network: Arc<Network>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        let state = self.state.lock();
+        if state.active == 0 {
+            SyncStatus::Idle
+        } else {
+            SyncStatus::Syncing { target: state.target }
+        }
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.status() != SyncStatus::Idle
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.network.as_ref().map_or(false, |network| network.is_banned(peer))
+    }
+
+    /// Returns true when a peer's announced finalized height means we have fallen behind.
+    pub async fn needs_sync(&self, peer_finalized_height: u64) -> bool {
+        peer_finalized_height > self.consensus.lock().await.finalized_height()
+    }
+
+    /// Serves a `GetBlocks` request from our finalized history.
+    pub fn handle_request(&self, message: &SyncMessage) -> Option<SyncMessage> {
+        let (from, to) = match message {
+            SyncMessage::GetBlocks { from, to } => (*from, *to),
+            SyncMessage::Blocks { .. } => return None,
+        };
+
+        let to = to.min(from.saturating_add(self.batch_size - 1));
+        let mut blocks = Vec::new();
+        let mut certificates = Vec::new();
+        for height in from..=to {
+            match self.store.get_finalized(height) {
+                Some((block, certificate)) => {
+                    blocks.push(block);
+                    certificates.push(certificate);
+                }
+                None => break,
+            }
+        }
+
+        Some(SyncMessage::Blocks { blocks, certificates })
+    }
+
+    /// Installs the configured snapshot if nothing has been finalized yet,
+    /// returning its height. Only tried once.
+    pub async fn bootstrap_from_snapshot(&self) -> Result<Option<u64>, SyncError> {
+        let source = match self.snapshot.lock().take() {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        let mut consensus = self.consensus.lock().await;
+        if consensus.finalized_height() > 0 {
+            return Ok(None);
+        }
+        let snapshot = source.storage.import_snapshot(&source.path, &source.trust)?;
+        let height = snapshot.height();
+        consensus.bootstrap_from_snapshot(snapshot).map_err(SnapshotError::from)?;
+        info!("Bootstrapped from snapshot at height {}", height);
+        Ok(Some(height))
+    }
+
+    /// Pulls finalized blocks from `peer` until we reach `target`. Returns the
+    /// local finalized height once caught up.
+    pub async fn sync_with(
+        &self,
+        peer: &dyn SyncPeer,
+        target: u64,
+        cancel: &CancellationToken,
+    ) -> Result<u64, SyncError> {
+        let peer_id = peer.id();
+        if self.is_banned(&peer_id) {
+            return Err(SyncError::PeerBanned(peer_id));
+        }
+
+        {
+            let mut state = self.state.lock();
+            state.active += 1;
+            state.target = state.target.max(target);
+        }
+        info!("Starting sync with {} up to height {}", peer_id, target);
+
+        let result = self.run_sync(peer, target, cancel).await;
+
+        {
+            let mut state = self.state.lock();
+            state.active -= 1;
+            if state.active == 0 {
+                state.target = 0;
+            }
+        }
+
+        match &result {
+            Ok(height) => info!("Caught up with {} at height {}", peer_id, height),
+            Err(e) => {
+                warn!("Sync with {} stopped: {}", peer_id, e);
+                self.penalize(peer_id, e);
+            }
+        }
+        result
+    }
+
+    async fn run_sync(
+        &self,
+        peer: &dyn SyncPeer,
+        target: u64,
+        cancel: &CancellationToken,
+    ) -> Result<u64, SyncError> {
+        if let Err(e) = self.bootstrap_from_snapshot().await {
+            warn!("{}; syncing from blocks instead", e);
+        }
+        loop {
+            if cancel.is_cancelled() {
+                return Err(SyncError::Cancelled);
+            }
+
+            let finalized = self.consensus.lock().await.finalized_height();
+            if finalized >= target {
+                return Ok(finalized);
+            }
+
+            let from = finalized + 1;
+            let to = (from + self.batch_size - 1).min(target);
+
+            if !self.claimed.lock().insert(from) {
+                debug!("Range starting at {} already in flight, waiting", from);
+                tokio::select! {
+                    _ = cancel.cancelled() => return Err(SyncError::Cancelled),
+                    _ = self.progress.notified() => {}
+                    _ = sleep(CLAIM_WAIT) => {}
+                }
+                continue;
+            }
+
+            let result = self.fetch_and_apply(peer, from, to, cancel).await;
+            self.claimed.lock().remove(&from);
+            self.progress.notify_waiters();
+            result?;
+        }
+    }
+
+    async fn fetch_and_apply(
+        &self,
+        peer: &dyn SyncPeer,
+        from: u64,
+        to: u64,
+        cancel: &CancellationToken,
+    ) -> Result<(), SyncError> {
+        let peer_id = peer.id();
+        let request = SyncMessage::GetBlocks { from, to };
+        let response = tokio::select! {
+            _ = cancel.cancelled() => return Err(SyncError::Cancelled),
+            response = timeout(self.request_timeout, peer.request(request)) => {
+                response.map_err(|_| SyncError::Timeout(peer_id))??
+            }
+        };
+
+        let invalid = |reason: &str| SyncError::InvalidResponse {
+            peer: peer_id,
+            reason: reason.to_string(),
+        };
+
+        let (blocks, certificates) = match response {
+            SyncMessage::Blocks { blocks, certificates } => (blocks, certificates),
+            SyncMessage::GetBlocks { .. } => return Err(invalid("unexpected GetBlocks response")),
+        };
+        if blocks.is_empty() {
+            return Err(invalid("empty block range"));
+        }
+        if blocks.len() != certificates.len() {
+            return Err(invalid("block and certificate counts differ"));
+        }
+        if blocks.len() as u64 > to - from + 1 {
+            return Err(invalid("more blocks than requested"));
+        }
+
+        let (chain_id, quorum, handovers, sig_pool) = {
+            let consensus = self.consensus.lock().await;
+            let chain_id = consensus.chain_id().to_string();
+            (chain_id, consensus.quorum_policy(), consensus.handovers().clone(), consensus.sig_pool())
+        };
+        let mut weights = Vec::with_capacity(blocks.len());
+        let (mut signers, mut signed) = (Vec::new(), Vec::new());
+        {
+            let history = self.validators.read();
+            for (offset, (block, certificate)) in blocks.iter().zip(&certificates).enumerate() {
+                let height = from + offset as u64;
+                if block.height() != height || certificate.height != height {
+                    return Err(invalid("non-contiguous block heights"));
+                }
+                if !block.verify_body() {
+                    return Err(invalid(&format!("block body at height {} does not match its header", height)));
+                }
+                let set = history.set_at(height).ok_or(SyncError::UnknownValidatorSet(height))?;
+                let set = handovers.validators_at(set, height);
+                let (weight, votes) = certificate.verify_deferred(&chain_id, &block.hash(), &set, quorum.as_ref())
+                    .map_err(|e| invalid(&format!("bad certificate at height {}: {}", height, e)))?;
+                weights.push(weight);
+                for (validator, message) in votes {
+                    signers.push((height, validator));
+                    signed.push(message);
+                }
+            }
+        }
+        // Every vote signature in the range is checked as one batch.
+        let verified = sig_verify::verify_with(sig_pool.as_deref(), signed).await;
+        let forged = signers.iter().zip(&verified).find(|(_, ok)| !**ok);
+        if let Some((&(height, validator), _)) = forged {
+            let e = CertificateError::InvalidSignature(validator);
+            return Err(invalid(&format!("bad certificate at height {}: {}", height, e)));
+        }
+
+        let mut consensus = self.consensus.lock().await;
+        for ((block, certificate), weight) in blocks.into_iter().zip(certificates).zip(weights) {
+            if block.height() <= consensus.finalized_height() {
+                continue;
+            }
+            if block.parent_hash() != consensus.block_tree().finalized_hash() {
+                return Err(invalid("block does not extend our finalized chain"));
+            }
+
+            consensus.check_block_params(&block)
+                .map_err(|e| invalid(&format!("block at height {} breaks protocol parameters: {}", block.height(), e)))?;
+
+            let hash = block.hash();
+            consensus.apply_block(block.clone(), weight)?;
+            consensus.finalize_block(&hash)?;
+            self.store.put_finalized(block, certificate);
+        }
+
+        Ok(())
+    }
+
+    fn penalize(&self, peer: PeerId, error: &SyncError) {
+        let offense = match error {
+            SyncError::InvalidResponse { .. } => Offense::InvalidBlocks,
+            SyncError::Timeout(_) | SyncError::Transport(_) => Offense::UnansweredRequest,
+            _ => return,
+        };
+        if let Some(network) = &self.network {
+            network.report(&peer, offense);
+        }
+    }
+}
+
+/// Syncs over the p2p network: serves peers' `GetBlocks` from our store,
+/// and catches up with a peer found ahead of us, one peer at a time.
+pub struct NetworkSync {
+    manager: Arc<SyncManager>,
+    network: Arc<Network>,
+    /// Requests waiting for their `Blocks`, by the peer asked. Replies
+    /// carry no request id, so each peer has one request out at a time.
+    waiting: Mutex<HashMap<PeerId, oneshot::Sender<SyncMessage>>>,
+    syncing: AtomicBool,
+}
+
+impl NetworkSync {
+    pub fn new(manager: Arc<SyncManager>, network: Arc<Network>) -> Self {
+        NetworkSync { manager, network, waiting: Mutex::new(HashMap::new()), syncing: AtomicBool::new(false) }
+    }
+
+    pub fn manager(&self) -> &Arc<SyncManager> {
+        &self.manager
+    }
+
+    /// Answers a `GetBlocks` from `from`, or hands `Blocks` to the request
+    /// waiting for them.
+    pub async fn on_message(&self, from: PeerId, message: SyncMessage) {
+        if let SyncMessage::Blocks { .. } = message {
+            match self.waiting.lock().remove(&from) {
+                Some(waiting) => {
+                    let _ = waiting.send(message);
+                }
+                None => debug!("Ignoring blocks from {} nobody asked for", from),
+            }
+            return;
+        }
+        if let Some(reply) = self.manager.handle_request(&message) {
+            if let Err(e) = self.network.send(&from, reply.into()).await {
+                debug!("Cannot send blocks to {}: {}", from, e);
+            }
+        }
+    }
+
+    /// Syncs up to `height`, which `from` finalized, in the background
+    /// unless we are not behind it or already syncing.
+    pub async fn on_peer_height(self: &Arc<Self>, from: PeerId, height: u64, cancel: &CancellationToken) {
+        if !self.manager.needs_sync(height).await || self.syncing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (sync, cancel) = (Arc::clone(self), cancel.clone());
+        tokio::spawn(async move {
+            let peer = NetworkPeer { sync: Arc::clone(&sync), id: from };
+            // Its outcome is logged by `sync_with`.
+            let _ = sync.manager.sync_with(&peer, height, &cancel).await;
+            sync.waiting.lock().remove(&from);
+            sync.syncing.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// A connected peer asked through `NetworkSync`.
+struct NetworkPeer {
+    sync: Arc<NetworkSync>,
+    id: PeerId,
+}
+
+#[async_trait]
+impl SyncPeer for NetworkPeer {
+    fn id(&self) -> PeerId {
+        self.id
+    }
+
+    async fn request(&self, message: SyncMessage) -> Result<SyncMessage, SyncError> {
+        let (reply, response) = oneshot::channel();
+        self.sync.waiting.lock().insert(self.id, reply);
+        self.sync.network.send(&self.id, message.into()).await
+            .map_err(|e| SyncError::Transport(e.to_string()))?;
+        response.await.map_err(|_| SyncError::Transport("request superseded".to_string()))
+    }
+}
@@ -0,0 +1,661 @@
+//! A few node gauges sampled every `interval_ms` and kept on disk for a
+//! fixed window, so there is something to look at after an incident even
+//! when nothing was scraping `/metrics`.
+//!
+//! The ring file is a header followed by one fixed-size slot per interval
+//! of the window. A sample taken at `at_ms` goes in slot
+//! `at_ms / interval_ms % slots`, overwriting the one a window older, so
+//! the file never grows. Records carry a checksum: empty slots and records
+//! torn by a crash read as missing.
+
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+use super::consensus_metrics::ConsensusMetrics;
+use super::mempool::Mempool;
+use super::metrics::ProcessMetrics;
+use super::network::Network;
+#[cfg(feature = "llm")]
+use crate::llm::AdmissionController;
+
+/// Ring file inside `storage_path`, unless `[telemetry] path` is set.
+pub const TELEMETRY_FILE: &str = "telemetry.ring";
+pub const DEFAULT_TELEMETRY_INTERVAL_MS: u64 = 10_000;
+pub const DEFAULT_TELEMETRY_RETENTION_HOURS: u64 = 72;
+pub const DEFAULT_TELEMETRY_FLUSH_SAMPLES: usize = 6;
+/// Unwritten samples held at most; the oldest are dropped past it.
+pub const MAX_PENDING_SAMPLES: usize = 360;
+/// Keeps the ring under 100 MB.
+pub const MAX_TELEMETRY_SLOTS: u64 = 1_000_000;
+/// Bytes of one sample on disk, checksum included.
+pub const RECORD_BYTES: usize = FIELDS_BYTES + 8;
+const FIELDS_BYTES: usize = 64;
+const HEADER_BYTES: u64 = 32;
+const MAGIC: &[u8; 8] = b"DADBSTEL";
+const FORMAT_VERSION: u32 = 1;
+const HOUR_MS: u64 = 3_600_000;
+
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} is not a telemetry ring")]
+    NotARing(PathBuf),
+}
+
+/// The `[telemetry]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TelemetryConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Defaults to <storage_path>/telemetry.ring.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// Samples are overwritten once this old. Changing it, or the
+    /// interval, starts the ring afresh.
+    #[serde(default = "default_retention_hours")]
+    pub retention_hours: u64,
+    /// Samples written together; a crash loses up to this many.
+    #[serde(default = "default_flush_samples")]
+    pub flush_samples: usize,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_interval_ms() -> u64 {
+    DEFAULT_TELEMETRY_INTERVAL_MS
+}
+
+fn default_retention_hours() -> u64 {
+    DEFAULT_TELEMETRY_RETENTION_HOURS
+}
+
+fn default_flush_samples() -> usize {
+    DEFAULT_TELEMETRY_FLUSH_SAMPLES
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            enabled: default_enabled(),
+            path: None,
+            interval_ms: default_interval_ms(),
+            retention_hours: default_retention_hours(),
+            flush_samples: default_flush_samples(),
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn file(&self, storage_path: &str) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(storage_path).join(TELEMETRY_FILE),
+        }
+    }
+
+    /// Samples the ring holds.
+    pub fn slots(&self) -> u64 {
+        self.retention_hours.saturating_mul(HOUR_MS) / self.interval_ms.max(1)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_ms == 0 {
+            return Err("telemetry.interval_ms must be at least 1".to_string());
+        }
+        if self.retention_hours == 0 || self.slots() == 0 {
+            return Err("telemetry.retention_hours must cover at least one telemetry.interval_ms".to_string());
+        }
+        if self.slots() > MAX_TELEMETRY_SLOTS {
+            return Err(format!(
+                "telemetry keeps at most {} samples; raise interval_ms or lower retention_hours",
+                MAX_TELEMETRY_SLOTS
+            ));
+        }
+        if self.flush_samples == 0 || self.flush_samples > MAX_PENDING_SAMPLES {
+            return Err(format!("telemetry.flush_samples must be between 1 and {}", MAX_PENDING_SAMPLES));
+        }
+        Ok(())
+    }
+}
+
+/// The gauges at one moment. Times are Unix milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TelemetrySample {
+    pub at_ms: i64,
+    /// Finalized height.
+    pub height: u64,
+    pub head_height: u64,
+    pub peers: u64,
+    /// Pending transactions.
+    pub mempool: u64,
+    /// Between the last two finalizations; `None` before the second.
+    pub round_secs: Option<f64>,
+    /// Inference requests waiting for admission.
+    pub llm_queue: u64,
+    /// Resident memory; 0 where it cannot be read.
+    pub memory_bytes: u64,
+}
+
+impl TelemetrySample {
+    fn encode(&self) -> [u8; RECORD_BYTES] {
+        let fields = [
+            self.at_ms as u64,
+            self.height,
+            self.head_height,
+            self.peers,
+            self.mempool,
+            self.round_secs.unwrap_or(f64::NAN).to_bits(),
+            self.llm_queue,
+            self.memory_bytes,
+        ];
+        let mut record = [0; RECORD_BYTES];
+        for (i, field) in fields.iter().enumerate() {
+            record[i * 8..i * 8 + 8].copy_from_slice(&field.to_be_bytes());
+        }
+        let checksum = checksum(&record[..FIELDS_BYTES]);
+        record[FIELDS_BYTES..].copy_from_slice(&checksum.to_be_bytes());
+        record
+    }
+
+    /// `None` for an empty or torn slot.
+    fn decode(record: &[u8]) -> Option<Self> {
+        if u64_at(record, FIELDS_BYTES) != checksum(&record[..FIELDS_BYTES]) {
+            return None;
+        }
+        let round_secs = f64::from_bits(u64_at(record, 40));
+        Some(TelemetrySample {
+            at_ms: u64_at(record, 0) as i64,
+            height: u64_at(record, 8),
+            head_height: u64_at(record, 16),
+            peers: u64_at(record, 24),
+            mempool: u64_at(record, 32),
+            round_secs: (!round_secs.is_nan()).then_some(round_secs),
+            llm_queue: u64_at(record, 48),
+            memory_bytes: u64_at(record, 56),
+        })
+    }
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(bytes[at..at + 8].try_into().expect("8 bytes"))
+}
+
+fn checksum(fields: &[u8]) -> u64 {
+    u64_at(&Sha256::digest(fields), 0)
+}
+
+/// `(interval_ms, slots)` from the header, or `None` if `file` has none.
+fn read_header(file: &mut File) -> io::Result<Option<(u64, u64)>> {
+    let mut header = [0; HEADER_BYTES as usize];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let version = u32::from_be_bytes(header[8..12].try_into().expect("4 bytes"));
+    if &header[..8] != MAGIC || version != FORMAT_VERSION {
+        return Ok(None);
+    }
+    Ok(Some((u64_at(&header, 16), u64_at(&header, 24))))
+}
+
+fn header(interval_ms: u64, slots: u64) -> [u8; HEADER_BYTES as usize] {
+    let mut header = [0; HEADER_BYTES as usize];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header[16..24].copy_from_slice(&interval_ms.to_be_bytes());
+    header[24..32].copy_from_slice(&slots.to_be_bytes());
+    header
+}
+
+/// The ring file.
+pub struct TelemetryStore {
+    path: PathBuf,
+    file: Mutex<File>,
+    interval_ms: u64,
+    slots: u64,
+    /// Record bytes written since opening.
+    bytes_written: AtomicU64,
+}
+
+impl TelemetryStore {
+    /// Opens the ring at `path`, creating it at full size for `config`. A
+    /// ring laid out for another interval or retention is started afresh.
+    pub fn open(path: impl Into<PathBuf>, config: &TelemetryConfig) -> Result<Self, TelemetryError> {
+        let path = path.into();
+        let (interval_ms, slots) = (config.interval_ms, config.slots());
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+        let len = HEADER_BYTES + slots * RECORD_BYTES as u64;
+        let current = read_header(&mut file)? == Some((interval_ms, slots)) && file.metadata()?.len() == len;
+        if !current {
+            if file.metadata()?.len() > 0 {
+                warn!(
+                    "Starting telemetry ring {} afresh for {} samples every {} ms",
+                    path.display(), slots, interval_ms
+                );
+            }
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header(interval_ms, slots))?;
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        Ok(TelemetryStore { path, file: Mutex::new(file), interval_ms, slots, bytes_written: AtomicU64::new(0) })
+    }
+
+    /// Opens an existing ring to read, laid out as its header says; a
+    /// running node may keep writing it meanwhile.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self, TelemetryError> {
+        let path = path.into();
+        let mut file = File::open(&path)?;
+        let (interval_ms, slots) = match read_header(&mut file)? {
+            Some((interval_ms, slots)) if interval_ms > 0 && slots > 0 => (interval_ms, slots),
+            _ => return Err(TelemetryError::NotARing(path)),
+        };
+        Ok(TelemetryStore { path, file: Mutex::new(file), interval_ms, slots, bytes_written: AtomicU64::new(0) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn retention_ms(&self) -> u64 {
+        self.slots * self.interval_ms
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    fn slot(&self, at_ms: i64) -> u64 {
+        (at_ms.max(0) as u64 / self.interval_ms) % self.slots
+    }
+
+    /// Writes `samples`, in the order taken, with one write per run of
+    /// adjacent slots and a single sync.
+    pub fn write_batch(&self, samples: &[TelemetrySample]) -> Result<(), TelemetryError> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let mut file = self.file.lock();
+        let (mut run, mut run_start, mut next_slot) = (Vec::new(), 0, None);
+        let mut written = 0;
+        for sample in samples {
+            let slot = self.slot(sample.at_ms);
+            if next_slot != Some(slot) {
+                written += write_run(&mut file, run_start, &run)?;
+                run.clear();
+                run_start = slot;
+            }
+            run.extend_from_slice(&sample.encode());
+            next_slot = Some(slot + 1).filter(|next| *next < self.slots);
+        }
+        written += write_run(&mut file, run_start, &run)?;
+        file.sync_data()?;
+        self.bytes_written.fetch_add(written, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Samples taken in `from_ms..to_ms`, oldest first. Slots left from
+    /// before a gap in sampling longer than the retention are skipped.
+    pub fn read(&self, from_ms: i64, to_ms: i64) -> Result<Vec<TelemetrySample>, TelemetryError> {
+        let mut bytes = vec![0; self.slots as usize * RECORD_BYTES];
+        {
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(HEADER_BYTES))?;
+            file.read_exact(&mut bytes)?;
+        }
+        let mut samples: Vec<_> = bytes.chunks_exact(RECORD_BYTES).filter_map(TelemetrySample::decode).collect();
+        let newest = samples.iter().map(|sample| sample.at_ms).max().unwrap_or(0);
+        let oldest = newest.saturating_sub(self.retention_ms() as i64);
+        samples.retain(|sample| sample.at_ms > oldest && (from_ms..to_ms).contains(&sample.at_ms));
+        samples.sort_by_key(|sample| sample.at_ms);
+        Ok(samples)
+    }
+}
+
+/// Writes `run` from `slot` on, returning the bytes written.
+fn write_run(file: &mut File, slot: u64, run: &[u8]) -> io::Result<u64> {
+    if run.is_empty() {
+        return Ok(0);
+    }
+    file.seek(SeekFrom::Start(HEADER_BYTES + slot * RECORD_BYTES as u64))?;
+    file.write_all(run)?;
+    Ok(run.len() as u64)
+}
+
+/// Where samples come from. Taking one must not wait on consensus.
+pub trait TelemetrySource: Send + Sync {
+    fn sample(&self, at_ms: i64) -> TelemetrySample;
+}
+
+/// Samples a running node from what its metrics read.
+pub struct NodeGauges {
+    network: Arc<Network>,
+    mempool: Arc<Mutex<Mempool>>,
+    consensus: Arc<ConsensusMetrics>,
+    #[cfg(feature = "llm")]
+    admission: Option<Arc<AdmissionController>>,
+}
+
+impl NodeGauges {
+    pub fn new(network: Arc<Network>, mempool: Arc<Mutex<Mempool>>, consensus: Arc<ConsensusMetrics>) -> Self {
+        NodeGauges {
+            network,
+            mempool,
+            consensus,
+            #[cfg(feature = "llm")]
+            admission: None,
+        }
+    }
+
+    /// Reads the LLM queue depth from `admission`.
+    #[cfg(feature = "llm")]
+    pub fn with_admission(mut self, admission: Arc<AdmissionController>) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    fn llm_queue(&self) -> u64 {
+        #[cfg(feature = "llm")]
+        if let Some(admission) = &self.admission {
+            return admission.waiting() as u64;
+        }
+        0
+    }
+}
+
+impl TelemetrySource for NodeGauges {
+    fn sample(&self, at_ms: i64) -> TelemetrySample {
+        let (head_height, height) = self.consensus.chain_heights();
+        TelemetrySample {
+            at_ms,
+            height,
+            head_height,
+            peers: self.network.peer_count() as u64,
+            mempool: self.mempool.lock().len() as u64,
+            round_secs: self.consensus.last_round_latency().map(|latency| latency.as_secs_f64()),
+            llm_queue: self.llm_queue(),
+            memory_bytes: ProcessMetrics::resident_memory_bytes().unwrap_or(0),
+        }
+    }
+}
+
+/// Samples `source` every `interval_ms` and writes the samples to the ring
+/// in batches of `flush_samples`, on the blocking pool. A slow disk delays
+/// nothing but the writes: past `MAX_PENDING_SAMPLES` unwritten samples
+/// the oldest are dropped.
+pub struct Telemetry {
+    store: TelemetryStore,
+    source: Arc<dyn TelemetrySource>,
+    config: TelemetryConfig,
+    pending: Mutex<VecDeque<TelemetrySample>>,
+    /// Held while a batch is written.
+    writing: Mutex<()>,
+    dropped: AtomicU64,
+}
+
+impl Telemetry {
+    pub fn new(store: TelemetryStore, source: Arc<dyn TelemetrySource>, config: TelemetryConfig) -> Self {
+        Telemetry {
+            store,
+            source,
+            config,
+            pending: Mutex::new(VecDeque::new()),
+            writing: Mutex::new(()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn store(&self) -> &TelemetryStore {
+        &self.store
+    }
+
+    /// Samples not yet written.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Samples dropped unwritten.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Queues `sample` for writing, returning whether a batch is due.
+    pub fn record(&self, sample: TelemetrySample) -> bool {
+        let mut pending = self.pending.lock();
+        if pending.len() == MAX_PENDING_SAMPLES {
+            pending.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        pending.push_back(sample);
+        pending.len() >= self.config.flush_samples
+    }
+
+    /// Writes the queued samples, returning how many. They stay queued,
+    /// and readable, until written; if the write fails they are tried
+    /// again with the next batch.
+    pub fn flush(&self) -> Result<usize, TelemetryError> {
+        let _writing = self.writing.lock();
+        let batch: Vec<_> = self.pending.lock().iter().cloned().collect();
+        self.store.write_batch(&batch)?;
+        if let Some(last) = batch.last() {
+            let mut pending = self.pending.lock();
+            while matches!(pending.front(), Some(sample) if sample.at_ms <= last.at_ms) {
+                pending.pop_front();
+            }
+        }
+        Ok(batch.len())
+    }
+
+    fn flush_logged(&self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to write telemetry to {}: {}", self.store.path().display(), e);
+        }
+    }
+
+    /// Samples taken in `from_ms..to_ms`, written or not, oldest first.
+    pub fn read(&self, from_ms: i64, to_ms: i64) -> Result<Vec<TelemetrySample>, TelemetryError> {
+        let mut samples = self.store.read(from_ms, to_ms)?;
+        let written_through = samples.last().map_or(i64::MIN, |sample| sample.at_ms);
+        let pending = self.pending.lock();
+        samples.extend(
+            pending.iter()
+                .filter(|sample| sample.at_ms > written_through && (from_ms..to_ms).contains(&sample.at_ms))
+                .cloned(),
+        );
+        Ok(samples)
+    }
+
+    /// Samples from the last `hours`, at most the retention.
+    pub fn recent(&self, hours: u64) -> Result<Vec<TelemetrySample>, TelemetryError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let span = hours.saturating_mul(HOUR_MS).min(self.store.retention_ms());
+        self.read(now.saturating_sub(span as i64), now.saturating_add(1))
+    }
+
+    /// Samples until `cancel` fires, then writes what is left.
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.interval_ms));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            let due = self.record(self.source.sample(chrono::Utc::now().timestamp_millis()));
+            // A batch still being written takes the new samples with the next.
+            if due && !self.writing.is_locked() {
+                let telemetry = Arc::clone(&self);
+                tokio::task::spawn_blocking(move || telemetry.flush_logged());
+            }
+        }
+        let telemetry = Arc::clone(&self);
+        let _ = tokio::task::spawn_blocking(move || telemetry.flush_logged()).await;
+    }
+}
+
+/// Writes `samples` as CSV with a header row; an unknown round time is
+/// left empty.
+pub fn write_csv(samples: &[TelemetrySample], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "at_ms,height,head_height,peers,mempool,round_secs,llm_queue,memory_bytes")?;
+    for sample in samples {
+        let round_secs = sample.round_secs.map(|secs| secs.to_string()).unwrap_or_default();
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            sample.at_ms, sample.height, sample.head_height, sample.peers, sample.mempool, round_secs,
+            sample.llm_queue, sample.memory_bytes
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL_MS: i64 = 10_000;
+
+    fn config() -> TelemetryConfig {
+        // Two hours at ten seconds: 720 slots.
+        TelemetryConfig { retention_hours: 2, ..TelemetryConfig::default() }
+    }
+
+    fn sample(at_ms: i64) -> TelemetrySample {
+        TelemetrySample {
+            at_ms,
+            height: at_ms as u64 / 1000,
+            head_height: at_ms as u64 / 1000 + 1,
+            peers: 4,
+            mempool: 12,
+            round_secs: (at_ms > 0).then_some(1.5),
+            llm_queue: 0,
+            memory_bytes: 64 << 20,
+        }
+    }
+
+    struct Fixed;
+
+    impl TelemetrySource for Fixed {
+        fn sample(&self, at_ms: i64) -> TelemetrySample {
+            sample(at_ms)
+        }
+    }
+
+    #[test]
+    fn test_samples_a_retention_old_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(TELEMETRY_FILE);
+        let store = TelemetryStore::open(&path, &config()).unwrap();
+        let retention = store.retention_ms() as i64;
+        let first: Vec<_> = (0..720).map(|i| sample(i * INTERVAL_MS)).collect();
+        store.write_batch(&first).unwrap();
+        assert_eq!(store.read(0, i64::MAX).unwrap(), first);
+
+        // Half a window later the first half is overwritten by the new samples.
+        let second: Vec<_> = (720..1080).map(|i| sample(i * INTERVAL_MS)).collect();
+        store.write_batch(&second).unwrap();
+        let kept = store.read(0, i64::MAX).unwrap();
+        assert_eq!(kept.len(), 720);
+        assert_eq!(kept[0].at_ms, 360 * INTERVAL_MS);
+        assert_eq!(kept.last().unwrap(), second.last().unwrap());
+
+        // After a gap the slots not yet overwritten are too old to report.
+        let late = sample(1080 * INTERVAL_MS + retention);
+        store.write_batch(std::slice::from_ref(&late)).unwrap();
+        assert_eq!(store.read(0, i64::MAX).unwrap(), vec![late]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_BYTES + 720 * RECORD_BYTES as u64);
+
+        // A torn record reads as missing.
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(HEADER_BYTES + store.slot(late.at_ms) * RECORD_BYTES as u64 + 3)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        assert!(store.read(0, i64::MAX).unwrap().is_empty());
+
+        // A ring laid out for another retention starts afresh.
+        drop(store);
+        let resized = TelemetryConfig { retention_hours: 1, ..config() };
+        let store = TelemetryStore::open(&path, &resized).unwrap();
+        store.write_batch(&[sample(0)]).unwrap();
+        assert_eq!(store.read(0, i64::MAX).unwrap(), vec![sample(0)]);
+        assert_eq!(TelemetryStore::open_read_only(&path).unwrap().retention_ms(), HOUR_MS);
+    }
+
+    #[test]
+    fn test_dump_ranges_are_half_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path().join(TELEMETRY_FILE), &config()).unwrap();
+        // Written out of order and across the end of the ring.
+        let mut samples: Vec<_> = (700..740).map(|i| sample(i * INTERVAL_MS)).collect();
+        samples.reverse();
+        store.write_batch(&samples).unwrap();
+
+        let range = store.read(710 * INTERVAL_MS, 720 * INTERVAL_MS).unwrap();
+        let taken: Vec<i64> = range.iter().map(|sample| sample.at_ms / INTERVAL_MS).collect();
+        assert_eq!(taken, (710..720).collect::<Vec<_>>());
+        assert!(store.read(740 * INTERVAL_MS, i64::MAX).unwrap().is_empty());
+        assert_eq!(store.read(i64::MIN, 701 * INTERVAL_MS).unwrap().len(), 1);
+
+        let mut csv = Vec::new();
+        write_csv(&range[..1], &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(1), Some("7100000,7100,7101,4,12,1.5,0,67108864"));
+
+        let reopened = TelemetryStore::open_read_only(dir.path().join(TELEMETRY_FILE)).unwrap();
+        assert_eq!(reopened.read(710 * INTERVAL_MS, 720 * INTERVAL_MS).unwrap(), range);
+        assert!(matches!(
+            TelemetryStore::open_read_only(dir.path().join("missing")),
+            Err(TelemetryError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_writes_are_batched_and_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path().join(TELEMETRY_FILE), &config()).unwrap();
+        let telemetry = Telemetry::new(store, Arc::new(Fixed), config());
+
+        // Nothing is written until a batch is due, and then each sample once.
+        for i in 0..(DEFAULT_TELEMETRY_FLUSH_SAMPLES as i64 * 20) {
+            let taken = Fixed.sample(i * INTERVAL_MS);
+            if telemetry.record(taken) {
+                assert_eq!(telemetry.flush().unwrap(), DEFAULT_TELEMETRY_FLUSH_SAMPLES);
+            }
+        }
+        let written = DEFAULT_TELEMETRY_FLUSH_SAMPLES as u64 * 20;
+        assert_eq!(telemetry.store().bytes_written(), written * RECORD_BYTES as u64);
+        assert_eq!(telemetry.pending(), 0);
+
+        // With the disk stalled, memory stays bounded and unwritten samples readable.
+        let start = written as i64;
+        for i in start..start + MAX_PENDING_SAMPLES as i64 + 10 {
+            telemetry.record(sample(i * INTERVAL_MS));
+        }
+        assert_eq!(telemetry.pending(), MAX_PENDING_SAMPLES);
+        assert_eq!(telemetry.dropped(), 10);
+        let read = telemetry.read(0, i64::MAX).unwrap();
+        assert_eq!(read.len(), written as usize + MAX_PENDING_SAMPLES);
+        assert!(read.windows(2).all(|pair| pair[0].at_ms < pair[1].at_ms));
+        assert_eq!(telemetry.flush().unwrap(), MAX_PENDING_SAMPLES);
+        assert_eq!(telemetry.store().bytes_written(), (written + MAX_PENDING_SAMPLES as u64) * RECORD_BYTES as u64);
+    }
+}
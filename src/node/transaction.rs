@@ -0,0 +1,122 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+};
+
+use super::config::DEFAULT_CHAIN_ID;
+use super::params::ParamChange;
+use super::vote::CommitCertificate;
+
+/// Largest `payload` nodes accept.
+pub const MAX_PAYLOAD_BYTES: usize = 1024;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum TxKind {
+    #[default]
+    Transfer,
+    /// Schedules new protocol parameters; `approvals` are the votes of a
+    /// validator quorum on the change. Carries no amount.
+    ParamChange { change: ParamChange, approvals: CommitCertificate },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+    pub nonce: u64,
+    pub timestamp: i64,
+    /// Signed, so a transaction cannot be replayed on another network.
+    pub chain_id: String,
+    /// Opaque application data.
+    pub payload: Vec<u8>,
+    #[serde(default)]
+    pub kind: TxKind,
+    pub signature: Signature,
+}
+
+impl Transaction {
+    /// A signed transfer on the default chain, without payload.
+    pub fn new_signed(keypair: &Keypair, recipient: Pubkey, amount: u64, fee: u64, nonce: u64, timestamp: i64) -> Self {
+        let mut transaction = Self::unsigned(keypair.pubkey(), recipient, amount, fee, nonce, timestamp);
+        transaction.signature = keypair.sign_message(&transaction.signing_bytes());
+        transaction
+    }
+
+    /// A transaction on the default chain awaiting its signature.
+    pub fn unsigned(sender: Pubkey, recipient: Pubkey, amount: u64, fee: u64, nonce: u64, timestamp: i64) -> Self {
+        Transaction {
+            sender,
+            recipient,
+            amount,
+            fee,
+            nonce,
+            timestamp,
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            payload: Vec::new(),
+            kind: TxKind::Transfer,
+            signature: Signature::default(),
+        }
+    }
+
+    /// A parameter change signed by `keypair`, on the default chain.
+    pub fn new_param_change(
+        keypair: &Keypair,
+        change: ParamChange,
+        approvals: CommitCertificate,
+        fee: u64,
+        nonce: u64,
+        timestamp: i64,
+    ) -> Self {
+        let mut transaction = Self::unsigned(keypair.pubkey(), keypair.pubkey(), 0, fee, nonce, timestamp);
+        transaction.kind = TxKind::ParamChange { change, approvals };
+        transaction.signature = keypair.sign_message(&transaction.signing_bytes());
+        transaction
+    }
+
+    /// The canonical encoding that is signed and hashed. Variable-length
+    /// fields are prefixed with their little-endian u32 length; the kind is
+    /// appended in its Borsh encoding.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let kind = self.kind.try_to_vec().expect("transaction kind serialization cannot fail");
+        let mut bytes = Vec::with_capacity(
+            32 + 32 + 8 + 8 + 8 + 8 + 4 + self.chain_id.len() + 4 + self.payload.len() + kind.len(),
+        );
+        bytes.extend_from_slice(self.sender.as_ref());
+        bytes.extend_from_slice(self.recipient.as_ref());
+        bytes.extend_from_slice(&self.amount.to_le_bytes());
+        bytes.extend_from_slice(&self.fee.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&(self.chain_id.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.chain_id.as_bytes());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes.extend_from_slice(&kind);
+        bytes
+    }
+
+    pub fn hash(&self) -> Hash {
+        hashv(&[&self.signing_bytes(), self.signature.as_ref()])
+    }
+
+    /// Serialized size in bytes, the denominator of the fee rate.
+    pub fn size(&self) -> usize {
+        self.try_to_vec().map(|bytes| bytes.len()).unwrap_or(usize::MAX)
+    }
+
+    /// Compares fee-per-byte without losing precision to division.
+    pub fn cmp_fee_rate(&self, other: &Transaction) -> std::cmp::Ordering {
+        let ours = self.fee as u128 * other.size() as u128;
+        let theirs = other.fee as u128 * self.size() as u128;
+        ours.cmp(&theirs)
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        self.signature.verify(self.sender.as_ref(), &self.signing_bytes())
+    }
+}
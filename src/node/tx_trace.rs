@@ -0,0 +1,213 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::Hash;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+use super::block::Block;
+use super::fork_choice::ChainUpdate;
+use super::network::PeerId;
+use super::validation::ValidationResult;
+
+/// Transactions whose lifecycle is kept for `trace_transaction`.
+pub const DEFAULT_TX_TRACE_CAPACITY: usize = 4096;
+/// Events kept per transaction; a transaction gossiped back and forth
+/// would otherwise grow without bound.
+const MAX_EVENTS_PER_TRANSACTION: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStage {
+    /// Arrived over RPC, or from `peer` by gossip.
+    Received,
+    /// Accepted into the mempool.
+    Admitted,
+    /// Refused by the mempool.
+    Rejected,
+    /// Forwarded to peers.
+    Gossiped,
+    /// Checked by consensus while building a block.
+    Validated,
+    /// In a block that joined the canonical chain.
+    Included,
+    Finalized,
+}
+
+impl fmt::Display for TxStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            TxStage::Received => "received",
+            TxStage::Admitted => "admitted",
+            TxStage::Rejected => "rejected",
+            TxStage::Gossiped => "gossiped",
+            TxStage::Validated => "validated",
+            TxStage::Included => "included",
+            TxStage::Finalized => "finalized",
+        };
+        f.write_str(stage)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxEvent {
+    pub stage: TxStage,
+    pub timestamp_ms: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_height: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl TxEvent {
+    pub fn new(stage: TxStage) -> Self {
+        TxEvent {
+            stage,
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            peer: None,
+            block_height: None,
+            block_hash: None,
+            detail: None,
+        }
+    }
+
+    pub fn with_peer(mut self, peer: PeerId) -> Self {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    pub fn with_block(mut self, block: &Block) -> Self {
+        self.block_height = Some(block.height());
+        self.block_hash = Some(block.hash().to_string());
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+#[derive(Default)]
+struct TraceBuffer {
+    events: HashMap<Hash, Vec<TxEvent>>,
+    /// Traced transactions, oldest first.
+    order: VecDeque<Hash>,
+}
+
+/// Lifecycle events of recent transactions, keyed by hash. Each recorded
+/// event is also emitted as a `tracing` event inside a `tx` span carrying
+/// the hash. Once `capacity` transactions are traced, the oldest is
+/// forgotten to make room.
+#[derive(Clone)]
+pub struct TxTracer {
+    capacity: usize,
+    buffer: Arc<Mutex<TraceBuffer>>,
+}
+
+impl TxTracer {
+    pub fn new(capacity: usize) -> Self {
+        TxTracer { capacity, buffer: Arc::new(Mutex::new(TraceBuffer::default())) }
+    }
+
+    pub fn record(&self, hash: &Hash, event: TxEvent) {
+        let span = tracing::debug_span!("tx", hash = %hash);
+        let _entered = span.enter();
+        tracing::debug!(
+            stage = %event.stage,
+            peer = event.peer.as_deref(),
+            block_height = event.block_height,
+            detail = event.detail.as_deref(),
+            "transaction {}",
+            event.stage
+        );
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut buffer = self.buffer.lock();
+        if !buffer.events.contains_key(hash) {
+            while buffer.order.len() >= self.capacity {
+                if let Some(oldest) = buffer.order.pop_front() {
+                    buffer.events.remove(&oldest);
+                }
+            }
+            buffer.order.push_back(*hash);
+        }
+        let events = buffer.events.entry(*hash).or_default();
+        if events.len() < MAX_EVENTS_PER_TRANSACTION {
+            events.push(event);
+        }
+    }
+
+    /// Records the outcome of consensus validation.
+    pub fn record_validation(&self, hash: &Hash, result: &ValidationResult) {
+        let detail = match result {
+            ValidationResult::Accepted { confirmations } => format!("accepted with {} confirmations", confirmations),
+            ValidationResult::Rejected { stage, confirmations } => {
+                format!("rejected at {} with {} confirmations", stage, confirmations)
+            }
+            ValidationResult::Conflict(error) => format!("conflict: {}", error),
+        };
+        self.record(hash, TxEvent::new(TxStage::Validated).with_detail(detail));
+    }
+
+    /// Records inclusion and finality for every transaction in `update`.
+    pub fn record_update(&self, update: &ChainUpdate) {
+        for (stage, blocks) in [(TxStage::Included, &update.applied), (TxStage::Finalized, &update.finalized)] {
+            for block in blocks {
+                for transaction in &block.transactions {
+                    self.record(&transaction.hash(), TxEvent::new(stage).with_block(block));
+                }
+            }
+        }
+    }
+
+    /// Events for `hash` in the order they happened, if it is still traced.
+    pub fn trace(&self, hash: &Hash) -> Option<Vec<TxEvent>> {
+        self.buffer.lock().events.get(hash).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.lock().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for TxTracer {
+    fn default() -> Self {
+        Self::new(DEFAULT_TX_TRACE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_transaction_evicted() {
+        let tracer = TxTracer::new(2);
+        let hashes = [Hash::new_unique(), Hash::new_unique(), Hash::new_unique()];
+        tracer.record(&hashes[0], TxEvent::new(TxStage::Received));
+        tracer.record(&hashes[1], TxEvent::new(TxStage::Received));
+        tracer.record(&hashes[0], TxEvent::new(TxStage::Admitted));
+        tracer.record(&hashes[2], TxEvent::new(TxStage::Received));
+
+        assert_eq!(tracer.len(), 2);
+        assert!(tracer.trace(&hashes[0]).is_none());
+        assert_eq!(tracer.trace(&hashes[1]).unwrap().len(), 1);
+        assert_eq!(tracer.trace(&hashes[2]).unwrap()[0].stage, TxStage::Received);
+
+        for _ in 0..MAX_EVENTS_PER_TRANSACTION * 2 {
+            tracer.record(&hashes[2], TxEvent::new(TxStage::Gossiped));
+        }
+        assert_eq!(tracer.trace(&hashes[2]).unwrap().len(), MAX_EVENTS_PER_TRANSACTION);
+    }
+}
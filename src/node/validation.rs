@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+use super::state::{Account, State};
+use super::transaction::Transaction;
+use crate::utils::DADBSAddress;
+
+/// The check that rejected a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValidationStage {
+    /// Signed for another chain.
+    Chain,
+    Fee,
+    Signature,
+    Timestamp,
+    Balance,
+    Nonce,
+    /// A parameter change that may not be scheduled.
+    Params,
+    Quorum,
+}
+
+impl fmt::Display for ValidationStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            ValidationStage::Chain => "chain",
+            ValidationStage::Fee => "fee",
+            ValidationStage::Signature => "signature",
+            ValidationStage::Timestamp => "timestamp",
+            ValidationStage::Balance => "balance",
+            ValidationStage::Nonce => "nonce",
+            ValidationStage::Params => "params",
+            ValidationStage::Quorum => "quorum",
+        };
+        f.write_str(stage)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValidationResult {
+    Accepted { confirmations: usize },
+    Rejected { stage: ValidationStage, confirmations: usize },
+    /// Rejected against account state before any validator was asked.
+    Conflict(ValidationError),
+}
+
+impl ValidationResult {
+    pub fn rejected(stage: ValidationStage) -> Self {
+        ValidationResult::Rejected { stage, confirmations: 0 }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        matches!(self, ValidationResult::Accepted { .. })
+    }
+
+    pub fn confirmations(&self) -> usize {
+        match self {
+            ValidationResult::Accepted { confirmations }
+            | ValidationResult::Rejected { confirmations, .. } => *confirmations,
+            ValidationResult::Conflict(_) => 0,
+        }
+    }
+
+    /// The failing stage, or `None` if the transaction was accepted.
+    pub fn stage(&self) -> Option<ValidationStage> {
+        match self {
+            ValidationResult::Accepted { .. } => None,
+            ValidationResult::Rejected { stage, .. } => Some(*stage),
+            ValidationResult::Conflict(error) => Some(error.stage()),
+        }
+    }
+}
+
+/// Why a transaction conflicts with account state or with transactions
+/// accepted before it.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValidationError {
+    #[error("Account {address} has {available} available, needs {required}")]
+    InsufficientBalance { address: DADBSAddress, available: u64, required: u64 },
+    #[error("Account {address} expects nonce {expected}, got {actual}")]
+    NonceConflict { address: DADBSAddress, expected: u64, actual: u64 },
+}
+
+/// Account state overlaid with the effects of transactions already accepted
+/// into the same batch, so a balance can only be spent once.
+pub struct BatchLedger<'a> {
+    state: &'a State,
+    pending: HashMap<DADBSAddress, Account>,
+}
+
+impl<'a> BatchLedger<'a> {
+    pub fn new(state: &'a State) -> Self {
+        BatchLedger { state, pending: HashMap::new() }
+    }
+
+    fn account(&self, address: &DADBSAddress) -> Account {
+        self.pending.get(address).copied().unwrap_or_else(|| self.state.account(address))
+    }
+
+    /// Checks `transaction` and, if it fits, debits the sender and credits
+    /// the recipient for the rest of the batch.
+    pub fn admit(&mut self, transaction: &Transaction) -> Result<(), ValidationError> {
+        let sender = DADBSAddress::from_pubkey(&transaction.sender);
+        let mut account = self.account(&sender);
+        if transaction.nonce != account.nonce {
+            return Err(ValidationError::NonceConflict {
+                address: sender,
+                expected: account.nonce,
+                actual: transaction.nonce,
+            });
+        }
+
+        let required = transaction.amount.saturating_add(transaction.fee);
+        if account.balance < required {
+            return Err(ValidationError::InsufficientBalance {
+                address: sender,
+                available: account.balance,
+                required,
+            });
+        }
+
+        account.balance -= required;
+        account.nonce += 1;
+        self.pending.insert(sender, account);
+
+        let recipient = DADBSAddress::from_pubkey(&transaction.recipient);
+        let mut credited = self.account(&recipient);
+        credited.balance = credited.balance.saturating_add(transaction.amount);
+        self.pending.insert(recipient, credited);
+        Ok(())
+    }
+}
+
+impl ValidationError {
+    pub fn stage(&self) -> ValidationStage {
+        match self {
+            ValidationError::InsufficientBalance { .. } => ValidationStage::Balance,
+            ValidationError::NonceConflict { .. } => ValidationStage::Nonce,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{
+        pubkey::Pubkey,
+        signature::{Keypair, Signer},
+    };
+
+    fn setup(balance: u64) -> (Keypair, State) {
+        let sender = Keypair::new();
+        let state = State::in_memory(vec![(DADBSAddress::from_pubkey(&sender.pubkey()), balance)]);
+        (sender, state)
+    }
+
+    fn spend(sender: &Keypair, amount: u64, nonce: u64) -> Transaction {
+        Transaction::new_signed(sender, Pubkey::new_unique(), amount, 10, nonce, 0)
+    }
+
+    #[test]
+    fn test_double_spend_in_one_batch() {
+        let (sender, state) = setup(100);
+        let mut ledger = BatchLedger::new(&state);
+        ledger.admit(&spend(&sender, 80, 0)).unwrap();
+        assert_eq!(
+            ledger.admit(&spend(&sender, 80, 1)),
+            Err(ValidationError::InsufficientBalance {
+                address: DADBSAddress::from_pubkey(&sender.pubkey()),
+                available: 10,
+                required: 90,
+            })
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_nonces() {
+        let (sender, state) = setup(1_000);
+        let mut ledger = BatchLedger::new(&state);
+        let err = ledger.admit(&spend(&sender, 1, 1)).unwrap_err();
+        assert!(matches!(err, ValidationError::NonceConflict { expected: 0, actual: 1, .. }));
+        assert_eq!(err.stage(), ValidationStage::Nonce);
+
+        ledger.admit(&spend(&sender, 1, 0)).unwrap();
+        ledger.admit(&spend(&sender, 1, 1)).unwrap();
+        assert!(matches!(
+            ledger.admit(&spend(&sender, 1, 1)),
+            Err(ValidationError::NonceConflict { expected: 2, actual: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_exact_balance_boundary() {
+        let (sender, state) = setup(110);
+        let mut ledger = BatchLedger::new(&state);
+        assert!(matches!(
+            BatchLedger::new(&state).admit(&spend(&sender, 101, 0)),
+            Err(ValidationError::InsufficientBalance { available: 110, required: 111, .. })
+        ));
+        ledger.admit(&spend(&sender, 100, 0)).unwrap();
+        assert!(matches!(
+            ledger.admit(&spend(&sender, 0, 1)),
+            Err(ValidationError::InsufficientBalance { available: 0, required: 10, .. })
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::hash::{hashv, Hash};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::BTreeMap;
+
+use super::crypto::{CryptoError, SchemeKind};
+use super::transaction::Transaction;
+
+/// A remote validator that can be asked to confirm a transaction.
+#[async_trait]
+pub trait Validator: Send + Sync {
+    fn pubkey(&self) -> Pubkey;
+    async fn verify_transaction(&self, transaction: &Transaction) -> bool;
+
+    /// Confirms many transactions in one round-trip, returning one answer per
+    /// transaction in order. Remote validators should override this; the
+    /// default falls back to one request per transaction.
+    async fn verify_batch(&self, transactions: &[Transaction]) -> Vec<bool> {
+        let mut confirmed = Vec::with_capacity(transactions.len());
+        for transaction in transactions {
+            confirmed.push(self.verify_transaction(transaction).await);
+        }
+        confirmed
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorInfo {
+    pub pubkey: Pubkey,
+    pub weight: u128,
+    #[serde(default)]
+    pub scheme: SchemeKind,
+    /// Public key votes are verified against. Ed25519 validators may leave
+    /// this unset to sign with their identity key.
+    #[serde(default)]
+    pub consensus_key: Option<Vec<u8>>,
+}
+
+impl ValidatorInfo {
+    pub fn new(pubkey: Pubkey, weight: u128) -> Self {
+        ValidatorInfo {
+            pubkey,
+            weight,
+            scheme: SchemeKind::Ed25519,
+            consensus_key: None,
+        }
+    }
+
+    pub fn with_consensus_key(mut self, scheme: SchemeKind, consensus_key: Vec<u8>) -> Self {
+        self.scheme = scheme;
+        self.consensus_key = Some(consensus_key);
+        self
+    }
+
+    pub fn consensus_key(&self) -> &[u8] {
+        self.consensus_key.as_deref().unwrap_or(self.pubkey.as_ref())
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorSet {
+    validators: Vec<ValidatorInfo>,
+}
+
+impl ValidatorSet {
+    pub fn new(validators: Vec<ValidatorInfo>) -> Self {
+        ValidatorSet { validators }
+    }
+
+    /// Builds a set, rejecting one whose validators sign with different
+    /// schemes since its certificates could not be verified uniformly.
+    pub fn try_new(validators: Vec<ValidatorInfo>) -> Result<Self, CryptoError> {
+        let set = ValidatorSet { validators };
+        set.scheme()?;
+        Ok(set)
+    }
+
+    /// The scheme shared by every validator; an empty set uses Ed25519.
+    pub fn scheme(&self) -> Result<SchemeKind, CryptoError> {
+        let mut schemes = self.validators.iter().map(|v| v.scheme);
+        let first = schemes.next().unwrap_or_default();
+        match schemes.find(|scheme| *scheme != first) {
+            Some(other) => Err(CryptoError::MixedSchemes(first, other)),
+            None => Ok(first),
+        }
+    }
+
+    pub fn validators(&self) -> &[ValidatorInfo] {
+        &self.validators
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    pub fn get(&self, pubkey: &Pubkey) -> Option<&ValidatorInfo> {
+        self.validators.iter().find(|v| v.pubkey == *pubkey)
+    }
+
+    pub fn contains(&self, pubkey: &Pubkey) -> bool {
+        self.get(pubkey).is_some()
+    }
+
+    pub fn weight_of(&self, pubkey: &Pubkey) -> u128 {
+        self.get(pubkey).map_or(0, |v| v.weight)
+    }
+
+    pub fn total_weight(&self) -> u128 {
+        self.validators.iter().map(|v| v.weight).sum()
+    }
+
+    /// What a block header announcing this set commits to.
+    pub fn hash(&self) -> Hash {
+        hashv(&[&self.try_to_vec().expect("validator set serialization cannot fail")])
+    }
+}
+
+/// Validator sets keyed by the first height they became active at, so blocks
+/// fetched during sync are checked against the set that actually signed them.
+#[derive(Debug, Clone, Default)]
+pub struct ValidatorSetHistory {
+    sets: BTreeMap<u64, ValidatorSet>,
+}
+
+impl ValidatorSetHistory {
+    pub fn new(genesis_set: ValidatorSet) -> Self {
+        let mut sets = BTreeMap::new();
+        sets.insert(0, genesis_set);
+        ValidatorSetHistory { sets }
+    }
+
+    pub fn insert(&mut self, activation_height: u64, set: ValidatorSet) {
+        self.sets.insert(activation_height, set);
+    }
+
+    pub fn set_at(&self, height: u64) -> Option<&ValidatorSet> {
+        self.sets.range(..=height).next_back().map(|(_, set)| set)
+    }
+
+    pub fn latest(&self) -> Option<&ValidatorSet> {
+        self.sets.values().next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mixed_schemes_rejected() {
+        let ed25519 = ValidatorInfo::new(Pubkey::new_unique(), 1);
+        let bls = ValidatorInfo::new(Pubkey::new_unique(), 1).with_consensus_key(SchemeKind::Bls, vec![0; 48]);
+
+        assert_eq!(ValidatorSet::try_new(vec![ed25519.clone()]).unwrap().scheme(), Ok(SchemeKind::Ed25519));
+        assert_eq!(
+            ValidatorSet::try_new(vec![ed25519, bls]),
+            Err(CryptoError::MixedSchemes(SchemeKind::Ed25519, SchemeKind::Bls))
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! Follows on-chain stake: at each epoch boundary, and once at startup, the
+//! stake program's active accounts are read over Solana RPC and their
+//! amounts become the weights of the validators registered for their
+//! owners.
+//!
+//! A set that cannot be fetched, or that has fewer than `min_validators`
+//! registered validators with stake, is never applied: the previous set
+//! stays in force and `dadbs_validator_oracle_failing` is raised until an
+//! attempt succeeds. A validator holding more than `max_weight_bps` of the
+//! weight is applied, but warned about.
+
+use async_trait::async_trait;
+use borsh::BorshDeserialize;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+
+use super::consensus::ConsensusManager;
+use super::metrics::{self, MetricsSource};
+use super::subscriptions::ChainEvents;
+use super::validator::{ValidatorInfo, ValidatorSet};
+use crate::program::stake::StakeAccount;
+use crate::utils::DADBSAddress;
+
+pub const DEFAULT_MIN_VALIDATORS: usize = 4;
+/// A third of the weight, enough to stall finality alone.
+pub const DEFAULT_MAX_WEIGHT_BPS: u16 = 3_333;
+pub const DEFAULT_RETRY_INTERVAL_MS: u64 = 10_000;
+
+/// Maps the owner of a stake account to the node identity it stakes for:
+/// the key the validator signs blocks and handshakes with, and which its
+/// `DADBSAddress` derives from.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ValidatorRegistration {
+    pub stake_owner: String,
+    pub identity: String,
+}
+
+/// The `[validator_oracle]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ValidatorOracleConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Solana RPC endpoint serving the stake program's accounts.
+    #[serde(default)]
+    pub rpc_url: String,
+    #[serde(default)]
+    pub program_id: String,
+    #[serde(default)]
+    pub registrations: Vec<ValidatorRegistration>,
+    /// Fewer registered validators with stake than this keeps the previous set.
+    #[serde(default = "default_min_validators")]
+    pub min_validators: usize,
+    /// Share of the total weight, in basis points, above which one
+    /// validator is warned about.
+    #[serde(default = "default_max_weight_bps")]
+    pub max_weight_bps: u16,
+    /// Wait after a failed attempt before trying the same epoch again.
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+}
+
+fn default_min_validators() -> usize {
+    DEFAULT_MIN_VALIDATORS
+}
+
+fn default_max_weight_bps() -> u16 {
+    DEFAULT_MAX_WEIGHT_BPS
+}
+
+fn default_retry_interval_ms() -> u64 {
+    DEFAULT_RETRY_INTERVAL_MS
+}
+
+impl Default for ValidatorOracleConfig {
+    fn default() -> Self {
+        ValidatorOracleConfig {
+            enabled: false,
+            rpc_url: String::new(),
+            program_id: String::new(),
+            registrations: Vec::new(),
+            min_validators: DEFAULT_MIN_VALIDATORS,
+            max_weight_bps: DEFAULT_MAX_WEIGHT_BPS,
+            retry_interval_ms: DEFAULT_RETRY_INTERVAL_MS,
+        }
+    }
+}
+
+impl ValidatorOracleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_validators == 0 {
+            return Err("validator_oracle.min_validators must be at least 1".to_string());
+        }
+        if self.max_weight_bps == 0 || self.max_weight_bps > 10_000 {
+            return Err(format!(
+                "validator_oracle.max_weight_bps must be between 1 and 10000, got {}",
+                self.max_weight_bps
+            ));
+        }
+        self.identities()?;
+        if self.enabled {
+            if self.rpc_url.is_empty() {
+                return Err("validator_oracle.rpc_url must be set".to_string());
+            }
+            self.program_id()?;
+        }
+        Ok(())
+    }
+
+    pub fn program_id(&self) -> Result<Pubkey, String> {
+        Pubkey::from_str(&self.program_id).map_err(|e| format!("validator_oracle.program_id: {}", e))
+    }
+
+    /// Registered identities by stake owner. Each owner and each identity
+    /// may be registered once.
+    pub fn identities(&self) -> Result<HashMap<Pubkey, Pubkey>, String> {
+        let parse = |key: &str| {
+            Pubkey::from_str(key).map_err(|e| format!("validator_oracle.registrations: bad key {}: {}", key, e))
+        };
+        let mut identities = HashMap::new();
+        let mut registered = HashSet::new();
+        for registration in &self.registrations {
+            let (owner, identity) = (parse(&registration.stake_owner)?, parse(&registration.identity)?);
+            if identities.insert(owner, identity).is_some() || !registered.insert(identity) {
+                return Err(format!(
+                    "validator_oracle.registrations: {} or {} is registered twice",
+                    registration.stake_owner, registration.identity
+                ));
+            }
+        }
+        Ok(identities)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum OracleError {
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Fetching stake records failed: {0}")]
+    Fetch(String),
+    #[error("Only {found} registered validators have stake; at least {required} are needed")]
+    TooFewValidators { found: usize, required: usize },
+}
+
+/// An active stake account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidatorRecord {
+    pub stake_account: Pubkey,
+    pub owner: Pubkey,
+    /// Lamports staked.
+    pub stake: u64,
+}
+
+impl ValidatorRecord {
+    /// The record in a stake program account's `data`, if it is an active
+    /// stake account; the program's config account is not.
+    pub fn parse(stake_account: Pubkey, data: &[u8]) -> Option<Self> {
+        let account = StakeAccount::try_from_slice(data).ok()?;
+        (account.is_active && account.amount > 0)
+            .then_some(ValidatorRecord { stake_account, owner: account.owner, stake: account.amount })
+    }
+}
+
+/// Where stake records come from; mocked in tests.
+#[async_trait]
+pub trait StakeSource: Send + Sync {
+    async fn validator_records(&self) -> Result<Vec<ValidatorRecord>, String>;
+}
+
+pub struct RpcStakeSource {
+    rpc_client: solana_client::nonblocking::rpc_client::RpcClient,
+    program_id: Pubkey,
+}
+
+impl RpcStakeSource {
+    pub fn new(rpc_url: String, program_id: Pubkey) -> Self {
+        RpcStakeSource { rpc_client: solana_client::nonblocking::rpc_client::RpcClient::new(rpc_url), program_id }
+    }
+}
+
+#[async_trait]
+impl StakeSource for RpcStakeSource {
+    async fn validator_records(&self) -> Result<Vec<ValidatorRecord>, String> {
+        let accounts = self.rpc_client.get_program_accounts(&self.program_id).await.map_err(|e| e.to_string())?;
+        Ok(accounts
+            .into_iter()
+            .filter_map(|(address, account)| ValidatorRecord::parse(address, &account.data))
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ValidatorOracleSnapshot {
+    pub syncs: u64,
+    pub failures: u64,
+    /// Whether the last attempt failed, leaving an older set in force.
+    pub failing: bool,
+    pub synced_epoch: Option<u64>,
+    pub validators: usize,
+    /// Largest share of the weight one validator holds, from 0 to 1.
+    pub max_weight_share: f64,
+}
+
+#[derive(Default)]
+pub struct ValidatorOracleMetrics {
+    inner: Mutex<ValidatorOracleSnapshot>,
+}
+
+impl ValidatorOracleMetrics {
+    pub fn snapshot(&self) -> ValidatorOracleSnapshot {
+        *self.inner.lock()
+    }
+}
+
+impl MetricsSource for ValidatorOracleMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        let snapshot = self.snapshot();
+        metrics::write_counter(
+            out,
+            "dadbs_validator_oracle_syncs_total",
+            "Validator sets applied from stake",
+            snapshot.syncs,
+        );
+        metrics::write_counter(
+            out,
+            "dadbs_validator_oracle_failures_total",
+            "Attempts to follow stake that kept the previous validator set",
+            snapshot.failures,
+        );
+        metrics::write_gauge(
+            out,
+            "dadbs_validator_oracle_failing",
+            "1 while the validator set in force is older than the epoch's stake",
+            if snapshot.failing { 1.0 } else { 0.0 },
+        );
+        if let Some(epoch) = snapshot.synced_epoch {
+            metrics::write_gauge(out, "dadbs_validator_oracle_epoch", "Epoch of the last applied set", epoch as f64);
+        }
+        metrics::write_gauge(
+            out,
+            "dadbs_validator_oracle_max_weight_share",
+            "Largest share of the weight one validator holds",
+            snapshot.max_weight_share,
+        );
+    }
+}
+
+/// Keeps a `ConsensusManager`'s validator set in line with stake.
+pub struct ValidatorSetOracle {
+    source: Arc<dyn StakeSource>,
+    identities: HashMap<Pubkey, Pubkey>,
+    min_validators: usize,
+    max_weight_bps: u16,
+    retry_interval: Duration,
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    metrics: Arc<ValidatorOracleMetrics>,
+}
+
+impl ValidatorSetOracle {
+    pub fn new(
+        config: &ValidatorOracleConfig,
+        source: Arc<dyn StakeSource>,
+        consensus: Arc<AsyncMutex<ConsensusManager>>,
+    ) -> Result<Self, OracleError> {
+        config.validate().map_err(OracleError::Config)?;
+        Ok(ValidatorSetOracle {
+            source,
+            identities: config.identities().map_err(OracleError::Config)?,
+            min_validators: config.min_validators,
+            max_weight_bps: config.max_weight_bps,
+            retry_interval: Duration::from_millis(config.retry_interval_ms),
+            consensus,
+            metrics: Arc::new(ValidatorOracleMetrics::default()),
+        })
+    }
+
+    pub fn metrics(&self) -> Arc<ValidatorOracleMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The set `records` stake for: each registered owner's stake, summed
+    /// over its accounts, is its validator's weight. Validators already in
+    /// `previous` keep their consensus keys.
+    pub fn build_set(&self, records: &[ValidatorRecord], previous: &ValidatorSet) -> Result<ValidatorSet, OracleError> {
+        let mut weights: BTreeMap<Pubkey, u128> = BTreeMap::new();
+        let mut unregistered = 0;
+        for record in records {
+            match self.identities.get(&record.owner) {
+                Some(identity) => *weights.entry(*identity).or_default() += u128::from(record.stake),
+                None => unregistered += 1,
+            }
+        }
+        if unregistered > 0 {
+            debug!("Ignoring {} stake accounts of unregistered owners", unregistered);
+        }
+        if weights.len() < self.min_validators {
+            return Err(OracleError::TooFewValidators { found: weights.len(), required: self.min_validators });
+        }
+        let validators = weights
+            .into_iter()
+            .map(|(identity, weight)| match previous.get(&identity) {
+                Some(known) => ValidatorInfo { weight, ..known.clone() },
+                None => ValidatorInfo::new(identity, weight),
+            })
+            .collect();
+        Ok(ValidatorSet::new(validators))
+    }
+
+    /// Fetches stake and applies the set it gives for `epoch`. On failure
+    /// the set in force is kept.
+    pub async fn sync_epoch(&self, epoch: u64) -> Result<ValidatorSet, OracleError> {
+        let result = match self.source.validator_records().await {
+            Ok(records) => {
+                let mut consensus = self.consensus.lock().await;
+                self.build_set(&records, consensus.validator_set()).map(|set| {
+                    consensus.replace_set(set.clone());
+                    set
+                })
+            }
+            Err(e) => Err(OracleError::Fetch(e)),
+        };
+        let mut metrics = self.metrics.inner.lock();
+        match &result {
+            Ok(set) => {
+                let max_share = set.validators().iter().map(|v| v.weight).max().unwrap_or(0) as f64
+                    / set.total_weight().max(1) as f64;
+                if max_share * 10_000.0 > f64::from(self.max_weight_bps) {
+                    warn!(
+                        "One validator holds {:.1}% of the weight in epoch {}, above the {:.1}% warned at",
+                        max_share * 100.0,
+                        epoch,
+                        f64::from(self.max_weight_bps) / 100.0
+                    );
+                }
+                let validators: Vec<String> =
+                    set.validators().iter().map(|v| DADBSAddress::from_pubkey(&v.pubkey).to_string()).collect();
+                info!("Validator set for epoch {} from stake: {}", epoch, validators.join(", "));
+                metrics.syncs += 1;
+                metrics.failing = false;
+                metrics.synced_epoch = Some(epoch);
+                metrics.validators = set.len();
+                metrics.max_weight_share = max_share;
+            }
+            Err(e) => {
+                warn!("Keeping the previous validator set for epoch {}: {}", epoch, e);
+                metrics.failures += 1;
+                metrics.failing = true;
+            }
+        }
+        result
+    }
+
+    /// Syncs once on the first finalized block from now, then on the first
+    /// of each later epoch, until `cancel` fires. A failed epoch is tried
+    /// again on finalized blocks at most every `retry_interval_ms`.
+    pub fn run(self: Arc<Self>, events: &ChainEvents, cancel: CancellationToken) -> impl Future<Output = ()> + Send {
+        let mut finalized = events.finalized();
+        async move {
+            let mut retry_at: Option<Instant> = None;
+            loop {
+                let height = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    block = finalized.recv() => match block {
+                        Ok(block) => block.height(),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+                let epoch = self.consensus.lock().await.params().epoch_of(height);
+                let synced = self.metrics.snapshot().synced_epoch;
+                if synced.is_some_and(|synced| synced >= epoch) || retry_at.is_some_and(|at| Instant::now() < at) {
+                    continue;
+                }
+                retry_at = self.sync_epoch(epoch).await.is_err().then(|| Instant::now() + self.retry_interval);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::crypto::SchemeKind;
+    use crate::node::quorum::ThresholdPolicy;
+    use std::collections::VecDeque;
+
+    /// Answers each fetch with the next of `responses`.
+    struct MockSource {
+        responses: Mutex<VecDeque<Result<Vec<ValidatorRecord>, String>>>,
+    }
+
+    #[async_trait]
+    impl StakeSource for MockSource {
+        async fn validator_records(&self) -> Result<Vec<ValidatorRecord>, String> {
+            self.responses.lock().pop_front().expect("a response for every fetch")
+        }
+    }
+
+    fn record(owner: Pubkey, stake: u64) -> ValidatorRecord {
+        ValidatorRecord { stake_account: Pubkey::new_unique(), owner, stake }
+    }
+
+    /// Five owners registered for five identities, and a consensus manager
+    /// running a configured set of the first two identities.
+    fn oracle() -> (ValidatorSetOracle, Arc<MockSource>, Vec<(Pubkey, Pubkey)>, Arc<AsyncMutex<ConsensusManager>>) {
+        let keys: Vec<(Pubkey, Pubkey)> = (0..5).map(|_| (Pubkey::new_unique(), Pubkey::new_unique())).collect();
+        let config = ValidatorOracleConfig {
+            registrations: keys
+                .iter()
+                .map(|(owner, identity)| ValidatorRegistration {
+                    stake_owner: owner.to_string(),
+                    identity: identity.to_string(),
+                })
+                .collect(),
+            min_validators: 3,
+            ..ValidatorOracleConfig::default()
+        };
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()));
+        consensus.set_validator_set(ValidatorSet::new(vec![
+            ValidatorInfo::new(keys[0].1, 1).with_consensus_key(SchemeKind::Ed25519, vec![7; 32]),
+            ValidatorInfo::new(keys[1].1, 1),
+        ]));
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let source = Arc::new(MockSource { responses: Mutex::new(VecDeque::new()) });
+        let oracle = ValidatorSetOracle::new(&config, source.clone(), Arc::clone(&consensus)).unwrap();
+        (oracle, source, keys, consensus)
+    }
+
+    #[tokio::test]
+    async fn test_set_follows_stake_across_epochs() {
+        let (oracle, source, keys, consensus) = oracle();
+        let stranger = Pubkey::new_unique();
+        let epoch_one = vec![
+            record(keys[0].0, 100),
+            record(keys[1].0, 200),
+            record(keys[2].0, 300),
+            record(stranger, 1_000_000),
+        ];
+        // The second validator unstakes; the fourth joins with two accounts.
+        let epoch_two = vec![
+            record(keys[0].0, 150),
+            record(keys[2].0, 300),
+            record(keys[3].0, 50),
+            record(keys[3].0, 70),
+        ];
+        source.responses.lock().extend([Ok(epoch_one), Ok(epoch_two)]);
+
+        oracle.sync_epoch(1).await.unwrap();
+        let weights = |set: &ValidatorSet| -> BTreeMap<Pubkey, u128> {
+            set.validators().iter().map(|v| (v.pubkey, v.weight)).collect()
+        };
+        let set = consensus.lock().await.validator_set().clone();
+        assert_eq!(weights(&set), BTreeMap::from([(keys[0].1, 100), (keys[1].1, 200), (keys[2].1, 300)]));
+        // A known validator keeps the key it votes with.
+        assert_eq!(set.get(&keys[0].1).unwrap().consensus_key, Some(vec![7; 32]));
+
+        oracle.sync_epoch(2).await.unwrap();
+        let set = consensus.lock().await.validator_set().clone();
+        assert_eq!(weights(&set), BTreeMap::from([(keys[0].1, 150), (keys[2].1, 300), (keys[3].1, 120)]));
+        let snapshot = oracle.metrics().snapshot();
+        assert_eq!((snapshot.syncs, snapshot.failures, snapshot.synced_epoch), (2, 0, Some(2)));
+        assert!((snapshot.max_weight_share - 300.0 / 570.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_failures_keep_the_previous_set_and_raise_the_alert() {
+        let (oracle, source, keys, consensus) = oracle();
+        let full = vec![record(keys[0].0, 10), record(keys[1].0, 10), record(keys[2].0, 10)];
+        source.responses.lock().extend([
+            Ok(full.clone()),
+            Err("connection refused".to_string()),
+            Ok(full[..2].to_vec()),
+            Ok(full),
+        ]);
+        let applied = oracle.sync_epoch(1).await.unwrap();
+
+        assert!(matches!(oracle.sync_epoch(2).await, Err(OracleError::Fetch(_))));
+        assert_eq!(consensus.lock().await.validator_set(), &applied);
+        let failing = oracle.metrics().snapshot();
+        assert!(failing.failing);
+        assert_eq!((failing.failures, failing.synced_epoch), (1, Some(1)));
+        let mut rendered = String::new();
+        oracle.metrics().render_metrics(&mut rendered);
+        assert!(rendered.contains("dadbs_validator_oracle_failing 1"), "{}", rendered);
+
+        // Too few validators is refused the same way, never emptying the set.
+        let refused = oracle.sync_epoch(2).await;
+        assert!(matches!(refused, Err(OracleError::TooFewValidators { found: 2, required: 3 })));
+        assert_eq!(consensus.lock().await.validator_set(), &applied);
+        assert_eq!(oracle.metrics().snapshot().failures, 2);
+
+        oracle.sync_epoch(2).await.unwrap();
+        let recovered = oracle.metrics().snapshot();
+        assert!(!recovered.failing);
+        assert_eq!(recovered.synced_epoch, Some(2));
+    }
+}
@@ -0,0 +1,459 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+use super::config::DEFAULT_CHAIN_ID;
+use super::crypto::{self, CryptoError, SchemeKind, SignatureScheme};
+use super::evidence::EquivocationEvidence;
+use super::handover::HandoverError;
+use super::quorum::QuorumPolicy;
+use super::sig_verify::SignedMessage;
+use super::validator::{ValidatorInfo, ValidatorSet};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CertificateError {
+    #[error("Certificate is for block {actual}, expected {expected}")]
+    BlockMismatch { expected: Hash, actual: Hash },
+    #[error("Vote from unknown validator: {0}")]
+    UnknownValidator(Pubkey),
+    #[error("Invalid vote signature from validator: {0}")]
+    InvalidSignature(Pubkey),
+    #[error("Duplicate vote from validator: {0}")]
+    DuplicateVote(Pubkey),
+    #[error("Insufficient commit weight: {weight} of {total}")]
+    InsufficientWeight { weight: u128, total: u128 },
+    #[error("Vote for height {height} round {round} does not belong to this vote set")]
+    WrongRound { height: u64, round: u32 },
+    #[error("Invalid aggregate signature")]
+    InvalidAggregate,
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error(transparent)]
+    Handover(#[from] HandoverError),
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Vote {
+    pub validator: Pubkey,
+    pub height: u64,
+    pub round: u32,
+    pub block_hash: Hash,
+    pub signature: Vec<u8>,
+}
+
+impl Vote {
+    /// Signs for the default chain; see `for_chain`.
+    pub fn new(keypair: &Keypair, height: u64, round: u32, block_hash: Hash) -> Self {
+        Self::for_chain(keypair, DEFAULT_CHAIN_ID, height, round, block_hash)
+    }
+
+    /// Signs a vote that only verifies on `chain_id`.
+    pub fn for_chain(keypair: &Keypair, chain_id: &str, height: u64, round: u32, block_hash: Hash) -> Self {
+        let signature = keypair.sign_message(&Self::signing_bytes(chain_id, height, round, &block_hash));
+        Vote {
+            validator: keypair.pubkey(),
+            height,
+            round,
+            block_hash,
+            signature: signature.as_ref().to_vec(),
+        }
+    }
+
+    /// Signs with a consensus key of `scheme` on behalf of `validator`.
+    pub fn new_with_scheme(
+        scheme: &dyn SignatureScheme,
+        secret_key: &[u8],
+        chain_id: &str,
+        validator: Pubkey,
+        height: u64,
+        round: u32,
+        block_hash: Hash,
+    ) -> Result<Self, CryptoError> {
+        let signature = scheme.sign(secret_key, &Self::signing_bytes(chain_id, height, round, &block_hash))?;
+        Ok(Vote {
+            validator,
+            height,
+            round,
+            block_hash,
+            signature,
+        })
+    }
+
+    pub fn signing_bytes(chain_id: &str, height: u64, round: u32, block_hash: &Hash) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + chain_id.len() + 8 + 4 + 32);
+        bytes.extend_from_slice(&(chain_id.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(chain_id.as_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&round.to_le_bytes());
+        bytes.extend_from_slice(block_hash.as_ref());
+        bytes
+    }
+
+    /// Checks an Ed25519 signature by the validator's identity key, made on `chain_id`.
+    pub fn verify_signature(&self, chain_id: &str) -> bool {
+        crypto::Ed25519Scheme.verify(
+            self.validator.as_ref(),
+            &Self::signing_bytes(chain_id, self.height, self.round, &self.block_hash),
+            &self.signature,
+        )
+    }
+
+    /// Checks the signature with the scheme and consensus key `validator`
+    /// registered, made on `chain_id`.
+    pub fn verify(&self, chain_id: &str, validator: &ValidatorInfo) -> bool {
+        match crypto::scheme(validator.scheme) {
+            Ok(scheme) => scheme.verify(
+                validator.consensus_key(),
+                &Self::signing_bytes(chain_id, self.height, self.round, &self.block_hash),
+                &self.signature,
+            ),
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoteOutcome {
+    Added,
+    Duplicate,
+    Equivocation(EquivocationEvidence),
+}
+
+/// Votes collected for a single (height, round).
+#[derive(Debug, Clone)]
+pub struct VoteSet {
+    height: u64,
+    round: u32,
+    votes: HashMap<Pubkey, Vote>,
+    weights: HashMap<Hash, u128>,
+}
+
+impl VoteSet {
+    pub fn new(height: u64, round: u32) -> Self {
+        VoteSet {
+            height,
+            round,
+            votes: HashMap::new(),
+            weights: HashMap::new(),
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    pub fn len(&self) -> usize {
+        self.votes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.votes.is_empty()
+    }
+
+    pub fn add_vote(
+        &mut self,
+        vote: Vote,
+        chain_id: &str,
+        validators: &ValidatorSet,
+    ) -> Result<VoteOutcome, CertificateError> {
+        if vote.height != self.height || vote.round != self.round {
+            return Err(CertificateError::WrongRound {
+                height: vote.height,
+                round: vote.round,
+            });
+        }
+        let validator = validators.get(&vote.validator)
+            .ok_or(CertificateError::UnknownValidator(vote.validator))?;
+        if !vote.verify(chain_id, validator) {
+            return Err(CertificateError::InvalidSignature(vote.validator));
+        }
+        let weight = validator.weight;
+
+        if let Some(existing) = self.votes.get(&vote.validator) {
+            if existing.block_hash == vote.block_hash {
+                return Ok(VoteOutcome::Duplicate);
+            }
+            return Ok(VoteOutcome::Equivocation(EquivocationEvidence::new(existing.clone(), vote)));
+        }
+
+        *self.weights.entry(vote.block_hash).or_insert(0) += weight;
+        self.votes.insert(vote.validator, vote);
+        Ok(VoteOutcome::Added)
+    }
+
+    pub fn voters(&self) -> impl Iterator<Item = Pubkey> + '_ {
+        self.votes.keys().copied()
+    }
+
+    pub fn weight_for(&self, block_hash: &Hash) -> u128 {
+        self.weights.get(block_hash).copied().unwrap_or(0)
+    }
+
+    pub fn certificate(&self, block_hash: &Hash) -> CommitCertificate {
+        let votes = self.votes.values()
+            .filter(|vote| vote.block_hash == *block_hash)
+            .cloned()
+            .collect();
+        CommitCertificate::new(self.height, *block_hash, votes)
+    }
+}
+
+/// One signature standing in for the votes of `signers`, all cast in `round`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AggregateSignature {
+    pub round: u32,
+    pub signers: Vec<Pubkey>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommitCertificate {
+    pub height: u64,
+    pub block_hash: Hash,
+    pub votes: Vec<Vote>,
+    #[serde(default)]
+    pub aggregate: Option<AggregateSignature>,
+}
+
+impl CommitCertificate {
+    pub fn new(height: u64, block_hash: Hash, votes: Vec<Vote>) -> Self {
+        CommitCertificate {
+            height,
+            block_hash,
+            votes,
+            aggregate: None,
+        }
+    }
+
+    /// Replaces the votes with one aggregate signature if the validator set's
+    /// scheme supports it and every vote shares a round. Otherwise the
+    /// certificate keeps its individual votes.
+    pub fn aggregated(self, validators: &ValidatorSet) -> Result<Self, CertificateError> {
+        let scheme = crypto::scheme(validators.scheme()?)?;
+        let round = match self.votes.first() {
+            Some(vote) if scheme.supports_aggregation() => vote.round,
+            _ => return Ok(self),
+        };
+        if self.votes.iter().any(|vote| vote.round != round) {
+            return Ok(self);
+        }
+
+        let signatures: Vec<&[u8]> = self.votes.iter().map(|vote| vote.signature.as_slice()).collect();
+        let signature = scheme.aggregate(&signatures).ok_or(CertificateError::InvalidAggregate)?;
+        let signers = self.votes.iter().map(|vote| vote.validator).collect();
+        Ok(CommitCertificate {
+            height: self.height,
+            block_hash: self.block_hash,
+            votes: Vec::new(),
+            aggregate: Some(AggregateSignature { round, signers, signature }),
+        })
+    }
+
+    /// Checks every vote against `validators` and returns the committed
+    /// weight. Votes signed on another chain than `chain_id` do not verify.
+    pub fn verify(
+        &self,
+        chain_id: &str,
+        block_hash: &Hash,
+        validators: &ValidatorSet,
+        quorum: &dyn QuorumPolicy,
+    ) -> Result<u128, CertificateError> {
+        let (weight, deferred) = self.verify_deferred(chain_id, block_hash, validators, quorum)?;
+        match deferred.iter().find(|(_, signed)| !signed.verify()) {
+            Some((validator, _)) => Err(CertificateError::InvalidSignature(*validator)),
+            None => Ok(weight),
+        }
+    }
+
+    /// Everything `verify` checks except the Ed25519 vote signatures, which
+    /// are returned with their signers so callers can verify them as a batch.
+    pub fn verify_deferred(
+        &self,
+        chain_id: &str,
+        block_hash: &Hash,
+        validators: &ValidatorSet,
+        quorum: &dyn QuorumPolicy,
+    ) -> Result<(u128, Vec<(Pubkey, SignedMessage)>), CertificateError> {
+        if self.block_hash != *block_hash {
+            return Err(CertificateError::BlockMismatch {
+                expected: *block_hash,
+                actual: self.block_hash,
+            });
+        }
+
+        let scheme = crypto::scheme(validators.scheme()?)?;
+        let mut seen = HashSet::new();
+        let mut weight = 0u128;
+        let mut deferred = Vec::new();
+        if let Some(aggregate) = &self.aggregate {
+            if !scheme.supports_aggregation() {
+                return Err(CertificateError::InvalidAggregate);
+            }
+            let mut keys = Vec::with_capacity(aggregate.signers.len());
+            for signer in &aggregate.signers {
+                if !seen.insert(*signer) {
+                    return Err(CertificateError::DuplicateVote(*signer));
+                }
+                let validator = validators.get(signer)
+                    .ok_or(CertificateError::UnknownValidator(*signer))?;
+                keys.push(validator.consensus_key());
+                weight += validator.weight;
+            }
+            let message = Vote::signing_bytes(chain_id, self.height, aggregate.round, &self.block_hash);
+            if !scheme.verify_aggregate(&keys, &message, &aggregate.signature) {
+                return Err(CertificateError::InvalidAggregate);
+            }
+        }
+
+        for vote in &self.votes {
+            if vote.height != self.height || vote.block_hash != self.block_hash {
+                return Err(CertificateError::BlockMismatch {
+                    expected: self.block_hash,
+                    actual: vote.block_hash,
+                });
+            }
+            if !seen.insert(vote.validator) {
+                return Err(CertificateError::DuplicateVote(vote.validator));
+            }
+            let validator = validators.get(&vote.validator)
+                .ok_or(CertificateError::UnknownValidator(vote.validator))?;
+            if validator.scheme == SchemeKind::Ed25519 {
+                deferred.push((vote.validator, SignedMessage::vote(vote, chain_id, validator.consensus_key())));
+            } else if !vote.verify(chain_id, validator) {
+                return Err(CertificateError::InvalidSignature(vote.validator));
+            }
+            weight += validator.weight;
+        }
+
+        let total = validators.total_weight();
+        if !quorum.is_met(total, weight) {
+            return Err(CertificateError::InsufficientWeight { weight, total });
+        }
+
+        Ok((weight, deferred))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::quorum::ThresholdPolicy;
+
+    #[test]
+    fn test_ed25519_certificate_keeps_votes() {
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let set = ValidatorSet::new(keys.iter().map(|k| ValidatorInfo::new(k.pubkey(), 1)).collect());
+        let hash = Hash::new_unique();
+        let votes = keys.iter().map(|k| Vote::new(k, 1, 0, hash)).collect();
+
+        let certificate = CommitCertificate::new(1, hash, votes).aggregated(&set).unwrap();
+        assert!(certificate.aggregate.is_none());
+        assert_eq!(certificate.votes.len(), 4);
+        assert_eq!(certificate.verify(DEFAULT_CHAIN_ID, &hash, &set, &ThresholdPolicy::bft()), Ok(4));
+    }
+
+    #[test]
+    fn test_votes_do_not_verify_on_another_chain() {
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+        let set = ValidatorSet::new(keys.iter().map(|k| ValidatorInfo::new(k.pubkey(), 1)).collect());
+        let hash = Hash::new_unique();
+        let votes: Vec<Vote> = keys.iter().map(|k| Vote::for_chain(k, "chain-a", 1, 0, hash)).collect();
+        assert!(votes[0].verify_signature("chain-a"));
+        assert!(!votes[0].verify_signature("chain-b"));
+
+        let certificate = CommitCertificate::new(1, hash, votes.clone());
+        assert_eq!(certificate.verify("chain-a", &hash, &set, &ThresholdPolicy::bft()), Ok(4));
+        assert_eq!(
+            certificate.verify("chain-b", &hash, &set, &ThresholdPolicy::bft()),
+            Err(CertificateError::InvalidSignature(keys[0].pubkey())),
+        );
+        let mut vote_set = VoteSet::new(1, 0);
+        assert_eq!(
+            vote_set.add_vote(votes[0].clone(), "chain-b", &set),
+            Err(CertificateError::InvalidSignature(keys[0].pubkey())),
+        );
+    }
+
+    #[cfg(feature = "bls")]
+    mod bls {
+        use super::*;
+        use crate::node::crypto::{BlsScheme, SchemeKind};
+
+        fn setup(count: u8) -> (Vec<(Pubkey, Vec<u8>)>, ValidatorSet) {
+            let mut secrets = Vec::new();
+            let mut validators = Vec::new();
+            for i in 0..count {
+                let (secret, public) = BlsScheme::keygen(&[i; 32]).unwrap();
+                let pubkey = Pubkey::new_unique();
+                secrets.push((pubkey, secret));
+                validators.push(ValidatorInfo::new(pubkey, 1).with_consensus_key(SchemeKind::Bls, public));
+            }
+            (secrets, ValidatorSet::try_new(validators).unwrap())
+        }
+
+        fn votes(secrets: &[(Pubkey, Vec<u8>)], round: u32, hash: Hash) -> Vec<Vote> {
+            secrets.iter()
+                .map(|(pubkey, secret)| {
+                    Vote::new_with_scheme(&BlsScheme, secret, DEFAULT_CHAIN_ID, *pubkey, 1, round, hash).unwrap()
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_aggregate_certificate_verifies() {
+            let (secrets, set) = setup(4);
+            let hash = Hash::new_unique();
+            let mut vote_set = VoteSet::new(1, 0);
+            for vote in votes(&secrets, 0, hash) {
+                assert_eq!(vote_set.add_vote(vote, DEFAULT_CHAIN_ID, &set), Ok(VoteOutcome::Added));
+            }
+
+            let certificate = vote_set.certificate(&hash).aggregated(&set).unwrap();
+            assert!(certificate.votes.is_empty());
+            assert_eq!(certificate.aggregate.as_ref().unwrap().signers.len(), 4);
+            assert_eq!(certificate.verify(DEFAULT_CHAIN_ID, &hash, &set, &ThresholdPolicy::bft()), Ok(4));
+        }
+
+        #[test]
+        fn test_forged_aggregate_rejected() {
+            let (secrets, set) = setup(4);
+            let hash = Hash::new_unique();
+            let quorum = ThresholdPolicy::bft();
+
+            // Two real signatures claimed to cover all four signers.
+            let mut forged = CommitCertificate::new(1, hash, votes(&secrets[..2], 0, hash))
+                .aggregated(&set)
+                .unwrap();
+            forged.aggregate.as_mut().unwrap().signers = secrets.iter().map(|(pubkey, _)| *pubkey).collect();
+            assert_eq!(forged.verify(DEFAULT_CHAIN_ID, &hash, &set, &quorum), Err(CertificateError::InvalidAggregate));
+
+            // A valid aggregate replayed for a different round.
+            let mut replayed = CommitCertificate::new(1, hash, votes(&secrets, 0, hash)).aggregated(&set).unwrap();
+            replayed.aggregate.as_mut().unwrap().round = 1;
+            let replayed = replayed.verify(DEFAULT_CHAIN_ID, &hash, &set, &quorum);
+            assert_eq!(replayed, Err(CertificateError::InvalidAggregate));
+        }
+
+        #[test]
+        fn test_mixed_rounds_fall_back_to_votes() {
+            let (secrets, set) = setup(4);
+            let hash = Hash::new_unique();
+            let mut mixed = votes(&secrets[..3], 0, hash);
+            mixed.extend(votes(&secrets[3..], 1, hash));
+
+            let certificate = CommitCertificate::new(1, hash, mixed).aggregated(&set).unwrap();
+            assert!(certificate.aggregate.is_none());
+            assert_eq!(certificate.verify(DEFAULT_CHAIN_ID, &hash, &set, &ThresholdPolicy::bft()), Ok(4));
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::warn;
+use sha2::{Digest, Sha256};
+use solana_sdk::hash::Hash;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::storage::{StorageError, WriteBatch};
+
+/// The log is truncated once it grows past this and every intent is complete.
+pub const DEFAULT_WAL_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+enum Record {
+    /// Written and synced before `batch` is applied.
+    Intent { seq: u64, block_hash: [u8; 32], height: u64, checksum: [u8; 32], batch: Vec<u8> },
+    /// Written once the batch with this `seq` is durable in the backend.
+    Complete { seq: u64 },
+}
+
+/// A block commit that was logged but never marked complete.
+#[derive(Debug)]
+pub(crate) struct Intent {
+    pub(crate) seq: u64,
+    pub(crate) block_hash: Hash,
+    pub(crate) height: u64,
+    /// `None` if the logged batch fails its checksum.
+    pub(crate) batch: Option<WriteBatch>,
+}
+
+/// Write-ahead intent log for block commits. Records are a big-endian `u32`
+/// length followed by the Borsh-encoded record; a torn final record from a
+/// crash mid-append is ignored on recovery.
+pub(crate) struct IntentLog {
+    file: File,
+    len: u64,
+    next_seq: u64,
+    max_bytes: u64,
+}
+
+impl IntentLog {
+    /// Opens the log at `path`, returning intents that were never completed,
+    /// oldest first.
+    pub(crate) fn open(path: &Path, max_bytes: u64) -> Result<(Self, Vec<Intent>), StorageError> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut intents: Vec<Intent> = Vec::new();
+        let mut next_seq = 0;
+        let mut offset = 0;
+        while let Some(record) = Self::next_record(&bytes, &mut offset) {
+            match record {
+                Record::Intent { seq, block_hash, height, checksum, batch } => {
+                    let batch = if Sha256::digest(&batch)[..] == checksum {
+                        WriteBatch::try_from_slice(&batch).ok()
+                    } else {
+                        None
+                    };
+                    intents.push(Intent { seq, block_hash: Hash::new_from_array(block_hash), height, batch });
+                    next_seq = seq + 1;
+                }
+                Record::Complete { seq } => intents.retain(|intent| intent.seq != seq),
+            }
+        }
+        if offset < bytes.len() {
+            warn!("Ignoring {} bytes of torn intent log record", bytes.len() - offset);
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let log = IntentLog { file, len: offset as u64, next_seq, max_bytes };
+        Ok((log, intents))
+    }
+
+    fn next_record(bytes: &[u8], offset: &mut usize) -> Option<Record> {
+        let header = bytes.get(*offset..*offset + 4)?;
+        let len = u32::from_be_bytes(header.try_into().ok()?) as usize;
+        let body = bytes.get(*offset + 4..*offset + 4 + len)?;
+        let record = Record::try_from_slice(body).ok()?;
+        *offset += 4 + len;
+        Some(record)
+    }
+
+    fn append(&mut self, record: &Record) -> Result<(), StorageError> {
+        let body = record.try_to_vec()?;
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+        self.file.write_all(&frame)?;
+        self.file.sync_data()?;
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn intent(&mut self, block_hash: &Hash, height: u64, batch: &WriteBatch) -> Result<(u64, Record), StorageError> {
+        let batch = batch.try_to_vec()?;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let record = Record::Intent {
+            seq,
+            block_hash: block_hash.to_bytes(),
+            height,
+            checksum: Sha256::digest(&batch).into(),
+            batch,
+        };
+        Ok((seq, record))
+    }
+
+    /// Logs and syncs the intent to apply `batch`, returning its sequence.
+    pub(crate) fn begin(&mut self, block_hash: &Hash, height: u64, batch: &WriteBatch) -> Result<u64, StorageError> {
+        let (seq, record) = self.intent(block_hash, height, batch)?;
+        self.append(&record)?;
+        Ok(seq)
+    }
+
+    pub(crate) fn complete(&mut self, seq: u64) -> Result<(), StorageError> {
+        self.append(&Record::Complete { seq })
+    }
+
+    /// Drops the log's contents once it outgrows its bound. Only call with
+    /// every intent complete.
+    pub(crate) fn checkpoint(&mut self, force: bool) -> Result<(), StorageError> {
+        if !force && self.len <= self.max_bytes {
+            return Ok(());
+        }
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        self.len = 0;
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Writes half an intent record, as if the process died mid-append.
+    #[cfg(test)]
+    pub(crate) fn begin_torn(&mut self, block_hash: &Hash, height: u64, batch: &WriteBatch) -> Result<(), StorageError> {
+        let body = self.intent(block_hash, height, batch)?.1.try_to_vec()?;
+        self.file.write_all(&(body.len() as u32).to_be_bytes())?;
+        self.file.write_all(&body[..body.len() / 2])?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,665 @@
+//! Webhooks: finalized blocks, finalized transactions touching watched
+//! addresses, accepted slashes and announced validator set changes, POSTed
+//! as JSON to HTTP endpoints.
+//!
+//! Each body is signed with HMAC-SHA256 under its endpoint's secret, sent
+//! as `sha256=<hex>` in `x-dadbs-signature`. Its `id`, also sent as
+//! `Idempotency-Key`, is derived from the event alone, so retries and
+//! redeliveries after a restart carry the same one and receivers can drop
+//! repeats. A failed delivery is retried with exponential backoff up to
+//! `max_attempts` times; then, or at once when the endpoint refuses it
+//! with a 4xx other than 408 or 429, it goes to a dead-letter log in
+//! storage with the body as sent. Endpoints are stored too, so those
+//! added through the admin API outlive restarts.
+
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use solana_sdk::hash::{hashv, Hash};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+use super::block::Block;
+use super::evidence::StakeSlashed;
+use super::metrics::{self, MetricsSource};
+use super::rpc::{BlockResult, TransactionResult};
+use super::storage::{Storage, StorageError, StoredTransaction};
+use super::subscriptions::ChainEvents;
+use crate::utils::DADBSAddress;
+
+pub const SIGNATURE_HEADER: &str = "x-dadbs-signature";
+pub const EVENT_HEADER: &str = "x-dadbs-event";
+pub const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+/// Which attempt at the delivery this is, from 1.
+pub const ATTEMPT_HEADER: &str = "x-dadbs-attempt";
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 6;
+pub const DEFAULT_INITIAL_BACKOFF_MS: u64 = 500;
+pub const DEFAULT_MAX_BACKOFF_MS: u64 = 60_000;
+pub const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+const MAX_ENDPOINT_ID_LEN: usize = 64;
+const ENDPOINT_PREFIX: u8 = b'e';
+const DEAD_LETTER_PREFIX: u8 = b'd';
+
+/// The `[webhooks]` section. Its `endpoints` are stored at startup,
+/// replacing any stored under the same id.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WebhooksConfig {
+    /// Deliver events; needs the `webhooks` feature.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Attempts at each delivery, the first included.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Wait after the first failed attempt, doubled after each further one.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Longest an endpoint may take to answer an attempt.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Requests open at once across all endpoints.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+fn default_max_attempts() -> u32 {
+    DEFAULT_MAX_ATTEMPTS
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    DEFAULT_INITIAL_BACKOFF_MS
+}
+
+fn default_max_backoff_ms() -> u64 {
+    DEFAULT_MAX_BACKOFF_MS
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+fn default_max_in_flight() -> usize {
+    DEFAULT_MAX_IN_FLIGHT
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            enabled: false,
+            endpoints: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff_ms: DEFAULT_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+}
+
+impl WebhooksConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("webhooks.max_attempts must be at least 1".to_string());
+        }
+        if self.initial_backoff_ms == 0 || self.max_backoff_ms < self.initial_backoff_ms {
+            return Err("webhooks.initial_backoff_ms must be positive and at most max_backoff_ms".to_string());
+        }
+        if self.timeout_ms == 0 || self.max_in_flight == 0 {
+            return Err("webhooks.timeout_ms and webhooks.max_in_flight must be at least 1".to_string());
+        }
+        for endpoint in &self.endpoints {
+            endpoint.validate().map_err(|e| format!("webhooks.endpoints: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WebhookError {
+    #[error("Invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// Events an endpoint asks for.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookFilter {
+    FinalizedBlock,
+    /// Finalized transactions sent from or to `address`.
+    AddressTransaction { address: String },
+    StakeSlashed,
+    /// Finalized blocks announcing the next validator set.
+    ValidatorSetChanged,
+}
+
+impl WebhookFilter {
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            WebhookFilter::FinalizedBlock => WebhookEventKind::FinalizedBlock,
+            WebhookFilter::AddressTransaction { .. } => WebhookEventKind::AddressTransaction,
+            WebhookFilter::StakeSlashed => WebhookEventKind::StakeSlashed,
+            WebhookFilter::ValidatorSetChanged => WebhookEventKind::ValidatorSetChanged,
+        }
+    }
+
+    pub fn matches(&self, event: &WebhookEvent) -> bool {
+        self.kind() == event.kind
+            && match self {
+                WebhookFilter::AddressTransaction { address } => {
+                    event.addresses.iter().any(|touched| touched.as_string() == address)
+                }
+                _ => true,
+            }
+    }
+}
+
+/// A receiver of events. The secret never leaves the node once set.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookEndpoint {
+    pub id: String,
+    pub url: String,
+    /// HMAC key signing each body sent to `url`.
+    pub secret: String,
+    pub events: Vec<WebhookFilter>,
+}
+
+impl WebhookEndpoint {
+    pub fn validate(&self) -> Result<(), String> {
+        let id_ok = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.id.is_empty() || self.id.len() > MAX_ENDPOINT_ID_LEN || !self.id.chars().all(id_ok) {
+            return Err(format!(
+                "id must be 1 to {} letters, digits, '-' or '_', not {:?}",
+                MAX_ENDPOINT_ID_LEN, self.id
+            ));
+        }
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(format!("{}: url must be http:// or https://", self.id));
+        }
+        if self.secret.is_empty() {
+            return Err(format!("{}: needs a secret", self.id));
+        }
+        if self.events.is_empty() {
+            return Err(format!("{}: needs at least one event", self.id));
+        }
+        for filter in &self.events {
+            if let WebhookFilter::AddressTransaction { address } = filter {
+                DADBSAddress::from_string(address).map_err(|e| format!("{}: {}: {}", self.id, address, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> WebhookEndpointInfo {
+        WebhookEndpointInfo { id: self.id.clone(), url: self.url.clone(), events: self.events.clone() }
+    }
+}
+
+/// An endpoint as the admin API shows it, without its secret.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookEndpointInfo {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookFilter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEventKind {
+    FinalizedBlock,
+    AddressTransaction,
+    StakeSlashed,
+    ValidatorSetChanged,
+}
+
+impl WebhookEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEventKind::FinalizedBlock => "finalized_block",
+            WebhookEventKind::AddressTransaction => "address_transaction",
+            WebhookEventKind::StakeSlashed => "stake_slashed",
+            WebhookEventKind::ValidatorSetChanged => "validator_set_changed",
+        }
+    }
+}
+
+/// Something that happened, to deliver to each endpoint asking for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookEvent {
+    pub kind: WebhookEventKind,
+    /// Hex of a hash of the kind and what the event is about; the
+    /// idempotency key of every delivery of it.
+    pub id: String,
+    pub data: Value,
+    /// What `AddressTransaction` filters match against.
+    pub addresses: Vec<DADBSAddress>,
+}
+
+impl WebhookEvent {
+    fn new(kind: WebhookEventKind, about: &[u8], data: Value, addresses: Vec<DADBSAddress>) -> Self {
+        let id = hex::encode(hashv(&[kind.as_str().as_bytes(), about]).to_bytes());
+        WebhookEvent { kind, id, data, addresses }
+    }
+
+    /// The block itself, each of its transactions and, if it announces
+    /// one, the next validator set.
+    pub fn from_finalized(block: &Block) -> Vec<WebhookEvent> {
+        let hash = block.hash();
+        let mut events = vec![WebhookEvent::new(
+            WebhookEventKind::FinalizedBlock,
+            hash.as_ref(),
+            json!(BlockResult::from(block)),
+            Vec::new(),
+        )];
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            let addresses =
+                vec![DADBSAddress::from_pubkey(&transaction.sender), DADBSAddress::from_pubkey(&transaction.recipient)];
+            let index = index as u32;
+            let stored = StoredTransaction { height: block.height(), index, transaction: transaction.clone() };
+            events.push(WebhookEvent::new(
+                WebhookEventKind::AddressTransaction,
+                transaction.hash().as_ref(),
+                json!(TransactionResult::from(&stored)),
+                addresses,
+            ));
+        }
+        let next_validators_hash = block.header.next_validators_hash;
+        if next_validators_hash != Hash::default() {
+            let data = json!({
+                "height": block.height(),
+                "block_hash": hash.to_string(),
+                "next_validators_hash": next_validators_hash.to_string(),
+            });
+            events.push(WebhookEvent::new(WebhookEventKind::ValidatorSetChanged, hash.as_ref(), data, Vec::new()));
+        }
+        events
+    }
+
+    pub fn from_slash(slashed: &StakeSlashed) -> WebhookEvent {
+        let data = json!({
+            "validator": slashed.validator.to_string(),
+            "height": slashed.height,
+            "round": slashed.round,
+            "evidence_hash": slashed.evidence_hash.to_string(),
+            "penalty_bps": slashed.penalty_bps,
+            "signature": slashed.signature.to_string(),
+        });
+        WebhookEvent::new(WebhookEventKind::StakeSlashed, slashed.evidence_hash.as_ref(), data, Vec::new())
+    }
+}
+
+/// The `x-dadbs-signature` header for `body` under `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Whether `signature`, an `x-dadbs-signature` header, signs `body` under
+/// `secret`. The comparison takes the same time wherever they differ.
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(tag) = signature.strip_prefix("sha256=").and_then(|tag| hex::decode(tag).ok()) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    mac.verify_slice(&tag).is_ok()
+}
+
+/// A delivery given up on.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub endpoint: String,
+    pub url: String,
+    pub event: String,
+    /// The idempotency key.
+    pub id: String,
+    /// As it was sent, so it can be delivered by hand.
+    pub body: String,
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    pub error: String,
+    pub failed_ms: i64,
+}
+
+pub(super) fn endpoint_key(id: &str) -> Vec<u8> {
+    [&[ENDPOINT_PREFIX][..], id.as_bytes()].concat()
+}
+
+pub(super) fn endpoint_range() -> (Vec<u8>, Vec<u8>) {
+    (vec![ENDPOINT_PREFIX], vec![ENDPOINT_PREFIX + 1])
+}
+
+pub(super) fn dead_letter_key(letter: &DeadLetter) -> Vec<u8> {
+    let failed_ms = (letter.failed_ms as u64).to_be_bytes();
+    [&[DEAD_LETTER_PREFIX][..], &failed_ms, letter.endpoint.as_bytes(), &[0], letter.id.as_bytes()].concat()
+}
+
+pub(super) fn dead_letter_range() -> (Vec<u8>, Vec<u8>) {
+    (vec![DEAD_LETTER_PREFIX], vec![DEAD_LETTER_PREFIX + 1])
+}
+
+/// Sends a signed body to an endpoint.
+#[async_trait]
+pub trait WebhookTransport: Send + Sync {
+    /// POSTs `body` to `url`, returning the response status.
+    async fn post(&self, url: &str, headers: &[(&str, String)], body: Vec<u8>) -> Result<u16, String>;
+}
+
+#[cfg(feature = "webhooks")]
+pub struct HttpTransport {
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "webhooks")]
+impl HttpTransport {
+    pub fn new(timeout: Duration) -> Self {
+        let client = reqwest::Client::builder().timeout(timeout).build().expect("default TLS backend is available");
+        HttpTransport { client }
+    }
+}
+
+#[cfg(feature = "webhooks")]
+#[async_trait]
+impl WebhookTransport for HttpTransport {
+    async fn post(&self, url: &str, headers: &[(&str, String)], body: Vec<u8>) -> Result<u16, String> {
+        let mut request = self.client.post(url).header("content-type", "application/json").body(body);
+        for (name, value) in headers {
+            request = request.header(*name, value.as_str());
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        Ok(response.status().as_u16())
+    }
+}
+
+/// An endpoint's deliveries since startup.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    pub delivered: u64,
+    pub failed_attempts: u64,
+    pub dead_lettered: u64,
+}
+
+#[derive(Default)]
+pub struct WebhookMetrics {
+    endpoints: Mutex<BTreeMap<String, DeliveryStats>>,
+    pending: AtomicU64,
+}
+
+impl WebhookMetrics {
+    pub fn snapshot(&self) -> BTreeMap<String, DeliveryStats> {
+        self.endpoints.lock().clone()
+    }
+
+    /// Deliveries started and not yet delivered or given up on.
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, endpoint: &str, update: impl FnOnce(&mut DeliveryStats)) {
+        update(self.endpoints.lock().entry(endpoint.to_string()).or_default());
+    }
+}
+
+impl MetricsSource for WebhookMetrics {
+    fn render_metrics(&self, out: &mut String) {
+        let endpoints = self.endpoints.lock();
+        let series: [(&str, &str, fn(&DeliveryStats) -> u64); 3] = [
+            ("dadbs_webhook_deliveries_total", "Webhook deliveries accepted, by endpoint", |s| s.delivered),
+            ("dadbs_webhook_failed_attempts_total", "Webhook delivery attempts that failed, by endpoint", |s| {
+                s.failed_attempts
+            }),
+            ("dadbs_webhook_dead_letters_total", "Webhook deliveries given up on, by endpoint", |s| s.dead_lettered),
+        ];
+        for (name, help, value) in series {
+            metrics::write_header(out, name, help, "counter");
+            for (endpoint, stats) in endpoints.iter() {
+                metrics::write_sample(out, name, &[("endpoint", endpoint.as_str())], value(stats) as f64);
+            }
+        }
+        metrics::write_gauge(
+            out,
+            "dadbs_webhook_deliveries_pending",
+            "Webhook deliveries under way or waiting to be retried",
+            self.pending() as f64,
+        );
+    }
+}
+
+/// Delivers events to the endpoints asking for them, in the background.
+pub struct Webhooks {
+    config: WebhooksConfig,
+    storage: Arc<Storage>,
+    transport: Arc<dyn WebhookTransport>,
+    endpoints: RwLock<BTreeMap<String, WebhookEndpoint>>,
+    metrics: Arc<WebhookMetrics>,
+    requests: Semaphore,
+    cancel: CancellationToken,
+}
+
+impl Webhooks {
+    /// Loads the stored endpoints, then stores those in `config`.
+    pub fn open(
+        config: WebhooksConfig,
+        storage: Arc<Storage>,
+        transport: Arc<dyn WebhookTransport>,
+    ) -> Result<Self, WebhookError> {
+        let mut endpoints: BTreeMap<String, WebhookEndpoint> =
+            storage.webhook_endpoints()?.into_iter().map(|endpoint| (endpoint.id.clone(), endpoint)).collect();
+        for endpoint in &config.endpoints {
+            endpoint.validate().map_err(WebhookError::InvalidEndpoint)?;
+            storage.put_webhook_endpoint(endpoint)?;
+            endpoints.insert(endpoint.id.clone(), endpoint.clone());
+        }
+        info!("Delivering webhooks to {} endpoints", endpoints.len());
+        Ok(Webhooks {
+            requests: Semaphore::new(config.max_in_flight.max(1)),
+            config,
+            storage,
+            transport,
+            endpoints: RwLock::new(endpoints),
+            metrics: Arc::new(WebhookMetrics::default()),
+            cancel: CancellationToken::new(),
+        })
+    }
+
+    pub fn metrics(&self) -> Arc<WebhookMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Adds `endpoint`, or replaces the one with its id.
+    pub fn add(&self, endpoint: WebhookEndpoint) -> Result<(), WebhookError> {
+        endpoint.validate().map_err(WebhookError::InvalidEndpoint)?;
+        self.storage.put_webhook_endpoint(&endpoint)?;
+        self.endpoints.write().insert(endpoint.id.clone(), endpoint);
+        Ok(())
+    }
+
+    /// Stops delivering to endpoint `id`. Deliveries already started still
+    /// run their course. Returns whether there was one.
+    pub fn remove(&self, id: &str) -> Result<bool, WebhookError> {
+        self.storage.delete_webhook_endpoint(id)?;
+        Ok(self.endpoints.write().remove(id).is_some())
+    }
+
+    pub fn endpoints(&self) -> Vec<WebhookEndpointInfo> {
+        self.endpoints.read().values().map(WebhookEndpoint::info).collect()
+    }
+
+    /// The most recent `limit` deliveries given up on, newest first.
+    pub fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, WebhookError> {
+        Ok(self.storage.dead_letters(limit)?)
+    }
+
+    /// Starts delivering `event` to each endpoint one of whose filters
+    /// matches it. Returns how many that was.
+    pub fn dispatch(self: &Arc<Self>, event: &WebhookEvent) -> usize {
+        let endpoints: Vec<WebhookEndpoint> = self.endpoints.read()
+            .values()
+            .filter(|endpoint| endpoint.events.iter().any(|filter| filter.matches(event)))
+            .cloned()
+            .collect();
+        let created_ms = chrono::Utc::now().timestamp_millis();
+        for endpoint in &endpoints {
+            let body = json!({
+                "id": event.id,
+                "event": event.kind.as_str(),
+                "endpoint": endpoint.id,
+                "created_ms": created_ms,
+                "data": event.data,
+            });
+            let body = serde_json::to_vec(&body).expect("JSON values serialize");
+            self.metrics.pending.fetch_add(1, Ordering::Relaxed);
+            let (webhooks, endpoint, kind, id) = (Arc::clone(self), endpoint.clone(), event.kind, event.id.clone());
+            tokio::spawn(async move {
+                webhooks.deliver(&endpoint, kind, &id, body).await;
+                webhooks.metrics.pending.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        endpoints.len()
+    }
+
+    /// Wait before attempt `failures + 1`.
+    fn backoff(&self, failures: u32) -> Duration {
+        let ms = self.config.initial_backoff_ms.saturating_mul(2u64.saturating_pow(failures.saturating_sub(1)));
+        Duration::from_millis(ms.min(self.config.max_backoff_ms))
+    }
+
+    async fn deliver(&self, endpoint: &WebhookEndpoint, kind: WebhookEventKind, id: &str, body: Vec<u8>) {
+        let signature = sign(&endpoint.secret, &body);
+        let mut attempts = 0;
+        let error = loop {
+            attempts += 1;
+            let headers = [
+                (SIGNATURE_HEADER, signature.clone()),
+                (EVENT_HEADER, kind.as_str().to_string()),
+                (IDEMPOTENCY_HEADER, id.to_string()),
+                (ATTEMPT_HEADER, attempts.to_string()),
+            ];
+            let outcome = {
+                let _permit = self.requests.acquire().await.expect("the semaphore is never closed");
+                tokio::select! {
+                    _ = self.cancel.cancelled() => break "the node shut down".to_string(),
+                    outcome = self.transport.post(&endpoint.url, &headers, body.clone()) => outcome,
+                }
+            };
+            let (error, retry) = match outcome {
+                Ok(status) if (200..300).contains(&status) => {
+                    debug!("Delivered {} {} to webhook {}", kind.as_str(), id, endpoint.id);
+                    self.metrics.record(&endpoint.id, |stats| stats.delivered += 1);
+                    return;
+                }
+                // Other client errors would only be refused again.
+                Ok(status) => {
+                    let retry = !(400..500).contains(&status) || matches!(status, 408 | 429);
+                    (format!("HTTP {}", status), retry)
+                }
+                Err(e) => (e, true),
+            };
+            self.metrics.record(&endpoint.id, |stats| stats.failed_attempts += 1);
+            if !retry || attempts >= self.config.max_attempts {
+                break error;
+            }
+            debug!("Webhook {} failed attempt {} at {}: {}", endpoint.id, attempts, id, error);
+            tokio::select! {
+                _ = self.cancel.cancelled() => break format!("{}; the node shut down before retrying", error),
+                _ = tokio::time::sleep(self.backoff(attempts)) => {}
+            }
+        };
+
+        warn!("Giving up on webhook {} delivery {} after {} attempts: {}", endpoint.id, id, attempts, error);
+        self.metrics.record(&endpoint.id, |stats| stats.dead_lettered += 1);
+        let letter = DeadLetter {
+            endpoint: endpoint.id.clone(),
+            url: endpoint.url.clone(),
+            event: kind.as_str().to_string(),
+            id: id.to_string(),
+            body: String::from_utf8_lossy(&body).into_owned(),
+            attempts,
+            error,
+            failed_ms: chrono::Utc::now().timestamp_millis(),
+        };
+        if let Err(e) = self.storage.put_dead_letter(&letter) {
+            warn!("Failed to record webhook dead letter: {}", e);
+        }
+    }
+
+    /// Delivers what `events` publishes from now until `cancel` fires.
+    /// Deliveries still under way are then dead-lettered instead of retried.
+    pub fn run(self: Arc<Self>, events: &ChainEvents, cancel: CancellationToken) -> impl Future<Output = ()> + Send {
+        // Subscribed before the first poll, so nothing published once this
+        // returns is missed.
+        let (mut finalized, mut slashed) = (events.finalized(), events.slashed());
+        async move {
+            loop {
+                let produced = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    block = finalized.recv() => block.map(|block| WebhookEvent::from_finalized(&block)),
+                    slash = slashed.recv() => slash.map(|slash| vec![WebhookEvent::from_slash(&slash)]),
+                };
+                match produced {
+                    Ok(produced) => {
+                        for event in &produced {
+                            self.dispatch(event);
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => warn!("Webhooks fell behind and missed {} events", missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            self.cancel.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Refuse;
+
+    #[async_trait]
+    impl WebhookTransport for Refuse {
+        async fn post(&self, _: &str, _: &[(&str, String)], _: Vec<u8>) -> Result<u16, String> {
+            Ok(500)
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let config = WebhooksConfig { initial_backoff_ms: 500, max_backoff_ms: 3_000, ..WebhooksConfig::default() };
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let webhooks = Webhooks::open(config, storage, Arc::new(Refuse)).unwrap();
+        let waits: Vec<u64> = (1..=5).map(|failures| webhooks.backoff(failures).as_millis() as u64).collect();
+        assert_eq!(waits, [500, 1_000, 2_000, 3_000, 3_000]);
+        assert_eq!(webhooks.backoff(u32::MAX), Duration::from_millis(3_000));
+    }
+
+    #[test]
+    fn test_signatures_cover_the_whole_body_under_the_secret() {
+        let body = br#"{"id":"abc","event":"finalized_block"}"#;
+        let signature = sign("s3cret", body);
+        assert!(signature.starts_with("sha256=") && signature.len() == 7 + 64);
+        assert!(verify("s3cret", body, &signature));
+        assert!(!verify("other", body, &signature));
+        assert!(!verify("s3cret", br#"{"id":"abd","event":"finalized_block"}"#, &signature));
+        assert!(!verify("s3cret", body, signature.trim_start_matches("sha256=")));
+    }
+}
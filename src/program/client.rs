@@ -0,0 +1,62 @@
+use borsh::BorshSerialize;
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program,
+};
+
+use super::stake::{config_address, slash_record_address, StakeInstruction};
+
+/// Creates the program's config; only its upgrade authority may.
+pub fn initialize_config_instruction(
+    program_id: &Pubkey,
+    upgrade_authority: &Pubkey,
+    slashing_authority: Pubkey,
+    treasury: Pubkey,
+) -> Instruction {
+    let data = StakeInstruction::InitializeConfig { slashing_authority, treasury }
+        .try_to_vec()
+        .expect("stake instruction serialization cannot fail");
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            // Pays for the config account.
+            AccountMeta::new(*upgrade_authority, true),
+            AccountMeta::new(config_address(program_id).0, false),
+            AccountMeta::new_readonly(bpf_loader_upgradeable::get_program_data_address(program_id), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data,
+    }
+}
+
+pub fn slash_instruction(
+    program_id: &Pubkey,
+    slashing_authority: &Pubkey,
+    stake_account: &Pubkey,
+    treasury_account: &Pubkey,
+    penalty_bps: u16,
+    evidence_hash: [u8; 32],
+) -> Instruction {
+    let data = StakeInstruction::Slash { penalty_bps, evidence_hash }
+        .try_to_vec()
+        .expect("stake instruction serialization cannot fail");
+
+    let (record_account, _) = slash_record_address(program_id, &evidence_hash);
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            // Pays for the account recording the slash.
+            AccountMeta::new(*slashing_authority, true),
+            AccountMeta::new_readonly(config_address(program_id).0, false),
+            AccountMeta::new(*stake_account, false),
+            AccountMeta::new(*treasury_account, false),
+            AccountMeta::new(record_account, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data,
+    }
+}
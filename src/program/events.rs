@@ -0,0 +1,100 @@
+//! Events the stake program logs, and the parser that reads them back out of
+//! a transaction's log messages.
+//!
+//! Each event is one `msg!` line, `<Name> key=value ...`, which the runtime
+//! prefixes with `Program log: `. A line counts as an event only while the
+//! stake program itself is executing, so a program it shares a transaction
+//! with cannot log a forged one.
+
+use solana_program::pubkey::Pubkey;
+use std::str::FromStr;
+
+const LOG_PREFIX: &str = "Program log: ";
+const DATA_PREFIX: &str = "Program data: ";
+const PROGRAM_PREFIX: &str = "Program ";
+pub const STAKE_CREATED: &str = "StakeCreated";
+
+/// Logged by `CreateStake` once the stake account holds the lamports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StakeCreated {
+    pub staker: Pubkey,
+    pub stake_account: Pubkey,
+    /// Lamports locked, besides the account's rent.
+    pub amount: u64,
+}
+
+impl StakeCreated {
+    /// The line the program logs.
+    pub fn log_line(&self) -> String {
+        format!("{} staker={} stake_account={} amount={}", STAKE_CREATED, self.staker, self.stake_account, self.amount)
+    }
+
+    /// The event in a logged line, without the runtime's prefix.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.strip_prefix(STAKE_CREATED)?.strip_prefix(' ')?.split(' ');
+        let staker = Pubkey::from_str(field(&mut fields, "staker")?).ok()?;
+        let stake_account = Pubkey::from_str(field(&mut fields, "stake_account")?).ok()?;
+        let amount = field(&mut fields, "amount")?.parse().ok()?;
+        fields.next().is_none().then_some(StakeCreated { staker, stake_account, amount })
+    }
+}
+
+fn field<'a>(fields: &mut impl Iterator<Item = &'a str>, name: &str) -> Option<&'a str> {
+    fields.next()?.strip_prefix(name)?.strip_prefix('=')
+}
+
+/// The `StakeCreated` events `program_id` logged, in order. Lines are told
+/// apart by the program executing them, which the runtime's `invoke`,
+/// `success` and `failed` lines track; whether the transaction succeeded is
+/// left to the caller.
+pub fn stake_created_events(program_id: &Pubkey, logs: &[String]) -> Vec<StakeCreated> {
+    let program_id = program_id.to_string();
+    let mut executing: Vec<&str> = Vec::new();
+    let mut events = Vec::new();
+    for line in logs {
+        if let Some(message) = line.strip_prefix(LOG_PREFIX) {
+            if executing.last() == Some(&program_id.as_str()) {
+                events.extend(StakeCreated::parse(message));
+            }
+        } else if line.starts_with(DATA_PREFIX) {
+            continue;
+        } else if let Some(rest) = line.strip_prefix(PROGRAM_PREFIX) {
+            let mut words = rest.split(' ');
+            match (words.next(), words.next()) {
+                (Some(program), Some("invoke")) => executing.push(program),
+                (Some(_), Some("success" | "failed:")) => {
+                    executing.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_the_stake_programs_own_lines_are_events() {
+        let (program_id, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let event = StakeCreated { staker: Pubkey::new_unique(), stake_account: Pubkey::new_unique(), amount: 42 };
+        let forged = StakeCreated { amount: 1_000_000, ..event };
+        let logs = vec![
+            format!("Program {} invoke [1]", other),
+            format!("Program log: {}", forged.log_line()),
+            // Messages cannot pass for the runtime's own lines.
+            format!("Program log: Program {} invoke [2]", program_id),
+            format!("Program log: {}", forged.log_line()),
+            format!("Program {} invoke [2]", program_id),
+            format!("Program log: {}", event.log_line()),
+            format!("Program {} consumed 2500 of 200000 compute units", program_id),
+            format!("Program {} success", program_id),
+            format!("Program log: {}", forged.log_line()),
+            format!("Program {} success", other),
+        ];
+        assert_eq!(stake_created_events(&program_id, &logs), vec![event]);
+        assert_eq!(StakeCreated::parse(&format!("{} extra=1", event.log_line())), None);
+    }
+}
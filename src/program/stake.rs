@@ -1,5 +1,6 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable,
     entrypoint,
     entrypoint::ProgramResult,
     msg,
@@ -12,6 +13,8 @@ use solana_program::{
 
 use borsh::{BorshDeserialize, BorshSerialize};
 
+use super::events::StakeCreated;
+
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct StakeAccount {
@@ -22,6 +25,44 @@ pub struct StakeAccount {
 }
 
 
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
+pub struct ProgramConfig {
+    pub slashing_authority: Pubkey,
+    /// The only account slashed lamports may go to.
+    pub treasury: Pubkey,
+    pub is_initialized: bool,
+}
+
+pub const MAX_PENALTY_BPS: u16 = 10_000;
+
+/// `UpgradeableLoaderState::ProgramData`, as the loader serializes it.
+const PROGRAM_DATA_TAG: u32 = 3;
+const PROGRAM_DATA_AUTHORITY_END: usize = 4 + 8 + 1 + 32;
+
+/// Seed of the program's one config account.
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Where the program's config lives. No other account is read as config.
+pub fn config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Seed of the account a slash is recorded in, with its evidence hash; the
+/// same evidence cannot slash twice.
+pub const SLASH_RECORD_SEED: &[u8] = b"slash";
+
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct SlashRecord {
+    pub stake_account: Pubkey,
+    pub penalty: u64,
+}
+
+/// Where the slash for `evidence_hash` is recorded.
+pub fn slash_record_address(program_id: &Pubkey, evidence_hash: &[u8; 32]) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[SLASH_RECORD_SEED, evidence_hash], program_id)
+}
+
+
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub enum StakeInstruction {
     
@@ -33,6 +74,17 @@ pub enum StakeInstruction {
     Withdraw {
         amount: u64,
     },
+
+    /// Signed by the program's upgrade authority, once.
+    InitializeConfig {
+        slashing_authority: Pubkey,
+        treasury: Pubkey,
+    },
+
+    Slash {
+        penalty_bps: u16,
+        evidence_hash: [u8; 32],
+    },
 }
 
 
@@ -52,6 +104,12 @@ pub fn process_instruction(
         StakeInstruction::Withdraw { amount } => {
             process_withdraw(program_id, accounts, amount)
         }
+        StakeInstruction::InitializeConfig { slashing_authority, treasury } => {
+            process_initialize_config(program_id, accounts, slashing_authority, treasury)
+        }
+        StakeInstruction::Slash { penalty_bps, evidence_hash } => {
+            process_slash(program_id, accounts, penalty_bps, evidence_hash)
+        }
     }
 }
 
@@ -107,6 +165,7 @@ fn process_create_stake(
     stake_account_data.serialize(&mut &mut stake_account.data.borrow_mut()[..])?;
 
     msg!("Stake account created and SOL locked successfully");
+    msg!("{}", StakeCreated { staker: *staker_account.key, stake_account: *stake_account.key, amount }.log_line());
     Ok(())
 }
 
@@ -155,3 +214,248 @@ fn process_withdraw(
     msg!("Withdrew {} lamports from stake account", amount);
     Ok(())
 }
+
+
+fn process_initialize_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    slashing_authority: Pubkey,
+    treasury: Pubkey,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let upgrade_authority_account = next_account_info(account_info_iter)?;
+    let config_account = next_account_info(account_info_iter)?;
+    let program_data_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !upgrade_authority_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if upgrade_authority(program_id, program_data_account)? != *upgrade_authority_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (config_address, bump) = config_address(program_id);
+    if *config_account.key != config_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if config_account.owner == program_id {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let config = ProgramConfig { slashing_authority, treasury, is_initialized: true };
+    let space = config.try_to_vec()?.len();
+    invoke_signed(
+        &system_instruction::create_account(
+            upgrade_authority_account.key,
+            config_account.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            upgrade_authority_account.clone(),
+            config_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[CONFIG_SEED, &[bump]]],
+    )?;
+    config.serialize(&mut &mut config_account.data.borrow_mut()[..])?;
+
+    msg!("Stake program config initialized with slashing authority {} and treasury {}", slashing_authority, treasury);
+    Ok(())
+}
+
+/// The key allowed to upgrade `program_id`, read from its program data
+/// account: the loader's enum tag, the deploy slot, then an optional key.
+fn upgrade_authority(program_id: &Pubkey, program_data_account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+    let program_data_address = bpf_loader_upgradeable::get_program_data_address(program_id);
+    if *program_data_account.key != program_data_address
+        || *program_data_account.owner != bpf_loader_upgradeable::id()
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let data = program_data_account.data.borrow();
+    match data.get(..PROGRAM_DATA_AUTHORITY_END) {
+        Some(metadata) if metadata[..4] == PROGRAM_DATA_TAG.to_le_bytes() && metadata[12] == 1 => {
+            Ok(Pubkey::new_from_array(metadata[13..].try_into().expect("32 bytes")))
+        }
+        // Immutable programs have no one to initialize them.
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+fn process_slash(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    penalty_bps: u16,
+    evidence_hash: [u8; 32],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let authority_account = next_account_info(account_info_iter)?;
+    let config_account = next_account_info(account_info_iter)?;
+    let stake_account = next_account_info(account_info_iter)?;
+    let treasury_account = next_account_info(account_info_iter)?;
+    let record_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !authority_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *config_account.key != config_address(program_id).0 {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if config_account.owner != program_id || stake_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = ProgramConfig::try_from_slice(&config_account.data.borrow())?;
+    if !config.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if config.slashing_authority != *authority_account.key || config.treasury != *treasury_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if penalty_bps == 0 || penalty_bps > MAX_PENALTY_BPS {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (record_address, bump) = slash_record_address(program_id, &evidence_hash);
+    if *record_account.key != record_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if record_account.owner == program_id {
+        msg!("Evidence {} was already slashed for", hex::encode(evidence_hash));
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let mut stake_data = StakeAccount::try_from_slice(&stake_account.data.borrow())?;
+    let penalty = (stake_data.amount as u128 * penalty_bps as u128 / MAX_PENALTY_BPS as u128) as u64;
+
+    let record = SlashRecord { stake_account: *stake_account.key, penalty };
+    let space = record.try_to_vec()?.len();
+    invoke_signed(
+        &system_instruction::create_account(
+            authority_account.key,
+            record_account.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            authority_account.clone(),
+            record_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[SLASH_RECORD_SEED, &evidence_hash, &[bump]]],
+    )?;
+    record.serialize(&mut &mut record_account.data.borrow_mut()[..])?;
+
+    **stake_account.try_borrow_mut_lamports()? -= penalty;
+    **treasury_account.try_borrow_mut_lamports()? += penalty;
+
+    stake_data.amount -= penalty;
+    if stake_data.amount < 10_000_000_000 {
+        stake_data.is_active = false;
+    }
+    stake_data.serialize(&mut &mut stake_account.data.borrow_mut()[..])?;
+
+    msg!("Slashed {} lamports for evidence {}", penalty, hex::encode(evidence_hash));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An account's key, lamports, data and owner, lent out as `AccountInfo`s.
+    struct TestAccount {
+        key: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+        is_signer: bool,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            TestAccount { key, lamports: 1_000_000_000, data, owner, is_signer: false }
+        }
+
+        fn signer(key: Pubkey) -> Self {
+            TestAccount { is_signer: true, ..TestAccount::new(key, Pubkey::default(), Vec::new()) }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(&self.key, self.is_signer, true, &mut self.lamports, &mut self.data, &self.owner, false, 0)
+        }
+    }
+
+    fn program_data(program_id: &Pubkey, upgrade_authority: &Pubkey) -> TestAccount {
+        let mut data = PROGRAM_DATA_TAG.to_le_bytes().to_vec();
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(upgrade_authority.as_ref());
+        let key = bpf_loader_upgradeable::get_program_data_address(program_id);
+        TestAccount::new(key, bpf_loader_upgradeable::id(), data)
+    }
+
+    fn initialize(program_id: &Pubkey, accounts: &mut [TestAccount]) -> ProgramResult {
+        let infos: Vec<AccountInfo> = accounts.iter_mut().map(TestAccount::info).collect();
+        let instruction = StakeInstruction::InitializeConfig {
+            slashing_authority: Pubkey::new_unique(),
+            treasury: Pubkey::new_unique(),
+        };
+        process_instruction(program_id, &infos, &instruction.try_to_vec().unwrap())
+    }
+
+    #[test]
+    fn test_slash_refuses_a_config_other_than_the_programs() {
+        let (program_id, attacker) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let forged = ProgramConfig { slashing_authority: attacker, treasury: attacker, is_initialized: true };
+        let stake =
+            StakeAccount { owner: Pubkey::new_unique(), amount: 20_000_000_000, locked_until: 0, is_active: true };
+        let mut accounts = [
+            TestAccount::signer(attacker),
+            TestAccount::new(Pubkey::new_unique(), program_id, forged.try_to_vec().unwrap()),
+            TestAccount::new(Pubkey::new_unique(), program_id, stake.try_to_vec().unwrap()),
+            TestAccount::new(attacker, Pubkey::default(), Vec::new()),
+            TestAccount::new(slash_record_address(&program_id, &[7; 32]).0, Pubkey::default(), Vec::new()),
+            TestAccount::new(solana_program::system_program::id(), Pubkey::default(), Vec::new()),
+        ];
+        let infos: Vec<AccountInfo> = accounts.iter_mut().map(TestAccount::info).collect();
+        let slash = StakeInstruction::Slash { penalty_bps: MAX_PENALTY_BPS, evidence_hash: [7; 32] };
+        let result = process_instruction(&program_id, &infos, &slash.try_to_vec().unwrap());
+        assert_eq!(result, Err(ProgramError::InvalidSeeds));
+        drop(infos);
+        assert_eq!(accounts[2].lamports, 1_000_000_000);
+    }
+
+    #[test]
+    fn test_config_is_initialized_by_the_upgrade_authority_once() {
+        let (program_id, upgrade_authority) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let config = config_address(&program_id).0;
+        let accounts = |signer: Pubkey, config_owner: Pubkey| {
+            [
+                TestAccount::signer(signer),
+                TestAccount::new(config, config_owner, Vec::new()),
+                program_data(&program_id, &upgrade_authority),
+                TestAccount::new(solana_program::system_program::id(), Pubkey::default(), Vec::new()),
+            ]
+        };
+
+        let mut by_anyone = accounts(Pubkey::new_unique(), Pubkey::default());
+        assert_eq!(initialize(&program_id, &mut by_anyone), Err(ProgramError::InvalidAccountData));
+
+        let mut elsewhere = accounts(upgrade_authority, Pubkey::default());
+        elsewhere[1].key = Pubkey::new_unique();
+        assert_eq!(initialize(&program_id, &mut elsewhere), Err(ProgramError::InvalidSeeds));
+
+        let mut again = accounts(upgrade_authority, program_id);
+        assert_eq!(initialize(&program_id, &mut again), Err(ProgramError::AccountAlreadyInitialized));
+    }
+}
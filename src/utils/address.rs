@@ -1,3 +1,6 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
 use std::fmt;
 use thiserror::Error;
 
@@ -12,7 +15,7 @@ pub enum AddressError {
     InvalidDADBSAddress(String),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
 pub struct DADBSAddress(String);
 
 impl DADBSAddress {
@@ -27,6 +30,16 @@ impl DADBSAddress {
             ));
         }
 
+        Ok(Self::derive(solana_address))
+    }
+
+    /// Derives the address for a public key. Unlike `from_solana` this accepts
+    /// keys whose base58 form is shorter than 44 characters.
+    pub fn from_pubkey(pubkey: &Pubkey) -> Self {
+        Self::derive(&pubkey.to_string())
+    }
+
+    fn derive(solana_address: &str) -> Self {
         
         let mut hashes = Vec::new();
         let mut prev_hash = solana_address.to_string();
@@ -49,7 +62,7 @@ impl DADBSAddress {
 
         
         let dadbs_addr = format!("{}{}", DADBS_PREFIX, hashes.join(""));
-        Ok(DADBSAddress(dadbs_addr))
+        DADBSAddress(dadbs_addr)
     }
 
    
@@ -0,0 +1,316 @@
+use dadbs_node::node::rpc::{API_KEY_REFUSED, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, RATE_LIMITED};
+use dadbs_node::node::{
+    AdminApi, AdminToken, ApiKeys, ApiKeysConfig, BackupError, BackupStatus, Block, ChainEvents, CommitCertificate,
+    ConsensusManager, HaltReason, IndexKind, LimitsConfig, Mempool, Network, NetworkConfig, ReindexReport, RpcConfig,
+    RpcContext, RpcServer, SnapshotManifest, State, Storage, ThresholdPolicy, TxTracer, ValidatorInfo, ValidatorSet,
+    Vote,
+};
+use dadbs_node::node::runtime::{CHAIN_DIR, STATE_FILE};
+use log::LevelFilter;
+use parking_lot::{Mutex, RwLock};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use solana_sdk::signature::{Keypair, Signer};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+const TOKEN: &str = "s3cret-admin-token";
+
+struct AdminNode {
+    server: Arc<RpcServer>,
+    storage: Arc<Storage>,
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    network: Arc<Network>,
+    snapshot_dir: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl AdminNode {
+    /// A node with one finalized block, serving admin calls if `token` is set.
+    async fn start(token: Option<&str>) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let validator = Keypair::new();
+        let storage = Arc::new(Storage::open(dir.path().join("chain")).unwrap());
+        let state = Arc::new(RwLock::new(State::in_memory(Vec::new())));
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+            .with_state(Arc::clone(&state));
+        consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+
+        let parent = consensus.block_tree().finalized().clone();
+        let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, validator.pubkey(), Vec::new());
+        block.header.state_root = state.read().root();
+        consensus.apply_block(block.clone(), 10).unwrap();
+        consensus.finalize_block(&block.hash()).unwrap();
+        let votes = vec![Vote::new(&validator, block.height(), 0, block.hash())];
+        storage.put_finalized_block(&block, &CommitCertificate::new(block.height(), block.hash(), votes)).unwrap();
+
+        let network_config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "admin-test")
+            .with_peer_store_path(dir.path().join("peers.json"));
+        let (network, _inbound) = Network::bind(network_config).await.unwrap();
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let context = RpcContext {
+            node_id: "admin-test".to_string(),
+            chain_id: "dadbs-testnet".to_string(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::clone(&consensus),
+            state,
+            mempool: Arc::new(Mutex::new(Mempool::new(1, 100))),
+            network: Some(Arc::clone(&network)),
+            events: ChainEvents::default(),
+            tracer: TxTracer::default(),
+        };
+        let snapshot_dir = dir.path().join("snapshots");
+        let admin = token.map(|token| AdminApi::new(AdminToken::new(token), &snapshot_dir));
+        let config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+        let server = RpcServer::bind_with(config, context, LimitsConfig::default(), admin).await.unwrap();
+        server.authenticate_api_keys(Arc::new(ApiKeys::new(&ApiKeysConfig::default(), Arc::clone(&storage))));
+        AdminNode { server, storage, consensus, network, snapshot_dir, _dir: dir }
+    }
+
+    async fn send(&self, authorization: Option<&str>, method: &str, params: Value) -> reqwest::Response {
+        let mut request = reqwest::Client::new()
+            .post(format!("http://{}/admin", self.server.local_addr()))
+            .json(&json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }));
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        request.send().await.unwrap()
+    }
+
+    /// Makes a public call, with `api_key` in the `X-Api-Key` header if set.
+    async fn rpc(&self, api_key: Option<&str>, request: Value) -> Value {
+        let mut request = reqwest::Client::new().post(format!("http://{}/", self.server.local_addr())).json(&request);
+        if let Some(api_key) = api_key {
+            request = request.header("X-Api-Key", api_key);
+        }
+        request.send().await.unwrap().json().await.unwrap()
+    }
+
+    /// Makes an authenticated call and returns the JSON-RPC response.
+    async fn call(&self, method: &str, params: Value) -> Value {
+        let response = self.send(Some(&format!("Bearer {}", TOKEN)), method, params).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().await.unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_bad_credentials_get_bare_401() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let wrong = format!("Bearer {}x", TOKEN);
+    let basic = format!("Basic {}", TOKEN);
+    for authorization in [None, Some(wrong.as_str()), Some(basic.as_str()), Some("Bearer ")] {
+        for method in ["admin_pause_consensus", "admin_no_such_method"] {
+            let response = node.send(authorization, method, Value::Null).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?} {}", authorization, method);
+            assert!(response.bytes().await.unwrap().is_empty());
+        }
+    }
+    assert!(node.consensus.lock().await.halt_status().is_none());
+    assert!(node.storage.audit_log().unwrap().is_empty());
+
+    let response = node.call("admin_no_such_method", Value::Null).await;
+    assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    let response = node.call("admin_ban_peer", json!({ "addr": "not an address" })).await;
+    assert_eq!(response["error"]["code"], INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_admin_disabled_without_token() {
+    let node = AdminNode::start(None).await;
+    let response = node.send(Some(&format!("Bearer {}", TOKEN)), "admin_pause_consensus", Value::Null).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(node.consensus.lock().await.halt_status().is_none());
+}
+
+#[tokio::test]
+async fn test_batches_are_refused() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let response: Value = reqwest::Client::new()
+        .post(format!("http://{}/admin", node.server.local_addr()))
+        .header("Authorization", format!("Bearer {}", TOKEN))
+        .json(&json!([{ "jsonrpc": "2.0", "method": "admin_pause_consensus", "id": 1 }]))
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    assert!(node.consensus.lock().await.halt_status().is_none());
+}
+
+#[tokio::test]
+async fn test_ban_and_unban_peer() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let addr: SocketAddr = "10.0.0.1:8000".parse().unwrap();
+    let response = node.call("admin_ban_peer", json!({ "addr": addr.to_string(), "reason": "spam" })).await;
+    assert_eq!(response["result"]["duration_secs"], node.network.ban_duration().as_secs());
+    let bans = node.network.bans();
+    assert_eq!((bans.len(), bans[0].addr, bans[0].reason.as_str()), (1, addr, "spam"));
+
+    let response = node.call("admin_unban_peer", json!({ "addr": addr.to_string() })).await;
+    assert_eq!(response["result"]["unbanned"], true);
+    assert!(node.network.bans().is_empty());
+    let response = node.call("admin_unban_peer", json!({ "addr": addr.to_string() })).await;
+    assert_eq!(response["result"]["unbanned"], false);
+}
+
+#[tokio::test]
+async fn test_pause_and_resume_consensus() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let response = node.call("admin_pause_consensus", Value::Null).await;
+    assert_eq!(response["result"]["halted"]["reason"], json!("Paused"));
+    let status = node.consensus.lock().await.halt_status().unwrap();
+    assert_eq!(status.reason, HaltReason::Paused);
+
+    let response = node.call("admin_resume_consensus", json!({ "reason": "maintenance done" })).await;
+    assert_eq!(response["result"]["halted"], Value::Null);
+    assert!(node.consensus.lock().await.halt_status().is_none());
+}
+
+#[tokio::test]
+async fn test_set_log_level() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let response = node.call("admin_set_log_level", json!({ "level": "debug" })).await;
+    assert_eq!(response["result"]["level"], "debug");
+    assert_eq!(log::max_level(), LevelFilter::Debug);
+    let response = node.call("admin_set_log_level", json!({ "level": "loud" })).await;
+    assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    assert_eq!(log::max_level(), LevelFilter::Debug);
+}
+
+#[tokio::test]
+async fn test_trigger_snapshot() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let response = node.call("admin_trigger_snapshot", Value::Null).await;
+    let manifest: SnapshotManifest = serde_json::from_value(response["result"]["manifest"].clone()).unwrap();
+    assert_eq!(manifest.height, 1);
+    let path = node.snapshot_dir.join("snapshot-1.snap");
+    assert_eq!(response["result"]["path"], path.display().to_string());
+    assert!(path.exists());
+}
+
+#[tokio::test]
+async fn test_reindex() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let response = node.call("admin_reindex", json!({ "kinds": ["tx-hash"] })).await;
+    let report: ReindexReport = serde_json::from_value(response["result"].clone()).unwrap();
+    assert_eq!((report.kinds, report.tip, report.entries), (vec![IndexKind::TxHash], Some(1), vec![0]));
+    let response = node.call("admin_reindex", json!({ "kinds": ["blooms"] })).await;
+    assert_eq!(response["error"]["code"], INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_backup_under_writes_restores_at_its_manifest_height() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (storage, stop) = (Arc::clone(&node.storage), Arc::clone(&stop));
+        std::thread::spawn(move || {
+            let proposer = Keypair::new();
+            let mut parent = storage.get_block_by_height(1).unwrap().unwrap();
+            while !stop.load(Ordering::Relaxed) {
+                let height = parent.height() + 1;
+                let block = Block::with_transactions(height, parent.hash(), 0, proposer.pubkey(), Vec::new());
+                let votes = vec![Vote::new(&proposer, height, 0, block.hash())];
+                storage.put_finalized_block(&block, &CommitCertificate::new(height, block.hash(), votes)).unwrap();
+                parent = block;
+            }
+            parent.height()
+        })
+    };
+
+    let backups = tempfile::tempdir().unwrap();
+    let dest = backups.path().join("backup");
+    let response = node.call("admin_backup", json!({ "dest": dest.display().to_string() })).await;
+    assert_eq!(response["result"]["running"], true, "{}", response);
+    let again = node.call("admin_backup", json!({ "dest": backups.path().join("other").display().to_string() })).await;
+    assert_eq!(again["error"]["code"], INVALID_PARAMS);
+    let status = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let response = node.call("admin_backup_status", Value::Null).await;
+            let status: BackupStatus = serde_json::from_value(response["result"].clone()).unwrap();
+            if !status.running {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("backup did not finish");
+    stop.store(true, Ordering::Relaxed);
+    let written = writer.join().unwrap();
+    let manifest = status.manifest.expect("backup failed");
+    let height = manifest.height.unwrap();
+    assert!((1..=written).contains(&height));
+    assert_eq!((manifest.state_height, manifest.state_root), (0, State::in_memory(Vec::new()).root()));
+
+    // Refused while the target store is open, as a running node holds it.
+    let fresh = tempfile::tempdir().unwrap();
+    let open = Storage::open(fresh.path().join(CHAIN_DIR)).unwrap();
+    assert!(matches!(Storage::restore(&dest, fresh.path()), Err(BackupError::InUse { .. })));
+    drop(open);
+    assert_eq!(Storage::restore(&dest, fresh.path()).unwrap(), manifest);
+
+    let restored = Storage::open(fresh.path().join(CHAIN_DIR)).unwrap();
+    assert_eq!(restored.finalized_height().unwrap(), Some(height));
+    let tip = restored.get_block_by_height(height).unwrap().unwrap();
+    assert_eq!(Some(tip.hash()), manifest.block_hash);
+    assert_eq!(node.storage.get_block_by_height(height).unwrap().unwrap(), tip);
+    assert!(restored.get_block_by_height(height + 1).unwrap().is_none());
+    let state = State::open(&fresh.path().join(STATE_FILE), Vec::new()).unwrap();
+    assert_eq!(state.root(), manifest.state_root);
+}
+
+#[tokio::test]
+async fn test_calls_are_audited() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    node.call("admin_pause_consensus", Value::Null).await;
+    node.call("admin_resume_consensus", json!({ "reason": "" })).await;
+    node.call("admin_ban_peer", json!({ "addr": "10.0.0.2:8000", "duration_secs": 60 })).await;
+
+    let log = node.storage.audit_log().unwrap();
+    let entries: Vec<(&str, &str)> = log.iter().map(|r| (r.method.as_str(), r.outcome.as_str())).collect();
+    assert_eq!(entries, [("admin_pause_consensus", "ok"), ("admin_resume_consensus", "ok"), ("admin_ban_peer", "ok")]);
+    assert!(log.iter().all(|r| r.caller.starts_with("127.0.0.1:") && r.timestamp_ms > 0));
+    assert_eq!(log[2].params, json!({ "addr": "10.0.0.2:8000", "duration_secs": 60 }));
+}
+
+#[tokio::test]
+async fn test_api_keys_hold_their_own_limits_until_revoked() {
+    let node = AdminNode::start(Some(TOKEN)).await;
+    let created = node.call("admin_create_api_key", json!({
+        "owner": "explorer",
+        "rate": { "rate_per_sec": 0.01, "burst": 2 },
+        "methods": ["get_balance", "get_api_key_usage"],
+    })).await;
+    let key = created["result"]["key"].as_str().unwrap().to_string();
+    let id = created["result"]["record"]["id"].as_str().unwrap().to_string();
+    let refused = node.call("admin_create_api_key", json!({ "owner": "explorer", "methods": ["get_secrets"] })).await;
+    assert_eq!(refused["error"]["code"], INVALID_PARAMS);
+    // Nor is the key itself kept in the audit trail.
+    assert!(!serde_json::to_string(&node.storage.audit_log().unwrap()).unwrap().contains(&key));
+
+    let balance = |id: u64| json!({ "jsonrpc": "2.0", "method": "get_balance", "params": { "address": "" }, "id": id });
+    let response = node.rpc(Some(&key), balance(1)).await;
+    assert_eq!(response["error"]["code"], INVALID_PARAMS, "{}", response);
+    let nonce = json!({ "jsonrpc": "2.0", "method": "get_nonce", "params": { "address": "x" }, "id": 2 });
+    assert_eq!(node.rpc(Some(&key), nonce).await["error"]["code"], API_KEY_REFUSED);
+    // The key can also be given in the request, which takes precedence.
+    let usage = json!({ "jsonrpc": "2.0", "method": "get_api_key_usage", "auth": key, "id": 3 });
+    let response = node.rpc(Some("dadbs_wrong"), usage).await;
+    assert_eq!(response["result"]["key"]["owner"], "explorer", "{}", response);
+    assert_eq!(response["result"]["today"]["requests"], 2);
+    assert_eq!(node.rpc(Some(&key), balance(4)).await["error"]["code"], RATE_LIMITED);
+    // Keyless calls are held to the client's buckets, untouched by the key's.
+    assert_eq!(node.rpc(None, balance(5)).await["error"]["code"], INVALID_PARAMS);
+
+    let revoked = node.call("admin_revoke_api_key", json!({ "id": id })).await;
+    assert!(revoked["result"]["revoked_ms"].is_i64());
+    assert_eq!(node.rpc(Some(&key), balance(6)).await["error"]["code"], API_KEY_REFUSED);
+    let listed = node.call("admin_list_api_keys", Value::Null).await;
+    assert_eq!(listed["result"].as_array().unwrap().len(), 1);
+    let missing = node.call("admin_revoke_api_key", json!({ "id": "00" })).await;
+    assert_eq!(missing["error"]["code"], INVALID_PARAMS);
+}
@@ -0,0 +1,173 @@
+use assert_cmd::Command;
+use dadbs_node::node::runtime::{CHAIN_DIR, STATE_FILE};
+use dadbs_node::node::{
+    Block, CommitCertificate, Genesis, IndexKind, NodeConfig, ReindexReport, SnapshotManifest, State, Storage,
+    Transaction, ValidatorInfo, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{read_keypair_file, Keypair, Signer};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const EXIT_CONFIG: i32 = 78;
+
+fn node() -> Command {
+    Command::cargo_bin("dadbs-node").unwrap()
+}
+
+fn stdout(command: &mut Command) -> String {
+    String::from_utf8(command.assert().success().get_output().stdout.clone()).unwrap()
+}
+
+/// A loopback config for node `name`, storing under `dir/name`.
+fn write_config(dir: &Path, name: &str, validators: &[Pubkey]) -> PathBuf {
+    let mut config = format!(
+        "node_id = \"{}\"\nhost = \"127.0.0.1\"\nport = 0\nstorage_path = \"{}\"\n\
+         max_connections = 8\nconsensus_timeout = 5000\nbootstrap_nodes = []\n",
+        name,
+        dir.join(name).display()
+    );
+    for pubkey in validators {
+        config += &format!("\n[[validators]]\npubkey = {:?}\nweight = 1\n", pubkey.to_bytes());
+    }
+    let path = dir.join(format!("{}.toml", name));
+    std::fs::write(&path, config).unwrap();
+    path
+}
+
+#[test]
+fn test_init_then_validate_with_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("node.toml");
+    let data = dir.path().join("data");
+    let init = stdout(node().args(["init", "--profile", "local", "--node-id", "cli-node", "--config"])
+        .arg(&config)
+        .arg("--storage-path")
+        .arg(&data));
+    assert!(init.contains("cli-node"));
+    node().arg("init").arg("--config").arg(&config).assert().code(1);
+
+    let validate = stdout(node().args(["--log-level", "debug", "config", "validate", "--config"]).arg(&config));
+    assert!(validate.contains("cli-node"));
+    let overridden = stdout(node().args(["config", "validate", "--node-id", "renamed", "--config"]).arg(&config));
+    assert!(overridden.contains("renamed"));
+    node().args(["config", "validate", "--rpc-listen", "nowhere", "--config"]).arg(&config).assert().code(EXIT_CONFIG);
+}
+
+#[test]
+fn test_init_writes_genesis_template() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("node.toml");
+    let validator = Keypair::new().pubkey();
+    let init = stdout(node().args(["init", "--profile", "local", "--config"])
+        .arg(&config)
+        .arg("--storage-path")
+        .arg(dir.path().join("data"))
+        .arg("--genesis-validator")
+        .arg(validator.to_string()));
+    let genesis = Genesis::load(dir.path().join("genesis.json")).unwrap();
+    assert_eq!(genesis.validators, vec![ValidatorInfo::new(validator, 1)]);
+    assert!(init.contains(&genesis.canonical_hash().to_string()));
+    assert_eq!(NodeConfig::load(&config).unwrap().genesis_path, Some(dir.path().join("genesis.json").display().to_string()));
+
+    std::fs::write(dir.path().join("genesis.json"), "{}").unwrap();
+    node().args(["config", "validate", "--config"]).arg(&config).assert().code(EXIT_CONFIG);
+}
+
+#[test]
+fn test_bad_config_exits_with_config_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("node.toml");
+    node().args(["config", "validate", "--config"]).arg(&config).assert().code(EXIT_CONFIG);
+    std::fs::write(&config, "node_id = [unterminated").unwrap();
+    node().args(["run", "--config"]).arg(&config).assert().code(EXIT_CONFIG);
+}
+
+#[test]
+fn test_keygen_writes_identity() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("node.key");
+    let printed = stdout(node().arg("keygen").arg("--out").arg(&out));
+    let pubkey = Pubkey::from_str(printed.trim()).unwrap();
+    assert_eq!(read_keypair_file(&out).unwrap().pubkey(), pubkey);
+    node().arg("keygen").arg("--out").arg(&out).assert().code(1);
+}
+
+#[test]
+fn test_snapshot_export_then_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let (validators, alice, bob) = ((0..4).map(|_| Keypair::new()).collect::<Vec<_>>(), Keypair::new(), Keypair::new());
+    let pubkeys: Vec<Pubkey> = validators.iter().map(Keypair::pubkey).collect();
+    let source = write_config(dir.path(), "source", &pubkeys);
+    let target = write_config(dir.path(), "target", &pubkeys);
+
+    {
+        let root = dir.path().join("source");
+        let storage = Storage::open(root.join(CHAIN_DIR)).unwrap();
+        let genesis = vec![(DADBSAddress::from_pubkey(&alice.pubkey()), 1_000)];
+        let mut state = State::open(&root.join(STATE_FILE), genesis).unwrap();
+        let mut parent = Block::genesis();
+        for nonce in 0..3 {
+            let tx = Transaction::new_signed(&alice, bob.pubkey(), 10, 0, nonce, 0);
+            let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, pubkeys[0], vec![tx]);
+            block.header.state_root = state.root();
+            state.apply_block(&block).unwrap();
+            let votes = validators.iter().map(|k| Vote::new(k, block.height(), 0, block.hash())).collect();
+            storage.put_finalized_block(&block, &CommitCertificate::new(block.height(), block.hash(), votes)).unwrap();
+            parent = block;
+        }
+        state.persist().unwrap();
+        storage.flush().unwrap();
+    }
+
+    let archive = dir.path().join("chain.snap");
+    let exported: SnapshotManifest =
+        serde_json::from_str(&stdout(node().args(["snapshot", "export", "--config"]).arg(&source).arg("--out").arg(&archive)))
+            .unwrap();
+    assert_eq!(exported.height, 3);
+    let imported: SnapshotManifest =
+        serde_json::from_str(&stdout(node().args(["snapshot", "import", "--config"]).arg(&target).arg("--path").arg(&archive)))
+            .unwrap();
+    assert_eq!(imported, exported);
+
+    let root = dir.path().join("target");
+    assert_eq!(Storage::open(root.join(CHAIN_DIR)).unwrap().finalized_height().unwrap(), Some(3));
+    let state = State::open(&root.join(STATE_FILE), Vec::new()).unwrap();
+    assert_eq!((state.height(), state.root()), (3, exported.state_root));
+}
+
+#[test]
+fn test_reindex_refused_while_store_in_use() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path(), "reindexed", &[]);
+    let storage = Storage::open(dir.path().join("reindexed").join(CHAIN_DIR)).unwrap();
+    let alice = Keypair::new();
+    let mut parent = Block::genesis();
+    for nonce in 0..3 {
+        let tx = Transaction::new_signed(&alice, Pubkey::new_unique(), 10, 0, nonce, 0);
+        let block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, Pubkey::default(), vec![tx]);
+        storage.put_block(&block).unwrap();
+        parent = block;
+    }
+    storage.flush().unwrap();
+
+    // The open store stands in for a running node.
+    let refused = node().args(["reindex", "--kind", "address-tx", "--config"]).arg(&config).assert().code(1);
+    assert!(String::from_utf8_lossy(&refused.get_output().stderr).contains("--online"));
+    drop(storage);
+
+    let report: ReindexReport =
+        serde_json::from_str(&stdout(node().args(["reindex", "--kind", "address-tx", "--config"]).arg(&config))).unwrap();
+    assert_eq!((report.kinds, report.entries, report.tip), (vec![IndexKind::AddressTx], vec![6], Some(3)));
+}
+
+#[test]
+fn test_peers_ban_then_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path(), "peers", &[]);
+    node().args(["peers", "ban", "--config"]).arg(&config).args(["10.0.0.1:8000", "--duration-secs", "60"]).assert().success();
+    let listed = stdout(node().args(["peers", "list", "--config"]).arg(&config));
+    assert!(listed.contains("10.0.0.1:8000"), "{}", listed);
+    assert!(listed.contains("banned by operator"));
+}
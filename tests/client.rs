@@ -0,0 +1,167 @@
+#![cfg(feature = "client")]
+
+use dadbs_node::client::{ClientError, RpcClient, TxBuilder};
+use dadbs_node::node::rpc::TRANSACTION_REJECTED;
+use dadbs_node::node::{
+    Block, ChainEvents, CommitCertificate, ConsensusManager, Keystore, KeystoreError, Mempool, ReceiptStatus, RpcConfig,
+    RpcContext, RpcServer, State, Storage, ThresholdPolicy, TxTracer, ValidatorInfo, ValidatorSet, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+const CHAIN_ID: &str = "client-test";
+
+struct TestNode {
+    server: Arc<RpcServer>,
+    storage: Arc<Storage>,
+    state: Arc<RwLock<State>>,
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    mempool: Arc<Mutex<Mempool>>,
+    validator: Keypair,
+    _dir: tempfile::TempDir,
+}
+
+impl TestNode {
+    async fn start(funded: &[&Keypair]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let validator = Keypair::new();
+        let genesis = funded.iter().map(|k| (DADBSAddress::from_pubkey(&k.pubkey()), 1_000)).collect();
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let state = Arc::new(RwLock::new(State::in_memory(genesis)));
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+            .with_chain_id(CHAIN_ID)
+            .with_state(Arc::clone(&state));
+        consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let mempool = Arc::new(Mutex::new(Mempool::new(1, 100).with_chain_id(CHAIN_ID)));
+        let context = RpcContext {
+            node_id: "client-test".to_string(),
+            chain_id: CHAIN_ID.to_string(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::clone(&consensus),
+            state: Arc::clone(&state),
+            mempool: Arc::clone(&mempool),
+            network: None,
+            events: ChainEvents::default(),
+            tracer: TxTracer::default(),
+        };
+        let config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+        let server = RpcServer::bind(config, context).await.unwrap();
+        TestNode { server, storage, state, consensus, mempool, validator, _dir: dir }
+    }
+
+    fn rpc(&self) -> RpcClient {
+        RpcClient::new(format!("http://{}/", self.server.local_addr()))
+    }
+
+    /// Finalizes and stores a block holding the whole mempool.
+    async fn produce(&self) -> Block {
+        let mut consensus = self.consensus.lock().await;
+        let transactions = self.mempool.lock().take_batch(100);
+        let parent = consensus.block_tree().finalized().clone();
+        let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, self.validator.pubkey(), transactions);
+        block.header.state_root = self.state.read().root();
+        consensus.apply_block(block.clone(), 10).unwrap();
+        consensus.finalize_block(&block.hash()).unwrap();
+        let vote = Vote::new(&self.validator, block.height(), 0, block.hash());
+        self.storage.put_finalized_block(&block, &CommitCertificate::new(block.height(), block.hash(), vec![vote])).unwrap();
+        block
+    }
+}
+
+#[tokio::test]
+async fn test_build_sign_submit_finalize() {
+    let (alice, bob) = (Keypair::new(), Keypair::new());
+    let node = Arc::new(TestNode::start(&[&alice]).await);
+    let rpc = node.rpc();
+
+    let signed = TxBuilder::new(CHAIN_ID)
+        .from(alice.pubkey())
+        .to(bob.pubkey())
+        .amount(100)
+        .fee(2)
+        .payload(b"invoice 42".to_vec())
+        .fetch_nonce(&rpc).await.unwrap()
+        .sign(&alice)
+        .unwrap();
+    assert!(signed.transaction().verify_signature());
+    let simulation = signed.simulate(&rpc).await.unwrap();
+    assert!(simulation.accepted, "{:?}", simulation.error);
+    assert_eq!(simulation.hash, signed.hash().to_string());
+    let execution = simulation.execution.expect("simulation was not executed");
+    assert_eq!((execution.status, execution.fee_paid), (ReceiptStatus::Success, 2));
+    assert_eq!(execution.state_root_before, node.state.read().root());
+    assert!(node.mempool.lock().is_empty());
+
+    let pending = signed.submit(&rpc).await.unwrap();
+    assert_eq!(pending.hash(), signed.hash().to_string());
+    let producer = tokio::spawn({
+        let node = Arc::clone(&node);
+        async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            node.produce().await
+        }
+    });
+    let finalized = pending.wait_for_finality(Duration::from_secs(5)).await.unwrap();
+    assert_eq!(finalized.height, producer.await.unwrap().height());
+    assert_eq!((finalized.amount, finalized.chain_id.as_str()), (100, CHAIN_ID));
+    assert_eq!(finalized.payload, hex::encode(b"invoice 42"));
+    assert_eq!(node.state.read().balance(&DADBSAddress::from_pubkey(&bob.pubkey())), 100);
+    // Inclusion left the accounts as simulated; only the proposer's fee credit differs.
+    for change in &execution.changes {
+        assert_eq!(node.state.read().account(&change.address), change.after);
+    }
+    assert_eq!(execution.changes.len(), 2);
+
+    // The next transaction picks up the new nonce, and a stale one is refused.
+    let next = TxBuilder::new(CHAIN_ID).from(alice.pubkey()).to(bob.pubkey()).amount(1).fee(2)
+        .fetch_nonce(&rpc).await.unwrap();
+    assert_eq!(next.clone().sign(&alice).unwrap().transaction().nonce, 1);
+    let stale = next.nonce(0).sign(&alice).unwrap().simulate(&rpc).await.unwrap();
+    assert!(!stale.accepted);
+    assert!(stale.execution.is_none() && stale.execution_error.unwrap().contains("has nonce 1"), "{:?}", stale.error);
+    let timeout = TxBuilder::new(CHAIN_ID).to(bob.pubkey()).amount(1).fee(2).nonce(1).sign(&alice).unwrap()
+        .submit(&rpc).await.unwrap()
+        .wait_for_finality(Duration::from_millis(200)).await;
+    assert!(matches!(timeout, Err(ClientError::Timeout(_))));
+}
+
+#[tokio::test]
+async fn test_other_chain_rejected() {
+    let (alice, bob) = (Keypair::new(), Keypair::new());
+    let node = TestNode::start(&[&alice]).await;
+    let rpc = node.rpc();
+    let foreign = TxBuilder::new("another-chain").to(bob.pubkey()).amount(1).fee(2).nonce(0).sign(&alice).unwrap();
+
+    let simulation = foreign.simulate(&rpc).await.unwrap();
+    assert!(!simulation.accepted && simulation.execution.is_none());
+    assert_eq!(simulation.error.unwrap().code, TRANSACTION_REJECTED);
+    match foreign.submit(&rpc).await {
+        Err(ClientError::Rpc(error)) => assert_eq!(error.code, TRANSACTION_REJECTED),
+        other => panic!("expected rejection, got {:?}", other.map(|p| p.hash().to_string())),
+    }
+    assert!(node.mempool.lock().is_empty());
+}
+
+#[tokio::test]
+async fn test_sign_with_keystore() {
+    let dir = tempfile::tempdir().unwrap();
+    let keystore = Keystore::open(dir.path().join("keys")).unwrap().with_scrypt_log_n(4);
+    let alice = Keypair::new();
+    keystore.import_key("alice", &alice.to_bytes(), "pass").unwrap();
+    let builder = TxBuilder::new(CHAIN_ID).to(Keypair::new().pubkey()).amount(5).fee(2).nonce(0);
+
+    let locked = builder.clone().sign(&(&keystore, "alice"));
+    assert!(matches!(locked, Err(ClientError::Keystore(KeystoreError::Locked(_)))));
+    keystore.unlock("alice", "pass").unwrap();
+    let signed = builder.clone().sign(&(&keystore, "alice")).unwrap();
+    assert_eq!(signed.transaction().sender, alice.pubkey());
+    assert!(signed.transaction().verify_signature());
+
+    let mismatch = builder.from(Keypair::new().pubkey()).sign(&(&keystore, "alice"));
+    assert!(matches!(mismatch, Err(ClientError::SignerMismatch { .. })));
+}
@@ -0,0 +1,104 @@
+//! Helpers shared by the integration tests. Each test crate uses only some.
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use dadbs_node::node::rpc::NodeInfo;
+use dadbs_node::node::{
+    Block, CommitCertificate, ConsensusManager, Genesis, NodeConfig, State, Storage, Transaction, Validator, Vote,
+};
+use parking_lot::RwLock;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{write_keypair_file, Keypair, Signer};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Confirms everything.
+pub struct Approver(pub Pubkey);
+
+#[async_trait]
+impl Validator for Approver {
+    fn pubkey(&self) -> Pubkey {
+        self.0
+    }
+
+    async fn verify_transaction(&self, _transaction: &Transaction) -> bool {
+        true
+    }
+}
+
+/// A node on the chain of `genesis`, storing under `dir` with `genesis`
+/// saved there too, whose ports are all picked by the OS and which dials
+/// no one.
+pub fn node_config(node_id: &str, dir: &Path, genesis: &Genesis) -> NodeConfig {
+    let genesis_path = dir.join("genesis.json");
+    genesis.save(&genesis_path).unwrap();
+    let mut config = NodeConfig {
+        node_id: node_id.to_string(),
+        chain_id: genesis.chain_id.clone(),
+        port: 0,
+        storage_path: dir.join("data").display().to_string(),
+        bootstrap_nodes: Vec::new(),
+        genesis_path: Some(genesis_path.display().to_string()),
+        ..NodeConfig::default()
+    };
+    config.rpc.listen = "127.0.0.1:0".to_string();
+    config.metrics.listen = "127.0.0.1:0".to_string();
+    std::fs::create_dir_all(&config.storage_path).unwrap();
+    config
+}
+
+/// Writes `keypair` under `dir` for `validator_key`.
+pub fn validator_key(dir: &Path, keypair: &Keypair) -> Option<String> {
+    let path = dir.join("node.key");
+    write_keypair_file(keypair, &path).unwrap();
+    Some(path.display().to_string())
+}
+
+/// Calls `method` on the JSON-RPC server at `rpc`, failing on an error.
+pub async fn call<T: DeserializeOwned>(rpc: SocketAddr, method: &str, params: Value) -> T {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let response: Value = reqwest::Client::new().post(format!("http://{}/", rpc)).json(&request)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert!(response.get("error").is_none(), "{}", response);
+    serde_json::from_value(response["result"].clone()).unwrap()
+}
+
+/// Waits up to ten seconds for the node serving `rpc` to finalize `height`.
+pub async fn finalized(rpc: SocketAddr, height: u64) -> NodeInfo {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let info: NodeInfo = call(rpc, "get_node_info", json!({})).await;
+            if info.finalized_height >= height {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("height {} was not finalized", height))
+}
+
+/// Proposes, finalizes and stores a block on top of the finalized tip, as
+/// the only validator.
+pub fn produce(
+    consensus: &mut ConsensusManager,
+    storage: &Storage,
+    state: &RwLock<State>,
+    validator: &Keypair,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let parent = consensus.block_tree().finalized().clone();
+    let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, validator.pubkey(), transactions);
+    block.header.state_root = state.read().root();
+    consensus.apply_block(block.clone(), 10).unwrap();
+    consensus.finalize_block(&block.hash()).unwrap();
+    let vote = Vote::new(validator, block.height(), 0, block.hash());
+    let certificate = CommitCertificate::new(block.height(), block.hash(), vec![vote]);
+    storage.put_finalized_block(&block, &certificate).unwrap();
+    block
+}
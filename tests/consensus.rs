@@ -0,0 +1,96 @@
+mod common;
+
+use borsh::BorshSerialize;
+use dadbs_node::node::rpc::BlockResult;
+use dadbs_node::node::{
+    Genesis, GenesisAccount, Heartbeat, NetMessage, Network, NetworkConfig, Node, NodeConfig, Transaction,
+    ValidatorInfo,
+};
+use dadbs_node::utils::DADBSAddress;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::time::Duration;
+use tokio::time::timeout;
+
+use common::call;
+
+/// Starts a lone validator on `chain_id`, sends it a transfer signed for
+/// that chain and checks it lands in a finalized block of its own.
+async fn lone_validator_extends(chain_id: &str) {
+    let dir = tempfile::tempdir().unwrap();
+    let (validator, alice) = (Keypair::new(), Keypair::new());
+    let mut genesis = Genesis::template(chain_id, vec![ValidatorInfo::new(validator.pubkey(), 1)]);
+    genesis.params.block_interval_ms = 50;
+    genesis.accounts = vec![GenesisAccount { address: DADBSAddress::from_pubkey(&alice.pubkey()), balance: 1_000_000 }];
+    let config = NodeConfig {
+        validator_key: common::validator_key(dir.path(), &validator),
+        ..common::node_config("consensus-test", dir.path(), &genesis)
+    };
+
+    let node = Node::start(config).await.unwrap();
+    let rpc = node.rpc_addr();
+    let now = chrono::Utc::now().timestamp_millis();
+    let fee = genesis.params.min_fee;
+    let mut transaction = Transaction::unsigned(alice.pubkey(), Pubkey::new_unique(), 10, fee, 0, now);
+    transaction.chain_id = chain_id.to_string();
+    transaction.signature = alice.sign_message(&transaction.signing_bytes());
+    let raw = hex::encode(transaction.try_to_vec().unwrap());
+    let _: Value = call(rpc, "send_transaction", json!({ "raw": raw })).await;
+
+    let hash = transaction.hash().to_string();
+    timeout(Duration::from_secs(10), async {
+        while call::<Value>(rpc, "get_transaction", json!({ "hash": hash })).await.is_null() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the transaction was never finalized");
+    let info = common::finalized(rpc, 3).await;
+    assert!(info.head_height >= info.finalized_height);
+
+    let mut included = Vec::new();
+    for height in 1..=info.finalized_height {
+        let block: Option<BlockResult> = call(rpc, "get_block_by_height", json!({ "height": height })).await;
+        let block = block.expect("finalized block missing from storage");
+        assert_eq!(block.proposer, validator.pubkey().to_string());
+        included.extend(block.transactions);
+    }
+    assert_eq!(included, vec![hash]);
+    node.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_lone_validator_extends_the_chain() {
+    lone_validator_extends("dadbs-testnet").await;
+}
+
+#[tokio::test]
+async fn test_lone_validator_extends_a_chain_of_its_own() {
+    lone_validator_extends("dadbs-devnet-7").await;
+}
+
+#[tokio::test]
+async fn test_stale_heartbeats_count_against_their_relay() {
+    let dir = tempfile::tempdir().unwrap();
+    let genesis = Genesis::template("dadbs-testnet", vec![ValidatorInfo::new(Keypair::new().pubkey(), 1)]);
+    let node = Node::start(common::node_config("heartbeat-test", dir.path(), &genesis)).await.unwrap();
+
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "relay")
+        .with_chain_id(genesis.chain_id.clone())
+        .with_genesis_hash(genesis.canonical_hash());
+    let (relay, _inbound) = Network::bind(config).await.unwrap();
+    relay.connect(node.p2p_addr()).await.unwrap();
+    // Well signed, so only consensus can tell it is too old to use.
+    let stale = Heartbeat::new_signed(&Keypair::new(), 1, chrono::Utc::now().timestamp_millis() - 10 * 60_000);
+    assert_eq!(relay.broadcast(NetMessage::Heartbeat(stale)), 1);
+
+    timeout(Duration::from_secs(5), async {
+        while node.peer_score(&relay.local_addr()) == 0.0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the stale heartbeat was never reported");
+    node.stop().await.unwrap();
+}
@@ -0,0 +1,119 @@
+#![cfg(feature = "faucet")]
+
+use dadbs_node::node::rpc::{FAUCET_LIMITED, INVALID_PARAMS};
+use dadbs_node::node::{
+    ChainEvents, ConsensusManager, Faucet, FaucetConfig, Keystore, Mempool, RpcConfig, RpcContext, RpcServer, State,
+    Storage, ThresholdPolicy, TxTracer, ValidatorInfo, ValidatorSet,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+const CHAIN_ID: &str = "faucet-test";
+const PASSPHRASE: &str = "faucet passphrase";
+
+struct FaucetNode {
+    server: Arc<RpcServer>,
+    mempool: Arc<Mutex<Mempool>>,
+    _dir: tempfile::TempDir,
+}
+
+impl FaucetNode {
+    /// A node whose faucet key is funded in genesis.
+    async fn start(config: FaucetConfig) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = Keystore::open(dir.path().join("keys")).unwrap().with_scrypt_log_n(4);
+        let faucet_key = keystore.create_key(&config.key, PASSPHRASE).unwrap();
+        let passphrase_file = dir.path().join("faucet.pass");
+        std::fs::write(&passphrase_file, format!("{}\n", PASSPHRASE)).unwrap();
+        let config = FaucetConfig { passphrase_file: Some(passphrase_file.display().to_string()), ..config };
+
+        let validator = Keypair::new();
+        let storage = Arc::new(Storage::open(dir.path().join("chain")).unwrap());
+        let state = Arc::new(RwLock::new(State::in_memory(vec![(DADBSAddress::from_pubkey(&faucet_key), 1_000_000)])));
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+            .with_chain_id(CHAIN_ID)
+            .with_state(Arc::clone(&state));
+        consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+        let mempool = Arc::new(Mutex::new(Mempool::new(1, 100).with_chain_id(CHAIN_ID)));
+        let context = RpcContext {
+            node_id: "faucet-test".to_string(),
+            chain_id: CHAIN_ID.to_string(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::new(AsyncMutex::new(consensus)),
+            state,
+            mempool: Arc::clone(&mempool),
+            network: None,
+            events: ChainEvents::default(),
+            tracer: TxTracer::default(),
+        };
+        let server = RpcServer::bind(RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() }, context)
+            .await
+            .unwrap();
+        let faucet = Faucet::open(config, storage, Arc::new(keystore)).unwrap();
+        server.serve_faucet(Arc::new(faucet));
+        FaucetNode { server, mempool, _dir: dir }
+    }
+
+    async fn request(&self, address: &str) -> Value {
+        reqwest::Client::new()
+            .post(format!("http://{}/", self.server.local_addr()))
+            .json(&json!({ "jsonrpc": "2.0", "method": "faucet_request", "params": { "address": address }, "id": 1 }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_grant_then_cooldowns_refuse() {
+    let node = FaucetNode::start(FaucetConfig { drip_amount: 500, ..FaucetConfig::default() }).await;
+    let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+    let response = node.request(&alice.to_string()).await;
+    let granted = &response["result"];
+    assert_eq!(granted["amount"], 500, "{}", response);
+    let pending = node.mempool.lock().take_batch(10);
+    assert_eq!(pending.len(), 1);
+    assert_eq!(granted["hash"], pending[0].hash().to_string());
+    assert_eq!((pending[0].recipient, pending[0].amount), (alice, 500));
+
+    // The same address, then another address from the same client.
+    for address in [alice, bob] {
+        let response = node.request(&address.to_string()).await;
+        assert_eq!(response["error"]["code"], FAUCET_LIMITED, "{}", response);
+        assert!(response["error"]["data"]["retry_at_ms"].as_i64().unwrap() > chrono::Utc::now().timestamp_millis());
+    }
+    assert!(node.mempool.lock().is_empty());
+
+    let response = node.request("not a pubkey").await;
+    assert_eq!(response["error"]["code"], INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_budget_exhaustion_retries_at_utc_midnight() {
+    let config = FaucetConfig { drip_amount: 100, daily_budget: 200, ip_cooldown_secs: 0, ..FaucetConfig::default() };
+    let node = FaucetNode::start(config).await;
+    for _ in 0..2 {
+        let response = node.request(&Pubkey::new_unique().to_string()).await;
+        assert!(response["result"]["hash"].is_string(), "{}", response);
+    }
+    // Drips queue behind each other rather than reusing a nonce.
+    let nonces: Vec<u64> = node.mempool.lock().take_batch(10).iter().map(|transaction| transaction.nonce).collect();
+    assert_eq!(nonces.len(), 2);
+    assert_eq!(nonces[1], nonces[0] + 1);
+
+    let response = node.request(&Pubkey::new_unique().to_string()).await;
+    assert_eq!(response["error"]["code"], FAUCET_LIMITED, "{}", response);
+    let day_ms = 86_400_000;
+    let midnight = (chrono::Utc::now().timestamp_millis() / day_ms + 1) * day_ms;
+    assert_eq!(response["error"]["data"]["retry_at_ms"], midnight);
+}
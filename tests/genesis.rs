@@ -0,0 +1,100 @@
+use dadbs_node::node::network::{read_frame, write_frame, PROTOCOL_VERSION};
+use dadbs_node::node::{Genesis, GenesisAccount, NetMessage, Node, NodeConfig, NodeError, ValidatorInfo};
+use dadbs_node::utils::DADBSAddress;
+use serde_json::{json, Value};
+use solana_sdk::hash::Hash;
+use solana_sdk::signature::{Keypair, Signer};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const CHAIN_ID: &str = "genesis-test";
+
+fn genesis(funded: &DADBSAddress) -> Genesis {
+    let mut genesis = Genesis::template(CHAIN_ID, vec![ValidatorInfo::new(Keypair::new().pubkey(), 1)]);
+    genesis.accounts = vec![GenesisAccount { address: funded.clone(), balance: 1_000 }];
+    genesis
+}
+
+/// A loopback node in `dir/name` started from `genesis`.
+async fn start(dir: &Path, name: &str, genesis: &Genesis) -> Result<Node, NodeError> {
+    let genesis_path = dir.join(format!("{}-genesis.json", name));
+    genesis.save(&genesis_path).unwrap();
+    std::fs::create_dir_all(dir.join(name)).unwrap();
+    let mut config = NodeConfig {
+        node_id: name.to_string(),
+        chain_id: CHAIN_ID.to_string(),
+        port: 0,
+        storage_path: dir.join(name).display().to_string(),
+        bootstrap_nodes: Vec::new(),
+        genesis_path: Some(genesis_path.display().to_string()),
+        ..NodeConfig::default()
+    };
+    config.rpc.listen = "127.0.0.1:0".to_string();
+    config.metrics.listen = "127.0.0.1:0".to_string();
+    Node::start(config).await
+}
+
+/// Handshakes with `node` as a peer on `chain_id` with `genesis_hash` and
+/// returns its second message.
+async fn handshake(node: &Node, chain_id: &str, genesis_hash: Hash) -> NetMessage {
+    let mut stream = TcpStream::connect(node.p2p_addr()).await.unwrap();
+    let hello = NetMessage::Handshake {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        node_id: "peer".to_string(),
+        listen_port: 0,
+        codecs: vec![],
+        chain_id: chain_id.to_string(),
+        genesis_hash,
+        observed_addr: None,
+        capabilities: 0,
+        llm: None,
+        identity: None,
+        nonce: [0; 32],
+    };
+    write_frame(&mut stream, &hello, 1024).await.unwrap();
+    let theirs = timeout(Duration::from_secs(5), read_frame(&mut stream, 1 << 20)).await.unwrap().unwrap();
+    assert!(matches!(theirs, NetMessage::Handshake { .. }));
+    timeout(Duration::from_secs(5), read_frame(&mut stream, 1 << 20)).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn test_nodes_with_different_genesis_do_not_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let funded = DADBSAddress::from_pubkey(&Keypair::new().pubkey());
+    // Same chain and balances, but different validators.
+    let (ours, theirs) = (genesis(&funded), genesis(&funded));
+    let a = start(dir.path(), "node-a", &ours).await.unwrap();
+    let b = start(dir.path(), "node-b", &theirs).await.unwrap();
+
+    match handshake(&b, CHAIN_ID, ours.canonical_hash()).await {
+        NetMessage::Disconnect(reason) => assert!(reason.contains("different genesis"), "{}", reason),
+        other => panic!("expected disconnect, got {:?}", other),
+    }
+    // The chain is checked first, whatever genesis the peer claims.
+    match handshake(&a, "another-chain", ours.canonical_hash()).await {
+        NetMessage::Disconnect(reason) => assert!(reason.contains("different chain"), "{}", reason),
+        other => panic!("expected disconnect, got {:?}", other),
+    }
+    assert_eq!(handshake(&a, CHAIN_ID, ours.canonical_hash()).await, NetMessage::GetPeers);
+
+    // The genesis balances are in place.
+    let response: Value = reqwest::Client::new()
+        .post(format!("http://{}/", a.rpc_addr()))
+        .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": "get_balance", "params": { "address": funded.to_string() } }))
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(response["result"], 1_000);
+    a.stop().await.unwrap();
+    b.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_genesis_for_another_chain_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut other = genesis(&DADBSAddress::from_pubkey(&Keypair::new().pubkey()));
+    other.chain_id = "another-chain".to_string();
+    assert!(matches!(start(dir.path(), "node", &other).await, Err(NodeError::Genesis(_))));
+}
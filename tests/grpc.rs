@@ -0,0 +1,197 @@
+#![cfg(feature = "grpc")]
+
+mod common;
+
+use borsh::BorshSerialize;
+use dadbs_node::node::grpc::proto::block_event::Event;
+use dadbs_node::node::grpc::proto::node_client::NodeClient;
+use dadbs_node::node::grpc::proto::{
+    AddressRequest, GetBlockByHeightRequest, HashRequest, SendTransactionRequest, SubscribeNewBlocksRequest,
+};
+use dadbs_node::node::grpc::RPC_CODE_KEY;
+use dadbs_node::node::{
+    Block, BucketConfig, ChainEvents, ConsensusManager, GrpcConfig, GrpcServer, LimitsConfig,
+    Mempool, RpcConfig, RpcContext, RpcServer, State, Storage, ThresholdPolicy, Transaction, TxTracer, ValidatorInfo,
+    ValidatorSet,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tonic::transport::Channel;
+use tonic::Code;
+
+struct TestNode {
+    rpc: Arc<RpcServer>,
+    grpc: GrpcServer,
+    storage: Arc<Storage>,
+    state: Arc<RwLock<State>>,
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    mempool: Arc<Mutex<Mempool>>,
+    validator: Keypair,
+    alice: Keypair,
+    _dir: tempfile::TempDir,
+}
+
+impl TestNode {
+    /// A node serving both interfaces, with alice funded in genesis.
+    async fn start(config: RpcConfig, limits: LimitsConfig) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let (validator, alice) = (Keypair::new(), Keypair::new());
+        let events = ChainEvents::default();
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let state = Arc::new(RwLock::new(State::in_memory(vec![(DADBSAddress::from_pubkey(&alice.pubkey()), 1_000)])));
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+            .with_state(Arc::clone(&state))
+            .with_events(events.clone());
+        consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let mempool = Arc::new(Mutex::new(Mempool::new(1, 100)));
+        let context = RpcContext {
+            node_id: "grpc-test".to_string(),
+            chain_id: "dadbs-testnet".to_string(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::clone(&consensus),
+            state: Arc::clone(&state),
+            mempool: Arc::clone(&mempool),
+            network: None,
+            events,
+            tracer: TxTracer::default(),
+        };
+        let config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..config };
+        let rpc = RpcServer::bind_with(config, context, limits, None).await.unwrap();
+        let grpc_config = GrpcConfig { enabled: true, listen: "127.0.0.1:0".to_string() };
+        let grpc = GrpcServer::bind(&grpc_config, Arc::clone(&rpc)).await.unwrap();
+        TestNode { rpc, grpc, storage, state, consensus, mempool, validator, alice, _dir: dir }
+    }
+
+    async fn client(&self) -> NodeClient<Channel> {
+        NodeClient::connect(format!("http://{}", self.grpc.local_addr())).await.unwrap()
+    }
+
+    /// Proposes, finalizes and stores a block on top of the finalized tip.
+    async fn produce(&self, transactions: Vec<Transaction>) -> Block {
+        let mut consensus = self.consensus.lock().await;
+        common::produce(&mut consensus, &self.storage, &self.state, &self.validator, transactions)
+    }
+
+    async fn json_rpc(&self, method: &str, params: Value) -> Value {
+        reqwest::Client::new()
+            .post(format!("http://{}/", self.rpc.local_addr()))
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+}
+
+fn address(keypair: &Keypair) -> String {
+    DADBSAddress::from_pubkey(&keypair.pubkey()).to_string()
+}
+
+#[tokio::test]
+async fn test_queries_and_submission_match_json_rpc() {
+    let node = TestNode::start(RpcConfig::default(), LimitsConfig::default()).await;
+    let bob = Keypair::new();
+    let mut transfer = Transaction::new_signed(&node.alice, bob.pubkey(), 10, 1, 0, 0);
+    transfer.payload = b"memo".to_vec();
+    transfer.signature = node.alice.sign_message(&transfer.signing_bytes());
+    let block = node.produce(vec![transfer.clone()]).await;
+    let mut client = node.client().await;
+
+    let by_height = client.get_block_by_height(GetBlockByHeightRequest { height: 1 }).await.unwrap().into_inner();
+    let by_height = by_height.block.unwrap();
+    let expected = node.json_rpc("get_block_by_height", json!({ "height": 1 })).await;
+    assert_eq!(by_height.hash, expected["result"]["hash"]);
+    assert_eq!(by_height.transactions, vec![transfer.hash().to_string()]);
+    let by_hash = client.get_block_by_hash(HashRequest { hash: block.hash().to_string() }).await.unwrap().into_inner();
+    assert_eq!(by_hash.block, Some(by_height));
+    let missing = client.get_block_by_height(GetBlockByHeightRequest { height: 99 }).await.unwrap().into_inner();
+    assert_eq!(missing.block, None);
+
+    let stored = client.get_transaction(HashRequest { hash: transfer.hash().to_string() }).await.unwrap().into_inner();
+    let stored = stored.transaction.unwrap();
+    assert_eq!((stored.height, stored.amount, stored.payload.as_slice()), (1, 10, b"memo".as_slice()));
+    assert_eq!(stored.recipient, bob.pubkey().to_string());
+    let status = client.get_transaction(HashRequest { hash: "not a hash".to_string() }).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let alice = AddressRequest { address: address(&node.alice) };
+    assert_eq!(client.get_balance(alice.clone()).await.unwrap().into_inner().balance, 1_000 - 11);
+    assert_eq!(client.get_nonce(alice).await.unwrap().into_inner().nonce, 1);
+
+    let next = Transaction::new_signed(&node.alice, bob.pubkey(), 5, 1, 1, 0);
+    let raw = next.try_to_vec().unwrap();
+    let sent = client.send_transaction(SendTransactionRequest { raw }).await.unwrap().into_inner();
+    assert_eq!(sent.hash, next.hash().to_string());
+    assert_eq!(node.mempool.lock().len(), 1);
+    // Refused as JSON-RPC refuses it, with its code alongside.
+    let replay = client.send_transaction(SendTransactionRequest { raw: next.try_to_vec().unwrap() }).await.unwrap_err();
+    assert_eq!(replay.code(), Code::FailedPrecondition);
+    assert_eq!(replay.metadata().get(RPC_CODE_KEY).unwrap(), "-32002");
+}
+
+#[tokio::test]
+async fn test_rate_limits_are_shared_with_json_rpc() {
+    let mut limits = LimitsConfig::default();
+    limits.methods.insert("get_balance".to_string(), BucketConfig::new(0.5, 2));
+    let node = TestNode::start(RpcConfig::default(), limits).await;
+    let mut client = node.client().await;
+    let alice = AddressRequest { address: address(&node.alice) };
+
+    client.get_balance(alice.clone()).await.unwrap();
+    assert!(node.json_rpc("get_balance", json!({ "address": address(&node.alice) })).await.get("result").is_some());
+    let limited = client.get_balance(alice.clone()).await.unwrap_err();
+    assert_eq!(limited.code(), Code::ResourceExhausted);
+    assert_eq!(limited.metadata().get(RPC_CODE_KEY).unwrap(), "-32005");
+    let limited = node.json_rpc("get_balance", json!({ "address": address(&node.alice) })).await;
+    assert_eq!(limited["error"]["code"], -32005);
+    // Other methods keep their own buckets.
+    client.get_nonce(alice).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_new_blocks_stream_and_stream_cap() {
+    let config = RpcConfig { max_subscriptions: 1, ..RpcConfig::default() };
+    let node = TestNode::start(config, LimitsConfig::default()).await;
+    let mut client = node.client().await;
+    let mut stream = client.subscribe_new_blocks(SubscribeNewBlocksRequest {}).await.unwrap().into_inner();
+
+    let refused = client.subscribe_new_blocks(SubscribeNewBlocksRequest {}).await.unwrap_err();
+    assert_eq!(refused.code(), Code::ResourceExhausted);
+
+    let mut produced = Vec::new();
+    for _ in 0..3 {
+        produced.push(node.produce(Vec::new()).await.hash().to_string());
+    }
+    let mut streamed = Vec::new();
+    while streamed.len() < produced.len() {
+        let event = tokio::time::timeout(Duration::from_secs(5), stream.message()).await.unwrap().unwrap().unwrap();
+        match event.event {
+            Some(Event::Block(block)) => streamed.push(block.hash),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+    assert_eq!(streamed, produced);
+
+    // Hanging up gives the stream back.
+    drop(stream);
+    let mut reopened = None;
+    for _ in 0..50 {
+        match client.subscribe_new_blocks(SubscribeNewBlocksRequest {}).await {
+            Ok(stream) => {
+                reopened = Some(stream);
+                break;
+            }
+            Err(status) => assert_eq!(status.code(), Code::ResourceExhausted),
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(reopened.is_some());
+}
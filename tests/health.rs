@@ -0,0 +1,180 @@
+mod common;
+
+use async_trait::async_trait;
+use dadbs_node::node::{
+    ClockCheck, ComponentHealth, ConsensusCheck, ConsensusManager, Genesis, HealthCheck, HealthConfig,
+    HealthRegistry, HealthReport, HealthStatus, Heartbeat, LlmCheck, MetricsConfig, MetricsRegistry, MetricsServer,
+    Network, NetworkConfig, Node, NodeConfig, P2pCheck, PeersCheck, StorageCheck, Storage, ThresholdPolicy,
+    ValidatorInfo,
+};
+use reqwest::StatusCode;
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+async fn ready(base: &str) -> (StatusCode, HealthReport) {
+    let response = reqwest::get(format!("{}/health/ready", base)).await.unwrap();
+    (response.status(), response.json().await.unwrap())
+}
+
+fn failing(report: &HealthReport) -> Vec<&str> {
+    let mut names: Vec<&str> = report.components.iter()
+        .filter(|c| c.health.status == HealthStatus::Unhealthy)
+        .map(|c| c.name.as_str())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+async fn bind_network(node_id: &str) -> Arc<Network> {
+    Network::bind(NetworkConfig::new("127.0.0.1:0".parse().unwrap(), node_id)).await.unwrap().0
+}
+
+#[tokio::test]
+async fn test_readiness_tracks_each_component() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(Storage::open(dir.path()).unwrap());
+    let (network, peer) = (bind_network("health-a").await, bind_network("health-b").await);
+    let consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()));
+    let consensus = Arc::new(AsyncMutex::new(consensus));
+    let llm_loaded = Arc::new(AtomicBool::new(false));
+
+    let config = HealthConfig { min_peers: 1, max_clock_skew_ms: 5_000, check_timeout_ms: 500 };
+    let health = Arc::new(HealthRegistry::from_config(&config));
+    health.register(Arc::new(StorageCheck(storage)));
+    health.register(Arc::new(P2pCheck(Arc::clone(&network))));
+    health.register(Arc::new(PeersCheck { network: Arc::clone(&network), min_peers: config.min_peers }));
+    health.register(Arc::new(ConsensusCheck(Arc::clone(&consensus))));
+    health.register(Arc::new(ClockCheck { consensus: Arc::clone(&consensus), max_skew_ms: config.max_clock_skew_ms }));
+    health.register(Arc::new(LlmCheck(Arc::clone(&llm_loaded))));
+    let metrics_config = MetricsConfig { listen: "127.0.0.1:0".to_string() };
+    let server = MetricsServer::bind(&metrics_config, Arc::new(MetricsRegistry::new()), health).await.unwrap();
+    let base = format!("http://{}", server.local_addr());
+
+    let live = reqwest::get(format!("{}/health/live", base)).await.unwrap();
+    assert_eq!(live.status(), StatusCode::OK);
+
+    let (code, report) = ready(&base).await;
+    assert_eq!((code, report.status), (StatusCode::SERVICE_UNAVAILABLE, HealthStatus::Unhealthy));
+    assert_eq!(failing(&report), vec!["llm", "peers"]);
+    let names: Vec<&str> = report.components.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["storage", "p2p", "peers", "consensus", "clock", "llm"]);
+
+    llm_loaded.store(true, Ordering::Release);
+    network.connect(peer.local_addr()).await.unwrap();
+    let (code, report) = ready(&base).await;
+    assert_eq!((code, report.status), (StatusCode::OK, HealthStatus::Healthy));
+    assert!(failing(&report).is_empty());
+
+    consensus.lock().await.control().pause();
+    let (code, report) = ready(&base).await;
+    assert_eq!((code, failing(&report)), (StatusCode::SERVICE_UNAVAILABLE, vec!["consensus"]));
+    assert!(report.components[3].health.detail.contains("halted"));
+    consensus.lock().await.control().resume("").unwrap();
+    assert_eq!(ready(&base).await.0, StatusCode::OK);
+
+    // One peer's clock is 10s behind ours; two agreeing peers outvote it.
+    let now = chrono::Utc::now().timestamp_millis();
+    let from = "127.0.0.1:9000".parse().unwrap();
+    consensus.lock().await.record_heartbeat(from, &Heartbeat::new_signed(&Keypair::new(), 1, now - 10_000)).unwrap();
+    let (code, report) = ready(&base).await;
+    assert_eq!((code, failing(&report)), (StatusCode::SERVICE_UNAVAILABLE, vec!["clock"]));
+    for _ in 0..2 {
+        consensus.lock().await.record_heartbeat(from, &Heartbeat::new_signed(&Keypair::new(), 1, now)).unwrap();
+    }
+    assert_eq!(ready(&base).await.0, StatusCode::OK);
+
+    network.shutdown();
+    let (code, report) = ready(&base).await;
+    assert_eq!((code, failing(&report)), (StatusCode::SERVICE_UNAVAILABLE, vec!["p2p", "peers"]));
+    server.shutdown();
+}
+
+struct Fixed(&'static str, ComponentHealth);
+
+#[async_trait]
+impl HealthCheck for Fixed {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        self.1.clone()
+    }
+}
+
+struct Hung;
+
+#[async_trait]
+impl HealthCheck for Hung {
+    fn name(&self) -> &str {
+        "hung"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        ComponentHealth::healthy("never")
+    }
+}
+
+#[tokio::test]
+async fn test_hung_component_times_out_and_worst_status_wins() {
+    let health = HealthRegistry::new(Duration::from_millis(100));
+    health.register(Arc::new(Fixed("ok", ComponentHealth::healthy("fine"))));
+    health.register(Arc::new(Fixed("slow", ComponentHealth::degraded("lagging"))));
+    let report = health.readiness().await;
+    assert_eq!(report.status, HealthStatus::Degraded);
+    assert!(report.is_ready());
+
+    health.register(Arc::new(Hung));
+    let started = Instant::now();
+    let report = health.readiness().await;
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(report.status, HealthStatus::Unhealthy);
+    assert!(!report.is_ready());
+    assert_eq!(failing(&report), vec!["hung"]);
+    assert_eq!(report.components[2].health.detail, "no answer within 100ms");
+}
+
+#[tokio::test]
+async fn test_running_nodes_exchange_heartbeats() {
+    let dir = tempfile::tempdir().unwrap();
+    let genesis = Genesis::template("dadbs-testnet", vec![ValidatorInfo::new(Keypair::new().pubkey(), 1)]);
+    let (first_dir, second_dir) = (dir.path().join("first"), dir.path().join("second"));
+    std::fs::create_dir_all(&first_dir).unwrap();
+    std::fs::create_dir_all(&second_dir).unwrap();
+    let first = Node::start(NodeConfig {
+        heartbeat_interval_ms: 50,
+        ..common::node_config("first", &first_dir, &genesis)
+    })
+    .await
+    .unwrap();
+    let second = Node::start(NodeConfig {
+        bootstrap_nodes: vec![first.p2p_addr().to_string()],
+        heartbeat_interval_ms: 50,
+        ..common::node_config("second", &second_dir, &genesis)
+    })
+    .await
+    .unwrap();
+
+    // Each compares its clock with the other's heartbeats.
+    for node in [&first, &second] {
+        let base = format!("http://{}", node.metrics_addr());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (_, report) = ready(&base).await;
+                let clock = report.components.iter().find(|c| c.name == "clock").unwrap();
+                if clock.health.detail.starts_with("clock is") {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("no heartbeat arrived");
+    }
+    second.stop().await.unwrap();
+    first.stop().await.unwrap();
+}
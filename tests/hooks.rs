@@ -0,0 +1,217 @@
+use async_trait::async_trait;
+use borsh::BorshSerialize;
+use dadbs_node::node::{
+    Block, ChainEvents, ConsensusManager, HookRegistry, HooksConfig, Mempool, NodeHooks, RpcConfig, RpcContext,
+    RpcServer, State, Storage, ThresholdPolicy, Transaction, TxTracer, ValidatorInfo, ValidatorSet,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+
+/// Every event it is called for, in order.
+#[derive(Default)]
+struct Recording(Mutex<Vec<String>>);
+
+#[async_trait]
+impl NodeHooks for Recording {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    async fn on_tx_admitted(&self, transaction: &Transaction) {
+        self.0.lock().push(format!("tx_admitted {}", transaction.hash()));
+    }
+
+    async fn on_block_proposed(&self, block: &Block) {
+        self.0.lock().push(format!("block_proposed {}", block.height()));
+    }
+
+    async fn on_block_finalized(&self, block: &Block) {
+        self.0.lock().push(format!("block_finalized {}", block.height()));
+    }
+
+    async fn on_reorg(&self, rolled_back: &[Block], applied: &[Block]) {
+        self.0.lock().push(format!("reorg {} {}", rolled_back.len(), applied.len()));
+    }
+
+    async fn on_validator_set_change(&self, previous: &ValidatorSet, current: &ValidatorSet) {
+        self.0.lock().push(format!("validator_set_change {} {}", previous.len(), current.len()));
+    }
+}
+
+/// Panics on every event.
+struct Panicking;
+
+#[async_trait]
+impl NodeHooks for Panicking {
+    fn name(&self) -> &str {
+        "panicking"
+    }
+
+    async fn on_tx_admitted(&self, _transaction: &Transaction) {
+        panic!("tx_admitted");
+    }
+
+    async fn on_block_proposed(&self, _block: &Block) {
+        panic!("block_proposed");
+    }
+
+    async fn on_block_finalized(&self, _block: &Block) {
+        panic!("block_finalized");
+    }
+
+    async fn on_reorg(&self, _rolled_back: &[Block], _applied: &[Block]) {
+        panic!("reorg");
+    }
+
+    async fn on_validator_set_change(&self, _previous: &ValidatorSet, _current: &ValidatorSet) {
+        panic!("validator_set_change");
+    }
+}
+
+/// Outlasts any sensible `timeout_ms` on every finalized block.
+struct Sleeping;
+
+#[async_trait]
+impl NodeHooks for Sleeping {
+    fn name(&self) -> &str {
+        "sleeping"
+    }
+
+    async fn on_block_finalized(&self, _block: &Block) {
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+fn child(parent: &Block) -> Block {
+    Block::with_transactions(parent.height() + 1, parent.hash(), 0, Pubkey::new_unique(), Vec::new())
+}
+
+#[tokio::test]
+async fn test_every_lifecycle_event_reaches_hooks_once_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let (validator, alice) = (Keypair::new(), Keypair::new());
+    let recording = Arc::new(Recording::default());
+    let (hooks, runner) = HookRegistry::new()
+        .with_hook(Arc::new(Panicking))
+        .with_hook(Arc::clone(&recording) as Arc<dyn NodeHooks>)
+        .start(&HooksConfig::default());
+    let cancel = CancellationToken::new();
+    tokio::spawn(runner.run(cancel.clone()));
+
+    let storage = Arc::new(Storage::open(dir.path()).unwrap());
+    let state = Arc::new(RwLock::new(State::in_memory(vec![(DADBSAddress::from_pubkey(&alice.pubkey()), 1_000)])));
+    let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_state(Arc::clone(&state))
+        .with_hooks(hooks.clone());
+    consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+    let mempool = Arc::new(Mutex::new(Mempool::new(1, 100)));
+    let consensus = Arc::new(AsyncMutex::new(consensus));
+    let context = RpcContext {
+        node_id: "hooks-test".to_string(),
+        chain_id: "dadbs-testnet".to_string(),
+        storage,
+        consensus: Arc::clone(&consensus),
+        state: Arc::clone(&state),
+        mempool: Arc::clone(&mempool),
+        network: None,
+        events: ChainEvents::default(),
+        tracer: TxTracer::default(),
+    };
+    let config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+    let rpc = RpcServer::bind(config, context).await.unwrap();
+    rpc.notify_hooks(hooks.clone());
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let tx = Transaction::new_signed(&alice, Pubkey::new_unique(), 10, 1, 0, now);
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "send_transaction",
+        "params": { "raw": hex::encode(tx.try_to_vec().unwrap()) },
+    });
+    let response: Value = rpc.handle_body([127, 0, 0, 1].into(), None, request.to_string().as_bytes()).await.unwrap();
+    assert!(response.get("result").is_some(), "{}", response);
+
+    let mut consensus = consensus.lock().await;
+    let proposed = consensus.build_block(&mut mempool.lock(), validator.pubkey(), 0, 10).await.unwrap();
+    assert_eq!(proposed.transactions, vec![tx.clone()]);
+    consensus.apply_block(proposed.clone(), 10).unwrap();
+    consensus.finalize_block(&proposed.hash()).unwrap();
+
+    // A heavier branch on top of the finalized block replaces the head.
+    let (light, heavy) = (child(&proposed), child(&proposed));
+    consensus.apply_block(light, 1).unwrap();
+    assert!(consensus.apply_block(heavy.clone(), 5).unwrap().is_reorg());
+    consensus.finalize_block(&heavy.hash()).unwrap();
+    let joined = ValidatorInfo::new(Pubkey::new_unique(), 5);
+    consensus.replace_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10), joined]));
+    assert_eq!(consensus.finalized_height(), 2);
+    drop(consensus);
+
+    let expected = vec![
+        format!("tx_admitted {}", tx.hash()),
+        "block_proposed 1".to_string(),
+        "block_finalized 1".to_string(),
+        "reorg 1 1".to_string(),
+        "block_finalized 2".to_string(),
+        "validator_set_change 1 2".to_string(),
+    ];
+    timeout(Duration::from_secs(5), async {
+        while recording.0.lock().len() < expected.len() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("hooks never saw every event");
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(*recording.0.lock(), expected);
+
+    // The panicking hook was called for each event too, without holding anything up.
+    let stats = hooks.stats().snapshot();
+    assert_eq!((stats.delivered, stats.panicked, stats.timed_out, stats.dropped), (6, 6, 0, 0));
+    cancel.cancel();
+}
+
+#[tokio::test]
+async fn test_slow_hook_times_out_without_holding_up_finalization() {
+    let recording = Arc::new(Recording::default());
+    let (hooks, runner) = HookRegistry::new()
+        .with_hook(Arc::new(Sleeping))
+        .with_hook(Arc::clone(&recording) as Arc<dyn NodeHooks>)
+        .start(&HooksConfig { timeout_ms: 50, ..HooksConfig::default() });
+    let cancel = CancellationToken::new();
+    tokio::spawn(runner.run(cancel.clone()));
+
+    let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_hooks(hooks.clone());
+    consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(Pubkey::new_unique(), 10)]));
+    let mut parent = consensus.block_tree().finalized().clone();
+    for _ in 0..2 {
+        let block = child(&parent);
+        consensus.apply_block(block.clone(), 10).unwrap();
+        consensus.finalize_block(&block.hash()).unwrap();
+        parent = block;
+    }
+    assert_eq!(consensus.finalized_height(), 2);
+
+    // Each late call is abandoned, and the hook after it still hears of both blocks.
+    let expected = vec!["block_finalized 1".to_string(), "block_finalized 2".to_string()];
+    timeout(Duration::from_secs(5), async {
+        while *recording.0.lock() != expected {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the hook after the slow one never saw every block");
+    let stats = hooks.stats().snapshot();
+    assert_eq!((stats.timed_out, stats.panicked, stats.dropped), (2, 0, 0));
+    cancel.cancel();
+}
@@ -0,0 +1,199 @@
+#![cfg(feature = "llm")]
+
+use async_trait::async_trait;
+use dadbs_node::llm::{
+    ChallengeConfig, ChallengeOutcome, Completion, GenerateParams, InferenceChallenger, InferenceRouter, KvCache, LightLLM,
+    LlmError, LocalInference, ModelBackend, RouterConfig,
+};
+use dadbs_node::node::network::CAPABILITY_LLM;
+use dadbs_node::node::{Network, NetworkConfig};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// A token per letter from a to h, with logits that depend on the whole
+/// sequence. A nonzero `skew` is a different model, or a dishonest peer.
+struct Waves {
+    skew: f32,
+}
+
+impl ModelBackend for Waves {
+    fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+        Ok(text.bytes().filter(|byte| (b'a'..=b'h').contains(byte)).map(|byte| u32::from(byte - b'a')).collect())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+        Ok(tokens.iter().map(|&token| char::from(b'a' + token as u8)).collect())
+    }
+
+    fn new_cache(&self) -> Result<KvCache, LlmError> {
+        Ok(KvCache::new(Vec::<u32>::new()))
+    }
+
+    fn forward(&self, cache: &mut KvCache, tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+        let seen = cache.state_mut::<Vec<u32>>().unwrap();
+        seen.extend_from_slice(tokens);
+        let phase = seen.iter().enumerate().map(|(i, &token)| (i as f32 + 1.0) * token as f32).sum::<f32>();
+        Ok((0..8).map(|i| (phase * 0.37 + i as f32 * (1.3 + self.skew)).sin() * 2.0).collect())
+    }
+
+    fn bos_token(&self) -> Option<u32> {
+        None
+    }
+
+    fn eos_token(&self) -> Option<u32> {
+        None
+    }
+
+    fn context_length(&self) -> usize {
+        4096
+    }
+}
+
+/// Advertises LLM capability but fails every request.
+struct Broken;
+
+#[async_trait]
+impl LocalInference for Broken {
+    async fn generate(&self, _prompt: &str, _params: GenerateParams) -> Result<Completion, LlmError> {
+        Err(LlmError::InferenceFailed("out of memory".to_string()))
+    }
+}
+
+fn model(skew: f32) -> Arc<LightLLM> {
+    Arc::new(LightLLM::with_backend(Arc::new(Waves { skew })))
+}
+
+async fn network(name: &str, capabilities: u32) -> Arc<Network> {
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), name).with_capabilities(capabilities);
+    Network::bind(config).await.unwrap().0
+}
+
+/// A node routing inference with `local`, answering its peers' requests.
+async fn node(name: &str, capabilities: u32, local: Arc<dyn LocalInference>, config: RouterConfig) -> (Arc<Network>, Arc<InferenceRouter>) {
+    let network = network(name, capabilities).await;
+    let router = Arc::new(InferenceRouter::new(Arc::clone(&network), local, config));
+    tokio::spawn(Arc::clone(&router).run(CancellationToken::new()));
+    (network, router)
+}
+
+fn params() -> GenerateParams {
+    GenerateParams::new(12).with_temperature(1.0).with_seed(7)
+}
+
+fn sorted(mut peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    peers.sort();
+    peers
+}
+
+#[tokio::test]
+async fn test_majority_answer_wins_and_dishonest_peer_is_penalized() {
+    let config = RouterConfig::default().with_peers(3).with_quorum(2).with_timeout(Duration::from_secs(5));
+    let (requester, router) = node("requester", 0, model(0.0), config).await;
+    let (honest_a, _) = node("honest-a", CAPABILITY_LLM, model(0.0), config).await;
+    let (honest_b, _) = node("honest-b", CAPABILITY_LLM, model(0.0), config).await;
+    let (dishonest, _) = node("dishonest", CAPABILITY_LLM, model(0.5), config).await;
+    // Connected, but not asked: it does not advertise the capability.
+    let (plain, _) = node("plain", 0, model(0.0), config).await;
+    for peer in [&honest_a, &honest_b, &dishonest, &plain] {
+        requester.connect(peer.local_addr()).await.unwrap();
+    }
+
+    let expected = model(0.0).generate("abc", params()).await.unwrap();
+    assert_ne!(model(0.5).generate("abc", params()).await.unwrap().text, expected.text);
+    let routed = router.generate("abc", params()).await.unwrap();
+    assert!(!routed.local);
+    assert_eq!(routed.completion.text, expected.text);
+    assert_eq!(routed.completion.finish, expected.finish);
+    assert_eq!(sorted(routed.agreeing), sorted(vec![honest_a.local_addr(), honest_b.local_addr()]));
+    assert_eq!(routed.disagreeing, vec![dishonest.local_addr()]);
+    assert!(routed.unanswered.is_empty());
+
+    assert!(requester.peer_score(&dishonest.local_addr()) > 0.0);
+    assert_eq!(requester.peer_score(&honest_a.local_addr()), 0.0);
+    assert_eq!(requester.peer_score(&plain.local_addr()), 0.0);
+
+    // Logprobs cannot be checked against the peers' answers.
+    assert!(matches!(router.generate("abc", params().with_logprobs(1)).await, Err(LlmError::InvalidParams(_))));
+}
+
+#[tokio::test]
+async fn test_falls_back_to_local_without_a_quorum() {
+    let config = RouterConfig::default().with_peers(4).with_quorum(3).with_timeout(Duration::from_millis(300));
+    let (requester, router) = node("requester", 0, model(0.0), config).await;
+    let (honest, _) = node("honest", CAPABILITY_LLM, model(0.0), config).await;
+    let (dishonest, _) = node("dishonest", CAPABILITY_LLM, model(0.5), config).await;
+    let (broken, _) = node("broken", CAPABILITY_LLM, Arc::new(Broken), config).await;
+    // Advertises the capability but never answers.
+    let silent = network("silent", CAPABILITY_LLM).await;
+    for peer in [&honest, &dishonest, &broken, &silent] {
+        requester.connect(peer.local_addr()).await.unwrap();
+    }
+
+    let routed = router.generate("abc", params()).await.unwrap();
+    assert!(routed.local);
+    assert_eq!(routed.completion.text, model(0.0).generate("abc", params()).await.unwrap().text);
+    assert!(routed.completion.logprobs.is_empty());
+    assert_eq!(routed.agreeing, vec![honest.local_addr()]);
+    assert_eq!(routed.disagreeing, vec![dishonest.local_addr()]);
+    assert_eq!(sorted(routed.unanswered), sorted(vec![broken.local_addr(), silent.local_addr()]));
+
+    // Refusing or timing out is not lying.
+    assert!(requester.peer_score(&dishonest.local_addr()) > 0.0);
+    for peer in [&honest, &broken, &silent] {
+        assert_eq!(requester.peer_score(&peer.local_addr()), 0.0);
+    }
+
+    // Too few capable peers to reach a quorum: nobody is asked.
+    let (lonely, router) = node("lonely", 0, model(0.0), config).await;
+    lonely.connect(honest.local_addr()).await.unwrap();
+    let routed = router.generate("abc", params()).await.unwrap();
+    assert!(routed.local);
+    assert!(routed.agreeing.is_empty() && routed.unanswered.is_empty());
+}
+
+/// A node answering challenges with `local`, and able to send its own.
+async fn challenger(name: &str, capabilities: u32, local: Arc<LightLLM>, config: ChallengeConfig) -> (Arc<Network>, Arc<InferenceChallenger>) {
+    let network = network(name, capabilities).await;
+    let challenger = Arc::new(InferenceChallenger::new(Arc::clone(&network), local, config));
+    tokio::spawn(Arc::clone(&challenger).run(CancellationToken::new()));
+    (network, challenger)
+}
+
+#[tokio::test]
+async fn test_challenges_catch_a_wrong_model_and_a_silent_peer() {
+    let config = ChallengeConfig::default().with_timeout(Duration::from_millis(300));
+    let (validator, challenges) = challenger("validator", 0, model(0.0), config).await;
+    let (honest, _) = challenger("honest", CAPABILITY_LLM, model(0.0), config).await;
+    let (wrong, _) = challenger("wrong", CAPABILITY_LLM, model(0.5), config).await;
+    // Advertises the capability but never answers.
+    let silent = network("silent", CAPABILITY_LLM).await;
+    for peer in [&honest, &wrong, &silent] {
+        validator.connect(peer.local_addr()).await.unwrap();
+    }
+
+    assert_eq!(challenges.challenge(honest.local_addr(), "abc").await.unwrap(), ChallengeOutcome::Passed);
+    assert_eq!(validator.peer_score(&honest.local_addr()), 0.0);
+
+    match challenges.challenge(wrong.local_addr(), "abc").await.unwrap() {
+        ChallengeOutcome::Mismatched(evidence) => {
+            assert_eq!((evidence.peer, evidence.positions.len()), (wrong.local_addr(), 4));
+            assert!(evidence.positions.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", evidence.positions);
+            assert_eq!(evidence.reported.len(), 4);
+            assert_ne!(evidence.reported, evidence.expected);
+        }
+        other => panic!("expected a mismatch, got {:?}", other),
+    }
+    assert_eq!(challenges.evidence().len(), 1);
+    assert!(validator.peer_score(&wrong.local_addr()) > 0.0);
+
+    assert_eq!(challenges.challenge(silent.local_addr(), "abc").await.unwrap(), ChallengeOutcome::TimedOut);
+    assert!(validator.peer_score(&silent.local_addr()) > 0.0);
+
+    // Each side allows one challenge per peer and interval.
+    assert!(matches!(challenges.challenge(honest.local_addr(), "abc").await, Err(LlmError::InvalidParams(_))));
+    let eager = Arc::new(InferenceChallenger::new(Arc::clone(&validator), model(0.0), config.with_min_interval(Duration::ZERO)));
+    tokio::spawn(Arc::clone(&eager).run(CancellationToken::new()));
+    assert!(matches!(eager.challenge(honest.local_addr(), "abc").await.unwrap(), ChallengeOutcome::Refused(_)));
+}
@@ -0,0 +1,182 @@
+use borsh::BorshSerialize;
+use dadbs_node::node::network::{write_frame, PROTOCOL_VERSION};
+use dadbs_node::node::rpc::MEMPOOL_FULL;
+use dadbs_node::node::{
+    ChainEvents, ConsensusManager, IngestConfig, IngestPipeline, Mempool, MetricsSource, NetMessage, Network,
+    NetworkConfig, RpcConfig, RpcContext, RpcServer, State, Storage, ThresholdPolicy, Transaction, TxTracer, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{sleep, timeout, Instant};
+
+const WAIT: Duration = Duration::from_secs(10);
+const FLOOD: usize = 2000;
+
+async fn start(ingest: IngestConfig) -> (Arc<Network>, Arc<IngestPipeline>) {
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "ingest-test")
+        .with_max_messages_per_second(1_000_000)
+        .with_ingest(ingest);
+    Network::bind(config).await.unwrap()
+}
+
+/// A handshaken connection that never reads, like a peer that only floods.
+async fn raw_peer(network: &Network, node_id: &str) -> TcpStream {
+    let mut raw = TcpStream::connect(network.local_addr()).await.unwrap();
+    let hello = NetMessage::Handshake {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        node_id: node_id.to_string(),
+        listen_port: 0,
+        codecs: vec![],
+        chain_id: "dadbs-testnet".to_string(),
+        genesis_hash: Hash::default(),
+        observed_addr: None,
+        capabilities: 0,
+        llm: None,
+        identity: None,
+        nonce: [0; 32],
+    };
+    write_frame(&mut raw, &hello, 1024).await.unwrap();
+    raw
+}
+
+async fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + WAIT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        sleep(Duration::from_millis(5)).await;
+    }
+}
+
+async fn rpc_server(network: &Arc<Network>, alice: &Keypair) -> (Arc<RpcServer>, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let state = Arc::new(RwLock::new(State::in_memory(vec![(DADBSAddress::from_pubkey(&alice.pubkey()), 1_000)])));
+    let consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_state(Arc::clone(&state));
+    let context = RpcContext {
+        node_id: "ingest-test".to_string(),
+        chain_id: "dadbs-testnet".to_string(),
+        storage: Arc::new(Storage::open(dir.path()).unwrap()),
+        consensus: Arc::new(AsyncMutex::new(consensus)),
+        state,
+        mempool: Arc::new(Mutex::new(Mempool::new(1, 100))),
+        network: Some(Arc::clone(network)),
+        events: ChainEvents::default(),
+        tracer: TxTracer::default(),
+    };
+    let rpc_config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+    (RpcServer::bind(rpc_config, context).await.unwrap(), dir)
+}
+
+async fn send_transaction(rpc: SocketAddr, transaction: &Transaction) -> Value {
+    let request = json!({
+        "jsonrpc": "2.0", "id": 1, "method": "send_transaction",
+        "params": { "raw": hex::encode(transaction.try_to_vec().unwrap()) },
+    });
+    reqwest::Client::new().post(format!("http://{}/", rpc)).json(&request)
+        .send().await.unwrap()
+        .json().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_flooding_peer_is_shed_while_votes_get_through() {
+    let (node, inbound) = start(IngestConfig::default().with_queue_sizes(8, 32).with_busy_transactions(32)).await;
+    let alice = Keypair::new();
+    let (rpc, _dir) = rpc_server(&node, &alice).await;
+    let flooder = raw_peer(&node, "flooder").await;
+    let mut validator = raw_peer(&node, "validator").await;
+    let validator_addr = validator.local_addr().unwrap();
+    wait_until("both peers", || node.peer_count() == 2).await;
+
+    // Nothing consumes the pipeline while the flood arrives.
+    let flood = tokio::spawn(async move {
+        let (mut flooder, sender) = (flooder, Keypair::new());
+        for nonce in 0..FLOOD as u64 {
+            let tx = Transaction::new_signed(&sender, Pubkey::new_unique(), 1, 1, nonce, 0);
+            write_frame(&mut flooder, &NetMessage::Tx(tx), 1 << 20).await.unwrap();
+        }
+        flooder
+    });
+    wait_until("the flood to be ingested", || {
+        let stats = inbound.stats();
+        assert!(stats.transactions_queued <= 32, "{:?}", stats);
+        stats.transactions_queued as u64 + stats.transactions_shed == FLOOD as u64
+    })
+    .await;
+    let _flooder = flood.await.unwrap();
+    let stats = inbound.stats();
+    assert_eq!((stats.transactions_queued, stats.transactions_shed), (32, (FLOOD - 32) as u64));
+    assert!(stats.busy);
+    let mut metrics = String::new();
+    inbound.render_metrics(&mut metrics);
+    assert!(metrics.contains(&format!("dadbs_ingest_shed_total {}", FLOOD - 32)), "{}", metrics);
+    assert!(metrics.contains("dadbs_ingest_busy 1"), "{}", metrics);
+
+    // A vote sent after the flood is still handled first.
+    let vote = Vote::new(&Keypair::new(), 1, 0, Hash::new_unique());
+    write_frame(&mut validator, &NetMessage::Vote(vote.clone()), 1 << 20).await.unwrap();
+    wait_until("the vote", || inbound.stats().consensus_queued == 1).await;
+    let first = timeout(WAIT, inbound.recv()).await.unwrap().unwrap();
+    assert_eq!(first, (validator_addr, NetMessage::Vote(vote)));
+
+    // While busy, RPC refuses transactions with a retriable error.
+    let transaction = Transaction::new_signed(&alice, Keypair::new().pubkey(), 10, 1, 0, 0);
+    let response = send_transaction(rpc.local_addr(), &transaction).await;
+    assert_eq!(response["error"]["code"], MEMPOOL_FULL, "{}", response);
+    assert!(response["error"]["data"]["retry_after_ms"].as_u64().unwrap() > 0);
+
+    let mut drained = 0;
+    while let Some((_, message)) = inbound.try_recv() {
+        assert!(matches!(message, NetMessage::Tx(_)));
+        drained += 1;
+    }
+    assert_eq!(drained, 32);
+    assert!(!inbound.is_busy());
+    let response = send_transaction(rpc.local_addr(), &transaction).await;
+    assert_eq!(response["result"], transaction.hash().to_string(), "{}", response);
+    node.shutdown();
+}
+
+#[tokio::test]
+async fn test_consensus_flood_backpressures_only_its_peer() {
+    let (node, inbound) = start(IngestConfig::default().with_queue_sizes(4, 4)).await;
+    let mut flooder = raw_peer(&node, "flooder").await;
+    let mut other = raw_peer(&node, "other").await;
+    let other_addr = other.local_addr().unwrap();
+    wait_until("both peers", || node.peer_count() == 2).await;
+
+    for nonce in 0..100 {
+        write_frame(&mut flooder, &NetMessage::Ping(nonce), 1024).await.unwrap();
+    }
+    wait_until("the flooder's queue to fill", || inbound.stats().consensus_queued == 4).await;
+    write_frame(&mut other, &NetMessage::Ping(u64::MAX), 1024).await.unwrap();
+    wait_until("the other peer's ping", || inbound.stats().consensus_queued == 5).await;
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(inbound.stats().consensus_queued, 5);
+
+    // Peers take turns, so the other peer waits behind at most one message.
+    let served: Vec<SocketAddr> = [inbound.try_recv(), inbound.try_recv()].into_iter().flatten().map(|(peer, _)| peer).collect();
+    assert!(served.contains(&other_addr), "{:?}", served);
+
+    // The flooder was slowed down, not dropped: every ping arrives in order.
+    let mut nonces = Vec::new();
+    while nonces.len() < 99 {
+        match timeout(WAIT, inbound.recv()).await.unwrap().unwrap() {
+            (_, NetMessage::Ping(nonce)) if nonce != u64::MAX => nonces.push(nonce),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(inbound.stats().consensus_queued <= 4);
+    }
+    assert!(nonces.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(nonces.last(), Some(&99));
+    node.shutdown();
+}
@@ -0,0 +1,522 @@
+#![cfg(feature = "llm")]
+
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+use dadbs_node::llm::{
+    cosine_similarity, detect_format, Activation, ChatMessage, DeviceInfo, GenerateParams, LightLLM, LlmError, LoraAdapter, LoraConfig,
+    ModelFormat, ModelManager, Pooling, StartupCache,
+};
+use dadbs_node::node::LLMConfig;
+use sha2::Digest;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::{PreTokenizerWrapper, Tokenizer};
+
+const EMBEDDING: usize = 32;
+const FEED_FORWARD: usize = 64;
+const WORDS: [&str; 16] = ["<unk>", "<s>", "</s>", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m"];
+
+fn weight(shape: &[usize], dtype: GgmlDType) -> QTensor {
+    let tensor = Tensor::randn(0f32, 0.5, shape, &Device::Cpu).unwrap();
+    QTensor::quantize(&tensor, dtype).unwrap()
+}
+
+/// A one-layer Llama small enough to write out in the test, with a word
+/// per token.
+fn fixture(dir: &Path) -> (PathBuf, PathBuf) {
+    layered_fixture(dir, 1)
+}
+
+/// `fixture` with `blocks` layers, those after the first adding little to
+/// what it computes.
+fn layered_fixture(dir: &Path, blocks: usize) -> (PathBuf, PathBuf) {
+    let matrix = |rows: usize, columns: usize| weight(&[rows, columns], GgmlDType::Q8_0);
+    let norm = || weight(&[EMBEDDING], GgmlDType::F32);
+    let mut tensors = vec![
+        ("token_embd.weight".to_string(), matrix(WORDS.len(), EMBEDDING)),
+        ("output_norm.weight".to_string(), norm()),
+        ("output.weight".to_string(), matrix(WORDS.len(), EMBEDDING)),
+    ];
+    for block in 0..blocks {
+        let output = |rows: usize, columns: usize| {
+            let scale = if block == 0 { 0.5 } else { 0.005 };
+            QTensor::quantize(&Tensor::randn(0f32, scale, (rows, columns), &Device::Cpu).unwrap(), GgmlDType::Q8_0).unwrap()
+        };
+        tensors.extend([
+            ("attn_norm", norm()),
+            ("attn_q", matrix(EMBEDDING, EMBEDDING)),
+            ("attn_k", matrix(EMBEDDING, EMBEDDING)),
+            ("attn_v", matrix(EMBEDDING, EMBEDDING)),
+            ("attn_output", output(EMBEDDING, EMBEDDING)),
+            ("ffn_norm", norm()),
+            ("ffn_gate", matrix(FEED_FORWARD, EMBEDDING)),
+            ("ffn_up", matrix(FEED_FORWARD, EMBEDDING)),
+            ("ffn_down", output(EMBEDDING, FEED_FORWARD)),
+        ].map(|(name, tensor)| (format!("blk.{}.{}.weight", block, name), tensor)));
+    }
+    let metadata = [
+        ("llama.attention.head_count", gguf_file::Value::U32(2)),
+        ("llama.attention.head_count_kv", gguf_file::Value::U32(2)),
+        ("llama.block_count", gguf_file::Value::U32(blocks as u32)),
+        ("llama.embedding_length", gguf_file::Value::U32(EMBEDDING as u32)),
+        ("llama.rope.dimension_count", gguf_file::Value::U32(16)),
+        ("llama.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-5)),
+        ("llama.context_length", gguf_file::Value::U32(256)),
+    ];
+    let model_path = dir.join("tiny.gguf");
+    let mut file = File::create(&model_path).unwrap();
+    let metadata: Vec<(&str, &gguf_file::Value)> = metadata.iter().map(|(key, value)| (*key, value)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect();
+    gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+
+    let vocab: HashMap<String, u32> = WORDS.iter().enumerate().map(|(id, word)| (word.to_string(), id as u32)).collect();
+    let mut tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("<unk>".to_string()).build().unwrap());
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::from(Whitespace {}));
+    let tokenizer_path = dir.join("tokenizer.json");
+    tokenizer.save(&tokenizer_path, false).unwrap();
+    (model_path, tokenizer_path)
+}
+
+/// The GGUF model at `path` cut down to its first `blocks` layers, beside
+/// it as `draft.gguf`.
+fn truncated(path: &Path, blocks: usize) -> PathBuf {
+    let mut file = File::open(path).unwrap();
+    let content = gguf_file::Content::read(&mut file).unwrap();
+    let mut metadata = content.metadata.clone();
+    metadata.insert("llama.block_count".to_string(), gguf_file::Value::U32(blocks as u32));
+    let kept = |name: &str| match name.strip_prefix("blk.").and_then(|rest| rest.split('.').next()) {
+        Some(block) => block.parse::<usize>().unwrap() < blocks,
+        None => true,
+    };
+    let tensors: Vec<(String, QTensor)> = content.tensor_infos.keys()
+        .filter(|name| kept(name.as_str()))
+        .map(|name| (name.clone(), content.tensor(&mut file, name, &Device::Cpu).unwrap()))
+        .collect();
+    let draft_path = path.with_file_name("draft.gguf");
+    let mut draft = File::create(&draft_path).unwrap();
+    let metadata: Vec<(&str, &gguf_file::Value)> = metadata.iter().map(|(key, value)| (key.as_str(), value)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect();
+    gguf_file::write(&mut draft, &metadata, &tensors).unwrap();
+    draft_path
+}
+
+fn write_architecture(path: &Path, kv_heads: usize) {
+    let architecture = serde_json::json!({
+        "hidden_size": EMBEDDING,
+        "intermediate_size": FEED_FORWARD,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 2,
+        "num_key_value_heads": kv_heads,
+        "vocab_size": WORDS.len(),
+    });
+    std::fs::write(path, architecture.to_string()).unwrap();
+}
+
+/// The fixture's model in f16 safetensors, with one key/value head for
+/// its two query heads, and the `config.json` that says so beside it.
+fn safetensors_fixture(dir: &Path) -> (PathBuf, PathBuf) {
+    let (_, tokenizer_path) = fixture(dir);
+    let matrix = |rows: usize, columns: usize| {
+        Tensor::randn(0f32, 0.5, (rows, columns), &Device::Cpu).unwrap().to_dtype(DType::F16).unwrap()
+    };
+    let norm = || Tensor::ones(EMBEDDING, DType::F16, &Device::Cpu).unwrap();
+    let kv = EMBEDDING / 2;
+    let tensors: HashMap<&str, Tensor> = [
+        ("model.embed_tokens.weight", matrix(WORDS.len(), EMBEDDING)),
+        ("model.norm.weight", norm()),
+        ("lm_head.weight", matrix(WORDS.len(), EMBEDDING)),
+        ("model.layers.0.input_layernorm.weight", norm()),
+        ("model.layers.0.self_attn.q_proj.weight", matrix(EMBEDDING, EMBEDDING)),
+        ("model.layers.0.self_attn.k_proj.weight", matrix(kv, EMBEDDING)),
+        ("model.layers.0.self_attn.v_proj.weight", matrix(kv, EMBEDDING)),
+        ("model.layers.0.self_attn.o_proj.weight", matrix(EMBEDDING, EMBEDDING)),
+        ("model.layers.0.post_attention_layernorm.weight", norm()),
+        ("model.layers.0.mlp.gate_proj.weight", matrix(FEED_FORWARD, EMBEDDING)),
+        ("model.layers.0.mlp.up_proj.weight", matrix(FEED_FORWARD, EMBEDDING)),
+        ("model.layers.0.mlp.down_proj.weight", matrix(EMBEDDING, FEED_FORWARD)),
+    ]
+    .into_iter()
+    .collect();
+    let model_path = dir.join("model.safetensors");
+    candle_core::safetensors::save(&tensors, &model_path).unwrap();
+    write_architecture(&dir.join("config.json"), 1);
+    (model_path, tokenizer_path)
+}
+
+fn config(model_path: &Path, tokenizer_path: &Path) -> LLMConfig {
+    LLMConfig {
+        enabled: true,
+        model_path: model_path.display().to_string(),
+        tokenizer_path: tokenizer_path.display().to_string(),
+        max_batch_size: 1,
+        max_queue_depth: 8,
+        batch_window_ms: 5,
+        use_gpu: false,
+        device: "auto".to_string(),
+        format: None,
+        pooling: Pooling::Mean,
+        model_sha256: None,
+        tokenizer_sha256: None,
+        model_config_path: None,
+        prompt_template: "llama2".to_string(),
+        worker_threads: 1,
+        cache_entries: 0,
+        cache_ttl_secs: 300,
+        distributed_peers: 3,
+        distributed_quorum: 2,
+        distributed_timeout_ms: 60_000,
+        admission_bytes_per_token: 512 * 1024,
+        consensus_cpu_share: 0.25,
+        admission_max_waiting: 32,
+        startup_cache: true,
+        draft_model_path: None,
+        draft_tokenizer_path: None,
+        draft_tokens: 4,
+    }
+}
+
+#[tokio::test]
+async fn test_gguf_model_loads_and_generates() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    assert_eq!(detect_format(&model_path).unwrap(), ModelFormat::Gguf);
+
+    let model = LightLLM::from_config(&config(&model_path, &tokenizer_path)).unwrap();
+    let info = model.model_info();
+    assert_eq!(info.format, Some(ModelFormat::Gguf));
+    assert_eq!(info.quantization, "Q8_0");
+    let matrices = 2 * WORDS.len() * EMBEDDING + 4 * EMBEDDING * EMBEDDING + 3 * FEED_FORWARD * EMBEDDING;
+    assert_eq!(info.parameters, (matrices + 3 * EMBEDDING) as u64);
+    assert!(info.memory_bytes < info.parameters * 2, "{:?}", info);
+    assert_eq!((info.context_length, model.context_length()), (256, 256));
+
+    let params = GenerateParams::new(4).with_temperature(0.0);
+    let first = model.generate("a b c", params.clone()).await.unwrap();
+    assert!(first.finish.completion_tokens <= 4);
+    assert_eq!(first.finish.prompt_tokens, 3);
+    let again = model.generate("a b c", params.clone()).await.unwrap();
+    assert_eq!((again.text, again.finish), (first.text.clone(), first.finish));
+    assert_eq!((first.usage.prompt_tokens, first.usage.completion_tokens), (3, first.finish.completion_tokens));
+    assert!(first.usage.device_memory_bytes.unwrap() > 0);
+
+    // A session's cache is its own copy of the quantized model's.
+    let mut session = model.new_session();
+    assert_eq!(session.generate("a b c", params).await.unwrap().text, first.text);
+
+    assert_eq!(model.embedding_dim(), EMBEDDING);
+    let embeddings = model.embed(&["a b", "c d e f", "a b"]).await.unwrap();
+    assert_eq!(embeddings[0], embeddings[2]);
+    for embedding in &embeddings {
+        assert_eq!(embedding.len(), EMBEDDING);
+        assert!((cosine_similarity(embedding, embedding) - 1.0).abs() < 1e-3);
+    }
+    assert!(cosine_similarity(&embeddings[0], &embeddings[1]) < 0.999);
+}
+
+#[tokio::test]
+async fn test_startup_cache_serves_the_next_load_until_a_file_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = safetensors_fixture(dir.path());
+    let cache = StartupCache::open(dir.path().join("llm-cache")).unwrap();
+    let config = config(&model_path, &tokenizer_path);
+    let params = GenerateParams::new(3).with_temperature(0.0);
+
+    let first = LightLLM::from_config_with_cache(&config, Some(&cache)).unwrap();
+    assert!(!first.model_info().load_cached);
+    assert!(cache.misses() > 0);
+
+    // A restart with the same files reparses nothing.
+    let restarted = StartupCache::open(cache.dir()).unwrap();
+    let second = LightLLM::from_config_with_cache(&config, Some(&restarted)).unwrap();
+    assert!(second.model_info().load_cached);
+    assert_eq!(restarted.misses(), 0);
+    assert!(restarted.hits() > 0);
+    assert_eq!(second.model_info().parameters, first.model_info().parameters);
+    let expected = first.generate("a b c", params.clone()).await.unwrap().text;
+    assert_eq!(second.generate("a b c", params).await.unwrap().text, expected);
+
+    // The same tokenizer written out again is a different file.
+    Tokenizer::from_file(&tokenizer_path).unwrap().save(&tokenizer_path, true).unwrap();
+    let third = LightLLM::from_config_with_cache(&config, Some(&restarted)).unwrap();
+    assert!(!third.model_info().load_cached);
+    assert!(restarted.misses() > 0);
+}
+
+#[tokio::test]
+async fn test_safetensors_architecture_read_from_config_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = safetensors_fixture(dir.path());
+    assert_eq!(detect_format(&model_path).unwrap(), ModelFormat::Safetensors);
+    let model = LightLLM::from_config(&config(&model_path, &tokenizer_path)).unwrap();
+    assert_eq!(model.model_info().format, Some(ModelFormat::Safetensors));
+    assert_eq!(model.embedding_dim(), EMBEDDING);
+    let completion = model.generate("a b c", GenerateParams::new(2).with_temperature(0.0)).await.unwrap();
+    assert_eq!(completion.finish.prompt_tokens, 3);
+
+    // The real config, given as an override, wins over a doctored one
+    // beside the model; without the override the first tensor it
+    // mis-shapes is named.
+    let (beside, moved) = (dir.path().join("config.json"), dir.path().join("tiny-config.json"));
+    std::fs::rename(&beside, &moved).unwrap();
+    write_architecture(&beside, 2);
+    let overridden = LLMConfig { model_config_path: Some(moved.display().to_string()), ..config(&model_path, &tokenizer_path) };
+    assert!(LightLLM::from_config(&overridden).is_ok());
+    match LightLLM::from_config(&config(&model_path, &tokenizer_path)).err() {
+        Some(LlmError::ModelLoad { path, reason }) => {
+            assert_eq!(path, model_path);
+            assert!(reason.contains("model.layers.0.self_attn.k_proj.weight"), "{}", reason);
+        }
+        other => panic!("expected ModelLoad, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_chat_uses_the_configured_template() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    let template_path = dir.path().join("template.txt");
+    std::fs::write(&template_path, "{system}\n{messages}").unwrap();
+    let config = LLMConfig { prompt_template: template_path.display().to_string(), ..config(&model_path, &tokenizer_path) };
+    let model = LightLLM::from_config(&config).unwrap();
+
+    let messages = [ChatMessage::user("a b"), ChatMessage::assistant("c"), ChatMessage::user("d")];
+    let reply = model.chat(&messages, GenerateParams::new(2).with_temperature(0.0)).await.unwrap();
+    // BOS, then "user : a b assistant : c user : d" a word at a time.
+    assert_eq!(reply.finish.prompt_tokens, 11);
+
+    std::fs::write(&template_path, "{system} only").unwrap();
+    match LightLLM::from_config(&config) {
+        Err(LlmError::TemplateLoad { path, .. }) => assert_eq!(path, template_path),
+        other => panic!("expected TemplateLoad, got {:?}", other.map(|model| model.model_info())),
+    }
+}
+
+#[cfg(not(feature = "cuda"))]
+#[tokio::test]
+async fn test_speculative_decoding_matches_plain_decoding() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = layered_fixture(dir.path(), 2);
+    let draft_path = truncated(&model_path, 1);
+    let plain = LightLLM::from_config(&config(&model_path, &tokenizer_path)).unwrap();
+    let with_draft = |draft: &Path| {
+        let config = LLMConfig { draft_model_path: Some(draft.display().to_string()), ..config(&model_path, &tokenizer_path) };
+        LightLLM::from_config(&config)
+    };
+
+    let greedy = GenerateParams::new(24).with_temperature(0.0);
+    let expected = plain.generate("a b c", greedy.clone()).await.unwrap();
+    let completion = with_draft(&draft_path).unwrap().generate("a b c", greedy).await.unwrap();
+    assert_eq!(completion.text, expected.text);
+    assert!(completion.usage.draft_acceptance_rate.unwrap() > 0.0, "{:?}", completion.usage);
+
+    // Drafting with the model itself, even the random draws line up.
+    let seeded = GenerateParams::new(24).with_temperature(1.0).with_seed(5);
+    let expected = plain.generate("a b c", seeded.clone()).await.unwrap();
+    let completion = with_draft(&model_path).unwrap().generate("a b c", seeded).await.unwrap();
+    assert_eq!(completion.text, expected.text);
+    assert_eq!(completion.usage.draft_acceptance_rate, Some(1.0));
+
+    let other_tokenizer = dir.path().join("other-tokenizer.json");
+    let vocab: HashMap<String, u32> = WORDS.iter().rev().enumerate().map(|(id, word)| (word.to_string(), id as u32)).collect();
+    Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("<unk>".to_string()).build().unwrap())
+        .save(&other_tokenizer, false)
+        .unwrap();
+    let config = LLMConfig {
+        draft_model_path: Some(draft_path.display().to_string()),
+        draft_tokenizer_path: Some(other_tokenizer.display().to_string()),
+        ..config(&model_path, &tokenizer_path)
+    };
+    assert!(matches!(LightLLM::from_config(&config), Err(LlmError::DraftMismatch(_))));
+}
+
+#[test]
+fn test_gguf_on_gpu_needs_a_device() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    let config = LLMConfig { use_gpu: true, ..config(&model_path, &tokenizer_path) };
+    match LightLLM::from_config(&config) {
+        Err(LlmError::DeviceUnavailable(reason)) => assert!(reason.contains("gguf"), "{}", reason),
+        other => panic!("expected DeviceUnavailable, got {:?}", other.map(|model| model.model_info())),
+    }
+}
+
+#[test]
+fn test_configured_hashes_checked_before_loading() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    let model_sha256 = hex::encode(sha2::Sha256::digest(std::fs::read(&model_path).unwrap()));
+    let good = LLMConfig { model_sha256: Some(model_sha256.clone()), ..config(&model_path, &tokenizer_path) };
+    assert!(LightLLM::from_config(&good).is_ok());
+
+    let wrong = "0".repeat(64);
+    let bad = LLMConfig { model_sha256: Some(wrong.clone()), ..good };
+    match LightLLM::from_config(&bad) {
+        Err(LlmError::ChecksumMismatch { path, expected, actual }) => {
+            assert_eq!((path, expected, actual), (model_path, wrong, model_sha256));
+        }
+        other => panic!("expected ChecksumMismatch, got {:?}", other.map(|model| model.model_info())),
+    }
+}
+
+#[test]
+fn test_device_placement_on_cpu() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    let on = |use_gpu: bool, device: &str| {
+        LightLLM::from_config(&LLMConfig { use_gpu, device: device.to_string(), ..config(&model_path, &tokenizer_path) })
+    };
+    let cpu = DeviceInfo { device: "cpu".to_string(), requested: "cpu".to_string() };
+    // Without use_gpu the device setting is not used.
+    for device in ["auto", "cuda:3", "metal", "cuda:0,cuda:1"] {
+        assert_eq!(on(false, device).unwrap().device_info(), &cpu);
+    }
+    assert_eq!(on(true, "cpu").unwrap().device_info(), &cpu);
+    assert!(matches!(on(false, "gpu"), Err(LlmError::DeviceUnavailable(_))));
+
+    // Layers cannot be split yet, whatever devices there are.
+    assert!(matches!(on(true, "cuda:0,cuda:1"), Err(LlmError::DeviceUnavailable(_))));
+}
+
+/// An adapter on the feed-forward output and the vocabulary projection,
+/// large enough to move every logit.
+fn write_adapter(path: &Path, shapes: &[(&str, usize, usize)]) -> LoraConfig {
+    let config = LoraConfig {
+        rank: 4,
+        alpha: 4.0,
+        target_modules: vec!["ffn_down".to_string(), "output".to_string()],
+        dropout: 0.0,
+    };
+    let modules: BTreeMap<String, (Tensor, Tensor)> = shapes.iter()
+        .map(|(module, out_dim, in_dim)| {
+            let a = Tensor::randn(0f32, 1.0, (config.rank, *in_dim), &Device::Cpu).unwrap();
+            let b = Tensor::randn(0f32, 1.0, (*out_dim, config.rank), &Device::Cpu).unwrap();
+            (module.to_string(), (a, b))
+        })
+        .collect();
+    LoraAdapter::new(config.clone(), modules).unwrap().save(path).unwrap();
+    config
+}
+
+#[tokio::test]
+async fn test_adapter_load_and_unload_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    let mut model = LightLLM::from_config(&config(&model_path, &tokenizer_path)).unwrap();
+    let params = GenerateParams::new(8).with_temperature(1.0).with_seed(11);
+    let base = model.generate("a b c", params.clone()).await.unwrap().text;
+
+    let adapter_path = dir.path().join("adapter.safetensors");
+    let lora = write_adapter(&adapter_path, &[("blk.0.ffn_down", EMBEDDING, FEED_FORWARD), ("output", WORDS.len(), EMBEDDING)]);
+    model.load_adapter(&adapter_path).unwrap();
+    assert_eq!(model.adapter(), Some(&lora));
+    assert_ne!(model.generate("a b c", params.clone()).await.unwrap().text, base);
+
+    model.unload_adapter().unwrap();
+    assert_eq!(model.adapter(), None);
+    assert_eq!(model.generate("a b c", params.clone()).await.unwrap().text, base);
+
+    // The up projection is 64 by 32, not 32 by 64.
+    write_adapter(&adapter_path, &[("blk.0.ffn_down", EMBEDDING, FEED_FORWARD), ("blk.0.ffn_up", EMBEDDING, FEED_FORWARD)]);
+    match model.load_adapter(&adapter_path) {
+        Err(LlmError::AdapterMismatch { module, .. }) => assert_eq!(module, "blk.0.ffn_up"),
+        other => panic!("expected AdapterMismatch, got {:?}", other),
+    }
+    write_adapter(&adapter_path, &[("blk.3.ffn_down", EMBEDDING, FEED_FORWARD)]);
+    assert!(matches!(model.load_adapter(&adapter_path), Err(LlmError::AdapterMismatch { module, .. }) if module == "blk.3.ffn_down"));
+    assert_eq!(model.adapter(), None);
+    assert_eq!(model.generate("a b c", params).await.unwrap().text, base);
+}
+
+#[tokio::test]
+async fn test_model_swap_keeps_serving() {
+    let dir = tempfile::tempdir().unwrap();
+    let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+    std::fs::create_dir_all(&first).unwrap();
+    std::fs::create_dir_all(&second).unwrap();
+    let (model_v1, tokenizer_v1) = fixture(&first);
+    let (model_v2, tokenizer_v2) = fixture(&second);
+    let storage = dir.path().join("data");
+    let manager = ModelManager::open(&storage, config(&model_v1, &tokenizer_v1)).unwrap().with_drain_timeout(Duration::from_secs(5));
+    let manager = Arc::new(manager);
+    manager.install("v1", &model_v1, &tokenizer_v1).unwrap();
+    manager.install("v2", &model_v2, &tokenizer_v2).unwrap();
+    let versions = |manager: &ModelManager| manager.list_models().unwrap().into_iter().map(|model| model.version).collect::<Vec<_>>();
+    assert_eq!(versions(&manager), ["v1", "v2"]);
+    manager.activate("v1").await.unwrap();
+
+    // Clients keep asking while the model is swapped under them.
+    let stop = Arc::new(AtomicBool::new(false));
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            let (manager, stop) = (Arc::clone(&manager), Arc::clone(&stop));
+            tokio::spawn(async move {
+                let mut served = Vec::new();
+                while !stop.load(Ordering::SeqCst) {
+                    let serving = manager.serving().unwrap();
+                    serving.queue().generate("a b c", GenerateParams::new(4).with_temperature(0.0)).await.unwrap();
+                    served.push(serving.version().unwrap().to_string());
+                }
+                served
+            })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let activation = manager.activate("v2").await.unwrap();
+    assert_eq!(activation, Activation { version: "v2".to_string(), previous: Some("v1".to_string()), drained: true });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stop.store(true, Ordering::SeqCst);
+    for client in clients {
+        let served = client.await.unwrap();
+        assert_eq!((served.first().unwrap().as_str(), served.last().unwrap().as_str()), ("v1", "v2"));
+    }
+
+    // A version that fails to load leaves the current one serving.
+    std::fs::write(storage.join("models/v1/tiny.gguf"), b"corrupt").unwrap();
+    assert!(matches!(manager.activate("v1").await, Err(LlmError::ChecksumMismatch { .. })));
+    assert!(matches!(manager.activate("v3").await, Err(LlmError::UnknownModel(version)) if version == "v3"));
+    assert_eq!(manager.serving_version().as_deref(), Some("v2"));
+    manager.serving().unwrap().queue().generate("a b", GenerateParams::new(2)).await.unwrap();
+
+    assert!(matches!(manager.remove("v2").await, Err(LlmError::ModelInUse(_))));
+    manager.remove("v1").await.unwrap();
+    assert_eq!(versions(&manager), ["v2"]);
+
+    // A restarted node serves the version last activated.
+    let restarted = ModelManager::open(&storage, config(&model_v1, &tokenizer_v1)).unwrap();
+    restarted.start().await.unwrap();
+    assert_eq!(restarted.serving_version().as_deref(), Some("v2"));
+}
+
+/// Whether the serving model answered a greedy request from the cache.
+async fn served_from_cache(manager: &ModelManager) -> bool {
+    let params = GenerateParams::new(4).with_temperature(0.0);
+    manager.serving().unwrap().queue().generate("a b c", params).await.unwrap().usage.cached
+}
+
+#[tokio::test]
+async fn test_cache_flushed_on_model_swap() {
+    let dir = tempfile::tempdir().unwrap();
+    let (model_path, tokenizer_path) = fixture(dir.path());
+    let config = LLMConfig { cache_entries: 8, ..config(&model_path, &tokenizer_path) };
+    let manager = ModelManager::open(dir.path().join("data"), config).unwrap();
+    manager.install("v1", &model_path, &tokenizer_path).unwrap();
+    manager.install("v2", &model_path, &tokenizer_path).unwrap();
+    manager.activate("v1").await.unwrap();
+    let cache = manager.cache().unwrap();
+
+    assert!(!served_from_cache(&manager).await);
+    assert!(served_from_cache(&manager).await);
+    assert_eq!((cache.len(), manager.metrics().cache_totals()), (1, (1, 1)));
+
+    manager.activate("v2").await.unwrap();
+    assert!(cache.is_empty());
+    assert!(!served_from_cache(&manager).await);
+    assert!(served_from_cache(&manager).await);
+    assert_eq!(manager.metrics().cache_totals(), (2, 2));
+}
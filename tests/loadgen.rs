@@ -0,0 +1,125 @@
+#![cfg(feature = "loadgen")]
+
+use dadbs_node::client::loadgen::{self, FeeDistribution, LoadConfig, LoadDuration};
+use dadbs_node::node::{
+    Block, ChainEvents, CommitCertificate, ConsensusManager, Mempool, RpcConfig, RpcContext, RpcServer, State,
+    Storage, ThresholdPolicy, TxTracer, ValidatorInfo, ValidatorSet, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+const CHAIN_ID: &str = "loadgen-test";
+
+/// A node whose blocks are finalized by a background task every
+/// `interval`, each holding up to `per_block` of its mempool.
+struct TestNode {
+    server: Arc<RpcServer>,
+    _producer: tokio::task::JoinHandle<()>,
+    _dir: tempfile::TempDir,
+}
+
+impl TestNode {
+    async fn start(faucet: &Keypair, capacity: usize, interval: Duration, per_block: usize) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let validator = Keypair::new();
+        let events = ChainEvents::default();
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let genesis = vec![(DADBSAddress::from_pubkey(&faucet.pubkey()), 1_000_000)];
+        let state = Arc::new(RwLock::new(State::in_memory(genesis)));
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+            .with_chain_id(CHAIN_ID)
+            .with_state(Arc::clone(&state))
+            .with_events(events.clone());
+        consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let mempool = Arc::new(Mutex::new(Mempool::new(1, capacity).with_chain_id(CHAIN_ID)));
+        let context = RpcContext {
+            node_id: "loadgen-test".to_string(),
+            chain_id: CHAIN_ID.to_string(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::clone(&consensus),
+            state: Arc::clone(&state),
+            mempool: Arc::clone(&mempool),
+            network: None,
+            events,
+            tracer: TxTracer::default(),
+        };
+        let config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+        let server = RpcServer::bind(config, context).await.unwrap();
+        let producer = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let mut consensus = consensus.lock().await;
+                let transactions = mempool.lock().take_batch(per_block);
+                let parent = consensus.block_tree().finalized().clone();
+                let mut block =
+                    Block::with_transactions(parent.height() + 1, parent.hash(), 0, validator.pubkey(), transactions);
+                block.header.state_root = state.read().root();
+                consensus.apply_block(block.clone(), 10).unwrap();
+                consensus.finalize_block(&block.hash()).unwrap();
+                let vote = Vote::new(&validator, block.height(), 0, block.hash());
+                storage.put_finalized_block(&block, &CommitCertificate::new(block.height(), block.hash(), vec![vote])).unwrap();
+            }
+        });
+        TestNode { server, _producer: producer, _dir: dir }
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}/", self.server.local_addr())
+    }
+}
+
+fn assert_invariants(report: &loadgen::LoadReport) {
+    assert_eq!(report.attempted, report.submitted + report.failed, "{:?}", report);
+    assert_eq!(report.submitted, report.finalized + report.timed_out, "{:?}", report);
+    assert_eq!(report.errors.values().sum::<u64>(), report.failed + report.timed_out, "{:?}", report);
+    let latency = report.latency;
+    assert!(latency.p50_ms <= latency.p95_ms && latency.p95_ms <= latency.p99_ms && latency.p99_ms <= latency.max_ms);
+    assert!(report.achieved_tps > 0.0 && report.elapsed_ms > 0);
+}
+
+#[tokio::test]
+async fn test_low_rate_run_reports_every_transaction() {
+    let faucet = Keypair::new();
+    let node = TestNode::start(&faucet, 100, Duration::from_millis(100), 100).await;
+    let config = LoadConfig::new(vec![node.url()], CHAIN_ID)
+        .with_accounts(3)
+        .with_funding(1_000)
+        .with_tps(20.0)
+        .with_duration(LoadDuration::Transactions(30))
+        .with_payload_bytes(0, 64)
+        .with_fees(FeeDistribution::Uniform { min: 1, max: 3 })
+        .with_finality_timeout(Duration::from_secs(10));
+    let report = loadgen::run(&config, &faucet).await.unwrap();
+    assert_invariants(&report);
+    assert_eq!((report.attempted, report.finalized), (30, 30), "{:?}", report);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert!(report.latency.max_ms <= 10_000);
+    assert!(report.achieved_tps <= 25.0, "{}", report.achieved_tps);
+
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["latency"]["p99_ms"], report.latency.p99_ms);
+}
+
+#[tokio::test]
+async fn test_full_mempool_backs_off_and_soak_stops_on_time() {
+    let faucet = Keypair::new();
+    // Room for four, and two taken every quarter second, against twenty a second.
+    let node = TestNode::start(&faucet, 4, Duration::from_millis(250), 2).await;
+    let config = LoadConfig::new(vec![node.url()], CHAIN_ID)
+        .with_accounts(2)
+        .with_funding(1_000)
+        .with_tps(20.0)
+        .with_duration(LoadDuration::Soak(Duration::from_secs(2)))
+        .with_finality_timeout(Duration::from_secs(15));
+    let report = loadgen::run(&config, &faucet).await.unwrap();
+    assert_invariants(&report);
+    assert!(report.backoffs > 0, "{:?}", report);
+    // Pauses hold the rate under the target.
+    assert!(report.attempted < 40, "{:?}", report);
+    assert!(report.elapsed_ms >= 2_000, "{:?}", report);
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use borsh::BorshSerialize;
+use dadbs_node::node::runtime::STATE_FILE;
+use dadbs_node::node::{Genesis, GenesisAccount, Node, NodeConfig, State, Transaction, ValidatorInfo};
+use dadbs_node::utils::DADBSAddress;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A node following `genesis`, with `validator_key` if given. Transactions
+/// stay valid to include for the length of the test.
+fn config(dir: &Path, genesis: &Genesis, validator_key: Option<String>) -> NodeConfig {
+    let mut config = NodeConfig {
+        validator_key,
+        consensus_timeout: 60_000,
+        ..common::node_config("mempool-test", dir, genesis)
+    };
+    config.mempool.max_reload_age_ms = 60_000;
+    config
+}
+
+async fn send(rpc: SocketAddr, transaction: &Transaction) -> Value {
+    let request = json!({
+        "jsonrpc": "2.0", "id": 1, "method": "send_transaction",
+        "params": { "raw": hex::encode(transaction.try_to_vec().unwrap()) },
+    });
+    reqwest::Client::new().post(format!("http://{}/", rpc)).json(&request)
+        .send().await.unwrap()
+        .json().await.unwrap()
+}
+
+async fn pending(node: &Node) -> usize {
+    let scrape = reqwest::get(format!("http://{}/metrics", node.metrics_addr())).await.unwrap().text().await.unwrap();
+    scrape.lines()
+        .find_map(|line| line.strip_prefix("dadbs_mempool_transactions ")?.parse::<f64>().ok())
+        .unwrap() as usize
+}
+
+#[tokio::test]
+async fn test_pending_transactions_survive_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let (validator, alice, bob, carol) = (Keypair::new(), Keypair::new(), Keypair::new(), Keypair::new());
+    let address = |keypair: &Keypair| DADBSAddress::from_pubkey(&keypair.pubkey());
+    let mut genesis = Genesis::template("dadbs-testnet", vec![ValidatorInfo::new(validator.pubkey(), 1)]);
+    genesis.accounts = [&alice, &bob, &carol].into_iter()
+        .map(|keypair| GenesisAccount { address: address(keypair), balance: 1_000_000 })
+        .collect();
+    genesis.params.block_interval_ms = 50;
+    let config = config(dir.path(), &genesis, None);
+
+    let node = Node::start(config.clone()).await.unwrap();
+    let (fee, now) = (genesis.params.min_fee, chrono::Utc::now().timestamp_millis());
+    let kept: Vec<Transaction> = (0..3)
+        .map(|nonce| Transaction::new_signed(&alice, Pubkey::new_unique(), 10, fee, nonce, now))
+        .collect();
+    let unfunded = Transaction::new_signed(&bob, Pubkey::new_unique(), 10, fee, 0, now);
+    let expired = Transaction::new_signed(&carol, Pubkey::new_unique(), 10, fee, 0, now - 120_000);
+    for transaction in kept.iter().chain([&unfunded, &expired]) {
+        let response = send(node.rpc_addr(), transaction).await;
+        assert!(response.get("error").is_none(), "{}", response);
+    }
+    assert_eq!(pending(&node).await, 5);
+    node.stop().await.unwrap();
+
+    // Bob's funds left while the node was down.
+    let data = Path::new(&config.storage_path);
+    let mut state = State::open(&data.join(STATE_FILE), Vec::new()).unwrap();
+    state.replace_with(State::in_memory(vec![(address(&alice), 1_000_000), (address(&carol), 1_000_000)])).unwrap();
+    drop(state);
+
+    let node = Node::start(config.clone()).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while pending(&node).await != kept.len() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("pending transactions were not reloaded");
+    for transaction in &kept {
+        let response = send(node.rpc_addr(), transaction).await;
+        assert!(response["error"]["message"].as_str().unwrap().contains("already pending"), "{}", response);
+    }
+    node.stop().await.unwrap();
+
+    // What was saved again goes into the blocks the node builds once it
+    // validates.
+    let validator_key = common::validator_key(dir.path(), &validator);
+    let node = Node::start(NodeConfig { validator_key, ..config }).await.unwrap();
+    let rpc = node.rpc_addr();
+    let included = |transaction: &Transaction| {
+        let params = json!({ "hash": transaction.hash().to_string() });
+        async move { !common::call::<Value>(rpc, "get_transaction", params).await.is_null() }
+    };
+    timeout(Duration::from_secs(10), async {
+        for transaction in &kept {
+            while !included(transaction).await {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    })
+    .await
+    .expect("reloaded transactions were not included");
+    assert!(!included(&unfunded).await);
+    assert!(!included(&expired).await);
+    node.stop().await.unwrap();
+}
@@ -0,0 +1,126 @@
+use dadbs_node::node::{
+    Block, ChainEvents, CommitCertificate, ConsensusManager, HealthRegistry, Mempool, MetricsConfig, MetricsRegistry,
+    MetricsServer, Network, NetworkConfig, ProcessMetrics, RpcConfig, RpcContext, RpcServer, State, Storage,
+    ThresholdPolicy, Transaction, TxTracer, ValidatorInfo, ValidatorSet, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::json;
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+/// The value of the unique sample line for `series`, labels included.
+fn sample(scrape: &str, series: &str) -> f64 {
+    let values: Vec<f64> = scrape.lines()
+        .filter_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse().ok())
+        .collect();
+    assert_eq!(values.len(), 1, "{} in\n{}", series, scrape);
+    values[0]
+}
+
+fn assert_type(scrape: &str, name: &str, kind: &str) {
+    assert!(scrape.contains(&format!("# TYPE {} {}\n", name, kind)), "no {} {} in\n{}", kind, name, scrape);
+}
+
+#[tokio::test]
+async fn test_scrape_running_node() {
+    let dir = tempfile::tempdir().unwrap();
+    let (validator, alice, bob) = (Keypair::new(), Keypair::new(), Keypair::new());
+    let storage = Arc::new(Storage::open(dir.path()).unwrap());
+    let state = Arc::new(RwLock::new(State::in_memory(vec![(DADBSAddress::from_pubkey(&alice.pubkey()), 1_000)])));
+    let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_state(Arc::clone(&state));
+    consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+    for _ in 0..2 {
+        let parent = consensus.block_tree().finalized().clone();
+        let mut block = Block::new(parent.height() + 1, parent.hash(), 0, validator.pubkey());
+        block.header.state_root = state.read().root();
+        consensus.apply_block(block.clone(), 10).unwrap();
+        consensus.finalize_block(&block.hash()).unwrap();
+        let vote = Vote::new(&validator, block.height(), 0, block.hash());
+        storage.put_finalized_block(&block, &CommitCertificate::new(block.height(), block.hash(), vec![vote])).unwrap();
+    }
+
+    let mempool = Arc::new(Mutex::new(Mempool::new(1, 100)));
+    let pending = Transaction::new_signed(&alice, bob.pubkey(), 10, 1, 0, 0);
+    mempool.lock().admit(pending.clone(), &state.read()).unwrap();
+    let (network, _inbound) = Network::bind(NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "metrics-test")).await.unwrap();
+
+    let registry = Arc::new(MetricsRegistry::new());
+    registry.register(Arc::new(ProcessMetrics::new()));
+    registry.register(consensus.metrics());
+    let consensus = Arc::new(AsyncMutex::new(consensus));
+    let context = RpcContext {
+        node_id: "metrics-test".to_string(),
+        chain_id: "dadbs-testnet".to_string(),
+        storage: Arc::clone(&storage),
+        consensus,
+        state,
+        mempool: Arc::clone(&mempool),
+        network: Some(Arc::clone(&network)),
+        events: ChainEvents::default(),
+        tracer: TxTracer::default(),
+    };
+    let rpc_config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+    let rpc = RpcServer::bind(rpc_config, context).await.unwrap();
+    registry.register(network);
+    registry.register(mempool);
+    registry.register(storage);
+    registry.register(rpc.metrics());
+
+    let client = reqwest::Client::new();
+    let rpc_url = format!("http://{}/", rpc.local_addr());
+    for method in ["get_node_info", "get_node_info", "no_such_method"] {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method });
+        client.post(&rpc_url).json(&request).send().await.unwrap();
+    }
+
+    let config = MetricsConfig { listen: "127.0.0.1:0".to_string() };
+    let metrics = MetricsServer::bind(&config, registry, Arc::new(HealthRegistry::new(Duration::from_secs(1)))).await.unwrap();
+    let response = client.get(format!("http://{}/metrics", metrics.local_addr())).send().await.unwrap();
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+    let scrape = response.text().await.unwrap();
+
+    assert_type(&scrape, "process_start_time_seconds", "gauge");
+    #[cfg(target_os = "linux")]
+    {
+        assert_type(&scrape, "process_resident_memory_bytes", "gauge");
+        assert_type(&scrape, "process_cpu_seconds_total", "counter");
+    }
+
+    assert_type(&scrape, "dadbs_block_height", "gauge");
+    assert_eq!(sample(&scrape, "dadbs_block_height"), 2.0);
+    assert_eq!(sample(&scrape, "dadbs_finalized_height"), 2.0);
+    assert_type(&scrape, "dadbs_consensus_round_latency_seconds", "histogram");
+    assert_eq!(sample(&scrape, "dadbs_consensus_round_latency_seconds_count"), 2.0);
+
+    assert_type(&scrape, "dadbs_peers", "gauge");
+    assert_eq!(sample(&scrape, "dadbs_peers{state=\"inbound\"}"), 0.0);
+    assert_eq!(sample(&scrape, "dadbs_peers{state=\"banned\"}"), 0.0);
+
+    assert_type(&scrape, "dadbs_mempool_transactions", "gauge");
+    assert_eq!(sample(&scrape, "dadbs_mempool_transactions"), 1.0);
+    assert_eq!(sample(&scrape, "dadbs_mempool_bytes"), pending.size() as f64);
+
+    assert_type(&scrape, "dadbs_storage_disk_bytes", "gauge");
+    assert!(sample(&scrape, "dadbs_storage_disk_bytes") > 0.0);
+
+    assert_type(&scrape, "dadbs_rpc_requests_total", "counter");
+    assert_eq!(sample(&scrape, "dadbs_rpc_requests_total{method=\"get_node_info\"}"), 2.0);
+    assert_eq!(sample(&scrape, "dadbs_rpc_errors_total{method=\"unknown\"}"), 1.0);
+    assert_type(&scrape, "dadbs_rpc_request_duration_seconds", "histogram");
+    assert_eq!(sample(&scrape, "dadbs_rpc_request_duration_seconds_count{method=\"get_node_info\"}"), 2.0);
+
+    metrics.shutdown();
+    rpc.shutdown();
+}
+
+#[test]
+fn test_registries_are_isolated() {
+    let (first, second) = (MetricsRegistry::new(), MetricsRegistry::new());
+    first.register(Arc::new(Mutex::new(Mempool::new(0, 10))));
+    assert!(first.render().contains("dadbs_mempool_transactions 0"));
+    assert_eq!(second.render(), "");
+}
@@ -0,0 +1,164 @@
+use async_trait::async_trait;
+use dadbs_node::node::network::{read_frame, write_frame, PROTOCOL_VERSION};
+use dadbs_node::node::{NatError, NetMessage, Network, NetworkConfig, PortMapper, PortMapping};
+use parking_lot::Mutex;
+use solana_sdk::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout, Instant};
+use tokio_util::sync::CancellationToken;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Call {
+    Add(u16, SocketAddr),
+    Remove(u16),
+}
+
+/// Records every request and forwards nothing.
+#[derive(Default)]
+struct MockGateway {
+    calls: Mutex<Vec<Call>>,
+    fail_adds: AtomicBool,
+    no_external_ip: AtomicBool,
+}
+
+impl MockGateway {
+    fn calls(&self) -> Vec<Call> {
+        self.calls.lock().clone()
+    }
+
+    fn adds(&self) -> usize {
+        self.calls().iter().filter(|call| matches!(call, Call::Add(..))).count()
+    }
+}
+
+#[async_trait]
+impl PortMapper for MockGateway {
+    async fn external_ip(&self) -> Result<IpAddr, NatError> {
+        if self.no_external_ip.load(Ordering::SeqCst) {
+            return Err(NatError::Gateway("no external address".to_string()));
+        }
+        Ok(IpAddr::from([198, 51, 100, 7]))
+    }
+
+    async fn add_mapping(&self, external_port: u16, local: SocketAddr, _lease: Duration) -> Result<(), NatError> {
+        self.calls.lock().push(Call::Add(external_port, local));
+        if self.fail_adds.load(Ordering::SeqCst) {
+            return Err(NatError::Gateway("conflict".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn remove_mapping(&self, external_port: u16) -> Result<(), NatError> {
+        self.calls.lock().push(Call::Remove(external_port));
+        Ok(())
+    }
+}
+
+async fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + WAIT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_mapping_renewed_and_removed_on_shutdown() {
+    let gateway = Arc::new(MockGateway::default());
+    let local: SocketAddr = "192.168.1.20:7000".parse().unwrap();
+    let mapping = PortMapping::start(gateway.clone(), local, 17000, Duration::from_millis(100)).await.unwrap();
+    assert_eq!(mapping.external(), "198.51.100.7:17000".parse().unwrap());
+    assert_eq!(gateway.calls(), [Call::Add(17000, local)]);
+
+    let cancel = CancellationToken::new();
+    let running = tokio::spawn(mapping.run(cancel.clone()));
+    wait_until("two renewals", || gateway.adds() >= 3).await;
+
+    // A failed renewal is retried rather than giving up on the mapping.
+    gateway.fail_adds.store(true, Ordering::SeqCst);
+    let failed_at = gateway.adds();
+    wait_until("a retry", || gateway.adds() >= failed_at + 2).await;
+    gateway.fail_adds.store(false, Ordering::SeqCst);
+
+    cancel.cancel();
+    timeout(WAIT, running).await.unwrap().unwrap();
+    let calls = gateway.calls();
+    assert_eq!(calls.last(), Some(&Call::Remove(17000)));
+    assert_eq!(calls.iter().filter(|call| matches!(call, Call::Remove(_))).count(), 1);
+}
+
+#[tokio::test]
+async fn test_mapping_undone_without_external_address() {
+    let gateway = Arc::new(MockGateway::default());
+    gateway.no_external_ip.store(true, Ordering::SeqCst);
+    let local: SocketAddr = "192.168.1.20:7000".parse().unwrap();
+    let result = PortMapping::start(gateway.clone(), local, 7000, Duration::from_secs(60)).await;
+    assert!(matches!(result, Err(NatError::Gateway(_))));
+    assert_eq!(gateway.calls(), [Call::Add(7000, local), Call::Remove(7000)]);
+}
+
+/// Connects to `network` from `ip`, reporting `observed` as its address.
+async fn report(network: &Network, ip: [u8; 4], listen_port: u16, observed: &str) -> TcpStream {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from((ip, 0))).unwrap();
+    let mut stream = socket.connect(network.local_addr()).await.unwrap();
+    let hello = NetMessage::Handshake {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        node_id: format!("reporter-{}", ip[3]),
+        listen_port,
+        codecs: vec![],
+        chain_id: "dadbs-testnet".to_string(),
+        genesis_hash: Hash::default(),
+        observed_addr: Some(observed.to_string()),
+        capabilities: 0,
+        llm: None,
+        identity: None,
+        nonce: [0; 32],
+    };
+    write_frame(&mut stream, &hello, 1024).await.unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn test_majority_observed_address_advertised() {
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "nat-test").with_min_observations(2);
+    let (network, _inbound) = Network::bind(config).await.unwrap();
+    let port = network.local_addr().port();
+
+    // Banned peers are refused before their report counts.
+    network.ban_peer("127.0.0.4:9000".parse().unwrap(), Duration::from_secs(60), "test").unwrap();
+    let _banned = report(&network, [127, 0, 0, 4], 9000, "192.0.2.1:1").await;
+    let _liar = report(&network, [127, 0, 0, 5], 9000, "192.0.2.1:1").await;
+    let mut first = report(&network, [127, 0, 0, 2], 9000, "198.51.100.7:4000").await;
+    wait_until("two peers", || network.peer_count() == 2).await;
+    assert_eq!(network.external_addr(), None);
+
+    let _second = report(&network, [127, 0, 0, 3], 9000, "198.51.100.7:4001").await;
+    wait_until("the majority address", || network.external_addr().is_some()).await;
+    let external: SocketAddr = SocketAddr::new(IpAddr::from([198, 51, 100, 7]), port);
+    assert_eq!(network.external_addr(), Some(external));
+
+    // Included first in the peer list we hand out.
+    write_frame(&mut first, &NetMessage::GetPeers, 1024).await.unwrap();
+    let peers = timeout(WAIT, async {
+        loop {
+            if let NetMessage::Peers(peers) = read_frame(&mut first, 1 << 20).await.unwrap() {
+                break peers;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(peers[0].addr, external.to_string());
+
+    // Once a reporter is banned its vote is dropped and the tie is unresolved.
+    network.ban_peer("127.0.0.3:9000".parse().unwrap(), Duration::from_secs(60), "test").unwrap();
+    assert_eq!(network.external_addr(), None);
+}
@@ -0,0 +1,774 @@
+use async_trait::async_trait;
+use dadbs_node::node::network::{read_frame, write_frame, CAPABILITY_LLM, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use dadbs_node::node::{
+    BackoffConfig, BandwidthConfig, Block, CertificateError, Codec, CommitCertificate, ConsensusManager, DnsSeedError,
+    GossipConfig, HandoverError, HandoverRegistry, Heartbeat, IdentityProof, IngestPipeline, KeyHandover, LlmCapability,
+    LlmPeerFilter, MessageCategory, NetMessage, Network, NetworkConfig, NetworkError, Offense, RetryState, ScoreConfig,
+    SeedResolver, ThresholdPolicy, Transaction, ValidatorInfo, ValidatorSet, Vote, VoteOutcome,
+};
+use dadbs_node::utils::DADBSAddress;
+use solana_sdk::{hash::Hash, pubkey::Pubkey, signature::{Keypair, Signer}};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Instant};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn loopback() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+async fn start(node_id: &str, config: impl FnOnce(NetworkConfig) -> NetworkConfig) -> (Arc<Network>, Arc<IngestPipeline>) {
+    Network::bind(config(NetworkConfig::new(loopback(), node_id))).await.unwrap()
+}
+
+async fn wait_for_peers(network: &Network, count: usize) {
+    timeout(WAIT, async {
+        while network.peer_count() != count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} never reached {} peers", network.node_id(), count));
+}
+
+async fn recv(inbound: &IngestPipeline) -> NetMessage {
+    timeout(WAIT, inbound.recv()).await.expect("timed out waiting for message").unwrap().1
+}
+
+fn hello(node_id: &str, min_version: u32, version: u32) -> NetMessage {
+    NetMessage::Handshake {
+        version,
+        min_version,
+        node_id: node_id.to_string(),
+        listen_port: 0,
+        codecs: vec![],
+        chain_id: "dadbs-testnet".to_string(),
+        genesis_hash: Hash::default(),
+        observed_addr: None,
+        capabilities: 0,
+        llm: None,
+        identity: None,
+        nonce: [0; 32],
+    }
+}
+
+/// Handshakes with `network` as `node_id` claiming `identity`, signs its
+/// nonce with `signer` and returns what it answers.
+async fn claim_identity(network: &Network, node_id: &str, identity: Pubkey, signer: &Keypair) -> NetMessage {
+    let mut claim = hello(node_id, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+    if let NetMessage::Handshake { identity: claimed, .. } = &mut claim {
+        *claimed = Some(identity);
+    }
+    let mut raw = TcpStream::connect(network.local_addr()).await.unwrap();
+    write_frame(&mut raw, &claim, 1024).await.unwrap();
+    let nonce = match read_frame(&mut raw, 1024).await.unwrap() {
+        NetMessage::Handshake { nonce, .. } => nonce,
+        other => panic!("expected handshake, got {:?}", other),
+    };
+    let mut proof = IdentityProof::new(signer, node_id, &Hash::default(), &nonce);
+    proof.pubkey = identity;
+    write_frame(&mut raw, &NetMessage::HandshakeProof(proof), 1024).await.unwrap();
+    read_frame(&mut raw, 1024).await.unwrap()
+}
+
+async fn connected_pair() -> ((Arc<Network>, Arc<IngestPipeline>), (Arc<Network>, Arc<IngestPipeline>)) {
+    let a = start("node-a", |c| c).await;
+    let b = start("node-b", |c| c).await;
+    a.0.connect(b.0.local_addr()).await.unwrap();
+    wait_for_peers(&b.0, 1).await;
+    (a, b)
+}
+
+#[tokio::test]
+async fn test_handshake_and_ping() {
+    let ((a, a_inbound), (b, b_inbound)) = connected_pair().await;
+    assert_eq!(a.peers()[0].node_id, "node-b");
+    assert!(a.peers()[0].outbound);
+    assert_eq!(b.peers()[0].node_id, "node-a");
+    assert!(!b.peers()[0].outbound);
+
+    a.send(&b.local_addr(), NetMessage::Ping(7)).await.unwrap();
+    assert_eq!(recv(&b_inbound).await, NetMessage::Ping(7));
+    assert_eq!(recv(&a_inbound).await, NetMessage::Pong(7));
+}
+
+#[tokio::test]
+async fn test_capabilities_exchanged_in_handshake() {
+    let (a, _a_inbound) = start("node-a", |c| c.with_capabilities(CAPABILITY_LLM)).await;
+    let (b, _b_inbound) = start("node-b", |c| c).await;
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for_peers(&b, 1).await;
+    assert!(b.peers()[0].supports(CAPABILITY_LLM));
+    assert!(!a.peers()[0].supports(CAPABILITY_LLM));
+    assert_eq!(a.capabilities(), CAPABILITY_LLM);
+}
+
+fn capability(model_version: &str, context_length: u32, quant: &str, accepts_remote: bool) -> LlmCapability {
+    LlmCapability { model_version: model_version.to_string(), context_length, quant: quant.to_string(), max_batch: 8, accepts_remote }
+}
+
+fn llm_peer_ids(network: &Network, filter: LlmPeerFilter) -> Vec<String> {
+    let mut ids: Vec<String> = network.find_llm_peers(&filter).into_iter().map(|peer| peer.node_id).collect();
+    ids.sort();
+    ids
+}
+
+async fn wait_for_llm_peers(network: &Network, filter: LlmPeerFilter, expected: &[&str]) {
+    timeout(WAIT, async {
+        while llm_peer_ids(network, filter.clone()) != expected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("expected {:?} for {:?}, found {:?}", expected, filter, llm_peer_ids(network, filter.clone())));
+}
+
+#[tokio::test]
+async fn test_llm_peers_found_by_advertised_capability() {
+    let (client, _client_inbound) = start("client", |c| c).await;
+    let mut servers = Vec::new();
+    for (node_id, advertised) in [
+        ("small", capability("tiny-1", 2048, "Q4_0", true)),
+        ("large", capability("base-2", 8192, "f16", true)),
+        ("private", capability("base-2", 8192, "Q8_0", false)),
+    ] {
+        let (server, inbound) = start(node_id, |c| c.with_capabilities(CAPABILITY_LLM)).await;
+        server.advertise_llm(Some(advertised.clone()));
+        assert_eq!(server.llm_capability(), Some(advertised));
+        client.connect(server.local_addr()).await.unwrap();
+        servers.push((server, inbound));
+    }
+    wait_for_peers(&client, 3).await;
+
+    assert_eq!(llm_peer_ids(&client, LlmPeerFilter::default()), ["large", "private", "small"]);
+    assert_eq!(llm_peer_ids(&client, LlmPeerFilter::default().with_model_version("base-2")), ["large", "private"]);
+    assert_eq!(llm_peer_ids(&client, LlmPeerFilter::default().with_min_context_length(4096).with_accepts_remote(true)), ["large"]);
+    assert_eq!(llm_peer_ids(&client, LlmPeerFilter::default().with_quant("q4_0")), ["small"]);
+    let found = client.find_llm_peers(&LlmPeerFilter::default());
+    assert!(found.iter().all(|peer| peer.last_seen > 0), "{:?}", found);
+
+    // A hot-swapped model is advertised to peers already connected.
+    servers[0].0.advertise_llm(Some(capability("base-2", 4096, "Q4_0", true)));
+    wait_for_llm_peers(&client, LlmPeerFilter::default().with_model_version("base-2"), &["large", "private", "small"]).await;
+    servers[2].0.advertise_llm(None);
+    wait_for_llm_peers(&client, LlmPeerFilter::default(), &["large", "small"]).await;
+
+    // A revoked peer stays out, whatever it advertises next.
+    let large = servers[1].0.local_addr();
+    assert!(client.revoke_llm(&large));
+    assert!(!client.peer_info(&large).unwrap().supports(CAPABILITY_LLM));
+    servers[1].0.advertise_llm(Some(capability("base-3", 8192, "f16", true)));
+    servers[0].0.advertise_llm(Some(capability("base-3", 4096, "Q4_0", true)));
+    wait_for_llm_peers(&client, LlmPeerFilter::default().with_model_version("base-3"), &["small"]).await;
+    assert_eq!(llm_peer_ids(&client, LlmPeerFilter::default()), ["small"]);
+}
+
+#[tokio::test]
+async fn test_each_message_type_is_delivered() {
+    let ((a, a_inbound), (b, b_inbound)) = connected_pair().await;
+    let keypair = Keypair::new();
+    let block = Block::genesis();
+    let vote = Vote::new(&keypair, 1, 0, block.hash());
+    let messages = vec![
+        NetMessage::Pong(3),
+        NetMessage::Tx(Transaction::new_signed(&keypair, Pubkey::new_unique(), 5, 1, 0, 0)),
+        NetMessage::Block(block.clone()),
+        NetMessage::Vote(vote.clone()),
+        NetMessage::Heartbeat(Heartbeat::new_signed(&keypair, 1, 0)),
+        NetMessage::GetBlocks { from: 1, to: 10 },
+        NetMessage::Blocks {
+            blocks: vec![block.clone()],
+            certificates: vec![CommitCertificate::new(1, block.hash(), vec![vote])],
+        },
+    ];
+
+    for message in &messages {
+        assert_eq!(a.broadcast(message.clone()), 1);
+    }
+    for message in messages {
+        assert_eq!(recv(&b_inbound).await, message);
+    }
+
+    // And in the other direction.
+    b.send(&b.peers()[0].id, NetMessage::GetBlocks { from: 2, to: 3 }).await.unwrap();
+    assert_eq!(recv(&a_inbound).await, NetMessage::GetBlocks { from: 2, to: 3 });
+}
+
+#[tokio::test]
+async fn test_oversized_frame_disconnects_peer() {
+    let (b, b_inbound) = start("node-b", |c| c.with_max_frame_bytes(64)).await;
+    let mut raw = TcpStream::connect(b.local_addr()).await.unwrap();
+
+    write_frame(&mut raw, &hello("raw", PROTOCOL_VERSION, PROTOCOL_VERSION), 64).await.unwrap();
+    wait_for_peers(&b, 1).await;
+
+    raw.write_all(&(1_000_000u32).to_be_bytes()).await.unwrap();
+    raw.write_u8(0).await.unwrap();
+    raw.write_all(&[0u8; 128]).await.unwrap();
+    wait_for_peers(&b, 0).await;
+    assert!(timeout(Duration::from_millis(100), b_inbound.recv()).await.is_err());
+
+    // Oversized frames are refused on the sending side too.
+    let (a, _a_inbound) = start("node-a", |c| c.with_max_frame_bytes(64)).await;
+    a.connect(b.local_addr()).await.unwrap();
+    let blocks = NetMessage::Blocks { blocks: vec![Block::genesis(); 4], certificates: vec![] };
+    a.broadcast(blocks);
+    wait_for_peers(&a, 0).await;
+}
+
+#[tokio::test]
+async fn test_max_connections_enforced() {
+    let (hub, _inbound) = start("hub", |c| c.with_max_connections(1)).await;
+    let (first, _first_inbound) = start("first", |c| c).await;
+    let (second, _second_inbound) = start("second", |c| c).await;
+
+    first.connect(hub.local_addr()).await.unwrap();
+    wait_for_peers(&hub, 1).await;
+    assert!(second.connect(hub.local_addr()).await.is_err());
+    assert_eq!(hub.peer_count(), 1);
+    assert!(hub.connect(second.local_addr()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_version_mismatch_rejected() {
+    let (b, _inbound) = start("node-b", |c| c).await;
+    let mut raw = TcpStream::connect(b.local_addr()).await.unwrap();
+    write_frame(&mut raw, &hello("future", 99, 99), 1024).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(b.peer_count(), 0);
+}
+
+#[tokio::test]
+async fn test_genesis_mismatch_rejected() {
+    let ours = Hash::new_unique();
+    let (a, _a_inbound) = start("node-a", |c| c.with_genesis_hash(ours)).await;
+    let (b, _b_inbound) = start("node-b", |c| c.with_genesis_hash(Hash::new_unique())).await;
+    match a.connect(b.local_addr()).await {
+        Err(NetworkError::GenesisMismatch { ours: a_hash, .. }) => assert_eq!(a_hash, ours),
+        other => panic!("expected genesis mismatch, got {:?}", other),
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!((a.peer_count(), b.peer_count()), (0, 0));
+
+    let (c, _c_inbound) = start("node-c", |c| c.with_genesis_hash(ours)).await;
+    a.connect(c.local_addr()).await.unwrap();
+    wait_for_peers(&c, 1).await;
+}
+
+fn node_ids(network: &Network) -> Vec<String> {
+    let mut ids: Vec<String> = network.peers().into_iter().map(|p| p.node_id).collect();
+    ids.sort();
+    ids
+}
+
+async fn wait_for_node(network: &Network, node_id: &str) {
+    timeout(WAIT, async {
+        while !node_ids(network).iter().any(|id| id == node_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} never connected to {}", network.node_id(), node_id));
+}
+
+#[tokio::test]
+async fn test_peer_discovery_through_gossip() {
+    let (a, _a_inbound) = start("node-a", |c| c).await;
+    let (b, _b_inbound) = start("node-b", |c| c).await;
+    let (c, _c_inbound) = start("node-c", |c| c).await;
+
+    b.connect(a.local_addr()).await.unwrap();
+    wait_for_peers(&a, 1).await;
+
+    // C only knows B, and learns A from B's peer list.
+    c.connect(b.local_addr()).await.unwrap();
+    wait_for_node(&c, "node-a").await;
+    assert_eq!(node_ids(&c), vec!["node-a", "node-b"]);
+    wait_for_node(&a, "node-c").await;
+
+    // B only relays addresses it dialed itself, so D learns A but never C,
+    // which B and A have only seen as an inbound peer.
+    let (d, _d_inbound) = start("node-d", |c| c).await;
+    d.connect(b.local_addr()).await.unwrap();
+    wait_for_node(&d, "node-a").await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(node_ids(&d), vec!["node-a", "node-b"]);
+}
+
+#[tokio::test]
+async fn test_reconnect_backs_off_exponentially() {
+    // A bootstrap node that accepts and immediately drops every connection.
+    let listener = TcpListener::bind(loopback()).await.unwrap();
+    let bootstrap = listener.local_addr().unwrap();
+    let backoff = BackoffConfig::new(Duration::from_millis(100), Duration::from_millis(400)).with_jitter(0.0);
+    let (a, _a_inbound) = start("node-a", |c| c.with_bootstrap_nodes(vec![bootstrap.to_string()]).with_backoff(backoff)).await;
+
+    let mut accepted = Vec::new();
+    while accepted.len() < 5 {
+        let (stream, _) = timeout(WAIT, listener.accept()).await.unwrap().unwrap();
+        accepted.push(Instant::now());
+        drop(stream);
+    }
+    let gaps: Vec<u128> = accepted.windows(2).map(|w| (w[1] - w[0]).as_millis()).collect();
+    for (gap, expected) in gaps.iter().zip([100, 200, 400, 400]) {
+        assert!(*gap >= expected - 10 && *gap < expected + 150, "gaps {:?}", gaps);
+    }
+    let status = a.peer_info(&bootstrap).unwrap();
+    assert!(!status.connected);
+    assert_eq!(status.backoff.unwrap().state, RetryState::Retrying);
+
+    // Once the node comes up the backoff resets.
+    drop(listener);
+    let (b, _b_inbound) = start("node-b", |c| NetworkConfig { listen_addr: bootstrap, ..c }).await;
+    wait_for_node(&a, "node-b").await;
+    let status = a.peer_info(&bootstrap).unwrap().backoff.unwrap();
+    assert_eq!((status.state, status.attempts), (RetryState::Connected, 0));
+
+    b.shutdown();
+    timeout(WAIT, async {
+        while a.peer_info(&bootstrap).unwrap().connected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let status = a.peer_info(&bootstrap).unwrap().backoff.unwrap();
+    assert_eq!(status.state, RetryState::Retrying);
+    assert!(status.attempts >= 1 && status.bootstrap);
+}
+
+/// Opens a raw connection to `network` and completes the handshake.
+async fn raw_client(network: &Network, node_id: &str) -> TcpStream {
+    let mut raw = TcpStream::connect(network.local_addr()).await.unwrap();
+    write_frame(&mut raw, &hello(node_id, PROTOCOL_VERSION, PROTOCOL_VERSION), 1024).await.unwrap();
+    raw
+}
+
+#[tokio::test]
+async fn test_offenses_ban_peer_until_expiry() {
+    let scoring = ScoreConfig::default()
+        .with_penalty(Offense::MalformedFrame, 40.0)
+        .with_ban_duration(Duration::from_millis(600));
+    let (b, _b_inbound) = start("node-b", |c| c.with_scoring(scoring)).await;
+    let raw_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    for attempt in 0..3 {
+        let mut raw = raw_client(&b, "raw").await;
+        wait_for_peers(&b, 1).await;
+        // Length 4, uncompressed, followed by an unknown message tag.
+        raw.write_all(&4u32.to_be_bytes()).await.unwrap();
+        raw.write_u8(0).await.unwrap();
+        raw.write_all(&[0xff; 4]).await.unwrap();
+        wait_for_peers(&b, 0).await;
+        // The score is cleared once the peer is banned.
+        let expected = if attempt < 2 { 40.0 * (attempt + 1) as f64 } else { 0.0 };
+        assert!((b.peer_score(&raw_addr) - expected).abs() < 1.0);
+    }
+    let banned_at = Instant::now();
+    assert_eq!(b.bans().len(), 1);
+    assert_eq!(b.bans()[0].addr, raw_addr);
+
+    let _refused = raw_client(&b, "raw").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(b.peer_count(), 0);
+
+    // Other peers on the same host are unaffected.
+    let (a, _a_inbound) = start("node-a", |c| c).await;
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for_peers(&b, 1).await;
+
+    tokio::time::sleep_until(banned_at + Duration::from_millis(650)).await;
+    assert!(b.bans().is_empty());
+    let _accepted = raw_client(&b, "raw").await;
+    wait_for_peers(&b, 2).await;
+}
+
+#[tokio::test]
+async fn test_ban_and_unban_peer() {
+    let ((a, _a_inbound), (b, _b_inbound)) = connected_pair().await;
+    let b_addr = b.local_addr();
+
+    a.ban_peer(b_addr, Duration::from_millis(400), "operator request").unwrap();
+    wait_for_peers(&a, 0).await;
+    wait_for_peers(&b, 0).await;
+    assert!(matches!(a.connect(b_addr).await, Err(NetworkError::Banned(addr, _)) if addr == b_addr));
+
+    // B dialing A is refused too, since A knows B by its listen address.
+    let _ = b.connect(a.local_addr()).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(a.peer_count(), 0);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    a.connect(b_addr).await.unwrap();
+
+    a.ban_peer(b_addr, Duration::from_secs(3600), "operator request").unwrap();
+    wait_for_peers(&a, 0).await;
+    assert!(a.unban_peer(&b_addr).unwrap());
+    assert!(!a.unban_peer(&b_addr).unwrap());
+    a.connect(b_addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_bans_survive_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("peers.json");
+    let banned: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+    let (a, _a_inbound) = start("node-a", |c| c.with_peer_store_path(&path)).await;
+    a.ban_peer(banned, Duration::from_secs(3600), "spam").unwrap();
+    a.shutdown();
+
+    let (restarted, _inbound) = start("node-a", |c| c.with_peer_store_path(&path)).await;
+    assert_eq!(restarted.bans().len(), 1);
+    assert_eq!(restarted.bans()[0].reason, "spam");
+    assert!(matches!(restarted.connect(banned).await, Err(NetworkError::Banned(..))));
+}
+
+#[tokio::test]
+async fn test_protocol_version_negotiation() {
+    // A node still on the oldest version talks to an up-to-date one, uncompressed.
+    let (old, _old_inbound) = start("node-old", |c| c.with_protocol_versions(MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION)).await;
+    let (new, new_inbound) = start("node-new", |c| c).await;
+    old.connect(new.local_addr()).await.unwrap();
+    wait_for_peers(&new, 1).await;
+    assert_eq!((old.peers()[0].protocol_version, old.peers()[0].codec), (MIN_PROTOCOL_VERSION, Codec::None));
+    assert_eq!((new.peers()[0].protocol_version, new.peers()[0].codec), (MIN_PROTOCOL_VERSION, Codec::None));
+    assert!(!new.peers()[0].multiplexed());
+    let blocks = NetMessage::Blocks { blocks: vec![Block::genesis(); 32], certificates: vec![] };
+    old.broadcast(blocks.clone());
+    assert_eq!(recv(&new_inbound).await, blocks);
+
+    // A future node that can still fall back to our version is accepted.
+    let mut raw = TcpStream::connect(new.local_addr()).await.unwrap();
+    write_frame(&mut raw, &hello("future", PROTOCOL_VERSION, PROTOCOL_VERSION + 3), 1024).await.unwrap();
+    wait_for_peers(&new, 2).await;
+    assert!(new.peers().iter().all(|p| p.protocol_version <= PROTOCOL_VERSION));
+
+    // One that has dropped support for every version we speak is told why.
+    let mut raw = TcpStream::connect(new.local_addr()).await.unwrap();
+    write_frame(&mut raw, &hello("too-new", PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 3), 1024).await.unwrap();
+    assert!(matches!(read_frame(&mut raw, 1024).await.unwrap(), NetMessage::Handshake { .. }));
+    match read_frame(&mut raw, 1024).await.unwrap() {
+        NetMessage::Disconnect(reason) => assert!(reason.contains("no common protocol version"), "{}", reason),
+        other => panic!("expected disconnect, got {:?}", other),
+    }
+    assert_eq!(new.peer_count(), 2);
+}
+
+#[tokio::test]
+async fn test_votes_overtake_a_saturated_bulk_lane() {
+    // Uncompressed and capped, a 1 MiB sync batch takes A about two seconds to send.
+    let upload = BandwidthConfig::default().with_peer_upload(512 * 1024);
+    let (a, _a_inbound) = start("node-a", |c| c.with_codecs(Vec::new()).with_bandwidth(upload)).await;
+    let (b, b_inbound) = start("node-b", |c| c).await;
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for_peers(&b, 1).await;
+    assert!(a.peers()[0].multiplexed() && b.peers()[0].multiplexed());
+
+    let blocks = (0..5500).map(|height| Block::new(height, Hash::new_unique(), 0, Pubkey::new_unique())).collect();
+    let batch = NetMessage::Blocks { blocks, certificates: vec![] };
+    a.send(&b.local_addr(), batch.clone()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for round in 0..3 {
+        let vote = NetMessage::Vote(Vote::new(&Keypair::new(), 1, round, Hash::new_unique()));
+        let sent = Instant::now();
+        a.send(&b.local_addr(), vote.clone()).await.unwrap();
+        assert_eq!(recv(&b_inbound).await, vote);
+        assert!(sent.elapsed() < Duration::from_millis(250), "vote took {:?}", sent.elapsed());
+    }
+    // The batch itself arrives whole once its last chunk is in.
+    assert_eq!(recv(&b_inbound).await, batch);
+}
+
+#[tokio::test]
+async fn test_codec_negotiation_falls_back() {
+    async fn negotiated(dialer: Vec<Codec>, listener: Vec<Codec>) -> (Codec, Codec) {
+        let (a, _a_inbound) = start("node-a", |c| c.with_codecs(dialer)).await;
+        let (b, b_inbound) = start("node-b", |c| c.with_codecs(listener)).await;
+        a.connect(b.local_addr()).await.unwrap();
+        wait_for_peers(&b, 1).await;
+        let blocks = NetMessage::Blocks { blocks: vec![Block::genesis(); 32], certificates: vec![] };
+        a.broadcast(blocks.clone());
+        assert_eq!(recv(&b_inbound).await, blocks);
+        (a.peers()[0].codec, b.peers()[0].codec)
+    }
+
+    assert_eq!(negotiated(vec![Codec::Zstd, Codec::Lz4], vec![Codec::Lz4, Codec::Zstd]).await, (Codec::Zstd, Codec::Zstd));
+    assert_eq!(negotiated(vec![Codec::Zstd, Codec::Lz4], vec![Codec::Lz4]).await, (Codec::Lz4, Codec::Lz4));
+    assert_eq!(negotiated(vec![Codec::Zstd], vec![Codec::Lz4]).await, (Codec::None, Codec::None));
+    assert_eq!(negotiated(vec![], vec![Codec::Zstd]).await, (Codec::None, Codec::None));
+}
+
+#[tokio::test]
+async fn test_decompression_bomb_disconnects_peer() {
+    let (b, b_inbound) = start("node-b", |c| c.with_max_frame_bytes(64 * 1024)).await;
+    let mut raw = TcpStream::connect(b.local_addr()).await.unwrap();
+    write_frame(&mut raw, &hello("raw", PROTOCOL_VERSION, PROTOCOL_VERSION), 1024).await.unwrap();
+    wait_for_peers(&b, 1).await;
+
+    // A few kilobytes on the wire, 16 MiB once inflated.
+    let bomb = Codec::Zstd.compress(&vec![0u8; 16 * 1024 * 1024]);
+    assert!(bomb.len() < 64 * 1024);
+    raw.write_all(&(bomb.len() as u32).to_be_bytes()).await.unwrap();
+    raw.write_u8(Codec::Zstd.flag()).await.unwrap();
+    raw.write_all(&bomb).await.unwrap();
+
+    wait_for_peers(&b, 0).await;
+    assert!(timeout(Duration::from_millis(100), b_inbound.recv()).await.is_err());
+    assert!(b.peer_score(&"127.0.0.1:0".parse().unwrap()) > 0.0);
+}
+
+#[tokio::test]
+async fn test_gossip_delivers_each_tx_once() {
+    let gossip = GossipConfig::default().with_fanout(3);
+    let mut nodes = Vec::new();
+    for node_id in ["node-a", "node-b", "node-c", "node-d"] {
+        nodes.push(start(node_id, |c| c.with_gossip(gossip.clone())).await);
+    }
+    // A full mesh, so every transaction reaches each node over several paths.
+    for i in 0..nodes.len() {
+        for j in i + 1..nodes.len() {
+            // Discovery may already have connected the pair.
+            let _ = nodes[i].0.connect(nodes[j].0.local_addr()).await;
+        }
+    }
+    for (network, _) in &nodes {
+        wait_for_peers(network, 3).await;
+    }
+
+    let keypair = Keypair::new();
+    let txs: Vec<Transaction> = (0..5)
+        .map(|nonce| Transaction::new_signed(&keypair, Pubkey::new_unique(), 10, 1, nonce, 0))
+        .collect();
+    for tx in &txs {
+        assert_eq!(nodes[0].0.gossip(NetMessage::Tx(tx.clone())), 3);
+    }
+    assert_eq!(nodes[0].0.gossip(NetMessage::Tx(txs[0].clone())), 0);
+
+    for (_, inbound) in nodes.iter().skip(1) {
+        let mut received = Vec::new();
+        while received.len() < txs.len() {
+            if let NetMessage::Tx(tx) = recv(inbound).await {
+                received.push(tx);
+            }
+        }
+        received.sort_by_key(|tx| tx.nonce);
+        assert_eq!(received, txs);
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for (network, inbound) in &nodes {
+        while let Some((_, message)) = inbound.try_recv() {
+            assert!(!matches!(message, NetMessage::Tx(_)), "{} got a transaction twice", network.node_id());
+        }
+    }
+
+    // Each of the three receivers forwards every transaction to its two
+    // other peers once, and A's three sends are the only first copies.
+    let duplicates: u64 = nodes.iter().map(|(network, _)| network.gossip_stats().duplicates_suppressed).sum();
+    assert_eq!(duplicates, 5 * 6);
+    assert!(nodes.iter().all(|(network, _)| network.gossip_stats().txs_dropped == 0));
+}
+
+#[tokio::test]
+async fn test_download_cap_paces_and_penalizes_flooding_peer() {
+    let (b, _inbound) = start("node-b", |c| c.with_bandwidth(BandwidthConfig::default().with_peer_download(1000))).await;
+    let mut raw = TcpStream::connect(b.local_addr()).await.unwrap();
+    write_frame(&mut raw, &hello("flooder", MIN_PROTOCOL_VERSION, PROTOCOL_VERSION), 1024).await.unwrap();
+    wait_for_peers(&b, 1).await;
+
+    // 150 pings of 14 bytes each: a second's worth is read at once, the
+    // rest as the cap allows.
+    let started = Instant::now();
+    for nonce in 0..150 {
+        write_frame(&mut raw, &NetMessage::Ping(nonce), 1024).await.unwrap();
+    }
+    timeout(WAIT, async {
+        while b.peers()[0].traffic.received(MessageCategory::Control) < 150 * 14 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(started.elapsed() >= Duration::from_secs(1), "{:?}", started.elapsed());
+    let penalty = Offense::ExcessBandwidth.default_penalty();
+    assert!(b.peer_score(&"127.0.0.1:0".parse().unwrap()) >= penalty * 0.9);
+    assert!(b.traffic().received(MessageCategory::Control) >= 150 * 14);
+}
+
+#[tokio::test]
+async fn test_rotated_identity_reconnects_and_old_key_is_refused() {
+    let (old, new) = (Arc::new(Keypair::new()), Arc::new(Keypair::new()));
+    let handovers = HandoverRegistry::new();
+    let (a, _a_inbound) = start("node-a", |c| c.with_identity(Arc::clone(&old))).await;
+    let (b, b_inbound) = start("node-b", |c| c.with_handovers(handovers.clone())).await;
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for_peers(&b, 1).await;
+    assert_eq!(b.peers()[0].identity, Some(old.pubkey()));
+
+    let handover = KeyHandover::new(&old, &new, 5);
+    assert_eq!(a.hand_over(handover.clone(), Arc::clone(&new)), Ok(1));
+    assert_eq!(recv(&b_inbound).await, NetMessage::KeyHandover(handover));
+    assert_eq!(handovers.identity(&new.pubkey()), old.pubkey());
+
+    // Back under the new key, the peer keeps the identity it had.
+    a.disconnect(&b.local_addr());
+    wait_for_peers(&b, 0).await;
+    handovers.set_finalized_height(5);
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for_peers(&b, 1).await;
+    assert_eq!(a.identity(), Some(new.pubkey()));
+    assert_eq!(b.peers()[0].identity, Some(old.pubkey()));
+
+    // Anyone still proving the old key is refused once the handover applies.
+    match claim_identity(&b, "node-c", old.pubkey(), &old).await {
+        NetMessage::Disconnect(reason) => assert!(reason.contains("retired"), "{}", reason),
+        other => panic!("expected disconnect, got {:?}", other),
+    }
+    assert_eq!(b.peer_count(), 1);
+
+    // Consensus on the same registry counts the new key's votes with the
+    // old key's stake, and refuses the old key's from the handover's height.
+    let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_handovers(handovers);
+    consensus.set_validator_set(ValidatorSet::new(vec![
+        ValidatorInfo::new(old.pubkey(), 10),
+        ValidatorInfo::new(Pubkey::new_unique(), 1),
+    ]));
+    let hash = Hash::new_unique();
+    assert_eq!(consensus.add_vote(Vote::new(&old, 4, 0, hash)), Ok(VoteOutcome::Added));
+    assert!(matches!(
+        consensus.add_vote(Vote::new(&new, 4, 1, hash)),
+        Err(CertificateError::Handover(HandoverError::NotYetValid { from: 5, .. }))
+    ));
+    assert!(matches!(
+        consensus.add_vote(Vote::new(&old, 5, 0, hash)),
+        Err(CertificateError::Handover(HandoverError::Retired { from: 5, .. }))
+    ));
+    assert_eq!(consensus.add_vote(Vote::new(&new, 5, 0, hash)), Ok(VoteOutcome::Added));
+    assert_eq!(consensus.vote_set(5, 0).unwrap().weight_for(&hash), 10);
+}
+
+#[tokio::test]
+async fn test_impersonator_without_the_key_is_refused() {
+    let validator = Arc::new(Keypair::new());
+    let (b, _b_inbound) = start("node-b", |c| c).await;
+
+    // Claiming a validator's key, with another key's signature.
+    match claim_identity(&b, "node-x", validator.pubkey(), &Keypair::new()).await {
+        NetMessage::Disconnect(reason) => assert!(reason.contains("does not verify"), "{}", reason),
+        other => panic!("expected disconnect, got {:?}", other),
+    }
+    let penalty = Offense::InvalidSignature.default_penalty();
+    assert!(b.peer_score(&"127.0.0.1:0".parse().unwrap()) >= penalty * 0.9);
+
+    // Taking a validator's address as node id, proving another key.
+    let address = DADBSAddress::from_pubkey(&validator.pubkey()).to_string();
+    let impostor = Keypair::new();
+    match claim_identity(&b, &address, impostor.pubkey(), &impostor).await {
+        NetMessage::Disconnect(reason) => assert!(reason.contains("not the address"), "{}", reason),
+        other => panic!("expected disconnect, got {:?}", other),
+    }
+    assert_eq!(b.peer_count(), 0);
+
+    let (a, _a_inbound) = start(&address, |c| c.with_identity(Arc::clone(&validator))).await;
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for_peers(&b, 1).await;
+    assert_eq!(b.peers()[0].node_id, address);
+    assert_eq!(b.peers()[0].identity, Some(validator.pubkey()));
+}
+
+#[tokio::test]
+async fn test_dial_back_withholds_addresses_peers_do_not_listen_at() {
+    let (b, _b_inbound) = start("node-b", |c| c.with_dial_back(true)).await;
+    let (elsewhere, _elsewhere_inbound) = start("node-e", |c| c).await;
+
+    // Advertises the port another node listens at.
+    let mut spoofed = hello("node-d", MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION);
+    if let NetMessage::Handshake { listen_port, .. } = &mut spoofed {
+        *listen_port = elsewhere.local_addr().port();
+    }
+    let mut raw = TcpStream::connect(b.local_addr()).await.unwrap();
+    write_frame(&mut raw, &spoofed, 1024).await.unwrap();
+    assert!(matches!(read_frame(&mut raw, 1024).await.unwrap(), NetMessage::Handshake { .. }));
+    let (a, _a_inbound) = start("node-a", |c| c).await;
+    a.connect(b.local_addr()).await.unwrap();
+
+    // Only the peer answering at its own address is passed on.
+    let listening = a.local_addr().to_string();
+    let peers = timeout(WAIT, async {
+        loop {
+            write_frame(&mut raw, &NetMessage::GetPeers, 1024).await.unwrap();
+            let peers = loop {
+                if let NetMessage::Peers(peers) = read_frame(&mut raw, 1 << 16).await.unwrap() {
+                    break peers;
+                }
+            };
+            if peers.iter().any(|peer| peer.addr == listening) {
+                return peers;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the listening peer was never passed on");
+    let spoofed = elsewhere.local_addr().to_string();
+    assert!(!peers.iter().any(|peer| peer.addr == spoofed), "{:?}", peers);
+    assert_eq!(b.peer_count(), 2);
+}
+
+/// A DNS seed serving one TXT record; with none every lookup fails, as
+/// when the seed is down.
+struct FixedSeed {
+    txt: Option<String>,
+}
+
+#[async_trait]
+impl SeedResolver for FixedSeed {
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsSeedError> {
+        match self.txt {
+            Some(_) => Ok(Vec::new()),
+            None => Err(DnsSeedError::Resolve { name: name.to_string(), reason: "SERVFAIL".to_string() }),
+        }
+    }
+
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsSeedError> {
+        match &self.txt {
+            Some(txt) => Ok(vec![txt.clone()]),
+            None => Err(DnsSeedError::Resolve { name: name.to_string(), reason: "SERVFAIL".to_string() }),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_dns_seed_peers_must_prove_the_listed_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("peers.json");
+    let (b, _b_inbound) = start("node-b", |c| c.with_identity(Arc::new(Keypair::new()))).await;
+    let (c, _c_inbound) = start("node-c", |c| c.with_identity(Arc::new(Keypair::new()))).await;
+    // C is listed with a key it does not hold.
+    let listed = format!("{},{} {},{}", b.local_addr(), b.identity().unwrap(), c.local_addr(), Pubkey::new_unique());
+    let seeded = |c: NetworkConfig| {
+        c.with_bootstrap_nodes(vec!["dnsseed:seed.test".to_string()]).with_peer_store_path(&path)
+    };
+
+    let (a, _a_inbound) = start("node-a", seeded).await;
+    assert_eq!(a.resolve_dns_seeds(&FixedSeed { txt: Some(listed) }).await, 2);
+    wait_for_node(&a, "node-b").await;
+    let refused = a.connect(c.local_addr()).await;
+    assert!(matches!(refused, Err(NetworkError::IdentityMismatch { actual: Some(_), .. })), "{:?}", refused);
+    assert_eq!(node_ids(&a), vec!["node-b"]);
+    a.shutdown();
+    wait_for_peers(&b, 0).await;
+
+    // The seed is down after a restart, so what it listed before is dialed,
+    // keys and all.
+    let (restarted, _inbound) = start("node-a", seeded).await;
+    assert_eq!(restarted.resolve_dns_seeds(&FixedSeed { txt: None }).await, 2);
+    wait_for_node(&restarted, "node-b").await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(node_ids(&restarted), vec!["node-b"]);
+}
@@ -0,0 +1,116 @@
+use dadbs_node::node::{
+    Block, BlockTree, ConsensusManager, Genesis, GenesisAccount, Heartbeat, Journal, JournalConfig, JournalEntry,
+    NetMessage, NodeConfig, Replayer, State, Transaction, Transition, ValidatorInfo, ValidatorSet, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::RwLock;
+use solana_sdk::hash::Hash;
+use solana_sdk::signature::{Keypair, Signer};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A chain of four validators with alice funded, journaling to `dir`.
+fn config(dir: &Path, validators: &[Keypair], alice: &Keypair) -> NodeConfig {
+    let mut config = NodeConfig::default();
+    let infos = validators.iter().map(|key| ValidatorInfo::new(key.pubkey(), 1)).collect();
+    let mut genesis = Genesis::template(config.chain_id.clone(), infos);
+    genesis.accounts.push(GenesisAccount { address: DADBSAddress::from_pubkey(&alice.pubkey()), balance: 1_000 });
+    let genesis_path = dir.join("genesis.json");
+    genesis.save(&genesis_path).unwrap();
+    config.genesis_path = Some(genesis_path.display().to_string());
+    config.storage_path = dir.display().to_string();
+    config.journal = JournalConfig { enabled: true, ..JournalConfig::default() };
+    config
+}
+
+/// Runs consensus as a node would for `heights` blocks, each carrying a
+/// transfer from alice and committed by three of the four validators,
+/// journaling what arrives. Returns the finalized hash and state root.
+fn record(config: &NodeConfig, validators: &[Keypair], alice: &Keypair, heights: u64) -> (Hash, Hash) {
+    let genesis = Genesis::from_config(config).unwrap().unwrap();
+    let state = Arc::new(RwLock::new(State::in_memory(genesis.balances().collect::<Vec<_>>())));
+    let tip = genesis.block();
+    let journal = Arc::new(Journal::open(&config.journal, &config.storage_path, (0, tip.hash())).unwrap());
+    let mut consensus = ConsensusManager::from_config(config)
+        .unwrap()
+        .with_params(genesis.params.clone())
+        .with_state(Arc::clone(&state))
+        .with_journal(Arc::clone(&journal));
+    consensus.set_validator_set(ValidatorSet::new(genesis.validators.clone()));
+    consensus.restore_block_tree(BlockTree::new(tip, config.max_fork_depth));
+
+    let peer: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let bob = Keypair::new().pubkey();
+    let mut clock = genesis.genesis_time.timestamp_millis();
+    for height in 1..=heights {
+        clock += 1_000;
+        let parent = consensus.block_tree().finalized_hash();
+        let transfer = Transaction::new_signed(alice, bob, 10, 1, height - 1, clock);
+        let proposer = &validators[height as usize % validators.len()];
+        let mut block = Block::with_transactions(height, parent, clock, proposer.pubkey(), vec![transfer]);
+        block.header.state_root = state.read().root();
+        journal.record_inbound(peer, &NetMessage::Block(block.clone()), clock);
+        consensus.apply_block(block.clone(), 0).unwrap();
+
+        for key in &validators[..3] {
+            let vote = Vote::new(key, height, 0, block.hash());
+            journal.record_inbound(peer, &NetMessage::Vote(vote.clone()), clock + 10);
+            consensus.add_vote(vote).unwrap();
+        }
+        consensus.finalize_block(&block.hash()).unwrap();
+
+        let root = state.read().root();
+        let heartbeat = Heartbeat::new_signed_with_root(&validators[3], consensus.chain_id(), height, root, clock + 20);
+        journal.record_inbound(peer, &NetMessage::Heartbeat(heartbeat.clone()), clock + 20);
+        let _ = consensus.record_heartbeat_at(peer, &heartbeat, clock + 20);
+    }
+    journal.flush().unwrap();
+    let root = state.read().root();
+    (consensus.block_tree().finalized_hash(), root)
+}
+
+#[test]
+fn test_replay_matches_the_recorded_run_until_a_message_is_corrupted() {
+    let dir = tempfile::tempdir().unwrap();
+    let validators: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+    let alice = Keypair::new();
+    let config = config(dir.path(), &validators, &alice);
+    let (finalized_hash, state_root) = record(&config, &validators, &alice, 5);
+
+    let journal_dir = config.journal.dir(&config.storage_path);
+    let entries = Journal::read(&journal_dir).unwrap();
+    let mut transitions = Vec::new();
+    let report = Replayer::from_config(&config)
+        .unwrap()
+        .run(&entries, None, |at_ms, transition| transitions.push((at_ms, transition.clone())))
+        .unwrap();
+    assert_eq!(report.divergence, None);
+    assert_eq!((report.finalized_height, report.finalized_hash), (5, finalized_hash));
+    assert_eq!(report.state_root, Some(state_root));
+    assert_eq!(report.checked, 5);
+    let finalized = transitions.iter().filter(|(_, transition)| matches!(transition, Transition::Finalized { .. }));
+    assert_eq!(finalized.count(), 5);
+
+    // Stopping early leaves the later blocks unreplayed.
+    let report = Replayer::from_config(&config).unwrap().run(&entries, Some(2), |_, _| {}).unwrap();
+    assert_eq!((report.finalized_height, report.checked, report.divergence), (2, 2, None));
+
+    // A vote at height 3 mangled on the way in leaves that height without
+    // a quorum, which the replay reports where the recording finalized it.
+    let mut corrupted = entries.clone();
+    let vote = corrupted.iter_mut().find_map(|entry| match entry {
+        JournalEntry::Inbound { message: NetMessage::Vote(vote), .. } if vote.height == 3 => Some(vote),
+        _ => None,
+    });
+    vote.unwrap().block_hash = Hash::new_unique();
+    let corrupted_dir = dir.path().join("corrupted");
+    Journal::write(&corrupted_dir, &corrupted).unwrap();
+    let report = Replayer::from_config(&config)
+        .unwrap()
+        .run(&Journal::read(&corrupted_dir).unwrap(), None, |_, _| {})
+        .unwrap();
+    let divergence = report.divergence.unwrap();
+    assert_eq!((divergence.height, divergence.actual), (3, None));
+    assert_eq!((report.finalized_height, report.checked), (2, 2));
+}
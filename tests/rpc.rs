@@ -0,0 +1,706 @@
+mod common;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use dadbs_node::node::subscriptions::TOO_MANY_SUBSCRIPTIONS;
+use dadbs_node::node::rpc::{
+    BlockResult, InclusionProofResult, NodeInfo, ReceiptResult, SignedHeaderResult, TransactionResult, ValidatorResult,
+    INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR, RATE_LIMITED, TRANSACTION_REJECTED,
+};
+use dadbs_node::node::{
+    light, Block, BucketConfig, LimitsConfig, ChainEvents, ConsensusManager, Event, FallbackPolicy, MemoClassifier,
+    MemoModerator, Mempool, MerkleProof, ModerationConfig, Page, ReceiptStatus, RpcConfig, RpcContext, RpcServer, State, Storage,
+    SignedHeader, ThresholdPolicy, Transaction, TrustedState, TxTracer, ValidatorInfo, ValidatorSet,
+};
+use dadbs_node::utils::DADBSAddress;
+use futures::{SinkExt, StreamExt};
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+const BLOCKS: u64 = 3;
+
+struct TestNode {
+    server: Arc<RpcServer>,
+    storage: Arc<Storage>,
+    state: Arc<RwLock<State>>,
+    consensus: Arc<AsyncMutex<ConsensusManager>>,
+    mempool: Arc<Mutex<Mempool>>,
+    blocks: Vec<Block>,
+    validator: Keypair,
+    alice: Keypair,
+    bob: Keypair,
+    carol: Keypair,
+    _dir: tempfile::TempDir,
+}
+
+impl TestNode {
+    /// A node with `BLOCKS` finalized blocks, each moving 10 from alice to bob.
+    async fn start(config: RpcConfig) -> Self {
+        Self::start_with_events(config, ChainEvents::default()).await
+    }
+
+    async fn start_with_events(config: RpcConfig, events: ChainEvents) -> Self {
+        Self::start_with(config, events, LimitsConfig::default()).await
+    }
+
+    async fn start_with(config: RpcConfig, events: ChainEvents, limits: LimitsConfig) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let (validator, alice, bob, carol) = (Keypair::new(), Keypair::new(), Keypair::new(), Keypair::new());
+        let genesis = vec![
+            (DADBSAddress::from_pubkey(&alice.pubkey()), 1_000),
+            (DADBSAddress::from_pubkey(&carol.pubkey()), 1_000),
+        ];
+        let storage = Arc::new(Storage::open(dir.path()).unwrap());
+        let state = Arc::new(RwLock::new(State::in_memory(genesis)));
+        let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+            .with_state(Arc::clone(&state))
+            .with_events(events.clone())
+            .with_receipt_store(Arc::clone(&storage));
+        consensus.set_validator_set(ValidatorSet::new(vec![ValidatorInfo::new(validator.pubkey(), 10)]));
+
+        let mempool = Arc::new(Mutex::new(Mempool::new(1, 100)));
+        let consensus = Arc::new(AsyncMutex::new(consensus));
+        let context = RpcContext {
+            node_id: "rpc-test".to_string(),
+            chain_id: "dadbs-testnet".to_string(),
+            storage: Arc::clone(&storage),
+            consensus: Arc::clone(&consensus),
+            state: Arc::clone(&state),
+            mempool: Arc::clone(&mempool),
+            network: None,
+            events,
+            tracer: TxTracer::default(),
+        };
+        let config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..config };
+        let server = RpcServer::bind_with(config, context, limits, None).await.unwrap();
+        let mut node = TestNode {
+            server,
+            storage,
+            state,
+            consensus,
+            mempool,
+            blocks: Vec::new(),
+            validator,
+            alice,
+            bob,
+            carol,
+            _dir: dir,
+        };
+        for nonce in 0..BLOCKS {
+            let tx = Transaction::new_signed(&node.alice, node.bob.pubkey(), 10, 1, nonce, 0);
+            let block = node.produce(vec![tx]).await;
+            node.blocks.push(block);
+        }
+        node
+    }
+
+    /// Proposes, finalizes and stores a block on top of the finalized tip.
+    async fn produce(&self, transactions: Vec<Transaction>) -> Block {
+        let mut consensus = self.consensus.lock().await;
+        self.produce_locked(&mut consensus, transactions)
+    }
+
+    fn produce_locked(&self, consensus: &mut ConsensusManager, transactions: Vec<Transaction>) -> Block {
+        common::produce(consensus, &self.storage, &self.state, &self.validator, transactions)
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}/", self.server.local_addr())
+    }
+
+    async fn post(&self, body: Value) -> Value {
+        reqwest::Client::new().post(self.url()).json(&body).send().await.unwrap().json().await.unwrap()
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Value {
+        let response = self.post(json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params })).await;
+        assert_eq!(response["id"], 1);
+        response
+    }
+
+    async fn result<T: serde::de::DeserializeOwned>(&self, method: &str, params: Value) -> T {
+        let response = self.call(method, params).await;
+        assert!(response.get("error").is_none(), "{}: {}", method, response);
+        serde_json::from_value(response["result"].clone()).unwrap()
+    }
+
+    async fn error_code(&self, method: &str, params: Value) -> i64 {
+        self.call(method, params).await["error"]["code"].as_i64().unwrap()
+    }
+}
+
+fn address(keypair: &Keypair) -> String {
+    DADBSAddress::from_pubkey(&keypair.pubkey()).to_string()
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect(node: &TestNode) -> Socket {
+    let url = format!("ws://{}/ws", node.server.local_addr());
+    tokio_tungstenite::connect_async(url).await.unwrap().0
+}
+
+async fn ws_call(socket: &mut Socket, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+    socket.send(Message::Text(request.to_string())).await.unwrap();
+    let response = next_message(socket).await.unwrap();
+    assert_eq!(response["id"], 7, "{}", response);
+    response
+}
+
+async fn subscribe(socket: &mut Socket, method: &str, params: Value) -> u64 {
+    ws_call(socket, method, params).await["result"].as_u64().unwrap()
+}
+
+/// The next JSON message, or `None` once the socket has been quiet a while.
+async fn next_message(socket: &mut Socket) -> Option<Value> {
+    loop {
+        match tokio::time::timeout(Duration::from_millis(300), socket.next()).await.ok()??.unwrap() {
+            Message::Text(text) => return Some(serde_json::from_str(&text).unwrap()),
+            _ => continue,
+        }
+    }
+}
+
+/// Notification params received until the socket goes quiet, by subscription.
+async fn drain(socket: &mut Socket) -> HashMap<u64, Vec<Value>> {
+    let mut received: HashMap<u64, Vec<Value>> = HashMap::new();
+    while let Some(message) = next_message(socket).await {
+        assert_eq!(message["method"], "subscription", "{}", message);
+        let params = message["params"].clone();
+        received.entry(params["subscription"].as_u64().unwrap()).or_default().push(params);
+    }
+    received
+}
+
+#[tokio::test]
+async fn test_block_transaction_and_account_queries() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let block = &node.blocks[1];
+
+    let by_height: Option<BlockResult> = node.result("get_block_by_height", json!({ "height": 2 })).await;
+    assert_eq!(by_height, Some(BlockResult::from(block)));
+    // Positional params work too.
+    let by_hash: Option<BlockResult> = node.result("get_block_by_hash", json!([block.hash().to_string()])).await;
+    assert_eq!(by_hash, Some(BlockResult::from(block)));
+    let missing: Option<BlockResult> = node.result("get_block_by_height", json!({ "height": 99 })).await;
+    assert_eq!(missing, None);
+    assert_eq!(node.error_code("get_block_by_height", json!({ "height": "two" })).await, INVALID_PARAMS);
+    assert_eq!(node.error_code("get_block_by_hash", json!({ "hash": "not-a-hash" })).await, INVALID_PARAMS);
+
+    let tx = &block.transactions[0];
+    let stored: TransactionResult = node.result("get_transaction", json!({ "hash": tx.hash().to_string() })).await;
+    assert_eq!((stored.height, stored.index, stored.amount, stored.nonce), (2, 0, 10, 1));
+    assert_eq!(stored.sender, node.alice.pubkey().to_string());
+    assert_eq!(node.error_code("get_transaction", json!({})).await, INVALID_PARAMS);
+
+    let balance: u64 = node.result("get_balance", json!({ "address": address(&node.bob) })).await;
+    assert_eq!(balance, 10 * BLOCKS);
+    let balance: u64 = node.result("get_balance", json!({ "address": address(&node.alice) })).await;
+    assert_eq!(balance, 1_000 - 11 * BLOCKS);
+    let nonce: u64 = node.result("get_nonce", json!({ "address": address(&node.alice) })).await;
+    assert_eq!(nonce, BLOCKS);
+    assert_eq!(node.error_code("get_balance", json!({ "address": "dadbsnothex" })).await, INVALID_PARAMS);
+    assert_eq!(node.error_code("get_nonce", json!({ "address": node.alice.pubkey().to_string() })).await, INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_receipts_tell_failed_from_never_included() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let block = &node.blocks[0];
+    let tx = &block.transactions[0];
+    let receipt: Option<ReceiptResult> = node.result("get_receipt", json!({ "hash": tx.hash().to_string() })).await;
+    assert_eq!(receipt, Some(ReceiptResult {
+        tx_hash: tx.hash().to_string(),
+        block_hash: block.hash().to_string(),
+        height: 1,
+        index: 0,
+        status: ReceiptStatus::Success,
+        error: None,
+        fee_paid: 1,
+        events: vec![Event::Transfer {
+            from: DADBSAddress::from_pubkey(&node.alice.pubkey()),
+            to: DADBSAddress::from_pubkey(&node.bob.pubkey()),
+            amount: 10,
+        }],
+    }));
+
+    // Bob holds 30: the transfer fails, but the fee is charged.
+    let overdraft = Transaction::new_signed(&node.bob, node.carol.pubkey(), 1_000, 1, 0, 0);
+    node.produce(vec![overdraft.clone()]).await;
+    let hash = json!({ "hash": overdraft.hash().to_string() });
+    let receipt: ReceiptResult = node.result("get_receipt", hash.clone()).await;
+    assert_eq!((receipt.height, receipt.status, receipt.fee_paid), (BLOCKS + 1, ReceiptStatus::Failed, 1));
+    assert!(receipt.error.unwrap().contains("needs 1000"));
+    assert!(receipt.events.is_empty());
+    let included: Option<TransactionResult> = node.result("get_transaction", hash).await;
+    assert!(included.is_some());
+    let balance: u64 = node.result("get_balance", json!({ "address": address(&node.bob) })).await;
+    assert_eq!(balance, 10 * BLOCKS - 1);
+
+    let never = Transaction::new_signed(&node.alice, node.bob.pubkey(), 1, 1, BLOCKS, 0);
+    let missing: Option<ReceiptResult> = node.result("get_receipt", json!({ "hash": never.hash().to_string() })).await;
+    assert_eq!(missing, None);
+    assert_eq!(node.error_code("get_receipt", json!({ "hash": "not-a-hash" })).await, INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_light_client_headers_and_inclusion_proofs() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let headers: Vec<SignedHeaderResult> = node.result("get_light_headers", json!({ "from_height": 1, "limit": 10 })).await;
+    assert_eq!(headers.len() as u64, BLOCKS);
+    let headers: Vec<SignedHeader> =
+        headers.iter().map(|header| SignedHeader::try_from_slice(&hex::decode(&header.raw).unwrap()).unwrap()).collect();
+    let validators = ValidatorSet::new(vec![ValidatorInfo::new(node.validator.pubkey(), 10)]);
+    let block_hash = node.blocks[0].parent_hash();
+    let trusted = TrustedState { height: 0, block_hash, validators, chain_id: "dadbs-testnet".to_string() };
+    let verified = light::verify_header_chain(&headers, &trusted).unwrap();
+    assert_eq!((verified.height, verified.block_hash), (BLOCKS, node.blocks[2].hash()));
+
+    let tx = &node.blocks[1].transactions[0];
+    let proof: InclusionProofResult = node.result("get_inclusion_proof", json!({ "hash": tx.hash().to_string() })).await;
+    assert_eq!((proof.height, proof.block_hash), (2, node.blocks[1].hash().to_string()));
+    let proof = MerkleProof::try_from_slice(&hex::decode(&proof.proof).unwrap()).unwrap();
+    assert!(light::verify_tx_inclusion(&headers[1].header, &proof, &tx.hash()).is_ok());
+    // The same proof does not place another block's transaction here.
+    let other = node.blocks[0].transactions[0].hash();
+    assert!(light::verify_tx_inclusion(&headers[1].header, &proof, &other).is_err());
+
+    let never = Transaction::new_signed(&node.alice, node.bob.pubkey(), 1, 1, BLOCKS, 0);
+    let missing: Option<InclusionProofResult> =
+        node.result("get_inclusion_proof", json!({ "hash": never.hash().to_string() })).await;
+    assert_eq!(missing, None);
+
+    // Bob is in every block; the headers' own filters agree, heights past the tip are left out.
+    let bob = DADBSAddress::from_pubkey(&node.bob.pubkey());
+    let hint = json!({ "address": bob.to_string(), "from_height": 1, "to_height": 100 });
+    let heights: Vec<u64> = node.result("get_address_activity_hint", hint).await;
+    assert_eq!(heights, [1, 2, 3]);
+    assert!(headers.iter().all(|signed| signed.header.may_touch(&bob)));
+    let wide = json!({ "address": bob.to_string(), "from_height": 0, "to_height": u64::MAX });
+    assert_eq!(node.error_code("get_address_activity_hint", wide).await, INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_paginated_listings() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let heights = |page: &Page<BlockResult>| page.items.iter().map(|block| block.height).collect::<Vec<_>>();
+
+    let first: Page<BlockResult> = node.result("get_blocks", json!({ "limit": 2 })).await;
+    assert_eq!(heights(&first), [1, 2]);
+    let cursor = first.next_cursor.unwrap();
+    let rest: Page<BlockResult> = node.result("get_blocks", json!({ "cursor": cursor, "limit": 2 })).await;
+    assert_eq!(heights(&rest), [3]);
+    assert_eq!(rest.next_cursor, None);
+    let newest: Page<BlockResult> = node.result("get_blocks", json!({ "direction": "descending", "limit": 1 })).await;
+    assert_eq!(heights(&newest), [BLOCKS]);
+
+    // Cursors only work for the listing that issued them, unaltered.
+    assert_eq!(node.error_code("get_blocks", json!({ "cursor": cursor, "direction": "descending" })).await, INVALID_PARAMS);
+    let forged = format!("{}{}", &cursor[..cursor.len() - 1], if cursor.ends_with('0') { '1' } else { '0' });
+    assert_eq!(node.error_code("get_blocks", json!({ "cursor": forged })).await, INVALID_PARAMS);
+
+    let sent: Page<TransactionResult> = node.result(
+        "get_account_transactions",
+        json!({ "pubkey": node.alice.pubkey().to_string(), "direction": "descending" }),
+    ).await;
+    assert_eq!(sent.items.iter().map(|tx| tx.nonce).collect::<Vec<_>>(), [2, 1, 0]);
+    assert_eq!(node.error_code("get_account_transactions", json!({ "pubkey": "nope" })).await, INVALID_PARAMS);
+    let recent: Page<TransactionResult> = node.result("get_recent_transactions", json!({ "limit": 2 })).await;
+    assert_eq!(recent.items.iter().map(|tx| tx.height).collect::<Vec<_>>(), [3, 2]);
+    let older: Page<TransactionResult> =
+        node.result("get_recent_transactions", json!({ "cursor": recent.next_cursor.unwrap() })).await;
+    assert_eq!(older.items.iter().map(|tx| tx.height).collect::<Vec<_>>(), [1]);
+}
+
+#[tokio::test]
+async fn test_send_transaction() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let raw = |tx: &Transaction| hex::encode(tx.try_to_vec().unwrap());
+
+    let tx = Transaction::new_signed(&node.alice, node.bob.pubkey(), 5, 2, BLOCKS, 0);
+    let hash: String = node.result("send_transaction", json!({ "raw": raw(&tx) })).await;
+    assert_eq!(hash, tx.hash().to_string());
+    assert_eq!(node.mempool.lock().len(), 1);
+
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": raw(&tx) })).await, TRANSACTION_REJECTED);
+    let stale = Transaction::new_signed(&node.alice, node.bob.pubkey(), 5, 2, 0, 0);
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": raw(&stale) })).await, TRANSACTION_REJECTED);
+    let mut forged = Transaction::new_signed(&node.bob, node.alice.pubkey(), 5, 2, 0, 0);
+    forged.signature = Signature::default();
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": raw(&forged) })).await, TRANSACTION_REJECTED);
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": "zz" })).await, INVALID_PARAMS);
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": "00ff" })).await, INVALID_PARAMS);
+    assert_eq!(node.mempool.lock().len(), 1);
+}
+
+/// Answers every memo with the same scores.
+struct Canned(&'static [(&'static str, f32)]);
+
+#[async_trait::async_trait]
+impl MemoClassifier for Canned {
+    async fn classify(&self, _text: &str, _labels: &[&str]) -> Result<Vec<(String, f32)>, String> {
+        Ok(self.0.iter().map(|&(label, score)| (label.to_string(), score)).collect())
+    }
+}
+
+#[tokio::test]
+async fn test_send_transaction_moderates_memos() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let raw = |tx: &Transaction| hex::encode(tx.try_to_vec().unwrap());
+    let memo = |nonce: u64, memo: &str| {
+        let mut tx = Transaction::unsigned(node.alice.pubkey(), node.bob.pubkey(), 5, 2, nonce, 0);
+        tx.payload = memo.as_bytes().to_vec();
+        tx.signature = node.alice.sign_message(&tx.signing_bytes());
+        tx
+    };
+    let moderator = |fallback: FallbackPolicy| {
+        Arc::new(MemoModerator::new(ModerationConfig { enabled: true, fallback, ..ModerationConfig::default() }))
+    };
+
+    // No model is serving: the fallback policy decides, and only for memos.
+    let rejecting = moderator(FallbackPolicy::Reject);
+    node.server.moderate_memos(Arc::clone(&rejecting));
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": raw(&memo(BLOCKS, "rent")) })).await, TRANSACTION_REJECTED);
+    let plain = Transaction::new_signed(&node.alice, node.bob.pubkey(), 5, 2, BLOCKS, 0);
+    let _: String = node.result("send_transaction", json!({ "raw": raw(&plain) })).await;
+    assert_eq!(rejecting.unavailable(), 1);
+    node.server.moderate_memos(moderator(FallbackPolicy::Allow));
+    let _: String = node.result("send_transaction", json!({ "raw": raw(&memo(BLOCKS + 1, "rent")) })).await;
+    assert_eq!(node.mempool.lock().len(), 2);
+
+    // With a classifier, the scores decide whatever the fallback.
+    let judged = moderator(FallbackPolicy::Allow);
+    judged.classify_with(Arc::new(Canned(&[("spam", 0.9), ("safe", 0.08), ("abuse", 0.02)])));
+    node.server.moderate_memos(Arc::clone(&judged));
+    assert_eq!(node.error_code("send_transaction", json!({ "raw": raw(&memo(BLOCKS + 2, "free coins")) })).await, TRANSACTION_REJECTED);
+    judged.classify_with(Arc::new(Canned(&[("safe", 0.8), ("spam", 0.15), ("abuse", 0.05)])));
+    let _: String = node.result("send_transaction", json!({ "raw": raw(&memo(BLOCKS + 2, "lunch")) })).await;
+    assert_eq!((judged.allowed(), judged.flagged()), (1, 1));
+    assert_eq!(node.mempool.lock().len(), 3);
+}
+
+#[tokio::test]
+async fn test_node_info_and_validator_set() {
+    let node = TestNode::start(RpcConfig::default()).await;
+
+    let info: NodeInfo = node.result("get_node_info", Value::Null).await;
+    assert_eq!((info.node_id.as_str(), info.chain_id.as_str()), ("rpc-test", "dadbs-testnet"));
+    assert_eq!((info.head_height, info.finalized_height, info.peers), (BLOCKS, BLOCKS, 0));
+    assert_eq!(info.halted, None);
+
+    let validators: Vec<ValidatorResult> = node.result("get_validator_set", json!([])).await;
+    assert_eq!(validators.len(), 1);
+    assert_eq!((validators[0].pubkey.clone(), validators[0].weight), (node.validator.pubkey().to_string(), 10));
+}
+
+#[tokio::test]
+async fn test_batches_and_protocol_errors() {
+    let node = TestNode::start(RpcConfig { max_batch_size: 3, max_request_bytes: 4096, ..RpcConfig::default() }).await;
+
+    let batch = node.post(json!([
+        { "jsonrpc": "2.0", "id": "a", "method": "get_block_by_height", "params": { "height": 1 } },
+        { "jsonrpc": "2.0", "method": "get_node_info" },
+        { "jsonrpc": "2.0", "id": "b", "method": "no_such_method" },
+    ])).await;
+    let responses = batch.as_array().unwrap();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["id"], "a");
+    assert_eq!(responses[0]["result"]["height"], 1);
+    assert_eq!((responses[1]["id"].clone(), responses[1]["error"]["code"].as_i64()), (json!("b"), Some(METHOD_NOT_FOUND)));
+
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "get_node_info" });
+    let oversized = node.post(json!([request, request, request, request])).await;
+    assert_eq!(oversized["error"]["code"], INVALID_REQUEST);
+    assert_eq!(node.post(json!([])).await["error"]["code"], INVALID_REQUEST);
+    assert_eq!(node.post(json!({ "jsonrpc": "1.0", "id": 1, "method": "get_node_info" })).await["error"]["code"], INVALID_REQUEST);
+    assert_eq!(node.post(json!({ "id": 1 })).await["error"]["code"], INVALID_REQUEST);
+
+    let client = reqwest::Client::new();
+    let garbage: Value = client.post(node.url()).body("{not json").send().await.unwrap().json().await.unwrap();
+    assert_eq!(garbage["error"]["code"], PARSE_ERROR);
+
+    let notification = client.post(node.url()).json(&json!({ "jsonrpc": "2.0", "method": "get_node_info" })).send().await.unwrap();
+    assert_eq!(notification.status(), reqwest::StatusCode::NO_CONTENT);
+
+    let huge = json!({ "jsonrpc": "2.0", "id": 1, "method": "send_transaction", "params": { "raw": "00".repeat(4096) } });
+    let refused = client.post(node.url()).json(&huge).send().await.unwrap();
+    assert_eq!(refused.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    node.server.shutdown();
+}
+
+#[tokio::test]
+async fn test_lagging_subscriber_is_told_what_it_missed() {
+    let node = TestNode::start_with_events(RpcConfig::default(), ChainEvents::new(4)).await;
+    let mut socket = connect(&node).await;
+    let subscription = subscribe(&mut socket, "subscribe_new_blocks", Value::Null).await;
+
+    // Twenty blocks land before the subscriber reads anything.
+    {
+        let mut consensus = node.consensus.lock().await;
+        for _ in 0..20 {
+            node.produce_locked(&mut consensus, Vec::new());
+        }
+    }
+    let received = drain(&mut socket).await.remove(&subscription).unwrap();
+    assert_eq!(received.len(), 5);
+    assert_eq!(received[0]["missed"], 16);
+    let heights: Vec<u64> = received[1..].iter().map(|params| params["result"]["height"].as_u64().unwrap()).collect();
+    assert_eq!(heights, vec![BLOCKS + 17, BLOCKS + 18, BLOCKS + 19, BLOCKS + 20]);
+
+    // Once caught up it keeps receiving.
+    let block = node.produce(Vec::new()).await;
+    let received = drain(&mut socket).await.remove(&subscription).unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["result"], json!(BlockResult::from(&block)));
+}
+
+#[tokio::test]
+async fn test_address_subscriptions_only_see_their_transactions() {
+    let node = TestNode::start(RpcConfig::default()).await;
+    let mut socket = connect(&node).await;
+    let bob = subscribe(&mut socket, "subscribe_address", json!({ "address": address(&node.bob) })).await;
+    let carol = subscribe(&mut socket, "subscribe_address", json!([address(&node.carol)])).await;
+    let invalid = ws_call(&mut socket, "subscribe_address", json!({ "address": "dadbsnothex" })).await;
+    assert_eq!(invalid["error"]["code"], INVALID_PARAMS);
+
+    let dave = Keypair::new();
+    let to_bob = Transaction::new_signed(&node.alice, node.bob.pubkey(), 10, 1, BLOCKS, 0);
+    let to_dave = Transaction::new_signed(&node.carol, dave.pubkey(), 10, 1, 0, 0);
+    let to_carol = Transaction::new_signed(&node.alice, node.carol.pubkey(), 10, 1, BLOCKS + 1, 0);
+    let carol_to_bob = Transaction::new_signed(&node.carol, node.bob.pubkey(), 10, 1, 1, 0);
+    node.produce(vec![to_bob.clone()]).await;
+    node.produce(vec![to_dave.clone()]).await;
+    node.produce(vec![to_carol.clone(), carol_to_bob.clone()]).await;
+
+    let received = drain(&mut socket).await;
+    // Each transaction is notified on inclusion, then with its receipt.
+    let notified = |subscription: u64, kind: &str, field: &str| -> Vec<Value> {
+        received[&subscription].iter().filter_map(|params| params.get(kind)).map(|entry| entry[field].clone()).collect()
+    };
+    let hashes = |transactions: &[&Transaction]| -> Vec<Value> {
+        transactions.iter().map(|tx| json!(tx.hash().to_string())).collect()
+    };
+    let expected = [(bob, hashes(&[&to_bob, &carol_to_bob])), (carol, hashes(&[&to_dave, &to_carol, &carol_to_bob]))];
+    for (subscription, transactions) in expected {
+        assert_eq!(notified(subscription, "result", "hash"), transactions);
+        assert_eq!(notified(subscription, "receipt", "tx_hash"), transactions);
+        assert_eq!(received[&subscription].len(), 2 * transactions.len());
+    }
+    assert_eq!(notified(carol, "result", "index")[2], 1);
+    assert_eq!(notified(carol, "receipt", "status")[2], "success");
+    assert_eq!(received.len(), 2);
+}
+
+#[tokio::test]
+async fn test_subscription_limit_and_unsubscribe() {
+    let node = TestNode::start(RpcConfig { max_subscriptions: 2, ..RpcConfig::default() }).await;
+    let mut socket = connect(&node).await;
+    let blocks = subscribe(&mut socket, "subscribe_new_blocks", Value::Null).await;
+    let finalized = subscribe(&mut socket, "subscribe_finalized", Value::Null).await;
+    let refused = ws_call(&mut socket, "subscribe_new_blocks", Value::Null).await;
+    assert_eq!(refused["error"]["code"], TOO_MANY_SUBSCRIPTIONS);
+
+    assert_eq!(ws_call(&mut socket, "unsubscribe", json!({ "subscription": blocks })).await["result"], true);
+    assert_eq!(ws_call(&mut socket, "unsubscribe", json!({ "subscription": blocks })).await["result"], false);
+    let replacement = subscribe(&mut socket, "subscribe_new_blocks", Value::Null).await;
+
+    // Plain queries work over the socket too.
+    let info = ws_call(&mut socket, "get_node_info", Value::Null).await;
+    assert_eq!(info["result"]["finalized_height"], BLOCKS);
+
+    let block = node.produce(Vec::new()).await;
+    let received = drain(&mut socket).await;
+    assert!(!received.contains_key(&blocks));
+    for subscription in [finalized, replacement] {
+        assert_eq!(received[&subscription].len(), 1);
+        assert_eq!(received[&subscription][0]["result"]["hash"], block.hash().to_string());
+    }
+}
+
+/// Only the nonce lookups run out; balances are served throughout.
+fn nonce_limited() -> LimitsConfig {
+    let mut limits = LimitsConfig::default();
+    limits.methods.insert("get_nonce".to_string(), BucketConfig::new(0.5, 3));
+    limits
+}
+
+#[tokio::test]
+async fn test_method_rate_limit() {
+    let node = TestNode::start_with(RpcConfig::default(), ChainEvents::default(), nonce_limited()).await;
+    let params = json!({ "address": address(&node.alice) });
+    for _ in 0..3 {
+        let nonce: u64 = node.result("get_nonce", params.clone()).await;
+        assert_eq!(nonce, BLOCKS);
+    }
+    for _ in 0..5 {
+        let limited = node.call("get_nonce", params.clone()).await;
+        assert_eq!(limited["error"]["code"], RATE_LIMITED);
+        let retry_after_ms = limited["error"]["data"]["retry_after_ms"].as_u64().unwrap();
+        assert!(retry_after_ms > 0 && retry_after_ms <= 2000, "{}", limited);
+    }
+    let balance: u64 = node.result("get_balance", params.clone()).await;
+    assert_eq!(balance, 1_000 - 11 * BLOCKS);
+    let _: NodeInfo = node.result("get_node_info", Value::Null).await;
+
+    // Every call in a batch is counted.
+    let batch = node.post(json!([
+        { "jsonrpc": "2.0", "id": "a", "method": "get_balance", "params": params },
+        { "jsonrpc": "2.0", "id": "b", "method": "get_nonce", "params": params },
+    ])).await;
+    assert!(batch[0].get("result").is_some());
+    assert_eq!(batch[1]["error"]["code"], RATE_LIMITED);
+}
+
+#[tokio::test]
+async fn test_client_rate_limit_and_allowlist() {
+    let limits = LimitsConfig { per_ip: BucketConfig::new(0.5, 4), ..LimitsConfig::default() };
+    let node = TestNode::start_with(RpcConfig::default(), ChainEvents::default(), limits.clone()).await;
+    for method in ["get_node_info", "get_validator_set", "get_node_info", "no_such_method"] {
+        assert_ne!(node.call(method, Value::Null).await["error"]["code"], RATE_LIMITED);
+    }
+    assert_eq!(node.error_code("get_validator_set", Value::Null).await, RATE_LIMITED);
+    assert_eq!(node.error_code("no_such_method", Value::Null).await, RATE_LIMITED);
+
+    let allowlisted = LimitsConfig { allowlist: vec!["127.0.0.1".parse().unwrap()], ..limits };
+    let node = TestNode::start_with(RpcConfig::default(), ChainEvents::default(), allowlisted).await;
+    for _ in 0..10 {
+        let _: NodeInfo = node.result("get_node_info", Value::Null).await;
+    }
+}
+
+
+#[cfg(feature = "llm")]
+mod llm {
+    use super::*;
+    use dadbs_node::llm::{KvCache, LightLLM, LlmError, ModelBackend, QueueConfig, UsageTotals};
+    use dadbs_node::node::rpc::GENERATION_INTERRUPTED;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// One token per prompt byte; every step takes a few milliseconds and
+    /// writes an "x", so generation runs until stopped.
+    #[derive(Default)]
+    struct Slow {
+        forwards: AtomicUsize,
+    }
+
+    impl ModelBackend for Slow {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, LlmError> {
+            Ok(text.bytes().map(|_| 1).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, LlmError> {
+            Ok("x".repeat(tokens.len()))
+        }
+
+        fn new_cache(&self) -> Result<KvCache, LlmError> {
+            Ok(KvCache::new(()))
+        }
+
+        fn forward(&self, _cache: &mut KvCache, _tokens: &[u32]) -> Result<Vec<f32>, LlmError> {
+            self.forwards.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            Ok(vec![0.0, 1.0])
+        }
+
+        fn bos_token(&self) -> Option<u32> {
+            None
+        }
+
+        fn eos_token(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn context_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[tokio::test]
+    async fn test_llm_generate_stops_on_deadline_and_disconnect() {
+        let node = TestNode::start(RpcConfig::default()).await;
+        let params = json!({ "prompt": "hello", "max_tokens": 3, "temperature": 0.0 });
+        assert_eq!(node.error_code("llm_generate", params.clone()).await, METHOD_NOT_FOUND);
+
+        let backend = Arc::new(Slow::default());
+        let model = LightLLM::with_backend(backend.clone());
+        node.server.serve_llm(Arc::new(model.start_queue(QueueConfig::new(2))));
+        let completion = node.result::<Value>("llm_generate", params).await;
+        assert_eq!((completion["text"].as_str(), completion["finish"]["reason"].as_str()), (Some("xxx"), Some("length")));
+
+        let params = json!({ "prompt": "hello", "max_tokens": 4000, "temperature": 0.0, "timeout_ms": 50 });
+        let timed_out = node.call("llm_generate", params.clone()).await;
+        assert_eq!(timed_out["error"]["code"], GENERATION_INTERRUPTED, "{}", timed_out);
+        let partial = &timed_out["error"]["data"];
+        let completion_tokens = partial["completion_tokens"].as_u64().unwrap() as usize;
+        assert!(completion_tokens > 0 && completion_tokens < 4000, "{}", partial);
+        assert_eq!(partial["text"].as_str().unwrap().len(), completion_tokens);
+
+        // A client that gives up takes its generation with it.
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "llm_generate", "params": { "prompt": "hello", "max_tokens": 4000, "temperature": 0.0 } });
+        let client = reqwest::Client::builder().timeout(Duration::from_millis(50)).build().unwrap();
+        assert!(client.post(node.url()).json(&request).send().await.is_err());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let forwards = backend.forwards.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(backend.forwards.load(Ordering::SeqCst), forwards);
+    }
+
+    #[tokio::test]
+    async fn test_llm_usage_reported_and_charged_by_address() {
+        let node = TestNode::start(RpcConfig::default()).await;
+        let model = LightLLM::with_backend(Arc::new(Slow::default()));
+        node.server.serve_llm(Arc::new(model.start_queue(QueueConfig::new(2))));
+        let (alice, bob) = (address(&Keypair::new()), address(&Keypair::new()));
+
+        for (address, max_tokens) in [(Some(&alice), 3), (Some(&alice), 2), (Some(&bob), 1), (None, 4)] {
+            let params = json!({ "prompt": "hello", "max_tokens": max_tokens, "temperature": 0.0, "address": address });
+            let completion = node.result::<Value>("llm_generate", params).await;
+            let usage = &completion["usage"];
+            assert_eq!((usage["prompt_tokens"].as_u64(), usage["completion_tokens"].as_u64()), (Some(5), Some(max_tokens)));
+            assert!(usage["time_to_first_token_secs"].as_f64().unwrap() > 0.0, "{}", usage);
+        }
+        let params = json!({ "prompt": "hello", "max_tokens": 1, "address": "not an address" });
+        assert_eq!(node.error_code("llm_generate", params).await, INVALID_PARAMS);
+
+        let usage = node.server.llm_usage_by_address();
+        let charged = |address: &str| usage[&DADBSAddress::from_string(address).unwrap()];
+        assert_eq!(charged(&alice), UsageTotals { requests: 2, prompt_tokens: 10, completion_tokens: 5 });
+        assert_eq!(charged(&bob), UsageTotals { requests: 1, prompt_tokens: 5, completion_tokens: 1 });
+        assert_eq!(usage.len(), 2);
+        assert_eq!(model.metrics().totals().requests, 4);
+    }
+
+    #[tokio::test]
+    async fn test_llm_estimate_matches_generated_usage() {
+        let node = TestNode::start(RpcConfig::default()).await;
+        let params = json!({ "prompt": "hello", "max_tokens": 3 });
+        assert_eq!(node.error_code("llm_estimate", params.clone()).await, METHOD_NOT_FOUND);
+
+        let model = LightLLM::with_backend(Arc::new(Slow::default()));
+        node.server.serve_llm(Arc::new(model.start_queue(QueueConfig::new(2))));
+        let estimate = node.result::<Value>("llm_estimate", params.clone()).await;
+        assert_eq!((estimate["tokens"].as_u64(), estimate["prompt_tokens"].as_u64()), (Some(5), Some(5)));
+        assert_eq!((estimate["max_total_tokens"].as_u64(), estimate["fits_context"].as_bool()), (Some(8), Some(true)));
+        assert!(estimate["est_latency_ms"].is_null(), "{}", estimate);
+
+        let generate = json!({ "prompt": "hello", "max_tokens": 3, "temperature": 0.0 });
+        let usage = node.result::<Value>("llm_generate", generate).await["usage"].clone();
+        assert_eq!(usage["prompt_tokens"], estimate["prompt_tokens"]);
+        assert_eq!(usage["prompt_tokens"].as_u64().unwrap() + usage["completion_tokens"].as_u64().unwrap(), 8);
+        // Three steps of at least 5ms each.
+        let estimate = node.result::<Value>("llm_estimate", params).await;
+        assert!(estimate["est_latency_ms"].as_u64().unwrap() >= 10, "{}", estimate);
+
+        let too_long = json!({ "prompt": "x".repeat(4095), "max_tokens": 3 });
+        assert_eq!(node.result::<Value>("llm_estimate", too_long).await["fits_context"], false);
+    }
+}
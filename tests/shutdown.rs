@@ -0,0 +1,72 @@
+#![cfg(unix)]
+
+use dadbs_node::node::network::PEER_STORE_FILE;
+use dadbs_node::node::runtime::{CHAIN_DIR, STATE_FILE};
+use dadbs_node::node::Storage;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_sigterm_flushes_and_exits_cleanly() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = dir.path().join("data");
+    let config = format!(
+        r#"
+node_id = "shutdown-test"
+host = "127.0.0.1"
+port = 0
+storage_path = "{}"
+max_connections = 8
+consensus_timeout = 5000
+bootstrap_nodes = []
+shutdown_deadline_ms = 5000
+
+[rpc]
+listen = "127.0.0.1:0"
+
+[metrics]
+listen = "127.0.0.1:0"
+"#,
+        data.display()
+    );
+    let config_path = dir.path().join("node.toml");
+    std::fs::write(&config_path, config).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dadbs-node"))
+        .arg("run")
+        .arg("--config")
+        .arg(&config_path)
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut log = BufReader::new(child.stderr.take().unwrap()).lines();
+    // Logged once the signal handlers are installed.
+    let started = log.by_ref().map_while(Result::ok).any(|line| line.contains("running: p2p"));
+    assert!(started, "node did not start");
+    assert!(Command::new("kill").arg("-TERM").arg(child.id().to_string()).status().unwrap().success());
+
+    let tail = std::thread::spawn(move || log.map_while(Result::ok).collect::<Vec<String>>());
+    let signalled = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if signalled.elapsed() > Duration::from_secs(15) {
+            child.kill().unwrap();
+            panic!("node did not exit after SIGTERM");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let tail = tail.join().unwrap();
+    assert_eq!(status.code(), Some(0), "{}", tail.join("\n"));
+    assert!(tail.iter().any(|line| line.contains("Received SIGTERM")));
+    assert!(tail.iter().any(|line| line.contains("Node shutdown-test stopped")));
+    assert!(!tail.iter().any(|line| line.contains("Aborted tasks")), "{}", tail.join("\n"));
+
+    assert!(data.join(STATE_FILE).exists());
+    assert!(data.join(PEER_STORE_FILE).exists());
+    let storage = Storage::open(data.join(CHAIN_DIR)).unwrap();
+    assert!(storage.previous_shutdown_clean());
+}
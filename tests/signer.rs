@@ -0,0 +1,152 @@
+use async_trait::async_trait;
+use dadbs_node::node::{
+    ConsensusManager, ControlError, HaltReason, RemoteSigner, SignGuard, SignerConnector, SignerError, SignerService,
+    SignerStream, ThresholdPolicy, UnreachablePolicy, VoteSigner,
+};
+use parking_lot::Mutex;
+use solana_sdk::hash::Hash;
+use solana_sdk::signature::Keypair;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+
+const SECRET: &[u8] = b"shared signer secret";
+
+/// Runs a `SignerService` in this process, serving each connection over one
+/// end of a socket pair. `restart` drops every connection and starts a new
+/// service from the persisted guard, as a restarted `dadbs-signer` would.
+struct InProcess {
+    key: Arc<Keypair>,
+    guard_path: PathBuf,
+    service: Mutex<Option<Arc<SignerService>>>,
+    connections: Mutex<Vec<JoinHandle<()>>>,
+    dialed: AtomicUsize,
+}
+
+impl InProcess {
+    fn start(key: Keypair, guard_path: PathBuf) -> Arc<Self> {
+        let signer = Arc::new(InProcess {
+            key: Arc::new(key),
+            guard_path,
+            service: Mutex::new(None),
+            connections: Mutex::new(Vec::new()),
+            dialed: AtomicUsize::new(0),
+        });
+        signer.restart();
+        signer
+    }
+
+    fn stop(&self) {
+        *self.service.lock() = None;
+        for connection in self.connections.lock().drain(..) {
+            connection.abort();
+        }
+    }
+
+    fn restart(&self) {
+        self.stop();
+        let guard = SignGuard::open(&self.guard_path).unwrap();
+        let key: Arc<dyn VoteSigner> = self.key.clone();
+        *self.service.lock() = Some(Arc::new(SignerService::new(key, guard, SECRET)));
+    }
+
+    fn remote(self: &Arc<Self>) -> RemoteSigner {
+        let connector: Arc<dyn SignerConnector> = self.clone();
+        RemoteSigner::new(self.key.pubkey(), connector, SECRET, SignGuard::in_memory())
+            .with_timeout(Duration::from_millis(500))
+    }
+}
+
+#[async_trait]
+impl SignerConnector for InProcess {
+    async fn connect(&self) -> io::Result<Box<dyn SignerStream>> {
+        let service = self.service.lock().clone().ok_or(io::ErrorKind::ConnectionRefused)?;
+        self.dialed.fetch_add(1, Ordering::SeqCst);
+        let (node, signer) = UnixStream::pair()?;
+        let connection = tokio::spawn(async move {
+            let _ = service.serve(Box::new(signer)).await;
+        });
+        self.connections.lock().push(connection);
+        Ok(Box::new(node))
+    }
+}
+
+fn consensus() -> ConsensusManager {
+    ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+}
+
+#[tokio::test]
+async fn test_votes_are_signed_across_a_signer_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let signer = InProcess::start(Keypair::new(), dir.path().join("signer_guard.json"));
+    let remote = signer.remote();
+    let consensus = consensus();
+    let (a, b) = (Hash::new_unique(), Hash::new_unique());
+
+    let first = consensus.sign_vote_with(&remote, 1, 0, a).await.unwrap();
+    assert!(first.verify_signature(consensus.chain_id()));
+    assert_eq!(first.validator, signer.key.pubkey());
+
+    // Restarted mid-round: the vote is asked for again, on a new connection.
+    signer.restart();
+    let again = consensus.sign_vote_with(&remote, 1, 0, a).await.unwrap();
+    assert_eq!(again, first);
+    let next = consensus.sign_vote_with(&remote, 2, 0, b).await.unwrap();
+    assert!(next.verify_signature(consensus.chain_id()));
+    assert_eq!(signer.dialed.load(Ordering::SeqCst), 2);
+    assert!(remote.is_connected().await);
+}
+
+#[tokio::test]
+async fn test_conflicting_votes_are_refused_on_both_sides() {
+    let dir = tempfile::tempdir().unwrap();
+    let signer = InProcess::start(Keypair::new(), dir.path().join("signer_guard.json"));
+    let remote = signer.remote();
+    let consensus = consensus();
+    let (a, b) = (Hash::new_unique(), Hash::new_unique());
+    consensus.sign_vote_with(&remote, 3, 1, a).await.unwrap();
+
+    // Refused locally, without asking the signer, even with it down.
+    signer.stop();
+    let conflict = consensus.sign_vote_with(&remote, 3, 1, b).await;
+    assert!(matches!(conflict, Err(SignerError::DoubleSign { height: 3, round: 1, .. })), "{:?}", conflict);
+    let earlier = consensus.sign_vote_with(&remote, 2, 0, b).await;
+    assert!(matches!(earlier, Err(SignerError::Regression { last_height: 3, .. })), "{:?}", earlier);
+
+    // A node that lost its record still gets nothing conflicting out of the signer.
+    signer.restart();
+    let forgetful = signer.remote();
+    match consensus.sign_vote_with(&forgetful, 3, 1, b).await {
+        Err(SignerError::Refused(reason)) => assert!(reason.contains("height 3 round 1"), "{}", reason),
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+    consensus.sign_vote_with(&forgetful, 3, 2, b).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_unreachable_signer_halts_or_skips() {
+    let dir = tempfile::tempdir().unwrap();
+    let signer = InProcess::start(Keypair::new(), dir.path().join("signer_guard.json"));
+    signer.stop();
+
+    let skipping = consensus();
+    let remote = signer.remote().with_unreachable_policy(UnreachablePolicy::Skip).with_control(skipping.control());
+    let skipped = skipping.sign_vote_with(&remote, 1, 0, Hash::new_unique()).await;
+    assert!(matches!(skipped, Err(SignerError::Unreachable(_))), "{:?}", skipped);
+    assert!(skipping.halt_status().is_none());
+
+    let halting = consensus();
+    let remote = signer.remote().with_control(halting.control());
+    let unreachable = halting.sign_vote_with(&remote, 1, 0, Hash::new_unique()).await;
+    assert!(matches!(unreachable, Err(SignerError::Unreachable(_))), "{:?}", unreachable);
+    assert!(matches!(halting.halt_status().unwrap().reason, HaltReason::SignerUnreachable(_)));
+
+    // Nothing more is signed until an operator resumes consensus.
+    signer.restart();
+    let halted = halting.sign_vote_with(&remote, 2, 0, Hash::new_unique()).await;
+    assert!(matches!(halted, Err(SignerError::Control(ControlError::Halted(_)))), "{:?}", halted);
+}
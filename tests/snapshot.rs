@@ -0,0 +1,193 @@
+use async_trait::async_trait;
+use dadbs_node::node::network::PeerId;
+use dadbs_node::node::sync::{BlockStore, MemoryBlockStore, SyncPeer};
+use dadbs_node::node::{
+    Block, CommitCertificate, ConsensusManager, SnapshotError, SnapshotTrust, State, Storage, StorageError,
+    SyncError, SyncManager, SyncMessage, ThresholdPolicy, Transaction, ValidatorInfo, ValidatorSet,
+    ValidatorSetHistory, Vote,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::RwLock;
+use solana_sdk::signature::{Keypair, Signer};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+const CHAIN_ID: &str = "dadbs-testnet";
+
+struct Chain {
+    keys: Vec<Keypair>,
+    alice: Keypair,
+    bob: Keypair,
+}
+
+impl Chain {
+    fn new() -> Self {
+        Chain { keys: (0..4).map(|_| Keypair::new()).collect(), alice: Keypair::new(), bob: Keypair::new() }
+    }
+
+    fn validators(&self) -> ValidatorSet {
+        ValidatorSet::new(self.keys.iter().map(|k| ValidatorInfo::new(k.pubkey(), 1)).collect())
+    }
+
+    fn trust(&self) -> SnapshotTrust {
+        SnapshotTrust::new(CHAIN_ID, self.validators(), Arc::new(ThresholdPolicy::bft()))
+    }
+
+    fn genesis(&self) -> Vec<(DADBSAddress, u64)> {
+        vec![(DADBSAddress::from_pubkey(&self.alice.pubkey()), 1_000)]
+    }
+
+    fn certificate(&self, block: &Block) -> CommitCertificate {
+        let votes = self.keys.iter().map(|k| Vote::new(k, block.height(), 0, block.hash())).collect();
+        CommitCertificate::new(block.height(), block.hash(), votes)
+    }
+
+    /// Finalizes `count` blocks, each moving 10 from alice to bob.
+    fn build(&self, state: &mut State, count: u64) -> Vec<(Block, CommitCertificate)> {
+        let mut parent = Block::genesis();
+        (0..count)
+            .map(|nonce| {
+                let tx = Transaction::new_signed(&self.alice, self.bob.pubkey(), 10, 0, nonce, 0);
+                let mut block = Block::with_transactions(parent.height() + 1, parent.hash(), 0, self.keys[0].pubkey(), vec![tx]);
+                block.header.state_root = state.root();
+                state.apply_block(&block).unwrap();
+                let certificate = self.certificate(&block);
+                parent = block.clone();
+                (block, certificate)
+            })
+            .collect()
+    }
+}
+
+fn exported(chain: &Chain, dir: &Path, count: u64) -> (std::path::PathBuf, State, Vec<(Block, CommitCertificate)>) {
+    let storage = Storage::open(dir.join("source")).unwrap();
+    let mut state = State::in_memory(chain.genesis());
+    let blocks = chain.build(&mut state, count);
+    for (block, certificate) in &blocks {
+        storage.put_finalized_block(block, certificate).unwrap();
+    }
+    let path = dir.join("snapshot.snap");
+    let manifest = storage.export_snapshot(&path, count, &state, CHAIN_ID).unwrap();
+    assert_eq!((manifest.height, manifest.state_root), (count, state.root()));
+    (path, state, blocks)
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let chain = Chain::new();
+    let (path, state, blocks) = exported(&chain, dir.path(), 20);
+    let (tip, certificate) = blocks.last().unwrap();
+
+    let storage = Storage::open(dir.path().join("target")).unwrap();
+    let snapshot = storage.import_snapshot(&path, &chain.trust()).unwrap();
+    assert_eq!(snapshot.height(), 20);
+    assert_eq!(snapshot.state.root(), state.root());
+    assert_eq!(snapshot.state.balance(&DADBSAddress::from_pubkey(&chain.bob.pubkey())), 200);
+    assert_eq!(snapshot.tip().hash(), tip.hash());
+
+    assert_eq!(storage.finalized_height().unwrap(), Some(20));
+    assert_eq!(storage.get_header_by_height(20).unwrap().as_ref(), Some(&tip.header));
+    assert_eq!(storage.get_certificate(20).unwrap().as_ref(), Some(certificate));
+    assert!(matches!(storage.get_block_by_height(20), Err(StorageError::Pruned { .. })));
+
+    // The next block lands on top of the snapshot and survives a reopen.
+    let mut state = snapshot.state;
+    let mut next = Block::new(21, tip.hash(), 0, chain.keys[0].pubkey());
+    next.header.state_root = state.root();
+    state.apply_block(&next).unwrap();
+    storage.put_finalized_block(&next, &chain.certificate(&next)).unwrap();
+    assert!(matches!(storage.import_snapshot(&path, &chain.trust()), Err(SnapshotError::Stale { .. })));
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = Storage::open(dir.path().join("target")).unwrap();
+    assert_eq!(storage.get_block_by_height(21).unwrap(), Some(next));
+}
+
+#[test]
+fn test_tampered_or_untrusted_snapshot_leaves_storage_untouched() {
+    let dir = tempfile::tempdir().unwrap();
+    let chain = Chain::new();
+    let (path, _, _) = exported(&chain, dir.path(), 5);
+    let storage = Storage::open(dir.path().join("target")).unwrap();
+
+    // Bump alice's balance inside the state section.
+    let mut archive = zstd::decode_all(std::fs::read(&path).unwrap().as_slice()).unwrap();
+    let needle = b"\"balance\":950";
+    let at = archive.windows(needle.len()).position(|w| w == needle).unwrap();
+    archive[at + needle.len() - 3] = b'8';
+    let tampered = dir.path().join("tampered.snap");
+    std::fs::write(&tampered, zstd::encode_all(archive.as_slice(), 3).unwrap()).unwrap();
+    assert!(matches!(
+        storage.import_snapshot(&tampered, &chain.trust()),
+        Err(SnapshotError::ChecksumMismatch(section)) if section == "state"
+    ));
+
+    let truncated = dir.path().join("truncated.snap");
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+    assert!(storage.import_snapshot(&truncated, &chain.trust()).is_err());
+
+    let strangers = Chain::new().trust();
+    assert!(matches!(storage.import_snapshot(&path, &strangers), Err(SnapshotError::Certificate(_))));
+    let other_chain = SnapshotTrust::new("dadbs-mainnet", chain.validators(), Arc::new(ThresholdPolicy::bft()));
+    assert!(matches!(storage.import_snapshot(&path, &other_chain), Err(SnapshotError::ChainMismatch { .. })));
+
+    assert_eq!(storage.latest_height().unwrap(), None);
+    assert_eq!(storage.finalized_height().unwrap(), None);
+    assert_eq!(storage.prune_horizon().unwrap(), 0);
+}
+
+struct LocalPeer {
+    id: PeerId,
+    store: Arc<MemoryBlockStore>,
+}
+
+#[async_trait]
+impl SyncPeer for LocalPeer {
+    fn id(&self) -> PeerId {
+        self.id
+    }
+
+    async fn request(&self, message: SyncMessage) -> Result<SyncMessage, SyncError> {
+        let (from, to) = match message {
+            SyncMessage::GetBlocks { from, to } => (from, to),
+            SyncMessage::Blocks { .. } => return Err(SyncError::Transport("unexpected blocks".to_string())),
+        };
+        let (blocks, certificates) = (from..=to).map_while(|height| self.store.get_finalized(height)).unzip();
+        Ok(SyncMessage::Blocks { blocks, certificates })
+    }
+}
+
+#[tokio::test]
+async fn test_sync_prefers_configured_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let chain = Chain::new();
+    let (path, _, _) = exported(&chain, dir.path(), 10);
+
+    // The peer has 30 blocks but only serves those above the snapshot.
+    let mut peer_state = State::in_memory(chain.genesis());
+    let peer_store = Arc::new(MemoryBlockStore::new());
+    for (block, certificate) in chain.build(&mut peer_state, 30).into_iter().skip(10) {
+        peer_store.put_finalized(block, certificate);
+    }
+    let peer = LocalPeer { id: "127.0.0.1:9000".parse().unwrap(), store: peer_store };
+
+    let state = Arc::new(RwLock::new(State::in_memory(chain.genesis())));
+    let consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_state(Arc::clone(&state));
+    let consensus = Arc::new(Mutex::new(consensus));
+    let storage = Arc::new(Storage::open(dir.path().join("target")).unwrap());
+    let history = Arc::new(RwLock::new(ValidatorSetHistory::new(chain.validators())));
+    let sync = SyncManager::new(Arc::clone(&consensus), storage.clone(), history, 8)
+        .with_snapshot(Arc::clone(&storage), path, chain.trust());
+
+    assert_eq!(sync.sync_with(&peer, 30, &CancellationToken::new()).await.unwrap(), 30);
+    assert_eq!(state.read().root(), peer_state.root());
+    assert!(storage.get_block_by_height(10).is_err());
+    assert!(storage.get_block_by_height(11).unwrap().is_some());
+}
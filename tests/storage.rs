@@ -0,0 +1,219 @@
+use dadbs_node::node::sync::BlockStore;
+use dadbs_node::node::pagination::MAX_PAGE_LIMIT;
+use dadbs_node::node::{
+    Block, CommitCertificate, CursorError, Direction, Page, Storage, StorageError, StoredTransaction, Transaction, Vote,
+};
+use solana_sdk::{
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+};
+
+const BLOCKS: u64 = 1000;
+
+fn build_chain(senders: &[Keypair], recipient: Pubkey) -> Vec<Block> {
+    let mut parent = Hash::default();
+    (1..=BLOCKS)
+        .map(|height| {
+            let sender = &senders[height as usize % senders.len()];
+            let transactions = vec![
+                Transaction::new_signed(sender, recipient, height, 1, height, height as i64),
+                Transaction::new_signed(sender, Pubkey::new_unique(), 1, 1, height + BLOCKS, height as i64),
+            ];
+            let block = Block::with_transactions(height, parent, height as i64, Pubkey::default(), transactions);
+            parent = block.hash();
+            block
+        })
+        .collect()
+}
+
+#[test]
+fn test_blocks_survive_reopen_and_every_index_answers() {
+    let dir = tempfile::tempdir().unwrap();
+    let senders: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+    let recipient = Pubkey::new_unique();
+    let chain = build_chain(&senders, recipient);
+    {
+        let storage = Storage::open(dir.path()).unwrap();
+        for block in &chain {
+            storage.put_block(block).unwrap();
+        }
+        storage.put_metadata("finalized_height", &BLOCKS.to_be_bytes()).unwrap();
+        storage.flush().unwrap();
+    }
+
+    let storage = Storage::open(dir.path()).unwrap();
+    assert_eq!(storage.latest_height().unwrap(), Some(BLOCKS));
+    assert_eq!(storage.get_metadata("finalized_height").unwrap(), Some(BLOCKS.to_be_bytes().to_vec()));
+    for block in &chain {
+        assert_eq!(storage.get_block(&block.hash()).unwrap().as_ref(), Some(block));
+        assert_eq!(storage.get_block_by_height(block.height()).unwrap().as_ref(), Some(block));
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let stored = storage.get_transaction(&tx.hash()).unwrap().unwrap();
+            assert_eq!((stored.height, stored.index, &stored.transaction), (block.height(), index as u32, tx));
+        }
+    }
+    assert_eq!(storage.get_block_by_height(BLOCKS + 1).unwrap(), None);
+    assert_eq!(storage.get_transaction(&Hash::new_unique()).unwrap(), None);
+
+    // The recipient got one transaction per block.
+    let received = storage.txs_for_address(&recipient, 100..200).unwrap();
+    assert_eq!(received.iter().map(|tx| tx.height).collect::<Vec<_>>(), (100..200).collect::<Vec<_>>());
+    assert!(received.iter().all(|tx| tx.transaction.recipient == recipient));
+
+    // Each sender signed both transactions of every fourth block.
+    let sender = senders[1].pubkey();
+    let sent = storage.txs_for_address(&sender, 0..BLOCKS + 1).unwrap();
+    assert_eq!(sent.len(), 2 * BLOCKS as usize / 4);
+    assert!(sent.windows(2).all(|pair| (pair[0].height, pair[0].index) < (pair[1].height, pair[1].index)));
+    assert!(storage.txs_for_address(&sender, 5..5).unwrap().is_empty());
+    assert!(storage.txs_for_address(&Pubkey::new_unique(), 0..BLOCKS).unwrap().is_empty());
+}
+
+#[test]
+fn test_finalized_blocks_through_block_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let keypair = Keypair::new();
+    let block = Block::new(1, Hash::default(), 0, keypair.pubkey());
+    let certificate = CommitCertificate::new(1, block.hash(), vec![Vote::new(&keypair, 1, 0, block.hash())]);
+    {
+        let storage = Storage::open(dir.path()).unwrap();
+        storage.put_finalized(block.clone(), certificate.clone());
+        storage.put_block(&Block::new(2, block.hash(), 1, keypair.pubkey())).unwrap();
+        storage.flush().unwrap();
+    }
+
+    let storage = Storage::open(dir.path()).unwrap();
+    assert_eq!(storage.get_finalized(1), Some((block, certificate)));
+    // Stored but not finalized.
+    assert_eq!(storage.get_finalized(2), None);
+}
+
+#[test]
+fn test_pruning_respects_horizon_and_is_idempotent() {
+    let dir = tempfile::tempdir().unwrap();
+    let keypair = Keypair::new();
+    let senders = vec![Keypair::new()];
+    let recipient = Pubkey::new_unique();
+    let chain = build_chain(&senders, recipient);
+    let storage = Storage::open(dir.path()).unwrap();
+    for block in &chain[..300] {
+        let vote = Vote::new(&keypair, block.height(), 0, block.hash());
+        let certificate = CommitCertificate::new(block.height(), block.hash(), vec![vote]);
+        storage.put_finalized_block(block, &certificate).unwrap();
+    }
+    assert_eq!(storage.finalized_height().unwrap(), Some(300));
+
+    // Keeping 100 finalized bodies moves the horizon to 201, 64 heights a step.
+    let mut steps = 0;
+    while storage.prune_step(100, 64).unwrap() > 0 {
+        steps += 1;
+    }
+    assert_eq!(steps, 4);
+    assert_eq!(storage.prune_horizon().unwrap(), 201);
+    assert_eq!(storage.prune_step(100, 64).unwrap(), 0);
+    assert_eq!(storage.prune_horizon().unwrap(), 201);
+
+    let pruned = &chain[199];
+    assert!(matches!(storage.get_block(&pruned.hash()), Err(StorageError::Pruned { height: 200, horizon: 201 })));
+    assert!(matches!(storage.get_block_by_height(1), Err(StorageError::Pruned { height: 1, horizon: 201 })));
+    assert!(matches!(storage.txs_for_address(&recipient, 150..250), Err(StorageError::Pruned { .. })));
+    assert_eq!(storage.get_transaction(&pruned.transactions[0].hash()).unwrap(), None);
+    assert_eq!(storage.get_header(&pruned.hash()).unwrap().as_ref(), Some(&pruned.header));
+    assert_eq!(storage.get_header_by_height(1).unwrap().as_ref(), Some(&chain[0].header));
+    assert!(storage.get_certificate(1).unwrap().is_some());
+    assert_eq!(storage.get_finalized(1), None);
+
+    let kept = &chain[200];
+    assert_eq!(storage.get_block(&kept.hash()).unwrap().as_ref(), Some(kept));
+    assert_eq!(storage.txs_for_address(&recipient, 201..301).unwrap().len(), 100);
+    assert!(storage.get_finalized(300).is_some());
+
+    // Unfinalized blocks are never pruned, however far the tip runs ahead.
+    for block in &chain[300..] {
+        storage.put_block(block).unwrap();
+    }
+    assert_eq!(storage.prune_step(100, 1000).unwrap(), 0);
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = Storage::open(dir.path()).unwrap();
+    assert_eq!(storage.prune_horizon().unwrap(), 201);
+    assert_eq!(storage.latest_height().unwrap(), Some(BLOCKS));
+}
+
+/// Every item of a listing, following cursors until the last page.
+fn walk<T>(mut page: impl FnMut(Option<&str>) -> Result<Page<T>, StorageError>) -> Vec<T> {
+    let (mut items, mut cursor) = (Vec::new(), None::<String>);
+    loop {
+        let next = page(cursor.as_deref()).unwrap();
+        assert!(next.items.len() <= MAX_PAGE_LIMIT);
+        items.extend(next.items);
+        match next.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return items,
+        }
+    }
+}
+
+#[test]
+fn test_pagination_walks_every_item_once() {
+    const TRANSACTIONS_PER_BLOCK: u64 = 100;
+    let dir = tempfile::tempdir().unwrap();
+    let (sender, recipient) = (Keypair::new(), Pubkey::new_unique());
+    let storage = Storage::open(dir.path()).unwrap();
+    let mut parent = Hash::default();
+    for height in 1..=50 {
+        let transactions = (0..TRANSACTIONS_PER_BLOCK)
+            .map(|i| Transaction::new_signed(&sender, recipient, 1, 1, height * TRANSACTIONS_PER_BLOCK + i, 0))
+            .collect();
+        let block = Block::with_transactions(height, parent, height as i64, Pubkey::default(), transactions);
+        parent = block.hash();
+        storage.put_block(&block).unwrap();
+    }
+    let expected: Vec<(u64, u32)> = (1..=50).flat_map(|height| (0..100).map(move |index| (height, index))).collect();
+    let positions = |items: Vec<StoredTransaction>| items.iter().map(|tx| (tx.height, tx.index)).collect::<Vec<_>>();
+
+    for direction in [Direction::Ascending, Direction::Descending] {
+        let mut expected = expected.clone();
+        if direction == Direction::Descending {
+            expected.reverse();
+        }
+        let listed = walk(|cursor| storage.transactions_by_address(&recipient, cursor, 73, direction));
+        assert_eq!(positions(listed), expected);
+        let listed = walk(|cursor| storage.transactions_by_address(&sender.pubkey(), cursor, 1_000, direction));
+        assert_eq!(positions(listed), expected);
+
+        let start = if direction == Direction::Ascending { 0 } else { u64::MAX };
+        let blocks = walk(|cursor| match cursor {
+            Some(cursor) => storage.resume_blocks(cursor, 7, direction),
+            None => storage.blocks_range(start, 7, direction),
+        });
+        let mut heights: Vec<u64> = (1..=50).collect();
+        if direction == Direction::Descending {
+            heights.reverse();
+        }
+        assert_eq!(blocks.iter().map(Block::height).collect::<Vec<_>>(), heights);
+    }
+    let mut newest_first = expected.clone();
+    newest_first.reverse();
+    assert_eq!(positions(walk(|cursor| storage.recent_transactions(cursor, 33))), newest_first);
+    // Oversized limits are capped.
+    assert_eq!(storage.recent_transactions(None, 1_000_000).unwrap().items.len(), MAX_PAGE_LIMIT);
+
+    let page = storage.transactions_by_address(&recipient, None, 10, Direction::Ascending).unwrap();
+    let cursor = page.next_cursor.unwrap();
+    let mut tampered = hex::decode(&cursor).unwrap();
+    tampered[11] = tampered[11].wrapping_add(1);
+    let forged = storage.transactions_by_address(&recipient, Some(&hex::encode(tampered)), 10, Direction::Ascending);
+    assert!(matches!(forged, Err(StorageError::InvalidCursor(CursorError::Forged))));
+    let elsewhere = storage.transactions_by_address(&sender.pubkey(), Some(&cursor), 10, Direction::Ascending);
+    assert!(matches!(elsewhere, Err(StorageError::InvalidCursor(CursorError::Forged))));
+    assert!(matches!(storage.recent_transactions(Some("00"), 10), Err(StorageError::InvalidCursor(_))));
+
+    // The signing key is kept, so cursors outlive a restart.
+    drop(storage);
+    let storage = Storage::open(dir.path()).unwrap();
+    let resumed = storage.transactions_by_address(&recipient, Some(&cursor), 10, Direction::Ascending).unwrap();
+    assert_eq!(resumed.items[0].index, 10);
+}
@@ -0,0 +1,230 @@
+mod common;
+
+use async_trait::async_trait;
+use dadbs_node::node::{
+    sync::{BlockStore, MemoryBlockStore, SyncPeer},
+    Block, CommitCertificate, ConsensusManager, Genesis, Node, NodeConfig, SyncError, SyncManager, SyncMessage,
+    ThresholdPolicy, ValidatorInfo, ValidatorSet, ValidatorSetHistory, Vote,
+};
+use dadbs_node::node::network::{Network, NetworkConfig, PeerId};
+use dadbs_node::node::rpc::BlockResult;
+use parking_lot::RwLock;
+use serde_json::json;
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+struct TestNode {
+    consensus: Arc<Mutex<ConsensusManager>>,
+    store: Arc<MemoryBlockStore>,
+    sync: Arc<SyncManager>,
+}
+
+impl TestNode {
+    fn new(validators: &ValidatorSet) -> Self {
+        Self::build(validators, None)
+    }
+
+    /// Scores and bans the peers it syncs from through a loopback network.
+    async fn networked(validators: &ValidatorSet) -> (Self, Arc<Network>) {
+        let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "behind");
+        let (network, _) = Network::bind(config).await.unwrap();
+        (Self::build(validators, Some(Arc::clone(&network))), network)
+    }
+
+    fn build(validators: &ValidatorSet, network: Option<Arc<Network>>) -> Self {
+        let consensus = Arc::new(Mutex::new(ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))));
+        let store = Arc::new(MemoryBlockStore::new());
+        let history = Arc::new(RwLock::new(ValidatorSetHistory::new(validators.clone())));
+        let sync = SyncManager::new(Arc::clone(&consensus), store.clone(), history, 16);
+        let sync = Arc::new(match network {
+            Some(network) => sync.with_network(network),
+            None => sync,
+        });
+        TestNode { consensus, store, sync }
+    }
+
+    async fn produce_blocks(&self, keys: &[Keypair], count: u64) {
+        let mut consensus = self.consensus.lock().await;
+        for _ in 0..count {
+            let parent = consensus.block_tree().finalized().clone();
+            let block = Block::new(parent.height() + 1, parent.hash(), parent.height() as i64 + 1, keys[0].pubkey());
+            let votes = keys.iter().map(|k| Vote::new(k, block.height(), 0, block.hash())).collect();
+            let certificate = CommitCertificate::new(block.height(), block.hash(), votes);
+
+            consensus.apply_block(block.clone(), keys.len() as u128).unwrap();
+            consensus.finalize_block(&block.hash()).unwrap();
+            self.store.put_finalized(block, certificate);
+        }
+    }
+
+    async fn finalized_height(&self) -> u64 {
+        self.consensus.lock().await.finalized_height()
+    }
+}
+
+struct LocalPeer {
+    id: PeerId,
+    remote: Arc<SyncManager>,
+}
+
+#[async_trait]
+impl SyncPeer for LocalPeer {
+    fn id(&self) -> PeerId {
+        self.id
+    }
+
+    async fn request(&self, message: SyncMessage) -> Result<SyncMessage, SyncError> {
+        self.remote.handle_request(&message)
+            .ok_or_else(|| SyncError::Transport("no response".to_string()))
+    }
+}
+
+struct TamperingPeer {
+    inner: LocalPeer,
+}
+
+#[async_trait]
+impl SyncPeer for TamperingPeer {
+    fn id(&self) -> PeerId {
+        self.inner.id
+    }
+
+    async fn request(&self, message: SyncMessage) -> Result<SyncMessage, SyncError> {
+        match self.inner.request(message).await? {
+            SyncMessage::Blocks { blocks, mut certificates } => {
+                for certificate in &mut certificates {
+                    certificate.votes.truncate(1);
+                }
+                Ok(SyncMessage::Blocks { blocks, certificates })
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+fn validator_keys() -> (Vec<Keypair>, ValidatorSet) {
+    let keys: Vec<Keypair> = (0..4).map(|_| Keypair::new()).collect();
+    let set = ValidatorSet::new(keys.iter()
+        .map(|k| ValidatorInfo::new(k.pubkey(), 1))
+        .collect());
+    (keys, set)
+}
+
+fn peer_id(port: u16) -> PeerId {
+    format!("127.0.0.1:{}", port).parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_lagging_node_catches_up() {
+    let (validator, dir) = (Keypair::new(), tempfile::tempdir().unwrap());
+    let mut genesis = Genesis::template("dadbs-testnet", vec![ValidatorInfo::new(validator.pubkey(), 1)]);
+    genesis.params.block_interval_ms = 50;
+    let (ahead_dir, behind_dir) = (dir.path().join("ahead"), dir.path().join("behind"));
+    std::fs::create_dir_all(&ahead_dir).unwrap();
+    std::fs::create_dir_all(&behind_dir).unwrap();
+    let ahead = Node::start(NodeConfig {
+        validator_key: common::validator_key(&ahead_dir, &validator),
+        ..common::node_config("ahead", &ahead_dir, &genesis)
+    })
+    .await
+    .unwrap();
+    // Far enough ahead that catching up takes more than one sync batch.
+    let lead = common::finalized(ahead.rpc_addr(), 50).await.finalized_height;
+
+    // Only following, it hears of the chain from the validator's proposals.
+    let behind = Node::start(NodeConfig {
+        bootstrap_nodes: vec![ahead.p2p_addr().to_string()],
+        ..common::node_config("behind", &behind_dir, &genesis)
+    })
+    .await
+    .unwrap();
+    common::finalized(behind.rpc_addr(), lead).await;
+    for height in 1..=lead {
+        let params = json!({ "height": height });
+        let theirs: Option<BlockResult> = common::call(ahead.rpc_addr(), "get_block_by_height", params.clone()).await;
+        let ours: Option<BlockResult> = common::call(behind.rpc_addr(), "get_block_by_height", params).await;
+        assert!(ours.is_some());
+        assert_eq!(ours, theirs);
+    }
+
+    // Caught up, it follows the chain as it grows.
+    common::finalized(behind.rpc_addr(), lead + 3).await;
+    behind.stop().await.unwrap();
+    ahead.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrent_syncs_from_multiple_peers() {
+    let (keys, set) = validator_keys();
+    let ahead = TestNode::new(&set);
+    let behind = TestNode::new(&set);
+    ahead.produce_blocks(&keys, 50).await;
+
+    let first = LocalPeer { id: peer_id(9001), remote: Arc::clone(&ahead.sync) };
+    let second = LocalPeer { id: peer_id(9002), remote: Arc::clone(&ahead.sync) };
+    let cancel = CancellationToken::new();
+
+    let (a, b) = tokio::join!(
+        behind.sync.sync_with(&first, 50, &cancel),
+        behind.sync.sync_with(&second, 50, &cancel),
+    );
+
+    assert_eq!(a.unwrap(), 50);
+    assert_eq!(b.unwrap(), 50);
+    assert_eq!(behind.store.len(), 50);
+}
+
+#[tokio::test]
+async fn test_garbage_peer_is_banned() {
+    let (keys, set) = validator_keys();
+    let ahead = TestNode::new(&set);
+    let (behind, network) = TestNode::networked(&set).await;
+    ahead.produce_blocks(&keys, 20).await;
+
+    let id = peer_id(9003);
+    let peer = TamperingPeer { inner: LocalPeer { id, remote: Arc::clone(&ahead.sync) } };
+    let cancel = CancellationToken::new();
+
+    let result = behind.sync.sync_with(&peer, 20, &cancel).await;
+    assert!(matches!(result, Err(SyncError::InvalidResponse { .. })));
+    assert!(network.peer_score(&id) > 0.0);
+    assert!(!network.is_banned(&id));
+
+    // Scores decay, so the ban may take a third bad batch rather than two.
+    for _ in 0..2 {
+        if network.is_banned(&id) {
+            break;
+        }
+        let result = behind.sync.sync_with(&peer, 20, &cancel).await;
+        assert!(matches!(result, Err(SyncError::InvalidResponse { .. })));
+    }
+
+    assert!(network.is_banned(&id));
+    assert!(behind.sync.is_banned(&id));
+    assert!(matches!(
+        behind.sync.sync_with(&peer, 20, &cancel).await,
+        Err(SyncError::PeerBanned(_))
+    ));
+    assert_eq!(behind.finalized_height().await, 0);
+}
+
+#[tokio::test]
+async fn test_sync_cancellation() {
+    let (keys, set) = validator_keys();
+    let ahead = TestNode::new(&set);
+    let (behind, network) = TestNode::networked(&set).await;
+    ahead.produce_blocks(&keys, 50).await;
+
+    let peer = LocalPeer { id: peer_id(9004), remote: Arc::clone(&ahead.sync) };
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+
+    assert!(matches!(
+        behind.sync.sync_with(&peer, 50, &cancel).await,
+        Err(SyncError::Cancelled)
+    ));
+    assert_eq!(network.peer_score(&peer.id), 0.0);
+}
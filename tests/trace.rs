@@ -0,0 +1,148 @@
+mod common;
+
+use borsh::BorshSerialize;
+use dadbs_node::node::network::{read_frame, write_frame, PROTOCOL_VERSION};
+use dadbs_node::node::{
+    ChainEvents, ConsensusManager, Genesis, GenesisAccount, Mempool, NetMessage, Network, NetworkConfig, Node,
+    RpcConfig, RpcContext, RpcServer, State, Storage, ThresholdPolicy, Transaction, TxEvent, TxStage,
+    TxTracer, ValidatorInfo,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::signature::{Keypair, Signer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::timeout;
+
+use common::Approver;
+
+async fn trace(rpc: SocketAddr, transaction: &Transaction) -> Option<Vec<TxEvent>> {
+    let request = json!({
+        "jsonrpc": "2.0", "id": 1, "method": "trace_transaction",
+        "params": { "hash": transaction.hash().to_string() },
+    });
+    let response: Value = reqwest::Client::new().post(format!("http://{}/", rpc)).json(&request)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert!(response.get("error").is_none(), "{}", response);
+    serde_json::from_value(response["result"].clone()).unwrap()
+}
+
+#[tokio::test]
+async fn test_trace_follows_transaction_to_finality() {
+    let dir = tempfile::tempdir().unwrap();
+    let (validator, alice, bob) = (Keypair::new(), Keypair::new(), Keypair::new());
+    let storage = Arc::new(Storage::open(dir.path()).unwrap());
+    let state = Arc::new(RwLock::new(State::in_memory(vec![(DADBSAddress::from_pubkey(&alice.pubkey()), 1_000)])));
+    let tracer = TxTracer::new(16);
+    let mut consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_state(Arc::clone(&state))
+        .with_tracer(tracer.clone());
+    consensus.add_validator(Arc::new(Approver(validator.pubkey())));
+    let consensus = Arc::new(AsyncMutex::new(consensus));
+    let mempool = Arc::new(Mutex::new(Mempool::new(1, 100)));
+    let (network, _inbound) = Network::bind(NetworkConfig::new("127.0.0.1:0".parse().unwrap(), "trace-test")).await.unwrap();
+    let context = RpcContext {
+        node_id: "trace-test".to_string(),
+        chain_id: "dadbs-testnet".to_string(),
+        storage,
+        consensus: Arc::clone(&consensus),
+        state,
+        mempool: Arc::clone(&mempool),
+        network: Some(network),
+        events: ChainEvents::default(),
+        tracer,
+    };
+    let rpc_config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+    let rpc = RpcServer::bind(rpc_config, context).await.unwrap();
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let transaction = Transaction::new_signed(&alice, bob.pubkey(), 10, 1, 0, now);
+    assert_eq!(trace(rpc.local_addr(), &transaction).await, None);
+    let request = json!({
+        "jsonrpc": "2.0", "id": 1, "method": "send_transaction",
+        "params": { "raw": hex::encode(transaction.try_to_vec().unwrap()) },
+    });
+    reqwest::Client::new().post(format!("http://{}/", rpc.local_addr())).json(&request).send().await.unwrap();
+
+    let block = {
+        let mut consensus = consensus.lock().await;
+        let mut batch = Mempool::new(1, 100);
+        for pending in mempool.lock().take_batch(100) {
+            batch.insert(pending).unwrap();
+        }
+        let block = consensus.build_block(&mut batch, validator.pubkey(), now, 100).await.unwrap();
+        assert_eq!(block.transactions, vec![transaction.clone()]);
+        consensus.apply_block(block.clone(), 10).unwrap();
+        consensus.finalize_block(&block.hash()).unwrap();
+        block
+    };
+
+    let events = trace(rpc.local_addr(), &transaction).await.unwrap();
+    let stages: Vec<TxStage> = events.iter().map(|event| event.stage).collect();
+    assert_eq!(stages, [
+        TxStage::Received,
+        TxStage::Admitted,
+        TxStage::Gossiped,
+        TxStage::Validated,
+        TxStage::Included,
+        TxStage::Finalized,
+    ]);
+    assert!(events.windows(2).all(|pair| pair[0].timestamp_ms <= pair[1].timestamp_ms));
+    assert_eq!(events[0].detail.as_deref(), Some("rpc"));
+    for event in &events[4..] {
+        assert_eq!(event.block_height, Some(block.height()));
+        assert_eq!(event.block_hash, Some(block.hash().to_string()));
+    }
+}
+
+#[tokio::test]
+async fn test_gossiped_transaction_traced_with_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let alice = Keypair::new();
+    let mut genesis = Genesis::template("dadbs-testnet", vec![ValidatorInfo::new(Keypair::new().pubkey(), 1)]);
+    genesis.accounts = vec![GenesisAccount { address: DADBSAddress::from_pubkey(&alice.pubkey()), balance: 1_000_000 }];
+    let node = Node::start(common::node_config("trace-node", dir.path(), &genesis)).await.unwrap();
+
+    let mut stream = TcpStream::connect(node.p2p_addr()).await.unwrap();
+    let hello = NetMessage::Handshake {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        node_id: "peer".to_string(),
+        listen_port: 0,
+        codecs: vec![],
+        chain_id: "dadbs-testnet".to_string(),
+        genesis_hash: genesis.canonical_hash(),
+        observed_addr: None,
+        capabilities: 0,
+        llm: None,
+        identity: None,
+        nonce: [0; 32],
+    };
+    write_frame(&mut stream, &hello, 1 << 20).await.unwrap();
+    let theirs = timeout(Duration::from_secs(5), read_frame(&mut stream, 1 << 20)).await.unwrap().unwrap();
+    assert!(matches!(theirs, NetMessage::Handshake { .. }));
+    let fee = genesis.params.min_fee;
+    let transaction = Transaction::new_signed(&alice, Keypair::new().pubkey(), 10, fee, 0, chrono::Utc::now().timestamp_millis());
+    write_frame(&mut stream, &NetMessage::Tx(transaction.clone()), 1 << 20).await.unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let events = loop {
+        match trace(node.rpc_addr(), &transaction).await {
+            Some(events) if events.len() >= 2 => break events,
+            _ if Instant::now() < deadline => tokio::time::sleep(Duration::from_millis(50)).await,
+            other => panic!("transaction not traced: {:?}", other),
+        }
+    };
+    assert_eq!(events[0].stage, TxStage::Received);
+    assert_eq!(events[0].detail.as_deref(), Some("gossip"));
+    assert_eq!(events[1].stage, TxStage::Admitted);
+    let peer: SocketAddr = events[0].peer.as_deref().unwrap().parse().unwrap();
+    assert!(peer.ip().is_loopback());
+    assert_eq!(events[1].peer, events[0].peer);
+    node.stop().await.unwrap();
+}
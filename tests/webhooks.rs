@@ -0,0 +1,310 @@
+#![cfg(feature = "webhooks")]
+
+use axum::body::Bytes;
+use axum::extract::State as Shared;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use dadbs_node::node::webhooks::{self, ATTEMPT_HEADER, EVENT_HEADER, IDEMPOTENCY_HEADER, SIGNATURE_HEADER};
+use dadbs_node::node::{
+    AdminApi, AdminToken, Block, ChainEvents, ChainUpdate, ConsensusManager, DeliveryStats, HttpTransport,
+    LimitsConfig, Mempool, MetricsSource, RpcConfig, RpcContext, RpcServer, StakeSlashed, State, Storage,
+    ThresholdPolicy, Transaction, TxTracer, WebhookEndpoint, WebhookEvent, WebhookFilter, Webhooks, WebhooksConfig,
+};
+use dadbs_node::utils::DADBSAddress;
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use solana_sdk::hash::Hash;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+const SECRET: &str = "backoffice-webhook-secret";
+const ADMIN_TOKEN: &str = "s3cret-admin-token";
+const WAIT: Duration = Duration::from_secs(10);
+
+/// A request as the receiving end saw it.
+#[derive(Debug, Clone)]
+struct Received {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Received {
+    fn header(&self, name: &str) -> &str {
+        self.headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// A local HTTP endpoint answering with `statuses` in turn, then 200.
+#[derive(Clone, Default)]
+struct Receiver {
+    statuses: Arc<Mutex<VecDeque<u16>>>,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+async fn receive(Shared(receiver): Shared<Receiver>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    receiver.received.lock().push(Received { headers, body });
+    let status = receiver.statuses.lock().pop_front().unwrap_or(200);
+    StatusCode::from_u16(status).unwrap()
+}
+
+impl Receiver {
+    /// Starts one and returns it with its URL.
+    async fn start(statuses: &[u16]) -> (Self, String) {
+        let receiver = Receiver::default();
+        receiver.statuses.lock().extend(statuses);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route("/hook", post(receive)).with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (receiver, url)
+    }
+
+    fn received(&self) -> Vec<Received> {
+        self.received.lock().clone()
+    }
+}
+
+async fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + WAIT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        sleep(Duration::from_millis(5)).await;
+    }
+}
+
+fn config() -> WebhooksConfig {
+    WebhooksConfig {
+        enabled: true,
+        max_attempts: 3,
+        initial_backoff_ms: 10,
+        max_backoff_ms: 40,
+        timeout_ms: 2_000,
+        ..WebhooksConfig::default()
+    }
+}
+
+fn endpoint(id: &str, url: &str, events: Vec<WebhookFilter>) -> WebhookEndpoint {
+    WebhookEndpoint { id: id.to_string(), url: url.to_string(), secret: SECRET.to_string(), events }
+}
+
+fn open(storage: &Arc<Storage>, config: WebhooksConfig) -> Arc<Webhooks> {
+    let transport = Arc::new(HttpTransport::new(Duration::from_millis(config.timeout_ms)));
+    Arc::new(Webhooks::open(config, Arc::clone(storage), transport).unwrap())
+}
+
+fn slash() -> StakeSlashed {
+    StakeSlashed {
+        validator: Keypair::new().pubkey(),
+        height: 7,
+        round: 1,
+        evidence_hash: Hash::new_unique(),
+        penalty_bps: 500,
+        signature: Signature::default(),
+    }
+}
+
+/// Checks that `received` is signed under `SECRET`, and only under it.
+fn assert_signed(received: &Received) {
+    let signature = received.header(SIGNATURE_HEADER);
+    assert!(webhooks::verify(SECRET, &received.body, signature));
+    assert!(!webhooks::verify("some-other-secret", &received.body, signature));
+    let mut tampered = received.body.to_vec();
+    tampered[1] ^= 1;
+    assert!(!webhooks::verify(SECRET, &tampered, signature));
+}
+
+#[tokio::test]
+async fn test_events_are_delivered_signed_to_the_endpoints_asking_for_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(Storage::open(dir.path().join("chain")).unwrap());
+    let (blocks, blocks_url) = Receiver::start(&[]).await;
+    let (slashes, slashes_url) = Receiver::start(&[]).await;
+    let (alice, carol) = (Keypair::new(), Keypair::new());
+    let watched = DADBSAddress::from_pubkey(&alice.pubkey()).as_string().to_string();
+    let config = WebhooksConfig {
+        endpoints: vec![
+            endpoint("blocks", &blocks_url, vec![
+                WebhookFilter::FinalizedBlock,
+                WebhookFilter::AddressTransaction { address: watched },
+            ]),
+            endpoint("slashes", &slashes_url, vec![WebhookFilter::StakeSlashed]),
+        ],
+        ..config()
+    };
+    let webhooks = open(&storage, config);
+    let (events, cancel) = (ChainEvents::default(), CancellationToken::new());
+    let running = tokio::spawn(Arc::clone(&webhooks).run(&events, cancel.clone()));
+
+    // Only the block and alice's transaction are asked for.
+    let to_alice = Transaction::new_signed(&carol, alice.pubkey(), 10, 1, 0, 1_000);
+    let elsewhere = Transaction::new_signed(&carol, Keypair::new().pubkey(), 10, 1, 1, 1_000);
+    let transactions = vec![to_alice.clone(), elsewhere];
+    let block = Block::with_transactions(1, Hash::default(), 1_000, carol.pubkey(), transactions);
+    let finalized = vec![block.clone()];
+    events.publish(&ChainUpdate { rolled_back: Vec::new(), applied: finalized.clone(), finalized });
+    let slashed = slash();
+    events.publish_slashed(slashed.clone());
+    wait_until("deliveries", || blocks.received().len() == 2 && slashes.received().len() == 1).await;
+
+    let mut received = blocks.received();
+    received.sort_by_key(|received| received.header(EVENT_HEADER).to_string());
+    let kinds: Vec<&str> = received.iter().map(|received| received.header(EVENT_HEADER)).collect();
+    assert_eq!(kinds, ["address_transaction", "finalized_block"]);
+    assert_eq!(received[0].json()["data"]["hash"], to_alice.hash().to_string());
+    assert_eq!(received[1].json()["data"]["hash"], block.hash().to_string());
+    let slash_received = slashes.received().remove(0);
+    assert_eq!(slash_received.json()["data"]["evidence_hash"], slashed.evidence_hash.to_string());
+    for received in received.iter().chain([&slash_received]) {
+        assert_signed(received);
+        assert_eq!(received.header(ATTEMPT_HEADER), "1");
+        assert_eq!(received.json()["id"], received.header(IDEMPOTENCY_HEADER));
+    }
+    // The key is the event's, not the delivery's.
+    assert_eq!(received[1].json()["id"], WebhookEvent::from_finalized(&block)[0].id);
+
+    wait_until("the deliveries to settle", || webhooks.metrics().pending() == 0).await;
+    let stats = webhooks.metrics().snapshot();
+    assert_eq!(stats["blocks"], DeliveryStats { delivered: 2, ..DeliveryStats::default() });
+    assert_eq!(stats["slashes"], DeliveryStats { delivered: 1, ..DeliveryStats::default() });
+    let mut rendered = String::new();
+    webhooks.metrics().render_metrics(&mut rendered);
+    assert!(rendered.contains("dadbs_webhook_deliveries_total{endpoint=\"blocks\"} 2"), "{}", rendered);
+    cancel.cancel();
+    running.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_failed_deliveries_are_retried_with_the_same_key_until_accepted() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(Storage::open(dir.path().join("chain")).unwrap());
+    // A server error and a timeout, both worth retrying.
+    let (receiver, url) = Receiver::start(&[500, 408]).await;
+    let webhooks = open(&storage, config());
+    webhooks.add(endpoint("backoffice", &url, vec![WebhookFilter::StakeSlashed])).unwrap();
+
+    assert_eq!(webhooks.dispatch(&WebhookEvent::from_slash(&slash())), 1);
+    wait_until("the third attempt", || receiver.received().len() == 3).await;
+    wait_until("the delivery to settle", || webhooks.metrics().pending() == 0).await;
+
+    let received = receiver.received();
+    let attempts: Vec<&str> = received.iter().map(|received| received.header(ATTEMPT_HEADER)).collect();
+    assert_eq!(attempts, ["1", "2", "3"]);
+    for retry in &received {
+        assert_signed(retry);
+        assert_eq!(retry.body, received[0].body);
+        assert_eq!(retry.header(IDEMPOTENCY_HEADER), received[0].header(IDEMPOTENCY_HEADER));
+    }
+    let stats = webhooks.metrics().snapshot()["backoffice"];
+    assert_eq!(stats, DeliveryStats { delivered: 1, failed_attempts: 2, dead_lettered: 0 });
+    assert!(webhooks.dead_letters(10).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_deliveries_given_up_on_are_dead_lettered() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(Storage::open(dir.path().join("chain")).unwrap());
+    let (failing, failing_url) = Receiver::start(&[500, 502, 503]).await;
+    // A refusal that would only be repeated is not retried.
+    let (refusing, refusing_url) = Receiver::start(&[400]).await;
+    let webhooks = open(&storage, config());
+    webhooks.add(endpoint("failing", &failing_url, vec![WebhookFilter::StakeSlashed])).unwrap();
+    webhooks.add(endpoint("refusing", &refusing_url, vec![WebhookFilter::StakeSlashed])).unwrap();
+
+    let event = WebhookEvent::from_slash(&slash());
+    assert_eq!(webhooks.dispatch(&event), 2);
+    wait_until("both to be given up on", || webhooks.metrics().pending() == 0).await;
+    assert_eq!(failing.received().len(), 3);
+    assert_eq!(refusing.received().len(), 1);
+
+    let mut letters = webhooks.dead_letters(10).unwrap();
+    letters.sort_by(|a, b| a.endpoint.cmp(&b.endpoint));
+    let summary: Vec<(&str, u32, &str)> =
+        letters.iter().map(|letter| (letter.endpoint.as_str(), letter.attempts, letter.error.as_str())).collect();
+    assert_eq!(summary, [("failing", 3, "HTTP 503"), ("refusing", 1, "HTTP 400")]);
+    for letter in &letters {
+        assert_eq!((letter.event.as_str(), letter.id.as_str()), ("stake_slashed", event.id.as_str()));
+    }
+    // Kept as sent, so it can still be delivered by hand.
+    assert_eq!(letters[0].body.as_bytes(), &failing.received()[2].body[..]);
+    assert!(webhooks::verify(SECRET, letters[0].body.as_bytes(), failing.received()[0].header(SIGNATURE_HEADER)));
+    let stats = webhooks.metrics().snapshot();
+    assert_eq!(stats["failing"], DeliveryStats { delivered: 0, failed_attempts: 3, dead_lettered: 1 });
+    assert_eq!(stats["refusing"], DeliveryStats { delivered: 0, failed_attempts: 1, dead_lettered: 1 });
+    assert_eq!(webhooks.dead_letters(1).unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_admin_methods_manage_endpoints_at_runtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(Storage::open(dir.path().join("chain")).unwrap());
+    let state = Arc::new(RwLock::new(State::in_memory(Vec::new())));
+    let consensus = ConsensusManager::new(Duration::from_secs(5), 64, Arc::new(ThresholdPolicy::bft()))
+        .with_state(Arc::clone(&state));
+    let context = RpcContext {
+        node_id: "webhooks-test".to_string(),
+        chain_id: "dadbs-testnet".to_string(),
+        storage: Arc::clone(&storage),
+        consensus: Arc::new(AsyncMutex::new(consensus)),
+        state,
+        mempool: Arc::new(Mutex::new(Mempool::new(1, 100))),
+        network: None,
+        events: ChainEvents::default(),
+        tracer: TxTracer::default(),
+    };
+    let admin = AdminApi::new(AdminToken::new(ADMIN_TOKEN), dir.path().join("snapshots"));
+    let rpc_config = RpcConfig { listen: "127.0.0.1:0".to_string(), ..RpcConfig::default() };
+    let server = RpcServer::bind_with(rpc_config, context, LimitsConfig::default(), Some(admin)).await.unwrap();
+    let webhooks = open(&storage, config());
+    server.manage_webhooks(Arc::clone(&webhooks));
+    let call = |method: &str, params: Value| {
+        let request = reqwest::Client::new()
+            .post(format!("http://{}/admin", server.local_addr()))
+            .bearer_auth(ADMIN_TOKEN)
+            .json(&json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }));
+        async move { request.send().await.unwrap().json::<Value>().await.unwrap() }
+    };
+
+    let (receiver, url) = Receiver::start(&[]).await;
+    let added = call("admin_add_webhook", json!({
+        "id": "backoffice",
+        "url": url,
+        "secret": SECRET,
+        "events": [{ "event": "stake_slashed" }],
+    }))
+    .await;
+    assert_eq!(added["result"]["id"], "backoffice");
+    assert!(added["result"].get("secret").is_none());
+    let bad = call("admin_add_webhook", json!({ "id": "bad", "url": "ftp://x", "secret": "s", "events": [] })).await;
+    assert_eq!(bad["error"]["code"], -32602);
+
+    webhooks.dispatch(&WebhookEvent::from_slash(&slash()));
+    wait_until("the delivery", || receiver.received().len() == 1).await;
+    wait_until("the delivery to settle", || webhooks.metrics().pending() == 0).await;
+    let listed = call("admin_list_webhooks", Value::Null).await;
+    assert_eq!(listed["result"][0]["url"], url);
+    assert_eq!(listed["result"][0]["stats"]["delivered"], 1);
+    assert!(call("admin_webhook_dead_letters", Value::Null).await["result"].as_array().unwrap().is_empty());
+
+    // Stored, so a restart delivers to it too; the audit trail never
+    // records the secret.
+    let reopened = open(&storage, config());
+    assert_eq!(reopened.endpoints().len(), 1);
+    let audit = storage.audit_log().unwrap();
+    assert!(audit.iter().any(|record| record.method == "admin_add_webhook"));
+    assert!(!serde_json::to_string(&audit).unwrap().contains(SECRET));
+
+    let removed = call("admin_remove_webhook", json!({ "id": "backoffice" })).await;
+    assert_eq!(removed["result"], json!({ "id": "backoffice", "removed": true }));
+    assert_eq!(webhooks.dispatch(&WebhookEvent::from_slash(&slash())), 0);
+    assert!(open(&storage, config()).endpoints().is_empty());
+}